@@ -59,6 +59,20 @@ impl SDT {
         sdt
     }
 
+    /// Wraps an existing table, e.g. provided by the user, recomputing its
+    /// checksum. The length in the header must match the table.
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        assert!(data.len() >= 36);
+        assert_eq!(
+            u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize,
+            data.len()
+        );
+
+        let mut sdt = SDT { data };
+        sdt.update_checksum();
+        sdt
+    }
+
     pub fn update_checksum(&mut self) {
         self.data[9] = 0;
         let checksum = super::generate_checksum(self.data.as_slice());
@@ -134,4 +148,19 @@ mod tests {
             .fold(0u8, |acc, x| acc.wrapping_add(*x));
         assert_eq!(sum, 0);
     }
+
+    #[test]
+    fn test_sdt_from_bytes() {
+        let mut data = SDT::new(*b"SSDT", 40, 2, *b"OEMOEM", *b"OEMTABLE", 1)
+            .as_slice()
+            .to_vec();
+        data[36] = 0x5a;
+        let sdt = SDT::from_bytes(data);
+        let sum: u8 = sdt
+            .as_slice()
+            .iter()
+            .fold(0u8, |acc, x| acc.wrapping_add(*x));
+        assert_eq!(sum, 0);
+        assert_eq!(sdt.as_slice()[36], 0x5a);
+    }
 }
@@ -16,7 +16,7 @@ pub mod kvm {
     use std::{boxed::Box, result};
     use vm_migration::{
         Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-        Transportable,
+        Transportable, VersionedState,
     };
 
     /// Errors thrown while saving/restoring the GICv3.
@@ -67,6 +67,10 @@ pub mod kvm {
         gicd_ctlr: u32,
     }
 
+    impl VersionedState for Gicv3State {
+        const VERSION: u16 = 1;
+    }
+
     impl KvmGICv3 {
         // Unfortunately bindgen omits defines that are based on other defines.
         // See arch/arm64/include/uapi/asm/kvm.h file from the linux kernel.
@@ -230,14 +234,11 @@ pub mod kvm {
 
         fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
             let gicr_typers = self.gicr_typers.clone();
-            let snapshot = serde_json::to_vec(&self.state(&gicr_typers).unwrap())
-                .map_err(|e| MigratableError::Snapshot(e.into()))?;
-
             let mut gic_v3_snapshot = Snapshot::new(self.id().as_str());
-            gic_v3_snapshot.add_data_section(SnapshotDataSection {
-                id: format!("{}-section", self.id()),
-                snapshot,
-            });
+            gic_v3_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+                &format!("{}-section", self.id()),
+                &self.state(&gicr_typers).unwrap(),
+            )?);
 
             Ok(gic_v3_snapshot)
         }
@@ -247,15 +248,7 @@ pub mod kvm {
                 .snapshot_data
                 .get(&format!("{}-section", self.id()))
             {
-                let gic_v3_state = match serde_json::from_slice(&gic_v3_section.snapshot) {
-                    Ok(state) => state,
-                    Err(error) => {
-                        return Err(MigratableError::Restore(anyhow!(
-                            "Could not deserialize GICv3 {}",
-                            error
-                        )))
-                    }
-                };
+                let gic_v3_state = gic_v3_section.to_versioned_state::<Gicv3State>()?;
 
                 let gicr_typers = self.gicr_typers.clone();
                 return self.set_state(&gicr_typers, &gic_v3_state).map_err(|e| {
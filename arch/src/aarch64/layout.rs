@@ -62,6 +62,8 @@ pub const MEM_32BIT_DEVICES_SIZE: u64 = 0x3000_0000;
 /// PCI MMCONFIG space (start: after the device space at 1 GiB, length: 256MiB)
 pub const PCI_MMCONFIG_START: GuestAddress = GuestAddress(0x4000_0000);
 pub const PCI_MMCONFIG_SIZE: u64 = 256 << 20;
+/// One MiB of PCI MMCONFIG space per PCI segment, covering its bus 0
+pub const PCI_MMIO_CONFIG_SIZE_PER_SEGMENT: u64 = 4096 * 256;
 
 /// Start of RAM on 64 bit ARM.
 pub const RAM_64BIT_START: u64 = 0x8000_0000;
@@ -24,7 +24,6 @@ extern crate vm_migration;
 #[cfg(target_arch = "aarch64")]
 #[macro_use]
 extern crate serde_derive;
-extern crate serde_json;
 extern crate thiserror;
 
 use std::fmt;
@@ -182,7 +182,7 @@ impl From<Error> for super::Error {
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum CpuidReg {
     EAX,
     EBX,
@@ -326,19 +326,98 @@ impl CpuidPatch {
     }
 }
 
+/// A CPUID leaf as exposed to the guest. It is sent to the destination of a
+/// migration, which checks it can expose the same CPU features.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct CpuidLeaf {
+    pub function: u32,
+    pub index: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+impl From<&CpuIdEntry> for CpuidLeaf {
+    fn from(entry: &CpuIdEntry) -> Self {
+        CpuidLeaf {
+            function: entry.function,
+            index: entry.index,
+            eax: entry.eax,
+            ebx: entry.ebx,
+            ecx: entry.ecx,
+            edx: entry.edx,
+        }
+    }
+}
+
+impl CpuidLeaf {
+    fn reg(&self, reg: CpuidReg) -> u32 {
+        match reg {
+            CpuidReg::EAX => self.eax,
+            CpuidReg::EBX => self.ebx,
+            CpuidReg::ECX => self.ecx,
+            CpuidReg::EDX => self.edx,
+        }
+    }
+}
+
+// The registers holding feature flags, as (function, index, register).
+const CPUID_FEATURE_REGS: [(u32, u32, CpuidReg); 8] = [
+    (0x1, 0, CpuidReg::ECX),
+    (0x1, 0, CpuidReg::EDX),
+    (0x7, 0, CpuidReg::EBX),
+    (0x7, 0, CpuidReg::ECX),
+    (0x7, 0, CpuidReg::EDX),
+    (0xd, 1, CpuidReg::EAX),
+    (0x8000_0001, 0, CpuidReg::ECX),
+    (0x8000_0001, 0, CpuidReg::EDX),
+];
+
+/// Compare the CPUID exposed to the guest on the source of a migration with
+/// the one the destination is able to expose, and describe each feature used
+/// by the guest which is missing on the destination.
+pub fn missing_cpuid_features(source: &[CpuidLeaf], destination: &[CpuidLeaf]) -> Vec<String> {
+    let mut missing = Vec::new();
+
+    for (function, index, reg) in CPUID_FEATURE_REGS.iter() {
+        let find = |leaves: &[CpuidLeaf]| {
+            leaves
+                .iter()
+                .find(|l| l.function == *function && l.index == *index)
+                .map_or(0, |l| l.reg(*reg))
+        };
+        let absent = find(source) & !find(destination);
+
+        for bit in 0..32 {
+            if absent & (1 << bit) != 0 {
+                missing.push(format!(
+                    "CPUID 0x{:x}.{} {:?}[{}]",
+                    function, index, reg, bit
+                ));
+            }
+        }
+    }
+
+    missing
+}
+
+/// Sets the x2APIC ID of a vCPU, reported by the topology leaves 0xb and 0x1f.
+pub fn set_cpuid_x2apic_id(cpuid: &mut CpuId, id: u8) {
+    CpuidPatch::set_cpuid_reg(cpuid, 0xb, None, CpuidReg::EDX, u32::from(id));
+    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, None, CpuidReg::EDX, u32::from(id));
+}
+
 pub fn configure_vcpu(
     fd: &Arc<dyn hypervisor::Vcpu>,
-    id: u8,
     kernel_entry_point: Option<EntryPoint>,
     vm_memory: &GuestMemoryAtomic<GuestMemoryMmap>,
     cpuid: CpuId,
     kvm_hyperv: bool,
+    encryption_mask: u64,
 ) -> super::Result<()> {
-    // Per vCPU CPUID changes; common are handled via CpuManager::generate_common_cpuid()
-    let mut cpuid = cpuid;
-    CpuidPatch::set_cpuid_reg(&mut cpuid, 0xb, None, CpuidReg::EDX, u32::from(id));
-    CpuidPatch::set_cpuid_reg(&mut cpuid, 0x1f, None, CpuidReg::EDX, u32::from(id));
-
+    // The CPUID is the one of the vCPU, with its x2APIC ID already set by
+    // set_cpuid_x2apic_id().
     fd.set_cpuid2(&cpuid)
         .map_err(|e| Error::SetSupportedCpusFailed(e.into()))?;
 
@@ -358,8 +437,13 @@ pub fn configure_vcpu(
         )
         .map_err(Error::REGSConfiguration)?;
         regs::setup_fpu(fd).map_err(Error::FPUConfiguration)?;
-        regs::setup_sregs(&vm_memory.memory(), fd, kernel_entry_point.protocol)
-            .map_err(Error::SREGSConfiguration)?;
+        regs::setup_sregs(
+            &vm_memory.memory(),
+            fd,
+            kernel_entry_point.protocol,
+            encryption_mask,
+        )
+        .map_err(Error::SREGSConfiguration)?;
     }
     interrupts::set_lint(fd).map_err(|e| Error::LocalIntConfiguration(e.into()))?;
     Ok(())
@@ -645,6 +729,15 @@ fn configure_64bit_boot(
     params.0.hdr.cmdline_size = cmdline_size as u32;
 
     if let Some(initramfs_config) = initramfs {
+        // A kernel providing its header tells how high the initramfs can be
+        // loaded, which may be lower than the end of the low memory.
+        let initramfs_end = initramfs_config.address.raw_value() + initramfs_config.size as u64 - 1;
+        if params.0.hdr.initrd_addr_max != 0
+            && initramfs_end > u64::from(params.0.hdr.initrd_addr_max)
+        {
+            return Err(super::Error::InitramfsAddress);
+        }
+
         params.0.hdr.ramdisk_image = initramfs_config.address.raw_value() as u32;
         params.0.hdr.ramdisk_size = initramfs_config.size as u32;
     }
@@ -773,15 +866,24 @@ pub fn get_host_cpu_phys_bits() -> u8 {
     }
 }
 
+// Number of low APIC ID bits identifying a thread within its core, a thread
+// within its die, and a thread within its package.
+fn topology_widths(threads_per_core: u8, cores_per_die: u8, dies_per_package: u8) -> [u32; 3] {
+    let thread_width = 8 - (threads_per_core - 1).leading_zeros();
+    let core_width = (8 - (cores_per_die - 1).leading_zeros()) + thread_width;
+    let die_width = (8 - (dies_per_package - 1).leading_zeros()) + core_width;
+
+    [thread_width, core_width, die_width]
+}
+
 pub fn update_cpuid_topology(
     cpuid: &mut CpuId,
     threads_per_core: u8,
     cores_per_die: u8,
     dies_per_package: u8,
 ) {
-    let thread_width = 8 - (threads_per_core - 1).leading_zeros();
-    let core_width = (8 - (cores_per_die - 1).leading_zeros()) + thread_width;
-    let die_width = (8 - (dies_per_package - 1).leading_zeros()) + core_width;
+    let [thread_width, core_width, die_width] =
+        topology_widths(threads_per_core, cores_per_die, dies_per_package);
 
     // CPU Topology leaf 0xb
     CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(0), CpuidReg::EAX, thread_width);
@@ -836,6 +938,53 @@ pub fn update_cpuid_topology(
     CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(2), CpuidReg::ECX, 5 << 8);
 }
 
+/// Part of the CPU topology whose logical processors share a cache.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheSharing {
+    Thread,
+    Core,
+    Die,
+    Package,
+}
+
+/// Updates the deterministic cache parameters leaf 0x4, for each level of
+/// cache to be shared as given by `sharing`, indexed by the level minus one,
+/// among the logical processors of the topology set by
+/// update_cpuid_topology(). The levels beyond `sharing` are shared by the
+/// whole package.
+pub fn update_cpuid_cache_topology(
+    cpuid: &mut CpuId,
+    threads_per_core: u8,
+    cores_per_die: u8,
+    dies_per_package: u8,
+    sharing: &[CacheSharing],
+) {
+    let [thread_width, core_width, die_width] =
+        topology_widths(threads_per_core, cores_per_die, dies_per_package);
+
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        // The sub-leaves with a null cache type don't describe any cache.
+        if entry.function != 0x4 || entry.eax & 0x1f == 0 {
+            continue;
+        }
+
+        let level = ((entry.eax >> 5) & 0x7) as usize;
+        let width = match sharing.get(level.saturating_sub(1)) {
+            Some(CacheSharing::Thread) => 0,
+            Some(CacheSharing::Core) => thread_width,
+            Some(CacheSharing::Die) => core_width,
+            Some(CacheSharing::Package) | None => die_width,
+        };
+
+        // EAX[25:14] holds the number of logical processor IDs sharing the
+        // cache, and EAX[31:26] the number of core IDs in the package, both
+        // minus one.
+        let sharing_ids = ((1u32 << width) - 1).min(0xfff);
+        let core_ids = ((1u32 << (die_width - thread_width)) - 1).min(0x3f);
+        entry.eax = (entry.eax & 0x3fff) | sharing_ids << 14 | core_ids << 26;
+    }
+}
+
 // The goal is to update the CPUID sub-leaves to reflect the number of EPC
 // sections exposed to the guest.
 pub fn update_cpuid_sgx(cpuid: &mut CpuId, epc_sections: Vec<SgxEpcSection>) -> Result<(), Error> {
@@ -887,6 +1036,116 @@ mod tests {
     use super::*;
     use linux_loader::loader::bootparam::boot_e820_entry;
 
+    #[test]
+    fn test_missing_cpuid_features() {
+        let source = vec![
+            CpuidLeaf {
+                function: 0x1,
+                ecx: 1 << 28 | 1 << 31,
+                edx: 1,
+                ..Default::default()
+            },
+            CpuidLeaf {
+                function: 0x7,
+                ebx: 1 << 5 | 1 << 16,
+                ..Default::default()
+            },
+            // Not a feature leaf, ignored.
+            CpuidLeaf {
+                function: 0x8000_0008,
+                eax: 0x30,
+                ..Default::default()
+            },
+        ];
+        let mut destination = vec![
+            CpuidLeaf {
+                function: 0x1,
+                ecx: 1 << 28 | 1 << 31,
+                edx: 1 | 1 << 4,
+                ..Default::default()
+            },
+            CpuidLeaf {
+                function: 0x7,
+                ebx: 1 << 5 | 1 << 16,
+                ..Default::default()
+            },
+        ];
+        assert!(missing_cpuid_features(&source, &destination).is_empty());
+
+        destination[1].ebx = 1 << 5;
+        destination[0].ecx = 1 << 31;
+        assert_eq!(
+            missing_cpuid_features(&source, &destination),
+            vec!["CPUID 0x1.0 ECX[28]", "CPUID 0x7.0 EBX[16]"]
+        );
+
+        // A leaf missing entirely on the destination.
+        destination.remove(1);
+        assert_eq!(
+            missing_cpuid_features(&source, &destination),
+            vec![
+                "CPUID 0x1.0 ECX[28]",
+                "CPUID 0x7.0 EBX[5]",
+                "CPUID 0x7.0 EBX[16]"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cpuid_cache_topology() {
+        // L1 data, L2 and L3 caches, each shared by another plain number of
+        // processors than the one of the guest topology.
+        let cache = |level: u32| CpuIdEntry {
+            function: 0x4,
+            index: level - 1,
+            flags: CPUID_FLAG_VALID_INDEX,
+            eax: 1 | level << 5 | 0x3f << 14 | 0x7 << 26,
+            ebx: 0x3f,
+            ..Default::default()
+        };
+        let no_cache = CpuIdEntry {
+            function: 0x4,
+            index: 3,
+            flags: CPUID_FLAG_VALID_INDEX,
+            ..Default::default()
+        };
+        let mut cpuid = CpuId::from_entries(&[cache(1), cache(2), cache(3), no_cache]);
+
+        // 2 threads per core, 3 cores per die and 2 dies per package.
+        update_cpuid_cache_topology(
+            &mut cpuid,
+            2,
+            3,
+            2,
+            &[CacheSharing::Core, CacheSharing::Core, CacheSharing::Die],
+        );
+        let eax: Vec<u32> = cpuid.as_slice().iter().map(|entry| entry.eax).collect();
+        assert_eq!(
+            eax,
+            vec![
+                1 | 1 << 5 | 0x1 << 14 | 0x7 << 26,
+                1 | 2 << 5 | 0x1 << 14 | 0x7 << 26,
+                1 | 3 << 5 | 0x7 << 14 | 0x7 << 26,
+                0,
+            ]
+        );
+        // The other registers are left as reported by the host.
+        assert!(cpuid.as_slice()[..3].iter().all(|entry| entry.ebx == 0x3f));
+
+        // The levels not given are shared by the package.
+        update_cpuid_cache_topology(&mut cpuid, 1, 4, 1, &[CacheSharing::Thread]);
+        let eax: Vec<u32> = cpuid.as_slice().iter().map(|entry| entry.eax).collect();
+        assert_eq!(
+            eax,
+            vec![
+                1 | 1 << 5 | 0x3 << 26,
+                1 | 2 << 5 | 0x3 << 14 | 0x3 << 26,
+                1 | 3 << 5 | 0x3 << 14 | 0x3 << 26,
+                0,
+            ]
+        );
+    }
+
     #[test]
     fn regions_lt_4gb() {
         let regions = arch_memory_regions(1 << 29);
@@ -903,6 +1162,53 @@ mod tests {
         assert_eq!(GuestAddress(1 << 32), regions[1].0);
     }
 
+    #[test]
+    fn test_initramfs_addr_max() {
+        let mem_size = 128 << 20;
+        let arch_mem_regions = arch_memory_regions(mem_size);
+        let ram_regions: Vec<(GuestAddress, usize)> = arch_mem_regions
+            .iter()
+            .filter(|r| r.2 == RegionType::Ram)
+            .map(|r| (r.0, r.1))
+            .collect();
+        let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
+
+        let size = 0x10_0000;
+        let initramfs = Some(InitramfsConfig {
+            address: GuestAddress(initramfs_load_addr(&gm, size).unwrap()),
+            size,
+        });
+        let mut hdr = setup_header::default();
+
+        hdr.initrd_addr_max = (mem_size - 1) as u32;
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            0,
+            &initramfs,
+            1,
+            Some(hdr),
+            None,
+            BootProtocol::LinuxBoot,
+            None,
+        )
+        .unwrap();
+
+        hdr.initrd_addr_max = (mem_size / 2) as u32;
+        assert!(configure_system(
+            &gm,
+            GuestAddress(0),
+            0,
+            &initramfs,
+            1,
+            Some(hdr),
+            None,
+            BootProtocol::LinuxBoot,
+            None,
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_system_configuration() {
         let no_vcpus = 4;
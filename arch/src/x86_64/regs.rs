@@ -121,13 +121,14 @@ pub fn setup_sregs(
     mem: &GuestMemoryMmap,
     vcpu: &Arc<dyn hypervisor::Vcpu>,
     boot_prot: BootProtocol,
+    encryption_mask: u64,
 ) -> Result<()> {
     let mut sregs: SpecialRegisters = vcpu.get_sregs().map_err(Error::GetStatusRegisters)?;
 
     configure_segments_and_sregs(mem, &mut sregs, boot_prot)?;
 
     if let BootProtocol::LinuxBoot = boot_prot {
-        setup_page_tables(mem, &mut sregs)?; // TODO(dgreid) - Can this be done once per system instead?
+        setup_page_tables(mem, &mut sregs, encryption_mask)?; // TODO(dgreid) - Can this be done once per system instead?
     }
 
     vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)
@@ -215,32 +216,42 @@ pub fn configure_segments_and_sregs(
     Ok(())
 }
 
-pub fn setup_page_tables(mem: &GuestMemoryMmap, sregs: &mut SpecialRegisters) -> Result<()> {
+/// Sets up identity mapped page tables, the `encryption_mask` being set in
+/// CR3 and every entry for the tables and memory they point to to be
+/// accessed encrypted, when the guest memory is encrypted.
+pub fn setup_page_tables(
+    mem: &GuestMemoryMmap,
+    sregs: &mut SpecialRegisters,
+    encryption_mask: u64,
+) -> Result<()> {
     // Puts PML5 or PML4 right after zero page but aligned to 4k.
     if unsafe { std::arch::x86_64::__cpuid(7).ecx } & (1 << 16) != 0 {
         // Entry covering VA [0..256TB)
-        mem.write_obj(PML4_START.raw_value() | 0x03, PML5_START)
+        mem.write_obj(PML4_START.raw_value() | encryption_mask | 0x03, PML5_START)
             .map_err(Error::WritePML5Address)?;
 
-        sregs.cr3 = PML5_START.raw_value();
+        sregs.cr3 = PML5_START.raw_value() | encryption_mask;
         sregs.cr4 |= CR4_LA57;
     } else {
-        sregs.cr3 = PML4_START.raw_value();
+        sregs.cr3 = PML4_START.raw_value() | encryption_mask;
     }
 
     // Entry covering VA [0..512GB)
-    mem.write_obj(PDPTE_START.raw_value() | 0x03, PML4_START)
+    mem.write_obj(PDPTE_START.raw_value() | encryption_mask | 0x03, PML4_START)
         .map_err(Error::WritePML4Address)?;
 
     // Entry covering VA [0..1GB)
-    mem.write_obj(PDE_START.raw_value() | 0x03, PDPTE_START)
+    mem.write_obj(PDE_START.raw_value() | encryption_mask | 0x03, PDPTE_START)
         .map_err(Error::WritePDPTEAddress)?;
 
     // 512 2MB entries together covering VA [0..1GB). Note we are assuming
     // CPU supports 2MB pages (/proc/cpuinfo has 'pse'). All modern CPUs do.
     for i in 0..512 {
-        mem.write_obj((i << 21) + 0x83u64, PDE_START.unchecked_add(i * 8))
-            .map_err(Error::WritePDEAddress)?;
+        mem.write_obj(
+            ((i << 21) + 0x83u64) | encryption_mask,
+            PDE_START.unchecked_add(i * 8),
+        )
+        .map_err(Error::WritePDEAddress)?;
     }
 
     sregs.cr4 |= CR4_PAE;
@@ -332,7 +343,7 @@ mod tests {
     fn page_tables() {
         let mut sregs: SpecialRegisters = Default::default();
         let gm = create_guest_mem();
-        setup_page_tables(&gm, &mut sregs).unwrap();
+        setup_page_tables(&gm, &mut sregs, 0).unwrap();
 
         if unsafe { std::arch::x86_64::__cpuid(7).ecx } & (1 << 16) != 0 {
             assert_eq!(0xa003, read_u64(&gm, PML5_START));
@@ -354,4 +365,28 @@ mod tests {
         assert_eq!(CR4_PAE, sregs.cr4);
         assert_eq!(CR0_PG, sregs.cr0);
     }
+
+    #[test]
+    fn page_tables_encrypted() {
+        let mask = 1 << 47;
+        let mut sregs: SpecialRegisters = Default::default();
+        let gm = create_guest_mem();
+        setup_page_tables(&gm, &mut sregs, mask).unwrap();
+
+        // The tables and the memory they map are accessed encrypted.
+        if unsafe { std::arch::x86_64::__cpuid(7).ecx } & (1 << 16) != 0 {
+            assert_eq!(0xa003 | mask, read_u64(&gm, PML5_START));
+            assert_eq!(PML5_START.raw_value() | mask, sregs.cr3);
+        } else {
+            assert_eq!(PML4_START.raw_value() | mask, sregs.cr3);
+        }
+        assert_eq!(0xb003 | mask, read_u64(&gm, PML4_START));
+        assert_eq!(0xc003 | mask, read_u64(&gm, PDPTE_START));
+        for i in 0..512 {
+            assert_eq!(
+                ((i << 21) + 0x83u64) | mask,
+                read_u64(&gm, PDE_START.unchecked_add(i * 8))
+            );
+        }
+    }
 }
@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+use crate::SECTOR_SIZE;
 use thiserror::Error;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -10,6 +11,9 @@ pub enum DiskFileError {
     /// Failed getting disk file size.
     #[error("Failed getting disk file size: {0}")]
     Size(#[source] std::io::Error),
+    /// Failed getting disk block size.
+    #[error("Failed getting disk block size: {0}")]
+    BlockSize(#[source] std::io::Error),
     /// Failed creating a new AsyncIo.
     #[error("Failed creating a new AsyncIo: {0}")]
     NewAsyncIo(#[source] std::io::Error),
@@ -19,7 +23,14 @@ pub type DiskFileResult<T> = std::result::Result<T, DiskFileError>;
 
 pub trait DiskFile: Send + Sync {
     fn size(&mut self) -> DiskFileResult<u64>;
+    fn block_size(&self) -> DiskFileResult<u32> {
+        Ok(SECTOR_SIZE as u32)
+    }
     fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>>;
+    /// Whether the AsyncIo instances of the disk implement write_zeroes().
+    fn supports_write_zeroes(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Error, Debug)]
@@ -33,6 +44,9 @@ pub enum AsyncIoError {
     /// Failed synchronizing file.
     #[error("Failed synchronizing file: {0}")]
     Fsync(#[source] std::io::Error),
+    /// Failed zeroing a range of the file.
+    #[error("Failed zeroing a range of the file: {0}")]
+    WriteZeroes(#[source] std::io::Error),
 }
 
 pub type AsyncIoResult<T> = std::result::Result<T, AsyncIoError>;
@@ -52,5 +66,16 @@ pub trait AsyncIo: Send + Sync {
         user_data: u64,
     ) -> AsyncIoResult<()>;
     fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()>;
+    fn write_zeroes(
+        &mut self,
+        _offset: libc::off_t,
+        _length: u64,
+        _unmap: bool,
+        _user_data: u64,
+    ) -> AsyncIoResult<()> {
+        Err(AsyncIoError::WriteZeroes(
+            std::io::Error::from_raw_os_error(libc::EOPNOTSUPP),
+        ))
+    }
     fn complete(&mut self) -> Vec<(u64, i32)>;
 }
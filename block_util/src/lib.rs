@@ -12,13 +12,17 @@
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate vmm_sys_util;
 
 pub mod async_io;
 pub mod fixed_vhd_async;
 pub mod fixed_vhd_sync;
+pub mod overlay;
 pub mod qcow_sync;
 pub mod raw_async;
 pub mod raw_sync;
+pub mod read_ahead;
 pub mod vhd;
 
 use crate::async_io::{AsyncIo, AsyncIoError, AsyncIoResult, DiskFileError, DiskFileResult};
@@ -30,8 +34,8 @@ use std::convert::TryInto;
 use std::fs::File;
 use std::io::{self, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
-#[cfg(feature = "io_uring")]
-use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
 use std::result;
 use std::sync::{Arc, Mutex};
@@ -39,6 +43,7 @@ use virtio_bindings::bindings::virtio_blk::*;
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
 use vm_virtio::DescriptorChain;
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
 
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = 0x01 << SECTOR_SHIFT;
@@ -63,6 +68,8 @@ pub enum Error {
     InvalidOffset,
     /// The requested operation does not support multiple descriptors.
     TooManyDescriptors,
+    /// The request holds more segments than supported by the device.
+    TooManySegments,
 }
 
 fn build_device_id(disk_path: &PathBuf) -> result::Result<String, Error> {
@@ -110,6 +117,7 @@ pub enum ExecuteError {
     AsyncRead(AsyncIoError),
     AsyncWrite(AsyncIoError),
     AsyncFlush(AsyncIoError),
+    AsyncWriteZeroes(AsyncIoError),
 }
 
 impl ExecuteError {
@@ -126,6 +134,7 @@ impl ExecuteError {
             ExecuteError::AsyncRead(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::AsyncWrite(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::AsyncFlush(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncWriteZeroes(_) => VIRTIO_BLK_S_IOERR,
         }
     }
 }
@@ -136,6 +145,7 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -149,6 +159,7 @@ pub fn request_type(
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
     }
 }
@@ -163,6 +174,85 @@ fn sector(mem: &GuestMemoryMmap, desc_addr: GuestAddress) -> result::Result<u64,
     mem.read_obj(addr).map_err(Error::GuestMemory)
 }
 
+/// Largest range a single write zeroes request can cover.
+pub const MAX_WRITE_ZEROES_SECTORS: u32 = u32::MAX >> SECTOR_SHIFT;
+
+// Range of sectors of a write zeroes request, as described by the
+// struct virtio_blk_discard_write_zeroes of the specification.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct WriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+unsafe impl ByteValued for WriteZeroesSegment {}
+
+// Buffer of zeroes, aligned for files opened with O_DIRECT.
+#[repr(align(4096))]
+struct ZeroesChunk([u8; 0x10000]);
+
+/// Zero the given range of the file. With `unmap`, the range is deallocated
+/// when the file supports it.
+pub fn write_zeroes(fd: RawFd, offset: u64, length: u64, unmap: bool) -> io::Result<()> {
+    let fallocate = |mode| {
+        // Safe because the file descriptor is valid and the return value is
+        // checked.
+        let ret = unsafe {
+            libc::fallocate64(
+                fd,
+                mode | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off64_t,
+                length as libc::off64_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    };
+
+    if unmap {
+        match fallocate(libc::FALLOC_FL_PUNCH_HOLE) {
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {}
+            r => return r,
+        }
+    }
+    match fallocate(libc::FALLOC_FL_ZERO_RANGE) {
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {}
+        r => return r,
+    }
+
+    // The filesystem can't zero a range, the zeroes are written instead.
+    let zeroes = Box::new(ZeroesChunk([0; 0x10000]));
+    let mut written = 0;
+    while written < length {
+        let len = cmp::min(length - written, zeroes.0.len() as u64) as usize;
+        // Safe because the buffer is valid for len bytes and the return value
+        // is checked.
+        let ret = unsafe {
+            libc::pwrite64(
+                fd,
+                zeroes.0.as_ptr() as *const libc::c_void,
+                len,
+                (offset + written) as libc::off64_t,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        written += ret as u64;
+    }
+
+    Ok(())
+}
+
 pub struct Request {
     pub request_type: RequestType,
     pub sector: u64,
@@ -202,7 +292,10 @@ impl Request {
             }
         } else {
             while desc.has_next() {
-                if desc.is_write_only() && req.request_type == RequestType::Out {
+                if desc.is_write_only()
+                    && (req.request_type == RequestType::Out
+                        || req.request_type == RequestType::WriteZeroes)
+                {
                     return Err(Error::UnexpectedWriteOnlyDescriptor);
                 }
                 if !desc.is_write_only() && req.request_type == RequestType::In {
@@ -277,6 +370,9 @@ impl Request {
                     mem.write_slice(&disk_id.as_slice(), *data_addr)
                         .map_err(ExecuteError::Write)?;
                 }
+                RequestType::WriteZeroes => {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES))
+                }
                 RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
             };
         }
@@ -349,12 +445,61 @@ impl Request {
                     .map_err(ExecuteError::Write)?;
                 return Ok(false);
             }
+            RequestType::WriteZeroes => {
+                let (offset, length, unmap) = self.write_zeroes_range(mem, disk_nsectors)?;
+                disk_image
+                    .write_zeroes(offset, length, unmap, user_data)
+                    .map_err(ExecuteError::AsyncWriteZeroes)?;
+            }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         }
 
         Ok(true)
     }
 
+    // Returns the offset and the length of the range to zero, and whether it
+    // can be deallocated.
+    fn write_zeroes_range(
+        &self,
+        mem: &GuestMemoryMmap,
+        disk_nsectors: u64,
+    ) -> result::Result<(libc::off_t, u64, bool), ExecuteError> {
+        // Only a single segment is supported, as advertised through
+        // max_write_zeroes_seg.
+        let (data_addr, data_len) = match self.data_descriptors.as_slice() {
+            [descriptor] => *descriptor,
+            _ => return Err(ExecuteError::BadRequest(Error::TooManyDescriptors)),
+        };
+        let segment_len = std::mem::size_of::<WriteZeroesSegment>();
+        if (data_len as usize) < segment_len {
+            return Err(ExecuteError::BadRequest(Error::DescriptorLengthTooSmall));
+        }
+        if data_len as usize > segment_len {
+            return Err(ExecuteError::BadRequest(Error::TooManySegments));
+        }
+
+        let segment: WriteZeroesSegment = mem.read_obj(data_addr).map_err(ExecuteError::Read)?;
+        if segment.flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+            return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES));
+        }
+        if segment.num_sectors > MAX_WRITE_ZEROES_SECTORS {
+            return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+        }
+        let top = segment
+            .sector
+            .checked_add(u64::from(segment.num_sectors))
+            .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+        if top > disk_nsectors {
+            return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+        }
+
+        Ok((
+            (segment.sector << SECTOR_SHIFT) as libc::off_t,
+            u64::from(segment.num_sectors) << SECTOR_SHIFT,
+            segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0,
+        ))
+    }
+
     pub fn set_writeback(&mut self, writeback: bool) {
         self.writeback = writeback
     }
@@ -658,6 +803,61 @@ pub fn fsync_sync(
     Ok(())
 }
 
+/// Find the extents of the file actually holding data, skipping the holes.
+/// Each extent is returned as an (offset, length) pair.
+pub fn data_extents(f: &File) -> std::io::Result<Vec<(u64, u64)>> {
+    let size = f.metadata()?.len();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+
+    while offset < size {
+        // Safe because the file descriptor is valid and the return value
+        // is checked.
+        let data =
+            unsafe { libc::lseek64(f.as_raw_fd(), offset as libc::off64_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = io::Error::last_os_error();
+            // No more data after the current offset.
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(e);
+        }
+
+        // Safe because the file descriptor is valid and the return value
+        // is checked.
+        let hole = unsafe { libc::lseek64(f.as_raw_fd(), data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        extents.push((data as u64, (hole - data) as u64));
+        offset = hole as u64;
+    }
+
+    Ok(extents)
+}
+
+/// Deallocate the given range of the file, which then reads as zeroes. The
+/// size of the file is left unchanged.
+pub fn punch_hole(f: &File, offset: u64, length: u64) -> std::io::Result<()> {
+    // Safe because the file descriptor is valid and the return value is
+    // checked.
+    let ret = unsafe {
+        libc::fallocate64(
+            f.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off64_t,
+            length as libc::off64_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
 pub enum ImageType {
     FixedVhd,
     Qcow2,
@@ -689,3 +889,148 @@ pub fn detect_image_type(f: &mut File) -> std::io::Result<ImageType> {
 
     Ok(image_type)
 }
+
+// See include/uapi/linux/fs.h in the kernel code.
+ioctl_io_nr!(BLKSSZGET, 0x12, 104);
+ioctl_ior_nr!(BLKGETSIZE64, 0x12, 114, u64);
+
+/// Whether the file is a block device, such as a disk or a LVM volume,
+/// rather than a regular file.
+pub fn is_block_device(f: &File) -> io::Result<bool> {
+    Ok(f.metadata()?.file_type().is_block_device())
+}
+
+/// Size of a RAW image, which is either a regular file or a block device.
+pub fn raw_image_size(f: &mut File) -> io::Result<u64> {
+    if !is_block_device(f)? {
+        return f.seek(SeekFrom::End(0));
+    }
+
+    let mut size: u64 = 0;
+    // Safe because the kernel only writes the size of the device, and we
+    // check the return value.
+    let ret = unsafe { ioctl_with_mut_ref(f, BLKGETSIZE64(), &mut size) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(size)
+}
+
+/// Smallest unit a RAW image can be accessed with. Accesses to a block
+/// device opened with O_DIRECT must be aligned on its logical block size,
+/// while regular files are addressed by sectors.
+pub fn raw_image_block_size(f: &File) -> io::Result<u32> {
+    if !is_block_device(f)? {
+        return Ok(SECTOR_SIZE as u32);
+    }
+
+    let mut block_size: libc::c_int = 0;
+    // Safe because the kernel only writes the logical block size of the
+    // device, and we check the return value.
+    let ret = unsafe { ioctl_with_mut_ref(f, BLKSSZGET(), &mut block_size) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(block_size as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use tempfile::tempfile;
+
+    #[test]
+    fn test_raw_image_regular_file() {
+        let mut file = tempfile().unwrap();
+        file.set_len(1 << 20).unwrap();
+
+        assert!(!is_block_device(&file).unwrap());
+        assert_eq!(raw_image_size(&mut file).unwrap(), 1 << 20);
+        assert_eq!(raw_image_block_size(&file).unwrap(), SECTOR_SIZE as u32);
+    }
+
+    #[test]
+    fn test_data_extents_punch_hole() {
+        const SIZE: u64 = 4 << 20;
+        const OFFSET: u64 = 1 << 20;
+
+        let file = tempfile().unwrap();
+        file.set_len(SIZE).unwrap();
+        file.write_all_at(&[0xaa; 4096], OFFSET).unwrap();
+
+        // Depending on the filesystem, the holes might not be reported but
+        // the written range always belongs to an extent.
+        let extents = data_extents(&file).unwrap();
+        assert!(extents
+            .iter()
+            .any(|(offset, length)| *offset <= OFFSET && OFFSET + 4096 <= offset + length));
+
+        punch_hole(&file, 0, SIZE).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), SIZE);
+        let mut buf = [0xffu8; 4096];
+        file.read_exact_at(&mut buf, OFFSET).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_write_zeroes() {
+        const SECTORS: u64 = 16;
+
+        let file = tempfile().unwrap();
+        file.write_all_at(&[0xaa; (SECTORS * SECTOR_SIZE) as usize], 0)
+            .unwrap();
+        let mut async_io = raw_sync::RawFileSync::new(file.as_raw_fd());
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let request = |sector, num_sectors, flags| {
+            let segment = WriteZeroesSegment {
+                sector,
+                num_sectors,
+                flags,
+            };
+            mem.write_obj(segment, GuestAddress(0)).unwrap();
+            Request {
+                request_type: RequestType::WriteZeroes,
+                sector: 0,
+                data_descriptors: vec![(GuestAddress(0), 16)],
+                status_addr: GuestAddress(0x100),
+                writeback: true,
+            }
+        };
+
+        // Zero sectors 2 and 3, then deallocate sectors 8 to 15.
+        assert!(request(2, 2, 0)
+            .execute_async(&mem, SECTORS, &mut async_io, &[], 1)
+            .unwrap());
+        assert!(request(8, 8, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP)
+            .execute_async(&mem, SECTORS, &mut async_io, &[], 2)
+            .unwrap());
+        assert_eq!(async_io.complete(), vec![(1, 0), (2, 0)]);
+
+        assert_eq!(file.metadata().unwrap().len(), SECTORS * SECTOR_SIZE);
+        let mut buf = vec![0xffu8; (SECTORS * SECTOR_SIZE) as usize];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        for (sector, data) in buf.chunks(SECTOR_SIZE as usize).enumerate() {
+            let expected = if (2..4).contains(&sector) || sector >= 8 {
+                0
+            } else {
+                0xaa
+            };
+            assert!(data.iter().all(|b| *b == expected));
+        }
+
+        // The range must stay within the disk, and the flags must be known.
+        let e = request(12, 8, 0)
+            .execute_async(&mem, SECTORS, &mut async_io, &[], 3)
+            .unwrap_err();
+        assert_eq!(e.status(), VIRTIO_BLK_S_IOERR);
+        let e = request(0, 1, 0x2)
+            .execute_async(&mem, SECTORS, &mut async_io, &[], 4)
+            .unwrap_err();
+        assert_eq!(e.status(), VIRTIO_BLK_S_UNSUPP);
+        assert!(async_io.complete().is_empty());
+    }
+}
@@ -0,0 +1,691 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
+use crate::{raw_image_size, SECTOR_SIZE};
+use std::cmp;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+// The overlay file starts with a header, followed by the bitmap of the
+// sectors it holds and by the data of these sectors, stored at the same
+// offset they have on the disk relative to the start of the data area. The
+// data area is sparse, so that the overlay only grows with the sectors
+// written by the guest. The header identifies the base image through its
+// size, inode and modification time, for the overlay not to be stacked on
+// another image, or on the image once modified.
+const OVERLAY_MAGIC: [u8; 8] = *b"CHOVRLAY";
+const OVERLAY_VERSION: u32 = 1;
+const OVERLAY_ALIGNMENT: u64 = 4096;
+const HEADER_SIZE: u64 = OVERLAY_ALIGNMENT;
+const BITMAP_OFFSET: u64 = HEADER_SIZE;
+
+struct OverlayHeader {
+    sector_size: u32,
+    disk_size: u64,
+    bitmap_offset: u64,
+    data_offset: u64,
+    base: BaseIdentity,
+}
+
+#[derive(Debug, PartialEq)]
+struct BaseIdentity {
+    ino: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl BaseIdentity {
+    fn new(base: &File) -> io::Result<Self> {
+        let metadata = base.metadata()?;
+        Ok(BaseIdentity {
+            ino: metadata.ino(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        })
+    }
+}
+
+impl OverlayHeader {
+    const LEN: usize = 64;
+
+    fn new(disk_size: u64, base: BaseIdentity) -> Self {
+        let bitmap_len = bitmap_len(disk_size) as u64;
+        OverlayHeader {
+            sector_size: SECTOR_SIZE as u32,
+            disk_size,
+            bitmap_offset: BITMAP_OFFSET,
+            data_offset: BITMAP_OFFSET + align_up(bitmap_len, OVERLAY_ALIGNMENT),
+            base,
+        }
+    }
+
+    fn read(file: &File) -> io::Result<Self> {
+        let mut buf = [0u8; Self::LEN];
+        file.read_exact_at(&mut buf, 0)?;
+
+        if buf[0..8] != OVERLAY_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a disk overlay file",
+            ));
+        }
+        let version = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        if version != OVERLAY_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported disk overlay version {}", version),
+            ));
+        }
+
+        let header = OverlayHeader {
+            sector_size: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            disk_size: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+            bitmap_offset: u64::from_le_bytes(buf[24..32].try_into().unwrap()),
+            data_offset: u64::from_le_bytes(buf[32..40].try_into().unwrap()),
+            base: BaseIdentity {
+                ino: u64::from_le_bytes(buf[40..48].try_into().unwrap()),
+                mtime: i64::from_le_bytes(buf[48..56].try_into().unwrap()),
+                mtime_nsec: i64::from_le_bytes(buf[56..64].try_into().unwrap()),
+            },
+        };
+        header.validate()?;
+
+        Ok(header)
+    }
+
+    // The layout must be the one new() gives, the bitmap and the data area
+    // fitting in the file without overlapping the header or each other.
+    fn validate(&self) -> io::Result<()> {
+        let invalid = |reason: &str| {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid disk overlay header: {}", reason),
+            ))
+        };
+
+        if self.sector_size != SECTOR_SIZE as u32 {
+            return invalid(&format!("unsupported sector size {}", self.sector_size));
+        }
+        if self.bitmap_offset < HEADER_SIZE || self.bitmap_offset % OVERLAY_ALIGNMENT != 0 {
+            return invalid(&format!("bitmap offset {:#x}", self.bitmap_offset));
+        }
+        let bitmap_end = self
+            .bitmap_offset
+            .checked_add(bitmap_len(self.disk_size) as u64);
+        if !matches!(bitmap_end, Some(end) if end <= self.data_offset)
+            || self.data_offset % OVERLAY_ALIGNMENT != 0
+            || self.data_offset.checked_add(self.disk_size).is_none()
+        {
+            return invalid(&format!("data offset {:#x}", self.data_offset));
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, file: &File) -> io::Result<()> {
+        let mut buf = [0u8; Self::LEN];
+        buf[0..8].copy_from_slice(&OVERLAY_MAGIC);
+        buf[8..12].copy_from_slice(&OVERLAY_VERSION.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.sector_size.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.disk_size.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.bitmap_offset.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.data_offset.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.base.ino.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.base.mtime.to_le_bytes());
+        buf[56..64].copy_from_slice(&self.base.mtime_nsec.to_le_bytes());
+        file.write_all_at(&buf, 0)
+    }
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+fn bitmap_len(disk_size: u64) -> usize {
+    let sectors = align_up(disk_size, SECTOR_SIZE) / SECTOR_SIZE;
+    (align_up(sectors, 8) / 8) as usize
+}
+
+fn is_present(bitmap: &[u8], sector: u64) -> bool {
+    bitmap[(sector / 8) as usize] & (1 << (sector % 8)) != 0
+}
+
+/// Sectors held by the overlay. The bits set since the last flush are only
+/// written to the file once the data they cover is durable, so that a crash
+/// never leaves a sector marked as held by the overlay without its data.
+struct Bitmap {
+    bits: Vec<u8>,
+    // Range of bytes of the bitmap changed since it was last written.
+    dirty: Option<(usize, usize)>,
+}
+
+impl Bitmap {
+    fn set(&mut self, sector: u64) {
+        let byte = (sector / 8) as usize;
+        self.bits[byte] |= 1 << (sector % 8);
+        self.dirty = Some(match self.dirty {
+            Some((start, end)) => (cmp::min(start, byte), cmp::max(end, byte + 1)),
+            None => (byte, byte + 1),
+        });
+    }
+}
+
+/// Syncs the data written to the overlay, then writes and syncs the bitmap
+/// bits covering it.
+fn flush_overlay(overlay_fd: RawFd, bitmap_offset: u64, bitmap: &Mutex<Bitmap>) -> io::Result<()> {
+    // Holding the lock, no sector can be marked as held in between.
+    let mut bitmap = bitmap.lock().unwrap();
+    if let Some((start, end)) = bitmap.dirty {
+        if unsafe { libc::fdatasync(overlay_fd as libc::c_int) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let iovecs = [libc::iovec {
+            iov_base: bitmap.bits[start..].as_ptr() as *mut libc::c_void,
+            iov_len: end - start,
+        }];
+        if OverlayFileSync::pwritev(overlay_fd, &iovecs, bitmap_offset + start as u64)?
+            != (end - start) as u64
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "Short write updating the overlay bitmap",
+            ));
+        }
+        bitmap.dirty = None;
+    }
+    if unsafe { libc::fsync(overlay_fd as libc::c_int) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Splits the range into runs of sectors all held by the overlay, or all
+/// coming from the base image.
+fn runs(bitmap: &[u8], offset: u64, len: u64) -> Vec<(u64, u64, bool)> {
+    let mut runs: Vec<(u64, u64, bool)> = Vec::new();
+    let end = offset + len;
+    let mut start = offset;
+    while start < end {
+        let sector = start / SECTOR_SIZE;
+        let run_end = cmp::min((sector + 1) * SECTOR_SIZE, end);
+        let present = is_present(bitmap, sector);
+        match runs.last_mut() {
+            Some((_, run_len, p)) if *p == present => *run_len += run_end - start,
+            _ => runs.push((start, run_end - start, present)),
+        }
+        start = run_end;
+    }
+    runs
+}
+
+/// Returns the iovecs describing `len` bytes of the buffers, starting `skip`
+/// bytes into them.
+fn sub_iovecs(iovecs: &[libc::iovec], mut skip: u64, mut len: u64) -> Vec<libc::iovec> {
+    let mut sub_iovecs = Vec::new();
+    for iovec in iovecs {
+        if len == 0 {
+            break;
+        }
+        let iov_len = iovec.iov_len as u64;
+        if skip >= iov_len {
+            skip -= iov_len;
+            continue;
+        }
+        let sub_len = cmp::min(iov_len - skip, len);
+        sub_iovecs.push(libc::iovec {
+            iov_base: unsafe { (iovec.iov_base as *mut u8).add(skip as usize) }
+                as *mut libc::c_void,
+            iov_len: sub_len as usize,
+        });
+        len -= sub_len;
+        skip = 0;
+    }
+    sub_iovecs
+}
+
+/// Raw disk image shared read-only between VMs, with the writes of the guest
+/// redirected to a copy-on-write overlay file private to the VM.
+pub struct OverlayDiskFile {
+    base: File,
+    overlay: File,
+    header: OverlayHeader,
+    bitmap: Arc<Mutex<Bitmap>>,
+}
+
+impl OverlayDiskFile {
+    /// Stacks the overlay on top of the base image. An empty overlay file is
+    /// formatted, while an existing one must have been created for this very
+    /// base image, left unmodified since.
+    pub fn new(mut base: File, overlay: File) -> io::Result<Self> {
+        let disk_size = raw_image_size(&mut base)?;
+        let base_identity = BaseIdentity::new(&base)?;
+
+        let header = if overlay.metadata()?.len() == 0 {
+            let header = OverlayHeader::new(disk_size, base_identity);
+            header.write(&overlay)?;
+            overlay.set_len(header.data_offset + disk_size)?;
+            overlay.sync_all()?;
+            header
+        } else {
+            let header = OverlayHeader::read(&overlay)?;
+            if header.disk_size != disk_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Disk overlay created for a {} bytes image, base image is {} bytes",
+                        header.disk_size, disk_size
+                    ),
+                ));
+            }
+            if header.base != base_identity {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Disk overlay created for another base image, or for the image \
+                         before it was modified: {:?}, base image is {:?}",
+                        header.base, base_identity
+                    ),
+                ));
+            }
+            header
+        };
+
+        let mut bits = vec![0u8; bitmap_len(disk_size)];
+        overlay.read_exact_at(&mut bits, header.bitmap_offset)?;
+
+        Ok(OverlayDiskFile {
+            base,
+            overlay,
+            header,
+            bitmap: Arc::new(Mutex::new(Bitmap { bits, dirty: None })),
+        })
+    }
+}
+
+impl Drop for OverlayDiskFile {
+    fn drop(&mut self) {
+        // The guest may not have flushed its last writes.
+        if let Err(e) = flush_overlay(
+            self.overlay.as_raw_fd(),
+            self.header.bitmap_offset,
+            &self.bitmap,
+        ) {
+            error!("Failed flushing the disk overlay: {}", e);
+        }
+    }
+}
+
+impl DiskFile for OverlayDiskFile {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.header.disk_size)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(OverlayFileSync {
+            base_fd: self.base.as_raw_fd(),
+            overlay_fd: self.overlay.as_raw_fd(),
+            disk_size: self.header.disk_size,
+            bitmap_offset: self.header.bitmap_offset,
+            data_offset: self.header.data_offset,
+            bitmap: self.bitmap.clone(),
+            eventfd: EventFd::new(libc::EFD_NONBLOCK).map_err(DiskFileError::NewAsyncIo)?,
+            completion_list: Vec::new(),
+        }) as Box<dyn AsyncIo>)
+    }
+}
+
+pub struct OverlayFileSync {
+    base_fd: RawFd,
+    overlay_fd: RawFd,
+    disk_size: u64,
+    bitmap_offset: u64,
+    data_offset: u64,
+    // Shared by all the queues, the writes hold the lock for their whole
+    // duration so that a sector is never copied up twice.
+    bitmap: Arc<Mutex<Bitmap>>,
+    eventfd: EventFd,
+    completion_list: Vec<(u64, i32)>,
+}
+
+impl OverlayFileSync {
+    fn preadv(fd: RawFd, iovecs: &[libc::iovec], offset: u64) -> io::Result<u64> {
+        let result = unsafe {
+            libc::preadv(
+                fd as libc::c_int,
+                iovecs.as_ptr() as *const libc::iovec,
+                iovecs.len() as libc::c_int,
+                offset as libc::off_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(result as u64)
+    }
+
+    fn pwritev(fd: RawFd, iovecs: &[libc::iovec], offset: u64) -> io::Result<u64> {
+        let result = unsafe {
+            libc::pwritev(
+                fd as libc::c_int,
+                iovecs.as_ptr() as *const libc::iovec,
+                iovecs.len() as libc::c_int,
+                offset as libc::off_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(result as u64)
+    }
+
+    // Copies a sector from the base image up to the overlay, before it gets
+    // partially overwritten.
+    fn copy_up(&self, bitmap: &mut Bitmap, sector: u64) -> io::Result<()> {
+        let offset = sector * SECTOR_SIZE;
+        let mut buf = vec![0u8; SECTOR_SIZE as usize];
+        let len = cmp::min(SECTOR_SIZE, self.disk_size - offset) as usize;
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: len,
+        }];
+        Self::preadv(self.base_fd, &iovecs, offset)?;
+        if Self::pwritev(self.overlay_fd, &iovecs, self.data_offset + offset)? != len as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "Short write copying up a sector",
+            ));
+        }
+        bitmap.set(sector);
+        Ok(())
+    }
+
+    fn read(&self, offset: u64, iovecs: &[libc::iovec]) -> io::Result<u64> {
+        let len = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum::<u64>();
+        let len = cmp::min(len, self.disk_size.saturating_sub(offset));
+        let runs = runs(&self.bitmap.lock().unwrap().bits, offset, len);
+
+        let mut count = 0;
+        for (start, len, present) in runs {
+            let iovecs = sub_iovecs(iovecs, start - offset, len);
+            let read = if present {
+                Self::preadv(self.overlay_fd, &iovecs, self.data_offset + start)?
+            } else {
+                Self::preadv(self.base_fd, &iovecs, start)?
+            };
+            count += read;
+            if read != len {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    fn write(&self, offset: u64, iovecs: &[libc::iovec]) -> io::Result<u64> {
+        let len = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum::<u64>();
+        if len == 0 {
+            return Ok(0);
+        }
+        if offset + len > self.disk_size {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+
+        let mut bitmap = self.bitmap.lock().unwrap();
+        let first = offset / SECTOR_SIZE;
+        let last = (offset + len - 1) / SECTOR_SIZE;
+
+        if offset % SECTOR_SIZE != 0 && !is_present(&bitmap.bits, first) {
+            self.copy_up(&mut bitmap, first)?;
+        }
+        let end = offset + len;
+        if end % SECTOR_SIZE != 0 && end != self.disk_size && !is_present(&bitmap.bits, last) {
+            self.copy_up(&mut bitmap, last)?;
+        }
+
+        let written = Self::pwritev(self.overlay_fd, iovecs, self.data_offset + offset)?;
+
+        // Only the sectors fully written, or copied up, can be read from the
+        // overlay from now on. The bitmap is written on the next flush.
+        let written_end = offset + written;
+        for sector in first..=last {
+            let sector_end = cmp::min((sector + 1) * SECTOR_SIZE, self.disk_size);
+            if sector_end <= written_end {
+                bitmap.set(sector);
+            }
+        }
+
+        Ok(written)
+    }
+}
+
+impl AsyncIo for OverlayFileSync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let result = self
+            .read(offset as u64, &iovecs)
+            .map_err(AsyncIoError::ReadVectored)?;
+
+        self.completion_list.push((user_data, result as i32));
+        self.eventfd.write(1).unwrap();
+
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let result = self
+            .write(offset as u64, &iovecs)
+            .map_err(AsyncIoError::WriteVectored)?;
+
+        self.completion_list.push((user_data, result as i32));
+        self.eventfd.write(1).unwrap();
+
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        // The base image is never written, only the overlay needs syncing.
+        flush_overlay(self.overlay_fd, self.bitmap_offset, &self.bitmap)
+            .map_err(AsyncIoError::Fsync)?;
+
+        if let Some(user_data) = user_data {
+            self.completion_list.push((user_data, 0));
+            self.eventfd.write(1).unwrap();
+        }
+
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        self.completion_list.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    fn iovec(buf: &mut [u8]) -> Vec<libc::iovec> {
+        vec![libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }]
+    }
+
+    fn read(async_io: &mut dyn AsyncIo, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        async_io
+            .read_vectored(offset as libc::off_t, iovec(&mut buf), 0)
+            .unwrap();
+        assert_eq!(async_io.complete(), vec![(0, len as i32)]);
+        buf
+    }
+
+    fn write(async_io: &mut dyn AsyncIo, offset: u64, mut buf: Vec<u8>) {
+        let len = buf.len();
+        async_io
+            .write_vectored(offset as libc::off_t, iovec(&mut buf), 1)
+            .unwrap();
+        assert_eq!(async_io.complete(), vec![(1, len as i32)]);
+    }
+
+    #[test]
+    fn test_overlay_copy_on_write() {
+        const SIZE: u64 = 64 << 10;
+
+        let base = tempfile().unwrap();
+        let content: Vec<u8> = (0..SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect();
+        base.write_all_at(&content, 0).unwrap();
+        let overlay = tempfile().unwrap();
+
+        let mut disk_file =
+            OverlayDiskFile::new(base.try_clone().unwrap(), overlay.try_clone().unwrap()).unwrap();
+        assert_eq!(disk_file.size().unwrap(), SIZE);
+        let mut async_io = disk_file.new_async_io(1).unwrap();
+
+        // an untouched disk reads as the base image
+        assert_eq!(read(async_io.as_mut(), 0, SIZE as usize), content);
+
+        // a write spanning partial sectors copies them up
+        write(async_io.as_mut(), 1000, vec![0xff; 2000]);
+        let mut expected = content.clone();
+        expected[1000..3000].copy_from_slice(&[0xff; 2000]);
+        assert_eq!(read(async_io.as_mut(), 0, 4096), &expected[..4096]);
+        {
+            let bitmap = disk_file.bitmap.lock().unwrap();
+            assert!(!is_present(&bitmap.bits, 0));
+            assert!((1..=5).all(|sector| is_present(&bitmap.bits, sector)));
+            assert!(!is_present(&bitmap.bits, 6));
+        }
+
+        // the base image is left untouched
+        let mut buf = vec![0u8; SIZE as usize];
+        base.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, content);
+
+        // the written sectors are still there once the overlay is reopened
+        drop(async_io);
+        drop(disk_file);
+        let disk_file = OverlayDiskFile::new(base.try_clone().unwrap(), overlay).unwrap();
+        let mut async_io = disk_file.new_async_io(1).unwrap();
+        assert_eq!(read(async_io.as_mut(), 0, SIZE as usize), expected);
+
+        // writes past the end of the disk are rejected
+        let mut buf = vec![0u8; SECTOR_SIZE as usize];
+        assert!(async_io
+            .write_vectored(SIZE as libc::off_t, iovec(&mut buf), 2)
+            .is_err());
+    }
+
+    #[test]
+    fn test_overlay_base_mismatch() {
+        let base = tempfile().unwrap();
+        base.set_len(1 << 20).unwrap();
+        let overlay = tempfile().unwrap();
+        OverlayDiskFile::new(base.try_clone().unwrap(), overlay.try_clone().unwrap()).unwrap();
+
+        // an overlay can't be stacked on top of an image of a different size
+        base.set_len(2 << 20).unwrap();
+        assert!(OverlayDiskFile::new(base, overlay.try_clone().unwrap()).is_err());
+
+        // nor on another image of the same size
+        let other_base = tempfile().unwrap();
+        other_base.set_len(1 << 20).unwrap();
+        assert!(OverlayDiskFile::new(
+            other_base.try_clone().unwrap(),
+            overlay.try_clone().unwrap()
+        )
+        .is_err());
+
+        // nor can a file which isn't an overlay be used as one
+        overlay.write_all_at(b"NOTOVRLY", 0).unwrap();
+        assert!(OverlayDiskFile::new(other_base, overlay).is_err());
+    }
+
+    #[test]
+    fn test_overlay_invalid_header() {
+        let base = tempfile().unwrap();
+        base.set_len(1 << 20).unwrap();
+        let overlay = tempfile().unwrap();
+        drop(
+            OverlayDiskFile::new(base.try_clone().unwrap(), overlay.try_clone().unwrap()).unwrap(),
+        );
+        let mut header = [0u8; OverlayHeader::LEN];
+        overlay.read_exact_at(&mut header, 0).unwrap();
+
+        // the sector size and the offsets found in the header are checked
+        for (range, value) in [
+            (12..16, 4096u64.to_le_bytes()[..4].to_vec()),
+            (24..32, 0u64.to_le_bytes().to_vec()),
+            (24..32, (BITMAP_OFFSET + 1).to_le_bytes().to_vec()),
+            (32..40, BITMAP_OFFSET.to_le_bytes().to_vec()),
+            (
+                32..40,
+                (u64::MAX & !(OVERLAY_ALIGNMENT - 1)).to_le_bytes().to_vec(),
+            ),
+        ] {
+            let mut corrupted = header;
+            corrupted[range].copy_from_slice(&value);
+            overlay.write_all_at(&corrupted, 0).unwrap();
+            assert_eq!(
+                OverlayDiskFile::new(base.try_clone().unwrap(), overlay.try_clone().unwrap())
+                    .err()
+                    .unwrap()
+                    .kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn test_overlay_bitmap_flush() {
+        let base = tempfile().unwrap();
+        base.write_all_at(&[0xaa; 4096], 0).unwrap();
+        let overlay = tempfile().unwrap();
+        let disk_file =
+            OverlayDiskFile::new(base.try_clone().unwrap(), overlay.try_clone().unwrap()).unwrap();
+        let mut async_io = disk_file.new_async_io(1).unwrap();
+        let on_disk_bitmap = || {
+            let mut bitmap = [0u8; 1];
+            overlay.read_exact_at(&mut bitmap, BITMAP_OFFSET).unwrap();
+            bitmap[0]
+        };
+
+        // the bitmap only reaches the file once the data it covers is
+        // synced, a crash in between leaving the sectors to the base image
+        write(async_io.as_mut(), 100, vec![0xff; 1000]);
+        assert_eq!(on_disk_bitmap(), 0);
+        async_io.fsync(Some(2)).unwrap();
+        assert_eq!(async_io.complete(), vec![(2, 0)]);
+        assert_eq!(on_disk_bitmap(), 0b111);
+
+        // the writes the guest didn't flush are kept when the disk is closed
+        write(async_io.as_mut(), 2048, vec![0xff; 512]);
+        assert_eq!(on_disk_bitmap(), 0b111);
+        drop(async_io);
+        drop(disk_file);
+        assert_eq!(on_disk_bitmap(), 0b1_0111);
+    }
+}
@@ -5,9 +5,9 @@
 use crate::async_io::{
     AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
 };
+use crate::{raw_image_block_size, raw_image_size, write_zeroes};
 use io_uring::{opcode, squeue, IoUring};
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
 use std::os::unix::io::{AsRawFd, RawFd};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -23,10 +23,11 @@ impl RawFileDisk {
 
 impl DiskFile for RawFileDisk {
     fn size(&mut self) -> DiskFileResult<u64> {
-        Ok(self
-            .file
-            .seek(SeekFrom::End(0))
-            .map_err(DiskFileError::Size)? as u64)
+        raw_image_size(&mut self.file).map_err(DiskFileError::Size)
+    }
+
+    fn block_size(&self) -> DiskFileResult<u32> {
+        raw_image_block_size(&self.file).map_err(DiskFileError::BlockSize)
     }
 
     fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
@@ -35,6 +36,10 @@ impl DiskFile for RawFileDisk {
                 .map_err(DiskFileError::NewAsyncIo)?,
         ) as Box<dyn AsyncIo>)
     }
+
+    fn supports_write_zeroes(&self) -> bool {
+        true
+    }
 }
 
 pub struct RawFileAsync {
@@ -157,6 +162,33 @@ impl AsyncIo for RawFileAsync {
         Ok(())
     }
 
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        // Zeroing a range only updates the metadata of the file. It is done
+        // synchronously, letting the punch hole fall back to zeroing the
+        // range when unsupported, and its completion is reported through a
+        // no-op.
+        write_zeroes(self.fd, offset as u64, length, unmap).map_err(AsyncIoError::WriteZeroes)?;
+
+        let (submitter, sq, _) = self.io_uring.split();
+        let mut avail_sq = sq.available();
+
+        // Safe because the no-op doesn't access any file or buffer.
+        let _ = unsafe { avail_sq.push(opcode::Nop::new().build().user_data(user_data)) };
+
+        // Update the submission queue and submit new operations to the
+        // io_uring instance.
+        avail_sq.sync();
+        submitter.submit().map_err(AsyncIoError::WriteZeroes)?;
+
+        Ok(())
+    }
+
     fn complete(&mut self) -> Vec<(u64, i32)> {
         let mut completion_list = Vec::new();
 
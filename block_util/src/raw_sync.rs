@@ -5,8 +5,8 @@
 use crate::async_io::{
     AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
 };
+use crate::{raw_image_block_size, raw_image_size, write_zeroes};
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
 use std::os::unix::io::{AsRawFd, RawFd};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -22,15 +22,20 @@ impl RawFileDiskSync {
 
 impl DiskFile for RawFileDiskSync {
     fn size(&mut self) -> DiskFileResult<u64> {
-        Ok(self
-            .file
-            .seek(SeekFrom::End(0))
-            .map_err(DiskFileError::Size)? as u64)
+        raw_image_size(&mut self.file).map_err(DiskFileError::Size)
+    }
+
+    fn block_size(&self) -> DiskFileResult<u32> {
+        raw_image_block_size(&self.file).map_err(DiskFileError::BlockSize)
     }
 
     fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
         Ok(Box::new(RawFileSync::new(self.file.as_raw_fd())) as Box<dyn AsyncIo>)
     }
+
+    fn supports_write_zeroes(&self) -> bool {
+        true
+    }
 }
 
 pub struct RawFileSync {
@@ -116,6 +121,21 @@ impl AsyncIo for RawFileSync {
         Ok(())
     }
 
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        write_zeroes(self.fd, offset as u64, length, unmap).map_err(AsyncIoError::WriteZeroes)?;
+
+        self.completion_list.push((user_data, 0));
+        self.eventfd.write(1).unwrap();
+
+        Ok(())
+    }
+
     fn complete(&mut self) -> Vec<(u64, i32)> {
         self.completion_list.drain(..).collect()
     }
@@ -0,0 +1,620 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use crate::async_io::{AsyncIo, AsyncIoResult, DiskFile, DiskFileResult};
+use std::alloc::{self, Layout};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+// Granularity of the cache.
+const BLOCK_SIZE: u64 = 64 << 10;
+
+// Alignment of the buffers read ahead into, for the disks opened with
+// O_DIRECT, covering the largest logical block size.
+const BUFFER_ALIGNMENT: usize = 4096;
+
+// Number of previous reads a new one is compared against, in order to
+// detect sequential accesses.
+const HISTORY_LEN: usize = 4;
+
+// Tells the completions of the read-ahead apart from the ones of the guest
+// requests, identified by descriptor indexes.
+const READ_AHEAD_USER_DATA: u64 = 1 << 63;
+
+struct CachedBlock {
+    data: Vec<u8>,
+    tick: u64,
+}
+
+// Zeroed buffer aligned on BUFFER_ALIGNMENT, as a Vec<u8> can't be.
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// The buffer is only ever accessed through the owning AlignedBuf.
+unsafe impl Send for AlignedBuf {}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let layout = Layout::from_size_align(cmp::max(len, 1), BUFFER_ALIGNMENT).unwrap();
+        // Safe because the layout isn't empty, and the allocation checked.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+
+        AlignedBuf { ptr, layout }
+    }
+
+    fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // Safe because the buffer was allocated with this size.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // Safe because the buffer was allocated with this layout.
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+struct PendingRead {
+    offset: u64,
+    buf: AlignedBuf,
+    // Overlapped by a write submitted after the read.
+    stale: bool,
+}
+
+/// Least recently used blocks read ahead of the guest, shared by all the
+/// queues of a disk so that a write from any of them invalidates them.
+struct ReadAheadCache {
+    max_blocks: usize,
+    blocks: HashMap<u64, CachedBlock>,
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    pending_reads: HashMap<u64, PendingRead>,
+    writes: HashMap<u64, (u64, u64)>,
+    next_id: u64,
+}
+
+fn iovecs_len(iovecs: &[libc::iovec]) -> u64 {
+    iovecs.iter().map(|iovec| iovec.iov_len as u64).sum()
+}
+
+fn overlaps(offset: u64, len: u64, other_offset: u64, other_len: u64) -> bool {
+    offset < other_offset + other_len && other_offset < offset + len
+}
+
+impl ReadAheadCache {
+    fn new(size: u64) -> Self {
+        ReadAheadCache {
+            max_blocks: cmp::max(size / BLOCK_SIZE, 1) as usize,
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            pending_reads: HashMap::new(),
+            writes: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id = (self.next_id + 1) & !READ_AHEAD_USER_DATA;
+        self.next_id
+    }
+
+    fn touch(&mut self, index: u64) {
+        self.tick += 1;
+        if let Some(block) = self.blocks.get_mut(&index) {
+            self.lru.remove(&block.tick);
+            block.tick = self.tick;
+            self.lru.insert(self.tick, index);
+        }
+    }
+
+    fn insert(&mut self, offset: u64, data: &[u8]) {
+        for (i, chunk) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+            let index = offset / BLOCK_SIZE + i as u64;
+            if let Some(block) = self.blocks.remove(&index) {
+                self.lru.remove(&block.tick);
+            }
+
+            self.tick += 1;
+            self.blocks.insert(
+                index,
+                CachedBlock {
+                    data: chunk.to_vec(),
+                    tick: self.tick,
+                },
+            );
+            self.lru.insert(self.tick, index);
+        }
+
+        while self.blocks.len() > self.max_blocks {
+            let tick = match self.lru.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            if let Some(index) = self.lru.remove(&tick) {
+                self.blocks.remove(&index);
+            }
+        }
+    }
+
+    fn is_busy(&self, index: u64) -> bool {
+        let offset = index * BLOCK_SIZE;
+        self.blocks.contains_key(&index)
+            || self
+                .pending_reads
+                .values()
+                .any(|r| overlaps(offset, BLOCK_SIZE, r.offset, r.buf.len() as u64))
+    }
+
+    // Copy the data into the buffers if it is entirely cached.
+    fn read(&mut self, offset: u64, iovecs: &[libc::iovec]) -> bool {
+        let len = iovecs_len(iovecs);
+        if len == 0 {
+            return false;
+        }
+
+        let first = offset / BLOCK_SIZE;
+        let last = (offset + len - 1) / BLOCK_SIZE;
+        for index in first..=last {
+            let end = cmp::min(offset + len, (index + 1) * BLOCK_SIZE);
+            match self.blocks.get(&index) {
+                Some(block) if index * BLOCK_SIZE + block.data.len() as u64 >= end => {}
+                _ => return false,
+            }
+        }
+
+        let mut position = offset;
+        for iovec in iovecs {
+            let mut done = 0;
+            while done < iovec.iov_len {
+                let data = &self.blocks[&(position / BLOCK_SIZE)].data[..];
+                let start = (position % BLOCK_SIZE) as usize;
+                let count = cmp::min(data.len() - start, iovec.iov_len - done);
+                // Safe because the iovecs describe buffers from the guest
+                // memory, validated when the request was parsed.
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        data[start..].as_ptr(),
+                        (iovec.iov_base as *mut u8).add(done),
+                        count,
+                    )
+                };
+                done += count;
+                position += count as u64;
+            }
+        }
+
+        for index in first..=last {
+            self.touch(index);
+        }
+
+        true
+    }
+
+    fn start_write(&mut self, offset: u64, len: u64) -> u64 {
+        if len > 0 {
+            let first = offset / BLOCK_SIZE;
+            let last = (offset + len - 1) / BLOCK_SIZE;
+            for index in first..=last {
+                if let Some(block) = self.blocks.remove(&index) {
+                    self.lru.remove(&block.tick);
+                }
+            }
+        }
+
+        for read in self.pending_reads.values_mut() {
+            if overlaps(offset, len, read.offset, read.buf.len() as u64) {
+                read.stale = true;
+            }
+        }
+
+        let id = self.next_id();
+        self.writes.insert(id, (offset, len));
+        id
+    }
+
+    fn complete_write(&mut self, id: u64) {
+        self.writes.remove(&id);
+    }
+
+    fn is_written(&self, offset: u64, len: u64) -> bool {
+        self.writes
+            .values()
+            .any(|(o, l)| overlaps(offset, len, *o, *l))
+    }
+
+    fn complete_read(&mut self, id: u64, result: i32) {
+        if let Some(read) = self.pending_reads.remove(&id) {
+            if result < 0 {
+                warn!(
+                    "Failed reading ahead at offset {}: {}",
+                    read.offset,
+                    std::io::Error::from_raw_os_error(-result)
+                );
+            } else if result > 0 && !read.stale {
+                let len = cmp::min(result as usize, read.buf.len());
+                self.insert(read.offset, &read.buf.as_slice()[..len]);
+            }
+        }
+    }
+}
+
+/// Asynchronous I/O serving the reads from the read-ahead cache whenever
+/// possible, and reading ahead of the guest when its accesses are sequential.
+pub struct ReadAheadAsyncIo {
+    async_io: Box<dyn AsyncIo>,
+    cache: Arc<Mutex<ReadAheadCache>>,
+    window_blocks: u64,
+    // End of the last reads, the next one being sequential if starting
+    // where one of them ended.
+    history: VecDeque<u64>,
+    reading_ahead: bool,
+    // Writes in flight, from the request user data to the cache identifier.
+    writes: HashMap<u64, u64>,
+    completion_list: Vec<(u64, i32)>,
+}
+
+impl ReadAheadAsyncIo {
+    // Read the blocks following the given offset which aren't cached yet,
+    // one read at a time being in flight for each queue.
+    fn read_ahead(&mut self, cache: &mut ReadAheadCache, offset: u64) {
+        if self.reading_ahead {
+            return;
+        }
+
+        let last = offset / BLOCK_SIZE + self.window_blocks;
+        let mut index = offset / BLOCK_SIZE;
+        while index < last && cache.is_busy(index) {
+            index += 1;
+        }
+        let first = index;
+        while index < last && !cache.is_busy(index) {
+            index += 1;
+        }
+        if first == index {
+            return;
+        }
+
+        let offset = first * BLOCK_SIZE;
+        let len = (index - first) * BLOCK_SIZE;
+        if cache.is_written(offset, len) {
+            return;
+        }
+
+        // The offset and length are multiples of BLOCK_SIZE, leaving the
+        // buffer as the only thing to align for O_DIRECT.
+        let buf = AlignedBuf::new(len as usize);
+        let iovecs = vec![libc::iovec {
+            iov_base: buf.ptr as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        let id = cache.next_id();
+        cache.pending_reads.insert(
+            id,
+            PendingRead {
+                offset,
+                buf,
+                stale: false,
+            },
+        );
+
+        if let Err(e) =
+            self.async_io
+                .read_vectored(offset as libc::off_t, iovecs, READ_AHEAD_USER_DATA | id)
+        {
+            warn!("Failed reading ahead at offset {}: {}", offset, e);
+            cache.pending_reads.remove(&id);
+        } else {
+            self.reading_ahead = true;
+        }
+    }
+}
+
+impl AsyncIo for ReadAheadAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        self.async_io.notifier()
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let offset = offset as u64;
+        let len = iovecs_len(&iovecs);
+
+        let sequential = self.history.contains(&offset);
+        self.history.push_back(offset + len);
+        if self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+
+        let cache = self.cache.clone();
+        let mut cache = cache.lock().unwrap();
+        if cache.read(offset, &iovecs) {
+            self.completion_list.push((user_data, len as i32));
+            self.async_io.notifier().write(1).unwrap();
+        } else {
+            self.async_io
+                .read_vectored(offset as libc::off_t, iovecs, user_data)?;
+        }
+
+        if sequential {
+            self.read_ahead(&mut cache, offset + len);
+        }
+
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let id = self
+            .cache
+            .lock()
+            .unwrap()
+            .start_write(offset as u64, iovecs_len(&iovecs));
+
+        if let Err(e) = self.async_io.write_vectored(offset, iovecs, user_data) {
+            self.cache.lock().unwrap().complete_write(id);
+            return Err(e);
+        }
+        self.writes.insert(user_data, id);
+
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.async_io.fsync(user_data)
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: libc::off_t,
+        length: u64,
+        unmap: bool,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let id = self
+            .cache
+            .lock()
+            .unwrap()
+            .start_write(offset as u64, length);
+
+        if let Err(e) = self.async_io.write_zeroes(offset, length, unmap, user_data) {
+            self.cache.lock().unwrap().complete_write(id);
+            return Err(e);
+        }
+        self.writes.insert(user_data, id);
+
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        let mut completion_list: Vec<(u64, i32)> = self.completion_list.drain(..).collect();
+
+        let mut cache = self.cache.lock().unwrap();
+        for (user_data, result) in self.async_io.complete() {
+            if user_data & READ_AHEAD_USER_DATA != 0 {
+                cache.complete_read(user_data & !READ_AHEAD_USER_DATA, result);
+                self.reading_ahead = false;
+                continue;
+            }
+
+            if let Some(id) = self.writes.remove(&user_data) {
+                cache.complete_write(id);
+            }
+            completion_list.push((user_data, result));
+        }
+
+        completion_list
+    }
+}
+
+/// Disk file adding a read-ahead cache in front of another one.
+pub struct ReadAheadDiskFile {
+    disk_file: Box<dyn DiskFile>,
+    cache: Arc<Mutex<ReadAheadCache>>,
+    window_blocks: u64,
+}
+
+impl ReadAheadDiskFile {
+    /// Cache up to `cache_size` bytes, reading `window` bytes ahead of the
+    /// guest when its accesses are sequential.
+    pub fn new(disk_file: Box<dyn DiskFile>, cache_size: u64, window: u64) -> Self {
+        ReadAheadDiskFile {
+            disk_file,
+            cache: Arc::new(Mutex::new(ReadAheadCache::new(cache_size))),
+            window_blocks: cmp::max((window + BLOCK_SIZE - 1) / BLOCK_SIZE, 1),
+        }
+    }
+}
+
+impl DiskFile for ReadAheadDiskFile {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        self.disk_file.size()
+    }
+
+    fn block_size(&self) -> DiskFileResult<u32> {
+        self.disk_file.block_size()
+    }
+
+    fn supports_write_zeroes(&self) -> bool {
+        self.disk_file.supports_write_zeroes()
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        // Leave room for the read-ahead on top of the guest requests.
+        let async_io = self.disk_file.new_async_io(ring_depth + 1)?;
+
+        Ok(Box::new(ReadAheadAsyncIo {
+            async_io,
+            cache: self.cache.clone(),
+            window_blocks: self.window_blocks,
+            history: VecDeque::with_capacity(HISTORY_LEN + 1),
+            reading_ahead: false,
+            writes: HashMap::new(),
+            completion_list: Vec::new(),
+        }) as Box<dyn AsyncIo>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_sync::RawFileDiskSync;
+    use std::os::unix::fs::FileExt;
+    use tempfile::tempfile;
+
+    fn read(async_io: &mut dyn AsyncIo, offset: u64, buf: &mut [u8], user_data: u64) {
+        let iovecs = vec![libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        async_io
+            .read_vectored(offset as libc::off_t, iovecs, user_data)
+            .unwrap();
+        assert_eq!(
+            async_io.complete(),
+            vec![(user_data, buf.len() as i32)],
+            "read at offset {}",
+            offset
+        );
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        const SIZE: u64 = 1 << 20;
+        const REQUEST_SIZE: usize = 16 << 10;
+
+        let file = tempfile().unwrap();
+        let content: Vec<u8> = (0..SIZE).map(|i| (i / 512) as u8).collect();
+        file.write_all_at(&content, 0).unwrap();
+
+        let disk_file = ReadAheadDiskFile::new(
+            Box::new(RawFileDiskSync::new(file.try_clone().unwrap())),
+            4 * BLOCK_SIZE,
+            2 * BLOCK_SIZE,
+        );
+        let cache = disk_file.cache.clone();
+        let mut async_io = disk_file.new_async_io(16).unwrap();
+        let mut buf = vec![0u8; REQUEST_SIZE];
+
+        // a random access doesn't trigger any read-ahead
+        read(async_io.as_mut(), 3 * BLOCK_SIZE, &mut buf, 0);
+        assert!(cache.lock().unwrap().blocks.is_empty());
+
+        // reading the whole disk sequentially returns the right data, with
+        // the cache never growing beyond its size
+        for (i, offset) in (0..SIZE).step_by(REQUEST_SIZE).enumerate() {
+            read(async_io.as_mut(), offset, &mut buf, i as u64);
+            assert_eq!(&buf[..], &content[offset as usize..][..REQUEST_SIZE]);
+            assert!(cache.lock().unwrap().blocks.len() <= 4);
+        }
+
+        // the blocks read ahead on the sequential accesses are cached...
+        read(async_io.as_mut(), 0, &mut buf, 0);
+        read(async_io.as_mut(), REQUEST_SIZE as u64, &mut buf, 1);
+        assert!(cache.lock().unwrap().blocks.contains_key(&1));
+
+        // ...and served from it
+        file.write_all_at(&[0xff; REQUEST_SIZE], BLOCK_SIZE)
+            .unwrap();
+        read(async_io.as_mut(), BLOCK_SIZE, &mut buf, 2);
+        assert_eq!(&buf[..], &content[BLOCK_SIZE as usize..][..REQUEST_SIZE]);
+        file.write_all_at(&content[BLOCK_SIZE as usize..][..REQUEST_SIZE], BLOCK_SIZE)
+            .unwrap();
+
+        // while the writes invalidate them
+        let data = vec![0xaau8; REQUEST_SIZE];
+        let iovecs = vec![libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        }];
+        async_io
+            .write_vectored(BLOCK_SIZE as libc::off_t, iovecs, 3)
+            .unwrap();
+        assert_eq!(async_io.complete(), vec![(3, REQUEST_SIZE as i32)]);
+        assert!(!cache.lock().unwrap().blocks.contains_key(&1));
+        assert!(cache.lock().unwrap().writes.is_empty());
+
+        read(async_io.as_mut(), BLOCK_SIZE, &mut buf, 4);
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_read_ahead_cache() {
+        let mut cache = ReadAheadCache::new(2 * BLOCK_SIZE);
+        let mut buf = vec![0u8; 1024];
+        let iovecs = vec![libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+
+        // a short block at the end of the disk only serves what it holds
+        cache.insert(0, &[1u8; BLOCK_SIZE as usize + 512]);
+        assert!(cache.read(BLOCK_SIZE - 512, &iovecs[..]));
+        assert!(!cache.read(BLOCK_SIZE, &iovecs[..]));
+        assert_eq!(buf[..512], [1u8; 512][..]);
+
+        // the least recently used block is evicted
+        cache.touch(0);
+        cache.insert(2 * BLOCK_SIZE, &[2u8; BLOCK_SIZE as usize]);
+        assert!(cache.blocks.contains_key(&0));
+        assert!(!cache.blocks.contains_key(&1));
+        assert!(cache.blocks.contains_key(&2));
+
+        // a read overlapped by a write is discarded
+        let id = cache.next_id();
+        cache.pending_reads.insert(
+            id,
+            PendingRead {
+                offset: 4 * BLOCK_SIZE,
+                buf: AlignedBuf::new(BLOCK_SIZE as usize),
+                stale: false,
+            },
+        );
+        assert!(cache.is_busy(4));
+        let write = cache.start_write(4 * BLOCK_SIZE + 4096, 512);
+        assert!(cache.is_written(4 * BLOCK_SIZE, BLOCK_SIZE));
+        cache.complete_read(id, BLOCK_SIZE as i32);
+        assert!(!cache.blocks.contains_key(&4));
+        cache.complete_write(write);
+        assert!(!cache.is_written(4 * BLOCK_SIZE, BLOCK_SIZE));
+
+        // a failed read isn't cached
+        let id = cache.next_id();
+        let buf = AlignedBuf::new(BLOCK_SIZE as usize);
+        assert_eq!(buf.ptr as usize % BUFFER_ALIGNMENT, 0);
+        assert!(buf.as_slice().iter().all(|b| *b == 0));
+        cache.pending_reads.insert(
+            id,
+            PendingRead {
+                offset: 5 * BLOCK_SIZE,
+                buf,
+                stale: false,
+            },
+        );
+        cache.complete_read(id, -libc::EINVAL);
+        assert!(cache.pending_reads.is_empty());
+        assert!(!cache.blocks.contains_key(&5));
+    }
+}
@@ -4,15 +4,22 @@
 //
 
 use acpi_tables::{aml, aml::Aml};
+use anyhow::anyhow;
+use clock::VirtualClock;
 use std::sync::{Arc, Barrier};
-use std::time::Instant;
+use std::time::Duration;
 use vm_device::interrupt::InterruptSourceGroup;
 use vm_device::BusDevice;
 use vm_memory::GuestAddress;
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable, VersionedState,
+};
 use vmm_sys_util::eventfd::EventFd;
 use AcpiNotificationFlags;
 
 pub const GED_DEVICE_ACPI_SIZE: usize = 0x1;
+pub const BATTERY_DEVICE_ACPI_SIZE: usize = 0x3;
 
 /// A device for handling ACPI shutdown and reboot
 pub struct AcpiShutdownDevice {
@@ -67,6 +74,8 @@ pub struct AcpiGEDDevice {
     notification_type: AcpiNotificationFlags,
     ged_irq: u32,
     address: GuestAddress,
+    // Whether the battery and AC adapter exist, for them to be notified.
+    battery: bool,
 }
 
 impl AcpiGEDDevice {
@@ -74,12 +83,14 @@ impl AcpiGEDDevice {
         interrupt: Arc<Box<dyn InterruptSourceGroup>>,
         ged_irq: u32,
         address: GuestAddress,
+        battery: bool,
     ) -> AcpiGEDDevice {
         AcpiGEDDevice {
             interrupt,
             notification_type: AcpiNotificationFlags::NO_DEVICES_CHANGED,
             ged_irq,
             address,
+            battery,
         }
     }
 
@@ -108,6 +119,16 @@ impl BusDevice for AcpiGEDDevice {
 #[cfg(feature = "acpi")]
 impl Aml for AcpiGEDDevice {
     fn to_aml_bytes(&self) -> Vec<u8> {
+        let notify_ac = aml::Notify::new(&aml::Path::new("\\_SB_.ADP0"), &0x80usize);
+        let notify_battery = aml::Notify::new(&aml::Path::new("\\_SB_.BAT0"), &0x80usize);
+        // Only notify the battery and AC adapter when they exist, the guest
+        // failing to resolve them otherwise.
+        let battery_notifications: Vec<&dyn Aml> = if self.battery {
+            vec![&notify_ac, &notify_battery]
+        } else {
+            Vec::new()
+        };
+
         aml::Device::new(
             "_SB_.GED_".into(),
             vec![
@@ -154,7 +175,7 @@ impl Aml for AcpiGEDDevice {
                         &aml::And::new(&aml::Local(1), &aml::Local(0), &4usize),
                         &aml::If::new(
                             &aml::Equal::new(&aml::Local(1), &4usize),
-                            vec![&aml::MethodCall::new("\\_SB_.PCI0.PCNT".into(), vec![])],
+                            vec![&aml::MethodCall::new("\\_SB_.PHPR.PSCN".into(), vec![])],
                         ),
                         &aml::And::new(&aml::Local(1), &aml::Local(0), &8usize),
                         &aml::If::new(
@@ -164,6 +185,11 @@ impl Aml for AcpiGEDDevice {
                                 &0x80usize,
                             )],
                         ),
+                        &aml::And::new(&aml::Local(1), &aml::Local(0), &16usize),
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Local(1), &16usize),
+                            battery_notifications,
+                        ),
                     ],
                 ),
             ],
@@ -172,28 +198,230 @@ impl Aml for AcpiGEDDevice {
     }
 }
 
-pub struct AcpiPMTimerDevice {
-    start: Instant,
+// Registers of the battery device, one byte each.
+const BATTERY_AC_ONLINE: u64 = 0;
+const BATTERY_STATE: u64 = 1;
+const BATTERY_CHARGE: u64 = 2;
+
+// Battery state bits, as reported by _BST.
+const BATTERY_DISCHARGING: u8 = 1 << 0;
+const BATTERY_CHARGING: u8 = 1 << 1;
+
+/// A device emulating a battery and an AC adapter, for the guests expecting
+/// to run on a laptop. The capacity of the battery is reported in percent.
+pub struct AcpiBatteryDevice {
+    id: String,
+    address: GuestAddress,
+    ac_online: bool,
+    charge: u8,
 }
 
-impl AcpiPMTimerDevice {
-    pub fn new() -> Self {
-        Self {
-            start: Instant::now(),
+#[derive(Serialize, Deserialize)]
+pub struct AcpiBatteryState {
+    ac_online: bool,
+    charge: u8,
+}
+
+impl VersionedState for AcpiBatteryState {
+    const VERSION: u16 = 1;
+}
+
+impl AcpiBatteryDevice {
+    /// Constructs a fully charged battery, with the AC adapter online.
+    pub fn new(id: String, address: GuestAddress) -> AcpiBatteryDevice {
+        AcpiBatteryDevice {
+            id,
+            address,
+            ac_online: true,
+            charge: 100,
+        }
+    }
+
+    pub fn set_state(&mut self, ac_online: bool, charge: u8) {
+        self.ac_online = ac_online;
+        self.charge = charge;
+    }
+
+    fn battery_state(&self) -> u8 {
+        if !self.ac_online {
+            BATTERY_DISCHARGING
+        } else if self.charge < 100 {
+            BATTERY_CHARGING
+        } else {
+            0
         }
     }
 }
 
-impl Default for AcpiPMTimerDevice {
-    fn default() -> Self {
-        Self::new()
+impl BusDevice for AcpiBatteryDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        data[0] = match offset {
+            BATTERY_AC_ONLINE => self.ac_online as u8,
+            BATTERY_STATE => self.battery_state(),
+            BATTERY_CHARGE => self.charge,
+            _ => 0,
+        };
+    }
+}
+
+impl Snapshottable for AcpiBatteryDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut battery_snapshot = Snapshot::new(self.id.as_str());
+        battery_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &AcpiBatteryState {
+                ac_online: self.ac_online,
+                charge: self.charge,
+            },
+        )?);
+
+        Ok(battery_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        if let Some(battery_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            let battery_state = battery_section.to_versioned_state::<AcpiBatteryState>()?;
+            self.set_state(battery_state.ac_online, battery_state.charge);
+            return Ok(());
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find battery snapshot section"
+        )))
+    }
+}
+
+impl Pausable for AcpiBatteryDevice {}
+impl Transportable for AcpiBatteryDevice {}
+impl Migratable for AcpiBatteryDevice {}
+
+#[cfg(feature = "acpi")]
+impl Aml for AcpiBatteryDevice {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = aml::Scope::new(
+            "_SB_".into(),
+            vec![
+                &aml::OpRegion::new(
+                    "BTST".into(),
+                    aml::OpRegionSpace::SystemMemory,
+                    self.address.0 as usize,
+                    BATTERY_DEVICE_ACPI_SIZE,
+                ),
+                &aml::Field::new(
+                    "BTST".into(),
+                    aml::FieldAccessType::Byte,
+                    aml::FieldUpdateRule::WriteAsZeroes,
+                    vec![
+                        aml::FieldEntry::Named(*b"BACO", 8),
+                        aml::FieldEntry::Named(*b"BSTA", 8),
+                        aml::FieldEntry::Named(*b"BCHG", 8),
+                    ],
+                ),
+            ],
+        )
+        .to_aml_bytes();
+
+        bytes.extend_from_slice(
+            &aml::Device::new(
+                "_SB_.ADP0".into(),
+                vec![
+                    &aml::Name::new("_HID".into(), &"ACPI0003"),
+                    &aml::Name::new(
+                        "_PCL".into(),
+                        &aml::Package::new(vec![&aml::Path::new("\\_SB_")]),
+                    ),
+                    &aml::Method::new(
+                        "_PSR".into(),
+                        0,
+                        false,
+                        vec![&aml::Return::new(&aml::Path::new("\\_SB_.BACO"))],
+                    ),
+                ],
+            )
+            .to_aml_bytes(),
+        );
+
+        // The capacities are in percent, the rates and voltages unknown.
+        let unknown = 0xffff_ffffu32;
+        bytes.extend_from_slice(
+            &aml::Device::new(
+                "_SB_.BAT0".into(),
+                vec![
+                    &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C0A")),
+                    &aml::Name::new("_UID".into(), &aml::ZERO),
+                    &aml::Name::new(
+                        "_PCL".into(),
+                        &aml::Package::new(vec![&aml::Path::new("\\_SB_")]),
+                    ),
+                    &aml::Method::new("_STA".into(), 0, false, vec![&aml::Return::new(&0x1fu8)]),
+                    &aml::Name::new(
+                        "_BIF".into(),
+                        &aml::Package::new(vec![
+                            &aml::ONE,
+                            &100u32,
+                            &100u32,
+                            &aml::ONE,
+                            &unknown,
+                            &10u32,
+                            &5u32,
+                            &aml::ONE,
+                            &aml::ONE,
+                            &"Virtual Battery",
+                            &"0",
+                            &"LION",
+                            &"Cloud Hypervisor",
+                        ]),
+                    ),
+                    &aml::Name::new(
+                        "BSTP".into(),
+                        &aml::Package::new(vec![&aml::ZERO, &unknown, &100u32, &unknown]),
+                    ),
+                    &aml::Method::new(
+                        "_BST".into(),
+                        0,
+                        false,
+                        vec![
+                            &aml::Store::new(
+                                &aml::Index::new(&aml::ZERO, &aml::Path::new("BSTP"), &aml::ZERO),
+                                &aml::Path::new("\\_SB_.BSTA"),
+                            ),
+                            &aml::Store::new(
+                                &aml::Index::new(&aml::ZERO, &aml::Path::new("BSTP"), &2u8),
+                                &aml::Path::new("\\_SB_.BCHG"),
+                            ),
+                            &aml::Return::new(&aml::Path::new("BSTP")),
+                        ],
+                    ),
+                ],
+            )
+            .to_aml_bytes(),
+        );
+
+        bytes
+    }
+}
+
+pub struct AcpiPMTimerDevice {
+    clock: VirtualClock,
+    start: Duration,
+}
+
+impl AcpiPMTimerDevice {
+    pub fn new(clock: VirtualClock) -> Self {
+        Self {
+            start: clock.elapsed(),
+            clock,
+        }
     }
 }
 
 impl BusDevice for AcpiPMTimerDevice {
     fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
-        let now = Instant::now();
-        let since = now.duration_since(self.start);
+        let since = self.clock.elapsed() - self.start;
         let nanos = since.as_nanos();
 
         const PM_TIMER_FREQUENCY_HZ: u128 = 3_579_545;
@@ -205,3 +433,100 @@ impl BusDevice for AcpiPMTimerDevice {
         data.copy_from_slice(&counter.to_le_bytes());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig};
+
+    struct TestInterrupt {}
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+        ) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn read_register(battery: &mut AcpiBatteryDevice, offset: u64) -> u8 {
+        let mut data = [0xffu8];
+        battery.read(0, offset, &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_battery_registers() {
+        let mut battery = AcpiBatteryDevice::new("_battery".to_string(), GuestAddress(0x1000));
+
+        // Fully charged, on AC.
+        assert_eq!(read_register(&mut battery, BATTERY_AC_ONLINE), 1);
+        assert_eq!(read_register(&mut battery, BATTERY_STATE), 0);
+        assert_eq!(read_register(&mut battery, BATTERY_CHARGE), 100);
+
+        battery.set_state(true, 40);
+        assert_eq!(read_register(&mut battery, BATTERY_STATE), BATTERY_CHARGING);
+        assert_eq!(read_register(&mut battery, BATTERY_CHARGE), 40);
+
+        battery.set_state(false, 40);
+        assert_eq!(read_register(&mut battery, BATTERY_AC_ONLINE), 0);
+        assert_eq!(
+            read_register(&mut battery, BATTERY_STATE),
+            BATTERY_DISCHARGING
+        );
+
+        // Past the registers.
+        assert_eq!(
+            read_register(&mut battery, BATTERY_DEVICE_ACPI_SIZE as u64),
+            0
+        );
+    }
+
+    #[test]
+    fn test_battery_snapshot() {
+        let mut battery = AcpiBatteryDevice::new("_battery".to_string(), GuestAddress(0x1000));
+        battery.set_state(false, 25);
+        let snapshot = battery.snapshot().unwrap();
+
+        let mut restored = AcpiBatteryDevice::new("_battery".to_string(), GuestAddress(0x1000));
+        restored.restore(snapshot).unwrap();
+        assert_eq!(read_register(&mut restored, BATTERY_AC_ONLINE), 0);
+        assert_eq!(read_register(&mut restored, BATTERY_CHARGE), 25);
+
+        assert!(restored.restore(Snapshot::new("_battery")).is_err());
+    }
+
+    #[test]
+    fn test_battery_aml() {
+        let battery = AcpiBatteryDevice::new("_battery".to_string(), GuestAddress(0x1000));
+        let aml = battery.to_aml_bytes();
+        let contains = |name: &[u8]| aml.windows(name.len()).any(|w| w == name);
+        assert!(contains(b"ADP0"));
+        assert!(contains(b"BAT0"));
+        assert!(contains(b"_BST"));
+    }
+
+    #[test]
+    fn test_ged_battery_notification() {
+        let interrupt: Arc<Box<dyn InterruptSourceGroup>> = Arc::new(Box::new(TestInterrupt {}));
+        let mentions_battery = |battery: bool| {
+            let ged = AcpiGEDDevice::new(interrupt.clone(), 5, GuestAddress(0x1000), battery);
+            let aml = ged.to_aml_bytes();
+            aml.windows(4).any(|w| w == b"BAT0")
+        };
+
+        // Without a battery, _EVT can't refer to it.
+        assert!(!mentions_battery(false));
+        assert!(mentions_battery(true));
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Time source of the emulated devices.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The clock the emulated time sources (RTC, ACPI PM timer) read from.
+///
+/// The clock follows the host clocks. Clones share the same start time, so
+/// that all the devices observe the same elapsed time.
+#[derive(Clone)]
+pub struct VirtualClock {
+    start: Instant,
+}
+
+impl VirtualClock {
+    /// Creates a clock following the host clocks.
+    pub fn real() -> Self {
+        VirtualClock {
+            start: Instant::now(),
+        }
+    }
+
+    /// Returns the monotonic time elapsed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Returns the wall clock time, as a duration since the UNIX epoch.
+    pub fn wall_time(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::real()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_real_clock() {
+        let clock = VirtualClock::real();
+        let elapsed = clock.elapsed();
+        assert!(clock.elapsed() >= elapsed);
+        assert!(clock.clone().elapsed() >= elapsed);
+        assert!(clock.wall_time() > Duration::from_secs(0));
+    }
+}
@@ -22,7 +22,7 @@ use vm_device::BusDevice;
 use vm_memory::GuestAddress;
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    Transportable, VersionedState,
 };
 use vmm_sys_util::eventfd::EventFd;
 
@@ -150,6 +150,10 @@ pub struct IoapicState {
     apic_address: GuestAddress,
 }
 
+impl VersionedState for IoapicState {
+    const VERSION: u16 = 1;
+}
+
 impl BusDevice for Ioapic {
     fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
         assert!(data.len() == 4);
@@ -418,29 +422,18 @@ impl Snapshottable for Ioapic {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut ioapic_snapshot = Snapshot::new(self.id.as_str());
-        ioapic_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id),
-            snapshot,
-        });
+        ioapic_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
 
         Ok(ioapic_snapshot)
     }
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(ioapic_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
-            let ioapic_state = match serde_json::from_slice(&ioapic_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize IOAPIC {}",
-                        error
-                    )))
-                }
-            };
+            let ioapic_state = ioapic_section.to_versioned_state::<IoapicState>()?;
 
             return self.set_state(&ioapic_state).map_err(|e| {
                 MigratableError::Restore(anyhow!("Could not restore IOAPIC state {:?}", e))
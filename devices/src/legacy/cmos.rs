@@ -2,7 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use libc::{clock_gettime, gmtime_r, time_t, timespec, tm, CLOCK_REALTIME};
+use clock::VirtualClock;
+use legacy::RealTimeClock;
+use libc::{gmtime_r, time_t, tm};
 use std::cmp::min;
 use std::mem;
 use std::sync::{Arc, Barrier};
@@ -13,17 +15,28 @@ const INDEX_OFFSET: u64 = 0x0;
 const DATA_OFFSET: u64 = 0x1;
 const DATA_LEN: usize = 128;
 
+const REG_B: usize = 0x0b;
+// Register B flags
+const REG_B_24_HOUR: u8 = 1 << 1;
+const REG_B_BINARY: u8 = 1 << 2;
+// Hours register flag set for PM in 12-hour mode
+const HOURS_PM: u8 = 1 << 7;
+
 /// A CMOS/RTC device commonly seen on x86 I/O port 0x70/0x71.
 pub struct Cmos {
     index: u8,
     data: [u8; DATA_LEN],
+    clock: VirtualClock,
+    // Offset in seconds of the RTC time from the clock time
+    rtc_offset: i64,
 }
 
 impl Cmos {
     /// Constructs a CMOS/RTC device with initial data.
     /// `mem_below_4g` is the size of memory in bytes below the 32-bit gap.
     /// `mem_above_4g` is the size of memory in bytes above the 32-bit gap.
-    pub fn new(mem_below_4g: u64, mem_above_4g: u64) -> Cmos {
+    /// `clock` is the clock the RTC reads the time from.
+    pub fn new(mem_below_4g: u64, mem_above_4g: u64, clock: VirtualClock) -> Cmos {
         let mut data = [0u8; DATA_LEN];
 
         // Extended memory from 16 MB to 4 GB in units of 64 KB
@@ -40,7 +53,25 @@ impl Cmos {
         data[0x5c] = (high_mem >> 8) as u8;
         data[0x5d] = (high_mem >> 16) as u8;
 
-        Cmos { index: 0, data }
+        // The firmware expects the RTC in 24-hour mode, with BCD values.
+        data[REG_B] = REG_B_24_HOUR;
+
+        Cmos {
+            index: 0,
+            data,
+            clock,
+            rtc_offset: 0,
+        }
+    }
+}
+
+impl RealTimeClock for Cmos {
+    fn time(&self) -> u64 {
+        (self.clock.wall_time().as_secs() as i64 + self.rtc_offset) as u64
+    }
+
+    fn set_time(&mut self, time: u64) {
+        self.rtc_offset = time as i64 - self.clock.wall_time().as_secs() as i64;
     }
 }
 
@@ -78,14 +109,12 @@ impl BusDevice for Cmos {
                 let day;
                 let month;
                 let year;
-                // The clock_gettime and gmtime_r calls are safe as long as the structs they are
-                // given are large enough, and neither of them fail. It is safe to zero initialize
-                // the tm and timespec struct because it contains only plain data.
+                let wall_time = self.clock.wall_time();
+                // The gmtime_r call is safe as long as the struct it is given is large enough,
+                // and it doesn't fail. It is safe to zero initialize the tm struct because it
+                // contains only plain data.
                 let update_in_progress = unsafe {
-                    let mut timespec: timespec = mem::zeroed();
-                    clock_gettime(CLOCK_REALTIME, &mut timespec as *mut _);
-
-                    let now: time_t = timespec.tv_sec;
+                    let now = (wall_time.as_secs() as i64 + self.rtc_offset) as time_t;
                     let mut tm: tm = mem::zeroed();
                     gmtime_r(&now, &mut tm as *mut _);
 
@@ -99,21 +128,37 @@ impl BusDevice for Cmos {
                     year = tm.tm_year;
 
                     // Update in Progress bit held for last 224us of each second
-                    const NANOSECONDS_PER_SECOND: i64 = 1_000_000_000;
-                    const UIP_HOLD_LENGTH: i64 = 8 * NANOSECONDS_PER_SECOND / 32768;
-                    timespec.tv_nsec >= (NANOSECONDS_PER_SECOND - UIP_HOLD_LENGTH)
+                    const NANOSECONDS_PER_SECOND: u32 = 1_000_000_000;
+                    const UIP_HOLD_LENGTH: u32 = 8 * NANOSECONDS_PER_SECOND / 32768;
+                    wall_time.subsec_nanos() >= (NANOSECONDS_PER_SECOND - UIP_HOLD_LENGTH)
+                };
+                // The guest picks the format of the time registers through
+                // register B.
+                let reg_b = self.data[REG_B];
+                let encode = |v: u8| {
+                    if reg_b & REG_B_BINARY != 0 {
+                        v
+                    } else {
+                        to_bcd(v)
+                    }
                 };
                 match self.index {
-                    0x00 => to_bcd(seconds as u8),
-                    0x02 => to_bcd(minutes as u8),
-                    0x04 => to_bcd(hours as u8),
-                    0x06 => to_bcd(week_day as u8),
-                    0x07 => to_bcd(day as u8),
-                    0x08 => to_bcd(month as u8),
-                    0x09 => to_bcd((year % 100) as u8),
+                    0x00 => encode(seconds as u8),
+                    0x02 => encode(minutes as u8),
+                    0x04 if reg_b & REG_B_24_HOUR != 0 => encode(hours as u8),
+                    0x04 => {
+                        let hours = hours as u8;
+                        // Hours are counted from 12 to 11 in 12-hour mode.
+                        let pm = if hours >= 12 { HOURS_PM } else { 0 };
+                        encode((hours + 11) % 12 + 1) | pm
+                    }
+                    0x06 => encode(week_day as u8),
+                    0x07 => encode(day as u8),
+                    0x08 => encode(month as u8),
+                    0x09 => encode((year % 100) as u8),
                     // Bit 5 for 32kHz clock. Bit 7 for Update in Progress
                     0x0a => 1 << 5 | (update_in_progress as u8) << 7,
-                    0x32 => to_bcd(((year + 1900) / 100) as u8),
+                    0x32 => encode(((year + 1900) / 100) as u8),
                     _ => {
                         // self.index is always guaranteed to be in range via INDEX_MASK.
                         self.data[(self.index & INDEX_MASK) as usize]
@@ -124,3 +169,43 @@ impl BusDevice for Cmos {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_reg(cmos: &mut Cmos, index: u8) -> u8 {
+        let mut data = [0u8];
+        cmos.write(0, INDEX_OFFSET, &[index]);
+        cmos.read(0, DATA_OFFSET, &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_rtc_time() {
+        let mut cmos = Cmos::new(0, 0, VirtualClock::real());
+
+        // 2021-06-15 13:45:30 UTC
+        cmos.set_time(1_623_764_730);
+        assert!(cmos.time() >= 1_623_764_730);
+
+        // BCD, 24-hour mode
+        assert_eq!(read_reg(&mut cmos, 0x04), 0x13);
+        assert_eq!(read_reg(&mut cmos, 0x07), 0x15);
+        assert_eq!(read_reg(&mut cmos, 0x08), 0x06);
+        assert_eq!(read_reg(&mut cmos, 0x09), 0x21);
+        assert_eq!(read_reg(&mut cmos, 0x32), 0x20);
+
+        // BCD, 12-hour mode
+        cmos.write(0, INDEX_OFFSET, &[REG_B as u8]);
+        cmos.write(0, DATA_OFFSET, &[0]);
+        assert_eq!(read_reg(&mut cmos, 0x04), HOURS_PM | 0x01);
+
+        // Binary, 24-hour mode
+        cmos.write(0, INDEX_OFFSET, &[REG_B as u8]);
+        cmos.write(0, DATA_OFFSET, &[REG_B_BINARY | REG_B_24_HOUR]);
+        assert_eq!(read_reg(&mut cmos, 0x04), 13);
+        assert_eq!(read_reg(&mut cmos, 0x09), 21);
+        assert_eq!(read_reg(&mut cmos, 0x32), 20);
+    }
+}
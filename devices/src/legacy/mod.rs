@@ -10,6 +10,8 @@ mod cmos;
 #[cfg(feature = "fwdebug")]
 mod fwdebug;
 mod i8042;
+#[cfg(target_arch = "x86_64")]
+mod pvpanic;
 #[cfg(target_arch = "aarch64")]
 mod rtc_pl031;
 mod serial;
@@ -19,7 +21,18 @@ pub use self::cmos::Cmos;
 #[cfg(feature = "fwdebug")]
 pub use self::fwdebug::FwDebugDevice;
 pub use self::i8042::I8042Device;
+#[cfg(target_arch = "x86_64")]
+pub use self::pvpanic::PvPanicDevice;
 pub use self::serial::Serial;
 
 #[cfg(target_arch = "aarch64")]
 pub use self::rtc_pl031::RTC;
+
+/// A real time clock whose time can be read and set from the host.
+pub trait RealTimeClock: Send {
+    /// Returns the time of the clock, in seconds since the UNIX epoch.
+    fn time(&self) -> u64;
+
+    /// Sets the time of the clock, in seconds since the UNIX epoch.
+    fn set_time(&mut self, time: u64);
+}
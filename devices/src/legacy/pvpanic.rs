@@ -0,0 +1,75 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::sync::{Arc, Barrier};
+use vm_device::BusDevice;
+use vmm_sys_util::eventfd::EventFd;
+
+// The guest panicked.
+const PVPANIC_PANICKED: u8 = 1 << 0;
+// The guest loaded a crash kernel, which runs in place of the panic.
+const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+/// The pvpanic device QEMU defines, which the guest writes to when it
+/// panics, signalling the given event for the VMM to run its crash actions.
+pub struct PvPanicDevice {
+    panic_evt: EventFd,
+}
+
+impl PvPanicDevice {
+    pub fn new(panic_evt: EventFd) -> PvPanicDevice {
+        PvPanicDevice { panic_evt }
+    }
+}
+
+// The single 8-bit register reports the supported events when read, and
+// takes the event which occurred when written.
+impl BusDevice for PvPanicDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if data.len() == 1 && offset == 0 {
+            data[0] = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if data.len() != 1 || offset != 0 {
+            return None;
+        }
+
+        if data[0] & PVPANIC_PANICKED != 0 {
+            debug!("pvpanic panic signalled");
+            if let Err(e) = self.panic_evt.write(1) {
+                error!("Error triggering pvpanic event: {}", e);
+            }
+        } else if data[0] & PVPANIC_CRASH_LOADED != 0 {
+            info!("Guest panicked, running its crash kernel");
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::EFD_NONBLOCK;
+
+    #[test]
+    fn test_pvpanic() {
+        let panic_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut pvpanic = PvPanicDevice::new(panic_evt.try_clone().unwrap());
+
+        let mut data = [0u8];
+        pvpanic.read(0x505, 0, &mut data);
+        assert_eq!(data[0], PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+
+        // Loading a crash kernel doesn't trigger the crash actions.
+        pvpanic.write(0x505, 0, &[PVPANIC_CRASH_LOADED]);
+        assert!(panic_evt.read().is_err());
+
+        pvpanic.write(0x505, 0, &[PVPANIC_PANICKED]);
+        assert_eq!(panic_evt.read().unwrap(), 1);
+    }
+}
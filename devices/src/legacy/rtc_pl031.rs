@@ -8,9 +8,12 @@
 //! This is achieved by generating an interrupt signal after counting for a programmed number of cycles of
 //! a real-time clock input.
 //!
+use clock::VirtualClock;
+use legacy::RealTimeClock;
+use std::cmp;
 use std::fmt;
 use std::sync::{Arc, Barrier};
-use std::time::Instant;
+use std::time::Duration;
 use std::{io, result};
 use vm_device::interrupt::InterruptSourceGroup;
 use vm_device::BusDevice;
@@ -261,7 +264,8 @@ pub fn seconds_to_nanoseconds(value: i64) -> Option<i64> {
 
 /// A RTC device following the PL031 specification..
 pub struct RTC {
-    previous_now: Instant,
+    clock: VirtualClock,
+    previous_now: Duration,
     tick_offset: i64,
     // This is used for implementing the RTC alarm. However, in Firecracker we do not need it.
     match_value: u32,
@@ -274,11 +278,12 @@ pub struct RTC {
 
 impl RTC {
     /// Constructs an AMBA PL031 RTC device.
-    pub fn new(interrupt: Arc<Box<dyn InterruptSourceGroup>>) -> RTC {
+    pub fn new(interrupt: Arc<Box<dyn InterruptSourceGroup>>, clock: VirtualClock) -> RTC {
         RTC {
             // This is used only for duration measuring purposes.
-            previous_now: Instant::now(),
-            tick_offset: get_time(ClockType::Real) as i64,
+            previous_now: clock.elapsed(),
+            tick_offset: clock.wall_time().as_nanos() as i64,
+            clock,
             match_value: 0,
             load: 0,
             imsc: 0,
@@ -294,7 +299,7 @@ impl RTC {
 
     fn get_time(&self) -> u32 {
         let ts = (self.tick_offset as i128)
-            + (Instant::now().duration_since(self.previous_now).as_nanos() as i128);
+            + ((self.clock.elapsed() - self.previous_now).as_nanos() as i128);
         (ts / NANOS_PER_SECOND as i128) as u32
     }
 
@@ -310,10 +315,7 @@ impl RTC {
             }
             RTCLR => {
                 self.load = val;
-                self.previous_now = Instant::now();
-                // If the unwrap fails, then the internal value of the clock has been corrupted and
-                // we want to terminate the execution of the process.
-                self.tick_offset = seconds_to_nanoseconds(i64::from(val)).unwrap();
+                self.set_time(u64::from(val));
             }
             RTCIMSC => {
                 self.imsc = val & 1;
@@ -334,6 +336,20 @@ impl RTC {
     }
 }
 
+impl RealTimeClock for RTC {
+    fn time(&self) -> u64 {
+        u64::from(self.get_time())
+    }
+
+    fn set_time(&mut self, time: u64) {
+        // The counter is 32-bit wide, later times saturate, which also keeps
+        // the conversion to nanoseconds from overflowing.
+        let time = cmp::min(time, u64::from(u32::MAX));
+        self.previous_now = self.clock.elapsed();
+        self.tick_offset = time as i64 * NANOS_PER_SECOND as i64;
+    }
+}
+
 impl BusDevice for RTC {
     fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
         let v;
@@ -491,9 +507,10 @@ mod tests {
     fn test_rtc_read_write_and_event() {
         let intr_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
 
-        let mut rtc = RTC::new(Arc::new(Box::new(TestInterrupt::new(
-            intr_evt.try_clone().unwrap(),
-        ))));
+        let mut rtc = RTC::new(
+            Arc::new(Box::new(TestInterrupt::new(intr_evt.try_clone().unwrap()))),
+            VirtualClock::real(),
+        );
         let mut data = [0; 4];
 
         // Read and write to the MR register.
@@ -564,6 +581,22 @@ mod tests {
         assert_eq!(data[0], PL031_ID[((index - AMBA_ID_LOW) >> 2) as usize]);
     }
 
+    #[test]
+    fn test_rtc_set_time() {
+        let intr_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut rtc = RTC::new(
+            Arc::new(Box::new(TestInterrupt::new(intr_evt))),
+            VirtualClock::real(),
+        );
+
+        rtc.set_time(1_623_764_730);
+        assert!(rtc.time() >= 1_623_764_730);
+
+        // Times the counter can't hold saturate instead of overflowing.
+        rtc.set_time(u64::MAX);
+        assert_eq!(rtc.time(), u64::from(u32::MAX));
+    }
+
     macro_rules! byte_order_test_read_write {
         ($test_name: ident, $write_fn_name: ident, $read_fn_name: ident, $is_be: expr, $data_type: ty) => {
             #[test]
@@ -13,7 +13,7 @@ use vm_device::interrupt::InterruptSourceGroup;
 use vm_device::BusDevice;
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    Transportable, VersionedState,
 };
 use vmm_sys_util::errno::Result;
 
@@ -87,6 +87,10 @@ pub struct SerialState {
     in_buffer: VecDeque<u8>,
 }
 
+impl VersionedState for SerialState {
+    const VERSION: u16 = 1;
+}
+
 impl Serial {
     pub fn new(
         id: String,
@@ -292,29 +296,18 @@ impl Snapshottable for Serial {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut serial_snapshot = Snapshot::new(self.id.as_str());
-        serial_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id),
-            snapshot,
-        });
+        serial_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
 
         Ok(serial_snapshot)
     }
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(serial_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
-            let serial_state = match serde_json::from_slice(&serial_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize SERIAL {}",
-                        error
-                    )))
-                }
-            };
+            let serial_state = serial_section.to_versioned_state::<SerialState>()?;
 
             self.set_state(&serial_state);
 
@@ -447,6 +440,36 @@ mod tests {
         assert_eq!(data[0], 0);
     }
 
+    #[test]
+    fn serial_snapshot_version() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut serial = Serial::new_sink(
+            String::from(SERIAL_NAME),
+            Arc::new(Box::new(TestInterrupt::new(intr_evt.try_clone().unwrap()))),
+        );
+        serial.write(0, IER as u64, &[IER_RECV_BIT]);
+
+        let section_id = format!("{}-section", SERIAL_NAME);
+        let snapshot = serial.snapshot().unwrap();
+        assert_eq!(
+            snapshot.snapshot_data[&section_id].version,
+            SerialState::VERSION
+        );
+        let mut restored = Serial::new_sink(
+            String::from(SERIAL_NAME),
+            Arc::new(Box::new(TestInterrupt::new(intr_evt.try_clone().unwrap()))),
+        );
+        restored.restore(snapshot).unwrap();
+        let mut data = [0u8];
+        restored.read(0, IER as u64, &mut data[..]);
+        assert_eq!(data[0] & IER_FIFO_BITS, IER_RECV_BIT);
+
+        // A snapshot taken with a newer format can't be restored.
+        let mut snapshot = serial.snapshot().unwrap();
+        snapshot.snapshot_data.get_mut(&section_id).unwrap().version += 1;
+        assert!(restored.restore(snapshot).is_err());
+    }
+
     #[test]
     fn serial_thr() {
         let intr_evt = EventFd::new(0).unwrap();
@@ -23,19 +23,21 @@ extern crate vm_migration;
 extern crate vmm_sys_util;
 #[macro_use]
 extern crate serde_derive;
-extern crate serde_json;
 
 #[cfg(feature = "acpi")]
 pub mod acpi;
+pub mod clock;
 #[cfg(target_arch = "aarch64")]
 pub mod gic;
 pub mod interrupt_controller;
 #[cfg(target_arch = "x86_64")]
 pub mod ioapic;
 pub mod legacy;
+pub mod tpm;
 
 #[cfg(feature = "acpi")]
-pub use self::acpi::{AcpiGEDDevice, AcpiPMTimerDevice, AcpiShutdownDevice};
+pub use self::acpi::{AcpiBatteryDevice, AcpiGEDDevice, AcpiPMTimerDevice, AcpiShutdownDevice};
+pub use self::clock::VirtualClock;
 
 bitflags! {
     pub struct AcpiNotificationFlags: u8 {
@@ -44,5 +46,6 @@ bitflags! {
         const MEMORY_DEVICES_CHANGED = 0b10;
         const PCI_DEVICES_CHANGED = 0b100;
         const POWER_BUTTON_CHANGED = 0b1000;
+        const BATTERY_CHANGED = 0b10000;
     }
 }
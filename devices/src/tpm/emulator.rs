@@ -0,0 +1,343 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+use tpm::{Error, Result, TpmBackend, TPM_HEADER_SIZE};
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+// Commands of the control channel of swtpm, as defined by its ioctl
+// interface.
+const CMD_GET_CAPABILITY: u32 = 1;
+const CMD_INIT: u32 = 2;
+const CMD_GET_TPMESTABLISHED: u32 = 4;
+const CMD_STOP: u32 = 14;
+const CMD_SET_DATAFD: u32 = 16;
+const CMD_SET_BUFFERSIZE: u32 = 17;
+
+// Capabilities matching the commands above.
+const PTM_CAP_INIT: u64 = 1 << 0;
+const PTM_CAP_GET_TPMESTABLISHED: u64 = 1 << 2;
+const PTM_CAP_STOP: u64 = 1 << 10;
+const PTM_CAP_SET_DATAFD: u64 = 1 << 12;
+const PTM_CAP_SET_BUFFERSIZE: u64 = 1 << 13;
+const REQUIRED_CAPABILITIES: u64 = PTM_CAP_INIT
+    | PTM_CAP_GET_TPMESTABLISHED
+    | PTM_CAP_STOP
+    | PTM_CAP_SET_DATAFD
+    | PTM_CAP_SET_BUFFERSIZE;
+
+// Result of a successful control command.
+const TPM_SUCCESS: u32 = 0;
+
+// Time swtpm gets to answer a command, long enough for it to generate an
+// RSA key, so that a hung swtpm can't block the vCPU writing the command.
+const EMULATOR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TPM emulated by swtpm, running as a separate process. The commands of
+/// the guest go through a data channel, handed to swtpm over its control
+/// socket, swtpm persisting the NVRAM in its own state directory.
+pub struct Emulator {
+    control: UnixStream,
+    data: UnixStream,
+    buffer_size: usize,
+    timed_out: bool,
+}
+
+impl Emulator {
+    /// Connects to the control socket of swtpm, and starts the TPM with
+    /// commands of at most `buffer_size` bytes.
+    pub fn new(socket: &Path, buffer_size: usize) -> Result<Self> {
+        let control = UnixStream::connect(socket).map_err(Error::Connect)?;
+        let emulator = Self::with_control(control, buffer_size, EMULATOR_TIMEOUT)?;
+
+        info!(
+            "Connected to the TPM emulator at {:?}, taking commands of up to {} bytes",
+            socket, emulator.buffer_size
+        );
+
+        Ok(emulator)
+    }
+
+    fn with_control(control: UnixStream, buffer_size: usize, timeout: Duration) -> Result<Self> {
+        let (data, emulator_data) = UnixStream::pair().map_err(Error::CreateDataChannel)?;
+        for socket in [&control, &data].iter() {
+            socket
+                .set_read_timeout(Some(timeout))
+                .and_then(|_| socket.set_write_timeout(Some(timeout)))
+                .map_err(Error::SetSocketTimeout)?;
+        }
+        let mut emulator = Emulator {
+            control,
+            data,
+            buffer_size,
+            timed_out: false,
+        };
+
+        let mut capabilities = [0u8; 8];
+        emulator.control_command(CMD_GET_CAPABILITY, &[], &mut capabilities, None)?;
+        let missing = REQUIRED_CAPABILITIES & !u64::from_be_bytes(capabilities);
+        if missing != 0 {
+            return Err(Error::MissingCapabilities(missing));
+        }
+
+        let mut result = [0u8; 4];
+        emulator.control_command(
+            CMD_SET_DATAFD,
+            &[],
+            &mut result,
+            Some(emulator_data.as_raw_fd()),
+        )?;
+        check_result(CMD_SET_DATAFD, &result)?;
+
+        // The buffer size can only be changed while the TPM is stopped.
+        emulator.control_command(CMD_STOP, &[], &mut result, None)?;
+        check_result(CMD_STOP, &result)?;
+
+        let mut buffer_sizes = [0u8; 16];
+        emulator.control_command(
+            CMD_SET_BUFFERSIZE,
+            &(buffer_size as u32).to_be_bytes(),
+            &mut buffer_sizes,
+            None,
+        )?;
+        check_result(CMD_SET_BUFFERSIZE, &buffer_sizes)?;
+        emulator.buffer_size = u32::from_be_bytes([
+            buffer_sizes[4],
+            buffer_sizes[5],
+            buffer_sizes[6],
+            buffer_sizes[7],
+        ]) as usize;
+
+        emulator.control_command(CMD_INIT, &0u32.to_be_bytes(), &mut result, None)?;
+        check_result(CMD_INIT, &result)?;
+
+        Ok(emulator)
+    }
+
+    // Sends a command over the control channel along with its input, and
+    // reads its output, which unlike the input has a fixed size.
+    fn control_command(
+        &mut self,
+        command: u32,
+        input: &[u8],
+        output: &mut [u8],
+        fd: Option<RawFd>,
+    ) -> Result<()> {
+        let mut request = command.to_be_bytes().to_vec();
+        request.extend_from_slice(input);
+
+        let sent = match fd {
+            Some(fd) => self
+                .control
+                .send_with_fd(&request[..], fd)
+                .map(|_| ())
+                .map_err(|e| io::Error::from_raw_os_error(e.errno())),
+            None => self.control.write_all(&request),
+        };
+
+        sent.and_then(|_| self.control.read_exact(output))
+            .map_err(|e| Error::ControlCommand(command, e))
+    }
+
+    fn exchange(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+        self.data.write_all(command).map_err(Error::Command)?;
+
+        let mut header = [0u8; TPM_HEADER_SIZE];
+        self.data.read_exact(&mut header).map_err(Error::Command)?;
+        let size = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+        if size < TPM_HEADER_SIZE || size > response.len() {
+            // Drain the response so that the next one can be read.
+            if size > TPM_HEADER_SIZE {
+                io::copy(
+                    &mut (&self.data).take((size - TPM_HEADER_SIZE) as u64),
+                    &mut io::sink(),
+                )
+                .map_err(Error::Command)?;
+            }
+            return Err(Error::InvalidResponseSize(size));
+        }
+
+        response[..TPM_HEADER_SIZE].copy_from_slice(&header);
+        self.data
+            .read_exact(&mut response[TPM_HEADER_SIZE..size])
+            .map_err(Error::Command)?;
+
+        Ok(size)
+    }
+}
+
+// Whether the TPM emulator timed out, the socket timeouts failing the reads
+// and writes with either error.
+fn is_timeout(e: &Error) -> bool {
+    match e {
+        Error::Command(e) => {
+            e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+        }
+        _ => false,
+    }
+}
+
+// Checks the TPM result leading the output of a control command.
+fn check_result(command: u32, output: &[u8]) -> Result<()> {
+    let result = u32::from_be_bytes([output[0], output[1], output[2], output[3]]);
+    if result != TPM_SUCCESS {
+        return Err(Error::ControlCommandFailed(command, result));
+    }
+
+    Ok(())
+}
+
+impl TpmBackend for Emulator {
+    fn execute(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+        if self.timed_out {
+            return Err(Error::Timeout);
+        }
+        if command.len() > self.buffer_size {
+            return Err(Error::CommandTooLarge(command.len()));
+        }
+
+        match self.exchange(command, response) {
+            Err(ref e) if is_timeout(e) => {
+                self.timed_out = true;
+                Err(Error::Timeout)
+            }
+            result => result,
+        }
+    }
+
+    fn established(&mut self) -> Result<bool> {
+        let mut output = [0u8; 8];
+        self.control_command(CMD_GET_TPMESTABLISHED, &[], &mut output, None)?;
+        check_result(CMD_GET_TPMESTABLISHED, &output)?;
+
+        Ok(output[4] != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::sync::mpsc;
+    use std::thread;
+
+    // Answers the control commands as swtpm does, handing the data channel
+    // it's given over.
+    fn fake_swtpm(control: UnixStream, data_tx: mpsc::Sender<UnixStream>) {
+        let mut control = control;
+        let mut buffer_size = 0u32;
+        loop {
+            let mut command = [0u8; 4];
+            let (len, file) = control.recv_with_fd(&mut command).unwrap();
+            if len == 0 {
+                return;
+            }
+            let mut output: Vec<u8> = TPM_SUCCESS.to_be_bytes().to_vec();
+            match u32::from_be_bytes(command) {
+                CMD_GET_CAPABILITY => output = REQUIRED_CAPABILITIES.to_be_bytes().to_vec(),
+                CMD_SET_DATAFD => {
+                    let file: File = file.unwrap();
+                    data_tx
+                        .send(unsafe { UnixStream::from_raw_fd(file.into_raw_fd()) })
+                        .unwrap();
+                }
+                CMD_SET_BUFFERSIZE => {
+                    let mut input = [0u8; 4];
+                    control.read_exact(&mut input).unwrap();
+                    // The buffer size is capped, as swtpm does.
+                    buffer_size = u32::from_be_bytes(input).min(0x200);
+                    for size in [buffer_size, TPM_HEADER_SIZE as u32, 0x1000].iter() {
+                        output.extend_from_slice(&size.to_be_bytes());
+                    }
+                }
+                CMD_INIT => {
+                    let mut input = [0u8; 4];
+                    control.read_exact(&mut input).unwrap();
+                    if buffer_size == 0 {
+                        output = 1u32.to_be_bytes().to_vec();
+                    }
+                }
+                CMD_STOP => {}
+                CMD_GET_TPMESTABLISHED => output.extend_from_slice(&[1, 0, 0, 0]),
+                _ => panic!("Unexpected control command {:?}", command),
+            }
+            control.write_all(&output).unwrap();
+        }
+    }
+
+    fn emulator(timeout: Duration) -> (Emulator, UnixStream, thread::JoinHandle<()>) {
+        let (control, swtpm_control) = UnixStream::pair().unwrap();
+        let (data_tx, data_rx) = mpsc::channel();
+        let swtpm = thread::spawn(move || fake_swtpm(swtpm_control, data_tx));
+        let emulator = Emulator::with_control(control, 0x1000, timeout).unwrap();
+        (emulator, data_rx.recv().unwrap(), swtpm)
+    }
+
+    fn response(size: u32) -> Vec<u8> {
+        let mut response = vec![0x80, 0x01];
+        response.extend_from_slice(&size.to_be_bytes());
+        response.resize(size as usize, 0);
+        response
+    }
+
+    #[test]
+    fn test_emulator() {
+        let (mut emulator, mut data, swtpm) = emulator(EMULATOR_TIMEOUT);
+        assert_eq!(emulator.buffer_size, 0x200);
+        assert!(emulator.established().unwrap());
+
+        let command = response(TPM_HEADER_SIZE as u32);
+        let mut output = [0u8; 0x200];
+        data.write_all(&response(12)).unwrap();
+        assert_eq!(emulator.execute(&command, &mut output).unwrap(), 12);
+        let mut received = [0u8; TPM_HEADER_SIZE];
+        data.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..], &command[..]);
+
+        // A response larger than the buffer is skipped, without getting
+        // mixed up with the next one.
+        data.write_all(&response(0x300)).unwrap();
+        data.write_all(&response(14)).unwrap();
+        assert!(matches!(
+            emulator.execute(&command, &mut output),
+            Err(Error::InvalidResponseSize(0x300))
+        ));
+        assert_eq!(emulator.execute(&command, &mut output).unwrap(), 14);
+
+        assert!(matches!(
+            emulator.execute(&[0u8; 0x201], &mut output),
+            Err(Error::CommandTooLarge(0x201))
+        ));
+
+        drop(emulator);
+        swtpm.join().unwrap();
+    }
+
+    #[test]
+    fn test_emulator_timeout() {
+        let (mut emulator, mut data, swtpm) = emulator(Duration::from_millis(100));
+        let command = response(TPM_HEADER_SIZE as u32);
+        let mut output = [0u8; 0x200];
+        assert!(matches!(
+            emulator.execute(&command, &mut output),
+            Err(Error::Timeout)
+        ));
+
+        // The late response can't be told apart from the next one.
+        data.write_all(&response(12)).unwrap();
+        assert!(matches!(
+            emulator.execute(&command, &mut output),
+            Err(Error::Timeout)
+        ));
+
+        drop(emulator);
+        swtpm.join().unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! TPM 2.0 exposed through the Command Response Buffer (CRB) interface.
+
+mod emulator;
+
+pub use self::emulator::Emulator;
+
+#[cfg(feature = "acpi")]
+use acpi_tables::{aml, aml::Aml};
+use std::io;
+use std::sync::{Arc, Barrier};
+use vm_device::BusDevice;
+use vm_memory::GuestAddress;
+
+/// Size of the MMIO region of the CRB interface, covering locality 0 only.
+pub const TPM_CRB_SIZE: u64 = 0x1000;
+
+// Registers of locality 0.
+const CRB_LOC_STATE: u64 = 0x00;
+const CRB_LOC_CTRL: u64 = 0x08;
+const CRB_LOC_STS: u64 = 0x0c;
+const CRB_INTF_ID: u64 = 0x30;
+const CRB_INTF_ID2: u64 = 0x34;
+const CRB_CTRL_REQ: u64 = 0x40;
+const CRB_CTRL_STS: u64 = 0x44;
+const CRB_CTRL_START: u64 = 0x4c;
+const CRB_CTRL_CMD_SIZE: u64 = 0x58;
+const CRB_CTRL_CMD_LADDR: u64 = 0x5c;
+const CRB_CTRL_CMD_HADDR: u64 = 0x60;
+const CRB_CTRL_RSP_SIZE: u64 = 0x64;
+const CRB_CTRL_RSP_LADDR: u64 = 0x68;
+const CRB_CTRL_RSP_HADDR: u64 = 0x6c;
+const CRB_DATA_BUFFER: u64 = 0x80;
+
+/// Size of the buffer holding the commands and their responses.
+pub const TPM_CRB_BUFFER_SIZE: usize = (TPM_CRB_SIZE - CRB_DATA_BUFFER) as usize;
+
+const LOC_STATE_ESTABLISHED: u32 = 1 << 0;
+const LOC_STATE_ASSIGNED: u32 = 1 << 1;
+const LOC_STATE_REG_VALID: u32 = 1 << 7;
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+const LOC_STS_GRANTED: u32 = 1 << 0;
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+const CTRL_STS_FATAL_ERROR: u32 = 1 << 0;
+const CTRL_STS_IDLE: u32 = 1 << 1;
+const CTRL_START: u32 = 1 << 0;
+
+// Active CRB interface of version 1, limited to locality 0, transferring
+// 64 bytes at once, without FIFO interface to select.
+const INTF_ID: u32 = 1 | (1 << 4) | (3 << 11) | (1 << 14) | (1 << 17);
+// Vendor ID of IBM, swtpm coming from there.
+const INTF_ID2: u32 = 0x1014;
+
+/// Size of the header leading the TPM commands and responses.
+pub const TPM_HEADER_SIZE: usize = 10;
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_RC_FAILURE: u32 = 0x101;
+const TPM_RC_COMMAND_SIZE: u32 = 0x142;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot connect to the control socket of the TPM emulator.
+    Connect(io::Error),
+    /// Cannot create the channel the TPM commands go through.
+    CreateDataChannel(io::Error),
+    /// Cannot exchange a control command with the TPM emulator.
+    ControlCommand(u32, io::Error),
+    /// The TPM emulator failed a control command with this result.
+    ControlCommandFailed(u32, u32),
+    /// The TPM emulator lacks these capabilities.
+    MissingCapabilities(u64),
+    /// The command is larger than the TPM accepts.
+    CommandTooLarge(usize),
+    /// Cannot set the timeouts of the sockets of the TPM emulator.
+    SetSocketTimeout(io::Error),
+    /// Cannot exchange a command with the TPM.
+    Command(io::Error),
+    /// The TPM emulator didn't answer in time, so that its later responses
+    /// can't be told apart from the one it was late with.
+    Timeout,
+    /// The response of the TPM has an invalid size.
+    InvalidResponseSize(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Runs the commands of the guest.
+pub trait TpmBackend: Send {
+    /// Runs a command, writing its response in `response` and returning
+    /// its size.
+    fn execute(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize>;
+
+    /// Whether the TPM is established, with a dynamic root of trust for
+    /// measurement in use.
+    fn established(&mut self) -> Result<bool>;
+}
+
+/// TPM 2.0 whose CRB interface sits on the MMIO bus, the commands being run
+/// synchronously by its backend.
+pub struct Tpm {
+    backend: Box<dyn TpmBackend>,
+    address: GuestAddress,
+    loc_state: u32,
+    loc_sts: u32,
+    ctrl_sts: u32,
+    // Queried from the backend once, as it only changes on TPM reset.
+    established: Option<bool>,
+    buffer: Vec<u8>,
+}
+
+impl Tpm {
+    pub fn new(backend: Box<dyn TpmBackend>, address: GuestAddress) -> Tpm {
+        Tpm {
+            backend,
+            address,
+            loc_state: LOC_STATE_REG_VALID,
+            loc_sts: 0,
+            ctrl_sts: CTRL_STS_IDLE,
+            established: None,
+            buffer: vec![0; TPM_CRB_BUFFER_SIZE],
+        }
+    }
+
+    /// Address of the control area, which the TPM2 ACPI table points at.
+    pub fn control_area_address(&self) -> GuestAddress {
+        GuestAddress(self.address.0 + CRB_CTRL_REQ)
+    }
+
+    fn established(&mut self) -> bool {
+        let backend = &mut self.backend;
+        *self
+            .established
+            .get_or_insert_with(|| match backend.established() {
+                Ok(established) => established,
+                Err(e) => {
+                    warn!("Failed to get the TPM established flag: {:?}", e);
+                    false
+                }
+            })
+    }
+
+    fn register(&mut self, offset: u64) -> u32 {
+        let buffer_address = self.address.0 + CRB_DATA_BUFFER;
+        match offset {
+            CRB_LOC_STATE => {
+                if self.established() {
+                    self.loc_state | LOC_STATE_ESTABLISHED
+                } else {
+                    self.loc_state
+                }
+            }
+            CRB_LOC_STS => self.loc_sts,
+            CRB_INTF_ID => INTF_ID,
+            CRB_INTF_ID2 => INTF_ID2,
+            CRB_CTRL_STS => self.ctrl_sts,
+            CRB_CTRL_CMD_SIZE | CRB_CTRL_RSP_SIZE => TPM_CRB_BUFFER_SIZE as u32,
+            CRB_CTRL_CMD_LADDR | CRB_CTRL_RSP_LADDR => buffer_address as u32,
+            CRB_CTRL_CMD_HADDR | CRB_CTRL_RSP_HADDR => (buffer_address >> 32) as u32,
+            // The commands complete before CRB_CTRL_START can be read back.
+            _ => 0,
+        }
+    }
+
+    fn run_command(&mut self) {
+        let size = u32::from_be_bytes([
+            self.buffer[2],
+            self.buffer[3],
+            self.buffer[4],
+            self.buffer[5],
+        ]) as usize;
+        if size < TPM_HEADER_SIZE || size > self.buffer.len() {
+            write_error_response(&mut self.buffer, TPM_RC_COMMAND_SIZE);
+            return;
+        }
+
+        let command = self.buffer[..size].to_vec();
+        if let Err(e) = self.backend.execute(&command, &mut self.buffer) {
+            error!("Failed to run the TPM command: {:?}", e);
+            self.ctrl_sts |= CTRL_STS_FATAL_ERROR;
+            write_error_response(&mut self.buffer, TPM_RC_FAILURE);
+        }
+    }
+}
+
+// Writes a response made of a header only, carrying the error code.
+fn write_error_response(buffer: &mut [u8], code: u32) {
+    buffer[0..2].copy_from_slice(&TPM_ST_NO_SESSIONS.to_be_bytes());
+    buffer[2..6].copy_from_slice(&(TPM_HEADER_SIZE as u32).to_be_bytes());
+    buffer[6..10].copy_from_slice(&code.to_be_bytes());
+}
+
+impl BusDevice for Tpm {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if offset >= CRB_DATA_BUFFER {
+            let start = (offset - CRB_DATA_BUFFER) as usize;
+            match self.buffer.get(start..start + data.len()) {
+                Some(bytes) => data.copy_from_slice(bytes),
+                None => warn!("Invalid TPM buffer read at offset 0x{:x}", offset),
+            }
+            return;
+        }
+
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset + i as u64;
+            *byte = (self.register(offset & !0x3) >> ((offset & 0x3) * 8)) as u8;
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if offset >= CRB_DATA_BUFFER {
+            let start = (offset - CRB_DATA_BUFFER) as usize;
+            match self.buffer.get_mut(start..start + data.len()) {
+                Some(bytes) => bytes.copy_from_slice(data),
+                None => warn!("Invalid TPM buffer write at offset 0x{:x}", offset),
+            }
+            return None;
+        }
+
+        if data.len() != 4 || offset & 0x3 != 0 {
+            warn!(
+                "Invalid TPM register write of {} bytes at offset 0x{:x}",
+                data.len(),
+                offset
+            );
+            return None;
+        }
+
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        match offset {
+            CRB_LOC_CTRL => {
+                if value & LOC_CTRL_REQUEST_ACCESS != 0 {
+                    self.loc_state |= LOC_STATE_ASSIGNED;
+                    self.loc_sts |= LOC_STS_GRANTED;
+                }
+                if value & LOC_CTRL_RELINQUISH != 0 {
+                    self.loc_state &= !LOC_STATE_ASSIGNED;
+                    self.loc_sts &= !LOC_STS_GRANTED;
+                }
+            }
+            CRB_CTRL_REQ => {
+                if value & CTRL_REQ_CMD_READY != 0 {
+                    self.ctrl_sts &= !CTRL_STS_IDLE;
+                } else if value & CTRL_REQ_GO_IDLE != 0 {
+                    self.ctrl_sts |= CTRL_STS_IDLE;
+                }
+            }
+            CRB_CTRL_START => {
+                if value & CTRL_START != 0
+                    && self.ctrl_sts & CTRL_STS_IDLE == 0
+                    && self.loc_state & LOC_STATE_ASSIGNED != 0
+                {
+                    self.run_command();
+                }
+            }
+            // The commands can't be cancelled, as they complete before the
+            // write returns.
+            _ => {}
+        }
+
+        None
+    }
+}
+
+#[cfg(feature = "acpi")]
+impl Aml for Tpm {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        aml::Device::new(
+            "_SB_.TPM2".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"MSFT0101"),
+                &aml::Name::new("_STA".into(), &0xfu8),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![&aml::Memory32Fixed::new(
+                        true,
+                        self.address.0 as u32,
+                        TPM_CRB_SIZE as u32,
+                    )]),
+                ),
+            ],
+        )
+        .to_aml_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers the commands with their own header, failing the ones with a
+    // non-zero code.
+    struct EchoBackend {}
+
+    impl TpmBackend for EchoBackend {
+        fn execute(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+            if command[6..10] != [0; 4] {
+                return Err(Error::Command(io::Error::from(io::ErrorKind::BrokenPipe)));
+            }
+            response[..TPM_HEADER_SIZE].copy_from_slice(&command[..TPM_HEADER_SIZE]);
+            Ok(TPM_HEADER_SIZE)
+        }
+
+        fn established(&mut self) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    fn read_register(tpm: &mut Tpm, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        tpm.read(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_register(tpm: &mut Tpm, offset: u64, value: u32) {
+        tpm.write(0, offset, &value.to_le_bytes());
+    }
+
+    fn run_command(tpm: &mut Tpm, size: u32, code: u32) -> (u32, u32) {
+        let mut command = TPM_ST_NO_SESSIONS.to_be_bytes().to_vec();
+        command.extend_from_slice(&size.to_be_bytes());
+        command.extend_from_slice(&code.to_be_bytes());
+        tpm.write(0, CRB_DATA_BUFFER, &command);
+        write_register(tpm, CRB_CTRL_START, CTRL_START);
+
+        let mut response = [0u8; TPM_HEADER_SIZE];
+        tpm.read(0, CRB_DATA_BUFFER, &mut response);
+        (
+            u32::from_be_bytes([response[2], response[3], response[4], response[5]]),
+            u32::from_be_bytes([response[6], response[7], response[8], response[9]]),
+        )
+    }
+
+    #[test]
+    fn test_tpm_crb() {
+        let mut tpm = Tpm::new(Box::new(EchoBackend {}), GuestAddress(0xfed4_0000));
+        assert_eq!(tpm.control_area_address(), GuestAddress(0xfed4_0040));
+        assert_eq!(read_register(&mut tpm, CRB_CTRL_CMD_LADDR), 0xfed4_0080);
+        assert_eq!(
+            read_register(&mut tpm, CRB_CTRL_RSP_SIZE),
+            TPM_CRB_BUFFER_SIZE as u32
+        );
+
+        assert_eq!(
+            read_register(&mut tpm, CRB_LOC_STATE),
+            LOC_STATE_REG_VALID | LOC_STATE_ESTABLISHED
+        );
+        write_register(&mut tpm, CRB_LOC_CTRL, LOC_CTRL_REQUEST_ACCESS);
+        assert_ne!(
+            read_register(&mut tpm, CRB_LOC_STATE) & LOC_STATE_ASSIGNED,
+            0
+        );
+        assert_eq!(read_register(&mut tpm, CRB_LOC_STS), LOC_STS_GRANTED);
+
+        // Commands are only run once the TPM left the idle state.
+        assert_eq!(read_register(&mut tpm, CRB_CTRL_STS), CTRL_STS_IDLE);
+        write_register(&mut tpm, CRB_CTRL_REQ, CTRL_REQ_CMD_READY);
+        assert_eq!(read_register(&mut tpm, CRB_CTRL_STS), 0);
+
+        assert_eq!(run_command(&mut tpm, 10, 0), (10, 0));
+        assert_eq!(run_command(&mut tpm, 4, 0), (10, TPM_RC_COMMAND_SIZE));
+        assert_eq!(read_register(&mut tpm, CRB_CTRL_STS), 0);
+
+        // A command the backend fails to run puts the TPM in fatal error.
+        assert_eq!(run_command(&mut tpm, 10, 1), (10, TPM_RC_FAILURE));
+        assert_eq!(read_register(&mut tpm, CRB_CTRL_STS), CTRL_STS_FATAL_ERROR);
+
+        write_register(&mut tpm, CRB_LOC_CTRL, LOC_CTRL_RELINQUISH);
+        assert_eq!(read_register(&mut tpm, CRB_LOC_STS), 0);
+    }
+}
@@ -14,7 +14,10 @@ use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::sync::Arc;
-use virtio_devices::{Block, VirtioDevice, VirtioInterrupt, VirtioInterruptType};
+use virtio_devices::{
+    Block, IoRetry, NotificationSuppression, QueueScheduling, VirtioDevice, VirtioInterrupt,
+    VirtioInterruptType,
+};
 use vm_memory::{Bytes, GuestAddress, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_virtio::Queue;
 use vmm_sys_util::eventfd::EventFd;
@@ -94,6 +97,9 @@ fuzz_target!(|bytes| {
         false,
         2,
         256,
+        NotificationSuppression::default(),
+        QueueScheduling::default(),
+        IoRetry::default(),
         SeccompAction::Allow,
     )
     .unwrap();
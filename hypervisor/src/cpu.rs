@@ -23,6 +23,8 @@ use crate::CpuState;
 use crate::MpState;
 #[cfg(target_arch = "x86_64")]
 use crate::Xsave;
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicU64, Ordering};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -155,6 +157,21 @@ pub enum HypervisorCpuError {
     #[error("Failed to notify guest its clock was paused: {0}")]
     NotifyGuestClockPaused(#[source] anyhow::Error),
     ///
+    /// Getting TSC frequency error
+    ///
+    #[error("Failed to get the TSC frequency: {0}")]
+    GetTscKhz(#[source] anyhow::Error),
+    ///
+    /// Setting TSC frequency error
+    ///
+    #[error("Failed to set the TSC frequency: {0}")]
+    SetTscKhz(#[source] anyhow::Error),
+    ///
+    /// Injecting NMI error
+    ///
+    #[error("Failed to inject NMI: {0}")]
+    InjectNmi(#[source] anyhow::Error),
+    ///
     /// Setting debug register error
     ///
     #[error("Failed to set debug registers: {0}")]
@@ -213,9 +230,103 @@ pub enum VmExit<'a> {
     Ignore,
     Reset,
     Shutdown,
+    /// The guest caused a triple fault, leaving the vCPU unable to run.
+    TripleFault,
     Hyperv,
 }
 
+///
+/// Reasons a vCPU exits to the VMM, used to bucket the exit statistics.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitReason {
+    Io,
+    Mmio,
+    Hlt,
+    Shutdown,
+    IoapicEoi,
+    Hyperv,
+    /// The vCPU was kicked out of the guest by a signal.
+    Interrupted,
+    Other,
+}
+
+const EXIT_REASON_COUNT: usize = 8;
+
+///
+/// Number of times a vCPU exited to the VMM, by exit reason.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExitStats {
+    pub io: u64,
+    pub mmio: u64,
+    pub hlt: u64,
+    pub shutdown: u64,
+    pub ioapic_eoi: u64,
+    pub hyperv: u64,
+    pub interrupted: u64,
+    pub other: u64,
+}
+
+impl ExitStats {
+    pub fn total(&self) -> u64 {
+        self.io
+            + self.mmio
+            + self.hlt
+            + self.shutdown
+            + self.ioapic_eoi
+            + self.hyperv
+            + self.interrupted
+            + self.other
+    }
+}
+
+impl AddAssign for ExitStats {
+    fn add_assign(&mut self, other: Self) {
+        self.io += other.io;
+        self.mmio += other.mmio;
+        self.hlt += other.hlt;
+        self.shutdown += other.shutdown;
+        self.ioapic_eoi += other.ioapic_eoi;
+        self.hyperv += other.hyperv;
+        self.interrupted += other.interrupted;
+        self.other += other.other;
+    }
+}
+
+///
+/// Per-vCPU exit counters, updated from the vCPU thread on every exit and
+/// read from any other thread.
+///
+#[derive(Default)]
+pub struct ExitCounters {
+    counters: [AtomicU64; EXIT_REASON_COUNT],
+}
+
+impl ExitCounters {
+    pub fn record(&self, reason: ExitReason) {
+        // Only the vCPU thread updates the counters, so a plain load and
+        // store is enough, sparing the locked read-modify-write of fetch_add.
+        let counter = &self.counters[reason as usize];
+        counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ExitStats {
+        let get = |reason: ExitReason| self.counters[reason as usize].load(Ordering::Relaxed);
+
+        ExitStats {
+            io: get(ExitReason::Io),
+            mmio: get(ExitReason::Mmio),
+            hlt: get(ExitReason::Hlt),
+            shutdown: get(ExitReason::Shutdown),
+            ioapic_eoi: get(ExitReason::IoapicEoi),
+            hyperv: get(ExitReason::Hyperv),
+            interrupted: get(ExitReason::Interrupted),
+            other: get(ExitReason::Other),
+        }
+    }
+}
+
 ///
 /// Result type for returning from a function
 ///
@@ -338,6 +449,23 @@ pub trait Vcpu: Send + Sync {
     /// potential soft lockups when being resumed.
     ///
     fn notify_guest_clock_paused(&self) -> Result<()>;
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    ///
+    /// Returns the frequency of the TSC seen by the guest, in kHz.
+    ///
+    fn tsc_khz(&self) -> Result<u32>;
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    ///
+    /// Sets the frequency of the TSC seen by the guest, in kHz. A frequency
+    /// other than the host one relies on TSC scaling (KVM_CAP_TSC_CONTROL).
+    ///
+    fn set_tsc_khz(&self, freq: u32) -> Result<()>;
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Queues a non-maskable interrupt for the vCPU, delivered the next time
+    /// it runs.
+    ///
+    fn nmi(&self) -> Result<()>;
     ///
     /// Sets the type of CPU to be exposed to the guest and optional features.
     ///
@@ -398,4 +526,43 @@ pub trait Vcpu: Send + Sync {
     /// Triggers the running of the current virtual CPU returning an exit reason.
     ///
     fn run(&self) -> std::result::Result<VmExit, HypervisorCpuError>;
+    ///
+    /// Returns the number of exits of the vCPU since its creation, by reason.
+    ///
+    fn exit_stats(&self) -> ExitStats;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_counters() {
+        let counters = ExitCounters::default();
+        counters.record(ExitReason::Io);
+        counters.record(ExitReason::Io);
+        counters.record(ExitReason::Mmio);
+        counters.record(ExitReason::Other);
+
+        let mut stats = counters.stats();
+        assert_eq!(
+            stats,
+            ExitStats {
+                io: 2,
+                mmio: 1,
+                other: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(stats.total(), 4);
+
+        stats += ExitStats {
+            hlt: 3,
+            io: 1,
+            ..Default::default()
+        };
+        assert_eq!(stats.io, 3);
+        assert_eq!(stats.hlt, 3);
+        assert_eq!(stats.total(), 8);
+    }
 }
@@ -29,6 +29,8 @@ use std::sync::Arc;
 #[cfg(target_arch = "x86_64")]
 use vm_memory::Address;
 use vmm_sys_util::eventfd::EventFd;
+#[cfg(target_arch = "x86_64")]
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_val};
 // x86_64 dependencies
 #[cfg(target_arch = "x86_64")]
 pub mod x86_64;
@@ -41,8 +43,11 @@ use kvm_bindings::{
     kvm_enable_cap, kvm_msr_entry, MsrList, KVM_CAP_HYPERV_SYNIC, KVM_CAP_SPLIT_IRQCHIP,
 };
 #[cfg(target_arch = "x86_64")]
+use x86_64::sev;
+#[cfg(target_arch = "x86_64")]
 use x86_64::{
-    check_required_kvm_extensions, FpuState, SpecialRegisters, StandardRegisters, KVM_TSS_ADDRESS,
+    check_required_kvm_extensions, FpuState, SpecialRegisters, StandardRegisters, KVM_GET_TSC_KHZ,
+    KVM_NMI, KVM_SET_TSC_KHZ, KVM_TSS_ADDRESS,
 };
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{
@@ -184,6 +189,7 @@ impl vm::Vm for KvmVm {
             vmmops,
             #[cfg(target_arch = "x86_64")]
             hyperv_synic: AtomicBool::new(false),
+            exit_counters: cpu::ExitCounters::default(),
         };
         Ok(Arc::new(vcpu))
     }
@@ -331,6 +337,75 @@ impl vm::Vm for KvmVm {
             .set_clock(data)
             .map_err(|e| vm::HypervisorVmError::SetClock(e.into()))
     }
+    /// Initializes the SEV context of the VM.
+    #[cfg(target_arch = "x86_64")]
+    fn sev_init(&self, sev_fd: RawFd) -> vm::Result<()> {
+        sev::sev_command(&self.fd, sev_fd, sev::KVM_SEV_INIT, &mut ())
+            .map_err(|e| vm::HypervisorVmError::SevCommand(e.into()))
+    }
+    /// Starts the SEV launch of the VM.
+    #[cfg(target_arch = "x86_64")]
+    fn sev_launch_start(
+        &self,
+        sev_fd: RawFd,
+        policy: u32,
+        dh_cert: &[u8],
+        session: &[u8],
+    ) -> vm::Result<()> {
+        let mut start = sev::KvmSevLaunchStart {
+            policy,
+            ..Default::default()
+        };
+        if !dh_cert.is_empty() {
+            start.dh_uaddr = dh_cert.as_ptr() as u64;
+            start.dh_len = dh_cert.len() as u32;
+        }
+        if !session.is_empty() {
+            start.session_uaddr = session.as_ptr() as u64;
+            start.session_len = session.len() as u32;
+        }
+        sev::sev_command(&self.fd, sev_fd, sev::KVM_SEV_LAUNCH_START, &mut start)
+            .map_err(|e| vm::HypervisorVmError::SevCommand(e.into()))
+    }
+    /// Registers host memory mapped to the guest as encrypted.
+    #[cfg(target_arch = "x86_64")]
+    fn sev_register_region(&self, addr: u64, size: u64) -> vm::Result<()> {
+        sev::register_enc_region(&self.fd, addr, size)
+            .map_err(|e| vm::HypervisorVmError::SevRegisterRegion(e.into()))
+    }
+    /// Encrypts host memory mapped to the guest in place.
+    #[cfg(target_arch = "x86_64")]
+    fn sev_launch_update_data(&self, sev_fd: RawFd, addr: u64, size: u64) -> vm::Result<()> {
+        let mut update = sev::KvmSevLaunchUpdateData {
+            uaddr: addr,
+            len: size as u32,
+        };
+        sev::sev_command(
+            &self.fd,
+            sev_fd,
+            sev::KVM_SEV_LAUNCH_UPDATE_DATA,
+            &mut update,
+        )
+        .map_err(|e| vm::HypervisorVmError::SevCommand(e.into()))
+    }
+    /// Retrieves the launch measurement, followed by its nonce.
+    #[cfg(target_arch = "x86_64")]
+    fn sev_launch_measure(&self, sev_fd: RawFd) -> vm::Result<Vec<u8>> {
+        let mut measurement = vec![0u8; sev::SEV_MEASUREMENT_LEN + sev::SEV_MNONCE_LEN];
+        let mut measure = sev::KvmSevLaunchMeasure {
+            uaddr: measurement.as_mut_ptr() as u64,
+            len: measurement.len() as u32,
+        };
+        sev::sev_command(&self.fd, sev_fd, sev::KVM_SEV_LAUNCH_MEASURE, &mut measure)
+            .map_err(|e| vm::HypervisorVmError::SevCommand(e.into()))?;
+        Ok(measurement)
+    }
+    /// Completes the SEV launch.
+    #[cfg(target_arch = "x86_64")]
+    fn sev_launch_finish(&self, sev_fd: RawFd) -> vm::Result<()> {
+        sev::sev_command(&self.fd, sev_fd, sev::KVM_SEV_LAUNCH_FINISH, &mut ())
+            .map_err(|e| vm::HypervisorVmError::SevCommand(e.into()))
+    }
     /// Checks if a particular `Cap` is available.
     fn check_extension(&self, c: Cap) -> bool {
         self.fd.check_extension(c)
@@ -515,7 +590,21 @@ pub struct KvmVcpu {
     vmmops: Option<Arc<Box<dyn vm::VmmOps>>>,
     #[cfg(target_arch = "x86_64")]
     hyperv_synic: AtomicBool,
+    exit_counters: cpu::ExitCounters,
+}
+
+fn exit_reason(exit: &VcpuExit) -> cpu::ExitReason {
+    match exit {
+        VcpuExit::IoIn(..) | VcpuExit::IoOut(..) => cpu::ExitReason::Io,
+        VcpuExit::MmioRead(..) | VcpuExit::MmioWrite(..) => cpu::ExitReason::Mmio,
+        VcpuExit::Hlt => cpu::ExitReason::Hlt,
+        VcpuExit::Shutdown | VcpuExit::SystemEvent(..) => cpu::ExitReason::Shutdown,
+        VcpuExit::IoapicEoi(_) => cpu::ExitReason::IoapicEoi,
+        VcpuExit::Hyperv => cpu::ExitReason::Hyperv,
+        _ => cpu::ExitReason::Other,
+    }
 }
+
 /// Implementation of Vcpu trait for KVM
 /// Example:
 /// #[cfg(feature = "kvm")]
@@ -710,82 +799,91 @@ impl cpu::Vcpu for KvmVcpu {
     ///
     fn run(&self) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
         match self.fd.run() {
-            Ok(run) => match run {
-                #[cfg(target_arch = "x86_64")]
-                VcpuExit::IoIn(addr, data) => {
-                    if let Some(vmmops) = &self.vmmops {
-                        return vmmops
-                            .pio_read(addr.into(), data)
-                            .map(|_| cpu::VmExit::Ignore)
-                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+            Ok(run) => {
+                self.exit_counters.record(exit_reason(&run));
+
+                match run {
+                    #[cfg(target_arch = "x86_64")]
+                    VcpuExit::IoIn(addr, data) => {
+                        if let Some(vmmops) = &self.vmmops {
+                            return vmmops
+                                .pio_read(addr.into(), data)
+                                .map(|_| cpu::VmExit::Ignore)
+                                .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+                        }
+
+                        Ok(cpu::VmExit::IoIn(addr, data))
                     }
-
-                    Ok(cpu::VmExit::IoIn(addr, data))
-                }
-                #[cfg(target_arch = "x86_64")]
-                VcpuExit::IoOut(addr, data) => {
-                    if let Some(vmmops) = &self.vmmops {
-                        return vmmops
-                            .pio_write(addr.into(), data)
-                            .map(|_| cpu::VmExit::Ignore)
-                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+                    #[cfg(target_arch = "x86_64")]
+                    VcpuExit::IoOut(addr, data) => {
+                        if let Some(vmmops) = &self.vmmops {
+                            return vmmops
+                                .pio_write(addr.into(), data)
+                                .map(|_| cpu::VmExit::Ignore)
+                                .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+                        }
+
+                        Ok(cpu::VmExit::IoOut(addr, data))
                     }
-
-                    Ok(cpu::VmExit::IoOut(addr, data))
-                }
-                #[cfg(target_arch = "x86_64")]
-                VcpuExit::IoapicEoi(vector) => Ok(cpu::VmExit::IoapicEoi(vector)),
-                #[cfg(target_arch = "x86_64")]
-                VcpuExit::Shutdown | VcpuExit::Hlt => Ok(cpu::VmExit::Reset),
-
-                #[cfg(target_arch = "aarch64")]
-                VcpuExit::SystemEvent(event_type, flags) => {
-                    use kvm_bindings::{KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN};
-                    // On Aarch64, when the VM is shutdown, run() returns
-                    // VcpuExit::SystemEvent with reason KVM_SYSTEM_EVENT_SHUTDOWN
-                    if event_type == KVM_SYSTEM_EVENT_RESET {
-                        Ok(cpu::VmExit::Reset)
-                    } else if event_type == KVM_SYSTEM_EVENT_SHUTDOWN {
-                        Ok(cpu::VmExit::Shutdown)
-                    } else {
-                        Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
-                            "Unexpected system event with type 0x{:x}, flags 0x{:x}",
-                            event_type,
-                            flags
-                        )))
+                    #[cfg(target_arch = "x86_64")]
+                    VcpuExit::IoapicEoi(vector) => Ok(cpu::VmExit::IoapicEoi(vector)),
+                    #[cfg(target_arch = "x86_64")]
+                    VcpuExit::Hlt => Ok(cpu::VmExit::Reset),
+                    #[cfg(target_arch = "x86_64")]
+                    VcpuExit::Shutdown => Ok(cpu::VmExit::TripleFault),
+
+                    #[cfg(target_arch = "aarch64")]
+                    VcpuExit::SystemEvent(event_type, flags) => {
+                        use kvm_bindings::{KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN};
+                        // On Aarch64, when the VM is shutdown, run() returns
+                        // VcpuExit::SystemEvent with reason KVM_SYSTEM_EVENT_SHUTDOWN
+                        if event_type == KVM_SYSTEM_EVENT_RESET {
+                            Ok(cpu::VmExit::Reset)
+                        } else if event_type == KVM_SYSTEM_EVENT_SHUTDOWN {
+                            Ok(cpu::VmExit::Shutdown)
+                        } else {
+                            Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
+                                "Unexpected system event with type 0x{:x}, flags 0x{:x}",
+                                event_type,
+                                flags
+                            )))
+                        }
                     }
-                }
 
-                VcpuExit::MmioRead(addr, data) => {
-                    if let Some(vmmops) = &self.vmmops {
-                        return vmmops
-                            .mmio_read(addr, data)
-                            .map(|_| cpu::VmExit::Ignore)
-                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
-                    }
+                    VcpuExit::MmioRead(addr, data) => {
+                        if let Some(vmmops) = &self.vmmops {
+                            return vmmops
+                                .mmio_read(addr, data)
+                                .map(|_| cpu::VmExit::Ignore)
+                                .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+                        }
 
-                    Ok(cpu::VmExit::MmioRead(addr, data))
-                }
-                VcpuExit::MmioWrite(addr, data) => {
-                    if let Some(vmmops) = &self.vmmops {
-                        return vmmops
-                            .mmio_write(addr, data)
-                            .map(|_| cpu::VmExit::Ignore)
-                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+                        Ok(cpu::VmExit::MmioRead(addr, data))
                     }
+                    VcpuExit::MmioWrite(addr, data) => {
+                        if let Some(vmmops) = &self.vmmops {
+                            return vmmops
+                                .mmio_write(addr, data)
+                                .map(|_| cpu::VmExit::Ignore)
+                                .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+                        }
+
+                        Ok(cpu::VmExit::MmioWrite(addr, data))
+                    }
+                    VcpuExit::Hyperv => Ok(cpu::VmExit::Hyperv),
 
-                    Ok(cpu::VmExit::MmioWrite(addr, data))
+                    r => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
+                        "Unexpected exit reason on vcpu run: {:?}",
+                        r
+                    ))),
                 }
-                VcpuExit::Hyperv => Ok(cpu::VmExit::Hyperv),
-
-                r => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
-                    "Unexpected exit reason on vcpu run: {:?}",
-                    r
-                ))),
-            },
+            }
 
             Err(ref e) => match e.errno() {
-                libc::EAGAIN | libc::EINTR => Ok(cpu::VmExit::Ignore),
+                libc::EAGAIN | libc::EINTR => {
+                    self.exit_counters.record(cpu::ExitReason::Interrupted);
+                    Ok(cpu::VmExit::Ignore)
+                }
                 _ => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
                     "VCPU error {:?}",
                     e
@@ -793,6 +891,12 @@ impl cpu::Vcpu for KvmVcpu {
             },
         }
     }
+    ///
+    /// Returns the number of exits of the vCPU since its creation, by reason.
+    ///
+    fn exit_stats(&self) -> cpu::ExitStats {
+        self.exit_counters.stats()
+    }
     #[cfg(target_arch = "x86_64")]
     ///
     /// Returns currently pending exceptions, interrupts, and NMIs as well as related
@@ -823,6 +927,52 @@ impl cpu::Vcpu for KvmVcpu {
             .kvmclock_ctrl()
             .map_err(|e| cpu::HypervisorCpuError::NotifyGuestClockPaused(e.into()))
     }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the frequency of the TSC seen by the guest, in kHz.
+    ///
+    fn tsc_khz(&self) -> cpu::Result<u32> {
+        // Safe because the ioctl doesn't touch the memory of the process.
+        let ret = unsafe { ioctl(&self.fd, KVM_GET_TSC_KHZ()) };
+        if ret < 0 {
+            return Err(cpu::HypervisorCpuError::GetTscKhz(
+                std::io::Error::last_os_error().into(),
+            ));
+        }
+
+        Ok(ret as u32)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Sets the frequency of the TSC seen by the guest, in kHz.
+    ///
+    fn set_tsc_khz(&self, freq: u32) -> cpu::Result<()> {
+        // Safe because the ioctl doesn't touch the memory of the process.
+        let ret = unsafe { ioctl_with_val(&self.fd, KVM_SET_TSC_KHZ(), freq.into()) };
+        if ret < 0 {
+            return Err(cpu::HypervisorCpuError::SetTscKhz(
+                std::io::Error::last_os_error().into(),
+            ));
+        }
+
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Queues a non-maskable interrupt for the vCPU, delivered the next time
+    /// it runs.
+    ///
+    fn nmi(&self) -> cpu::Result<()> {
+        // Safe because the ioctl doesn't touch the memory of the process.
+        let ret = unsafe { ioctl(&self.fd, KVM_NMI()) };
+        if ret < 0 {
+            return Err(cpu::HypervisorCpuError::InjectNmi(
+                std::io::Error::last_os_error().into(),
+            ));
+        }
+
+        Ok(())
+    }
     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
     fn vcpu_init(&self, kvi: &VcpuInit) -> cpu::Result<()> {
         self.fd
@@ -1208,6 +1358,7 @@ impl cpu::Vcpu for KvmVcpu {
         };
 
         let vcpu_events = self.get_vcpu_events()?;
+        let tsc_khz = self.tsc_khz()?;
 
         Ok(CpuState {
             cpuid,
@@ -1220,6 +1371,7 @@ impl cpu::Vcpu for KvmVcpu {
             xsave,
             xcrs,
             mp_state,
+            tsc_khz: Some(tsc_khz),
         })
     }
     ///
@@ -12,6 +12,9 @@ use crate::arch::x86::{msr_index, SegmentRegisterOps, MTRR_ENABLE, MTRR_MEM_TYPE
 use crate::kvm::{Cap, Kvm, KvmError, KvmResult};
 use serde_derive::{Deserialize, Serialize};
 use vm_memory::GuestAddress;
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
+
+pub mod sev;
 
 ///
 /// Export generically-named wrappers of kvm-bindings for Unix-based platforms
@@ -27,6 +30,11 @@ pub use {
     kvm_bindings::KVM_CPUID_FLAG_SIGNIFCANT_INDEX as CPUID_FLAG_VALID_INDEX,
 };
 
+// Not wrapped by kvm-ioctls yet.
+ioctl_io_nr!(KVM_SET_TSC_KHZ, kvm_bindings::KVMIO, 0xa2);
+ioctl_io_nr!(KVM_GET_TSC_KHZ, kvm_bindings::KVMIO, 0xa3);
+ioctl_io_nr!(KVM_NMI, kvm_bindings::KVMIO, 0x9a);
+
 impl SegmentRegisterOps for SegmentRegister {
     fn segment_type(&self) -> u8 {
         self.type_
@@ -120,9 +128,6 @@ pub fn check_required_kvm_extensions(kvm: &Kvm) -> KvmResult<()> {
     if !kvm.check_extension(Cap::SignalMsi) {
         return Err(KvmError::CapabilityMissing(Cap::SignalMsi));
     }
-    if !kvm.check_extension(Cap::TscDeadlineTimer) {
-        return Err(KvmError::CapabilityMissing(Cap::TscDeadlineTimer));
-    }
     if !kvm.check_extension(Cap::SplitIrqchip) {
         return Err(KvmError::CapabilityMissing(Cap::SplitIrqchip));
     }
@@ -140,4 +145,7 @@ pub struct VcpuKvmState {
     pub xsave: Xsave,
     pub xcrs: ExtendedControlRegisters,
     pub mp_state: MpState,
+    // Missing from the snapshots taken before the TSC frequency was saved.
+    #[serde(default)]
+    pub tsc_khz: Option<u32>,
 }
@@ -0,0 +1,119 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! AMD SEV commands, issued to the PSP firmware through KVM on behalf of a
+//! VM. Not wrapped by kvm-ioctls yet.
+
+use kvm_ioctls::VmFd;
+use std::io;
+use std::os::unix::io::RawFd;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iowr_nr};
+
+ioctl_iowr_nr!(
+    KVM_MEMORY_ENCRYPT_OP,
+    kvm_bindings::KVMIO,
+    0xba,
+    std::os::raw::c_ulong
+);
+ioctl_ior_nr!(
+    KVM_MEMORY_ENCRYPT_REG_REGION,
+    kvm_bindings::KVMIO,
+    0xbb,
+    KvmEncRegion
+);
+
+pub const KVM_SEV_INIT: u32 = 0;
+pub const KVM_SEV_LAUNCH_START: u32 = 2;
+pub const KVM_SEV_LAUNCH_UPDATE_DATA: u32 = 3;
+pub const KVM_SEV_LAUNCH_MEASURE: u32 = 6;
+pub const KVM_SEV_LAUNCH_FINISH: u32 = 7;
+
+/// Size of the launch measurement, followed by the nonce it was computed
+/// with.
+pub const SEV_MEASUREMENT_LEN: usize = 32;
+pub const SEV_MNONCE_LEN: usize = 16;
+
+#[repr(C)]
+#[derive(Default)]
+struct KvmSevCmd {
+    id: u32,
+    data: u64,
+    error: u32,
+    sev_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KvmEncRegion {
+    pub addr: u64,
+    pub size: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KvmSevLaunchStart {
+    pub handle: u32,
+    pub policy: u32,
+    pub dh_uaddr: u64,
+    pub dh_len: u32,
+    pub session_uaddr: u64,
+    pub session_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KvmSevLaunchUpdateData {
+    pub uaddr: u64,
+    pub len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct KvmSevLaunchMeasure {
+    pub uaddr: u64,
+    pub len: u32,
+}
+
+/// Issues the SEV command `id`, with `data` pointing to its parameters, the
+/// firmware error code being part of the error when the command fails.
+pub fn sev_command<T>(vm_fd: &VmFd, sev_fd: RawFd, id: u32, data: &mut T) -> io::Result<()> {
+    let mut cmd = KvmSevCmd {
+        id,
+        data: data as *mut T as u64,
+        sev_fd: sev_fd as u32,
+        ..Default::default()
+    };
+
+    // Safe because the command and its parameters outlive the ioctl, which
+    // only accesses their memory, and the return value is checked.
+    let ret = unsafe { ioctl_with_mut_ref(vm_fd, KVM_MEMORY_ENCRYPT_OP(), &mut cmd) };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(
+            e.kind(),
+            format!(
+                "SEV command {} failed: {}, firmware error {:#x}",
+                id, e, cmd.error
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Registers the host memory backing guest memory as encrypted, pinning it.
+pub fn register_enc_region(vm_fd: &VmFd, addr: u64, size: u64) -> io::Result<()> {
+    let mut region = KvmEncRegion { addr, size };
+
+    // Safe because the region outlives the ioctl, which only reads it, and
+    // the return value is checked.
+    let ret = unsafe { ioctl_with_mut_ref(vm_fd, KVM_MEMORY_ENCRYPT_REG_REGION(), &mut region) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
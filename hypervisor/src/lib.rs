@@ -53,7 +53,7 @@ mod cpu;
 mod device;
 
 pub use crate::hypervisor::{Hypervisor, HypervisorError};
-pub use cpu::{HypervisorCpuError, Vcpu, VmExit};
+pub use cpu::{ExitStats, HypervisorCpuError, Vcpu, VmExit};
 pub use device::{Device, HypervisorDeviceError};
 #[cfg(feature = "kvm")]
 pub use kvm::*;
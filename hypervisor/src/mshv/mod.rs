@@ -170,6 +170,7 @@ pub struct MshvVcpu {
     gsi_routes: Arc<RwLock<HashMap<u32, MshvIrqRoutingEntry>>>,
     hv_state: Arc<RwLock<HvState>>, // Mshv State
     vmmops: Option<Arc<Box<dyn vm::VmmOps>>>,
+    exit_counters: cpu::ExitCounters,
 }
 
 /// Implementation of Vcpu trait for Microsoft Hypervisor
@@ -304,6 +305,21 @@ impl cpu::Vcpu for MshvVcpu {
         /* We always have SynIC enabled on MSHV */
         Ok(())
     }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Queues a non-maskable interrupt for the vCPU.
+    ///
+    fn nmi(&self) -> cpu::Result<()> {
+        Err(cpu::HypervisorCpuError::InjectNmi(anyhow!(
+            "NMI injection not implemented"
+        )))
+    }
+    ///
+    /// Returns the number of exits of the vCPU since its creation, by reason.
+    ///
+    fn exit_stats(&self) -> cpu::ExitStats {
+        self.exit_counters.stats()
+    }
     #[allow(non_upper_case_globals)]
     fn run(&self) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
         // Safe because this is just only done during initialization.
@@ -312,14 +328,17 @@ impl cpu::Vcpu for MshvVcpu {
         match self.fd.run(hv_message) {
             Ok(x) => match x.header.message_type {
                 hv_message_type_HVMSG_X64_HALT => {
+                    self.exit_counters.record(cpu::ExitReason::Hlt);
                     debug!("HALT");
                     Ok(cpu::VmExit::Reset)
                 }
                 hv_message_type_HVMSG_UNRECOVERABLE_EXCEPTION => {
+                    self.exit_counters.record(cpu::ExitReason::Shutdown);
                     warn!("TRIPLE FAULT");
-                    Ok(cpu::VmExit::Shutdown)
+                    Ok(cpu::VmExit::TripleFault)
                 }
                 hv_message_type_HVMSG_X64_IO_PORT_INTERCEPT => {
+                    self.exit_counters.record(cpu::ExitReason::Io);
                     let info = x.to_ioport_info().unwrap();
                     let access_info = info.access_info;
                     let len = unsafe { access_info.__bindgen_anon_1.access_size() } as usize;
@@ -405,6 +424,7 @@ impl cpu::Vcpu for MshvVcpu {
                     Ok(cpu::VmExit::Ignore)
                 }
                 hv_message_type_HVMSG_UNMAPPED_GPA => {
+                    self.exit_counters.record(cpu::ExitReason::Mmio);
                     let info = x.to_memory_info().unwrap();
                     let insn_len = info.instruction_byte_count as usize;
                     assert!(insn_len > 0 && insn_len <= 16);
@@ -435,11 +455,13 @@ impl cpu::Vcpu for MshvVcpu {
                     Ok(cpu::VmExit::Ignore)
                 }
                 hv_message_type_HVMSG_X64_CPUID_INTERCEPT => {
+                    self.exit_counters.record(cpu::ExitReason::Other);
                     let info = x.to_cpuid_info().unwrap();
                     debug!("cpuid eax: {:x}", info.rax);
                     Ok(cpu::VmExit::Ignore)
                 }
                 hv_message_type_HVMSG_X64_MSR_INTERCEPT => {
+                    self.exit_counters.record(cpu::ExitReason::Other);
                     let info = x.to_msr_info().unwrap();
                     if info.header.intercept_access_type == 0 {
                         debug!("msr read: {:x}", info.msr_number);
@@ -449,6 +471,7 @@ impl cpu::Vcpu for MshvVcpu {
                     Ok(cpu::VmExit::Ignore)
                 }
                 hv_message_type_HVMSG_X64_EXCEPTION_INTERCEPT => {
+                    self.exit_counters.record(cpu::ExitReason::Other);
                     //TODO: Handler for VMCALL here.
                     let info = x.to_exception_info().unwrap();
                     debug!("Exception Info {:?}", info.exception_vector);
@@ -461,7 +484,10 @@ impl cpu::Vcpu for MshvVcpu {
             },
 
             Err(e) => match e.errno() {
-                libc::EAGAIN | libc::EINTR => Ok(cpu::VmExit::Ignore),
+                libc::EAGAIN | libc::EINTR => {
+                    self.exit_counters.record(cpu::ExitReason::Interrupted);
+                    Ok(cpu::VmExit::Ignore)
+                }
                 _ => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
                     "VCPU error {:?}",
                     e
@@ -764,6 +790,7 @@ impl vm::Vm for MshvVm {
             gsi_routes: self.gsi_routes.clone(),
             hv_state: self.hv_state.clone(),
             vmmops,
+            exit_counters: cpu::ExitCounters::default(),
         };
         Ok(Arc::new(vcpu))
     }
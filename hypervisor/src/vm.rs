@@ -23,6 +23,8 @@ use crate::KvmVmState as VmState;
 use crate::{IoEventAddress, IrqRoutingEntry, MemoryRegion};
 #[cfg(feature = "kvm")]
 use kvm_ioctls::Cap;
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
 use thiserror::Error;
 use vmm_sys_util::eventfd::EventFd;
@@ -163,6 +165,16 @@ pub enum HypervisorVmError {
     ///
     #[error("Failed to assert virtual Interrupt: {0}")]
     AsserttVirtualInterrupt(#[source] anyhow::Error),
+    ///
+    /// SEV command error
+    ///
+    #[error("Failed to issue SEV command: {0}")]
+    SevCommand(#[source] anyhow::Error),
+    ///
+    /// Register encrypted memory region error
+    ///
+    #[error("Failed to register encrypted memory region: {0}")]
+    SevRegisterRegion(#[source] anyhow::Error),
 }
 ///
 /// Result type for returning from a function
@@ -224,6 +236,33 @@ pub trait Vm: Send + Sync {
     /// Set guest clock.
     #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
     fn set_clock(&self, data: &ClockData) -> Result<()>;
+    /// Initializes the SEV context of the VM, through the `/dev/sev` fd.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    fn sev_init(&self, sev_fd: RawFd) -> Result<()>;
+    /// Starts the SEV launch of the VM with the given guest policy, and the
+    /// guest owner's certificate and session blobs, if any.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    fn sev_launch_start(
+        &self,
+        sev_fd: RawFd,
+        policy: u32,
+        dh_cert: &[u8],
+        session: &[u8],
+    ) -> Result<()>;
+    /// Registers host memory mapped to the guest as encrypted.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    fn sev_register_region(&self, addr: u64, size: u64) -> Result<()>;
+    /// Encrypts host memory mapped to the guest in place, adding it to the
+    /// launch measurement.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    fn sev_launch_update_data(&self, sev_fd: RawFd, addr: u64, size: u64) -> Result<()>;
+    /// Retrieves the launch measurement, followed by the nonce it was
+    /// computed with.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    fn sev_launch_measure(&self, sev_fd: RawFd) -> Result<Vec<u8>>;
+    /// Completes the SEV launch, after which the guest can run.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    fn sev_launch_finish(&self, sev_fd: RawFd) -> Result<()>;
     #[cfg(feature = "kvm")]
     /// Checks if a particular `Cap` is available.
     fn check_extension(&self, c: Cap) -> bool;
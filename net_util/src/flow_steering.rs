@@ -0,0 +1,422 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Steering of the frames sent to the guest through a multiqueue tap
+//! interface, following explicit flow rules.
+//!
+//! The tap interface picks the queue of each frame with an eBPF program,
+//! generated from the rules: the first rule matching the IPv4 header, and
+//! the TCP or UDP ports, of a frame gives its queue. The frames no rule
+//! matches are spread over the queues by a hash of their addresses and
+//! ports, while the frames other than IPv4 all go to the first queue.
+
+use super::{Tap, TapError};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+/// Largest number of rules programmed on an interface.
+pub const MAX_FLOW_RULES: usize = 64;
+
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+const ETH_P_IP: i32 = 0x0800;
+const ETHERTYPE_OFFSET: i32 = 12;
+const IP_HEADER_OFFSET: i32 = 14;
+const IP_PROTOCOL_OFFSET: i32 = IP_HEADER_OFFSET + 9;
+const IP_SRC_OFFSET: i32 = IP_HEADER_OFFSET + 12;
+const IP_DST_OFFSET: i32 = IP_HEADER_OFFSET + 16;
+
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_TYPE_SOCKET_FILTER: u32 = 1;
+
+// Instruction classes, sizes, modes and operations.
+const BPF_LD: u8 = 0x00;
+const BPF_LDX: u8 = 0x01;
+const BPF_ST: u8 = 0x02;
+const BPF_STX: u8 = 0x03;
+const BPF_ALU: u8 = 0x04;
+const BPF_JMP: u8 = 0x05;
+const BPF_ALU64: u8 = 0x07;
+const BPF_W: u8 = 0x00;
+const BPF_H: u8 = 0x08;
+const BPF_B: u8 = 0x10;
+const BPF_ABS: u8 = 0x20;
+const BPF_IND: u8 = 0x40;
+const BPF_MEM: u8 = 0x60;
+const BPF_K: u8 = 0x00;
+const BPF_X: u8 = 0x08;
+const BPF_AND: u8 = 0x50;
+const BPF_LSH: u8 = 0x60;
+const BPF_RSH: u8 = 0x70;
+const BPF_XOR: u8 = 0xa0;
+const BPF_MOV: u8 = 0xb0;
+const BPF_JEQ: u8 = 0x10;
+const BPF_JNE: u8 = 0x50;
+const BPF_EXIT: u8 = 0x90;
+
+// R0 holds the packet loads and the return value, R1 to R5 are clobbered
+// by the packet loads, R6 must hold the context for them and R10 is the
+// frame pointer. R7 to R9 hold the source address, the protocol and the
+// length of the IP header while the rules are checked.
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R6: u8 = 6;
+const R7: u8 = 7;
+const R8: u8 = 8;
+const R9: u8 = 9;
+const R10: u8 = 10;
+
+// Stack slots of the destination address, and of both ports, the source
+// one in the upper 16 bits.
+const DST_IP_SLOT: i16 = -4;
+const PORTS_SLOT: i16 = -8;
+
+#[derive(Debug)]
+pub enum Error {
+    /// More rules than MAX_FLOW_RULES.
+    TooManyRules(usize),
+    /// The queue doesn't exist on the interface.
+    InvalidQueue(u16),
+    /// Only the TCP and UDP rules can match ports.
+    PortsRequireTcpOrUdp,
+    /// The kernel refused the program.
+    LoadProgram(io::Error),
+    /// The program can't be attached to the interface.
+    AttachProgram(TapError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Frames matching all the fields set are sent to the guest through the
+/// receive queue of the queue pair `queue`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlowRule {
+    pub protocol: Option<u8>,
+    pub src_ip: Option<Ipv4Addr>,
+    pub dst_ip: Option<Ipv4Addr>,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    pub queue: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct BpfInsn {
+    code: u8,
+    // Destination register in the lower 4 bits, source in the upper ones.
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> BpfInsn {
+    BpfInsn {
+        code,
+        regs: dst | src << 4,
+        off,
+        imm,
+    }
+}
+
+// Loads from the packet are converted to host byte order.
+fn ld_abs(size: u8, offset: i32) -> BpfInsn {
+    insn(BPF_LD | size | BPF_ABS, 0, 0, 0, offset)
+}
+
+fn ld_ind(size: u8, src: u8, offset: i32) -> BpfInsn {
+    insn(BPF_LD | size | BPF_IND, 0, src, 0, offset)
+}
+
+fn alu64_imm(op: u8, dst: u8, imm: i32) -> BpfInsn {
+    insn(BPF_ALU64 | op | BPF_K, dst, 0, 0, imm)
+}
+
+fn alu64_reg(op: u8, dst: u8, src: u8) -> BpfInsn {
+    insn(BPF_ALU64 | op | BPF_X, dst, src, 0, 0)
+}
+
+// Unlike the 64-bit one, the immediate isn't sign extended.
+fn mov32_imm(dst: u8, imm: u32) -> BpfInsn {
+    insn(BPF_ALU | BPF_MOV | BPF_K, dst, 0, 0, imm as i32)
+}
+
+fn load_slot(dst: u8, slot: i16) -> BpfInsn {
+    insn(BPF_LDX | BPF_MEM | BPF_W, dst, R10, slot, 0)
+}
+
+fn store_slot(slot: i16, src: u8) -> BpfInsn {
+    insn(BPF_STX | BPF_MEM | BPF_W, R10, src, slot, 0)
+}
+
+fn store_slot_imm(slot: i16, imm: i32) -> BpfInsn {
+    insn(BPF_ST | BPF_MEM | BPF_W, R10, 0, slot, imm)
+}
+
+fn jmp_imm(op: u8, dst: u8, imm: i32, off: i16) -> BpfInsn {
+    insn(BPF_JMP | op | BPF_K, dst, 0, off, imm)
+}
+
+fn jmp_reg(op: u8, dst: u8, src: u8, off: i16) -> BpfInsn {
+    insn(BPF_JMP | op | BPF_X, dst, src, off, 0)
+}
+
+fn exit() -> BpfInsn {
+    insn(BPF_JMP | BPF_EXIT, 0, 0, 0, 0)
+}
+
+// Checks each field the rule sets, jumping to the next rule as soon as one
+// doesn't match.
+fn rule_insns(rule: &FlowRule) -> Vec<BpfInsn> {
+    let mut insns = Vec::new();
+    let mut jumps = Vec::new();
+
+    if let Some(protocol) = rule.protocol {
+        jumps.push(insns.len());
+        insns.push(jmp_imm(BPF_JNE, R8, protocol.into(), 0));
+    }
+    if let Some(ip) = rule.src_ip {
+        insns.push(mov32_imm(R1, ip.into()));
+        jumps.push(insns.len());
+        insns.push(jmp_reg(BPF_JNE, R7, R1, 0));
+    }
+    if let Some(ip) = rule.dst_ip {
+        insns.push(load_slot(R0, DST_IP_SLOT));
+        insns.push(mov32_imm(R1, ip.into()));
+        jumps.push(insns.len());
+        insns.push(jmp_reg(BPF_JNE, R0, R1, 0));
+    }
+    if let Some(port) = rule.src_port {
+        insns.push(load_slot(R0, PORTS_SLOT));
+        insns.push(alu64_imm(BPF_RSH, R0, 16));
+        jumps.push(insns.len());
+        insns.push(jmp_imm(BPF_JNE, R0, port.into(), 0));
+    }
+    if let Some(port) = rule.dst_port {
+        insns.push(load_slot(R0, PORTS_SLOT));
+        insns.push(alu64_imm(BPF_AND, R0, 0xffff));
+        jumps.push(insns.len());
+        insns.push(jmp_imm(BPF_JNE, R0, port.into(), 0));
+    }
+    insns.push(alu64_imm(BPF_MOV, R0, rule.queue.into()));
+    insns.push(exit());
+
+    for jump in jumps {
+        insns[jump].off = (insns.len() - jump - 1) as i16;
+    }
+
+    insns
+}
+
+fn program(rules: &[FlowRule]) -> Vec<BpfInsn> {
+    let mut insns = vec![
+        alu64_reg(BPF_MOV, R6, R1),
+        ld_abs(BPF_H, ETHERTYPE_OFFSET),
+        jmp_imm(BPF_JEQ, R0, ETH_P_IP, 2),
+        alu64_imm(BPF_MOV, R0, 0),
+        exit(),
+        // Length of the IP header, from its number of 32-bit words.
+        ld_abs(BPF_B, IP_HEADER_OFFSET),
+        alu64_imm(BPF_AND, R0, 0xf),
+        alu64_imm(BPF_LSH, R0, 2),
+        alu64_reg(BPF_MOV, R9, R0),
+        ld_abs(BPF_B, IP_PROTOCOL_OFFSET),
+        alu64_reg(BPF_MOV, R8, R0),
+        ld_abs(BPF_W, IP_SRC_OFFSET),
+        alu64_reg(BPF_MOV, R7, R0),
+        ld_abs(BPF_W, IP_DST_OFFSET),
+        store_slot(DST_IP_SLOT, R0),
+        // The ports are only loaded for TCP and UDP, and left to 0 for the
+        // other protocols.
+        store_slot_imm(PORTS_SLOT, 0),
+        jmp_imm(BPF_JEQ, R8, IPPROTO_TCP.into(), 1),
+        jmp_imm(BPF_JNE, R8, IPPROTO_UDP.into(), 2),
+        ld_ind(BPF_W, R9, IP_HEADER_OFFSET),
+        store_slot(PORTS_SLOT, R0),
+    ];
+
+    for rule in rules {
+        insns.extend(rule_insns(rule));
+    }
+
+    // No rule matched, the tap interface takes the hash modulo the number
+    // of queues.
+    insns.extend(&[
+        alu64_reg(BPF_MOV, R0, R7),
+        load_slot(R1, DST_IP_SLOT),
+        alu64_reg(BPF_XOR, R0, R1),
+        load_slot(R1, PORTS_SLOT),
+        alu64_reg(BPF_XOR, R0, R1),
+        alu64_reg(BPF_MOV, R1, R0),
+        alu64_imm(BPF_RSH, R1, 16),
+        alu64_reg(BPF_XOR, R0, R1),
+        exit(),
+    ]);
+
+    insns
+}
+
+fn load_program(insns: &[BpfInsn]) -> io::Result<File> {
+    let license = b"Apache-2.0\0";
+    let attr = BpfProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_SOCKET_FILTER,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        ..Default::default()
+    };
+
+    // Safe because the attributes, and the buffers they point to, outlive
+    // the call, and the return value is checked.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_PROG_LOAD,
+            &attr as *const BpfProgLoadAttr,
+            mem::size_of::<BpfProgLoadAttr>(),
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because the file descriptor was just created, and is owned by
+    // no one else.
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+fn validate(rules: &[FlowRule], num_queue_pairs: usize) -> Result<()> {
+    if rules.len() > MAX_FLOW_RULES {
+        return Err(Error::TooManyRules(rules.len()));
+    }
+
+    for rule in rules {
+        if rule.queue as usize >= num_queue_pairs {
+            return Err(Error::InvalidQueue(rule.queue));
+        }
+        if (rule.src_port.is_some() || rule.dst_port.is_some())
+            && rule.protocol != Some(IPPROTO_TCP)
+            && rule.protocol != Some(IPPROTO_UDP)
+        {
+            return Err(Error::PortsRequireTcpOrUdp);
+        }
+    }
+
+    Ok(())
+}
+
+/// Programs the flow rules of the tap interface backing a virtio-net
+/// device, through one of its queues.
+#[derive(Clone)]
+pub struct FlowSteering {
+    tap: Tap,
+    num_queue_pairs: usize,
+}
+
+impl FlowSteering {
+    pub fn new(tap: Tap, num_queue_pairs: usize) -> Self {
+        FlowSteering {
+            tap,
+            num_queue_pairs,
+        }
+    }
+
+    /// Replaces the rules, the tap interface going back to its own queue
+    /// selection if there are none.
+    pub fn set_rules(&self, rules: &[FlowRule]) -> Result<()> {
+        validate(rules, self.num_queue_pairs)?;
+
+        if rules.is_empty() {
+            return self.tap.set_steering_ebpf(-1).map_err(Error::AttachProgram);
+        }
+
+        // The interface holds a reference to the program, which doesn't
+        // need to be kept open once attached.
+        let prog = load_program(&program(rules)).map_err(Error::LoadProgram)?;
+        self.tap
+            .set_steering_ebpf(prog.as_raw_fd())
+            .map_err(Error::AttachProgram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flow_rule_insns() {
+        let rule = FlowRule {
+            protocol: Some(IPPROTO_TCP),
+            dst_ip: Some(Ipv4Addr::new(192, 168, 1, 2)),
+            dst_port: Some(80),
+            queue: 3,
+            ..Default::default()
+        };
+
+        let insns = rule_insns(&rule);
+        assert_eq!(insns.len(), 9);
+        // Every mismatch jumps right after the exit returning the queue.
+        assert_eq!(insns[0].code, BPF_JMP | BPF_JNE | BPF_K);
+        assert_eq!(insns[0].off, 8);
+        assert_eq!(insns[2].imm, 0xc0a8_0102u32 as i32);
+        assert_eq!(insns[3].off, 5);
+        assert_eq!(insns[6].off, 2);
+        assert_eq!(insns[7].imm, 3);
+        assert_eq!(insns[8], exit());
+
+        // A rule without any field matches all the IPv4 frames.
+        let insns = rule_insns(&FlowRule::default());
+        assert_eq!(insns, vec![alu64_imm(BPF_MOV, R0, 0), exit()]);
+
+        let prog = program(&[rule.clone(), rule]);
+        assert_eq!(prog.len(), 20 + 2 * 9 + 9);
+        assert_eq!(prog.last(), Some(&exit()));
+    }
+
+    #[test]
+    fn test_flow_rules_validation() {
+        let rule = FlowRule {
+            protocol: Some(IPPROTO_UDP),
+            src_port: Some(53),
+            queue: 1,
+            ..Default::default()
+        };
+        assert!(validate(&[rule.clone()], 2).is_ok());
+        assert!(matches!(
+            validate(&[rule.clone()], 1),
+            Err(Error::InvalidQueue(1))
+        ));
+        assert!(matches!(
+            validate(&vec![rule.clone(); MAX_FLOW_RULES + 1], 2),
+            Err(Error::TooManyRules(_))
+        ));
+
+        let rule = FlowRule {
+            protocol: None,
+            ..rule
+        };
+        assert!(matches!(
+            validate(&[rule], 2),
+            Err(Error::PortsRequireTcpOrUdp)
+        ));
+    }
+}
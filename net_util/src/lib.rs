@@ -21,19 +21,32 @@ extern crate vm_memory;
 extern crate vm_virtio;
 extern crate vmm_sys_util;
 
+mod flow_steering;
 mod mac;
 mod open_tap;
+mod pcap;
 mod queue_pair;
 mod tap;
+mod vlan;
 
 use std::io::Error as IoError;
+use std::os::raw::c_uint;
 use std::os::unix::io::{FromRawFd, RawFd};
 use std::{io, mem, net};
-
+use virtio_bindings::bindings::virtio_net::{
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4,
+    VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO,
+};
+
+pub use flow_steering::{
+    Error as FlowSteeringError, FlowRule, FlowSteering, IPPROTO_TCP, IPPROTO_UDP, MAX_FLOW_RULES,
+};
 pub use mac::{MacAddr, MAC_ADDR_LEN};
 pub use open_tap::{open_tap, Error as OpenTapError};
+pub use pcap::{Direction, PacketCapture};
 pub use queue_pair::{NetCounters, NetQueuePair, NetQueuePairError, RxVirtio, TxVirtio};
 pub use tap::{Error as TapError, Tap};
+pub use vlan::{VlanFilter, VLAN_ID_COUNT};
 
 #[derive(Debug)]
 pub enum Error {
@@ -43,6 +56,14 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Offset from the start of the configuration space of a vhost-user-net
+/// backend, past the virtio-net fields, the MAC address the frames sent by
+/// the guest must come from is written to. Unlike a write to the `mac`
+/// field, which the guest can make too, it asks the backend to drop the
+/// frames sent from any other address, the backend reporting the address it
+/// filters on when read.
+pub const VHOST_USER_NET_ANTI_SPOOF_OFFSET: u32 = 0x100;
+
 /// Create a sockaddr_in from an IPv4 address, and expose it as
 /// an opaque sockaddr suitable for usage by socket ioctls.
 fn create_sockaddr(ip_addr: net::Ipv4Addr) -> net_gen::sockaddr {
@@ -74,6 +95,33 @@ fn vnet_hdr_len() -> usize {
     std::mem::size_of::<virtio_net_hdr_v1>()
 }
 
+/// Convert the offload features acked by the guest into the TAP offload
+/// flags, so that the TAP never sends the guest a frame with a checksum or
+/// segmentation type it didn't agree to receive.
+pub fn virtio_features_to_tap_offload(features: u64) -> c_uint {
+    let mut tap_offloads: c_uint = 0;
+
+    // Segmentation offloads are only valid along with checksum offload.
+    if features & (1 << VIRTIO_NET_F_GUEST_CSUM) != 0 {
+        tap_offloads |= net_gen::TUN_F_CSUM;
+
+        if features & (1 << VIRTIO_NET_F_GUEST_TSO4) != 0 {
+            tap_offloads |= net_gen::TUN_F_TSO4;
+        }
+        if features & (1 << VIRTIO_NET_F_GUEST_TSO6) != 0 {
+            tap_offloads |= net_gen::TUN_F_TSO6;
+        }
+        if features & (1 << VIRTIO_NET_F_GUEST_ECN) != 0 {
+            tap_offloads |= net_gen::TUN_F_TSO_ECN;
+        }
+        if features & (1 << VIRTIO_NET_F_GUEST_UFO) != 0 {
+            tap_offloads |= net_gen::TUN_F_UFO;
+        }
+    }
+
+    tap_offloads
+}
+
 pub fn register_listener(
     epoll_fd: RawFd,
     fd: RawFd,
@@ -125,4 +173,36 @@ mod tests {
         assert_eq!(data[4], 0);
         assert_eq!(data[5], 1);
     }
+
+    #[test]
+    fn test_virtio_features_to_tap_offload() {
+        assert_eq!(virtio_features_to_tap_offload(0), 0);
+
+        // Segmentation offloads are ignored without checksum offload.
+        assert_eq!(
+            virtio_features_to_tap_offload(
+                1 << VIRTIO_NET_F_GUEST_TSO4 | 1 << VIRTIO_NET_F_GUEST_TSO6
+            ),
+            0
+        );
+
+        assert_eq!(
+            virtio_features_to_tap_offload(1 << VIRTIO_NET_F_GUEST_CSUM),
+            net_gen::TUN_F_CSUM
+        );
+        assert_eq!(
+            virtio_features_to_tap_offload(
+                1 << VIRTIO_NET_F_GUEST_CSUM
+                    | 1 << VIRTIO_NET_F_GUEST_TSO4
+                    | 1 << VIRTIO_NET_F_GUEST_TSO6
+                    | 1 << VIRTIO_NET_F_GUEST_ECN
+                    | 1 << VIRTIO_NET_F_GUEST_UFO
+            ),
+            net_gen::TUN_F_CSUM
+                | net_gen::TUN_F_TSO4
+                | net_gen::TUN_F_TSO6
+                | net_gen::TUN_F_TSO_ECN
+                | net_gen::TUN_F_UFO
+        );
+    }
 }
@@ -0,0 +1,255 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Capture of the frames going through a virtio-net device into a pcap file,
+//! which Wireshark or tcpdump can open.
+//!
+//! The frames are copied and handed over to a thread writing the file, the
+//! ones coming while too many are waiting being dropped and counted rather
+//! than slowing down the datapath.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 65535;
+// Linux cooked capture, the only link type of the classic pcap format
+// telling whether a frame was sent or received.
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const SLL_HEADER_LEN: usize = 16;
+const SLL_HOST: u16 = 0;
+const SLL_OUTGOING: u16 = 4;
+const ARPHRD_ETHER: u16 = 1;
+
+const ETH_ADDR_LEN: usize = 6;
+const ETH_HEADER_LEN: usize = 14;
+
+// Frames waiting to be written, beyond which they are dropped.
+const CAPTURE_QUEUE_SIZE: usize = 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Direction of a frame, from the guest's point of view.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+struct Record {
+    timestamp: Duration,
+    direction: Direction,
+    frame: Vec<u8>,
+    len: usize,
+}
+
+struct Writer {
+    sender: SyncSender<Record>,
+    dropped: AtomicU64,
+}
+
+/// Shared between a virtio-net device and its queue pairs, so that the
+/// capture can be started and stopped while the datapath runs.
+#[derive(Clone, Default)]
+pub struct PacketCapture {
+    active: Arc<AtomicBool>,
+    writer: Arc<Mutex<Option<Writer>>>,
+}
+
+impl PacketCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts capturing into a new pcap file at `path`, ending the capture
+    /// in progress if any.
+    pub fn start(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        write_file_header(&mut file)?;
+
+        let (sender, receiver) = sync_channel(CAPTURE_QUEUE_SIZE);
+        thread::Builder::new()
+            .name("net_capture".to_string())
+            .spawn(move || write_records(file, receiver))?;
+
+        if let Some(dropped) = self.replace(Some(Writer {
+            sender,
+            dropped: AtomicU64::new(0),
+        })) {
+            info!("Previous capture ended, {} frames dropped", dropped);
+        }
+        self.active.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Stops the capture, returning the number of frames which were
+    /// dropped, or None if there was no capture in progress.
+    pub fn stop(&self) -> Option<u64> {
+        self.active.store(false, Ordering::Release);
+        self.replace(None)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Queues the Ethernet frame for writing, without the virtio-net header.
+    pub fn capture(&self, direction: Direction, frame: &[u8]) {
+        if !self.is_active() || frame.len() < ETH_HEADER_LEN {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        if let Some(writer) = self.writer.lock().unwrap().as_ref() {
+            let count = cmp::min(frame.len(), PCAP_SNAPLEN as usize - SLL_HEADER_LEN);
+            let record = Record {
+                timestamp,
+                direction,
+                frame: frame[..count].to_vec(),
+                len: frame.len(),
+            };
+            if writer.sender.try_send(record).is_err() {
+                writer.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Dropping the previous writer lets its thread write the frames left
+    // and close the file.
+    fn replace(&self, writer: Option<Writer>) -> Option<u64> {
+        let previous = std::mem::replace(&mut *self.writer.lock().unwrap(), writer);
+        previous.map(|writer| writer.dropped.into_inner())
+    }
+}
+
+fn write_file_header<W: Write>(file: &mut W) -> io::Result<()> {
+    file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+    file.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+    file.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+    // Timestamps are in UTC, with no timezone correction.
+    file.write_all(&0i32.to_le_bytes())?;
+    file.write_all(&0u32.to_le_bytes())?;
+    file.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+    file.write_all(&LINKTYPE_LINUX_SLL.to_le_bytes())
+}
+
+// The Ethernet header is replaced by the cooked one, holding the source
+// address and the EtherType along with the direction.
+fn write_record<W: Write>(file: &mut W, record: &Record) -> io::Result<()> {
+    let frame = &record.frame;
+    let payload = &frame[ETH_HEADER_LEN..];
+
+    file.write_all(&(record.timestamp.as_secs() as u32).to_le_bytes())?;
+    file.write_all(&record.timestamp.subsec_micros().to_le_bytes())?;
+    file.write_all(&((SLL_HEADER_LEN + payload.len()) as u32).to_le_bytes())?;
+    file.write_all(&((SLL_HEADER_LEN + record.len - ETH_HEADER_LEN) as u32).to_le_bytes())?;
+
+    let packet_type = match record.direction {
+        Direction::Rx => SLL_HOST,
+        Direction::Tx => SLL_OUTGOING,
+    };
+    let mut address = [0u8; 8];
+    address[..ETH_ADDR_LEN].copy_from_slice(&frame[ETH_ADDR_LEN..2 * ETH_ADDR_LEN]);
+    file.write_all(&packet_type.to_be_bytes())?;
+    file.write_all(&ARPHRD_ETHER.to_be_bytes())?;
+    file.write_all(&(ETH_ADDR_LEN as u16).to_be_bytes())?;
+    file.write_all(&address)?;
+    file.write_all(&frame[2 * ETH_ADDR_LEN..ETH_HEADER_LEN])?;
+    file.write_all(payload)
+}
+
+fn write_records(mut file: BufWriter<File>, receiver: Receiver<Record>) {
+    loop {
+        let result = match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(record) => write_record(&mut file, &record),
+            Err(RecvTimeoutError::Timeout) => file.flush(),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(e) = result {
+            error!("Failed to write the network capture: {}", e);
+            return;
+        }
+    }
+
+    if let Err(e) = file.flush() {
+        error!("Failed to write the network capture: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcap_record() {
+        let mut frame = vec![0u8; 60];
+        frame[..6].copy_from_slice(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        frame[6..12].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef, 0x12, 0x34]);
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame[14] = 0x45;
+        let record = Record {
+            timestamp: Duration::new(1_600_000_000, 123_456_000),
+            direction: Direction::Tx,
+            frame: frame[..20].to_vec(),
+            len: frame.len(),
+        };
+
+        let mut data = Vec::new();
+        write_record(&mut data, &record).unwrap();
+        assert_eq!(data.len(), 16 + SLL_HEADER_LEN + 6);
+        assert_eq!(&data[0..4], &1_600_000_000u32.to_le_bytes());
+        assert_eq!(&data[4..8], &123_456u32.to_le_bytes());
+        // The frame was truncated.
+        assert_eq!(&data[8..12], &(SLL_HEADER_LEN as u32 + 6).to_le_bytes());
+        assert_eq!(&data[12..16], &(SLL_HEADER_LEN as u32 + 46).to_le_bytes());
+        assert_eq!(&data[16..18], &SLL_OUTGOING.to_be_bytes());
+        assert_eq!(&data[22..28], &frame[6..12]);
+        assert_eq!(&data[30..32], &[0x08, 0x00]);
+        assert_eq!(data[32], 0x45);
+    }
+
+    #[test]
+    fn test_packet_capture() {
+        let dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let path = dir.as_path().join("capture.pcap");
+        let capture = PacketCapture::new();
+
+        // Nothing is captured until the capture starts.
+        capture.capture(Direction::Rx, &[0u8; 64]);
+        assert_eq!(capture.stop(), None);
+
+        capture.start(&path).unwrap();
+        assert!(capture.is_active());
+        capture.capture(Direction::Rx, &[0u8; 64]);
+        assert_eq!(capture.stop(), Some(0));
+        assert!(!capture.is_active());
+
+        // The writer thread is done with the file once it has flushed the
+        // frame in flight.
+        let expected_len = 24 + 16 + SLL_HEADER_LEN + 64 - ETH_HEADER_LEN;
+        for _ in 0..50 {
+            if std::fs::metadata(&path).unwrap().len() == expected_len as u64 {
+                break;
+            }
+            thread::sleep(FLUSH_INTERVAL);
+        }
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), expected_len);
+        assert_eq!(&data[0..4], &PCAP_MAGIC.to_le_bytes());
+        assert_eq!(&data[20..24], &LINKTYPE_LINUX_SLL.to_le_bytes());
+    }
+}
@@ -2,29 +2,62 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use super::{register_listener, unregister_listener, vnet_hdr_len, Tap};
+use super::{
+    register_listener, unregister_listener, vnet_hdr_len, Direction, MacAddr, PacketCapture, Tap,
+    VlanFilter, MAC_ADDR_LEN,
+};
 use libc::EAGAIN;
 use std::cmp;
 use std::io;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
-use vm_virtio::{DescriptorChain, Queue};
+use vm_memory::{
+    Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
+    GuestMemoryError, GuestMemoryMmap,
+};
+use vm_virtio::{DescriptorChainView, DescriptorInspector, Queue, Verdict};
 
 /// The maximum buffer size when segmentation offload is enabled. This
 /// includes the 12-byte virtio net header.
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
 const MAX_BUFFER_SIZE: usize = 65562;
 
+/// Offset of the flags field in the virtio net header.
+const VNET_HDR_FLAGS_OFFSET: usize = 0;
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_F_DATA_VALID: u8 = 2;
+
+/// Offset of the num_buffers field in the virtio net header.
+const VNET_HDR_NUM_BUFFERS_OFFSET: usize = 10;
+
+/// Offset of the gso_type field in the virtio net header, followed by the
+/// hdr_len and gso_size fields.
+const VNET_HDR_GSO_TYPE_OFFSET: usize = 1;
+const VNET_HDR_GSO_LEN: usize = 5;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+/// Offset of the source MAC address in an Ethernet frame.
+const ETH_SOURCE_OFFSET: usize = 6;
+
 #[derive(Clone)]
 pub struct TxVirtio {
     pub iovec: Vec<(GuestAddress, usize)>,
     pub frame_buf: [u8; MAX_BUFFER_SIZE],
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    pub counter_spoofed_frames: Wrapping<u64>,
+    pub capture: PacketCapture,
+    // When set, the frames whose source MAC address differs from this one
+    // are dropped instead of being sent.
+    pub anti_spoof: Option<MacAddr>,
+    // When set, the hook is given each frame, virtio-net header included,
+    // and the frames it drops aren't sent.
+    pub inspector: Option<Arc<dyn DescriptorInspector>>,
+    pub queue_index: u16,
 }
 
 impl Default for TxVirtio {
@@ -40,10 +73,34 @@ impl TxVirtio {
             frame_buf: [0u8; MAX_BUFFER_SIZE],
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            counter_spoofed_frames: Wrapping(0),
+            capture: PacketCapture::new(),
+            anti_spoof: None,
+            inspector: None,
+            queue_index: 0,
+        }
+    }
+
+    // Whether the frame, virtio-net header included, must not be sent as
+    // it doesn't carry the expected source MAC address.
+    fn spoofed(&self, frame: &[u8]) -> bool {
+        match &self.anti_spoof {
+            Some(mac) => {
+                let source = vnet_hdr_len() + ETH_SOURCE_OFFSET;
+                frame
+                    .get(source..source + MAC_ADDR_LEN)
+                    .map_or(true, |source| source != mac.get_bytes())
+            }
+            None => false,
         }
     }
 
-    pub fn process_desc_chain(&mut self, mem: &GuestMemoryMmap, tap: &mut Tap, queue: &mut Queue) {
+    pub fn process_desc_chain<W: Write>(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        tap: &mut W,
+        queue: &mut Queue,
+    ) {
         while let Some(avail_desc) = queue.iter(&mem).next() {
             let head_index = avail_desc.index;
             let mut read_count = 0;
@@ -59,6 +116,20 @@ impl TxVirtio {
                 next_desc = desc.next_descriptor();
             }
 
+            // A truncated frame would carry a virtio-net header describing
+            // segments which don't match the payload, drop it instead.
+            if read_count > self.frame_buf.len() {
+                warn!(
+                    "net: tx: dropping frame of {} bytes, larger than {} bytes",
+                    read_count,
+                    self.frame_buf.len()
+                );
+                self.iovec.clear();
+                queue.add_used(&mem, head_index, 0);
+                queue.update_avail_event(&mem);
+                continue;
+            }
+
             read_count = 0;
             // Copy buffer from across multiple descriptors.
             // TODO(performance - Issue #420): change this to use `writev()` instead of `write()`
@@ -80,6 +151,31 @@ impl TxVirtio {
                 }
             }
 
+            if let Some(inspector) = &self.inspector {
+                let chain = DescriptorChainView {
+                    queue_index: self.queue_index,
+                    head_index,
+                    data: &self.frame_buf[..read_count],
+                };
+                if inspector.inspect(&chain) == Verdict::Drop {
+                    queue.add_used(&mem, head_index, 0);
+                    queue.update_avail_event(&mem);
+                    continue;
+                }
+            }
+
+            if self.spoofed(&self.frame_buf[..read_count]) {
+                self.counter_spoofed_frames += Wrapping(1);
+                queue.add_used(&mem, head_index, 0);
+                queue.update_avail_event(&mem);
+                continue;
+            }
+
+            if read_count > vnet_hdr_len() {
+                self.capture
+                    .capture(Direction::Tx, &self.frame_buf[vnet_hdr_len()..read_count]);
+            }
+
             let write_result = tap.write(&self.frame_buf[..read_count]);
             match write_result {
                 Ok(_) => {}
@@ -99,12 +195,32 @@ impl TxVirtio {
 
 #[derive(Clone)]
 pub struct RxVirtio {
-    pub deferred_frame: bool,
     pub deferred_irqs: bool,
-    pub bytes_read: usize,
-    pub frame_buf: [u8; MAX_BUFFER_SIZE],
+    // Whether VIRTIO_NET_F_MRG_RXBUF has been negotiated, allowing a frame
+    // to span several descriptor chains.
+    pub mrg_rxbuf: bool,
+    // Set once VIRTIO_NET_F_CTRL_VLAN has been negotiated, the frames tagged
+    // with a VLAN the guest didn't add being dropped.
+    pub vlan_filter: Option<VlanFilter>,
+    // Set when the frames come from trusted local sources, their checksum
+    // being reported as valid so that the guest skips verifying it. This
+    // requires VIRTIO_NET_F_GUEST_CSUM to be negotiated.
+    pub data_valid: bool,
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    // Frames the host coalesced from several segments, along with the
+    // number of segments they were made of.
+    pub counter_coalesced_frames: Wrapping<u64>,
+    pub counter_coalesced_segments: Wrapping<u64>,
+    pub capture: PacketCapture,
+    // Descriptor chains and buffers the current frame is read into, kept
+    // from one frame to the other not to allocate them for every frame.
+    chains: Vec<RxChain>,
+    buffers: Vec<(GuestAddress, usize)>,
+    iovecs: IoVecs,
+    // Receives whatever doesn't fit in the buffers of the guest, telling
+    // the frames which would otherwise be truncated.
+    overflow: Box<[u8]>,
 }
 
 impl Default for RxVirtio {
@@ -113,78 +229,398 @@ impl Default for RxVirtio {
     }
 }
 
+// Writable buffers of a descriptor chain, along with how much of them the
+// frame uses.
+#[derive(Clone, Copy)]
+struct RxChain {
+    head_index: u16,
+    len: usize,
+    used: u32,
+}
+
+// iovecs pointing to the guest buffers a frame is read into.
+#[derive(Default)]
+struct IoVecs(Vec<libc::iovec>);
+
+// SAFETY: The iovecs only point to guest memory while a frame is being read,
+// they are cleared before the next one.
+unsafe impl Send for IoVecs {}
+// SAFETY: See above.
+unsafe impl Sync for IoVecs {}
+
+impl Clone for IoVecs {
+    fn clone(&self) -> Self {
+        IoVecs::default()
+    }
+}
+
 impl RxVirtio {
     pub fn new() -> Self {
         RxVirtio {
-            deferred_frame: false,
             deferred_irqs: false,
-            bytes_read: 0,
-            frame_buf: [0u8; MAX_BUFFER_SIZE],
+            mrg_rxbuf: false,
+            vlan_filter: None,
+            data_valid: false,
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            counter_coalesced_frames: Wrapping(0),
+            counter_coalesced_segments: Wrapping(0),
+            capture: PacketCapture::new(),
+            chains: Vec::new(),
+            buffers: Vec::new(),
+            iovecs: IoVecs::default(),
+            overflow: vec![0u8; MAX_BUFFER_SIZE].into_boxed_slice(),
         }
     }
 
-    pub fn process_desc_chain(
+    // Number of segments a frame coalesced by the host is made of, from the
+    // gso_type, hdr_len and gso_size fields the TAP filled its virtio net
+    // header with, or None if the frame wasn't coalesced.
+    fn coalesced_segments(gso: &[u8; VNET_HDR_GSO_LEN], frame_len: usize) -> Option<u64> {
+        if gso[0] & !VIRTIO_NET_HDR_GSO_ECN == VIRTIO_NET_HDR_GSO_NONE {
+            return None;
+        }
+        let hdr_len = u16::from_le_bytes([gso[1], gso[2]]) as usize;
+        let gso_size = u16::from_le_bytes([gso[3], gso[4]]) as usize;
+        if gso_size == 0 {
+            return None;
+        }
+
+        let payload_len = frame_len.saturating_sub(hdr_len);
+        Some(cmp::max((payload_len + gso_size - 1) / gso_size, 1) as u64)
+    }
+
+    // Reads as many frames as possible from the TAP, each frame being
+    // directly read into the buffers of the descriptor chains, without any
+    // intermediate copy. With mergeable buffers, descriptor chains are
+    // gathered until they can hold the largest frame, the chains left unused
+    // by the frame being given back to the queue. The frames too large for
+    // the buffers are dropped rather than truncated. Returns false if the
+    // queue ran out of descriptors before the TAP could be drained.
+    pub fn process_desc_chain<T: AsRawFd>(
         &mut self,
         mem: &GuestMemoryMmap,
-        mut next_desc: Option<DescriptorChain>,
+        tap: &T,
         queue: &mut Queue,
-    ) -> bool {
-        let head_index = next_desc.as_ref().unwrap().index;
-        let mut write_count = 0;
-
-        // Copy from frame into buffer, which may span multiple descriptors.
+    ) -> Result<bool, NetQueuePairError> {
         loop {
-            match next_desc {
-                Some(desc) => {
-                    if !desc.is_write_only() {
+            self.chains.clear();
+            self.buffers.clear();
+
+            let chain = match Self::next_chain(mem, queue, &mut self.buffers) {
+                Some(chain) => chain,
+                None => return Ok(false),
+            };
+            if chain.len == 0 {
+                warn!("net: rx: descriptor chain without any writable buffer");
+                queue.add_used(&mem, chain.head_index, 0);
+                queue.update_avail_event(&mem);
+                continue;
+            }
+
+            self.chains.push(chain);
+            let mut capacity = chain.len;
+            while self.mrg_rxbuf && capacity < MAX_BUFFER_SIZE {
+                let buffers_len = self.buffers.len();
+                match Self::next_chain(mem, queue, &mut self.buffers) {
+                    Some(chain) if chain.len > 0 => {
+                        capacity += chain.len;
+                        self.chains.push(chain);
+                    }
+                    Some(_) => {
+                        // Left for the next frame, so that the chains of a
+                        // frame stay contiguous in the used ring.
+                        self.buffers.truncate(buffers_len);
+                        queue.go_to_previous_position();
                         break;
                     }
-                    let limit = cmp::min(write_count + desc.len as usize, self.bytes_read);
-                    let source_slice = &self.frame_buf[write_count..limit];
-                    let write_result = mem.write_slice(source_slice, desc.addr);
-
-                    match write_result {
-                        Ok(_) => {
-                            write_count = limit;
+                    None => {
+                        // Reading the frame now could truncate it, so wait
+                        // for the guest to make more buffers available,
+                        // unless the whole queue has already been gathered.
+                        if self.chains.len() < queue.actual_size() as usize {
+                            self.rewind_chains(queue);
+                            queue.update_avail_event(&mem);
+                            return Ok(false);
                         }
-                        Err(e) => {
-                            error!("Failed to write slice: {:?}", e);
-                            break;
-                        }
-                    };
+                        break;
+                    }
+                }
+            }
 
-                    if write_count >= self.bytes_read {
+            self.iovecs.0.clear();
+            let mut invalid_buffer = None;
+            for (addr, len) in self.buffers.iter() {
+                match mem.get_slice(*addr, *len) {
+                    Ok(buf) => self.iovecs.0.push(libc::iovec {
+                        iov_base: buf.as_ptr() as *mut libc::c_void,
+                        iov_len: buf.len(),
+                    }),
+                    Err(e) => {
+                        invalid_buffer = Some(e);
                         break;
                     }
-                    next_desc = desc.next_descriptor();
                 }
-                None => {
-                    warn!("Receiving buffer is too small to hold frame of current size");
+            }
+            if let Some(e) = invalid_buffer {
+                // The chains are given back to the guest, empty, rather
+                // than being lost.
+                error!("net: rx: invalid descriptor buffer: {:?}", e);
+                self.iovecs.0.clear();
+                self.release_chains(mem, queue);
+                continue;
+            }
+            self.iovecs.0.push(libc::iovec {
+                iov_base: self.overflow.as_mut_ptr() as *mut libc::c_void,
+                iov_len: self.overflow.len(),
+            });
+
+            // Safe because the iovecs point to guest memory which is mapped
+            // as long as `mem` is alive and to the overflow buffer, and we
+            // check the return value.
+            let result = unsafe {
+                libc::readv(
+                    tap.as_raw_fd(),
+                    self.iovecs.0.as_ptr(),
+                    self.iovecs.0.len() as libc::c_int,
+                )
+            };
+            self.iovecs.0.clear();
+            if result < 0 {
+                let e = io::Error::last_os_error();
+                // Give the descriptor chains back as nothing was read.
+                self.rewind_chains(queue);
+
+                // The tap device is non-blocking, so any error aside from
+                // EAGAIN is unexpected.
+                match e.raw_os_error() {
+                    Some(err) if err == EAGAIN => return Ok(true),
+                    _ => {
+                        error!("Failed to read tap: {:?}", e);
+                        return Err(NetQueuePairError::FailedReadTap);
+                    }
+                }
+            }
+
+            let len = result as usize;
+            if len > capacity {
+                warn!(
+                    "net: rx: dropping frame of {} bytes, larger than the {} bytes of buffers",
+                    len, capacity
+                );
+                // The chains are reused for the next frame.
+                self.rewind_chains(queue);
+                continue;
+            }
+
+            if let Some(vlan_filter) = &self.vlan_filter {
+                let mut header = [0u8; VlanFilter::HEADER_LEN];
+                let count = cmp::min(len.saturating_sub(vnet_hdr_len()), header.len());
+                // The frame is accepted if its header can't be read back, as it
+                // can't be told apart from the frames of the guest VLANs.
+                let accepts = match Self::read_at_offset(
+                    mem,
+                    &self.buffers,
+                    vnet_hdr_len(),
+                    &mut header[..count],
+                ) {
+                    Ok(()) => vlan_filter.accepts(&header[..count]),
+                    Err(e) => {
+                        error!("net: rx: failed reading the frame header: {:?}", e);
+                        true
+                    }
+                };
+                if !accepts {
+                    // The chains are reused for the next frame.
+                    self.rewind_chains(queue);
+                    continue;
+                }
+            }
+
+            // The frame is only copied out of the guest memory while it's
+            // being captured, the overflow buffer being free by then. A frame
+            // which can't be read back is still delivered to the guest.
+            if self.capture.is_active() && len > vnet_hdr_len() {
+                let frame_len = cmp::min(len - vnet_hdr_len(), self.overflow.len());
+                let frame = &mut self.overflow[..frame_len];
+                match Self::read_at_offset(mem, &self.buffers, vnet_hdr_len(), frame) {
+                    Ok(()) => self.capture.capture(Direction::Rx, frame),
+                    Err(e) => error!("net: rx: failed capturing the frame: {:?}", e),
+                }
+            }
+
+            // Split the frame across the chains, in order.
+            let mut used_chains = 0;
+            let mut remaining = len;
+            for chain in self.chains.iter_mut() {
+                if remaining == 0 && used_chains > 0 {
                     break;
                 }
+                let count = cmp::min(chain.len, remaining);
+                chain.used = count as u32;
+                remaining -= count;
+                used_chains += 1;
+            }
+            for _ in used_chains..self.chains.len() {
+                queue.go_to_previous_position();
             }
+            self.chains.truncate(used_chains);
+
+            if len >= vnet_hdr_len() {
+                // The TAP fills the GSO and checksum fields of the virtio-net
+                // header but leaves num_buffers untouched, while it must be
+                // the number of descriptor chains the frame spans.
+                if let Err(e) = Self::write_at_offset(
+                    mem,
+                    &self.buffers,
+                    VNET_HDR_NUM_BUFFERS_OFFSET,
+                    &(used_chains as u16).to_le_bytes(),
+                ) {
+                    error!("net: rx: failed writing the virtio net header: {:?}", e);
+                    self.release_chains(mem, queue);
+                    continue;
+                }
+
+                // The frames the TAP leaves a partial checksum in keep
+                // NEEDS_CSUM, as the guest must still complete it.
+                if self.data_valid {
+                    if let Err(e) = self.set_data_valid(mem) {
+                        error!("net: rx: failed setting DATA_VALID: {:?}", e);
+                    }
+                }
+
+                self.counter_bytes += Wrapping((len - vnet_hdr_len()) as u64);
+
+                // Only the counters miss the frame if its header can't be
+                // read back.
+                let mut gso = [0u8; VNET_HDR_GSO_LEN];
+                match Self::read_at_offset(mem, &self.buffers, VNET_HDR_GSO_TYPE_OFFSET, &mut gso) {
+                    Ok(()) => {
+                        if let Some(segments) = Self::coalesced_segments(&gso, len - vnet_hdr_len())
+                        {
+                            self.counter_coalesced_frames += Wrapping(1);
+                            self.counter_coalesced_segments += Wrapping(segments);
+                        }
+                    }
+                    Err(e) => error!("net: rx: failed reading the GSO header: {:?}", e),
+                }
+            }
+            self.counter_frames += Wrapping(1);
+
+            // The whole frame is made visible to the guest once every chain
+            // has been filled.
+            for chain in self.chains.iter() {
+                queue.add_used(&mem, chain.head_index, chain.used);
+            }
+            queue.update_avail_event(&mem);
+
+            // Mark that we have at least one pending packet and we need to interrupt the guest.
+            self.deferred_irqs = true;
         }
+    }
 
-        self.counter_bytes += Wrapping((write_count - vnet_hdr_len()) as u64);
-        self.counter_frames += Wrapping(1);
+    fn set_data_valid(&self, mem: &GuestMemoryMmap) -> Result<(), GuestMemoryError> {
+        let mut flags = [0u8; 1];
+        Self::read_at_offset(mem, &self.buffers, VNET_HDR_FLAGS_OFFSET, &mut flags)?;
+        if flags[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 {
+            flags[0] |= VIRTIO_NET_HDR_F_DATA_VALID;
+            Self::write_at_offset(mem, &self.buffers, VNET_HDR_FLAGS_OFFSET, &flags)?;
+        }
+        Ok(())
+    }
 
-        queue.add_used(&mem, head_index, write_count as u32);
-        queue.update_avail_event(&mem);
+    // Gives the chains of the current frame back to the queue, for the next
+    // frame to use them.
+    fn rewind_chains(&self, queue: &mut Queue) {
+        for _ in self.chains.iter() {
+            queue.go_to_previous_position();
+        }
+    }
 
-        // Mark that we have at least one pending packet and we need to interrupt the guest.
+    // Returns the chains of the current frame to the guest, without any
+    // data, when they can't be used.
+    fn release_chains(&mut self, mem: &GuestMemoryMmap, queue: &mut Queue) {
+        for chain in self.chains.iter() {
+            queue.add_used(&mem, chain.head_index, 0);
+        }
+        queue.update_avail_event(&mem);
         self.deferred_irqs = true;
+    }
 
-        // Update the frame_buf buffer.
-        if write_count < self.bytes_read {
-            self.frame_buf.copy_within(write_count..self.bytes_read, 0);
-            self.bytes_read -= write_count;
-            false
-        } else {
-            self.bytes_read = 0;
-            true
+    fn next_chain(
+        mem: &GuestMemoryMmap,
+        queue: &mut Queue,
+        buffers: &mut Vec<(GuestAddress, usize)>,
+    ) -> Option<RxChain> {
+        let avail_desc = queue.iter(&mem).next()?;
+
+        let mut chain = RxChain {
+            head_index: avail_desc.index,
+            len: 0,
+            used: 0,
+        };
+        let mut next_desc = Some(avail_desc);
+        while let Some(desc) = next_desc {
+            if desc.is_write_only() && desc.len > 0 {
+                buffers.push((desc.addr, desc.len as usize));
+                chain.len += desc.len as usize;
+            }
+            next_desc = desc.next_descriptor();
+        }
+
+        Some(chain)
+    }
+
+    // Reads `data` at `offset` from the start of the buffers, the data
+    // being possibly split across several of them.
+    fn read_at_offset(
+        mem: &GuestMemoryMmap,
+        buffers: &[(GuestAddress, usize)],
+        mut offset: usize,
+        mut data: &mut [u8],
+    ) -> Result<(), GuestMemoryError> {
+        for (addr, len) in buffers {
+            if data.is_empty() {
+                break;
+            }
+            if offset >= *len {
+                offset -= len;
+                continue;
+            }
+
+            let count = cmp::min(len - offset, data.len());
+            mem.read_slice(&mut data[..count], addr.unchecked_add(offset as u64))?;
+            data = &mut std::mem::take(&mut data)[count..];
+            offset = 0;
+        }
+
+        Ok(())
+    }
+
+    // Writes `data` at `offset` from the start of the buffers, the data
+    // being possibly split across several of them.
+    fn write_at_offset(
+        mem: &GuestMemoryMmap,
+        buffers: &[(GuestAddress, usize)],
+        mut offset: usize,
+        mut data: &[u8],
+    ) -> Result<(), GuestMemoryError> {
+        for (addr, len) in buffers {
+            if data.is_empty() {
+                break;
+            }
+            if offset >= *len {
+                offset -= len;
+                continue;
+            }
+
+            let count = cmp::min(len - offset, data.len());
+            mem.write_slice(&data[..count], addr.unchecked_add(offset as u64))?;
+            data = &data[count..];
+            offset = 0;
         }
+
+        Ok(())
     }
 }
 
@@ -192,8 +628,11 @@ impl RxVirtio {
 pub struct NetCounters {
     pub tx_bytes: Arc<AtomicU64>,
     pub tx_frames: Arc<AtomicU64>,
+    pub tx_spoofed_frames: Arc<AtomicU64>,
     pub rx_bytes: Arc<AtomicU64>,
     pub rx_frames: Arc<AtomicU64>,
+    pub rx_coalesced_frames: Arc<AtomicU64>,
+    pub rx_coalesced_segments: Arc<AtomicU64>,
 }
 
 #[derive(Debug)]
@@ -206,6 +645,8 @@ pub enum NetQueuePairError {
     UnregisterListener(io::Error),
     /// Error reading from the TAP device
     FailedReadTap,
+    /// Error accessing guest memory
+    GuestMemory(GuestMemoryError),
 }
 
 pub struct NetQueuePair {
@@ -220,60 +661,26 @@ pub struct NetQueuePair {
 }
 
 impl NetQueuePair {
-    // Copies a single frame from `self.rx.frame_buf` into the guest. Returns true
-    // if a buffer was used, and false if the frame must be deferred until a buffer
-    // is made available by the driver.
-    fn rx_single_frame(&mut self, mut queue: &mut Queue) -> Result<bool, NetQueuePairError> {
+    fn process_rx(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
         let mem = self
             .mem
             .as_ref()
             .ok_or(NetQueuePairError::NoMemoryConfigured)
             .map(|m| m.memory())?;
-        let next_desc = queue.iter(&mem).next();
-
-        if next_desc.is_none() {
-            // Queue has no available descriptors
-            if self.rx_tap_listening {
-                unregister_listener(
-                    self.epoll_fd.unwrap(),
-                    self.tap.as_raw_fd(),
-                    epoll::Events::EPOLLIN,
-                    u64::from(self.tap_event_id),
-                )
-                .map_err(NetQueuePairError::UnregisterListener)?;
-                self.rx_tap_listening = false;
-                info!("Listener unregistered");
-            }
-            return Ok(false);
-        }
-
-        Ok(self.rx.process_desc_chain(&mem, next_desc, &mut queue))
-    }
 
-    fn process_rx(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
         // Read as many frames as possible.
-        loop {
-            match self.read_tap() {
-                Ok(count) => {
-                    self.rx.bytes_read = count;
-                    if !self.rx_single_frame(queue)? {
-                        self.rx.deferred_frame = true;
-                        break;
-                    }
-                }
-                Err(e) => {
-                    // The tap device is non-blocking, so any error aside from EAGAIN is
-                    // unexpected.
-                    match e.raw_os_error() {
-                        Some(err) if err == EAGAIN => (),
-                        _ => {
-                            error!("Failed to read tap: {:?}", e);
-                            return Err(NetQueuePairError::FailedReadTap);
-                        }
-                    };
-                    break;
-                }
-            }
+        if !self.rx.process_desc_chain(&mem, &self.tap, queue)? && self.rx_tap_listening {
+            // The remaining frames are left in the TAP until the driver
+            // makes more descriptors available.
+            unregister_listener(
+                self.epoll_fd.unwrap(),
+                self.tap.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                u64::from(self.tap_event_id),
+            )
+            .map_err(NetQueuePairError::UnregisterListener)?;
+            self.rx_tap_listening = false;
+            info!("Listener unregistered");
         }
 
         // Consume the counters from the Rx/Tx queues and accumulate into
@@ -285,16 +692,20 @@ impl NetQueuePair {
         self.counters
             .rx_frames
             .fetch_add(self.rx.counter_frames.0, Ordering::AcqRel);
+        self.counters
+            .rx_coalesced_frames
+            .fetch_add(self.rx.counter_coalesced_frames.0, Ordering::AcqRel);
+        self.counters
+            .rx_coalesced_segments
+            .fetch_add(self.rx.counter_coalesced_segments.0, Ordering::AcqRel);
         self.rx.counter_bytes = Wrapping(0);
         self.rx.counter_frames = Wrapping(0);
+        self.rx.counter_coalesced_frames = Wrapping(0);
+        self.rx.counter_coalesced_segments = Wrapping(0);
 
+        // A single notification is sent for all the frames received.
         if self.rx.deferred_irqs {
             self.rx.deferred_irqs = false;
-            let mem = self
-                .mem
-                .as_ref()
-                .ok_or(NetQueuePairError::NoMemoryConfigured)
-                .map(|m| m.memory())?;
             Ok(queue.needs_notification(&mem, queue.next_used))
         } else {
             Ok(false)
@@ -312,24 +723,10 @@ impl NetQueuePair {
             .map_err(NetQueuePairError::RegisterListener)?;
             self.rx_tap_listening = true;
             info!("Listener registered");
-        }
-        if self.rx.deferred_frame {
-            if self.rx_single_frame(queue)? {
-                self.rx.deferred_frame = false;
-                // process_rx() was interrupted possibly before consuming all
-                // packets in the tap; try continuing now.
-                self.process_rx(queue)
-            } else if self.rx.deferred_irqs {
-                self.rx.deferred_irqs = false;
-                let mem = self
-                    .mem
-                    .as_ref()
-                    .ok_or(NetQueuePairError::NoMemoryConfigured)
-                    .map(|m| m.memory())?;
-                Ok(queue.needs_notification(&mem, queue.next_used))
-            } else {
-                Ok(false)
-            }
+
+            // Frames might have been left in the TAP while the queue was
+            // out of descriptors, try receiving them now.
+            self.process_rx(queue)
         } else {
             Ok(false)
         }
@@ -349,32 +746,430 @@ impl NetQueuePair {
         self.counters
             .tx_frames
             .fetch_add(self.tx.counter_frames.0, Ordering::AcqRel);
+        self.counters
+            .tx_spoofed_frames
+            .fetch_add(self.tx.counter_spoofed_frames.0, Ordering::AcqRel);
         self.tx.counter_bytes = Wrapping(0);
         self.tx.counter_frames = Wrapping(0);
+        self.tx.counter_spoofed_frames = Wrapping(0);
 
         Ok(queue.needs_notification(&mem, queue.next_used))
     }
 
-    pub fn process_rx_tap(&mut self, mut queue: &mut Queue) -> Result<bool, NetQueuePairError> {
-        if self.rx.deferred_frame
-        // Process a deferred frame first if available. Don't read from tap again
-        // until we manage to receive this deferred frame.
-        {
-            if self.rx_single_frame(&mut queue)? {
-                self.rx.deferred_frame = false;
-                self.process_rx(&mut queue)
-            } else if self.rx.deferred_irqs {
-                self.rx.deferred_irqs = false;
-                Ok(true)
-            } else {
-                Ok(false)
+    pub fn process_rx_tap(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
+        self.process_rx(queue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
+
+    #[test]
+    fn test_rx_coalesced_segments() {
+        // gso_type, hdr_len and gso_size
+        let gso = |gso_type: u8, hdr_len: u16, gso_size: u16| {
+            let mut gso = [gso_type, 0, 0, 0, 0];
+            gso[1..3].copy_from_slice(&hdr_len.to_le_bytes());
+            gso[3..5].copy_from_slice(&gso_size.to_le_bytes());
+            gso
+        };
+
+        assert_eq!(RxVirtio::coalesced_segments(&gso(0, 0, 0), 1514), None);
+        assert_eq!(RxVirtio::coalesced_segments(&gso(1, 66, 0), 1514), None);
+        // 10 full TCP segments over IPv4 and a partial one
+        assert_eq!(
+            RxVirtio::coalesced_segments(&gso(1, 66, 1448), 66 + 10 * 1448 + 100),
+            Some(11)
+        );
+        // TCP over IPv6 with ECN
+        assert_eq!(
+            RxVirtio::coalesced_segments(&gso(4 | 0x80, 86, 1428), 86 + 4 * 1428),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn test_rx_coalesced_counters() {
+        const BUFFER_SIZE: usize = 0x1_0000;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = guest_queue.create_queue();
+        for i in 0..2 {
+            let addr = 0x1_0000 + (i * BUFFER_SIZE) as u64;
+            guest_queue.dtable[i].set(addr, BUFFER_SIZE as u32, VIRTQ_DESC_F_WRITE, 0);
+            guest_queue.avail.ring[i].set(i as u16);
+        }
+        guest_queue.avail.idx.set(2);
+
+        // A TCP over IPv4 frame coalesced from 3 segments, followed by a
+        // frame which wasn't coalesced.
+        let mut coalesced_frame = vec![0u8; vnet_hdr_len() + 66 + 3 * 1448];
+        coalesced_frame[VNET_HDR_GSO_TYPE_OFFSET] = 1;
+        coalesced_frame[2..4].copy_from_slice(&66u16.to_le_bytes());
+        coalesced_frame[4..6].copy_from_slice(&1448u16.to_le_bytes());
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        sender.send(&coalesced_frame).unwrap();
+        sender.send(&vec![0u8; vnet_hdr_len() + 100]).unwrap();
+
+        let mut rx = RxVirtio::new();
+        assert!(rx.process_desc_chain(&mem, &receiver, &mut queue).unwrap());
+        assert_eq!(rx.counter_frames, Wrapping(2));
+        assert_eq!(rx.counter_coalesced_frames, Wrapping(1));
+        assert_eq!(rx.counter_coalesced_segments, Wrapping(3));
+    }
+
+    #[test]
+    fn test_rx_mergeable_buffers() {
+        const BUFFER_SIZE: usize = 1536;
+        const BUFFERS_START: u64 = 0x1_0000;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = guest_queue.create_queue();
+        let buffer_addr = |i: usize| GuestAddress(BUFFERS_START + (i * BUFFER_SIZE) as u64);
+        for i in 0..16 {
+            guest_queue.dtable[i].set(buffer_addr(i).0, BUFFER_SIZE as u32, VIRTQ_DESC_F_WRITE, 0);
+            guest_queue.avail.ring[i].set(i as u16);
+        }
+        guest_queue.avail.idx.set(16);
+
+        // A jumbo frame spanning six buffers, followed by a small frame.
+        let jumbo_frame: Vec<u8> = (0..vnet_hdr_len() + 9000)
+            .map(|i| if i < vnet_hdr_len() { 0 } else { i as u8 })
+            .collect();
+        let small_frame = vec![0u8; vnet_hdr_len() + 100];
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        sender.send(&jumbo_frame).unwrap();
+        sender.send(&small_frame).unwrap();
+
+        let mut rx = RxVirtio::new();
+        rx.mrg_rxbuf = true;
+        assert!(rx.process_desc_chain(&mem, &receiver, &mut queue).unwrap());
+        assert!(rx.deferred_irqs);
+        assert_eq!(rx.counter_frames, Wrapping(2));
+        assert_eq!(rx.counter_bytes, Wrapping(9100));
+
+        // The buffers left unused are given back to the queue.
+        assert_eq!(guest_queue.used.idx.get(), 7);
+        assert_eq!(queue.next_avail, Wrapping(7));
+
+        let mut received = vec![0u8; jumbo_frame.len()];
+        let mut offset = 0;
+        for i in 0..6 {
+            let used = guest_queue.used.ring[i].get();
+            assert_eq!(used.id, i as u32);
+            let len = cmp::min(BUFFER_SIZE, jumbo_frame.len() - offset);
+            assert_eq!(used.len, len as u32);
+            mem.read_slice(&mut received[offset..offset + len], buffer_addr(i))
+                .unwrap();
+            offset += len;
+        }
+        let num_buffers = VNET_HDR_NUM_BUFFERS_OFFSET..VNET_HDR_NUM_BUFFERS_OFFSET + 2;
+        assert_eq!(received[num_buffers.clone()], 6u16.to_le_bytes());
+        received[num_buffers.clone()].copy_from_slice(&[0, 0]);
+        assert_eq!(received, jumbo_frame);
+
+        let used = guest_queue.used.ring[6].get();
+        assert_eq!(used.id, 6);
+        assert_eq!(used.len, small_frame.len() as u32);
+        let mut header = [0u8; 12];
+        mem.read_slice(&mut header, buffer_addr(6)).unwrap();
+        assert_eq!(header[num_buffers], 1u16.to_le_bytes());
+    }
+
+    #[test]
+    fn test_rx_mergeable_buffers_wait() {
+        const BUFFER_SIZE: usize = 1536;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = guest_queue.create_queue();
+        for i in 0..16 {
+            let addr = 0x1_0000 + (i * BUFFER_SIZE) as u64;
+            guest_queue.dtable[i].set(addr, BUFFER_SIZE as u32, VIRTQ_DESC_F_WRITE, 0);
+            guest_queue.avail.ring[i].set(i as u16);
+        }
+        guest_queue.avail.idx.set(4);
+
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        sender.send(&vec![0u8; vnet_hdr_len() + 9000]).unwrap();
+
+        // Four buffers can't hold the largest frame, the frame is left in
+        // the TAP until the guest makes more buffers available.
+        let mut rx = RxVirtio::new();
+        rx.mrg_rxbuf = true;
+        assert!(!rx.process_desc_chain(&mem, &receiver, &mut queue).unwrap());
+        assert_eq!(rx.counter_frames, Wrapping(0));
+        assert_eq!(guest_queue.used.idx.get(), 0);
+        assert_eq!(queue.next_avail, Wrapping(0));
+
+        guest_queue.avail.idx.set(16);
+        assert!(rx.process_desc_chain(&mem, &receiver, &mut queue).unwrap());
+        assert_eq!(rx.counter_frames, Wrapping(1));
+        assert_eq!(rx.counter_bytes, Wrapping(9000));
+        assert_eq!(guest_queue.used.idx.get(), 6);
+    }
+
+    #[test]
+    fn test_rx_oversized_frame() {
+        const BUFFER_SIZE: usize = 1536;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = guest_queue.create_queue();
+        for i in 0..2 {
+            let addr = 0x1_0000 + (i * BUFFER_SIZE) as u64;
+            guest_queue.dtable[i].set(addr, BUFFER_SIZE as u32, VIRTQ_DESC_F_WRITE, 0);
+            guest_queue.avail.ring[i].set(i as u16);
+        }
+        guest_queue.avail.idx.set(2);
+
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        sender.send(&vec![0u8; BUFFER_SIZE + 1]).unwrap();
+        sender.send(&vec![0u8; vnet_hdr_len() + 100]).unwrap();
+
+        // The frame which doesn't fit is dropped, its descriptor chain being
+        // reused for the next frame.
+        let mut rx = RxVirtio::new();
+        assert!(rx.process_desc_chain(&mem, &receiver, &mut queue).unwrap());
+        assert_eq!(rx.counter_frames, Wrapping(1));
+        assert_eq!(rx.counter_bytes, Wrapping(100));
+        assert_eq!(guest_queue.used.idx.get(), 1);
+        assert_eq!(queue.next_avail, Wrapping(1));
+        let used = guest_queue.used.ring[0].get();
+        assert_eq!(used.id, 0);
+        assert_eq!(used.len, (vnet_hdr_len() + 100) as u32);
+    }
+
+    #[test]
+    fn test_rx_invalid_buffer() {
+        const BUFFER_SIZE: usize = 1536;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = guest_queue.create_queue();
+        // The first buffer is out of the guest memory.
+        guest_queue.dtable[0].set(0x20_0000, BUFFER_SIZE as u32, VIRTQ_DESC_F_WRITE, 0);
+        guest_queue.dtable[1].set(0x1_0000, BUFFER_SIZE as u32, VIRTQ_DESC_F_WRITE, 0);
+        guest_queue.avail.ring[0].set(0);
+        guest_queue.avail.ring[1].set(1);
+        guest_queue.avail.idx.set(2);
+
+        let frame = vec![0u8; vnet_hdr_len() + 100];
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        sender.send(&frame).unwrap();
+
+        // The invalid descriptor chain is given back to the guest rather
+        // than being lost, the frame going to the next one.
+        let mut rx = RxVirtio::new();
+        assert!(rx.process_desc_chain(&mem, &receiver, &mut queue).unwrap());
+        assert_eq!(rx.counter_frames, Wrapping(1));
+        assert_eq!(guest_queue.used.idx.get(), 2);
+        let used = guest_queue.used.ring[0].get();
+        assert_eq!((used.id, used.len), (0, 0));
+        let used = guest_queue.used.ring[1].get();
+        assert_eq!((used.id, used.len), (1, frame.len() as u32));
+    }
+
+    #[test]
+    fn test_rx_vlan_filter() {
+        const BUFFER_SIZE: usize = 1536;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = guest_queue.create_queue();
+        for i in 0..4 {
+            let addr = 0x1_0000 + (i * BUFFER_SIZE) as u64;
+            guest_queue.dtable[i].set(addr, BUFFER_SIZE as u32, VIRTQ_DESC_F_WRITE, 0);
+            guest_queue.avail.ring[i].set(i as u16);
+        }
+        guest_queue.avail.idx.set(4);
+
+        let frame = |tag: Option<u16>| {
+            let mut frame = vec![0u8; vnet_hdr_len() + 12];
+            if let Some(vid) = tag {
+                frame.extend_from_slice(&0x8100u16.to_be_bytes());
+                frame.extend_from_slice(&vid.to_be_bytes());
+            }
+            frame.extend_from_slice(&0x0800u16.to_be_bytes());
+            frame.resize(vnet_hdr_len() + 64, 0);
+            frame
+        };
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        sender.send(&frame(Some(100))).unwrap();
+        sender.send(&frame(Some(200))).unwrap();
+        sender.send(&frame(None)).unwrap();
+
+        let vlan_filter = VlanFilter::new();
+        vlan_filter.add(200);
+        let mut rx = RxVirtio::new();
+        rx.vlan_filter = Some(vlan_filter);
+        assert!(rx.process_desc_chain(&mem, &receiver, &mut queue).unwrap());
+
+        // The frame of VLAN 100 is dropped, its descriptor chain being used
+        // by the next frame.
+        assert_eq!(rx.counter_frames, Wrapping(2));
+        assert_eq!(guest_queue.used.idx.get(), 2);
+        assert_eq!(queue.next_avail, Wrapping(2));
+        let mut tci = [0u8; 2];
+        mem.read_slice(
+            &mut tci,
+            GuestAddress(0x1_0000 + vnet_hdr_len() as u64 + 14),
+        )
+        .unwrap();
+        assert_eq!(u16::from_be_bytes(tci), 200);
+    }
+
+    #[test]
+    fn test_rx_data_valid() {
+        const BUFFER_SIZE: usize = 1536;
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = guest_queue.create_queue();
+        let buffer_addr = |i: usize| GuestAddress(0x1_0000 + (i * BUFFER_SIZE) as u64);
+        for i in 0..2 {
+            guest_queue.dtable[i].set(buffer_addr(i).0, BUFFER_SIZE as u32, VIRTQ_DESC_F_WRITE, 0);
+            guest_queue.avail.ring[i].set(i as u16);
+        }
+        guest_queue.avail.idx.set(2);
+
+        // A frame with a complete checksum, followed by one whose checksum
+        // is left to the guest.
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let mut frame = vec![0u8; vnet_hdr_len() + 64];
+        sender.send(&frame).unwrap();
+        frame[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+        sender.send(&frame).unwrap();
+
+        let mut rx = RxVirtio::new();
+        rx.data_valid = true;
+        assert!(rx.process_desc_chain(&mem, &receiver, &mut queue).unwrap());
+        assert_eq!(rx.counter_frames, Wrapping(2));
+
+        let flags: u8 = mem.read_obj(buffer_addr(0)).unwrap();
+        assert_eq!(flags, VIRTIO_NET_HDR_F_DATA_VALID);
+        let flags: u8 = mem.read_obj(buffer_addr(1)).unwrap();
+        assert_eq!(flags, VIRTIO_NET_HDR_F_NEEDS_CSUM);
+    }
+
+    #[test]
+    fn test_tx_anti_spoof() {
+        const BUFFER_SIZE: usize = 1536;
+
+        // Records each frame written to the TAP.
+        #[derive(Default)]
+        struct Frames(Vec<Vec<u8>>);
+        impl Write for Frames {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.to_vec());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
             }
-        } else {
-            self.process_rx(&mut queue)
         }
+
+        let mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let spoofed_mac = MacAddr::parse_str("12:34:56:78:9a:bd").unwrap();
+        let frame = |source: &MacAddr| {
+            let mut frame = vec![0u8; vnet_hdr_len() + ETH_SOURCE_OFFSET];
+            frame.extend_from_slice(source.get_bytes());
+            frame.resize(vnet_hdr_len() + 64, 0);
+            frame
+        };
+        let frames = [
+            frame(&mac),
+            frame(&spoofed_mac),
+            vec![0u8; vnet_hdr_len() + 8],
+            frame(&mac),
+        ];
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = guest_queue.create_queue();
+        for (i, frame) in frames.iter().enumerate() {
+            let addr = GuestAddress(0x1_0000 + (i * BUFFER_SIZE) as u64);
+            mem.write_slice(frame, addr).unwrap();
+            guest_queue.dtable[i].set(addr.0, frame.len() as u32, 0, 0);
+            guest_queue.avail.ring[i].set(i as u16);
+        }
+        guest_queue.avail.idx.set(frames.len() as u16);
+
+        let mut tap = Frames::default();
+        let mut tx = TxVirtio::new();
+        tx.anti_spoof = Some(mac);
+        tx.process_desc_chain(&mem, &mut tap, &mut queue);
+
+        // The frames from another MAC address, or too short to carry one,
+        // are dropped while their descriptor chains are still used.
+        assert_eq!(tap.0, vec![frames[0].clone(), frames[3].clone()]);
+        assert_eq!(tx.counter_frames, Wrapping(2));
+        assert_eq!(tx.counter_spoofed_frames, Wrapping(2));
+        assert_eq!(guest_queue.used.idx.get(), 4);
     }
 
-    fn read_tap(&mut self) -> io::Result<usize> {
-        self.tap.read(&mut self.rx.frame_buf)
+    #[test]
+    fn test_tx_inspector() {
+        // Drops the frames whose first payload byte is odd, counting the
+        // frames it's given.
+        #[derive(Default)]
+        struct OddFilter(AtomicU64);
+        impl DescriptorInspector for OddFilter {
+            fn inspect(&self, chain: &DescriptorChainView) -> Verdict {
+                assert_eq!(chain.queue_index, 1);
+                self.0.fetch_add(1, Ordering::AcqRel);
+                if chain.data[vnet_hdr_len()] % 2 == 1 {
+                    Verdict::Drop
+                } else {
+                    Verdict::Process
+                }
+            }
+        }
+
+        let frames: Vec<Vec<u8>> = (0..4u8)
+            .map(|i| {
+                let mut frame = vec![0u8; vnet_hdr_len() + 64];
+                frame[vnet_hdr_len()] = i;
+                frame
+            })
+            .collect();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = guest_queue.create_queue();
+        for (i, frame) in frames.iter().enumerate() {
+            let addr = GuestAddress(0x1_0000 + (i * 1536) as u64);
+            mem.write_slice(frame, addr).unwrap();
+            guest_queue.dtable[i].set(addr.0, frame.len() as u32, 0, 0);
+            guest_queue.avail.ring[i].set(i as u16);
+        }
+        guest_queue.avail.idx.set(frames.len() as u16);
+
+        let inspector = Arc::new(OddFilter::default());
+        let mut tap = Vec::new();
+        let mut tx = TxVirtio::new();
+        tx.inspector = Some(inspector.clone());
+        tx.queue_index = 1;
+        tx.process_desc_chain(&mem, &mut tap, &mut queue);
+
+        // The dropped frames are not sent while their descriptor chains are
+        // still used.
+        assert_eq!(inspector.0.load(Ordering::Acquire), 4);
+        assert_eq!(tap, [frames[0].clone(), frames[2].clone()].concat());
+        assert_eq!(tx.counter_frames, Wrapping(2));
+        assert_eq!(guest_queue.used.idx.get(), 4);
     }
 }
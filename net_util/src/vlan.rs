@@ -0,0 +1,144 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Number of VLAN IDs, 802.1Q tags carrying a 12-bit identifier.
+pub const VLAN_ID_COUNT: usize = 4096;
+
+/// The Ethertype identifying an 802.1Q tagged frame.
+const ETH_P_8021Q: u16 = 0x8100;
+
+/// Offset of the Ethertype in an Ethernet frame, after both MAC addresses.
+const ETHERTYPE_OFFSET: usize = 12;
+
+const TABLE_WORDS: usize = VLAN_ID_COUNT / 64;
+
+/// The VLAN IDs the guest is interested in, shared between the control
+/// queue programming them and the queues receiving the frames. Tagged
+/// frames whose VLAN ID isn't in the table are not delivered to the guest,
+/// untagged frames always are.
+#[derive(Clone)]
+pub struct VlanFilter {
+    table: Arc<Vec<AtomicU64>>,
+}
+
+impl Default for VlanFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VlanFilter {
+    /// Number of bytes of an Ethernet frame `accepts()` needs to look at.
+    pub const HEADER_LEN: usize = ETHERTYPE_OFFSET + 4;
+
+    /// Creates a filter with an empty table, dropping all tagged frames.
+    pub fn new() -> Self {
+        VlanFilter {
+            table: Arc::new((0..TABLE_WORDS).map(|_| AtomicU64::new(0)).collect()),
+        }
+    }
+
+    /// Adds a VLAN ID to the table, returning false if it's out of range.
+    pub fn add(&self, vid: u16) -> bool {
+        if vid as usize >= VLAN_ID_COUNT {
+            return false;
+        }
+        self.table[vid as usize / 64].fetch_or(1 << (vid % 64), Ordering::AcqRel);
+        true
+    }
+
+    /// Removes a VLAN ID from the table, returning false if it's out of
+    /// range.
+    pub fn remove(&self, vid: u16) -> bool {
+        if vid as usize >= VLAN_ID_COUNT {
+            return false;
+        }
+        self.table[vid as usize / 64].fetch_and(!(1 << (vid % 64)), Ordering::AcqRel);
+        true
+    }
+
+    pub fn contains(&self, vid: u16) -> bool {
+        (vid as usize) < VLAN_ID_COUNT
+            && self.table[vid as usize / 64].load(Ordering::Acquire) & (1 << (vid % 64)) != 0
+    }
+
+    /// Empties the table.
+    pub fn clear(&self) {
+        for word in self.table.iter() {
+            word.store(0, Ordering::Release);
+        }
+    }
+
+    /// The table as a bitmap, bit n standing for VLAN ID n.
+    pub fn table(&self) -> Vec<u64> {
+        self.table
+            .iter()
+            .map(|word| word.load(Ordering::Acquire))
+            .collect()
+    }
+
+    /// Replaces the table with a bitmap returned by `table()`.
+    pub fn set_table(&self, table: &[u64]) {
+        for (i, word) in self.table.iter().enumerate() {
+            word.store(table.get(i).copied().unwrap_or(0), Ordering::Release);
+        }
+    }
+
+    /// Tells whether a frame must be delivered, given the beginning of its
+    /// Ethernet header. A header too short to carry a tag is accepted.
+    pub fn accepts(&self, header: &[u8]) -> bool {
+        if header.len() < Self::HEADER_LEN {
+            return true;
+        }
+        let ethertype =
+            u16::from_be_bytes([header[ETHERTYPE_OFFSET], header[ETHERTYPE_OFFSET + 1]]);
+        if ethertype != ETH_P_8021Q {
+            return true;
+        }
+        let tci = u16::from_be_bytes([header[ETHERTYPE_OFFSET + 2], header[ETHERTYPE_OFFSET + 3]]);
+        self.contains(tci & 0x0fff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(ethertype: u16, tci: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; ETHERTYPE_OFFSET];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(&tci.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_vlan_filter() {
+        let filter = VlanFilter::new();
+        let shared = filter.clone();
+
+        // untagged frames always go through, tagged ones only once added
+        assert!(filter.accepts(&frame(0x0800, 0)));
+        assert!(!filter.accepts(&frame(ETH_P_8021Q, 100)));
+        assert!(shared.add(100));
+        // the priority bits don't matter
+        assert!(filter.accepts(&frame(ETH_P_8021Q, 0xe000 | 100)));
+        assert!(!filter.accepts(&frame(ETH_P_8021Q, 101)));
+        assert!(shared.remove(100));
+        assert!(!filter.accepts(&frame(ETH_P_8021Q, 100)));
+
+        assert!(!filter.add(VLAN_ID_COUNT as u16));
+        assert!(!filter.remove(VLAN_ID_COUNT as u16));
+
+        filter.add(4095);
+        let table = filter.table();
+        filter.clear();
+        assert!(!filter.contains(4095));
+        filter.set_table(&table);
+        assert!(filter.contains(4095));
+    }
+}
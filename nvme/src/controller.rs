@@ -0,0 +1,961 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::queue::{
+    write_data, Command, CompletionQueue, Status, SubmissionQueue, CQ_ENTRY_SHIFT, PAGE_SIZE,
+    SQ_ENTRY_SHIFT,
+};
+use std::collections::HashMap;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+
+// Offsets of the controller registers.
+const REG_CAP: usize = 0x00;
+const REG_VS: usize = 0x08;
+const REG_INTMS: usize = 0x0c;
+const REG_INTMC: usize = 0x10;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1c;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+pub const REGISTERS_SIZE: u64 = 0x38;
+
+/// Offset of the doorbells, with a stride of 4 bytes.
+pub const DOORBELLS_OFFSET: u64 = 0x1000;
+
+const NVME_VERSION: u32 = 0x0001_0400;
+
+const CC_EN: u32 = 1;
+const CC_CSS_SHIFT: u32 = 4;
+const CC_MPS_SHIFT: u32 = 7;
+const CC_SHN_SHIFT: u32 = 14;
+const CC_IOSQES_SHIFT: u32 = 16;
+const CC_IOCQES_SHIFT: u32 = 20;
+
+const CSTS_RDY: u32 = 1;
+const CSTS_CFS: u32 = 1 << 1;
+const CSTS_SHST_COMPLETE: u32 = 2 << 2;
+
+// Admin command set opcodes.
+const ADMIN_DELETE_SQ: u8 = 0x00;
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const ADMIN_DELETE_CQ: u8 = 0x04;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_ABORT: u8 = 0x08;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+const ADMIN_GET_FEATURES: u8 = 0x0a;
+const ADMIN_ASYNC_EVENT_REQUEST: u8 = 0x0c;
+
+const IDENTIFY_NAMESPACE: u8 = 0x00;
+const IDENTIFY_CONTROLLER: u8 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u8 = 0x02;
+const IDENTIFY_NAMESPACE_DESCRIPTORS: u8 = 0x03;
+const IDENTIFY_SIZE: usize = 4096;
+
+const LOG_ERROR_INFORMATION: u8 = 0x01;
+const LOG_SMART_HEALTH: u8 = 0x02;
+const LOG_FIRMWARE_SLOT: u8 = 0x03;
+
+const FEATURE_ARBITRATION: u8 = 0x01;
+const FEATURE_POWER_MANAGEMENT: u8 = 0x02;
+const FEATURE_TEMPERATURE_THRESHOLD: u8 = 0x04;
+const FEATURE_ERROR_RECOVERY: u8 = 0x05;
+const FEATURE_VOLATILE_WRITE_CACHE: u8 = 0x06;
+const FEATURE_NUMBER_OF_QUEUES: u8 = 0x07;
+const FEATURE_INTERRUPT_COALESCING: u8 = 0x08;
+const FEATURE_INTERRUPT_VECTOR_CONFIG: u8 = 0x09;
+const FEATURE_WRITE_ATOMICITY: u8 = 0x0a;
+const FEATURE_ASYNC_EVENT_CONFIG: u8 = 0x0b;
+
+/// The only namespace of the controller.
+const NSID: u32 = 1;
+const BROADCAST_NSID: u32 = 0xffff_ffff;
+
+/// Number of Asynchronous Event Requests the driver can have outstanding,
+/// none of them ever completes as the controller has no event to report.
+const MAX_ASYNC_EVENTS: usize = 4;
+
+/// log2 of the largest transfer, in pages.
+const MDTS: u8 = 5;
+pub const MAX_TRANSFER_SIZE: usize = (PAGE_SIZE as usize) << MDTS;
+
+const MODEL: &str = "Cloud Hypervisor NVMe Ctrl";
+const FIRMWARE_REVISION: &str = env!("CARGO_PKG_VERSION");
+
+/// The storage exposed through the namespace.
+#[derive(Clone, Copy, Debug)]
+pub struct Namespace {
+    /// log2 of the logical block size.
+    pub lba_shift: u32,
+    /// Size of the namespace in logical blocks.
+    pub num_blocks: u64,
+    pub readonly: bool,
+}
+
+impl Namespace {
+    pub fn is_valid(nsid: u32) -> bool {
+        nsid == NSID
+    }
+
+    pub fn is_valid_or_broadcast(nsid: u32) -> bool {
+        nsid == NSID || nsid == BROADCAST_NSID
+    }
+}
+
+fn copy_str(buf: &mut [u8], s: &str) {
+    // Identify strings are padded with spaces.
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = s.as_bytes().get(i).copied().unwrap_or(b' ');
+    }
+}
+
+/// The state of the controller shared between the vCPU threads accessing
+/// its registers and the worker thread processing the queues.
+pub struct Controller {
+    cap: u64,
+    cc: u32,
+    csts: u32,
+    intms: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    // Indexed by queue ID, the admin queues coming first.
+    sqs: Vec<Option<SubmissionQueue>>,
+    cqs: Vec<Option<CompletionQueue>>,
+    features: HashMap<u8, u32>,
+    async_events: usize,
+    /// Set while disabling or shutting down the controller, to stop
+    /// fetching commands until the ones in flight are done.
+    quiescing: bool,
+    namespace: Namespace,
+    serial: String,
+    vendor_id: u16,
+    num_vectors: u16,
+    /// Number of I/O commands submitted to the disk and not completed yet.
+    pub inflight: usize,
+    /// Set while the VM is paused, to stop fetching commands.
+    pub paused: bool,
+}
+
+impl Controller {
+    /// Creates a controller supporting `max_io_queues` submission and
+    /// completion queues of up to `max_queue_size` entries, each completion
+    /// queue being able to get its own interrupt vector.
+    pub fn new(
+        namespace: Namespace,
+        serial: String,
+        vendor_id: u16,
+        max_io_queues: u16,
+        max_queue_size: u16,
+    ) -> Self {
+        // Contiguous queues only, a 10 seconds timeout and the NVM command
+        // set.
+        let cap = u64::from(max_queue_size - 1) | 1 << 16 | 20 << 24 | 1 << 37;
+        let num_queues = usize::from(max_io_queues) + 1;
+
+        let mut controller = Controller {
+            cap,
+            cc: 0,
+            csts: 0,
+            intms: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            sqs: (0..num_queues).map(|_| None).collect(),
+            cqs: (0..num_queues).map(|_| None).collect(),
+            features: HashMap::new(),
+            async_events: 0,
+            quiescing: false,
+            namespace,
+            serial,
+            vendor_id,
+            num_vectors: max_io_queues + 1,
+            inflight: 0,
+            paused: false,
+        };
+        controller.reset();
+        controller
+    }
+
+    fn max_io_queues(&self) -> u16 {
+        self.num_vectors - 1
+    }
+
+    fn max_queue_size(&self) -> u32 {
+        (self.cap & 0xffff) as u32 + 1
+    }
+
+    fn reset(&mut self) {
+        self.csts = 0;
+        self.intms = 0;
+        self.sqs.iter_mut().for_each(|q| *q = None);
+        self.cqs.iter_mut().for_each(|q| *q = None);
+        self.async_events = 0;
+
+        let num_queues = u32::from(self.max_io_queues() - 1);
+        self.features = [
+            (FEATURE_ARBITRATION, 0),
+            (FEATURE_POWER_MANAGEMENT, 0),
+            // 343 kelvins, the default over temperature threshold.
+            (FEATURE_TEMPERATURE_THRESHOLD, 0x157),
+            (FEATURE_ERROR_RECOVERY, 0),
+            (FEATURE_VOLATILE_WRITE_CACHE, 1),
+            (FEATURE_NUMBER_OF_QUEUES, num_queues << 16 | num_queues),
+            (FEATURE_INTERRUPT_COALESCING, 0),
+            (FEATURE_WRITE_ATOMICITY, 0),
+            (FEATURE_ASYNC_EVENT_CONFIG, 0),
+        ]
+        .iter()
+        .copied()
+        .collect();
+    }
+
+    fn enable(&mut self, mem: &GuestMemoryMmap) {
+        let sq_size = (self.aqa & 0xfff) + 1;
+        let cq_size = ((self.aqa >> 16) & 0xfff) + 1;
+        let css = (self.cc >> CC_CSS_SHIFT) & 0x7;
+        let mps = (self.cc >> CC_MPS_SHIFT) & 0xf;
+
+        if sq_size < 2
+            || cq_size < 2
+            || self.asq % PAGE_SIZE != 0
+            || self.acq % PAGE_SIZE != 0
+            || css != 0
+            || mps != 0
+            || !mem.check_range(GuestAddress(self.asq), (sq_size << SQ_ENTRY_SHIFT) as usize)
+            || !mem.check_range(GuestAddress(self.acq), (cq_size << CQ_ENTRY_SHIFT) as usize)
+        {
+            error!(
+                "Invalid NVMe controller configuration: CC {:#x}, AQA {:#x}, ASQ {:#x}, ACQ {:#x}",
+                self.cc, self.aqa, self.asq, self.acq
+            );
+            self.csts |= CSTS_CFS;
+            return;
+        }
+
+        self.sqs[0] = Some(SubmissionQueue::new(
+            GuestAddress(self.asq),
+            sq_size as u16,
+            0,
+        ));
+        self.cqs[0] = Some(CompletionQueue::new(
+            GuestAddress(self.acq),
+            cq_size as u16,
+            0,
+            true,
+        ));
+        self.csts |= CSTS_RDY;
+    }
+
+    pub fn ready(&self) -> bool {
+        self.csts & (CSTS_RDY | CSTS_CFS) == CSTS_RDY && !self.quiescing
+    }
+
+    pub fn namespace(&self) -> Namespace {
+        self.namespace
+    }
+
+    pub fn write_cache_enabled(&self) -> bool {
+        self.features[&FEATURE_VOLATILE_WRITE_CACHE] & 1 != 0
+    }
+
+    fn registers(&self) -> [u8; REGISTERS_SIZE as usize] {
+        let mut regs = [0u8; REGISTERS_SIZE as usize];
+        regs[REG_CAP..REG_CAP + 8].copy_from_slice(&self.cap.to_le_bytes());
+        regs[REG_VS..REG_VS + 4].copy_from_slice(&NVME_VERSION.to_le_bytes());
+        regs[REG_INTMS..REG_INTMS + 4].copy_from_slice(&self.intms.to_le_bytes());
+        regs[REG_INTMC..REG_INTMC + 4].copy_from_slice(&self.intms.to_le_bytes());
+        regs[REG_CC..REG_CC + 4].copy_from_slice(&self.cc.to_le_bytes());
+        regs[REG_CSTS..REG_CSTS + 4].copy_from_slice(&self.csts.to_le_bytes());
+        regs[REG_AQA..REG_AQA + 4].copy_from_slice(&self.aqa.to_le_bytes());
+        regs[REG_ASQ..REG_ASQ + 8].copy_from_slice(&self.asq.to_le_bytes());
+        regs[REG_ACQ..REG_ACQ + 8].copy_from_slice(&self.acq.to_le_bytes());
+        regs
+    }
+
+    pub fn read_register(&self, offset: u64, data: &mut [u8]) {
+        let regs = self.registers();
+        let start = offset as usize;
+        if let Some(reg) = regs.get(start..start + data.len()) {
+            data.copy_from_slice(reg);
+        } else {
+            data.iter_mut().for_each(|b| *b = 0);
+        }
+    }
+
+    /// Tells whether writing `data` at `offset` disables or shuts down the
+    /// controller, in which case no more command is fetched and the write
+    /// must wait for the I/O in flight to complete.
+    pub fn quiesce(&mut self, offset: u64, data: &[u8]) -> bool {
+        if offset as usize != REG_CC || data.len() != 4 {
+            return false;
+        }
+        let cc = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let disabled = self.cc & CC_EN != 0 && cc & CC_EN == 0;
+        let shutdown = (self.cc >> CC_SHN_SHIFT) & 0x3 == 0 && (cc >> CC_SHN_SHIFT) & 0x3 != 0;
+        self.quiescing = disabled || shutdown;
+        self.quiescing
+    }
+
+    pub fn write_register(&mut self, mem: &GuestMemoryMmap, offset: u64, data: &[u8]) {
+        match data.len() {
+            4 => {
+                let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                self.write_register_u32(mem, offset as usize, value);
+            }
+            8 => {
+                let mut value = [0u8; 8];
+                value.copy_from_slice(data);
+                let value = u64::from_le_bytes(value);
+                self.write_register_u32(mem, offset as usize, value as u32);
+                self.write_register_u32(mem, offset as usize + 4, (value >> 32) as u32);
+            }
+            len => warn!(
+                "Unsupported {} bytes write to NVMe register {:#x}",
+                len, offset
+            ),
+        }
+    }
+
+    fn write_register_u32(&mut self, mem: &GuestMemoryMmap, offset: usize, value: u32) {
+        // The admin queue attributes can only be changed while the
+        // controller is disabled.
+        let enabled = self.cc & CC_EN != 0;
+        match offset {
+            REG_INTMS => self.intms |= value,
+            REG_INTMC => self.intms &= !value,
+            REG_CC => self.write_cc(mem, value),
+            REG_AQA if !enabled => self.aqa = value,
+            REG_ASQ if !enabled => self.asq = (self.asq & !0xffff_ffff) | u64::from(value),
+            o if o == REG_ASQ + 4 && !enabled => {
+                self.asq = (self.asq & 0xffff_ffff) | u64::from(value) << 32
+            }
+            REG_ACQ if !enabled => self.acq = (self.acq & !0xffff_ffff) | u64::from(value),
+            o if o == REG_ACQ + 4 && !enabled => {
+                self.acq = (self.acq & 0xffff_ffff) | u64::from(value) << 32
+            }
+            _ => debug!("Ignoring write to NVMe register {:#x}", offset),
+        }
+    }
+
+    fn write_cc(&mut self, mem: &GuestMemoryMmap, cc: u32) {
+        let old = self.cc;
+        self.cc = cc;
+        self.quiescing = false;
+
+        if old & CC_EN == 0 && cc & CC_EN != 0 {
+            self.enable(mem);
+        } else if old & CC_EN != 0 && cc & CC_EN == 0 {
+            self.reset();
+        }
+
+        // The data in flight is written already, and whatever is in the
+        // host page cache survives the guest.
+        if (old >> CC_SHN_SHIFT) & 0x3 == 0 && (cc >> CC_SHN_SHIFT) & 0x3 != 0 {
+            self.csts |= CSTS_SHST_COMPLETE;
+        } else if (cc >> CC_SHN_SHIFT) & 0x3 == 0 {
+            self.csts &= !CSTS_SHST_COMPLETE;
+        }
+    }
+
+    /// Handles the write of a doorbell, `offset` being relative to the
+    /// first one.
+    pub fn write_doorbell(&mut self, offset: u64, value: u32) {
+        let index = (offset / 4) as usize;
+        let qid = index / 2;
+        let valid = if index % 2 == 0 {
+            self.sqs
+                .get_mut(qid)
+                .and_then(Option::as_mut)
+                .map_or(false, |sq| sq.set_tail(value as u16))
+        } else {
+            self.cqs
+                .get_mut(qid)
+                .and_then(Option::as_mut)
+                .map_or(false, |cq| cq.set_head(value as u16))
+        };
+        if !valid {
+            warn!("Invalid NVMe doorbell write {:#x} at {:#x}", value, offset);
+        }
+    }
+
+    /// Identifiers of the I/O submission queues.
+    pub fn io_submission_queues(&self) -> Vec<u16> {
+        (1..self.sqs.len())
+            .filter(|qid| self.sqs[*qid].is_some())
+            .map(|qid| qid as u16)
+            .collect()
+    }
+
+    /// Fetches the next command of a submission queue, unless its
+    /// completion queue is full, so that the completions kept for later
+    /// are bounded by the commands in flight.
+    pub fn pop_command(&mut self, mem: &GuestMemoryMmap, sqid: u16) -> Option<Command> {
+        let sq = self.sqs.get_mut(usize::from(sqid))?.as_mut()?;
+        let backlogged = self.cqs[usize::from(sq.cqid)]
+            .as_ref()
+            .map_or(false, CompletionQueue::is_backlogged);
+        if backlogged {
+            return None;
+        }
+        match sq.pop(mem) {
+            Ok(command) => command,
+            Err(e) => {
+                error!("Failed reading NVMe submission queue {}: {}", sqid, e);
+                self.csts |= CSTS_CFS;
+                None
+            }
+        }
+    }
+
+    /// Posts the completion of a command, unless its submission queue has
+    /// been deleted in the meantime.
+    pub fn complete(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        sqid: u16,
+        cid: u16,
+        status: Status,
+        dw0: u32,
+    ) {
+        let (cqid, sq_head) = match self.sqs.get(usize::from(sqid)).and_then(Option::as_ref) {
+            Some(sq) => (sq.cqid, sq.head()),
+            None => return,
+        };
+        if let Some(cq) = self.cqs[usize::from(cqid)].as_mut() {
+            if let Err(e) = cq.push(mem, sqid, sq_head, cid, status, dw0) {
+                error!("Failed writing NVMe completion queue {}: {}", cqid, e);
+                self.csts |= CSTS_CFS;
+            }
+        }
+    }
+
+    /// Posts the completions that were waiting for room in their queue.
+    pub fn flush_completions(&mut self, mem: &GuestMemoryMmap) {
+        for (cqid, cq) in self.cqs.iter_mut().enumerate() {
+            if let Some(cq) = cq.as_mut() {
+                if let Err(e) = cq.flush(mem) {
+                    error!("Failed writing NVMe completion queue {}: {}", cqid, e);
+                    self.csts |= CSTS_CFS;
+                }
+            }
+        }
+    }
+
+    /// Returns the interrupt vectors of the completion queues having new
+    /// entries.
+    pub fn take_interrupts(&mut self) -> Vec<u16> {
+        let mut vectors = Vec::new();
+        for cq in self.cqs.iter_mut().flatten() {
+            if cq.needs_interrupt && cq.interrupts && !vectors.contains(&cq.vector) {
+                vectors.push(cq.vector);
+            }
+            cq.needs_interrupt = false;
+        }
+        vectors
+    }
+
+    /// Processes an admin command, returning its status and the first
+    /// dword of its completion, or None if it doesn't complete right away.
+    pub fn admin_command(&mut self, mem: &GuestMemoryMmap, cmd: &Command) -> Option<(Status, u32)> {
+        let result = match cmd.opcode() {
+            ADMIN_DELETE_SQ => self.delete_sq(cmd),
+            ADMIN_CREATE_SQ => self.create_sq(mem, cmd),
+            ADMIN_GET_LOG_PAGE => self.get_log_page(mem, cmd),
+            ADMIN_DELETE_CQ => self.delete_cq(cmd),
+            ADMIN_CREATE_CQ => self.create_cq(mem, cmd),
+            ADMIN_IDENTIFY => self.identify(mem, cmd),
+            // Commands are processed as soon as they are fetched, there's
+            // never anything left to abort.
+            ADMIN_ABORT => Ok(1),
+            ADMIN_SET_FEATURES => self.set_features(cmd),
+            ADMIN_GET_FEATURES => self.get_features(cmd),
+            ADMIN_ASYNC_EVENT_REQUEST => {
+                if self.async_events == MAX_ASYNC_EVENTS {
+                    Err(Status::ASYNC_EVENT_LIMIT_EXCEEDED)
+                } else {
+                    self.async_events += 1;
+                    return None;
+                }
+            }
+            opcode => {
+                debug!("Unsupported NVMe admin command {:#x}", opcode);
+                Err(Status::INVALID_OPCODE)
+            }
+        };
+
+        Some(match result {
+            Ok(dw0) => (Status::SUCCESS, dw0),
+            Err(status) => (status, 0),
+        })
+    }
+
+    /// Validates the identifier and size of a queue to create.
+    fn check_new_queue(&self, qid: u16, qsize: u32, exists: bool) -> Result<(), Status> {
+        if qid == 0 || qid > self.max_io_queues() || exists {
+            return Err(Status::INVALID_QUEUE_ID);
+        }
+        if qsize < 2 || qsize > self.max_queue_size() {
+            return Err(Status::INVALID_QUEUE_SIZE);
+        }
+        Ok(())
+    }
+
+    fn create_cq(&mut self, mem: &GuestMemoryMmap, cmd: &Command) -> Result<u32, Status> {
+        let qid = cmd.cdw10 as u16;
+        let qsize = (cmd.cdw10 >> 16) + 1;
+        let contiguous = cmd.cdw11 & 1 != 0;
+        let interrupts = cmd.cdw11 & 2 != 0;
+        let vector = (cmd.cdw11 >> 16) as u16;
+
+        self.check_new_queue(
+            qid,
+            qsize,
+            self.cqs
+                .get(usize::from(qid))
+                .map_or(false, Option::is_some),
+        )?;
+        if vector >= self.num_vectors {
+            return Err(Status::INVALID_INTERRUPT_VECTOR);
+        }
+        if !contiguous
+            || (self.cc >> CC_IOCQES_SHIFT) & 0xf != CQ_ENTRY_SHIFT
+            || cmd.prp1 % PAGE_SIZE != 0
+            || !mem.check_range(GuestAddress(cmd.prp1), (qsize << CQ_ENTRY_SHIFT) as usize)
+        {
+            return Err(Status::INVALID_FIELD);
+        }
+
+        self.cqs[usize::from(qid)] = Some(CompletionQueue::new(
+            GuestAddress(cmd.prp1),
+            qsize as u16,
+            vector,
+            interrupts,
+        ));
+        Ok(0)
+    }
+
+    fn create_sq(&mut self, mem: &GuestMemoryMmap, cmd: &Command) -> Result<u32, Status> {
+        let qid = cmd.cdw10 as u16;
+        let qsize = (cmd.cdw10 >> 16) + 1;
+        let contiguous = cmd.cdw11 & 1 != 0;
+        let cqid = (cmd.cdw11 >> 16) as u16;
+
+        self.check_new_queue(
+            qid,
+            qsize,
+            self.sqs
+                .get(usize::from(qid))
+                .map_or(false, Option::is_some),
+        )?;
+        if cqid == 0
+            || self
+                .cqs
+                .get(usize::from(cqid))
+                .map_or(true, Option::is_none)
+        {
+            return Err(Status::COMPLETION_QUEUE_INVALID);
+        }
+        if !contiguous
+            || (self.cc >> CC_IOSQES_SHIFT) & 0xf != SQ_ENTRY_SHIFT
+            || cmd.prp1 % PAGE_SIZE != 0
+            || !mem.check_range(GuestAddress(cmd.prp1), (qsize << SQ_ENTRY_SHIFT) as usize)
+        {
+            return Err(Status::INVALID_FIELD);
+        }
+
+        self.sqs[usize::from(qid)] = Some(SubmissionQueue::new(
+            GuestAddress(cmd.prp1),
+            qsize as u16,
+            cqid,
+        ));
+        Ok(0)
+    }
+
+    fn delete_sq(&mut self, cmd: &Command) -> Result<u32, Status> {
+        let qid = usize::from(cmd.cdw10 as u16);
+        if qid == 0 || self.sqs.get(qid).map_or(true, Option::is_none) {
+            return Err(Status::INVALID_QUEUE_ID);
+        }
+        // The commands of the queue still in flight complete silently.
+        self.sqs[qid] = None;
+        Ok(0)
+    }
+
+    fn delete_cq(&mut self, cmd: &Command) -> Result<u32, Status> {
+        let qid = cmd.cdw10 as u16;
+        if qid == 0 || self.cqs.get(usize::from(qid)).map_or(true, Option::is_none) {
+            return Err(Status::INVALID_QUEUE_ID);
+        }
+        if self.sqs.iter().flatten().any(|sq| sq.cqid == qid) {
+            return Err(Status::INVALID_QUEUE_DELETION);
+        }
+        self.cqs[usize::from(qid)] = None;
+        Ok(0)
+    }
+
+    fn identify(&self, mem: &GuestMemoryMmap, cmd: &Command) -> Result<u32, Status> {
+        let mut data = [0u8; IDENTIFY_SIZE];
+
+        match cmd.cdw10 as u8 {
+            IDENTIFY_NAMESPACE => {
+                if !Namespace::is_valid(cmd.nsid) {
+                    return Err(Status::INVALID_NAMESPACE);
+                }
+                let num_blocks = self.namespace.num_blocks.to_le_bytes();
+                // Size, capacity and utilization.
+                data[0..8].copy_from_slice(&num_blocks);
+                data[8..16].copy_from_slice(&num_blocks);
+                data[16..24].copy_from_slice(&num_blocks);
+                // A single LBA format, without metadata.
+                data[128 + 2] = self.namespace.lba_shift as u8;
+            }
+            IDENTIFY_CONTROLLER => {
+                data[0..2].copy_from_slice(&self.vendor_id.to_le_bytes());
+                data[2..4].copy_from_slice(&self.vendor_id.to_le_bytes());
+                copy_str(&mut data[4..24], &self.serial);
+                copy_str(&mut data[24..64], MODEL);
+                copy_str(&mut data[64..72], FIRMWARE_REVISION);
+                data[77] = MDTS;
+                data[80..84].copy_from_slice(&NVME_VERSION.to_le_bytes());
+                // An I/O controller.
+                data[111] = 1;
+                // Abort and asynchronous event request limits, zero-based.
+                data[258] = 3;
+                data[259] = (MAX_ASYNC_EVENTS - 1) as u8;
+                // A single read-only firmware slot.
+                data[260] = 0x3;
+                data[512] = (SQ_ENTRY_SHIFT << 4 | SQ_ENTRY_SHIFT) as u8;
+                data[513] = (CQ_ENTRY_SHIFT << 4 | CQ_ENTRY_SHIFT) as u8;
+                data[516..520].copy_from_slice(&NSID.to_le_bytes());
+                // A volatile write cache, flushed by the Flush command.
+                data[525] = 1;
+                copy_str(
+                    &mut data[768..1024],
+                    &format!("nqn.2021-01.org.cloudhypervisor:nvme:{}", self.serial),
+                );
+            }
+            IDENTIFY_ACTIVE_NAMESPACES => {
+                if cmd.nsid >= 0xffff_fffe {
+                    return Err(Status::INVALID_NAMESPACE);
+                }
+                if cmd.nsid < NSID {
+                    data[0..4].copy_from_slice(&NSID.to_le_bytes());
+                }
+            }
+            // No identifier is reported for the namespace.
+            IDENTIFY_NAMESPACE_DESCRIPTORS => {
+                if !Namespace::is_valid(cmd.nsid) {
+                    return Err(Status::INVALID_NAMESPACE);
+                }
+            }
+            cns => {
+                debug!("Unsupported NVMe identify CNS {:#x}", cns);
+                return Err(Status::INVALID_FIELD);
+            }
+        }
+
+        write_data(mem, cmd, &data, IDENTIFY_SIZE)?;
+        Ok(0)
+    }
+
+    fn get_log_page(&self, mem: &GuestMemoryMmap, cmd: &Command) -> Result<u32, Status> {
+        let numd = ((cmd.cdw11 & 0xffff) << 16 | cmd.cdw10 >> 16) as usize + 1;
+        let offset = (u64::from(cmd.cdw13) << 32 | u64::from(cmd.cdw12)) as usize;
+        let len = std::cmp::min(numd * 4, MAX_TRANSFER_SIZE);
+
+        let mut log = match cmd.cdw10 as u8 {
+            // No error ever logged.
+            LOG_ERROR_INFORMATION => vec![0u8; 64],
+            LOG_SMART_HEALTH => {
+                let mut log = vec![0u8; 512];
+                // 20 degrees Celsius, and all the spare capacity.
+                log[1..3].copy_from_slice(&293u16.to_le_bytes());
+                log[3] = 100;
+                log[4] = 10;
+                log
+            }
+            LOG_FIRMWARE_SLOT => {
+                let mut log = vec![0u8; 512];
+                log[0] = 1;
+                copy_str(&mut log[8..16], FIRMWARE_REVISION);
+                log
+            }
+            lid => {
+                debug!("Unsupported NVMe log page {:#x}", lid);
+                return Err(Status::INVALID_LOG_PAGE);
+            }
+        };
+        if offset > log.len() || offset % 4 != 0 {
+            return Err(Status::INVALID_FIELD);
+        }
+
+        // Reading past the end of the log returns zeros.
+        let mut data = log.split_off(offset);
+        data.resize(len, 0);
+        write_data(mem, cmd, &data, len)?;
+        Ok(0)
+    }
+
+    fn set_features(&mut self, cmd: &Command) -> Result<u32, Status> {
+        let fid = cmd.cdw10 as u8;
+        let value = match fid {
+            FEATURE_NUMBER_OF_QUEUES => {
+                let nsqr = cmd.cdw11 & 0xffff;
+                let ncqr = cmd.cdw11 >> 16;
+                if nsqr == 0xffff || ncqr == 0xffff {
+                    return Err(Status::INVALID_FIELD);
+                }
+                let max = u32::from(self.max_io_queues() - 1);
+                std::cmp::min(ncqr, max) << 16 | std::cmp::min(nsqr, max)
+            }
+            FEATURE_INTERRUPT_VECTOR_CONFIG => {
+                if (cmd.cdw11 & 0xffff) >= u32::from(self.num_vectors) {
+                    return Err(Status::INVALID_FIELD);
+                }
+                return Ok(0);
+            }
+            FEATURE_VOLATILE_WRITE_CACHE => cmd.cdw11 & 1,
+            fid if self.features.contains_key(&fid) => cmd.cdw11,
+            fid => {
+                debug!("Unsupported NVMe feature {:#x}", fid);
+                return Err(Status::INVALID_FIELD);
+            }
+        };
+        self.features.insert(fid, value);
+
+        // The number of queues allocated is reported to the driver, which
+        // may be fewer than requested.
+        Ok(if fid == FEATURE_NUMBER_OF_QUEUES {
+            value
+        } else {
+            0
+        })
+    }
+
+    fn get_features(&self, cmd: &Command) -> Result<u32, Status> {
+        match cmd.cdw10 as u8 {
+            // No interrupt coalescing, whatever the vector.
+            FEATURE_INTERRUPT_VECTOR_CONFIG => {
+                if (cmd.cdw11 & 0xffff) >= u32::from(self.num_vectors) {
+                    return Err(Status::INVALID_FIELD);
+                }
+                Ok(cmd.cdw11 & 0xffff)
+            }
+            fid => self.features.get(&fid).copied().ok_or_else(|| {
+                debug!("Unsupported NVMe feature {:#x}", fid);
+                Status::INVALID_FIELD
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::queue::Completion;
+    use vm_memory::Bytes;
+
+    const ASQ: u64 = 0x1000;
+    const ACQ: u64 = 0x2000;
+    const DATA: u64 = 0x3000;
+
+    pub fn controller(mem: &GuestMemoryMmap) -> Controller {
+        let namespace = Namespace {
+            lba_shift: 9,
+            num_blocks: 2048,
+            readonly: false,
+        };
+        let mut ctrl = Controller::new(namespace, "serial".to_owned(), 0x1b36, 2, 64);
+        ctrl.write_register(mem, REG_AQA as u64, &(3u32 << 16 | 3).to_le_bytes());
+        ctrl.write_register(mem, REG_ASQ as u64, &ASQ.to_le_bytes());
+        ctrl.write_register(mem, REG_ACQ as u64, &ACQ.to_le_bytes());
+        let cc = CC_EN | 6 << CC_IOSQES_SHIFT | 4 << CC_IOCQES_SHIFT;
+        ctrl.write_register(mem, REG_CC as u64, &cc.to_le_bytes());
+        ctrl
+    }
+
+    fn admin(ctrl: &mut Controller, mem: &GuestMemoryMmap, cmd: Command) -> (Status, u32) {
+        ctrl.admin_command(mem, &cmd).unwrap()
+    }
+
+    /// Creates the I/O queues `qid` of 16 entries, the completion queue at
+    /// `cq` and the submission queue at `sq`.
+    pub fn create_io_queues(
+        ctrl: &mut Controller,
+        mem: &GuestMemoryMmap,
+        qid: u16,
+        cq: u64,
+        sq: u64,
+    ) {
+        let create_cq = Command {
+            cdw0: u32::from(ADMIN_CREATE_CQ),
+            prp1: cq,
+            cdw10: 15 << 16 | u32::from(qid),
+            cdw11: u32::from(qid) << 16 | 0x3,
+            ..Default::default()
+        };
+        assert_eq!(admin(ctrl, mem, create_cq).0, Status::SUCCESS);
+        let create_sq = Command {
+            cdw0: u32::from(ADMIN_CREATE_SQ),
+            prp1: sq,
+            cdw10: 15 << 16 | u32::from(qid),
+            cdw11: u32::from(qid) << 16 | 0x1,
+            ..Default::default()
+        };
+        assert_eq!(admin(ctrl, mem, create_sq).0, Status::SUCCESS);
+    }
+
+    #[test]
+    fn test_controller_enable() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let mut ctrl = controller(&mem);
+        let mut csts = [0u8; 4];
+        ctrl.read_register(REG_CSTS as u64, &mut csts);
+        assert_eq!(u32::from_le_bytes(csts), CSTS_RDY);
+
+        // a command submitted to the admin queue completes on its completion
+        // queue, with the phase tag set
+        let identify = Command {
+            cdw0: u32::from(ADMIN_IDENTIFY) | 7 << 16,
+            prp1: DATA,
+            cdw10: u32::from(IDENTIFY_CONTROLLER),
+            ..Default::default()
+        };
+        mem.write_obj(identify, GuestAddress(ASQ)).unwrap();
+        ctrl.write_doorbell(0, 1);
+        let cmd = ctrl.pop_command(&mem, 0).unwrap();
+        let (status, dw0) = admin(&mut ctrl, &mem, cmd);
+        ctrl.complete(&mem, 0, cmd.cid(), status, dw0);
+        let entry: Completion = mem.read_obj(GuestAddress(ACQ)).unwrap();
+        assert_eq!((entry.cid, entry.sq_head, entry.status), (7, 1, 1));
+        assert_eq!(ctrl.take_interrupts(), vec![0]);
+        let nn: u32 = mem.read_obj(GuestAddress(DATA + 516)).unwrap();
+        assert_eq!(nn, 1);
+
+        // disabling the controller drops the queues
+        let mut cc = [0u8; 4];
+        ctrl.read_register(REG_CC as u64, &mut cc);
+        assert!(!ctrl.quiesce(REG_CC as u64, &cc));
+        assert!(ctrl.quiesce(REG_CC as u64, &0u32.to_le_bytes()));
+        assert!(!ctrl.ready());
+        ctrl.write_register(&mem, REG_CC as u64, &0u32.to_le_bytes());
+        assert!(!ctrl.ready());
+        assert!(ctrl.pop_command(&mem, 0).is_none());
+    }
+
+    #[test]
+    fn test_controller_io_queues() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let mut ctrl = controller(&mem);
+        let create_cq = |qid: u32, vector: u32| Command {
+            cdw0: u32::from(ADMIN_CREATE_CQ),
+            prp1: 0x4000,
+            cdw10: 15 << 16 | qid,
+            cdw11: vector << 16 | 0x3,
+            ..Default::default()
+        };
+        let create_sq = |qid: u32, cqid: u32| Command {
+            cdw0: u32::from(ADMIN_CREATE_SQ),
+            prp1: 0x5000,
+            cdw10: 15 << 16 | qid,
+            cdw11: cqid << 16 | 0x1,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            admin(&mut ctrl, &mem, create_sq(1, 1)).0,
+            Status::COMPLETION_QUEUE_INVALID
+        );
+        assert_eq!(
+            admin(&mut ctrl, &mem, create_cq(1, 3)).0,
+            Status::INVALID_INTERRUPT_VECTOR
+        );
+        assert_eq!(
+            admin(&mut ctrl, &mem, create_cq(3, 1)).0,
+            Status::INVALID_QUEUE_ID
+        );
+        assert_eq!(admin(&mut ctrl, &mem, create_cq(1, 1)).0, Status::SUCCESS);
+        assert_eq!(admin(&mut ctrl, &mem, create_sq(1, 1)).0, Status::SUCCESS);
+        assert_eq!(
+            admin(&mut ctrl, &mem, create_sq(1, 1)).0,
+            Status::INVALID_QUEUE_ID
+        );
+        assert_eq!(ctrl.io_submission_queues(), vec![1]);
+
+        // a completion queue can't go before its submission queues
+        let delete = |opcode: u8, qid: u32| Command {
+            cdw0: u32::from(opcode),
+            cdw10: qid,
+            ..Default::default()
+        };
+        assert_eq!(
+            admin(&mut ctrl, &mem, delete(ADMIN_DELETE_CQ, 1)).0,
+            Status::INVALID_QUEUE_DELETION
+        );
+        assert_eq!(
+            admin(&mut ctrl, &mem, delete(ADMIN_DELETE_SQ, 1)).0,
+            Status::SUCCESS
+        );
+        assert_eq!(
+            admin(&mut ctrl, &mem, delete(ADMIN_DELETE_CQ, 1)).0,
+            Status::SUCCESS
+        );
+
+        // the queues allocated are capped to the ones supported
+        let set_queues = Command {
+            cdw0: u32::from(ADMIN_SET_FEATURES),
+            cdw10: u32::from(FEATURE_NUMBER_OF_QUEUES),
+            cdw11: 7 << 16 | 7,
+            ..Default::default()
+        };
+        assert_eq!(
+            admin(&mut ctrl, &mem, set_queues),
+            (Status::SUCCESS, 1 << 16 | 1)
+        );
+    }
+
+    #[test]
+    fn test_controller_backlog() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let mut ctrl = controller(&mem);
+        let get_features = |cid: u32| Command {
+            cdw0: u32::from(ADMIN_GET_FEATURES) | cid << 16,
+            cdw10: u32::from(FEATURE_ARBITRATION),
+            ..Default::default()
+        };
+
+        // the admin completion queue holds 3 entries
+        for cid in 0..3 {
+            mem.write_obj(get_features(cid), GuestAddress(ASQ + u64::from(cid) * 64))
+                .unwrap();
+        }
+        ctrl.write_doorbell(0, 3);
+        for _ in 0..3 {
+            let cmd = ctrl.pop_command(&mem, 0).unwrap();
+            let (status, dw0) = admin(&mut ctrl, &mem, cmd);
+            ctrl.complete(&mem, 0, cmd.cid(), status, dw0);
+        }
+        mem.write_obj(get_features(3), GuestAddress(ASQ + 3 * 64))
+            .unwrap();
+        ctrl.write_doorbell(0, 0);
+        let cmd = ctrl.pop_command(&mem, 0).unwrap();
+        let (status, dw0) = admin(&mut ctrl, &mem, cmd);
+        ctrl.complete(&mem, 0, cmd.cid(), status, dw0);
+
+        // no command is fetched while a completion waits for room
+        mem.write_obj(get_features(4), GuestAddress(ASQ)).unwrap();
+        ctrl.write_doorbell(0, 1);
+        assert!(ctrl.pop_command(&mem, 0).is_none());
+
+        ctrl.write_doorbell(4, 3);
+        ctrl.flush_completions(&mem);
+        let entry: Completion = mem.read_obj(GuestAddress(ACQ + 3 * 16)).unwrap();
+        assert_eq!(entry.cid, 3);
+        assert_eq!(ctrl.pop_command(&mem, 0).unwrap().cid(), 4);
+    }
+}
@@ -0,0 +1,394 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::controller::{Controller, Namespace, DOORBELLS_OFFSET, REGISTERS_SIZE};
+use crate::seccomp_filters::get_seccomp_filter;
+use crate::worker::{NvmeInterrupt, NvmeWorker, Shared};
+use crate::{Error, Result};
+use anyhow::anyhow;
+use block_util::async_io::DiskFile;
+use pci::{
+    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
+    PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciMassStorageSubclass, PciProgrammingInterface,
+};
+use seccomp::{SeccompAction, SeccompFilter};
+use std::any::Any;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::interrupt::{InterruptIndex, InterruptManager, MsiIrqGroupConfig};
+use vm_device::BusDevice;
+use vm_memory::{
+    Address, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap, GuestUsize,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+
+// Guests bind their NVMe driver from the class code, these are the
+// identifiers of the controller QEMU emulates.
+const NVME_PCI_VENDOR_ID: u16 = 0x1b36;
+const NVME_PCI_DEVICE_ID: u16 = 0x0010;
+
+// The registers, the doorbells and the MSI-X structures all live in BAR0.
+const BAR_SIZE: u64 = 0x4000;
+const MSIX_TABLE_BAR_OFFSET: u64 = 0x2000;
+const MSIX_PBA_BAR_OFFSET: u64 = 0x3000;
+
+/// Largest number of I/O queues, each of them getting its own MSI-X vector
+/// on top of the admin queue one.
+pub const MAX_IO_QUEUES: usize = 255;
+
+struct NvmeProgrammingInterface;
+
+impl PciProgrammingInterface for NvmeProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        // NVM Express
+        0x02
+    }
+}
+
+/// An NVMe controller exposing a disk image as its only namespace.
+pub struct NvmePciDevice {
+    id: String,
+    configuration: PciConfiguration,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    msix_num: u16,
+    shared: Arc<Shared>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    kick_evt: EventFd,
+    kill_evt: EventFd,
+    bar_regions: Vec<(GuestAddress, GuestUsize, PciBarRegionType)>,
+}
+
+impl NvmePciDevice {
+    /// Creates a controller with `num_queues` I/O queue pairs of up to
+    /// `queue_size` entries, and starts the thread processing them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: String,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        mut disk_image: Box<dyn DiskFile>,
+        readonly: bool,
+        num_queues: usize,
+        queue_size: u16,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        pci_device_bdf: u32,
+        seccomp_action: SeccompAction,
+    ) -> Result<Self> {
+        if num_queues == 0 || num_queues > MAX_IO_QUEUES {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
+        let disk_size = disk_image.size().map_err(Error::DiskSize)?;
+        let block_size = disk_image.block_size().map_err(Error::BlockSize)?;
+        let lba_shift = block_size.trailing_zeros();
+        let namespace = Namespace {
+            lba_shift,
+            num_blocks: disk_size >> lba_shift,
+            readonly,
+        };
+        if disk_size % u64::from(block_size) != 0 {
+            warn!(
+                "Disk size {} is not a multiple of {} bytes, the last bytes are not exposed",
+                disk_size, block_size
+            );
+        }
+
+        let msix_num = num_queues as u16 + 1;
+        let interrupt_source_group = interrupt_manager
+            .create_group(MsiIrqGroupConfig {
+                base: 0,
+                count: msix_num as InterruptIndex,
+            })
+            .map_err(Error::CreateInterruptGroup)?;
+        let msix_config = Arc::new(Mutex::new(MsixConfig::new(
+            msix_num,
+            interrupt_source_group.clone(),
+            pci_device_bdf,
+        )));
+
+        let configuration = PciConfiguration::new(
+            NVME_PCI_VENDOR_ID,
+            NVME_PCI_DEVICE_ID,
+            0x2,
+            PciClassCode::MassStorage,
+            &PciMassStorageSubclass::NVMController,
+            Some(&NvmeProgrammingInterface),
+            PciHeaderType::Device,
+            NVME_PCI_VENDOR_ID,
+            NVME_PCI_DEVICE_ID,
+            Some(msix_config.clone()),
+        );
+
+        // The device identifier is unique within the VM, which is all the
+        // serial number is needed for.
+        let controller = Controller::new(
+            namespace,
+            id.clone(),
+            NVME_PCI_VENDOR_ID,
+            num_queues as u16,
+            queue_size,
+        );
+        let shared = Arc::new(Shared::new(controller));
+
+        let kick_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let queue_depth = u32::from(queue_size);
+        let disk = disk_image
+            .new_async_io(queue_depth)
+            .map_err(Error::CreateAsyncIo)?;
+        let mut worker = NvmeWorker::new(
+            shared.clone(),
+            memory.clone(),
+            disk,
+            NvmeInterrupt {
+                msix_config: msix_config.clone(),
+                interrupt_source_group,
+            },
+            kick_evt.try_clone().map_err(Error::EventFd)?,
+            kill_evt.try_clone().map_err(Error::EventFd)?,
+            queue_depth as usize,
+        );
+
+        let seccomp_filter =
+            get_seccomp_filter(&seccomp_action).map_err(Error::CreateSeccompFilter)?;
+        thread::Builder::new()
+            .name(id.clone())
+            .spawn(move || {
+                // The asynchronous I/O relies on the disk image staying open.
+                let _disk_image = disk_image;
+                if let Err(e) = SeccompFilter::apply(seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                } else if let Err(e) = worker.run() {
+                    error!("Error running NVMe worker: {:?}", e);
+                }
+            })
+            .map_err(Error::SpawnThread)?;
+
+        Ok(NvmePciDevice {
+            id,
+            configuration,
+            msix_config,
+            msix_num,
+            shared,
+            mem: memory,
+            kick_evt,
+            kill_evt,
+            bar_regions: Vec::new(),
+        })
+    }
+
+    fn kick(&self) {
+        if let Err(e) = self.kick_evt.write(1) {
+            error!("Failed to kick the NVMe worker: {}", e);
+        }
+    }
+}
+
+impl Drop for NvmePciDevice {
+    fn drop(&mut self) {
+        // Ignore the result because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+    }
+}
+
+impl PciDevice for NvmePciDevice {
+    fn allocate_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+        _mmio_allocator: &mut AddressAllocator,
+        mmio_hole_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
+    {
+        // A 32-bit BAR, for the firmwares to reach the disk without
+        // requiring excessive identity mapping.
+        let region_type = PciBarRegionType::Memory32BitRegion;
+        let addr = mmio_hole_allocator
+            .allocate(None, BAR_SIZE, Some(BAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(BAR_SIZE))?;
+
+        let config = PciBarConfiguration::default()
+            .set_register_index(0)
+            .set_address(addr.raw_value())
+            .set_size(BAR_SIZE)
+            .set_region_type(region_type);
+        let bar = self
+            .configuration
+            .add_pci_bar(&config)
+            .map_err(|e| PciDeviceError::IoRegistrationFailed(addr.raw_value(), e))?
+            as u8;
+
+        let msix_cap = MsixCap::new(
+            bar,
+            self.msix_num,
+            MSIX_TABLE_BAR_OFFSET as u32,
+            bar,
+            MSIX_PBA_BAR_OFFSET as u32,
+        );
+        self.configuration
+            .add_capability(&msix_cap)
+            .map_err(PciDeviceError::CapabilitiesSetup)?;
+
+        self.bar_regions.push((addr, BAR_SIZE, region_type));
+        Ok(vec![(addr, BAR_SIZE, region_type)])
+    }
+
+    fn free_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+        _mmio_allocator: &mut AddressAllocator,
+        mmio_hole_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for (addr, length, _) in self.bar_regions.drain(..) {
+            mmio_hole_allocator.free(addr, length);
+        }
+        Ok(())
+    }
+
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn move_bar(
+        &mut self,
+        old_base: u64,
+        new_base: u64,
+    ) -> std::result::Result<(), std::io::Error> {
+        for (addr, _, _) in self.bar_regions.iter_mut() {
+            if addr.0 == old_base {
+                *addr = GuestAddress(new_base);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match offset {
+            o if o < REGISTERS_SIZE => self
+                .shared
+                .controller
+                .lock()
+                .unwrap()
+                .read_register(o, data),
+            o if (MSIX_TABLE_BAR_OFFSET..MSIX_PBA_BAR_OFFSET).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .read_table(o - MSIX_TABLE_BAR_OFFSET, data),
+            o if (MSIX_PBA_BAR_OFFSET..BAR_SIZE).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .read_pba(o - MSIX_PBA_BAR_OFFSET, data),
+            // The doorbells are write-only.
+            _ => data.iter_mut().for_each(|b| *b = 0),
+        }
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        match offset {
+            o if o < REGISTERS_SIZE => {
+                let mem = self.mem.memory();
+                let mut controller = self.shared.controller.lock().unwrap();
+                if controller.quiesce(o, data) {
+                    controller = self.shared.wait_idle(controller);
+                }
+                controller.write_register(&mem, o, data);
+            }
+            o if (DOORBELLS_OFFSET..MSIX_TABLE_BAR_OFFSET).contains(&o) && data.len() == 4 => {
+                let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                self.shared
+                    .controller
+                    .lock()
+                    .unwrap()
+                    .write_doorbell(o - DOORBELLS_OFFSET, value);
+                self.kick();
+            }
+            o if (MSIX_TABLE_BAR_OFFSET..MSIX_PBA_BAR_OFFSET).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .write_table(o - MSIX_TABLE_BAR_OFFSET, data),
+            o if (MSIX_PBA_BAR_OFFSET..BAR_SIZE).contains(&o) => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .write_pba(o - MSIX_PBA_BAR_OFFSET, data),
+            _ => warn!(
+                "{}: Unsupported {} bytes write at {:#x}",
+                self.id,
+                data.len(),
+                offset
+            ),
+        }
+
+        None
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl BusDevice for NvmePciDevice {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl Pausable for NvmePciDevice {
+    fn pause(&mut self) -> std::result::Result<(), MigratableError> {
+        self.shared.pause();
+        Ok(())
+    }
+
+    fn resume(&mut self) -> std::result::Result<(), MigratableError> {
+        self.shared.resume();
+        self.kick();
+        Ok(())
+    }
+}
+
+impl Snapshottable for NvmePciDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // Neither the registers nor the queues are saved, the restored guest
+    // would find its disk gone.
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "NVMe controller {} doesn't support snapshots",
+            self.id
+        )))
+    }
+}
+
+impl Transportable for NvmePciDevice {}
+impl Migratable for NvmePciDevice {}
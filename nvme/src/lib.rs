@@ -0,0 +1,45 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulates an NVMe controller, exposing a disk image through a single
+//! namespace. The controller implements the mandatory admin commands and
+//! the Read, Write and Flush commands of the NVM command set, the queues
+//! being processed by a dedicated thread.
+
+#[macro_use]
+extern crate log;
+
+mod controller;
+mod device;
+mod queue;
+mod seccomp_filters;
+mod worker;
+
+pub use self::device::{NvmePciDevice, MAX_IO_QUEUES};
+
+use block_util::async_io::DiskFileError;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No I/O queue, or more of them than supported.
+    InvalidNumQueues(usize),
+    /// Failed to get the size of the disk image.
+    DiskSize(DiskFileError),
+    /// Failed to get the block size of the disk image.
+    BlockSize(DiskFileError),
+    /// Failed to create the asynchronous I/O of the disk image.
+    CreateAsyncIo(DiskFileError),
+    /// Failed to create the MSI-X interrupt group.
+    CreateInterruptGroup(io::Error),
+    /// Failed to create an EventFd.
+    EventFd(io::Error),
+    /// Failed to create the seccomp filter of the worker thread.
+    CreateSeccompFilter(seccomp::SeccompError),
+    /// Failed to spawn the worker thread.
+    SpawnThread(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
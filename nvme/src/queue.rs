@@ -0,0 +1,426 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::VecDeque;
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+
+/// The memory page size of the controller, the only one it supports.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// log2 of the size of a submission queue entry.
+pub const SQ_ENTRY_SHIFT: u32 = 6;
+/// log2 of the size of a completion queue entry.
+pub const CQ_ENTRY_SHIFT: u32 = 4;
+
+/// A submission queue entry, common to the admin and I/O command sets.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Command {
+    pub cdw0: u32,
+    pub nsid: u32,
+    pub rsvd: [u32; 2],
+    pub mptr: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+// SAFETY: Command only contains plain integers, without any padding.
+unsafe impl ByteValued for Command {}
+
+impl Command {
+    pub fn opcode(&self) -> u8 {
+        self.cdw0 as u8
+    }
+
+    pub fn cid(&self) -> u16 {
+        (self.cdw0 >> 16) as u16
+    }
+
+    /// Whether the data buffer is described by SGLs rather than PRPs.
+    pub fn uses_sgl(&self) -> bool {
+        (self.cdw0 >> 14) & 0x3 != 0
+    }
+}
+
+/// A completion queue entry.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Completion {
+    pub dw0: u32,
+    pub rsvd: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub cid: u16,
+    /// The phase tag in bit 0 and the status field above it.
+    pub status: u16,
+}
+
+// SAFETY: Completion only contains plain integers, without any padding.
+unsafe impl ByteValued for Completion {}
+
+/// The status of a completed command, as its status code type and status
+/// code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Status {
+    sct: u8,
+    sc: u8,
+}
+
+impl Status {
+    const fn generic(sc: u8) -> Self {
+        Status { sct: 0, sc }
+    }
+
+    const fn command_specific(sc: u8) -> Self {
+        Status { sct: 1, sc }
+    }
+
+    const fn media(sc: u8) -> Self {
+        Status { sct: 2, sc }
+    }
+
+    pub const SUCCESS: Status = Status::generic(0x00);
+    pub const INVALID_OPCODE: Status = Status::generic(0x01);
+    pub const INVALID_FIELD: Status = Status::generic(0x02);
+    pub const DATA_TRANSFER_ERROR: Status = Status::generic(0x04);
+    pub const INTERNAL_ERROR: Status = Status::generic(0x06);
+    pub const INVALID_NAMESPACE: Status = Status::generic(0x0b);
+    pub const LBA_OUT_OF_RANGE: Status = Status::generic(0x80);
+
+    pub const COMPLETION_QUEUE_INVALID: Status = Status::command_specific(0x00);
+    pub const INVALID_QUEUE_ID: Status = Status::command_specific(0x01);
+    pub const INVALID_QUEUE_SIZE: Status = Status::command_specific(0x02);
+    pub const ASYNC_EVENT_LIMIT_EXCEEDED: Status = Status::command_specific(0x05);
+    pub const INVALID_INTERRUPT_VECTOR: Status = Status::command_specific(0x08);
+    pub const INVALID_LOG_PAGE: Status = Status::command_specific(0x09);
+    pub const INVALID_QUEUE_DELETION: Status = Status::command_specific(0x0c);
+    pub const WRITE_TO_READ_ONLY: Status = Status::command_specific(0x82);
+
+    pub const WRITE_FAULT: Status = Status::media(0x80);
+    pub const UNRECOVERED_READ_ERROR: Status = Status::media(0x81);
+
+    /// The status field of a completion entry, without the phase tag. The
+    /// errors are all reported with Do Not Retry set, retrying them would
+    /// fail the same way.
+    fn field(self) -> u16 {
+        let dnr = if self == Status::SUCCESS { 0 } else { 1 << 14 };
+        dnr | u16::from(self.sct) << 8 | u16::from(self.sc)
+    }
+}
+
+#[derive(Debug)]
+pub struct SubmissionQueue {
+    addr: GuestAddress,
+    size: u16,
+    head: u16,
+    tail: u16,
+    pub cqid: u16,
+}
+
+impl SubmissionQueue {
+    pub fn new(addr: GuestAddress, size: u16, cqid: u16) -> Self {
+        SubmissionQueue {
+            addr,
+            size,
+            head: 0,
+            tail: 0,
+            cqid,
+        }
+    }
+
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// Updates the tail from its doorbell, returning false if the value is
+    /// out of the queue.
+    pub fn set_tail(&mut self, tail: u16) -> bool {
+        if tail >= self.size {
+            return false;
+        }
+        self.tail = tail;
+        true
+    }
+
+    /// Fetches the next command submitted by the driver.
+    pub fn pop(&mut self, mem: &GuestMemoryMmap) -> Result<Option<Command>, GuestMemoryError> {
+        if self.head == self.tail {
+            return Ok(None);
+        }
+        let addr = self
+            .addr
+            .unchecked_add(u64::from(self.head) << SQ_ENTRY_SHIFT);
+        let command = mem.read_obj(addr)?;
+        self.head = (self.head + 1) % self.size;
+        Ok(Some(command))
+    }
+}
+
+#[derive(Debug)]
+pub struct CompletionQueue {
+    addr: GuestAddress,
+    size: u16,
+    head: u16,
+    tail: u16,
+    phase: bool,
+    pub vector: u16,
+    pub interrupts: bool,
+    /// Completions waiting for the driver to make room in the queue.
+    pending: VecDeque<Completion>,
+    /// Whether entries have been posted since the last interrupt.
+    pub needs_interrupt: bool,
+}
+
+impl CompletionQueue {
+    pub fn new(addr: GuestAddress, size: u16, vector: u16, interrupts: bool) -> Self {
+        CompletionQueue {
+            addr,
+            size,
+            head: 0,
+            tail: 0,
+            phase: true,
+            vector,
+            interrupts,
+            pending: VecDeque::new(),
+            needs_interrupt: false,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        (self.tail + 1) % self.size == self.head
+    }
+
+    /// Updates the head from its doorbell, returning false if the value is
+    /// out of the queue.
+    pub fn set_head(&mut self, head: u16) -> bool {
+        if head >= self.size {
+            return false;
+        }
+        self.head = head;
+        true
+    }
+
+    fn write(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        mut entry: Completion,
+    ) -> Result<(), GuestMemoryError> {
+        entry.status |= u16::from(self.phase);
+        let addr = self
+            .addr
+            .unchecked_add(u64::from(self.tail) << CQ_ENTRY_SHIFT);
+        mem.write_obj(entry, addr)?;
+        self.tail += 1;
+        if self.tail == self.size {
+            self.tail = 0;
+            self.phase = !self.phase;
+        }
+        self.needs_interrupt = true;
+        Ok(())
+    }
+
+    /// Posts a completion, or keeps it for later if the queue is full.
+    pub fn push(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        sq_id: u16,
+        sq_head: u16,
+        cid: u16,
+        status: Status,
+        dw0: u32,
+    ) -> Result<(), GuestMemoryError> {
+        let entry = Completion {
+            dw0,
+            rsvd: 0,
+            sq_head,
+            sq_id,
+            cid,
+            status: status.field() << 1,
+        };
+        if !self.pending.is_empty() || self.is_full() {
+            self.pending.push_back(entry);
+            return Ok(());
+        }
+        self.write(mem, entry)
+    }
+
+    /// Whether completions are waiting for room in the queue, in which case
+    /// no more commands should be fetched for it.
+    pub fn is_backlogged(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Posts the pending completions the queue has room for.
+    pub fn flush(&mut self, mem: &GuestMemoryMmap) -> Result<(), GuestMemoryError> {
+        while !self.is_full() {
+            match self.pending.pop_front() {
+                Some(entry) => self.write(mem, entry)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Walks the PRP entries of a command, returning the guest memory segments
+/// making its `len` bytes long data buffer.
+pub fn prp_segments(
+    mem: &GuestMemoryMmap,
+    prp1: u64,
+    prp2: u64,
+    len: usize,
+) -> Result<Vec<(GuestAddress, usize)>, Status> {
+    let mut segments = Vec::new();
+    let mut remaining = len as u64;
+
+    let first = std::cmp::min(remaining, PAGE_SIZE - prp1 % PAGE_SIZE);
+    segments.push((GuestAddress(prp1), first as usize));
+    remaining -= first;
+
+    if remaining == 0 {
+        return Ok(segments);
+    }
+    // Every entry but the first one must point to the start of a page.
+    if remaining <= PAGE_SIZE {
+        if prp2 % PAGE_SIZE != 0 {
+            return Err(Status::INVALID_FIELD);
+        }
+        segments.push((GuestAddress(prp2), remaining as usize));
+        return Ok(segments);
+    }
+
+    // PRP2 points to a list of entries, which may start anywhere in a page
+    // and whose last entry points to the next list if the buffer doesn't
+    // end within the page.
+    let mut list = prp2;
+    if list % 8 != 0 {
+        return Err(Status::INVALID_FIELD);
+    }
+    while remaining > 0 {
+        let entry: u64 = mem
+            .read_obj(GuestAddress(list))
+            .map_err(|_| Status::DATA_TRANSFER_ERROR)?;
+        let last = (list + 8) % PAGE_SIZE == 0;
+        if entry % PAGE_SIZE != 0 {
+            return Err(Status::INVALID_FIELD);
+        }
+        if last && remaining > PAGE_SIZE {
+            list = entry;
+            continue;
+        }
+        let count = std::cmp::min(remaining, PAGE_SIZE);
+        segments.push((GuestAddress(entry), count as usize));
+        remaining -= count;
+        list += 8;
+    }
+
+    Ok(segments)
+}
+
+/// Copies `data` to the buffer of an admin command, truncated to `len`.
+pub fn write_data(
+    mem: &GuestMemoryMmap,
+    command: &Command,
+    data: &[u8],
+    len: usize,
+) -> Result<(), Status> {
+    let len = std::cmp::min(data.len(), len);
+    let mut offset = 0;
+    for (addr, count) in prp_segments(mem, command.prp1, command.prp2, len)? {
+        mem.write_slice(&data[offset..offset + count], addr)
+            .map_err(|_| Status::DATA_TRANSFER_ERROR)?;
+        offset += count;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap()
+    }
+
+    #[test]
+    fn test_prp_segments() {
+        let mem = memory();
+
+        // within the first page, then across two pages
+        assert_eq!(
+            prp_segments(&mem, 0x1100, 0, 0x100).unwrap(),
+            vec![(GuestAddress(0x1100), 0x100)]
+        );
+        assert_eq!(
+            prp_segments(&mem, 0x1800, 0x3000, 0x1000).unwrap(),
+            vec![(GuestAddress(0x1800), 0x800), (GuestAddress(0x3000), 0x800)]
+        );
+        assert_eq!(
+            prp_segments(&mem, 0x1800, 0x3010, 0x1000),
+            Err(Status::INVALID_FIELD)
+        );
+
+        // a list starting at the end of a page chains to the next list
+        mem.write_obj(0x5000u64, GuestAddress(0x8ff0)).unwrap();
+        mem.write_obj(0xa000u64, GuestAddress(0x8ff8)).unwrap();
+        mem.write_obj(0x6000u64, GuestAddress(0xa000)).unwrap();
+        mem.write_obj(0x7000u64, GuestAddress(0xa008)).unwrap();
+        assert_eq!(
+            prp_segments(&mem, 0x4000, 0x8ff0, 0x3800).unwrap(),
+            vec![
+                (GuestAddress(0x4000), 0x1000),
+                (GuestAddress(0x5000), 0x1000),
+                (GuestAddress(0x6000), 0x1000),
+                (GuestAddress(0x7000), 0x800),
+            ]
+        );
+
+        // the last entry of a list is data when the buffer ends there
+        assert_eq!(
+            prp_segments(&mem, 0x4000, 0x8ff0, 0x3000).unwrap(),
+            vec![
+                (GuestAddress(0x4000), 0x1000),
+                (GuestAddress(0x5000), 0x1000),
+                (GuestAddress(0xa000), 0x1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_completion_queue() {
+        let mem = memory();
+        let mut cq = CompletionQueue::new(GuestAddress(0x1000), 2, 0, true);
+
+        cq.push(&mem, 1, 1, 10, Status::SUCCESS, 0).unwrap();
+        let entry: Completion = mem.read_obj(GuestAddress(0x1000)).unwrap();
+        assert_eq!((entry.cid, entry.sq_id, entry.status), (10, 1, 1));
+
+        // the queue holds a single entry, the next one waits for the head
+        cq.push(&mem, 1, 2, 11, Status::INVALID_FIELD, 0).unwrap();
+        let entry: Completion = mem.read_obj(GuestAddress(0x1010)).unwrap();
+        assert_eq!(entry.cid, 0);
+        assert!(cq.is_backlogged());
+
+        assert!(cq.set_head(1));
+        cq.flush(&mem).unwrap();
+        assert!(!cq.is_backlogged());
+        let entry: Completion = mem.read_obj(GuestAddress(0x1010)).unwrap();
+        assert_eq!(entry.cid, 11);
+        assert_eq!(entry.status, 1 | (1 << 15) | (0x02 << 1));
+
+        // the phase tag flips once the queue wraps
+        assert!(cq.set_head(0));
+        cq.push(&mem, 1, 3, 12, Status::SUCCESS, 0).unwrap();
+        let entry: Completion = mem.read_obj(GuestAddress(0x1000)).unwrap();
+        assert_eq!((entry.cid, entry.status), (12, 0));
+        assert!(!cq.set_head(2));
+    }
+}
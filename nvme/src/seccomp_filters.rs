@@ -0,0 +1,84 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use seccomp::{
+    allow_syscall, BpfProgram, Error, SeccompAction, SeccompError, SeccompFilter, SyscallRuleSet,
+};
+use std::convert::TryInto;
+
+// Define io_uring syscalls as they are not yet part of libc.
+const SYS_IO_URING_ENTER: i64 = 426;
+
+// The worker thread accesses the disk image the same way the virtio-block
+// ones do.
+fn nvme_worker_thread_rules() -> Vec<SyscallRuleSet> {
+    vec![
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_dup),
+        allow_syscall(libc::SYS_epoll_create1),
+        allow_syscall(libc::SYS_epoll_ctl),
+        allow_syscall(libc::SYS_epoll_pwait),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_epoll_wait),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_fallocate),
+        allow_syscall(libc::SYS_fdatasync),
+        allow_syscall(libc::SYS_fsync),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_ftruncate),
+        #[cfg(target_arch = "aarch64")]
+        // The definition of libc::SYS_ftruncate is missing on AArch64.
+        // Use a hard-code number instead.
+        allow_syscall(46),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(SYS_IO_URING_ENTER),
+        allow_syscall(libc::SYS_lseek),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_mprotect),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_openat),
+        allow_syscall(libc::SYS_prctl),
+        allow_syscall(libc::SYS_pread64),
+        allow_syscall(libc::SYS_preadv),
+        allow_syscall(libc::SYS_pwrite64),
+        allow_syscall(libc::SYS_pwritev),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sched_getaffinity),
+        allow_syscall(libc::SYS_set_robust_list),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_write),
+    ]
+}
+
+fn get_seccomp_filter_trap() -> Result<SeccompFilter, Error> {
+    SeccompFilter::new(
+        nvme_worker_thread_rules().into_iter().collect(),
+        SeccompAction::Trap,
+    )
+}
+
+fn get_seccomp_filter_log() -> Result<SeccompFilter, Error> {
+    SeccompFilter::new(
+        nvme_worker_thread_rules().into_iter().collect(),
+        SeccompAction::Log,
+    )
+}
+
+/// Generate a BPF program based on the seccomp_action value
+pub fn get_seccomp_filter(seccomp_action: &SeccompAction) -> Result<BpfProgram, SeccompError> {
+    match seccomp_action {
+        SeccompAction::Allow => Ok(vec![]),
+        SeccompAction::Log => get_seccomp_filter_log()
+            .and_then(|filter| filter.try_into())
+            .map_err(SeccompError::SeccompFilter),
+        _ => get_seccomp_filter_trap()
+            .and_then(|filter| filter.try_into())
+            .map_err(SeccompError::SeccompFilter),
+    }
+}
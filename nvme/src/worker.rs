@@ -0,0 +1,505 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::controller::{Controller, Namespace, MAX_TRANSFER_SIZE};
+use crate::queue::{prp_segments, Command, Status};
+use block_util::async_io::AsyncIo;
+use pci::MsixConfig;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use vm_device::interrupt::{InterruptIndex, InterruptSourceGroup};
+use vm_memory::{GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+// NVM command set opcodes.
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+/// Force Unit Access, in the command dword 12 of reads and writes.
+const IO_FUA: u32 = 1 << 30;
+
+const KICK_EVENT: u64 = 0;
+const COMPLETION_EVENT: u64 = 1;
+const KILL_EVENT: u64 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the epoll file.
+    CreateEpoll(io::Error),
+    /// Failed to add an event to the epoll file.
+    RegisterEvent(io::Error),
+    /// Failed to wait for the events.
+    Wait(io::Error),
+    /// Failed to read an event.
+    ReadEvent(io::Error),
+}
+
+/// The controller along with the condition the vCPU threads disabling it
+/// wait on.
+pub struct Shared {
+    pub controller: Mutex<Controller>,
+    /// Notified when the last I/O in flight completes.
+    idle: Condvar,
+}
+
+impl Shared {
+    pub fn new(controller: Controller) -> Self {
+        Shared {
+            controller: Mutex::new(controller),
+            idle: Condvar::new(),
+        }
+    }
+
+    /// Waits for the I/O in flight to complete.
+    pub fn wait_idle<'a>(
+        &self,
+        controller: MutexGuard<'a, Controller>,
+    ) -> MutexGuard<'a, Controller> {
+        self.idle
+            .wait_while(controller, |controller| controller.inflight > 0)
+            .unwrap()
+    }
+
+    /// Stops fetching commands and waits for the I/O in flight to
+    /// complete, leaving the guest memory untouched until resumed.
+    pub fn pause(&self) {
+        let mut controller = self.controller.lock().unwrap();
+        controller.paused = true;
+        self.wait_idle(controller);
+    }
+
+    /// Lets the commands be fetched again, the worker having to be kicked
+    /// for the ones submitted while paused.
+    pub fn resume(&self) {
+        self.controller.lock().unwrap().paused = false;
+    }
+}
+
+pub struct NvmeInterrupt {
+    pub msix_config: Arc<Mutex<MsixConfig>>,
+    pub interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+}
+
+impl NvmeInterrupt {
+    fn trigger(&self, vector: u16) -> io::Result<()> {
+        let config = &mut self.msix_config.lock().unwrap();
+        let entry = &config.table_entries[vector as usize];
+        // A masked vector is left pending, the interrupt being delivered
+        // once it's unmasked.
+        if config.masked() || entry.masked() {
+            config.set_pba_bit(vector, false);
+            return Ok(());
+        }
+
+        self.interrupt_source_group
+            .trigger(vector as InterruptIndex)
+    }
+}
+
+struct Request {
+    sqid: u16,
+    cid: u16,
+    opcode: u8,
+    /// Whether the data must be flushed before the write completes.
+    flush: bool,
+}
+
+/// Fetches the commands from the submission queues and completes them
+/// once the disk is done.
+pub struct NvmeWorker {
+    shared: Arc<Shared>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    disk: Box<dyn AsyncIo>,
+    interrupt: NvmeInterrupt,
+    kick_evt: EventFd,
+    kill_evt: EventFd,
+    /// Number of I/O commands the disk can have in flight.
+    queue_depth: usize,
+    inflight: HashMap<u64, Request>,
+    next_token: u64,
+}
+
+impl NvmeWorker {
+    pub fn new(
+        shared: Arc<Shared>,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        disk: Box<dyn AsyncIo>,
+        interrupt: NvmeInterrupt,
+        kick_evt: EventFd,
+        kill_evt: EventFd,
+        queue_depth: usize,
+    ) -> Self {
+        NvmeWorker {
+            shared,
+            mem,
+            disk,
+            interrupt,
+            kick_evt,
+            kill_evt,
+            queue_depth,
+            inflight: HashMap::new(),
+            next_token: 0,
+        }
+    }
+
+    fn register(epoll_fd: RawFd, fd: RawFd, event: u64) -> Result<(), Error> {
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, event),
+        )
+        .map_err(Error::RegisterEvent)
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        let epoll_fd = epoll::create(true).map_err(Error::CreateEpoll)?;
+        // Use 'File' to enforce closing on 'epoll_fd'
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        Self::register(epoll_fd, self.kick_evt.as_raw_fd(), KICK_EVENT)?;
+        Self::register(epoll_fd, self.disk.notifier().as_raw_fd(), COMPLETION_EVENT)?;
+        Self::register(epoll_fd, self.kill_evt.as_raw_fd(), KILL_EVENT)?;
+
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 3];
+        loop {
+            let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events[..]) {
+                Ok(num_events) => num_events,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Wait(e)),
+            };
+
+            for event in events.iter().take(num_events) {
+                match event.data {
+                    KICK_EVENT => {
+                        self.kick_evt.read().map_err(Error::ReadEvent)?;
+                    }
+                    COMPLETION_EVENT => {
+                        self.disk.notifier().read().map_err(Error::ReadEvent)?;
+                        self.process_completions();
+                    }
+                    KILL_EVENT => return Ok(()),
+                    _ => {}
+                }
+            }
+
+            // Completions make room for the commands that couldn't be
+            // submitted yet.
+            self.process_queues();
+        }
+    }
+
+    fn signal(&self, controller: &mut Controller) {
+        for vector in controller.take_interrupts() {
+            if let Err(e) = self.interrupt.trigger(vector) {
+                error!("Failed to trigger NVMe interrupt {}: {}", vector, e);
+            }
+        }
+    }
+
+    fn process_queues(&mut self) {
+        let mem = self.mem.memory();
+        let shared = self.shared.clone();
+        let mut controller = shared.controller.lock().unwrap();
+        if !controller.ready() || controller.paused {
+            return;
+        }
+
+        controller.flush_completions(&mem);
+
+        while let Some(cmd) = controller.pop_command(&mem, 0) {
+            if let Some((status, dw0)) = controller.admin_command(&mem, &cmd) {
+                controller.complete(&mem, 0, cmd.cid(), status, dw0);
+            }
+        }
+
+        // The queues are served in turn, a command at a time, until they
+        // are empty or the disk can't take more.
+        let namespace = controller.namespace();
+        let write_cache = controller.write_cache_enabled();
+        let mut sqids = controller.io_submission_queues();
+        while !sqids.is_empty() && self.inflight.len() < self.queue_depth {
+            sqids.retain(|sqid| {
+                if self.inflight.len() >= self.queue_depth {
+                    return true;
+                }
+                let cmd = match controller.pop_command(&mem, *sqid) {
+                    Some(cmd) => cmd,
+                    None => return false,
+                };
+                if let Err(status) = self.submit(&mem, &namespace, write_cache, *sqid, &cmd) {
+                    controller.complete(&mem, *sqid, cmd.cid(), status, 0);
+                }
+                true
+            });
+        }
+
+        controller.inflight = self.inflight.len();
+        self.signal(&mut controller);
+    }
+
+    fn submit(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        namespace: &Namespace,
+        write_cache: bool,
+        sqid: u16,
+        cmd: &Command,
+    ) -> Result<(), Status> {
+        let token = self.next_token;
+        let opcode = cmd.opcode();
+        let mut flush = false;
+
+        match opcode {
+            IO_FLUSH => {
+                if !Namespace::is_valid_or_broadcast(cmd.nsid) {
+                    return Err(Status::INVALID_NAMESPACE);
+                }
+                self.disk.fsync(Some(token)).map_err(|e| {
+                    error!("Failed to submit NVMe flush: {}", e);
+                    Status::INTERNAL_ERROR
+                })?;
+            }
+            IO_READ | IO_WRITE => {
+                if !Namespace::is_valid(cmd.nsid) {
+                    return Err(Status::INVALID_NAMESPACE);
+                }
+                if cmd.uses_sgl() {
+                    return Err(Status::INVALID_FIELD);
+                }
+                if opcode == IO_WRITE && namespace.readonly {
+                    return Err(Status::WRITE_TO_READ_ONLY);
+                }
+                let slba = u64::from(cmd.cdw11) << 32 | u64::from(cmd.cdw10);
+                let nlb = u64::from(cmd.cdw12 & 0xffff) + 1;
+                if slba
+                    .checked_add(nlb)
+                    .map_or(true, |end| end > namespace.num_blocks)
+                {
+                    return Err(Status::LBA_OUT_OF_RANGE);
+                }
+                let len = (nlb << namespace.lba_shift) as usize;
+                if len > MAX_TRANSFER_SIZE {
+                    return Err(Status::INVALID_FIELD);
+                }
+
+                let iovecs = prp_segments(mem, cmd.prp1, cmd.prp2, len)?
+                    .into_iter()
+                    .map(|(addr, len)| {
+                        let buf = mem
+                            .get_slice(addr, len)
+                            .map_err(|_| Status::DATA_TRANSFER_ERROR)?
+                            .as_ptr();
+                        Ok(libc::iovec {
+                            iov_base: buf as *mut libc::c_void,
+                            iov_len: len as libc::size_t,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, Status>>()?;
+                let offset = (slba << namespace.lba_shift) as libc::off_t;
+
+                let result = if opcode == IO_WRITE {
+                    flush = !write_cache || cmd.cdw12 & IO_FUA != 0;
+                    self.disk.write_vectored(offset, iovecs, token)
+                } else {
+                    self.disk.read_vectored(offset, iovecs, token)
+                };
+                result.map_err(|e| {
+                    error!("Failed to submit NVMe I/O: {}", e);
+                    Status::INTERNAL_ERROR
+                })?;
+            }
+            opcode => {
+                debug!("Unsupported NVMe I/O command {:#x}", opcode);
+                return Err(Status::INVALID_OPCODE);
+            }
+        }
+
+        self.next_token = self.next_token.wrapping_add(1);
+        self.inflight.insert(
+            token,
+            Request {
+                sqid,
+                cid: cmd.cid(),
+                opcode,
+                flush,
+            },
+        );
+        Ok(())
+    }
+
+    fn process_completions(&mut self) {
+        let mem = self.mem.memory();
+        let completions = self.disk.complete();
+        let shared = self.shared.clone();
+        let mut controller = shared.controller.lock().unwrap();
+
+        for (token, result) in completions {
+            let mut request = match self.inflight.remove(&token) {
+                Some(request) => request,
+                None => continue,
+            };
+
+            let status = if result < 0 {
+                error!(
+                    "NVMe command {:#x} failed: {}",
+                    request.opcode,
+                    io::Error::from_raw_os_error(-result)
+                );
+                if request.opcode == IO_READ {
+                    Status::UNRECOVERED_READ_ERROR
+                } else {
+                    Status::WRITE_FAULT
+                }
+            } else if request.flush {
+                // The write only completes once the data is on the disk.
+                request.flush = false;
+                match self.disk.fsync(Some(token)) {
+                    Ok(()) => {
+                        self.inflight.insert(token, request);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Failed to submit NVMe flush: {}", e);
+                        Status::WRITE_FAULT
+                    }
+                }
+            } else {
+                Status::SUCCESS
+            };
+
+            controller.complete(&mem, request.sqid, request.cid, status, 0);
+        }
+
+        controller.inflight = self.inflight.len();
+        if controller.inflight == 0 {
+            shared.idle.notify_all();
+        }
+        self.signal(&mut controller);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::tests::{controller, create_io_queues};
+    use crate::queue::Completion;
+    use block_util::raw_sync::RawFileSync;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use vm_device::interrupt::InterruptSourceConfig;
+    use vm_memory::{Bytes, GuestAddress};
+    use vmm_sys_util::tempfile::TempFile;
+
+    const CQ: u64 = 0x4000;
+    const SQ: u64 = 0x5000;
+    const DATA: u64 = 0x6000;
+
+    struct TestInterruptGroup {}
+
+    impl InterruptSourceGroup for TestInterruptGroup {
+        fn trigger(&self, _index: InterruptIndex) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+
+        fn update(&self, _index: InterruptIndex, _config: InterruptSourceConfig) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn io_command(opcode: u8, cid: u16, slba: u64) -> Command {
+        Command {
+            cdw0: u32::from(opcode) | u32::from(cid) << 16,
+            nsid: 1,
+            prp1: DATA,
+            cdw10: slba as u32,
+            cdw11: (slba >> 32) as u32,
+            ..Default::default()
+        }
+    }
+
+    // Submits a command to the I/O queue 1 and returns its completion.
+    fn run(worker: &mut NvmeWorker, mem: &GuestMemoryMmap, slot: u16, cmd: Command) -> Completion {
+        mem.write_obj(cmd, GuestAddress(SQ + u64::from(slot) * 64))
+            .unwrap();
+        worker
+            .shared
+            .controller
+            .lock()
+            .unwrap()
+            .write_doorbell(8, u32::from(slot) + 1);
+        worker.process_queues();
+        worker.process_completions();
+        mem.read_obj(GuestAddress(CQ + u64::from(slot) * 16))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_worker_io() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let mut ctrl = controller(&mem);
+        create_io_queues(&mut ctrl, &mem, 1, CQ, SQ);
+        let shared = Arc::new(Shared::new(ctrl));
+
+        let image = TempFile::new().unwrap();
+        let mut disk = image.as_file().try_clone().unwrap();
+        disk.set_len(2048 * 512).unwrap();
+        let group: Arc<Box<dyn InterruptSourceGroup>> = Arc::new(Box::new(TestInterruptGroup {}));
+        let mut worker = NvmeWorker::new(
+            shared.clone(),
+            GuestMemoryAtomic::new(mem.clone()),
+            Box::new(RawFileSync::new(disk.as_raw_fd())),
+            NvmeInterrupt {
+                msix_config: Arc::new(Mutex::new(MsixConfig::new(3, group.clone(), 0))),
+                interrupt_source_group: group,
+            },
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            8,
+        );
+
+        // a block written from the guest memory lands in the disk image
+        mem.write_slice(&[0xaa; 512], GuestAddress(DATA)).unwrap();
+        let entry = run(&mut worker, &mem, 0, io_command(IO_WRITE, 1, 2));
+        assert_eq!((entry.cid, entry.status), (1, 1));
+        let mut data = [0u8; 512];
+        disk.seek(SeekFrom::Start(2 * 512)).unwrap();
+        disk.read_exact(&mut data).unwrap();
+        assert_eq!(data, [0xaa; 512]);
+
+        // and read back into it
+        disk.seek(SeekFrom::Start(3 * 512)).unwrap();
+        disk.write_all(&[0x55; 512]).unwrap();
+        let entry = run(&mut worker, &mem, 1, io_command(IO_READ, 2, 3));
+        assert_eq!((entry.cid, entry.status), (2, 1));
+        mem.read_slice(&mut data, GuestAddress(DATA)).unwrap();
+        assert_eq!(data, [0x55; 512]);
+
+        let entry = run(&mut worker, &mem, 2, io_command(IO_FLUSH, 3, 0));
+        assert_eq!((entry.cid, entry.status), (3, 1));
+
+        // beyond the end of the namespace
+        let entry = run(&mut worker, &mem, 3, io_command(IO_READ, 4, 2048));
+        assert_eq!(entry.cid, 4);
+        assert_eq!(entry.status >> 1, 1 << 14 | 0x80);
+
+        // no command is fetched while paused
+        shared.pause();
+        let entry = run(&mut worker, &mem, 4, io_command(IO_FLUSH, 5, 0));
+        assert_eq!(entry.cid, 0);
+        shared.resume();
+        worker.process_queues();
+        worker.process_completions();
+        let entry: Completion = mem.read_obj(GuestAddress(CQ + 4 * 16)).unwrap();
+        assert_eq!((entry.cid, entry.status), (5, 1));
+        assert_eq!(shared.controller.lock().unwrap().inflight, 0);
+    }
+}
@@ -172,6 +172,52 @@ impl FromStr for ByteSized {
     }
 }
 
+/// A 16 bits integer, given in decimal or in hexadecimal with a `0x` prefix.
+pub struct HexU16(pub u16);
+
+pub enum HexU16ParseError {
+    InvalidValue(String),
+}
+
+impl FromStr for HexU16 {
+    type Err = HexU16ParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let value = match s.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => s.parse::<u16>(),
+        };
+
+        Ok(HexU16(value.map_err(|_| {
+            HexU16ParseError::InvalidValue(s.to_owned())
+        })?))
+    }
+}
+
+/// A 32 bits integer, given in decimal or in hexadecimal with a `0x` prefix.
+pub struct HexU32(pub u32);
+
+pub enum HexU32ParseError {
+    InvalidValue(String),
+}
+
+impl FromStr for HexU32 {
+    type Err = HexU32ParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let value = match s.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse::<u32>(),
+        };
+
+        Ok(HexU32(value.map_err(|_| {
+            HexU32ParseError::InvalidValue(s.to_owned())
+        })?))
+    }
+}
+
 pub struct IntegerList(pub Vec<u64>);
 
 pub enum IntegerListParseError {
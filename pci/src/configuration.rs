@@ -3,12 +3,15 @@
 // found in the LICENSE-BSD-3-Clause file.
 
 use crate::device::BarReprogrammingParams;
+use crate::vpd::{Vpd, VpdCap, VPD_ADDR_FLAG};
 use crate::{MsixConfig, PciInterruptPin};
 use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use std::fmt::{self, Display};
 use std::sync::{Arc, Mutex};
-use vm_migration::{MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable};
+use vm_migration::{
+    MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable, VersionedState,
+};
 
 // The number of 32bit registers in the config space, 4096 bytes.
 const NUM_CONFIGURATION_REGISTERS: usize = 1024;
@@ -295,6 +298,12 @@ struct PciConfigurationState {
     rom_bar_used: bool,
     last_capability: Option<(usize, usize)>,
     msix_cap_reg_idx: Option<usize>,
+    #[serde(default)]
+    vpd: Option<Vpd>,
+}
+
+impl VersionedState for PciConfigurationState {
+    const VERSION: u16 = 1;
 }
 
 /// Contains the configuration space of a PCI node.
@@ -311,6 +320,7 @@ pub struct PciConfiguration {
     last_capability: Option<(usize, usize)>,
     msix_cap_reg_idx: Option<usize>,
     msix_config: Option<Arc<Mutex<MsixConfig>>>,
+    vpd: Option<Vpd>,
 }
 
 /// See pci_regs.h in kernel
@@ -444,6 +454,7 @@ impl PciConfiguration {
             last_capability: None,
             msix_cap_reg_idx: None,
             msix_config,
+            vpd: None,
         }
     }
 
@@ -457,6 +468,7 @@ impl PciConfiguration {
             rom_bar_used: self.rom_bar_used,
             last_capability: self.last_capability,
             msix_cap_reg_idx: self.msix_cap_reg_idx,
+            vpd: self.vpd.clone(),
         }
     }
 
@@ -470,6 +482,7 @@ impl PciConfiguration {
         self.rom_bar_used = state.rom_bar_used;
         self.last_capability = state.last_capability;
         self.msix_cap_reg_idx = state.msix_cap_reg_idx;
+        self.vpd = state.vpd.clone();
     }
 
     /// Reads a 32bit register from `reg_idx` in the register map.
@@ -718,6 +731,21 @@ impl PciConfiguration {
         Ok(cap_offset)
     }
 
+    /// Adds the Vital Product Data capability, through which the guest reads
+    /// `vpd` a dword at a time.
+    pub fn add_vpd_capability(&mut self, vpd: Vec<u8>) -> Result<usize> {
+        let cap_offset = self.add_capability(&VpdCap::default())?;
+        // The guest writes the address register, while the data register is
+        // filled by the device.
+        self.writable_bits[cap_offset / 4] |= 0xffff_0000;
+        self.vpd = Some(Vpd {
+            cap_reg_idx: cap_offset / 4,
+            data: vpd,
+        });
+
+        Ok(cap_offset)
+    }
+
     // Find the next aligned offset after the one given.
     fn next_dword(offset: usize, len: usize) -> usize {
         let next = offset + len;
@@ -750,6 +778,19 @@ impl PciConfiguration {
             4 => self.write_reg(reg_idx, LittleEndian::read_u32(data)),
             _ => (),
         }
+
+        // The VPD accesses complete as soon as the address register is
+        // written, the reads filling the data register and the writes being
+        // ignored.
+        if let Some(vpd) = &self.vpd {
+            if vpd.cap_reg_idx == reg_idx && offset as usize + data.len() > 2 {
+                let addr = self.registers[reg_idx] >> 16;
+                if addr & VPD_ADDR_FLAG == 0 {
+                    self.registers[reg_idx + 1] = vpd.read(addr);
+                }
+                self.registers[reg_idx] ^= VPD_ADDR_FLAG << 16;
+            }
+        }
     }
 
     pub fn read_config_register(&self, reg_idx: usize) -> u32 {
@@ -890,14 +931,11 @@ impl Snapshottable for PciConfiguration {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut config_snapshot = Snapshot::new(self.id().as_str());
-        config_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id()),
-            snapshot,
-        });
+        config_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id()),
+            &self.state(),
+        )?);
 
         Ok(config_snapshot)
     }
@@ -907,16 +945,7 @@ impl Snapshottable for PciConfiguration {
             .snapshot_data
             .get(&format!("{}-section", self.id()))
         {
-            let config_state = match serde_json::from_slice(&config_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize {}: {}",
-                        self.id(),
-                        error
-                    )))
-                }
-            };
+            let config_state = config_section.to_versioned_state::<PciConfigurationState>()?;
 
             self.set_state(&config_state);
 
@@ -1089,4 +1118,50 @@ mod tests {
         assert_eq!(subclass, 0x01);
         assert_eq!(prog_if, 0x5a);
     }
+
+    #[test]
+    fn vpd_capability() {
+        let mut cfg = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x1,
+            PciClassCode::MassStorage,
+            &PciMassStorageSubclass::MassStorage,
+            None,
+            PciHeaderType::Device,
+            0xABCD,
+            0x2468,
+            None,
+        );
+
+        let vpd = crate::build_vpd("disk", "0123456789");
+        let cap_offset = cfg.add_vpd_capability(vpd.clone()).unwrap();
+        let cap_reg_idx = cap_offset / 4;
+        assert_eq!(cfg.read_reg(cap_reg_idx) & 0xFF, 0x03); // capability ID
+
+        // Read the whole VPD a dword at a time, the way Linux does.
+        let mut data = Vec::new();
+        for addr in (0..vpd.len() as u16).step_by(4) {
+            cfg.write_config_register(cap_reg_idx, 2, &addr.to_le_bytes());
+            let addr_reg = cfg.read_reg(cap_reg_idx) >> 16;
+            assert_eq!(addr_reg, u32::from(addr) | 0x8000);
+            data.extend_from_slice(&cfg.read_reg(cap_reg_idx + 1).to_le_bytes());
+        }
+        assert_eq!(&data[..vpd.len()], vpd.as_slice());
+        assert!(data[vpd.len()..].iter().all(|b| *b == 0));
+
+        // The identifier string and the serial number keyword, followed by
+        // the checksum making the sum of the bytes up to it zero.
+        assert_eq!(&vpd[..7], b"\x82\x04\x00disk");
+        assert_eq!(&vpd[10..23], b"SN\x0a0123456789");
+        let checksum = vpd.len() - 2;
+        assert_eq!(&vpd[checksum - 3..checksum], b"RV\x01");
+        assert_eq!(
+            vpd[..=checksum]
+                .iter()
+                .fold(0u8, |sum, b| sum.wrapping_add(*b)),
+            0
+        );
+        assert_eq!(vpd[checksum + 1], 0x78);
+    }
 }
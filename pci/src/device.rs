@@ -7,7 +7,7 @@ use std::any::Any;
 use std::fmt::{self, Display};
 use std::sync::{Arc, Barrier};
 use std::{self, io, result};
-use vm_allocator::SystemAllocator;
+use vm_allocator::{AddressAllocator, SystemAllocator};
 use vm_device::BusDevice;
 use vm_memory::{GuestAddress, GuestUsize};
 
@@ -49,15 +49,25 @@ pub struct BarReprogrammingParams {
 pub trait PciDevice: BusDevice {
     /// Allocates the needed PCI BARs space using the `allocate` function which takes a size and
     /// returns an address. Returns a Vec of (GuestAddress, GuestUsize) tuples.
+    /// The memory BARs are allocated from the windows of the PCI segment the
+    /// device belongs to, `mmio_allocator` for the 64 bits ones and
+    /// `mmio_hole_allocator` for the 32 bits ones.
     fn allocate_bars(
         &mut self,
         _allocator: &mut SystemAllocator,
+        _mmio_allocator: &mut AddressAllocator,
+        _mmio_hole_allocator: &mut AddressAllocator,
     ) -> Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>> {
         Ok(Vec::new())
     }
 
     /// Frees the PCI BARs previously allocated with a call to allocate_bars().
-    fn free_bars(&mut self, _allocator: &mut SystemAllocator) -> Result<()> {
+    fn free_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+        _mmio_allocator: &mut AddressAllocator,
+        _mmio_hole_allocator: &mut AddressAllocator,
+    ) -> Result<()> {
         Ok(())
     }
 
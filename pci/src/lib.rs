@@ -9,7 +9,6 @@ extern crate hypervisor;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
-extern crate serde_json;
 extern crate vm_memory;
 
 mod bus;
@@ -18,6 +17,8 @@ mod device;
 mod msi;
 mod msix;
 mod vfio;
+mod vfio_user;
+mod vpd;
 
 pub use self::bus::{PciBus, PciConfigIo, PciConfigMmio, PciRoot, PciRootError};
 pub use self::configuration::{
@@ -31,6 +32,8 @@ pub use self::device::{
 pub use self::msi::{msi_num_enabled_vectors, MsiCap, MsiConfig};
 pub use self::msix::{MsixCap, MsixConfig, MsixTableEntry, MSIX_TABLE_ENTRY_SIZE};
 pub use self::vfio::{VfioPciDevice, VfioPciError};
+pub use self::vfio_user::{VfioUserPciDevice, VfioUserPciDeviceError};
+pub use self::vpd::{build_vpd, VpdCap, MAX_VPD_SERIAL_LEN};
 
 /// PCI has four interrupt pins A->D.
 #[derive(Copy, Clone)]
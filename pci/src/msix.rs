@@ -16,7 +16,9 @@ use vm_device::interrupt::{
     InterruptIndex, InterruptSourceConfig, InterruptSourceGroup, MsiIrqSourceConfig,
 };
 use vm_memory::ByteValued;
-use vm_migration::{MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable};
+use vm_migration::{
+    MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable, VersionedState,
+};
 
 const MAX_MSIX_VECTORS_PER_DEVICE: u16 = 2048;
 const MSIX_TABLE_ENTRIES_MODULO: u64 = 16;
@@ -69,6 +71,10 @@ struct MsixConfigState {
     enabled: bool,
 }
 
+impl VersionedState for MsixConfigState {
+    const VERSION: u16 = 1;
+}
+
 pub struct MsixConfig {
     pub table_entries: Vec<MsixTableEntry>,
     pub pba_entries: Vec<u64>,
@@ -432,14 +438,11 @@ impl Snapshottable for MsixConfig {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut msix_snapshot = Snapshot::new(self.id().as_str());
-        msix_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id()),
-            snapshot,
-        });
+        msix_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id()),
+            &self.state(),
+        )?);
 
         Ok(msix_snapshot)
     }
@@ -449,15 +452,7 @@ impl Snapshottable for MsixConfig {
             .snapshot_data
             .get(&format!("{}-section", self.id()))
         {
-            let msix_state = match serde_json::from_slice(&msix_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize MSI-X {}",
-                        error
-                    )))
-                }
-            };
+            let msix_state = msix_section.to_versioned_state::<MsixConfigState>()?;
 
             return self.set_state(&msix_state).map_err(|e| {
                 MigratableError::Restore(anyhow!("Could not restore MSI-X state {:?}", e))
@@ -18,8 +18,8 @@ use std::ptr::null_mut;
 use std::sync::{Arc, Barrier};
 use std::{fmt, io, result};
 use vfio_bindings::bindings::vfio::*;
-use vfio_ioctls::{VfioDevice, VfioError};
-use vm_allocator::SystemAllocator;
+use vfio_ioctls::VfioDevice;
+use vm_allocator::{AddressAllocator, SystemAllocator};
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
 };
@@ -30,6 +30,21 @@ use vm_memory::{
 };
 use vmm_sys_util::eventfd::EventFd;
 
+#[derive(Debug)]
+pub enum VfioError {
+    KernelVfio(vfio_ioctls::VfioError),
+    VfioUser(vfio_user::Error),
+}
+
+impl fmt::Display for VfioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VfioError::KernelVfio(e) => write!(f, "kernel VFIO error: {}", e),
+            VfioError::VfioUser(e) => write!(f, "vfio-user error: {}", e),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum VfioPciError {
     AllocateGsi,
@@ -249,74 +264,144 @@ pub struct MmioRegion {
     mmap_size: Option<usize>,
 }
 
-struct VfioPciConfig {
-    device: Arc<VfioDevice>,
+#[derive(Debug, Default)]
+pub(crate) struct VfioIrq {
+    pub flags: u32,
+    pub index: u32,
+    pub count: u32,
 }
 
-impl VfioPciConfig {
-    fn new(device: Arc<VfioDevice>) -> Self {
-        VfioPciConfig { device }
-    }
-
+/// Operations a VFIO backend, being the kernel VFIO driver or a remote
+/// vfio-user server, provides to the PCI layer.
+pub(crate) trait Vfio {
     fn read_config_byte(&self, offset: u32) -> u8 {
         let mut data: [u8; 1] = [0];
-        self.device
-            .region_read(VFIO_PCI_CONFIG_REGION_INDEX, data.as_mut(), offset.into());
-
+        self.read_config(offset, &mut data);
         data[0]
     }
 
     fn read_config_word(&self, offset: u32) -> u16 {
         let mut data: [u8; 2] = [0, 0];
-        self.device
-            .region_read(VFIO_PCI_CONFIG_REGION_INDEX, data.as_mut(), offset.into());
-
+        self.read_config(offset, &mut data);
         u16::from_le_bytes(data)
     }
 
     fn read_config_dword(&self, offset: u32) -> u32 {
         let mut data: [u8; 4] = [0, 0, 0, 0];
-        self.device
-            .region_read(VFIO_PCI_CONFIG_REGION_INDEX, data.as_mut(), offset.into());
-
+        self.read_config(offset, &mut data);
         u32::from_le_bytes(data)
     }
 
     fn write_config_dword(&self, buf: u32, offset: u32) {
         let data: [u8; 4] = buf.to_le_bytes();
-        self.device
-            .region_write(VFIO_PCI_CONFIG_REGION_INDEX, &data, offset.into())
+        self.write_config(offset, &data)
+    }
+
+    fn read_config(&self, offset: u32, data: &mut [u8]) {
+        self.region_read(VFIO_PCI_CONFIG_REGION_INDEX, offset.into(), data);
+    }
+
+    fn write_config(&self, offset: u32, data: &[u8]) {
+        self.region_write(VFIO_PCI_CONFIG_REGION_INDEX, offset.into(), data)
+    }
+
+    fn enable_msi(&self, fds: Vec<&EventFd>) -> result::Result<(), VfioError> {
+        self.enable_irq(VFIO_PCI_MSI_IRQ_INDEX, fds)
+    }
+
+    fn disable_msi(&self) -> result::Result<(), VfioError> {
+        self.disable_irq(VFIO_PCI_MSI_IRQ_INDEX)
+    }
+
+    fn enable_msix(&self, fds: Vec<&EventFd>) -> result::Result<(), VfioError> {
+        self.enable_irq(VFIO_PCI_MSIX_IRQ_INDEX, fds)
+    }
+
+    fn disable_msix(&self) -> result::Result<(), VfioError> {
+        self.disable_irq(VFIO_PCI_MSIX_IRQ_INDEX)
     }
+
+    fn region_read(&self, index: u32, offset: u64, data: &mut [u8]);
+
+    fn region_write(&self, index: u32, offset: u64, data: &[u8]);
+
+    fn get_region_flags(&self, index: u32) -> u32;
+
+    fn get_irq_info(&self, irq_index: u32) -> Option<VfioIrq>;
+
+    fn enable_irq(&self, irq_index: u32, event_fds: Vec<&EventFd>)
+        -> result::Result<(), VfioError>;
+
+    fn disable_irq(&self, irq_index: u32) -> result::Result<(), VfioError>;
+
+    fn unmask_irq(&self, irq_index: u32) -> result::Result<(), VfioError>;
 }
 
-/// VfioPciDevice represents a VFIO PCI device.
-/// This structure implements the BusDevice and PciDevice traits.
-///
-/// A VfioPciDevice is bound to a VfioDevice and is also a PCI device.
-/// The VMM creates a VfioDevice, then assigns it to a VfioPciDevice,
-/// which then gets added to the PCI bus.
-pub struct VfioPciDevice {
-    vm: Arc<dyn hypervisor::Vm>,
+struct VfioDeviceWrapper {
     device: Arc<VfioDevice>,
-    vfio_pci_configuration: VfioPciConfig,
+}
+
+impl VfioDeviceWrapper {
+    fn new(device: Arc<VfioDevice>) -> Self {
+        Self { device }
+    }
+}
+
+impl Vfio for VfioDeviceWrapper {
+    fn region_read(&self, index: u32, offset: u64, data: &mut [u8]) {
+        self.device.region_read(index, data, offset)
+    }
+
+    fn region_write(&self, index: u32, offset: u64, data: &[u8]) {
+        self.device.region_write(index, data, offset)
+    }
+
+    fn get_region_flags(&self, index: u32) -> u32 {
+        self.device.get_region_flags(index)
+    }
+
+    fn get_irq_info(&self, irq_index: u32) -> Option<VfioIrq> {
+        self.device.get_irq_info(irq_index).map(|irq| VfioIrq {
+            flags: irq.flags,
+            index: irq.index,
+            count: irq.count,
+        })
+    }
+
+    fn enable_irq(
+        &self,
+        irq_index: u32,
+        event_fds: Vec<&EventFd>,
+    ) -> result::Result<(), VfioError> {
+        self.device
+            .enable_irq(irq_index, event_fds)
+            .map_err(VfioError::KernelVfio)
+    }
+
+    fn disable_irq(&self, irq_index: u32) -> result::Result<(), VfioError> {
+        self.device
+            .disable_irq(irq_index)
+            .map_err(VfioError::KernelVfio)
+    }
+
+    fn unmask_irq(&self, irq_index: u32) -> result::Result<(), VfioError> {
+        self.device
+            .unmask_irq(irq_index)
+            .map_err(VfioError::KernelVfio)
+    }
+}
+
+/// The part of a VFIO PCI device which doesn't depend on the backend used
+/// to reach the physical or emulated device. It handles the BARs, the PCI
+/// configuration space and the interrupts.
+pub(crate) struct VfioCommon {
     configuration: PciConfiguration,
-    mmio_regions: Vec<MmioRegion>,
+    pub(crate) mmio_regions: Vec<MmioRegion>,
     interrupt: Interrupt,
-    mem: GuestMemoryAtomic<GuestMemoryMmap>,
 }
 
-impl VfioPciDevice {
-    /// Constructs a new Vfio Pci device for the given Vfio device
-    pub fn new(
-        vm: &Arc<dyn hypervisor::Vm>,
-        device: VfioDevice,
-        msi_interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
-        legacy_interrupt_group: Option<Arc<Box<dyn InterruptSourceGroup>>>,
-        mem: GuestMemoryAtomic<GuestMemoryMmap>,
-    ) -> Result<Self> {
-        let device = Arc::new(device);
-        device.reset();
-
+impl VfioCommon {
+    pub(crate) fn new() -> Self {
         let configuration = PciConfiguration::new(
             0,
             0,
@@ -330,162 +415,362 @@ impl VfioPciDevice {
             None,
         );
 
-        let vfio_pci_configuration = VfioPciConfig::new(Arc::clone(&device));
-
-        let mut vfio_pci_device = VfioPciDevice {
-            vm: vm.clone(),
-            device,
+        VfioCommon {
             configuration,
-            vfio_pci_configuration,
             mmio_regions: Vec::new(),
             interrupt: Interrupt {
                 intx: None,
                 msi: None,
                 msix: None,
             },
-            mem,
-        };
-
-        vfio_pci_device.parse_capabilities(msi_interrupt_manager);
-
-        vfio_pci_device.initialize_legacy_interrupt(legacy_interrupt_group)?;
-
-        Ok(vfio_pci_device)
-    }
-
-    fn enable_intx(&mut self) -> Result<()> {
-        if let Some(intx) = &mut self.interrupt.intx {
-            if !intx.enabled {
-                if let Some(eventfd) = intx.interrupt_source_group.notifier(0) {
-                    self.device
-                        .enable_irq(VFIO_PCI_INTX_IRQ_INDEX, vec![&eventfd])
-                        .map_err(VfioPciError::EnableIntx)?;
-
-                    intx.enabled = true;
-                } else {
-                    return Err(VfioPciError::MissingNotifier);
-                }
-            }
         }
-
-        Ok(())
     }
 
-    fn disable_intx(&mut self) {
-        if let Some(intx) = &mut self.interrupt.intx {
-            if intx.enabled {
-                if let Err(e) = self.device.disable_irq(VFIO_PCI_INTX_IRQ_INDEX) {
-                    error!("Could not disable INTx: {}", e);
-                } else {
-                    intx.enabled = false;
-                }
-            }
-        }
-    }
+    #[cfg_attr(target_arch = "aarch64", allow(unused_variables))]
+    pub(crate) fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+        mmio_allocator: &mut AddressAllocator,
+        mmio_hole_allocator: &mut AddressAllocator,
+        vfio_wrapper: &dyn Vfio,
+    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
+    {
+        let mut ranges = Vec::new();
+        let mut bar_id = VFIO_PCI_BAR0_REGION_INDEX as u32;
 
-    fn enable_msi(&self) -> Result<()> {
-        if let Some(msi) = &self.interrupt.msi {
-            let mut irq_fds: Vec<EventFd> = Vec::new();
-            for i in 0..msi.cfg.num_enabled_vectors() {
-                if let Some(eventfd) = msi.interrupt_source_group.notifier(i as InterruptIndex) {
-                    irq_fds.push(eventfd);
-                } else {
-                    return Err(VfioPciError::MissingNotifier);
-                }
-            }
+        // Going through all regular regions to compute the BAR size.
+        // We're not saving the BAR address to restore it, because we
+        // are going to allocate a guest address for each BAR and write
+        // that new address back.
+        while bar_id < VFIO_PCI_CONFIG_REGION_INDEX {
+            let mut lsb_size: u32 = 0xffff_ffff;
+            let mut msb_size = 0;
+            let mut region_size: u64;
+            let bar_addr: GuestAddress;
 
-            self.device
-                .enable_msi(irq_fds.iter().collect())
-                .map_err(VfioPciError::EnableMsi)?;
-        }
+            // Read the BAR size (Starts by all 1s to the BAR)
+            let bar_offset = if bar_id == VFIO_PCI_ROM_REGION_INDEX {
+                (PCI_ROM_EXP_BAR_INDEX * 4) as u32
+            } else {
+                PCI_CONFIG_BAR_OFFSET + bar_id * 4
+            };
 
-        Ok(())
-    }
+            vfio_wrapper.write_config_dword(lsb_size, bar_offset);
+            lsb_size = vfio_wrapper.read_config_dword(bar_offset);
 
-    fn disable_msi(&self) {
-        if let Err(e) = self.device.disable_msi() {
-            error!("Could not disable MSI: {}", e);
-        }
-    }
+            // We've just read the BAR size back. Or at least its LSB.
+            let lsb_flag = lsb_size & PCI_CONFIG_MEMORY_BAR_FLAG_MASK;
 
-    fn enable_msix(&self) -> Result<()> {
-        if let Some(msix) = &self.interrupt.msix {
-            let mut irq_fds: Vec<EventFd> = Vec::new();
-            for i in 0..msix.bar.table_entries.len() {
-                if let Some(eventfd) = msix.interrupt_source_group.notifier(i as InterruptIndex) {
-                    irq_fds.push(eventfd);
-                } else {
-                    return Err(VfioPciError::MissingNotifier);
-                }
+            if lsb_size == 0 {
+                bar_id += 1;
+                continue;
             }
 
-            self.device
-                .enable_msix(irq_fds.iter().collect())
-                .map_err(VfioPciError::EnableMsi)?;
-        }
+            // Is this an IO BAR?
+            let io_bar = if bar_id != VFIO_PCI_ROM_REGION_INDEX {
+                matches!(lsb_flag & PCI_CONFIG_IO_BAR, PCI_CONFIG_IO_BAR)
+            } else {
+                false
+            };
 
-        Ok(())
-    }
+            // Is this a 64-bit BAR?
+            let is_64bit_bar = if bar_id != VFIO_PCI_ROM_REGION_INDEX {
+                matches!(
+                    lsb_flag & PCI_CONFIG_MEMORY_BAR_64BIT,
+                    PCI_CONFIG_MEMORY_BAR_64BIT
+                )
+            } else {
+                false
+            };
 
-    fn disable_msix(&self) {
-        if let Err(e) = self.device.disable_msix() {
-            error!("Could not disable MSI-X: {}", e);
-        }
-    }
+            // By default, the region type is 32 bits memory BAR.
+            let mut region_type = PciBarRegionType::Memory32BitRegion;
 
-    fn initialize_legacy_interrupt(
-        &mut self,
-        legacy_interrupt_group: Option<Arc<Box<dyn InterruptSourceGroup>>>,
-    ) -> Result<()> {
-        if let Some(irq_info) = self.device.get_irq_info(VFIO_PCI_INTX_IRQ_INDEX) {
-            if irq_info.count == 0 {
-                // A count of 0 means the INTx IRQ is not supported, therefore
-                // it shouldn't be initialized.
-                return Ok(());
-            }
-        }
+            if io_bar {
+                #[cfg(target_arch = "x86_64")]
+                {
+                    // IO BAR
+                    region_type = PciBarRegionType::IORegion;
 
-        if let Some(interrupt_source_group) = legacy_interrupt_group {
-            self.interrupt.intx = Some(VfioIntx {
-                interrupt_source_group,
-                enabled: false,
-            });
+                    // Clear first bit.
+                    lsb_size &= 0xffff_fffc;
 
-            self.enable_intx()?;
-        }
+                    // Find the first bit that's set to 1.
+                    let first_bit = lsb_size.trailing_zeros();
+                    region_size = 2u64.pow(first_bit);
+                    // We need to allocate a guest PIO address range for that BAR.
+                    // The address needs to be 4 bytes aligned.
+                    bar_addr = allocator
+                        .allocate_io_addresses(None, region_size, Some(0x4))
+                        .ok_or(PciDeviceError::IoAllocationFailed(region_size))?;
+                }
+                #[cfg(target_arch = "aarch64")]
+                unimplemented!()
+            } else {
+                if is_64bit_bar {
+                    // 64 bits Memory BAR
+                    region_type = PciBarRegionType::Memory64BitRegion;
 
-        Ok(())
-    }
+                    msb_size = 0xffff_ffff;
+                    let msb_bar_offset: u32 = PCI_CONFIG_BAR_OFFSET + (bar_id + 1) * 4;
 
-    fn parse_msix_capabilities(
-        &mut self,
-        cap: u8,
-        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
-    ) {
-        let msg_ctl = self
-            .vfio_pci_configuration
-            .read_config_word((cap + 2).into());
+                    vfio_wrapper.write_config_dword(msb_size, msb_bar_offset);
 
-        let table = self
-            .vfio_pci_configuration
-            .read_config_dword((cap + 4).into());
+                    msb_size = vfio_wrapper.read_config_dword(msb_bar_offset);
+                }
 
-        let pba = self
-            .vfio_pci_configuration
-            .read_config_dword((cap + 8).into());
+                // Clear the first four bytes from our LSB.
+                lsb_size &= 0xffff_fff0;
 
-        let msix_cap = MsixCap {
-            msg_ctl,
-            table,
-            pba,
-        };
+                region_size = u64::from(msb_size);
+                region_size <<= 32;
+                region_size |= u64::from(lsb_size);
 
-        let interrupt_source_group = interrupt_manager
-            .create_group(MsiIrqGroupConfig {
-                base: 0,
-                count: msix_cap.table_size() as InterruptIndex,
-            })
-            .unwrap();
+                // Find the first that's set to 1.
+                let first_bit = region_size.trailing_zeros();
+                region_size = 2u64.pow(first_bit);
+
+                // We need to allocate a guest MMIO address range for that BAR.
+                // In case the BAR is mappable directly, this means it might be
+                // set as user memory region, which expects to deal with 4K
+                // pages. Therefore, the alignment has to be set accordingly.
+                let bar_alignment = if (bar_id == VFIO_PCI_ROM_REGION_INDEX)
+                    || (vfio_wrapper.get_region_flags(bar_id) & VFIO_REGION_INFO_FLAG_MMAP != 0)
+                {
+                    // 4K alignment
+                    0x1000
+                } else {
+                    // Default 16 bytes alignment
+                    0x10
+                };
+                if is_64bit_bar {
+                    bar_addr = mmio_allocator
+                        .allocate(None, region_size, Some(bar_alignment))
+                        .ok_or(PciDeviceError::IoAllocationFailed(region_size))?;
+                } else {
+                    bar_addr = mmio_hole_allocator
+                        .allocate(None, region_size, Some(bar_alignment))
+                        .ok_or(PciDeviceError::IoAllocationFailed(region_size))?;
+                }
+            }
+
+            let reg_idx = if bar_id == VFIO_PCI_ROM_REGION_INDEX {
+                PCI_ROM_EXP_BAR_INDEX
+            } else {
+                bar_id as usize
+            };
+
+            // We can now build our BAR configuration block.
+            let config = PciBarConfiguration::default()
+                .set_register_index(reg_idx)
+                .set_address(bar_addr.raw_value())
+                .set_size(region_size)
+                .set_region_type(region_type);
+
+            if bar_id == VFIO_PCI_ROM_REGION_INDEX {
+                self.configuration
+                    .add_pci_rom_bar(&config, lsb_flag & 0x1)
+                    .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
+            } else {
+                self.configuration
+                    .add_pci_bar(&config)
+                    .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
+            }
+
+            ranges.push((bar_addr, region_size, region_type));
+            self.mmio_regions.push(MmioRegion {
+                start: bar_addr,
+                length: region_size,
+                type_: region_type,
+                index: bar_id as u32,
+                mem_slot: None,
+                host_addr: None,
+                mmap_size: None,
+            });
+
+            bar_id += 1;
+            if is_64bit_bar {
+                bar_id += 1;
+            }
+        }
+
+        Ok(ranges)
+    }
+
+    #[cfg_attr(target_arch = "aarch64", allow(unused_variables))]
+    pub(crate) fn free_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+        mmio_allocator: &mut AddressAllocator,
+        mmio_hole_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for region in self.mmio_regions.iter() {
+            match region.type_ {
+                PciBarRegionType::IORegion => {
+                    #[cfg(target_arch = "x86_64")]
+                    allocator.free_io_addresses(region.start, region.length);
+                    #[cfg(target_arch = "aarch64")]
+                    error!("I/O region is not supported");
+                }
+                PciBarRegionType::Memory32BitRegion => {
+                    mmio_hole_allocator.free(region.start, region.length);
+                }
+                PciBarRegionType::Memory64BitRegion => {
+                    mmio_allocator.free(region.start, region.length);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn enable_intx(&mut self, vfio_wrapper: &dyn Vfio) -> Result<()> {
+        if let Some(intx) = &mut self.interrupt.intx {
+            if !intx.enabled {
+                if let Some(eventfd) = intx.interrupt_source_group.notifier(0) {
+                    vfio_wrapper
+                        .enable_irq(VFIO_PCI_INTX_IRQ_INDEX, vec![&eventfd])
+                        .map_err(VfioPciError::EnableIntx)?;
+
+                    intx.enabled = true;
+                } else {
+                    return Err(VfioPciError::MissingNotifier);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn disable_intx(&mut self, vfio_wrapper: &dyn Vfio) {
+        if let Some(intx) = &mut self.interrupt.intx {
+            if intx.enabled {
+                if let Err(e) = vfio_wrapper.disable_irq(VFIO_PCI_INTX_IRQ_INDEX) {
+                    error!("Could not disable INTx: {}", e);
+                } else {
+                    intx.enabled = false;
+                }
+            }
+        }
+    }
+
+    fn enable_msi(&self, vfio_wrapper: &dyn Vfio) -> Result<()> {
+        if let Some(msi) = &self.interrupt.msi {
+            let mut irq_fds: Vec<EventFd> = Vec::new();
+            for i in 0..msi.cfg.num_enabled_vectors() {
+                if let Some(eventfd) = msi.interrupt_source_group.notifier(i as InterruptIndex) {
+                    irq_fds.push(eventfd);
+                } else {
+                    return Err(VfioPciError::MissingNotifier);
+                }
+            }
+
+            vfio_wrapper
+                .enable_msi(irq_fds.iter().collect())
+                .map_err(VfioPciError::EnableMsi)?;
+        }
+
+        Ok(())
+    }
+
+    fn disable_msi(&self, vfio_wrapper: &dyn Vfio) {
+        if let Err(e) = vfio_wrapper.disable_msi() {
+            error!("Could not disable MSI: {}", e);
+        }
+    }
+
+    fn enable_msix(&self, vfio_wrapper: &dyn Vfio) -> Result<()> {
+        if let Some(msix) = &self.interrupt.msix {
+            let mut irq_fds: Vec<EventFd> = Vec::new();
+            for i in 0..msix.bar.table_entries.len() {
+                if let Some(eventfd) = msix.interrupt_source_group.notifier(i as InterruptIndex) {
+                    irq_fds.push(eventfd);
+                } else {
+                    return Err(VfioPciError::MissingNotifier);
+                }
+            }
+
+            vfio_wrapper
+                .enable_msix(irq_fds.iter().collect())
+                .map_err(VfioPciError::EnableMsi)?;
+        }
+
+        Ok(())
+    }
+
+    fn disable_msix(&self, vfio_wrapper: &dyn Vfio) {
+        if let Err(e) = vfio_wrapper.disable_msix() {
+            error!("Could not disable MSI-X: {}", e);
+        }
+    }
+
+    /// Disable all the interrupts which have been enabled on the device.
+    pub(crate) fn disable_interrupts(&mut self, vfio_wrapper: &dyn Vfio) {
+        if let Some(msix) = &self.interrupt.msix {
+            if msix.bar.enabled() {
+                self.disable_msix(vfio_wrapper);
+            }
+        }
+
+        if let Some(msi) = &self.interrupt.msi {
+            if msi.cfg.enabled() {
+                self.disable_msi(vfio_wrapper);
+            }
+        }
+
+        if self.interrupt.intx_in_use() {
+            self.disable_intx(vfio_wrapper);
+        }
+    }
+
+    pub(crate) fn initialize_legacy_interrupt(
+        &mut self,
+        legacy_interrupt_group: Option<Arc<Box<dyn InterruptSourceGroup>>>,
+        vfio_wrapper: &dyn Vfio,
+    ) -> Result<()> {
+        if let Some(irq_info) = vfio_wrapper.get_irq_info(VFIO_PCI_INTX_IRQ_INDEX) {
+            if irq_info.count == 0 {
+                // A count of 0 means the INTx IRQ is not supported, therefore
+                // it shouldn't be initialized.
+                return Ok(());
+            }
+        }
+
+        if let Some(interrupt_source_group) = legacy_interrupt_group {
+            self.interrupt.intx = Some(VfioIntx {
+                interrupt_source_group,
+                enabled: false,
+            });
+
+            self.enable_intx(vfio_wrapper)?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_msix_capabilities(
+        &mut self,
+        cap: u8,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        vfio_wrapper: &dyn Vfio,
+    ) {
+        let msg_ctl = vfio_wrapper.read_config_word((cap + 2).into());
+
+        let table = vfio_wrapper.read_config_dword((cap + 4).into());
+
+        let pba = vfio_wrapper.read_config_dword((cap + 8).into());
+
+        let msix_cap = MsixCap {
+            msg_ctl,
+            table,
+            pba,
+        };
+
+        let interrupt_source_group = interrupt_manager
+            .create_group(MsiIrqGroupConfig {
+                base: 0,
+                count: msix_cap.table_size() as InterruptIndex,
+            })
+            .unwrap();
 
         let msix_config = MsixConfig::new(msix_cap.table_size(), interrupt_source_group.clone(), 0);
 
@@ -501,10 +786,9 @@ impl VfioPciDevice {
         &mut self,
         cap: u8,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        vfio_wrapper: &dyn Vfio,
     ) {
-        let msg_ctl = self
-            .vfio_pci_configuration
-            .read_config_word((cap + 2).into());
+        let msg_ctl = vfio_wrapper.read_config_word((cap + 2).into());
 
         let interrupt_source_group = interrupt_manager
             .create_group(MsiIrqGroupConfig {
@@ -522,58 +806,58 @@ impl VfioPciDevice {
         });
     }
 
-    fn parse_capabilities(
+    pub(crate) fn parse_capabilities(
         &mut self,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        vfio_wrapper: &dyn Vfio,
     ) {
-        let mut cap_next = self
-            .vfio_pci_configuration
-            .read_config_byte(PCI_CONFIG_CAPABILITY_OFFSET);
+        let mut cap_next = vfio_wrapper.read_config_byte(PCI_CONFIG_CAPABILITY_OFFSET);
 
         while cap_next != 0 {
-            let cap_id = self
-                .vfio_pci_configuration
-                .read_config_byte(cap_next.into());
+            let cap_id = vfio_wrapper.read_config_byte(cap_next.into());
 
             match PciCapabilityID::from(cap_id) {
                 PciCapabilityID::MessageSignalledInterrupts => {
-                    if let Some(irq_info) = self.device.get_irq_info(VFIO_PCI_MSI_IRQ_INDEX) {
+                    if let Some(irq_info) = vfio_wrapper.get_irq_info(VFIO_PCI_MSI_IRQ_INDEX) {
                         if irq_info.count > 0 {
                             // Parse capability only if the VFIO device
                             // supports MSI.
-                            self.parse_msi_capabilities(cap_next, interrupt_manager);
+                            self.parse_msi_capabilities(cap_next, interrupt_manager, vfio_wrapper);
                         }
                     }
                 }
                 PciCapabilityID::MSIX => {
-                    if let Some(irq_info) = self.device.get_irq_info(VFIO_PCI_MSIX_IRQ_INDEX) {
+                    if let Some(irq_info) = vfio_wrapper.get_irq_info(VFIO_PCI_MSIX_IRQ_INDEX) {
                         if irq_info.count > 0 {
                             // Parse capability only if the VFIO device
                             // supports MSI-X.
-                            self.parse_msix_capabilities(cap_next, interrupt_manager);
+                            self.parse_msix_capabilities(cap_next, interrupt_manager, vfio_wrapper);
                         }
                     }
                 }
                 _ => {}
             };
 
-            cap_next = self
-                .vfio_pci_configuration
-                .read_config_byte((cap_next + 1).into());
+            cap_next = vfio_wrapper.read_config_byte((cap_next + 1).into());
         }
     }
 
-    fn update_msi_capabilities(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+    fn update_msi_capabilities(
+        &mut self,
+        offset: u64,
+        data: &[u8],
+        vfio_wrapper: &dyn Vfio,
+    ) -> Result<()> {
         match self.interrupt.update_msi(offset, data) {
             Some(InterruptUpdateAction::EnableMsi) => {
                 // Disable INTx before we can enable MSI
-                self.disable_intx();
-                self.enable_msi()?;
+                self.disable_intx(vfio_wrapper);
+                self.enable_msi(vfio_wrapper)?;
             }
             Some(InterruptUpdateAction::DisableMsi) => {
                 // Fallback onto INTx when disabling MSI
-                self.disable_msi();
-                self.enable_intx()?;
+                self.disable_msi(vfio_wrapper);
+                self.enable_intx(vfio_wrapper)?;
             }
             _ => {}
         }
@@ -581,17 +865,22 @@ impl VfioPciDevice {
         Ok(())
     }
 
-    fn update_msix_capabilities(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+    fn update_msix_capabilities(
+        &mut self,
+        offset: u64,
+        data: &[u8],
+        vfio_wrapper: &dyn Vfio,
+    ) -> Result<()> {
         match self.interrupt.update_msix(offset, data) {
             Some(InterruptUpdateAction::EnableMsix) => {
                 // Disable INTx before we can enable MSI-X
-                self.disable_intx();
-                self.enable_msix()?;
+                self.disable_intx(vfio_wrapper);
+                self.enable_msix(vfio_wrapper)?;
             }
             Some(InterruptUpdateAction::DisableMsix) => {
                 // Fallback onto INTx when disabling MSI-X
-                self.disable_msix();
-                self.enable_intx()?;
+                self.disable_msix(vfio_wrapper);
+                self.enable_intx(vfio_wrapper)?;
             }
             _ => {}
         }
@@ -610,40 +899,233 @@ impl VfioPciDevice {
         None
     }
 
-    /// Map MMIO regions into the guest, and avoid VM exits when the guest tries
-    /// to reach those regions.
-    ///
-    /// # Arguments
-    ///
-    /// * `vm` - The VM object. It is used to set the VFIO MMIO regions
-    ///          as user memory regions.
-    /// * `mem_slot` - The closure to return a memory slot.
-    pub fn map_mmio_regions<F>(&mut self, vm: &Arc<dyn hypervisor::Vm>, mem_slot: F) -> Result<()>
-    where
-        F: Fn() -> u32,
-    {
-        let fd = self.device.as_raw_fd();
+    pub(crate) fn read_bar(
+        &mut self,
+        base: u64,
+        offset: u64,
+        data: &mut [u8],
+        vfio_wrapper: &dyn Vfio,
+    ) {
+        let addr = base + offset;
+        if let Some(region) = self.find_region(addr) {
+            let offset = addr - region.start.raw_value();
 
-        for region in self.mmio_regions.iter_mut() {
-            // We want to skip the mapping of the BAR containing the MSI-X
-            // table even if it is mappable. The reason is we need to trap
-            // any access to the MSI-X table and update the GSI routing
-            // accordingly.
-            if let Some(msix) = &self.interrupt.msix {
-                if region.index == msix.cap.table_bir() || region.index == msix.cap.pba_bir() {
-                    continue;
-                }
+            if self.interrupt.msix_table_accessed(region.index, offset) {
+                self.interrupt.msix_read_table(offset, data);
+            } else {
+                vfio_wrapper.region_read(region.index, offset, data);
             }
+        }
 
-            let region_flags = self.device.get_region_flags(region.index);
-            if region_flags & VFIO_REGION_INFO_FLAG_MMAP != 0 {
-                let mut prot = 0;
-                if region_flags & VFIO_REGION_INFO_FLAG_READ != 0 {
-                    prot |= libc::PROT_READ;
-                }
-                if region_flags & VFIO_REGION_INFO_FLAG_WRITE != 0 {
-                    prot |= libc::PROT_WRITE;
-                }
+        // INTx EOI
+        // The guest reading from the BAR potentially means the interrupt has
+        // been received and can be acknowledged.
+        if self.interrupt.intx_in_use() {
+            if let Err(e) = vfio_wrapper.unmask_irq(VFIO_PCI_INTX_IRQ_INDEX) {
+                error!("Failed unmasking INTx IRQ: {}", e);
+            }
+        }
+    }
+
+    pub(crate) fn write_bar(
+        &mut self,
+        base: u64,
+        offset: u64,
+        data: &[u8],
+        vfio_wrapper: &dyn Vfio,
+    ) -> Option<Arc<Barrier>> {
+        let addr = base + offset;
+        if let Some(region) = self.find_region(addr) {
+            let offset = addr - region.start.raw_value();
+
+            // If the MSI-X table is written to, we need to update our cache.
+            if self.interrupt.msix_table_accessed(region.index, offset) {
+                self.interrupt.msix_write_table(offset, data);
+            } else {
+                vfio_wrapper.region_write(region.index, offset, data);
+            }
+        }
+
+        // INTx EOI
+        // The guest writing to the BAR potentially means the interrupt has
+        // been received and can be acknowledged.
+        if self.interrupt.intx_in_use() {
+            if let Err(e) = vfio_wrapper.unmask_irq(VFIO_PCI_INTX_IRQ_INDEX) {
+                error!("Failed unmasking INTx IRQ: {}", e);
+            }
+        }
+
+        None
+    }
+
+    pub(crate) fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+        vfio_wrapper: &dyn Vfio,
+    ) -> Option<Arc<Barrier>> {
+        // When the guest wants to write to a BAR, we trap it into
+        // our local configuration space. We're not reprogramming
+        // VFIO device.
+        if (PCI_CONFIG_BAR0_INDEX..PCI_CONFIG_BAR0_INDEX + BAR_NUMS).contains(&reg_idx)
+            || reg_idx == PCI_ROM_EXP_BAR_INDEX
+        {
+            // We keep our local cache updated with the BARs.
+            // We'll read it back from there when the guest is asking
+            // for BARs (see read_config_register()).
+            self.configuration
+                .write_config_register(reg_idx, offset, data);
+            return None;
+        }
+
+        let reg = (reg_idx * PCI_CONFIG_REGISTER_SIZE) as u64;
+
+        // If the MSI or MSI-X capabilities are accessed, we need to
+        // update our local cache accordingly.
+        // Depending on how the capabilities are modified, this could
+        // trigger a VFIO MSI or MSI-X toggle.
+        if let Some((cap_id, cap_base)) = self.interrupt.accessed(reg) {
+            let cap_offset: u64 = reg - cap_base + offset;
+            match cap_id {
+                PciCapabilityID::MessageSignalledInterrupts => {
+                    if let Err(e) = self.update_msi_capabilities(cap_offset, data, vfio_wrapper) {
+                        error!("Could not update MSI capabilities: {}", e);
+                    }
+                }
+                PciCapabilityID::MSIX => {
+                    if let Err(e) = self.update_msix_capabilities(cap_offset, data, vfio_wrapper) {
+                        error!("Could not update MSI-X capabilities: {}", e);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Make sure to write to the device's PCI config space after MSI/MSI-X
+        // interrupts have been enabled/disabled. In case of MSI, when the
+        // interrupts are enabled through VFIO (using VFIO_DEVICE_SET_IRQS),
+        // the MSI Enable bit in the MSI capability structure found in the PCI
+        // config space is disabled by default. That's why when the guest is
+        // enabling this bit, we first need to enable the MSI interrupts with
+        // VFIO through VFIO_DEVICE_SET_IRQS ioctl, and only after we can write
+        // to the device region to update the MSI Enable bit.
+        vfio_wrapper.write_config((reg + offset) as u32, data);
+
+        None
+    }
+
+    pub(crate) fn read_config_register(&mut self, reg_idx: usize, vfio_wrapper: &dyn Vfio) -> u32 {
+        // When reading the BARs, we trap it and return what comes
+        // from our local configuration space. We want the guest to
+        // use that and not the VFIO device BARs as it does not map
+        // with the guest address space.
+        if (PCI_CONFIG_BAR0_INDEX..PCI_CONFIG_BAR0_INDEX + BAR_NUMS).contains(&reg_idx)
+            || reg_idx == PCI_ROM_EXP_BAR_INDEX
+        {
+            return self.configuration.read_reg(reg_idx);
+        }
+
+        // Since we don't support passing multi-functions devices, we should
+        // mask the multi-function bit, bit 7 of the Header Type byte on the
+        // register 3.
+        let mask = if reg_idx == PCI_HEADER_TYPE_REG_INDEX {
+            0xff7f_ffff
+        } else {
+            0xffff_ffff
+        };
+
+        // The config register read comes from the VFIO device itself.
+        vfio_wrapper.read_config_dword((reg_idx * 4) as u32) & mask
+    }
+
+    pub(crate) fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+}
+
+/// VfioPciDevice represents a VFIO PCI device.
+/// This structure implements the BusDevice and PciDevice traits.
+///
+/// A VfioPciDevice is bound to a VfioDevice and is also a PCI device.
+/// The VMM creates a VfioDevice, then assigns it to a VfioPciDevice,
+/// which then gets added to the PCI bus.
+pub struct VfioPciDevice {
+    vm: Arc<dyn hypervisor::Vm>,
+    device: Arc<VfioDevice>,
+    vfio_wrapper: VfioDeviceWrapper,
+    common: VfioCommon,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+}
+
+impl VfioPciDevice {
+    /// Constructs a new Vfio Pci device for the given Vfio device
+    pub fn new(
+        vm: &Arc<dyn hypervisor::Vm>,
+        device: VfioDevice,
+        msi_interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        legacy_interrupt_group: Option<Arc<Box<dyn InterruptSourceGroup>>>,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    ) -> Result<Self> {
+        let device = Arc::new(device);
+        device.reset();
+
+        let vfio_wrapper = VfioDeviceWrapper::new(Arc::clone(&device));
+
+        let mut common = VfioCommon::new();
+
+        common.parse_capabilities(msi_interrupt_manager, &vfio_wrapper);
+        common.initialize_legacy_interrupt(legacy_interrupt_group, &vfio_wrapper)?;
+
+        let vfio_pci_device = VfioPciDevice {
+            vm: vm.clone(),
+            device,
+            vfio_wrapper,
+            common,
+            mem,
+        };
+
+        Ok(vfio_pci_device)
+    }
+
+    /// Map MMIO regions into the guest, and avoid VM exits when the guest tries
+    /// to reach those regions.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The VM object. It is used to set the VFIO MMIO regions
+    ///          as user memory regions.
+    /// * `mem_slot` - The closure to return a memory slot.
+    pub fn map_mmio_regions<F>(&mut self, vm: &Arc<dyn hypervisor::Vm>, mem_slot: F) -> Result<()>
+    where
+        F: Fn() -> u32,
+    {
+        let fd = self.device.as_raw_fd();
+
+        for region in self.common.mmio_regions.iter_mut() {
+            // We want to skip the mapping of the BAR containing the MSI-X
+            // table even if it is mappable. The reason is we need to trap
+            // any access to the MSI-X table and update the GSI routing
+            // accordingly.
+            if let Some(msix) = &self.common.interrupt.msix {
+                if region.index == msix.cap.table_bir() || region.index == msix.cap.pba_bir() {
+                    continue;
+                }
+            }
+
+            let region_flags = self.device.get_region_flags(region.index);
+            if region_flags & VFIO_REGION_INFO_FLAG_MMAP != 0 {
+                let mut prot = 0;
+                if region_flags & VFIO_REGION_INFO_FLAG_READ != 0 {
+                    prot |= libc::PROT_READ;
+                }
+                if region_flags & VFIO_REGION_INFO_FLAG_WRITE != 0 {
+                    prot |= libc::PROT_WRITE;
+                }
                 let (mmap_offset, mmap_size) = self.device.get_region_mmap(region.index);
                 let offset = self.device.get_region_offset(region.index) + mmap_offset;
 
@@ -690,7 +1172,7 @@ impl VfioPciDevice {
     }
 
     pub fn unmap_mmio_regions(&mut self) {
-        for region in self.mmio_regions.iter() {
+        for region in self.common.mmio_regions.iter() {
             if let (Some(host_addr), Some(mmap_size), Some(mem_slot)) =
                 (region.host_addr, region.mmap_size, region.mem_slot)
             {
@@ -725,11 +1207,11 @@ impl VfioPciDevice {
     pub fn update_memory(&self, new_region: &Arc<GuestRegionMmap>) -> Result<()> {
         self.device
             .extend_dma_map(new_region)
-            .map_err(VfioPciError::UpdateMemory)
+            .map_err(|e| VfioPciError::UpdateMemory(VfioError::KernelVfio(e)))
     }
 
     pub fn mmio_regions(&self) -> Vec<MmioRegion> {
-        self.mmio_regions.clone()
+        self.common.mmio_regions.clone()
     }
 }
 
@@ -737,21 +1219,7 @@ impl Drop for VfioPciDevice {
     fn drop(&mut self) {
         self.unmap_mmio_regions();
 
-        if let Some(msix) = &self.interrupt.msix {
-            if msix.bar.enabled() {
-                self.disable_msix();
-            }
-        }
-
-        if let Some(msi) = &self.interrupt.msi {
-            if msi.cfg.enabled() {
-                self.disable_msi();
-            }
-        }
-
-        if self.interrupt.intx_in_use() {
-            self.disable_intx();
-        }
+        self.common.disable_interrupts(&self.vfio_wrapper);
 
         if self
             .device
@@ -798,170 +1266,16 @@ impl PciDevice for VfioPciDevice {
     fn allocate_bars(
         &mut self,
         allocator: &mut SystemAllocator,
+        mmio_allocator: &mut AddressAllocator,
+        mmio_hole_allocator: &mut AddressAllocator,
     ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
     {
-        let mut ranges = Vec::new();
-        let mut bar_id = VFIO_PCI_BAR0_REGION_INDEX as u32;
-
-        // Going through all regular regions to compute the BAR size.
-        // We're not saving the BAR address to restore it, because we
-        // are going to allocate a guest address for each BAR and write
-        // that new address back.
-        while bar_id < VFIO_PCI_CONFIG_REGION_INDEX {
-            let mut lsb_size: u32 = 0xffff_ffff;
-            let mut msb_size = 0;
-            let mut region_size: u64;
-            let bar_addr: GuestAddress;
-
-            // Read the BAR size (Starts by all 1s to the BAR)
-            let bar_offset = if bar_id == VFIO_PCI_ROM_REGION_INDEX {
-                (PCI_ROM_EXP_BAR_INDEX * 4) as u32
-            } else {
-                PCI_CONFIG_BAR_OFFSET + bar_id * 4
-            };
-
-            self.vfio_pci_configuration
-                .write_config_dword(lsb_size, bar_offset);
-            lsb_size = self.vfio_pci_configuration.read_config_dword(bar_offset);
-
-            // We've just read the BAR size back. Or at least its LSB.
-            let lsb_flag = lsb_size & PCI_CONFIG_MEMORY_BAR_FLAG_MASK;
-
-            if lsb_size == 0 {
-                bar_id += 1;
-                continue;
-            }
-
-            // Is this an IO BAR?
-            let io_bar = if bar_id != VFIO_PCI_ROM_REGION_INDEX {
-                matches!(lsb_flag & PCI_CONFIG_IO_BAR, PCI_CONFIG_IO_BAR)
-            } else {
-                false
-            };
-
-            // Is this a 64-bit BAR?
-            let is_64bit_bar = if bar_id != VFIO_PCI_ROM_REGION_INDEX {
-                matches!(
-                    lsb_flag & PCI_CONFIG_MEMORY_BAR_64BIT,
-                    PCI_CONFIG_MEMORY_BAR_64BIT
-                )
-            } else {
-                false
-            };
-
-            // By default, the region type is 32 bits memory BAR.
-            let mut region_type = PciBarRegionType::Memory32BitRegion;
-
-            if io_bar {
-                #[cfg(target_arch = "x86_64")]
-                {
-                    // IO BAR
-                    region_type = PciBarRegionType::IORegion;
-
-                    // Clear first bit.
-                    lsb_size &= 0xffff_fffc;
-
-                    // Find the first bit that's set to 1.
-                    let first_bit = lsb_size.trailing_zeros();
-                    region_size = 2u64.pow(first_bit);
-                    // We need to allocate a guest PIO address range for that BAR.
-                    // The address needs to be 4 bytes aligned.
-                    bar_addr = allocator
-                        .allocate_io_addresses(None, region_size, Some(0x4))
-                        .ok_or(PciDeviceError::IoAllocationFailed(region_size))?;
-                }
-                #[cfg(target_arch = "aarch64")]
-                unimplemented!()
-            } else {
-                if is_64bit_bar {
-                    // 64 bits Memory BAR
-                    region_type = PciBarRegionType::Memory64BitRegion;
-
-                    msb_size = 0xffff_ffff;
-                    let msb_bar_offset: u32 = PCI_CONFIG_BAR_OFFSET + (bar_id + 1) * 4;
-
-                    self.vfio_pci_configuration
-                        .write_config_dword(msb_size, msb_bar_offset);
-
-                    msb_size = self
-                        .vfio_pci_configuration
-                        .read_config_dword(msb_bar_offset);
-                }
-
-                // Clear the first four bytes from our LSB.
-                lsb_size &= 0xffff_fff0;
-
-                region_size = u64::from(msb_size);
-                region_size <<= 32;
-                region_size |= u64::from(lsb_size);
-
-                // Find the first that's set to 1.
-                let first_bit = region_size.trailing_zeros();
-                region_size = 2u64.pow(first_bit);
-
-                // We need to allocate a guest MMIO address range for that BAR.
-                // In case the BAR is mappable directly, this means it might be
-                // set as user memory region, which expects to deal with 4K
-                // pages. Therefore, the alignment has to be set accordingly.
-                let bar_alignment = if (bar_id == VFIO_PCI_ROM_REGION_INDEX)
-                    || (self.device.get_region_flags(bar_id) & VFIO_REGION_INFO_FLAG_MMAP != 0)
-                {
-                    // 4K alignment
-                    0x1000
-                } else {
-                    // Default 16 bytes alignment
-                    0x10
-                };
-                if is_64bit_bar {
-                    bar_addr = allocator
-                        .allocate_mmio_addresses(None, region_size, Some(bar_alignment))
-                        .ok_or(PciDeviceError::IoAllocationFailed(region_size))?;
-                } else {
-                    bar_addr = allocator
-                        .allocate_mmio_hole_addresses(None, region_size, Some(bar_alignment))
-                        .ok_or(PciDeviceError::IoAllocationFailed(region_size))?;
-                }
-            }
-
-            let reg_idx = if bar_id == VFIO_PCI_ROM_REGION_INDEX {
-                PCI_ROM_EXP_BAR_INDEX
-            } else {
-                bar_id as usize
-            };
-
-            // We can now build our BAR configuration block.
-            let config = PciBarConfiguration::default()
-                .set_register_index(reg_idx)
-                .set_address(bar_addr.raw_value())
-                .set_size(region_size)
-                .set_region_type(region_type);
-
-            if bar_id == VFIO_PCI_ROM_REGION_INDEX {
-                self.configuration
-                    .add_pci_rom_bar(&config, lsb_flag & 0x1)
-                    .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
-            } else {
-                self.configuration
-                    .add_pci_bar(&config)
-                    .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
-            }
-
-            ranges.push((bar_addr, region_size, region_type));
-            self.mmio_regions.push(MmioRegion {
-                start: bar_addr,
-                length: region_size,
-                type_: region_type,
-                index: bar_id as u32,
-                mem_slot: None,
-                host_addr: None,
-                mmap_size: None,
-            });
-
-            bar_id += 1;
-            if is_64bit_bar {
-                bar_id += 1;
-            }
-        }
+        let ranges = self.common.allocate_bars(
+            allocator,
+            mmio_allocator,
+            mmio_hole_allocator,
+            &self.vfio_wrapper,
+        )?;
 
         if self
             .device
@@ -977,24 +1291,11 @@ impl PciDevice for VfioPciDevice {
     fn free_bars(
         &mut self,
         allocator: &mut SystemAllocator,
+        mmio_allocator: &mut AddressAllocator,
+        mmio_hole_allocator: &mut AddressAllocator,
     ) -> std::result::Result<(), PciDeviceError> {
-        for region in self.mmio_regions.iter() {
-            match region.type_ {
-                PciBarRegionType::IORegion => {
-                    #[cfg(target_arch = "x86_64")]
-                    allocator.free_io_addresses(region.start, region.length);
-                    #[cfg(target_arch = "aarch64")]
-                    error!("I/O region is not supported");
-                }
-                PciBarRegionType::Memory32BitRegion => {
-                    allocator.free_mmio_hole_addresses(region.start, region.length);
-                }
-                PciBarRegionType::Memory64BitRegion => {
-                    allocator.free_mmio_addresses(region.start, region.length);
-                }
-            }
-        }
-        Ok(())
+        self.common
+            .free_bars(allocator, mmio_allocator, mmio_hole_allocator)
     }
 
     fn write_config_register(
@@ -1003,81 +1304,13 @@ impl PciDevice for VfioPciDevice {
         offset: u64,
         data: &[u8],
     ) -> Option<Arc<Barrier>> {
-        // When the guest wants to write to a BAR, we trap it into
-        // our local configuration space. We're not reprogramming
-        // VFIO device.
-        if (PCI_CONFIG_BAR0_INDEX..PCI_CONFIG_BAR0_INDEX + BAR_NUMS).contains(&reg_idx)
-            || reg_idx == PCI_ROM_EXP_BAR_INDEX
-        {
-            // We keep our local cache updated with the BARs.
-            // We'll read it back from there when the guest is asking
-            // for BARs (see read_config_register()).
-            self.configuration
-                .write_config_register(reg_idx, offset, data);
-            return None;
-        }
-
-        let reg = (reg_idx * PCI_CONFIG_REGISTER_SIZE) as u64;
-
-        // If the MSI or MSI-X capabilities are accessed, we need to
-        // update our local cache accordingly.
-        // Depending on how the capabilities are modified, this could
-        // trigger a VFIO MSI or MSI-X toggle.
-        if let Some((cap_id, cap_base)) = self.interrupt.accessed(reg) {
-            let cap_offset: u64 = reg - cap_base + offset;
-            match cap_id {
-                PciCapabilityID::MessageSignalledInterrupts => {
-                    if let Err(e) = self.update_msi_capabilities(cap_offset, data) {
-                        error!("Could not update MSI capabilities: {}", e);
-                    }
-                }
-                PciCapabilityID::MSIX => {
-                    if let Err(e) = self.update_msix_capabilities(cap_offset, data) {
-                        error!("Could not update MSI-X capabilities: {}", e);
-                    }
-                }
-                _ => {}
-            }
-        }
-
-        // Make sure to write to the device's PCI config space after MSI/MSI-X
-        // interrupts have been enabled/disabled. In case of MSI, when the
-        // interrupts are enabled through VFIO (using VFIO_DEVICE_SET_IRQS),
-        // the MSI Enable bit in the MSI capability structure found in the PCI
-        // config space is disabled by default. That's why when the guest is
-        // enabling this bit, we first need to enable the MSI interrupts with
-        // VFIO through VFIO_DEVICE_SET_IRQS ioctl, and only after we can write
-        // to the device region to update the MSI Enable bit.
-        self.device
-            .region_write(VFIO_PCI_CONFIG_REGION_INDEX, data, reg + offset);
-
-        None
+        self.common
+            .write_config_register(reg_idx, offset, data, &self.vfio_wrapper)
     }
 
     fn read_config_register(&mut self, reg_idx: usize) -> u32 {
-        // When reading the BARs, we trap it and return what comes
-        // from our local configuration space. We want the guest to
-        // use that and not the VFIO device BARs as it does not map
-        // with the guest address space.
-        if (PCI_CONFIG_BAR0_INDEX..PCI_CONFIG_BAR0_INDEX + BAR_NUMS).contains(&reg_idx)
-            || reg_idx == PCI_ROM_EXP_BAR_INDEX
-        {
-            return self.configuration.read_reg(reg_idx);
-        }
-
-        // Since we don't support passing multi-functions devices, we should
-        // mask the multi-function bit, bit 7 of the Header Type byte on the
-        // register 3.
-        let mask = if reg_idx == PCI_HEADER_TYPE_REG_INDEX {
-            0xff7f_ffff
-        } else {
-            0xffff_ffff
-        };
-
-        // The config register read comes from the VFIO device itself.
-        self.vfio_pci_configuration
-            .read_config_dword((reg_idx * 4) as u32)
-            & mask
+        self.common
+            .read_config_register(reg_idx, &self.vfio_wrapper)
     }
 
     fn detect_bar_reprogramming(
@@ -1085,58 +1318,20 @@ impl PciDevice for VfioPciDevice {
         reg_idx: usize,
         data: &[u8],
     ) -> Option<BarReprogrammingParams> {
-        self.configuration.detect_bar_reprogramming(reg_idx, data)
+        self.common.detect_bar_reprogramming(reg_idx, data)
     }
 
     fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
-        let addr = base + offset;
-        if let Some(region) = self.find_region(addr) {
-            let offset = addr - region.start.raw_value();
-
-            if self.interrupt.msix_table_accessed(region.index, offset) {
-                self.interrupt.msix_read_table(offset, data);
-            } else {
-                self.device.region_read(region.index, data, offset);
-            }
-        }
-
-        // INTx EOI
-        // The guest reading from the BAR potentially means the interrupt has
-        // been received and can be acknowledged.
-        if self.interrupt.intx_in_use() {
-            if let Err(e) = self.device.unmask_irq(VFIO_PCI_INTX_IRQ_INDEX) {
-                error!("Failed unmasking INTx IRQ: {}", e);
-            }
-        }
+        self.common.read_bar(base, offset, data, &self.vfio_wrapper)
     }
 
     fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
-        let addr = base + offset;
-        if let Some(region) = self.find_region(addr) {
-            let offset = addr - region.start.raw_value();
-
-            // If the MSI-X table is written to, we need to update our cache.
-            if self.interrupt.msix_table_accessed(region.index, offset) {
-                self.interrupt.msix_write_table(offset, data);
-            } else {
-                self.device.region_write(region.index, data, offset);
-            }
-        }
-
-        // INTx EOI
-        // The guest writing to the BAR potentially means the interrupt has
-        // been received and can be acknowledged.
-        if self.interrupt.intx_in_use() {
-            if let Err(e) = self.device.unmask_irq(VFIO_PCI_INTX_IRQ_INDEX) {
-                error!("Failed unmasking INTx IRQ: {}", e);
-            }
-        }
-
-        None
+        self.common
+            .write_bar(base, offset, data, &self.vfio_wrapper)
     }
 
     fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), io::Error> {
-        for region in self.mmio_regions.iter_mut() {
+        for region in self.common.mmio_regions.iter_mut() {
             if region.start.raw_value() == old_base {
                 region.start = GuestAddress(new_base);
 
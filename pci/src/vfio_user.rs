@@ -0,0 +1,318 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::vfio::{Vfio, VfioCommon, VfioError, VfioIrq};
+use crate::{BarReprogrammingParams, PciBarRegionType, PciDevice, PciDeviceError, VfioPciError};
+use std::any::Any;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Barrier, Mutex};
+use std::{fmt, io, result};
+use vfio_bindings::bindings::vfio::*;
+use vfio_user::{Client, Error as VfioUserError};
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::interrupt::{InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig};
+use vm_device::BusDevice;
+use vm_memory::{Address, GuestAddress, GuestMemoryRegion, GuestRegionMmap, GuestUsize};
+use vmm_sys_util::eventfd::EventFd;
+
+#[derive(Debug)]
+pub enum VfioUserPciDeviceError {
+    Client(VfioUserError),
+    DmaMap(VfioUserError),
+    DmaUnmap(VfioUserError),
+    DmaMapNoFileOffset,
+    InitializeLegacyInterrupts(VfioPciError),
+}
+pub type Result<T> = std::result::Result<T, VfioUserPciDeviceError>;
+
+impl fmt::Display for VfioUserPciDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VfioUserPciDeviceError::Client(e) => write!(f, "vfio-user client error: {}", e),
+            VfioUserPciDeviceError::DmaMap(e) => write!(f, "failed to DMA map: {}", e),
+            VfioUserPciDeviceError::DmaUnmap(e) => write!(f, "failed to DMA unmap: {}", e),
+            VfioUserPciDeviceError::DmaMapNoFileOffset => {
+                write!(f, "memory region is not backed by a file")
+            }
+            VfioUserPciDeviceError::InitializeLegacyInterrupts(e) => {
+                write!(f, "failed to initialize legacy interrupts: {}", e)
+            }
+        }
+    }
+}
+
+struct VfioUserClientWrapper {
+    client: Arc<Mutex<Client>>,
+}
+
+impl Vfio for VfioUserClientWrapper {
+    fn region_read(&self, index: u32, offset: u64, data: &mut [u8]) {
+        if let Err(e) = self.client.lock().unwrap().region_read(index, offset, data) {
+            error!(
+                "Failed reading region {} at offset 0x{:x}: {}",
+                index, offset, e
+            );
+        }
+    }
+
+    fn region_write(&self, index: u32, offset: u64, data: &[u8]) {
+        if let Err(e) = self
+            .client
+            .lock()
+            .unwrap()
+            .region_write(index, offset, data)
+        {
+            error!(
+                "Failed writing region {} at offset 0x{:x}: {}",
+                index, offset, e
+            );
+        }
+    }
+
+    fn get_region_flags(&self, index: u32) -> u32 {
+        self.client
+            .lock()
+            .unwrap()
+            .region(index)
+            .map(|r| r.flags)
+            .unwrap_or(0)
+    }
+
+    fn get_irq_info(&self, irq_index: u32) -> Option<VfioIrq> {
+        self.client
+            .lock()
+            .unwrap()
+            .get_irq_info(irq_index)
+            .ok()
+            .map(|irq| VfioIrq {
+                index: irq.index,
+                flags: irq.flags,
+                count: irq.count,
+            })
+    }
+
+    fn enable_irq(
+        &self,
+        irq_index: u32,
+        event_fds: Vec<&EventFd>,
+    ) -> result::Result<(), VfioError> {
+        info!(
+            "Enabling IRQ {:x} number of fds = {:?}",
+            irq_index,
+            event_fds.len()
+        );
+        let fds: Vec<i32> = event_fds.iter().map(|e| e.as_raw_fd()).collect();
+        self.client
+            .lock()
+            .unwrap()
+            .set_irqs(
+                irq_index,
+                VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+                0,
+                event_fds.len() as u32,
+                &fds,
+            )
+            .map_err(VfioError::VfioUser)
+    }
+
+    fn disable_irq(&self, irq_index: u32) -> result::Result<(), VfioError> {
+        info!("Disabling IRQ {:x}", irq_index);
+        self.client
+            .lock()
+            .unwrap()
+            .set_irqs(
+                irq_index,
+                VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER,
+                0,
+                0,
+                &[],
+            )
+            .map_err(VfioError::VfioUser)
+    }
+
+    fn unmask_irq(&self, irq_index: u32) -> result::Result<(), VfioError> {
+        self.client
+            .lock()
+            .unwrap()
+            .set_irqs(
+                irq_index,
+                VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_UNMASK,
+                0,
+                1,
+                &[],
+            )
+            .map_err(VfioError::VfioUser)
+    }
+}
+
+/// VfioUserPciDevice represents a PCI device emulated by an external
+/// process, reached through the vfio-user protocol.
+/// This structure implements the BusDevice and PciDevice traits.
+///
+/// Accesses to the PCI configuration space and to the BARs are forwarded
+/// to the vfio-user server as region reads and writes. The guest memory
+/// needs to be shared with the server through dma_map() so that the
+/// emulated device can perform DMA.
+pub struct VfioUserPciDevice {
+    client: Arc<Mutex<Client>>,
+    vfio_wrapper: VfioUserClientWrapper,
+    common: VfioCommon,
+}
+
+impl VfioUserPciDevice {
+    /// Constructs a new PCI device for the given vfio-user client
+    pub fn new(
+        client: Arc<Mutex<Client>>,
+        msi_interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        legacy_interrupt_group: Option<Arc<Box<dyn InterruptSourceGroup>>>,
+    ) -> Result<Self> {
+        // Start from a known device state.
+        client
+            .lock()
+            .unwrap()
+            .reset()
+            .map_err(VfioUserPciDeviceError::Client)?;
+
+        let vfio_wrapper = VfioUserClientWrapper {
+            client: client.clone(),
+        };
+
+        let mut common = VfioCommon::new();
+
+        common.parse_capabilities(msi_interrupt_manager, &vfio_wrapper);
+        common
+            .initialize_legacy_interrupt(legacy_interrupt_group, &vfio_wrapper)
+            .map_err(VfioUserPciDeviceError::InitializeLegacyInterrupts)?;
+
+        Ok(VfioUserPciDevice {
+            client,
+            vfio_wrapper,
+            common,
+        })
+    }
+
+    /// Share the guest memory region with the vfio-user server. The region
+    /// must be backed by a file so that it can be mapped by the server.
+    pub fn dma_map(&mut self, region: &GuestRegionMmap) -> Result<()> {
+        let (fd, offset) = match region.file_offset() {
+            Some(file_offset) => (file_offset.file().as_raw_fd(), file_offset.start()),
+            None => return Err(VfioUserPciDeviceError::DmaMapNoFileOffset),
+        };
+
+        self.client
+            .lock()
+            .unwrap()
+            .dma_map(
+                offset,
+                region.start_addr().raw_value(),
+                region.len() as u64,
+                fd,
+            )
+            .map_err(VfioUserPciDeviceError::DmaMap)
+    }
+
+    /// Stop sharing the guest memory region with the vfio-user server.
+    pub fn dma_unmap(&mut self, region: &GuestRegionMmap) -> Result<()> {
+        self.client
+            .lock()
+            .unwrap()
+            .dma_unmap(region.start_addr().raw_value(), region.len() as u64)
+            .map_err(VfioUserPciDeviceError::DmaUnmap)
+    }
+}
+
+impl Drop for VfioUserPciDevice {
+    fn drop(&mut self) {
+        self.common.disable_interrupts(&self.vfio_wrapper);
+
+        self.client.lock().unwrap().shutdown();
+    }
+}
+
+impl BusDevice for VfioUserPciDevice {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for VfioUserPciDevice {
+    fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+        mmio_allocator: &mut AddressAllocator,
+        mmio_hole_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
+    {
+        self.common.allocate_bars(
+            allocator,
+            mmio_allocator,
+            mmio_hole_allocator,
+            &self.vfio_wrapper,
+        )
+    }
+
+    fn free_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+        mmio_allocator: &mut AddressAllocator,
+        mmio_hole_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        self.common
+            .free_bars(allocator, mmio_allocator, mmio_hole_allocator)
+    }
+
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.common
+            .write_config_register(reg_idx, offset, data, &self.vfio_wrapper)
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.common
+            .read_config_register(reg_idx, &self.vfio_wrapper)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.common.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.common.read_bar(base, offset, data, &self.vfio_wrapper)
+    }
+
+    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.common
+            .write_bar(base, offset, data, &self.vfio_wrapper)
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), io::Error> {
+        // The BARs are not mapped into the guest, all the accesses are
+        // trapped and forwarded to the server. There's nothing more to do
+        // than updating the region start address.
+        for region in self.common.mmio_regions.iter_mut() {
+            if region.start.raw_value() == old_base {
+                region.start = GuestAddress(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
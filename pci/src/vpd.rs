@@ -0,0 +1,92 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::configuration::{PciCapability, PciCapabilityID};
+use vm_memory::ByteValued;
+
+// Resource tags the VPD is made of, see the Vital Product Data section of the
+// PCI Local Bus specification.
+const VPD_ID_STRING_TAG: u8 = 0x82;
+const VPD_READ_ONLY_TAG: u8 = 0x90;
+const VPD_END_TAG: u8 = 0x78;
+
+// Cleared by the guest when requesting a read at the address, and set by the
+// device once the data register holds the data. Writes work the other way
+// around.
+pub(crate) const VPD_ADDR_FLAG: u32 = 0x8000;
+const VPD_ADDR_MASK: u32 = 0x7ffc;
+
+/// Longest serial number the VPD can hold, the length of a keyword being
+/// given on a byte.
+pub const MAX_VPD_SERIAL_LEN: usize = 255;
+
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+pub struct VpdCap {
+    // Address of the dword to read, and flag telling whether it has been.
+    addr: u16,
+    // Dword of the VPD at the address.
+    data: u32,
+}
+
+// It is safe to implement ByteValued. All members are simple numbers and any value is valid.
+unsafe impl ByteValued for VpdCap {}
+
+impl PciCapability for VpdCap {
+    fn bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn id(&self) -> PciCapabilityID {
+        PciCapabilityID::VitalProductData
+    }
+}
+
+/// The VPD of a device, read through its capability.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Vpd {
+    pub(crate) cap_reg_idx: usize,
+    pub(crate) data: Vec<u8>,
+}
+
+impl Vpd {
+    // Returns the dword the guest asked for through the address register,
+    // reading zeroes past the end of the VPD.
+    pub(crate) fn read(&self, addr: u32) -> u32 {
+        let addr = (addr & VPD_ADDR_MASK) as usize;
+        let mut dword = [0u8; 4];
+        for (i, byte) in dword.iter_mut().enumerate() {
+            *byte = self.data.get(addr + i).copied().unwrap_or(0);
+        }
+
+        u32::from_le_bytes(dword)
+    }
+}
+
+/// Builds the VPD of a device, made of its product name and of a read-only
+/// section holding its serial number. The serial number can't be longer than
+/// MAX_VPD_SERIAL_LEN.
+pub fn build_vpd(product_name: &str, serial: &str) -> Vec<u8> {
+    let mut vpd = vec![VPD_ID_STRING_TAG];
+    vpd.extend_from_slice(&(product_name.len() as u16).to_le_bytes());
+    vpd.extend_from_slice(product_name.as_bytes());
+
+    // The serial number keyword, followed by the checksum one, which makes
+    // the sum of all the bytes up to the checksum zero.
+    vpd.push(VPD_READ_ONLY_TAG);
+    vpd.extend_from_slice(&(3 + serial.len() as u16 + 4).to_le_bytes());
+    vpd.extend_from_slice(b"SN");
+    vpd.push(serial.len() as u8);
+    vpd.extend_from_slice(serial.as_bytes());
+    vpd.extend_from_slice(b"RV");
+    vpd.push(1);
+    let sum = vpd.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    vpd.push(0u8.wrapping_sub(sum));
+
+    vpd.push(VPD_END_TAG);
+
+    vpd
+}
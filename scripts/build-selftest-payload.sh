@@ -0,0 +1,36 @@
+#!/bin/bash
+# Builds the self-test payload: a kernel with a built-in initramfs made of a
+# static busybox, the guest agent and the ch-selftest helper.
+#
+# Usage: scripts/build-selftest-payload.sh <linux source directory> <output>
+set -ex
+
+LINUX_DIR=$(realpath "$1")
+OUTPUT=$(realpath "$2")
+BUSYBOX=${BUSYBOX-$(command -v busybox)}
+SRCDIR=$(realpath "$(dirname "$0")/..")
+BUILD_TARGET="$(uname -m)-unknown-linux-musl"
+
+# The agent runs in the guest, so it's linked statically.
+pushd "$SRCDIR"
+cargo build --release --bin ch-agent --target "$BUILD_TARGET"
+popd
+
+INITRAMFS_DIR=$(mktemp -d)
+trap 'rm -rf "$INITRAMFS_DIR"' EXIT
+mkdir -p "$INITRAMFS_DIR"/{bin,dev,proc,sys,tmp,mnt}
+cp "$BUSYBOX" "$INITRAMFS_DIR/bin/busybox"
+for applet in $("$BUSYBOX" --list); do
+    ln -sf busybox "$INITRAMFS_DIR/bin/$applet"
+done
+cp "$SRCDIR/target/$BUILD_TARGET/release/ch-agent" "$INITRAMFS_DIR/bin/"
+cp "$SRCDIR/resources/selftest/ch-selftest" "$INITRAMFS_DIR/bin/"
+cp "$SRCDIR/resources/selftest/init" "$INITRAMFS_DIR/init"
+
+pushd "$LINUX_DIR"
+cp "$SRCDIR/resources/linux-config-$(uname -m)" .config
+scripts/config --set-str CONFIG_INITRAMFS_SOURCE "$INITRAMFS_DIR"
+make olddefconfig
+make vmlinux -j "$(nproc)"
+cp vmlinux "$OUTPUT"
+popd
@@ -0,0 +1,218 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Guest agent of the self-test payload, answering the requests the VMM
+//! sends on a vsock port.
+
+extern crate vmm;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::process::{self, Command};
+use std::ptr;
+use std::thread;
+use vmm::api::agent::{
+    read_frame, write_frame, AgentMessage, AgentRequest, AgentResponse, Error, Result,
+};
+use vmm::selftest::SELFTEST_AGENT_PORT;
+
+// Connections waiting to be accepted.
+const LISTEN_BACKLOG: i32 = 8;
+
+fn vsock_listen(port: u32) -> io::Result<File> {
+    // SAFETY: the arguments are valid, and the result is checked.
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor was just created, and isn't owned by
+    // anything else.
+    let listener = unsafe { File::from_raw_fd(fd) };
+
+    // SAFETY: sockaddr_vm is made of integers, which can be zeroed.
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_port = port;
+    addr.svm_cid = libc::VMADDR_CID_ANY;
+    // SAFETY: the address is valid for the given length, and the result is
+    // checked.
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    // SAFETY: the socket is valid, and the result is checked.
+    if ret < 0 || unsafe { libc::listen(fd, LISTEN_BACKLOG) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+fn vsock_accept(listener: &File) -> io::Result<File> {
+    // SAFETY: the socket is valid, the peer address isn't asked for, and
+    // the result is checked.
+    let fd = unsafe {
+        libc::accept4(
+            listener.as_raw_fd(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            libc::SOCK_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: the file descriptor was just accepted, and isn't owned by
+    // anything else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn handle_request(request: AgentRequest) -> AgentResponse {
+    match request {
+        AgentRequest::Ping => AgentResponse::Pong,
+        AgentRequest::Exec { command, args } => match Command::new(&command).args(&args).output() {
+            Ok(output) => AgentResponse::Exec {
+                // Killed by a signal.
+                status: output.status.code().unwrap_or(-1),
+                stdout: output.stdout,
+                stderr: output.stderr,
+            },
+            Err(e) => AgentResponse::Error {
+                message: format!("Failed running {}: {}", command, e),
+            },
+        },
+        AgentRequest::FileRead { path } => match fs::read(&path) {
+            Ok(content) => AgentResponse::FileRead { content },
+            Err(e) => AgentResponse::Error {
+                message: format!("Failed reading {}: {}", path, e),
+            },
+        },
+    }
+}
+
+// Answers the requests of a connection until the VMM closes it.
+fn serve<S: Read + Write>(stream: &mut S) -> Result<()> {
+    loop {
+        let payload = match read_frame(stream) {
+            Ok(payload) => payload,
+            Err(Error::Read(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let request: AgentMessage<AgentRequest> =
+            serde_json::from_slice(&payload).map_err(Error::Deserialize)?;
+
+        let response = AgentMessage {
+            id: request.id,
+            body: handle_request(request.body),
+        };
+        let payload = serde_json::to_vec(&response).map_err(Error::Serialize)?;
+        write_frame(stream, &payload)?;
+    }
+}
+
+fn main() {
+    let port = match env::args().nth(1) {
+        Some(port) => match port.parse() {
+            Ok(port) => port,
+            Err(e) => {
+                eprintln!("Invalid vsock port {}: {}", port, e);
+                process::exit(1);
+            }
+        },
+        None => SELFTEST_AGENT_PORT,
+    };
+
+    let listener = match vsock_listen(port) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed listening on vsock port {}: {}", port, e);
+            process::exit(1);
+        }
+    };
+
+    loop {
+        match vsock_accept(&listener) {
+            Ok(mut stream) => {
+                thread::spawn(move || {
+                    if let Err(e) = serve(&mut stream) {
+                        eprintln!("Error serving the VMM: {:?}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Failed accepting a connection: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use vmm::api::agent::send_request;
+
+    #[test]
+    fn test_handle_request() {
+        assert_eq!(handle_request(AgentRequest::Ping), AgentResponse::Pong);
+
+        match handle_request(AgentRequest::Exec {
+            command: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "echo out; echo err >&2; exit 3".to_string(),
+            ],
+        }) {
+            AgentResponse::Exec {
+                status,
+                stdout,
+                stderr,
+            } => {
+                assert_eq!(status, 3);
+                assert_eq!(stdout, b"out\n");
+                assert_eq!(stderr, b"err\n");
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+
+        assert!(matches!(
+            handle_request(AgentRequest::Exec {
+                command: "/nonexistent".to_string(),
+                args: Vec::new(),
+            }),
+            AgentResponse::Error { .. }
+        ));
+        assert!(matches!(
+            handle_request(AgentRequest::FileRead {
+                path: "/nonexistent".to_string(),
+            }),
+            AgentResponse::Error { .. }
+        ));
+    }
+
+    #[test]
+    fn test_serve() {
+        let (mut vmm, mut agent) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || serve(&mut agent));
+
+        assert_eq!(
+            send_request(&mut vmm, 1, AgentRequest::Ping).unwrap(),
+            AgentResponse::Pong
+        );
+        assert_eq!(
+            send_request(&mut vmm, 2, AgentRequest::Ping).unwrap(),
+            AgentResponse::Pong
+        );
+
+        // The agent is done once the VMM closes the connection.
+        drop(vmm);
+        assert!(server.join().unwrap().is_ok());
+    }
+}
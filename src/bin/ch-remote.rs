@@ -12,9 +12,10 @@ extern crate vmm;
 use api_client::simple_api_command;
 use api_client::Error as ApiClientError;
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
-use option_parser::{ByteSized, ByteSizedParseError};
+use option_parser::{ByteSized, ByteSizedParseError, OptionParser};
 use std::fmt;
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::process;
 
 #[derive(Debug)]
@@ -24,6 +25,15 @@ enum Error {
     InvalidCPUCount(std::num::ParseIntError),
     InvalidMemorySize(ByteSizedParseError),
     InvalidBalloonSize(ByteSizedParseError),
+    InvalidRtcTime(std::num::ParseIntError),
+    InvalidInputEvent(String),
+    InvalidGpioLine(std::num::ParseIntError),
+    InvalidThrottle(std::num::ParseIntError),
+    InvalidReplicationInterval(std::num::ParseIntError),
+    InvalidBatteryCharge(std::num::ParseIntError),
+    InvalidFlowRule(String),
+    InvalidNumQueues(std::num::ParseIntError),
+    InvalidVcpuId(std::num::ParseIntError),
     AddDeviceConfig(vmm::config::Error),
     AddDiskConfig(vmm::config::Error),
     AddFsConfig(vmm::config::Error),
@@ -42,6 +52,17 @@ impl fmt::Display for Error {
             InvalidCPUCount(e) => write!(f, "Error parsing CPU count: {}", e),
             InvalidMemorySize(e) => write!(f, "Error parsing memory size: {:?}", e),
             InvalidBalloonSize(e) => write!(f, "Error parsing balloon size: {:?}", e),
+            InvalidRtcTime(e) => write!(f, "Error parsing RTC time: {}", e),
+            InvalidInputEvent(e) => write!(f, "Error parsing input event: {}", e),
+            InvalidGpioLine(e) => write!(f, "Error parsing GPIO line: {}", e),
+            InvalidThrottle(e) => write!(f, "Error parsing vCPU throttling: {}", e),
+            InvalidReplicationInterval(e) => {
+                write!(f, "Error parsing replication interval: {}", e)
+            }
+            InvalidBatteryCharge(e) => write!(f, "Error parsing battery charge: {}", e),
+            InvalidFlowRule(e) => write!(f, "Error parsing flow rule: {}", e),
+            InvalidNumQueues(e) => write!(f, "Error parsing number of queues: {}", e),
+            InvalidVcpuId(e) => write!(f, "Error parsing vCPU identifier: {}", e),
             AddDeviceConfig(e) => write!(f, "Error parsing device syntax: {}", e),
             AddDiskConfig(e) => write!(f, "Error parsing disk syntax: {}", e),
             AddFsConfig(e) => write!(f, "Error parsing filesystem syntax: {}", e),
@@ -102,6 +123,23 @@ fn resize_api_command(
     .map_err(Error::ApiClient)
 }
 
+fn add_dimm_api_command(socket: &mut UnixStream, size: &str) -> Result<(), Error> {
+    let add_dimm = vmm::api::VmAddDimmData {
+        size: size
+            .parse::<ByteSized>()
+            .map_err(Error::InvalidMemorySize)?
+            .0,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "add-dimm",
+        Some(&serde_json::to_string(&add_dimm).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
 fn resize_zone_api_command(socket: &mut UnixStream, id: &str, size: &str) -> Result<(), Error> {
     let resize_zone = vmm::api::VmResizeZoneData {
         id: id.to_owned(),
@@ -120,6 +158,231 @@ fn resize_zone_api_command(socket: &mut UnixStream, id: &str, size: &str) -> Res
     .map_err(Error::ApiClient)
 }
 
+fn set_memory_target_api_command(socket: &mut UnixStream, size: &str) -> Result<(), Error> {
+    let memory_target = vmm::api::VmSetMemoryTargetData {
+        size: size
+            .parse::<ByteSized>()
+            .map_err(Error::InvalidMemorySize)?
+            .0,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-memory-target",
+        Some(&serde_json::to_string(&memory_target).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn set_rtc_api_command(socket: &mut UnixStream, time: &str) -> Result<(), Error> {
+    let rtc = vmm::api::VmRtcData {
+        time: time.parse().map_err(Error::InvalidRtcTime)?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-rtc",
+        Some(&serde_json::to_string(&rtc).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn set_battery_api_command(
+    socket: &mut UnixStream,
+    charge: &str,
+    ac_online: bool,
+) -> Result<(), Error> {
+    let battery = vmm::api::VmBatteryData {
+        ac_online,
+        charge: charge.parse().map_err(Error::InvalidBatteryCharge)?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-battery",
+        Some(&serde_json::to_string(&battery).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn set_flow_rules_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    rules: Vec<&str>,
+) -> Result<(), Error> {
+    let parse_rule = |rule: &str| -> Result<vmm::api::FlowRuleData, Error> {
+        let invalid = |e| Error::InvalidFlowRule(format!("{}: {}", rule, e));
+        let mut parser = OptionParser::new();
+        parser
+            .add("protocol")
+            .add("src_ip")
+            .add("dst_ip")
+            .add("src_port")
+            .add("dst_port")
+            .add("queue");
+        parser.parse(rule).map_err(invalid)?;
+
+        let protocol = match parser.get("protocol").as_deref() {
+            None => None,
+            Some("tcp") => Some(vmm::api::FlowProtocol::Tcp),
+            Some("udp") => Some(vmm::api::FlowProtocol::Udp),
+            Some(_) => return Err(Error::InvalidFlowRule(rule.to_owned())),
+        };
+        Ok(vmm::api::FlowRuleData {
+            protocol,
+            src_ip: parser.convert("src_ip").map_err(invalid)?,
+            dst_ip: parser.convert("dst_ip").map_err(invalid)?,
+            src_port: parser.convert("src_port").map_err(invalid)?,
+            dst_port: parser.convert("dst_port").map_err(invalid)?,
+            queue: parser
+                .convert("queue")
+                .map_err(invalid)?
+                .ok_or_else(|| Error::InvalidFlowRule(rule.to_owned()))?,
+        })
+    };
+
+    let flow_rules = vmm::api::VmFlowRulesData {
+        id: id.to_owned(),
+        rules: rules
+            .into_iter()
+            .map(parse_rule)
+            .collect::<Result<Vec<_>, Error>>()?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-flow-rules",
+        Some(&serde_json::to_string(&flow_rules).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn set_net_queues_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    num_queues: &str,
+) -> Result<(), Error> {
+    let net_queues = vmm::api::VmNetQueuesData {
+        id: id.to_owned(),
+        num_queues: num_queues.parse().map_err(Error::InvalidNumQueues)?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-net-queues",
+        Some(&serde_json::to_string(&net_queues).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn inject_nmi_api_command(socket: &mut UnixStream, id: Option<&str>) -> Result<(), Error> {
+    let inject_nmi = vmm::api::VmInjectNmiData {
+        id: id
+            .map(|id| id.parse().map_err(Error::InvalidVcpuId))
+            .transpose()?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "inject-nmi",
+        Some(&serde_json::to_string(&inject_nmi).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn input_event_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    events: Vec<&str>,
+) -> Result<(), Error> {
+    let parse_event = |event: &str| -> Result<vmm::api::InputEvent, Error> {
+        let fields: Vec<&str> = event.split(':').collect();
+        if fields.len() != 3 {
+            return Err(Error::InvalidInputEvent(event.to_owned()));
+        }
+        let invalid = |_| Error::InvalidInputEvent(event.to_owned());
+        Ok(vmm::api::InputEvent {
+            event_type: fields[0].parse().map_err(invalid)?,
+            code: fields[1].parse().map_err(invalid)?,
+            value: fields[2].parse().map_err(invalid)?,
+        })
+    };
+
+    let input_event = vmm::api::VmInputEventData {
+        id: id.to_owned(),
+        events: events
+            .into_iter()
+            .map(parse_event)
+            .collect::<Result<Vec<_>, Error>>()?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "input-event",
+        Some(&serde_json::to_string(&input_event).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn set_gpio_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    line: &str,
+    value: &str,
+) -> Result<(), Error> {
+    let gpio = vmm::api::VmGpioData {
+        id: id.to_owned(),
+        line: line.parse().map_err(Error::InvalidGpioLine)?,
+        value: value == "on",
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-gpio",
+        Some(&serde_json::to_string(&gpio).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn gpio_lines_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
+    let gpio_lines = vmm::api::VmGpioLinesData { id: id.to_owned() };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "gpio-lines",
+        Some(&serde_json::to_string(&gpio_lines).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn net_capture_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    path: Option<&str>,
+) -> Result<(), Error> {
+    let net_capture = vmm::api::VmNetCaptureData {
+        id: id.to_owned(),
+        path: path.map(PathBuf::from),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "net-capture",
+        Some(&serde_json::to_string(&net_capture).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
 fn add_device_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let device_config = vmm::config::DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)?;
 
@@ -144,6 +407,54 @@ fn remove_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Er
     .map_err(Error::ApiClient)
 }
 
+fn reset_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
+    let reset_device_data = vmm::api::VmResetDeviceData { id: id.to_owned() };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "reset-device",
+        Some(&serde_json::to_string(&reset_device_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn pause_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
+    let pause_device_data = vmm::api::VmPauseDeviceData { id: id.to_owned() };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "pause-device",
+        Some(&serde_json::to_string(&pause_device_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn resume_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
+    let resume_device_data = vmm::api::VmResumeDeviceData { id: id.to_owned() };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "resume-device",
+        Some(&serde_json::to_string(&resume_device_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn activate_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
+    let activate_device_data = vmm::api::VmActivateDeviceData { id: id.to_owned() };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "activate-device",
+        Some(&serde_json::to_string(&activate_device_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
 fn add_disk_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let disk_config = vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
 
@@ -204,9 +515,14 @@ fn add_vsock_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Er
     .map_err(Error::ApiClient)
 }
 
-fn snapshot_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+fn snapshot_api_command(
+    socket: &mut UnixStream,
+    url: &str,
+    devices_only: bool,
+) -> Result<(), Error> {
     let snapshot_config = vmm::api::VmSnapshotConfig {
         destination_url: String::from(url),
+        devices_only,
     };
 
     simple_api_command(
@@ -243,9 +559,40 @@ fn receive_migration_api_command(socket: &mut UnixStream, url: &str) -> Result<(
     .map_err(Error::ApiClient)
 }
 
-fn send_migration_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+fn send_migration_api_command(
+    socket: &mut UnixStream,
+    url: &str,
+    copy_disks: bool,
+    matches: &ArgMatches,
+) -> Result<(), Error> {
+    let auto_converge = if matches.is_present("auto_converge") {
+        let parse = |arg: &str| -> Result<Option<u8>, Error> {
+            matches
+                .value_of(arg)
+                .map(str::parse)
+                .transpose()
+                .map_err(Error::InvalidThrottle)
+        };
+        let default = vmm::api::MigrationThrottleConfig::default();
+        Some(vmm::api::MigrationThrottleConfig {
+            initial: parse("throttle_initial")?.unwrap_or(default.initial),
+            increment: parse("throttle_increment")?.unwrap_or(default.increment),
+            max: parse("throttle_max")?.unwrap_or(default.max),
+        })
+    } else {
+        None
+    };
+    let replication_interval = matches
+        .value_of("replication_interval")
+        .map(str::parse)
+        .transpose()
+        .map_err(Error::InvalidReplicationInterval)?;
     let send_migration_data = vmm::api::VmSendMigrationData {
         destination_url: url.to_owned(),
+        copy_disks,
+        auto_converge,
+        replication_interval,
+        check_cpuid: matches.is_present("check_cpuid"),
     };
     simple_api_command(
         socket,
@@ -267,6 +614,119 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
         Some("counters") => {
             simple_api_command(&mut socket, "GET", "counters", None).map_err(Error::ApiClient)
         }
+        Some("get-rtc") => {
+            simple_api_command(&mut socket, "GET", "get-rtc", None).map_err(Error::ApiClient)
+        }
+        Some("launch-measurement") => {
+            simple_api_command(&mut socket, "GET", "launch-measurement", None)
+                .map_err(Error::ApiClient)
+        }
+        Some("set-rtc") => set_rtc_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-rtc")
+                .unwrap()
+                .value_of("time")
+                .unwrap(),
+        ),
+        Some("set-battery") => set_battery_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-battery")
+                .unwrap()
+                .value_of("charge")
+                .unwrap(),
+            !matches
+                .subcommand_matches("set-battery")
+                .unwrap()
+                .is_present("ac_offline"),
+        ),
+        Some("set-flow-rules") => set_flow_rules_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-flow-rules")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("set-flow-rules")
+                .unwrap()
+                .values_of("rules")
+                .map(|v| v.collect())
+                .unwrap_or_default(),
+        ),
+        Some("set-net-queues") => set_net_queues_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-net-queues")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("set-net-queues")
+                .unwrap()
+                .value_of("num_queues")
+                .unwrap(),
+        ),
+        Some("inject-nmi") => inject_nmi_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("inject-nmi")
+                .unwrap()
+                .value_of("id"),
+        ),
+        Some("input-event") => input_event_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("input-event")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("input-event")
+                .unwrap()
+                .values_of("events")
+                .unwrap()
+                .collect(),
+        ),
+        Some("set-gpio") => set_gpio_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-gpio")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("set-gpio")
+                .unwrap()
+                .value_of("line")
+                .unwrap(),
+            matches
+                .subcommand_matches("set-gpio")
+                .unwrap()
+                .value_of("value")
+                .unwrap(),
+        ),
+        Some("gpio-lines") => gpio_lines_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("gpio-lines")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+        ),
+        Some("net-capture") => net_capture_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("net-capture")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("net-capture")
+                .unwrap()
+                .value_of("path"),
+        ),
         Some("resize") => resize_api_command(
             &mut socket,
             matches
@@ -295,6 +755,22 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("size")
                 .unwrap(),
         ),
+        Some("add-dimm") => add_dimm_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("add-dimm")
+                .unwrap()
+                .value_of("size")
+                .unwrap(),
+        ),
+        Some("set-memory-target") => set_memory_target_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-memory-target")
+                .unwrap()
+                .value_of("size")
+                .unwrap(),
+        ),
         Some("add-device") => add_device_api_command(
             &mut socket,
             matches
@@ -311,6 +787,38 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("id")
                 .unwrap(),
         ),
+        Some("reset-device") => reset_device_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("reset-device")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+        ),
+        Some("pause-device") => pause_device_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("pause-device")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+        ),
+        Some("resume-device") => resume_device_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("resume-device")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+        ),
+        Some("activate-device") => activate_device_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("activate-device")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+        ),
         Some("add-disk") => add_disk_api_command(
             &mut socket,
             matches
@@ -358,6 +866,10 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("snapshot_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .is_present("devices_only"),
         ),
         Some("restore") => restore_api_command(
             &mut socket,
@@ -374,6 +886,11 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("send_migration_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("send-migration")
+                .unwrap()
+                .is_present("copy_disks"),
+            matches.subcommand_matches("send-migration").unwrap(),
         ),
         Some("receive-migration") => receive_migration_api_command(
             &mut socket,
@@ -410,6 +927,15 @@ fn main() {
                         .help(vmm::config::DeviceConfig::SYNTAX),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("add-dimm")
+                .about("Add a DIMM to the VM")
+                .arg(
+                    Arg::with_name("size")
+                        .index(1)
+                        .help("<dimm_size> (supports K/M/G suffix)"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("add-disk")
                 .about("Add block device")
@@ -460,8 +986,72 @@ fn main() {
                 .about("Remove VFIO device")
                 .arg(Arg::with_name("id").index(1).help("<device_id>")),
         )
+        .subcommand(
+            SubCommand::with_name("reset-device")
+                .about("Reset virtio device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>")),
+        )
+        .subcommand(
+            SubCommand::with_name("pause-device")
+                .about("Pause virtio device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>")),
+        )
+        .subcommand(
+            SubCommand::with_name("resume-device")
+                .about("Resume virtio device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>")),
+        )
+        .subcommand(
+            SubCommand::with_name("activate-device")
+                .about("Activate a cold device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>")),
+        )
         .subcommand(SubCommand::with_name("info").about("Info on the VM"))
+        .subcommand(
+            SubCommand::with_name("inject-nmi")
+                .about("Inject an NMI into a vCPU, or into all of them without one")
+                .arg(Arg::with_name("id").index(1).help("<vcpu_id>")),
+        )
         .subcommand(SubCommand::with_name("counters").about("Counters from the VM"))
+        .subcommand(SubCommand::with_name("get-rtc").about("Time of the guest RTC"))
+        .subcommand(
+            SubCommand::with_name("launch-measurement")
+                .about("SEV launch measurement of the VM, for attestation"),
+        )
+        .subcommand(
+            SubCommand::with_name("input-event")
+                .about("Inject events into a virtio-input device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>"))
+                .arg(
+                    Arg::with_name("events")
+                        .index(2)
+                        .min_values(1)
+                        .help("<type>:<code>:<value>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-gpio")
+                .about("Drive an input line of a virtio-gpio device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>"))
+                .arg(Arg::with_name("line").index(2).help("<line>"))
+                .arg(
+                    Arg::with_name("value")
+                        .index(3)
+                        .possible_values(&["on", "off"])
+                        .help("<on|off>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gpio-lines")
+                .about("State of the lines of a virtio-gpio device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>")),
+        )
+        .subcommand(
+            SubCommand::with_name("net-capture")
+                .about("Capture the frames of a virtio-net device, or stop without a file")
+                .arg(Arg::with_name("id").index(1).help("<device_id>"))
+                .arg(Arg::with_name("path").index(2).help("<pcap_file>")),
+        )
         .subcommand(SubCommand::with_name("pause").about("Pause the VM"))
         .subcommand(SubCommand::with_name("reboot").about("Reboot the VM"))
         .subcommand(SubCommand::with_name("power-button").about("Trigger a power button in the VM"))
@@ -508,7 +1098,65 @@ fn main() {
                         .number_of_values(1),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("set-memory-target")
+                .about("Set the memory available to the guest")
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .help("Memory size in bytes (supports K/M/G suffix)")
+                        .takes_value(true)
+                        .number_of_values(1),
+                ),
+        )
         .subcommand(SubCommand::with_name("resume").about("Resume the VM"))
+        .subcommand(
+            SubCommand::with_name("set-rtc")
+                .about("Set the time of the guest RTC")
+                .arg(
+                    Arg::with_name("time")
+                        .index(1)
+                        .help("<seconds_since_unix_epoch>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-battery")
+                .about("Set the state of the battery and of the AC adapter")
+                .arg(
+                    Arg::with_name("charge")
+                        .long("charge")
+                        .help("Remaining capacity in percent")
+                        .default_value("100")
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("ac_offline")
+                        .long("ac-offline")
+                        .help("Unplug the AC adapter")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-flow-rules")
+                .about(
+                    "Steer the flows to the queues of a virtio-net device, or stop without rules",
+                )
+                .arg(Arg::with_name("id").index(1).help("<device_id>"))
+                .arg(Arg::with_name("rules").index(2).min_values(1).help(
+                    "protocol=tcp|udp,src_ip=<ip>,dst_ip=<ip>,src_port=<port>,dst_port=<port>,\
+                     queue=<queue_pair>",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("set-net-queues")
+                .about("Set the number of queues the backend of a vhost-user-net device processes")
+                .arg(Arg::with_name("id").index(1).help("<device_id>"))
+                .arg(
+                    Arg::with_name("num_queues")
+                        .index(2)
+                        .help("<num_rx_and_tx_queues>"),
+                ),
+        )
         .subcommand(SubCommand::with_name("shutdown").about("Shutdown the VM"))
         .subcommand(
             SubCommand::with_name("snapshot")
@@ -517,6 +1165,11 @@ fn main() {
                     Arg::with_name("snapshot_config")
                         .index(1)
                         .help("<destination_url>"),
+                )
+                .arg(
+                    Arg::with_name("devices_only")
+                        .long("devices-only")
+                        .help("Only snapshot the config and the device state, without the memory"),
                 ),
         )
         .subcommand(
@@ -535,8 +1188,56 @@ fn main() {
                     Arg::with_name("send_migration_config")
                         .index(1)
                         .help("<destination_url>"),
+                )
+                .arg(
+                    Arg::with_name("copy_disks")
+                        .long("copy-disks")
+                        .help("Copy the content of the raw disks, skipping the holes"),
+                )
+                .arg(
+                    Arg::with_name("check_cpuid")
+                        .long("check-cpuid")
+                        .help("Refuse the migration if the destination CPU lacks CPUID features"),
+                )
+                .arg(
+                    Arg::with_name("auto_converge")
+                        .long("auto-converge")
+                        .help("Throttle the vCPUs while the guest dirties memory too fast"),
+                )
+                .arg(
+                    Arg::with_name("throttle_initial")
+                        .long("throttle-initial")
+                        .help("Percentage of the time the vCPUs are first throttled by")
+                        .takes_value(true)
+                        .requires("auto_converge"),
+                )
+                .arg(
+                    Arg::with_name("throttle_increment")
+                        .long("throttle-increment")
+                        .help("Percentage added to the throttling after each pass")
+                        .takes_value(true)
+                        .requires("auto_converge"),
+                )
+                .arg(
+                    Arg::with_name("throttle_max")
+                        .long("throttle-max")
+                        .help("Maximum percentage of the time the vCPUs are throttled by")
+                        .takes_value(true)
+                        .requires("auto_converge"),
+                )
+                .arg(
+                    Arg::with_name("replication_interval")
+                        .long("replication-interval")
+                        .help(
+                            "Keep sending the dirty memory every given milliseconds until failover",
+                        )
+                        .takes_value(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("failover")
+                .about("Complete the migration to the standby being replicated to"),
+        )
         .subcommand(
             SubCommand::with_name("receive-migration")
                 .about("Receive a VM migration")
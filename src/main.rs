@@ -20,6 +20,8 @@ use signal_hook::{
     iterator::{exfiltrator::WithRawSiginfo, SignalsInfo},
 };
 use std::env;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -56,6 +58,44 @@ enum Error {
     ThreadJoin(std::boxed::Box<dyn std::any::Any + std::marker::Send>),
     #[error("VMM thread exited with error: {0}")]
     VmmThread(#[source] vmm::Error),
+    #[error("Failed to load the self-test payload: {0}")]
+    SelfTestPayload(#[source] std::io::Error),
+    #[error("The self-test requires an agent_port on the vsock device")]
+    SelfTestAgentPort,
+    #[error("Error running the self-test: {0:?}")]
+    SelfTest(vmm::selftest::Error),
+    #[error("{0} device(s) failed the self-test")]
+    SelfTestFailed(usize),
+    #[error("Error shutting down VMM: {0:?}")]
+    VmmShutdown(vmm::api::ApiError),
+}
+
+// Kernel with a built-in initramfs running the guest agent and providing
+// the ch-selftest helper, booted by --selftest when no kernel is given.
+#[cfg(feature = "selftest_payload")]
+static SELFTEST_PAYLOAD: &[u8] = include_bytes!(env!("CH_SELFTEST_PAYLOAD"));
+
+#[cfg(feature = "selftest_payload")]
+fn selftest_payload() -> std::io::Result<Option<File>> {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    let name = b"selftest_payload\0";
+    // Safe because the name is a valid C string, and the file descriptor
+    // is checked before being owned by the file.
+    let fd = unsafe { libc::memfd_create(name.as_ptr() as *const libc::c_char, libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(SELFTEST_PAYLOAD)?;
+
+    Ok(Some(file))
+}
+
+#[cfg(not(feature = "selftest_payload"))]
+fn selftest_payload() -> std::io::Result<Option<File>> {
+    Ok(None)
 }
 
 struct Logger {
@@ -137,7 +177,9 @@ fn create_app<'a, 'b>(
                 .help(
                     "boot=<boot_vcpus>,max=<max_vcpus>,\
                     topology=<threads_per_core>:<cores_per_die>:<dies_per_package>:<packages>,\
-                    kvm_hyperv=on|off,max_phys_bits=<maximum_number_of_physical_bits>",
+                    caches=<l1>:<l2>:<l3>,kvm_hyperv=on|off,max_phys_bits=<maximum_number_of_physical_bits>,\
+                    nested=on|off,tsc_deadline=on|off,\
+                    cpuid=<function>[.<index>]@host|zero|<eax>/<ebx>/<ecx>/<edx>:...",
                 )
                 .default_value(&default_vcpus)
                 .group("vm-config"),
@@ -151,7 +193,10 @@ fn create_app<'a, 'b>(
                      hugepages=on|off,hugepage_size=<hugepage_size>\
                      hotplug_method=acpi|virtio-mem,\
                      hotplug_size=<hotpluggable_memory_size>,\
-                     hotplugged_size=<hotplugged_memory_size>\"",
+                     hotplug_base=<hotplug_region_guest_address>,\
+                     hotplugged_size=<hotplugged_memory_size>,\
+                     hotplug_slots=<number_of_dimm_slots>,\
+                     prefault=on|off,locked=on|off\"",
                 )
                 .default_value(&default_memory)
                 .group("vm-config"),
@@ -216,7 +261,8 @@ fn create_app<'a, 'b>(
             Arg::with_name("rng")
                 .long("rng")
                 .help(
-                    "Random number generator parameters \"src=<entropy_source_path>,iommu=on|off\"",
+                    "Random number generator parameters \"src=<entropy_source_path>,iommu=on|off,transport=pci|mmio,\
+                    seed=<seed_file_path>,seed_mode=consume|replay,quality=on|off\"",
                 )
                 .default_value(&default_rng)
                 .group("vm-config"),
@@ -228,6 +274,41 @@ fn create_app<'a, 'b>(
                 .takes_value(true)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("cgroup")
+                .long("cgroup")
+                .help(config::CgroupConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("oom-policy")
+                .long("oom-policy")
+                .help(config::OomPolicyConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("on-triple-fault")
+                .long("on-triple-fault")
+                .help("Action when the guest triple faults \"reboot|shutdown|pause\"")
+                .default_value("reboot")
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("on-crash")
+                .long("on-crash")
+                .help(config::CrashAction::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("platform")
+                .long("platform")
+                .help(config::PlatformConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("fs")
                 .long("fs")
@@ -247,7 +328,7 @@ fn create_app<'a, 'b>(
         .arg(
             Arg::with_name("serial")
                 .long("serial")
-                .help("Control serial port: off|null|pty|tty|file=/path/to/a/file")
+                .help("Control serial port: off|null|pty|tty|file=/path/to/a/file|tcp=<address>:<port>")
                 .default_value("null")
                 .group("vm-config"),
         )
@@ -255,11 +336,18 @@ fn create_app<'a, 'b>(
             Arg::with_name("console")
                 .long("console")
                 .help(
-                    "Control (virtio) console: \"off|null|pty|tty|file=/path/to/a/file,iommu=on|off\"",
+                    "Control (virtio) console: \"off|null|pty|tty|file=/path/to/a/file|tcp=<address>:<port>,iommu=on|off\"",
                 )
                 .default_value("tty")
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("crash-dump")
+                .long("crash-dump")
+                .help(config::CrashDumpConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("device")
                 .long("device")
@@ -268,6 +356,30 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("user-device")
+                .long("user-device")
+                .help(config::UserDeviceConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("input")
+                .long("input")
+                .help(config::InputConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("gpio")
+                .long("gpio")
+                .help(config::GpioConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("vsock")
                 .long("vsock")
@@ -291,6 +403,50 @@ fn create_app<'a, 'b>(
                 .takes_value(false)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("battery")
+                .long("battery")
+                .help("Enable an ACPI battery and AC adapter")
+                .takes_value(false)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("tpm")
+                .long("tpm")
+                .help(config::TpmConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("acpi-table")
+                .long("acpi-table")
+                .help(config::AcpiTableConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("access-log")
+                .long("access-log")
+                .help(
+                    "Identifiers of the devices whose register accesses are logged, \
+                     requires the access_log build feature",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("selftest")
+                .long("selftest")
+                .help(
+                    "Boot the VM with the self-test payload, check each device from the guest \
+                     and exit, reporting which ones failed",
+                )
+                .takes_value(false)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -306,6 +462,24 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("logging"),
         )
+        .arg(
+            Arg::with_name("event-monitor")
+                .long("event-monitor")
+                .help("File to report the lifecycle events of the VMM to")
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("guest-log-limit")
+                .long("guest-log-limit")
+                .help(
+                    "Messages each site logging on behalf of the guest can log per second, \
+                     0 lifting the limit",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("logging"),
+        )
         .arg(
             Arg::with_name("api-socket")
                 .long("api-socket")
@@ -341,6 +515,13 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vm-config"),
         );
+        app = app.arg(
+            Arg::with_name("sev")
+                .long("sev")
+                .help(config::SevConfig::SYNTAX)
+                .takes_value(true)
+                .group("vm-config"),
+        );
     }
 
     app
@@ -401,12 +582,41 @@ fn start_vmm(cmd_arguments: ArgMatches, api_socket_path: &str) -> Result<(), Err
     )
     .map_err(Error::StartVMMThread)?;
 
+    let selftest = cmd_arguments.is_present("selftest");
+    // The embedded payload is booted when no kernel is given, and the agent
+    // reached through a vsock device added for the occasion if needed.
+    let payload = if selftest && !cmd_arguments.is_present("kernel") {
+        selftest_payload().map_err(Error::SelfTestPayload)?
+    } else {
+        None
+    };
+    let payload_path = payload
+        .as_ref()
+        .map(|p| format!("/proc/self/fd/{}", p.as_raw_fd()));
+    let selftest_vsock_socket = format!("{}.vsock", api_socket_path);
+    let selftest_vsock = format!(
+        "cid=3,socket={},agent_port={}",
+        selftest_vsock_socket,
+        vmm::selftest::SELFTEST_AGENT_PORT
+    );
+
     // Can't test for "vm-config" group as some have default values. The kernel
     // is the only required option for booting the VM.
-    if cmd_arguments.is_present("kernel") {
-        let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
+    if cmd_arguments.is_present("kernel") || selftest {
+        let mut vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
+        if payload_path.is_some() {
+            vm_params.kernel = payload_path.as_deref();
+        }
+        let add_vsock = selftest && vm_params.vsock.is_none();
+        if add_vsock {
+            vm_params.vsock = Some(selftest_vsock.as_str());
+        }
         let vm_config = config::VmConfig::parse(vm_params).map_err(Error::ParsingConfig)?;
 
+        if selftest && !matches!(&vm_config.vsock, Some(v) if v.agent_port.is_some()) {
+            return Err(Error::SelfTestAgentPort);
+        }
+
         println!(
             "Cloud Hypervisor Guest\n\tAPI server: {}\n\tvCPUs: {}\n\tMemory: {} MB\n\tKernel: \
              {:?}\n\tInitramfs: {:?}\n\tKernel cmdline: {}\n\tDisk(s): {:?}",
@@ -420,14 +630,46 @@ fn start_vmm(cmd_arguments: ArgMatches, api_socket_path: &str) -> Result<(), Err
         );
 
         // Create and boot the VM based off the VM config we just built.
-        let sender = api_request_sender.clone();
+        let vm_config = Arc::new(Mutex::new(vm_config));
         vmm::api::vm_create(
             api_evt.try_clone().unwrap(),
-            api_request_sender,
-            Arc::new(Mutex::new(vm_config)),
+            api_request_sender.clone(),
+            vm_config.clone(),
         )
         .map_err(Error::VmCreate)?;
-        vmm::api::vm_boot(api_evt.try_clone().unwrap(), sender).map_err(Error::VmBoot)?;
+        vmm::api::vm_boot(api_evt.try_clone().unwrap(), api_request_sender.clone())
+            .map_err(Error::VmBoot)?;
+
+        if selftest {
+            // The devices are named once the VM is booted.
+            let checks = vmm::selftest::checks(&vm_config.lock().unwrap());
+            let results = vmm::selftest::run(&api_evt, &api_request_sender, checks);
+
+            vmm::api::vmm_shutdown(api_evt.try_clone().unwrap(), api_request_sender)
+                .map_err(Error::VmmShutdown)?;
+            vmm_thread
+                .join()
+                .map_err(Error::ThreadJoin)?
+                .map_err(Error::VmmThread)?;
+            if add_vsock {
+                std::fs::remove_file(&selftest_vsock_socket).ok();
+            }
+
+            let results = results.map_err(Error::SelfTest)?;
+            println!("Self-test results:");
+            for result in results.iter() {
+                println!("\t{}: {}", result.device, result.outcome);
+            }
+            let failed = results
+                .iter()
+                .filter(|r| matches!(r.outcome, vmm::selftest::Outcome::Fail(_)))
+                .count();
+            if failed > 0 {
+                return Err(Error::SelfTestFailed(failed));
+            }
+
+            return Ok(());
+        }
     } else if let Some(restore_params) = cmd_arguments.value_of("restore") {
         vmm::api::vm_restore(
             api_evt.try_clone().unwrap(),
@@ -496,6 +738,30 @@ fn main() {
     .map(|()| log::set_max_level(log_level))
     .expect("Expected to be able to setup logger");
 
+    if let Some(limit) = cmd_arguments.value_of("guest-log-limit") {
+        match limit.parse() {
+            Ok(limit) => vmm::set_guest_log_limit(limit),
+            Err(e) => {
+                eprintln!("Invalid guest log limit {}: {}", limit, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(file) = cmd_arguments.value_of("event-monitor") {
+        match std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+        {
+            Ok(file) => vmm::event_monitor::set_event_monitor(file),
+            Err(e) => {
+                eprintln!("Error opening event monitor file: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     let api_socket_path = cmd_arguments
         .value_of("api-socket")
         .expect("Missing argument: api-socket")
@@ -520,7 +786,7 @@ mod unit_tests {
     use std::path::PathBuf;
     use vmm::config::{
         CmdlineConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, KernelConfig, MemoryConfig,
-        RngConfig, VmConfig, VmParams,
+        RngConfig, RngSeedMode, TripleFaultAction, VirtioTransportType, VmConfig, VmParams,
     };
 
     fn get_vm_config_from_vec(args: &[&str]) -> VmConfig {
@@ -576,19 +842,27 @@ mod unit_tests {
                     boot_vcpus: 1,
                     max_vcpus: 1,
                     topology: None,
+                    caches: None,
                     kvm_hyperv: false,
                     max_phys_bits: None,
+                    nested: None,
+                    tsc_deadline: None,
+                    cpuid: Vec::new(),
                 },
                 memory: MemoryConfig {
                     size: 536_870_912,
                     mergeable: false,
                     hotplug_method: HotplugMethod::Acpi,
                     hotplug_size: None,
+                    hotplug_base: None,
                     hotplugged_size: None,
+                    hotplug_slots: 8,
                     shared: false,
                     hugepages: false,
                     zones: None,
                     hugepage_size: None,
+                    prefault: false,
+                    locked: false,
                 },
                 kernel: Some(KernelConfig {
                     path: PathBuf::from("/path/to/kernel"),
@@ -602,27 +876,48 @@ mod unit_tests {
                 rng: RngConfig {
                     src: PathBuf::from("/dev/urandom"),
                     iommu: false,
+                    transport: VirtioTransportType::Pci,
+                    seed: None,
+                    seed_mode: RngSeedMode::Replay,
+                    quality: false,
                 },
                 balloon: None,
+                cgroup: None,
+                oom_policy: None,
+                on_triple_fault: TripleFaultAction::Reboot,
+                on_crash: None,
+                platform: None,
                 fs: None,
                 pmem: None,
                 serial: ConsoleConfig {
                     file: None,
                     mode: ConsoleOutputMode::Null,
                     iommu: false,
+                    socket: None,
                 },
                 console: ConsoleConfig {
                     file: None,
                     mode: ConsoleOutputMode::Tty,
                     iommu: false,
+                    socket: None,
                 },
+                crash_dump: None,
                 devices: None,
+                user_devices: None,
                 vsock: None,
+                input: None,
+                gpio: None,
                 iommu: false,
                 #[cfg(target_arch = "x86_64")]
                 sgx_epc: None,
+                #[cfg(target_arch = "x86_64")]
+                sev: None,
                 numa: None,
+                acpi_tables: None,
                 watchdog: false,
+                battery: false,
+                tpm: None,
+                access_log: None,
             };
 
             aver_eq!(tb, expected_vm_config, result_vm_config);
@@ -2263,6 +2263,57 @@ mod tests {
         handle_child_output(r, &output);
     }
 
+    // Throughput of the frames the guest receives from an iperf3 server on
+    // the host, in bits per second.
+    #[cfg(target_arch = "x86_64")]
+    fn measure_net_rx_throughput(trusted_local: bool) -> f64 {
+        let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+        let guest = Guest::new(&mut focal as &mut dyn DiskConfig);
+        let net_params = if trusted_local {
+            format!("{},trusted_local=on", guest.default_net_string())
+        } else {
+            guest.default_net_string()
+        };
+
+        let mut child = GuestCommand::new(&guest)
+            .args(&["--cpus", "boot=2"])
+            .args(&["--memory", "size=1G"])
+            .args(&["--kernel", direct_kernel_boot_path().to_str().unwrap()])
+            .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+            .default_disks()
+            .args(&["--net", net_params.as_str()])
+            .capture_output()
+            .spawn()
+            .unwrap();
+
+        let mut server = std::process::Command::new("iperf3")
+            .args(&["-s", "-1", "-B", &guest.network.host_ip])
+            .spawn()
+            .unwrap();
+
+        let r = std::panic::catch_unwind(|| {
+            guest.wait_vm_boot(None).unwrap();
+
+            let output = guest
+                .ssh_command(&format!("iperf3 -c {} -R -t 10 -J", guest.network.host_ip))
+                .unwrap();
+            let report: serde_json::Value = serde_json::from_str(&output).unwrap();
+            report["end"]["sum_received"]["bits_per_second"]
+                .as_f64()
+                .unwrap()
+        });
+        let throughput = r.as_ref().map_or(0.0, |t| *t);
+
+        let _ = server.kill();
+        server.wait().unwrap();
+        let _ = child.kill();
+        let output = child.wait_with_output().unwrap();
+
+        handle_child_output(r.map(|_| ()), &output);
+
+        throughput
+    }
+
     fn _get_vmm_overhead(pid: u32, guest_memory_size: u32) -> HashMap<String, u32> {
         let smaps = fs::File::open(format!("/proc/{}/smaps", pid)).unwrap();
         let reader = io::BufReader::new(smaps);
@@ -6090,6 +6141,24 @@ mod tests {
         fn test_memory_mergeable_on() {
             test_memory_mergeable(true)
         }
+
+        // Run alone, for the guests not to compete for the host CPUs.
+        #[test]
+        #[cfg(target_arch = "x86_64")]
+        fn test_net_trusted_local_throughput() {
+            let throughput = measure_net_rx_throughput(false);
+            let trusted_throughput = measure_net_rx_throughput(true);
+
+            println!(
+                "RX throughput {:.0} bits/s, {:.0} bits/s with trusted_local=on ({:+.1}%)",
+                throughput,
+                trusted_throughput,
+                (trusted_throughput / throughput - 1.0) * 100.0
+            );
+            // The gain depends on the host, the measurement being noisy
+            // enough for only a loss to be worth failing on.
+            assert!(trusted_throughput > throughput * 0.95);
+        }
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -0,0 +1,824 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Client side implementation of the vfio-user protocol.
+//!
+//! vfio-user lets a PCI device be emulated by an external process. The VMM
+//! talks to that process over a UNIX socket, using messages modelled after
+//! the VFIO ioctls: the device is described in terms of regions and IRQs,
+//! regions can be read and written, guest memory is shared with the device
+//! through DMA map requests and interrupts are delivered through eventfds.
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+
+use std::io::{self, Read, Write};
+use std::num::Wrapping;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use thiserror::Error;
+use vm_memory::ByteValued;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+/// Errors associated with the vfio-user client.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Error connecting to the vfio-user server: {0}")]
+    Connect(#[source] io::Error),
+    #[error("Error serializing capabilities: {0}")]
+    SerializeCapabilites(#[source] serde_json::Error),
+    #[error("Error deserializing capabilities: {0}")]
+    DeserializeCapabilites(#[source] serde_json::Error),
+    #[error("Error writing to the socket: {0}")]
+    SocketWrite(#[source] vmm_sys_util::errno::Error),
+    #[error("Error reading from the socket: {0}")]
+    SocketRead(#[source] io::Error),
+    #[error("Error receiving from the socket: {0}")]
+    SocketReceive(#[source] vmm_sys_util::errno::Error),
+    #[error("Short read from the socket: expected {0} bytes, got {1}")]
+    ShortRead(usize, usize),
+    #[error("Unsupported vfio-user server version: {0}.{1}")]
+    UnsupportedVersion(u16, u16),
+    #[error("Unexpected reply for command {0:?}: {1:?}")]
+    UnexpectedReply(Command, Header),
+    #[error("Error {1} returned by the server for command {0:?}")]
+    Server(Command, u32),
+    #[error("Invalid region index: {0}")]
+    InvalidRegion(u32),
+    #[error("Data transfer too large: {0} bytes")]
+    DataTransferTooLarge(usize),
+    #[error("Message from the server too large: {0} bytes")]
+    MessageTooLarge(usize),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Commands defined by the vfio-user protocol.
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Unknown = 0,
+    Version = 1,
+    DmaMap = 2,
+    DmaUnmap = 3,
+    DeviceGetInfo = 4,
+    DeviceGetRegionInfo = 5,
+    GetRegionIoFds = 6,
+    DeviceGetIrqInfo = 7,
+    DeviceSetIrqs = 8,
+    RegionRead = 9,
+    RegionWrite = 10,
+    DmaRead = 11,
+    DmaWrite = 12,
+    DeviceReset = 13,
+    UserDirtyPages = 14,
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Command::Unknown
+    }
+}
+
+// Protocol version implemented by this client.
+const VFIO_USER_MAJOR_VERSION: u16 = 0;
+const VFIO_USER_MINOR_VERSION: u16 = 1;
+
+// Message type, stored in the lower 4 bits of the header flags.
+const VFIO_USER_FLAGS_TYPE_MASK: u32 = 0xf;
+const VFIO_USER_FLAGS_TYPE_COMMAND: u32 = 0x0;
+const VFIO_USER_FLAGS_TYPE_REPLY: u32 = 0x1;
+// The reply carries an error code.
+const VFIO_USER_FLAGS_ERROR: u32 = 1 << 5;
+
+// Maximum number of file descriptors sent with a single message.
+const MAX_MSG_FDS: u32 = 16;
+// Maximum size of the data carried by a single region read or write.
+const MAX_DATA_XFER_SIZE: u32 = 1024 * 1024;
+// Maximum size of a message received from the server: the largest data
+// transfer, along with room for the fixed size part of the reply.
+const MAX_MESSAGE_SIZE: usize = MAX_DATA_XFER_SIZE as usize + 4096;
+
+/// DMA region can be read by the device.
+pub const VFIO_USER_DMA_MAP_FLAG_READ: u32 = 1 << 0;
+/// DMA region can be written by the device.
+pub const VFIO_USER_DMA_MAP_FLAG_WRITE: u32 = 1 << 1;
+
+/// Header common to all vfio-user messages.
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Header {
+    pub message_id: u16,
+    pub command: u16,
+    pub message_size: u32,
+    pub flags: u32,
+    pub error: u32,
+}
+
+// SAFETY: Header only contains plain integers and has no padding.
+unsafe impl ByteValued for Header {}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug)]
+struct Version {
+    header: Header,
+    major: u16,
+    minor: u16,
+}
+
+// SAFETY: Version only contains plain integers and has no padding.
+unsafe impl ByteValued for Version {}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MigrationCapabilities {
+    pgsize: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Capabilities {
+    max_msg_fds: u32,
+    max_data_xfer_size: u32,
+    migration: MigrationCapabilities,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            max_msg_fds: MAX_MSG_FDS,
+            max_data_xfer_size: MAX_DATA_XFER_SIZE,
+            migration: MigrationCapabilities { pgsize: 4096 },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct CapabilitiesData {
+    capabilities: Capabilities,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug)]
+struct DmaMap {
+    header: Header,
+    argsz: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    size: u64,
+}
+
+// SAFETY: DmaMap only contains plain integers and has no padding.
+unsafe impl ByteValued for DmaMap {}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug)]
+struct DmaUnmap {
+    header: Header,
+    argsz: u32,
+    flags: u32,
+    address: u64,
+    size: u64,
+}
+
+// SAFETY: DmaUnmap only contains plain integers and has no padding.
+unsafe impl ByteValued for DmaUnmap {}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug)]
+struct DeviceGetInfo {
+    header: Header,
+    argsz: u32,
+    flags: u32,
+    num_regions: u32,
+    num_irqs: u32,
+}
+
+// SAFETY: DeviceGetInfo only contains plain integers and has no padding.
+unsafe impl ByteValued for DeviceGetInfo {}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug)]
+struct DeviceGetRegionInfo {
+    header: Header,
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    cap_offset: u32,
+    size: u64,
+    offset: u64,
+}
+
+// SAFETY: DeviceGetRegionInfo only contains plain integers and has no padding.
+unsafe impl ByteValued for DeviceGetRegionInfo {}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug)]
+struct RegionAccess {
+    header: Header,
+    offset: u64,
+    region: u32,
+    count: u32,
+}
+
+// SAFETY: RegionAccess only contains plain integers and has no padding.
+unsafe impl ByteValued for RegionAccess {}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug)]
+struct DeviceGetIrqInfo {
+    header: Header,
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    count: u32,
+}
+
+// SAFETY: DeviceGetIrqInfo only contains plain integers and has no padding.
+unsafe impl ByteValued for DeviceGetIrqInfo {}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug)]
+struct DeviceSetIrqs {
+    header: Header,
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    start: u32,
+    count: u32,
+}
+
+// SAFETY: DeviceSetIrqs only contains plain integers and has no padding.
+unsafe impl ByteValued for DeviceSetIrqs {}
+
+/// Description of a region exposed by the vfio-user device.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Region {
+    pub flags: u32,
+    pub index: u32,
+    pub size: u64,
+}
+
+/// Description of an IRQ index exposed by the vfio-user device.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IrqInfo {
+    pub index: u32,
+    pub flags: u32,
+    pub count: u32,
+}
+
+/// vfio-user client, connected to a single device.
+pub struct Client {
+    stream: UnixStream,
+    next_message_id: Wrapping<u16>,
+    num_irqs: u32,
+    resettable: bool,
+    regions: Vec<Region>,
+}
+
+// Set when the device supports VFIO_DEVICE_RESET.
+const VFIO_DEVICE_FLAGS_RESET: u32 = 1 << 0;
+
+impl Client {
+    /// Connect to the vfio-user server listening on `path`, negotiate the
+    /// protocol version and retrieve the description of the device.
+    pub fn new(path: &Path) -> Result<Client> {
+        let stream = UnixStream::connect(path).map_err(Error::Connect)?;
+
+        Self::from_stream(stream)
+    }
+
+    fn from_stream(stream: UnixStream) -> Result<Client> {
+        let mut client = Client {
+            stream,
+            next_message_id: Wrapping(0),
+            num_irqs: 0,
+            resettable: false,
+            regions: Vec::new(),
+        };
+
+        client.negotiate_version()?;
+
+        let (flags, num_regions, num_irqs) = client.get_device_info()?;
+        client.resettable = flags & VFIO_DEVICE_FLAGS_RESET != 0;
+        client.num_irqs = num_irqs;
+
+        for index in 0..num_regions {
+            let region = client.get_region_info(index)?;
+            client.regions.push(region);
+        }
+
+        Ok(client)
+    }
+
+    fn header(&mut self, command: Command, message_size: usize) -> Header {
+        let message_id = self.next_message_id.0;
+        self.next_message_id += Wrapping(1);
+
+        Header {
+            message_id,
+            command: command as u16,
+            message_size: message_size as u32,
+            flags: VFIO_USER_FLAGS_TYPE_COMMAND,
+            error: 0,
+        }
+    }
+
+    fn send(&mut self, bufs: &[&[u8]], fds: &[RawFd]) -> Result<()> {
+        self.stream
+            .send_with_fds(bufs, fds)
+            .map_err(Error::SocketWrite)?;
+
+        Ok(())
+    }
+
+    // Receive the reply to `request`, filling `reply` with the fixed size
+    // part of the message. Any remaining payload is returned separately.
+    fn receive<T: ByteValued>(&mut self, request: &Header, reply: &mut T) -> Result<Vec<u8>> {
+        let reply_slice = reply.as_mut_slice();
+        let header_size = std::mem::size_of::<Header>();
+
+        let (len, _) = self
+            .stream
+            .recv_with_fd(&mut reply_slice[..header_size])
+            .map_err(Error::SocketReceive)?;
+        if len != header_size {
+            return Err(Error::ShortRead(header_size, len));
+        }
+
+        let header = *Header::from_slice(&reply_slice[..header_size]).unwrap();
+        let command = command_from_u16(request.command);
+        if header.message_id != request.message_id
+            || header.command != request.command
+            || header.flags & VFIO_USER_FLAGS_TYPE_MASK != VFIO_USER_FLAGS_TYPE_REPLY
+        {
+            return Err(Error::UnexpectedReply(command, header));
+        }
+
+        if header.flags & VFIO_USER_FLAGS_ERROR != 0 {
+            return Err(Error::Server(command, header.error));
+        }
+
+        let message_size = header.message_size as usize;
+        if message_size < header_size {
+            return Err(Error::UnexpectedReply(command, header));
+        }
+        if message_size > MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooLarge(message_size));
+        }
+
+        // Only read the fixed size part if the server sent it, some replies
+        // are made of the header only.
+        let body_size = std::cmp::min(message_size, reply_slice.len()) - header_size;
+        self.stream
+            .read_exact(&mut reply_slice[header_size..header_size + body_size])
+            .map_err(Error::SocketRead)?;
+
+        let mut payload = vec![0u8; message_size - header_size - body_size];
+        self.stream
+            .read_exact(&mut payload)
+            .map_err(Error::SocketRead)?;
+
+        Ok(payload)
+    }
+
+    fn negotiate_version(&mut self) -> Result<()> {
+        let caps = serde_json::to_string(&CapabilitiesData::default())
+            .map_err(Error::SerializeCapabilites)?;
+        // The capabilities are sent as a NUL terminated string.
+        let mut caps = caps.into_bytes();
+        caps.push(0);
+
+        let version = Version {
+            header: self.header(
+                Command::Version,
+                std::mem::size_of::<Version>() + caps.len(),
+            ),
+            major: VFIO_USER_MAJOR_VERSION,
+            minor: VFIO_USER_MINOR_VERSION,
+        };
+        debug!("Sending version: {:?}", version);
+        self.send(&[version.as_slice(), &caps], &[])?;
+
+        let mut server_version = Version::default();
+        let server_caps = self.receive(&version.header, &mut server_version)?;
+        debug!("Received version: {:?}", server_version);
+
+        if server_version.major != VFIO_USER_MAJOR_VERSION {
+            return Err(Error::UnsupportedVersion(
+                server_version.major,
+                server_version.minor,
+            ));
+        }
+
+        let server_caps = server_caps.split(|b| *b == 0).next().unwrap_or_default();
+        if !server_caps.is_empty() {
+            let server_caps: CapabilitiesData =
+                serde_json::from_slice(server_caps).map_err(Error::DeserializeCapabilites)?;
+            debug!("Received capabilities: {:?}", server_caps);
+        }
+
+        Ok(())
+    }
+
+    fn get_device_info(&mut self) -> Result<(u32, u32, u32)> {
+        let get_info = DeviceGetInfo {
+            header: self.header(Command::DeviceGetInfo, std::mem::size_of::<DeviceGetInfo>()),
+            argsz: (std::mem::size_of::<DeviceGetInfo>() - std::mem::size_of::<Header>()) as u32,
+            ..Default::default()
+        };
+        self.send(&[get_info.as_slice()], &[])?;
+
+        let mut reply = DeviceGetInfo::default();
+        self.receive(&get_info.header, &mut reply)?;
+        debug!("Received device info: {:?}", reply);
+
+        Ok((reply.flags, reply.num_regions, reply.num_irqs))
+    }
+
+    fn get_region_info(&mut self, index: u32) -> Result<Region> {
+        let get_region_info = DeviceGetRegionInfo {
+            header: self.header(
+                Command::DeviceGetRegionInfo,
+                std::mem::size_of::<DeviceGetRegionInfo>(),
+            ),
+            argsz: (std::mem::size_of::<DeviceGetRegionInfo>() - std::mem::size_of::<Header>())
+                as u32,
+            index,
+            ..Default::default()
+        };
+        self.send(&[get_region_info.as_slice()], &[])?;
+
+        let mut reply = DeviceGetRegionInfo::default();
+        // Region capabilities are not supported yet, ignore them.
+        self.receive(&get_region_info.header, &mut reply)?;
+        debug!("Received region info: {:?}", reply);
+
+        Ok(Region {
+            flags: reply.flags,
+            index: reply.index,
+            size: reply.size,
+        })
+    }
+
+    /// Share the guest memory range starting at `address` with the device.
+    /// The memory is backed by `fd` at `offset`.
+    pub fn dma_map(&mut self, offset: u64, address: u64, size: u64, fd: RawFd) -> Result<()> {
+        let dma_map = DmaMap {
+            header: self.header(Command::DmaMap, std::mem::size_of::<DmaMap>()),
+            argsz: (std::mem::size_of::<DmaMap>() - std::mem::size_of::<Header>()) as u32,
+            flags: VFIO_USER_DMA_MAP_FLAG_READ | VFIO_USER_DMA_MAP_FLAG_WRITE,
+            offset,
+            address,
+            size,
+        };
+        debug!("Sending DMA map: {:?}", dma_map);
+        self.send(&[dma_map.as_slice()], &[fd])?;
+
+        let mut reply = Header::default();
+        self.receive(&dma_map.header, &mut reply)?;
+
+        Ok(())
+    }
+
+    /// Stop sharing the guest memory range starting at `address`.
+    pub fn dma_unmap(&mut self, address: u64, size: u64) -> Result<()> {
+        let dma_unmap = DmaUnmap {
+            header: self.header(Command::DmaUnmap, std::mem::size_of::<DmaUnmap>()),
+            argsz: (std::mem::size_of::<DmaUnmap>() - std::mem::size_of::<Header>()) as u32,
+            flags: 0,
+            address,
+            size,
+        };
+        debug!("Sending DMA unmap: {:?}", dma_unmap);
+        self.send(&[dma_unmap.as_slice()], &[])?;
+
+        let mut reply = DmaUnmap::default();
+        self.receive(&dma_unmap.header, &mut reply)?;
+
+        Ok(())
+    }
+
+    /// Read `data.len()` bytes from `region` at `offset`.
+    pub fn region_read(&mut self, region: u32, offset: u64, data: &mut [u8]) -> Result<()> {
+        if data.len() > MAX_DATA_XFER_SIZE as usize {
+            return Err(Error::DataTransferTooLarge(data.len()));
+        }
+
+        let region_read = RegionAccess {
+            header: self.header(Command::RegionRead, std::mem::size_of::<RegionAccess>()),
+            offset,
+            region,
+            count: data.len() as u32,
+        };
+        self.send(&[region_read.as_slice()], &[])?;
+
+        let mut reply = RegionAccess::default();
+        let payload = self.receive(&region_read.header, &mut reply)?;
+        if reply.count as usize != data.len() || payload.len() != data.len() {
+            return Err(Error::UnexpectedReply(Command::RegionRead, reply.header));
+        }
+        data.copy_from_slice(&payload);
+
+        Ok(())
+    }
+
+    /// Write `data` to `region` at `offset`.
+    pub fn region_write(&mut self, region: u32, offset: u64, data: &[u8]) -> Result<()> {
+        if data.len() > MAX_DATA_XFER_SIZE as usize {
+            return Err(Error::DataTransferTooLarge(data.len()));
+        }
+
+        let region_write = RegionAccess {
+            header: self.header(
+                Command::RegionWrite,
+                std::mem::size_of::<RegionAccess>() + data.len(),
+            ),
+            offset,
+            region,
+            count: data.len() as u32,
+        };
+        self.send(&[region_write.as_slice(), data], &[])?;
+
+        let mut reply = RegionAccess::default();
+        self.receive(&region_write.header, &mut reply)?;
+
+        Ok(())
+    }
+
+    /// Retrieve the description of the IRQ `index`.
+    pub fn get_irq_info(&mut self, index: u32) -> Result<IrqInfo> {
+        let get_irq_info = DeviceGetIrqInfo {
+            header: self.header(
+                Command::DeviceGetIrqInfo,
+                std::mem::size_of::<DeviceGetIrqInfo>(),
+            ),
+            argsz: (std::mem::size_of::<DeviceGetIrqInfo>() - std::mem::size_of::<Header>()) as u32,
+            index,
+            ..Default::default()
+        };
+        self.send(&[get_irq_info.as_slice()], &[])?;
+
+        let mut reply = DeviceGetIrqInfo::default();
+        self.receive(&get_irq_info.header, &mut reply)?;
+        debug!("Received IRQ info: {:?}", reply);
+
+        Ok(IrqInfo {
+            index: reply.index,
+            flags: reply.flags,
+            count: reply.count,
+        })
+    }
+
+    /// Configure the IRQs `start..start + count` of the IRQ `index`. The
+    /// eventfds used to signal the interrupts are passed through `fds`.
+    pub fn set_irqs(
+        &mut self,
+        index: u32,
+        flags: u32,
+        start: u32,
+        count: u32,
+        fds: &[RawFd],
+    ) -> Result<()> {
+        let set_irqs = DeviceSetIrqs {
+            header: self.header(Command::DeviceSetIrqs, std::mem::size_of::<DeviceSetIrqs>()),
+            argsz: (std::mem::size_of::<DeviceSetIrqs>() - std::mem::size_of::<Header>()) as u32,
+            flags,
+            index,
+            start,
+            count,
+        };
+        debug!("Sending set IRQs: {:?}", set_irqs);
+        self.send(&[set_irqs.as_slice()], fds)?;
+
+        let mut reply = Header::default();
+        self.receive(&set_irqs.header, &mut reply)?;
+
+        Ok(())
+    }
+
+    /// Reset the device, if supported.
+    pub fn reset(&mut self) -> Result<()> {
+        if !self.resettable {
+            return Ok(());
+        }
+
+        let reset = self.header(Command::DeviceReset, std::mem::size_of::<Header>());
+        self.send(&[reset.as_slice()], &[])?;
+
+        let mut reply = Header::default();
+        self.receive(&reset, &mut reply)?;
+
+        Ok(())
+    }
+
+    /// Regions exposed by the device.
+    pub fn regions(&self) -> Vec<Region> {
+        self.regions.clone()
+    }
+
+    /// Description of the region `index`.
+    pub fn region(&self, index: u32) -> Option<&Region> {
+        self.regions.get(index as usize)
+    }
+
+    /// Number of IRQ indexes exposed by the device.
+    pub fn num_irqs(&self) -> u32 {
+        self.num_irqs
+    }
+
+    /// Close the connection with the server.
+    pub fn shutdown(&mut self) {
+        if let Err(e) = self.stream.flush() {
+            warn!("Failed flushing the vfio-user socket: {}", e);
+        }
+        if let Err(e) = self.stream.shutdown(std::net::Shutdown::Both) {
+            warn!("Failed shutting down the vfio-user socket: {}", e);
+        }
+    }
+}
+
+fn command_from_u16(command: u16) -> Command {
+    match command {
+        1 => Command::Version,
+        2 => Command::DmaMap,
+        3 => Command::DmaUnmap,
+        4 => Command::DeviceGetInfo,
+        5 => Command::DeviceGetRegionInfo,
+        6 => Command::GetRegionIoFds,
+        7 => Command::DeviceGetIrqInfo,
+        8 => Command::DeviceSetIrqs,
+        9 => Command::RegionRead,
+        10 => Command::RegionWrite,
+        11 => Command::DmaRead,
+        12 => Command::DmaWrite,
+        13 => Command::DeviceReset,
+        14 => Command::UserDirtyPages,
+        _ => Command::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const HEADER_SIZE: usize = std::mem::size_of::<Header>();
+
+    // Builds the reply to `request`, made of `body` following the header.
+    fn reply(request: &Header, body: &[u8]) -> Vec<u8> {
+        let header = Header {
+            message_id: request.message_id,
+            command: request.command,
+            message_size: (HEADER_SIZE + body.len()) as u32,
+            flags: VFIO_USER_FLAGS_TYPE_REPLY,
+            error: 0,
+        };
+        let mut reply = header.as_slice().to_vec();
+        reply.extend_from_slice(body);
+        reply
+    }
+
+    // Answers the requests of the client with `handler`, until the client
+    // goes away.
+    fn serve<F>(mut stream: UnixStream, handler: F) -> thread::JoinHandle<()>
+    where
+        F: Fn(&Header, &[u8]) -> Vec<u8> + Send + 'static,
+    {
+        thread::spawn(move || loop {
+            let mut request = Header::default();
+            if stream.read_exact(request.as_mut_slice()).is_err() {
+                return;
+            }
+            let mut body = vec![0u8; request.message_size as usize - HEADER_SIZE];
+            stream.read_exact(&mut body).unwrap();
+            if stream.write_all(&handler(&request, &body)).is_err() {
+                return;
+            }
+        })
+    }
+
+    // Emulates a device with a single region of 4 KiB, filled with its
+    // offset, and two IRQ indexes.
+    fn device(request: &Header, body: &[u8]) -> Vec<u8> {
+        match command_from_u16(request.command) {
+            Command::Version => {
+                let version = Version {
+                    major: VFIO_USER_MAJOR_VERSION,
+                    minor: VFIO_USER_MINOR_VERSION,
+                    ..Default::default()
+                };
+                reply(request, &version.as_slice()[HEADER_SIZE..])
+            }
+            Command::DeviceGetInfo => {
+                let info = DeviceGetInfo {
+                    flags: VFIO_DEVICE_FLAGS_RESET,
+                    num_regions: 1,
+                    num_irqs: 2,
+                    ..Default::default()
+                };
+                reply(request, &info.as_slice()[HEADER_SIZE..])
+            }
+            Command::DeviceGetRegionInfo => {
+                let info = DeviceGetRegionInfo {
+                    index: 0,
+                    size: 0x1000,
+                    ..Default::default()
+                };
+                reply(request, &info.as_slice()[HEADER_SIZE..])
+            }
+            Command::RegionRead => {
+                let access =
+                    *RegionAccess::from_slice(&[&[0u8; HEADER_SIZE][..], body].concat()[..])
+                        .unwrap();
+                let mut body = access.as_slice()[HEADER_SIZE..].to_vec();
+                body.extend((0..access.count).map(|i| (access.offset as u32 + i) as u8));
+                reply(request, &body)
+            }
+            Command::RegionWrite => reply(
+                request,
+                &body[..std::mem::size_of::<RegionAccess>() - HEADER_SIZE],
+            ),
+            _ => reply(request, &[]),
+        }
+    }
+
+    #[test]
+    fn test_client_device() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let server = serve(server_stream, device);
+
+        let mut client = Client::from_stream(client_stream).unwrap();
+        assert_eq!(client.num_irqs(), 2);
+        assert_eq!(client.regions().len(), 1);
+        assert_eq!(client.region(0).unwrap().size, 0x1000);
+        assert!(client.region(1).is_none());
+
+        let mut data = [0u8; 4];
+        client.region_read(0, 0x10, &mut data).unwrap();
+        assert_eq!(data, [0x10, 0x11, 0x12, 0x13]);
+        client.region_write(0, 0x10, &data).unwrap();
+        client.reset().unwrap();
+
+        assert!(matches!(
+            client.region_read(0, 0, &mut vec![0u8; MAX_DATA_XFER_SIZE as usize + 1]),
+            Err(Error::DataTransferTooLarge(_))
+        ));
+
+        client.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_client_invalid_replies() {
+        // The server reports an error.
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let server = serve(server_stream, |request, body| {
+            let mut reply = device(request, body);
+            if command_from_u16(request.command) == Command::DeviceGetInfo {
+                reply[8..12].copy_from_slice(
+                    &(VFIO_USER_FLAGS_TYPE_REPLY | VFIO_USER_FLAGS_ERROR).to_ne_bytes(),
+                );
+                reply[12..16].copy_from_slice(&(libc::EINVAL as u32).to_ne_bytes());
+            }
+            reply
+        });
+        assert!(matches!(
+            Client::from_stream(client_stream),
+            Err(Error::Server(Command::DeviceGetInfo, e)) if e == libc::EINVAL as u32
+        ));
+        server.join().unwrap();
+
+        // The server announces a message larger than any valid one, it is
+        // refused before anything gets allocated for it.
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let server = serve(server_stream, |request, body| {
+            let mut reply = device(request, body);
+            if command_from_u16(request.command) == Command::DeviceGetInfo {
+                reply[4..8].copy_from_slice(&u32::MAX.to_ne_bytes());
+            }
+            reply
+        });
+        assert!(matches!(
+            Client::from_stream(client_stream),
+            Err(Error::MessageTooLarge(size)) if size == u32::MAX as usize
+        ));
+        server.join().unwrap();
+
+        // The reply doesn't match the request.
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let server = serve(server_stream, |request, body| {
+            let mut reply = device(request, body);
+            reply[0..2].copy_from_slice(&request.message_id.wrapping_add(1).to_ne_bytes());
+            reply
+        });
+        assert!(matches!(
+            Client::from_stream(client_stream),
+            Err(Error::UnexpectedReply(Command::Version, _))
+        ));
+        server.join().unwrap();
+    }
+}
@@ -15,6 +15,7 @@ use libc::{self, EFD_NONBLOCK};
 use log::*;
 use net_util::{
     open_tap, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio, Tap, TxVirtio,
+    MAC_ADDR_LEN, VHOST_USER_NET_ANTI_SPOOF_OFFSET,
 };
 use option_parser::{OptionParser, OptionParserError};
 use std::fmt;
@@ -186,7 +187,9 @@ impl VhostUserBackend for VhostUserNetBackend {
     }
 
     fn protocol_features(&self) -> VhostUserProtocolFeatures {
-        VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::REPLY_ACK
+        VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::REPLY_ACK
+            | VhostUserProtocolFeatures::CONFIG
     }
 
     fn set_event_idx(&mut self, _enabled: bool) {}
@@ -253,6 +256,43 @@ impl VhostUserBackend for VhostUserNetBackend {
         Ok(false)
     }
 
+    fn get_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        if offset != VHOST_USER_CONFIG_OFFSET + VHOST_USER_NET_ANTI_SPOOF_OFFSET
+            || size as usize != MAC_ADDR_LEN
+        {
+            return Vec::new();
+        }
+
+        // The MAC address the frames are filtered on, zeroed when they
+        // aren't.
+        match self.threads[0].lock().unwrap().net.tx.anti_spoof {
+            Some(mac) => mac.get_bytes().to_vec(),
+            None => vec![0; MAC_ADDR_LEN],
+        }
+    }
+
+    fn set_config(&mut self, offset: u32, buf: &[u8]) -> VhostUserBackendResult<()> {
+        // The VMM only writes the MAC address of the device, from which the
+        // frames sent by the guest must come, at an offset of its own.
+        if offset != VHOST_USER_CONFIG_OFFSET + VHOST_USER_NET_ANTI_SPOOF_OFFSET
+            || buf.len() != MAC_ADDR_LEN
+        {
+            error!(
+                "Unexpected config write: offset {:x} length {}",
+                offset,
+                buf.len()
+            );
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mac = MacAddr::from_bytes_unchecked(buf);
+        info!("Dropping the frames not sent from MAC address {}", mac);
+        for thread in self.threads.iter() {
+            thread.lock().unwrap().net.tx.anti_spoof = Some(mac);
+        }
+        Ok(())
+    }
+
     fn exit_event(&self, thread_index: usize) -> Option<(EventFd, Option<u16>)> {
         // The exit event is placed after the queues and the tap event, which
         // is event index 3.
@@ -18,7 +18,7 @@ use super::{
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::vm_memory::GuestMemory;
-use crate::{VirtioInterrupt, VirtioInterruptType};
+use crate::{PauseBarrier, VirtioInterrupt, VirtioInterruptType};
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
 use std::io;
@@ -27,18 +27,22 @@ use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
-use std::sync::{Arc, Barrier, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::Instant;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap,
 };
+use vm_migration::protocol::{MemoryRange, MemoryRangeTable};
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 const QUEUE_SIZE: u16 = 128;
 const NUM_QUEUES: usize = 2;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+// The free page hinting queue follows the inflate and deflate queues, the
+// statistics queue not being offered.
+const FREE_PAGE_HINT_QUEUE_INDEX: usize = 2;
 
 // Get resize event.
 const RESIZE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
@@ -46,9 +50,21 @@ const RESIZE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const INFLATE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // New descriptors are pending on the virtio queue.
 const DEFLATE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// New descriptors are pending on the free page hinting queue.
+const FREE_PAGE_HINT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 
 // Size of a PFN in the balloon interface.
-const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
+pub const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
+
+// The guest reports its free pages on request.
+const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u64 = 3;
+// The guest tells the value its free pages are filled with.
+const VIRTIO_BALLOON_F_PAGE_POISON: u64 = 4;
+
+// Command IDs of the free page hinting which aren't requests: the guest
+// must stop reporting, or can reuse the pages it reported.
+const VIRTIO_BALLOON_CMD_ID_STOP: u32 = 0;
+const VIRTIO_BALLOON_CMD_ID_DONE: u32 = 1;
 
 #[derive(Debug)]
 pub enum Error {
@@ -82,10 +98,16 @@ struct VirtioBalloonConfig {
     num_pages: u32,
     // Number of pages we've actually got in balloon.
     actual: u32,
+    // Free page hinting request the guest reports its free pages for.
+    free_page_hint_cmd_id: u32,
+    // Value the guest fills its free pages with.
+    poison_val: u32,
 }
 
 const CONFIG_ACTUAL_OFFSET: u64 = 4;
 const CONFIG_ACTUAL_SIZE: usize = 4;
+const CONFIG_POISON_VAL_OFFSET: u64 = 12;
+const CONFIG_POISON_VAL_SIZE: usize = 4;
 
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioBalloonConfig {}
@@ -140,6 +162,96 @@ impl VirtioBalloonResize {
     }
 }
 
+/// Pages of the guest memory the guest reported as free, one bit per page.
+#[derive(Default)]
+pub struct FreePageBitmap {
+    bits: Vec<u64>,
+}
+
+impl FreePageBitmap {
+    // Sizes the bitmap for the guest memory, no page being free. It's never
+    // resized by the device thread, which isn't allowed to allocate it.
+    fn reset(&mut self, memory_size: u64) {
+        let pages = (memory_size + (1 << VIRTIO_BALLOON_PFN_SHIFT) - 1) >> VIRTIO_BALLOON_PFN_SHIFT;
+        self.bits = vec![0; ((pages + 63) / 64) as usize];
+    }
+
+    // Marks the pages lying entirely within the range as free.
+    fn set(&mut self, gpa: u64, length: u64) {
+        let first = (gpa + (1 << VIRTIO_BALLOON_PFN_SHIFT) - 1) >> VIRTIO_BALLOON_PFN_SHIFT;
+        let end = ((gpa + length) >> VIRTIO_BALLOON_PFN_SHIFT).min(self.bits.len() as u64 * 64);
+        for pfn in first..end {
+            self.bits[(pfn / 64) as usize] |= 1 << (pfn % 64);
+        }
+    }
+
+    /// Whether the page holding the address is free.
+    pub fn is_free(&self, gpa: u64) -> bool {
+        let pfn = gpa >> VIRTIO_BALLOON_PFN_SHIFT;
+        self.bits
+            .get((pfn / 64) as usize)
+            .map_or(false, |bits| bits & (1 << (pfn % 64)) != 0)
+    }
+
+    /// Number of pages reported as free.
+    pub fn free_pages(&self) -> u64 {
+        self.bits.iter().map(|bits| bits.count_ones() as u64).sum()
+    }
+
+    /// Removes the free pages from the ranges of guest memory.
+    pub fn exclude(&self, table: &MemoryRangeTable) -> MemoryRangeTable {
+        let page_size = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+        let mut excluded = MemoryRangeTable::default();
+        for range in table.regions() {
+            let end = range.gpa + range.length;
+            let mut start = None;
+            let mut gpa = range.gpa;
+            while gpa < end {
+                let next = ((gpa >> VIRTIO_BALLOON_PFN_SHIFT) + 1) << VIRTIO_BALLOON_PFN_SHIFT;
+                let next = next.min(end);
+                // Pages only partly covered by the range are kept.
+                if next - gpa == page_size && self.is_free(gpa) {
+                    if let Some(start) = start.take() {
+                        excluded.push(MemoryRange {
+                            gpa: start,
+                            length: gpa - start,
+                        });
+                    }
+                } else if start.is_none() {
+                    start = Some(gpa);
+                }
+                gpa = next;
+            }
+            if let Some(start) = start {
+                excluded.push(MemoryRange {
+                    gpa: start,
+                    length: end - start,
+                });
+            }
+        }
+
+        excluded
+    }
+}
+
+// Progress of the guest reporting its free pages.
+#[derive(Default)]
+struct FreePageReport {
+    // Request being reported, the guest sending its ID before the hints.
+    cmd_id: u32,
+    // The guest is sending the hints of the request.
+    reporting: bool,
+    // The guest sent all the hints of the request.
+    complete: bool,
+    bitmap: FreePageBitmap,
+}
+
+#[derive(Default)]
+struct FreePageHints {
+    report: Mutex<FreePageReport>,
+    complete: Condvar,
+}
+
 struct BalloonEpollHandler {
     config: Arc<Mutex<VirtioBalloonConfig>>,
     resize_receiver: VirtioBalloonResizeReceiver,
@@ -148,6 +260,8 @@ struct BalloonEpollHandler {
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     inflate_queue_evt: EventFd,
     deflate_queue_evt: EventFd,
+    free_page_hint_queue_evt: Option<EventFd>,
+    free_page_hints: Arc<FreePageHints>,
     kill_evt: EventFd,
     pause_evt: EventFd,
 }
@@ -231,15 +345,79 @@ impl BalloonEpollHandler {
         Ok(())
     }
 
+    // The guest sends the ID of the request it reports its free pages for,
+    // followed by the free pages themselves, writable by the device, and
+    // the STOP command ID once it's done.
+    fn process_free_page_hint_queue(&mut self) -> result::Result<(), Error> {
+        let mut used_desc_heads = [0; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.memory();
+        let mut report = self.free_page_hints.report.lock().unwrap();
+        for avail_desc in self.queues[FREE_PAGE_HINT_QUEUE_INDEX].iter(&mem) {
+            used_desc_heads[used_count] = avail_desc.index;
+            used_count += 1;
+
+            if avail_desc.is_write_only() {
+                if !mem.check_range(avail_desc.addr, avail_desc.len as usize) {
+                    error!(
+                        "Free page hint 0x{:x} of {} bytes is not available",
+                        avail_desc.addr.raw_value(),
+                        avail_desc.len
+                    );
+                    return Err(Error::InvalidRequest);
+                }
+                if report.reporting {
+                    report
+                        .bitmap
+                        .set(avail_desc.addr.raw_value(), avail_desc.len as u64);
+                }
+                continue;
+            }
+
+            if (avail_desc.len as usize) < size_of::<u32>() {
+                error!("the command ID size {} is not right", avail_desc.len);
+                return Err(Error::InvalidRequest);
+            }
+            let cmd_id: u32 = mem.read_obj(avail_desc.addr).map_err(Error::GuestMemory)?;
+            if cmd_id == VIRTIO_BALLOON_CMD_ID_STOP {
+                if report.reporting {
+                    report.reporting = false;
+                    report.complete = true;
+                    self.free_page_hints.complete.notify_all();
+                }
+            } else {
+                // The hints of a previous request are ignored.
+                report.reporting = cmd_id == report.cmd_id;
+            }
+        }
+        drop(report);
+
+        let queue = &mut self.queues[FREE_PAGE_HINT_QUEUE_INDEX];
+        for &desc_index in &used_desc_heads[..used_count] {
+            queue.add_used(&mem, desc_index, 0);
+        }
+        if used_count > 0 {
+            self.signal(
+                &VirtioInterruptType::Queue,
+                Some(&self.queues[FREE_PAGE_HINT_QUEUE_INDEX]),
+            )?;
+        }
+
+        Ok(())
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.resize_receiver.evt.as_raw_fd(), RESIZE_EVENT)?;
         helper.add_event(self.inflate_queue_evt.as_raw_fd(), INFLATE_QUEUE_EVENT)?;
         helper.add_event(self.deflate_queue_evt.as_raw_fd(), DEFLATE_QUEUE_EVENT)?;
+        if let Some(evt) = &self.free_page_hint_queue_evt {
+            helper.add_event(evt.as_raw_fd(), FREE_PAGE_HINT_QUEUE_EVENT)?;
+        }
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -297,6 +475,15 @@ impl EpollHelperHandler for BalloonEpollHandler {
                     return true;
                 }
             }
+            FREE_PAGE_HINT_QUEUE_EVENT => {
+                if let Err(e) = self.free_page_hint_queue_evt.as_ref().unwrap().read() {
+                    error!("Failed to get free page hinting queue event: {:?}", e);
+                    return true;
+                } else if let Err(e) = self.process_free_page_hint_queue() {
+                    error!("Failed to signal used free page hinting queue: {:?}", e);
+                    return true;
+                }
+            }
             _ => {
                 error!("Unknown event for virtio-balloon");
                 return true;
@@ -313,13 +500,25 @@ pub struct Balloon {
     id: String,
     resize: VirtioBalloonResize,
     config: Arc<Mutex<VirtioBalloonConfig>>,
+    free_page_hints: Arc<FreePageHints>,
     seccomp_action: SeccompAction,
 }
 
 impl Balloon {
     // Create a new virtio-balloon.
-    pub fn new(id: String, size: u64, seccomp_action: SeccompAction) -> io::Result<Self> {
-        let avail_features = 1u64 << VIRTIO_F_VERSION_1;
+    pub fn new(
+        id: String,
+        size: u64,
+        free_page_hinting: bool,
+        seccomp_action: SeccompAction,
+    ) -> io::Result<Self> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut num_queues = NUM_QUEUES;
+        if free_page_hinting {
+            avail_features |=
+                1u64 << VIRTIO_BALLOON_F_FREE_PAGE_HINT | 1u64 << VIRTIO_BALLOON_F_PAGE_POISON;
+            num_queues += 1;
+        }
 
         let config = VirtioBalloonConfig {
             num_pages: (size >> VIRTIO_BALLOON_PFN_SHIFT) as u32,
@@ -330,14 +529,15 @@ impl Balloon {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::TYPE_BALLOON as u32,
                 avail_features,
-                paused_sync: Some(Arc::new(Barrier::new(2))),
-                queue_sizes: QUEUE_SIZES.to_vec(),
+                paused_sync: Some(Arc::new(PauseBarrier::new(2))),
+                queue_sizes: vec![QUEUE_SIZE; num_queues],
                 min_queues: NUM_QUEUES as u16,
                 ..Default::default()
             },
             id,
             resize: VirtioBalloonResize::new()?,
             config: Arc::new(Mutex::new(config)),
+            free_page_hints: Arc::new(FreePageHints::default()),
             seccomp_action,
         })
     }
@@ -350,6 +550,110 @@ impl Balloon {
     pub fn get_actual(&self) -> u64 {
         (self.config.lock().unwrap().actual as u64) << VIRTIO_BALLOON_PFN_SHIFT
     }
+
+    fn signal_config(&self) -> Result<(), Error> {
+        if let Some(interrupt_cb) = &self.common.interrupt_cb {
+            interrupt_cb
+                .trigger(&VirtioInterruptType::Config, None)
+                .map_err(Error::FailedSignal)?;
+        }
+        Ok(())
+    }
+
+    /// Asks the guest to report its free pages, out of the `memory_size`
+    /// bytes of its memory, which it then holds until they're released.
+    /// Returns false if the guest can't report them, either because it
+    /// doesn't support it or because its free pages are poisoned, their
+    /// content mattering.
+    pub fn request_free_page_hints(&mut self, memory_size: u64) -> Result<bool, Error> {
+        if !self.common.feature_acked(VIRTIO_BALLOON_F_FREE_PAGE_HINT)
+            || self.common.interrupt_cb.is_none()
+        {
+            return Ok(false);
+        }
+
+        {
+            let mut config = self.config.lock().unwrap();
+            if self.common.feature_acked(VIRTIO_BALLOON_F_PAGE_POISON) && config.poison_val != 0 {
+                warn!("The guest poisons its free pages, they can't be skipped");
+                return Ok(false);
+            }
+
+            let mut report = self.free_page_hints.report.lock().unwrap();
+            report.cmd_id = report
+                .cmd_id
+                .wrapping_add(1)
+                .max(VIRTIO_BALLOON_CMD_ID_DONE + 1);
+            report.reporting = false;
+            report.complete = false;
+            report.bitmap.reset(memory_size);
+            config.free_page_hint_cmd_id = report.cmd_id;
+        }
+        self.signal_config()?;
+
+        Ok(true)
+    }
+
+    /// Waits for the guest to report all its free pages, or for the
+    /// deadline to expire in which case false is returned. The pages
+    /// reported meanwhile are still free.
+    pub fn wait_free_page_hints(&self, deadline: Instant) -> bool {
+        let mut report = self.free_page_hints.report.lock().unwrap();
+        while !report.complete {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            report = self
+                .free_page_hints
+                .complete
+                .wait_timeout(report, deadline - now)
+                .unwrap()
+                .0;
+        }
+
+        true
+    }
+
+    /// Removes the pages the guest reported as free from the ranges of
+    /// guest memory.
+    pub fn exclude_free_pages(&self, table: &MemoryRangeTable) -> MemoryRangeTable {
+        self.free_page_hints
+            .report
+            .lock()
+            .unwrap()
+            .bitmap
+            .exclude(table)
+    }
+
+    /// Number of pages the guest reported as free.
+    pub fn free_pages(&self) -> u64 {
+        self.free_page_hints
+            .report
+            .lock()
+            .unwrap()
+            .bitmap
+            .free_pages()
+    }
+
+    /// Lets the guest reuse the pages it reported as free, their content
+    /// mattering again.
+    pub fn release_free_page_hints(&mut self) -> Result<(), Error> {
+        {
+            let mut config = self.config.lock().unwrap();
+            if config.free_page_hint_cmd_id <= VIRTIO_BALLOON_CMD_ID_DONE {
+                return Ok(());
+            }
+            config.free_page_hint_cmd_id = VIRTIO_BALLOON_CMD_ID_DONE;
+
+            let mut report = self.free_page_hints.report.lock().unwrap();
+            report.reporting = false;
+            report.complete = false;
+            report.bitmap = FreePageBitmap::default();
+        }
+
+        self.signal_config()
+    }
 }
 
 impl Drop for Balloon {
@@ -383,8 +687,10 @@ impl VirtioDevice for Balloon {
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        // The "actual" field is the only mutable field
-        if offset != CONFIG_ACTUAL_OFFSET || data.len() != CONFIG_ACTUAL_SIZE {
+        // The "actual" and "poison_val" fields are the only mutable fields
+        if !(offset == CONFIG_ACTUAL_OFFSET && data.len() == CONFIG_ACTUAL_SIZE
+            || offset == CONFIG_POISON_VAL_OFFSET && data.len() == CONFIG_POISON_VAL_SIZE)
+        {
             error!(
                 "Attempt to write to read-only field: offset {:x} length {}",
                 offset,
@@ -425,6 +731,14 @@ impl VirtioDevice for Balloon {
                 ActivateError::BadActivate
             })?;
 
+        let free_page_hint_queue_evt = if self.common.feature_acked(VIRTIO_BALLOON_F_FREE_PAGE_HINT)
+            && queue_evts.len() > FREE_PAGE_HINT_QUEUE_INDEX
+        {
+            Some(queue_evts.remove(FREE_PAGE_HINT_QUEUE_INDEX))
+        } else {
+            None
+        };
+
         let mut handler = BalloonEpollHandler {
             config: self.config.clone(),
             resize_receiver: self.resize.get_receiver().map_err(|e| {
@@ -436,6 +750,8 @@ impl VirtioDevice for Balloon {
             interrupt_cb,
             inflate_queue_evt: queue_evts.remove(0),
             deflate_queue_evt: queue_evts.remove(0),
+            free_page_hint_queue_evt,
+            free_page_hints: self.free_page_hints.clone(),
             kill_evt,
             pause_evt,
         };
@@ -475,6 +791,10 @@ impl Pausable for Balloon {
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         self.common.resume()
     }
@@ -487,3 +807,206 @@ impl Snapshottable for Balloon {
 }
 impl Transportable for Balloon {}
 impl Migratable for Balloon {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vsock::tests::NoopVirtioInterrupt;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
+
+    #[test]
+    fn test_free_page_bitmap() {
+        let mut bitmap = FreePageBitmap::default();
+        bitmap.reset(0x10_0000);
+
+        // Only the pages entirely within a hint are free.
+        bitmap.set(0x2000, 0x2000);
+        bitmap.set(0x8800, 0x2000);
+        // Hints beyond the memory are ignored.
+        bitmap.set(0xf_f000, 0x2000);
+        assert!(!bitmap.is_free(0x1000));
+        assert!(bitmap.is_free(0x2000));
+        assert!(bitmap.is_free(0x3fff));
+        assert!(!bitmap.is_free(0x8000));
+        assert!(bitmap.is_free(0x9000));
+        assert!(!bitmap.is_free(0xa000));
+        assert!(bitmap.is_free(0xf_f000));
+        assert!(!bitmap.is_free(0x10_0000));
+        assert_eq!(bitmap.free_pages(), 4);
+
+        let mut table = MemoryRangeTable::default();
+        table.push(MemoryRange {
+            gpa: 0,
+            length: 0x8000,
+        });
+        table.push(MemoryRange {
+            gpa: 0x9000,
+            length: 0x1000,
+        });
+        let excluded = bitmap.exclude(&table);
+        let ranges: Vec<(u64, u64)> = excluded
+            .regions()
+            .iter()
+            .map(|range| (range.gpa, range.length))
+            .collect();
+        assert_eq!(ranges, vec![(0, 0x2000), (0x4000, 0x4000)]);
+    }
+
+    // A balloon whose driver negotiated the free page hinting, along with
+    // the handler of its queues.
+    fn free_page_hinting_balloon(
+        mem: &GuestMemoryMmap,
+        queues: &[GuestQ],
+    ) -> (Balloon, BalloonEpollHandler) {
+        let mut balloon =
+            Balloon::new("_balloon".to_owned(), 0, true, SeccompAction::Trap).unwrap();
+        let interrupt_cb: Arc<dyn VirtioInterrupt> = Arc::new(NoopVirtioInterrupt {});
+        balloon.common.acked_features = balloon.common.avail_features;
+        balloon.common.interrupt_cb = Some(interrupt_cb.clone());
+
+        let handler = BalloonEpollHandler {
+            config: balloon.config.clone(),
+            resize_receiver: balloon.resize.get_receiver().unwrap(),
+            queues: queues.iter().map(|q| q.create_queue()).collect(),
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb,
+            inflate_queue_evt: EventFd::new(0).unwrap(),
+            deflate_queue_evt: EventFd::new(0).unwrap(),
+            free_page_hint_queue_evt: Some(EventFd::new(0).unwrap()),
+            free_page_hints: balloon.free_page_hints.clone(),
+            kill_evt: EventFd::new(0).unwrap(),
+            pause_evt: EventFd::new(0).unwrap(),
+        };
+
+        (balloon, handler)
+    }
+
+    // Queues the given command IDs and hints, as (address, length), on the
+    // free page hinting queue.
+    fn push_free_page_hints(
+        queue: &GuestQ,
+        mem: &GuestMemoryMmap,
+        start: u16,
+        entries: &[(Option<u32>, u64, u32)],
+    ) {
+        for (i, (cmd_id, addr, len)) in entries.iter().enumerate() {
+            let index = start + i as u16;
+            let flags = match cmd_id {
+                Some(cmd_id) => {
+                    mem.write_obj(*cmd_id, GuestAddress(*addr)).unwrap();
+                    0
+                }
+                None => VIRTQ_DESC_F_WRITE,
+            };
+            queue.dtable[index as usize].set(*addr, *len, flags, 0);
+            queue.avail.ring[index as usize].set(index);
+            queue.avail.idx.set(index + 1);
+        }
+    }
+
+    #[test]
+    fn test_free_page_hints() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let queues = [
+            GuestQ::new(GuestAddress(0x1_0000), &mem, 16),
+            GuestQ::new(GuestAddress(0x2_0000), &mem, 16),
+            GuestQ::new(GuestAddress(0x3_0000), &mem, 16),
+        ];
+        let (mut balloon, mut handler) = free_page_hinting_balloon(&mem, &queues);
+        let queue = &queues[FREE_PAGE_HINT_QUEUE_INDEX];
+
+        // The guest is given the ID of the request through the configuration.
+        assert!(balloon.request_free_page_hints(0x10_0000).unwrap());
+        let cmd_id = balloon.config.lock().unwrap().free_page_hint_cmd_id;
+        assert!(cmd_id > VIRTIO_BALLOON_CMD_ID_DONE);
+
+        // The hints sent for a previous request are ignored, unlike the
+        // ones following the ID of the current request.
+        push_free_page_hints(
+            queue,
+            &mem,
+            0,
+            &[
+                (Some(cmd_id - 1), 0x8000, 4),
+                (None, 0x40000, 0x2000),
+                (Some(cmd_id), 0x8010, 4),
+                (None, 0x50000, 0x3000),
+            ],
+        );
+        handler.process_free_page_hint_queue().unwrap();
+        assert_eq!(queue.used.idx.get(), 4);
+        assert_eq!(balloon.free_pages(), 3);
+
+        // The report is only complete once the guest sends STOP.
+        assert!(!balloon.wait_free_page_hints(Instant::now()));
+        push_free_page_hints(
+            queue,
+            &mem,
+            4,
+            &[
+                (None, 0x60000, 0x1000),
+                (Some(VIRTIO_BALLOON_CMD_ID_STOP), 0x8020, 4),
+            ],
+        );
+        handler.process_free_page_hint_queue().unwrap();
+        assert!(balloon.wait_free_page_hints(Instant::now()));
+        assert_eq!(balloon.free_pages(), 4);
+
+        let mut table = MemoryRangeTable::default();
+        table.push(MemoryRange {
+            gpa: 0x40000,
+            length: 0x30000,
+        });
+        let ranges: Vec<(u64, u64)> = balloon
+            .exclude_free_pages(&table)
+            .regions()
+            .iter()
+            .map(|range| (range.gpa, range.length))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![(0x40000, 0x10000), (0x53000, 0xd000), (0x61000, 0xf000)]
+        );
+
+        // Releasing the hints lets the guest reuse the pages, only once.
+        balloon.release_free_page_hints().unwrap();
+        assert_eq!(
+            balloon.config.lock().unwrap().free_page_hint_cmd_id,
+            VIRTIO_BALLOON_CMD_ID_DONE
+        );
+        assert_eq!(balloon.free_pages(), 0);
+        assert!(!balloon.wait_free_page_hints(Instant::now()));
+        balloon.release_free_page_hints().unwrap();
+
+        // The next request gets a new ID.
+        assert!(balloon.request_free_page_hints(0x10_0000).unwrap());
+        assert!(balloon.config.lock().unwrap().free_page_hint_cmd_id > cmd_id);
+    }
+
+    #[test]
+    fn test_free_page_hints_unavailable() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let queues = [
+            GuestQ::new(GuestAddress(0x1_0000), &mem, 16),
+            GuestQ::new(GuestAddress(0x2_0000), &mem, 16),
+            GuestQ::new(GuestAddress(0x3_0000), &mem, 16),
+        ];
+
+        // The guest poisoning its free pages isn't asked for them.
+        let (mut balloon, _handler) = free_page_hinting_balloon(&mem, &queues);
+        balloon.config.lock().unwrap().poison_val = 0xaa;
+        assert!(!balloon.request_free_page_hints(0x10_0000).unwrap());
+        balloon.config.lock().unwrap().poison_val = 0;
+        assert!(balloon.request_free_page_hints(0x10_0000).unwrap());
+
+        // Nor is the guest which didn't negotiate the feature.
+        let (mut balloon, _handler) = free_page_hinting_balloon(&mem, &queues);
+        balloon.common.acked_features &= !(1u64 << VIRTIO_BALLOON_F_FREE_PAGE_HINT);
+        assert!(!balloon.request_free_page_hints(0x10_0000).unwrap());
+        assert_eq!(
+            balloon.config.lock().unwrap().free_page_hint_cmd_id,
+            VIRTIO_BALLOON_CMD_ID_STOP
+        );
+    }
+}
@@ -10,26 +10,33 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler,
+    InflightLimit, InflightTracker, NotificationCounters, NotificationSuppression,
+    NotificationThrottle, Queue, VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
+    EPOLL_HELPER_EVENT_LAST,
 };
+use crate::notification::nonblocking_timer;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::VirtioInterrupt;
+use crate::{PauseBarrier, VirtioInterrupt};
 use anyhow::anyhow;
 use block_util::{
     async_io::AsyncIo, async_io::AsyncIoError, async_io::DiskFile, build_disk_image_id, Request,
-    RequestType, VirtioBlockConfig,
+    RequestType, VirtioBlockConfig, MAX_WRITE_ZEROES_SECTORS,
 };
 use seccomp::{SeccompAction, SeccompFilter};
+use std::cmp;
 use std::collections::HashMap;
+use std::fs::File;
 use std::io;
 use std::num::Wrapping;
-use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
 use std::result;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 use virtio_bindings::bindings::virtio_blk::*;
 use vm_memory::{
     ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError,
@@ -37,17 +44,57 @@ use vm_memory::{
 };
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    Transportable, VersionedState,
 };
+use vmm_sys_util::errno;
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
 
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = 0x01 << SECTOR_SHIFT;
 
-// New descriptors are pending on the virtio queue.
-const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 // New completed tasks are pending on the completion ring.
-const COMPLETION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+const COMPLETION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// The requests failed with a transient error are due for a retry.
+const RETRY_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// Descriptor chains were released after the limit of the ones in flight was
+// reached.
+const INFLIGHT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// Requests completed after the queue depth of the device was reached.
+const QUEUE_DEPTH_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+// The events of the queues served by a worker start at QUEUE_EVENTS_BASE,
+// each queue owning QUEUE_EVENTS_PER_QUEUE consecutive ones.
+const QUEUE_EVENTS_BASE: u16 = EPOLL_HELPER_EVENT_LAST + 5;
+const QUEUE_EVENTS_PER_QUEUE: u16 = 2;
+// New descriptors are pending on the virtio queue.
+const QUEUE_AVAIL_EVENT: u16 = 0;
+// The used entries held back by the notification suppression are due.
+const NOTIFICATION_TIMER_EVENT: u16 = 1;
+
+// Number of requests a queue submits per unit of weight, before the worker
+// moves on to the next of its queues.
+const SCHEDULER_QUANTUM: usize = 8;
+
+/// Largest relative share of the requests of a worker a queue can be given.
+pub const MAX_QUEUE_WEIGHT: u32 = 64;
+
+// Longest time a request waits before being retried, however many times it
+// already failed.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+// Host I/O schedulers telling the I/O of the threads apart by their priority.
+const IOPRIO_SCHEDULERS: [&str; 3] = ["bfq", "cfq", "mq-deadline"];
+// The I/O priority is set for the calling thread.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+// The user data of the asynchronous operations holds the descriptor head of
+// the request in its lower bits, and the queue of the worker it came from
+// above them.
+const USER_DATA_QUEUE_SHIFT: u32 = 16;
+
+fn user_data(queue_index: usize, desc_index: u16) -> u64 {
+    ((queue_index as u64) << USER_DATA_QUEUE_SHIFT) | u64::from(desc_index)
+}
 
 #[derive(Debug)]
 pub enum Error {
@@ -79,6 +126,12 @@ pub enum Error {
     AsyncRequestFailure,
     /// Failed synchronizing the file
     Fsync(AsyncIoError),
+    /// Failed waiting for the requests in flight to complete.
+    Drain(io::Error),
+    /// The requests in flight didn't complete by the deadline of the pause.
+    DrainTimeout,
+    /// Failed to arm or read the timer of the retries.
+    RetryTimer(errno::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -89,10 +142,121 @@ pub struct BlockCounters {
     read_ops: Arc<AtomicU64>,
     write_bytes: Arc<AtomicU64>,
     write_ops: Arc<AtomicU64>,
+    // Requests submitted again after a transient error.
+    retried_ops: Arc<AtomicU64>,
 }
 
-struct BlockEpollHandler {
+/// Counters of a queue, telling how the worker serving it shares the disk
+/// between its queues.
+#[derive(Default, Clone)]
+pub struct QueueCounters {
+    // Requests taken from the queue.
+    requests: Arc<AtomicU64>,
+    // Times the queue used up its share of a round with requests left.
+    preemptions: Arc<AtomicU64>,
+}
+
+/// How the queues are spread over the worker threads of the device.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueScheduling {
+    /// Number of worker threads, queue i being served by worker i modulo
+    /// the number of workers. Zero gives every queue a worker of its own.
+    pub num_workers: usize,
+    /// Relative share of the requests of its worker each queue gets while
+    /// several of them are busy. Empty gives all the queues the same weight.
+    pub weights: Vec<u32>,
+}
+
+/// Retries of the requests failing with an error the backing storage can
+/// recover from, such as the EIO of a networked storage failing over. The
+/// n-th retry of a request happens `backoff_ms` milliseconds after its
+/// failure, doubled n - 1 times, the error being reported to the guest once
+/// the request failed `max_retries` more times. Disabled by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IoRetry {
+    pub max_retries: u32,
+    pub backoff_ms: u64,
+}
+
+impl IoRetry {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff_ms = self.backoff_ms.saturating_mul(1 << attempt.min(32));
+        Duration::from_millis(backoff_ms).min(MAX_RETRY_BACKOFF)
+    }
+}
+
+// Errors that can go away by themselves, unlike the ones such as ENOSPC on
+// a full thin pool, pointless to retry.
+fn is_transient(errno: i32) -> bool {
+    matches!(
+        errno,
+        libc::EIO | libc::EAGAIN | libc::EINTR | libc::EBUSY | libc::ETIMEDOUT
+    )
+}
+
+// Requests the flushes must wait for.
+fn is_write(request_type: RequestType) -> bool {
+    matches!(request_type, RequestType::Out | RequestType::WriteZeroes)
+}
+
+// The active scheduler is the one between brackets, e.g. "mq-deadline [bfq] none".
+fn active_io_scheduler(schedulers: &str) -> Option<String> {
+    schedulers
+        .split_whitespace()
+        .find_map(|s| s.strip_prefix('[')?.strip_suffix(']'))
+        .map(String::from)
+}
+
+// Active I/O scheduler of the host block device holding the disk image,
+// going by sysfs. None if the image doesn't sit on a block device, e.g. on
+// a network file system.
+fn host_io_scheduler(disk_path: &Path) -> Option<String> {
+    let metadata = disk_path.metadata().ok()?;
+    let dev = if metadata.file_type().is_block_device() {
+        metadata.rdev()
+    } else {
+        metadata.dev()
+    };
+    let device = PathBuf::from(format!(
+        "/sys/dev/block/{}:{}",
+        libc::major(dev),
+        libc::minor(dev)
+    ));
+
+    // A partition uses the queue of its whole disk.
+    let schedulers = std::fs::read_to_string(device.join("queue/scheduler"))
+        .or_else(|_| std::fs::read_to_string(device.join("../queue/scheduler")))
+        .ok()?;
+    active_io_scheduler(&schedulers)
+}
+
+// A virtio queue served by a worker, along with the requests it has in
+// flight.
+struct BlockQueue {
     queue: Queue,
+    queue_evt: EventFd,
+    weight: u32,
+    counters: QueueCounters,
+    request_list: HashMap<u16, Request>,
+    // Number of write requests submitted but not completed yet.
+    inflight_writes: usize,
+    // Flush request waiting for the writes submitted before it to complete.
+    pending_flush: Option<(u16, Request)>,
+    // Times the requests in flight have been retried.
+    retry_attempts: HashMap<u16, u32>,
+    notification_throttle: NotificationThrottle,
+}
+
+// Request waiting for the time of its next attempt.
+struct PendingRetry {
+    due: Instant,
+    queue_index: usize,
+    desc_index: u16,
+    request: Request,
+}
+
+struct BlockEpollHandler {
+    queues: Vec<BlockQueue>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     disk_image: Box<dyn AsyncIo>,
     disk_nsectors: u64,
@@ -102,57 +266,180 @@ struct BlockEpollHandler {
     pause_evt: EventFd,
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
-    queue_evt: EventFd,
-    request_list: HashMap<u16, Request>,
+    // Queue the next scheduling round starts with, so that no queue is
+    // always served first.
+    next_queue: usize,
+    io_retry: IoRetry,
+    pending_retries: Vec<PendingRetry>,
+    // Expires when the first of the pending retries is due, only created
+    // when the retries are enabled.
+    retry_timer: Option<TimerFd>,
+    // Descriptor chains taken from the queues, until given back to the
+    // guest.
+    inflight: InflightTracker,
+    // Requests of the device in flight, shared by all of its workers.
+    queue_depth: InflightTracker,
 }
 
 impl BlockEpollHandler {
-    fn process_queue_submit(&mut self) -> Result<bool> {
-        let queue = &mut self.queue;
+    // Submits up to `budget` requests of the queue. Returns the number of
+    // entries added to the used ring, and whether the budget was used up,
+    // meaning there can be requests left.
+    fn process_queue_submit(&mut self, queue_index: usize, budget: usize) -> Result<(usize, bool)> {
+        let block_queue = &mut self.queues[queue_index];
+
+        // Nothing is submitted until the pending flush has been issued.
+        if block_queue.pending_flush.is_some() {
+            return Ok((0, false));
+        }
+
+        let queue = &mut block_queue.queue;
         let mem = self.mem.memory();
 
         let mut used_desc_heads = Vec::new();
         let mut used_count = 0;
-
-        for avail_desc in queue.iter(&mem) {
-            let mut request = Request::parse(&avail_desc, &mem).map_err(Error::RequestParsing)?;
+        let mut taken = 0;
+        let mut throttled = false;
+        let mut result = Ok(());
+
+        for avail_desc in queue.iter(&mem).take(budget) {
+            // Past the queue depth of the device, or the limit of the
+            // descriptor chains in flight, the chain is left in the queue
+            // until others are given back.
+            if !self.queue_depth.acquire() {
+                throttled = true;
+                break;
+            }
+            if !self.inflight.acquire() {
+                self.queue_depth.release(1);
+                throttled = true;
+                break;
+            }
+            taken += 1;
+
+            // A chain which can't be submitted gives its slots back, the
+            // limits being shared with the other queues of the device.
+            let mut request = match Request::parse(&avail_desc, &mem) {
+                Ok(request) => request,
+                Err(e) => {
+                    self.inflight.release(1);
+                    self.queue_depth.release(1);
+                    result = Err(Error::RequestParsing(e));
+                    break;
+                }
+            };
             request.set_writeback(self.writeback.load(Ordering::Acquire));
 
-            if request
-                .execute_async(
-                    &mem,
-                    self.disk_nsectors,
-                    self.disk_image.as_mut(),
-                    &self.disk_image_id,
-                    avail_desc.index as u64,
-                )
-                .map_err(Error::RequestExecuting)?
-            {
-                self.request_list.insert(avail_desc.index, request);
-            } else {
-                // We use unwrap because the request parsing process already
-                // checked that the status_addr was valid.
-                mem.write_obj(VIRTIO_BLK_S_OK, request.status_addr).unwrap();
-
-                // If no asynchronous operation has been submitted, we can
-                // simply return the used descriptor.
-                used_desc_heads.push((avail_desc.index, 0));
-                used_count += 1;
+            // The asynchronous backends don't order the operations, meaning
+            // a flush submitted while writes are in flight could complete
+            // before the data reaches the disk. The flush is held back, and
+            // the processing of the queue stopped, until they complete.
+            if request.request_type == RequestType::Flush && block_queue.inflight_writes > 0 {
+                block_queue.pending_flush = Some((avail_desc.index, request));
+                break;
             }
+
+            let status = match request.execute_async(
+                &mem,
+                self.disk_nsectors,
+                self.disk_image.as_mut(),
+                &self.disk_image_id,
+                user_data(queue_index, avail_desc.index),
+            ) {
+                Ok(true) => {
+                    if is_write(request.request_type) {
+                        block_queue.inflight_writes += 1;
+                    }
+                    block_queue.request_list.insert(avail_desc.index, request);
+                    continue;
+                }
+                Ok(false) => VIRTIO_BLK_S_OK,
+                // A write zeroes request which can't be carried out, such as
+                // one going past the end of the disk, is failed with the
+                // status matching the error.
+                Err(e) if request.request_type == RequestType::WriteZeroes => {
+                    guest_warn!("Write zeroes request failed: {:?}", e);
+                    e.status()
+                }
+                Err(e) => {
+                    self.inflight.release(1);
+                    self.queue_depth.release(1);
+                    result = Err(Error::RequestExecuting(e));
+                    break;
+                }
+            };
+
+            // We use unwrap because the request parsing process already
+            // checked that the status_addr was valid.
+            mem.write_obj(status, request.status_addr).unwrap();
+
+            // If no asynchronous operation has been submitted, we can
+            // simply return the used descriptor.
+            used_desc_heads.push((avail_desc.index, 0));
+            used_count += 1;
+        }
+
+        if throttled {
+            queue.go_to_previous_position();
         }
 
         for &(desc_index, len) in used_desc_heads.iter() {
             queue.add_used(&mem, desc_index, len);
         }
-
-        Ok(used_count > 0)
+        self.inflight.release(used_desc_heads.len());
+        self.queue_depth.release(used_desc_heads.len());
+
+        block_queue
+            .counters
+            .requests
+            .fetch_add(taken as u64, Ordering::AcqRel);
+        result?;
+
+        Ok((
+            used_count,
+            taken == budget && block_queue.pending_flush.is_none(),
+        ))
     }
 
-    fn process_queue_complete(&mut self) -> Result<bool> {
-        let queue = &mut self.queue;
+    // Submits the requests of the queues in weighted round robin: in each
+    // round, every queue submits up to its share of requests before the
+    // next one gets its turn, until all of them are drained. A queue flooded
+    // with requests can't hold the disk for itself this way. Returns the
+    // number of entries added to the used ring of each queue.
+    fn process_queues_submit(&mut self) -> Result<Vec<usize>> {
+        let num_queues = self.queues.len();
+        let mut used_counts = vec![0; num_queues];
+        let mut busy = vec![true; num_queues];
+
+        while busy.iter().any(|b| *b) {
+            for i in 0..num_queues {
+                let queue_index = (self.next_queue + i) % num_queues;
+                if !busy[queue_index] {
+                    continue;
+                }
+
+                let budget = self.queues[queue_index].weight as usize * SCHEDULER_QUANTUM;
+                let (used_count, preempted) = self.process_queue_submit(queue_index, budget)?;
+                used_counts[queue_index] += used_count;
+                if preempted {
+                    self.queues[queue_index]
+                        .counters
+                        .preemptions
+                        .fetch_add(1, Ordering::AcqRel);
+                } else {
+                    busy[queue_index] = false;
+                }
+            }
+            self.next_queue = (self.next_queue + 1) % num_queues;
+        }
 
+        Ok(used_counts)
+    }
+
+    // Returns the number of entries added to the used ring of each queue.
+    fn process_queue_complete(&mut self) -> Result<Vec<usize>> {
         let mut used_desc_heads = Vec::new();
-        let mut used_count = 0;
+        let mut used_counts = vec![0; self.queues.len()];
         let mem = self.mem.memory();
         let mut read_bytes = Wrapping(0);
         let mut write_bytes = Wrapping(0);
@@ -161,12 +448,37 @@ impl BlockEpollHandler {
 
         let completion_list = self.disk_image.complete();
         for (user_data, result) in completion_list {
+            let queue_index = (user_data >> USER_DATA_QUEUE_SHIFT) as usize;
             let desc_index = user_data as u16;
-            let request = self
+            let block_queue = self
+                .queues
+                .get_mut(queue_index)
+                .ok_or(Error::MissingEntryRequestList)?;
+            let request = block_queue
                 .request_list
                 .remove(&desc_index)
                 .ok_or(Error::MissingEntryRequestList)?;
 
+            if result < 0 {
+                let attempt = block_queue
+                    .retry_attempts
+                    .get(&desc_index)
+                    .copied()
+                    .unwrap_or(0);
+                if is_transient(-result) && attempt < self.io_retry.max_retries {
+                    block_queue.retry_attempts.insert(desc_index, attempt + 1);
+                    self.pending_retries.push(PendingRetry {
+                        due: Instant::now() + self.io_retry.backoff(attempt),
+                        queue_index,
+                        desc_index,
+                        request,
+                    });
+                    self.counters.retried_ops.fetch_add(1, Ordering::AcqRel);
+                    continue;
+                }
+            }
+            block_queue.retry_attempts.remove(&desc_index);
+
             let (status, len) = if result >= 0 {
                 match request.request_type {
                     RequestType::In => {
@@ -176,6 +488,7 @@ impl BlockEpollHandler {
                         read_ops += Wrapping(1);
                     }
                     RequestType::Out => {
+                        block_queue.inflight_writes -= 1;
                         if !request.writeback {
                             self.disk_image.fsync(None).map_err(Error::Fsync)?;
                         }
@@ -184,29 +497,43 @@ impl BlockEpollHandler {
                         }
                         write_ops += Wrapping(1);
                     }
+                    RequestType::WriteZeroes => {
+                        block_queue.inflight_writes -= 1;
+                        if !request.writeback {
+                            self.disk_image.fsync(None).map_err(Error::Fsync)?;
+                        }
+                        write_ops += Wrapping(1);
+                    }
                     _ => {}
                 }
 
                 (VIRTIO_BLK_S_OK, result as u32)
             } else {
-                error!(
+                guest_error!(
                     "Request failed: {:?}",
                     io::Error::from_raw_os_error(-result)
                 );
-                return Err(Error::AsyncRequestFailure);
+                if is_write(request.request_type) {
+                    block_queue.inflight_writes -= 1;
+                }
+                (VIRTIO_BLK_S_IOERR, 0)
             };
 
             // We use unwrap because the request parsing process already
             // checked that the status_addr was valid.
             mem.write_obj(status, request.status_addr).unwrap();
 
-            used_desc_heads.push((desc_index as u16, len));
-            used_count += 1;
+            used_desc_heads.push((queue_index, desc_index, len));
+            used_counts[queue_index] += 1;
         }
 
-        for &(desc_index, len) in used_desc_heads.iter() {
-            queue.add_used(&mem, desc_index, len);
+        for &(queue_index, desc_index, len) in used_desc_heads.iter() {
+            self.queues[queue_index]
+                .queue
+                .add_used(&mem, desc_index, len);
         }
+        self.inflight.release(used_desc_heads.len());
+        self.queue_depth.release(used_desc_heads.len());
 
         self.counters
             .write_bytes
@@ -222,26 +549,316 @@ impl BlockEpollHandler {
             .read_ops
             .fetch_add(read_ops.0, Ordering::AcqRel);
 
-        Ok(used_count > 0)
+        self.arm_retry_timer().map_err(Error::RetryTimer)?;
+
+        if self.submit_pending_flushes()? {
+            let resumed_counts = self.process_queues_submit()?;
+            for (used_count, resumed_count) in used_counts.iter_mut().zip(resumed_counts) {
+                *used_count += resumed_count;
+            }
+        }
+
+        Ok(used_counts)
     }
 
-    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+    // Arms the timer for the first of the pending retries.
+    fn arm_retry_timer(&mut self) -> errno::Result<()> {
+        let timer = match &mut self.retry_timer {
+            Some(timer) => timer,
+            None => return Ok(()),
+        };
+
+        match self.pending_retries.iter().map(|retry| retry.due).min() {
+            Some(due) => {
+                // A zero duration would disarm the timer.
+                let timeout = due
+                    .saturating_duration_since(Instant::now())
+                    .max(Duration::from_micros(1));
+                timer.reset(timeout, None)
+            }
+            None => timer.clear(),
+        }
+    }
+
+    // Submits again the requests whose retry is due. Returns the number of
+    // entries added to the used ring of each queue, for the requests which
+    // complete right away.
+    fn process_retries(&mut self) -> Result<Vec<usize>> {
+        let mut used_counts = vec![0; self.queues.len()];
+        if let Some(timer) = &mut self.retry_timer {
+            match timer.wait() {
+                Ok(_) => {}
+                Err(e) if e.errno() == libc::EAGAIN => return Ok(used_counts),
+                Err(e) => return Err(Error::RetryTimer(e)),
+            }
+        }
+
+        let mem = self.mem.memory();
+        let now = Instant::now();
+        let (due, pending): (Vec<_>, Vec<_>) = self
+            .pending_retries
+            .drain(..)
+            .partition(|retry| retry.due <= now);
+        self.pending_retries = pending;
+
+        for retry in due {
+            let block_queue = &mut self.queues[retry.queue_index];
+            let status = match retry.request.execute_async(
+                &mem,
+                self.disk_nsectors,
+                self.disk_image.as_mut(),
+                &self.disk_image_id,
+                user_data(retry.queue_index, retry.desc_index),
+            ) {
+                Ok(true) => {
+                    block_queue
+                        .request_list
+                        .insert(retry.desc_index, retry.request);
+                    continue;
+                }
+                Ok(false) => VIRTIO_BLK_S_OK,
+                Err(e) => {
+                    guest_warn!("Request failed: {:?}", e);
+                    e.status()
+                }
+            };
+
+            block_queue.retry_attempts.remove(&retry.desc_index);
+            if is_write(retry.request.request_type) {
+                block_queue.inflight_writes -= 1;
+            }
+            // We use unwrap because the request parsing process already
+            // checked that the status_addr was valid.
+            mem.write_obj(status, retry.request.status_addr).unwrap();
+            block_queue.queue.add_used(&mem, retry.desc_index, 0);
+            self.inflight.release(1);
+            self.queue_depth.release(1);
+            used_counts[retry.queue_index] += 1;
+        }
+
+        self.arm_retry_timer().map_err(Error::RetryTimer)?;
+
+        if self.submit_pending_flushes()? {
+            let resumed_counts = self.process_queues_submit()?;
+            for (used_count, resumed_count) in used_counts.iter_mut().zip(resumed_counts) {
+                *used_count += resumed_count;
+            }
+        }
+
+        Ok(used_counts)
+    }
+
+    // Issue the pending flushes once all the writes they were waiting for
+    // have completed. Returns whether any was issued, and the processing of
+    // its queue must be resumed.
+    fn submit_pending_flushes(&mut self) -> Result<bool> {
+        let mem = self.mem.memory();
+        let mut submitted = false;
+
+        for (queue_index, block_queue) in self.queues.iter_mut().enumerate() {
+            if block_queue.inflight_writes > 0 {
+                continue;
+            }
+
+            let (desc_index, request) = match block_queue.pending_flush.take() {
+                Some(pending_flush) => pending_flush,
+                None => continue,
+            };
+
+            request
+                .execute_async(
+                    &mem,
+                    self.disk_nsectors,
+                    self.disk_image.as_mut(),
+                    &self.disk_image_id,
+                    user_data(queue_index, desc_index),
+                )
+                .map_err(Error::RequestExecuting)?;
+            block_queue.request_list.insert(desc_index, request);
+            submitted = true;
+        }
+
+        Ok(submitted)
+    }
+
+    // Whether requests haven't completed yet, including the flushes and the
+    // retries not issued yet.
+    fn has_inflight(&self) -> bool {
+        !self.pending_retries.is_empty()
+            || self
+                .queues
+                .iter()
+                .any(|q| !q.request_list.is_empty() || q.pending_flush.is_some())
+    }
+
+    // Waits for the requests in flight to complete, then synchronizes the
+    // disk image, so that a snapshot taken while the device is paused is
+    // consistent with the backing file. Returns the number of entries added
+    // to the used ring of each queue.
+    fn drain(&mut self, deadline: Option<Instant>) -> Result<Vec<usize>> {
+        let mut used_counts = vec![0; self.queues.len()];
+
+        if self.has_inflight() {
+            // Only the completions and the retries are waited for, the
+            // queues being left alone.
+            let epoll_fd = epoll::create(true).map_err(Error::Drain)?;
+            // Use 'File' to enforce closing on 'epoll_fd'
+            let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+            let mut fds = vec![(self.disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)];
+            if let Some(retry_timer) = &self.retry_timer {
+                fds.push((retry_timer.as_raw_fd(), RETRY_TIMER_EVENT));
+            }
+            for (fd, id) in fds {
+                epoll::ctl(
+                    epoll_file.as_raw_fd(),
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    fd,
+                    epoll::Event::new(epoll::Events::EPOLLIN, id.into()),
+                )
+                .map_err(Error::Drain)?;
+            }
+
+            let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 2];
+            while self.has_inflight() {
+                // The backend is given until the deadline of the pause.
+                let timeout = match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Err(Error::DrainTimeout);
+                        }
+                        // Rounded up, not to spin on the last millisecond.
+                        let remaining = deadline - now;
+                        let millis = remaining.as_secs() * 1000
+                            + u64::from((remaining.subsec_nanos() + 999_999) / 1_000_000);
+                        cmp::min(millis, i32::MAX as u64) as i32
+                    }
+                    None => -1,
+                };
+                let num_events = match epoll::wait(epoll_file.as_raw_fd(), timeout, &mut events[..])
+                {
+                    Ok(num_events) => num_events,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(Error::Drain(e)),
+                };
+
+                for event in events.iter().take(num_events) {
+                    let counts = if event.data as u16 == COMPLETION_EVENT {
+                        self.disk_image.notifier().read().map_err(Error::Drain)?;
+                        self.process_queue_complete()?
+                    } else {
+                        self.process_retries()?
+                    };
+                    for (used_count, count) in used_counts.iter_mut().zip(counts) {
+                        *used_count += count;
+                    }
+                }
+            }
+        }
+
+        self.disk_image.fsync(None).map_err(Error::Fsync)?;
+
+        Ok(used_counts)
+    }
+
+    fn signal_used_queue(&mut self, queue_index: usize) -> result::Result<(), DeviceError> {
+        let block_queue = &mut self.queues[queue_index];
+
         self.interrupt_cb
-            .trigger(&VirtioInterruptType::Queue, Some(&self.queue))
+            .trigger(&VirtioInterruptType::Queue, Some(&block_queue.queue))
             .map_err(|e| {
                 error!("Failed to signal used queue: {:?}", e);
                 DeviceError::FailedSignalingUsedQueue(e)
-            })
+            })?;
+
+        block_queue
+            .notification_throttle
+            .notified()
+            .map_err(DeviceError::NotificationTimer)
+    }
+
+    // Signal the used queues unless the notification is held back to batch
+    // it with the next used entries.
+    fn used(&mut self, used_counts: &[usize]) -> result::Result<(), DeviceError> {
+        for (queue_index, used_count) in used_counts.iter().enumerate() {
+            if self.queues[queue_index]
+                .notification_throttle
+                .used(*used_count)
+                .map_err(DeviceError::NotificationTimer)?
+            {
+                self.signal_used_queue(queue_index)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_queue_event(&mut self, queue_index: usize, event: u16) -> bool {
+        match event {
+            QUEUE_AVAIL_EVENT => {
+                if let Err(e) = self.queues[queue_index].queue_evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                }
+
+                match self.process_queues_submit() {
+                    Ok(used_counts) => {
+                        if let Err(e) = self.used(&used_counts) {
+                            error!("Failed to signal used queue: {:?}", e);
+                            return true;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to process queue (submit): {:?}", e);
+                        return true;
+                    }
+                }
+            }
+            NOTIFICATION_TIMER_EVENT => {
+                match self.queues[queue_index]
+                    .notification_throttle
+                    .timer_expired()
+                {
+                    Ok(true) => {
+                        if let Err(e) = self.signal_used_queue(queue_index) {
+                            error!("Failed to signal used queue: {:?}", e);
+                            return true;
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("Failed to read notification timer: {:?}", e);
+                        return true;
+                    }
+                }
+            }
+            _ => {
+                error!("Unexpected queue event: {}", event);
+                return true;
+            }
+        }
+        false
     }
 
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
-        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
         helper.add_event(self.disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)?;
+        if let Some(retry_timer) = &self.retry_timer {
+            helper.add_event(retry_timer.as_raw_fd(), RETRY_TIMER_EVENT)?;
+        }
+        helper.add_event(self.inflight.waiter().as_raw_fd(), INFLIGHT_EVENT)?;
+        helper.add_event(self.queue_depth.waiter().as_raw_fd(), QUEUE_DEPTH_EVENT)?;
+        for (i, block_queue) in self.queues.iter().enumerate() {
+            let base = QUEUE_EVENTS_BASE + i as u16 * QUEUE_EVENTS_PER_QUEUE;
+            helper.add_event(block_queue.queue_evt.as_raw_fd(), base + QUEUE_AVAIL_EVENT)?;
+            if let Some(timer_fd) = block_queue.notification_throttle.timer_fd() {
+                helper.add_event(timer_fd, base + NOTIFICATION_TIMER_EVENT)?;
+            }
+        }
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -252,50 +869,84 @@ impl EpollHelperHandler for BlockEpollHandler {
     fn handle_event(&mut self, _helper: &mut EpollHelper, event: &epoll::Event) -> bool {
         let ev_type = event.data as u16;
         match ev_type {
-            QUEUE_AVAIL_EVENT => {
-                if let Err(e) = self.queue_evt.read() {
+            COMPLETION_EVENT => {
+                if let Err(e) = self.disk_image.notifier().read() {
                     error!("Failed to get queue event: {:?}", e);
                     return true;
                 }
 
-                match self.process_queue_submit() {
-                    Ok(needs_notification) => {
-                        if needs_notification {
-                            if let Err(e) = self.signal_used_queue() {
-                                error!("Failed to signal used queue: {:?}", e);
-                                return true;
-                            }
+                match self.process_queue_complete() {
+                    Ok(used_counts) => {
+                        if let Err(e) = self.used(&used_counts) {
+                            error!("Failed to signal used queue: {:?}", e);
+                            return true;
                         }
                     }
                     Err(e) => {
-                        error!("Failed to process queue (submit): {:?}", e);
+                        error!("Failed to process queue (complete): {:?}", e);
                         return true;
                     }
                 }
             }
-            COMPLETION_EVENT => {
-                if let Err(e) = self.disk_image.notifier().read() {
-                    error!("Failed to get queue event: {:?}", e);
+            INFLIGHT_EVENT | QUEUE_DEPTH_EVENT => {
+                let waiter = if ev_type == INFLIGHT_EVENT {
+                    self.inflight.waiter()
+                } else {
+                    self.queue_depth.waiter()
+                };
+                if let Err(e) = waiter.read() {
+                    error!("Failed to get inflight event: {:?}", e);
                     return true;
                 }
 
-                match self.process_queue_complete() {
-                    Ok(needs_notification) => {
-                        if needs_notification {
-                            if let Err(e) = self.signal_used_queue() {
-                                error!("Failed to signal used queue: {:?}", e);
-                                return true;
-                            }
+                match self.process_queues_submit() {
+                    Ok(used_counts) => {
+                        if let Err(e) = self.used(&used_counts) {
+                            error!("Failed to signal used queue: {:?}", e);
+                            return true;
                         }
                     }
                     Err(e) => {
-                        error!("Failed to process queue (complete): {:?}", e);
+                        error!("Failed to process queue (submit): {:?}", e);
                         return true;
                     }
                 }
             }
+            RETRY_TIMER_EVENT => match self.process_retries() {
+                Ok(used_counts) => {
+                    if let Err(e) = self.used(&used_counts) {
+                        error!("Failed to signal used queue: {:?}", e);
+                        return true;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to process queue (retry): {:?}", e);
+                    return true;
+                }
+            },
             _ => {
-                error!("Unexpected event: {}", ev_type);
+                let offset = ev_type.wrapping_sub(QUEUE_EVENTS_BASE);
+                let queue_index = usize::from(offset / QUEUE_EVENTS_PER_QUEUE);
+                if ev_type < QUEUE_EVENTS_BASE || queue_index >= self.queues.len() {
+                    error!("Unexpected event: {}", ev_type);
+                    return true;
+                }
+                return self.handle_queue_event(queue_index, offset % QUEUE_EVENTS_PER_QUEUE);
+            }
+        }
+        false
+    }
+
+    fn prepare_pause(&mut self, deadline: Option<Instant>) -> bool {
+        match self.drain(deadline) {
+            Ok(used_counts) => {
+                if let Err(e) = self.used(&used_counts) {
+                    error!("Failed to signal used queue: {:?}", e);
+                    return true;
+                }
+            }
+            Err(e) => {
+                error!("Failed to drain the requests in flight: {:?}", e);
                 return true;
             }
         }
@@ -313,6 +964,20 @@ pub struct Block {
     config: VirtioBlockConfig,
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
+    notification_suppression: NotificationSuppression,
+    notification_counters: NotificationCounters,
+    num_workers: usize,
+    queue_weights: Vec<u32>,
+    queue_counters: Vec<QueueCounters>,
+    io_retry: IoRetry,
+    inflight_limit: InflightLimit,
+    // Requests the device holds in flight, across all of its workers.
+    queue_depth: InflightLimit,
+    // I/O priority given to the workers, as passed to ioprio_set(2).
+    io_priority: Option<u16>,
+    // Workers of the last activation running with the I/O priority.
+    io_priority_workers: Arc<AtomicUsize>,
+    host_io_scheduler: Option<String>,
     seccomp_action: SeccompAction,
 }
 
@@ -325,6 +990,10 @@ pub struct BlockState {
     pub config: VirtioBlockConfig,
 }
 
+impl VersionedState for BlockState {
+    const VERSION: u16 = 1;
+}
+
 impl Block {
     /// Create a new virtio block device that operates on the given file.
     #[allow(clippy::too_many_arguments)]
@@ -336,6 +1005,9 @@ impl Block {
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        notification_suppression: NotificationSuppression,
+        queue_scheduling: QueueScheduling,
+        io_retry: IoRetry,
         seccomp_action: SeccompAction,
     ) -> io::Result<Self> {
         let disk_size = disk_image.size().map_err(|e| {
@@ -376,11 +1048,51 @@ impl Block {
             config.num_queues = num_queues as u16;
         }
 
+        // Let the guest know about block devices with larger logical blocks,
+        // as they can't be accessed with O_DIRECT on a sector granularity.
+        let block_size = disk_image.block_size().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed getting disk block size: {}", e),
+            )
+        })?;
+        if u64::from(block_size) != SECTOR_SIZE {
+            avail_features |= 1u64 << VIRTIO_BLK_F_BLK_SIZE;
+            config.blk_size = block_size;
+        }
+
+        if !is_disk_read_only && disk_image.supports_write_zeroes() {
+            avail_features |= 1u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+            config.max_write_zeroes_sectors = MAX_WRITE_ZEROES_SECTORS;
+            config.max_write_zeroes_seg = 1;
+            config.write_zeroes_may_unmap = 1;
+        }
+
+        let num_workers = match queue_scheduling.num_workers {
+            0 => num_queues,
+            n => n.min(num_queues),
+        };
+        let queue_weights = if queue_scheduling.weights.is_empty() {
+            vec![1; num_queues]
+        } else if queue_scheduling.weights.len() == num_queues
+            && queue_scheduling
+                .weights
+                .iter()
+                .all(|w| (1..=MAX_QUEUE_WEIGHT).contains(w))
+        {
+            queue_scheduling.weights
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid queue weights: {:?}", queue_scheduling.weights),
+            ));
+        };
+
         Ok(Block {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::TYPE_BLOCK as u32,
                 avail_features,
-                paused_sync: Some(Arc::new(Barrier::new(num_queues + 1))),
+                paused_sync: Some(Arc::new(PauseBarrier::new(num_workers + 1))),
                 queue_sizes: vec![queue_size; num_queues],
                 min_queues: 1,
                 ..Default::default()
@@ -392,10 +1104,65 @@ impl Block {
             config,
             writeback: Arc::new(AtomicBool::new(true)),
             counters: BlockCounters::default(),
+            notification_suppression,
+            notification_counters: NotificationCounters::default(),
+            num_workers,
+            queue_weights,
+            queue_counters: (0..num_queues).map(|_| QueueCounters::default()).collect(),
+            io_retry,
+            inflight_limit: InflightLimit::new(None),
+            queue_depth: InflightLimit::new(None),
+            io_priority: None,
+            io_priority_workers: Arc::new(AtomicUsize::new(0)),
+            host_io_scheduler: None,
             seccomp_action,
         })
     }
 
+    /// Sets the I/O priority of the workers, encoded as by the
+    /// IOPRIO_PRIO_VALUE() macro of the kernel.
+    pub fn set_io_priority(&mut self, io_priority: u16) {
+        self.host_io_scheduler = host_io_scheduler(&self.disk_path);
+        match &self.host_io_scheduler {
+            Some(scheduler) if !IOPRIO_SCHEDULERS.contains(&scheduler.as_str()) => warn!(
+                "The host I/O scheduler {} of {:?} ignores the I/O priority of {}",
+                scheduler, self.disk_path, self.id
+            ),
+            None => warn!(
+                "{:?} isn't on a host block device, the I/O priority of {} may be ignored",
+                self.disk_path, self.id
+            ),
+            _ => {}
+        }
+        self.io_priority = Some(io_priority);
+    }
+
+    /// Whether all the workers of the device run with its I/O priority.
+    pub fn io_priority_applied(&self) -> bool {
+        self.io_priority.is_some()
+            && self.common.epoll_threads.as_ref().map_or(false, |threads| {
+                !threads.is_empty()
+                    && self.io_priority_workers.load(Ordering::Acquire) == threads.len()
+            })
+    }
+
+    /// Active I/O scheduler of the host block device holding the disk
+    /// image, once an I/O priority is set.
+    pub fn host_io_scheduler(&self) -> Option<&str> {
+        self.host_io_scheduler.as_deref()
+    }
+
+    /// Limits the requests the device holds in flight. Past it, the
+    /// requests are left in the queues until others complete.
+    pub fn set_queue_depth(&mut self, queue_depth: u32) {
+        self.queue_depth = InflightLimit::new(Some(u64::from(queue_depth)));
+    }
+
+    /// Shares the limit of the descriptor chains the devices hold in flight.
+    pub fn set_inflight_limit(&mut self, inflight_limit: InflightLimit) {
+        self.inflight_limit = inflight_limit;
+    }
+
     fn state(&self) -> BlockState {
         BlockState {
             disk_path: self.disk_path.clone(),
@@ -495,11 +1262,42 @@ impl VirtioDevice for Block {
         let disk_image_id = build_disk_image_id(&self.disk_path);
         self.update_writeback();
 
-        let mut epoll_threads = Vec::new();
+        // Queue i is served by worker i modulo the number of workers.
+        let num_workers = self.num_workers.min(queues.len());
+        let mut worker_queues: Vec<Vec<BlockQueue>> =
+            (0..num_workers).map(|_| Vec::new()).collect();
         for i in 0..queues.len() {
-            let queue_evt = queue_evts.remove(0);
             let queue = queues.remove(0);
             let queue_size = queue.size;
+            worker_queues[i % num_workers].push(BlockQueue {
+                queue,
+                queue_evt: queue_evts.remove(0),
+                weight: self.queue_weights[i],
+                counters: self.queue_counters[i].clone(),
+                request_list: HashMap::with_capacity(queue_size.into()),
+                inflight_writes: 0,
+                pending_flush: None,
+                retry_attempts: HashMap::new(),
+                notification_throttle: NotificationThrottle::new(
+                    self.notification_suppression,
+                    self.notification_counters.clone(),
+                )
+                .map_err(|e| {
+                    error!("failed to create notification timer: {}", e);
+                    ActivateError::BadActivate
+                })?,
+            });
+        }
+
+        self.io_priority_workers.store(0, Ordering::Release);
+        let mut epoll_threads = Vec::new();
+        for (i, queues) in worker_queues.into_iter().enumerate() {
+            // Every request of the queues can be in flight at once, up to
+            // the queue depth of the device.
+            let mut ring_depth: u32 = queues.iter().map(|q| u32::from(q.queue.size)).sum();
+            if let Some(queue_depth) = self.queue_depth.max() {
+                ring_depth = ring_depth.min(queue_depth as u32);
+            }
             let kill_evt = self
                 .common
                 .kill_evt
@@ -521,16 +1319,22 @@ impl VirtioDevice for Block {
                     ActivateError::BadActivate
                 })?;
 
+            let retry_timer = if self.io_retry.max_retries > 0 {
+                Some(nonblocking_timer().map_err(|e| {
+                    error!("failed to create retry timer: {}", e);
+                    ActivateError::BadActivate
+                })?)
+            } else {
+                None
+            };
+
             let mut handler = BlockEpollHandler {
-                queue,
+                queues,
                 mem: mem.clone(),
-                disk_image: self
-                    .disk_image
-                    .new_async_io(queue_size as u32)
-                    .map_err(|e| {
-                        error!("failed to create new AsyncIo: {}", e);
-                        ActivateError::BadActivate
-                    })?,
+                disk_image: self.disk_image.new_async_io(ring_depth).map_err(|e| {
+                    error!("failed to create new AsyncIo: {}", e);
+                    ActivateError::BadActivate
+                })?,
                 disk_nsectors: self.disk_nsectors,
                 interrupt_cb: interrupt_cb.clone(),
                 disk_image_id: disk_image_id.clone(),
@@ -538,12 +1342,24 @@ impl VirtioDevice for Block {
                 pause_evt,
                 writeback: self.writeback.clone(),
                 counters: self.counters.clone(),
-                queue_evt,
-                request_list: HashMap::with_capacity(queue_size.into()),
+                next_queue: 0,
+                io_retry: self.io_retry,
+                pending_retries: Vec::new(),
+                retry_timer,
+                inflight: self.inflight_limit.tracker().map_err(|e| {
+                    error!("failed to create inflight tracker: {}", e);
+                    ActivateError::BadActivate
+                })?,
+                queue_depth: self.queue_depth.tracker().map_err(|e| {
+                    error!("failed to create queue depth tracker: {}", e);
+                    ActivateError::BadActivate
+                })?,
             };
 
             let paused = self.common.paused.clone();
             let paused_sync = self.common.paused_sync.clone();
+            let io_priority = self.io_priority;
+            let io_priority_workers = self.io_priority_workers.clone();
 
             // Retrieve seccomp filter for virtio_block thread
             let virtio_block_seccomp_filter =
@@ -553,6 +1369,27 @@ impl VirtioDevice for Block {
             thread::Builder::new()
                 .name(format!("{}_q{}", self.id.clone(), i))
                 .spawn(move || {
+                    if let Some(io_priority) = io_priority {
+                        // Safe as the syscall only changes the I/O priority
+                        // of the calling thread.
+                        let ret = unsafe {
+                            libc::syscall(
+                                libc::SYS_ioprio_set,
+                                IOPRIO_WHO_PROCESS,
+                                0,
+                                libc::c_int::from(io_priority),
+                            )
+                        };
+                        if ret < 0 {
+                            error!(
+                                "Failed to set the I/O priority: {}",
+                                io::Error::last_os_error()
+                            );
+                        } else {
+                            io_priority_workers.fetch_add(1, Ordering::AcqRel);
+                        }
+                    }
+
                     if let Err(e) = SeccompFilter::apply(virtio_block_seccomp_filter) {
                         error!("Error applying seccomp filter: {:?}", e);
                     } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
@@ -594,9 +1431,50 @@ impl VirtioDevice for Block {
             "write_ops",
             Wrapping(self.counters.write_ops.load(Ordering::Acquire)),
         );
+        counters.insert(
+            "retried_ops",
+            Wrapping(self.counters.retried_ops.load(Ordering::Acquire)),
+        );
+        counters.insert("queue_depth", Wrapping(self.queue_depth.inflight()));
+        counters.insert(
+            "queue_depth_high_watermark",
+            Wrapping(self.queue_depth.high_watermark()),
+        );
+        counters.insert(
+            "queue_depth_max",
+            Wrapping(self.queue_depth.max().unwrap_or(0)),
+        );
+        counters.insert(
+            "queue_depth_throttled",
+            Wrapping(self.queue_depth.throttled()),
+        );
+        self.notification_counters.report(&mut counters);
 
         Some(counters)
     }
+
+    fn queue_counters(&self) -> Option<Vec<HashMap<&'static str, Wrapping<u64>>>> {
+        let queue_counters = self
+            .queue_counters
+            .iter()
+            .map(|queue_counters| {
+                let mut counters = HashMap::new();
+
+                counters.insert(
+                    "requests",
+                    Wrapping(queue_counters.requests.load(Ordering::Acquire)),
+                );
+                counters.insert(
+                    "preemptions",
+                    Wrapping(queue_counters.preemptions.load(Ordering::Acquire)),
+                );
+
+                counters
+            })
+            .collect();
+
+        Some(queue_counters)
+    }
 }
 
 impl Pausable for Block {
@@ -604,6 +1482,10 @@ impl Pausable for Block {
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         self.common.resume()
     }
@@ -615,29 +1497,18 @@ impl Snapshottable for Block {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut block_snapshot = Snapshot::new(self.id.as_str());
-        block_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id),
-            snapshot,
-        });
+        block_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
 
         Ok(block_snapshot)
     }
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(block_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
-            let block_state = match serde_json::from_slice(&block_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize BLOCK {}",
-                        error
-                    )))
-                }
-            };
+            let block_state = block_section.to_versioned_state::<BlockState>()?;
 
             self.set_state(&block_state);
             return Ok(());
@@ -650,3 +1521,569 @@ impl Snapshottable for Block {
 }
 impl Transportable for Block {}
 impl Migratable for Block {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vsock::tests::NoopVirtioInterrupt;
+    use std::sync::Mutex;
+    use vm_memory::GuestMemoryMmap;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const DISK_SECTORS: u64 = 16;
+
+    // Models a disk behind a volatile cache. Writes are held in flight until
+    // complete_writes() is called, and only reach the disk, the content that
+    // would survive a crash, once a later fsync has been issued.
+    #[derive(Default)]
+    struct CachedDisk {
+        inflight: Vec<(u64, libc::off_t, Vec<u8>)>,
+        cache: Vec<u8>,
+        disk: Vec<u8>,
+        completions: Vec<(u64, i32)>,
+        // Errors the next writes complete with.
+        failures: Vec<i32>,
+    }
+
+    impl CachedDisk {
+        fn complete_writes(&mut self) {
+            for (user_data, offset, data) in self.inflight.drain(..) {
+                if !self.failures.is_empty() {
+                    self.completions.push((user_data, -self.failures.remove(0)));
+                    continue;
+                }
+                let offset = offset as usize;
+                self.cache[offset..offset + data.len()].copy_from_slice(&data);
+                self.completions.push((user_data, data.len() as i32));
+            }
+        }
+    }
+
+    struct CachedDiskAsync {
+        disk: Arc<Mutex<CachedDisk>>,
+        eventfd: EventFd,
+    }
+
+    impl AsyncIo for CachedDiskAsync {
+        fn notifier(&self) -> &EventFd {
+            &self.eventfd
+        }
+
+        // Reads are served from the cache, and complete right away.
+        fn read_vectored(
+            &mut self,
+            offset: libc::off_t,
+            iovecs: Vec<libc::iovec>,
+            user_data: u64,
+        ) -> block_util::async_io::AsyncIoResult<()> {
+            let mut disk = self.disk.lock().unwrap();
+            let mut offset = offset as usize;
+            let mut len = 0;
+            for iovec in iovecs {
+                // Safe because the buffers come from the guest memory of the
+                // test, which outlives the request.
+                let buf = unsafe {
+                    std::slice::from_raw_parts_mut(iovec.iov_base as *mut u8, iovec.iov_len)
+                };
+                buf.copy_from_slice(&disk.cache[offset..offset + buf.len()]);
+                offset += buf.len();
+                len += buf.len();
+            }
+            disk.completions.push((user_data, len as i32));
+            Ok(())
+        }
+
+        fn write_vectored(
+            &mut self,
+            offset: libc::off_t,
+            iovecs: Vec<libc::iovec>,
+            user_data: u64,
+        ) -> block_util::async_io::AsyncIoResult<()> {
+            let mut data = Vec::new();
+            for iovec in iovecs {
+                // Safe because the buffers come from the guest memory of the
+                // test, which outlives the request.
+                data.extend_from_slice(unsafe {
+                    std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len)
+                });
+            }
+            self.disk
+                .lock()
+                .unwrap()
+                .inflight
+                .push((user_data, offset, data));
+            Ok(())
+        }
+
+        fn fsync(&mut self, user_data: Option<u64>) -> block_util::async_io::AsyncIoResult<()> {
+            let mut disk = self.disk.lock().unwrap();
+            disk.disk = disk.cache.clone();
+            if let Some(user_data) = user_data {
+                disk.completions.push((user_data, 0));
+            }
+            Ok(())
+        }
+
+        fn complete(&mut self) -> Vec<(u64, i32)> {
+            self.disk.lock().unwrap().completions.drain(..).collect()
+        }
+    }
+
+    // Places a request in the queue, using the descriptors and the buffers
+    // corresponding to the given index, above the start of the queue.
+    fn push_request(
+        guest_queue: &GuestQ,
+        mem: &GuestMemoryMmap,
+        index: u16,
+        req: Option<(u64, u8)>,
+    ) {
+        let start = guest_queue.start().raw_value();
+        let header = start + 0x1000 + u64::from(index) * 0x10;
+        let data = start + 0x2000 + u64::from(index) * SECTOR_SIZE;
+        let status = start + 0x4000 + u64::from(index);
+        let desc = index * 3;
+
+        let request_type = if req.is_some() {
+            VIRTIO_BLK_T_OUT
+        } else {
+            VIRTIO_BLK_T_FLUSH
+        };
+        mem.write_obj(request_type, GuestAddress(header)).unwrap();
+
+        match req {
+            Some((sector, pattern)) => {
+                mem.write_obj(sector, GuestAddress(header + 8)).unwrap();
+                mem.write_slice(&[pattern; SECTOR_SIZE as usize], GuestAddress(data))
+                    .unwrap();
+                guest_queue.dtable[desc as usize].set(header, 0x10, VIRTQ_DESC_F_NEXT, desc + 1);
+                guest_queue.dtable[desc as usize + 1].set(
+                    data,
+                    SECTOR_SIZE as u32,
+                    VIRTQ_DESC_F_NEXT,
+                    desc + 2,
+                );
+                guest_queue.dtable[desc as usize + 2].set(status, 1, VIRTQ_DESC_F_WRITE, 0);
+            }
+            None => {
+                mem.write_obj(0u64, GuestAddress(header + 8)).unwrap();
+                guest_queue.dtable[desc as usize].set(header, 0x10, VIRTQ_DESC_F_NEXT, desc + 1);
+                guest_queue.dtable[desc as usize + 1].set(status, 1, VIRTQ_DESC_F_WRITE, 0);
+            }
+        }
+
+        guest_queue.avail.ring[index as usize].set(desc);
+        guest_queue.avail.idx.set(index + 1);
+    }
+
+    fn block_queue(queue: Queue, weight: u32) -> BlockQueue {
+        BlockQueue {
+            queue,
+            queue_evt: EventFd::new(0).unwrap(),
+            weight,
+            counters: QueueCounters::default(),
+            request_list: HashMap::new(),
+            inflight_writes: 0,
+            pending_flush: None,
+            retry_attempts: HashMap::new(),
+            notification_throttle: NotificationThrottle::new(
+                NotificationSuppression::default(),
+                NotificationCounters::default(),
+            )
+            .unwrap(),
+        }
+    }
+
+    fn epoll_handler(
+        queues: Vec<BlockQueue>,
+        mem: &GuestMemoryMmap,
+        disk: &Arc<Mutex<CachedDisk>>,
+    ) -> BlockEpollHandler {
+        BlockEpollHandler {
+            queues,
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            disk_image: Box::new(CachedDiskAsync {
+                disk: disk.clone(),
+                eventfd: EventFd::new(0).unwrap(),
+            }),
+            disk_nsectors: DISK_SECTORS,
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            disk_image_id: Vec::new(),
+            kill_evt: EventFd::new(0).unwrap(),
+            pause_evt: EventFd::new(0).unwrap(),
+            writeback: Arc::new(AtomicBool::new(true)),
+            counters: BlockCounters::default(),
+            next_queue: 0,
+            io_retry: IoRetry::default(),
+            pending_retries: Vec::new(),
+            retry_timer: None,
+            inflight: InflightLimit::new(None).tracker().unwrap(),
+            queue_depth: InflightLimit::new(None).tracker().unwrap(),
+        }
+    }
+
+    fn cached_disk() -> Arc<Mutex<CachedDisk>> {
+        Arc::new(Mutex::new(CachedDisk {
+            cache: vec![0; (DISK_SECTORS * SECTOR_SIZE) as usize],
+            disk: vec![0; (DISK_SECTORS * SECTOR_SIZE) as usize],
+            ..Default::default()
+        }))
+    }
+
+    fn sector(data: &[u8], sector: u64) -> &[u8] {
+        &data[(sector * SECTOR_SIZE) as usize..((sector + 1) * SECTOR_SIZE) as usize]
+    }
+
+    #[test]
+    fn test_flush_barrier() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+
+        let disk = cached_disk();
+        let mut handler = epoll_handler(
+            vec![block_queue(guest_queue.create_queue(), 1)],
+            &mem,
+            &disk,
+        );
+
+        // Two writes, a flush, and another write following the flush.
+        push_request(&guest_queue, &mem, 0, Some((0, 0xaa)));
+        push_request(&guest_queue, &mem, 1, Some((1, 0xbb)));
+        push_request(&guest_queue, &mem, 2, None);
+        push_request(&guest_queue, &mem, 3, Some((2, 0xcc)));
+
+        // The flush is held back while the writes are in flight, and so is
+        // the write following it.
+        assert_eq!(handler.process_queues_submit().unwrap(), vec![0]);
+        assert_eq!(disk.lock().unwrap().inflight.len(), 2);
+        assert!(handler.queues[0].pending_flush.is_some());
+
+        // Crashing now loses the writes, which were never acknowledged.
+        assert!(disk.lock().unwrap().disk.iter().all(|b| *b == 0));
+
+        // Completing the writes issues the flush, and the following write.
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![2]);
+        assert_eq!(guest_queue.used.idx.get(), 2);
+        assert!(handler.queues[0].pending_flush.is_none());
+        assert_eq!(disk.lock().unwrap().inflight.len(), 1);
+
+        // The flush has completed, the writes preceding it must survive a
+        // crash, while the one following it hasn't reached the disk.
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![1]);
+        assert_eq!(guest_queue.used.idx.get(), 3);
+        assert_eq!(guest_queue.used.ring[2].get().id, 6);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x4002)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        {
+            let disk = disk.lock().unwrap();
+            assert!(sector(&disk.disk, 0).iter().all(|b| *b == 0xaa));
+            assert!(sector(&disk.disk, 1).iter().all(|b| *b == 0xbb));
+            assert!(sector(&disk.disk, 2).iter().all(|b| *b == 0));
+        }
+
+        // With no write in flight, a flush is issued right away.
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![1]);
+        push_request(&guest_queue, &mem, 4, None);
+        handler.process_queues_submit().unwrap();
+        assert!(handler.queues[0].pending_flush.is_none());
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![1]);
+        assert_eq!(guest_queue.used.idx.get(), 5);
+        assert!(sector(&disk.lock().unwrap().disk, 2)
+            .iter()
+            .all(|b| *b == 0xcc));
+    }
+
+    #[test]
+    fn test_pause_drain() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+
+        let disk = cached_disk();
+        let mut handler = epoll_handler(
+            vec![block_queue(guest_queue.create_queue(), 1)],
+            &mem,
+            &disk,
+        );
+
+        // A write completed but only cached, and another one in flight.
+        push_request(&guest_queue, &mem, 0, Some((0, 0xaa)));
+        handler.process_queues_submit().unwrap();
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![1]);
+        push_request(&guest_queue, &mem, 1, Some((1, 0xbb)));
+        handler.process_queues_submit().unwrap();
+        assert_eq!(disk.lock().unwrap().inflight.len(), 1);
+
+        // The write in flight completes while the device is pausing.
+        let notifier = handler.disk_image.notifier().try_clone().unwrap();
+        let backend = {
+            let disk = disk.clone();
+            thread::spawn(move || {
+                disk.lock().unwrap().complete_writes();
+                notifier.write(1).unwrap();
+            })
+        };
+
+        // Once paused, no request is left in flight and both writes have
+        // reached the disk.
+        assert!(!handler.prepare_pause(None));
+        backend.join().unwrap();
+        assert!(!handler.has_inflight());
+        assert_eq!(guest_queue.used.idx.get(), 2);
+        let disk = disk.lock().unwrap();
+        assert_eq!(disk.disk, disk.cache);
+        assert!(sector(&disk.disk, 0).iter().all(|b| *b == 0xaa));
+        assert!(sector(&disk.disk, 1).iter().all(|b| *b == 0xbb));
+    }
+
+    #[test]
+    fn test_pause_drain_timeout() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+
+        let disk = cached_disk();
+        let mut handler = epoll_handler(
+            vec![block_queue(guest_queue.create_queue(), 1)],
+            &mem,
+            &disk,
+        );
+
+        // A write the backend never completes.
+        push_request(&guest_queue, &mem, 0, Some((0, 0xaa)));
+        handler.process_queues_submit().unwrap();
+
+        // The pause fails by its deadline, the write still in flight.
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert!(matches!(
+            handler.drain(Some(deadline)),
+            Err(Error::DrainTimeout)
+        ));
+        assert!(Instant::now() >= deadline);
+        assert!(handler.prepare_pause(Some(Instant::now())));
+        assert!(handler.has_inflight());
+        assert_eq!(guest_queue.used.idx.get(), 0);
+    }
+
+    #[test]
+    fn test_queue_scheduling() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queues = [
+            GuestQ::new(GuestAddress(0), &mem, 64),
+            GuestQ::new(GuestAddress(0x1_0000), &mem, 64),
+        ];
+
+        let disk = cached_disk();
+        let mut handler = epoll_handler(
+            vec![
+                block_queue(guest_queues[0].create_queue(), 1),
+                block_queue(guest_queues[1].create_queue(), 2),
+            ],
+            &mem,
+            &disk,
+        );
+
+        // The first queue is flooded with requests, and the second one, with
+        // a double share, gets requests while it is busy.
+        for index in 0..12 {
+            push_request(&guest_queues[0], &mem, index, Some((0, 0xaa)));
+        }
+        for index in 0..14 {
+            push_request(&guest_queues[1], &mem, index, Some((1, 0xbb)));
+        }
+
+        // The second queue is served after the share of the first one,
+        // rather than once it has been drained.
+        assert_eq!(handler.process_queues_submit().unwrap(), vec![0, 0]);
+        let queues: Vec<u64> = disk
+            .lock()
+            .unwrap()
+            .inflight
+            .iter()
+            .map(|(user_data, _, _)| user_data >> USER_DATA_QUEUE_SHIFT)
+            .collect();
+        let mut expected = vec![0; SCHEDULER_QUANTUM];
+        expected.extend(vec![1; 14]);
+        expected.extend(vec![0; 12 - SCHEDULER_QUANTUM]);
+        assert_eq!(queues, expected);
+
+        let counters = |i: usize| {
+            let counters = &handler.queues[i].counters;
+            (
+                counters.requests.load(Ordering::Acquire),
+                counters.preemptions.load(Ordering::Acquire),
+            )
+        };
+        assert_eq!(counters(0), (12, 1));
+        assert_eq!(counters(1), (14, 0));
+
+        // Every request completes on the queue it came from.
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![12, 14]);
+        assert_eq!(guest_queues[0].used.idx.get(), 12);
+        assert_eq!(guest_queues[1].used.idx.get(), 14);
+    }
+
+    #[test]
+    fn test_queue_depth() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 64);
+
+        let disk = cached_disk();
+        let mut handler = epoll_handler(
+            vec![block_queue(guest_queue.create_queue(), 1)],
+            &mem,
+            &disk,
+        );
+        let queue_depth = InflightLimit::new(Some(4));
+        handler.queue_depth = queue_depth.tracker().unwrap();
+
+        for index in 0..6 {
+            push_request(&guest_queue, &mem, index, Some((index as u64, 0xaa)));
+        }
+
+        // Past the queue depth, the requests are left in the queue.
+        assert_eq!(handler.process_queues_submit().unwrap(), vec![0]);
+        assert_eq!(disk.lock().unwrap().inflight.len(), 4);
+        assert_eq!(queue_depth.inflight(), 4);
+
+        // The waiter is signaled once requests complete, and the ones left
+        // are then submitted.
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![4]);
+        assert_eq!(handler.queue_depth.waiter().read().unwrap(), 1);
+        assert_eq!(handler.process_queues_submit().unwrap(), vec![0]);
+        assert_eq!(disk.lock().unwrap().inflight.len(), 2);
+
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![2]);
+        assert_eq!(guest_queue.used.idx.get(), 6);
+        assert_eq!(queue_depth.inflight(), 0);
+        assert_eq!(queue_depth.high_watermark(), 4);
+        assert_eq!(queue_depth.throttled(), 1);
+    }
+
+    #[test]
+    fn test_malformed_request() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+
+        let disk = cached_disk();
+        let mut handler = epoll_handler(
+            vec![block_queue(guest_queue.create_queue(), 1)],
+            &mem,
+            &disk,
+        );
+        let inflight = InflightLimit::new(Some(4));
+        let queue_depth = InflightLimit::new(Some(4));
+        handler.inflight = inflight.tracker().unwrap();
+        handler.queue_depth = queue_depth.tracker().unwrap();
+
+        // A write followed by one with a read-only status descriptor.
+        push_request(&guest_queue, &mem, 0, Some((0, 0xaa)));
+        push_request(&guest_queue, &mem, 1, Some((1, 0xbb)));
+        guest_queue.dtable[5].set(0x4001, 1, 0, 0);
+
+        // The malformed chain doesn't hold on to its slots.
+        assert!(matches!(
+            handler.process_queues_submit(),
+            Err(Error::RequestParsing(_))
+        ));
+        assert_eq!(disk.lock().unwrap().inflight.len(), 1);
+        assert_eq!(inflight.inflight(), 1);
+        assert_eq!(queue_depth.inflight(), 1);
+
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![1]);
+        assert_eq!(inflight.inflight(), 0);
+        assert_eq!(queue_depth.inflight(), 0);
+    }
+
+    #[test]
+    fn test_io_retry() {
+        let io_retry = IoRetry {
+            max_retries: 3,
+            backoff_ms: 100,
+        };
+        assert_eq!(io_retry.backoff(0), Duration::from_millis(100));
+        assert_eq!(io_retry.backoff(2), Duration::from_millis(400));
+        assert_eq!(io_retry.backoff(u32::MAX), MAX_RETRY_BACKOFF);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+
+        let disk = cached_disk();
+        let mut handler = epoll_handler(
+            vec![block_queue(guest_queue.create_queue(), 1)],
+            &mem,
+            &disk,
+        );
+        handler.io_retry = IoRetry {
+            max_retries: 1,
+            backoff_ms: 0,
+        };
+        handler.retry_timer = Some(nonblocking_timer().unwrap());
+
+        push_request(&guest_queue, &mem, 0, Some((0, 0xaa)));
+        push_request(&guest_queue, &mem, 1, Some((1, 0xbb)));
+        push_request(&guest_queue, &mem, 2, None);
+        handler.process_queues_submit().unwrap();
+
+        // A transient error is retried, while a full disk is reported to the
+        // guest right away. The flush keeps waiting for the retried write.
+        disk.lock().unwrap().failures = vec![libc::EIO, libc::ENOSPC];
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![1]);
+        assert_eq!(guest_queue.used.ring[0].get().id, 3);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x4001)).unwrap(),
+            VIRTIO_BLK_S_IOERR as u8
+        );
+        assert!(handler.queues[0].pending_flush.is_some());
+        assert_eq!(handler.counters.retried_ops.load(Ordering::Acquire), 1);
+
+        // The retry succeeds, the flush being issued once it completes.
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(handler.process_retries().unwrap(), vec![0]);
+        assert!(handler.pending_retries.is_empty());
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![1]);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x4000)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        assert!(handler.queues[0].pending_flush.is_none());
+        assert!(handler.queues[0].retry_attempts.is_empty());
+
+        // A request failing again once retried as many times as allowed is
+        // reported to the guest.
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![1]);
+        push_request(&guest_queue, &mem, 3, Some((2, 0xcc)));
+        handler.process_queues_submit().unwrap();
+        disk.lock().unwrap().failures = vec![libc::EIO, libc::EIO];
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![0]);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(handler.process_retries().unwrap(), vec![0]);
+        disk.lock().unwrap().complete_writes();
+        assert_eq!(handler.process_queue_complete().unwrap(), vec![1]);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x4003)).unwrap(),
+            VIRTIO_BLK_S_IOERR as u8
+        );
+        assert_eq!(handler.counters.retried_ops.load(Ordering::Acquire), 2);
+    }
+
+    #[test]
+    fn test_active_io_scheduler() {
+        assert_eq!(
+            active_io_scheduler("mq-deadline kyber [bfq] none\n"),
+            Some("bfq".to_owned())
+        );
+        assert_eq!(active_io_scheduler("[none]"), Some("none".to_owned()));
+        assert_eq!(active_io_scheduler("mq-deadline none"), None);
+    }
+}
@@ -8,7 +8,7 @@ use super::{
     VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::VirtioInterrupt;
+use crate::{PauseBarrier, VirtioInterrupt};
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
@@ -21,18 +21,27 @@ use std::ops::DerefMut;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Barrier, Mutex};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 use vm_memory::{ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    Transportable, VersionedState,
 };
 use vmm_sys_util::eventfd::EventFd;
 
 const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 2;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+// The control queues and the queues of the crash port come after the ones
+// of the console port.
+const NUM_MULTIPORT_QUEUES: usize = 6;
+
+const RECEIVE_QUEUE: usize = 0;
+const TRANSMIT_QUEUE: usize = 1;
+const CONTROL_RECEIVE_QUEUE: usize = 2;
+const CONTROL_TRANSMIT_QUEUE: usize = 3;
+const CRASH_TRANSMIT_QUEUE: usize = 5;
 
 // New descriptors are pending on the virtio queue.
 const INPUT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
@@ -41,9 +50,32 @@ const OUTPUT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 const INPUT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
 // Console configuration change event is triggered.
 const CONFIG_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+// New descriptors are pending on the control queues.
+const CONTROL_RECEIVE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
+const CONTROL_TRANSMIT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
+// New descriptors are pending on the transmit queue of the crash port.
+const CRASH_TRANSMIT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 7;
 
 //Console size feature bit
 const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+//Multiple ports feature bit
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1;
+
+// Control messages
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+const CONSOLE_PORT_ID: u32 = 0;
+const CRASH_PORT_ID: u32 = 1;
+
+/// Name of the port the guest can write its crash data to, showing up as
+/// /dev/virtio-ports/org.cloudhypervisor.crash for a Linux guest.
+pub const CRASH_PORT_NAME: &str = "org.cloudhypervisor.crash";
 
 #[derive(Copy, Clone, Debug, Default, Deserialize)]
 #[repr(C, packed)]
@@ -81,18 +113,43 @@ impl Serialize for VirtioConsoleConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioConsoleConfig {}
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioConsoleControl {}
+
+type ConsoleOutput = Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>;
+
 struct ConsoleEpollHandler {
     queues: Vec<Queue>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     in_buffer: Arc<Mutex<VecDeque<u8>>>,
-    out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
+    out: ConsoleOutput,
     input_queue_evt: EventFd,
     output_queue_evt: EventFd,
     input_evt: EventFd,
     config_evt: EventFd,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    config: Arc<Mutex<VirtioConsoleConfig>>,
+    // Only set when the driver supports multiple ports.
+    multiport: Option<MultiportHandler>,
+}
+
+struct MultiportHandler {
+    crash_out: ConsoleOutput,
+    control_receive_queue_evt: EventFd,
+    control_transmit_queue_evt: EventFd,
+    crash_transmit_queue_evt: EventFd,
+    // Control messages waiting for a buffer from the driver.
+    pending_control: VecDeque<Vec<u8>>,
 }
 
 impl ConsoleEpollHandler {
@@ -144,16 +201,16 @@ impl ConsoleEpollHandler {
      * we read data from the transmit queue and flush them
      * to the referenced address.
      */
-    fn process_output_queue(&mut self) -> bool {
-        let trans_queue = &mut self.queues[1]; //transmitq
+    fn process_output_queue(&mut self, queue_index: usize, out: &ConsoleOutput) -> bool {
+        let trans_queue = &mut self.queues[queue_index]; //transmitq
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
 
         let mem = self.mem.memory();
         for avail_desc in trans_queue.iter(&mem) {
             let len;
-            let mut out = self.out.lock().unwrap();
-            let _ = mem.write_to(
+            let mut out = out.lock().unwrap();
+            let _ = mem.write_all_to(
                 avail_desc.addr,
                 &mut out.deref_mut(),
                 avail_desc.len as usize,
@@ -171,25 +228,189 @@ impl ConsoleEpollHandler {
         used_count > 0
     }
 
-    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+    fn queue_control_message(&mut self, id: u32, event: u16, value: u16, data: &[u8]) {
+        let mut message = VirtioConsoleControl { id, event, value }
+            .as_slice()
+            .to_vec();
+        message.extend_from_slice(data);
+
+        if let Some(multiport) = self.multiport.as_mut() {
+            multiport.pending_control.push_back(message);
+        }
+    }
+
+    fn queue_resize_message(&mut self) {
+        let (cols, rows) = {
+            let config = self.config.lock().unwrap();
+            (config.cols, config.rows)
+        };
+
+        let mut size = rows.to_le_bytes().to_vec();
+        size.extend_from_slice(&cols.to_le_bytes());
+        self.queue_control_message(CONSOLE_PORT_ID, VIRTIO_CONSOLE_RESIZE, 0, &size);
+    }
+
+    fn handle_control_message(&mut self, message: VirtioConsoleControl) {
+        let (id, event, value) = (message.id, message.event, message.value);
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                if value != 1 {
+                    error!("Console driver failed to initialize");
+                    return;
+                }
+                self.queue_control_message(CONSOLE_PORT_ID, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                self.queue_control_message(CRASH_PORT_ID, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+            }
+            VIRTIO_CONSOLE_PORT_READY => {
+                if value != 1 {
+                    error!("Console driver failed to add port {}", id);
+                    return;
+                }
+                match id {
+                    CONSOLE_PORT_ID => {
+                        self.queue_control_message(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                        self.queue_resize_message();
+                    }
+                    CRASH_PORT_ID => {
+                        self.queue_control_message(
+                            id,
+                            VIRTIO_CONSOLE_PORT_NAME,
+                            1,
+                            CRASH_PORT_NAME.as_bytes(),
+                        );
+                        self.queue_control_message(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                    }
+                    _ => error!("Console driver added unknown port {}", id),
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if id == CRASH_PORT_ID {
+                    info!(
+                        "Crash port {} by the guest",
+                        if value == 1 { "opened" } else { "closed" }
+                    );
+                }
+            }
+            _ => guest_warn!("Unexpected control message {} for port {}", event, id),
+        }
+    }
+
+    /*
+     * The driver sends control messages through the control
+     * transmit queue, announcing it is ready to handle the
+     * ports, and then that each of them is ready.
+     */
+    fn process_control_transmit_queue(&mut self) -> bool {
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mut messages = Vec::new();
+
+        let mem = self.mem.memory();
+        for avail_desc in self.queues[CONTROL_TRANSMIT_QUEUE].iter(&mem) {
+            if avail_desc.len as usize >= std::mem::size_of::<VirtioConsoleControl>() {
+                match mem.read_obj::<VirtioConsoleControl>(avail_desc.addr) {
+                    Ok(message) => messages.push(message),
+                    Err(e) => error!("Failed to read control message: {:?}", e),
+                }
+            } else {
+                error!("Control message too short: {}", avail_desc.len);
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, 0);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            self.queues[CONTROL_TRANSMIT_QUEUE].add_used(&mem, desc_index, len);
+        }
+
+        for message in messages {
+            self.handle_control_message(message);
+        }
+
+        used_count > 0
+    }
+
+    /*
+     * The control messages from the device are placed into the
+     * buffers the driver made available on the control receive
+     * queue, as long as there are some.
+     */
+    fn process_control_receive_queue(&mut self) -> bool {
+        let pending_control = match self.multiport.as_mut() {
+            Some(multiport) if !multiport.pending_control.is_empty() => {
+                &mut multiport.pending_control
+            }
+            _ => return false,
+        };
+        let recv_queue = &mut self.queues[CONTROL_RECEIVE_QUEUE];
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+
+        let mem = self.mem.memory();
+        for avail_desc in recv_queue.iter(&mem) {
+            let message = pending_control.pop_front().unwrap();
+            let len = cmp::min(avail_desc.len as usize, message.len());
+            if let Err(e) = mem.write_slice(&message[..len], avail_desc.addr) {
+                error!("Failed to write control message: {:?}", e);
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, len as u32);
+            used_count += 1;
+
+            if pending_control.is_empty() {
+                break;
+            }
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            recv_queue.add_used(&mem, desc_index, len);
+        }
+
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
         self.interrupt_cb
-            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[0]))
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
             .map_err(|e| {
                 error!("Failed to signal used queue: {:?}", e);
                 DeviceError::FailedSignalingUsedQueue(e)
             })
     }
 
+    fn flush_control_messages(&mut self) -> result::Result<(), DeviceError> {
+        if self.process_control_receive_queue() {
+            self.signal_used_queue(CONTROL_RECEIVE_QUEUE)?;
+        }
+
+        Ok(())
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.input_queue_evt.as_raw_fd(), INPUT_QUEUE_EVENT)?;
         helper.add_event(self.output_queue_evt.as_raw_fd(), OUTPUT_QUEUE_EVENT)?;
         helper.add_event(self.input_evt.as_raw_fd(), INPUT_EVENT)?;
         helper.add_event(self.config_evt.as_raw_fd(), CONFIG_EVENT)?;
+        if let Some(multiport) = &self.multiport {
+            helper.add_event(
+                multiport.control_receive_queue_evt.as_raw_fd(),
+                CONTROL_RECEIVE_QUEUE_EVENT,
+            )?;
+            helper.add_event(
+                multiport.control_transmit_queue_evt.as_raw_fd(),
+                CONTROL_TRANSMIT_QUEUE_EVENT,
+            )?;
+            helper.add_event(
+                multiport.crash_transmit_queue_evt.as_raw_fd(),
+                CRASH_TRANSMIT_QUEUE_EVENT,
+            )?;
+        }
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -205,7 +426,7 @@ impl EpollHelperHandler for ConsoleEpollHandler {
                     error!("Failed to get queue event: {:?}", e);
                     return true;
                 } else if self.process_input_queue() {
-                    if let Err(e) = self.signal_used_queue() {
+                    if let Err(e) = self.signal_used_queue(RECEIVE_QUEUE) {
                         error!("Failed to signal used queue: {:?}", e);
                         return true;
                     }
@@ -216,7 +437,8 @@ impl EpollHelperHandler for ConsoleEpollHandler {
                     error!("Failed to get queue event: {:?}", e);
                     return true;
                 } else {
-                    self.process_output_queue();
+                    let out = self.out.clone();
+                    self.process_output_queue(TRANSMIT_QUEUE, &out);
                 }
             }
             INPUT_EVENT => {
@@ -224,7 +446,7 @@ impl EpollHelperHandler for ConsoleEpollHandler {
                     error!("Failed to get input event: {:?}", e);
                     return true;
                 } else if self.process_input_queue() {
-                    if let Err(e) = self.signal_used_queue() {
+                    if let Err(e) = self.signal_used_queue(RECEIVE_QUEUE) {
                         error!("Failed to signal used queue: {:?}", e);
                         return true;
                     }
@@ -241,6 +463,52 @@ impl EpollHelperHandler for ConsoleEpollHandler {
                     error!("Failed to signal console driver: {:?}", e);
                     return true;
                 }
+
+                // With multiple ports, the driver only learns about the new
+                // size of the console through a control message.
+                if self.multiport.is_some() {
+                    self.queue_resize_message();
+                    if let Err(e) = self.flush_control_messages() {
+                        error!("Failed to send control messages: {:?}", e);
+                        return true;
+                    }
+                }
+            }
+            CONTROL_RECEIVE_QUEUE_EVENT => {
+                let multiport = self.multiport.as_ref().unwrap();
+                if let Err(e) = multiport.control_receive_queue_evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                } else if let Err(e) = self.flush_control_messages() {
+                    error!("Failed to send control messages: {:?}", e);
+                    return true;
+                }
+            }
+            CONTROL_TRANSMIT_QUEUE_EVENT => {
+                let multiport = self.multiport.as_ref().unwrap();
+                if let Err(e) = multiport.control_transmit_queue_evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                } else if self.process_control_transmit_queue() {
+                    if let Err(e) = self.signal_used_queue(CONTROL_TRANSMIT_QUEUE) {
+                        error!("Failed to signal used queue: {:?}", e);
+                        return true;
+                    }
+                    if let Err(e) = self.flush_control_messages() {
+                        error!("Failed to send control messages: {:?}", e);
+                        return true;
+                    }
+                }
+            }
+            CRASH_TRANSMIT_QUEUE_EVENT => {
+                let multiport = self.multiport.as_ref().unwrap();
+                if let Err(e) = multiport.crash_transmit_queue_evt.read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                } else {
+                    let crash_out = multiport.crash_out.clone();
+                    self.process_output_queue(CRASH_TRANSMIT_QUEUE, &crash_out);
+                }
             }
             _ => {
                 error!("Unknown event for virtio-console");
@@ -302,7 +570,8 @@ pub struct Console {
     id: String,
     config: Arc<Mutex<VirtioConsoleConfig>>,
     input: Arc<ConsoleInput>,
-    out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
+    out: ConsoleOutput,
+    crash_out: Option<ConsoleOutput>,
     seccomp_action: SeccompAction,
 }
 
@@ -314,11 +583,18 @@ pub struct ConsoleState {
     in_buffer: VecDeque<u8>,
 }
 
+impl VersionedState for ConsoleState {
+    const VERSION: u16 = 1;
+}
+
 impl Console {
     /// Create a new virtio console device that gets random data from /dev/urandom.
+    /// When `crash_out` is given, a second port is exposed to the guest,
+    /// named after CRASH_PORT_NAME, its output going to `crash_out`.
     pub fn new(
         id: String,
         out: Box<dyn io::Write + Send + Sync + 'static>,
+        crash_out: Option<Box<dyn io::Write + Send + Sync + 'static>>,
         cols: u16,
         rows: u16,
         iommu: bool,
@@ -330,9 +606,17 @@ impl Console {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
+        let mut config = VirtioConsoleConfig::new(cols, rows);
+        let mut num_queues = NUM_QUEUES;
+        if crash_out.is_some() {
+            avail_features |= 1u64 << VIRTIO_CONSOLE_F_MULTIPORT;
+            config.max_nr_ports = 2;
+            num_queues = NUM_MULTIPORT_QUEUES;
+        }
+
         let input_evt = EventFd::new(EFD_NONBLOCK).unwrap();
         let config_evt = EventFd::new(EFD_NONBLOCK).unwrap();
-        let console_config = Arc::new(Mutex::new(VirtioConsoleConfig::new(cols, rows)));
+        let console_config = Arc::new(Mutex::new(config));
         let console_input = Arc::new(ConsoleInput {
             input_evt,
             config_evt,
@@ -345,9 +629,9 @@ impl Console {
             Console {
                 common: VirtioCommon {
                     device_type: VirtioDeviceType::TYPE_CONSOLE as u32,
-                    queue_sizes: QUEUE_SIZES.to_vec(),
+                    queue_sizes: vec![QUEUE_SIZE; num_queues],
                     avail_features,
-                    paused_sync: Some(Arc::new(Barrier::new(2))),
+                    paused_sync: Some(Arc::new(PauseBarrier::new(2))),
                     min_queues: NUM_QUEUES as u16,
                     ..Default::default()
                 },
@@ -355,6 +639,7 @@ impl Console {
                 config: console_config,
                 input: console_input.clone(),
                 out: Arc::new(Mutex::new(out)),
+                crash_out: crash_out.map(|o| Arc::new(Mutex::new(o))),
                 seccomp_action,
             },
             console_input,
@@ -416,6 +701,15 @@ impl VirtioDevice for Console {
         mut queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
         self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
+        let multiport = self.common.feature_acked(VIRTIO_CONSOLE_F_MULTIPORT);
+        if multiport && queues.len() != NUM_MULTIPORT_QUEUES {
+            error!(
+                "Cannot activate: expected {} queues, got {}",
+                NUM_MULTIPORT_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
         self.input
             .acked_features
             .store(self.common.acked_features, Ordering::Relaxed);
@@ -459,8 +753,26 @@ impl VirtioDevice for Console {
             config_evt: self.input.config_evt.try_clone().unwrap(),
             kill_evt,
             pause_evt,
+            config: self.config.clone(),
+            multiport: None,
         };
 
+        if multiport {
+            let control_receive_queue_evt = queue_evts.remove(0);
+            let control_transmit_queue_evt = queue_evts.remove(0);
+            // Nothing is ever sent to the guest through the crash port.
+            queue_evts.remove(0);
+            let crash_transmit_queue_evt = queue_evts.remove(0);
+
+            handler.multiport = Some(MultiportHandler {
+                crash_out: self.crash_out.clone().unwrap(),
+                control_receive_queue_evt,
+                control_transmit_queue_evt,
+                crash_transmit_queue_evt,
+                pending_control: VecDeque::new(),
+            });
+        }
+
         let paused = self.common.paused.clone();
         let paused_sync = self.common.paused_sync.clone();
         let mut epoll_threads = Vec::new();
@@ -498,6 +810,10 @@ impl Pausable for Console {
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         self.common.resume()
     }
@@ -509,29 +825,18 @@ impl Snapshottable for Console {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut console_snapshot = Snapshot::new(self.id.as_str());
-        console_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id),
-            snapshot,
-        });
+        console_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
 
         Ok(console_snapshot)
     }
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(console_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
-            let console_state = match serde_json::from_slice(&console_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize CONSOLE {}",
-                        error
-                    )))
-                }
-            };
+            let console_state = console_section.to_versioned_state::<ConsoleState>()?;
 
             self.set_state(&console_state);
             return Ok(());
@@ -544,3 +849,161 @@ impl Snapshottable for Console {
 }
 impl Transportable for Console {}
 impl Migratable for Console {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vsock::tests::NoopVirtioInterrupt;
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn control_message(mem: &GuestMemoryMmap, addr: u64) -> (u32, u16, u16) {
+        let message: VirtioConsoleControl = mem.read_obj(GuestAddress(addr)).unwrap();
+        (message.id, message.event, message.value)
+    }
+
+    fn send_control_message(
+        guest_queue: &GuestQ,
+        mem: &GuestMemoryMmap,
+        index: u16,
+        message: VirtioConsoleControl,
+    ) {
+        let addr = 0x8_0000 + u64::from(index) * 0x100;
+        mem.write_obj(message, GuestAddress(addr)).unwrap();
+        guest_queue.dtable[index as usize].set(addr, message.as_slice().len() as u32, 0, 0);
+        guest_queue.avail.ring[index as usize].set(index);
+        guest_queue.avail.idx.set(index + 1);
+    }
+
+    #[test]
+    fn test_console_multiport() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queues: Vec<GuestQ> = (0..NUM_MULTIPORT_QUEUES)
+            .map(|i| GuestQ::new(GuestAddress(i as u64 * 0x1_0000), &mem, 16))
+            .collect();
+        let out = SharedOutput::default();
+        let crash_out = SharedOutput::default();
+
+        let mut handler = ConsoleEpollHandler {
+            queues: guest_queues.iter().map(|q| q.create_queue()).collect(),
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            in_buffer: Arc::new(Mutex::new(VecDeque::new())),
+            out: Arc::new(Mutex::new(Box::new(out.clone()))),
+            input_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            output_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            input_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            config_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            config: Arc::new(Mutex::new(VirtioConsoleConfig::default())),
+            multiport: Some(MultiportHandler {
+                crash_out: Arc::new(Mutex::new(Box::new(crash_out.clone()))),
+                control_receive_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+                control_transmit_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+                crash_transmit_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+                pending_control: VecDeque::new(),
+            }),
+        };
+
+        // The driver makes a few buffers available for the control messages
+        // of the device.
+        let control_receive = &guest_queues[CONTROL_RECEIVE_QUEUE];
+        for i in 0..4 {
+            control_receive.dtable[i].set(
+                0x9_0000 + i as u64 * 0x100,
+                0x100,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            control_receive.avail.ring[i].set(i as u16);
+        }
+        control_receive.avail.idx.set(4);
+        // Nothing is sent before the driver is ready.
+        assert!(!handler.process_control_receive_queue());
+
+        // Both ports are added once the driver is ready.
+        let control_transmit = &guest_queues[CONTROL_TRANSMIT_QUEUE];
+        send_control_message(
+            control_transmit,
+            &mem,
+            0,
+            VirtioConsoleControl {
+                id: 0,
+                event: VIRTIO_CONSOLE_DEVICE_READY,
+                value: 1,
+            },
+        );
+        assert!(handler.process_control_transmit_queue());
+        assert!(handler.process_control_receive_queue());
+        assert_eq!(control_receive.used.idx.get(), 2);
+        assert_eq!(
+            control_message(&mem, 0x9_0000),
+            (CONSOLE_PORT_ID, VIRTIO_CONSOLE_DEVICE_ADD, 0)
+        );
+        assert_eq!(
+            control_message(&mem, 0x9_0100),
+            (CRASH_PORT_ID, VIRTIO_CONSOLE_DEVICE_ADD, 0)
+        );
+
+        // The crash port gets named and opened once the driver added it.
+        send_control_message(
+            control_transmit,
+            &mem,
+            1,
+            VirtioConsoleControl {
+                id: CRASH_PORT_ID,
+                event: VIRTIO_CONSOLE_PORT_READY,
+                value: 1,
+            },
+        );
+        assert!(handler.process_control_transmit_queue());
+        assert!(handler.process_control_receive_queue());
+        assert_eq!(control_receive.used.idx.get(), 4);
+        assert_eq!(
+            control_message(&mem, 0x9_0200),
+            (CRASH_PORT_ID, VIRTIO_CONSOLE_PORT_NAME, 1)
+        );
+        let header_len = std::mem::size_of::<VirtioConsoleControl>();
+        assert_eq!(
+            control_receive.used.ring[2].get().len as usize,
+            header_len + CRASH_PORT_NAME.len()
+        );
+        let mut name = vec![0u8; CRASH_PORT_NAME.len()];
+        mem.read_slice(&mut name, GuestAddress(0x9_0200 + header_len as u64))
+            .unwrap();
+        assert_eq!(name, CRASH_PORT_NAME.as_bytes());
+        assert_eq!(
+            control_message(&mem, 0x9_0300),
+            (CRASH_PORT_ID, VIRTIO_CONSOLE_PORT_OPEN, 1)
+        );
+
+        // What the guest writes to the crash port only reaches the crash
+        // dump output.
+        let crash_transmit = &guest_queues[CRASH_TRANSMIT_QUEUE];
+        mem.write_slice(b"panic", GuestAddress(0xa_0000)).unwrap();
+        crash_transmit.dtable[0].set(0xa_0000, 5, 0, 0);
+        crash_transmit.avail.ring[0].set(0);
+        crash_transmit.avail.idx.set(1);
+        let crash = handler.multiport.as_ref().unwrap().crash_out.clone();
+        assert!(handler.process_output_queue(CRASH_TRANSMIT_QUEUE, &crash));
+        assert_eq!(crash_transmit.used.idx.get(), 1);
+        assert_eq!(*crash_out.0.lock().unwrap(), b"panic");
+        assert!(out.0.lock().unwrap().is_empty());
+    }
+}
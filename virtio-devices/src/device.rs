@@ -7,18 +7,22 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use crate::{ActivateError, ActivateResult, Error, Queue};
+use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use std::collections::HashMap;
 use std::io::Write;
 use std::num::Wrapping;
+use std::ops::Range;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc, Barrier,
+    Arc, Condvar, Mutex,
 };
 use std::thread;
+use std::time::Instant;
 use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryMmap, GuestUsize};
+use vm_migration::protocol::MemoryRangeTable;
 use vm_migration::{MigratableError, Pausable};
-use vm_virtio::VirtioDeviceType;
+use vm_virtio::{DescriptorInspector, VirtioDeviceType};
 use vmm_sys_util::eventfd::EventFd;
 
 pub enum VirtioInterruptType {
@@ -87,15 +91,21 @@ pub trait VirtioDevice: Send {
 
     /// Reads this device configuration space at `offset`.
     fn read_config(&self, _offset: u64, _data: &mut [u8]) {
-        warn!(
+        guest_warn!(
             "No readable configuration fields for {}",
             VirtioDeviceType::from(self.device_type())
         );
     }
 
+    /// Ranges of the configuration space the guest can't modify, the writes
+    /// to them being ignored by write_config_helper.
+    fn read_only_config_ranges(&self) -> Vec<Range<u64>> {
+        Vec::new()
+    }
+
     /// Writes to this device configuration space at `offset`.
     fn write_config(&mut self, _offset: u64, _data: &[u8]) {
-        warn!(
+        guest_warn!(
             "No writable configuration fields for {}",
             VirtioDeviceType::from(self.device_type())
         );
@@ -143,6 +153,22 @@ pub trait VirtioDevice: Send {
         Ok(())
     }
 
+    /// Start logging the guest memory the device writes to behind the back
+    /// of the VMM, as a vhost backend does, for the memory to be migrated.
+    fn start_dirty_log(&mut self, _mem: &GuestMemoryMmap) -> std::result::Result<(), Error> {
+        Ok(())
+    }
+
+    fn stop_dirty_log(&mut self, _mem: &GuestMemoryMmap) -> std::result::Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returns the guest memory the device wrote to since the last call,
+    /// once start_dirty_log() was called.
+    fn dirty_log(&mut self) -> std::result::Result<MemoryRangeTable, Error> {
+        Ok(MemoryRangeTable::default())
+    }
+
     /// Returns the list of userspace mappings associated with this device.
     fn userspace_mappings(&self) -> Vec<UserspaceMapping> {
         Vec::new()
@@ -153,12 +179,22 @@ pub trait VirtioDevice: Send {
         None
     }
 
+    /// Return the counters that this device exposes for each of its queues
+    fn queue_counters(&self) -> Option<Vec<HashMap<&'static str, Wrapping<u64>>>> {
+        None
+    }
+
+    /// Registers a hook inspecting the descriptor chains before the device
+    /// processes them, which takes effect on the next activation. Devices
+    /// not supporting it ignore the hook.
+    fn set_descriptor_inspector(&mut self, _inspector: Arc<dyn DescriptorInspector>) {}
+
     /// Helper to allow common implementation of read_config
     fn read_config_from_slice(&self, config: &[u8], offset: u64, mut data: &mut [u8]) {
         let config_len = config.len() as u64;
         let data_len = data.len() as u64;
         if offset + data_len > config_len {
-            error!(
+            guest_error!(
                 "Out-of-bound access to configuration: config_len = {} offset = {:x} length = {} for {}",
                 config_len,
                 offset,
@@ -178,7 +214,7 @@ pub trait VirtioDevice: Send {
         let config_len = config.len() as u64;
         let data_len = data.len() as u64;
         if offset + data_len > config_len {
-            error!(
+            guest_error!(
                     "Out-of-bound access to configuration: config_len = {} offset = {:x} length = {} for {}",
                     config_len,
                     offset,
@@ -188,12 +224,38 @@ pub trait VirtioDevice: Send {
             return;
         }
 
-        if let Some(end) = offset.checked_add(config.len() as u64) {
-            let mut offset_config =
-                &mut config[offset as usize..std::cmp::min(end, config_len) as usize];
-            offset_config.write_all(data).unwrap();
+        let ignored = write_config_masked(config, offset, data, &self.read_only_config_ranges());
+        if ignored > 0 {
+            guest_warn!(
+                "Ignored write of {} bytes to read-only configuration fields: offset = {:x} length = {} for {}",
+                ignored,
+                offset,
+                data_len,
+                self.device_type()
+            );
+        }
+    }
+}
+
+/// Writes `data` to the configuration space at `offset`, except for the bytes
+/// within the `read_only` ranges, returning how many of them were left as is.
+/// The write must fit in the configuration space.
+pub fn write_config_masked(
+    config: &mut [u8],
+    offset: u64,
+    data: &[u8],
+    read_only: &[Range<u64>],
+) -> usize {
+    let mut ignored = 0;
+    for (byte_offset, byte) in (offset..).zip(data.iter()) {
+        if read_only.iter().any(|range| range.contains(&byte_offset)) {
+            ignored += 1;
+        } else {
+            config[byte_offset as usize] = *byte;
         }
     }
+
+    ignored
 }
 
 /// Trait providing address translation the same way a physical DMA remapping
@@ -206,6 +268,114 @@ pub trait DmaRemapping: Send + Sync {
     fn translate(&self, id: u32, addr: u64) -> std::result::Result<u64, std::io::Error>;
 }
 
+/// Point where the threads of a device acknowledge it's being paused before
+/// parking, no descriptor chain being processed anymore. Unlike a `Barrier`,
+/// the VMM waiting for the threads can give up after a deadline.
+pub struct PauseBarrier {
+    threads: usize,
+    state: Mutex<PauseState>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct PauseState {
+    // Bumped every time the device is resumed.
+    generation: u64,
+    acknowledged: usize,
+    // Whether a thread couldn't settle its operations.
+    failed: bool,
+    deadline: Option<Instant>,
+}
+
+impl PauseBarrier {
+    /// Creates the barrier of `count` parties, as a `Barrier` would: the
+    /// threads of the device and the VMM.
+    pub fn new(count: usize) -> Self {
+        PauseBarrier {
+            threads: count.saturating_sub(1),
+            state: Mutex::new(PauseState::default()),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Called by the VMM before telling the threads to pause, with the
+    /// deadline they have to settle their operations by.
+    pub fn start(&self, deadline: Option<Instant>) {
+        self.state.lock().unwrap().deadline = deadline;
+    }
+
+    /// Deadline of the pause in progress.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.state.lock().unwrap().deadline
+    }
+
+    /// Generation of the pause, to be read by a device thread as soon as
+    /// it's told to pause and handed back to `acknowledge()` or `fail()`.
+    pub fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    /// Called by a device thread once it's been told to pause. The
+    /// acknowledgement of a pause the device was resumed from since is
+    /// ignored.
+    pub fn acknowledge(&self, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        state.acknowledged += 1;
+        self.cond.notify_all();
+    }
+
+    /// Called by a device thread which couldn't settle its operations,
+    /// making the pause fail without waiting for the deadline.
+    pub fn fail(&self, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        state.failed = true;
+        self.cond.notify_all();
+    }
+
+    /// Waits for all the threads of the device to acknowledge the pause.
+    /// False is returned if one of them failed to, or if the deadline
+    /// expired. The threads acknowledging late are accounted for by the
+    /// next wait.
+    pub fn wait(&self, deadline: Option<Instant>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while state.acknowledged < self.threads {
+            if state.failed {
+                return false;
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.cond.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.cond.wait(state).unwrap(),
+            };
+        }
+        state.acknowledged -= self.threads;
+
+        true
+    }
+
+    /// Forgets about the acknowledgements, once the device is resumed,
+    /// starting a new generation so that the threads acknowledging the
+    /// previous pause late aren't accounted for.
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.generation = state.generation.wrapping_add(1);
+        state.acknowledged = 0;
+        state.failed = false;
+        state.deadline = None;
+    }
+}
+
 /// Structure to handle device state common to all devices
 #[derive(Default)]
 pub struct VirtioCommon {
@@ -216,7 +386,7 @@ pub struct VirtioCommon {
     pub queue_evts: Option<Vec<EventFd>>,
     pub pause_evt: Option<EventFd>,
     pub paused: Arc<AtomicBool>,
-    pub paused_sync: Option<Arc<Barrier>>,
+    pub paused_sync: Option<Arc<PauseBarrier>>,
     pub epoll_threads: Option<Vec<thread::JoinHandle<()>>>,
     pub queue_sizes: Vec<u16>,
     pub device_type: u32,
@@ -233,7 +403,7 @@ impl VirtioCommon {
         // Check if the guest is ACK'ing a feature that we didn't claim to have.
         let unrequested_features = v & !self.avail_features;
         if unrequested_features != 0 {
-            warn!("Received acknowledge request for unknown feature.");
+            guest_warn!("Received acknowledge request for unknown feature.");
 
             // Don't count these features as acked.
             v &= !unrequested_features;
@@ -318,14 +488,18 @@ impl VirtioCommon {
     }
 }
 
-impl Pausable for VirtioCommon {
-    fn pause(&mut self) -> std::result::Result<(), MigratableError> {
+impl VirtioCommon {
+    fn pause_threads(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> std::result::Result<(), MigratableError> {
         debug!(
             "Pausing virtio-{}",
             VirtioDeviceType::from(self.device_type)
         );
         self.paused.store(true, Ordering::SeqCst);
         if let Some(pause_evt) = &self.pause_evt {
+            self.paused_sync.as_ref().unwrap().start(deadline);
             pause_evt
                 .write(1)
                 .map_err(|e| MigratableError::Pause(e.into()))?;
@@ -335,11 +509,26 @@ impl Pausable for VirtioCommon {
             // eventfd is Some(), as this means the virtio device has been
             // activated. One specific case where the device can be paused
             // while it hasn't been yet activated is snapshot/restore.
-            self.paused_sync.as_ref().unwrap().wait();
+            if !self.paused_sync.as_ref().unwrap().wait(deadline) {
+                return Err(MigratableError::Quiesce(anyhow!(
+                    "The virtio-{} threads failed to pause in time",
+                    VirtioDeviceType::from(self.device_type)
+                )));
+            }
         }
 
         Ok(())
     }
+}
+
+impl Pausable for VirtioCommon {
+    fn pause(&mut self) -> std::result::Result<(), MigratableError> {
+        self.pause_threads(None)
+    }
+
+    fn quiesce(&mut self, deadline: Instant) -> std::result::Result<(), MigratableError> {
+        self.pause_threads(Some(deadline))
+    }
 
     fn resume(&mut self) -> std::result::Result<(), MigratableError> {
         debug!(
@@ -347,6 +536,9 @@ impl Pausable for VirtioCommon {
             VirtioDeviceType::from(self.device_type)
         );
         self.paused.store(false, Ordering::SeqCst);
+        if let Some(paused_sync) = &self.paused_sync {
+            paused_sync.reset();
+        }
         if let Some(epoll_threads) = &self.epoll_threads {
             for t in epoll_threads.iter() {
                 t.thread().unpark();
@@ -356,3 +548,83 @@ impl Pausable for VirtioCommon {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pause_barrier() {
+        let barrier = Arc::new(PauseBarrier::new(3));
+
+        let generation = barrier.generation();
+
+        // One of the two threads doesn't acknowledge in time.
+        barrier.acknowledge(generation);
+        assert!(!barrier.wait(Some(Instant::now() + Duration::from_millis(10))));
+
+        // Its late acknowledgement completes the next wait.
+        let thread_barrier = barrier.clone();
+        let thread = thread::spawn(move || thread_barrier.acknowledge(generation));
+        assert!(barrier.wait(None));
+        thread.join().unwrap();
+
+        // The acknowledgements are consumed by a successful wait, and
+        // forgotten once the device resumes.
+        barrier.acknowledge(generation);
+        assert!(!barrier.wait(Some(Instant::now())));
+        barrier.reset();
+        let generation = barrier.generation();
+        barrier.acknowledge(generation);
+        barrier.acknowledge(generation);
+        assert!(barrier.wait(Some(Instant::now())));
+    }
+
+    #[test]
+    fn test_pause_barrier_rollback() {
+        let barrier = PauseBarrier::new(3);
+        let generation = barrier.generation();
+
+        // The pause times out with a single acknowledgement, and is rolled
+        // back by resuming the device.
+        barrier.acknowledge(generation);
+        assert!(!barrier.wait(Some(Instant::now())));
+        barrier.reset();
+
+        // The thread which was late acknowledges the pause rolled back,
+        // which doesn't complete the next one early.
+        barrier.acknowledge(generation);
+        let generation = barrier.generation();
+        barrier.acknowledge(generation);
+        assert!(!barrier.wait(Some(Instant::now())));
+        barrier.acknowledge(generation);
+        assert!(barrier.wait(Some(Instant::now())));
+    }
+
+    #[test]
+    fn test_pause_barrier_failure() {
+        let barrier = Arc::new(PauseBarrier::new(3));
+        let deadline = Instant::now() + Duration::from_secs(60);
+        barrier.start(Some(deadline));
+        assert_eq!(barrier.deadline(), Some(deadline));
+        let generation = barrier.generation();
+
+        // A thread failing to settle its operations makes the pause fail
+        // right away, rather than by the deadline.
+        barrier.acknowledge(generation);
+        let thread_barrier = barrier.clone();
+        let thread = thread::spawn(move || thread_barrier.fail(generation));
+        assert!(!barrier.wait(Some(deadline)));
+        thread.join().unwrap();
+
+        // Which is forgotten once the device resumes.
+        barrier.reset();
+        assert_eq!(barrier.deadline(), None);
+        barrier.fail(generation);
+        let generation = barrier.generation();
+        barrier.acknowledge(generation);
+        barrier.acknowledge(generation);
+        assert!(barrier.wait(Some(Instant::now())));
+    }
+}
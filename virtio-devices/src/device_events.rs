@@ -0,0 +1,153 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// State a device transitioned to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceEvent {
+    /// The device was activated by the driver, or resumed.
+    Activated,
+    /// The device was paused.
+    Deactivated,
+    /// The device was reset by the driver.
+    Reset,
+}
+
+/// Callback invoked with the id of the device and the state it transitioned
+/// to.
+pub type DeviceEventCallback = Arc<dyn Fn(&str, DeviceEvent) + Send + Sync>;
+
+type Callbacks = Arc<Mutex<Vec<DeviceEventCallback>>>;
+
+/// Dispatches the events of the devices to the registered callbacks, from a
+/// thread of its own so that the device threads never wait for them. The
+/// thread exits once the dispatcher and all its notifiers are dropped.
+pub struct DeviceEventDispatcher {
+    sender: Sender<(String, DeviceEvent)>,
+    callbacks: Callbacks,
+}
+
+impl DeviceEventDispatcher {
+    pub fn new() -> io::Result<Self> {
+        let (sender, receiver) = channel();
+        let callbacks = Callbacks::default();
+
+        let thread_callbacks = callbacks.clone();
+        thread::Builder::new()
+            .name("device_events".to_string())
+            .spawn(move || Self::dispatch(receiver, thread_callbacks))?;
+
+        Ok(DeviceEventDispatcher { sender, callbacks })
+    }
+
+    fn dispatch(receiver: Receiver<(String, DeviceEvent)>, callbacks: Callbacks) {
+        for (id, event) in receiver {
+            // The lock isn't held while the callbacks run, for them to be
+            // able to register others.
+            let callbacks = callbacks.lock().unwrap().clone();
+            for callback in callbacks.iter() {
+                callback(&id, event);
+            }
+        }
+    }
+
+    /// Registers a callback invoked on each event of any device.
+    pub fn register(&self, callback: DeviceEventCallback) {
+        self.callbacks.lock().unwrap().push(callback);
+    }
+
+    /// Notifier reporting the events of the device with the given id.
+    pub fn notifier(&self, id: String) -> DeviceEventNotifier {
+        DeviceEventNotifier {
+            id,
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DeviceEventNotifier {
+    id: String,
+    sender: Sender<(String, DeviceEvent)>,
+}
+
+impl DeviceEventNotifier {
+    /// Queues the event for the callbacks, without waiting for them.
+    pub fn notify(&self, event: DeviceEvent) {
+        if self.sender.send((self.id.clone(), event)).is_err() {
+            warn!("Device event dispatcher gone, dropping {:?} event", event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_device_event_dispatcher() {
+        let dispatcher = DeviceEventDispatcher::new().unwrap();
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        dispatcher.register(Arc::new(move |id, event| {
+            sender
+                .lock()
+                .unwrap()
+                .send((id.to_string(), event))
+                .unwrap();
+        }));
+
+        let notifier = dispatcher.notifier("_net0".to_string());
+        notifier.notify(DeviceEvent::Activated);
+        notifier.notify(DeviceEvent::Reset);
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            receiver.recv_timeout(timeout).unwrap(),
+            ("_net0".to_string(), DeviceEvent::Activated)
+        );
+        assert_eq!(
+            receiver.recv_timeout(timeout).unwrap(),
+            ("_net0".to_string(), DeviceEvent::Reset)
+        );
+    }
+
+    #[test]
+    fn test_device_event_callback_registering() {
+        let dispatcher = DeviceEventDispatcher::new().unwrap();
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+
+        // A callback registering another one, as registering does, doesn't
+        // deadlock the dispatcher.
+        let callbacks = dispatcher.callbacks.clone();
+        dispatcher.register(Arc::new(move |_, _| {
+            let sender = Mutex::new(sender.lock().unwrap().clone());
+            callbacks.lock().unwrap().push(Arc::new(move |id, event| {
+                sender
+                    .lock()
+                    .unwrap()
+                    .send((id.to_string(), event))
+                    .unwrap();
+            }));
+        }));
+
+        let notifier = dispatcher.notifier("_net0".to_string());
+        notifier.notify(DeviceEvent::Activated);
+        notifier.notify(DeviceEvent::Deactivated);
+
+        // The registered callback only sees the events following its
+        // registration.
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            ("_net0".to_string(), DeviceEvent::Deactivated)
+        );
+    }
+}
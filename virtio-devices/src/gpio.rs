@@ -0,0 +1,1045 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Virtio GPIO device, exposing lines the guest configures as inputs or
+//! outputs. The host observes the outputs driven by the guest, such as
+//! status LEDs, and drives the inputs, such as buttons, raising the
+//! interrupts the guest enabled on them.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, EpollHelper, EpollHelperError,
+    EpollHelperHandler, Queue, VirtioCommon, VirtioDevice, VirtioDeviceType,
+    EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
+};
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::{PauseBarrier, VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use seccomp::{SeccompAction, SeccompFilter};
+use std::convert::TryFrom;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use vm_memory::{
+    ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap,
+};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable, VersionedState,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 64;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+
+const REQUEST_QUEUE: usize = 0;
+const EVENT_QUEUE: usize = 1;
+
+// The driver sent requests on the request queue.
+const REQUEST_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// The driver made new buffers available for the interrupts.
+const EVENT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// Input lines have been driven by the VMM.
+const INJECT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+
+/// Most lines a virtio-gpio device can expose.
+pub const MAX_GPIO_LINES: u16 = 256;
+
+// The device supports interrupts on its input lines.
+const VIRTIO_GPIO_F_IRQ: u64 = 0;
+
+// Request types.
+const VIRTIO_GPIO_MSG_GET_NAMES: u16 = 0x0001;
+const VIRTIO_GPIO_MSG_GET_DIRECTION: u16 = 0x0002;
+const VIRTIO_GPIO_MSG_SET_DIRECTION: u16 = 0x0003;
+const VIRTIO_GPIO_MSG_GET_VALUE: u16 = 0x0004;
+const VIRTIO_GPIO_MSG_SET_VALUE: u16 = 0x0005;
+const VIRTIO_GPIO_MSG_SET_IRQ_TYPE: u16 = 0x0006;
+
+// Line directions.
+const VIRTIO_GPIO_DIRECTION_NONE: u8 = 0x00;
+const VIRTIO_GPIO_DIRECTION_OUT: u8 = 0x01;
+const VIRTIO_GPIO_DIRECTION_IN: u8 = 0x02;
+
+// Interrupt types.
+const VIRTIO_GPIO_IRQ_TYPE_NONE: u32 = 0x00;
+const VIRTIO_GPIO_IRQ_TYPE_EDGE_RISING: u32 = 0x01;
+const VIRTIO_GPIO_IRQ_TYPE_EDGE_FALLING: u32 = 0x02;
+const VIRTIO_GPIO_IRQ_TYPE_EDGE_BOTH: u32 = 0x03;
+const VIRTIO_GPIO_IRQ_TYPE_LEVEL_HIGH: u32 = 0x04;
+const VIRTIO_GPIO_IRQ_TYPE_LEVEL_LOW: u32 = 0x08;
+
+const VIRTIO_GPIO_STATUS_OK: u8 = 0x0;
+const VIRTIO_GPIO_STATUS_ERR: u8 = 0x1;
+
+const VIRTIO_GPIO_IRQ_STATUS_INVALID: u8 = 0x0;
+const VIRTIO_GPIO_IRQ_STATUS_VALID: u8 = 0x1;
+
+// struct virtio_gpio_config
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VirtioGpioConfig {
+    ngpio: u16,
+    padding: [u8; 2],
+    gpio_names_size: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpioConfig {}
+
+// struct virtio_gpio_request
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VirtioGpioRequest {
+    type_: u16,
+    gpio: u16,
+    value: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpioRequest {}
+
+// struct virtio_gpio_response
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VirtioGpioResponse {
+    status: u8,
+    value: u8,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpioResponse {}
+
+// struct virtio_gpio_irq_request
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VirtioGpioIrqRequest {
+    gpio: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpioIrqRequest {}
+
+/// Direction of a line, as configured by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum GpioDirection {
+    None,
+    Out,
+    In,
+}
+
+impl Default for GpioDirection {
+    fn default() -> Self {
+        GpioDirection::None
+    }
+}
+
+/// Interrupt the guest enabled on an input line.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum GpioIrqType {
+    None,
+    EdgeRising,
+    EdgeFalling,
+    EdgeBoth,
+    LevelHigh,
+    LevelLow,
+}
+
+impl Default for GpioIrqType {
+    fn default() -> Self {
+        GpioIrqType::None
+    }
+}
+
+impl GpioIrqType {
+    fn from_raw(value: u32) -> Option<Self> {
+        match value {
+            VIRTIO_GPIO_IRQ_TYPE_NONE => Some(GpioIrqType::None),
+            VIRTIO_GPIO_IRQ_TYPE_EDGE_RISING => Some(GpioIrqType::EdgeRising),
+            VIRTIO_GPIO_IRQ_TYPE_EDGE_FALLING => Some(GpioIrqType::EdgeFalling),
+            VIRTIO_GPIO_IRQ_TYPE_EDGE_BOTH => Some(GpioIrqType::EdgeBoth),
+            VIRTIO_GPIO_IRQ_TYPE_LEVEL_HIGH => Some(GpioIrqType::LevelHigh),
+            VIRTIO_GPIO_IRQ_TYPE_LEVEL_LOW => Some(GpioIrqType::LevelLow),
+            _ => None,
+        }
+    }
+}
+
+/// State of a line, as seen by the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct GpioLineState {
+    pub direction: GpioDirection,
+    /// Value driven by the guest for an output, by the host otherwise.
+    pub value: bool,
+    pub irq_type: GpioIrqType,
+}
+
+/// Notified of the changes the guest makes to the lines of a device.
+pub trait GpioObserver: Send + Sync {
+    /// Called once the guest changed the direction or the value of `line`.
+    fn line_changed(&self, line: u16, state: &GpioLineState);
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+struct GpioLine {
+    direction: GpioDirection,
+    // Value set by the guest, driven while the line is an output.
+    output: bool,
+    // Value driven by the host.
+    input: bool,
+    irq_type: GpioIrqType,
+    // An edge happened while no buffer was waiting for the interrupt.
+    irq_latched: bool,
+    // Head index and status address of the buffer waiting for the
+    // interrupt, which the driver queues again after each of them.
+    irq_buffer: Option<(u16, u64)>,
+}
+
+impl GpioLine {
+    fn value(&self) -> bool {
+        if self.direction == GpioDirection::Out {
+            self.output
+        } else {
+            self.input
+        }
+    }
+
+    fn state(&self) -> GpioLineState {
+        GpioLineState {
+            direction: self.direction,
+            value: self.value(),
+            irq_type: self.irq_type,
+        }
+    }
+
+    // Whether the interrupt enabled on the line is pending.
+    fn irq_pending(&self) -> bool {
+        match self.irq_type {
+            GpioIrqType::None => false,
+            GpioIrqType::LevelHigh => self.input,
+            GpioIrqType::LevelLow => !self.input,
+            _ => self.irq_latched,
+        }
+    }
+
+    // Drives the line from the host, latching the edge if the guest
+    // enabled an interrupt on it.
+    fn set_input(&mut self, value: bool) {
+        let rising = !self.input && value;
+        let falling = self.input && !value;
+        self.input = value;
+        self.irq_latched |= match self.irq_type {
+            GpioIrqType::EdgeRising => rising,
+            GpioIrqType::EdgeFalling => falling,
+            GpioIrqType::EdgeBoth => rising || falling,
+            _ => false,
+        };
+    }
+}
+
+struct GpioEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    lines: Arc<Mutex<Vec<GpioLine>>>,
+    observer: Option<Arc<dyn GpioObserver>>,
+    inject_evt: EventFd,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+}
+
+impl GpioEpollHandler {
+    // Carries out a request of the driver, returning the status and the
+    // value of the response.
+    fn handle_request(
+        &self,
+        lines: &mut [GpioLine],
+        request: &VirtioGpioRequest,
+        cancelled: &mut Vec<(u16, u64)>,
+    ) -> (u8, u8) {
+        let type_ = u16::from_le(request.type_);
+        let gpio = u16::from_le(request.gpio);
+        let value = u32::from_le(request.value);
+
+        // The lines have no name, which the driver knows from the size of
+        // the names reported in the configuration space.
+        if type_ == VIRTIO_GPIO_MSG_GET_NAMES {
+            return (VIRTIO_GPIO_STATUS_ERR, 0);
+        }
+        let line = match lines.get_mut(gpio as usize) {
+            Some(line) => line,
+            None => return (VIRTIO_GPIO_STATUS_ERR, 0),
+        };
+        let previous = line.state();
+
+        match type_ {
+            VIRTIO_GPIO_MSG_GET_DIRECTION => {
+                let direction = match line.direction {
+                    GpioDirection::None => VIRTIO_GPIO_DIRECTION_NONE,
+                    GpioDirection::Out => VIRTIO_GPIO_DIRECTION_OUT,
+                    GpioDirection::In => VIRTIO_GPIO_DIRECTION_IN,
+                };
+                return (VIRTIO_GPIO_STATUS_OK, direction);
+            }
+            VIRTIO_GPIO_MSG_GET_VALUE => return (VIRTIO_GPIO_STATUS_OK, line.value() as u8),
+            VIRTIO_GPIO_MSG_SET_DIRECTION => {
+                line.direction = match u8::try_from(value) {
+                    Ok(VIRTIO_GPIO_DIRECTION_NONE) => GpioDirection::None,
+                    Ok(VIRTIO_GPIO_DIRECTION_OUT) => GpioDirection::Out,
+                    Ok(VIRTIO_GPIO_DIRECTION_IN) => GpioDirection::In,
+                    _ => return (VIRTIO_GPIO_STATUS_ERR, 0),
+                };
+            }
+            // The driver sets the value of an output before its direction.
+            VIRTIO_GPIO_MSG_SET_VALUE => match value {
+                0 | 1 => line.output = value == 1,
+                _ => return (VIRTIO_GPIO_STATUS_ERR, 0),
+            },
+            VIRTIO_GPIO_MSG_SET_IRQ_TYPE => {
+                let irq_type = match GpioIrqType::from_raw(value) {
+                    Some(irq_type) => irq_type,
+                    None => return (VIRTIO_GPIO_STATUS_ERR, 0),
+                };
+                if irq_type != GpioIrqType::None && line.direction == GpioDirection::Out {
+                    return (VIRTIO_GPIO_STATUS_ERR, 0);
+                }
+                line.irq_type = irq_type;
+                line.irq_latched = false;
+                // The buffer waiting for an interrupt which is now disabled
+                // is given back to the driver.
+                if irq_type == GpioIrqType::None {
+                    cancelled.extend(line.irq_buffer.take());
+                }
+            }
+            _ => return (VIRTIO_GPIO_STATUS_ERR, 0),
+        }
+
+        let state = line.state();
+        if state.direction != previous.direction || state.value != previous.value {
+            if let Some(observer) = &self.observer {
+                observer.line_changed(gpio, &state);
+            }
+        }
+
+        (VIRTIO_GPIO_STATUS_OK, 0)
+    }
+
+    fn parse_request(
+        avail_desc: &DescriptorChain,
+        mem: &GuestMemoryMmap,
+    ) -> Option<(VirtioGpioRequest, GuestAddress)> {
+        if avail_desc.is_write_only() || (avail_desc.len as usize) < size_of::<VirtioGpioRequest>()
+        {
+            return None;
+        }
+        let request = mem.read_obj(avail_desc.addr).ok()?;
+        let response_desc = avail_desc.next_descriptor()?;
+        if !response_desc.is_write_only()
+            || (response_desc.len as usize) < size_of::<VirtioGpioResponse>()
+        {
+            return None;
+        }
+        Some((request, response_desc.addr))
+    }
+
+    // Returns whether entries were added to the used ring of the request
+    // queue and of the event queue, whose buffers are given back when the
+    // interrupts of their lines are disabled.
+    fn process_request_queue(&mut self) -> (bool, bool) {
+        let mem = self.mem.memory();
+        let lines = self.lines.clone();
+        let mut lines = lines.lock().unwrap();
+        let mut used_desc_heads = Vec::new();
+        let mut cancelled = Vec::new();
+
+        // The available ring is read once, and never for more requests than
+        // the queue holds, whatever the index written by the driver.
+        let requests: Vec<_> = self.queues[REQUEST_QUEUE]
+            .iter(&mem)
+            .take(QUEUE_SIZE as usize)
+            .map(|avail_desc| (avail_desc.index, Self::parse_request(&avail_desc, &mem)))
+            .collect();
+
+        for (desc_index, request) in requests {
+            let mut len = 0;
+            match request {
+                Some((request, response_addr)) => {
+                    let (status, value) = self.handle_request(&mut lines, &request, &mut cancelled);
+                    match mem.write_obj(VirtioGpioResponse { status, value }, response_addr) {
+                        Ok(_) => len = size_of::<VirtioGpioResponse>() as u32,
+                        Err(e) => error!("Failed to write GPIO response: {:?}", e),
+                    }
+                }
+                None => error!("Invalid descriptor chain on the GPIO request queue"),
+            }
+
+            used_desc_heads.push((desc_index, len));
+        }
+
+        for &(desc_index, len) in used_desc_heads.iter() {
+            self.queues[REQUEST_QUEUE].add_used(&mem, desc_index, len);
+        }
+        for &(desc_index, status_addr) in cancelled.iter() {
+            self.complete_irq(desc_index, status_addr, VIRTIO_GPIO_IRQ_STATUS_INVALID);
+        }
+
+        (!used_desc_heads.is_empty(), !cancelled.is_empty())
+    }
+
+    fn complete_irq(&mut self, desc_index: u16, status_addr: u64, status: u8) {
+        let mem = self.mem.memory();
+        let len = match mem.write_obj(status, GuestAddress(status_addr)) {
+            Ok(_) => size_of::<u8>() as u32,
+            Err(e) => {
+                error!("Failed to write GPIO interrupt status: {:?}", e);
+                0
+            }
+        };
+        self.queues[EVENT_QUEUE].add_used(&mem, desc_index, len);
+    }
+
+    // Takes the buffers the driver queued for the interrupts of the lines,
+    // and completes the ones whose interrupt is pending. Returns whether
+    // entries were added to the used ring.
+    fn process_event_queue(&mut self) -> bool {
+        let mem = self.mem.memory();
+        let lines = self.lines.clone();
+        let mut lines = lines.lock().unwrap();
+        let mut used = false;
+
+        while let Some(avail_desc) = self.queues[EVENT_QUEUE].iter(&mem).next() {
+            let buffer = if !avail_desc.is_write_only()
+                && avail_desc.len as usize >= size_of::<VirtioGpioIrqRequest>()
+            {
+                let request = mem.read_obj::<VirtioGpioIrqRequest>(avail_desc.addr);
+                let status_desc = avail_desc
+                    .next_descriptor()
+                    .filter(|desc| desc.is_write_only() && desc.len > 0);
+                match (request, status_desc) {
+                    (Ok(request), Some(status_desc)) => {
+                        Some((u16::from_le(request.gpio), status_desc.addr.raw_value()))
+                    }
+                    _ => None,
+                }
+            } else {
+                None
+            };
+
+            let (gpio, status_addr) = match buffer {
+                Some(buffer) => buffer,
+                None => {
+                    error!("Invalid descriptor chain on the GPIO event queue");
+                    self.queues[EVENT_QUEUE].add_used(&mem, avail_desc.index, 0);
+                    used = true;
+                    continue;
+                }
+            };
+
+            // A buffer for a line without interrupt, or with one already
+            // waiting, is given back right away.
+            match lines.get_mut(gpio as usize) {
+                Some(line) if line.irq_type != GpioIrqType::None && line.irq_buffer.is_none() => {
+                    line.irq_buffer = Some((avail_desc.index, status_addr));
+                }
+                _ => {
+                    self.complete_irq(
+                        avail_desc.index,
+                        status_addr,
+                        VIRTIO_GPIO_IRQ_STATUS_INVALID,
+                    );
+                    used = true;
+                }
+            }
+        }
+
+        for line in lines.iter_mut().filter(|line| line.irq_pending()) {
+            if let Some((desc_index, status_addr)) = line.irq_buffer.take() {
+                line.irq_latched = false;
+                self.complete_irq(desc_index, status_addr, VIRTIO_GPIO_IRQ_STATUS_VALID);
+                used = true;
+            }
+        }
+
+        used
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<PauseBarrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(
+            self.queue_evts[REQUEST_QUEUE].as_raw_fd(),
+            REQUEST_QUEUE_EVENT,
+        )?;
+        helper.add_event(self.queue_evts[EVENT_QUEUE].as_raw_fd(), EVENT_QUEUE_EVENT)?;
+        helper.add_event(self.inject_evt.as_raw_fd(), INJECT_EVENT)?;
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for GpioEpollHandler {
+    fn handle_event(&mut self, _helper: &mut EpollHelper, event: &epoll::Event) -> bool {
+        let ev_type = event.data as u16;
+        match ev_type {
+            REQUEST_QUEUE_EVENT => {
+                if let Err(e) = self.queue_evts[REQUEST_QUEUE].read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                }
+                let (requests_used, events_used) = self.process_request_queue();
+                if requests_used {
+                    if let Err(e) = self.signal_used_queue(REQUEST_QUEUE) {
+                        error!("Failed to signal used queue: {:?}", e);
+                        return true;
+                    }
+                }
+                if events_used {
+                    if let Err(e) = self.signal_used_queue(EVENT_QUEUE) {
+                        error!("Failed to signal used queue: {:?}", e);
+                        return true;
+                    }
+                }
+                // A level triggered interrupt enabled on a line already at
+                // that level is pending right away.
+            }
+            EVENT_QUEUE_EVENT => {
+                if let Err(e) = self.queue_evts[EVENT_QUEUE].read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                }
+            }
+            INJECT_EVENT => {
+                if let Err(e) = self.inject_evt.read() {
+                    error!("Failed to get inject event: {:?}", e);
+                    return true;
+                }
+            }
+            _ => {
+                error!("Unexpected event: {}", ev_type);
+                return true;
+            }
+        }
+
+        if self.process_event_queue() {
+            if let Err(e) = self.signal_used_queue(EVENT_QUEUE) {
+                error!("Failed to signal used queue: {:?}", e);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Virtio device exposing GPIO lines to the guest OS.
+pub struct Gpio {
+    common: VirtioCommon,
+    id: String,
+    lines: Arc<Mutex<Vec<GpioLine>>>,
+    observer: Option<Arc<dyn GpioObserver>>,
+    inject_evt: EventFd,
+    seccomp_action: SeccompAction,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GpioState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    lines: Vec<GpioLine>,
+}
+
+impl VersionedState for GpioState {
+    const VERSION: u16 = 1;
+}
+
+impl Gpio {
+    /// Create a new virtio GPIO device exposing `num_lines` lines.
+    pub fn new(id: String, num_lines: u16, seccomp_action: SeccompAction) -> io::Result<Gpio> {
+        if num_lines == 0 || num_lines > MAX_GPIO_LINES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid number of GPIO lines {}", num_lines),
+            ));
+        }
+
+        Ok(Gpio {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::TYPE_GPIO as u32,
+                queue_sizes: QUEUE_SIZES.to_vec(),
+                paused_sync: Some(Arc::new(PauseBarrier::new(2))),
+                avail_features: 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_GPIO_F_IRQ,
+                min_queues: 2,
+                ..Default::default()
+            },
+            id,
+            lines: Arc::new(Mutex::new(vec![GpioLine::default(); num_lines as usize])),
+            observer: None,
+            inject_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            seccomp_action,
+        })
+    }
+
+    /// Registers the observer notified of the changes the guest makes to
+    /// the lines.
+    pub fn set_observer(&mut self, observer: Arc<dyn GpioObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Drives an input line from the host, raising the interrupt the guest
+    /// enabled on it, if any. Fails if the guest made the line an output.
+    pub fn set_input(&self, line: u16, value: bool) -> io::Result<()> {
+        {
+            let mut lines = self.lines.lock().unwrap();
+            let gpio_line = lines.get_mut(line as usize).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no GPIO line {}", line),
+                )
+            })?;
+            if gpio_line.direction == GpioDirection::Out {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("GPIO line {} is an output", line),
+                ));
+            }
+            gpio_line.set_input(value);
+        }
+
+        self.inject_evt.write(1)
+    }
+
+    /// State of the lines, as seen by the guest.
+    pub fn lines(&self) -> Vec<GpioLineState> {
+        self.lines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(GpioLine::state)
+            .collect()
+    }
+
+    fn state(&self) -> GpioState {
+        GpioState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            lines: self.lines.lock().unwrap().clone(),
+        }
+    }
+
+    fn set_state(&mut self, state: &GpioState) -> io::Result<()> {
+        let mut lines = self.lines.lock().unwrap();
+        if state.lines.len() != lines.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "snapshot has {} GPIO lines, device has {}",
+                    state.lines.len(),
+                    lines.len()
+                ),
+            ));
+        }
+
+        self.common.avail_features = state.avail_features;
+        self.common.acked_features = state.acked_features;
+        *lines = state.lines.clone();
+        Ok(())
+    }
+}
+
+impl Drop for Gpio {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Gpio {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = VirtioGpioConfig {
+            ngpio: (self.lines.lock().unwrap().len() as u16).to_le(),
+            ..Default::default()
+        };
+        self.read_config_from_slice(config.as_slice(), offset, data);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
+        let kill_evt = self
+            .common
+            .kill_evt
+            .as_ref()
+            .unwrap()
+            .try_clone()
+            .map_err(|e| {
+                error!("failed to clone kill_evt eventfd: {}", e);
+                ActivateError::BadActivate
+            })?;
+        let pause_evt = self
+            .common
+            .pause_evt
+            .as_ref()
+            .unwrap()
+            .try_clone()
+            .map_err(|e| {
+                error!("failed to clone pause_evt eventfd: {}", e);
+                ActivateError::BadActivate
+            })?;
+        let inject_evt = self.inject_evt.try_clone().map_err(|e| {
+            error!("failed to clone inject_evt eventfd: {}", e);
+            ActivateError::BadActivate
+        })?;
+
+        let mut handler = GpioEpollHandler {
+            queues,
+            mem,
+            interrupt_cb,
+            queue_evts,
+            lines: self.lines.clone(),
+            observer: self.observer.clone(),
+            inject_evt,
+            kill_evt,
+            pause_evt,
+        };
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        // Retrieve seccomp filter for virtio_gpio thread
+        let virtio_gpio_seccomp_filter =
+            get_seccomp_filter(&self.seccomp_action, Thread::VirtioGpio)
+                .map_err(ActivateError::CreateSeccompFilter)?;
+        thread::Builder::new()
+            .name(self.id.clone())
+            .spawn(move || {
+                if let Err(e) = SeccompFilter::apply(virtio_gpio_seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
+                    error!("Error running worker: {:?}", e);
+                }
+            })
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-gpio epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        // The configuration of the lines goes with the driver, only the
+        // values driven by the host remain.
+        for line in self.lines.lock().unwrap().iter_mut() {
+            *line = GpioLine {
+                input: line.input,
+                ..Default::default()
+            };
+        }
+        self.common.reset()
+    }
+}
+
+impl Pausable for Gpio {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for Gpio {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut gpio_snapshot = Snapshot::new(self.id.as_str());
+        gpio_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
+
+        Ok(gpio_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        if let Some(gpio_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            let gpio_state = gpio_section.to_versioned_state::<GpioState>()?;
+
+            return self
+                .set_state(&gpio_state)
+                .map_err(|e| MigratableError::Restore(anyhow!("Could not restore GPIO {}", e)));
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find GPIO snapshot section"
+        )))
+    }
+}
+
+impl Transportable for Gpio {}
+impl Migratable for Gpio {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vsock::tests::NoopVirtioInterrupt;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    #[derive(Default)]
+    struct Recorder {
+        changes: Mutex<Vec<(u16, GpioLineState)>>,
+    }
+
+    impl GpioObserver for Recorder {
+        fn line_changed(&self, line: u16, state: &GpioLineState) {
+            self.changes.lock().unwrap().push((line, *state));
+        }
+    }
+
+    fn handler(gpio: &Gpio, mem: &GuestMemoryMmap, queues: &[GuestQ]) -> GpioEpollHandler {
+        GpioEpollHandler {
+            queues: queues.iter().map(|q| q.create_queue()).collect(),
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            queue_evts: vec![EventFd::new(0).unwrap(), EventFd::new(0).unwrap()],
+            lines: gpio.lines.clone(),
+            observer: gpio.observer.clone(),
+            inject_evt: gpio.inject_evt.try_clone().unwrap(),
+            kill_evt: EventFd::new(0).unwrap(),
+            pause_evt: EventFd::new(0).unwrap(),
+        }
+    }
+
+    // Queues a request, whose response lands at 0x2000 + 0x10 * index.
+    fn push_request(
+        queue: &GuestQ,
+        mem: &GuestMemoryMmap,
+        index: u16,
+        type_: u16,
+        gpio: u16,
+        value: u32,
+    ) {
+        let request = 0x1000 + 0x10 * index as u64;
+        let response = 0x2000 + 0x10 * index as u64;
+        mem.write_obj(
+            VirtioGpioRequest {
+                type_: type_.to_le(),
+                gpio: gpio.to_le(),
+                value: value.to_le(),
+            },
+            GuestAddress(request),
+        )
+        .unwrap();
+        let desc = index * 2;
+        queue.dtable[desc as usize].set(request, 8, VIRTQ_DESC_F_NEXT, desc + 1);
+        queue.dtable[desc as usize + 1].set(response, 2, VIRTQ_DESC_F_WRITE, 0);
+        queue.avail.ring[index as usize].set(desc);
+        queue.avail.idx.set(index + 1);
+    }
+
+    fn response(mem: &GuestMemoryMmap, index: u16) -> (u8, u8) {
+        let response: VirtioGpioResponse = mem
+            .read_obj(GuestAddress(0x2000 + 0x10 * index as u64))
+            .unwrap();
+        (response.status, response.value)
+    }
+
+    #[test]
+    fn test_gpio_requests() {
+        let mut gpio = Gpio::new("_gpio".to_owned(), 4, SeccompAction::Trap).unwrap();
+        let recorder = Arc::new(Recorder::default());
+        gpio.set_observer(recorder.clone());
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let queues = [
+            GuestQ::new(GuestAddress(0x10000), &mem, 16),
+            GuestQ::new(GuestAddress(0x20000), &mem, 16),
+        ];
+        let mut handler = handler(&gpio, &mem, &queues);
+
+        // The value of an output is set before its direction.
+        push_request(&queues[0], &mem, 0, VIRTIO_GPIO_MSG_SET_VALUE, 1, 1);
+        push_request(&queues[0], &mem, 1, VIRTIO_GPIO_MSG_SET_DIRECTION, 1, 1);
+        push_request(&queues[0], &mem, 2, VIRTIO_GPIO_MSG_GET_VALUE, 1, 0);
+        push_request(&queues[0], &mem, 3, VIRTIO_GPIO_MSG_GET_DIRECTION, 1, 0);
+        push_request(&queues[0], &mem, 4, VIRTIO_GPIO_MSG_GET_VALUE, 4, 0);
+        assert_eq!(handler.process_request_queue(), (true, false));
+
+        assert_eq!(queues[0].used.idx.get(), 5);
+        assert_eq!(response(&mem, 0), (VIRTIO_GPIO_STATUS_OK, 0));
+        assert_eq!(response(&mem, 2), (VIRTIO_GPIO_STATUS_OK, 1));
+        assert_eq!(
+            response(&mem, 3),
+            (VIRTIO_GPIO_STATUS_OK, VIRTIO_GPIO_DIRECTION_OUT)
+        );
+        // There is no line 4.
+        assert_eq!(response(&mem, 4), (VIRTIO_GPIO_STATUS_ERR, 0));
+
+        // Only the direction change is visible from the host.
+        let expected = GpioLineState {
+            direction: GpioDirection::Out,
+            value: true,
+            irq_type: GpioIrqType::None,
+        };
+        assert_eq!(*recorder.changes.lock().unwrap(), vec![(1, expected)]);
+        assert_eq!(gpio.lines()[1], expected);
+
+        // The host can't drive an output.
+        assert!(gpio.set_input(1, false).is_err());
+        assert!(gpio.set_input(0, true).is_ok());
+        assert!(gpio.lines()[0].value);
+    }
+
+    #[test]
+    fn test_gpio_irq() {
+        let gpio = Gpio::new("_gpio".to_owned(), 2, SeccompAction::Trap).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let queues = [
+            GuestQ::new(GuestAddress(0x10000), &mem, 16),
+            GuestQ::new(GuestAddress(0x20000), &mem, 16),
+        ];
+        let mut handler = handler(&gpio, &mem, &queues);
+
+        push_request(&queues[0], &mem, 0, VIRTIO_GPIO_MSG_SET_DIRECTION, 0, 2);
+        push_request(
+            &queues[0],
+            &mem,
+            1,
+            VIRTIO_GPIO_MSG_SET_IRQ_TYPE,
+            0,
+            VIRTIO_GPIO_IRQ_TYPE_EDGE_RISING,
+        );
+        handler.process_request_queue();
+        assert_eq!(response(&mem, 1), (VIRTIO_GPIO_STATUS_OK, 0));
+
+        // The buffers for the interrupts of lines 0 and 1, the latter
+        // without interrupt enabled.
+        let push_irq_buffer = |index: u16, gpio: u16| {
+            let request = 0x3000 + 0x10 * index as u64;
+            mem.write_obj(gpio.to_le(), GuestAddress(request)).unwrap();
+            let desc = index * 2;
+            queues[1].dtable[desc as usize].set(request, 2, VIRTQ_DESC_F_NEXT, desc + 1);
+            queues[1].dtable[desc as usize + 1].set(request + 8, 1, VIRTQ_DESC_F_WRITE, 0);
+            queues[1].avail.ring[index as usize].set(desc);
+            queues[1].avail.idx.set(index + 1);
+        };
+        let irq_status = |index: u16| -> u8 {
+            mem.read_obj(GuestAddress(0x3000 + 0x10 * index as u64 + 8))
+                .unwrap()
+        };
+        mem.write_obj(0xffu8, GuestAddress(0x3008)).unwrap();
+        push_irq_buffer(0, 0);
+        push_irq_buffer(1, 1);
+        assert!(handler.process_event_queue());
+        assert_eq!(queues[1].used.idx.get(), 1);
+        assert_eq!(irq_status(0), 0xff);
+        assert_eq!(irq_status(1), VIRTIO_GPIO_IRQ_STATUS_INVALID);
+
+        // A falling edge doesn't raise the interrupt, a rising one does.
+        gpio.set_input(0, false).unwrap();
+        assert!(!handler.process_event_queue());
+        gpio.set_input(0, true).unwrap();
+        assert!(handler.process_event_queue());
+        assert_eq!(queues[1].used.idx.get(), 2);
+        assert_eq!(irq_status(0), VIRTIO_GPIO_IRQ_STATUS_VALID);
+
+        // An edge without buffer is latched until the driver queues one.
+        gpio.set_input(0, false).unwrap();
+        gpio.set_input(0, true).unwrap();
+        assert!(!handler.process_event_queue());
+        push_irq_buffer(2, 0);
+        assert!(handler.process_event_queue());
+        assert_eq!(irq_status(2), VIRTIO_GPIO_IRQ_STATUS_VALID);
+
+        // Disabling the interrupt gives the waiting buffer back.
+        push_irq_buffer(3, 0);
+        assert!(!handler.process_event_queue());
+        push_request(
+            &queues[0],
+            &mem,
+            2,
+            VIRTIO_GPIO_MSG_SET_IRQ_TYPE,
+            0,
+            VIRTIO_GPIO_IRQ_TYPE_NONE,
+        );
+        assert_eq!(handler.process_request_queue(), (true, true));
+        assert_eq!(irq_status(3), VIRTIO_GPIO_IRQ_STATUS_INVALID);
+        assert_eq!(queues[1].used.idx.get(), 4);
+    }
+
+    #[test]
+    fn test_gpio_requests_bounded() {
+        let gpio = Gpio::new("_gpio".to_owned(), 1, SeccompAction::Trap).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let queues = [
+            GuestQ::new(GuestAddress(0x10000), &mem, 16),
+            GuestQ::new(GuestAddress(0x20000), &mem, 16),
+        ];
+        let mut handler = handler(&gpio, &mem, &queues);
+
+        // The driver claims many more requests than the queue holds, all
+        // of them pointing at the same chain.
+        push_request(&queues[0], &mem, 0, VIRTIO_GPIO_MSG_GET_VALUE, 0, 0);
+        queues[0].avail.idx.set(1000);
+
+        // They are processed a queue worth at a time.
+        assert_eq!(handler.process_request_queue(), (true, false));
+        assert_eq!(queues[0].used.idx.get(), QUEUE_SIZE);
+        assert_eq!(handler.process_request_queue(), (true, false));
+        assert_eq!(queues[0].used.idx.get(), 2 * QUEUE_SIZE);
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Rate limiting of the messages the guest can trigger at will, so that a
+//! misbehaving guest can't flood the host logs.
+//!
+//! Each call site of `guest_warn!` and `guest_error!` owns a token bucket
+//! holding as many messages as can be logged per second, and refilled
+//! continuously. The messages coming while the bucket is empty are dropped,
+//! their number being reported along with the next message logged, or by
+//! `report_suppressed_guest_logs()` if none comes.
+
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+/// Default number of messages each call site can log per second.
+pub const DEFAULT_GUEST_LOG_LIMIT: u64 = 10;
+
+const USECS_PER_SEC: u64 = 1_000_000;
+
+static GUEST_LOG_LIMIT: AtomicU64 = AtomicU64::new(DEFAULT_GUEST_LOG_LIMIT);
+
+// The call sites which suppressed messages at some point, linked through
+// their `next` field. They are never removed, as they are static.
+static SUPPRESSING_SITES: AtomicPtr<GuestLogLimiter> = AtomicPtr::new(ptr::null_mut());
+
+/// Sets the number of messages each call site can log per second, 0 lifting
+/// the limit.
+pub fn set_guest_log_limit(limit: u64) {
+    GUEST_LOG_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+fn monotonic_usecs() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because the timespec is valid for writes, and the monotonic
+    // clock can't fail.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * USECS_PER_SEC + now.tv_nsec as u64 / 1_000
+}
+
+pub struct GuestLogLimiter {
+    // Where the messages are logged from, for the periodic report.
+    site: &'static str,
+    // Time the bucket is full again, in microseconds. Each message logged
+    // pushes it by the time a token takes to be refilled, the bucket being
+    // empty once it's a second ahead.
+    full_at: AtomicU64,
+    suppressed: AtomicU64,
+    registered: AtomicBool,
+    next: AtomicPtr<GuestLogLimiter>,
+}
+
+impl GuestLogLimiter {
+    pub const fn new(site: &'static str) -> Self {
+        GuestLogLimiter {
+            site,
+            full_at: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Takes a token from the bucket, returning the number of messages
+    /// suppressed since the last one logged, or None if this one must be
+    /// suppressed as well.
+    pub fn check(&'static self) -> Option<u64> {
+        let limit = GUEST_LOG_LIMIT.load(Ordering::Relaxed);
+        if limit != 0 && self.take_token(limit, monotonic_usecs()).is_none() {
+            self.register();
+            return None;
+        }
+
+        Some(self.suppressed.swap(0, Ordering::Relaxed))
+    }
+
+    // Adds the site to the ones the periodic report goes through.
+    fn register(&'static self) {
+        if self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let this = self as *const GuestLogLimiter as *mut GuestLogLimiter;
+        let mut head = SUPPRESSING_SITES.load(Ordering::Acquire);
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+            match SUPPRESSING_SITES.compare_exchange_weak(
+                head,
+                this,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn take_token(&self, limit: u64, now: u64) -> Option<()> {
+        let refill = (USECS_PER_SEC / limit).max(1);
+        let mut full_at = self.full_at.load(Ordering::Relaxed);
+        loop {
+            let next_full_at = full_at.max(now) + refill;
+            if next_full_at > now + USECS_PER_SEC {
+                self.suppressed.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+
+            match self.full_at.compare_exchange_weak(
+                full_at,
+                next_full_at,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(()),
+                Err(current) => full_at = current,
+            }
+        }
+    }
+}
+
+// Goes through the sites which suppressed messages, returning the number
+// suppressed since the last message logged by each of them.
+fn take_suppressed() -> Vec<(&'static str, u64)> {
+    let mut suppressed = Vec::new();
+    let mut site = SUPPRESSING_SITES.load(Ordering::Acquire);
+    // SAFETY: only static limiters are linked.
+    while let Some(limiter) = unsafe { site.as_ref() } {
+        let count = limiter.suppressed.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            suppressed.push((limiter.site, count));
+        }
+        site = limiter.next.load(Ordering::Acquire);
+    }
+    suppressed
+}
+
+/// Reports the messages suppressed since the last message logged by each
+/// site, meant to be called periodically for the floods which stopped to
+/// be reported as well.
+pub fn report_suppressed_guest_logs() {
+    for (site, count) in take_suppressed() {
+        warn!(
+            "Suppressed {} messages triggered by the guest at {}",
+            count, site
+        );
+    }
+}
+
+macro_rules! guest_log {
+    ($lvl:expr, $($arg:tt)+) => {{
+        static LIMITER: $crate::guest_log::GuestLogLimiter =
+            $crate::guest_log::GuestLogLimiter::new(concat!(file!(), ":", line!()));
+        if log_enabled!($lvl) {
+            if let Some(suppressed) = LIMITER.check() {
+                if suppressed > 0 {
+                    log!($lvl, "Suppressed {} similar messages", suppressed);
+                }
+                log!($lvl, $($arg)+);
+            }
+        }
+    }};
+}
+
+/// Logs a warning triggered by the guest, rate limited per call site.
+macro_rules! guest_warn {
+    ($($arg:tt)+) => {
+        guest_log!(log::Level::Warn, $($arg)+)
+    };
+}
+
+/// Logs an error triggered by the guest, rate limited per call site.
+macro_rules! guest_error {
+    ($($arg:tt)+) => {
+        guest_log!(log::Level::Error, $($arg)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_log_limiter() {
+        let limiter = GuestLogLimiter::new("test");
+        let start = 10 * USECS_PER_SEC;
+
+        // A full bucket lets a burst of messages through.
+        for _ in 0..4 {
+            assert!(limiter.take_token(4, start).is_some());
+        }
+        assert!(limiter.take_token(4, start).is_none());
+        assert!(limiter.take_token(4, start + 100_000).is_none());
+        assert_eq!(limiter.suppressed.load(Ordering::Relaxed), 2);
+
+        // One token is refilled every 250ms.
+        assert!(limiter.take_token(4, start + 250_000).is_some());
+        assert!(limiter.take_token(4, start + 250_000).is_none());
+
+        // The bucket doesn't hold more than a second worth of tokens.
+        let later = start + 60 * USECS_PER_SEC;
+        for _ in 0..4 {
+            assert!(limiter.take_token(4, later).is_some());
+        }
+        assert!(limiter.take_token(4, later).is_none());
+    }
+
+    #[test]
+    fn test_guest_log_report() {
+        static LIMITER: GuestLogLimiter = GuestLogLimiter::new("report");
+        let report = || {
+            take_suppressed()
+                .into_iter()
+                .find(|(site, _)| *site == "report")
+                .map(|(_, count)| count)
+        };
+
+        set_guest_log_limit(1);
+        assert_eq!(LIMITER.check(), Some(0));
+        assert_eq!(LIMITER.check(), None);
+        assert_eq!(LIMITER.check(), None);
+        set_guest_log_limit(DEFAULT_GUEST_LOG_LIMIT);
+
+        // The suppressed messages are reported once, however often the
+        // sites are gone through.
+        assert_eq!(report(), Some(2));
+        assert_eq!(report(), None);
+        LIMITER.suppressed.store(3, Ordering::Relaxed);
+        assert_eq!(report(), Some(3));
+        assert!(LIMITER.registered.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::HashMap;
+use std::io;
+use std::num::Wrapping;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+struct InflightLimitState {
+    max: Option<u64>,
+    inflight: AtomicU64,
+    // Most descriptor chains held in flight at once.
+    high_watermark: AtomicU64,
+    throttled: AtomicU64,
+    // Devices waiting for descriptor chains to be released.
+    waiters: Mutex<Vec<Arc<EventFd>>>,
+}
+
+/// Cap on the descriptor chains the virtio-block devices hold in flight
+/// altogether, shared by all of them. A device reaching it stops taking
+/// descriptor chains from its queues until others are released.
+#[derive(Clone)]
+pub struct InflightLimit {
+    state: Arc<InflightLimitState>,
+}
+
+impl InflightLimit {
+    pub fn new(max: Option<u64>) -> Self {
+        InflightLimit {
+            state: Arc::new(InflightLimitState {
+                max,
+                inflight: AtomicU64::new(0),
+                high_watermark: AtomicU64::new(0),
+                throttled: AtomicU64::new(0),
+                waiters: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Creates the tracker through which a device thread accounts for the
+    /// descriptor chains it holds.
+    pub fn tracker(&self) -> io::Result<InflightTracker> {
+        Ok(InflightTracker {
+            limit: self.clone(),
+            waiter: Arc::new(EventFd::new(EFD_NONBLOCK)?),
+            held: 0,
+        })
+    }
+
+    /// Number of descriptor chains in flight.
+    pub fn inflight(&self) -> u64 {
+        self.state.inflight.load(Ordering::Acquire)
+    }
+
+    /// Limit of the descriptor chains in flight, if any.
+    pub fn max(&self) -> Option<u64> {
+        self.state.max
+    }
+
+    /// Most descriptor chains held in flight at once so far.
+    pub fn high_watermark(&self) -> u64 {
+        self.state.high_watermark.load(Ordering::Acquire)
+    }
+
+    /// Number of times a device had to wait for descriptor chains to be
+    /// released.
+    pub fn throttled(&self) -> u64 {
+        self.state.throttled.load(Ordering::Acquire)
+    }
+
+    fn try_acquire(&self) -> bool {
+        let previous = match self.state.max {
+            Some(max) => {
+                match self.state.inflight.fetch_update(
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                    |inflight| {
+                        if inflight < max {
+                            Some(inflight + 1)
+                        } else {
+                            None
+                        }
+                    },
+                ) {
+                    Ok(previous) => previous,
+                    Err(_) => return false,
+                }
+            }
+            None => self.state.inflight.fetch_add(1, Ordering::AcqRel),
+        };
+
+        self.state
+            .high_watermark
+            .fetch_max(previous + 1, Ordering::AcqRel);
+        true
+    }
+
+    fn acquire(&self, waiter: &Arc<EventFd>) -> bool {
+        if self.try_acquire() {
+            return true;
+        }
+
+        self.state.throttled.fetch_add(1, Ordering::AcqRel);
+        {
+            let mut waiters = self.state.waiters.lock().unwrap();
+            if !waiters.iter().any(|w| Arc::ptr_eq(w, waiter)) {
+                waiters.push(waiter.clone());
+            }
+        }
+
+        // Descriptor chains released before the waiter was added wouldn't
+        // have signaled it.
+        self.try_acquire()
+    }
+
+    fn release(&self, count: u64) {
+        if count == 0 {
+            return;
+        }
+
+        self.state.inflight.fetch_sub(count, Ordering::AcqRel);
+        if self.state.max.is_some() {
+            let waiters = std::mem::take(&mut *self.state.waiters.lock().unwrap());
+            for waiter in waiters {
+                if let Err(e) = waiter.write(1) {
+                    error!("Failed to signal the release of descriptor chains: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Adds the counters reported for the devices as a whole.
+    pub fn report(&self, counters: &mut HashMap<&'static str, Wrapping<u64>>) {
+        counters.insert("descriptors", Wrapping(self.inflight()));
+        counters.insert("max_descriptors", Wrapping(self.state.max.unwrap_or(0)));
+        counters.insert("throttled", Wrapping(self.throttled()));
+    }
+}
+
+/// Accounts for the descriptor chains a device thread holds in flight,
+/// which are released when it's dropped.
+pub struct InflightTracker {
+    limit: InflightLimit,
+    waiter: Arc<EventFd>,
+    held: u64,
+}
+
+impl InflightTracker {
+    /// Accounts for one more descriptor chain taken from a queue. Returns
+    /// false if the limit is reached, in which case the chain must be left
+    /// in the queue until the waiter is signaled.
+    pub fn acquire(&mut self) -> bool {
+        let acquired = self.limit.acquire(&self.waiter);
+        if acquired {
+            self.held += 1;
+        }
+        acquired
+    }
+
+    /// Accounts for descriptor chains given back to the guest.
+    pub fn release(&mut self, count: usize) {
+        let count = count as u64;
+        self.held -= count;
+        self.limit.release(count);
+    }
+
+    /// Signaled once descriptor chains are released after the limit was
+    /// reached.
+    pub fn waiter(&self) -> &EventFd {
+        &self.waiter
+    }
+}
+
+impl Drop for InflightTracker {
+    fn drop(&mut self) {
+        self.limit.release(self.held);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inflight_limit() {
+        let limit = InflightLimit::new(Some(3));
+        let mut first = limit.tracker().unwrap();
+        let mut second = limit.tracker().unwrap();
+
+        assert!(first.acquire());
+        assert!(first.acquire());
+        assert!(second.acquire());
+        assert_eq!(limit.inflight(), 3);
+
+        // The limit is reached, the waiter is signaled once a chain is
+        // released.
+        assert!(!second.acquire());
+        assert!(second.waiter().read().is_err());
+        first.release(1);
+        assert_eq!(second.waiter().read().unwrap(), 1);
+        assert!(second.acquire());
+
+        // The chains held by a device going away are released.
+        drop(first);
+        assert_eq!(limit.inflight(), 2);
+
+        let mut counters = HashMap::new();
+        limit.report(&mut counters);
+        assert_eq!(counters["descriptors"], Wrapping(2));
+        assert_eq!(counters["max_descriptors"], Wrapping(3));
+        assert_eq!(counters["throttled"], Wrapping(1));
+        assert_eq!(limit.high_watermark(), 3);
+    }
+
+    #[test]
+    fn test_inflight_unlimited() {
+        let limit = InflightLimit::new(None);
+        let mut tracker = limit.tracker().unwrap();
+        for _ in 0..1000 {
+            assert!(tracker.acquire());
+        }
+        tracker.release(1000);
+        assert_eq!(limit.inflight(), 0);
+    }
+}
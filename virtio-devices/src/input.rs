@@ -0,0 +1,1060 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Virtio input device, forwarding keyboard, mouse and touch events to the
+//! guest. The events either come from a host evdev device being passed
+//! through, or are injected by the VMM, which lets a synthetic keyboard,
+//! mouse or tablet be driven without any physical device behind it.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
+    VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
+};
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::{PauseBarrier, VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use seccomp::{SeccompAction, SeccompFilter};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use vm_memory::{ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable, VersionedState,
+};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_val};
+
+const QUEUE_SIZE: u16 = 64;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+
+const EVENT_QUEUE: usize = 0;
+const STATUS_QUEUE: usize = 1;
+
+// The driver made new buffers available on the event queue.
+const EVENT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// The driver sent status updates, such as the keyboard LEDs.
+const STATUS_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// Events have been injected by the VMM.
+const INJECT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// Events are ready to be read from the evdev device.
+const EVDEV_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+
+// Events waiting for the driver to provide buffers are dropped past this
+// limit, oldest first, so that a guest not running the driver can't make
+// the VMM grow without bound.
+const MAX_PENDING_EVENTS: usize = 1024;
+
+// Event types and codes, see include/uapi/linux/input-event-codes.h in the
+// kernel code.
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const EV_MSC: u16 = 0x04;
+const EV_SW: u16 = 0x05;
+const EV_LED: u16 = 0x11;
+
+// Event types whose capabilities are exposed to the guest.
+const EVENT_TYPES: &[u16] = &[EV_KEY, EV_REL, EV_ABS, EV_MSC, EV_SW, EV_LED];
+
+const KEY_ESC: u16 = 1;
+const KEY_MICMUTE: u16 = 248;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const BTN_TOUCH: u16 = 0x14a;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_WHEEL: u16 = 0x08;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_CNT: u16 = 0x40;
+const LED_NUML: u16 = 0x00;
+const LED_CAPSL: u16 = 0x01;
+const LED_SCROLLL: u16 = 0x02;
+
+// Range of the absolute axes of the synthetic tablet.
+const TABLET_ABS_MAX: i32 = 0x7fff;
+
+const BUS_VIRTUAL: u16 = 0x06;
+
+// Configuration space selectors.
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_PROP_BITS: u8 = 0x10;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+// The configuration space is made of the select, subsel and size bytes,
+// 5 reserved bytes and the payload selected by the driver.
+const CONFIG_HEADER_SIZE: usize = 8;
+const CONFIG_PAYLOAD_SIZE: usize = 128;
+
+// See include/uapi/asm-generic/ioctl.h and include/uapi/linux/input.h in
+// the kernel code.
+const IOC_WRITE: u64 = 1;
+const IOC_READ: u64 = 2;
+
+const fn evdev_ioctl(dir: u64, nr: u64, size: u64) -> u64 {
+    (dir << 30) | (size << 16) | ((b'E' as u64) << 8) | nr
+}
+
+const EVIOCGID: u64 = evdev_ioctl(IOC_READ, 0x02, 8);
+const EVIOCGNAME: u64 = evdev_ioctl(IOC_READ, 0x06, CONFIG_PAYLOAD_SIZE as u64);
+const EVIOCGUNIQ: u64 = evdev_ioctl(IOC_READ, 0x08, CONFIG_PAYLOAD_SIZE as u64);
+const EVIOCGPROP: u64 = evdev_ioctl(IOC_READ, 0x09, CONFIG_PAYLOAD_SIZE as u64);
+const EVIOCGRAB: u64 = evdev_ioctl(IOC_WRITE, 0x90, 4);
+
+const fn eviocgbit(ev: u16) -> u64 {
+    evdev_ioctl(IOC_READ, 0x20 + ev as u64, CONFIG_PAYLOAD_SIZE as u64)
+}
+
+const fn eviocgabs(axis: u16) -> u64 {
+    evdev_ioctl(IOC_READ, 0x40 + axis as u64, 24)
+}
+
+/// The ioctls issued on an evdev device when the device is created, which
+/// have to be allowed by the seccomp filter of the thread creating it.
+pub fn evdev_ioctls() -> Vec<u64> {
+    let mut ioctls = vec![EVIOCGID, EVIOCGNAME, EVIOCGUNIQ, EVIOCGPROP, EVIOCGRAB];
+    ioctls.extend(EVENT_TYPES.iter().map(|ev| eviocgbit(*ev)));
+    ioctls.extend((0..ABS_CNT).map(eviocgabs));
+    ioctls
+}
+
+/// An input event, as defined by the Linux input subsystem.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct InputEvent {
+    #[serde(rename = "type")]
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+// struct virtio_input_event
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VirtioInputEvent {
+    event_type: u16,
+    code: u16,
+    value: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioInputEvent {}
+
+// struct input_event, as read from and written to an evdev device.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct EvdevEvent {
+    tv_sec: libc::c_long,
+    tv_usec: libc::c_long,
+    event_type: u16,
+    code: u16,
+    value: i32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for EvdevEvent {}
+
+// struct input_absinfo
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct EvdevAbsInfo {
+    value: i32,
+    minimum: i32,
+    maximum: i32,
+    fuzz: i32,
+    flat: i32,
+    resolution: i32,
+}
+
+/// Where the events of a virtio-input device come from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputSource {
+    /// Host evdev device, such as /dev/input/event0, grabbed for the guest
+    /// exclusive use.
+    Evdev(PathBuf),
+    /// Synthetic keyboard, only receiving injected events.
+    Keyboard,
+    /// Synthetic relative pointer, only receiving injected events.
+    Mouse,
+    /// Synthetic absolute pointer, only receiving injected events.
+    Tablet,
+}
+
+#[derive(Clone, Copy, Default)]
+struct AbsInfo {
+    min: i32,
+    max: i32,
+    fuzz: i32,
+    flat: i32,
+    res: i32,
+}
+
+// What the device reports to the driver through the configuration space.
+#[derive(Default)]
+struct InputCapabilities {
+    name: String,
+    serial: String,
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+    properties: Vec<u8>,
+    events: BTreeMap<u16, Vec<u8>>,
+    abs_info: BTreeMap<u16, AbsInfo>,
+}
+
+fn set_bit(bitmap: &mut Vec<u8>, bit: u16) {
+    let byte = bit as usize / 8;
+    if bitmap.len() <= byte {
+        bitmap.resize(byte + 1, 0);
+    }
+    bitmap[byte] |= 1 << (bit % 8);
+}
+
+fn test_bit(bitmap: &[u8], bit: u16) -> bool {
+    bitmap
+        .get(bit as usize / 8)
+        .map_or(false, |byte| byte & (1 << (bit % 8)) != 0)
+}
+
+// Bitmaps are reported without their trailing empty bytes, in particular an
+// empty bitmap tells the driver the event type is not supported.
+fn trim_bitmap(mut bitmap: Vec<u8>) -> Vec<u8> {
+    while bitmap.last() == Some(&0) {
+        bitmap.pop();
+    }
+    bitmap
+}
+
+impl InputCapabilities {
+    fn synthetic(source: &InputSource) -> Self {
+        let mut caps = InputCapabilities {
+            bustype: BUS_VIRTUAL,
+            version: 1,
+            ..Default::default()
+        };
+        let set = |caps: &mut InputCapabilities, ev: u16, codes: &[u16]| {
+            let bitmap = caps.events.entry(ev).or_default();
+            for code in codes {
+                set_bit(bitmap, *code);
+            }
+        };
+
+        match source {
+            InputSource::Keyboard => {
+                caps.name = "Cloud Hypervisor virtio keyboard".to_string();
+                caps.product = 1;
+                let keys: Vec<u16> = (KEY_ESC..=KEY_MICMUTE).collect();
+                set(&mut caps, EV_KEY, &keys);
+                set(&mut caps, EV_LED, &[LED_NUML, LED_CAPSL, LED_SCROLLL]);
+            }
+            InputSource::Mouse => {
+                caps.name = "Cloud Hypervisor virtio mouse".to_string();
+                caps.product = 2;
+                set(&mut caps, EV_KEY, &[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]);
+                set(&mut caps, EV_REL, &[REL_X, REL_Y, REL_WHEEL]);
+            }
+            InputSource::Tablet => {
+                caps.name = "Cloud Hypervisor virtio tablet".to_string();
+                caps.product = 3;
+                set(
+                    &mut caps,
+                    EV_KEY,
+                    &[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE, BTN_TOUCH],
+                );
+                set(&mut caps, EV_ABS, &[ABS_X, ABS_Y]);
+                for axis in [ABS_X, ABS_Y].iter() {
+                    caps.abs_info.insert(
+                        *axis,
+                        AbsInfo {
+                            max: TABLET_ABS_MAX,
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+            InputSource::Evdev(_) => unreachable!(),
+        }
+
+        caps
+    }
+
+    fn from_evdev(evdev: &File) -> io::Result<Self> {
+        let read_string = |request: u64| -> io::Result<String> {
+            let mut buf = [0u8; CONFIG_PAYLOAD_SIZE];
+            // Safe because the kernel writes at most the size encoded in
+            // the request, which is the size of the buffer.
+            let ret = unsafe { ioctl_with_mut_ref(evdev, request, &mut buf) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+        };
+        let read_bitmap = |request: u64| -> io::Result<Vec<u8>> {
+            let mut buf = [0u8; CONFIG_PAYLOAD_SIZE];
+            // Safe because the kernel writes at most the size encoded in
+            // the request, which is the size of the buffer.
+            let ret = unsafe { ioctl_with_mut_ref(evdev, request, &mut buf) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(trim_bitmap(buf[..ret as usize].to_vec()))
+        };
+
+        let mut ids = [0u16; 4];
+        // Safe because struct input_id is made of 4 u16.
+        let ret = unsafe { ioctl_with_mut_ref(evdev, EVIOCGID, &mut ids) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut caps = InputCapabilities {
+            name: read_string(EVIOCGNAME)?,
+            // Most devices don't have a unique identifier.
+            serial: read_string(EVIOCGUNIQ).unwrap_or_default(),
+            bustype: ids[0],
+            vendor: ids[1],
+            product: ids[2],
+            version: ids[3],
+            properties: read_bitmap(EVIOCGPROP)?,
+            ..Default::default()
+        };
+
+        for ev in EVENT_TYPES {
+            let bitmap = read_bitmap(eviocgbit(*ev))?;
+            if !bitmap.is_empty() {
+                caps.events.insert(*ev, bitmap);
+            }
+        }
+
+        if let Some(axes) = caps.events.get(&EV_ABS).cloned() {
+            for axis in (0..ABS_CNT).filter(|axis| test_bit(&axes, *axis)) {
+                let mut abs = EvdevAbsInfo::default();
+                // Safe because the kernel writes a struct input_absinfo.
+                let ret = unsafe { ioctl_with_mut_ref(evdev, eviocgabs(axis), &mut abs) };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                caps.abs_info.insert(
+                    axis,
+                    AbsInfo {
+                        min: abs.minimum,
+                        max: abs.maximum,
+                        fuzz: abs.fuzz,
+                        flat: abs.flat,
+                        res: abs.resolution,
+                    },
+                );
+            }
+        }
+
+        Ok(caps)
+    }
+
+    fn supports(&self, event: &InputEvent) -> bool {
+        event.event_type == EV_SYN
+            || self
+                .events
+                .get(&event.event_type)
+                .map_or(false, |bitmap| test_bit(bitmap, event.code))
+    }
+
+    // Payload of the configuration space for the given selector.
+    fn payload(&self, select: u8, subsel: u8) -> Vec<u8> {
+        let mut payload = match (select, subsel) {
+            (VIRTIO_INPUT_CFG_ID_NAME, 0) => self.name.as_bytes().to_vec(),
+            (VIRTIO_INPUT_CFG_ID_SERIAL, 0) => self.serial.as_bytes().to_vec(),
+            (VIRTIO_INPUT_CFG_ID_DEVIDS, 0) => {
+                [self.bustype, self.vendor, self.product, self.version]
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes().to_vec())
+                    .collect()
+            }
+            (VIRTIO_INPUT_CFG_PROP_BITS, 0) => self.properties.clone(),
+            (VIRTIO_INPUT_CFG_EV_BITS, ev) => {
+                self.events.get(&(ev as u16)).cloned().unwrap_or_default()
+            }
+            (VIRTIO_INPUT_CFG_ABS_INFO, axis) => self
+                .abs_info
+                .get(&(axis as u16))
+                .map(|abs| {
+                    [abs.min, abs.max, abs.fuzz, abs.flat, abs.res]
+                        .iter()
+                        .flat_map(|v| v.to_le_bytes().to_vec())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        payload.truncate(CONFIG_PAYLOAD_SIZE);
+        payload
+    }
+}
+
+struct InputEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    evdev: Option<File>,
+    pending: Arc<Mutex<VecDeque<InputEvent>>>,
+    inject_evt: EventFd,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+}
+
+impl InputEpollHandler {
+    // Hands the pending events to the driver, as long as it provides
+    // buffers to hold them.
+    fn process_event_queue(&mut self) -> bool {
+        let queue = &mut self.queues[EVENT_QUEUE];
+        let mem = self.mem.memory();
+        let mut pending = self.pending.lock().unwrap();
+        let mut used = false;
+
+        while let Some(event) = pending.front() {
+            let avail_desc = match queue.iter(&mem).next() {
+                Some(avail_desc) => avail_desc,
+                None => break,
+            };
+
+            let mut len = 0;
+            let virtio_event = VirtioInputEvent {
+                event_type: event.event_type.to_le(),
+                code: event.code.to_le(),
+                value: (event.value as u32).to_le(),
+            };
+            if avail_desc.is_write_only()
+                && avail_desc.len as usize >= std::mem::size_of::<VirtioInputEvent>()
+            {
+                match mem.write_obj(virtio_event, avail_desc.addr) {
+                    Ok(_) => len = std::mem::size_of::<VirtioInputEvent>() as u32,
+                    Err(e) => error!("Failed to write input event: {:?}", e),
+                }
+            } else {
+                error!("Invalid descriptor on the input event queue");
+            }
+
+            queue.add_used(&mem, avail_desc.index, len);
+            used = true;
+            pending.pop_front();
+        }
+
+        used
+    }
+
+    // Status updates are forwarded to the evdev device, which turns the
+    // keyboard LEDs on and off, or dropped for synthetic devices.
+    fn process_status_queue(&mut self) -> bool {
+        let queue = &mut self.queues[STATUS_QUEUE];
+        let mem = self.mem.memory();
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+
+        for avail_desc in queue.iter(&mem) {
+            if !avail_desc.is_write_only()
+                && avail_desc.len as usize >= std::mem::size_of::<VirtioInputEvent>()
+            {
+                match mem.read_obj::<VirtioInputEvent>(avail_desc.addr) {
+                    Ok(virtio_event) => {
+                        if let Some(evdev) = self.evdev.as_mut() {
+                            let event = EvdevEvent {
+                                event_type: u16::from_le(virtio_event.event_type),
+                                code: u16::from_le(virtio_event.code),
+                                value: u32::from_le(virtio_event.value) as i32,
+                                ..Default::default()
+                            };
+                            if let Err(e) = evdev.write_all(event.as_slice()) {
+                                warn!("Failed to forward status to the evdev device: {}", e);
+                            }
+                        }
+                    }
+                    Err(e) => error!("Failed to read input status: {:?}", e),
+                }
+            } else {
+                error!("Invalid descriptor on the input status queue");
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, 0);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            queue.add_used(&mem, desc_index, len);
+        }
+        used_count > 0
+    }
+
+    // Moves the events available on the evdev device to the pending ones.
+    fn read_evdev(&mut self) -> io::Result<()> {
+        let evdev = match self.evdev.as_mut() {
+            Some(evdev) => evdev,
+            None => return Ok(()),
+        };
+
+        loop {
+            let mut event = EvdevEvent::default();
+            match evdev.read(event.as_mut_slice()) {
+                Ok(len) if len == std::mem::size_of::<EvdevEvent>() => {
+                    push_event(
+                        &self.pending,
+                        InputEvent {
+                            event_type: event.event_type,
+                            code: event.code,
+                            value: event.value,
+                        },
+                    );
+                }
+                Ok(_) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<PauseBarrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.queue_evts[EVENT_QUEUE].as_raw_fd(), EVENT_QUEUE_EVENT)?;
+        helper.add_event(
+            self.queue_evts[STATUS_QUEUE].as_raw_fd(),
+            STATUS_QUEUE_EVENT,
+        )?;
+        helper.add_event(self.inject_evt.as_raw_fd(), INJECT_EVENT)?;
+        if let Some(evdev) = self.evdev.as_ref() {
+            helper.add_event(evdev.as_raw_fd(), EVDEV_EVENT)?;
+        }
+
+        // Deliver the events injected before the driver was ready.
+        if self.process_event_queue() {
+            if let Err(e) = self.signal_used_queue(EVENT_QUEUE) {
+                error!("Failed to signal used queue: {:?}", e);
+            }
+        }
+
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+fn push_event(pending: &Mutex<VecDeque<InputEvent>>, event: InputEvent) {
+    let mut pending = pending.lock().unwrap();
+    if pending.len() >= MAX_PENDING_EVENTS {
+        pending.pop_front();
+    }
+    pending.push_back(event);
+}
+
+impl EpollHelperHandler for InputEpollHandler {
+    fn handle_event(&mut self, helper: &mut EpollHelper, event: &epoll::Event) -> bool {
+        let ev_type = event.data as u16;
+        match ev_type {
+            EVENT_QUEUE_EVENT => {
+                if let Err(e) = self.queue_evts[EVENT_QUEUE].read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                }
+            }
+            STATUS_QUEUE_EVENT => {
+                if let Err(e) = self.queue_evts[STATUS_QUEUE].read() {
+                    error!("Failed to get queue event: {:?}", e);
+                    return true;
+                } else if self.process_status_queue() {
+                    if let Err(e) = self.signal_used_queue(STATUS_QUEUE) {
+                        error!("Failed to signal used queue: {:?}", e);
+                        return true;
+                    }
+                }
+                return false;
+            }
+            INJECT_EVENT => {
+                if let Err(e) = self.inject_evt.read() {
+                    error!("Failed to get inject event: {:?}", e);
+                    return true;
+                }
+            }
+            EVDEV_EVENT => {
+                if let Err(e) = self.read_evdev() {
+                    // The device went away, stop polling it rather than
+                    // spinning on the hang up.
+                    error!("Failed to read from the evdev device: {}", e);
+                    if let Some(evdev) = self.evdev.take() {
+                        if let Err(e) = helper.del_event(evdev.as_raw_fd(), EVDEV_EVENT) {
+                            error!("Failed to remove the evdev device: {:?}", e);
+                            return true;
+                        }
+                    }
+                }
+            }
+            _ => {
+                error!("Unexpected event: {}", ev_type);
+                return true;
+            }
+        }
+
+        if self.process_event_queue() {
+            if let Err(e) = self.signal_used_queue(EVENT_QUEUE) {
+                error!("Failed to signal used queue: {:?}", e);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Virtio device forwarding input events to the guest OS.
+pub struct Input {
+    common: VirtioCommon,
+    id: String,
+    evdev: Option<File>,
+    capabilities: InputCapabilities,
+    select: u8,
+    subsel: u8,
+    pending: Arc<Mutex<VecDeque<InputEvent>>>,
+    inject_evt: EventFd,
+    seccomp_action: SeccompAction,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InputState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    pub select: u8,
+    pub subsel: u8,
+}
+
+impl VersionedState for InputState {
+    const VERSION: u16 = 1;
+}
+
+impl Input {
+    /// Create a new virtio input device, either passing through the evdev
+    /// device from `source`, or emulating a synthetic one.
+    pub fn new(
+        id: String,
+        source: InputSource,
+        seccomp_action: SeccompAction,
+    ) -> io::Result<Input> {
+        let (evdev, capabilities) = match &source {
+            InputSource::Evdev(path) => {
+                let evdev = Self::open_evdev(path).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("cannot open evdev device {}: {}", path.display(), e),
+                    )
+                })?;
+                let capabilities = InputCapabilities::from_evdev(&evdev)?;
+                (Some(evdev), capabilities)
+            }
+            _ => (None, InputCapabilities::synthetic(&source)),
+        };
+
+        Ok(Input {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::TYPE_INPUT as u32,
+                queue_sizes: QUEUE_SIZES.to_vec(),
+                paused_sync: Some(Arc::new(PauseBarrier::new(2))),
+                avail_features: 1u64 << VIRTIO_F_VERSION_1,
+                min_queues: 2,
+                ..Default::default()
+            },
+            id,
+            evdev,
+            capabilities,
+            select: 0,
+            subsel: 0,
+            pending: Arc::new(Mutex::new(VecDeque::new())),
+            inject_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            seccomp_action,
+        })
+    }
+
+    fn open_evdev(path: &Path) -> io::Result<File> {
+        let evdev = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+
+        // The events are meant for the guest only, and must not be acted
+        // upon by the host as well.
+        // Safe because the file descriptor is valid as it is owned by `evdev`.
+        let ret = unsafe { ioctl_with_val(&evdev, EVIOCGRAB, 1) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(evdev)
+    }
+
+    /// Queue events for the guest, as if they were coming from the device.
+    /// The whole batch is rejected if one of the events is not supported by
+    /// the device, as reported to the guest.
+    pub fn inject_events(&self, events: &[InputEvent]) -> io::Result<()> {
+        if let Some(event) = events.iter().find(|e| !self.capabilities.supports(e)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unsupported input event type {} code {}",
+                    event.event_type, event.code
+                ),
+            ));
+        }
+
+        for event in events {
+            push_event(&self.pending, *event);
+        }
+        // Events are only reports with a trailing EV_SYN, add it if the
+        // caller didn't, so that the guest doesn't wait for more.
+        if events.last().map_or(false, |e| e.event_type != EV_SYN) {
+            push_event(&self.pending, InputEvent::default());
+        }
+
+        self.inject_evt.write(1)
+    }
+
+    fn config_space(&self) -> Vec<u8> {
+        let payload = self.capabilities.payload(self.select, self.subsel);
+        let mut config = vec![0u8; CONFIG_HEADER_SIZE + CONFIG_PAYLOAD_SIZE];
+        config[0] = self.select;
+        config[1] = self.subsel;
+        config[2] = payload.len() as u8;
+        config[CONFIG_HEADER_SIZE..CONFIG_HEADER_SIZE + payload.len()].copy_from_slice(&payload);
+        config
+    }
+
+    fn state(&self) -> InputState {
+        InputState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            select: self.select,
+            subsel: self.subsel,
+        }
+    }
+
+    fn set_state(&mut self, state: &InputState) {
+        self.common.avail_features = state.avail_features;
+        self.common.acked_features = state.acked_features;
+        self.select = state.select;
+        self.subsel = state.subsel;
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Input {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(&self.config_space(), offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only the select and subsel fields are writable.
+        for (i, byte) in data.iter().enumerate() {
+            match offset + i as u64 {
+                0 => self.select = *byte,
+                1 => self.subsel = *byte,
+                o => guest_warn!("Write to read-only input configuration offset {:x}", o),
+            }
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
+        let kill_evt = self
+            .common
+            .kill_evt
+            .as_ref()
+            .unwrap()
+            .try_clone()
+            .map_err(|e| {
+                error!("failed to clone kill_evt eventfd: {}", e);
+                ActivateError::BadActivate
+            })?;
+        let pause_evt = self
+            .common
+            .pause_evt
+            .as_ref()
+            .unwrap()
+            .try_clone()
+            .map_err(|e| {
+                error!("failed to clone pause_evt eventfd: {}", e);
+                ActivateError::BadActivate
+            })?;
+        let inject_evt = self.inject_evt.try_clone().map_err(|e| {
+            error!("failed to clone inject_evt eventfd: {}", e);
+            ActivateError::BadActivate
+        })?;
+        let evdev = match self.evdev.as_ref() {
+            Some(evdev) => Some(evdev.try_clone().map_err(|e| {
+                error!("failed cloning evdev device: {}", e);
+                ActivateError::BadActivate
+            })?),
+            None => None,
+        };
+
+        let mut handler = InputEpollHandler {
+            queues,
+            mem,
+            interrupt_cb,
+            queue_evts,
+            evdev,
+            pending: self.pending.clone(),
+            inject_evt,
+            kill_evt,
+            pause_evt,
+        };
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        // Retrieve seccomp filter for virtio_input thread
+        let virtio_input_seccomp_filter =
+            get_seccomp_filter(&self.seccomp_action, Thread::VirtioInput)
+                .map_err(ActivateError::CreateSeccompFilter)?;
+        thread::Builder::new()
+            .name(self.id.clone())
+            .spawn(move || {
+                if let Err(e) = SeccompFilter::apply(virtio_input_seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
+                    error!("Error running worker: {:?}", e);
+                }
+            })
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-input epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        self.common.reset()
+    }
+}
+
+impl Pausable for Input {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for Input {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut input_snapshot = Snapshot::new(self.id.as_str());
+        input_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
+
+        Ok(input_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        if let Some(input_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            let input_state = input_section.to_versioned_state::<InputState>()?;
+
+            self.set_state(&input_state);
+            return Ok(());
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find input snapshot section"
+        )))
+    }
+}
+
+impl Transportable for Input {}
+impl Migratable for Input {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vsock::tests::NoopVirtioInterrupt;
+    use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryMmap};
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
+
+    fn select(input: &mut Input, select: u8, subsel: u8) -> Vec<u8> {
+        input.write_config(0, &[select, subsel]);
+        let mut size = [0u8];
+        input.read_config(2, &mut size);
+        let mut payload = vec![0u8; size[0] as usize];
+        input.read_config(CONFIG_HEADER_SIZE as u64, &mut payload);
+        payload
+    }
+
+    #[test]
+    fn test_input_config_space() {
+        let mut input = Input::new(
+            "_input".to_owned(),
+            InputSource::Tablet,
+            SeccompAction::Trap,
+        )
+        .unwrap();
+
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_ID_NAME, 0),
+            b"Cloud Hypervisor virtio tablet"
+        );
+        assert!(select(&mut input, VIRTIO_INPUT_CFG_ID_SERIAL, 0).is_empty());
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_ID_DEVIDS, 0),
+            vec![BUS_VIRTUAL as u8, 0, 0, 0, 3, 0, 1, 0]
+        );
+
+        let abs = select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8);
+        assert_eq!(abs, vec![0b11]);
+        assert!(select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_REL as u8).is_empty());
+        let keys = select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert!(test_bit(&keys, BTN_TOUCH));
+        assert!(!test_bit(&keys, KEY_ESC));
+
+        let abs_info = select(&mut input, VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y as u8);
+        assert_eq!(abs_info.len(), 20);
+        assert_eq!(abs_info[4..8], TABLET_ABS_MAX.to_le_bytes());
+        assert!(select(&mut input, VIRTIO_INPUT_CFG_ABS_INFO, 2).is_empty());
+    }
+
+    #[test]
+    fn test_input_inject_events() {
+        let input =
+            Input::new("_input".to_owned(), InputSource::Mouse, SeccompAction::Trap).unwrap();
+
+        // A keyboard key can't come from a mouse.
+        assert!(input
+            .inject_events(&[InputEvent {
+                event_type: EV_KEY,
+                code: KEY_ESC,
+                value: 1,
+            }])
+            .is_err());
+        assert!(input.pending.lock().unwrap().is_empty());
+
+        input
+            .inject_events(&[
+                InputEvent {
+                    event_type: EV_REL,
+                    code: REL_X,
+                    value: -5,
+                },
+                InputEvent {
+                    event_type: EV_KEY,
+                    code: BTN_LEFT,
+                    value: 1,
+                },
+            ])
+            .unwrap();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        // Only two buffers for the three events, the EV_SYN being added
+        // after the injected ones.
+        guest_queue.dtable[0].set(0x1000, 8, VIRTQ_DESC_F_WRITE, 0);
+        guest_queue.dtable[1].set(0x2000, 8, VIRTQ_DESC_F_WRITE, 0);
+        guest_queue.avail.ring[0].set(0);
+        guest_queue.avail.ring[1].set(1);
+        guest_queue.avail.idx.set(2);
+        let status_queue = GuestQ::new(GuestAddress(0x8000), &mem, 16);
+
+        let mut handler = InputEpollHandler {
+            queues: vec![guest_queue.create_queue(), status_queue.create_queue()],
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            queue_evts: vec![EventFd::new(0).unwrap(), EventFd::new(0).unwrap()],
+            evdev: None,
+            pending: input.pending.clone(),
+            inject_evt: input.inject_evt.try_clone().unwrap(),
+            kill_evt: EventFd::new(0).unwrap(),
+            pause_evt: EventFd::new(0).unwrap(),
+        };
+        assert!(handler.process_event_queue());
+
+        assert_eq!(guest_queue.used.idx.get(), 2);
+        assert_eq!(guest_queue.used.ring[0].get().len, 8);
+        let event: VirtioInputEvent = mem.read_obj(GuestAddress(0x1000)).unwrap();
+        assert_eq!(u16::from_le(event.event_type), EV_REL);
+        assert_eq!(u16::from_le(event.code), REL_X);
+        assert_eq!(u32::from_le(event.value) as i32, -5);
+        let event: VirtioInputEvent = mem.read_obj(GuestAddress(0x2000)).unwrap();
+        assert_eq!(u16::from_le(event.event_type), EV_KEY);
+
+        // The EV_SYN waits for the next buffer.
+        assert_eq!(*input.pending.lock().unwrap(), vec![InputEvent::default()]);
+        assert!(!handler.process_event_queue());
+    }
+}
@@ -9,7 +9,7 @@ use super::{
     EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::{DmaRemapping, VirtioInterrupt, VirtioInterruptType};
+use crate::{DmaRemapping, PauseBarrier, VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
 use seccomp::{SeccompAction, SeccompFilter};
 use std::collections::BTreeMap;
@@ -20,8 +20,9 @@ use std::ops::Bound::Included;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Barrier, RwLock};
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Instant;
 use vfio_ioctls::ExternalDmaMapping;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
@@ -29,7 +30,7 @@ use vm_memory::{
 };
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    Transportable, VersionedState,
 };
 use vmm_sys_util::eventfd::EventFd;
 
@@ -650,7 +651,7 @@ impl IommuEpollHandler {
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evts[0].as_raw_fd(), REQUEST_Q_EVENT)?;
@@ -752,6 +753,10 @@ struct IommuState {
     mappings: BTreeMap<u32, BTreeMap<u64, Mapping>>,
 }
 
+impl VersionedState for IommuState {
+    const VERSION: u16 = 1;
+}
+
 impl Iommu {
     pub fn new(id: String, seccomp_action: SeccompAction) -> io::Result<(Self, Arc<IommuMapping>)> {
         let config = VirtioIommuConfig {
@@ -774,7 +779,7 @@ impl Iommu {
                     avail_features: 1u64 << VIRTIO_F_VERSION_1
                         | 1u64 << VIRTIO_IOMMU_F_MAP_UNMAP
                         | 1u64 << VIRTIO_IOMMU_F_PROBE,
-                    paused_sync: Some(Arc::new(Barrier::new(2))),
+                    paused_sync: Some(Arc::new(PauseBarrier::new(2))),
                     ..Default::default()
                 },
                 config,
@@ -961,6 +966,10 @@ impl Pausable for Iommu {
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         self.common.resume()
     }
@@ -972,29 +981,18 @@ impl Snapshottable for Iommu {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut iommu_snapshot = Snapshot::new(self.id.as_str());
-        iommu_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id),
-            snapshot,
-        });
+        iommu_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
 
         Ok(iommu_snapshot)
     }
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(iommu_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
-            let iommu_state = match serde_json::from_slice(&iommu_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize IOMMU {}",
-                        error
-                    )))
-                }
-            };
+            let iommu_state = iommu_section.to_versioned_state::<IommuState>()?;
 
             self.set_state(&iommu_state);
             return Ok(());
@@ -23,23 +23,33 @@ extern crate vhost_rs;
 extern crate virtio_bindings;
 extern crate vm_device;
 extern crate vm_memory;
+#[macro_use]
+extern crate vmm_sys_util;
 
 use std::io;
 
+#[macro_use]
+mod guest_log;
 #[macro_use]
 mod device;
 pub mod balloon;
 pub mod block;
 mod console;
+mod device_events;
 pub mod epoll_helper;
+mod gpio;
+mod inflight;
+mod input;
 mod iommu;
 pub mod mem;
 pub mod net;
 pub mod net_util;
+mod notification;
 mod pmem;
 mod rng;
 pub mod seccomp_filters;
 pub mod transport;
+pub mod vhost_kernel;
 pub mod vhost_user;
 pub mod vsock;
 pub mod watchdog;
@@ -48,11 +58,19 @@ pub use self::balloon::*;
 pub use self::block::*;
 pub use self::console::*;
 pub use self::device::*;
+pub use self::device_events::*;
 pub use self::epoll_helper::*;
+pub use self::gpio::*;
+pub use self::guest_log::{
+    report_suppressed_guest_logs, set_guest_log_limit, DEFAULT_GUEST_LOG_LIMIT,
+};
+pub use self::inflight::*;
+pub use self::input::*;
 pub use self::iommu::*;
 pub use self::mem::*;
 pub use self::net::*;
 pub use self::net_util::*;
+pub use self::notification::*;
 pub use self::pmem::*;
 pub use self::rng::*;
 pub use self::vsock::*;
@@ -90,6 +108,8 @@ pub enum ActivateError {
     VhostUserBlkSetup(vhost_user::Error),
     /// Failed to reset vhost-user daemon.
     VhostUserReset(vhost_user::Error),
+    /// Failed to setup vhost-net.
+    VhostKernelNetSetup(vhost_kernel::Error),
     /// Cannot create seccomp filter
     CreateSeccompFilter(seccomp::SeccompError),
 }
@@ -116,10 +136,20 @@ pub enum Error {
     EpollWait(io::Error),
     FailedSignalingDriver(io::Error),
     VhostUserUpdateMemory(vhost_user::Error),
+    VhostKernelUpdateMemory(vhost_kernel::Error),
+    VhostKernelDirtyLog(vhost_kernel::Error),
     EventfdError(io::Error),
     SetShmRegionsNotSupported,
     EpollHander(String),
     NoMemoryConfigured,
     NetQueuePair(::net_util::NetQueuePairError),
     ApplySeccompFilter(seccomp::Error),
+    /// The device can't be reset.
+    ResetNotSupported,
+    /// Failed to read the ring indexes of a queue.
+    QueueRingIndex(vm_virtio::queue::Error),
+    /// Failed to activate the device again after resetting it.
+    ActivateDevice(ActivateError),
+    /// Failed to arm or read the timer bounding the notification latency.
+    NotificationTimer(vmm_sys_util::errno::Error),
 }
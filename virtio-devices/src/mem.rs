@@ -20,7 +20,7 @@ use super::{
 };
 
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::{VirtioInterrupt, VirtioInterruptType};
+use crate::{PauseBarrier, VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
@@ -30,8 +30,9 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
-use std::sync::{Arc, Barrier, Mutex};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
@@ -46,7 +47,7 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 // be aligned on this size, and the region size must be a multiple of it.
 pub const VIRTIO_MEM_ALIGN_SIZE: u64 = 128 << 20;
 // Use 2 MiB alignment so transparent hugepages can be used by KVM.
-const VIRTIO_MEM_DEFAULT_BLOCK_SIZE: u64 = 2 << 20;
+pub const VIRTIO_MEM_DEFAULT_BLOCK_SIZE: u64 = 2 << 20;
 
 // Request processed successfully, applicable for
 // - VIRTIO_MEM_REQ_PLUG
@@ -602,7 +603,7 @@ impl MemEpollHandler {
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.resize.evt.as_raw_fd(), RESIZE_EVENT)?;
@@ -746,7 +747,7 @@ impl Mem {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::TYPE_MEM as u32,
                 avail_features,
-                paused_sync: Some(Arc::new(Barrier::new(2))),
+                paused_sync: Some(Arc::new(PauseBarrier::new(2))),
                 queue_sizes: QUEUE_SIZES.to_vec(),
                 min_queues: 1,
                 ..Default::default()
@@ -760,6 +761,11 @@ impl Mem {
             hugepages,
         })
     }
+
+    // Get the amount of memory currently plugged by the guest.
+    pub fn plugged_size(&self) -> u64 {
+        self.config.lock().unwrap().plugged_size
+    }
 }
 
 impl Drop for Mem {
@@ -882,6 +888,10 @@ impl Pausable for Mem {
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         self.common.resume()
     }
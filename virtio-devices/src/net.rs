@@ -6,37 +6,43 @@
 // found in the THIRD-PARTY file.
 
 use super::net_util::{
-    build_net_config_space, build_net_config_space_with_mq, CtrlVirtio, NetCtrlEpollHandler,
-    VirtioNetConfig,
+    build_net_config_space, build_net_config_space_with_mq, net_config_read_only_ranges,
+    CtrlVirtio, GuestOffloads, NetCtrlEpollHandler, VirtioNetConfig,
 };
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    ActivateError, ActivateResult, BusyPoll, BusyPollCounters, DeviceEvent, DeviceEventNotifier,
+    EpollHelper, EpollHelperError, EpollHelperHandler, NotificationCounters,
+    NotificationSuppression, NotificationThrottle, Queue, VirtioCommon, VirtioDevice,
+    VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::VirtioInterrupt;
+use crate::{PauseBarrier, VirtioInterrupt};
 use anyhow::anyhow;
 use net_util::{
-    open_tap, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio, Tap, TapError, TxVirtio,
+    open_tap, virtio_features_to_tap_offload, FlowSteering, MacAddr, NetCounters, NetQueuePair,
+    OpenTapError, PacketCapture, RxVirtio, Tap, TapError, TxVirtio, VlanFilter,
 };
 use seccomp::{SeccompAction, SeccompFilter};
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::num::Wrapping;
+use std::ops::Range;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use virtio_bindings::bindings::virtio_net::*;
 use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    Transportable, VersionedState,
 };
+use vm_virtio::DescriptorInspector;
 use vmm_sys_util::eventfd::EventFd;
 
 // The guest has made a buffer available to receive a frame into.
@@ -45,6 +51,9 @@ pub const RX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 pub const TX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // A frame is available for reading from the tap device to receive in the guest.
 pub const RX_TAP_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// The used entries held back by the notification suppression are due.
+const RX_NOTIFICATION_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+const TX_NOTIFICATION_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
 
 #[derive(Debug)]
 pub enum Error {
@@ -69,16 +78,51 @@ struct NetEpollHandler {
     // a restore as the vCPU thread isn't ready to handle the interrupt. This causes
     // issues when combined with VIRTIO_RING_F_EVENT_IDX interrupt suppression.
     driver_awake: bool,
+    busy_poll: Option<BusyPoll>,
+    // One for the RX queue and one for the TX queue.
+    notification_throttles: Vec<NotificationThrottle>,
 }
 
 impl NetEpollHandler {
-    fn signal_used_queue(&self, queue: &Queue) -> result::Result<(), DeviceError> {
+    fn signal_used_queue(&mut self, index: usize) -> result::Result<(), DeviceError> {
         self.interrupt_cb
-            .trigger(&VirtioInterruptType::Queue, Some(queue))
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queue_pair[index]))
             .map_err(|e| {
                 error!("Failed to signal used queue: {:?}", e);
                 DeviceError::FailedSignalingUsedQueue(e)
-            })
+            })?;
+
+        self.notification_throttles[index]
+            .notified()
+            .map_err(DeviceError::NotificationTimer)
+    }
+
+    // Signal the used queue after some descriptors were processed, unless
+    // the notification is held back to batch it with the next used entries.
+    // Without suppression, the queue decides through EVENT_IDX.
+    fn used(
+        &mut self,
+        index: usize,
+        next_used: Wrapping<u16>,
+        needs_notification: bool,
+    ) -> result::Result<(), DeviceError> {
+        let used_count = (self.queue_pair[index].next_used - next_used).0 as usize;
+        let throttle = &mut self.notification_throttles[index];
+        let mut notify = throttle
+            .used(used_count)
+            .map_err(DeviceError::NotificationTimer)?;
+        if throttle.timer_fd().is_none() {
+            notify = needs_notification;
+        }
+
+        if notify || !self.driver_awake {
+            self.signal_used_queue(index)?;
+            debug!("Signalling queue {}", index);
+        } else {
+            debug!("Not signalling queue {}", index);
+        }
+
+        Ok(())
     }
 
     fn handle_rx_event(&mut self) -> result::Result<(), DeviceError> {
@@ -87,19 +131,16 @@ impl NetEpollHandler {
             error!("Failed to get rx queue event: {:?}", e);
         }
 
-        if self
+        self.resume_rx()
+    }
+
+    fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
+        let next_used = self.queue_pair[0].next_used;
+        let needs_notification = self
             .net
             .resume_rx(&mut self.queue_pair[0])
-            .map_err(DeviceError::NetQueuePair)?
-            || !self.driver_awake
-        {
-            self.signal_used_queue(&self.queue_pair[0])?;
-            debug!("Signalling RX queue");
-        } else {
-            debug!("Not signalling RX queue");
-        }
-
-        Ok(())
+            .map_err(DeviceError::NetQueuePair)?;
+        self.used(0, next_used, needs_notification)
     }
 
     fn handle_tx_event(&mut self) -> result::Result<(), DeviceError> {
@@ -107,43 +148,54 @@ impl NetEpollHandler {
         if let Err(e) = queue_evt.read() {
             error!("Failed to get tx queue event: {:?}", e);
         }
-        if self
+
+        self.process_tx()
+    }
+
+    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
+        let next_used = self.queue_pair[1].next_used;
+        let needs_notification = self
             .net
             .process_tx(&mut self.queue_pair[1])
-            .map_err(DeviceError::NetQueuePair)?
-            || !self.driver_awake
-        {
-            self.signal_used_queue(&self.queue_pair[1])?;
-            debug!("Signalling TX queue");
-        } else {
-            debug!("Not signalling TX queue");
-        }
-        Ok(())
+            .map_err(DeviceError::NetQueuePair)?;
+        self.used(1, next_used, needs_notification)
     }
 
     fn handle_rx_tap_event(&mut self) -> result::Result<(), DeviceError> {
-        if self
+        let next_used = self.queue_pair[0].next_used;
+        let needs_notification = self
             .net
             .process_rx_tap(&mut self.queue_pair[0])
-            .map_err(DeviceError::NetQueuePair)?
-            || !self.driver_awake
+            .map_err(DeviceError::NetQueuePair)?;
+        self.used(0, next_used, needs_notification)
+    }
+
+    fn handle_notification_timer_event(&mut self, index: usize) -> result::Result<(), DeviceError> {
+        if self.notification_throttles[index]
+            .timer_expired()
+            .map_err(DeviceError::NotificationTimer)?
         {
-            self.signal_used_queue(&self.queue_pair[0])?;
-            debug!("Signalling RX queue");
-        } else {
-            debug!("Not signalling RX queue");
+            self.signal_used_queue(index)?;
         }
+
         Ok(())
     }
 
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.set_busy_poll(self.busy_poll.clone());
         helper.add_event(self.queue_evt_pair[0].as_raw_fd(), RX_QUEUE_EVENT)?;
         helper.add_event(self.queue_evt_pair[1].as_raw_fd(), TX_QUEUE_EVENT)?;
+        if let Some(timer_fd) = self.notification_throttles[0].timer_fd() {
+            helper.add_event(timer_fd, RX_NOTIFICATION_TIMER_EVENT)?;
+        }
+        if let Some(timer_fd) = self.notification_throttles[1].timer_fd() {
+            helper.add_event(timer_fd, TX_NOTIFICATION_TIMER_EVENT)?;
+        }
 
         // If there are some already available descriptors on the RX queue,
         // then we can start the thread while listening onto the TAP.
@@ -189,6 +241,13 @@ impl EpollHelperHandler for NetEpollHandler {
                     return true;
                 }
             }
+            RX_NOTIFICATION_TIMER_EVENT | TX_NOTIFICATION_TIMER_EVENT => {
+                let index = (ev_type - RX_NOTIFICATION_TIMER_EVENT) as usize;
+                if let Err(e) = self.handle_notification_timer_event(index) {
+                    error!("Error handling notification timer: {:?}", e);
+                    return true;
+                }
+            }
             _ => {
                 error!("Unknown event: {}", ev_type);
                 return true;
@@ -196,6 +255,46 @@ impl EpollHelperHandler for NetEpollHandler {
         }
         false
     }
+
+    // The frames the guest made available are sent, and the RX queue which
+    // ran out of descriptors resumes once it made some available.
+    fn poll_queues(&mut self) -> Option<bool> {
+        let mem = self.net.mem.as_ref().unwrap().memory();
+        let pending = self.queue_pair[1]
+            .avail_index_from_memory(&mem)
+            .and_then(|tx_avail| {
+                Ok((
+                    tx_avail != self.queue_pair[1].next_avail.0,
+                    !self.net.rx_tap_listening && self.queue_pair[0].available_descriptors(&mem)?,
+                ))
+            });
+        drop(mem);
+        let (tx_pending, rx_pending) = match pending {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!("Failed to poll the queues: {:?}", e);
+                return None;
+            }
+        };
+
+        if tx_pending || rx_pending {
+            self.driver_awake = true;
+        }
+        if tx_pending {
+            if let Err(e) = self.process_tx() {
+                error!("Error processing TX queue: {:?}", e);
+                return None;
+            }
+        }
+        if rx_pending {
+            if let Err(e) = self.resume_rx() {
+                error!("Error processing RX queue: {:?}", e);
+                return None;
+            }
+        }
+
+        Some(tx_pending || rx_pending)
+    }
 }
 
 pub struct Net {
@@ -205,6 +304,17 @@ pub struct Net {
     config: VirtioNetConfig,
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<()>>,
     counters: NetCounters,
+    vlan_filter: VlanFilter,
+    guest_offloads: Arc<Mutex<Option<u64>>>,
+    capture: PacketCapture,
+    flow_steering: FlowSteering,
+    anti_spoof: bool,
+    trusted_local: bool,
+    inspector: Option<Arc<dyn DescriptorInspector>>,
+    event_notifier: Option<DeviceEventNotifier>,
+    busy_poll: Option<BusyPoll>,
+    notification_suppression: NotificationSuppression,
+    notification_counters: NotificationCounters,
     seccomp_action: SeccompAction,
 }
 
@@ -214,6 +324,28 @@ pub struct NetState {
     pub acked_features: u64,
     pub config: VirtioNetConfig,
     pub queue_size: Vec<u16>,
+    #[serde(default)]
+    pub vlan_table: Vec<u64>,
+    pub guest_offloads: Option<u64>,
+}
+
+// Version 1 is the format of the snapshots taken before the state got
+// versioned. Changes to the fields must bump the version, along with a
+// migration step from the previous one.
+impl VersionedState for NetState {
+    const VERSION: u16 = 2;
+
+    fn migrate(version: u16, mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+        match version {
+            // Version 2 added the offloads programmed by the guest, the
+            // older snapshots using the negotiated ones.
+            1 => {
+                state["guest_offloads"] = serde_json::Value::Null;
+                Ok(state)
+            }
+            _ => Err(anyhow!("No migration step")),
+        }
+    }
 }
 
 impl Net {
@@ -230,9 +362,12 @@ impl Net {
         let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_CSUM
             | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_TSO6
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
+            | 1 << VIRTIO_NET_F_HOST_TSO6
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_MRG_RXBUF
             | 1 << VIRTIO_RING_F_EVENT_IDX
             | 1 << VIRTIO_F_VERSION_1;
 
@@ -240,7 +375,9 @@ impl Net {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
-        avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ;
+        avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ
+            | 1 << VIRTIO_NET_F_CTRL_VLAN
+            | 1 << VIRTIO_NET_F_CTRL_GUEST_OFFLOADS;
         let queue_num = num_queues + 1;
 
         let mut config = VirtioNetConfig::default();
@@ -250,12 +387,15 @@ impl Net {
             build_net_config_space_with_mq(&mut config, num_queues, &mut avail_features);
         }
 
+        // Any queue of the tap interface can program its flow rules.
+        let flow_steering = FlowSteering::new(taps[0].clone(), num_queues / 2);
+
         Ok(Net {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::TYPE_NET as u32,
                 avail_features,
                 queue_sizes: vec![queue_size; queue_num],
-                paused_sync: Some(Arc::new(Barrier::new((num_queues / 2) + 1))),
+                paused_sync: Some(Arc::new(PauseBarrier::new((num_queues / 2) + 1))),
                 min_queues: 2,
                 ..Default::default()
             },
@@ -264,6 +404,17 @@ impl Net {
             config,
             ctrl_queue_epoll_thread: None,
             counters: NetCounters::default(),
+            vlan_filter: VlanFilter::new(),
+            guest_offloads: Arc::new(Mutex::new(None)),
+            capture: PacketCapture::new(),
+            flow_steering,
+            anti_spoof: false,
+            trusted_local: false,
+            inspector: None,
+            event_notifier: None,
+            busy_poll: None,
+            notification_suppression: NotificationSuppression::default(),
+            notification_counters: NotificationCounters::default(),
             seccomp_action,
         })
     }
@@ -324,12 +475,75 @@ impl Net {
         )
     }
 
+    /// Capture shared with the queue pairs, through which the frames they
+    /// process can be recorded at any time.
+    pub fn packet_capture(&self) -> PacketCapture {
+        self.capture.clone()
+    }
+
+    pub fn flow_steering(&self) -> FlowSteering {
+        self.flow_steering.clone()
+    }
+
+    /// Drops the frames sent by the guest from another MAC address than the
+    /// one of the device.
+    pub fn set_anti_spoof(&mut self, anti_spoof: bool) {
+        self.anti_spoof = anti_spoof;
+    }
+
+    /// Reports the checksum of the frames received from the TAP as valid,
+    /// sparing the guest from verifying it. Only the TAP interfaces carrying
+    /// traffic from trusted local sources should be set so, as corrupted
+    /// frames would then reach the guest applications.
+    pub fn set_trusted_local(&mut self, trusted_local: bool) {
+        self.trusted_local = trusted_local;
+    }
+
+    /// Has the threads of the queue pairs poll for their events for the
+    /// given duration after handling some, before blocking.
+    pub fn set_busy_poll(&mut self, duration: Option<Duration>) {
+        self.busy_poll = duration.map(|duration| BusyPoll {
+            duration,
+            counters: BusyPollCounters::default(),
+        });
+    }
+
+    /// Holds the used buffer notifications of each queue back until enough
+    /// of them are pending, or for a bounded time.
+    pub fn set_notification_suppression(
+        &mut self,
+        notification_suppression: NotificationSuppression,
+    ) {
+        self.notification_suppression = notification_suppression;
+    }
+
+    /// Reports the activation, pause and reset of the device.
+    pub fn set_event_notifier(&mut self, event_notifier: DeviceEventNotifier) {
+        self.event_notifier = Some(event_notifier);
+    }
+
+    fn notify_event(&self, event: DeviceEvent) {
+        if let Some(event_notifier) = &self.event_notifier {
+            event_notifier.notify(event);
+        }
+    }
+
+    // Pausing and resuming a device the driver didn't activate doesn't
+    // change its state.
+    fn notify_activation_event(&self, event: DeviceEvent) {
+        if self.common.epoll_threads.is_some() {
+            self.notify_event(event);
+        }
+    }
+
     fn state(&self) -> NetState {
         NetState {
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
             config: self.config,
             queue_size: self.common.queue_sizes.clone(),
+            vlan_table: self.vlan_filter.table(),
+            guest_offloads: *self.guest_offloads.lock().unwrap(),
         }
     }
 
@@ -338,6 +552,8 @@ impl Net {
         self.common.acked_features = state.acked_features;
         self.config = state.config;
         self.common.queue_sizes = state.queue_size.clone();
+        self.vlan_filter.set_table(&state.vlan_table);
+        *self.guest_offloads.lock().unwrap() = state.guest_offloads;
     }
 }
 
@@ -371,6 +587,16 @@ impl VirtioDevice for Net {
         self.read_config_from_slice(self.config.as_slice(), offset, data);
     }
 
+    fn read_only_config_ranges(&self) -> Vec<Range<u64>> {
+        net_config_read_only_ranges(self.common.avail_features)
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let mut config = self.config;
+        self.write_config_helper(config.as_mut_slice(), offset, data);
+        self.config = config;
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
@@ -381,6 +607,29 @@ impl VirtioDevice for Net {
         if let Some(mut taps) = self.taps.clone() {
             self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
 
+            // Without VIRTIO_NET_F_CTRL_VLAN, the guest can't program the
+            // VLANs it wants to receive, and gets all of them.
+            let vlan_filter = if self.common.feature_acked(VIRTIO_NET_F_CTRL_VLAN.into()) {
+                Some(self.vlan_filter.clone())
+            } else {
+                None
+            };
+
+            // Lets the guest change the offloads programmed below after
+            // boot, for instance when GRO is disabled through ethtool.
+            let guest_offloads = if self
+                .common
+                .feature_acked(VIRTIO_NET_F_CTRL_GUEST_OFFLOADS.into())
+            {
+                Some(GuestOffloads::new(
+                    taps.clone(),
+                    self.common.acked_features,
+                    self.guest_offloads.clone(),
+                ))
+            } else {
+                None
+            };
+
             let queue_num = queues.len();
             if self.common.feature_acked(VIRTIO_NET_F_CTRL_VQ.into()) && queue_num % 2 != 0 {
                 let cvq_queue = queues.remove(queue_num - 1);
@@ -411,7 +660,12 @@ impl VirtioDevice for Net {
                     mem: mem.clone(),
                     kill_evt,
                     pause_evt,
-                    ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt),
+                    ctrl_q: CtrlVirtio::new(
+                        cvq_queue,
+                        cvq_queue_evt,
+                        vlan_filter.clone(),
+                        guest_offloads,
+                    ),
                     epoll_fd: 0,
                 };
 
@@ -419,7 +673,7 @@ impl VirtioDevice for Net {
                 // Let's update the barrier as we need 1 for each RX/TX pair +
                 // 1 for the control queue + 1 for the main thread signalling
                 // the pause.
-                self.common.paused_sync = Some(Arc::new(Barrier::new(taps.len() + 2)));
+                self.common.paused_sync = Some(Arc::new(PauseBarrier::new(taps.len() + 2)));
                 let paused_sync = self.common.paused_sync.clone();
 
                 // Retrieve seccomp filter for virtio_net_ctl thread
@@ -443,11 +697,43 @@ impl VirtioDevice for Net {
             }
 
             let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
+            let mrg_rxbuf = self.common.feature_acked(VIRTIO_NET_F_MRG_RXBUF.into());
+            let data_valid =
+                self.trusted_local && self.common.feature_acked(VIRTIO_NET_F_GUEST_CSUM.into());
+            // The offloads the guest programmed survive a restore.
+            let tap_offloads = virtio_features_to_tap_offload(
+                self.guest_offloads
+                    .lock()
+                    .unwrap()
+                    .unwrap_or(self.common.acked_features),
+            );
+            let anti_spoof = if self.anti_spoof {
+                Some(MacAddr::from_bytes_unchecked(&self.config.mac))
+            } else {
+                None
+            };
 
             let mut epoll_threads = Vec::new();
             for i in 0..taps.len() {
-                let rx = RxVirtio::new();
-                let tx = TxVirtio::new();
+                // The virtio-net header is passed through as is between the
+                // guest and the TAP, which means the TAP must only produce
+                // frames the guest negotiated.
+                let tap = taps.remove(0);
+                tap.set_offload(tap_offloads).map_err(|e| {
+                    error!("Error programming tap offload: {:?}", e);
+                    ActivateError::BadActivate
+                })?;
+
+                let mut rx = RxVirtio::new();
+                rx.mrg_rxbuf = mrg_rxbuf;
+                rx.data_valid = data_valid;
+                rx.vlan_filter = vlan_filter.clone();
+                rx.capture = self.capture.clone();
+                let mut tx = TxVirtio::new();
+                tx.capture = self.capture.clone();
+                tx.anti_spoof = anti_spoof;
+                tx.inspector = self.inspector.clone();
+                tx.queue_index = (2 * i + 1) as u16;
                 let rx_tap_listening = false;
 
                 let mut queue_pair = Vec::new();
@@ -481,10 +767,24 @@ impl VirtioDevice for Net {
                         ActivateError::BadActivate
                     })?;
 
+                let mut notification_throttles = Vec::new();
+                for _ in 0..2 {
+                    notification_throttles.push(
+                        NotificationThrottle::new(
+                            self.notification_suppression,
+                            self.notification_counters.clone(),
+                        )
+                        .map_err(|e| {
+                            error!("failed to create notification timer: {}", e);
+                            ActivateError::BadActivate
+                        })?,
+                    );
+                }
+
                 let mut handler = NetEpollHandler {
                     net: NetQueuePair {
                         mem: Some(mem.clone()),
-                        tap: taps.remove(0),
+                        tap,
                         rx,
                         tx,
                         epoll_fd: None,
@@ -498,6 +798,8 @@ impl VirtioDevice for Net {
                     kill_evt,
                     pause_evt,
                     driver_awake: false,
+                    busy_poll: self.busy_poll.clone(),
+                    notification_throttles,
                 };
 
                 let paused = self.common.paused.clone();
@@ -524,13 +826,19 @@ impl VirtioDevice for Net {
 
             self.common.epoll_threads = Some(epoll_threads);
 
+            self.notify_event(DeviceEvent::Activated);
             return Ok(());
         }
         Err(ActivateError::BadActivate)
     }
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
-        self.common.reset()
+        // The driver programs the VLANs again once the device is reset.
+        self.vlan_filter.clear();
+        *self.guest_offloads.lock().unwrap() = None;
+        let result = self.common.reset();
+        self.notify_event(DeviceEvent::Reset);
+        result
     }
 
     fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
@@ -552,14 +860,51 @@ impl VirtioDevice for Net {
             "tx_frames",
             Wrapping(self.counters.tx_frames.load(Ordering::Acquire)),
         );
+        counters.insert(
+            "tx_spoofed_frames",
+            Wrapping(self.counters.tx_spoofed_frames.load(Ordering::Acquire)),
+        );
+
+        // The frames the host coalesced before handing them to the guest,
+        // and how many segments each of them was made of on average.
+        let coalesced_frames = self.counters.rx_coalesced_frames.load(Ordering::Acquire);
+        let coalesced_segments = self.counters.rx_coalesced_segments.load(Ordering::Acquire);
+        counters.insert("rx_coalesced_frames", Wrapping(coalesced_frames));
+        counters.insert("rx_coalesced_segments", Wrapping(coalesced_segments));
+        counters.insert(
+            "rx_coalesced_segments_avg",
+            Wrapping(
+                coalesced_segments
+                    .checked_div(coalesced_frames)
+                    .unwrap_or(0),
+            ),
+        );
+
+        if let Some(busy_poll) = &self.busy_poll {
+            busy_poll.counters.report(&mut counters);
+        }
+        self.notification_counters.report(&mut counters);
 
         Some(counters)
     }
+
+    // The hook sees each frame sent by the guest.
+    fn set_descriptor_inspector(&mut self, inspector: Arc<dyn DescriptorInspector>) {
+        self.inspector = Some(inspector);
+    }
 }
 
 impl Pausable for Net {
     fn pause(&mut self) -> result::Result<(), MigratableError> {
-        self.common.pause()
+        self.common.pause()?;
+        self.notify_activation_event(DeviceEvent::Deactivated);
+        Ok(())
+    }
+
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)?;
+        self.notify_activation_event(DeviceEvent::Deactivated);
+        Ok(())
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
@@ -568,6 +913,7 @@ impl Pausable for Net {
         if let Some(ctrl_queue_epoll_thread) = &self.ctrl_queue_epoll_thread {
             ctrl_queue_epoll_thread.thread().unpark();
         }
+        self.notify_activation_event(DeviceEvent::Activated);
         Ok(())
     }
 }
@@ -578,29 +924,18 @@ impl Snapshottable for Net {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut net_snapshot = Snapshot::new(self.id.as_str());
-        net_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id),
-            snapshot,
-        });
+        net_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
 
         Ok(net_snapshot)
     }
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(net_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
-            let net_state = match serde_json::from_slice(&net_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize NET {}",
-                        error
-                    )))
-                }
-            };
+            let net_state = net_section.to_versioned_state::<NetState>()?;
 
             self.set_state(&net_state);
             return Ok(());
@@ -613,3 +948,81 @@ impl Snapshottable for Net {
 }
 impl Transportable for Net {}
 impl Migratable for Net {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_config_masked;
+    use net_util::MAC_ADDR_LEN;
+
+    #[test]
+    fn test_restore_net_state_v1() {
+        // Section of a snapshot taken before the state got versioned.
+        let section = SnapshotDataSection {
+            id: "_net0-section".to_string(),
+            snapshot: br#"{
+                "avail_features": 4294967295,
+                "acked_features": 65536,
+                "config": {
+                    "mac": [82, 84, 0, 18, 52, 86],
+                    "status": 1,
+                    "max_virtqueue_pairs": 2,
+                    "mtu": 1500,
+                    "speed": 0,
+                    "duplex": 0
+                },
+                "queue_size": [256, 256, 256, 256]
+            }"#
+            .to_vec(),
+            ..Default::default()
+        };
+
+        let state = section.to_versioned_state::<NetState>().unwrap();
+        assert_eq!(state.avail_features, 0xffff_ffff);
+        assert_eq!(state.acked_features, 1 << 16);
+        assert_eq!({ state.config.mac }, [0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        assert_eq!({ state.config.max_virtqueue_pairs }, 2);
+        assert_eq!({ state.config.mtu }, 1500);
+        assert_eq!(state.queue_size, vec![256; 4]);
+        assert!(state.vlan_table.is_empty());
+        assert_eq!(state.guest_offloads, None);
+
+        // A snapshot taken with a newer format can't be restored.
+        let section = SnapshotDataSection {
+            version: NetState::VERSION + 1,
+            ..section
+        };
+        assert!(section.to_versioned_state::<NetState>().is_err());
+    }
+
+    #[test]
+    fn test_write_read_only_mac() {
+        let mac = MacAddr::parse_str("52:54:00:12:34:56").unwrap();
+        let mut config = VirtioNetConfig::default();
+        let mut avail_features = 0;
+        build_net_config_space(&mut config, mac, 2, &mut avail_features);
+
+        // The advertised MAC can't be overwritten by the guest, nor can the
+        // fields after it.
+        let status = config.status;
+        let max_virtqueue_pairs = config.max_virtqueue_pairs;
+        let read_only = net_config_read_only_ranges(avail_features);
+        let data = [0xffu8; 10];
+        assert_eq!(
+            write_config_masked(config.as_mut_slice(), 0, &data, &read_only),
+            10
+        );
+        assert_eq!({ config.mac }, [0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        assert_eq!({ config.status }, status);
+        assert_eq!({ config.max_virtqueue_pairs }, max_virtqueue_pairs);
+
+        // Without VIRTIO_NET_F_MAC, the guest picks its own.
+        let read_only = net_config_read_only_ranges(0);
+        assert_eq!(
+            write_config_masked(config.as_mut_slice(), 0, &data, &read_only),
+            4
+        );
+        assert_eq!({ config.mac }, [0xff; 6]);
+        assert_eq!({ config.status }, status);
+    }
+}
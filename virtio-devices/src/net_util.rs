@@ -6,11 +6,13 @@ use super::{
     DescriptorChain, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
     EPOLL_HELPER_EVENT_LAST,
 };
-use net_util::MacAddr;
+use crate::PauseBarrier;
+use net_util::{virtio_features_to_tap_offload, MacAddr, Tap, TapError, VlanFilter, MAC_ADDR_LEN};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::ops::Range;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Mutex};
 use virtio_bindings::bindings::virtio_net::*;
 use vm_memory::{
     ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError, GuestMemoryMmap,
@@ -19,11 +21,17 @@ use vmm_sys_util::eventfd::EventFd;
 
 type Result<T> = std::result::Result<T, Error>;
 
-const QUEUE_SIZE: usize = 256;
-
 // Event available on the control queue.
 const CTRL_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 
+// Offloads the guest can toggle through VIRTIO_NET_CTRL_GUEST_OFFLOADS,
+// each one using the bit of the feature it depends on.
+const GUEST_OFFLOADS: u64 = 1 << VIRTIO_NET_F_GUEST_CSUM
+    | 1 << VIRTIO_NET_F_GUEST_TSO4
+    | 1 << VIRTIO_NET_F_GUEST_TSO6
+    | 1 << VIRTIO_NET_F_GUEST_ECN
+    | 1 << VIRTIO_NET_F_GUEST_UFO;
+
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug, Default, Deserialize)]
 pub struct VirtioNetConfig {
@@ -68,8 +76,6 @@ unsafe impl ByteValued for VirtioNetConfig {}
 
 #[derive(Debug)]
 pub enum Error {
-    /// Read process MQ.
-    FailedProcessMQ,
     /// Read queue failed.
     GuestMemory(GuestMemoryError),
     /// Invalid ctrl class
@@ -78,17 +84,68 @@ pub enum Error {
     InvalidCtlCmd,
     /// Invalid descriptor
     InvalidDesc,
+    /// Guest offloads which weren't negotiated
+    InvalidGuestOffloads(u64),
     /// Invalid queue pairs number
     InvalidQueuePairsNum,
+    /// Invalid VLAN ID
+    InvalidVlanId(u16),
     /// No memory passed in.
     NoMemory,
-    /// No ueue pairs number.
-    NoQueuePairsNum,
+    /// No data for the ctrl command.
+    NoCtlData,
+    /// Failed to program the guest offloads on the TAP.
+    SetGuestOffloads(TapError),
+}
+
+/// The TAPs whose offloads the guest programs, along with the features it
+/// negotiated.
+#[derive(Clone)]
+pub struct GuestOffloads {
+    taps: Vec<Tap>,
+    acked_features: u64,
+    // The offloads last programmed by the guest, shared with the device so
+    // that they're snapshotted. None until the guest programs any, the
+    // negotiated ones being used.
+    programmed: Arc<Mutex<Option<u64>>>,
+}
+
+impl GuestOffloads {
+    pub fn new(taps: Vec<Tap>, acked_features: u64, programmed: Arc<Mutex<Option<u64>>>) -> Self {
+        GuestOffloads {
+            taps,
+            acked_features,
+            programmed,
+        }
+    }
+
+    fn set(&self, offloads: u64) -> Result<()> {
+        // The guest can only enable the offloads it negotiated.
+        if offloads & !(self.acked_features & GUEST_OFFLOADS) != 0 {
+            return Err(Error::InvalidGuestOffloads(offloads));
+        }
+
+        let tap_offloads = virtio_features_to_tap_offload(offloads);
+        for tap in self.taps.iter() {
+            tap.set_offload(tap_offloads)
+                .map_err(Error::SetGuestOffloads)?;
+        }
+        *self.programmed.lock().unwrap() = Some(offloads);
+
+        Ok(())
+    }
 }
 
 pub struct CtrlVirtio {
     pub queue_evt: EventFd,
     pub queue: Queue,
+    // Only set for the devices filtering VLANs in the VMM, the VLAN
+    // commands being refused otherwise.
+    pub vlan_filter: Option<VlanFilter>,
+    // Only set for the devices which negotiated
+    // VIRTIO_NET_F_CTRL_GUEST_OFFLOADS, the offloads commands being refused
+    // otherwise.
+    pub guest_offloads: Option<GuestOffloads>,
 }
 
 impl std::clone::Clone for CtrlVirtio {
@@ -96,68 +153,145 @@ impl std::clone::Clone for CtrlVirtio {
         CtrlVirtio {
             queue_evt: self.queue_evt.try_clone().unwrap(),
             queue: self.queue.clone(),
+            vlan_filter: self.vlan_filter.clone(),
+            guest_offloads: self.guest_offloads.clone(),
         }
     }
 }
 
 impl CtrlVirtio {
-    pub fn new(queue: Queue, queue_evt: EventFd) -> Self {
-        CtrlVirtio { queue_evt, queue }
+    pub fn new(
+        queue: Queue,
+        queue_evt: EventFd,
+        vlan_filter: Option<VlanFilter>,
+        guest_offloads: Option<GuestOffloads>,
+    ) -> Self {
+        CtrlVirtio {
+            queue_evt,
+            queue,
+            vlan_filter,
+            guest_offloads,
+        }
     }
 
-    fn process_mq(&self, mem: &GuestMemoryMmap, avail_desc: DescriptorChain) -> Result<()> {
-        let mq_desc = if avail_desc.has_next() {
-            avail_desc.next_descriptor().unwrap()
-        } else {
-            return Err(Error::NoQueuePairsNum);
-        };
+    fn process_mq(&self, mem: &GuestMemoryMmap, data_desc: &DescriptorChain) -> Result<()> {
         let queue_pairs = mem
-            .read_obj::<u16>(mq_desc.addr)
+            .read_obj::<u16>(data_desc.addr)
             .map_err(Error::GuestMemory)?;
         if (queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
             || (queue_pairs > VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
         {
             return Err(Error::InvalidQueuePairsNum);
         }
-        let status_desc = if mq_desc.has_next() {
-            mq_desc.next_descriptor().unwrap()
-        } else {
-            return Err(Error::NoQueuePairsNum);
-        };
-        mem.write_obj::<u8>(0, status_desc.addr)
+
+        Ok(())
+    }
+
+    fn process_vlan(
+        &self,
+        mem: &GuestMemoryMmap,
+        cmd: u32,
+        data_desc: &DescriptorChain,
+    ) -> Result<()> {
+        let vlan_filter = self.vlan_filter.as_ref().ok_or(Error::InvalidCtlClass)?;
+        let vid = mem
+            .read_obj::<u16>(data_desc.addr)
             .map_err(Error::GuestMemory)?;
+        let valid = match cmd {
+            VIRTIO_NET_CTRL_VLAN_ADD => vlan_filter.add(vid),
+            VIRTIO_NET_CTRL_VLAN_DEL => vlan_filter.remove(vid),
+            _ => return Err(Error::InvalidCtlCmd),
+        };
+        if !valid {
+            return Err(Error::InvalidVlanId(vid));
+        }
 
         Ok(())
     }
 
-    pub fn process_cvq(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE];
-        let mut used_count = 0;
-        if let Some(avail_desc) = self.queue.iter(&mem).next() {
-            used_desc_heads[used_count] = (avail_desc.index, avail_desc.len);
-            used_count += 1;
-            let ctrl_hdr = mem
-                .read_obj::<u16>(avail_desc.addr)
-                .map_err(Error::GuestMemory)?;
-            let ctrl_hdr_v = ctrl_hdr.as_slice();
-            let class = ctrl_hdr_v[0];
-            let cmd = ctrl_hdr_v[1];
-            match u32::from(class) {
-                VIRTIO_NET_CTRL_MQ => {
-                    if u32::from(cmd) != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
-                        return Err(Error::InvalidCtlCmd);
-                    }
-                    if let Err(_e) = self.process_mq(&mem, avail_desc) {
-                        return Err(Error::FailedProcessMQ);
-                    }
+    fn process_guest_offloads(
+        &self,
+        mem: &GuestMemoryMmap,
+        cmd: u32,
+        data_desc: &DescriptorChain,
+    ) -> Result<()> {
+        let guest_offloads = self.guest_offloads.as_ref().ok_or(Error::InvalidCtlClass)?;
+        if cmd != VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET {
+            return Err(Error::InvalidCtlCmd);
+        }
+        let offloads = mem
+            .read_obj::<u64>(data_desc.addr)
+            .map_err(Error::GuestMemory)?;
+
+        guest_offloads.set(u64::from_le(offloads))
+    }
+
+    fn process_cmd(
+        &self,
+        mem: &GuestMemoryMmap,
+        ctrl_desc: &DescriptorChain,
+        data_desc: &DescriptorChain,
+    ) -> Result<()> {
+        let ctrl_hdr = mem
+            .read_obj::<u16>(ctrl_desc.addr)
+            .map_err(Error::GuestMemory)?;
+        let ctrl_hdr_v = ctrl_hdr.as_slice();
+        let class = ctrl_hdr_v[0];
+        let cmd = ctrl_hdr_v[1];
+        match u32::from(class) {
+            VIRTIO_NET_CTRL_MQ => {
+                if u32::from(cmd) != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
+                    return Err(Error::InvalidCtlCmd);
                 }
-                _ => return Err(Error::InvalidCtlClass),
+                self.process_mq(mem, data_desc)
             }
-        } else {
-            return Err(Error::InvalidDesc);
+            VIRTIO_NET_CTRL_VLAN => self.process_vlan(mem, u32::from(cmd), data_desc),
+            VIRTIO_NET_CTRL_GUEST_OFFLOADS => {
+                self.process_guest_offloads(mem, u32::from(cmd), data_desc)
+            }
+            _ => Err(Error::InvalidCtlClass),
         }
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
-            self.queue.add_used(&mem, desc_index, len);
+    }
+
+    pub fn process_cvq(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        while let Some(avail_desc) = self.queue.iter(&mem).next() {
+            let head_index = avail_desc.index;
+            let len = avail_desc.len;
+
+            // Each command is made of the ctrl header, the data of the
+            // command and the status byte acknowledging it.
+            let data_desc = avail_desc.next_descriptor();
+            let status_desc = data_desc.as_ref().and_then(|d| d.next_descriptor());
+            let result = match (&data_desc, &status_desc) {
+                (Some(data_desc), Some(status_desc)) if status_desc.is_write_only() => {
+                    self.process_cmd(mem, &avail_desc, data_desc)
+                }
+                (None, _) => Err(Error::NoCtlData),
+                _ => Err(Error::InvalidDesc),
+            };
+            let status = match result {
+                Ok(()) => VIRTIO_NET_OK,
+                Err(e) => {
+                    guest_warn!("Refusing ctrl command: {:?}", e);
+                    VIRTIO_NET_ERR
+                }
+            };
+
+            // A malformed command is acknowledged as well, the status byte
+            // being the last descriptor of the chain when it's writable.
+            let mut last_desc = avail_desc.clone();
+            while let Some(desc) = last_desc.next_descriptor() {
+                last_desc = desc;
+            }
+            if last_desc.is_write_only() && last_desc.len > 0 {
+                if let Err(e) = mem.write_obj::<u8>(status as u8, last_desc.addr) {
+                    guest_warn!("Failed acknowledging ctrl command: {:?}", e);
+                }
+            }
+
+            // Even a command which can't be acknowledged is given back, so
+            // that the driver isn't left waiting for it.
+            self.queue.add_used(&mem, head_index, len);
             self.queue.update_avail_event(&mem);
         }
 
@@ -177,7 +311,7 @@ impl NetCtrlEpollHandler {
     pub fn run_ctrl(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> std::result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.ctrl_q.queue_evt.as_raw_fd(), CTRL_QUEUE_EVENT)?;
@@ -224,6 +358,19 @@ pub fn build_net_config_space(
     build_net_config_space_with_mq(&mut config, num_queues, &mut avail_features);
 }
 
+/// Ranges of the configuration space the guest can't modify, which is all
+/// of it but the MAC, unless the device advertises it.
+pub fn net_config_read_only_ranges(avail_features: u64) -> Vec<Range<u64>> {
+    // The MAC leads the configuration space, the fields after it being
+    // read-only as per the virtio specification.
+    let mut ranges = vec![MAC_ADDR_LEN as u64..std::mem::size_of::<VirtioNetConfig>() as u64];
+    if avail_features & (1 << VIRTIO_NET_F_MAC) != 0 {
+        ranges.push(0..MAC_ADDR_LEN as u64);
+    }
+
+    ranges
+}
+
 pub fn build_net_config_space_with_mq(
     config: &mut VirtioNetConfig,
     num_queues: usize,
@@ -237,3 +384,147 @@ pub fn build_net_config_space_with_mq(
         *avail_features |= 1 << VIRTIO_NET_F_MQ;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const STATUS_ADDR: u64 = 0x3000;
+
+    #[test]
+    fn test_process_cvq_malformed() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let queue = GuestQ::new(GuestAddress(0x10000), &mem, 16);
+        let vlan_filter = VlanFilter::new();
+        let mut ctrl = CtrlVirtio::new(
+            queue.create_queue(),
+            EventFd::new(0).unwrap(),
+            Some(vlan_filter.clone()),
+            None,
+        );
+
+        // A VLAN command missing its data, then a well formed one.
+        mem.write_obj::<u8>(VIRTIO_NET_CTRL_VLAN as u8, GuestAddress(0x1000))
+            .unwrap();
+        mem.write_obj::<u8>(VIRTIO_NET_CTRL_VLAN_ADD as u8, GuestAddress(0x1001))
+            .unwrap();
+        mem.write_obj::<u16>(100, GuestAddress(0x2000)).unwrap();
+        mem.write_obj::<u8>(0xff, GuestAddress(STATUS_ADDR))
+            .unwrap();
+        mem.write_obj::<u8>(0xff, GuestAddress(STATUS_ADDR + 1))
+            .unwrap();
+        queue.dtable[0].set(0x1000, 2, VIRTQ_DESC_F_NEXT, 1);
+        queue.dtable[1].set(STATUS_ADDR, 1, VIRTQ_DESC_F_WRITE, 0);
+        queue.dtable[2].set(0x1000, 2, VIRTQ_DESC_F_NEXT, 3);
+        queue.dtable[3].set(0x2000, 2, VIRTQ_DESC_F_NEXT, 4);
+        queue.dtable[4].set(STATUS_ADDR + 1, 1, VIRTQ_DESC_F_WRITE, 0);
+        queue.avail.ring[0].set(0);
+        queue.avail.ring[1].set(2);
+        queue.avail.idx.set(2);
+
+        ctrl.process_cvq(&mem).unwrap();
+        assert_eq!(queue.used.idx.get(), 2);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(STATUS_ADDR)).unwrap(),
+            VIRTIO_NET_ERR as u8
+        );
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(STATUS_ADDR + 1)).unwrap(),
+            VIRTIO_NET_OK as u8
+        );
+        assert!(vlan_filter.contains(100));
+    }
+
+    #[test]
+    fn test_process_cvq_guest_offloads() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let queue = GuestQ::new(GuestAddress(0x10000), &mem, 16);
+        let programmed = Arc::new(Mutex::new(None));
+        let acked_features = 1 << VIRTIO_NET_F_GUEST_CSUM | 1 << VIRTIO_NET_F_GUEST_TSO4;
+        let mut ctrl = CtrlVirtio::new(
+            queue.create_queue(),
+            EventFd::new(0).unwrap(),
+            None,
+            Some(GuestOffloads::new(
+                Vec::new(),
+                acked_features,
+                programmed.clone(),
+            )),
+        );
+
+        // Disabling TSO4 is accepted, enabling the UFO the guest didn't
+        // negotiate isn't.
+        let commands = [
+            1u64 << VIRTIO_NET_F_GUEST_CSUM,
+            1 << VIRTIO_NET_F_GUEST_CSUM | 1 << VIRTIO_NET_F_GUEST_UFO,
+        ];
+        for (i, offloads) in commands.iter().enumerate() {
+            let hdr_addr = 0x1000 + 0x100 * i as u64;
+            let data_addr = 0x2000 + 0x100 * i as u64;
+            let status_addr = STATUS_ADDR + i as u64;
+            let desc = 3 * i as u16;
+            mem.write_obj::<u8>(VIRTIO_NET_CTRL_GUEST_OFFLOADS as u8, GuestAddress(hdr_addr))
+                .unwrap();
+            mem.write_obj::<u8>(
+                VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET as u8,
+                GuestAddress(hdr_addr + 1),
+            )
+            .unwrap();
+            mem.write_obj::<u64>(offloads.to_le(), GuestAddress(data_addr))
+                .unwrap();
+            mem.write_obj::<u8>(0xff, GuestAddress(status_addr))
+                .unwrap();
+            queue.dtable[desc as usize].set(hdr_addr, 2, VIRTQ_DESC_F_NEXT, desc + 1);
+            queue.dtable[desc as usize + 1].set(data_addr, 8, VIRTQ_DESC_F_NEXT, desc + 2);
+            queue.dtable[desc as usize + 2].set(status_addr, 1, VIRTQ_DESC_F_WRITE, 0);
+            queue.avail.ring[i].set(desc);
+        }
+        queue.avail.idx.set(commands.len() as u16);
+
+        ctrl.process_cvq(&mem).unwrap();
+        assert_eq!(queue.used.idx.get(), 2);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(STATUS_ADDR)).unwrap(),
+            VIRTIO_NET_OK as u8
+        );
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(STATUS_ADDR + 1)).unwrap(),
+            VIRTIO_NET_ERR as u8
+        );
+        // The refused command leaves the offloads programmed untouched.
+        assert_eq!(
+            *programmed.lock().unwrap(),
+            Some(1 << VIRTIO_NET_F_GUEST_CSUM)
+        );
+    }
+
+    #[test]
+    fn test_process_cvq_guest_offloads_not_negotiated() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let queue = GuestQ::new(GuestAddress(0x10000), &mem, 16);
+        let mut ctrl = CtrlVirtio::new(queue.create_queue(), EventFd::new(0).unwrap(), None, None);
+
+        mem.write_obj::<u8>(VIRTIO_NET_CTRL_GUEST_OFFLOADS as u8, GuestAddress(0x1000))
+            .unwrap();
+        mem.write_obj::<u8>(
+            VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET as u8,
+            GuestAddress(0x1001),
+        )
+        .unwrap();
+        mem.write_obj::<u64>(0, GuestAddress(0x2000)).unwrap();
+        queue.dtable[0].set(0x1000, 2, VIRTQ_DESC_F_NEXT, 1);
+        queue.dtable[1].set(0x2000, 8, VIRTQ_DESC_F_NEXT, 2);
+        queue.dtable[2].set(STATUS_ADDR, 1, VIRTQ_DESC_F_WRITE, 0);
+        queue.avail.ring[0].set(0);
+        queue.avail.idx.set(1);
+
+        ctrl.process_cvq(&mem).unwrap();
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(STATUS_ADDR)).unwrap(),
+            VIRTIO_NET_ERR as u8
+        );
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::HashMap;
+use std::num::Wrapping;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use vmm_sys_util::errno::{Error, Result};
+use vmm_sys_util::timerfd::TimerFd;
+
+/// Creates a non blocking timer, as an expiration can be reported by epoll
+/// after the timer got disarmed or armed again, before it's handled.
+pub(crate) fn nonblocking_timer() -> Result<TimerFd> {
+    let fd = unsafe {
+        libc::timerfd_create(
+            libc::CLOCK_MONOTONIC,
+            libc::TFD_CLOEXEC | libc::TFD_NONBLOCK,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last());
+    }
+    // Safe because the fd was just created and is owned by the TimerFd from
+    // now on.
+    Ok(unsafe { TimerFd::from_raw_fd(fd) })
+}
+
+/// Suppression of the used buffer notifications of a queue: the guest is
+/// notified once `threshold` used entries are pending, or `max_latency_us`
+/// microseconds after the first of them was added to the used ring, whichever
+/// comes first. A threshold of 0 or 1 notifies the guest of every batch of
+/// used entries, as without suppression.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct NotificationSuppression {
+    pub threshold: u16,
+    pub max_latency_us: u64,
+}
+
+impl NotificationSuppression {
+    pub fn enabled(&self) -> bool {
+        self.threshold > 1
+    }
+}
+
+/// Counters shared by the queues of a device, from which the effective
+/// notification rate and the number of entries per notification are derived.
+#[derive(Default, Clone)]
+pub struct NotificationCounters {
+    notifications: Arc<AtomicU64>,
+    timer_notifications: Arc<AtomicU64>,
+    used_entries: Arc<AtomicU64>,
+}
+
+impl NotificationCounters {
+    /// Adds the counters to the ones reported by the device.
+    pub fn report(&self, counters: &mut HashMap<&'static str, Wrapping<u64>>) {
+        counters.insert(
+            "notifications",
+            Wrapping(self.notifications.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "timer_notifications",
+            Wrapping(self.timer_notifications.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "used_entries",
+            Wrapping(self.used_entries.load(Ordering::Acquire)),
+        );
+    }
+}
+
+/// Decides when the guest must be notified about the used entries of a
+/// queue, following its NotificationSuppression settings.
+pub struct NotificationThrottle {
+    config: NotificationSuppression,
+    counters: NotificationCounters,
+    pending: usize,
+    timer: Option<TimerFd>,
+    timer_armed: bool,
+}
+
+impl NotificationThrottle {
+    pub fn new(config: NotificationSuppression, counters: NotificationCounters) -> Result<Self> {
+        let timer = if config.enabled() {
+            Some(nonblocking_timer()?)
+        } else {
+            None
+        };
+
+        Ok(NotificationThrottle {
+            config,
+            counters,
+            pending: 0,
+            timer,
+            timer_armed: false,
+        })
+    }
+
+    /// The timer bounding the latency of the notifications, which the queue
+    /// thread must poll when notifications are suppressed.
+    pub fn timer_fd(&self) -> Option<RawFd> {
+        self.timer.as_ref().map(|timer| timer.as_raw_fd())
+    }
+
+    /// Accounts for entries added to the used ring, returning whether the
+    /// guest must be notified right away.
+    pub fn used(&mut self, count: usize) -> Result<bool> {
+        if count == 0 {
+            return Ok(false);
+        }
+
+        self.counters
+            .used_entries
+            .fetch_add(count as u64, Ordering::AcqRel);
+        self.pending += count;
+
+        let timer = match &mut self.timer {
+            Some(timer) => timer,
+            None => return Ok(true),
+        };
+        if self.pending >= self.config.threshold as usize {
+            return Ok(true);
+        }
+        if !self.timer_armed {
+            timer.reset(Duration::from_micros(self.config.max_latency_us), None)?;
+            self.timer_armed = true;
+        }
+
+        Ok(false)
+    }
+
+    /// Consumes the expiration of the timer, returning whether the guest must
+    /// be notified about the entries still pending.
+    pub fn timer_expired(&mut self) -> Result<bool> {
+        if let Some(timer) = &mut self.timer {
+            match timer.wait() {
+                Ok(_) => {}
+                Err(e) if e.errno() == libc::EAGAIN => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        self.timer_armed = false;
+
+        if self.pending == 0 {
+            return Ok(false);
+        }
+        self.counters
+            .timer_notifications
+            .fetch_add(1, Ordering::AcqRel);
+
+        Ok(true)
+    }
+
+    /// To be called once the guest has been notified.
+    pub fn notified(&mut self) -> Result<()> {
+        self.pending = 0;
+        self.counters.notifications.fetch_add(1, Ordering::AcqRel);
+
+        if self.timer_armed {
+            if let Some(timer) = &mut self.timer {
+                timer.clear()?;
+            }
+            self.timer_armed = false;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Waits for the timer to expire, as the queue thread polling it would.
+    fn wait_timer(throttle: &NotificationThrottle) {
+        let mut pollfd = libc::pollfd {
+            fd: throttle.timer_fd().unwrap(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because the pollfd is valid for the duration of the call.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 5000) };
+        assert_eq!(ret, 1);
+    }
+
+    #[test]
+    fn test_notification_throttle() {
+        // without suppression, every batch of used entries is notified
+        let counters = NotificationCounters::default();
+        let mut throttle =
+            NotificationThrottle::new(NotificationSuppression::default(), counters.clone())
+                .unwrap();
+        assert!(throttle.timer_fd().is_none());
+        assert!(!throttle.used(0).unwrap());
+        assert!(throttle.used(1).unwrap());
+        throttle.notified().unwrap();
+
+        // with suppression, the guest is notified once the threshold is
+        // reached...
+        let config = NotificationSuppression {
+            threshold: 4,
+            max_latency_us: 1000,
+        };
+        let mut throttle = NotificationThrottle::new(config, counters.clone()).unwrap();
+        assert!(throttle.timer_fd().is_some());
+        assert!(!throttle.used(2).unwrap());
+        assert!(throttle.used(2).unwrap());
+        throttle.notified().unwrap();
+
+        // ...or when the timer expires, a disarmed timer being ignored
+        assert!(!throttle.timer_expired().unwrap());
+        assert!(!throttle.used(1).unwrap());
+        wait_timer(&throttle);
+        assert!(throttle.timer_expired().unwrap());
+        throttle.notified().unwrap();
+        assert!(!throttle.timer_expired().unwrap());
+
+        let mut reported = HashMap::new();
+        counters.report(&mut reported);
+        assert_eq!(reported["notifications"], Wrapping(3));
+        assert_eq!(reported["timer_notifications"], Wrapping(1));
+        assert_eq!(reported["used_entries"], Wrapping(6));
+    }
+}
@@ -13,7 +13,7 @@ use super::{
     EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::{VirtioInterrupt, VirtioInterruptType};
+use crate::{PauseBarrier, VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
 use seccomp::{SeccompAction, SeccompFilter};
 use std::fmt::{self, Display};
@@ -23,15 +23,16 @@ use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Barrier};
+use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap, MmapRegion,
 };
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    Transportable, VersionedState,
 };
 use vmm_sys_util::eventfd::EventFd;
 
@@ -223,7 +224,7 @@ impl PmemEpollHandler {
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
@@ -277,6 +278,10 @@ pub struct PmemState {
     config: VirtioPmemConfig,
 }
 
+impl VersionedState for PmemState {
+    const VERSION: u16 = 1;
+}
+
 impl Pmem {
     pub fn new(
         id: String,
@@ -302,7 +307,7 @@ impl Pmem {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::TYPE_PMEM as u32,
                 queue_sizes: QUEUE_SIZES.to_vec(),
-                paused_sync: Some(Arc::new(Barrier::new(2))),
+                paused_sync: Some(Arc::new(PauseBarrier::new(2))),
                 avail_features,
                 min_queues: 1,
                 ..Default::default()
@@ -447,6 +452,10 @@ impl Pausable for Pmem {
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         self.common.resume()
     }
@@ -458,29 +467,18 @@ impl Snapshottable for Pmem {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut pmem_snapshot = Snapshot::new(self.id.as_str());
-        pmem_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id),
-            snapshot,
-        });
+        pmem_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
 
         Ok(pmem_snapshot)
     }
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(pmem_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
-            let pmem_state = match serde_json::from_slice(&pmem_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize PMEM {}",
-                        error
-                    )))
-                }
-            };
+            let pmem_state = pmem_section.to_versioned_state::<PmemState>()?;
 
             self.set_state(&pmem_state);
             return Ok(());
@@ -9,20 +9,22 @@ use super::{
     VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::{VirtioInterrupt, VirtioInterruptType};
+use crate::{PauseBarrier, VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
 use seccomp::{SeccompAction, SeccompFilter};
-use std::fs::File;
-use std::io;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Barrier};
+use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    Transportable, VersionedState,
 };
 use vmm_sys_util::eventfd::EventFd;
 
@@ -32,10 +34,93 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 
+// The virtio specification doesn't define any feature for virtio-rng. This
+// device-specific bit advertises the quality of the entropy, as a byte
+// leading the configuration space, which guests unaware of it ignore. It's
+// a non-standard extension, which a later revision of the specification
+// could give another meaning to, hence only advertised when asked for.
+const VIRTIO_RNG_F_QUALITY: u64 = 0;
+
+/// Quality of the entropy given to the guest, only meant as a hint for it to
+/// decide whether to trust the device, for instance to generate its keys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum RngQuality {
+    /// The source is a regular file or a named pipe, of unknown quality.
+    Unknown = 0,
+    /// The source is the hardware random number generator of the host.
+    Hardware = 1,
+    /// The source is the random number generator of the host kernel.
+    Kernel = 2,
+    /// The entropy is recorded to or replayed from a seed file, and is
+    /// predictable.
+    Deterministic = 3,
+}
+
+impl RngQuality {
+    fn new(path: &str, seeded: bool) -> Self {
+        if seeded {
+            return RngQuality::Deterministic;
+        }
+
+        let path = std::fs::canonicalize(path).ok();
+        match path.as_ref().and_then(|path| path.to_str()) {
+            Some("/dev/hwrng") => RngQuality::Hardware,
+            Some("/dev/random") | Some("/dev/urandom") => RngQuality::Kernel,
+            _ => RngQuality::Unknown,
+        }
+    }
+}
+
+/// How the entropy given to the guest relates to the seed file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RngSeedMode {
+    /// The entropy is read from the source and appended to the seed file.
+    Consume,
+    /// The entropy is read from the seed file, from its start. Once it's
+    /// exhausted, the entropy is read from the source and appended to the
+    /// seed file, for the next runs to replay it too.
+    Replay,
+}
+
+struct RngSeed {
+    file: File,
+    mode: RngSeedMode,
+}
+
+// The entropy source, along with the seed file the entropy is recorded to or
+// replayed from.
+struct EntropySource {
+    random_file: File,
+    seed: Option<RngSeed>,
+}
+
+impl Read for EntropySource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let seed = match self.seed.as_mut() {
+            Some(seed) => seed,
+            None => return self.random_file.read(buf),
+        };
+
+        if seed.mode == RngSeedMode::Replay {
+            let count = seed.file.read(buf)?;
+            if count > 0 {
+                return Ok(count);
+            }
+        }
+
+        // The seed file is at its end, whatever is read from the source is
+        // appended to it.
+        let count = self.random_file.read(buf)?;
+        seed.file.write_all(&buf[..count])?;
+        Ok(count)
+    }
+}
+
 struct RngEpollHandler {
     queues: Vec<Queue>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
-    random_file: File,
+    source: EntropySource,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     queue_evt: EventFd,
     kill_evt: EventFd,
@@ -55,15 +140,12 @@ impl RngEpollHandler {
             // Drivers can only read from the random device.
             if avail_desc.is_write_only() {
                 // Fill the read with data from the random device on the host.
-                if mem
-                    .read_from(
-                        avail_desc.addr,
-                        &mut self.random_file,
-                        avail_desc.len as usize,
-                    )
-                    .is_ok()
-                {
-                    len = avail_desc.len;
+                // This happens from the virtio-rng worker thread, meaning a
+                // blocking source such as /dev/random can't stall the vCPUs
+                // or the VMM thread.
+                match mem.read_from(avail_desc.addr, &mut self.source, avail_desc.len as usize) {
+                    Ok(count) => len = count as u32,
+                    Err(e) => error!("Failed reading from the entropy source: {:?}", e),
                 }
             }
 
@@ -89,7 +171,7 @@ impl RngEpollHandler {
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
@@ -128,6 +210,8 @@ pub struct Rng {
     common: VirtioCommon,
     id: String,
     random_file: Option<File>,
+    seed: Option<RngSeed>,
+    quality: RngQuality,
     seccomp_action: SeccompAction,
 }
 
@@ -135,48 +219,141 @@ pub struct Rng {
 pub struct RngState {
     pub avail_features: u64,
     pub acked_features: u64,
+    /// Position in the seed file, for a restored guest to go on with the
+    /// same entropy.
+    #[serde(default)]
+    pub seed_offset: Option<u64>,
+}
+
+impl VersionedState for RngState {
+    const VERSION: u16 = 1;
 }
 
 impl Rng {
-    /// Create a new virtio rng device that gets random data from /dev/urandom.
+    /// Create a new virtio rng device that gets random data from the
+    /// entropy source found at `path`. This can be /dev/urandom, the
+    /// blocking /dev/random, or any readable file or named pipe.
+    ///
+    /// With a `seed` file, the entropy becomes reproducible from one run to
+    /// the other, which is insecure and only meant for tests.
+    ///
+    /// With `quality`, the guest is told where the entropy comes from.
     pub fn new(
         id: String,
         path: &str,
         iommu: bool,
+        seed: Option<(&str, RngSeedMode)>,
+        quality: bool,
         seccomp_action: SeccompAction,
     ) -> io::Result<Rng> {
-        let random_file = File::open(path)?;
+        let random_file = Self::open_source(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("cannot open entropy source {}: {}", path, e),
+            )
+        })?;
+        let seed = match seed {
+            Some((seed_path, mode)) => {
+                let file = Self::open_seed(seed_path, mode).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("cannot open entropy seed {}: {}", seed_path, e),
+                    )
+                })?;
+                warn!(
+                    "Entropy of {} seeded from {}, it is predictable",
+                    id, seed_path
+                );
+                Some(RngSeed { file, mode })
+            }
+            None => None,
+        };
         let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
+        let rng_quality = RngQuality::new(path, seed.is_some());
+        if quality {
+            avail_features |= 1u64 << VIRTIO_RNG_F_QUALITY;
+            info!("Entropy quality of {} advertised as {:?}", id, rng_quality);
+        }
+
         Ok(Rng {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::TYPE_RNG as u32,
                 queue_sizes: QUEUE_SIZES.to_vec(),
-                paused_sync: Some(Arc::new(Barrier::new(2))),
+                paused_sync: Some(Arc::new(PauseBarrier::new(2))),
                 avail_features,
                 min_queues: 1,
                 ..Default::default()
             },
             id,
             random_file: Some(random_file),
+            seed,
+            quality: rng_quality,
             seccomp_action,
         })
     }
 
-    fn state(&self) -> RngState {
-        RngState {
+    fn open_seed(path: &str, mode: RngSeedMode) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        match mode {
+            RngSeedMode::Consume => options.append(true),
+            RngSeedMode::Replay => options.read(true).write(true),
+        };
+
+        // Anyone able to read the seed knows the entropy of the guest.
+        options.create(true).mode(0o600).open(path)
+    }
+
+    fn open_source(path: &str) -> io::Result<File> {
+        // Opening a named pipe would block until a writer shows up, that's
+        // why the source is opened in non-blocking mode first.
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+
+        if file.metadata()?.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+
+        // Reads are performed from the worker thread, they can safely block.
+        // Safe because the file descriptor is valid as it is owned by `file`.
+        let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(file)
+    }
+
+    fn state(&mut self) -> io::Result<RngState> {
+        let seed_offset = match self.seed.as_mut() {
+            Some(seed) => Some(seed.file.seek(SeekFrom::Current(0))?),
+            None => None,
+        };
+
+        Ok(RngState {
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
-        }
+            seed_offset,
+        })
     }
 
-    fn set_state(&mut self, state: &RngState) {
+    fn set_state(&mut self, state: &RngState) -> io::Result<()> {
         self.common.avail_features = state.avail_features;
         self.common.acked_features = state.acked_features;
+
+        if let (Some(seed), Some(offset)) = (self.seed.as_mut(), state.seed_offset) {
+            if seed.mode == RngSeedMode::Replay {
+                seed.file.seek(SeekFrom::Start(offset))?;
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -206,6 +383,15 @@ impl VirtioDevice for Rng {
         self.common.ack_features(value)
     }
 
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        if !self.common.feature_acked(VIRTIO_RNG_F_QUALITY) {
+            guest_warn!("No readable configuration fields for virtio-rng");
+            return;
+        }
+
+        self.read_config_from_slice(&[self.quality as u8], offset, data);
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
@@ -240,10 +426,22 @@ impl VirtioDevice for Rng {
                 error!("failed cloning rng source: {}", e);
                 ActivateError::BadActivate
             })?;
+            // The clone shares its offset in the seed file, the entropy goes
+            // on from there when the device is activated again.
+            let seed = match self.seed.as_ref() {
+                Some(seed) => Some(RngSeed {
+                    file: seed.file.try_clone().map_err(|e| {
+                        error!("failed cloning rng seed: {}", e);
+                        ActivateError::BadActivate
+                    })?,
+                    mode: seed.mode,
+                }),
+                None => None,
+            };
             let mut handler = RngEpollHandler {
                 queues,
                 mem,
-                random_file,
+                source: EntropySource { random_file, seed },
                 interrupt_cb,
                 queue_evt: queue_evts.remove(0),
                 kill_evt,
@@ -289,6 +487,10 @@ impl Pausable for Rng {
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         self.common.resume()
     }
@@ -300,32 +502,25 @@ impl Snapshottable for Rng {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
+        let state = self
+            .state()
+            .map_err(|e| MigratableError::Snapshot(e.into()))?;
         let mut rng_snapshot = Snapshot::new(self.id.as_str());
-        rng_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id),
-            snapshot,
-        });
+        rng_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &state,
+        )?);
 
         Ok(rng_snapshot)
     }
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(rng_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
-            let rng_state = match serde_json::from_slice(&rng_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize RNG {}",
-                        error
-                    )))
-                }
-            };
+            let rng_state = rng_section.to_versioned_state::<RngState>()?;
 
-            self.set_state(&rng_state);
-            return Ok(());
+            return self
+                .set_state(&rng_state)
+                .map_err(|e| MigratableError::Restore(e.into()));
         }
 
         Err(MigratableError::Restore(anyhow!(
@@ -336,3 +531,222 @@ impl Snapshottable for Rng {
 
 impl Transportable for Rng {}
 impl Migratable for Rng {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vsock::tests::NoopVirtioInterrupt;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryMmap};
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_rng_invalid_source() {
+        let err = Rng::new(
+            "_rng".to_owned(),
+            "/nonexistent/entropy",
+            false,
+            None,
+            false,
+            SeccompAction::Trap,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("/nonexistent/entropy"));
+
+        assert!(Rng::new(
+            "_rng".to_owned(),
+            "/tmp",
+            false,
+            None,
+            false,
+            SeccompAction::Trap
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_rng_file_source() {
+        let pattern: Vec<u8> = (0..32).collect();
+        let source = TempFile::new().unwrap();
+        source.as_file().write_all(&pattern).unwrap();
+
+        let mut rng = Rng::new(
+            "_rng".to_owned(),
+            source.as_path().to_str().unwrap(),
+            false,
+            None,
+            false,
+            SeccompAction::Trap,
+        )
+        .unwrap();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        // Two descriptors reading 16 bytes each, followed by one reaching
+        // the end of the source.
+        guest_queue.dtable[0].set(0x1000, 16, VIRTQ_DESC_F_WRITE, 0);
+        guest_queue.dtable[1].set(0x2000, 16, VIRTQ_DESC_F_WRITE, 0);
+        guest_queue.dtable[2].set(0x3000, 16, VIRTQ_DESC_F_WRITE, 0);
+        for i in 0..3 {
+            guest_queue.avail.ring[i].set(i as u16);
+        }
+        guest_queue.avail.idx.set(3);
+
+        let mut handler = RngEpollHandler {
+            queues: vec![guest_queue.create_queue()],
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            source: EntropySource {
+                random_file: rng.random_file.take().unwrap(),
+                seed: None,
+            },
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            queue_evt: EventFd::new(0).unwrap(),
+            kill_evt: EventFd::new(0).unwrap(),
+            pause_evt: EventFd::new(0).unwrap(),
+        };
+        assert!(handler.process_queue());
+
+        assert_eq!(guest_queue.used.idx.get(), 3);
+        assert_eq!(guest_queue.used.ring[0].get().len, 16);
+        assert_eq!(guest_queue.used.ring[1].get().len, 16);
+        assert_eq!(guest_queue.used.ring[2].get().len, 0);
+
+        let mut data = [0u8; 16];
+        mem.read_slice(&mut data, GuestAddress(0x1000)).unwrap();
+        assert_eq!(data, pattern[..16]);
+        mem.read_slice(&mut data, GuestAddress(0x2000)).unwrap();
+        assert_eq!(data, pattern[16..]);
+    }
+
+    // Reads 16 bytes of entropy from the device, through a single descriptor.
+    fn read_entropy(source: &str, seed: Option<(&str, RngSeedMode)>) -> [u8; 16] {
+        let mut rng = Rng::new(
+            "_rng".to_owned(),
+            source,
+            false,
+            seed,
+            false,
+            SeccompAction::Trap,
+        )
+        .unwrap();
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        guest_queue.dtable[0].set(0x1000, 16, VIRTQ_DESC_F_WRITE, 0);
+        guest_queue.avail.ring[0].set(0);
+        guest_queue.avail.idx.set(1);
+
+        let mut handler = RngEpollHandler {
+            queues: vec![guest_queue.create_queue()],
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            source: EntropySource {
+                random_file: rng.random_file.take().unwrap(),
+                seed: rng.seed.take(),
+            },
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            queue_evt: EventFd::new(0).unwrap(),
+            kill_evt: EventFd::new(0).unwrap(),
+            pause_evt: EventFd::new(0).unwrap(),
+        };
+        assert!(handler.process_queue());
+        assert_eq!(guest_queue.used.ring[0].get().len, 16);
+
+        let mut data = [0u8; 16];
+        mem.read_slice(&mut data, GuestAddress(0x1000)).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_rng_seed() {
+        let pattern: Vec<u8> = (0..64).collect();
+        let source = TempFile::new().unwrap();
+        source.as_file().write_all(&pattern).unwrap();
+        let source = source.as_path().to_str().unwrap();
+        let seed = TempFile::new().unwrap();
+        let seed_path = seed.as_path().to_str().unwrap();
+
+        // The entropy read from the source is recorded to the seed file.
+        let data = read_entropy(source, Some((seed_path, RngSeedMode::Consume)));
+        assert_eq!(data, pattern[..16]);
+        assert_eq!(std::fs::read(seed_path).unwrap(), pattern[..16]);
+
+        // The recorded entropy is replayed, whatever the source gives.
+        let other_source = TempFile::new().unwrap();
+        other_source.as_file().write_all(&[0xff; 64]).unwrap();
+        let other_source = other_source.as_path().to_str().unwrap();
+        let data = read_entropy(other_source, Some((seed_path, RngSeedMode::Replay)));
+        assert_eq!(data, pattern[..16]);
+
+        // Once the seed is exhausted, it's extended from the source.
+        std::fs::write(seed_path, &pattern[..8]).unwrap();
+        let data = read_entropy(other_source, Some((seed_path, RngSeedMode::Replay)));
+        assert_eq!(data[..8], pattern[..8]);
+        assert_eq!(data[8..], [0xff; 8]);
+        assert_eq!(std::fs::read(seed_path).unwrap(), data);
+
+        // A new seed file is only readable by its owner.
+        let dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let seed_path = dir.as_path().join("seed");
+        read_entropy(
+            source,
+            Some((seed_path.to_str().unwrap(), RngSeedMode::Consume)),
+        );
+        let mode = std::fs::metadata(&seed_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // Reads the entropy quality from the configuration space of the device.
+    fn read_quality(source: &str, seed: Option<(&str, RngSeedMode)>) -> u8 {
+        let mut rng = Rng::new(
+            "_rng".to_owned(),
+            source,
+            false,
+            seed,
+            true,
+            SeccompAction::Trap,
+        )
+        .unwrap();
+        assert_ne!(rng.features() & (1u64 << VIRTIO_RNG_F_QUALITY), 0);
+
+        // The quality is only exposed to the guests which acked it.
+        let mut data = [0xffu8];
+        rng.read_config(0, &mut data);
+        assert_eq!(data[0], 0xff);
+
+        rng.ack_features(1u64 << VIRTIO_RNG_F_QUALITY);
+        rng.read_config(0, &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_rng_quality() {
+        let source = TempFile::new().unwrap();
+        let source = source.as_path().to_str().unwrap();
+        let seed = TempFile::new().unwrap();
+        let seed_path = seed.as_path().to_str().unwrap();
+
+        assert_eq!(read_quality("/dev/urandom", None), RngQuality::Kernel as u8);
+        assert_eq!(read_quality(source, None), RngQuality::Unknown as u8);
+        assert_eq!(
+            read_quality("/dev/urandom", Some((seed_path, RngSeedMode::Replay))),
+            RngQuality::Deterministic as u8
+        );
+
+        // Unless asked for, the quality isn't advertised.
+        let rng = Rng::new(
+            "_rng".to_owned(),
+            source,
+            false,
+            None,
+            false,
+            SeccompAction::Trap,
+        )
+        .unwrap();
+        assert_eq!(rng.features() & (1u64 << VIRTIO_RNG_F_QUALITY), 0);
+    }
+}
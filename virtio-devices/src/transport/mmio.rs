@@ -0,0 +1,757 @@
+// Copyright 2017 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+//
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use crate::{
+    ActivateResult, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE,
+    DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT,
+};
+use anyhow::anyhow;
+use byteorder::{ByteOrder, LittleEndian};
+use libc::EFD_NONBLOCK;
+use std::num::Wrapping;
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use vm_device::interrupt::InterruptSourceGroup;
+use vm_device::BusDevice;
+use vm_memory::{GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable, VersionedState,
+};
+use vm_virtio::queue;
+use vmm_sys_util::{errno::Result, eventfd::EventFd};
+
+/// Size of the register window of a virtio-mmio device.
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+const MMIO_MAGIC_VALUE: u32 = 0x7472_6976; // "virt"
+const MMIO_VERSION: u32 = 2; // Modern (non-legacy) virtio-mmio.
+const VENDOR_ID: u32 = 0;
+
+// Register offsets, as laid out by the virtio specification.
+const MAGIC_VALUE: u64 = 0x00;
+const VERSION: u64 = 0x04;
+const DEVICE_ID: u64 = 0x08;
+const VENDOR: u64 = 0x0c;
+const DEVICE_FEATURES: u64 = 0x10;
+const DEVICE_FEATURES_SEL: u64 = 0x14;
+const DRIVER_FEATURES: u64 = 0x20;
+const DRIVER_FEATURES_SEL: u64 = 0x24;
+const QUEUE_SEL: u64 = 0x30;
+const QUEUE_NUM_MAX: u64 = 0x34;
+const QUEUE_NUM: u64 = 0x38;
+const QUEUE_READY: u64 = 0x44;
+const QUEUE_NOTIFY: u64 = 0x50;
+const INTERRUPT_STATUS: u64 = 0x60;
+const INTERRUPT_ACK: u64 = 0x64;
+const STATUS: u64 = 0x70;
+const QUEUE_DESC_LOW: u64 = 0x80;
+const QUEUE_DESC_HIGH: u64 = 0x84;
+const QUEUE_AVAIL_LOW: u64 = 0x90;
+const QUEUE_AVAIL_HIGH: u64 = 0x94;
+const QUEUE_USED_LOW: u64 = 0xa0;
+const QUEUE_USED_HIGH: u64 = 0xa4;
+const CONFIG_GENERATION: u64 = 0xfc;
+const DEVICE_CONFIG: u64 = 0x100;
+
+const INTERRUPT_STATUS_USED_RING: usize = 0x1;
+const INTERRUPT_STATUS_CONFIG_CHANGED: usize = 0x2;
+
+#[derive(Debug)]
+enum Error {
+    /// Failed to retrieve queue ring's index.
+    QueueRingIndex(queue::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+struct VirtioMmioDeviceState {
+    device_activated: bool,
+    queues: Vec<Queue>,
+    interrupt_status: usize,
+    driver_status: u32,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u32,
+}
+
+impl VersionedState for VirtioMmioDeviceState {
+    const VERSION: u16 = 1;
+}
+
+/// Implements the virtio-mmio transport, exposing a virtio device through a
+/// page of registers and a legacy interrupt line, for the guests relying on
+/// the device tree or the ACPI tables to discover their devices instead of
+/// probing a PCI bus.
+pub struct VirtioMmioDevice {
+    id: String,
+
+    // Virtio device reference and status
+    device: Arc<Mutex<dyn VirtioDevice>>,
+    device_activated: Arc<AtomicBool>,
+
+    // Transport registers
+    driver_status: u32,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u32,
+
+    // Legacy interrupt
+    interrupt_status: Arc<AtomicUsize>,
+    virtio_interrupt: Option<Arc<dyn VirtioInterrupt>>,
+
+    // virtio queues
+    queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
+
+    // Guest memory
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+
+    // EventFd to signal on to request activation
+    activate_evt: EventFd,
+
+    // Barrier that is used to wait on for activation
+    activate_barrier: Arc<Barrier>,
+
+    // Whether the accesses to the registers are logged
+    #[cfg(feature = "access_log")]
+    access_log: bool,
+}
+
+impl VirtioMmioDevice {
+    /// Constructs a new MMIO transport for the given virtio device, raising
+    /// its interrupts through the given legacy interrupt group.
+    pub fn new(
+        id: String,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        device: Arc<Mutex<dyn VirtioDevice>>,
+        interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+        activate_evt: EventFd,
+    ) -> Result<Self> {
+        let locked_device = device.lock().unwrap();
+        let mut queue_evts = Vec::new();
+        for _ in locked_device.queue_max_sizes().iter() {
+            queue_evts.push(EventFd::new(EFD_NONBLOCK)?)
+        }
+        let queues = locked_device
+            .queue_max_sizes()
+            .iter()
+            .map(|&s| Queue::new(s))
+            .collect();
+        drop(locked_device);
+
+        let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let virtio_interrupt: Arc<dyn VirtioInterrupt> = Arc::new(VirtioInterruptMmio {
+            interrupt_status: interrupt_status.clone(),
+            interrupt_source_group,
+        });
+
+        Ok(VirtioMmioDevice {
+            id,
+            device,
+            device_activated: Arc::new(AtomicBool::new(false)),
+            driver_status: DEVICE_INIT,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            queue_select: 0,
+            interrupt_status,
+            virtio_interrupt: Some(virtio_interrupt),
+            queues,
+            queue_evts,
+            memory,
+            activate_evt,
+            activate_barrier: Arc::new(Barrier::new(2)),
+            #[cfg(feature = "access_log")]
+            access_log: false,
+        })
+    }
+
+    fn state(&self) -> VirtioMmioDeviceState {
+        VirtioMmioDeviceState {
+            device_activated: self.device_activated.load(Ordering::Acquire),
+            queues: self.queues.clone(),
+            interrupt_status: self.interrupt_status.load(Ordering::Acquire),
+            driver_status: self.driver_status,
+            device_feature_select: self.device_feature_select,
+            driver_feature_select: self.driver_feature_select,
+            queue_select: self.queue_select,
+        }
+    }
+
+    fn set_state(&mut self, state: &VirtioMmioDeviceState) -> std::result::Result<(), Error> {
+        self.device_activated
+            .store(state.device_activated, Ordering::Release);
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::Release);
+        self.driver_status = state.driver_status;
+        self.device_feature_select = state.device_feature_select;
+        self.driver_feature_select = state.driver_feature_select;
+        self.queue_select = state.queue_select;
+
+        // Update virtqueues indexes for both available and used rings.
+        let mem = self.memory.memory();
+        for (i, queue) in self.queues.iter_mut().enumerate() {
+            queue.max_size = state.queues[i].max_size;
+            queue.size = state.queues[i].size;
+            queue.ready = state.queues[i].ready;
+            queue.desc_table = state.queues[i].desc_table;
+            queue.avail_ring = state.queues[i].avail_ring;
+            queue.used_ring = state.queues[i].used_ring;
+            queue.next_avail = Wrapping(
+                queue
+                    .used_index_from_memory(&mem)
+                    .map_err(Error::QueueRingIndex)?,
+            );
+            queue.next_used = Wrapping(
+                queue
+                    .used_index_from_memory(&mem)
+                    .map_err(Error::QueueRingIndex)?,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the queue events along with the address and the value of
+    /// the QueueNotify register write each of them must be triggered on.
+    pub fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64, u32)> {
+        self.queue_evts
+            .iter()
+            .enumerate()
+            .map(|(i, event)| (event, base_addr + QUEUE_NOTIFY, i as u32))
+            .collect()
+    }
+
+    pub fn virtio_device(&self) -> Arc<Mutex<dyn VirtioDevice>> {
+        self.device.clone()
+    }
+
+    #[cfg(feature = "access_log")]
+    pub fn set_access_log(&mut self, access_log: bool) {
+        self.access_log = access_log;
+    }
+
+    fn is_driver_ready(&self) -> bool {
+        let ready_bits = DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK;
+        self.driver_status == ready_bits && self.driver_status & DEVICE_FAILED == 0
+    }
+
+    fn activate(&mut self) -> ActivateResult {
+        if let Some(virtio_interrupt) = self.virtio_interrupt.take() {
+            let mem = self.memory.clone();
+            let mut device = self.device.lock().unwrap();
+            let mut queue_evts = Vec::new();
+            let mut queues = self.queues.clone();
+            queues.retain(|q| q.ready);
+            for (i, queue) in queues.iter().enumerate() {
+                queue_evts.push(self.queue_evts[i].try_clone().unwrap());
+                if !queue.is_valid(&mem.memory()) {
+                    error!("Queue {} is not valid", i);
+                }
+            }
+            return device.activate(mem, virtio_interrupt, queues, queue_evts);
+        }
+        Ok(())
+    }
+
+    pub fn maybe_activate(&mut self) -> ActivateResult {
+        if self.needs_activation() {
+            let result = self.activate();
+            if result.is_ok() {
+                self.device_activated.store(true, Ordering::SeqCst);
+            }
+            // The vCPU which set DRIVER_OK waits for the activation to be
+            // over, whether it succeeded or not.
+            info!("{}: Waiting for barrier", self.id);
+            self.activate_barrier.wait();
+            info!("{}: Barrier released", self.id);
+            result
+        } else {
+            info!("{}: Device does not need activation", self.id);
+            Ok(())
+        }
+    }
+
+    fn needs_activation(&self) -> bool {
+        !self.device_activated.load(Ordering::SeqCst) && self.is_driver_ready()
+    }
+
+    fn reset(&mut self) {
+        if self.device_activated.load(Ordering::SeqCst) {
+            let mut device = self.device.lock().unwrap();
+            if let Some(virtio_interrupt) = device.reset() {
+                // Upon reset the device returns its interrupt EventFD
+                self.virtio_interrupt = Some(virtio_interrupt);
+                self.device_activated.store(false, Ordering::SeqCst);
+            } else {
+                error!("Attempt to reset device when not implemented in underlying device");
+                self.driver_status = DEVICE_FAILED;
+                return;
+            }
+        }
+
+        self.queues.iter_mut().for_each(Queue::reset);
+        self.queue_select = 0;
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.interrupt_status.store(0, Ordering::Release);
+    }
+
+    fn with_queue<U, F>(&self, f: F) -> Option<U>
+    where
+        F: FnOnce(&Queue) -> U,
+    {
+        self.queues.get(self.queue_select as usize).map(f)
+    }
+
+    fn with_queue_mut<F: FnOnce(&mut Queue)>(&mut self, f: F) {
+        // The queue can't be reconfigured once the driver has enabled it.
+        match self.queues.get_mut(self.queue_select as usize) {
+            Some(queue) if !queue.ready => f(queue),
+            Some(_) => guest_warn!(
+                "{}: queue {} is configured while enabled",
+                self.id,
+                self.queue_select
+            ),
+            None => guest_warn!("{}: invalid queue {}", self.id, self.queue_select),
+        }
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            MAGIC_VALUE => MMIO_MAGIC_VALUE,
+            VERSION => MMIO_VERSION,
+            DEVICE_ID => self.device.lock().unwrap().device_type(),
+            VENDOR => VENDOR_ID,
+            DEVICE_FEATURES => {
+                // Only 64 bits of features (2 pages) are defined for now.
+                if self.device_feature_select < 2 {
+                    (self.device.lock().unwrap().features() >> (self.device_feature_select * 32))
+                        as u32
+                } else {
+                    0
+                }
+            }
+            QUEUE_NUM_MAX => self.with_queue(|q| u32::from(q.max_size)).unwrap_or(0),
+            QUEUE_READY => self.with_queue(|q| q.ready as u32).unwrap_or(0),
+            INTERRUPT_STATUS => self.interrupt_status.load(Ordering::Acquire) as u32,
+            STATUS => self.driver_status,
+            CONFIG_GENERATION => 0,
+            _ => {
+                guest_warn!("{}: invalid virtio-mmio read: 0x{:x}", self.id, offset);
+                0
+            }
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        fn hi(v: &mut GuestAddress, x: u32) {
+            *v = (*v & 0xffff_ffff) | ((u64::from(x)) << 32)
+        }
+
+        fn lo(v: &mut GuestAddress, x: u32) {
+            *v = (*v & !0xffff_ffff) | (u64::from(x))
+        }
+
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_feature_select = value,
+            DRIVER_FEATURES => {
+                if self.driver_feature_select < 2 {
+                    self.device
+                        .lock()
+                        .unwrap()
+                        .ack_features(u64::from(value) << (self.driver_feature_select * 32));
+                } else {
+                    guest_warn!(
+                        "{}: invalid ack_features (page {}, value 0x{:x})",
+                        self.id,
+                        self.driver_feature_select,
+                        value
+                    );
+                }
+            }
+            DRIVER_FEATURES_SEL => self.driver_feature_select = value,
+            QUEUE_SEL => self.queue_select = value,
+            QUEUE_NUM => self.with_queue_mut(|q| q.size = value as u16),
+            QUEUE_READY => {
+                if let Some(queue) = self.queues.get_mut(self.queue_select as usize) {
+                    queue.enable(value == 1);
+                }
+            }
+            QUEUE_NOTIFY => {
+                // Handled with ioeventfds, unless the hypervisor couldn't
+                // match on the value written.
+                if let Some(evt) = self.queue_evts.get(value as usize) {
+                    evt.write(1).ok();
+                }
+            }
+            INTERRUPT_ACK => {
+                self.interrupt_status
+                    .fetch_and(!(value as usize), Ordering::AcqRel);
+            }
+            STATUS => {
+                self.driver_status = value;
+                if value == DEVICE_INIT {
+                    self.reset();
+                }
+            }
+            QUEUE_DESC_LOW => self.with_queue_mut(|q| lo(&mut q.desc_table, value)),
+            QUEUE_DESC_HIGH => self.with_queue_mut(|q| hi(&mut q.desc_table, value)),
+            QUEUE_AVAIL_LOW => self.with_queue_mut(|q| lo(&mut q.avail_ring, value)),
+            QUEUE_AVAIL_HIGH => self.with_queue_mut(|q| hi(&mut q.avail_ring, value)),
+            QUEUE_USED_LOW => self.with_queue_mut(|q| lo(&mut q.used_ring, value)),
+            QUEUE_USED_HIGH => self.with_queue_mut(|q| hi(&mut q.used_ring, value)),
+            _ => {
+                guest_warn!("{}: invalid virtio-mmio write: 0x{:x}", self.id, offset);
+            }
+        }
+    }
+}
+
+impl BusDevice for VirtioMmioDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if offset >= DEVICE_CONFIG {
+            self.device
+                .lock()
+                .unwrap()
+                .read_config(offset - DEVICE_CONFIG, data);
+        } else if data.len() != 4 || offset % 4 != 0 {
+            // The driver is only allowed aligned 32-bit accesses to the
+            // registers.
+            guest_warn!(
+                "{}: invalid virtio-mmio read: 0x{:x}, len {}",
+                self.id,
+                offset,
+                data.len()
+            );
+        } else {
+            LittleEndian::write_u32(data, self.read_register(offset));
+        }
+
+        #[cfg(feature = "access_log")]
+        if self.access_log {
+            super::log_access(&self.id, "MMIO read", offset, data);
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        #[cfg(feature = "access_log")]
+        if self.access_log {
+            super::log_access(&self.id, "MMIO write", offset, data);
+        }
+
+        if offset >= DEVICE_CONFIG {
+            self.device
+                .lock()
+                .unwrap()
+                .write_config(offset - DEVICE_CONFIG, data);
+            return None;
+        }
+
+        if data.len() != 4 || offset % 4 != 0 {
+            guest_warn!(
+                "{}: invalid virtio-mmio write: 0x{:x}, len {}",
+                self.id,
+                offset,
+                data.len()
+            );
+            return None;
+        }
+
+        self.write_register(offset, LittleEndian::read_u32(data));
+
+        // Try and activate the device if the driver status has changed
+        if self.needs_activation() {
+            info!(
+                "{}: Needs activation; writing to activate event fd",
+                self.id
+            );
+            self.activate_evt.write(1).ok();
+            info!("{}: Needs activation; returning barrier", self.id);
+            return Some(self.activate_barrier.clone());
+        }
+
+        None
+    }
+}
+
+/// Raises the interrupts of a virtio-mmio device on its legacy line, the
+/// InterruptStatus register telling the driver what they are for.
+pub struct VirtioInterruptMmio {
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+}
+
+impl VirtioInterrupt for VirtioInterruptMmio {
+    fn trigger(
+        &self,
+        int_type: &VirtioInterruptType,
+        _queue: Option<&Queue>,
+    ) -> std::result::Result<(), std::io::Error> {
+        let status = match int_type {
+            VirtioInterruptType::Config => INTERRUPT_STATUS_CONFIG_CHANGED,
+            VirtioInterruptType::Queue => INTERRUPT_STATUS_USED_RING,
+        };
+        self.interrupt_status.fetch_or(status, Ordering::AcqRel);
+
+        self.interrupt_source_group.trigger(0)
+    }
+}
+
+impl Pausable for VirtioMmioDevice {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+impl Snapshottable for VirtioMmioDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut virtio_mmio_dev_snapshot = Snapshot::new(self.id.as_str());
+        virtio_mmio_dev_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
+
+        Ok(virtio_mmio_dev_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        if let Some(virtio_mmio_dev_section) =
+            snapshot.snapshot_data.get(&format!("{}-section", self.id))
+        {
+            let virtio_mmio_dev_state =
+                virtio_mmio_dev_section.to_versioned_state::<VirtioMmioDeviceState>()?;
+
+            // First restore the status of the virtqueues.
+            self.set_state(&virtio_mmio_dev_state).map_err(|e| {
+                MigratableError::Restore(anyhow!(
+                    "Could not restore VIRTIO_MMIO_DEVICE state {:?}",
+                    e
+                ))
+            })?;
+
+            // Then we can activate the device, as we know at this point that
+            // the virtqueues are in the right state and the device is ready
+            // to be activated, which will spawn each virtio worker thread.
+            if self.device_activated.load(Ordering::SeqCst) && self.is_driver_ready() {
+                self.activate().map_err(|e| {
+                    MigratableError::Restore(anyhow!("Failed activating the device: {:?}", e))
+                })?;
+            }
+
+            return Ok(());
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find VIRTIO_MMIO_DEVICE snapshot section"
+        )))
+    }
+}
+impl Transportable for VirtioMmioDevice {}
+impl Migratable for VirtioMmioDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig};
+
+    struct DummyDevice;
+    const QUEUE_SIZES: &[u16] = &[256, 128];
+    const DUMMY_FEATURES: u64 = 0x1_5555_aaaa;
+    impl VirtioDevice for DummyDevice {
+        fn device_type(&self) -> u32 {
+            4
+        }
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+        fn activate(
+            &mut self,
+            _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+            _interrupt_evt: Arc<dyn VirtioInterrupt>,
+            _queues: Vec<Queue>,
+            _queue_evts: Vec<EventFd>,
+        ) -> ActivateResult {
+            Ok(())
+        }
+
+        fn features(&self) -> u64 {
+            DUMMY_FEATURES
+        }
+    }
+
+    struct TestInterruptGroup {
+        triggers: Arc<AtomicUsize>,
+    }
+
+    impl InterruptSourceGroup for TestInterruptGroup {
+        fn trigger(&self, _index: InterruptIndex) -> std::io::Result<()> {
+            self.triggers.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+        ) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn read(device: &mut VirtioMmioDevice, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        BusDevice::read(device, 0, offset, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    fn write(device: &mut VirtioMmioDevice, offset: u64, value: u32) -> Option<Arc<Barrier>> {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        BusDevice::write(device, 0, offset, &data)
+    }
+
+    fn test_device() -> (VirtioMmioDevice, Arc<AtomicUsize>) {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let triggers = Arc::new(AtomicUsize::new(0));
+        let group: Box<dyn InterruptSourceGroup> = Box::new(TestInterruptGroup {
+            triggers: triggers.clone(),
+        });
+        let device = VirtioMmioDevice::new(
+            "test".to_owned(),
+            GuestMemoryAtomic::new(mem),
+            Arc::new(Mutex::new(DummyDevice)),
+            Arc::new(group),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+        )
+        .unwrap();
+        (device, triggers)
+    }
+
+    #[test]
+    fn test_identification_registers() {
+        let (mut device, _) = test_device();
+
+        assert_eq!(read(&mut device, MAGIC_VALUE), MMIO_MAGIC_VALUE);
+        assert_eq!(read(&mut device, VERSION), MMIO_VERSION);
+        assert_eq!(read(&mut device, DEVICE_ID), 4);
+        assert_eq!(read(&mut device, VENDOR), VENDOR_ID);
+
+        // The features are read a page at a time.
+        assert_eq!(read(&mut device, DEVICE_FEATURES), 0x5555_aaaa);
+        write(&mut device, DEVICE_FEATURES_SEL, 1);
+        assert_eq!(read(&mut device, DEVICE_FEATURES), 1);
+        write(&mut device, DEVICE_FEATURES_SEL, 2);
+        assert_eq!(read(&mut device, DEVICE_FEATURES), 0);
+    }
+
+    #[test]
+    fn test_queue_registers() {
+        let (mut device, _) = test_device();
+
+        write(&mut device, QUEUE_SEL, 1);
+        assert_eq!(read(&mut device, QUEUE_NUM_MAX), 128);
+        write(&mut device, QUEUE_NUM, 64);
+        write(&mut device, QUEUE_DESC_LOW, 0x1000);
+        write(&mut device, QUEUE_DESC_HIGH, 0x1);
+        write(&mut device, QUEUE_AVAIL_LOW, 0x2000);
+        write(&mut device, QUEUE_USED_LOW, 0x3000);
+        write(&mut device, QUEUE_READY, 1);
+        assert_eq!(read(&mut device, QUEUE_READY), 1);
+        assert_eq!(device.queues[1].size, 64);
+        assert_eq!(device.queues[1].desc_table, GuestAddress(0x1_0000_1000));
+        assert_eq!(device.queues[1].avail_ring, GuestAddress(0x2000));
+        assert_eq!(device.queues[1].used_ring, GuestAddress(0x3000));
+
+        // An enabled queue can't be reconfigured.
+        write(&mut device, QUEUE_NUM, 32);
+        assert_eq!(device.queues[1].size, 64);
+
+        // Unknown queues read as absent.
+        write(&mut device, QUEUE_SEL, 2);
+        assert_eq!(read(&mut device, QUEUE_NUM_MAX), 0);
+
+        // Every queue is notified on the same register, by its index.
+        let ioeventfds = device.ioeventfds(0xd000_0000);
+        assert_eq!(ioeventfds.len(), 2);
+        assert_eq!(ioeventfds[1].1, 0xd000_0000 + QUEUE_NOTIFY);
+        assert_eq!(ioeventfds[1].2, 1);
+
+        // Writing 0 to the status register resets the queues.
+        write(&mut device, STATUS, DEVICE_ACKNOWLEDGE);
+        write(&mut device, STATUS, DEVICE_INIT);
+        assert!(!device.queues[1].ready);
+        assert_eq!(device.queue_select, 0);
+    }
+
+    #[test]
+    fn test_interrupt_status() {
+        let (mut device, triggers) = test_device();
+        let interrupt = device.virtio_interrupt.clone().unwrap();
+
+        interrupt
+            .trigger(&VirtioInterruptType::Queue, None)
+            .unwrap();
+        interrupt
+            .trigger(&VirtioInterruptType::Config, None)
+            .unwrap();
+        assert_eq!(triggers.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            read(&mut device, INTERRUPT_STATUS) as usize,
+            INTERRUPT_STATUS_USED_RING | INTERRUPT_STATUS_CONFIG_CHANGED
+        );
+
+        write(
+            &mut device,
+            INTERRUPT_ACK,
+            INTERRUPT_STATUS_USED_RING as u32,
+        );
+        assert_eq!(
+            read(&mut device, INTERRUPT_STATUS) as usize,
+            INTERRUPT_STATUS_CONFIG_CHANGED
+        );
+    }
+
+    #[test]
+    fn test_activation() {
+        let (mut device, _) = test_device();
+
+        write(&mut device, STATUS, DEVICE_ACKNOWLEDGE);
+        write(&mut device, STATUS, DEVICE_ACKNOWLEDGE | DEVICE_DRIVER);
+        write(
+            &mut device,
+            STATUS,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK,
+        );
+        assert!(!device.needs_activation());
+
+        // Setting DRIVER_OK asks the VMM to activate the device, the vCPU
+        // waiting on the barrier until it's done.
+        assert!(write(
+            &mut device,
+            STATUS,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK | DEVICE_DRIVER_OK,
+        )
+        .is_some());
+        assert_eq!(device.activate_evt.read().unwrap(), 1);
+        assert!(device.needs_activation());
+        assert!(device.activate().is_ok());
+    }
+}
@@ -3,11 +3,63 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use vmm_sys_util::eventfd::EventFd;
+mod mmio;
 mod pci_common_config;
 mod pci_device;
+pub use mmio::{VirtioInterruptMmio, VirtioMmioDevice, VIRTIO_MMIO_SIZE};
 pub use pci_common_config::VirtioPciCommonConfig;
-pub use pci_device::VirtioPciDevice;
+pub use pci_device::{MsixVectorStats, VirtioPciDevice, VirtioPciIdentity};
 
 pub trait VirtioTransport {
     fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64)>;
 }
+
+/// Logs an access of the guest to the registers of a device, for debugging
+/// its driver. The accesses of all the devices share the same rate limit.
+#[cfg(feature = "access_log")]
+fn log_access(id: &str, access: &str, offset: u64, data: &[u8]) {
+    guest_log!(
+        log::Level::Info,
+        "{}",
+        format_access(id, access, offset, data)
+    );
+}
+
+// The value accessed is the little endian number made of all the bytes, as
+// long as the access is.
+#[cfg(feature = "access_log")]
+fn format_access(id: &str, access: &str, offset: u64, data: &[u8]) -> String {
+    let value: String = data.iter().rev().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}: {} at 0x{:x}, {} bytes: 0x{}",
+        id,
+        access,
+        offset,
+        data.len(),
+        value
+    )
+}
+
+#[cfg(all(test, feature = "access_log"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_access() {
+        assert_eq!(
+            format_access("_net0", "MMIO read", 0x70, &[0x0f]),
+            "_net0: MMIO read at 0x70, 1 bytes: 0x0f"
+        );
+        assert_eq!(
+            format_access("_disk0", "BAR write", 0x2000, &[0x78, 0x56, 0x34, 0x12]),
+            "_disk0: BAR write at 0x2000, 4 bytes: 0x12345678"
+        );
+
+        // Nothing is left out of the accesses wider than 8 bytes.
+        let data: Vec<u8> = (1..=12).collect();
+        assert_eq!(
+            format_access("_net0", "config read", 0, &data),
+            "_net0: config read at 0x0, 12 bytes: 0x0c0b0a090807060504030201"
+        );
+    }
+}
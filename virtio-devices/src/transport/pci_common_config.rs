@@ -7,13 +7,15 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 extern crate byteorder;
 
-use crate::{Queue, VirtioDevice};
+use crate::{Queue, VirtioDevice, VIRTIO_F_IOMMU_PLATFORM};
 use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::{Arc, Mutex};
 use vm_memory::GuestAddress;
-use vm_migration::{MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable};
+use vm_migration::{
+    MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable, VersionedState,
+};
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct VirtioPciCommonConfigState {
@@ -25,6 +27,10 @@ pub struct VirtioPciCommonConfigState {
     pub msix_config: u16,
 }
 
+impl VersionedState for VirtioPciCommonConfigState {
+    const VERSION: u16 = 1;
+}
+
 /// Contains the data for reading and writing the common configuration structure of a virtio PCI
 /// device.
 ///
@@ -54,9 +60,21 @@ pub struct VirtioPciCommonConfig {
     pub driver_feature_select: u32,
     pub queue_select: u16,
     pub msix_config: Arc<AtomicU16>,
+    // Offer VIRTIO_F_ACCESS_PLATFORM on behalf of the device, for the guest
+    // to map its buffers through the DMA API, e.g. into bounce buffers when
+    // its memory is encrypted.
+    pub access_platform: bool,
 }
 
 impl VirtioPciCommonConfig {
+    fn platform_features(&self) -> u64 {
+        if self.access_platform {
+            1u64 << VIRTIO_F_IOMMU_PLATFORM
+        } else {
+            0
+        }
+    }
+
     fn state(&self) -> VirtioPciCommonConfigState {
         VirtioPciCommonConfigState {
             driver_status: self.driver_status,
@@ -103,7 +121,7 @@ impl VirtioPciCommonConfig {
                 let v = self.read_common_config_qword(offset);
                 LittleEndian::write_u64(data, v);
             }
-            _ => error!("invalid data length for virtio read: len {}", data.len()),
+            _ => guest_error!("invalid data length for virtio read: len {}", data.len()),
         }
     }
 
@@ -123,7 +141,7 @@ impl VirtioPciCommonConfig {
                 self.write_common_config_dword(offset, LittleEndian::read_u32(data), queues, device)
             }
             8 => self.write_common_config_qword(offset, LittleEndian::read_u64(data), queues),
-            _ => error!("invalid data length for virtio write: len {}", data.len()),
+            _ => guest_error!("invalid data length for virtio write: len {}", data.len()),
         }
     }
 
@@ -134,7 +152,7 @@ impl VirtioPciCommonConfig {
             0x14 => self.driver_status,
             0x15 => self.config_generation,
             _ => {
-                warn!("invalid virtio config byte read: 0x{:x}", offset);
+                guest_warn!("invalid virtio config byte read: 0x{:x}", offset);
                 0
             }
         }
@@ -145,7 +163,7 @@ impl VirtioPciCommonConfig {
         match offset {
             0x14 => self.driver_status = value,
             _ => {
-                warn!("invalid virtio config byte write: 0x{:x}", offset);
+                guest_warn!("invalid virtio config byte write: 0x{:x}", offset);
             }
         }
     }
@@ -167,7 +185,7 @@ impl VirtioPciCommonConfig {
             }
             0x1e => self.queue_select, // notify_off
             _ => {
-                warn!("invalid virtio register word read: 0x{:x}", offset);
+                guest_warn!("invalid virtio register word read: 0x{:x}", offset);
                 0
             }
         }
@@ -182,7 +200,7 @@ impl VirtioPciCommonConfig {
             0x1a => self.with_queue_mut(queues, |q| q.vector = value),
             0x1c => self.with_queue_mut(queues, |q| q.enable(value == 1)),
             _ => {
-                warn!("invalid virtio register word write: 0x{:x}", offset);
+                guest_warn!("invalid virtio register word write: 0x{:x}", offset);
             }
         }
     }
@@ -196,14 +214,15 @@ impl VirtioPciCommonConfig {
                 // Only 64 bits of features (2 pages) are defined for now, so limit
                 // device_feature_select to avoid shifting by 64 or more bits.
                 if self.device_feature_select < 2 {
-                    (locked_device.features() >> (self.device_feature_select * 32)) as u32
+                    ((locked_device.features() | self.platform_features())
+                        >> (self.device_feature_select * 32)) as u32
                 } else {
                     0
                 }
             }
             0x08 => self.driver_feature_select,
             _ => {
-                warn!("invalid virtio register dword read: 0x{:x}", offset);
+                guest_warn!("invalid virtio register dword read: 0x{:x}", offset);
                 0
             }
         }
@@ -231,12 +250,16 @@ impl VirtioPciCommonConfig {
             0x0c => {
                 if self.driver_feature_select < 2 {
                     let mut locked_device = device.lock().unwrap();
-                    locked_device
-                        .ack_features(u64::from(value) << (self.driver_feature_select * 32));
+                    // The device doesn't know about the features offered by
+                    // the transport.
+                    let features = (u64::from(value) << (self.driver_feature_select * 32))
+                        & !self.platform_features();
+                    locked_device.ack_features(features);
                 } else {
-                    warn!(
+                    guest_warn!(
                         "invalid ack_features (page {}, value 0x{:x})",
-                        self.driver_feature_select, value
+                        self.driver_feature_select,
+                        value
                     );
                 }
             }
@@ -247,7 +270,7 @@ impl VirtioPciCommonConfig {
             0x30 => self.with_queue_mut(queues, |q| lo(&mut q.used_ring, value)),
             0x34 => self.with_queue_mut(queues, |q| hi(&mut q.used_ring, value)),
             _ => {
-                warn!("invalid virtio register dword write: 0x{:x}", offset);
+                guest_warn!("invalid virtio register dword write: 0x{:x}", offset);
             }
         }
     }
@@ -264,7 +287,7 @@ impl VirtioPciCommonConfig {
             0x28 => self.with_queue_mut(queues, |q| q.avail_ring = GuestAddress(value)),
             0x30 => self.with_queue_mut(queues, |q| q.used_ring = GuestAddress(value)),
             _ => {
-                warn!("invalid virtio register qword write: 0x{:x}", offset);
+                guest_warn!("invalid virtio register qword write: 0x{:x}", offset);
             }
         }
     }
@@ -291,14 +314,11 @@ impl Snapshottable for VirtioPciCommonConfig {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut config_snapshot = Snapshot::new(self.id().as_str());
-        config_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id()),
-            snapshot,
-        });
+        config_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id()),
+            &self.state(),
+        )?);
 
         Ok(config_snapshot)
     }
@@ -308,16 +328,7 @@ impl Snapshottable for VirtioPciCommonConfig {
             .snapshot_data
             .get(&format!("{}-section", self.id()))
         {
-            let config_state = match serde_json::from_slice(&config_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize {}: {}",
-                        self.id(),
-                        error
-                    )))
-                }
-            };
+            let config_state = config_section.to_versioned_state::<VirtioPciCommonConfigState>()?;
 
             self.set_state(&config_state);
 
@@ -380,6 +391,7 @@ mod tests {
             driver_feature_select: 0x0,
             queue_select: 0xff,
             msix_config: Arc::new(AtomicU16::new(0)),
+            access_platform: false,
         };
 
         let dev = Arc::new(Mutex::new(DummyDevice(0)));
@@ -420,4 +432,34 @@ mod tests {
         assert_eq!(read_back[0], 0xaa);
         assert_eq!(read_back[1], 0x55);
     }
+
+    #[test]
+    fn access_platform_feature() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 0x0,
+            driver_feature_select: 0x0,
+            queue_select: 0,
+            msix_config: Arc::new(AtomicU16::new(0)),
+            access_platform: true,
+        };
+
+        let dev = Arc::new(Mutex::new(DummyDevice(0)));
+        let mut queues = Vec::new();
+
+        // The first page is passed through from the device.
+        let mut read_back = vec![0, 0, 0, 0];
+        regs.read(0x04, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u32(&read_back), DUMMY_FEATURES as u32);
+
+        // VIRTIO_F_ACCESS_PLATFORM is offered on the second page.
+        regs.write(0x00, &[1, 0, 0, 0], &mut queues, dev.clone());
+        let mut read_back = vec![0, 0, 0, 0];
+        regs.read(0x04, &mut read_back, &mut queues, dev);
+        assert_eq!(
+            LittleEndian::read_u32(&read_back),
+            1 << (VIRTIO_F_IOMMU_PLATFORM - 32)
+        );
+    }
 }
@@ -21,18 +21,20 @@ use crate::{
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use pci::{
-    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
+    build_vpd, BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
     PciCapability, PciCapabilityID, PciClassCode, PciConfiguration, PciDevice, PciDeviceError,
-    PciHeaderType, PciMassStorageSubclass, PciNetworkControllerSubclass, PciSubclass,
+    PciHeaderType, PciInterruptPin, PciMassStorageSubclass, PciNetworkControllerSubclass,
+    PciSubclass,
 };
 use std::any::Any;
 use std::cmp;
 use std::io::Write;
 use std::num::Wrapping;
 use std::result;
-use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
-use vm_allocator::SystemAllocator;
+use std::time::{SystemTime, UNIX_EPOCH};
+use vm_allocator::{AddressAllocator, SystemAllocator};
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
 };
@@ -43,7 +45,7 @@ use vm_memory::{
 };
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    Transportable, VersionedState,
 };
 use vm_virtio::{queue, VirtioIommuRemapping};
 use vmm_sys_util::{errno::Result, eventfd::EventFd};
@@ -248,6 +250,10 @@ const COMMON_CONFIG_BAR_OFFSET: u64 = 0x0000;
 const COMMON_CONFIG_SIZE: u64 = 56;
 const ISR_CONFIG_BAR_OFFSET: u64 = 0x2000;
 const ISR_CONFIG_SIZE: u64 = 1;
+// Bits of the ISR status register, telling the driver what an INTx
+// interrupt was raised for.
+const ISR_STATUS_QUEUE: usize = 0x1;
+const ISR_STATUS_CONFIG: usize = 0x2;
 const DEVICE_CONFIG_BAR_OFFSET: u64 = 0x4000;
 const DEVICE_CONFIG_SIZE: u64 = 0x1000;
 const NOTIFICATION_BAR_OFFSET: u64 = 0x6000;
@@ -268,6 +274,48 @@ const NOTIFY_OFF_MULTIPLIER: u32 = 4; // A dword per notification address.
 const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040; // Add to device type to get device ID.
 
+/// Interrupt statistics for a single MSI-X vector of a virtio-pci device.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MsixVectorStats {
+    /// MSI-X vector the statistics apply to.
+    pub vector: u16,
+    /// Number of interrupts the device delivered on this vector, the ones
+    /// raised while the vector was masked being left out.
+    pub count: u64,
+    /// Time of the last interrupt raised on this vector, in microseconds
+    /// since the UNIX epoch, or 0 if no interrupt has been raised yet.
+    pub last_trigger_us: u64,
+}
+
+// Reads the coarse monotonic clock, in microseconds. It is served from the
+// vDSO without reading the TSC, being cheap enough for the interrupt path.
+fn coarse_monotonic_us() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because the timespec is valid for writes, and the monotonic
+    // clock can't fail.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_COARSE, &mut now) };
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
+
+#[derive(Default)]
+struct MsixVectorCounters {
+    count: AtomicU64,
+    // Coarse monotonic time of the last interrupt, only converted to a
+    // wall clock time when the statistics are read.
+    last_trigger_us: AtomicU64,
+}
+
+impl MsixVectorCounters {
+    fn record(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.last_trigger_us
+            .store(coarse_monotonic_us(), Ordering::Relaxed);
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct VirtioPciDeviceState {
     device_activated: bool,
@@ -275,6 +323,10 @@ struct VirtioPciDeviceState {
     interrupt_status: usize,
 }
 
+impl VersionedState for VirtioPciDeviceState {
+    const VERSION: u16 = 1;
+}
+
 pub struct VirtioPciDevice {
     id: String,
 
@@ -290,6 +342,10 @@ pub struct VirtioPciDevice {
     // Number of MSI-X vectors
     msix_num: u16,
 
+    // Per MSI-X vector interrupt counters, shared with the interrupt
+    // handed over to the virtio device.
+    msix_vector_counters: Arc<Vec<MsixVectorCounters>>,
+
     // Virtio device reference and status
     device: Arc<Mutex<dyn VirtioDevice>>,
     device_activated: Arc<AtomicBool>,
@@ -329,10 +385,32 @@ pub struct VirtioPciDevice {
 
     // Barrier that is used to wait on for activation
     activate_barrier: Arc<Barrier>,
+
+    // Vital Product Data holding the serial number of the device
+    vpd: Option<Vec<u8>>,
+
+    // Whether the accesses to the configuration space and the BAR are logged
+    #[cfg(feature = "access_log")]
+    access_log: bool,
+}
+
+/// How the device identifies itself to the guest, beyond its virtio device
+/// type.
+#[derive(Clone, Debug, Default)]
+pub struct VirtioPciIdentity {
+    /// Subsystem vendor ID, the virtio vendor ID by default.
+    pub subsystem_vendor_id: Option<u16>,
+    /// Subsystem ID, the PCI device ID by default.
+    pub subsystem_id: Option<u16>,
+    /// Serial number, exposed in the Vital Product Data capability.
+    pub serial: Option<String>,
 }
 
 impl VirtioPciDevice {
     /// Constructs a new PCI transport for the given virtio device.
+    ///
+    /// The `legacy_irq` is the INTx line of the device and its interrupt
+    /// group, used whenever the guest hasn't enabled MSI-X.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
@@ -341,8 +419,10 @@ impl VirtioPciDevice {
         msix_num: u16,
         iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        legacy_irq: Option<(u32, Arc<Box<dyn InterruptSourceGroup>>)>,
         pci_device_bdf: u32,
         activate_evt: EventFd,
+        identity: VirtioPciIdentity,
     ) -> Result<Self> {
         let device_clone = device.clone();
         let locked_device = device_clone.lock().unwrap();
@@ -361,6 +441,13 @@ impl VirtioPciDevice {
             .collect();
 
         let pci_device_id = VIRTIO_PCI_DEVICE_ID_BASE + locked_device.device_type() as u16;
+        let vpd = identity.serial.as_ref().map(|serial| {
+            let product_name = format!(
+                "Cloud Hypervisor virtio-{}",
+                VirtioDeviceType::from(locked_device.device_type())
+            );
+            build_vpd(&product_name, serial)
+        });
 
         let interrupt_source_group = interrupt_manager.create_group(MsiIrqGroupConfig {
             base: 0,
@@ -401,7 +488,7 @@ impl VirtioPciDevice {
             ),
         };
 
-        let configuration = PciConfiguration::new(
+        let mut configuration = PciConfiguration::new(
             VIRTIO_PCI_VENDOR_ID,
             pci_device_id,
             0x1, // For modern virtio-PCI devices
@@ -409,10 +496,13 @@ impl VirtioPciDevice {
             subclass,
             None,
             PciHeaderType::Device,
-            VIRTIO_PCI_VENDOR_ID,
-            pci_device_id,
+            identity.subsystem_vendor_id.unwrap_or(VIRTIO_PCI_VENDOR_ID),
+            identity.subsystem_id.unwrap_or(pci_device_id),
             msix_config_clone,
         );
+        if let Some((irq, _)) = &legacy_irq {
+            configuration.set_irq(*irq as u8, PciInterruptPin::IntA);
+        }
 
         let mut virtio_pci_device = VirtioPciDevice {
             id,
@@ -424,9 +514,15 @@ impl VirtioPciDevice {
                 driver_feature_select: 0,
                 queue_select: 0,
                 msix_config: Arc::new(AtomicU16::new(0)),
+                access_platform: false,
             },
             msix_config,
             msix_num,
+            msix_vector_counters: Arc::new(
+                (0..msix_num)
+                    .map(|_| MsixVectorCounters::default())
+                    .collect(),
+            ),
             device,
             device_activated: Arc::new(AtomicBool::new(false)),
             interrupt_status: Arc::new(AtomicUsize::new(0)),
@@ -442,14 +538,27 @@ impl VirtioPciDevice {
             bar_regions: vec![],
             activate_evt,
             activate_barrier: Arc::new(Barrier::new(2)),
+            vpd,
+            #[cfg(feature = "access_log")]
+            access_log: false,
         };
 
-        if let Some(msix_config) = &virtio_pci_device.msix_config {
-            virtio_pci_device.virtio_interrupt = Some(Arc::new(VirtioInterruptMsix::new(
+        let msix = virtio_pci_device.msix_config.as_ref().map(|msix_config| {
+            VirtioInterruptMsix::new(
                 msix_config.clone(),
                 virtio_pci_device.common_config.msix_config.clone(),
                 virtio_pci_device.interrupt_source_group.clone(),
-            )));
+                virtio_pci_device.msix_vector_counters.clone(),
+            )
+        });
+        let intx = legacy_irq.map(|(_, interrupt_source_group)| {
+            VirtioInterruptIntx::new(
+                virtio_pci_device.interrupt_status.clone(),
+                interrupt_source_group,
+            )
+        });
+        if msix.is_some() || intx.is_some() {
+            virtio_pci_device.virtio_interrupt = Some(Arc::new(VirtioInterruptPci { msix, intx }));
         }
 
         Ok(virtio_pci_device)
@@ -521,6 +630,17 @@ impl VirtioPciDevice {
         self.settings_bar_addr = Some(GuestAddress(bar_addr));
     }
 
+    // Offers VIRTIO_F_ACCESS_PLATFORM whatever the device supports, which the
+    // guest needs when its memory is encrypted.
+    pub fn set_access_platform(&mut self, access_platform: bool) {
+        self.common_config.access_platform = access_platform;
+    }
+
+    #[cfg(feature = "access_log")]
+    pub fn set_access_log(&mut self, access_log: bool) {
+        self.access_log = access_log;
+    }
+
     pub fn config_bar_addr(&self) -> u64 {
         self.configuration.get_bar_addr(self.settings_bar as usize)
     }
@@ -593,6 +713,12 @@ impl VirtioPciDevice {
                 .map_err(PciDeviceError::CapabilitiesSetup)?;
         }
 
+        if let Some(vpd) = &self.vpd {
+            self.configuration
+                .add_vpd_capability(vpd.clone())
+                .map_err(PciDeviceError::CapabilitiesSetup)?;
+        }
+
         self.settings_bar = settings_bar;
         Ok(())
     }
@@ -602,7 +728,7 @@ impl VirtioPciDevice {
         let data_len = data.len();
         let cap_len = cap_slice.len();
         if offset + data_len > cap_len {
-            error!("Failed to read cap_pci_cfg from config space");
+            guest_error!("Failed to read cap_pci_cfg from config space");
             return;
         }
 
@@ -625,7 +751,7 @@ impl VirtioPciDevice {
         let data_len = data.len();
         let cap_len = cap_slice.len();
         if offset + data_len > cap_len {
-            error!("Failed to write cap_pci_cfg to config space");
+            guest_error!("Failed to write cap_pci_cfg to config space");
             return None;
         }
 
@@ -645,6 +771,39 @@ impl VirtioPciDevice {
         self.device.clone()
     }
 
+    /// Returns the interrupt statistics of every MSI-X vector the device
+    /// has raised at least one interrupt on.
+    ///
+    /// Interrupts the backend injects directly through an irqfd, as with
+    /// vhost-user and vhost-kernel devices, are not accounted for.
+    pub fn msix_vector_stats(&self) -> Vec<MsixVectorStats> {
+        let now_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        let monotonic_now_us = coarse_monotonic_us();
+
+        self.msix_vector_counters
+            .iter()
+            .enumerate()
+            .filter_map(|(vector, counters)| {
+                let count = counters.count.load(Ordering::Relaxed);
+                if count == 0 {
+                    return None;
+                }
+
+                Some(MsixVectorStats {
+                    vector: vector as u16,
+                    count,
+                    last_trigger_us: now_us.saturating_sub(
+                        monotonic_now_us
+                            .saturating_sub(counters.last_trigger_us.load(Ordering::Relaxed)),
+                    ),
+                })
+            })
+            .collect()
+    }
+
     fn activate(&mut self) -> ActivateResult {
         if let Some(virtio_interrupt) = self.virtio_interrupt.take() {
             if self.memory.is_some() {
@@ -665,21 +824,68 @@ impl VirtioPciDevice {
         Ok(())
     }
 
-    pub fn maybe_activate(&mut self) {
+    pub fn maybe_activate(&mut self) -> ActivateResult {
         if self.needs_activation() {
-            self.activate().expect("Failed to activate device");
-            self.device_activated.store(true, Ordering::SeqCst);
+            let result = self.activate();
+            if result.is_ok() {
+                self.device_activated.store(true, Ordering::SeqCst);
+            }
+            // The vCPU which set DRIVER_OK waits for the activation to be
+            // over, whether it succeeded or not.
             info!("{}: Waiting for barrier", self.id);
             self.activate_barrier.wait();
             info!("{}: Barrier released", self.id);
+            result
         } else {
-            info!("{}: Device does not need activation", self.id)
+            info!("{}: Device does not need activation", self.id);
+            Ok(())
         }
     }
 
-    fn needs_activation(&self) -> bool {
+    /// Whether the driver is done initializing a device which is not
+    /// activated yet, either for the first time or after a reset.
+    pub fn needs_activation(&self) -> bool {
         !self.device_activated.load(Ordering::SeqCst) && self.is_driver_ready()
     }
+
+    /// Reset the device on behalf of the VMM, without the driver noticing.
+    /// The device goes through the same reset as when the driver writes 0 to
+    /// the status register, then is activated again with the features the
+    /// driver acknowledged, vhost-user devices negotiating them with their
+    /// backend again. The queues resume after the last used descriptor, the
+    /// requests which were in flight being processed again.
+    pub fn reset_device(&mut self) -> result::Result<(), crate::Error> {
+        if !self.device_activated.load(Ordering::SeqCst) {
+            // Nothing has been negotiated yet, the driver will go through
+            // the whole initialization anyway.
+            return Ok(());
+        }
+
+        let virtio_interrupt = self
+            .device
+            .lock()
+            .unwrap()
+            .reset()
+            .ok_or(crate::Error::ResetNotSupported)?;
+        self.device_activated.store(false, Ordering::SeqCst);
+        self.virtio_interrupt = Some(virtio_interrupt);
+
+        if let Some(mem) = self.memory.as_ref() {
+            let mem = mem.memory();
+            for queue in self.queues.iter_mut().filter(|q| q.ready) {
+                let used_index = queue
+                    .used_index_from_memory(&mem)
+                    .map_err(crate::Error::QueueRingIndex)?;
+                queue.next_avail = Wrapping(used_index);
+                queue.next_used = Wrapping(used_index);
+            }
+        }
+
+        self.activate().map_err(crate::Error::ActivateDevice)?;
+        self.device_activated.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
 }
 
 impl VirtioTransport for VirtioPciDevice {
@@ -702,18 +908,21 @@ pub struct VirtioInterruptMsix {
     msix_config: Arc<Mutex<MsixConfig>>,
     config_vector: Arc<AtomicU16>,
     interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+    vector_counters: Arc<Vec<MsixVectorCounters>>,
 }
 
 impl VirtioInterruptMsix {
-    pub fn new(
+    fn new(
         msix_config: Arc<Mutex<MsixConfig>>,
         config_vector: Arc<AtomicU16>,
         interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+        vector_counters: Arc<Vec<MsixVectorCounters>>,
     ) -> Self {
         VirtioInterruptMsix {
             msix_config,
             config_vector,
             interrupt_source_group,
+            vector_counters,
         }
     }
 }
@@ -751,6 +960,10 @@ impl VirtioInterrupt for VirtioInterruptMsix {
             return Ok(());
         }
 
+        if let Some(counters) = self.vector_counters.get(vector as usize) {
+            counters.record();
+        }
+
         self.interrupt_source_group
             .trigger(vector as InterruptIndex)
     }
@@ -772,6 +985,80 @@ impl VirtioInterrupt for VirtioInterruptMsix {
     }
 }
 
+pub struct VirtioInterruptIntx {
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+}
+
+impl VirtioInterruptIntx {
+    fn new(
+        interrupt_status: Arc<AtomicUsize>,
+        interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+    ) -> Self {
+        VirtioInterruptIntx {
+            interrupt_status,
+            interrupt_source_group,
+        }
+    }
+}
+
+impl VirtioInterrupt for VirtioInterruptIntx {
+    fn trigger(
+        &self,
+        int_type: &VirtioInterruptType,
+        _queue: Option<&Queue>,
+    ) -> std::result::Result<(), std::io::Error> {
+        // The line is shared with other devices, the driver reads the ISR
+        // status to find out whether the interrupt is meant for this one.
+        let status = match int_type {
+            VirtioInterruptType::Config => ISR_STATUS_CONFIG,
+            VirtioInterruptType::Queue => ISR_STATUS_QUEUE,
+        };
+        self.interrupt_status.fetch_or(status, Ordering::AcqRel);
+
+        self.interrupt_source_group.trigger(0)
+    }
+}
+
+/// Delivers the interrupts through MSI-X when the guest has enabled it, and
+/// falls back to INTx otherwise, as some guests and firmwares never enable
+/// MSI-X.
+pub struct VirtioInterruptPci {
+    msix: Option<VirtioInterruptMsix>,
+    intx: Option<VirtioInterruptIntx>,
+}
+
+impl VirtioInterruptPci {
+    fn msix_enabled(&self) -> Option<&VirtioInterruptMsix> {
+        self.msix
+            .as_ref()
+            .filter(|msix| msix.msix_config.lock().unwrap().enabled())
+    }
+}
+
+impl VirtioInterrupt for VirtioInterruptPci {
+    fn trigger(
+        &self,
+        int_type: &VirtioInterruptType,
+        queue: Option<&Queue>,
+    ) -> std::result::Result<(), std::io::Error> {
+        if let Some(msix) = self.msix_enabled() {
+            msix.trigger(int_type, queue)
+        } else if let Some(intx) = &self.intx {
+            intx.trigger(int_type, queue)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn notifier(&self, int_type: &VirtioInterruptType, queue: Option<&Queue>) -> Option<EventFd> {
+        // Without MSI-X, the ISR status must be updated along with the
+        // interrupt, which requires going through trigger().
+        self.msix_enabled()
+            .and_then(|msix| msix.notifier(int_type, queue))
+    }
+}
+
 impl PciDevice for VirtioPciDevice {
     fn write_config_register(
         &mut self,
@@ -779,6 +1066,11 @@ impl PciDevice for VirtioPciDevice {
         offset: u64,
         data: &[u8],
     ) -> Option<Arc<Barrier>> {
+        #[cfg(feature = "access_log")]
+        if self.access_log {
+            super::log_access(&self.id, "config write", reg_idx as u64 * 4 + offset, data);
+        }
+
         // Handle the special case where the capability VIRTIO_PCI_CAP_PCI_CFG
         // is accessed. This capability has a special meaning as it allows the
         // guest to access other capabilities without mapping the PCI BAR.
@@ -801,7 +1093,7 @@ impl PciDevice for VirtioPciDevice {
         // is accessed. This capability has a special meaning as it allows the
         // guest to access other capabilities without mapping the PCI BAR.
         let base = reg_idx * 4;
-        if base >= self.cap_pci_cfg_info.offset
+        let value = if base >= self.cap_pci_cfg_info.offset
             && base + 4 <= self.cap_pci_cfg_info.offset + self.cap_pci_cfg_info.cap.bytes().len()
         {
             let offset = base - self.cap_pci_cfg_info.offset;
@@ -810,7 +1102,14 @@ impl PciDevice for VirtioPciDevice {
             u32::from_le_bytes(data)
         } else {
             self.configuration.read_reg(reg_idx)
+        };
+
+        #[cfg(feature = "access_log")]
+        if self.access_log {
+            super::log_access(&self.id, "config read", base as u64, &value.to_le_bytes());
         }
+
+        value
     }
 
     fn detect_bar_reprogramming(
@@ -823,7 +1122,9 @@ impl PciDevice for VirtioPciDevice {
 
     fn allocate_bars(
         &mut self,
-        allocator: &mut SystemAllocator,
+        _allocator: &mut SystemAllocator,
+        mmio_allocator: &mut AddressAllocator,
+        mmio_hole_allocator: &mut AddressAllocator,
     ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
     {
         let mut ranges = Vec::new();
@@ -834,15 +1135,23 @@ impl PciDevice for VirtioPciDevice {
         // See http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-740004
         let (virtio_pci_bar_addr, region_type) = if self.use_64bit_bar {
             let region_type = PciBarRegionType::Memory64BitRegion;
-            let addr = allocator
-                .allocate_mmio_addresses(self.settings_bar_addr, CAPABILITY_BAR_SIZE, None)
+            let addr = mmio_allocator
+                .allocate(
+                    self.settings_bar_addr,
+                    CAPABILITY_BAR_SIZE,
+                    Some(CAPABILITY_BAR_SIZE),
+                )
                 .ok_or(PciDeviceError::IoAllocationFailed(CAPABILITY_BAR_SIZE))?;
             ranges.push((addr, CAPABILITY_BAR_SIZE, region_type));
             (addr, region_type)
         } else {
             let region_type = PciBarRegionType::Memory32BitRegion;
-            let addr = allocator
-                .allocate_mmio_hole_addresses(self.settings_bar_addr, CAPABILITY_BAR_SIZE, None)
+            let addr = mmio_hole_allocator
+                .allocate(
+                    self.settings_bar_addr,
+                    CAPABILITY_BAR_SIZE,
+                    Some(CAPABILITY_BAR_SIZE),
+                )
                 .ok_or(PciDeviceError::IoAllocationFailed(CAPABILITY_BAR_SIZE))?;
             ranges.push((addr, CAPABILITY_BAR_SIZE, region_type));
             (addr, region_type)
@@ -898,15 +1207,17 @@ impl PciDevice for VirtioPciDevice {
 
     fn free_bars(
         &mut self,
-        allocator: &mut SystemAllocator,
+        _allocator: &mut SystemAllocator,
+        mmio_allocator: &mut AddressAllocator,
+        mmio_hole_allocator: &mut AddressAllocator,
     ) -> std::result::Result<(), PciDeviceError> {
         for (addr, length, type_) in self.bar_regions.drain(..) {
             match type_ {
                 PciBarRegionType::Memory32BitRegion => {
-                    allocator.free_mmio_hole_addresses(addr, length);
+                    mmio_hole_allocator.free(addr, length);
                 }
                 PciBarRegionType::Memory64BitRegion => {
-                    allocator.free_mmio_addresses(addr, length);
+                    mmio_allocator.free(addr, length);
                 }
                 _ => error!("Unexpected PCI bar type"),
             }
@@ -1054,10 +1365,20 @@ impl PciDevice for VirtioPciDevice {
 
 impl BusDevice for VirtioPciDevice {
     fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
-        self.read_bar(base, offset, data)
+        self.read_bar(base, offset, data);
+
+        #[cfg(feature = "access_log")]
+        if self.access_log {
+            super::log_access(&self.id, "BAR read", offset, data);
+        }
     }
 
     fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        #[cfg(feature = "access_log")]
+        if self.access_log {
+            super::log_access(&self.id, "BAR write", offset, data);
+        }
+
         self.write_bar(base, offset, data)
     }
 }
@@ -1078,14 +1399,11 @@ impl Snapshottable for VirtioPciDevice {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut virtio_pci_dev_snapshot = Snapshot::new(self.id.as_str());
-        virtio_pci_dev_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id),
-            snapshot,
-        });
+        virtio_pci_dev_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
 
         // Snapshot PciConfiguration
         virtio_pci_dev_snapshot.add_snapshot(self.configuration.snapshot()?);
@@ -1128,15 +1446,7 @@ impl Snapshottable for VirtioPciDevice {
             }
 
             let virtio_pci_dev_state =
-                match serde_json::from_slice(&virtio_pci_dev_section.snapshot) {
-                    Ok(state) => state,
-                    Err(error) => {
-                        return Err(MigratableError::Restore(anyhow!(
-                            "Could not deserialize VIRTIO_PCI_DEVICE {}",
-                            error
-                        )))
-                    }
-                };
+                virtio_pci_dev_section.to_versioned_state::<VirtioPciDeviceState>()?;
 
             // First restore the status of the virtqueues.
             self.set_state(&virtio_pci_dev_state).map_err(|e| {
@@ -1165,3 +1475,274 @@ impl Snapshottable for VirtioPciDevice {
 }
 impl Transportable for VirtioPciDevice {}
 impl Migratable for VirtioPciDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_device::interrupt::InterruptSourceConfig;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+
+    struct TestInterruptGroup {
+        triggers: Arc<AtomicUsize>,
+    }
+
+    impl InterruptSourceGroup for TestInterruptGroup {
+        fn trigger(&self, _index: InterruptIndex) -> std::io::Result<()> {
+            self.triggers.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+        ) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_interrupt_group() -> (Arc<Box<dyn InterruptSourceGroup>>, Arc<AtomicUsize>) {
+        let triggers = Arc::new(AtomicUsize::new(0));
+        let group: Box<dyn InterruptSourceGroup> = Box::new(TestInterruptGroup {
+            triggers: triggers.clone(),
+        });
+        (Arc::new(group), triggers)
+    }
+
+    struct TestInterruptManager {}
+
+    impl InterruptManager for TestInterruptManager {
+        type GroupConfig = MsiIrqGroupConfig;
+
+        fn create_group(
+            &self,
+            _config: Self::GroupConfig,
+        ) -> std::io::Result<Arc<Box<dyn InterruptSourceGroup>>> {
+            Ok(test_interrupt_group().0)
+        }
+
+        fn destroy_group(&self, _group: Arc<Box<dyn InterruptSourceGroup>>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Records where its queues start from each time it's activated.
+    struct TestDevice {
+        resettable: bool,
+        interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+        activations: Vec<Vec<Wrapping<u16>>>,
+    }
+
+    impl VirtioDevice for TestDevice {
+        fn device_type(&self) -> u32 {
+            VirtioDeviceType::TYPE_RNG as u32
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &[16]
+        }
+
+        fn activate(
+            &mut self,
+            _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+            interrupt_cb: Arc<dyn VirtioInterrupt>,
+            queues: Vec<Queue>,
+            _queue_evts: Vec<EventFd>,
+        ) -> ActivateResult {
+            self.activations
+                .push(queues.iter().map(|q| q.next_avail).collect());
+            self.interrupt_cb = Some(interrupt_cb);
+            Ok(())
+        }
+
+        fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+            if self.resettable {
+                self.interrupt_cb.take()
+            } else {
+                None
+            }
+        }
+    }
+
+    fn activated_device(
+        resettable: bool,
+        mem: &GuestMemoryMmap,
+        guest_queue: &GuestQ,
+    ) -> (VirtioPciDevice, Arc<Mutex<TestDevice>>) {
+        let device = Arc::new(Mutex::new(TestDevice {
+            resettable,
+            interrupt_cb: None,
+            activations: Vec::new(),
+        }));
+        let interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>> =
+            Arc::new(TestInterruptManager {});
+        let mut pci_device = VirtioPciDevice::new(
+            "test".to_string(),
+            GuestMemoryAtomic::new(mem.clone()),
+            device.clone(),
+            2,
+            None,
+            &interrupt_manager,
+            None,
+            0,
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            VirtioPciIdentity::default(),
+        )
+        .unwrap();
+
+        // Nothing to reset until the device is activated.
+        pci_device.reset_device().unwrap();
+        assert!(device.lock().unwrap().activations.is_empty());
+
+        pci_device.queues[0] = guest_queue.create_queue();
+        pci_device.activate().unwrap();
+        pci_device.device_activated.store(true, Ordering::SeqCst);
+
+        (pci_device, device)
+    }
+
+    #[test]
+    fn test_reset_device() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let (mut pci_device, device) = activated_device(true, &mem, &guest_queue);
+
+        // The device is activated again right away, its queue resuming
+        // after the descriptors it used before getting stuck.
+        guest_queue.used.idx.set(3);
+        pci_device.reset_device().unwrap();
+        assert!(pci_device.device_activated.load(Ordering::SeqCst));
+        assert_eq!(
+            device.lock().unwrap().activations,
+            vec![vec![Wrapping(0)], vec![Wrapping(3)]]
+        );
+        assert_eq!(pci_device.queues[0].next_used, Wrapping(3));
+        assert_eq!(pci_device.common_config.driver_status, 0);
+    }
+
+    #[test]
+    fn test_reset_device_not_supported() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let (mut pci_device, device) = activated_device(false, &mem, &guest_queue);
+
+        assert!(matches!(
+            pci_device.reset_device(),
+            Err(crate::Error::ResetNotSupported)
+        ));
+        assert!(pci_device.device_activated.load(Ordering::SeqCst));
+        assert_eq!(device.lock().unwrap().activations.len(), 1);
+    }
+
+    #[test]
+    fn test_msix_vector_stats() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let (pci_device, device) = activated_device(true, &mem, &guest_queue);
+        let interrupt = device.lock().unwrap().interrupt_cb.clone().unwrap();
+        let mut queue = Queue::new(16);
+        queue.vector = 1;
+        assert!(pci_device.msix_vector_stats().is_empty());
+
+        // Nothing is counted while the vector is masked, the interrupt only
+        // being left pending.
+        let msix_config = pci_device.msix_config.clone().unwrap();
+        msix_config.lock().unwrap().set_msg_ctl(1 << 15);
+        interrupt
+            .trigger(&VirtioInterruptType::Queue, Some(&queue))
+            .unwrap();
+        assert!(pci_device.msix_vector_stats().is_empty());
+
+        let start_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        msix_config.lock().unwrap().write_table(0x1c, &[0, 0, 0, 0]);
+        interrupt
+            .trigger(&VirtioInterruptType::Queue, Some(&queue))
+            .unwrap();
+        interrupt
+            .trigger(&VirtioInterruptType::Queue, Some(&queue))
+            .unwrap();
+
+        let stats = pci_device.msix_vector_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].vector, 1);
+        assert_eq!(stats[0].count, 2);
+        // The coarse clock lags behind by a tick at most.
+        assert!(stats[0].last_trigger_us + 100_000 >= start_us);
+        assert!(
+            stats[0].last_trigger_us
+                <= SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_micros() as u64
+        );
+    }
+
+    #[test]
+    fn test_intx_fallback() {
+        let (msix_group, msix_triggers) = test_interrupt_group();
+        let (intx_group, intx_triggers) = test_interrupt_group();
+        let msix_config = Arc::new(Mutex::new(MsixConfig::new(2, msix_group.clone(), 0)));
+        let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let interrupt = VirtioInterruptPci {
+            msix: Some(VirtioInterruptMsix::new(
+                msix_config.clone(),
+                Arc::new(AtomicU16::new(1)),
+                msix_group,
+                Arc::new((0..2).map(|_| MsixVectorCounters::default()).collect()),
+            )),
+            intx: Some(VirtioInterruptIntx::new(
+                interrupt_status.clone(),
+                intx_group,
+            )),
+        };
+        let mut queue = Queue::new(16);
+        queue.vector = 0;
+
+        // MSI-X is disabled, the interrupts go through INTx and the ISR
+        // status tells what they are for
+        interrupt
+            .trigger(&VirtioInterruptType::Queue, Some(&queue))
+            .unwrap();
+        assert_eq!(intx_triggers.load(Ordering::SeqCst), 1);
+        assert_eq!(interrupt_status.load(Ordering::SeqCst), ISR_STATUS_QUEUE);
+        interrupt
+            .trigger(&VirtioInterruptType::Config, None)
+            .unwrap();
+        assert_eq!(intx_triggers.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            interrupt_status.swap(0, Ordering::SeqCst),
+            ISR_STATUS_QUEUE | ISR_STATUS_CONFIG
+        );
+        assert_eq!(msix_triggers.load(Ordering::SeqCst), 0);
+        assert!(interrupt
+            .notifier(&VirtioInterruptType::Queue, Some(&queue))
+            .is_none());
+
+        // once the guest enables MSI-X, the interrupts go through it
+        {
+            let mut msix_config = msix_config.lock().unwrap();
+            msix_config.set_msg_ctl(1 << 15);
+            msix_config.write_table(0xc, &[0, 0, 0, 0]);
+        }
+        interrupt
+            .trigger(&VirtioInterruptType::Queue, Some(&queue))
+            .unwrap();
+        assert_eq!(msix_triggers.load(Ordering::SeqCst), 1);
+        assert_eq!(intx_triggers.load(Ordering::SeqCst), 2);
+        assert_eq!(interrupt_status.load(Ordering::SeqCst), 0);
+
+        // and fall back to INTx again if it gets disabled
+        msix_config.lock().unwrap().set_msg_ctl(0);
+        interrupt
+            .trigger(&VirtioInterruptType::Queue, Some(&queue))
+            .unwrap();
+        assert_eq!(intx_triggers.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,528 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Devices whose datapath is handled by the vhost support of the host kernel,
+//! which directly processes the virtqueues from the guest memory.
+
+use super::{Descriptor, Queue};
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use libc::{c_int, EFD_NONBLOCK};
+use net_util::{OpenTapError, TapError};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use vfio_ioctls::get_host_address_range;
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_migration::protocol::{MemoryRange, MemoryRangeTable};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+
+pub mod net;
+
+pub use self::net::Net;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid available address.
+    AvailAddress,
+    /// Invalid descriptor table address.
+    DescriptorTableAddress,
+    /// Invalid used address.
+    UsedAddress,
+    /// Failed to open the vhost device.
+    VhostOpen(io::Error),
+    /// Set owner failed.
+    VhostSetOwner(io::Error),
+    /// Reset owner failed.
+    VhostResetOwner(io::Error),
+    /// Get features failed.
+    VhostGetFeatures(io::Error),
+    /// Set features failed.
+    VhostSetFeatures(io::Error),
+    /// The kernel can't log the guest memory it writes to.
+    VhostLogNotSupported,
+    /// Set log base failed.
+    VhostSetLogBase(io::Error),
+    /// The guest memory is made of more regions than the kernel accepts.
+    VhostTooManyMemoryRegions(usize),
+    /// Set mem table failed.
+    VhostSetMemTable(io::Error),
+    /// Set vring num failed.
+    VhostSetVringNum(io::Error),
+    /// Set vring addr failed.
+    VhostSetVringAddr(io::Error),
+    /// Set vring base failed.
+    VhostSetVringBase(io::Error),
+    /// Get vring base failed.
+    VhostGetVringBase(io::Error),
+    /// Set vring call failed.
+    VhostSetVringCall(io::Error),
+    /// Set vring kick failed.
+    VhostSetVringKick(io::Error),
+    /// Set backend failed.
+    VhostNetSetBackend(io::Error),
+    /// Failed to create vhost eventfd.
+    VhostIrqCreate(io::Error),
+    /// Failed to open taps.
+    OpenTap(OpenTapError),
+    /// Using existing tap.
+    TapError(TapError),
+    /// Failed to program the tap offloads.
+    TapSetOffload(TapError),
+    /// Failed to set the size of the virtio-net header on the tap.
+    TapSetVnetHdrSize(TapError),
+}
+type Result<T> = std::result::Result<T, Error>;
+
+// See include/uapi/linux/vhost.h in the kernel code.
+const VHOST_VIRTIO: u32 = 0xaf;
+// Feature asking the kernel to log all the guest memory it writes to.
+pub const VHOST_F_LOG_ALL: u64 = 26;
+// Flag asking the kernel to log the writes to the used ring of a vring.
+const VHOST_VRING_F_LOG: u32 = 0;
+// Size of the pages tracked by each bit of the dirty log.
+pub const VHOST_LOG_PAGE_SIZE: u64 = 0x1000;
+// Highest number of memory regions accepted by default by vhost, as set by
+// the max_mem_regions parameter of the vhost module.
+const VHOST_MAX_MEM_REGIONS: usize = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostVringState {
+    index: u32,
+    num: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostVringFile {
+    index: u32,
+    fd: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostVringAddr {
+    index: u32,
+    flags: u32,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostMemoryRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    flags_padding: u64,
+}
+
+// The header of struct vhost_memory, which is followed by the regions.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VhostMemoryHeader {
+    nregions: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct VhostMemory {
+    header: VhostMemoryHeader,
+    regions: [VhostMemoryRegion; VHOST_MAX_MEM_REGIONS],
+}
+
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST_VIRTIO, 0x01);
+ioctl_io_nr!(VHOST_RESET_OWNER, VHOST_VIRTIO, 0x02);
+ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST_VIRTIO, 0x03, VhostMemoryHeader);
+ioctl_iow_nr!(VHOST_SET_LOG_BASE, VHOST_VIRTIO, 0x04, u64);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST_VIRTIO, 0x10, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST_VIRTIO, 0x11, VhostVringAddr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST_VIRTIO, 0x12, VhostVringState);
+ioctl_iowr_nr!(VHOST_GET_VRING_BASE, VHOST_VIRTIO, 0x12, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST_VIRTIO, 0x20, VhostVringFile);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST_VIRTIO, 0x21, VhostVringFile);
+ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST_VIRTIO, 0x30, VhostVringFile);
+
+fn ioctl_result(ret: c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Handle on a vhost device from the host kernel, such as /dev/vhost-net.
+///
+/// Every handle is tied to its own set of virtqueues, which the kernel
+/// starts processing once they are fully described and a backend is set.
+pub struct VhostKernel {
+    file: File,
+}
+
+impl VhostKernel {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+            .open(path)
+            .map_err(Error::VhostOpen)?;
+
+        Ok(VhostKernel { file })
+    }
+
+    pub fn set_owner(&self) -> Result<()> {
+        // Safe because the ioctl doesn't take any argument and we check the
+        // return value.
+        ioctl_result(unsafe { ioctl(&self.file, VHOST_SET_OWNER()) }).map_err(Error::VhostSetOwner)
+    }
+
+    pub fn reset_owner(&self) -> Result<()> {
+        // Safe because the ioctl doesn't take any argument and we check the
+        // return value.
+        ioctl_result(unsafe { ioctl(&self.file, VHOST_RESET_OWNER()) })
+            .map_err(Error::VhostResetOwner)
+    }
+
+    pub fn get_features(&self) -> Result<u64> {
+        let mut features: u64 = 0;
+        // Safe because the kernel only writes the features, and we check the
+        // return value.
+        ioctl_result(unsafe {
+            ioctl_with_mut_ref(&self.file, VHOST_GET_FEATURES(), &mut features)
+        })
+        .map_err(Error::VhostGetFeatures)?;
+
+        Ok(features)
+    }
+
+    pub fn set_features(&self, features: u64) -> Result<()> {
+        // Safe because the kernel only reads the features, and we check the
+        // return value.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_FEATURES(), &features) })
+            .map_err(Error::VhostSetFeatures)
+    }
+
+    /// Describe the guest memory, for the kernel to translate the guest
+    /// addresses found in the virtqueues.
+    pub fn set_mem_table(&self, mem: &GuestMemoryMmap) -> Result<()> {
+        let memory = vhost_memory(mem)?;
+
+        // Safe because the kernel only reads the header and the number of
+        // regions it gives, and we check the return value.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_MEM_TABLE(), &*memory) })
+            .map_err(Error::VhostSetMemTable)
+    }
+
+    /// Give the kernel the bitmap it logs the guest memory it writes to in,
+    /// one bit for each VHOST_LOG_PAGE_SIZE page of guest memory.
+    pub fn set_log_base(&self, log: &[AtomicU64]) -> Result<()> {
+        let log_base = log.as_ptr() as u64;
+        // Safe because the kernel only reads the address, and we check the
+        // return value. The caller keeps the bitmap alive as long as the
+        // logging is enabled.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_LOG_BASE(), &log_base) })
+            .map_err(Error::VhostSetLogBase)
+    }
+
+    pub fn set_vring_num(&self, index: usize, num: u16) -> Result<()> {
+        let state = VhostVringState {
+            index: index as u32,
+            num: num.into(),
+        };
+        // Safe because the kernel only reads the vring state, and we check
+        // the return value.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_NUM(), &state) })
+            .map_err(Error::VhostSetVringNum)
+    }
+
+    /// Give the kernel the addresses of the virtqueue rings, translated into
+    /// the VMM address space. With `log`, the kernel also logs its writes to
+    /// the used ring in the bitmap given to set_log_base().
+    pub fn set_vring_addr(
+        &self,
+        index: usize,
+        mem: &GuestMemoryMmap,
+        queue: &Queue,
+        log: bool,
+    ) -> Result<()> {
+        let addr = vring_addr(index, mem, queue, log)?;
+
+        // Safe because the kernel only reads the vring addresses, and we
+        // check the return value.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_ADDR(), &addr) })
+            .map_err(Error::VhostSetVringAddr)
+    }
+
+    pub fn set_vring_base(&self, index: usize, base: u16) -> Result<()> {
+        let state = VhostVringState {
+            index: index as u32,
+            num: base.into(),
+        };
+        // Safe because the kernel only reads the vring state, and we check
+        // the return value.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_BASE(), &state) })
+            .map_err(Error::VhostSetVringBase)
+    }
+
+    /// Stop the processing of the virtqueue, returning the index of the next
+    /// available descriptor.
+    pub fn get_vring_base(&self, index: usize) -> Result<u16> {
+        let mut state = VhostVringState {
+            index: index as u32,
+            num: 0,
+        };
+        // Safe because the kernel only writes the vring state, and we check
+        // the return value.
+        ioctl_result(unsafe { ioctl_with_mut_ref(&self.file, VHOST_GET_VRING_BASE(), &mut state) })
+            .map_err(Error::VhostGetVringBase)?;
+
+        Ok(state.num as u16)
+    }
+
+    pub fn set_vring_call(&self, index: usize, fd: &EventFd) -> Result<()> {
+        self.set_vring_file(VHOST_SET_VRING_CALL(), index, fd.as_raw_fd())
+            .map_err(Error::VhostSetVringCall)
+    }
+
+    pub fn set_vring_kick(&self, index: usize, fd: &EventFd) -> Result<()> {
+        self.set_vring_file(VHOST_SET_VRING_KICK(), index, fd.as_raw_fd())
+            .map_err(Error::VhostSetVringKick)
+    }
+
+    /// Attach the virtqueue to the file backing it, such as a TAP, or
+    /// detach it when `fd` is None.
+    pub fn net_set_backend(&self, index: usize, fd: Option<RawFd>) -> Result<()> {
+        self.set_vring_file(VHOST_NET_SET_BACKEND(), index, fd.unwrap_or(-1))
+            .map_err(Error::VhostNetSetBackend)
+    }
+
+    fn set_vring_file(&self, request: libc::c_ulong, index: usize, fd: RawFd) -> io::Result<()> {
+        let file = VhostVringFile {
+            index: index as u32,
+            fd,
+        };
+        // Safe because the kernel only reads the vring file, and we check the
+        // return value.
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, request, &file) })
+    }
+}
+
+fn vhost_memory(mem: &GuestMemoryMmap) -> Result<Box<VhostMemory>> {
+    let mut memory = Box::new(VhostMemory {
+        header: VhostMemoryHeader::default(),
+        regions: [VhostMemoryRegion::default(); VHOST_MAX_MEM_REGIONS],
+    });
+
+    let num_regions = mem.num_regions();
+    if num_regions > VHOST_MAX_MEM_REGIONS {
+        return Err(Error::VhostTooManyMemoryRegions(num_regions));
+    }
+
+    for (region, vhost_region) in mem.iter().zip(memory.regions.iter_mut()) {
+        *vhost_region = VhostMemoryRegion {
+            guest_phys_addr: region.start_addr().raw_value(),
+            memory_size: region.len() as u64,
+            userspace_addr: region.as_ptr() as u64,
+            flags_padding: 0,
+        };
+    }
+    memory.header.nregions = num_regions as u32;
+
+    Ok(memory)
+}
+
+fn vring_addr(
+    index: usize,
+    mem: &GuestMemoryMmap,
+    queue: &Queue,
+    log: bool,
+) -> Result<VhostVringAddr> {
+    let actual_size: usize = queue.actual_size().try_into().unwrap();
+
+    Ok(VhostVringAddr {
+        index: index as u32,
+        flags: if log { 1 << VHOST_VRING_F_LOG } else { 0 },
+        desc_user_addr: get_host_address_range(
+            mem,
+            queue.desc_table,
+            actual_size * std::mem::size_of::<Descriptor>(),
+        )
+        .ok_or(Error::DescriptorTableAddress)? as u64,
+        // The used ring is {flags: u16; idx: u16; virtq_used_elem [{id: u16, len: u16}; actual_size]},
+        // i.e. 4 + (4 + 4) * actual_size.
+        used_user_addr: get_host_address_range(mem, queue.used_ring, 4 + actual_size * 8)
+            .ok_or(Error::UsedAddress)? as u64,
+        // The used ring is {flags: u16; idx: u16; elem [u16; actual_size]},
+        // i.e. 4 + (2) * actual_size.
+        avail_user_addr: get_host_address_range(mem, queue.avail_ring, 4 + actual_size * 2)
+            .ok_or(Error::AvailAddress)? as u64,
+        // The kernel logs its writes to the used ring by guest address, as
+        // it does for the writes to the buffers.
+        log_guest_addr: queue.used_ring.raw_value(),
+    })
+}
+
+/// Build the table of the guest memory ranges marked as dirty in the bitmap
+/// the kernel logs into, clearing the bitmap as it goes.
+pub fn dirty_log_range_table(log: &[AtomicU64]) -> MemoryRangeTable {
+    let mut table = MemoryRangeTable::default();
+    let mut entry: Option<MemoryRange> = None;
+    for (i, block) in log.iter().enumerate() {
+        let mut bits = block.swap(0, Ordering::SeqCst);
+        let block_addr = i as u64 * 64 * VHOST_LOG_PAGE_SIZE;
+        for bit in 0..64 {
+            if bits & 1 != 0 {
+                let gpa = block_addr + bit * VHOST_LOG_PAGE_SIZE;
+                match entry.as_mut() {
+                    Some(entry) if entry.gpa + entry.length == gpa => {
+                        entry.length += VHOST_LOG_PAGE_SIZE
+                    }
+                    _ => {
+                        if let Some(entry) = entry.take() {
+                            table.push(entry);
+                        }
+                        entry = Some(MemoryRange {
+                            gpa,
+                            length: VHOST_LOG_PAGE_SIZE,
+                        });
+                    }
+                }
+            }
+            bits >>= 1;
+        }
+    }
+    if let Some(entry) = entry {
+        table.push(entry);
+    }
+
+    table
+}
+
+/// Describe the virtqueues to the kernel, returning the eventfd the kernel
+/// signals for each of them when no interrupt can be directly injected into
+/// the guest, so that the VMM can relay it. The kernel picks up each of them
+/// from the next available descriptor of the queue, which isn't 0 when
+/// restoring the device.
+pub fn setup_vhost_kernel_vring(
+    vhost: &VhostKernel,
+    mem: &GuestMemoryMmap,
+    queues: Vec<Queue>,
+    queue_evts: &[EventFd],
+    virtio_interrupt: &Arc<dyn VirtioInterrupt>,
+) -> Result<Vec<(Option<EventFd>, Queue)>> {
+    vhost.set_mem_table(mem)?;
+
+    let mut interrupt_list = Vec::new();
+
+    for (queue_index, queue) in queues.into_iter().enumerate() {
+        vhost.set_vring_num(queue_index, queue.actual_size())?;
+        vhost.set_vring_addr(queue_index, mem, &queue, false)?;
+        vhost.set_vring_base(queue_index, queue.next_avail.0)?;
+
+        if let Some(eventfd) = virtio_interrupt.notifier(&VirtioInterruptType::Queue, Some(&queue))
+        {
+            vhost.set_vring_call(queue_index, &eventfd)?;
+            interrupt_list.push((None, queue));
+        } else {
+            let eventfd = EventFd::new(EFD_NONBLOCK).map_err(Error::VhostIrqCreate)?;
+            vhost.set_vring_call(queue_index, &eventfd)?;
+            interrupt_list.push((Some(eventfd), queue));
+        }
+
+        vhost.set_vring_kick(queue_index, &queue_evts[queue_index])?;
+    }
+
+    Ok(interrupt_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_vhost_memory() {
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10_0000),
+            (GuestAddress(0x100_0000), 0x20_0000),
+        ])
+        .unwrap();
+        let memory = vhost_memory(&mem).unwrap();
+        assert_eq!(memory.header.nregions, 2);
+        assert_eq!(memory.regions[1].guest_phys_addr, 0x100_0000);
+        assert_eq!(memory.regions[1].memory_size, 0x20_0000);
+        assert_eq!(
+            memory.regions[1].userspace_addr,
+            mem.get_host_address(GuestAddress(0x100_0000)).unwrap() as u64
+        );
+        assert_eq!(memory.regions[2].memory_size, 0);
+    }
+
+    #[test]
+    fn test_vring_addr() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let mut queue = Queue::new(256);
+        queue.desc_table = GuestAddress(0x1000);
+        queue.avail_ring = GuestAddress(0x2000);
+        queue.used_ring = GuestAddress(0x3000);
+
+        let addr = vring_addr(1, &mem, &queue, false).unwrap();
+        assert_eq!(addr.index, 1);
+        assert_eq!(addr.flags, 0);
+        assert_eq!(
+            addr.used_user_addr,
+            mem.get_host_address(GuestAddress(0x3000)).unwrap() as u64
+        );
+
+        // Logging the writes to the used ring, by guest address.
+        let addr = vring_addr(1, &mem, &queue, true).unwrap();
+        assert_eq!(addr.flags, 1 << VHOST_VRING_F_LOG);
+        assert_eq!(addr.log_guest_addr, 0x3000);
+
+        // A used ring beyond the guest memory.
+        queue.used_ring = GuestAddress(0x10_0000 - 8);
+        assert!(matches!(
+            vring_addr(1, &mem, &queue, false),
+            Err(Error::UsedAddress)
+        ));
+    }
+
+    #[test]
+    fn test_dirty_log_range_table() {
+        let log: Vec<AtomicU64> = vec![
+            AtomicU64::new(0b1110),
+            AtomicU64::new(1 << 63),
+            AtomicU64::new(1),
+            AtomicU64::new(0),
+            AtomicU64::new(1 << 1),
+        ];
+
+        let table = dirty_log_range_table(&log);
+        let ranges: Vec<(u64, u64)> = table.regions().iter().map(|r| (r.gpa, r.length)).collect();
+        assert_eq!(
+            ranges,
+            vec![
+                (VHOST_LOG_PAGE_SIZE, 3 * VHOST_LOG_PAGE_SIZE),
+                // Pages 127 and 128 are contiguous across two blocks.
+                (127 * VHOST_LOG_PAGE_SIZE, 2 * VHOST_LOG_PAGE_SIZE),
+                (257 * VHOST_LOG_PAGE_SIZE, VHOST_LOG_PAGE_SIZE),
+            ]
+        );
+
+        // The bitmap is cleared as it's read.
+        assert!(log.iter().all(|b| b.load(Ordering::SeqCst) == 0));
+        assert!(dirty_log_range_table(&log).regions().is_empty());
+    }
+}
@@ -0,0 +1,676 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use super::super::net_util::{
+    build_net_config_space, CtrlVirtio, NetCtrlEpollHandler, VirtioNetConfig,
+};
+use super::super::vhost_user::handler::{VhostUserEpollConfig, VhostUserEpollHandler};
+use super::super::{
+    ActivateError, ActivateResult, Queue, VirtioCommon, VirtioDevice, VirtioDeviceType,
+};
+use super::{
+    dirty_log_range_table, setup_vhost_kernel_vring, Error, Result, VhostKernel, VHOST_F_LOG_ALL,
+    VHOST_LOG_PAGE_SIZE,
+};
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::{PauseBarrier, VirtioInterrupt};
+use anyhow::anyhow;
+use net_util::{open_tap, virtio_features_to_tap_offload, MacAddr, Tap};
+use seccomp::{SeccompAction, SeccompFilter};
+use std::net::Ipv4Addr;
+use std::num::Wrapping;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use std::vec::Vec;
+use vhost_rs::vhost_user::VhostUserMasterReqHandler;
+use virtio_bindings::bindings::virtio_net;
+use virtio_bindings::bindings::virtio_ring;
+use vm_memory::{
+    Address, ByteValued, GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestMemoryMmap,
+};
+use vm_migration::protocol::MemoryRangeTable;
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable, VersionedState,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+
+// The virtio-net header, with or without the num_buffers field, which is
+// exchanged with the TAP as the kernel doesn't handle it on its own.
+const VIRTIO_NET_HDR_LEN: i32 = 10;
+const VIRTIO_NET_HDR_MRG_RXBUF_LEN: i32 = 12;
+
+// Features the kernel implements on the virtqueues, the offloads being
+// handled by the TAP instead.
+const VHOST_NET_BACKEND_FEATURES: u64 = 1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF
+    | 1 << virtio_net::VIRTIO_F_NOTIFY_ON_EMPTY
+    | 1 << virtio_net::VIRTIO_F_VERSION_1
+    | 1 << virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+
+struct SlaveReqHandler {}
+impl VhostUserMasterReqHandler for SlaveReqHandler {}
+
+#[derive(Serialize, Deserialize)]
+pub struct VhostKernelNetState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    pub config: VirtioNetConfig,
+    // Next available descriptor the kernel was at for each RX/TX queue, as
+    // the transport doesn't know about the progress the kernel made.
+    pub vring_bases: Vec<u16>,
+}
+
+impl VersionedState for VhostKernelNetState {
+    const VERSION: u16 = 1;
+}
+
+// Size of the virtio-net header exchanged with the TAP for the features
+// acked by the guest.
+fn vnet_hdr_len(acked_features: u64) -> i32 {
+    if acked_features
+        & (1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF | 1 << virtio_net::VIRTIO_F_VERSION_1)
+        != 0
+    {
+        VIRTIO_NET_HDR_MRG_RXBUF_LEN
+    } else {
+        VIRTIO_NET_HDR_LEN
+    }
+}
+
+pub struct Net {
+    common: VirtioCommon,
+    id: String,
+    // One vhost-net handle and one TAP queue for each RX/TX queue pair.
+    vhost_net: Vec<VhostKernel>,
+    taps: Vec<Tap>,
+    backend_features: u64,
+    log_supported: bool,
+    owned: bool,
+    // RX/TX queues handed to the kernel, along with where the kernel
+    // should pick them up from when restoring the device.
+    vrings: Vec<Queue>,
+    vring_bases: Option<Vec<u16>>,
+    // Bitmap the kernel logs the guest memory it writes to in, while the
+    // memory is migrated.
+    dirty_log: Option<Box<[AtomicU64]>>,
+    config: VirtioNetConfig,
+    ctrl_queue_epoll_thread: Option<thread::JoinHandle<()>>,
+    seccomp_action: SeccompAction,
+}
+
+impl Net {
+    /// Create a new vhost-net device with the given TAP interface.
+    pub fn new_with_tap(
+        id: String,
+        taps: Vec<Tap>,
+        guest_mac: MacAddr,
+        queue_size: u16,
+        seccomp_action: SeccompAction,
+    ) -> Result<Self> {
+        let mut vhost_net = Vec::with_capacity(taps.len());
+        for _ in taps.iter() {
+            vhost_net.push(VhostKernel::open(VHOST_NET_PATH)?);
+        }
+
+        let kernel_features = vhost_net[0].get_features()?;
+        let backend_features = kernel_features & VHOST_NET_BACKEND_FEATURES;
+        let log_supported = kernel_features & (1 << VHOST_F_LOG_ALL) != 0;
+
+        let mut avail_features = 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
+            | 1 << virtio_net::VIRTIO_NET_F_CSUM
+            | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4
+            | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO6
+            | 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO
+            | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO4
+            | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO6
+            | 1 << virtio_net::VIRTIO_NET_F_HOST_UFO
+            | backend_features;
+
+        avail_features |= 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ;
+        let num_queues = taps.len() * 2;
+        let queue_num = num_queues + 1;
+
+        let mut config = VirtioNetConfig::default();
+        build_net_config_space(&mut config, guest_mac, num_queues, &mut avail_features);
+
+        Ok(Net {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::TYPE_NET as u32,
+                avail_features,
+                queue_sizes: vec![queue_size; queue_num],
+                paused_sync: Some(Arc::new(PauseBarrier::new((num_queues / 2) + 1))),
+                min_queues: 2,
+                ..Default::default()
+            },
+            id,
+            vhost_net,
+            taps,
+            backend_features,
+            log_supported,
+            owned: false,
+            vrings: Vec::new(),
+            vring_bases: None,
+            dirty_log: None,
+            config,
+            ctrl_queue_epoll_thread: None,
+            seccomp_action,
+        })
+    }
+
+    /// Create a new vhost-net device with the given IP address and netmask.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: String,
+        if_name: Option<&str>,
+        ip_addr: Option<Ipv4Addr>,
+        netmask: Option<Ipv4Addr>,
+        guest_mac: MacAddr,
+        host_mac: &mut Option<MacAddr>,
+        num_queues: usize,
+        queue_size: u16,
+        seccomp_action: SeccompAction,
+    ) -> Result<Self> {
+        let taps = open_tap(if_name, ip_addr, netmask, host_mac, num_queues / 2, None)
+            .map_err(Error::OpenTap)?;
+
+        Self::new_with_tap(id, taps, guest_mac, queue_size, seccomp_action)
+    }
+
+    pub fn from_tap_fds(
+        id: String,
+        fds: &[RawFd],
+        guest_mac: MacAddr,
+        queue_size: u16,
+        seccomp_action: SeccompAction,
+    ) -> Result<Self> {
+        let mut taps: Vec<Tap> = Vec::new();
+        let num_queue_pairs = fds.len();
+
+        for fd in fds.iter() {
+            let tap = Tap::from_tap_fd(*fd, num_queue_pairs).map_err(Error::TapError)?;
+            taps.push(tap);
+        }
+
+        Self::new_with_tap(id, taps, guest_mac, queue_size, seccomp_action)
+    }
+
+    // Program the TAPs and get the kernel to process the virtqueues, from now
+    // on moving the frames between the guest and the TAPs on its own.
+    fn setup_vhost_net(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        mut queues: Vec<Queue>,
+        queue_evts: &[EventFd],
+        interrupt_cb: &Arc<dyn VirtioInterrupt>,
+    ) -> Result<Vec<(Option<EventFd>, Queue)>> {
+        let acked_features = self.common.acked_features;
+        let vnet_hdr_len = vnet_hdr_len(acked_features);
+        let tap_offloads = virtio_features_to_tap_offload(acked_features);
+
+        // Pick up where the kernel was when the device was snapshotted.
+        if let Some(vring_bases) = self.vring_bases.take() {
+            for (queue, base) in queues.iter_mut().zip(vring_bases.into_iter()) {
+                queue.next_avail = Wrapping(base);
+            }
+        }
+        self.vrings = queues.clone();
+
+        let mut interrupt_list = Vec::new();
+        for (i, (vhost_net, tap)) in self.vhost_net.iter().zip(self.taps.iter()).enumerate() {
+            // The virtio-net header is passed through as is between the guest
+            // and the TAP, which means the TAP must only produce frames the
+            // guest negotiated.
+            tap.set_offload(tap_offloads)
+                .map_err(Error::TapSetOffload)?;
+            tap.set_vnet_hdr_size(vnet_hdr_len)
+                .map_err(Error::TapSetVnetHdrSize)?;
+
+            vhost_net.set_owner()?;
+            vhost_net.set_features(acked_features & self.backend_features)?;
+
+            let queue_pair = queues.drain(..2).collect();
+            interrupt_list.extend(setup_vhost_kernel_vring(
+                vhost_net,
+                mem,
+                queue_pair,
+                &queue_evts[i * 2..i * 2 + 2],
+                interrupt_cb,
+            )?);
+
+            for queue_index in 0..2 {
+                vhost_net.net_set_backend(queue_index, Some(tap.as_raw_fd()))?;
+            }
+        }
+        self.owned = true;
+
+        Ok(interrupt_list)
+    }
+
+    // Attach the TAPs to the virtqueues or, when `attach` is false, detach
+    // them so that the kernel stops processing the virtqueues.
+    fn set_vhost_net_backends(&self, attach: bool) -> Result<()> {
+        if !self.owned {
+            return Ok(());
+        }
+
+        for (vhost_net, tap) in self.vhost_net.iter().zip(self.taps.iter()) {
+            for queue_index in 0..2 {
+                let fd = if attach { Some(tap.as_raw_fd()) } else { None };
+                vhost_net.net_set_backend(queue_index, fd)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Next available descriptor of each RX/TX queue, which must only be
+    // read once the TAPs are detached.
+    fn vring_bases(&self) -> Result<Vec<u16>> {
+        if !self.owned {
+            return Ok(self.vrings.iter().map(|q| q.next_avail.0).collect());
+        }
+
+        let mut vring_bases = Vec::new();
+        for vhost_net in self.vhost_net.iter() {
+            for queue_index in 0..2 {
+                vring_bases.push(vhost_net.get_vring_base(queue_index)?);
+            }
+        }
+
+        Ok(vring_bases)
+    }
+
+    // Get the kernel to log the guest memory it writes to, or stop it from
+    // doing so when `log` is None.
+    fn set_vhost_net_log(&self, mem: &GuestMemoryMmap, log: Option<&[AtomicU64]>) -> Result<()> {
+        let mut features = self.common.acked_features & self.backend_features;
+        if log.is_some() {
+            features |= 1 << VHOST_F_LOG_ALL;
+        }
+
+        for (i, vhost_net) in self.vhost_net.iter().enumerate() {
+            if let Some(log) = log {
+                vhost_net.set_log_base(log)?;
+            }
+            vhost_net.set_features(features)?;
+            for queue_index in 0..2 {
+                vhost_net.set_vring_addr(
+                    queue_index,
+                    mem,
+                    &self.vrings[i * 2 + queue_index],
+                    log.is_some(),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn reset_vhost_net(&mut self) -> Result<()> {
+        if !self.owned {
+            return Ok(());
+        }
+
+        for vhost_net in self.vhost_net.iter() {
+            for queue_index in 0..2 {
+                // Detach the TAP, and stop the vrings.
+                vhost_net.net_set_backend(queue_index, None)?;
+                vhost_net.get_vring_base(queue_index)?;
+            }
+
+            // Reset the owner.
+            vhost_net.reset_owner()?;
+        }
+        self.owned = false;
+        self.dirty_log = None;
+
+        Ok(())
+    }
+}
+
+impl Drop for Net {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(self.config.as_slice(), offset, data);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
+
+        let queue_num = queues.len();
+        if self
+            .common
+            .feature_acked(virtio_net::VIRTIO_NET_F_CTRL_VQ.into())
+            && queue_num % 2 != 0
+        {
+            let cvq_queue = queues.remove(queue_num - 1);
+            let cvq_queue_evt = queue_evts.remove(queue_num - 1);
+
+            let kill_evt = self
+                .common
+                .kill_evt
+                .as_ref()
+                .unwrap()
+                .try_clone()
+                .map_err(|e| {
+                    error!("failed to clone kill_evt eventfd: {}", e);
+                    ActivateError::BadActivate
+                })?;
+            let pause_evt = self
+                .common
+                .pause_evt
+                .as_ref()
+                .unwrap()
+                .try_clone()
+                .map_err(|e| {
+                    error!("failed to clone pause_evt eventfd: {}", e);
+                    ActivateError::BadActivate
+                })?;
+
+            let mut ctrl_handler = NetCtrlEpollHandler {
+                mem: mem.clone(),
+                kill_evt,
+                pause_evt,
+                ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt, None, None),
+                epoll_fd: 0,
+            };
+
+            let paused = self.common.paused.clone();
+            // Let's update the barrier as we need 1 for each RX/TX pair +
+            // 1 for the control queue + 1 for the main thread signalling
+            // the pause.
+            self.common.paused_sync = Some(Arc::new(PauseBarrier::new(self.taps.len() + 2)));
+            let paused_sync = self.common.paused_sync.clone();
+            let virtio_vhost_net_ctl_seccomp_filter =
+                get_seccomp_filter(&self.seccomp_action, Thread::VirtioVhostNetCtl)
+                    .map_err(ActivateError::CreateSeccompFilter)?;
+            thread::Builder::new()
+                .name(format!("{}_ctrl", self.id))
+                .spawn(move || {
+                    if let Err(e) = SeccompFilter::apply(virtio_vhost_net_ctl_seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                    } else if let Err(e) = ctrl_handler.run_ctrl(paused, paused_sync.unwrap()) {
+                        error!("Error running worker: {:?}", e);
+                    }
+                })
+                .map(|thread| self.ctrl_queue_epoll_thread = Some(thread))
+                .map_err(|e| {
+                    error!("failed to clone queue EventFd: {}", e);
+                    ActivateError::BadActivate
+                })?;
+        }
+
+        let mut interrupt_list = self
+            .setup_vhost_net(&mem.memory(), queues, &queue_evts, &interrupt_cb)
+            .map_err(ActivateError::VhostKernelNetSetup)?;
+
+        // The kernel directly processes the virtqueues, leaving the VMM to
+        // only relay the interrupts it can't directly inject into the guest.
+        let mut epoll_threads = Vec::new();
+        for i in 0..interrupt_list.len() / 2 {
+            let mut interrupt_list_sub: Vec<(Option<EventFd>, Queue)> = Vec::with_capacity(2);
+            interrupt_list_sub.push(interrupt_list.remove(0));
+            interrupt_list_sub.push(interrupt_list.remove(0));
+
+            let kill_evt = self
+                .common
+                .kill_evt
+                .as_ref()
+                .unwrap()
+                .try_clone()
+                .map_err(|e| {
+                    error!("failed to clone kill_evt eventfd: {}", e);
+                    ActivateError::BadActivate
+                })?;
+            let pause_evt = self
+                .common
+                .pause_evt
+                .as_ref()
+                .unwrap()
+                .try_clone()
+                .map_err(|e| {
+                    error!("failed to clone pause_evt eventfd: {}", e);
+                    ActivateError::BadActivate
+                })?;
+
+            let mut handler = VhostUserEpollHandler::<SlaveReqHandler>::new(VhostUserEpollConfig {
+                interrupt_cb: interrupt_cb.clone(),
+                kill_evt,
+                pause_evt,
+                vu_interrupt_list: interrupt_list_sub,
+                slave_req_handler: None,
+                mem: mem.clone(),
+                busy_poll: None,
+            });
+
+            let paused = self.common.paused.clone();
+            let paused_sync = self.common.paused_sync.clone();
+            let virtio_vhost_net_seccomp_filter =
+                get_seccomp_filter(&self.seccomp_action, Thread::VirtioVhostNet)
+                    .map_err(ActivateError::CreateSeccompFilter)?;
+            thread::Builder::new()
+                .name(format!("{}_qp{}", self.id.clone(), i))
+                .spawn(move || {
+                    if let Err(e) = SeccompFilter::apply(virtio_vhost_net_seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                    } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
+                        error!("Error running worker: {:?}", e);
+                    }
+                })
+                .map(|thread| epoll_threads.push(thread))
+                .map_err(|e| {
+                    error!("failed to clone queue EventFd: {}", e);
+                    ActivateError::BadActivate
+                })?;
+        }
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        if let Err(e) = self.reset_vhost_net() {
+            error!("Failed to reset vhost-net: {:?}", e);
+            return None;
+        }
+
+        self.common.reset()
+    }
+
+    fn start_dirty_log(&mut self, mem: &GuestMemoryMmap) -> std::result::Result<(), crate::Error> {
+        if !self.owned {
+            return Ok(());
+        }
+        if !self.log_supported {
+            return Err(crate::Error::VhostKernelDirtyLog(
+                Error::VhostLogNotSupported,
+            ));
+        }
+
+        let pages = mem.last_addr().raw_value() / VHOST_LOG_PAGE_SIZE + 1;
+        let log: Box<[AtomicU64]> = (0..(pages + 63) / 64).map(|_| AtomicU64::new(0)).collect();
+        self.set_vhost_net_log(mem, Some(&log))
+            .map_err(crate::Error::VhostKernelDirtyLog)?;
+        self.dirty_log = Some(log);
+
+        Ok(())
+    }
+
+    fn stop_dirty_log(&mut self, mem: &GuestMemoryMmap) -> std::result::Result<(), crate::Error> {
+        if !self.owned || self.dirty_log.is_none() {
+            return Ok(());
+        }
+
+        self.set_vhost_net_log(mem, None)
+            .map_err(crate::Error::VhostKernelDirtyLog)?;
+        self.dirty_log = None;
+
+        Ok(())
+    }
+
+    fn dirty_log(&mut self) -> std::result::Result<MemoryRangeTable, crate::Error> {
+        Ok(self
+            .dirty_log
+            .as_ref()
+            .map(|log| dirty_log_range_table(log))
+            .unwrap_or_default())
+    }
+
+    fn update_memory(&mut self, mem: &GuestMemoryMmap) -> std::result::Result<(), crate::Error> {
+        // The memory table is given to the kernel on activation otherwise.
+        if !self.owned {
+            return Ok(());
+        }
+
+        for vhost_net in self.vhost_net.iter() {
+            vhost_net
+                .set_mem_table(mem)
+                .map_err(crate::Error::VhostKernelUpdateMemory)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Pausable for Net {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        // The kernel processes the virtqueues on its own, and only stops
+        // once the TAPs are detached.
+        self.set_vhost_net_backends(false).map_err(|e| {
+            MigratableError::Pause(anyhow!("Error detaching the vhost-net backends: {:?}", e))
+        })?;
+        self.common.pause()
+    }
+
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()?;
+
+        if let Some(ctrl_queue_epoll_thread) = &self.ctrl_queue_epoll_thread {
+            ctrl_queue_epoll_thread.thread().unpark();
+        }
+
+        self.set_vhost_net_backends(true).map_err(|e| {
+            MigratableError::Resume(anyhow!("Error attaching the vhost-net backends: {:?}", e))
+        })
+    }
+}
+
+impl Snapshottable for Net {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let vring_bases = self.vring_bases().map_err(|e| {
+            MigratableError::Snapshot(anyhow!("Error getting the vhost-net vring bases: {:?}", e))
+        })?;
+        let state = VhostKernelNetState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            config: self.config,
+            vring_bases,
+        };
+
+        let mut net_snapshot = Snapshot::new(self.id.as_str());
+        net_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &state,
+        )?);
+
+        Ok(net_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        if let Some(net_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            let state = net_section.to_versioned_state::<VhostKernelNetState>()?;
+
+            self.common.avail_features = state.avail_features;
+            self.common.acked_features = state.acked_features;
+            self.config = state.config;
+            self.vring_bases = Some(state.vring_bases);
+            return Ok(());
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find vhost-net snapshot section"
+        )))
+    }
+}
+impl Transportable for Net {}
+impl Migratable for Net {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vnet_hdr_len() {
+        assert_eq!(vnet_hdr_len(0), VIRTIO_NET_HDR_LEN);
+        assert_eq!(
+            vnet_hdr_len(1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF),
+            VIRTIO_NET_HDR_MRG_RXBUF_LEN
+        );
+        assert_eq!(
+            vnet_hdr_len(1 << virtio_net::VIRTIO_F_VERSION_1),
+            VIRTIO_NET_HDR_MRG_RXBUF_LEN
+        );
+    }
+
+    #[test]
+    fn test_vhost_kernel_net_state() {
+        let state = VhostKernelNetState {
+            avail_features: 1 << virtio_net::VIRTIO_F_VERSION_1,
+            acked_features: 1 << virtio_net::VIRTIO_F_VERSION_1,
+            config: VirtioNetConfig::default(),
+            vring_bases: vec![12, 34],
+        };
+        let section =
+            SnapshotDataSection::new_from_versioned_state("_net0-section", &state).unwrap();
+        let restored = section.to_versioned_state::<VhostKernelNetState>().unwrap();
+        assert_eq!(restored.acked_features, state.acked_features);
+        assert_eq!(restored.vring_bases, vec![12, 34]);
+    }
+}
@@ -8,14 +8,15 @@ use super::handler::*;
 use super::vu_common_ctrl::*;
 use super::{Error, Result};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::VirtioInterrupt;
+use crate::{PauseBarrier, VirtioInterrupt};
 use block_util::VirtioBlockConfig;
 use seccomp::{SeccompAction, SeccompFilter};
 use std::mem;
 use std::os::unix::io::AsRawFd;
 use std::result;
-use std::sync::{Arc, Barrier};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use vhost_rs::vhost_user::message::VhostUserConfigFlags;
 use vhost_rs::vhost_user::message::VHOST_USER_CONFIG_OFFSET;
@@ -37,6 +38,7 @@ pub struct Blk {
     vhost_user_blk: Master,
     config: VirtioBlockConfig,
     seccomp_action: SeccompAction,
+    activate_timeout: Option<Duration>,
 }
 
 impl Blk {
@@ -134,7 +136,7 @@ impl Blk {
                 queue_sizes: vec![vu_cfg.queue_size; vu_cfg.num_queues],
                 avail_features,
                 acked_features,
-                paused_sync: Some(Arc::new(Barrier::new(vu_cfg.num_queues + 1))),
+                paused_sync: Some(Arc::new(PauseBarrier::new(vu_cfg.num_queues + 1))),
                 min_queues: 1,
                 ..Default::default()
             },
@@ -142,6 +144,7 @@ impl Blk {
             vhost_user_blk,
             config,
             seccomp_action,
+            activate_timeout: vu_cfg.activate_timeout,
         })
     }
 }
@@ -206,15 +209,25 @@ impl VirtioDevice for Blk {
     ) -> ActivateResult {
         self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
 
-        let mut vu_interrupt_list = setup_vhost_user(
-            &mut self.vhost_user_blk,
-            &mem.memory(),
-            queues,
-            queue_evts,
-            &interrupt_cb,
-            self.common.acked_features,
-        )
-        .map_err(ActivateError::VhostUserBlkSetup)?;
+        let acked_features = self.common.acked_features;
+        let setup = with_reply_timeout(&mut self.vhost_user_blk, self.activate_timeout, |vu| {
+            setup_vhost_user(
+                vu,
+                &mem.memory(),
+                queues,
+                queue_evts,
+                &interrupt_cb,
+                acked_features,
+            )
+        });
+        let mut vu_interrupt_list = match setup {
+            Ok(vu_interrupt_list) => vu_interrupt_list,
+            Err(e) => {
+                // No thread was started yet.
+                self.common.reset();
+                return Err(ActivateError::VhostUserBlkSetup(e));
+            }
+        };
 
         let mut epoll_threads = Vec::new();
         for i in 0..vu_interrupt_list.len() {
@@ -248,6 +261,8 @@ impl VirtioDevice for Blk {
                 pause_evt,
                 vu_interrupt_list: interrupt_list_sub,
                 slave_req_handler: None,
+                mem: mem.clone(),
+                busy_poll: None,
             });
 
             let paused = self.common.paused.clone();
@@ -309,6 +324,10 @@ impl Pausable for Blk {
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         self.common.resume()
     }
@@ -6,16 +6,17 @@ use super::{Error, Result};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::vhost_user::handler::{VhostUserEpollConfig, VhostUserEpollHandler};
 use crate::{
-    ActivateError, ActivateResult, Queue, UserspaceMapping, VirtioCommon, VirtioDevice,
-    VirtioDeviceType, VirtioInterrupt, VirtioSharedMemoryList, VIRTIO_F_VERSION_1,
+    ActivateError, ActivateResult, PauseBarrier, Queue, UserspaceMapping, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioSharedMemoryList, VIRTIO_F_VERSION_1,
 };
 use libc::{self, c_void, off64_t, pread64, pwrite64};
 use seccomp::{SeccompAction, SeccompFilter};
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
-use std::sync::{Arc, Barrier, Mutex};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 use vhost_rs::vhost_user::message::{
     VhostUserFSSlaveMsg, VhostUserFSSlaveMsgFlags, VhostUserProtocolFeatures,
     VhostUserVirtioFeatures, VHOST_USER_FS_SLAVE_ENTRIES,
@@ -33,6 +34,9 @@ use vmm_sys_util::eventfd::EventFd;
 
 const NUM_QUEUE_OFFSET: usize = 1;
 
+/// Longest tag a virtio-fs device can be mounted with, in bytes.
+pub const MAX_FS_TAG_LEN: usize = 36;
+
 struct SlaveReqHandler {
     cache_offset: GuestAddress,
     cache_size: u64,
@@ -249,14 +253,14 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
 struct VirtioFsConfig {
-    tag: [u8; 36],
+    tag: [u8; MAX_FS_TAG_LEN],
     num_request_queues: u32,
 }
 
 impl Default for VirtioFsConfig {
     fn default() -> Self {
         VirtioFsConfig {
-            tag: [0; 36],
+            tag: [0; MAX_FS_TAG_LEN],
             num_request_queues: 0,
         }
     }
@@ -287,6 +291,10 @@ impl Fs {
         cache: Option<(VirtioSharedMemoryList, MmapRegion)>,
         seccomp_action: SeccompAction,
     ) -> Result<Fs> {
+        if tag.is_empty() || tag.len() > MAX_FS_TAG_LEN {
+            return Err(Error::InvalidFsTag(tag.to_owned()));
+        }
+
         let mut slave_req_support = false;
 
         // Calculate the actual number of queues needed.
@@ -351,7 +359,7 @@ impl Fs {
                 avail_features,
                 acked_features,
                 queue_sizes: vec![queue_size; num_queues],
-                paused_sync: Some(Arc::new(Barrier::new(2))),
+                paused_sync: Some(Arc::new(PauseBarrier::new(2))),
                 min_queues: NUM_QUEUE_OFFSET as u16,
                 ..Default::default()
             },
@@ -442,7 +450,7 @@ impl VirtioDevice for Fs {
                     cache_offset: cache.0.addr,
                     cache_size: cache.0.len,
                     mmap_cache_addr: cache.0.host_addr,
-                    mem,
+                    mem: mem.clone(),
                 }));
 
                 let req_handler = MasterReqHandler::new(vu_master_req_handler).map_err(|e| {
@@ -467,6 +475,8 @@ impl VirtioDevice for Fs {
             kill_evt,
             pause_evt,
             slave_req_handler,
+            mem,
+            busy_poll: None,
         });
 
         let paused = self.common.paused.clone();
@@ -564,6 +574,10 @@ impl Pausable for Fs {
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         self.common.resume()
     }
@@ -576,3 +590,27 @@ impl Snapshottable for Fs {
 }
 impl Transportable for Fs {}
 impl Migratable for Fs {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fs_invalid_tag() {
+        // The tag is checked before connecting to the backend.
+        for tag in &["".to_owned(), "x".repeat(MAX_FS_TAG_LEN + 1)] {
+            assert!(matches!(
+                Fs::new(
+                    "_fs0".to_owned(),
+                    "/nonexistent/virtiofsd.sock",
+                    tag,
+                    1,
+                    1024,
+                    None,
+                    SeccompAction::Trap,
+                ),
+                Err(Error::InvalidFsTag(t)) if &t == tag
+            ));
+        }
+    }
+}
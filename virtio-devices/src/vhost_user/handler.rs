@@ -8,17 +8,18 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use super::super::{
-    EpollHelper, EpollHelperError, EpollHelperHandler, Queue, VirtioInterruptType,
+    BusyPoll, EpollHelper, EpollHelperError, EpollHelperHandler, Queue, VirtioInterruptType,
     EPOLL_HELPER_EVENT_LAST,
 };
 use super::{Error, Result};
 use vmm_sys_util::eventfd::EventFd;
 
-use crate::VirtioInterrupt;
+use crate::{PauseBarrier, VirtioInterrupt};
 use std::os::unix::io::AsRawFd;
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Barrier};
+use std::sync::Arc;
 use vhost_rs::vhost_user::{MasterReqHandler, VhostUserMasterReqHandler};
+use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 
 /// Collection of common parameters required by vhost-user devices while
 /// call Epoll handler.
@@ -27,18 +28,26 @@ use vhost_rs::vhost_user::{MasterReqHandler, VhostUserMasterReqHandler};
 /// * `interrupt_cb` interrupt for virtqueue change.
 /// * `kill_evt` - EventFd used to kill the vhost-user device.
 /// * `vu_interrupt_list` - virtqueue and EventFd to signal when buffer used.
+/// * `mem` - guest memory the used rings are polled in.
+/// * `busy_poll` - how long to poll for the next events before blocking.
 pub struct VhostUserEpollConfig<S: VhostUserMasterReqHandler> {
     pub interrupt_cb: Arc<dyn VirtioInterrupt>,
     pub kill_evt: EventFd,
     pub pause_evt: EventFd,
     pub vu_interrupt_list: Vec<(Option<EventFd>, Queue)>,
     pub slave_req_handler: Option<MasterReqHandler<S>>,
+    pub mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    pub busy_poll: Option<BusyPoll>,
 }
 
 pub struct VhostUserEpollHandler<S: VhostUserMasterReqHandler> {
     vu_epoll_cfg: VhostUserEpollConfig<S>,
     queue_evt_start_idx: u16,
     slave_evt_idx: u16,
+    // Used index of each queue the guest was last signalled for while busy
+    // polling, for the chains the backend used to be signalled once, whether
+    // they are found polling or the backend signals them.
+    signalled_used: Vec<u16>,
 }
 
 impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
@@ -53,13 +62,34 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
         let queue_evt_start_idx = EPOLL_HELPER_EVENT_LAST + 1;
         let slave_evt_idx = queue_evt_start_idx + vu_epoll_cfg.vu_interrupt_list.len() as u16;
 
+        let signalled_used = vu_epoll_cfg
+            .vu_interrupt_list
+            .iter()
+            .map(|(_, queue)| queue.next_used.0)
+            .collect();
         VhostUserEpollHandler {
             vu_epoll_cfg,
             queue_evt_start_idx,
             slave_evt_idx,
+            signalled_used,
         }
     }
 
+    // Records the used index of the queue if it changed since the guest
+    // was last signalled for it, returning whether it did.
+    fn update_used_index(&mut self, idx: usize) -> Result<bool> {
+        let used_index = self.vu_epoll_cfg.vu_interrupt_list[idx]
+            .1
+            .used_index_from_memory(&self.vu_epoll_cfg.mem.memory())
+            .map_err(Error::QueueRingIndex)?;
+        if self.signalled_used[idx] == used_index {
+            return Ok(false);
+        }
+        self.signalled_used[idx] = used_index;
+
+        Ok(true)
+    }
+
     fn signal_used_queue(&self, queue: &Queue) -> Result<()> {
         self.vu_epoll_cfg
             .interrupt_cb
@@ -70,10 +100,11 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
     pub fn run(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> std::result::Result<(), EpollHelperError> {
         let mut helper =
             EpollHelper::new(&self.vu_epoll_cfg.kill_evt, &self.vu_epoll_cfg.pause_evt)?;
+        helper.set_busy_poll(self.vu_epoll_cfg.busy_poll.clone());
 
         for (i, vhost_user_interrupt) in self.vu_epoll_cfg.vu_interrupt_list.iter().enumerate() {
             if let Some(eventfd) = &vhost_user_interrupt.0 {
@@ -102,6 +133,17 @@ impl<S: VhostUserMasterReqHandler> EpollHelperHandler for VhostUserEpollHandler<
                         error!("Failed to read queue: {:?}", e);
                         return true;
                     }
+                    if self.vu_epoll_cfg.busy_poll.is_some() {
+                        match self.update_used_index(idx) {
+                            Ok(true) => {}
+                            // Already signalled while polling.
+                            Ok(false) => return false,
+                            Err(e) => {
+                                error!("Failed to read the used ring: {:?}", e);
+                                return true;
+                            }
+                        }
+                    }
                     if let Err(e) =
                         self.signal_used_queue(&self.vu_epoll_cfg.vu_interrupt_list[idx].1)
                     {
@@ -126,4 +168,106 @@ impl<S: VhostUserMasterReqHandler> EpollHelperHandler for VhostUserEpollHandler<
 
         false
     }
+
+    // The guest is signalled for the descriptor chains the backend used
+    // without waiting for the backend to signal them.
+    fn poll_queues(&mut self) -> Option<bool> {
+        let mut found = false;
+        for idx in 0..self.vu_epoll_cfg.vu_interrupt_list.len() {
+            if self.vu_epoll_cfg.vu_interrupt_list[idx].0.is_none() {
+                continue;
+            }
+            match self.update_used_index(idx) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    error!("Failed to read the used ring: {:?}", e);
+                    return None;
+                }
+            }
+            if let Err(e) = self.signal_used_queue(&self.vu_epoll_cfg.vu_interrupt_list[idx].1) {
+                error!("Failed to signal used queue: {:?}", e);
+                return None;
+            }
+            found = true;
+        }
+
+        Some(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BusyPollCounters;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+
+    struct NoopReqHandler {}
+    impl VhostUserMasterReqHandler for NoopReqHandler {}
+
+    #[derive(Default)]
+    struct CountingInterrupt {
+        triggered: AtomicUsize,
+    }
+
+    impl VirtioInterrupt for CountingInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> std::result::Result<(), std::io::Error> {
+            self.triggered.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_busy_poll_used_ring() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, 16);
+        let interrupt = Arc::new(CountingInterrupt::default());
+        let call_evt = EventFd::new(0).unwrap();
+        let kill_evt = EventFd::new(0).unwrap();
+        let pause_evt = EventFd::new(0).unwrap();
+
+        let mut handler = VhostUserEpollHandler::<NoopReqHandler>::new(VhostUserEpollConfig {
+            interrupt_cb: interrupt.clone(),
+            kill_evt: kill_evt.try_clone().unwrap(),
+            pause_evt: pause_evt.try_clone().unwrap(),
+            vu_interrupt_list: vec![(
+                Some(call_evt.try_clone().unwrap()),
+                guest_queue.create_queue(),
+            )],
+            slave_req_handler: None,
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            busy_poll: Some(BusyPoll {
+                duration: Duration::from_micros(50),
+                counters: BusyPollCounters::default(),
+            }),
+        });
+        let mut helper = EpollHelper::new(&kill_evt, &pause_evt).unwrap();
+        let call_event =
+            epoll::Event::new(epoll::Events::EPOLLIN, handler.queue_evt_start_idx.into());
+
+        // Nothing was used yet.
+        assert_eq!(handler.poll_queues(), Some(false));
+
+        // The guest is signalled once for the descriptor chain the backend
+        // used, found while polling before the backend signals it.
+        guest_queue.used.idx.set(1);
+        assert_eq!(handler.poll_queues(), Some(true));
+        call_evt.write(1).unwrap();
+        assert!(!handler.handle_event(&mut helper, &call_event));
+        assert_eq!(interrupt.triggered.load(Ordering::SeqCst), 1);
+
+        // The backend signalling first, it isn't found while polling.
+        guest_queue.used.idx.set(2);
+        call_evt.write(1).unwrap();
+        assert!(!handler.handle_event(&mut helper, &call_event));
+        assert_eq!(handler.poll_queues(), Some(false));
+        assert_eq!(interrupt.triggered.load(Ordering::SeqCst), 2);
+    }
 }
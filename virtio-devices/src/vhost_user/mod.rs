@@ -8,19 +8,20 @@ extern crate virtio_bindings;
 extern crate vm_memory;
 
 use std::io;
+use std::time::Duration;
 use vhost_rs::Error as VhostError;
 use vm_memory::Error as MmapError;
 
 pub mod blk;
 pub mod fs;
-mod handler;
+pub(crate) mod handler;
 pub mod net;
 pub mod vu_common_ctrl;
 
 pub use self::blk::Blk;
 pub use self::fs::*;
-pub use self::net::Net;
-pub use self::vu_common_ctrl::VhostUserConfig;
+pub use self::net::{Net, NetBackend};
+pub use self::vu_common_ctrl::{NetOffloads, VhostUserConfig};
 
 #[derive(Debug)]
 pub enum Error {
@@ -88,5 +89,19 @@ pub enum Error {
     UsedAddress,
     /// Invalid features provided from vhost-user backend
     InvalidFeatures,
+    /// Setting the timeout of the socket failed.
+    VhostUserSetTimeout(io::Error),
+    /// The backend didn't answer a request in time.
+    VhostUserTimeout(Duration),
+    /// The backend taken over was set up for another device configuration.
+    VhostUserBackendMismatch,
+    /// Number of active queues not even, or out of the device's queues.
+    InvalidNumQueues(usize),
+    /// Failed to read the index of a ring from the guest memory.
+    QueueRingIndex(vm_virtio::queue::Error),
+    /// The backend didn't send the frames pending on a TX queue in time.
+    PendingTxFrames(usize),
+    /// The virtio-fs tag is empty or doesn't fit the device configuration.
+    InvalidFsTag(String),
 }
 type Result<T> = std::result::Result<T, Error>;
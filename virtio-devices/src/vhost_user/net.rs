@@ -5,46 +5,185 @@ use super::super::net_util::{
     build_net_config_space, CtrlVirtio, NetCtrlEpollHandler, VirtioNetConfig,
 };
 use super::super::{
-    ActivateError, ActivateResult, Queue, VirtioCommon, VirtioDevice, VirtioDeviceType,
+    ActivateError, ActivateResult, BusyPoll, BusyPollCounters, Queue, VirtioCommon, VirtioDevice,
+    VirtioDeviceType,
 };
 use super::handler::*;
 use super::vu_common_ctrl::*;
 use super::{Error, Result};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::VirtioInterrupt;
-use net_util::MacAddr;
+use crate::{PauseBarrier, VirtioInterrupt};
+use anyhow::anyhow;
+use net_util::{MacAddr, MAC_ADDR_LEN, VHOST_USER_NET_ANTI_SPOOF_OFFSET};
 use seccomp::{SeccompAction, SeccompFilter};
+use std::collections::HashMap;
+use std::num::Wrapping;
+use std::ops::Range;
 use std::os::unix::io::AsRawFd;
 use std::result;
-use std::sync::{Arc, Barrier};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
-use vhost_rs::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
+use vhost_rs::vhost_user::message::{
+    VhostUserConfigFlags, VhostUserProtocolFeatures, VhostUserVirtioFeatures,
+    VHOST_USER_CONFIG_OFFSET,
+};
 use vhost_rs::vhost_user::{Master, VhostUserMaster, VhostUserMasterReqHandler};
 use vhost_rs::VhostBackend;
 use virtio_bindings::bindings::virtio_net;
 use virtio_bindings::bindings::virtio_ring;
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
-use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
+    Transportable, VersionedState,
+};
 use vmm_sys_util::eventfd::EventFd;
 
 const DEFAULT_QUEUE_NUMBER: usize = 2;
 
+// How long the backend is given to send the frames pending on the TX queues
+// being disabled, unless the device has an activation timeout, and how often
+// they are checked.
+const TX_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+const TX_DRAIN_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Serialize, Deserialize)]
+pub struct VhostUserNetState {
+    pub active_queues: usize,
+}
+
+impl VersionedState for VhostUserNetState {
+    const VERSION: u16 = 1;
+}
+
+// Offloads of each direction, as logged when the device is activated.
+const HOST_OFFLOADS: [(&str, u32); 5] = [
+    ("csum", virtio_net::VIRTIO_NET_F_CSUM),
+    ("tso4", virtio_net::VIRTIO_NET_F_HOST_TSO4),
+    ("tso6", virtio_net::VIRTIO_NET_F_HOST_TSO6),
+    ("ecn", virtio_net::VIRTIO_NET_F_HOST_ECN),
+    ("ufo", virtio_net::VIRTIO_NET_F_HOST_UFO),
+];
+const GUEST_OFFLOADS: [(&str, u32); 5] = [
+    ("csum", virtio_net::VIRTIO_NET_F_GUEST_CSUM),
+    ("tso4", virtio_net::VIRTIO_NET_F_GUEST_TSO4),
+    ("tso6", virtio_net::VIRTIO_NET_F_GUEST_TSO6),
+    ("ecn", virtio_net::VIRTIO_NET_F_GUEST_ECN),
+    ("ufo", virtio_net::VIRTIO_NET_F_GUEST_UFO),
+];
+
+fn enabled_offloads(features: u64, offloads: &[(&str, u32)]) -> String {
+    let enabled: Vec<&str> = offloads
+        .iter()
+        .filter(|(_, feature)| features & (1 << feature) != 0)
+        .map(|(name, _)| *name)
+        .collect();
+    if enabled.is_empty() {
+        "none".to_string()
+    } else {
+        enabled.join(",")
+    }
+}
+
+// Asks the backend to drop the frames sent from another MAC address than
+// the one of the device, returning whether it confirmed by reporting the
+// address it filters on. Backends unaware of the offset reject the write,
+// or don't report the address.
+fn set_anti_spoof(vhost_user_net: &mut Master, mac_addr: &MacAddr) -> bool {
+    let offset = VHOST_USER_CONFIG_OFFSET + VHOST_USER_NET_ANTI_SPOOF_OFFSET;
+    if let Err(e) =
+        vhost_user_net.set_config(offset, VhostUserConfigFlags::WRITABLE, mac_addr.get_bytes())
+    {
+        debug!("Failed writing the anti-spoofing MAC address: {:?}", e);
+        return false;
+    }
+
+    match vhost_user_net.get_config(
+        offset,
+        MAC_ADDR_LEN as u32,
+        VhostUserConfigFlags::WRITABLE,
+        &[0u8; MAC_ADDR_LEN],
+    ) {
+        Ok((_, filtered)) => filtered.as_slice() == mac_addr.get_bytes(),
+        Err(e) => {
+            debug!("Failed reading the anti-spoofing MAC address: {:?}", e);
+            false
+        }
+    }
+}
+
 struct SlaveReqHandler {}
 impl VhostUserMasterReqHandler for SlaveReqHandler {}
 
+// Waits for the backend to use the descriptor chains the guest made
+// available on the TX queues among queue_indexes, which would be stuck there
+// once their vrings are disabled.
+fn wait_tx_queues_used(
+    mem: &GuestMemoryMmap,
+    vrings: &[Queue],
+    queue_indexes: Range<usize>,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    // The TX queue of each pair follows its RX queue.
+    for queue_index in queue_indexes.filter(|i| i % 2 == 1) {
+        let vring = &vrings[queue_index];
+        while vring
+            .used_index_from_memory(mem)
+            .map_err(Error::QueueRingIndex)?
+            != vring
+                .avail_index_from_memory(mem)
+                .map_err(Error::QueueRingIndex)?
+        {
+            if Instant::now() >= deadline {
+                return Err(Error::PendingTxFrames(queue_index));
+            }
+            thread::sleep(TX_DRAIN_INTERVAL);
+        }
+    }
+
+    Ok(())
+}
+
+/// Connection to a vhost-user-net backend, along with what was negotiated
+/// with it, which the device replacing the one of a rebooting guest takes
+/// over instead of connecting to the backend again.
+pub struct NetBackend {
+    vhost_user_net: Master,
+    mac_addr: MacAddr,
+    vu_cfg: VhostUserConfig,
+    avail_features: u64,
+    backend_features: u64,
+    protocol_features: VhostUserProtocolFeatures,
+    queue_size: u16,
+    config: VirtioNetConfig,
+}
+
 pub struct Net {
     common: VirtioCommon,
     id: String,
     vhost_user_net: Master,
+    mac_addr: MacAddr,
+    vu_cfg: VhostUserConfig,
     backend_features: u64,
+    protocol_features: VhostUserProtocolFeatures,
     config: VirtioNetConfig,
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<()>>,
     seccomp_action: SeccompAction,
+    // The connection was handed over to another device, and mustn't be
+    // closed along with this one.
+    backend_taken: bool,
+    // Data queues the backend processes, the vrings of the others being
+    // kept disabled whatever the number of queue pairs the guest enabled.
+    active_queues: usize,
+    // Memory and data queues handed to the backend once activated, for the
+    // frames pending on a TX queue to be waited for before disabling it.
+    vrings: Option<(GuestMemoryAtomic<GuestMemoryMmap>, Vec<Queue>)>,
+    busy_poll: Option<BusyPoll>,
 }
 
 impl Net {
-    /// Create a new vhost-user-net device
     /// Create a new vhost-user-net device
     pub fn new(
         id: String,
@@ -52,8 +191,8 @@ impl Net {
         vu_cfg: VhostUserConfig,
         seccomp_action: SeccompAction,
     ) -> Result<Net> {
-        let mut vhost_user_net = Master::connect(&vu_cfg.socket, vu_cfg.num_queues as u64)
-            .map_err(Error::VhostUserCreateMaster)?;
+        let mut vhost_user_net =
+            connect_vhost_user(&vu_cfg.socket, vu_cfg.num_queues, vu_cfg.connect_timeout)?;
 
         // Filling device and vring features VMM supports.
         let mut avail_features = 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
@@ -82,16 +221,17 @@ impl Net {
             .get_features()
             .map_err(Error::VhostUserGetFeatures)?;
         avail_features &= backend_features;
+        // Offloads can be turned off to work around a backend which offers
+        // them without handling them properly.
+        avail_features &= !vu_cfg.offloads.disabled_features();
         // Set features back is required by the vhost crate mechanism, since the
         // later vhost call will check if features is filled in master before execution.
         vhost_user_net
             .set_features(avail_features)
             .map_err(Error::VhostUserSetFeatures)?;
 
-        let protocol_features;
-        let mut acked_features = 0;
+        let mut protocol_features;
         if avail_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
-            acked_features |= VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
             protocol_features = vhost_user_net
                 .get_protocol_features()
                 .map_err(Error::VhostUserGetProtocolFeatures)?;
@@ -99,11 +239,36 @@ impl Net {
             return Err(Error::VhostUserProtocolNotSupport);
         }
 
+        // Replies to the requests tell which queue sizes the backend
+        // supports, when it offers to send them.
+        let mut wanted_protocol_features = VhostUserProtocolFeatures::MQ;
+        if protocol_features.contains(VhostUserProtocolFeatures::REPLY_ACK) {
+            wanted_protocol_features |= VhostUserProtocolFeatures::REPLY_ACK;
+        }
+        // The MAC address the frames sent by the guest must come from is
+        // written to the configuration space of the backend.
+        if vu_cfg.anti_spoof {
+            wanted_protocol_features |= VhostUserProtocolFeatures::CONFIG;
+        }
+        protocol_features &= wanted_protocol_features;
+        if !protocol_features.is_empty() {
+            vhost_user_net
+                .set_protocol_features(protocol_features)
+                .map_err(Error::VhostUserSetProtocolFeatures)?;
+        }
+
+        if vu_cfg.anti_spoof
+            && !(protocol_features.contains(VhostUserProtocolFeatures::CONFIG)
+                && set_anti_spoof(&mut vhost_user_net, &mac_addr))
+        {
+            warn!(
+                "vhost-user-net backend can't drop the frames spoofing MAC address {}",
+                mac_addr
+            );
+        }
+
         let max_queue_number =
             if protocol_features.bits() & VhostUserProtocolFeatures::MQ.bits() != 0 {
-                vhost_user_net
-                    .set_protocol_features(protocol_features & VhostUserProtocolFeatures::MQ)
-                    .map_err(Error::VhostUserSetProtocolFeatures)?;
                 match vhost_user_net.get_queue_num() {
                     Ok(qn) => qn,
                     Err(_) => DEFAULT_QUEUE_NUMBER as u64,
@@ -117,8 +282,19 @@ impl Net {
             return Err(Error::BadQueueNum);
         }
 
+        let queue_size = negotiate_queue_size(
+            &mut vhost_user_net,
+            vu_cfg.queue_size,
+            protocol_features.contains(VhostUserProtocolFeatures::REPLY_ACK),
+        )?;
+        if queue_size < vu_cfg.queue_size {
+            warn!(
+                "vhost-user-net backend doesn't support queues of {} entries, using {} instead",
+                vu_cfg.queue_size, queue_size
+            );
+        }
+
         avail_features |= 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ;
-        let queue_num = vu_cfg.num_queues + 1;
 
         let mut config = VirtioNetConfig::default();
         build_net_config_space(
@@ -136,24 +312,184 @@ impl Net {
                 .map_err(Error::VhostUserSetVringBase)?;
         }
 
-        Ok(Net {
+        let backend = NetBackend {
+            vhost_user_net,
+            mac_addr,
+            vu_cfg,
+            avail_features,
+            backend_features,
+            protocol_features,
+            queue_size,
+            config,
+        };
+
+        Ok(Self::with_backend(id, backend, seccomp_action))
+    }
+
+    /// Creates the device replacing the one of a rebooting guest, taking
+    /// over its connection to the backend. It fails if the device isn't
+    /// configured the same, or if the backend doesn't answer as it did once
+    /// its vrings were stopped, for a new connection to be made instead.
+    pub fn from_backend(
+        id: String,
+        mac_addr: MacAddr,
+        vu_cfg: VhostUserConfig,
+        mut backend: NetBackend,
+        seccomp_action: SeccompAction,
+    ) -> Result<Net> {
+        if backend.mac_addr != mac_addr || backend.vu_cfg != vu_cfg {
+            return Err(Error::VhostUserBackendMismatch);
+        }
+
+        let backend_features = backend.backend_features;
+        let protocol_features = backend.protocol_features;
+        let queue_size = backend.queue_size;
+        with_reply_timeout(&mut backend.vhost_user_net, vu_cfg.activate_timeout, |vu| {
+            if vu.get_features().map_err(Error::VhostUserGetFeatures)? != backend_features
+                || !vu
+                    .get_protocol_features()
+                    .map_err(Error::VhostUserGetProtocolFeatures)?
+                    .contains(protocol_features)
+            {
+                return Err(Error::InvalidFeatures);
+            }
+            // The stopped vrings must be accepted again as they were set
+            // up, which backends offering replies confirm.
+            for i in 0..vu_cfg.num_queues {
+                vu.set_vring_num(i, queue_size)
+                    .map_err(Error::VhostUserSetVringNum)?;
+                vu.set_vring_base(i, 0)
+                    .map_err(Error::VhostUserSetVringBase)?;
+            }
+            Ok(())
+        })?;
+
+        Ok(Self::with_backend(id, backend, seccomp_action))
+    }
+
+    fn with_backend(id: String, backend: NetBackend, seccomp_action: SeccompAction) -> Net {
+        let num_queues = backend.vu_cfg.num_queues;
+        Net {
             id,
             common: VirtioCommon {
                 device_type: VirtioDeviceType::TYPE_NET as u32,
-                queue_sizes: vec![vu_cfg.queue_size; queue_num],
-                avail_features,
-                acked_features,
-                paused_sync: Some(Arc::new(Barrier::new((vu_cfg.num_queues / 2) + 1))),
+                queue_sizes: vec![backend.queue_size; num_queues + 1],
+                avail_features: backend.avail_features,
+                acked_features: VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits(),
+                paused_sync: Some(Arc::new(PauseBarrier::new((num_queues / 2) + 1))),
                 min_queues: 2,
                 ..Default::default()
             },
-            vhost_user_net,
-            backend_features,
-            config,
+            vhost_user_net: backend.vhost_user_net,
+            mac_addr: backend.mac_addr,
+            vu_cfg: backend.vu_cfg,
+            backend_features: backend.backend_features,
+            protocol_features: backend.protocol_features,
+            config: backend.config,
             ctrl_queue_epoll_thread: None,
             seccomp_action,
+            backend_taken: false,
+            active_queues: num_queues,
+            vrings: None,
+            busy_poll: None,
+        }
+    }
+
+    /// Has the threads relaying the interrupts of the backend poll for them
+    /// for the given duration after relaying some, before blocking.
+    pub fn set_busy_poll(&mut self, duration: Option<Duration>) {
+        self.busy_poll = duration.map(|duration| BusyPoll {
+            duration,
+            counters: BusyPollCounters::default(),
+        });
+    }
+
+    /// Sets the number of data queues the backend processes, the vrings of
+    /// the queues added or removed being enabled or disabled right away if
+    /// the device is activated. The frames pending on the TX queues removed
+    /// are sent first. The number holds across the resets of the device, and
+    /// must be even and no larger than its number of queues.
+    pub fn set_active_queues(&mut self, active_queues: usize) -> Result<()> {
+        if active_queues < 2 || active_queues % 2 != 0 || active_queues > self.vu_cfg.num_queues {
+            return Err(Error::InvalidNumQueues(active_queues));
+        }
+
+        if let Some((mem, vrings)) = &self.vrings {
+            let (queue_indexes, enable) = if active_queues > self.active_queues {
+                (self.active_queues..active_queues, true)
+            } else {
+                wait_tx_queues_used(
+                    &mem.memory(),
+                    vrings,
+                    active_queues..self.active_queues,
+                    self.vu_cfg.activate_timeout.unwrap_or(TX_DRAIN_TIMEOUT),
+                )?;
+                (active_queues..self.active_queues, false)
+            };
+            with_reply_timeout(
+                &mut self.vhost_user_net,
+                self.vu_cfg.activate_timeout,
+                |vu| enable_vhost_user_vrings(vu, queue_indexes, enable),
+            )?;
+        }
+        self.active_queues = active_queues;
+
+        Ok(())
+    }
+
+    /// Hands the connection to the backend over to the device replacing
+    /// this one when the guest reboots. The vrings of an activated device are
+    /// stopped, as on a reset, but the backend keeps its owner and features.
+    /// Returns None if the backend can't stop the vrings.
+    pub fn take_backend(&mut self) -> Option<NetBackend> {
+        if self.backend_taken {
+            return None;
+        }
+
+        if self.common.interrupt_cb.is_some() {
+            // We first must resume the virtio thread if it was paused.
+            if self.common.pause_evt.take().is_some() {
+                self.common.resume().ok()?;
+            }
+
+            if let Err(e) =
+                stop_vhost_user_vrings(&mut self.vhost_user_net, self.common.queue_sizes.len())
+            {
+                warn!("{}: Failed to stop the vhost-user vrings: {:?}", self.id, e);
+                return None;
+            }
+
+            if let Some(kill_evt) = self.common.kill_evt.take() {
+                // Ignore the result because there is nothing we can do about it.
+                let _ = kill_evt.write(1);
+            }
+            self.common.interrupt_cb = None;
+        }
+        self.vrings = None;
+        self.backend_taken = true;
+
+        Some(NetBackend {
+            vhost_user_net: self.vhost_user_net.clone(),
+            mac_addr: self.mac_addr,
+            vu_cfg: self.vu_cfg.clone(),
+            avail_features: self.common.avail_features,
+            backend_features: self.backend_features,
+            protocol_features: self.protocol_features,
+            queue_size: self.common.queue_sizes[0],
+            config: self.config,
         })
     }
+
+    // Stops the threads a failed activation started. The backend, which
+    // may not be answering anymore, is left alone.
+    fn abort_activation(&mut self) {
+        self.common.reset();
+        if let Some(thread) = self.ctrl_queue_epoll_thread.take() {
+            if let Err(e) = thread.join() {
+                error!("Error joining thread: {:?}", e);
+            }
+        }
+    }
 }
 
 impl Drop for Net {
@@ -231,7 +567,7 @@ impl VirtioDevice for Net {
                 mem: mem.clone(),
                 kill_evt,
                 pause_evt,
-                ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt),
+                ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt, None, None),
                 epoll_fd: 0,
             };
 
@@ -239,7 +575,7 @@ impl VirtioDevice for Net {
             // Let's update the barrier as we need 1 for each RX/TX pair +
             // 1 for the control queue + 1 for the main thread signalling
             // the pause.
-            self.common.paused_sync = Some(Arc::new(Barrier::new((queue_num / 2) + 2)));
+            self.common.paused_sync = Some(Arc::new(PauseBarrier::new((queue_num / 2) + 2)));
             let paused_sync = self.common.paused_sync.clone();
             let virtio_vhost_net_ctl_seccomp_filter =
                 get_seccomp_filter(&self.seccomp_action, Thread::VirtioVhostNetCtl)
@@ -260,15 +596,39 @@ impl VirtioDevice for Net {
                 })?;
         }
 
-        let mut vu_interrupt_list = setup_vhost_user(
+        let acked_features = self.common.acked_features & self.backend_features;
+        info!(
+            "{}: host offloads {}, guest offloads {}",
+            self.id,
+            enabled_offloads(acked_features, &HOST_OFFLOADS),
+            enabled_offloads(acked_features, &GUEST_OFFLOADS)
+        );
+        let inactive_queues = self.active_queues..self.vu_cfg.num_queues;
+        let vrings = queues.clone();
+        let setup = with_reply_timeout(
             &mut self.vhost_user_net,
-            &mem.memory(),
-            queues,
-            queue_evts,
-            &interrupt_cb,
-            self.common.acked_features & self.backend_features,
-        )
-        .map_err(ActivateError::VhostUserNetSetup)?;
+            self.vu_cfg.activate_timeout,
+            |vu| {
+                let vu_interrupt_list = setup_vhost_user(
+                    vu,
+                    &mem.memory(),
+                    queues,
+                    queue_evts,
+                    &interrupt_cb,
+                    acked_features,
+                )?;
+                enable_vhost_user_vrings(vu, inactive_queues, false)?;
+                Ok(vu_interrupt_list)
+            },
+        );
+        let mut vu_interrupt_list = match setup {
+            Ok(vu_interrupt_list) => vu_interrupt_list,
+            Err(e) => {
+                self.abort_activation();
+                return Err(ActivateError::VhostUserNetSetup(e));
+            }
+        };
+        self.vrings = Some((mem.clone(), vrings));
 
         let mut epoll_threads = Vec::new();
         for i in 0..vu_interrupt_list.len() / 2 {
@@ -303,6 +663,8 @@ impl VirtioDevice for Net {
                 pause_evt,
                 vu_interrupt_list: interrupt_list_sub,
                 slave_req_handler: None,
+                mem: mem.clone(),
+                busy_poll: self.busy_poll.clone(),
             });
 
             let paused = self.common.paused.clone();
@@ -341,6 +703,7 @@ impl VirtioDevice for Net {
             error!("Failed to reset vhost-user daemon: {:?}", e);
             return None;
         }
+        self.vrings = None;
 
         if let Some(kill_evt) = self.common.kill_evt.take() {
             // Ignore the result because there is nothing we can do about it.
@@ -352,12 +715,21 @@ impl VirtioDevice for Net {
     }
 
     fn shutdown(&mut self) {
-        let _ = unsafe { libc::close(self.vhost_user_net.as_raw_fd()) };
+        if !self.backend_taken {
+            let _ = unsafe { libc::close(self.vhost_user_net.as_raw_fd()) };
+        }
     }
 
     fn update_memory(&mut self, mem: &GuestMemoryMmap) -> std::result::Result<(), crate::Error> {
         update_mem_table(&mut self.vhost_user_net, mem).map_err(crate::Error::VhostUserUpdateMemory)
     }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        let busy_poll = self.busy_poll.as_ref()?;
+        let mut counters = HashMap::new();
+        busy_poll.counters.report(&mut counters);
+        Some(counters)
+    }
 }
 
 impl Pausable for Net {
@@ -365,6 +737,10 @@ impl Pausable for Net {
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         self.common.resume()?;
 
@@ -379,6 +755,436 @@ impl Snapshottable for Net {
     fn id(&self) -> String {
         self.id.clone()
     }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let state = VhostUserNetState {
+            active_queues: self.active_queues,
+        };
+
+        let mut net_snapshot = Snapshot::new(self.id.as_str());
+        net_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &state,
+        )?);
+
+        Ok(net_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        if let Some(net_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id)) {
+            let state = net_section.to_versioned_state::<VhostUserNetState>()?;
+            if state.active_queues > self.vu_cfg.num_queues {
+                return Err(MigratableError::Restore(anyhow!(
+                    "{} active queues out of the {} queues of vhost-user-net device {}",
+                    state.active_queues,
+                    self.vu_cfg.num_queues,
+                    self.id
+                )));
+            }
+
+            // The vrings past the active queues are disabled when the
+            // restored device is activated.
+            self.active_queues = state.active_queues;
+            return Ok(());
+        }
+
+        Err(MigratableError::Restore(anyhow!(
+            "Could not find vhost-user-net snapshot section"
+        )))
+    }
 }
 impl Transportable for Net {}
 impl Migratable for Net {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vmm_sys_util::tempdir::TempDir;
+
+    const GET_FEATURES: u32 = 1;
+    const SET_VRING_NUM: u32 = 8;
+    const GET_PROTOCOL_FEATURES: u32 = 15;
+    const GET_QUEUE_NUM: u32 = 17;
+    const SET_VRING_ENABLE: u32 = 18;
+    const GET_CONFIG: u32 = 24;
+    const SET_CONFIG: u32 = 25;
+    const VERSION: u32 = 0x1;
+    const REPLY: u32 = 0x4;
+    const NEED_REPLY: u32 = 0x8;
+
+    fn le_u32(bytes: &[u8]) -> u32 {
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    // What the fake backend offers, which can change while it runs.
+    struct FakeBackendOffer {
+        features: AtomicU64,
+        max_queue_size: AtomicU32,
+    }
+
+    impl FakeBackendOffer {
+        fn new(max_queue_size: u32) -> Arc<Self> {
+            Arc::new(FakeBackendOffer {
+                features: AtomicU64::new(
+                    VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+                        | 1 << virtio_net::VIRTIO_F_VERSION_1,
+                ),
+                max_queue_size: AtomicU32::new(max_queue_size),
+            })
+        }
+    }
+
+    // What the fake backend was sent: the sizes of the vrings, the offsets
+    // of the configuration writes, and the vrings enabled or disabled.
+    #[derive(Default)]
+    struct FakeBackendLog {
+        vring_nums: Vec<u32>,
+        config_writes: Vec<u32>,
+        vring_enables: Vec<(u32, u32)>,
+    }
+
+    // A vhost-user-net backend handling the requests made when the device
+    // is created or takes the backend over, which rejects the vrings of
+    // more than max_queue_size entries when asked for a reply, and supports
+    // up to 4 queues. Unless it filters on the anti-spoofing MAC address,
+    // reading it returns zeroes.
+    fn fake_backend(
+        listener: UnixListener,
+        protocol_features: VhostUserProtocolFeatures,
+        offer: Arc<FakeBackendOffer>,
+        anti_spoof: bool,
+    ) -> thread::JoinHandle<FakeBackendLog> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut log = FakeBackendLog::default();
+            let mut filtered = [0u8; MAC_ADDR_LEN];
+            loop {
+                let mut hdr = [0u8; 12];
+                if stream.read_exact(&mut hdr).is_err() {
+                    return log;
+                }
+                let request = le_u32(&hdr[0..4]);
+                let flags = le_u32(&hdr[4..8]);
+                let mut body = vec![0u8; le_u32(&hdr[8..12]) as usize];
+                stream.read_exact(&mut body).unwrap();
+
+                let need_reply = flags & NEED_REPLY != 0;
+                // The configuration requests start with its offset, size
+                // and flags.
+                if request == GET_CONFIG {
+                    let mut msg = Vec::new();
+                    msg.extend_from_slice(&request.to_le_bytes());
+                    msg.extend_from_slice(&(VERSION | REPLY).to_le_bytes());
+                    msg.extend_from_slice(&((12 + MAC_ADDR_LEN) as u32).to_le_bytes());
+                    msg.extend_from_slice(&body[0..12]);
+                    msg.extend_from_slice(&filtered);
+                    stream.write_all(&msg).unwrap();
+                    continue;
+                } else if request == SET_CONFIG {
+                    let offset = le_u32(&body[0..4]);
+                    log.config_writes.push(offset);
+                    if anti_spoof
+                        && offset == VHOST_USER_CONFIG_OFFSET + VHOST_USER_NET_ANTI_SPOOF_OFFSET
+                    {
+                        filtered.copy_from_slice(&body[12..12 + MAC_ADDR_LEN]);
+                    }
+                }
+
+                let reply = match request {
+                    GET_FEATURES => Some(offer.features.load(Ordering::SeqCst)),
+                    GET_PROTOCOL_FEATURES => Some(protocol_features.bits()),
+                    GET_QUEUE_NUM => Some(4),
+                    SET_VRING_NUM => {
+                        let num = le_u32(&body[4..8]);
+                        log.vring_nums.push(num);
+                        if need_reply {
+                            Some((num > offer.max_queue_size.load(Ordering::SeqCst)) as u64)
+                        } else {
+                            None
+                        }
+                    }
+                    SET_VRING_ENABLE => {
+                        log.vring_enables
+                            .push((le_u32(&body[0..4]), le_u32(&body[4..8])));
+                        if need_reply {
+                            Some(0)
+                        } else {
+                            None
+                        }
+                    }
+                    _ if need_reply => Some(0),
+                    _ => None,
+                };
+                if let Some(value) = reply {
+                    let mut msg = Vec::new();
+                    msg.extend_from_slice(&request.to_le_bytes());
+                    msg.extend_from_slice(&(VERSION | REPLY).to_le_bytes());
+                    msg.extend_from_slice(&8u32.to_le_bytes());
+                    msg.extend_from_slice(&value.to_le_bytes());
+                    stream.write_all(&msg).unwrap();
+                }
+            }
+        })
+    }
+
+    fn create_net(
+        protocol_features: VhostUserProtocolFeatures,
+        max_queue_size: u32,
+        queue_size: u16,
+    ) -> (Vec<u16>, Vec<u32>) {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let socket = dir.as_path().join("net.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let backend = fake_backend(
+            listener,
+            protocol_features,
+            FakeBackendOffer::new(max_queue_size),
+            false,
+        );
+
+        let vu_cfg = VhostUserConfig {
+            socket: socket.to_str().unwrap().to_owned(),
+            num_queues: 2,
+            queue_size,
+            connect_timeout: None,
+            activate_timeout: None,
+            offloads: NetOffloads::default(),
+            anti_spoof: false,
+        };
+        let net = Net::new(
+            "net0".to_owned(),
+            MacAddr::parse_str("12:34:56:78:90:ab").unwrap(),
+            vu_cfg,
+            SeccompAction::Allow,
+        )
+        .unwrap();
+        let queue_sizes = net.common.queue_sizes.clone();
+        drop(net);
+
+        (queue_sizes, backend.join().unwrap().vring_nums)
+    }
+
+    #[test]
+    fn test_queue_size_clamp() {
+        // The backend supports smaller vrings than the requested ones.
+        let (queue_sizes, vring_nums) = create_net(VhostUserProtocolFeatures::REPLY_ACK, 64, 256);
+        assert_eq!(queue_sizes, vec![64; 3]);
+        assert_eq!(vring_nums, vec![256, 128, 64]);
+
+        // The backend supports the requested vrings.
+        let (queue_sizes, vring_nums) = create_net(VhostUserProtocolFeatures::REPLY_ACK, 1024, 256);
+        assert_eq!(queue_sizes, vec![256; 3]);
+        assert_eq!(vring_nums, vec![256]);
+    }
+
+    #[test]
+    fn test_queue_size_without_reply_ack() {
+        // Without replies, the backend can't tell which sizes it supports,
+        // nor is it asked to.
+        let (queue_sizes, vring_nums) = create_net(VhostUserProtocolFeatures::empty(), 64, 256);
+        assert_eq!(queue_sizes, vec![256; 3]);
+        assert!(vring_nums.is_empty());
+    }
+
+    #[test]
+    fn test_anti_spoof() {
+        let mac_addr = MacAddr::parse_str("12:34:56:78:90:ab").unwrap();
+        for anti_spoof in [false, true].iter() {
+            let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+            let socket = dir.as_path().join("net.sock");
+            let listener = UnixListener::bind(&socket).unwrap();
+            let backend = fake_backend(
+                listener,
+                VhostUserProtocolFeatures::REPLY_ACK | VhostUserProtocolFeatures::CONFIG,
+                FakeBackendOffer::new(1024),
+                *anti_spoof,
+            );
+
+            let vu_cfg = VhostUserConfig {
+                socket: socket.to_str().unwrap().to_owned(),
+                num_queues: 2,
+                queue_size: 256,
+                connect_timeout: None,
+                activate_timeout: None,
+                offloads: NetOffloads::default(),
+                anti_spoof: true,
+            };
+            let mut net =
+                Net::new("net0".to_owned(), mac_addr, vu_cfg, SeccompAction::Allow).unwrap();
+            // Only a backend filtering on the address confirms it.
+            assert_eq!(
+                set_anti_spoof(&mut net.vhost_user_net, &mac_addr),
+                *anti_spoof
+            );
+            drop(net);
+
+            // The address is never written to the mac field.
+            assert_eq!(
+                backend.join().unwrap().config_writes,
+                vec![VHOST_USER_CONFIG_OFFSET + VHOST_USER_NET_ANTI_SPOOF_OFFSET; 2]
+            );
+        }
+    }
+
+    #[test]
+    fn test_take_over_backend() {
+        let mac_addr = MacAddr::parse_str("12:34:56:78:90:ab").unwrap();
+        // The device of the rebooted guest takes the backend over after
+        // the offer of the backend changed, returning the sizes of the
+        // vrings it was sent.
+        let take_over = |mac_addr: MacAddr, change: fn(&FakeBackendOffer)| {
+            let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+            let socket = dir.as_path().join("net.sock");
+            let listener = UnixListener::bind(&socket).unwrap();
+            let offer = FakeBackendOffer::new(1024);
+            let backend = fake_backend(
+                listener,
+                VhostUserProtocolFeatures::REPLY_ACK,
+                offer.clone(),
+                false,
+            );
+
+            let vu_cfg = VhostUserConfig {
+                socket: socket.to_str().unwrap().to_owned(),
+                num_queues: 2,
+                queue_size: 256,
+                connect_timeout: None,
+                activate_timeout: None,
+                offloads: NetOffloads::default(),
+                anti_spoof: false,
+            };
+            let mut net = Net::new(
+                "net0".to_owned(),
+                MacAddr::parse_str("12:34:56:78:90:ab").unwrap(),
+                vu_cfg.clone(),
+                SeccompAction::Allow,
+            )
+            .unwrap();
+            let net_backend = net.take_backend().unwrap();
+            // The backend is only handed over once.
+            assert!(net.take_backend().is_none());
+            drop(net);
+
+            change(&offer);
+            let result = Net::from_backend(
+                "net0".to_owned(),
+                mac_addr,
+                vu_cfg,
+                net_backend,
+                SeccompAction::Allow,
+            );
+            let queue_sizes = result.as_ref().map(|net| net.common.queue_sizes.clone());
+            drop(result);
+
+            (queue_sizes, backend.join().unwrap().vring_nums)
+        };
+
+        // The vrings are set up again as they were.
+        let (queue_sizes, vring_nums) = take_over(mac_addr, |_| {});
+        assert_eq!(queue_sizes.unwrap(), vec![256; 3]);
+        assert_eq!(vring_nums, vec![256; 3]);
+
+        // The device must be configured the same.
+        let other_mac_addr = MacAddr::parse_str("12:34:56:78:90:ac").unwrap();
+        assert!(matches!(
+            take_over(other_mac_addr, |_| {}).0,
+            Err(Error::VhostUserBackendMismatch)
+        ));
+
+        // The backend must offer the same features, and accept the vrings.
+        assert!(matches!(
+            take_over(mac_addr, |offer| {
+                offer
+                    .features
+                    .fetch_or(1 << virtio_net::VIRTIO_NET_F_MQ, Ordering::SeqCst);
+            })
+            .0,
+            Err(Error::InvalidFeatures)
+        ));
+        let (queue_sizes, vring_nums) = take_over(mac_addr, |offer| {
+            offer.max_queue_size.store(128, Ordering::SeqCst);
+        });
+        assert!(matches!(queue_sizes, Err(Error::VhostUserSetVringNum(_))));
+        assert_eq!(vring_nums, vec![256; 2]);
+    }
+
+    #[test]
+    fn test_set_active_queues() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let socket = dir.as_path().join("net.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let backend = fake_backend(
+            listener,
+            VhostUserProtocolFeatures::REPLY_ACK | VhostUserProtocolFeatures::MQ,
+            FakeBackendOffer::new(1024),
+            false,
+        );
+
+        let vu_cfg = VhostUserConfig {
+            socket: socket.to_str().unwrap().to_owned(),
+            num_queues: 4,
+            queue_size: 256,
+            connect_timeout: None,
+            activate_timeout: Some(Duration::from_millis(100)),
+            offloads: NetOffloads::default(),
+            anti_spoof: false,
+        };
+        let mut net = Net::new(
+            "net0".to_owned(),
+            MacAddr::parse_str("12:34:56:78:90:ab").unwrap(),
+            vu_cfg,
+            SeccompAction::Allow,
+        )
+        .unwrap();
+
+        // Only whole queue pairs among the device's ones can be active.
+        for active_queues in [0, 3, 6].iter() {
+            assert!(matches!(
+                net.set_active_queues(*active_queues),
+                Err(Error::InvalidNumQueues(_))
+            ));
+        }
+        // The backend is left alone until the device is activated.
+        net.set_active_queues(2).unwrap();
+        assert_eq!(net.active_queues, 2);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queues: Vec<GuestQ> = (0..4)
+            .map(|i| GuestQ::new(GuestAddress(i * 0x1000), &mem, 16))
+            .collect();
+        net.vrings = Some((
+            GuestMemoryAtomic::new(mem.clone()),
+            guest_queues.iter().map(|q| q.create_queue()).collect(),
+        ));
+        net.set_active_queues(4).unwrap();
+
+        // The queue pair isn't disabled while the backend hasn't sent the
+        // frame pending on its TX queue.
+        guest_queues[3].avail.idx.set(1);
+        assert!(matches!(
+            net.set_active_queues(2),
+            Err(Error::PendingTxFrames(3))
+        ));
+        assert_eq!(net.active_queues, 4);
+        guest_queues[3].used.idx.set(1);
+        net.set_active_queues(2).unwrap();
+
+        // The number of active queues is restored.
+        let snapshot = net.snapshot().unwrap();
+        net.active_queues = 4;
+        net.restore(snapshot).unwrap();
+        assert_eq!(net.active_queues, 2);
+        drop(net);
+
+        assert_eq!(
+            backend.join().unwrap().vring_enables,
+            vec![(2, 1), (3, 1), (2, 0), (3, 0)]
+        );
+    }
+}
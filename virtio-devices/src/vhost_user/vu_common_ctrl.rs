@@ -6,20 +6,147 @@ use super::{Error, Result};
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use libc::EFD_NONBLOCK;
 use std::convert::TryInto;
-use std::os::unix::io::AsRawFd;
+use std::mem::ManuallyDrop;
+use std::net::Shutdown;
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use vfio_ioctls::get_host_address_range;
-use vhost_rs::vhost_user::{Master, VhostUserMaster};
-use vhost_rs::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
+use vhost_rs::vhost_user::{Error as VhostUserError, Master, VhostUserMaster};
+use vhost_rs::{
+    Error as VhostError, Result as VhostResult, VhostBackend, VhostUserMemoryRegionInfo,
+    VringConfigData,
+};
+use virtio_bindings::bindings::virtio_net::{
+    VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4,
+    VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_ECN, VIRTIO_NET_F_HOST_TSO4,
+    VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO,
+};
 use vm_memory::{Address, Error as MmapError, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 use vmm_sys_util::eventfd::EventFd;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct VhostUserConfig {
     pub socket: String,
     pub num_queues: usize,
     pub queue_size: u16,
+    /// Time the backend has to start listening on its socket when the
+    /// device is created.
+    pub connect_timeout: Option<Duration>,
+    pub activate_timeout: Option<Duration>,
+    pub offloads: NetOffloads,
+    /// Whether the vhost-user-net backend is asked to drop the frames sent
+    /// from another MAC address than the one of the device.
+    pub anti_spoof: bool,
+}
+
+/// Offloads of a vhost-user-net device, the `host_*` ones applying to the
+/// frames the guest sends and the `guest_*` ones to the frames it receives.
+/// The ones turned off are hidden from the guest, even when the backend
+/// offers them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetOffloads {
+    pub host_csum: bool,
+    pub host_tso: bool,
+    pub host_ufo: bool,
+    pub guest_csum: bool,
+    pub guest_tso: bool,
+    pub guest_ufo: bool,
+}
+
+impl Default for NetOffloads {
+    fn default() -> Self {
+        NetOffloads {
+            host_csum: true,
+            host_tso: true,
+            host_ufo: true,
+            guest_csum: true,
+            guest_tso: true,
+            guest_ufo: true,
+        }
+    }
+}
+
+impl NetOffloads {
+    /// Features the guest must not be offered. The segmentation offloads
+    /// rely on the checksum offload of the same direction, and ECN on TSO.
+    pub fn disabled_features(&self) -> u64 {
+        let mut features = 0;
+        if !self.host_csum {
+            features |= 1 << VIRTIO_NET_F_CSUM | 1 << VIRTIO_NET_F_HOST_UFO;
+        }
+        if !self.host_csum || !self.host_tso {
+            features |= 1 << VIRTIO_NET_F_HOST_TSO4
+                | 1 << VIRTIO_NET_F_HOST_TSO6
+                | 1 << VIRTIO_NET_F_HOST_ECN;
+        }
+        if !self.host_ufo {
+            features |= 1 << VIRTIO_NET_F_HOST_UFO;
+        }
+        if !self.guest_csum {
+            features |= 1 << VIRTIO_NET_F_GUEST_CSUM | 1 << VIRTIO_NET_F_GUEST_UFO;
+        }
+        if !self.guest_csum || !self.guest_tso {
+            features |= 1 << VIRTIO_NET_F_GUEST_TSO4
+                | 1 << VIRTIO_NET_F_GUEST_TSO6
+                | 1 << VIRTIO_NET_F_GUEST_ECN;
+        }
+        if !self.guest_ufo {
+            features |= 1 << VIRTIO_NET_F_GUEST_UFO;
+        }
+        features
+    }
+}
+
+// Delay before the first retry to connect to a backend, doubled after each
+// attempt up to CONNECT_RETRY_MAX_DELAY.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(10);
+const CONNECT_RETRY_MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// Connects to the backend listening on `socket`. With a timeout, failed
+/// attempts are retried with an increasing delay until it expires, for a
+/// backend still starting to get a chance.
+pub fn connect_vhost_user(
+    socket: &str,
+    num_queues: usize,
+    timeout: Option<Duration>,
+) -> Result<Master> {
+    retry_connect(socket, timeout, || {
+        Master::connect(socket, num_queues as u64)
+    })
+}
+
+fn retry_connect<F>(socket: &str, timeout: Option<Duration>, mut connect: F) -> Result<Master>
+where
+    F: FnMut() -> std::result::Result<Master, VhostError>,
+{
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut delay = CONNECT_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let e = match connect() {
+            Ok(vu) => return Ok(vu),
+            Err(e) => e,
+        };
+
+        let now = Instant::now();
+        let deadline = match deadline {
+            Some(deadline) if now < deadline => deadline,
+            _ => return Err(Error::VhostUserCreateMaster(e)),
+        };
+        let delay_left = delay.min(deadline - now);
+        warn!(
+            "Failed to connect to vhost-user backend {} (attempt {}), retrying in {:?}: {:?}",
+            socket, attempt, delay_left, e
+        );
+        thread::sleep(delay_left);
+        delay = (delay * 2).min(CONNECT_RETRY_MAX_DELAY);
+        attempt += 1;
+    }
 }
 
 pub fn update_mem_table(vu: &mut Master, mem: &GuestMemoryMmap) -> Result<()> {
@@ -131,7 +258,111 @@ pub fn setup_vhost_user(
     setup_vhost_user_vring(vu, mem, queues, queue_evts, virtio_interrupt)
 }
 
-pub fn reset_vhost_user(vu: &mut Master, num_queues: usize) -> Result<()> {
+fn master_socket(vu: &Master) -> ManuallyDrop<UnixStream> {
+    // The socket is only borrowed, the master keeps owning it.
+    ManuallyDrop::new(unsafe { UnixStream::from_raw_fd(vu.as_raw_fd()) })
+}
+
+fn set_socket_timeout(vu: &Master, timeout: Option<Duration>) -> Result<()> {
+    let socket = master_socket(vu);
+    socket
+        .set_read_timeout(timeout)
+        .and_then(|_| socket.set_write_timeout(timeout))
+        .map_err(Error::VhostUserSetTimeout)
+}
+
+/// Runs `f` with `timeout` for the backend to answer each request, failing
+/// with VhostUserTimeout when it doesn't. Without timeout, the requests wait
+/// for as long as the backend takes.
+///
+/// A request which failed half way leaves the connection out of sync, the
+/// late reply being taken for the one of the next request. The connection
+/// is shut down whenever `f` fails, for the next requests to fail as well,
+/// until a new connection is made.
+pub fn with_reply_timeout<F, T>(vu: &mut Master, timeout: Option<Duration>, f: F) -> Result<T>
+where
+    F: FnOnce(&mut Master) -> Result<T>,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return f(vu),
+    };
+
+    set_socket_timeout(vu, Some(timeout))?;
+    let start = Instant::now();
+    let result = f(vu);
+    if result.is_ok() {
+        set_socket_timeout(vu, None)?;
+        return result;
+    }
+
+    if let Err(e) = master_socket(vu).shutdown(Shutdown::Both) {
+        warn!("Failed shutting down the vhost-user connection: {}", e);
+    }
+    result.map_err(|e| {
+        // The EAGAIN of the request running out of time isn't exposed by
+        // the errors of the vhost crate, but only such a request can make
+        // the whole timeout elapse.
+        if start.elapsed() >= timeout {
+            error!(
+                "vhost-user backend didn't answer within {:?}: {:?}",
+                timeout, e
+            );
+            Error::VhostUserTimeout(timeout)
+        } else {
+            e
+        }
+    })
+}
+
+/// Looks for the largest queue size, up to `queue_size`, the backend
+/// supports. The protocol has no message for the maximum size of the vrings,
+/// but a backend replying to the requests rejects a SET_VRING_NUM it can't
+/// honor, so the size is halved until accepted. Unless REPLY_ACK was
+/// negotiated with the backend, nothing is sent and the requested size is
+/// assumed to be supported.
+pub fn negotiate_queue_size(vu: &mut Master, queue_size: u16, reply_ack: bool) -> Result<u16> {
+    if !reply_ack {
+        return Ok(queue_size);
+    }
+
+    largest_accepted_queue_size(queue_size, |size| vu.set_vring_num(0, size))
+        .map_err(Error::VhostUserSetVringNum)
+}
+
+fn largest_accepted_queue_size<F>(queue_size: u16, mut set_vring_num: F) -> VhostResult<u16>
+where
+    F: FnMut(u16) -> VhostResult<()>,
+{
+    let mut size = queue_size;
+    loop {
+        match set_vring_num(size) {
+            Ok(()) => return Ok(size),
+            Err(VhostError::VhostUserProtocol(VhostUserError::SlaveInternalError)) if size > 1 => {
+                size /= 2
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Enables or disables the vrings of `queue_indexes`, leaving the others as
+/// they are.
+pub fn enable_vhost_user_vrings(
+    vu: &mut Master,
+    queue_indexes: Range<usize>,
+    enable: bool,
+) -> Result<()> {
+    for queue_index in queue_indexes {
+        vu.set_vring_enable(queue_index, enable)
+            .map_err(Error::VhostUserSetVringEnable)?;
+    }
+
+    Ok(())
+}
+
+/// Stops the vrings, leaving the backend owned and its features negotiated.
+pub fn stop_vhost_user_vrings(vu: &mut Master, num_queues: usize) -> Result<()> {
     for queue_index in 0..num_queues {
         // Disable the vrings.
         vu.set_vring_enable(queue_index, false)
@@ -142,6 +373,170 @@ pub fn reset_vhost_user(vu: &mut Master, num_queues: usize) -> Result<()> {
             .map_err(Error::VhostUserSetFeatures)?;
     }
 
+    Ok(())
+}
+
+pub fn reset_vhost_user(vu: &mut Master, num_queues: usize) -> Result<()> {
+    stop_vhost_user_vrings(vu, num_queues)?;
+
     // Reset the owner.
     vu.reset_owner().map_err(Error::VhostUserResetOwner)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // A backend rejecting the vrings larger than its maximum size.
+    fn backend(max_queue_size: u16) -> impl FnMut(u16) -> VhostResult<()> {
+        move |size| {
+            if size > max_queue_size {
+                Err(VhostError::VhostUserProtocol(
+                    VhostUserError::SlaveInternalError,
+                ))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn test_queue_size_negotiation() {
+        assert_eq!(
+            largest_accepted_queue_size(256, backend(1024)).unwrap(),
+            256
+        );
+        assert_eq!(largest_accepted_queue_size(256, backend(256)).unwrap(), 256);
+        assert_eq!(
+            largest_accepted_queue_size(1024, backend(128)).unwrap(),
+            128
+        );
+        assert!(largest_accepted_queue_size(256, backend(0)).is_err());
+
+        // Failures other than a rejection of the size are reported as such.
+        let mut requests = 0;
+        assert!(matches!(
+            largest_accepted_queue_size(256, |_| {
+                requests += 1;
+                Err(VhostError::VhostUserProtocol(VhostUserError::SocketBroken(
+                    std::io::Error::from_raw_os_error(libc::EPIPE),
+                )))
+            }),
+            Err(VhostError::VhostUserProtocol(VhostUserError::SocketBroken(
+                _
+            )))
+        ));
+        assert_eq!(requests, 1);
+    }
+
+    #[test]
+    fn test_connect_retry() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let path = dir.as_path().join("backend.sock");
+        let socket = path.to_str().unwrap();
+
+        // A backend not listening is an error, right away without a timeout
+        // or once the timeout expired.
+        assert!(matches!(
+            connect_vhost_user(socket, 1, None),
+            Err(Error::VhostUserCreateMaster(_))
+        ));
+        assert!(matches!(
+            connect_vhost_user(socket, 1, Some(Duration::from_millis(50))),
+            Err(Error::VhostUserCreateMaster(_))
+        ));
+
+        // A backend starting within the timeout, here before the third
+        // attempt, is connected to.
+        let mut attempts = 0;
+        let mut listener = None;
+        let vu = retry_connect(socket, Some(Duration::from_secs(5)), || {
+            attempts += 1;
+            if attempts == 3 {
+                listener = Some(UnixListener::bind(&path).unwrap());
+            }
+            Master::connect(socket, 1)
+        });
+        assert!(vu.is_ok());
+        assert_eq!(attempts, 3);
+        assert!(listener.unwrap().accept().is_ok());
+    }
+
+    #[test]
+    fn test_reply_timeout() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let path = dir.as_path().join("backend.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // A backend answering GET_FEATURES only once told to.
+        let (answer_tx, answer_rx) = mpsc::channel();
+        let backend = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut hdr = [0u8; 12];
+            stream.read_exact(&mut hdr).unwrap();
+            answer_rx.recv().unwrap();
+            let mut reply = Vec::new();
+            reply.extend_from_slice(&hdr[0..4]);
+            reply.extend_from_slice(&0x5u32.to_le_bytes());
+            reply.extend_from_slice(&8u32.to_le_bytes());
+            reply.extend_from_slice(&1u64.to_le_bytes());
+            // The connection may be gone already.
+            stream.write_all(&reply).ok();
+        });
+
+        let mut vu = Master::connect(path.to_str().unwrap(), 1).unwrap();
+        let timeout = Duration::from_millis(100);
+        assert!(matches!(
+            with_reply_timeout(&mut vu, Some(timeout), |vu| vu
+                .get_features()
+                .map_err(Error::VhostUserGetFeatures)),
+            Err(Error::VhostUserTimeout(t)) if t == timeout
+        ));
+
+        // The late reply isn't taken for the one of the next request.
+        answer_tx.send(()).unwrap();
+        backend.join().unwrap();
+        assert!(vu.get_features().is_err());
+    }
+
+    #[test]
+    fn test_disabled_offloads() {
+        assert_eq!(NetOffloads::default().disabled_features(), 0);
+
+        // The host TSO stays on when only the guest UFO is broken.
+        let offloads = NetOffloads {
+            guest_ufo: false,
+            ..Default::default()
+        };
+        assert_eq!(offloads.disabled_features(), 1 << VIRTIO_NET_F_GUEST_UFO);
+
+        // No segmentation offload works without the checksum offload.
+        let offloads = NetOffloads {
+            host_csum: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            offloads.disabled_features(),
+            1 << VIRTIO_NET_F_CSUM
+                | 1 << VIRTIO_NET_F_HOST_TSO4
+                | 1 << VIRTIO_NET_F_HOST_TSO6
+                | 1 << VIRTIO_NET_F_HOST_ECN
+                | 1 << VIRTIO_NET_F_HOST_UFO
+        );
+
+        let offloads = NetOffloads {
+            guest_tso: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            offloads.disabled_features(),
+            1 << VIRTIO_NET_F_GUEST_TSO4
+                | 1 << VIRTIO_NET_F_GUEST_TSO6
+                | 1 << VIRTIO_NET_F_GUEST_ECN
+        );
+    }
+}
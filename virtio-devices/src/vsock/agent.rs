@@ -0,0 +1,228 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// Messages exchanged with an agent running in the guest, listening on a
+/// well-known vsock port.
+///
+/// Each message is carried in a frame, made of its length as a little endian
+/// u32, followed by the message serialized as JSON. Every request carries an
+/// identifier, which the agent copies into the matching response so that
+/// responses can be routed back to the request they answer.
+///
+use std::io::{self, Read, Write};
+
+/// Largest frame accepted, protecting against a guest sending garbage.
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The frame is larger than MAX_FRAME_SIZE.
+    FrameTooLarge(usize),
+    /// Error reading a frame.
+    Read(io::Error),
+    /// Error writing a frame.
+    Write(io::Error),
+    /// Error serializing a message.
+    Serialize(serde_json::Error),
+    /// Error deserializing a message.
+    Deserialize(serde_json::Error),
+    /// The response doesn't match the request.
+    UnexpectedResponseId(u64),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentRequest {
+    /// Check the agent is alive.
+    Ping,
+    /// Run a command in the guest and wait for its completion.
+    Exec {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// Read the content of a file from the guest.
+    FileRead { path: String },
+}
+
+impl Default for AgentRequest {
+    fn default() -> Self {
+        AgentRequest::Ping
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentResponse {
+    Pong,
+    Exec {
+        status: i32,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    },
+    FileRead {
+        content: Vec<u8>,
+    },
+    /// The agent failed to handle the request.
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AgentMessage<T> {
+    pub id: u64,
+    pub body: T,
+}
+
+/// Write a single frame containing `payload`.
+pub fn write_frame(w: &mut dyn Write, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(Error::FrameTooLarge(payload.len()));
+    }
+
+    w.write_all(&(payload.len() as u32).to_le_bytes())
+        .and_then(|_| w.write_all(payload))
+        .map_err(Error::Write)
+}
+
+/// Read a single frame, returning its payload.
+pub fn read_frame(r: &mut dyn Read) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len).map_err(Error::Read)?;
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::FrameTooLarge(len));
+    }
+
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload).map_err(Error::Read)?;
+
+    Ok(payload)
+}
+
+/// Send `request` to the agent and wait for the response carrying the same
+/// identifier.
+pub fn send_request<S: Read + Write>(
+    stream: &mut S,
+    id: u64,
+    request: AgentRequest,
+) -> Result<AgentResponse> {
+    let message = AgentMessage { id, body: request };
+    let payload = serde_json::to_vec(&message).map_err(Error::Serialize)?;
+    write_frame(stream, &payload)?;
+
+    let payload = read_frame(stream)?;
+    let response: AgentMessage<AgentResponse> =
+        serde_json::from_slice(&payload).map_err(Error::Deserialize)?;
+    if response.id != id {
+        return Err(Error::UnexpectedResponseId(response.id));
+    }
+
+    Ok(response.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frame() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").unwrap();
+        write_frame(&mut buf, b"").unwrap();
+        assert_eq!(&buf[..4], &5u32.to_le_bytes());
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(read_frame(&mut cursor).unwrap(), b"hello");
+        assert!(read_frame(&mut cursor).unwrap().is_empty());
+        // Nothing left to read.
+        assert!(matches!(read_frame(&mut cursor), Err(Error::Read(_))));
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let mut buf = Vec::new();
+        assert!(matches!(
+            write_frame(&mut buf, &vec![0u8; MAX_FRAME_SIZE + 1]),
+            Err(Error::FrameTooLarge(_))
+        ));
+        assert!(buf.is_empty());
+
+        let mut cursor = Cursor::new(((MAX_FRAME_SIZE + 1) as u32).to_le_bytes().to_vec());
+        assert!(matches!(
+            read_frame(&mut cursor),
+            Err(Error::FrameTooLarge(_))
+        ));
+    }
+
+    // Plays the role of the guest agent, answering a single request.
+    struct FakeAgent {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl FakeAgent {
+        fn new(id: u64, response: AgentResponse) -> Self {
+            let mut input = Vec::new();
+            let message = AgentMessage { id, body: response };
+            write_frame(&mut input, &serde_json::to_vec(&message).unwrap()).unwrap();
+            FakeAgent {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for FakeAgent {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakeAgent {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send_request() {
+        let mut agent = FakeAgent::new(3, AgentResponse::Pong);
+        assert_eq!(
+            send_request(&mut agent, 3, AgentRequest::Ping).unwrap(),
+            AgentResponse::Pong
+        );
+        let request: AgentMessage<AgentRequest> =
+            serde_json::from_slice(&read_frame(&mut Cursor::new(agent.output)).unwrap()).unwrap();
+        assert_eq!(
+            request,
+            AgentMessage {
+                id: 3,
+                body: AgentRequest::Ping
+            }
+        );
+
+        let mut agent = FakeAgent::new(4, AgentResponse::Pong);
+        assert!(matches!(
+            send_request(
+                &mut agent,
+                5,
+                AgentRequest::FileRead {
+                    path: "/etc/hostname".to_string()
+                }
+            ),
+            Err(Error::UnexpectedResponseId(4))
+        ));
+    }
+}
@@ -50,6 +50,10 @@ pub enum Error {
     UnixRead(std::io::Error),
     /// Muxer connection limit reached.
     TooManyConnections,
+    /// Error retrieving the credentials of the host-side peer.
+    PeerCredentials(std::io::Error),
+    /// The host-side peer is not allowed to connect to the agent port.
+    AgentPortDenied(libc::pid_t),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -36,6 +36,7 @@ use std::fs::File;
 use std::io::{self, Read};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
+use vmm_sys_util::eventfd::EventFd;
 
 use super::super::csm::ConnState;
 use super::super::defs::uapi;
@@ -115,6 +116,11 @@ pub struct VsockMuxer {
     local_port_set: HashSet<u32>,
     /// The last used host-side port.
     local_port_last: u32,
+    /// The port the guest agent is listening on, if any.
+    agent_port: Option<u32>,
+    /// The port whose connections the guest closes once its workload is
+    /// completed, along with the event signaled when it does.
+    completion: Option<(u32, EventFd)>,
 }
 
 impl VsockChannel for VsockMuxer {
@@ -242,6 +248,8 @@ impl VsockChannel for VsockMuxer {
             return Ok(());
         }
 
+        self.check_completion(conn_key, pkt);
+
         // Right, we know where to send this packet, then (to `conn_key`).
         // However, if this is an RST, we have to forcefully terminate the connection, so
         // there's no point in forwarding it the packet.
@@ -330,7 +338,7 @@ impl VsockBackend for VsockMuxer {}
 impl VsockMuxer {
     /// Muxer constructor.
     ///
-    pub fn new(cid: u64, host_sock_path: String) -> Result<Self> {
+    pub fn new(cid: u64, host_sock_path: String, agent_port: Option<u32>) -> Result<Self> {
         // Create the nested epoll FD. This FD will be added to the VMM `EpollContext`, at
         // device activation time.
         let epoll_fd = epoll::create(true).map_err(Error::EpollFdCreate)?;
@@ -354,12 +362,22 @@ impl VsockMuxer {
             killq: MuxerKillQ::new(),
             local_port_last: (1u32 << 30) - 1,
             local_port_set: HashSet::with_capacity(defs::MAX_CONNECTIONS),
+            agent_port,
+            completion: None,
         };
 
         muxer.add_listener(muxer.host_sock.as_raw_fd(), EpollListener::HostSock)?;
         Ok(muxer)
     }
 
+    /// Signal the event each time the guest closes a connection on the given port, be it a
+    /// connection the guest initiated to this host port, or one initiated by the host to this
+    /// guest port.
+    ///
+    pub fn set_completion(&mut self, port: u32, evt: EventFd) {
+        self.completion = Some((port, evt));
+    }
+
     /// Handle/dispatch an epoll event to its listener.
     ///
     fn handle_event(&mut self, fd: RawFd, evset: epoll::Events) {
@@ -420,6 +438,9 @@ impl VsockMuxer {
             Some(EpollListener::LocalStream(_)) => {
                 if let Some(EpollListener::LocalStream(mut stream)) = self.remove_listener(fd) {
                     Self::read_local_stream_port(&mut stream)
+                        .and_then(|peer_port| {
+                            self.check_agent_port(&stream, peer_port).map(|_| peer_port)
+                        })
                         .map(|peer_port| (self.allocate_local_port(), peer_port))
                         .and_then(|(local_port, peer_port)| {
                             self.add_connection(
@@ -492,6 +513,86 @@ impl VsockMuxer {
             .map_err(|e| Error::ReadStreamPort(Box::new(e)))
     }
 
+    /// Only the VMM process itself is allowed to connect to the guest agent
+    /// port, other host processes have to go through the VMM API.
+    ///
+    fn check_agent_port(&self, stream: &UnixStream, peer_port: u32) -> Result<()> {
+        if self.agent_port != Some(peer_port) {
+            return Ok(());
+        }
+
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // Safe because the socket is valid, and cred and len are properly
+        // sized. The return value is checked.
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::PeerCredentials(io::Error::last_os_error()));
+        }
+
+        if cred.pid as u32 != std::process::id() {
+            return Err(Error::AgentPortDenied(cred.pid));
+        }
+
+        Ok(())
+    }
+
+    /// Signal the completion event if the guest packet closes a connection on the completion
+    /// port. Connections closed by the host first don't count.
+    ///
+    fn check_completion(&self, key: ConnMapKey, pkt: &VsockPacket) {
+        let (port, evt) = match &self.completion {
+            Some(completion) => completion,
+            None => return,
+        };
+
+        // The host-initiated connections get a local port allocated, and are made to the
+        // guest port.
+        let conn_port = if self.local_port_set.contains(&key.local_port) {
+            key.peer_port
+        } else {
+            key.local_port
+        };
+        if conn_port != *port {
+            return;
+        }
+
+        let state = match self.conn_map.get(&key) {
+            Some(conn) => conn.state(),
+            None => return,
+        };
+        let closed = match pkt.op() {
+            uapi::VSOCK_OP_SHUTDOWN => {
+                let flags = uapi::VSOCK_FLAGS_SHUTDOWN_RCV | uapi::VSOCK_FLAGS_SHUTDOWN_SEND;
+                pkt.flags() & flags == flags && state != ConnState::LocalClosed
+            }
+            uapi::VSOCK_OP_RST => state == ConnState::Established,
+            _ => false,
+        };
+
+        if closed {
+            info!(
+                "vsock: guest closed a connection on completion port {}",
+                port
+            );
+            if let Err(e) = evt.write(1) {
+                error!("vsock: failed to signal the completion: {:?}", e);
+            }
+        }
+    }
+
     /// Add a new connection to the active connection pool.
     ///
     fn add_connection(&mut self, key: ConnMapKey, conn: MuxerConnection) -> Result<()> {
@@ -819,6 +920,7 @@ mod tests {
     use super::super::super::csm::defs as csm_defs;
     use super::super::super::tests::TestContext as VsockTestContext;
     use super::*;
+    use vmm_sys_util::eventfd::EFD_NONBLOCK;
 
     const PEER_CID: u64 = 3;
     const PEER_BUF_ALLOC: u32 = 64 * 1024;
@@ -847,7 +949,7 @@ mod tests {
             )
             .unwrap();
             let uds_path = format!("test_vsock_{}.sock", name);
-            let muxer = VsockMuxer::new(PEER_CID, uds_path).unwrap();
+            let muxer = VsockMuxer::new(PEER_CID, uds_path, None).unwrap();
 
             Self {
                 _vsock_test_ctx: vsock_test_ctx,
@@ -1218,6 +1320,41 @@ mod tests {
         assert_eq!(stream.read(buf.as_mut_slice()).unwrap(), 0);
     }
 
+    #[test]
+    fn test_completion() {
+        let peer_port = 1025;
+        let local_port = 1026;
+        let mut ctx = MuxerTestContext::new("completion");
+        let completion_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        ctx.muxer
+            .set_completion(local_port, completion_evt.try_clone().unwrap());
+
+        // Connections closed by the host don't signal the completion.
+        {
+            let (_stream, host_local_port) = ctx.local_connect(peer_port);
+            ctx.notify_muxer();
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_SHUTDOWN);
+            ctx.init_pkt(host_local_port, peer_port, uapi::VSOCK_OP_RST);
+            ctx.send();
+        }
+        assert!(completion_evt.read().is_err());
+
+        // The guest closing a connection to the completion port does.
+        let mut sock = ctx.create_local_listener(local_port);
+        ctx.init_pkt(local_port, peer_port, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        let _stream = sock.accept();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+
+        ctx.init_pkt(local_port, peer_port, uapi::VSOCK_OP_SHUTDOWN)
+            .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_SEND)
+            .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_RCV);
+        ctx.send();
+        assert_eq!(completion_evt.read().unwrap(), 1);
+    }
+
     #[test]
     fn test_muxer_rxq() {
         let mut ctx = MuxerTestContext::new("muxer_rxq");
@@ -11,7 +11,7 @@ use super::{
     VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::{VirtioInterrupt, VirtioInterruptType};
+use crate::{PauseBarrier, VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
 use seccomp::{SeccompAction, SeccompFilter};
 use std::fs::File;
@@ -19,13 +19,13 @@ use std::io::{self, Read};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::result;
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Barrier, Mutex};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Instant;
 use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
-    Transportable,
+    Transportable, VersionedState,
 };
 use vmm_sys_util::eventfd::EventFd;
 
@@ -104,7 +104,7 @@ impl WatchdogEpollHandler {
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
-        paused_sync: Arc<Barrier>,
+        paused_sync: Arc<PauseBarrier>,
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
@@ -174,6 +174,10 @@ pub struct WatchdogState {
     pub enabled: bool,
 }
 
+impl VersionedState for WatchdogState {
+    const VERSION: u16 = 1;
+}
+
 impl Watchdog {
     /// Create a new virtio watchdog device that will reboot VM if the guest hangs
     pub fn new(
@@ -191,7 +195,7 @@ impl Watchdog {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::TYPE_WATCHDOG as u32,
                 queue_sizes: QUEUE_SIZES.to_vec(),
-                paused_sync: Some(Arc::new(Barrier::new(2))),
+                paused_sync: Some(Arc::new(PauseBarrier::new(2))),
                 avail_features,
                 min_queues: 1,
                 ..Default::default()
@@ -363,14 +367,25 @@ impl VirtioDevice for Watchdog {
     }
 }
 
-impl Pausable for Watchdog {
-    fn pause(&mut self) -> result::Result<(), MigratableError> {
+impl Watchdog {
+    fn disable_timer(&self) -> result::Result<(), MigratableError> {
         info!("Watchdog paused - disabling timer");
         timerfd_setup(&self.timer, 0)
-            .map_err(|e| MigratableError::Pause(anyhow!("Error clearing timer: {:?}", e)))?;
+            .map_err(|e| MigratableError::Pause(anyhow!("Error clearing timer: {:?}", e)))
+    }
+}
+
+impl Pausable for Watchdog {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.disable_timer()?;
         self.common.pause()
     }
 
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.disable_timer()?;
+        self.common.quiesce(deadline)
+    }
+
     fn resume(&mut self) -> result::Result<(), MigratableError> {
         // Reset the timer on pause if it was previously used
         if self.last_ping_time.lock().unwrap().is_some() {
@@ -392,14 +407,11 @@ impl Snapshottable for Watchdog {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let snapshot =
-            serde_json::to_vec(&self.state()).map_err(|e| MigratableError::Snapshot(e.into()))?;
-
         let mut watchdog_snapshot = Snapshot::new(self.id.as_str());
-        watchdog_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", self.id),
-            snapshot,
-        });
+        watchdog_snapshot.add_data_section(SnapshotDataSection::new_from_versioned_state(
+            &format!("{}-section", self.id),
+            &self.state(),
+        )?);
 
         Ok(watchdog_snapshot)
     }
@@ -407,15 +419,7 @@ impl Snapshottable for Watchdog {
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         if let Some(watchdog_section) = snapshot.snapshot_data.get(&format!("{}-section", self.id))
         {
-            let watchdog_state = match serde_json::from_slice(&watchdog_section.snapshot) {
-                Ok(state) => state,
-                Err(error) => {
-                    return Err(MigratableError::Restore(anyhow!(
-                        "Could not deserialize watchdog {}",
-                        error
-                    )))
-                }
-            };
+            let watchdog_state = watchdog_section.to_versioned_state::<WatchdogState>()?;
 
             self.set_state(&watchdog_state);
             return Ok(());
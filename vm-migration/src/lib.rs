@@ -9,6 +9,10 @@ extern crate thiserror;
 extern crate serde_derive;
 extern crate vm_memory;
 
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::Instant;
 use thiserror::Error;
 
 pub mod protocol;
@@ -21,6 +25,9 @@ pub enum MigratableError {
     #[error("Failed to resume migratable component: {0}")]
     Resume(#[source] anyhow::Error),
 
+    #[error("Failed to quiesce migratable component: {0}")]
+    Quiesce(#[source] anyhow::Error),
+
     #[error("Failed to snapshot migratable component: {0}")]
     Snapshot(#[source] anyhow::Error),
 
@@ -48,6 +55,22 @@ pub trait Pausable {
     fn resume(&mut self) -> std::result::Result<(), MigratableError> {
         Ok(())
     }
+
+    /// Pause the component, failing with MigratableError::Quiesce if its
+    /// threads aren't all parked by the deadline. Components pausing without
+    /// waiting for other threads don't need to implement it.
+    fn quiesce(&mut self, _deadline: Instant) -> std::result::Result<(), MigratableError> {
+        self.pause()
+    }
+}
+
+/// Version of the state held by a snapshot section that isn't versioned,
+/// which is also the version assumed for the sections of the snapshots
+/// taken before the sections carried one.
+pub const SNAPSHOT_DATA_SECTION_DEFAULT_VERSION: u16 = 1;
+
+fn default_snapshot_data_section_version() -> u16 {
+    SNAPSHOT_DATA_SECTION_DEFAULT_VERSION
 }
 
 /// A Snapshottable component snapshot section.
@@ -55,15 +78,90 @@ pub trait Pausable {
 /// separate sections.
 /// Splitting a component migration data into different sections
 /// allows for easier and forward compatible extensions.
-#[derive(Clone, Default, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct SnapshotDataSection {
     /// The section id.
     pub id: String,
 
+    /// The version of the format of the section serialized snapshot.
+    #[serde(default = "default_snapshot_data_section_version")]
+    pub version: u16,
+
     /// The section serialized snapshot.
     pub snapshot: Vec<u8>,
 }
 
+impl Default for SnapshotDataSection {
+    fn default() -> Self {
+        SnapshotDataSection {
+            id: String::new(),
+            version: SNAPSHOT_DATA_SECTION_DEFAULT_VERSION,
+            snapshot: Vec::new(),
+        }
+    }
+}
+
+/// The state of a component whose format is versioned, so that it can still
+/// be restored from the snapshots taken with its older formats.
+///
+/// Changing the format means bumping the version and adding the migration
+/// step from the previous version, which usually fills in the new fields.
+pub trait VersionedState: Serialize + DeserializeOwned {
+    /// The version of the current format.
+    const VERSION: u16;
+
+    /// Migrate the serialized state from the given version to the next one.
+    /// The versions without a migration step can't be restored from.
+    fn migrate(_version: u16, _state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+        Err(anyhow!("No migration step"))
+    }
+}
+
+impl SnapshotDataSection {
+    /// Create a section holding the state in its current format.
+    pub fn new_from_versioned_state<T: VersionedState>(
+        id: &str,
+        state: &T,
+    ) -> std::result::Result<Self, MigratableError> {
+        let snapshot =
+            serde_json::to_vec(state).map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        Ok(SnapshotDataSection {
+            id: id.to_owned(),
+            version: T::VERSION,
+            snapshot,
+        })
+    }
+
+    /// Retrieve the state held by the section, migrating it one version at
+    /// a time if it was taken with an older format.
+    pub fn to_versioned_state<T: VersionedState>(&self) -> std::result::Result<T, MigratableError> {
+        if self.version > T::VERSION {
+            return Err(MigratableError::Restore(anyhow!(
+                "Snapshot section {} has version {}, newer than the supported version {}",
+                self.id,
+                self.version,
+                T::VERSION
+            )));
+        }
+
+        let mut state: serde_json::Value = serde_json::from_slice(&self.snapshot)
+            .map_err(|e| MigratableError::Restore(e.into()))?;
+        for version in self.version..T::VERSION {
+            state = T::migrate(version, state).map_err(|e| {
+                MigratableError::Restore(anyhow!(
+                    "Could not migrate snapshot section {} from version {}: {}",
+                    self.id,
+                    version,
+                    e
+                ))
+            })?;
+        }
+
+        serde_json::from_value(state).map_err(|e| MigratableError::Restore(e.into()))
+    }
+}
+
 /// A Snapshottable component's snapshot is a tree of snapshots, where leafs
 /// contain the snapshot data. Nodes of this tree track all their children
 /// through the snapshots field, which is basically their sub-components.
@@ -166,3 +264,74 @@ pub trait Transportable: Pausable + Snapshottable {
 /// Moreover a migratable component can be transported to a remote or local
 /// destination and thus must be Transportable.
 pub trait Migratable: Send + Pausable + Snapshottable + Transportable {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct TestState {
+        queues: u16,
+        queue_sizes: Vec<u16>,
+    }
+
+    impl VersionedState for TestState {
+        const VERSION: u16 = 2;
+
+        fn migrate(
+            version: u16,
+            mut state: serde_json::Value,
+        ) -> anyhow::Result<serde_json::Value> {
+            match version {
+                // Version 2 added the size of each queue.
+                1 => {
+                    let queues = state["queues"]
+                        .as_u64()
+                        .ok_or_else(|| anyhow!("Missing number of queues"))?;
+                    state["queue_sizes"] = vec![256; queues as usize].into();
+                    Ok(state)
+                }
+                _ => Err(anyhow!("No migration step")),
+            }
+        }
+    }
+
+    #[test]
+    fn test_versioned_state() {
+        let state = TestState {
+            queues: 2,
+            queue_sizes: vec![128, 512],
+        };
+        let section = SnapshotDataSection::new_from_versioned_state("test", &state).unwrap();
+        assert_eq!(section.version, 2);
+        assert_eq!(section.to_versioned_state::<TestState>().unwrap(), state);
+
+        // Sections of the snapshots taken before they carried a version
+        // are at the first one.
+        let section: SnapshotDataSection = serde_json::from_value(serde_json::json!({
+            "id": "test",
+            "snapshot": br#"{"queues":2}"#.to_vec(),
+        }))
+        .unwrap();
+        assert_eq!(section.version, SNAPSHOT_DATA_SECTION_DEFAULT_VERSION);
+        assert_eq!(
+            section.to_versioned_state::<TestState>().unwrap(),
+            TestState {
+                queues: 2,
+                queue_sizes: vec![256, 256],
+            }
+        );
+
+        let section = SnapshotDataSection {
+            version: 0,
+            ..section
+        };
+        assert!(section.to_versioned_state::<TestState>().is_err());
+
+        let section = SnapshotDataSection {
+            version: 3,
+            ..section
+        };
+        assert!(section.to_versioned_state::<TestState>().is_err());
+    }
+}
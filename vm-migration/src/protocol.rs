@@ -13,12 +13,29 @@ use vm_memory::ByteValued;
 // 3: Dest -> Source : sends "ok response" when read to accept state data
 // 4: Source -> Dest : sends "config command" followed by config data, length
 //                     in command is length of config data
+// On x86_64:
+//   Source -> Dest : sends "cpuid command" followed by the CPUID exposed to the
+//                    guest, as JSON, length in command is length of CPUID data
+//   Dest -> Source : sends "ok response" if it can expose the same CPU
+//                    features, otherwise an "error response" followed by the
+//                    list of missing features, length in response is length
+//                    of that list
 // 5: Dest -> Source : sends "ok response" when ready to accept memory data
 // 6: Source -> Dest : send "memory command" followed by table of u64 pairs (GPA, size)
 //                     followed by the memory described in those pairs.
 //                     !! length is size of table i.e. 16 * number of ranges !!
 // 7: Dest -> Source : sends "ok response" when ready to accept more memory data
 // 8..(n-4): Repeat steps 6 and 7 until source has no more memory to send
+//           When replicating to a standby, the source keeps repeating them
+//           periodically until failover.
+// Optionally, once the VM is paused, for each disk which content is copied:
+//   Source -> Dest : send "disk command" followed by a disk header (index of
+//                    the disk in the config, size of the disk), a table of
+//                    u64 pairs (offset, size) describing the extents holding
+//                    data, and the data of those extents. Anything outside
+//                    of the extents is a hole.
+//                    !! length is size of header + size of table !!
+//   Dest -> Source : sends "ok response" once the disk has been written
 // (n-3): Source -> Dest : sends "state command" followed by state data, length
 //                     in command is length of config data
 // (n-2): Dest -> Source : sends "ok response"
@@ -40,6 +57,8 @@ pub enum Command {
     Memory,
     Complete,
     Abandon,
+    Disk,
+    Cpuid,
 }
 
 impl Default for Command {
@@ -89,6 +108,14 @@ impl Request {
         Self::new(Command::Abandon, 0)
     }
 
+    pub fn disk(length: u64) -> Self {
+        Self::new(Command::Disk, length)
+    }
+
+    pub fn cpuid(length: u64) -> Self {
+        Self::new(Command::Cpuid, length)
+    }
+
     pub fn command(&self) -> Command {
         self.command
     }
@@ -156,6 +183,10 @@ impl Response {
         self.status
     }
 
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
     pub fn read_from(fd: &mut dyn Read) -> Result<Response, MigratableError> {
         let mut response = Response::default();
         fd.read_exact(Self::as_mut_slice(&mut response))
@@ -172,6 +203,36 @@ impl Response {
 
 unsafe impl ByteValued for Response {}
 
+#[repr(C)]
+#[derive(Default, Copy, Clone)]
+pub struct DiskHeader {
+    pub index: u64, // Index of the disk in the VM config
+    pub size: u64,  // Size of the disk image file
+    pub dev: u64,   // Device and inode of the disk image file on the source
+    pub ino: u64,
+}
+
+impl DiskHeader {
+    pub fn length() -> u64 {
+        std::mem::size_of::<Self>() as u64
+    }
+
+    pub fn read_from(fd: &mut dyn Read) -> Result<DiskHeader, MigratableError> {
+        let mut header = DiskHeader::default();
+        fd.read_exact(Self::as_mut_slice(&mut header))
+            .map_err(MigratableError::MigrateSocket)?;
+
+        Ok(header)
+    }
+
+    pub fn write_to(&self, fd: &mut dyn Write) -> Result<(), MigratableError> {
+        fd.write_all(Self::as_slice(self))
+            .map_err(MigratableError::MigrateSocket)
+    }
+}
+
+unsafe impl ByteValued for DiskHeader {}
+
 #[repr(C)]
 pub struct MemoryRange {
     pub gpa: u64,
@@ -192,6 +253,10 @@ impl MemoryRangeTable {
         self.data.push(range)
     }
 
+    pub fn extend(&mut self, table: MemoryRangeTable) {
+        self.data.extend(table.data)
+    }
+
     pub fn read_from(fd: &mut dyn Read, length: u64) -> Result<MemoryRangeTable, MigratableError> {
         assert!(length as usize % std::mem::size_of::<MemoryRange>() == 0);
 
@@ -214,6 +279,11 @@ impl MemoryRangeTable {
         (std::mem::size_of::<MemoryRange>() * self.data.len()) as u64
     }
 
+    /// Number of bytes of guest memory covered by the ranges.
+    pub fn memory_size(&self) -> u64 {
+        self.data.iter().map(|range| range.length).sum()
+    }
+
     pub fn write_to(&self, fd: &mut dyn Write) -> Result<(), MigratableError> {
         fd.write_all(unsafe {
             std::slice::from_raw_parts(
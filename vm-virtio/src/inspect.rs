@@ -0,0 +1,30 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// Decision of a `DescriptorInspector` on a descriptor chain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    /// The device processes the chain as usual.
+    Process,
+    /// The device gives the chain back to the driver without processing it.
+    Drop,
+}
+
+/// Read-only view of a descriptor chain a device is about to process.
+pub struct DescriptorChainView<'a> {
+    /// Index of the queue the chain was taken from.
+    pub queue_index: u16,
+    /// Index of the head descriptor of the chain.
+    pub head_index: u16,
+    /// Data the driver made readable along the chain.
+    pub data: &'a [u8],
+}
+
+/// Hook a device calls with each descriptor chain before processing it, to
+/// build custom filtering or accounting on top of the device. It's called
+/// from the threads of the device, which it slows down, so it must be quick.
+pub trait DescriptorInspector: Send + Sync {
+    fn inspect(&self, chain: &DescriptorChainView) -> Verdict;
+}
@@ -17,7 +17,9 @@ extern crate serde_derive;
 
 use std::fmt;
 
+pub mod inspect;
 pub mod queue;
+pub use inspect::*;
 pub use queue::*;
 
 pub type VirtioIommuRemapping =
@@ -43,6 +45,7 @@ pub enum VirtioDeviceType {
     TYPE_FS = 26,
     TYPE_PMEM = 27,
     TYPE_WATCHDOG = 35, // Temporary until official number allocated
+    TYPE_GPIO = 41,
     TYPE_UNKNOWN = 0xFF,
 }
 
@@ -63,6 +66,7 @@ impl From<u32> for VirtioDeviceType {
             26 => VirtioDeviceType::TYPE_FS,
             27 => VirtioDeviceType::TYPE_PMEM,
             35 => VirtioDeviceType::TYPE_WATCHDOG,
+            41 => VirtioDeviceType::TYPE_GPIO,
             _ => VirtioDeviceType::TYPE_UNKNOWN,
         }
     }
@@ -88,6 +92,7 @@ impl fmt::Display for VirtioDeviceType {
             VirtioDeviceType::TYPE_FS => "fs",
             VirtioDeviceType::TYPE_PMEM => "pmem",
             VirtioDeviceType::TYPE_WATCHDOG => "watchdog",
+            VirtioDeviceType::TYPE_GPIO => "gpio",
             VirtioDeviceType::TYPE_UNKNOWN => "UNKNOWN",
         };
         write!(f, "{}", output)
@@ -13,7 +13,7 @@ use std::cmp::min;
 use std::convert::TryInto;
 use std::fmt::{self, Display};
 use std::num::Wrapping;
-use std::sync::atomic::{fence, Ordering};
+use std::sync::atomic::{fence, AtomicBool, Ordering};
 use std::sync::Arc;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
@@ -113,6 +113,8 @@ pub struct DescriptorChain<'a> {
     table_size: u16,
     ttl: u16, // used to prevent infinite chain cycles
     iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+    // Set on the queue the chain is from when it's longer than its ttl.
+    broken: Option<Arc<AtomicBool>>,
 
     /// Reference to guest memory
     pub mem: &'a GuestMemoryMmap,
@@ -180,6 +182,7 @@ impl<'a> DescriptorChain<'a> {
             flags: desc.flags,
             next: desc.next,
             iommu_mapping_cb,
+            broken: None,
         };
 
         if chain.is_valid() {
@@ -216,17 +219,24 @@ impl<'a> DescriptorChain<'a> {
                 (desc.addr, None)
             };
 
+        let table_size: u16 = (self.len / 16)
+            .try_into()
+            .map_err(|_| Error::InvalidIndirectDescriptor)?;
+
+        // The descriptors from the indirect table count towards the length
+        // of the chain.
         let chain = DescriptorChain {
             mem: self.mem,
             desc_table: self.addr,
-            table_size: (self.len / 16).try_into().unwrap(),
-            ttl: (self.len / 16).try_into().unwrap(),
+            table_size,
+            ttl: min(table_size, self.ttl),
             index: 0,
             addr: GuestAddress(desc_addr),
             len: desc.len,
             flags: desc.flags,
             next: desc.next,
             iommu_mapping_cb,
+            broken: self.broken.clone(),
         };
 
         if !chain.is_valid() {
@@ -293,6 +303,9 @@ impl<'a> DescriptorChain<'a> {
     ///
     /// Note that this is distinct from the next descriptor chain returned by `AvailIter`, which is
     /// the head of the next _available_ descriptor chain.
+    ///
+    /// A chain going on once its ttl is exhausted, such as one looping on
+    /// itself, is cut short and marks its queue as broken.
     pub fn next_descriptor(&self) -> Option<DescriptorChain<'a>> {
         if self.has_next() {
             DescriptorChain::checked_new(
@@ -304,9 +317,16 @@ impl<'a> DescriptorChain<'a> {
             )
             .map(|mut c| {
                 c.ttl = self.ttl - 1;
+                c.broken = self.broken.clone();
                 c
             })
         } else {
+            if self.flags & VIRTQ_DESC_F_NEXT != 0 {
+                if let Some(broken) = &self.broken {
+                    error!("Descriptor chain too long, the queue is broken");
+                    broken.store(true, Ordering::Release);
+                }
+            }
             None
         }
     }
@@ -331,6 +351,7 @@ pub struct AvailIter<'a, 'b> {
     queue_size: u16,
     next_avail: &'b mut Wrapping<u16>,
     iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+    broken: Option<Arc<AtomicBool>>,
 }
 
 impl<'a, 'b> AvailIter<'a, 'b> {
@@ -344,6 +365,7 @@ impl<'a, 'b> AvailIter<'a, 'b> {
             queue_size: 0,
             next_avail: q_next_avail,
             iommu_mapping_cb: None,
+            broken: None,
         }
     }
 }
@@ -356,6 +378,13 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
             return None;
         }
 
+        // Nothing is processed anymore once a chain broke the queue.
+        if let Some(broken) = &self.broken {
+            if broken.load(Ordering::Acquire) {
+                return None;
+            }
+        }
+
         let offset = (4 + (self.next_index.0 % self.queue_size) * 2) as usize;
         let avail_addr = match self.mem.checked_offset(self.avail_ring, offset) {
             Some(a) => a,
@@ -373,17 +402,19 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
 
         self.next_index += Wrapping(1);
 
-        let ret = DescriptorChain::checked_new(
+        let mut chain = DescriptorChain::checked_new(
             self.mem,
             self.desc_table,
             self.queue_size,
             desc_index,
             self.iommu_mapping_cb.clone(),
-        );
-        if ret.is_some() {
-            *self.next_avail += Wrapping(1);
-        }
-        ret
+        )?;
+        // The ttl of the chain bounds the work done by the device, whatever
+        // the driver made available, and marks the queue as broken once hit.
+        chain.broken = self.broken.clone();
+
+        *self.next_avail += Wrapping(1);
+        Some(chain)
     }
 }
 
@@ -429,6 +460,11 @@ pub struct Queue {
 
     /// The last used value when using EVENT_IDX
     signalled_used: Option<Wrapping<u16>>,
+
+    /// Set once the driver made a chain longer than the queue available,
+    /// the queue not being processed anymore until the device is reset
+    #[serde(skip)]
+    broken: Arc<AtomicBool>,
 }
 
 impl Queue {
@@ -447,6 +483,7 @@ impl Queue {
             iommu_mapping_cb: None,
             event_idx: false,
             signalled_used: None,
+            broken: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -492,6 +529,7 @@ impl Queue {
         self.used_ring = GuestAddress(0);
         self.event_idx = false;
         self.signalled_used = None;
+        self.broken.store(false, Ordering::Release);
     }
 
     pub fn is_valid(&self, mem: &GuestMemoryMmap) -> bool {
@@ -555,6 +593,10 @@ impl Queue {
 
     /// A consuming iterator over all available descriptor chain heads offered by the driver.
     pub fn iter<'a, 'b>(&'b mut self, mem: &'a GuestMemoryMmap) -> AvailIter<'a, 'b> {
+        if self.broken.load(Ordering::Acquire) {
+            return AvailIter::new(mem, &mut self.next_avail);
+        }
+
         let queue_size = self.actual_size();
         let avail_ring = self.avail_ring;
 
@@ -581,6 +623,7 @@ impl Queue {
             queue_size,
             next_avail: &mut self.next_avail,
             iommu_mapping_cb: self.iommu_mapping_cb.clone(),
+            broken: Some(self.broken.clone()),
         }
     }
 
@@ -1065,6 +1108,63 @@ pub mod tests {
             assert_eq!(i.next, j + 1);
             i = i.next_descriptor().unwrap();
         }
+
+        // an indirect table can't hold more descriptors than a queue
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x20_0000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        vq.dtable[0].set(0x1000, 0x10_0000, VIRTQ_DESC_F_INDIRECT, 0);
+        let c = DescriptorChain::checked_new(m, vq.start(), 16, 0, None).unwrap();
+        assert!(c.new_from_indirect().is_err());
+    }
+
+    #[test]
+    fn test_queue_broken() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+
+        // the chains are (0, 1, 2) and (3, 4, 5, 6), the second one looping
+        // back on its first descriptor
+        for j in 0..7 {
+            vq.dtable[j].set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, (j + 1) as u16);
+        }
+        vq.dtable[2].flags.set(0);
+        vq.dtable[6].set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 3);
+        vq.avail.ring[0].set(0);
+        vq.avail.ring[1].set(3);
+        vq.avail.ring[2].set(0);
+        vq.avail.idx.set(3);
+
+        {
+            let mut i = q.iter(m);
+            assert_eq!(i.next().unwrap().into_iter().count(), 3);
+
+            // the loop is cut short once the chain is as long as the queue...
+            assert_eq!(i.next().unwrap().into_iter().count(), 16);
+            assert!(i.next().is_none());
+        }
+        assert!(q.broken.load(Ordering::Acquire));
+        assert_eq!(q.next_avail.0, 2);
+
+        // ...and the queue isn't processed anymore
+        assert!(q.iter(m).next().is_none());
+
+        // until the device is reset
+        q.reset();
+        assert!(!q.broken.load(Ordering::Acquire));
+
+        // the work done on a looping indirect table is bounded as well
+        let mut q = vq.create_queue();
+        vq.dtable[0].set(0x2000, 0x100, VIRTQ_DESC_F_INDIRECT, 0);
+        for j in 0..16 {
+            let desc = VirtqDesc::new(GuestAddress(0x2000 + (j * 16)), m);
+            desc.set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, ((j + 1) % 16) as u16);
+        }
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+        let c = q.iter(m).next().unwrap();
+        assert_eq!(c.new_from_indirect().unwrap().into_iter().count(), 16);
+        assert!(q.broken.load(Ordering::Acquire));
     }
 
     #[test]
@@ -2,18 +2,100 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 //
+use crate::config::AcpiTableConfig;
 use crate::cpu::CpuManager;
 use crate::device_manager::DeviceManager;
-use crate::memory_manager::MemoryManager;
+use crate::memory_manager::{MemoryHotplugRegion, MemoryManager};
 use crate::vm::NumaNodes;
 #[cfg(target_arch = "x86_64")]
 use acpi_tables::sdt::GenericAddress;
 use acpi_tables::{aml::Aml, rsdp::RSDP, sdt::SDT};
 
 use bitflags::bitflags;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use vm_memory::GuestRegionMmap;
-use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+// Tables the VMM generates itself, which the user can't provide.
+const GENERATED_TABLES: [&[u8; 4]; 9] = [
+    b"RSD ", b"RSDT", b"XSDT", b"DSDT", b"FACP", b"APIC", b"MCFG", b"SRAT", b"SLIT",
+];
+// Generated along with the vTPM only.
+const TPM2_SIGNATURE: &[u8; 4] = b"TPM2";
+
+const SDT_HEADER_LEN: usize = 36;
+
+/// Maximum size of all the tables provided by the user, which must fit in
+/// the area the ACPI tables are placed in, next to the generated ones.
+pub const MAX_USER_TABLES_SIZE: usize = 128 << 10;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot read the table file.
+    ReadTable(PathBuf, io::Error),
+    /// The table is shorter than its header, or its header tells another
+    /// length.
+    InvalidTableLength(PathBuf, usize),
+    /// The table signature isn't made of 4 uppercase letters, digits or
+    /// underscores.
+    InvalidTableSignature(PathBuf),
+    /// The VMM generates the table with this signature itself.
+    ConflictingTable(PathBuf, String),
+    /// The tables are larger than MAX_USER_TABLES_SIZE.
+    TablesTooLarge(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Checks a table provided by the user, recomputing its checksum.
+fn check_user_table(path: &Path, data: Vec<u8>, tpm: bool) -> Result<SDT> {
+    if data.len() < SDT_HEADER_LEN
+        || u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize != data.len()
+    {
+        return Err(Error::InvalidTableLength(path.to_path_buf(), data.len()));
+    }
+
+    let signature = &data[..4];
+    if !signature
+        .iter()
+        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || *c == b'_')
+    {
+        return Err(Error::InvalidTableSignature(path.to_path_buf()));
+    }
+    if GENERATED_TABLES.iter().any(|s| &s[..] == signature)
+        || (tpm && &TPM2_SIGNATURE[..] == signature)
+    {
+        return Err(Error::ConflictingTable(
+            path.to_path_buf(),
+            String::from_utf8_lossy(signature).into_owned(),
+        ));
+    }
+
+    Ok(SDT::from_bytes(data))
+}
+
+/// Loads the tables provided by the user, to be linked into the XSDT. The
+/// TPM2 table is generated when the VM has a vTPM.
+pub fn load_user_tables(configs: &[AcpiTableConfig], tpm: bool) -> Result<Vec<SDT>> {
+    let mut tables = Vec::new();
+    let mut size = 0;
+    for config in configs {
+        let data = fs::read(&config.path).map_err(|e| Error::ReadTable(config.path.clone(), e))?;
+        let table = check_user_table(&config.path, data, tpm)?;
+        size += table.len();
+        tables.push(table);
+    }
+    if size > MAX_USER_TABLES_SIZE {
+        return Err(Error::TablesTooLarge(size));
+    }
+
+    Ok(tables)
+}
 
 #[repr(packed)]
 #[derive(Default)]
@@ -69,10 +151,23 @@ impl MemoryAffinity {
         proximity_domain: u32,
         flags: MemAffinityFlags,
     ) -> Self {
-        let base_addr = region.start_addr().raw_value();
+        Self::from_range(
+            region.start_addr(),
+            region.len() as u64,
+            proximity_domain,
+            flags,
+        )
+    }
+
+    fn from_range(
+        base_addr: GuestAddress,
+        length: u64,
+        proximity_domain: u32,
+        flags: MemAffinityFlags,
+    ) -> Self {
+        let base_addr = base_addr.raw_value();
         let base_addr_lo = (base_addr & 0xffff_ffff) as u32;
         let base_addr_hi = (base_addr >> 32) as u32;
-        let length = region.len() as u64;
         let length_lo = (length & 0xffff_ffff) as u32;
         let length_hi = (length >> 32) as u32;
 
@@ -105,22 +200,96 @@ pub fn create_dsdt_table(
     dsdt
 }
 
+fn create_srat_header() -> SDT {
+    let mut srat = SDT::new(*b"SRAT", 36, 3, *b"CLOUDH", *b"CHSRAT  ", 1);
+    // SRAT reserved 12 bytes
+    srat.append_slice(&[0u8; 12]);
+
+    // Check the MemoryAffinity structure is the right size as expected by
+    // the ACPI specification.
+    assert_eq!(std::mem::size_of::<MemoryAffinity>(), 40);
+
+    srat
+}
+
+fn processor_affinity(proximity_domain: u32, x2apic_id: u32) -> ProcessorLocalX2ApicAffinity {
+    // Flags
+    // - Enabled = 1 (bit 0)
+    // - Reserved bits 1-31
+    let flags = 1;
+
+    ProcessorLocalX2ApicAffinity {
+        type_: 2,
+        length: 24,
+        proximity_domain,
+        x2apic_id,
+        flags,
+        clock_domain: 0,
+        ..Default::default()
+    }
+}
+
+// Without NUMA nodes, the RAM and the vCPUs all belong to proximity domain 0,
+// the SRAT only being there for the memory hotplug region.
+fn create_single_node_srat_table(
+    ram: &[(GuestAddress, u64)],
+    hotplug_region: &MemoryHotplugRegion,
+    max_vcpus: u8,
+) -> SDT {
+    let mut srat = create_srat_header();
+    for (base, size) in ram {
+        srat.append(MemoryAffinity::from_range(
+            *base,
+            *size,
+            0,
+            MemAffinityFlags::ENABLE,
+        ));
+    }
+    srat.append(MemoryAffinity::from_range(
+        GuestAddress(hotplug_region.base),
+        hotplug_region.size,
+        0,
+        MemAffinityFlags::ENABLE | MemAffinityFlags::HOTPLUGGABLE,
+    ));
+    for x2apic_id in 0..u32::from(max_vcpus) {
+        srat.append(processor_affinity(0, x2apic_id));
+    }
+
+    srat
+}
+
+// The vTPM is found through the TPM2 table, pointing at the control area of
+// its CRB interface.
+#[cfg(target_arch = "x86_64")]
+fn create_tpm2_table(control_area: GuestAddress) -> SDT {
+    // Revision 4 of the TPM2 table is 64 bytes long, without the log area
+    let mut tpm2 = SDT::new(*TPM2_SIGNATURE, 64, 4, *b"CLOUDH", *b"CHTPM2  ", 1);
+
+    // Platform Class: client
+    tpm2.write(36, 0u16);
+    // Address of the CRB control area
+    tpm2.write(40, control_area.0);
+    // Start Method: Command Response Buffer, without specific parameters
+    tpm2.write(48, 7u32);
+
+    tpm2.update_checksum();
+    tpm2
+}
+
 pub fn create_acpi_tables(
     guest_mem: &GuestMemoryMmap,
     device_manager: &Arc<Mutex<DeviceManager>>,
     cpu_manager: &Arc<Mutex<CpuManager>>,
     memory_manager: &Arc<Mutex<MemoryManager>>,
     numa_nodes: &NumaNodes,
+    user_tables: &[SDT],
 ) -> GuestAddress {
     #[cfg(target_arch = "x86_64")]
     // RSDP is at the EBDA
     let rsdp_offset = arch::layout::RSDP_POINTER;
     #[cfg(target_arch = "aarch64")]
     // TODO: For aarch64 place the ACPI tables in the last MiB of guest RAM
-    let rsdp_offset = {
-        use vm_memory::GuestMemory;
-        guest_mem.last_addr().checked_sub(1 << 20).unwrap()
-    };
+    let rsdp_offset = guest_mem.last_addr().checked_sub(1 << 20).unwrap();
 
     let mut tables: Vec<u64> = Vec::new();
 
@@ -187,14 +356,16 @@ pub fn create_acpi_tables(
     // MCFG reserved 8 bytes
     mcfg.append(0u64);
 
-    // 32-bit PCI enhanced configuration mechanism
-    mcfg.append(PCIRangeEntry {
-        base_address: arch::layout::PCI_MMCONFIG_START.0,
-        segment: 0,
-        start: 0,
-        end: 0,
-        ..Default::default()
-    });
+    // 32-bit PCI enhanced configuration mechanism, one entry per segment
+    for pci_segment in device_manager.lock().unwrap().pci_segments().iter() {
+        mcfg.append(PCIRangeEntry {
+            base_address: pci_segment.mmio_config_address,
+            segment: pci_segment.id,
+            start: 0,
+            end: 0,
+            ..Default::default()
+        });
+    }
 
     let mcfg_offset = madt_offset.checked_add(madt.len() as u64).unwrap();
     guest_mem
@@ -203,18 +374,35 @@ pub fn create_acpi_tables(
     tables.push(mcfg_offset.0);
 
     // SRAT and SLIT
-    // Only created if the NUMA nodes list is not empty.
-    let (prev_tbl_len, prev_tbl_off) = if numa_nodes.is_empty() {
-        (mcfg.len(), mcfg_offset)
+    // Only created if the NUMA nodes list is not empty, or for the SRAT to
+    // describe the memory hotplug region, as Linux expects.
+    let hotplug_region = memory_manager.lock().unwrap().hotplug_region();
+    let (mut prev_tbl_len, mut prev_tbl_off) = if numa_nodes.is_empty() {
+        if let Some(hotplug_region) = hotplug_region {
+            // Leaves the DIMMs plugged before a reboot out.
+            let ram: Vec<(GuestAddress, u64)> = guest_mem
+                .iter()
+                .filter(|region| region.start_addr().raw_value() < hotplug_region.base)
+                .map(|region| (region.start_addr(), region.len() as u64))
+                .collect();
+            let srat = create_single_node_srat_table(
+                &ram,
+                &hotplug_region,
+                cpu_manager.lock().unwrap().max_vcpus(),
+            );
+            let srat_offset = mcfg_offset.checked_add(mcfg.len() as u64).unwrap();
+            guest_mem
+                .write_slice(srat.as_slice(), srat_offset)
+                .expect("Error writing SRAT table");
+            tables.push(srat_offset.0);
+
+            (srat.len(), srat_offset)
+        } else {
+            (mcfg.len(), mcfg_offset)
+        }
     } else {
         // SRAT
-        let mut srat = SDT::new(*b"SRAT", 36, 3, *b"CLOUDH", *b"CHSRAT  ", 1);
-        // SRAT reserved 12 bytes
-        srat.append_slice(&[0u8; 12]);
-
-        // Check the MemoryAffinity structure is the right size as expected by
-        // the ACPI specification.
-        assert_eq!(std::mem::size_of::<MemoryAffinity>(), 40);
+        let mut srat = create_srat_header();
 
         for (node_id, node) in numa_nodes.iter() {
             let proximity_domain = *node_id as u32;
@@ -236,22 +424,7 @@ pub fn create_acpi_tables(
             }
 
             for cpu in node.cpus() {
-                let x2apic_id = *cpu as u32;
-
-                // Flags
-                // - Enabled = 1 (bit 0)
-                // - Reserved bits 1-31
-                let flags = 1;
-
-                srat.append(ProcessorLocalX2ApicAffinity {
-                    type_: 2,
-                    length: 24,
-                    proximity_domain,
-                    x2apic_id,
-                    flags,
-                    clock_domain: 0,
-                    ..Default::default()
-                });
+                srat.append(processor_affinity(proximity_domain, *cpu as u32));
             }
         }
 
@@ -291,6 +464,33 @@ pub fn create_acpi_tables(
         (slit.len(), slit_offset)
     };
 
+    // TPM2
+    // Only created along with the vTPM.
+    #[cfg(target_arch = "x86_64")]
+    if let Some(tpm) = device_manager.lock().unwrap().tpm() {
+        let tpm2 = create_tpm2_table(tpm.lock().unwrap().control_area_address());
+        let tpm2_offset = prev_tbl_off.checked_add(prev_tbl_len as u64).unwrap();
+        guest_mem
+            .write_slice(tpm2.as_slice(), tpm2_offset)
+            .expect("Error writing TPM2 table");
+        tables.push(tpm2_offset.0);
+
+        prev_tbl_len = tpm2.len();
+        prev_tbl_off = tpm2_offset;
+    }
+
+    // Tables provided by the user
+    for table in user_tables {
+        let offset = prev_tbl_off.checked_add(prev_tbl_len as u64).unwrap();
+        guest_mem
+            .write_slice(table.as_slice(), offset)
+            .expect("Error writing user ACPI table");
+        tables.push(offset.0);
+
+        prev_tbl_len = table.len();
+        prev_tbl_off = offset;
+    }
+
     // XSDT
     let mut xsdt = SDT::new(*b"XSDT", 36, 1, *b"CLOUDH", *b"CHXSDT  ", 1);
     for table in tables {
@@ -311,3 +511,129 @@ pub fn create_acpi_tables(
 
     rsdp_offset
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(signature: &[u8; 4], len: u32) -> Vec<u8> {
+        let mut data = vec![0u8; len as usize];
+        data[..4].copy_from_slice(signature);
+        data[4..8].copy_from_slice(&len.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_check_user_table() {
+        let path = Path::new("table.aml");
+
+        let sdt = check_user_table(path, table(b"SSDT", 40), false).unwrap();
+        assert_eq!(sdt.len(), 40);
+        assert_eq!(
+            sdt.as_slice().iter().fold(0u8, |s, b| s.wrapping_add(*b)),
+            0
+        );
+        // The TPM2 table is only generated along with the vTPM.
+        check_user_table(path, table(b"TPM2", 36), false).unwrap();
+        assert!(matches!(
+            check_user_table(path, table(b"TPM2", 36), true),
+            Err(Error::ConflictingTable(_, s)) if s == "TPM2"
+        ));
+
+        assert!(matches!(
+            check_user_table(path, table(b"SSDT", 20), false),
+            Err(Error::InvalidTableLength(_, 20))
+        ));
+        let mut data = table(b"SSDT", 40);
+        data.push(0);
+        assert!(matches!(
+            check_user_table(path, data, false),
+            Err(Error::InvalidTableLength(_, 41))
+        ));
+        assert!(matches!(
+            check_user_table(path, table(b"ss t", 40), false),
+            Err(Error::InvalidTableSignature(_))
+        ));
+        assert!(matches!(
+            check_user_table(path, table(b"DSDT", 40), false),
+            Err(Error::ConflictingTable(_, s)) if s == "DSDT"
+        ));
+    }
+
+    #[test]
+    fn test_load_user_tables() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), table(b"SSDT", 64)).unwrap();
+        let config = AcpiTableConfig {
+            path: file.path().to_path_buf(),
+        };
+
+        let tables = load_user_tables(&[config.clone(), config.clone()], false).unwrap();
+        assert_eq!(tables.len(), 2);
+
+        fs::write(file.path(), table(b"SSDT", MAX_USER_TABLES_SIZE as u32)).unwrap();
+        assert!(matches!(
+            load_user_tables(&[config.clone(), config], false),
+            Err(Error::TablesTooLarge(_))
+        ));
+
+        let config = AcpiTableConfig {
+            path: PathBuf::from("/nonexistent/table.aml"),
+        };
+        assert!(matches!(
+            load_user_tables(&[config], false),
+            Err(Error::ReadTable(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_single_node_srat_table() {
+        let hotplug_region = MemoryHotplugRegion {
+            base: 8 << 30,
+            size: 1 << 30,
+        };
+        let srat = create_single_node_srat_table(
+            &[(GuestAddress(0), 3 << 30), (GuestAddress(4 << 30), 1 << 30)],
+            &hotplug_region,
+            2,
+        );
+        let data = srat.as_slice();
+        assert_eq!(srat.len(), 48 + 3 * 40 + 2 * 24);
+        assert_eq!(data.iter().fold(0u8, |s, b| s.wrapping_add(*b)), 0);
+
+        // A memory affinity structure, in proximity domain 0.
+        let memory_affinity = |offset: usize| {
+            let entry = &data[offset..offset + 40];
+            assert_eq!(entry[0], 1);
+            assert_eq!(&entry[2..6], &[0; 4]);
+            let u64_at = |o: usize| {
+                u64::from(u32::from_le_bytes([
+                    entry[o],
+                    entry[o + 1],
+                    entry[o + 2],
+                    entry[o + 3],
+                ])) | u64::from(u32::from_le_bytes([
+                    entry[o + 4],
+                    entry[o + 5],
+                    entry[o + 6],
+                    entry[o + 7],
+                ])) << 32
+            };
+            let flags = u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]);
+            (u64_at(8), u64_at(16), flags)
+        };
+        assert_eq!(memory_affinity(48), (0, 3 << 30, 1));
+        assert_eq!(memory_affinity(88), (4 << 30, 1 << 30, 1));
+        // The hotplug region is enabled and hotpluggable.
+        assert_eq!(memory_affinity(128), (8 << 30, 1 << 30, 0b11));
+
+        for (i, offset) in [168, 192].iter().enumerate() {
+            let entry = &data[*offset..*offset + 24];
+            assert_eq!(entry[0], 2);
+            assert_eq!(
+                u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+                i as u32
+            );
+        }
+    }
+}
@@ -71,12 +71,30 @@ pub enum HttpError {
     /// Could not resize a memory zone
     VmResizeZone(ApiError),
 
+    /// Could not add a DIMM
+    VmAddDimm(ApiError),
+
+    /// Could not set the memory target of a VM
+    VmSetMemoryTarget(ApiError),
+
     /// Could not add a device to a VM
     VmAddDevice(ApiError),
 
     /// Could not remove a device from a VM
     VmRemoveDevice(ApiError),
 
+    /// Could not reset a device
+    VmResetDevice(ApiError),
+
+    /// Could not pause a device
+    VmPauseDevice(ApiError),
+
+    /// Could not resume a device
+    VmResumeDevice(ApiError),
+
+    /// Could not activate a device
+    VmActivateDevice(ApiError),
+
     /// Could not shut the VMM down
     VmmShutdown(ApiError),
 
@@ -98,6 +116,9 @@ pub enum HttpError {
     /// Could not add a vsock device to a VM
     VmAddVsock(ApiError),
 
+    /// Could not send a request to the guest agent
+    VmAgentRequest(ApiError),
+
     /// Could not get counters from VM
     VmCounters(ApiError),
 
@@ -107,8 +128,56 @@ pub enum HttpError {
     /// Error setting up migration sender
     VmSendMigration(ApiError),
 
+    /// Error failing over to the standby
+    VmFailover(ApiError),
+
     /// Error activating power button
     VmPowerButton(ApiError),
+
+    /// Could not get the guest RTC time
+    VmGetRtc(ApiError),
+
+    /// Could not set the guest RTC time
+    VmSetRtc(ApiError),
+
+    /// Could not get the launch measurement
+    VmLaunchMeasurement(ApiError),
+
+    /// Could not inject input events
+    VmInputEvent(ApiError),
+
+    /// Could not set the GPIO line
+    VmSetGpio(ApiError),
+
+    /// Could not get the GPIO lines
+    VmGpioLines(ApiError),
+
+    /// Could not start or stop the network capture
+    VmNetCapture(ApiError),
+
+    /// Could not set the battery state
+    VmSetBattery(ApiError),
+
+    /// Could not program the flow rules
+    VmSetFlowRules(ApiError),
+
+    /// Could not set the active network queues
+    VmSetNetQueues(ApiError),
+
+    /// Could not inject the NMI
+    VmInjectNmi(ApiError),
+
+    /// Could not read the guest memory
+    #[cfg(feature = "guest_debug")]
+    VmReadGuestMem(ApiError),
+
+    /// Could not write the guest memory
+    #[cfg(feature = "guest_debug")]
+    VmWriteGuestMem(ApiError),
+
+    /// Could not read the vCPU registers
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    VmVcpuRegisters(ApiError),
 }
 
 impl From<serde_json::Error> for HttpError {
@@ -201,29 +270,54 @@ lazy_static! {
             routes: HashMap::new(),
         };
 
+        r.routes.insert(endpoint!("/vm.activate-device"), Box::new(VmActionHandler::new(VmAction::ActivateDevice(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-device"), Box::new(VmActionHandler::new(VmAction::AddDevice(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.add-dimm"), Box::new(VmActionHandler::new(VmAction::AddDimm(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-disk"), Box::new(VmActionHandler::new(VmAction::AddDisk(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-fs"), Box::new(VmActionHandler::new(VmAction::AddFs(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-net"), Box::new(VmActionHandler::new(VmAction::AddNet(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-pmem"), Box::new(VmActionHandler::new(VmAction::AddPmem(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-vsock"), Box::new(VmActionHandler::new(VmAction::AddVsock(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.agent-request"), Box::new(VmActionHandler::new(VmAction::AgentRequest(Arc::default()))));
         r.routes.insert(endpoint!("/vm.boot"), Box::new(VmActionHandler::new(VmAction::Boot)));
         r.routes.insert(endpoint!("/vm.counters"), Box::new(VmActionHandler::new(VmAction::Counters)));
         r.routes.insert(endpoint!("/vm.create"), Box::new(VmCreate {}));
         r.routes.insert(endpoint!("/vm.delete"), Box::new(VmActionHandler::new(VmAction::Delete)));
+        r.routes.insert(endpoint!("/vm.failover"), Box::new(VmActionHandler::new(VmAction::Failover)));
+        r.routes.insert(endpoint!("/vm.gpio-lines"), Box::new(VmActionHandler::new(VmAction::GpioLines(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.get-rtc"), Box::new(VmActionHandler::new(VmAction::GetRtc)));
         r.routes.insert(endpoint!("/vm.info"), Box::new(VmInfo {}));
+        r.routes.insert(endpoint!("/vm.inject-nmi"), Box::new(VmActionHandler::new(VmAction::InjectNmi(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.input-event"), Box::new(VmActionHandler::new(VmAction::InputEvent(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.launch-measurement"), Box::new(VmActionHandler::new(VmAction::LaunchMeasurement)));
+        r.routes.insert(endpoint!("/vm.net-capture"), Box::new(VmActionHandler::new(VmAction::NetCapture(Arc::default()))));
         r.routes.insert(endpoint!("/vm.pause"), Box::new(VmActionHandler::new(VmAction::Pause)));
+        r.routes.insert(endpoint!("/vm.pause-device"), Box::new(VmActionHandler::new(VmAction::PauseDevice(Arc::default()))));
         r.routes.insert(endpoint!("/vm.power-button"), Box::new(VmActionHandler::new(VmAction::PowerButton)));
+        #[cfg(feature = "guest_debug")]
+        r.routes.insert(endpoint!("/vm.read-guest-mem"), Box::new(VmActionHandler::new(VmAction::ReadGuestMem(Arc::default()))));
         r.routes.insert(endpoint!("/vm.reboot"), Box::new(VmActionHandler::new(VmAction::Reboot)));
         r.routes.insert(endpoint!("/vm.receive-migration"), Box::new(VmActionHandler::new(VmAction::ReceiveMigration(Arc::default()))));
         r.routes.insert(endpoint!("/vm.remove-device"), Box::new(VmActionHandler::new(VmAction::RemoveDevice(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.reset-device"), Box::new(VmActionHandler::new(VmAction::ResetDevice(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resize"), Box::new(VmActionHandler::new(VmAction::Resize(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resize-zone"), Box::new(VmActionHandler::new(VmAction::ResizeZone(Arc::default()))));
         r.routes.insert(endpoint!("/vm.restore"), Box::new(VmActionHandler::new(VmAction::Restore(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resume"), Box::new(VmActionHandler::new(VmAction::Resume)));
+        r.routes.insert(endpoint!("/vm.resume-device"), Box::new(VmActionHandler::new(VmAction::ResumeDevice(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.set-flow-rules"), Box::new(VmActionHandler::new(VmAction::SetFlowRules(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.set-gpio"), Box::new(VmActionHandler::new(VmAction::SetGpio(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.set-memory-target"), Box::new(VmActionHandler::new(VmAction::SetMemoryTarget(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.set-net-queues"), Box::new(VmActionHandler::new(VmAction::SetNetQueues(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.set-rtc"), Box::new(VmActionHandler::new(VmAction::SetRtc(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.set-battery"), Box::new(VmActionHandler::new(VmAction::SetBattery(Arc::default()))));
         r.routes.insert(endpoint!("/vm.send-migration"), Box::new(VmActionHandler::new(VmAction::SendMigration(Arc::default()))));
         r.routes.insert(endpoint!("/vm.shutdown"), Box::new(VmActionHandler::new(VmAction::Shutdown)));
         r.routes.insert(endpoint!("/vm.snapshot"), Box::new(VmActionHandler::new(VmAction::Snapshot(Arc::default()))));
+        #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+        r.routes.insert(endpoint!("/vm.vcpu-registers"), Box::new(VmActionHandler::new(VmAction::VcpuRegisters(Arc::default()))));
+        #[cfg(feature = "guest_debug")]
+        r.routes.insert(endpoint!("/vm.write-guest-mem"), Box::new(VmActionHandler::new(VmAction::WriteGuestMem(Arc::default()))));
         r.routes.insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
         r.routes.insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
 
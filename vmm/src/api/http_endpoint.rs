@@ -4,13 +4,21 @@
 //
 
 use crate::api::http::{error_response, EndpointHandler, HttpError};
+#[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+use crate::api::vm_vcpu_registers;
 use crate::api::{
-    vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_vsock, vm_boot,
-    vm_counters, vm_create, vm_delete, vm_info, vm_pause, vm_power_button, vm_reboot,
-    vm_receive_migration, vm_remove_device, vm_resize, vm_resize_zone, vm_restore, vm_resume,
-    vm_send_migration, vm_shutdown, vm_snapshot, vmm_ping, vmm_shutdown, ApiRequest, VmAction,
-    VmConfig,
+    vm_activate_device, vm_add_device, vm_add_dimm, vm_add_disk, vm_add_fs, vm_add_net,
+    vm_add_pmem, vm_add_vsock, vm_agent_request, vm_boot, vm_counters, vm_create, vm_delete,
+    vm_failover, vm_get_rtc, vm_gpio_lines, vm_info, vm_inject_nmi, vm_input_event,
+    vm_launch_measurement, vm_net_capture, vm_pause, vm_pause_device, vm_power_button, vm_reboot,
+    vm_receive_migration, vm_remove_device, vm_reset_device, vm_resize, vm_resize_zone, vm_restore,
+    vm_resume, vm_resume_device, vm_send_migration, vm_set_battery, vm_set_flow_rules, vm_set_gpio,
+    vm_set_memory_target, vm_set_net_queues, vm_set_rtc, vm_shutdown, vm_snapshot, vmm_ping,
+    vmm_shutdown, ApiError, ApiRequest, VmAction, VmConfig,
 };
+#[cfg(feature = "guest_debug")]
+use crate::api::{vm_read_guest_mem, vm_write_guest_mem};
+use crate::vm::Error as VmError;
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
@@ -43,6 +51,16 @@ impl EndpointHandler for VmCreate {
                             .map_err(HttpError::VmCreate)
                         {
                             Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            // Report the configuration problems in a readable
+                            // form, so they can be acted upon.
+                            Err(HttpError::VmCreate(ApiError::VmCreate(
+                                VmError::ConfigValidation(e),
+                            ))) => {
+                                let mut response =
+                                    Response::new(Version::Http11, StatusCode::BadRequest);
+                                response.set_body(Body::new(e.to_string()));
+                                response
+                            }
                             Err(e) => error_response(e, StatusCode::InternalServerError),
                         }
                     }
@@ -119,6 +137,13 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmAddVsock),
 
+                AgentRequest(_) => vm_agent_request(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmAgentRequest),
+
                 RemoveDevice(_) => vm_remove_device(
                     api_notifier,
                     api_sender,
@@ -126,6 +151,34 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmRemoveDevice),
 
+                ResetDevice(_) => vm_reset_device(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmResetDevice),
+
+                PauseDevice(_) => vm_pause_device(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmPauseDevice),
+
+                ResumeDevice(_) => vm_resume_device(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmResumeDevice),
+
+                ActivateDevice(_) => vm_activate_device(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmActivateDevice),
+
                 Resize(_) => vm_resize(
                     api_notifier,
                     api_sender,
@@ -140,6 +193,107 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmResizeZone),
 
+                AddDimm(_) => vm_add_dimm(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmAddDimm),
+
+                SetMemoryTarget(_) => vm_set_memory_target(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmSetMemoryTarget),
+
+                SetRtc(_) => vm_set_rtc(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmSetRtc),
+
+                InputEvent(_) => vm_input_event(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmInputEvent),
+
+                SetGpio(_) => vm_set_gpio(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmSetGpio),
+
+                GpioLines(_) => vm_gpio_lines(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmGpioLines),
+
+                NetCapture(_) => vm_net_capture(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmNetCapture),
+
+                SetBattery(_) => vm_set_battery(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmSetBattery),
+
+                SetFlowRules(_) => vm_set_flow_rules(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmSetFlowRules),
+
+                SetNetQueues(_) => vm_set_net_queues(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmSetNetQueues),
+
+                InjectNmi(_) => vm_inject_nmi(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmInjectNmi),
+
+                #[cfg(feature = "guest_debug")]
+                ReadGuestMem(_) => vm_read_guest_mem(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmReadGuestMem),
+
+                #[cfg(feature = "guest_debug")]
+                WriteGuestMem(_) => vm_write_guest_mem(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmWriteGuestMem),
+
+                #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+                VcpuRegisters(_) => vm_vcpu_registers(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmVcpuRegisters),
+
                 Restore(_) => vm_restore(
                     api_notifier,
                     api_sender,
@@ -181,6 +335,7 @@ impl EndpointHandler for VmActionHandler {
                 PowerButton => {
                     vm_power_button(api_notifier, api_sender).map_err(HttpError::VmPowerButton)
                 }
+                Failover => vm_failover(api_notifier, api_sender).map_err(HttpError::VmFailover),
                 _ => Err(HttpError::BadRequest),
             }
         }
@@ -195,6 +350,9 @@ impl EndpointHandler for VmActionHandler {
         use VmAction::*;
         match self.action {
             Counters => vm_counters(api_notifier, api_sender).map_err(HttpError::VmCounters),
+            GetRtc => vm_get_rtc(api_notifier, api_sender).map_err(HttpError::VmGetRtc),
+            LaunchMeasurement => vm_launch_measurement(api_notifier, api_sender)
+                .map_err(HttpError::VmLaunchMeasurement),
             _ => Err(HttpError::BadRequest),
         }
     }
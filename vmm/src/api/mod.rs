@@ -39,13 +39,25 @@ pub mod http_endpoint;
 use crate::config::{
     DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
 };
+use crate::cpu::MAX_THROTTLE_PERCENTAGE;
+use crate::device_manager::DiskIoPriorityInfo;
 use crate::device_tree::DeviceTree;
-use crate::vm::{Error as VmError, VmState};
+use crate::memory_manager::MemoryHotplugRegion;
+use crate::oom_policy::OomPolicyInfo;
+use crate::replication::ReplicationInfo;
+use crate::vm::{CrashInfo, Error as VmError, MemoryTargetInfo, VmState};
 use micro_http::Body;
+use net_util::{FlowRule, IPPROTO_TCP, IPPROTO_UDP};
 use std::io;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+pub use virtio_devices::vsock::agent;
+use virtio_devices::vsock::agent::AgentRequest;
+pub use virtio_devices::InputEvent;
 use vm_migration::MigratableError;
+use vm_virtio::DescriptorInspector;
 use vmm_sys_util::eventfd::EventFd;
 
 /// API errors are sent back from the VMM API server through the ApiResponse.
@@ -114,12 +126,30 @@ pub enum ApiError {
     /// The memory zone could not be resized.
     VmResizeZone(VmError),
 
+    /// The DIMM could not be added.
+    VmAddDimm(VmError),
+
+    /// The memory target could not be set.
+    VmSetMemoryTarget(VmError),
+
     /// The device could not be added to the VM.
     VmAddDevice(VmError),
 
     /// The device could not be removed from the VM.
     VmRemoveDevice(VmError),
 
+    /// The device could not be reset.
+    VmResetDevice(VmError),
+
+    /// The device could not be paused.
+    VmPauseDevice(VmError),
+
+    /// The device could not be resumed.
+    VmResumeDevice(VmError),
+
+    /// The device could not be activated.
+    VmActivateDevice(VmError),
+
     /// Cannot create seccomp filter
     CreateSeccompFilter(seccomp::SeccompError),
 
@@ -141,14 +171,68 @@ pub enum ApiError {
     /// The vsock device could not be added to the VM.
     VmAddVsock(VmError),
 
+    /// The request to the guest agent failed.
+    VmAgentRequest(VmError),
+
     /// Error starting migration receiever
     VmReceiveMigration(MigratableError),
 
     /// Error starting migration sender
     VmSendMigration(MigratableError),
 
+    /// Error failing over to the standby
+    VmFailover(MigratableError),
+
     /// Error triggering power button
     VmPowerButton(VmError),
+
+    /// The guest RTC time could not be read.
+    VmGetRtc(VmError),
+
+    /// The guest RTC time could not be set.
+    VmSetRtc(VmError),
+
+    /// The descriptor inspector could not be registered.
+    VmSetDescriptorInspector(VmError),
+
+    /// The launch measurement could not be retrieved.
+    VmLaunchMeasurement(VmError),
+
+    /// The input events could not be injected.
+    VmInputEvent(VmError),
+
+    /// The GPIO line could not be set.
+    VmSetGpio(VmError),
+
+    /// The state of the GPIO lines could not be retrieved.
+    VmGpioLines(VmError),
+
+    /// The capture of the network frames could not be started or stopped.
+    VmNetCapture(VmError),
+
+    /// The battery state could not be set.
+    VmSetBattery(VmError),
+
+    /// The flow rules could not be programmed.
+    VmSetFlowRules(VmError),
+
+    /// The number of active queues could not be set.
+    VmSetNetQueues(VmError),
+
+    /// The NMI could not be injected.
+    VmInjectNmi(VmError),
+
+    /// The guest memory could not be read.
+    #[cfg(feature = "guest_debug")]
+    VmReadGuestMem(VmError),
+
+    /// The guest memory could not be written.
+    #[cfg(feature = "guest_debug")]
+    VmWriteGuestMem(VmError),
+
+    /// The vCPU registers could not be read.
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    VmVcpuRegisters(VmError),
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -157,7 +241,25 @@ pub struct VmInfo {
     pub config: Arc<Mutex<VmConfig>>,
     pub state: VmState,
     pub memory_actual_size: u64,
+    /// Guest RAM locked into host memory, once created with locked memory
+    pub memory_locked_size: Option<u64>,
+    /// Guest address range reserved for the hotpluggable memory, once created
+    pub memory_hotplug_region: Option<MemoryHotplugRegion>,
     pub device_tree: Option<Arc<Mutex<DeviceTree>>>,
+    pub cgroup: Option<PathBuf>,
+    pub memory_target: Option<MemoryTargetInfo>,
+    pub oom_policy: Option<OomPolicyInfo>,
+    pub triple_fault: Option<CrashInfo>,
+    /// Last time the guest reported a panic, through the pvpanic device
+    pub guest_panic: Option<CrashInfo>,
+    /// Whether nested virtualization is exposed to the guest, once booted
+    pub nested: Option<bool>,
+    /// Whether the TSC-deadline timer is exposed to the guest, once booted
+    pub tsc_deadline: Option<bool>,
+    /// Progress of the replication to a standby, until failover
+    pub replication: Option<ReplicationInfo>,
+    /// I/O priority of the disks given one
+    pub disk_io_priorities: Option<Vec<DiskIoPriorityInfo>>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -178,15 +280,192 @@ pub struct VmResizeZoneData {
     pub desired_ram: u64,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmAddDimmData {
+    /// Size of the DIMM in bytes, a multiple of 128MiB
+    pub size: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmSetMemoryTargetData {
+    pub size: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmRtcData {
+    /// Time of the guest RTC, in seconds since the UNIX epoch
+    pub time: u64,
+}
+
+#[cfg(feature = "guest_debug")]
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmReadGuestMemData {
+    /// Guest physical address to read from
+    pub gpa: u64,
+    /// Number of bytes to read
+    pub len: usize,
+}
+
+#[cfg(feature = "guest_debug")]
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmWriteGuestMemData {
+    /// Guest physical address to write to
+    pub gpa: u64,
+    pub data: Vec<u8>,
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmVcpuRegistersData {
+    /// Identifier of the vCPU
+    pub id: u8,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmInputEventData {
+    /// Identifier of the virtio-input device
+    pub id: String,
+    pub events: Vec<InputEvent>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmGpioData {
+    /// Identifier of the virtio-gpio device
+    pub id: String,
+    pub line: u16,
+    pub value: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmGpioLinesData {
+    /// Identifier of the virtio-gpio device
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmNetCaptureData {
+    /// Identifier of the virtio-net device
+    pub id: String,
+    /// File the frames are written to, the capture being stopped if unset
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmBatteryData {
+    /// Whether the AC adapter is plugged in
+    pub ac_online: bool,
+    /// Remaining capacity of the battery, in percent
+    pub charge: u8,
+}
+
+impl Default for VmBatteryData {
+    fn default() -> Self {
+        VmBatteryData {
+            ac_online: true,
+            charge: 100,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Frames sent to the guest matching all the fields set go to the receive
+/// queue of the queue pair `queue`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct FlowRuleData {
+    #[serde(default)]
+    pub protocol: Option<FlowProtocol>,
+    #[serde(default)]
+    pub src_ip: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub dst_ip: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub src_port: Option<u16>,
+    #[serde(default)]
+    pub dst_port: Option<u16>,
+    pub queue: u16,
+}
+
+impl From<&FlowRuleData> for FlowRule {
+    fn from(rule: &FlowRuleData) -> Self {
+        FlowRule {
+            protocol: rule.protocol.map(|p| match p {
+                FlowProtocol::Tcp => IPPROTO_TCP,
+                FlowProtocol::Udp => IPPROTO_UDP,
+            }),
+            src_ip: rule.src_ip,
+            dst_ip: rule.dst_ip,
+            src_port: rule.src_port,
+            dst_port: rule.dst_port,
+            queue: rule.queue,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmFlowRulesData {
+    /// Identifier of the virtio-net device
+    pub id: String,
+    /// Rules replacing the current ones, checked in order
+    #[serde(default)]
+    pub rules: Vec<FlowRuleData>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmNetQueuesData {
+    /// Identifier of the vhost-user-net device
+    pub id: String,
+    /// Number of RX and TX queues the backend processes
+    pub num_queues: usize,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmInjectNmiData {
+    /// Identifier of the vCPU, the NMI being injected into all of them if
+    /// unset
+    #[serde(default)]
+    pub id: Option<u8>,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default)]
 pub struct VmRemoveDeviceData {
     pub id: String,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmResetDeviceData {
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmPauseDeviceData {
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmResumeDeviceData {
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmActivateDeviceData {
+    pub id: String,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default)]
 pub struct VmSnapshotConfig {
     /// The snapshot destination URL
     pub destination_url: String,
+    /// Only snapshot the VM config and the state of the devices, leaving the
+    /// guest memory out. Such a snapshot can't be restored.
+    #[serde(default)]
+    pub devices_only: bool,
 }
 
 #[derive(Clone, Deserialize, Serialize, Default)]
@@ -199,6 +478,78 @@ pub struct VmReceiveMigrationData {
 pub struct VmSendMigrationData {
     /// URL to migrate the VM to
     pub destination_url: String,
+    /// Copy the content of the disks, for when the storage is not shared
+    #[serde(default)]
+    pub copy_disks: bool,
+    /// Throttle the vCPUs when the guest dirties its memory faster than it
+    /// is sent
+    #[serde(default)]
+    pub auto_converge: Option<MigrationThrottleConfig>,
+    /// Keep sending the memory dirtied by the guest every given number of
+    /// milliseconds, until failover, instead of completing the migration
+    #[serde(default)]
+    pub replication_interval: Option<u64>,
+    /// Let the destination check its CPU can expose every CPUID feature the
+    /// guest relies on, refusing the migration otherwise
+    #[serde(default)]
+    pub check_cpuid: bool,
+}
+
+/// How hard the vCPUs are throttled while the migration doesn't converge,
+/// in percentage of the time they are kept from running.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MigrationThrottleConfig {
+    /// Throttling applied after the first pass failing to converge
+    #[serde(default = "default_throttle_initial")]
+    pub initial: u8,
+    /// Throttling added after each of the following passes
+    #[serde(default = "default_throttle_increment")]
+    pub increment: u8,
+    /// Throttling never exceeded
+    #[serde(default = "default_throttle_max")]
+    pub max: u8,
+}
+
+fn default_throttle_initial() -> u8 {
+    20
+}
+
+fn default_throttle_increment() -> u8 {
+    10
+}
+
+fn default_throttle_max() -> u8 {
+    99
+}
+
+impl MigrationThrottleConfig {
+    pub fn is_valid(&self) -> bool {
+        self.initial > 0 && self.initial <= self.max && self.max <= MAX_THROTTLE_PERCENTAGE
+    }
+
+    /// Throttling to apply after a pass sending `size` dirty bytes when
+    /// the vCPUs are throttled by `current`, the previous pass having sent
+    /// `previous_size` bytes. It starts or increases when the guest dirties
+    /// its memory faster than half the migration bandwidth.
+    pub fn next_percentage(&self, current: u8, size: u64, previous_size: u64) -> u8 {
+        if size <= previous_size / 2 || current >= self.max {
+            current
+        } else if current == 0 {
+            self.initial
+        } else {
+            current.saturating_add(self.increment).min(self.max)
+        }
+    }
+}
+
+impl Default for MigrationThrottleConfig {
+    fn default() -> Self {
+        MigrationThrottleConfig {
+            initial: default_throttle_initial(),
+            increment: default_throttle_increment(),
+            max: default_throttle_max(),
+        }
+    }
 }
 
 pub enum ApiResponsePayload {
@@ -252,6 +603,54 @@ pub enum ApiRequest {
     /// Get counters for a VM.
     VmCounters(Sender<ApiResponse>),
 
+    /// Get the time of the guest RTC.
+    VmGetRtc(Sender<ApiResponse>),
+
+    /// Set the time of the guest RTC.
+    VmSetRtc(Arc<VmRtcData>, Sender<ApiResponse>),
+
+    /// Register a hook inspecting the descriptor chains of a device.
+    VmSetDescriptorInspector(String, Arc<dyn DescriptorInspector>, Sender<ApiResponse>),
+
+    /// Get the launch measurement of a guest with encrypted memory.
+    VmLaunchMeasurement(Sender<ApiResponse>),
+
+    /// Inject events into a virtio-input device.
+    VmInputEvent(Arc<VmInputEventData>, Sender<ApiResponse>),
+
+    /// Drive an input line of a virtio-gpio device.
+    VmSetGpio(Arc<VmGpioData>, Sender<ApiResponse>),
+
+    /// Get the state of the lines of a virtio-gpio device.
+    VmGpioLines(Arc<VmGpioLinesData>, Sender<ApiResponse>),
+
+    /// Start or stop capturing the frames of a virtio-net device.
+    VmNetCapture(Arc<VmNetCaptureData>, Sender<ApiResponse>),
+
+    /// Set the state of the battery and of the AC adapter.
+    VmSetBattery(Arc<VmBatteryData>, Sender<ApiResponse>),
+
+    /// Program the flow rules of a virtio-net device.
+    VmSetFlowRules(Arc<VmFlowRulesData>, Sender<ApiResponse>),
+
+    /// Set the number of active queues of a vhost-user-net device.
+    VmSetNetQueues(Arc<VmNetQueuesData>, Sender<ApiResponse>),
+
+    /// Inject an NMI into a vCPU, or into all of them.
+    VmInjectNmi(Arc<VmInjectNmiData>, Sender<ApiResponse>),
+
+    /// Read the guest physical memory.
+    #[cfg(feature = "guest_debug")]
+    VmReadGuestMem(Arc<VmReadGuestMemData>, Sender<ApiResponse>),
+
+    /// Write the guest physical memory.
+    #[cfg(feature = "guest_debug")]
+    VmWriteGuestMem(Arc<VmWriteGuestMemData>, Sender<ApiResponse>),
+
+    /// Read the registers of a vCPU of the paused virtual machine.
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    VmVcpuRegisters(Arc<VmVcpuRegistersData>, Sender<ApiResponse>),
+
     /// Shut the previously booted virtual machine down.
     /// If the VM was not previously booted or created, the VMM API server
     /// will send a VmShutdown error back.
@@ -273,12 +672,30 @@ pub enum ApiRequest {
     /// Resize the memory zone.
     VmResizeZone(Arc<VmResizeZoneData>, Sender<ApiResponse>),
 
+    /// Add a DIMM to the VM.
+    VmAddDimm(Arc<VmAddDimmData>, Sender<ApiResponse>),
+
+    /// Set the amount of memory available to the guest.
+    VmSetMemoryTarget(Arc<VmSetMemoryTargetData>, Sender<ApiResponse>),
+
     /// Add a device to the VM.
     VmAddDevice(Arc<DeviceConfig>, Sender<ApiResponse>),
 
     /// Remove a device from the VM.
     VmRemoveDevice(Arc<VmRemoveDeviceData>, Sender<ApiResponse>),
 
+    /// Reset a device, forcing the guest driver to re-initialize it.
+    VmResetDevice(Arc<VmResetDeviceData>, Sender<ApiResponse>),
+
+    /// Pause a device, leaving the vCPUs and the other devices running.
+    VmPauseDevice(Arc<VmPauseDeviceData>, Sender<ApiResponse>),
+
+    /// Resume a device paused through VmPauseDevice.
+    VmResumeDevice(Arc<VmResumeDeviceData>, Sender<ApiResponse>),
+
+    /// Activate a device the VM was booted with but which was left out.
+    VmActivateDevice(Arc<VmActivateDeviceData>, Sender<ApiResponse>),
+
     /// Add a disk to the VM.
     VmAddDisk(Arc<DiskConfig>, Sender<ApiResponse>),
 
@@ -294,6 +711,9 @@ pub enum ApiRequest {
     /// Add a vsock device to the VM.
     VmAddVsock(Arc<VsockConfig>, Sender<ApiResponse>),
 
+    /// Send a request to the guest agent.
+    VmAgentRequest(Arc<AgentRequest>, Sender<ApiResponse>),
+
     /// Take a VM snapshot
     VmSnapshot(Arc<VmSnapshotConfig>, Sender<ApiResponse>),
 
@@ -306,6 +726,9 @@ pub enum ApiRequest {
     /// Outgoing migration
     VmSendMigration(Arc<VmSendMigrationData>, Sender<ApiResponse>),
 
+    /// Complete the migration to the standby
+    VmFailover(Sender<ApiResponse>),
+
     // Trigger power button
     VmPowerButton(Sender<ApiResponse>),
 }
@@ -353,6 +776,51 @@ pub enum VmAction {
     /// Return VM counters
     Counters,
 
+    /// Get guest RTC time
+    GetRtc,
+
+    /// Set guest RTC time
+    SetRtc(Arc<VmRtcData>),
+
+    /// Get the launch measurement
+    LaunchMeasurement,
+
+    /// Inject input events
+    InputEvent(Arc<VmInputEventData>),
+
+    /// Set a GPIO line
+    SetGpio(Arc<VmGpioData>),
+
+    /// Get the GPIO lines
+    GpioLines(Arc<VmGpioLinesData>),
+
+    /// Start or stop a network capture
+    NetCapture(Arc<VmNetCaptureData>),
+
+    /// Set the battery state
+    SetBattery(Arc<VmBatteryData>),
+
+    /// Program flow rules
+    SetFlowRules(Arc<VmFlowRulesData>),
+
+    /// Set the active network queues
+    SetNetQueues(Arc<VmNetQueuesData>),
+
+    /// Inject an NMI
+    InjectNmi(Arc<VmInjectNmiData>),
+
+    /// Read guest memory
+    #[cfg(feature = "guest_debug")]
+    ReadGuestMem(Arc<VmReadGuestMemData>),
+
+    /// Write guest memory
+    #[cfg(feature = "guest_debug")]
+    WriteGuestMem(Arc<VmWriteGuestMemData>),
+
+    /// Read vCPU registers
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    VcpuRegisters(Arc<VmVcpuRegistersData>),
+
     /// Add VFIO device
     AddDevice(Arc<DeviceConfig>),
 
@@ -371,15 +839,36 @@ pub enum VmAction {
     /// Add vsock
     AddVsock(Arc<VsockConfig>),
 
+    /// Request to the guest agent
+    AgentRequest(Arc<AgentRequest>),
+
     /// Remove VFIO device
     RemoveDevice(Arc<VmRemoveDeviceData>),
 
+    /// Reset device
+    ResetDevice(Arc<VmResetDeviceData>),
+
+    /// Pause device
+    PauseDevice(Arc<VmPauseDeviceData>),
+
+    /// Resume device
+    ResumeDevice(Arc<VmResumeDeviceData>),
+
+    /// Activate cold device
+    ActivateDevice(Arc<VmActivateDeviceData>),
+
     /// Resize VM
     Resize(Arc<VmResizeData>),
 
     /// Resize memory zone
     ResizeZone(Arc<VmResizeZoneData>),
 
+    /// Add a DIMM
+    AddDimm(Arc<VmAddDimmData>),
+
+    /// Set memory target
+    SetMemoryTarget(Arc<VmSetMemoryTargetData>),
+
     /// Restore VM
     Restore(Arc<RestoreConfig>),
 
@@ -392,6 +881,9 @@ pub enum VmAction {
     /// Outgoing migration
     SendMigration(Arc<VmSendMigrationData>),
 
+    /// Complete the migration to the standby
+    Failover,
+
     /// Power Button for clean shutdown
     PowerButton,
 }
@@ -412,19 +904,44 @@ fn vm_action(
         Pause => ApiRequest::VmPause(response_sender),
         Resume => ApiRequest::VmResume(response_sender),
         Counters => ApiRequest::VmCounters(response_sender),
+        GetRtc => ApiRequest::VmGetRtc(response_sender),
+        SetRtc(v) => ApiRequest::VmSetRtc(v, response_sender),
+        LaunchMeasurement => ApiRequest::VmLaunchMeasurement(response_sender),
+        InputEvent(v) => ApiRequest::VmInputEvent(v, response_sender),
+        SetGpio(v) => ApiRequest::VmSetGpio(v, response_sender),
+        GpioLines(v) => ApiRequest::VmGpioLines(v, response_sender),
+        NetCapture(v) => ApiRequest::VmNetCapture(v, response_sender),
+        SetBattery(v) => ApiRequest::VmSetBattery(v, response_sender),
+        SetFlowRules(v) => ApiRequest::VmSetFlowRules(v, response_sender),
+        SetNetQueues(v) => ApiRequest::VmSetNetQueues(v, response_sender),
+        InjectNmi(v) => ApiRequest::VmInjectNmi(v, response_sender),
+        #[cfg(feature = "guest_debug")]
+        ReadGuestMem(v) => ApiRequest::VmReadGuestMem(v, response_sender),
+        #[cfg(feature = "guest_debug")]
+        WriteGuestMem(v) => ApiRequest::VmWriteGuestMem(v, response_sender),
+        #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+        VcpuRegisters(v) => ApiRequest::VmVcpuRegisters(v, response_sender),
         AddDevice(v) => ApiRequest::VmAddDevice(v, response_sender),
         AddDisk(v) => ApiRequest::VmAddDisk(v, response_sender),
         AddFs(v) => ApiRequest::VmAddFs(v, response_sender),
         AddPmem(v) => ApiRequest::VmAddPmem(v, response_sender),
         AddNet(v) => ApiRequest::VmAddNet(v, response_sender),
         AddVsock(v) => ApiRequest::VmAddVsock(v, response_sender),
+        AgentRequest(v) => ApiRequest::VmAgentRequest(v, response_sender),
         RemoveDevice(v) => ApiRequest::VmRemoveDevice(v, response_sender),
+        ResetDevice(v) => ApiRequest::VmResetDevice(v, response_sender),
+        PauseDevice(v) => ApiRequest::VmPauseDevice(v, response_sender),
+        ResumeDevice(v) => ApiRequest::VmResumeDevice(v, response_sender),
+        ActivateDevice(v) => ApiRequest::VmActivateDevice(v, response_sender),
         Resize(v) => ApiRequest::VmResize(v, response_sender),
         ResizeZone(v) => ApiRequest::VmResizeZone(v, response_sender),
+        AddDimm(v) => ApiRequest::VmAddDimm(v, response_sender),
+        SetMemoryTarget(v) => ApiRequest::VmSetMemoryTarget(v, response_sender),
         Restore(v) => ApiRequest::VmRestore(v, response_sender),
         Snapshot(v) => ApiRequest::VmSnapshot(v, response_sender),
         ReceiveMigration(v) => ApiRequest::VmReceiveMigration(v, response_sender),
         SendMigration(v) => ApiRequest::VmSendMigration(v, response_sender),
+        Failover => ApiRequest::VmFailover(response_sender),
         PowerButton => ApiRequest::VmPowerButton(response_sender),
     };
 
@@ -469,6 +986,143 @@ pub fn vm_counters(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResul
     vm_action(api_evt, api_sender, VmAction::Counters)
 }
 
+pub fn vm_get_rtc(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::GetRtc)
+}
+
+pub fn vm_set_rtc(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmRtcData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetRtc(data))
+}
+
+/// Registers a hook inspecting the descriptor chains of the device with the
+/// given id before it processes them, for the current VM and the ones it's
+/// rebooted into. It takes effect on the next activation of the device, so
+/// it's best registered before the VM boots. Only the programs embedding the
+/// VMM can use it, the hook being code rather than data.
+pub fn vm_set_descriptor_inspector(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    id: String,
+    inspector: Arc<dyn DescriptorInspector>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmSetDescriptorInspector(
+            id,
+            inspector,
+            response_sender,
+        ))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_launch_measurement(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::LaunchMeasurement)
+}
+
+pub fn vm_input_event(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmInputEventData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::InputEvent(data))
+}
+
+pub fn vm_set_gpio(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmGpioData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetGpio(data))
+}
+
+pub fn vm_gpio_lines(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmGpioLinesData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::GpioLines(data))
+}
+
+pub fn vm_net_capture(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmNetCaptureData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::NetCapture(data))
+}
+
+pub fn vm_set_battery(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmBatteryData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetBattery(data))
+}
+
+pub fn vm_set_flow_rules(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmFlowRulesData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetFlowRules(data))
+}
+
+pub fn vm_set_net_queues(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmNetQueuesData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetNetQueues(data))
+}
+
+pub fn vm_inject_nmi(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmInjectNmiData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::InjectNmi(data))
+}
+
+#[cfg(feature = "guest_debug")]
+pub fn vm_read_guest_mem(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmReadGuestMemData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ReadGuestMem(data))
+}
+
+#[cfg(feature = "guest_debug")]
+pub fn vm_write_guest_mem(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmWriteGuestMemData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::WriteGuestMem(data))
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+pub fn vm_vcpu_registers(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmVcpuRegistersData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::VcpuRegisters(data))
+}
+
 pub fn vm_power_button(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -492,6 +1146,10 @@ pub fn vm_send_migration(
     vm_action(api_evt, api_sender, VmAction::SendMigration(data))
 }
 
+pub fn vm_failover(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::Failover)
+}
+
 pub fn vm_snapshot(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -571,6 +1229,22 @@ pub fn vm_resize_zone(
     vm_action(api_evt, api_sender, VmAction::ResizeZone(data))
 }
 
+pub fn vm_add_dimm(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmAddDimmData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::AddDimm(data))
+}
+
+pub fn vm_set_memory_target(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSetMemoryTargetData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetMemoryTarget(data))
+}
+
 pub fn vm_add_device(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -587,6 +1261,38 @@ pub fn vm_remove_device(
     vm_action(api_evt, api_sender, VmAction::RemoveDevice(data))
 }
 
+pub fn vm_reset_device(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmResetDeviceData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ResetDevice(data))
+}
+
+pub fn vm_pause_device(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmPauseDeviceData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::PauseDevice(data))
+}
+
+pub fn vm_resume_device(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmResumeDeviceData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ResumeDevice(data))
+}
+
+pub fn vm_activate_device(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmActivateDeviceData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ActivateDevice(data))
+}
+
 pub fn vm_add_disk(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -626,3 +1332,49 @@ pub fn vm_add_vsock(
 ) -> ApiResult<Option<Body>> {
     vm_action(api_evt, api_sender, VmAction::AddVsock(data))
 }
+
+pub fn vm_agent_request(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<AgentRequest>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::AgentRequest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migration_throttle() {
+        let config = MigrationThrottleConfig {
+            initial: 20,
+            increment: 30,
+            max: 60,
+        };
+        assert!(config.is_valid());
+
+        // nothing changes while the migration converges
+        assert_eq!(config.next_percentage(0, 400, 1000), 0);
+        assert_eq!(config.next_percentage(20, 500, 1000), 20);
+
+        // the throttling starts, then increases up to the maximum
+        assert_eq!(config.next_percentage(0, 600, 1000), 20);
+        assert_eq!(config.next_percentage(20, 600, 1000), 50);
+        assert_eq!(config.next_percentage(50, 600, 1000), 60);
+        assert_eq!(config.next_percentage(60, 2000, 1000), 60);
+
+        assert!(!MigrationThrottleConfig {
+            initial: 0,
+            ..config
+        }
+        .is_valid());
+        assert!(!MigrationThrottleConfig {
+            initial: 70,
+            ..config
+        }
+        .is_valid());
+        assert!(!MigrationThrottleConfig { max: 100, ..config }.is_valid());
+        assert!(MigrationThrottleConfig::default().is_valid());
+    }
+}
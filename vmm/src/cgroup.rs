@@ -0,0 +1,171 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::CgroupConfig;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const CGROUP2_MOUNT: &str = "/sys/fs/cgroup";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot find which cgroup the VMM currently belongs to.
+    CurrentCgroup(io::Error),
+    /// Cannot create the cgroup.
+    Create(io::Error),
+    /// Cannot set the CPU bandwidth limit.
+    SetCpuMax(io::Error),
+    /// Cannot set the memory limit.
+    SetMemoryMax(io::Error),
+    /// Cannot move the VMM process into the cgroup.
+    AddProcess(io::Error),
+}
+
+impl Error {
+    /// Whether the VMM isn't allowed to manage the cgroup, in which case the
+    /// VM can still run, without resource limits.
+    pub fn is_permission_denied(&self) -> bool {
+        let e = match self {
+            Error::CurrentCgroup(e)
+            | Error::Create(e)
+            | Error::SetCpuMax(e)
+            | Error::SetMemoryMax(e)
+            | Error::AddProcess(e) => e,
+        };
+
+        e.kind() == io::ErrorKind::PermissionDenied || e.raw_os_error() == Some(libc::EROFS)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A cgroups v2 group limiting the CPU and memory usage of the VMM.
+///
+/// The whole VMM process is moved into the group, meaning all the threads
+/// already running, as well as the ones created later on, are accounted for.
+/// The process is moved back to its original group and the group removed
+/// when dropped.
+pub struct Cgroup {
+    path: PathBuf,
+    original: PathBuf,
+}
+
+impl Cgroup {
+    pub fn new(config: &CgroupConfig) -> Result<Self> {
+        let original = current_cgroup().map_err(Error::CurrentCgroup)?;
+
+        fs::create_dir_all(&config.path).map_err(Error::Create)?;
+        // From now on, the group is removed if anything goes wrong.
+        let cgroup = Cgroup {
+            path: config.path.clone(),
+            original,
+        };
+
+        if let Some(cpu_quota) = config.cpu_quota {
+            cgroup
+                .write("cpu.max", &format!("{} {}", cpu_quota, config.cpu_period))
+                .map_err(Error::SetCpuMax)?;
+        }
+        if let Some(memory_max) = config.memory_max {
+            cgroup
+                .write("memory.max", &memory_max.to_string())
+                .map_err(Error::SetMemoryMax)?;
+        }
+
+        cgroup
+            .write("cgroup.procs", &std::process::id().to_string())
+            .map_err(Error::AddProcess)?;
+
+        info!("VMM moved to cgroup {:?}", cgroup.path);
+
+        Ok(cgroup)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        fs::write(self.path.join(file), value)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // A cgroup can only be removed once it has no process left.
+        if let Err(e) = fs::write(
+            self.original.join("cgroup.procs"),
+            std::process::id().to_string(),
+        ) {
+            warn!(
+                "Failed moving the VMM back to cgroup {:?}: {}",
+                self.original, e
+            );
+        }
+
+        if let Err(e) = fs::remove_dir(&self.path) {
+            warn!("Failed removing cgroup {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// Find the cgroups v2 group of the current process.
+fn current_cgroup() -> io::Result<PathBuf> {
+    parse_cgroup(&fs::read_to_string("/proc/self/cgroup")?)
+}
+
+// The cgroups v2 group is described in /proc/self/cgroup by an entry such as
+// "0::/user.slice", along with the groups of the v1 hierarchies if any.
+fn parse_cgroup(proc_cgroup: &str) -> io::Result<PathBuf> {
+    proc_cgroup
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .map(|p| Path::new(CGROUP2_MOUNT).join(p.trim_start_matches('/')))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no cgroups v2 hierarchy"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cgroup() {
+        assert_eq!(
+            parse_cgroup("0::/user.slice/user-1000.slice\n").unwrap(),
+            Path::new("/sys/fs/cgroup/user.slice/user-1000.slice")
+        );
+        assert_eq!(parse_cgroup("0::/\n").unwrap(), Path::new("/sys/fs/cgroup"));
+
+        // The v1 hierarchies are skipped.
+        assert_eq!(
+            parse_cgroup("12:memory:/vmm\n1:name=systemd:/init.scope\n0::/vmm.slice\n").unwrap(),
+            Path::new("/sys/fs/cgroup/vmm.slice")
+        );
+        assert_eq!(
+            parse_cgroup("12:memory:/vmm\n").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_is_permission_denied() {
+        assert!(
+            Error::Create(io::Error::from(io::ErrorKind::PermissionDenied)).is_permission_denied()
+        );
+        assert!(
+            Error::AddProcess(io::Error::from_raw_os_error(libc::EACCES)).is_permission_denied()
+        );
+        assert!(
+            Error::SetMemoryMax(io::Error::from_raw_os_error(libc::EROFS)).is_permission_denied()
+        );
+        assert!(
+            !Error::CurrentCgroup(io::Error::from(io::ErrorKind::NotFound)).is_permission_denied()
+        );
+        assert!(
+            !Error::SetCpuMax(io::Error::from_raw_os_error(libc::EINVAL)).is_permission_denied()
+        );
+    }
+}
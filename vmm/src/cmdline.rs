@@ -0,0 +1,321 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::ConsoleOutputMode;
+use linux_loader::cmdline::Cmdline;
+use std::fmt;
+
+/// Parameters for which the kernel only takes the last occurrence into
+/// account.
+const SINGLE_VALUE_PARAMS: [&str; 5] = ["root", "rootfstype", "rootflags", "init", "rdinit"];
+
+#[cfg(target_arch = "x86_64")]
+const SERIAL_CONSOLE: &str = "ttyS0";
+#[cfg(target_arch = "aarch64")]
+const SERIAL_CONSOLE: &str = "ttyAMA0";
+const VIRTIO_CONSOLE: &str = "hvc0";
+
+/// Separates the kernel parameters from the arguments of init.
+const INIT_ARGS_SEPARATOR: &str = "--";
+
+#[derive(Debug)]
+pub enum Error {
+    /// A double quote of the command line isn't closed.
+    UnterminatedQuote(String),
+    /// Cannot add a parameter to the command line.
+    Insert(linux_loader::cmdline::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A combination of parameters which doesn't prevent the kernel from booting
+/// but usually ends up in a hang or in a guest without any output.
+#[derive(Debug, PartialEq)]
+pub enum Warning {
+    /// The parameter is given more than once.
+    DuplicateParameter(String),
+    /// No root device is given, nor an initramfs through the configuration.
+    /// The kernel may still embed one.
+    MissingRoot,
+    /// The console isn't provided by the VM.
+    MissingConsole(String),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Warning::*;
+        match self {
+            DuplicateParameter(p) => write!(f, "{} repeats an earlier parameter", p),
+            MissingRoot => write!(
+                f,
+                "root= is missing, the kernel needs a built-in initramfs to boot"
+            ),
+            MissingConsole(c) => write!(f, "console={} is not provided by the VM", c),
+        }
+    }
+}
+
+/// What the VM provides to the guest kernel, against which the parameters
+/// are checked.
+#[derive(Clone, Debug)]
+pub struct BootEnvironment {
+    pub serial: ConsoleOutputMode,
+    pub console: ConsoleOutputMode,
+    pub initramfs: bool,
+}
+
+fn has_output(mode: &ConsoleOutputMode) -> bool {
+    !matches!(mode, ConsoleOutputMode::Off | ConsoleOutputMode::Null)
+}
+
+/// Splits a command line in parameters, the way the kernel does: spaces
+/// inside double quotes don't separate parameters.
+fn split(args: &str) -> Result<Vec<String>> {
+    let mut params = Vec::new();
+    let mut param = String::new();
+    let mut quoted = false;
+
+    for c in args.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_ascii_whitespace() && !quoted => {
+                if !param.is_empty() {
+                    params.push(param);
+                    param = String::new();
+                }
+                continue;
+            }
+            _ => {}
+        }
+        param.push(c);
+    }
+
+    if quoted {
+        return Err(Error::UnterminatedQuote(param));
+    }
+    if !param.is_empty() {
+        params.push(param);
+    }
+
+    Ok(params)
+}
+
+fn key(param: &str) -> &str {
+    param.split('=').next().unwrap()
+}
+
+/// Composes the guest kernel command line from the user provided one and the
+/// parameters added by the VMM, checking the result for common mistakes.
+pub struct CmdlineBuilder {
+    env: BootEnvironment,
+    params: Vec<String>,
+    init_args: Vec<String>,
+}
+
+impl CmdlineBuilder {
+    pub fn new(env: BootEnvironment) -> Self {
+        CmdlineBuilder {
+            env,
+            params: Vec::new(),
+            init_args: Vec::new(),
+        }
+    }
+
+    /// Adds the parameters of a raw command line, as they are. Whatever
+    /// follows "--" is passed to init.
+    pub fn raw(&mut self, args: &str) -> Result<&mut Self> {
+        let mut init = false;
+        for param in split(args)? {
+            if init {
+                self.init_args.push(param);
+            } else if param == INIT_ARGS_SEPARATOR {
+                init = true;
+            } else {
+                self.params.push(param);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Adds a kernel parameter.
+    pub fn param(&mut self, key: &str, value: &str) -> &mut Self {
+        self.params.push(format!("{}={}", key, value));
+        self
+    }
+
+    fn values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.params.iter().filter_map(move |param| {
+            param
+                .strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix('='))
+        })
+    }
+
+    /// Adds a console= parameter for each console whose output goes
+    /// somewhere, unless the consoles have been chosen already. The virtio
+    /// console comes last, making it the one backing /dev/console.
+    pub fn default_consoles(&mut self) -> &mut Self {
+        if self.values("console").next().is_some() {
+            return self;
+        }
+        if has_output(&self.env.serial) {
+            self.param("console", SERIAL_CONSOLE);
+        }
+        if has_output(&self.env.console) {
+            self.param("console", VIRTIO_CONSOLE);
+        }
+
+        self
+    }
+
+    /// Looks for suspicious combinations of parameters.
+    pub fn warnings(&self) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+
+        for (i, param) in self.params.iter().enumerate() {
+            let duplicate = self.params[..i].iter().any(|p| {
+                p == param || (key(p) == key(param) && SINGLE_VALUE_PARAMS.contains(&key(p)))
+            });
+            let warning = Warning::DuplicateParameter(param.clone());
+            if duplicate && !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+
+        if !self.env.initramfs && self.values("root").next().is_none() {
+            warnings.push(Warning::MissingRoot);
+        }
+
+        for console in self.values("console") {
+            let device = console.split(',').next().unwrap();
+            let provided = if device == SERIAL_CONSOLE {
+                self.env.serial != ConsoleOutputMode::Off
+            } else if device.starts_with("hvc") {
+                device == VIRTIO_CONSOLE && self.env.console != ConsoleOutputMode::Off
+            } else {
+                true
+            };
+            if !provided {
+                warnings.push(Warning::MissingConsole(console.to_owned()));
+            }
+        }
+
+        warnings
+    }
+
+    pub fn build(&self, max_size: usize) -> Result<Cmdline> {
+        let mut cmdline = Cmdline::new(max_size);
+        for param in self.params.iter() {
+            cmdline.insert_str(param).map_err(Error::Insert)?;
+        }
+        if !self.init_args.is_empty() {
+            cmdline
+                .insert_str(INIT_ARGS_SEPARATOR)
+                .map_err(Error::Insert)?;
+            for arg in self.init_args.iter() {
+                cmdline.insert_str(arg).map_err(Error::Insert)?;
+            }
+        }
+
+        Ok(cmdline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder(serial: ConsoleOutputMode, console: ConsoleOutputMode) -> CmdlineBuilder {
+        CmdlineBuilder::new(BootEnvironment {
+            serial,
+            console,
+            initramfs: false,
+        })
+    }
+
+    #[test]
+    fn test_cmdline_raw() {
+        let mut b = builder(ConsoleOutputMode::Null, ConsoleOutputMode::Tty);
+        b.raw("root=/dev/vda1  dyndbg=\"file vm.c +p\" -- single")
+            .unwrap()
+            .raw("earlycon=uart,mmio,0x00001000")
+            .unwrap()
+            .default_consoles();
+        assert_eq!(
+            b.build(4096).unwrap().as_str(),
+            "root=/dev/vda1 dyndbg=\"file vm.c +p\" earlycon=uart,mmio,0x00001000 \
+             console=hvc0 -- single"
+        );
+        assert!(b.warnings().is_empty());
+
+        assert!(matches!(
+            b.raw("dyndbg=\"file vm.c +p"),
+            Err(Error::UnterminatedQuote(_))
+        ));
+        assert!(matches!(b.build(16), Err(Error::Insert(_))));
+    }
+
+    #[test]
+    fn test_cmdline_default_consoles() {
+        let mut b = builder(ConsoleOutputMode::Tty, ConsoleOutputMode::File);
+        b.raw("root=/dev/vda1").unwrap().default_consoles();
+        assert_eq!(
+            b.build(4096).unwrap().as_str(),
+            format!("root=/dev/vda1 console={} console=hvc0", SERIAL_CONSOLE)
+        );
+
+        let mut b = builder(ConsoleOutputMode::Tty, ConsoleOutputMode::Tty);
+        b.raw("root=/dev/vda1 console=tty0")
+            .unwrap()
+            .default_consoles();
+        assert_eq!(
+            b.build(4096).unwrap().as_str(),
+            "root=/dev/vda1 console=tty0"
+        );
+
+        let mut b = builder(ConsoleOutputMode::Null, ConsoleOutputMode::Off);
+        b.raw("root=/dev/vda1").unwrap().default_consoles();
+        assert_eq!(b.build(4096).unwrap().as_str(), "root=/dev/vda1");
+    }
+
+    #[test]
+    fn test_cmdline_warnings() {
+        let mut b = builder(ConsoleOutputMode::Off, ConsoleOutputMode::Tty);
+        b.raw("console=hvc0 console=hvc0 root=/dev/vda1 root=/dev/vda2")
+            .unwrap()
+            .param("console", SERIAL_CONSOLE);
+        assert_eq!(
+            b.warnings(),
+            vec![
+                Warning::DuplicateParameter("console=hvc0".to_owned()),
+                Warning::DuplicateParameter("root=/dev/vda2".to_owned()),
+                Warning::MissingConsole(SERIAL_CONSOLE.to_owned()),
+            ]
+        );
+
+        let mut b = builder(ConsoleOutputMode::Off, ConsoleOutputMode::Off);
+        b.raw("console=hvc1,115200 quiet").unwrap();
+        assert_eq!(
+            b.warnings(),
+            vec![
+                Warning::MissingRoot,
+                Warning::MissingConsole("hvc1,115200".to_owned()),
+            ]
+        );
+        assert_eq!(
+            Warning::MissingRoot.to_string(),
+            "root= is missing, the kernel needs a built-in initramfs to boot"
+        );
+
+        let mut b = CmdlineBuilder::new(BootEnvironment {
+            serial: ConsoleOutputMode::Off,
+            console: ConsoleOutputMode::Off,
+            initramfs: true,
+        });
+        b.raw("quiet").unwrap();
+        assert!(b.warnings().is_empty());
+    }
+}
@@ -6,22 +6,36 @@
 use clap::ArgMatches;
 use net_util::MacAddr;
 use option_parser::{
-    ByteSized, IntegerList, OptionParser, OptionParserError, StringList, Toggle, TupleTwoIntegers,
+    ByteSized, HexU16, HexU32, IntegerList, OptionParser, OptionParserError, StringList, Toggle,
+    TupleTwoIntegers,
 };
+use std::collections::BTreeSet;
 use std::convert::From;
 use std::fmt;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 use std::result;
 use std::str::FromStr;
+use std::sync::Arc;
 
 pub const DEFAULT_VCPUS: u8 = 1;
 pub const DEFAULT_MEMORY_MB: u64 = 512;
+pub const DEFAULT_MEMORY_HOTPLUG_SLOTS: usize = 8;
+pub const MAX_MEMORY_HOTPLUG_SLOTS: usize = 256;
+// The DIMMs and the virtio-mem regions are aligned on 128MiB.
+pub const MEMORY_HOTPLUG_ALIGNMENT: u64 = 128 << 20;
 pub const DEFAULT_RNG_SOURCE: &str = "/dev/urandom";
 pub const DEFAULT_NUM_QUEUES_VUNET: usize = 2;
 pub const DEFAULT_QUEUE_SIZE_VUNET: u16 = 256;
 pub const DEFAULT_NUM_QUEUES_VUBLK: usize = 1;
 pub const DEFAULT_QUEUE_SIZE_VUBLK: u16 = 128;
+pub const DEFAULT_DISK_READAHEAD_WINDOW: u64 = 128 << 10;
+pub const DEFAULT_DISK_IO_RETRY_BACKOFF_MS: u64 = 10;
+pub const DEFAULT_DISK_QUEUE_DEPTH: u32 = 256;
+pub const DEFAULT_GPIO_LINES: u16 = 8;
+// The connection to a hotplugged vhost-user-net backend holds the VMM thread
+// up to the timeout.
+pub const MAX_NET_CONNECT_TIMEOUT_MS: u64 = 10_000;
 
 /// Errors associated with VM configuration parameters.
 #[derive(Debug)]
@@ -56,6 +70,26 @@ pub enum Error {
     ParseRNG(OptionParserError),
     /// Error parsing balloon options
     ParseBalloon(OptionParserError),
+    /// Error parsing cgroup options
+    ParseCgroup(OptionParserError),
+    /// Missing path from cgroup
+    ParseCgroupPathMissing,
+    /// Error parsing OOM policy options
+    ParseOomPolicy(OptionParserError),
+    /// Missing RSS limit from OOM policy
+    ParseOomPolicyRssLimitMissing,
+    /// Error parsing the triple fault action
+    ParseOnTripleFault(ParseTripleFaultActionError),
+    /// Error parsing the crash actions
+    ParseOnCrash(ParseCrashActionError),
+    /// Error parsing crash dump options
+    ParseCrashDump(OptionParserError),
+    /// Missing file from crash dump
+    ParseCrashDumpFileMissing,
+    /// Error parsing vTPM parameters
+    ParseTpm(OptionParserError),
+    /// Missing socket from vTPM
+    ParseTpmSocketMissing,
     /// Error parsing filesystem parameters
     ParseFileSystem(OptionParserError),
     /// Error parsing persistent memory parameters
@@ -68,15 +102,32 @@ pub enum Error {
     ParseDevice(OptionParserError),
     /// Missing path from device,
     ParseDevicePathMissing,
+    /// Failed parsing user device parameters
+    ParseUserDevice(OptionParserError),
+    /// Missing socket for user device
+    ParseUserDeviceSocketMissing,
     /// Failed to parse vsock parameters
     ParseVsock(OptionParserError),
+    /// Failed to parse input device parameters
+    ParseInput(OptionParserError),
+    /// Failed to parse GPIO device parameters
+    ParseGpio(OptionParserError),
     /// Failed to parse restore parameters
     ParseRestore(OptionParserError),
     /// Failed to parse SGX EPC parameters
     #[cfg(target_arch = "x86_64")]
     ParseSgxEpc(OptionParserError),
+    /// Failed to parse SEV parameters
+    #[cfg(target_arch = "x86_64")]
+    ParseSev(OptionParserError),
     /// Failed to parse NUMA parameters
     ParseNuma(OptionParserError),
+    /// Failed to parse ACPI table parameters
+    ParseAcpiTable(OptionParserError),
+    /// Missing file from ACPI table parameters
+    ParseAcpiTablePathMissing,
+    /// Failed to parse platform parameters
+    ParsePlatform(OptionParserError),
     /// Failed to validate configuration
     Validation(ValidationError),
 }
@@ -87,20 +138,79 @@ pub enum ValidationError {
     DoubleTtyMode,
     /// No kernel specified
     KernelMissing,
+    /// Neither initramfs path nor data specified
+    InitramfsMissing,
+    /// Both initramfs path and data specified
+    InitramfsPathAndData,
     /// Missing file value for console
     ConsoleFileMissing,
+    /// Missing address to listen on for a tcp console
+    ConsoleSocketMissing,
     /// Max is less than boot
     CpusMaxLowerThanBoot,
+    /// Nested virtualization is only available on x86_64
+    #[cfg(target_arch = "aarch64")]
+    NestedUnsupported,
+    /// The TSC-deadline timer is only available on x86_64
+    #[cfg(target_arch = "aarch64")]
+    TscDeadlineUnsupported,
+    /// CPUID leaves are only available on x86_64
+    #[cfg(target_arch = "aarch64")]
+    CpuidUnsupported,
+    /// A CPUID leaf the guest needs to boot is zeroed
+    CpuidMandatoryLeafZeroed(u32),
+    /// The same CPUID leaf and index are given more than once
+    CpuidLeafDuplicate(u32, u32),
+    /// The cache topology is only available on x86_64
+    #[cfg(target_arch = "aarch64")]
+    CacheTopologyUnsupported,
+    /// Cache topology given without CPU topology
+    CacheTopologyRequiresTopology,
+    /// A level of cache shared by fewer processors than the level below
+    CacheTopologyInconsistent,
+    /// The battery is an ACPI device
+    #[cfg(not(feature = "acpi"))]
+    BatteryRequiresAcpi,
+    /// ACPI tables can't be added without ACPI support
+    #[cfg(not(feature = "acpi"))]
+    AcpiTablesRequireAcpi,
+    /// The vTPM is found by the guest through ACPI
+    #[cfg(not(feature = "acpi"))]
+    TpmRequiresAcpi,
+    /// The vTPM is only available on x86_64
+    #[cfg(target_arch = "aarch64")]
+    TpmUnsupported,
+    /// Logging the register accesses requires the access_log build feature
+    #[cfg(not(feature = "access_log"))]
+    AccessLogUnsupported,
+    /// SEV is only supported with KVM
+    #[cfg(all(target_arch = "x86_64", not(feature = "kvm")))]
+    SevRequiresKvm,
+    /// The hotplugged memory can't be encrypted with SEV
+    #[cfg(target_arch = "x86_64")]
+    SevMemoryHotplug,
     /// Both socket and path specified
     DiskSocketAndPath,
     /// Using vhost user requires shared memory
     VhostUserRequiresSharedMemory,
     /// No socket provided for vhost_use
     VhostUserMissingSocket,
+    /// Both vhost-user and vhost-kernel selected for a network device
+    VhostKernelAndVhostUser,
+    /// Trying to use an IOMMU with vhost-kernel
+    VhostKernelIommu,
+    /// Trying to drop spoofed frames with vhost-kernel
+    VhostKernelAntiSpoof,
+    /// Trying to busy poll with vhost-kernel
+    VhostKernelBusyPoll,
+    /// Trying to trust the received frames with vhost-user or vhost-kernel
+    TrustedLocalUnsupported,
     /// Trying to use IOMMU without PCI
     IommuUnsupported,
     /// Trying to use VFIO without PCI
     VfioUnsupported,
+    /// Using vfio-user requires shared memory
+    UserDeviceRequiresSharedMemory,
     /// CPU topology count doesn't match max
     CpuTopologyCount,
     /// One part of the CPU topology was zero
@@ -109,29 +219,329 @@ pub enum ValidationError {
     VnetQueueLowerThan2,
     /// The input queue number for virtio_net must match the number of input fds
     VnetQueueFdMismatch,
+    /// Virtio-block needs at least one queue
+    VblkQueueLowerThan1,
+    /// Boot index used by more than one disk
+    DuplicateBootIndex(u16),
     // Hugepages not turned on
     HugePageSizeWithoutHugePages,
     // Huge page size is not power of 2
     InvalidHugePageSize(u64),
+    /// Number of DIMM slots out of range
+    InvalidHotplugSlots(usize),
+    /// Base of the hotplug region given without hotpluggable memory
+    HotplugBaseWithoutHotplugSize,
+    /// Base of the hotplug region not aligned on MEMORY_HOTPLUG_ALIGNMENT
+    InvalidHotplugBase(u64),
+    // Inflating the balloon from the OOM policy requires a balloon
+    OomPolicyBalloonMissing,
+    /// The OOM policy would never check the memory usage
+    OomPolicyZeroInterval,
+    /// The crash port belongs to the virtio-console device
+    CrashDumpRequiresConsole,
+    /// The crash dump file can't hold anything
+    CrashDumpEmpty,
+    /// No crash action to run
+    CrashActionsEmpty,
+    /// Nothing can run once the VM is rebooted or shut down
+    CrashActionNotLast,
+    /// The read-ahead window is empty or larger than the disk cache
+    InvalidReadAheadWindow(u64),
+    /// Read-ahead is not supported by vhost-user disks
+    VhostUserReadAhead,
+    /// Disk overlays are not supported by vhost-user disks
+    VhostUserOverlay,
+    /// A disk with an overlay is writable
+    ReadOnlyOverlay,
+    /// Disk overlays are not supported with direct I/O
+    DirectOverlay,
+    /// The notification threshold exceeds the queue size, or has no latency bound
+    InvalidNotificationSuppression(u16, u64),
+    /// Notification suppression is not supported by vhost-user and vhost-kernel devices
+    NotificationSuppressionUnsupported,
+    /// No worker thread, or more of them than queues
+    InvalidNumDiskWorkers(usize),
+    /// The queue weights don't match the queues, or are out of range
+    InvalidQueueWeights(Vec<u32>),
+    /// The queue scheduling is not supported by vhost-user disks
+    VhostUserQueueScheduling,
+    /// The retries of the failed I/O are not supported by vhost-user disks
+    VhostUserIoRetries,
+    /// No request of the disk could ever be in flight
+    InvalidDiskQueueDepth,
+    /// The queue depth is not supported by vhost-user disks
+    VhostUserQueueDepth,
+    /// The I/O priority level is out of range, or set in the idle class
+    InvalidIoPriorityLevel(u8),
+    /// The I/O priority is not supported by vhost-user disks
+    VhostUserIoPriority,
+    /// Number of PCI segments out of range
+    InvalidNumPciSegments(u16),
+    /// No descriptor chain could ever be in flight
+    InvalidMaxInflightDescriptors,
+    /// Device placed on a PCI segment that doesn't exist
+    InvalidPciSegment(u16),
+    /// Devices behind the IOMMU must be on the first PCI segment
+    IommuNotSupportedOnSegment(u16),
+    /// Device attached to a NUMA node that doesn't exist
+    InvalidNumaNode(u32),
+    /// Virtio-net queues come in pairs
+    VnetQueueOdd,
+    /// More queues than the device has MSI-X vectors for
+    TooManyQueues(usize),
+    /// Queue size is not a power of 2
+    InvalidQueueSize(u16),
+    /// Device identifier used by more than one device
+    DuplicateDeviceId(String),
+    /// virtio-fs tag used by more than one device
+    DuplicateFsTag(String),
+    /// virtio-fs tag empty or too long
+    InvalidFsTag(String),
+    /// Host device assigned more than once
+    DuplicateHostDevice(PathBuf),
+    /// Backend socket doesn't exist
+    BackendSocketMissing(PathBuf),
+    /// Evdev input device without the path to the host device
+    InputPathMissing,
+    /// Synthetic input device given a host device path
+    InputPathUnexpected(PathBuf),
+    /// No GPIO line, or more of them than supported
+    InvalidGpioLines(u16),
+    /// PCI subsystem vendor ID reserved for absent devices
+    InvalidPciSubsystemVendorId(u16),
+    /// PCI serial number too long or not printable ASCII
+    InvalidPciSerial(String),
+    /// Activation timeout of zero
+    InvalidActivateTimeout,
+    /// Activation timeout for a device without vhost-user backend
+    ActivateTimeoutRequiresVhostUser,
+    /// Connection timeout of zero or above MAX_NET_CONNECT_TIMEOUT_MS
+    InvalidConnectTimeout,
+    /// Connection timeout for a device without vhost-user backend
+    ConnectTimeoutRequiresVhostUser,
+    /// Offload turned off for a network device without vhost-user backend
+    OffloadsRequireVhostUser,
+    /// Disk option the NVMe controller doesn't support
+    NvmeUnsupportedOption(&'static str),
+    /// Device option the virtio-mmio transport doesn't support
+    MmioUnsupportedOption(&'static str),
+    /// Device activated after boot placed behind the IOMMU
+    ColdDeviceIommu,
+    /// Several problems found in the configuration
+    Multiple(Vec<ValidationError>),
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
 
+impl ValidationError {
+    fn from_list(mut errors: Vec<ValidationError>) -> ValidationResult<()> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(ValidationError::Multiple(errors)),
+        }
+    }
+
+    /// Returns the individual problems this error is made of.
+    pub fn errors(&self) -> &[ValidationError] {
+        match self {
+            ValidationError::Multiple(errors) => errors,
+            e => std::slice::from_ref(e),
+        }
+    }
+}
+
+// Every queue gets its own MSI-X vector, on top of the configuration one and
+// the virtio-net control queue one, out of the 2048 a PCI device can have.
+const MAX_NUM_QUEUES: usize = 2046;
+
+fn validate_queues(num_queues: usize, queue_size: u16) -> ValidationResult<()> {
+    if num_queues > MAX_NUM_QUEUES {
+        return Err(ValidationError::TooManyQueues(num_queues));
+    }
+    if !queue_size.is_power_of_two() {
+        return Err(ValidationError::InvalidQueueSize(queue_size));
+    }
+
+    Ok(())
+}
+
+fn validate_pci_identity(
+    subsystem_vendor_id: Option<u16>,
+    serial: Option<&String>,
+) -> ValidationResult<()> {
+    // Reading 0xffff from the vendor ID is how software detects that no
+    // device is present.
+    if let Some(0xffff) = subsystem_vendor_id {
+        return Err(ValidationError::InvalidPciSubsystemVendorId(0xffff));
+    }
+    if let Some(serial) = serial {
+        if serial.is_empty()
+            || serial.len() > pci::MAX_VPD_SERIAL_LEN
+            || !serial.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+        {
+            return Err(ValidationError::InvalidPciSerial(serial.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+// Only the vhost-user backends can leave the activation hanging.
+fn validate_activate_timeout(timeout: Option<u64>, vhost_user: bool) -> ValidationResult<()> {
+    match timeout {
+        Some(0) => Err(ValidationError::InvalidActivateTimeout),
+        Some(_) if !vhost_user => Err(ValidationError::ActivateTimeoutRequiresVhostUser),
+        _ => Ok(()),
+    }
+}
+
+// The used buffer notifications are suppressed by the queue threads of the
+// VMM, which vhost-user and vhost-kernel backends bypass.
+fn validate_notification_suppression(
+    threshold: u16,
+    max_latency_us: u64,
+    queue_size: u16,
+    vhost: bool,
+) -> ValidationResult<()> {
+    if threshold <= 1 {
+        return Ok(());
+    }
+    if vhost {
+        return Err(ValidationError::NotificationSuppressionUnsupported);
+    }
+    // Without a latency bound, the guest could wait forever for the
+    // completion of fewer requests than the threshold.
+    if threshold > queue_size || max_latency_us == 0 {
+        return Err(ValidationError::InvalidNotificationSuppression(
+            threshold,
+            max_latency_us,
+        ));
+    }
+
+    Ok(())
+}
+
+// The NVMe controller is a plain PCI device with a single worker thread,
+// the virtio specific options have no meaning for it.
+fn validate_nvme_disk(disk: &DiskConfig) -> ValidationResult<()> {
+    let unsupported = [
+        ("vhost_user", disk.vhost_user),
+        ("iommu", disk.iommu),
+        ("notify_threshold", disk.notify_threshold > 1),
+        ("num_workers", disk.num_workers.is_some()),
+        ("queue_weights", disk.queue_weights.is_some()),
+        ("io_retries", disk.io_retries > 0),
+        ("queue_depth", disk.queue_depth.is_some()),
+        ("io_priority", disk.io_priority.is_some()),
+        (
+            "pci_subsystem_vendor_id",
+            disk.pci_subsystem_vendor_id.is_some(),
+        ),
+        ("pci_subsystem_id", disk.pci_subsystem_id.is_some()),
+        ("pci_serial", disk.pci_serial.is_some()),
+        ("cold", disk.cold),
+    ];
+    if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(ValidationError::NvmeUnsupportedOption(*option));
+    }
+    // A queue is full when it holds one entry less than its size.
+    if disk.queue_size < 2 {
+        return Err(ValidationError::InvalidQueueSize(disk.queue_size));
+    }
+
+    Ok(())
+}
+
+// The virtio-mmio devices sit outside of any PCI segment, behind a single
+// legacy interrupt, and are only discovered by the guest at boot.
+fn validate_mmio_transport(
+    transport: VirtioTransportType,
+    unsupported: &[(&'static str, bool)],
+) -> ValidationResult<()> {
+    if transport != VirtioTransportType::Mmio {
+        return Ok(());
+    }
+    if let Some((option, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(ValidationError::MmioUnsupportedOption(*option));
+    }
+
+    Ok(())
+}
+
 impl fmt::Display for ValidationError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::ValidationError::*;
         match self {
             DoubleTtyMode => write!(f, "Console mode tty specified for both serial and console"),
             KernelMissing => write!(f, "No kernel specified"),
+            InitramfsMissing => write!(f, "Neither initramfs path nor data provided"),
+            InitramfsPathAndData => write!(f, "Initramfs path and data both provided"),
             ConsoleFileMissing => write!(f, "Path missing when using file console mode"),
+            ConsoleSocketMissing => write!(f, "Address missing when using tcp console mode"),
             CpusMaxLowerThanBoot => write!(f, "Max CPUs greater than boot CPUs"),
+            #[cfg(target_arch = "aarch64")]
+            NestedUnsupported => write!(f, "Nested virtualization is not supported"),
+            #[cfg(target_arch = "aarch64")]
+            TscDeadlineUnsupported => write!(f, "The TSC-deadline timer is not supported"),
+            #[cfg(target_arch = "aarch64")]
+            CpuidUnsupported => write!(f, "CPUID leaves are not supported"),
+            #[cfg(target_arch = "aarch64")]
+            CacheTopologyUnsupported => write!(f, "The cache topology is not supported"),
+            CacheTopologyRequiresTopology => {
+                write!(f, "The cache topology requires the CPU topology")
+            }
+            CacheTopologyInconsistent => write!(
+                f,
+                "A level of cache can't be shared by fewer processors than the level below"
+            ),
+            CpuidMandatoryLeafZeroed(function) => write!(
+                f,
+                "CPUID leaf 0x{:x} can't be zeroed, the guest needs it to boot",
+                function
+            ),
+            CpuidLeafDuplicate(function, index) => write!(
+                f,
+                "CPUID leaf 0x{:x} index {} is given more than once",
+                function, index
+            ),
+            #[cfg(not(feature = "acpi"))]
+            BatteryRequiresAcpi => write!(f, "The battery requires ACPI support"),
+            #[cfg(not(feature = "acpi"))]
+            AcpiTablesRequireAcpi => write!(f, "Adding ACPI tables requires ACPI support"),
+            #[cfg(not(feature = "acpi"))]
+            TpmRequiresAcpi => write!(f, "The vTPM requires ACPI support"),
+            #[cfg(target_arch = "aarch64")]
+            TpmUnsupported => write!(f, "The vTPM is not supported"),
+            #[cfg(not(feature = "access_log"))]
+            AccessLogUnsupported => write!(
+                f,
+                "Logging the register accesses requires the access_log build feature"
+            ),
+            #[cfg(all(target_arch = "x86_64", not(feature = "kvm")))]
+            SevRequiresKvm => write!(f, "SEV requires the KVM hypervisor"),
+            #[cfg(target_arch = "x86_64")]
+            SevMemoryHotplug => write!(f, "SEV doesn't support memory hotplug"),
             DiskSocketAndPath => write!(f, "Disk path and vhost socket both provided"),
             VhostUserRequiresSharedMemory => {
                 write!(f, "Using vhost-user requires using shared memory")
             }
             VhostUserMissingSocket => write!(f, "No socket provided when using vhost-user"),
+            VhostKernelAndVhostUser => {
+                write!(f, "Using both vhost-kernel and vhost-user is unsupported")
+            }
+            VhostKernelIommu => write!(f, "Using an IOMMU with vhost-kernel is unsupported"),
+            VhostKernelAntiSpoof => write!(f, "Using anti_spoof with vhost-kernel is unsupported"),
+            VhostKernelBusyPoll => write!(f, "Using busy_poll with vhost-kernel is unsupported"),
+            TrustedLocalUnsupported => write!(
+                f,
+                "Using trusted_local with vhost-user or vhost-kernel is unsupported"
+            ),
             IommuUnsupported => write!(f, "Using an IOMMU without PCI support is unsupported"),
             VfioUnsupported => write!(f, "Using VFIO without PCI support is unsupported"),
+            UserDeviceRequiresSharedMemory => {
+                write!(f, "Using user devices requires using shared memory")
+            }
             CpuTopologyZeroPart => write!(f, "No part of the CPU topology can be zero"),
             CpuTopologyCount => write!(
                 f,
@@ -142,12 +552,172 @@ impl fmt::Display for ValidationError {
                 f,
                 "Number of queues to virtio_net does not match the number of input FDs"
             ),
+            VblkQueueLowerThan1 => write!(f, "Number of queues to virtio_block less than 1"),
+            DuplicateBootIndex(i) => write!(f, "Boot index {} used by more than one disk", i),
             HugePageSizeWithoutHugePages => {
                 write!(f, "Huge page size specified but huge pages not enabled")
             }
             InvalidHugePageSize(s) => {
                 write!(f, "Huge page size is not power of 2: {}", s)
             }
+            InvalidHotplugSlots(s) => write!(
+                f,
+                "Number of memory hotplug slots must be between 1 and {}: {}",
+                MAX_MEMORY_HOTPLUG_SLOTS, s
+            ),
+            HotplugBaseWithoutHotplugSize => {
+                write!(f, "The memory hotplug base requires a hotplug size")
+            }
+            InvalidHotplugBase(b) => write!(
+                f,
+                "The memory hotplug base 0x{:x} is not aligned on 0x{:x}",
+                b, MEMORY_HOTPLUG_ALIGNMENT
+            ),
+            OomPolicyBalloonMissing => {
+                write!(f, "OOM policy balloon action requires a balloon")
+            }
+            OomPolicyZeroInterval => {
+                write!(f, "OOM policy interval must be at least 1ms")
+            }
+            InvalidReadAheadWindow(w) => write!(
+                f,
+                "Read-ahead window {} is empty or larger than the disk cache",
+                w
+            ),
+            VhostUserReadAhead => write!(f, "Read-ahead is unsupported with vhost-user disks"),
+            VhostUserOverlay => write!(f, "Overlay is unsupported with vhost-user disks"),
+            ReadOnlyOverlay => write!(f, "Overlay is unsupported with read-only disks"),
+            DirectOverlay => write!(f, "Overlay is unsupported with direct I/O disks"),
+            InvalidNotificationSuppression(threshold, max_latency) => write!(
+                f,
+                "Notification threshold {} is larger than the queue size, \
+                 or has no maximum latency ({}us)",
+                threshold, max_latency
+            ),
+            NotificationSuppressionUnsupported => write!(
+                f,
+                "Notification suppression is unsupported with vhost-user and vhost-kernel devices"
+            ),
+            InvalidNumDiskWorkers(n) => write!(
+                f,
+                "Number of disk worker threads {} must be between 1 and the number of queues",
+                n
+            ),
+            InvalidQueueWeights(w) => write!(
+                f,
+                "Queue weights {:?} must give each queue a weight between 1 and {}",
+                w,
+                virtio_devices::MAX_QUEUE_WEIGHT
+            ),
+            VhostUserQueueScheduling => {
+                write!(f, "Queue scheduling is unsupported with vhost-user disks")
+            }
+            VhostUserIoRetries => {
+                write!(f, "I/O retries are unsupported with vhost-user disks")
+            }
+            InvalidDiskQueueDepth => write!(f, "Disk queue depth is zero"),
+            VhostUserQueueDepth => {
+                write!(f, "Queue depth is unsupported with vhost-user disks")
+            }
+            InvalidIoPriorityLevel(l) => write!(
+                f,
+                "I/O priority level {} is out of 0 to {}, or set in the idle class",
+                l,
+                IoPriority::MAX_LEVEL
+            ),
+            VhostUserIoPriority => {
+                write!(f, "I/O priority is unsupported with vhost-user disks")
+            }
+            CrashDumpRequiresConsole => {
+                write!(f, "Crash dump requires the virtio-console device")
+            }
+            CrashDumpEmpty => write!(f, "Crash dump maximum size is zero"),
+            CrashActionsEmpty => write!(f, "No crash action given"),
+            CrashActionNotLast => {
+                write!(f, "Reboot and shutdown must be the last crash action")
+            }
+            InvalidNumPciSegments(n) => write!(
+                f,
+                "Number of PCI segments {} is not between 1 and {}",
+                n, MAX_NUM_PCI_SEGMENTS
+            ),
+            InvalidMaxInflightDescriptors => {
+                write!(f, "Maximum number of descriptors in flight is zero")
+            }
+            InvalidPciSegment(s) => write!(f, "PCI segment {} doesn't exist", s),
+            IommuNotSupportedOnSegment(s) => write!(
+                f,
+                "Devices on PCI segment {} can't be placed behind the IOMMU",
+                s
+            ),
+            InvalidNumaNode(n) => write!(f, "NUMA node {} doesn't exist", n),
+            VnetQueueOdd => write!(f, "Number of queues to virtio_net is not even"),
+            TooManyQueues(n) => write!(
+                f,
+                "Number of queues {} greater than the maximum {}",
+                n, MAX_NUM_QUEUES
+            ),
+            InvalidQueueSize(s) => write!(f, "Queue size is not a power of 2: {}", s),
+            InvalidPciSubsystemVendorId(id) => {
+                write!(f, "PCI subsystem vendor ID {:#06x} is reserved", id)
+            }
+            InvalidPciSerial(s) => write!(
+                f,
+                "PCI serial number \"{}\" must be 1 to {} printable ASCII characters",
+                s,
+                pci::MAX_VPD_SERIAL_LEN
+            ),
+            InvalidActivateTimeout => write!(f, "Activation timeout must not be zero"),
+            ActivateTimeoutRequiresVhostUser => {
+                write!(f, "Activation timeout requires vhost_user=on")
+            }
+            InvalidConnectTimeout => write!(
+                f,
+                "Connection timeout must be between 1 and {} milliseconds",
+                MAX_NET_CONNECT_TIMEOUT_MS
+            ),
+            ConnectTimeoutRequiresVhostUser => {
+                write!(f, "Connection timeout requires vhost_user=on")
+            }
+            OffloadsRequireVhostUser => {
+                write!(f, "Turning offloads off requires vhost_user=on")
+            }
+            NvmeUnsupportedOption(o) => write!(f, "{} is not supported by NVMe disks", o),
+            MmioUnsupportedOption(o) => {
+                write!(f, "{} is not supported by the virtio-mmio transport", o)
+            }
+            ColdDeviceIommu => write!(f, "Devices activated after boot can't use the IOMMU"),
+            DuplicateDeviceId(id) => write!(f, "Device identifier {} used more than once", id),
+            DuplicateFsTag(tag) => write!(f, "virtio-fs tag {} used more than once", tag),
+            InvalidFsTag(tag) => write!(
+                f,
+                "virtio-fs tag \"{}\" must be 1 to {} bytes long",
+                tag,
+                virtio_devices::vhost_user::MAX_FS_TAG_LEN
+            ),
+            InputPathMissing => write!(f, "Evdev input device requires a path"),
+            InputPathUnexpected(p) => write!(
+                f,
+                "Synthetic input device can't use the host device {}",
+                p.display()
+            ),
+            InvalidGpioLines(n) => write!(
+                f,
+                "Number of GPIO lines {} is not between 1 and {}",
+                n,
+                virtio_devices::MAX_GPIO_LINES
+            ),
+            DuplicateHostDevice(p) => {
+                write!(f, "Host device {} assigned more than once", p.display())
+            }
+            BackendSocketMissing(p) => write!(f, "Backend socket {} doesn't exist", p.display()),
+            Multiple(errors) => {
+                write!(f, "{} problems found", errors.len())?;
+                for e in errors {
+                    write!(f, "; {}", e)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -164,6 +734,10 @@ impl fmt::Display for Error {
 
             ParseDevice(o) => write!(f, "Error parsing --device: {}", o),
             ParseDevicePathMissing => write!(f, "Error parsing --device: path missing"),
+            ParseUserDevice(o) => write!(f, "Error parsing --user-device: {}", o),
+            ParseUserDeviceSocketMissing => {
+                write!(f, "Error parsing --user-device: socket missing")
+            }
             ParseFileSystem(o) => write!(f, "Error parsing --fs: {}", o),
             ParseFsSockMissing => write!(f, "Error parsing --fs: socket missing"),
             ParseFsTagMissing => write!(f, "Error parsing --fs: tag missing"),
@@ -175,6 +749,8 @@ impl fmt::Display for Error {
             ParseVsock(o) => write!(f, "Error parsing --vsock: {}", o),
             ParseVsockCidMissing => write!(f, "Error parsing --vsock: cid missing"),
             ParseVsockSockMissing => write!(f, "Error parsing --vsock: socket missing"),
+            ParseInput(o) => write!(f, "Error parsing --input: {}", o),
+            ParseGpio(o) => write!(f, "Error parsing --gpio: {}", o),
             ParseMemory(o) => write!(f, "Error parsing --memory: {}", o),
             ParseMemoryZone(o) => write!(f, "Error parsing --memory-zone: {}", o),
             ParseMemoryZoneIdMissing => write!(f, "Error parsing --memory-zone: id missing"),
@@ -182,10 +758,31 @@ impl fmt::Display for Error {
             ParseDisk(o) => write!(f, "Error parsing --disk: {}", o),
             ParseRNG(o) => write!(f, "Error parsing --rng: {}", o),
             ParseBalloon(o) => write!(f, "Error parsing --balloon: {}", o),
+            ParseCgroup(o) => write!(f, "Error parsing --cgroup: {}", o),
+            ParseCgroupPathMissing => write!(f, "Error parsing --cgroup: path missing"),
+            ParseOomPolicy(o) => write!(f, "Error parsing --oom-policy: {}", o),
+            ParseOomPolicyRssLimitMissing => {
+                write!(f, "Error parsing --oom-policy: rss_limit missing")
+            }
+            ParseOnTripleFault(ParseTripleFaultActionError::InvalidValue(v)) => {
+                write!(f, "Error parsing --on-triple-fault: invalid action {}", v)
+            }
+            ParseOnCrash(ParseCrashActionError::InvalidValue(v)) => {
+                write!(f, "Error parsing --on-crash: invalid action {}", v)
+            }
+            ParseCrashDump(o) => write!(f, "Error parsing --crash-dump: {}", o),
+            ParseCrashDumpFileMissing => write!(f, "Error parsing --crash-dump: file missing"),
+            ParseTpm(o) => write!(f, "Error parsing --tpm: {}", o),
+            ParseTpmSocketMissing => write!(f, "Error parsing --tpm: socket missing"),
             ParseRestore(o) => write!(f, "Error parsing --restore: {}", o),
             #[cfg(target_arch = "x86_64")]
             ParseSgxEpc(o) => write!(f, "Error parsing --sgx-epc: {}", o),
+            #[cfg(target_arch = "x86_64")]
+            ParseSev(o) => write!(f, "Error parsing --sev: {}", o),
             ParseNuma(o) => write!(f, "Error parsing --numa: {}", o),
+            ParseAcpiTable(o) => write!(f, "Error parsing --acpi-table: {}", o),
+            ParseAcpiTablePathMissing => write!(f, "Error parsing --acpi-table: path missing"),
+            ParsePlatform(o) => write!(f, "Error parsing --platform: {}", o),
             ParseRestoreSourceUrlMissing => {
                 write!(f, "Error parsing --restore: source_url missing")
             }
@@ -207,16 +804,31 @@ pub struct VmParams<'a> {
     pub net: Option<Vec<&'a str>>,
     pub rng: &'a str,
     pub balloon: Option<&'a str>,
+    pub cgroup: Option<&'a str>,
+    pub oom_policy: Option<&'a str>,
+    pub on_triple_fault: &'a str,
+    pub on_crash: Option<&'a str>,
+    pub platform: Option<&'a str>,
     pub fs: Option<Vec<&'a str>>,
     pub pmem: Option<Vec<&'a str>>,
     pub serial: &'a str,
     pub console: &'a str,
+    pub crash_dump: Option<&'a str>,
     pub devices: Option<Vec<&'a str>>,
+    pub user_devices: Option<Vec<&'a str>>,
     pub vsock: Option<&'a str>,
+    pub input: Option<Vec<&'a str>>,
+    pub gpio: Option<Vec<&'a str>>,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<&'a str>>,
+    #[cfg(target_arch = "x86_64")]
+    pub sev: Option<&'a str>,
     pub numa: Option<Vec<&'a str>>,
+    pub acpi_tables: Option<Vec<&'a str>>,
     pub watchdog: bool,
+    pub battery: bool,
+    pub tpm: Option<&'a str>,
+    pub access_log: Option<Vec<&'a str>>,
 }
 
 impl<'a> VmParams<'a> {
@@ -227,6 +839,8 @@ impl<'a> VmParams<'a> {
         let memory_zones: Option<Vec<&str>> = args.values_of("memory-zone").map(|x| x.collect());
         let rng = args.value_of("rng").unwrap();
         let serial = args.value_of("serial").unwrap();
+        let on_triple_fault = args.value_of("on-triple-fault").unwrap();
+        let on_crash = args.value_of("on-crash");
 
         let kernel = args.value_of("kernel");
         let initramfs = args.value_of("initramfs");
@@ -235,15 +849,28 @@ impl<'a> VmParams<'a> {
         let disks: Option<Vec<&str>> = args.values_of("disk").map(|x| x.collect());
         let net: Option<Vec<&str>> = args.values_of("net").map(|x| x.collect());
         let console = args.value_of("console").unwrap();
+        let crash_dump = args.value_of("crash-dump");
         let balloon = args.value_of("balloon");
+        let cgroup = args.value_of("cgroup");
+        let oom_policy = args.value_of("oom-policy");
+        let platform = args.value_of("platform");
         let fs: Option<Vec<&str>> = args.values_of("fs").map(|x| x.collect());
         let pmem: Option<Vec<&str>> = args.values_of("pmem").map(|x| x.collect());
         let devices: Option<Vec<&str>> = args.values_of("device").map(|x| x.collect());
+        let user_devices: Option<Vec<&str>> = args.values_of("user-device").map(|x| x.collect());
         let vsock: Option<&str> = args.value_of("vsock");
+        let input: Option<Vec<&str>> = args.values_of("input").map(|x| x.collect());
+        let gpio: Option<Vec<&str>> = args.values_of("gpio").map(|x| x.collect());
         #[cfg(target_arch = "x86_64")]
         let sgx_epc: Option<Vec<&str>> = args.values_of("sgx-epc").map(|x| x.collect());
+        #[cfg(target_arch = "x86_64")]
+        let sev: Option<&str> = args.value_of("sev");
         let numa: Option<Vec<&str>> = args.values_of("numa").map(|x| x.collect());
+        let acpi_tables: Option<Vec<&str>> = args.values_of("acpi-table").map(|x| x.collect());
         let watchdog = args.is_present("watchdog");
+        let battery = args.is_present("battery");
+        let tpm: Option<&str> = args.value_of("tpm");
+        let access_log: Option<Vec<&str>> = args.values_of("access-log").map(|x| x.collect());
 
         VmParams {
             cpus,
@@ -256,16 +883,31 @@ impl<'a> VmParams<'a> {
             net,
             rng,
             balloon,
+            cgroup,
+            oom_policy,
+            on_triple_fault,
+            on_crash,
+            platform,
             fs,
             pmem,
             serial,
             console,
+            crash_dump,
             devices,
+            user_devices,
             vsock,
+            input,
+            gpio,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
+            #[cfg(target_arch = "x86_64")]
+            sev,
             numa,
+            acpi_tables,
             watchdog,
+            battery,
+            tpm,
+            access_log,
         }
     }
 }
@@ -340,16 +982,200 @@ impl FromStr for CpuTopology {
     }
 }
 
+/// Part of the CPU topology whose logical processors share a cache.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub enum CacheSharing {
+    Thread,
+    Core,
+    Die,
+    Package,
+}
+
+#[derive(Debug)]
+pub enum CacheTopologyParseError {
+    InvalidValue(String),
+}
+
+impl FromStr for CacheSharing {
+    type Err = CacheTopologyParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "thread" => Ok(CacheSharing::Thread),
+            "core" => Ok(CacheSharing::Core),
+            "die" => Ok(CacheSharing::Die),
+            "package" => Ok(CacheSharing::Package),
+            _ => Err(CacheTopologyParseError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Processors sharing each level of cache, exposed to the guest along with
+/// the CPU topology.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CacheTopology {
+    pub l1: CacheSharing,
+    pub l2: CacheSharing,
+    pub l3: CacheSharing,
+}
+
+impl Default for CacheTopology {
+    fn default() -> Self {
+        CacheTopology {
+            l1: CacheSharing::Core,
+            l2: CacheSharing::Core,
+            l3: CacheSharing::Package,
+        }
+    }
+}
+
+impl FromStr for CacheTopology {
+    type Err = CacheTopologyParseError;
+
+    // <l1>:<l2>:<l3>
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts = s
+            .split(':')
+            .map(CacheSharing::from_str)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if parts.len() != 3 {
+            return Err(Self::Err::InvalidValue(s.to_owned()));
+        }
+
+        Ok(CacheTopology {
+            l1: parts[0],
+            l2: parts[1],
+            l3: parts[2],
+        })
+    }
+}
+
+pub enum CpuidLeafParseError {
+    InvalidValue(String),
+}
+
+/// What the guest reads from a CPUID leaf, in place of what the VMM would
+/// expose otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum CpuidLeafAction {
+    /// The leaf as reported by the host CPU.
+    Host,
+    /// All the registers of the leaf read as zero.
+    Zero,
+    /// Explicit values of the registers.
+    Values {
+        eax: u32,
+        ebx: u32,
+        ecx: u32,
+        edx: u32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CpuidLeafConfig {
+    pub function: u32,
+    #[serde(default)]
+    pub index: u32,
+    pub action: CpuidLeafAction,
+}
+
+// Leaves the guest can't boot without, which therefore can't be zeroed.
+const MANDATORY_CPUID_LEAVES: [u32; 4] = [0x0, 0x1, 0x8000_0000, 0x8000_0001];
+
+impl FromStr for CpuidLeafConfig {
+    type Err = CpuidLeafParseError;
+
+    // <function>[.<index>]@host|zero|<eax>/<ebx>/<ecx>/<edx>
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse = |v: &str| {
+            v.parse::<HexU32>()
+                .map(|v| v.0)
+                .map_err(|_| Self::Err::InvalidValue(s.to_owned()))
+        };
+
+        let parts: Vec<&str> = s.split('@').collect();
+        if parts.len() != 2 {
+            return Err(Self::Err::InvalidValue(s.to_owned()));
+        }
+
+        let leaf: Vec<&str> = parts[0].split('.').collect();
+        if leaf.len() > 2 {
+            return Err(Self::Err::InvalidValue(s.to_owned()));
+        }
+        let function = parse(leaf[0])?;
+        let index = match leaf.get(1) {
+            Some(index) => parse(index)?,
+            None => 0,
+        };
+
+        let action = match parts[1] {
+            "host" => CpuidLeafAction::Host,
+            "zero" => CpuidLeafAction::Zero,
+            values => {
+                let regs = values
+                    .split('/')
+                    .map(parse)
+                    .collect::<std::result::Result<Vec<u32>, _>>()?;
+                if regs.len() != 4 {
+                    return Err(Self::Err::InvalidValue(s.to_owned()));
+                }
+                CpuidLeafAction::Values {
+                    eax: regs[0],
+                    ebx: regs[1],
+                    ecx: regs[2],
+                    edx: regs[3],
+                }
+            }
+        };
+
+        Ok(CpuidLeafConfig {
+            function,
+            index,
+            action,
+        })
+    }
+}
+
+struct CpuidLeafList(Vec<CpuidLeafConfig>);
+
+impl FromStr for CpuidLeafList {
+    type Err = CpuidLeafParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(CpuidLeafList(
+            s.split(':')
+                .map(CpuidLeafConfig::from_str)
+                .collect::<std::result::Result<Vec<_>, _>>()?,
+        ))
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct CpusConfig {
     pub boot_vcpus: u8,
     pub max_vcpus: u8,
     #[serde(default)]
     pub topology: Option<CpuTopology>,
+    /// Processors sharing each level of cache, the L1 and L2 caches being
+    /// per core and the L3 per package if unset.
+    #[serde(default)]
+    pub caches: Option<CacheTopology>,
     #[serde(default)]
     pub kvm_hyperv: bool,
     #[serde(default)]
     pub max_phys_bits: Option<u8>,
+    /// Whether the guest can run its own guests, left to what the host
+    /// exposes if unset.
+    #[serde(default)]
+    pub nested: Option<bool>,
+    /// Whether the guest can use the TSC-deadline mode of its local APIC
+    /// timer, left to what the host supports if unset.
+    #[serde(default)]
+    pub tsc_deadline: Option<bool>,
+    /// CPUID leaves exposed to the guest as requested, applied once the
+    /// VMM is done setting up the CPUID.
+    #[serde(default)]
+    pub cpuid: Vec<CpuidLeafConfig>,
 }
 
 impl CpusConfig {
@@ -359,8 +1185,12 @@ impl CpusConfig {
             .add("boot")
             .add("max")
             .add("topology")
+            .add("caches")
             .add("kvm_hyperv")
-            .add("max_phys_bits");
+            .add("max_phys_bits")
+            .add("nested")
+            .add("tsc_deadline")
+            .add("cpuid");
         parser.parse(cpus).map_err(Error::ParseCpus)?;
 
         let boot_vcpus: u8 = parser
@@ -372,6 +1202,7 @@ impl CpusConfig {
             .map_err(Error::ParseCpus)?
             .unwrap_or(boot_vcpus);
         let topology = parser.convert("topology").map_err(Error::ParseCpus)?;
+        let caches = parser.convert("caches").map_err(Error::ParseCpus)?;
         let kvm_hyperv = parser
             .convert::<Toggle>("kvm_hyperv")
             .map_err(Error::ParseCpus)?
@@ -380,13 +1211,30 @@ impl CpusConfig {
         let max_phys_bits = parser
             .convert::<u8>("max_phys_bits")
             .map_err(Error::ParseCpus)?;
+        let nested = parser
+            .convert::<Toggle>("nested")
+            .map_err(Error::ParseCpus)?
+            .map(|toggle| toggle.0);
+        let tsc_deadline = parser
+            .convert::<Toggle>("tsc_deadline")
+            .map_err(Error::ParseCpus)?
+            .map(|toggle| toggle.0);
+        let cpuid = parser
+            .convert::<CpuidLeafList>("cpuid")
+            .map_err(Error::ParseCpus)?
+            .map(|list| list.0)
+            .unwrap_or_default();
 
         Ok(CpusConfig {
             boot_vcpus,
             max_vcpus,
             topology,
+            caches,
             kvm_hyperv,
             max_phys_bits,
+            nested,
+            tsc_deadline,
+            cpuid,
         })
     }
 }
@@ -397,8 +1245,12 @@ impl Default for CpusConfig {
             boot_vcpus: DEFAULT_VCPUS,
             max_vcpus: DEFAULT_VCPUS,
             topology: None,
+            caches: None,
             kvm_hyperv: false,
             max_phys_bits: None,
+            nested: None,
+            tsc_deadline: None,
+            cpuid: Vec::new(),
         }
     }
 }
@@ -432,8 +1284,15 @@ pub struct MemoryConfig {
     pub hotplug_method: HotplugMethod,
     #[serde(default)]
     pub hotplug_size: Option<u64>,
+    /// Guest address the region reserved for the hotpluggable memory starts
+    /// at, right after the boot RAM by default.
+    #[serde(default)]
+    pub hotplug_base: Option<u64>,
     #[serde(default)]
     pub hotplugged_size: Option<u64>,
+    /// Number of DIMMs which can be added with the ACPI hotplug method.
+    #[serde(default = "default_memoryconfig_hotplug_slots")]
+    pub hotplug_slots: usize,
     #[serde(default)]
     pub shared: bool,
     #[serde(default)]
@@ -441,9 +1300,19 @@ pub struct MemoryConfig {
     #[serde(default)]
     pub hugepage_size: Option<u64>,
     #[serde(default)]
+    pub prefault: bool,
+    /// Lock the guest RAM into host memory, implying `prefault`. The zones
+    /// backed by huge pages, which can't be swapped, are left as is.
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
     pub zones: Option<Vec<MemoryZoneConfig>>,
 }
 
+fn default_memoryconfig_hotplug_slots() -> usize {
+    DEFAULT_MEMORY_HOTPLUG_SLOTS
+}
+
 impl MemoryConfig {
     pub fn parse(memory: &str, memory_zones: Option<Vec<&str>>) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -453,10 +1322,14 @@ impl MemoryConfig {
             .add("mergeable")
             .add("hotplug_method")
             .add("hotplug_size")
+            .add("hotplug_base")
             .add("hotplugged_size")
+            .add("hotplug_slots")
             .add("shared")
             .add("hugepages")
-            .add("hugepage_size");
+            .add("hugepage_size")
+            .add("prefault")
+            .add("locked");
         parser.parse(memory).map_err(Error::ParseMemory)?;
 
         let size = parser
@@ -477,10 +1350,18 @@ impl MemoryConfig {
             .convert::<ByteSized>("hotplug_size")
             .map_err(Error::ParseMemory)?
             .map(|v| v.0);
+        let hotplug_base = parser
+            .convert::<ByteSized>("hotplug_base")
+            .map_err(Error::ParseMemory)?
+            .map(|v| v.0);
         let hotplugged_size = parser
             .convert::<ByteSized>("hotplugged_size")
             .map_err(Error::ParseMemory)?
             .map(|v| v.0);
+        let hotplug_slots = parser
+            .convert("hotplug_slots")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or_else(default_memoryconfig_hotplug_slots);
         let shared = parser
             .convert::<Toggle>("shared")
             .map_err(Error::ParseMemory)?
@@ -495,6 +1376,16 @@ impl MemoryConfig {
             .convert::<ByteSized>("hugepage_size")
             .map_err(Error::ParseMemory)?
             .map(|v| v.0);
+        let prefault = parser
+            .convert::<Toggle>("prefault")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let locked = parser
+            .convert::<Toggle>("locked")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
 
         let zones: Option<Vec<MemoryZoneConfig>> = if let Some(memory_zones) = &memory_zones {
             let mut zones = Vec::new();
@@ -568,10 +1459,14 @@ impl MemoryConfig {
             mergeable,
             hotplug_method,
             hotplug_size,
+            hotplug_base,
             hotplugged_size,
+            hotplug_slots,
             shared,
             hugepages,
             hugepage_size,
+            prefault,
+            locked,
             zones,
         })
     }
@@ -593,6 +1488,16 @@ impl MemoryConfig {
 
         size
     }
+
+    /// Whether the guest RAM can be mapped by other processes, such as
+    /// vhost-user backends. With user defined memory zones, all of them must
+    /// be shared.
+    pub fn is_shared(&self) -> bool {
+        match &self.zones {
+            Some(zones) if self.size == 0 => zones.iter().all(|zone| zone.shared),
+            _ => self.shared,
+        }
+    }
 }
 
 impl Default for MemoryConfig {
@@ -602,10 +1507,14 @@ impl Default for MemoryConfig {
             mergeable: false,
             hotplug_method: HotplugMethod::Acpi,
             hotplug_size: None,
+            hotplug_base: None,
             hotplugged_size: None,
+            hotplug_slots: default_memoryconfig_hotplug_slots(),
             shared: false,
             hugepages: false,
             hugepage_size: None,
+            prefault: false,
+            locked: false,
             zones: None,
         }
     }
@@ -616,9 +1525,15 @@ pub struct KernelConfig {
     pub path: PathBuf,
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 pub struct InitramfsConfig {
+    #[serde(default)]
     pub path: PathBuf,
+    /// Content of the initramfs, as an alternative to loading it from a file.
+    /// It's only provided by the VMM users embedding it, not through the
+    /// API, and is neither part of the snapshots nor sent on migration.
+    #[serde(skip)]
+    pub data: Option<Arc<Vec<u8>>>,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
@@ -636,56 +1551,270 @@ impl CmdlineConfig {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct DiskConfig {
-    pub path: Option<PathBuf>,
-    #[serde(default)]
-    pub readonly: bool,
-    #[serde(default)]
-    pub direct: bool,
-    #[serde(default)]
-    pub iommu: bool,
-    #[serde(default = "default_diskconfig_num_queues")]
-    pub num_queues: usize,
-    #[serde(default = "default_diskconfig_queue_size")]
-    pub queue_size: u16,
-    #[serde(default)]
-    pub vhost_user: bool,
-    pub vhost_socket: Option<String>,
-    #[serde(default = "default_diskconfig_poll_queue")]
-    pub poll_queue: bool,
-    #[serde(default)]
-    pub id: Option<String>,
-    // For testing use only. Not exposed in API.
-    #[serde(default)]
-    pub disable_io_uring: bool,
+/// How a virtio device is exposed to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum VirtioTransportType {
+    /// A PCI device, found by the guest probing the PCI bus.
+    Pci,
+    /// A page of MMIO registers and a legacy interrupt, described to the
+    /// guest through the device tree or the ACPI tables.
+    Mmio,
 }
 
-fn default_diskconfig_num_queues() -> usize {
-    DEFAULT_NUM_QUEUES_VUBLK
+impl Default for VirtioTransportType {
+    fn default() -> Self {
+        VirtioTransportType::Pci
+    }
 }
 
-fn default_diskconfig_queue_size() -> u16 {
-    DEFAULT_QUEUE_SIZE_VUBLK
+#[derive(Debug)]
+pub enum ParseVirtioTransportTypeError {
+    InvalidValue(String),
 }
 
-fn default_diskconfig_poll_queue() -> bool {
-    true
+impl FromStr for VirtioTransportType {
+    type Err = ParseVirtioTransportTypeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pci" => Ok(VirtioTransportType::Pci),
+            "mmio" => Ok(VirtioTransportType::Mmio),
+            _ => Err(ParseVirtioTransportTypeError::InvalidValue(s.to_owned())),
+        }
+    }
 }
 
-impl Default for DiskConfig {
-    fn default() -> Self {
-        Self {
-            path: None,
-            readonly: false,
-            direct: false,
-            iommu: false,
-            num_queues: default_diskconfig_num_queues(),
+/// Scheduling class of the I/O of a thread, as set with ionice(1).
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum IoPriorityClass {
+    /// Served first, whatever the I/O of the other threads.
+    Realtime,
+    /// Given a share of the disk time depending on its level.
+    BestEffort,
+    /// Only served once no other thread does I/O.
+    Idle,
+}
+
+/// I/O priority of the worker threads of a disk on the host, the lower
+/// levels of a class being served first.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(from = "IoPrioritySpec")]
+pub struct IoPriority {
+    pub class: IoPriorityClass,
+    pub level: u8,
+}
+
+// I/O priority as given to the API, the level defaulting as it does on the
+// command line.
+#[derive(Deserialize)]
+struct IoPrioritySpec {
+    class: IoPriorityClass,
+    level: Option<u8>,
+}
+
+impl From<IoPrioritySpec> for IoPriority {
+    fn from(spec: IoPrioritySpec) -> Self {
+        IoPriority {
+            class: spec.class,
+            level: spec
+                .level
+                .unwrap_or_else(|| IoPriority::default_level(spec.class)),
+        }
+    }
+}
+
+impl IoPriority {
+    pub const MAX_LEVEL: u8 = 7;
+    // Level of the threads the I/O priority isn't set for.
+    const DEFAULT_LEVEL: u8 = 4;
+
+    // The idle class has no levels, which must then be 0.
+    fn default_level(class: IoPriorityClass) -> u8 {
+        if class == IoPriorityClass::Idle {
+            0
+        } else {
+            Self::DEFAULT_LEVEL
+        }
+    }
+
+    /// Value given to ioprio_set(2), as built by IOPRIO_PRIO_VALUE().
+    pub fn ioprio(&self) -> u16 {
+        let class: u16 = match self.class {
+            IoPriorityClass::Realtime => 1,
+            IoPriorityClass::BestEffort => 2,
+            IoPriorityClass::Idle => 3,
+        };
+        (class << 13) | u16::from(self.level)
+    }
+}
+
+#[derive(Debug)]
+pub enum IoPriorityParseError {
+    InvalidValue(String),
+}
+
+impl FromStr for IoPriority {
+    type Err = IoPriorityParseError;
+
+    // <class>[:<level>]
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let class = match parts.next().unwrap_or_default().to_lowercase().as_str() {
+            "realtime" => IoPriorityClass::Realtime,
+            "best-effort" => IoPriorityClass::BestEffort,
+            "idle" => IoPriorityClass::Idle,
+            _ => return Err(IoPriorityParseError::InvalidValue(s.to_owned())),
+        };
+        let level = match parts.next() {
+            Some(level) => level
+                .parse()
+                .map_err(|_| IoPriorityParseError::InvalidValue(s.to_owned()))?,
+            None => Self::default_level(class),
+        };
+
+        Ok(IoPriority { class, level })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DiskConfig {
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub readonly: bool,
+    #[serde(default)]
+    pub direct: bool,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default = "default_diskconfig_num_queues")]
+    pub num_queues: usize,
+    #[serde(default = "default_diskconfig_queue_size")]
+    pub queue_size: u16,
+    #[serde(default)]
+    pub vhost_user: bool,
+    pub vhost_socket: Option<String>,
+    #[serde(default = "default_diskconfig_poll_queue")]
+    pub poll_queue: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub boot_index: Option<u16>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub numa_node: Option<u32>,
+    #[serde(default)]
+    pub readahead_cache: u64,
+    #[serde(default = "default_diskconfig_readahead_window")]
+    pub readahead_window: u64,
+    #[serde(default)]
+    pub overlay: Option<PathBuf>,
+    #[serde(default)]
+    pub notify_threshold: u16,
+    #[serde(default)]
+    pub notify_max_latency_us: u64,
+    #[serde(default)]
+    pub num_workers: Option<usize>,
+    #[serde(default)]
+    pub queue_weights: Option<Vec<u32>>,
+    /// Times a request failing with a transient error is retried before the
+    /// error is reported to the guest.
+    #[serde(default)]
+    pub io_retries: u32,
+    /// Milliseconds before the first retry, doubled for each of the next.
+    #[serde(default = "default_diskconfig_io_retry_backoff_ms")]
+    pub io_retry_backoff_ms: u64,
+    /// Requests the device holds in flight at most, DEFAULT_DISK_QUEUE_DEPTH
+    /// if not set.
+    #[serde(default)]
+    pub queue_depth: Option<u32>,
+    /// I/O priority of the worker threads on the host.
+    #[serde(default)]
+    pub io_priority: Option<IoPriority>,
+    #[serde(default)]
+    pub pci_subsystem_vendor_id: Option<u16>,
+    #[serde(default)]
+    pub pci_subsystem_id: Option<u16>,
+    #[serde(default)]
+    pub pci_serial: Option<String>,
+    /// Milliseconds the vhost-user backend has to answer each request while
+    /// the device is activated.
+    #[serde(default)]
+    pub activate_timeout: Option<u64>,
+    /// Whether the disk is exposed through an emulated NVMe controller
+    /// rather than virtio-block.
+    #[serde(default)]
+    pub nvme: bool,
+    /// Whether the device is left out until activated through the API, its
+    /// PCI slot being reserved meanwhile.
+    #[serde(default)]
+    pub cold: bool,
+    /// PCI b/d/f reserved for a cold device, for it to keep its slot once
+    /// activated and across reboots. It's neither part of the snapshots nor
+    /// sent on migration, the b/d/f being part of the device tree then.
+    #[serde(skip)]
+    pub cold_pci_bdf: Option<u32>,
+    #[serde(default)]
+    pub transport: VirtioTransportType,
+    // For testing use only. Not exposed in API.
+    #[serde(default)]
+    pub disable_io_uring: bool,
+}
+
+fn default_diskconfig_num_queues() -> usize {
+    DEFAULT_NUM_QUEUES_VUBLK
+}
+
+fn default_diskconfig_queue_size() -> u16 {
+    DEFAULT_QUEUE_SIZE_VUBLK
+}
+
+fn default_diskconfig_poll_queue() -> bool {
+    true
+}
+
+fn default_diskconfig_readahead_window() -> u64 {
+    DEFAULT_DISK_READAHEAD_WINDOW
+}
+
+fn default_diskconfig_io_retry_backoff_ms() -> u64 {
+    DEFAULT_DISK_IO_RETRY_BACKOFF_MS
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            readonly: false,
+            direct: false,
+            iommu: false,
+            num_queues: default_diskconfig_num_queues(),
             queue_size: default_diskconfig_queue_size(),
             vhost_user: false,
             vhost_socket: None,
             poll_queue: default_diskconfig_poll_queue(),
             id: None,
+            boot_index: None,
+            pci_segment: 0,
+            numa_node: None,
+            readahead_cache: 0,
+            readahead_window: default_diskconfig_readahead_window(),
+            overlay: None,
+            notify_threshold: 0,
+            notify_max_latency_us: 0,
+            num_workers: None,
+            queue_weights: None,
+            io_retries: 0,
+            io_retry_backoff_ms: default_diskconfig_io_retry_backoff_ms(),
+            queue_depth: None,
+            io_priority: None,
+            pci_subsystem_vendor_id: None,
+            pci_subsystem_id: None,
+            pci_serial: None,
+            activate_timeout: None,
+            nvme: false,
+            cold: false,
+            cold_pci_bdf: None,
+            transport: VirtioTransportType::Pci,
             disable_io_uring: false,
         }
     }
@@ -695,7 +1824,17 @@ impl DiskConfig {
     pub const SYNTAX: &'static str = "Disk parameters \
          \"path=<disk_image_path>,readonly=on|off,iommu=on|off,num_queues=<number_of_queues>,\
          queue_size=<size_of_each_queue>,vhost_user=<vhost_user_enable>,\
-         socket=<vhost_user_socket_path>, default true>,id=<device_id>\"";
+         socket=<vhost_user_socket_path>, default true>,id=<device_id>,\
+         boot_index=<boot_order_index>,pci_segment=<segment_id>,\
+         numa_node=<guest_numa_id>,readahead_cache=<read_ahead_cache_size>,readahead_window=<read_ahead_window_size>,\
+         overlay=<writable_overlay_path>,notify_threshold=<used_entries>,\
+         notify_max_latency_us=<microseconds>,num_workers=<number_of_worker_threads>,\
+         queue_weights=<weight_of_queue_0:weight_of_queue_1:...>,\
+         io_retries=<number_of_retries>,io_retry_backoff=<milliseconds>,\
+         queue_depth=<requests>,io_priority=realtime|best-effort|idle[:<level>],\
+         pci_subsystem_vendor_id=<vendor_id>,pci_subsystem_id=<subsystem_id>,\
+         pci_serial=<serial_number>,activate_timeout=<milliseconds>,nvme=on|off,cold=on|off,\
+         transport=pci|mmio\"";
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -710,6 +1849,27 @@ impl DiskConfig {
             .add("socket")
             .add("poll_queue")
             .add("id")
+            .add("boot_index")
+            .add("pci_segment")
+            .add("numa_node")
+            .add("readahead_cache")
+            .add("readahead_window")
+            .add("overlay")
+            .add("notify_threshold")
+            .add("notify_max_latency_us")
+            .add("num_workers")
+            .add("queue_weights")
+            .add("io_retries")
+            .add("io_retry_backoff")
+            .add("queue_depth")
+            .add("io_priority")
+            .add("pci_subsystem_vendor_id")
+            .add("pci_subsystem_id")
+            .add("pci_serial")
+            .add("activate_timeout")
+            .add("nvme")
+            .add("cold")
+            .add("transport")
             .add("_disable_io_uring");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
@@ -749,6 +1909,72 @@ impl DiskConfig {
             .unwrap_or_else(|| Toggle(default_diskconfig_poll_queue()))
             .0;
         let id = parser.get("id");
+        let boot_index = parser.convert("boot_index").map_err(Error::ParseDisk)?;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let numa_node = parser.convert("numa_node").map_err(Error::ParseDisk)?;
+        let readahead_cache = parser
+            .convert::<ByteSized>("readahead_cache")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(ByteSized(0))
+            .0;
+        let readahead_window = parser
+            .convert::<ByteSized>("readahead_window")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_else(|| ByteSized(default_diskconfig_readahead_window()))
+            .0;
+        let overlay = parser.get("overlay").map(PathBuf::from);
+        let notify_threshold = parser
+            .convert("notify_threshold")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let notify_max_latency_us = parser
+            .convert("notify_max_latency_us")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let num_workers = parser.convert("num_workers").map_err(Error::ParseDisk)?;
+        let queue_weights = parser
+            .convert::<IntegerList>("queue_weights")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0.iter().map(|e| *e as u32).collect());
+        let io_retries = parser
+            .convert("io_retries")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let io_retry_backoff_ms = parser
+            .convert("io_retry_backoff")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_else(default_diskconfig_io_retry_backoff_ms);
+        let queue_depth = parser.convert("queue_depth").map_err(Error::ParseDisk)?;
+        let io_priority = parser.convert("io_priority").map_err(Error::ParseDisk)?;
+        let pci_subsystem_vendor_id = parser
+            .convert::<HexU16>("pci_subsystem_vendor_id")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let pci_subsystem_id = parser
+            .convert::<HexU16>("pci_subsystem_id")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let pci_serial = parser.get("pci_serial");
+        let activate_timeout = parser
+            .convert("activate_timeout")
+            .map_err(Error::ParseDisk)?;
+        let nvme = parser
+            .convert::<Toggle>("nvme")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let cold = parser
+            .convert::<Toggle>("cold")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let transport = parser
+            .convert("transport")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
         let disable_io_uring = parser
             .convert::<Toggle>("_disable_io_uring")
             .map_err(Error::ParseDisk)?
@@ -770,6 +1996,28 @@ impl DiskConfig {
             vhost_user,
             poll_queue,
             id,
+            boot_index,
+            pci_segment,
+            numa_node,
+            readahead_cache,
+            readahead_window,
+            overlay,
+            notify_threshold,
+            notify_max_latency_us,
+            num_workers,
+            queue_weights,
+            io_retries,
+            io_retry_backoff_ms,
+            queue_depth,
+            io_priority,
+            pci_subsystem_vendor_id,
+            pci_subsystem_id,
+            pci_serial,
+            activate_timeout,
+            nvme,
+            cold,
+            cold_pci_bdf: None,
+            transport,
             disable_io_uring,
         })
     }
@@ -797,9 +2045,72 @@ pub struct NetConfig {
     pub vhost_user: bool,
     pub vhost_socket: Option<String>,
     #[serde(default)]
+    pub vhost_kernel: bool,
+    #[serde(default)]
     pub id: Option<String>,
     #[serde(default)]
     pub fds: Option<Vec<i32>>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub numa_node: Option<u32>,
+    #[serde(default)]
+    pub pci_subsystem_vendor_id: Option<u16>,
+    #[serde(default)]
+    pub pci_subsystem_id: Option<u16>,
+    #[serde(default)]
+    pub pci_serial: Option<String>,
+    /// Milliseconds the vhost-user backend has to answer each request while
+    /// the device is activated.
+    #[serde(default)]
+    pub activate_timeout: Option<u64>,
+    /// Milliseconds the vhost-user backend has to start listening on its
+    /// socket when the device is created.
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+    /// Whether the device is left out until activated through the API, its
+    /// PCI slot being reserved meanwhile.
+    #[serde(default)]
+    pub cold: bool,
+    /// PCI b/d/f reserved for a cold device, for it to keep its slot once
+    /// activated and across reboots. It's neither part of the snapshots nor
+    /// sent on migration, the b/d/f being part of the device tree then.
+    #[serde(skip)]
+    pub cold_pci_bdf: Option<u32>,
+    #[serde(default)]
+    pub transport: VirtioTransportType,
+    /// Offloads of the frames sent by the guest, which the vhost-user
+    /// backend is allowed to offer.
+    #[serde(default = "default_netconfig_offload")]
+    pub host_csum: bool,
+    #[serde(default = "default_netconfig_offload")]
+    pub host_tso: bool,
+    #[serde(default = "default_netconfig_offload")]
+    pub host_ufo: bool,
+    /// Offloads of the frames received by the guest, which the vhost-user
+    /// backend is allowed to offer.
+    #[serde(default = "default_netconfig_offload")]
+    pub guest_csum: bool,
+    #[serde(default = "default_netconfig_offload")]
+    pub guest_tso: bool,
+    #[serde(default = "default_netconfig_offload")]
+    pub guest_ufo: bool,
+    /// Whether the frames sent by the guest from another MAC address than
+    /// `mac` are dropped.
+    #[serde(default)]
+    pub anti_spoof: bool,
+    /// Microseconds the threads of the device keep polling for the next
+    /// events once they handled some, before blocking.
+    #[serde(default)]
+    pub busy_poll: Option<u64>,
+    /// Whether the frames received from the TAP come from trusted local
+    /// sources, their checksum being reported as valid to the guest.
+    #[serde(default)]
+    pub trusted_local: bool,
+    #[serde(default)]
+    pub notify_threshold: u16,
+    #[serde(default)]
+    pub notify_max_latency_us: u64,
 }
 
 fn default_netconfig_tap() -> Option<String> {
@@ -826,6 +2137,10 @@ fn default_netconfig_queue_size() -> u16 {
     DEFAULT_QUEUE_SIZE_VUNET
 }
 
+fn default_netconfig_offload() -> bool {
+    true
+}
+
 impl Default for NetConfig {
     fn default() -> Self {
         Self {
@@ -839,8 +2154,30 @@ impl Default for NetConfig {
             queue_size: default_netconfig_queue_size(),
             vhost_user: false,
             vhost_socket: None,
+            vhost_kernel: false,
             id: None,
             fds: None,
+            pci_segment: 0,
+            numa_node: None,
+            pci_subsystem_vendor_id: None,
+            pci_subsystem_id: None,
+            pci_serial: None,
+            activate_timeout: None,
+            connect_timeout: None,
+            cold: false,
+            cold_pci_bdf: None,
+            transport: VirtioTransportType::Pci,
+            host_csum: default_netconfig_offload(),
+            host_tso: default_netconfig_offload(),
+            host_ufo: default_netconfig_offload(),
+            guest_csum: default_netconfig_offload(),
+            guest_tso: default_netconfig_offload(),
+            guest_ufo: default_netconfig_offload(),
+            anti_spoof: false,
+            busy_poll: None,
+            trusted_local: false,
+            notify_threshold: 0,
+            notify_max_latency_us: 0,
         }
     }
 }
@@ -849,7 +2186,15 @@ impl NetConfig {
     pub const SYNTAX: &'static str = "Network parameters \
     \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,fd=<fd1:fd2...>,iommu=on|off,\
     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
-    vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,id=<device_id>\"";
+    vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,\
+    vhost_kernel=<vhost_kernel_enable>,id=<device_id>,pci_segment=<segment_id>,\
+    numa_node=<guest_numa_id>,\
+    pci_subsystem_vendor_id=<vendor_id>,pci_subsystem_id=<subsystem_id>,\
+    pci_serial=<serial_number>,activate_timeout=<milliseconds>,\
+    connect_timeout=<milliseconds>,cold=on|off,transport=pci|mmio,host_csum=on|off,host_tso=on|off,host_ufo=on|off,\
+    guest_csum=on|off,guest_tso=on|off,guest_ufo=on|off,anti_spoof=on|off,\
+    busy_poll=<microseconds>,trusted_local=on|off,notify_threshold=<used_entries>,\
+    notify_max_latency_us=<microseconds>\"";
 
     pub fn parse(net: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -865,8 +2210,29 @@ impl NetConfig {
             .add("num_queues")
             .add("vhost_user")
             .add("socket")
+            .add("vhost_kernel")
             .add("id")
-            .add("fd");
+            .add("fd")
+            .add("pci_segment")
+            .add("numa_node")
+            .add("pci_subsystem_vendor_id")
+            .add("pci_subsystem_id")
+            .add("pci_serial")
+            .add("activate_timeout")
+            .add("connect_timeout")
+            .add("cold")
+            .add("transport")
+            .add("host_csum")
+            .add("host_tso")
+            .add("host_ufo")
+            .add("guest_csum")
+            .add("guest_tso")
+            .add("guest_ufo")
+            .add("anti_spoof")
+            .add("busy_poll")
+            .add("trusted_local")
+            .add("notify_threshold")
+            .add("notify_max_latency_us");
         parser.parse(net).map_err(Error::ParseNetwork)?;
 
         let tap = parser.get("tap");
@@ -902,11 +2268,77 @@ impl NetConfig {
             .unwrap_or(Toggle(false))
             .0;
         let vhost_socket = parser.get("socket");
+        let vhost_kernel = parser
+            .convert::<Toggle>("vhost_kernel")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
         let id = parser.get("id");
         let fds = parser
             .convert::<IntegerList>("fd")
             .map_err(Error::ParseNetwork)?
             .map(|v| v.0.iter().map(|e| *e as i32).collect());
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_default();
+        let numa_node = parser.convert("numa_node").map_err(Error::ParseNetwork)?;
+        let pci_subsystem_vendor_id = parser
+            .convert::<HexU16>("pci_subsystem_vendor_id")
+            .map_err(Error::ParseNetwork)?
+            .map(|v| v.0);
+        let pci_subsystem_id = parser
+            .convert::<HexU16>("pci_subsystem_id")
+            .map_err(Error::ParseNetwork)?
+            .map(|v| v.0);
+        let pci_serial = parser.get("pci_serial");
+        let activate_timeout = parser
+            .convert("activate_timeout")
+            .map_err(Error::ParseNetwork)?;
+        let connect_timeout = parser
+            .convert("connect_timeout")
+            .map_err(Error::ParseNetwork)?;
+        let cold = parser
+            .convert::<Toggle>("cold")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let transport = parser
+            .convert("transport")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_default();
+        let offload = |name| -> Result<bool> {
+            Ok(parser
+                .convert::<Toggle>(name)
+                .map_err(Error::ParseNetwork)?
+                .unwrap_or(Toggle(default_netconfig_offload()))
+                .0)
+        };
+        let host_csum = offload("host_csum")?;
+        let host_tso = offload("host_tso")?;
+        let host_ufo = offload("host_ufo")?;
+        let guest_csum = offload("guest_csum")?;
+        let guest_tso = offload("guest_tso")?;
+        let guest_ufo = offload("guest_ufo")?;
+        let anti_spoof = parser
+            .convert::<Toggle>("anti_spoof")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let busy_poll = parser.convert("busy_poll").map_err(Error::ParseNetwork)?;
+        let trusted_local = parser
+            .convert::<Toggle>("trusted_local")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let notify_threshold = parser
+            .convert("notify_threshold")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_default();
+        let notify_max_latency_us = parser
+            .convert("notify_max_latency_us")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_default();
 
         let config = NetConfig {
             tap,
@@ -919,8 +2351,30 @@ impl NetConfig {
             queue_size,
             vhost_user,
             vhost_socket,
+            vhost_kernel,
             id,
             fds,
+            pci_segment,
+            numa_node,
+            pci_subsystem_vendor_id,
+            pci_subsystem_id,
+            pci_serial,
+            activate_timeout,
+            connect_timeout,
+            cold,
+            cold_pci_bdf: None,
+            transport,
+            host_csum,
+            host_tso,
+            host_ufo,
+            guest_csum,
+            guest_tso,
+            guest_ufo,
+            anti_spoof,
+            busy_poll,
+            trusted_local,
+            notify_threshold,
+            notify_max_latency_us,
         };
         config.validate().map_err(Error::Validation)?;
         Ok(config)
@@ -930,25 +2384,159 @@ impl NetConfig {
             return Err(ValidationError::VnetQueueLowerThan2);
         }
 
+        if self.num_queues % 2 != 0 {
+            return Err(ValidationError::VnetQueueOdd);
+        }
+
         if self.fds.is_some() && self.fds.as_ref().unwrap().len() * 2 != self.num_queues {
             return Err(ValidationError::VnetQueueFdMismatch);
         }
 
+        if self.vhost_user && self.vhost_socket.is_none() {
+            return Err(ValidationError::VhostUserMissingSocket);
+        }
+
+        if self.vhost_kernel && self.vhost_user {
+            return Err(ValidationError::VhostKernelAndVhostUser);
+        }
+
+        // The kernel would need to translate the addresses through the
+        // virtio-iommu mappings.
+        if self.vhost_kernel && self.iommu {
+            return Err(ValidationError::VhostKernelIommu);
+        }
+
+        // The frames sent by the guest are handled by the kernel only.
+        if self.vhost_kernel && self.anti_spoof {
+            return Err(ValidationError::VhostKernelAntiSpoof);
+        }
+
+        // The queues are processed by the threads of the kernel.
+        if self.vhost_kernel && self.busy_poll.is_some() {
+            return Err(ValidationError::VhostKernelBusyPoll);
+        }
+
+        // The frames received from the TAP don't go through the VMM.
+        if (self.vhost_kernel || self.vhost_user) && self.trusted_local {
+            return Err(ValidationError::TrustedLocalUnsupported);
+        }
+
+        validate_notification_suppression(
+            self.notify_threshold,
+            self.notify_max_latency_us,
+            self.queue_size,
+            self.vhost_kernel || self.vhost_user,
+        )?;
+
+        validate_activate_timeout(self.activate_timeout, self.vhost_user)?;
+        match self.connect_timeout {
+            Some(timeout) if timeout == 0 || timeout > MAX_NET_CONNECT_TIMEOUT_MS => {
+                return Err(ValidationError::InvalidConnectTimeout)
+            }
+            Some(_) if !self.vhost_user => {
+                return Err(ValidationError::ConnectTimeoutRequiresVhostUser)
+            }
+            _ => {}
+        }
+
+        // The virtio-net device only offers the offloads its TAP handles.
+        let offloads = [
+            self.host_csum,
+            self.host_tso,
+            self.host_ufo,
+            self.guest_csum,
+            self.guest_tso,
+            self.guest_ufo,
+        ];
+        if !self.vhost_user && offloads.iter().any(|enabled| !enabled) {
+            return Err(ValidationError::OffloadsRequireVhostUser);
+        }
+
+        // Like any hotplugged device, they can't be attached to the
+        // virtio-iommu once the guest is running.
+        if self.cold && self.iommu {
+            return Err(ValidationError::ColdDeviceIommu);
+        }
+
+        validate_mmio_transport(
+            self.transport,
+            &[
+                ("vhost_user", self.vhost_user),
+                ("vhost_kernel", self.vhost_kernel),
+                ("iommu", self.iommu),
+                ("pci_segment", self.pci_segment != 0),
+                ("numa_node", self.numa_node.is_some()),
+                (
+                    "pci_subsystem_vendor_id",
+                    self.pci_subsystem_vendor_id.is_some(),
+                ),
+                ("pci_subsystem_id", self.pci_subsystem_id.is_some()),
+                ("pci_serial", self.pci_serial.is_some()),
+                ("cold", self.cold),
+            ],
+        )?;
+
         Ok(())
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum RngSeedMode {
+    Consume,
+    Replay,
+}
+
+impl Default for RngSeedMode {
+    fn default() -> Self {
+        RngSeedMode::Replay
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseRngSeedModeError {
+    InvalidValue(String),
+}
+
+impl FromStr for RngSeedMode {
+    type Err = ParseRngSeedModeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "consume" => Ok(RngSeedMode::Consume),
+            "replay" => Ok(RngSeedMode::Replay),
+            _ => Err(ParseRngSeedModeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct RngConfig {
     pub src: PathBuf,
     #[serde(default)]
     pub iommu: bool,
+    #[serde(default)]
+    pub transport: VirtioTransportType,
+    /// File the entropy is recorded to or replayed from, making it
+    /// predictable. Only meant for tests.
+    #[serde(default)]
+    pub seed: Option<PathBuf>,
+    #[serde(default)]
+    pub seed_mode: RngSeedMode,
+    /// Tells the guest where the entropy comes from.
+    #[serde(default)]
+    pub quality: bool,
 }
 
 impl RngConfig {
     pub fn parse(rng: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("src").add("iommu");
+        parser
+            .add("src")
+            .add("iommu")
+            .add("transport")
+            .add("seed")
+            .add("seed_mode")
+            .add("quality");
         parser.parse(rng).map_err(Error::ParseRNG)?;
 
         let src = PathBuf::from(
@@ -961,8 +2549,29 @@ impl RngConfig {
             .map_err(Error::ParseRNG)?
             .unwrap_or(Toggle(false))
             .0;
+        let transport = parser
+            .convert("transport")
+            .map_err(Error::ParseRNG)?
+            .unwrap_or_default();
+        let seed = parser.get("seed").map(PathBuf::from);
+        let seed_mode = parser
+            .convert("seed_mode")
+            .map_err(Error::ParseRNG)?
+            .unwrap_or_default();
+        let quality = parser
+            .convert::<Toggle>("quality")
+            .map_err(Error::ParseRNG)?
+            .unwrap_or(Toggle(false))
+            .0;
 
-        Ok(RngConfig { src, iommu })
+        Ok(RngConfig {
+            src,
+            iommu,
+            transport,
+            seed,
+            seed_mode,
+            quality,
+        })
     }
 }
 
@@ -971,6 +2580,10 @@ impl Default for RngConfig {
         RngConfig {
             src: PathBuf::from(DEFAULT_RNG_SOURCE),
             iommu: false,
+            transport: VirtioTransportType::Pci,
+            seed: None,
+            seed_mode: RngSeedMode::Replay,
+            quality: false,
         }
     }
 }
@@ -978,14 +2591,18 @@ impl Default for RngConfig {
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct BalloonConfig {
     pub size: u64,
+    /// Lets the guest report its free pages, skipped by the migration.
+    #[serde(default)]
+    pub free_page_hinting: bool,
 }
 
 impl BalloonConfig {
-    pub const SYNTAX: &'static str = "Balloon parameters \"size=<balloon_size>\"";
+    pub const SYNTAX: &'static str =
+        "Balloon parameters \"size=<balloon_size>,free_page_hinting=on|off\"";
 
     pub fn parse(balloon: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("size");
+        parser.add("size").add("free_page_hinting");
         parser.parse(balloon).map_err(Error::ParseBalloon)?;
 
         let size = parser
@@ -993,36 +2610,376 @@ impl BalloonConfig {
             .map_err(Error::ParseBalloon)?
             .map(|v| v.0)
             .unwrap_or(0);
+        let free_page_hinting = parser
+            .convert::<Toggle>("free_page_hinting")
+            .map_err(Error::ParseBalloon)?
+            .unwrap_or(Toggle(false))
+            .0;
 
-        Ok(BalloonConfig { size })
+        Ok(BalloonConfig {
+            size,
+            free_page_hinting,
+        })
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct FsConfig {
-    pub tag: String,
-    pub socket: PathBuf,
-    #[serde(default = "default_fsconfig_num_queues")]
-    pub num_queues: usize,
-    #[serde(default = "default_fsconfig_queue_size")]
-    pub queue_size: u16,
-    #[serde(default = "default_fsconfig_dax")]
-    pub dax: bool,
-    #[serde(default = "default_fsconfig_cache_size")]
-    pub cache_size: u64,
-    #[serde(default)]
-    pub id: Option<String>,
-}
+pub const DEFAULT_CGROUP_CPU_PERIOD: u64 = 100_000;
 
-fn default_fsconfig_num_queues() -> usize {
-    1
+fn default_cgroup_cpu_period() -> u64 {
+    DEFAULT_CGROUP_CPU_PERIOD
 }
 
-fn default_fsconfig_queue_size() -> u16 {
-    1024
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CgroupConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub cpu_quota: Option<u64>,
+    #[serde(default = "default_cgroup_cpu_period")]
+    pub cpu_period: u64,
+    #[serde(default)]
+    pub memory_max: Option<u64>,
 }
 
-fn default_fsconfig_dax() -> bool {
+impl CgroupConfig {
+    pub const SYNTAX: &'static str = "cgroups v2 parameters \
+        \"path=<cgroup_path>,cpu_quota=<cpu_quota_us>,cpu_period=<cpu_period_us>,\
+        memory_max=<memory_limit>\"";
+
+    pub fn parse(cgroup: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("path")
+            .add("cpu_quota")
+            .add("cpu_period")
+            .add("memory_max");
+        parser.parse(cgroup).map_err(Error::ParseCgroup)?;
+
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseCgroupPathMissing)?;
+        let cpu_quota = parser.convert("cpu_quota").map_err(Error::ParseCgroup)?;
+        let cpu_period = parser
+            .convert("cpu_period")
+            .map_err(Error::ParseCgroup)?
+            .unwrap_or(DEFAULT_CGROUP_CPU_PERIOD);
+        let memory_max = parser
+            .convert::<ByteSized>("memory_max")
+            .map_err(Error::ParseCgroup)?
+            .map(|v| v.0);
+
+        Ok(CgroupConfig {
+            path,
+            cpu_quota,
+            cpu_period,
+            memory_max,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum OomAction {
+    Balloon,
+    Pause,
+}
+
+impl Default for OomAction {
+    fn default() -> Self {
+        OomAction::Balloon
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseOomActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for OomAction {
+    type Err = ParseOomActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "balloon" => Ok(OomAction::Balloon),
+            "pause" => Ok(OomAction::Pause),
+            _ => Err(ParseOomActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+pub const DEFAULT_OOM_POLICY_BALLOON_STEP: u64 = 128 << 20;
+pub const DEFAULT_OOM_POLICY_INTERVAL: u64 = 1000;
+
+fn default_oompolicy_balloon_step() -> u64 {
+    DEFAULT_OOM_POLICY_BALLOON_STEP
+}
+
+fn default_oompolicy_interval() -> u64 {
+    DEFAULT_OOM_POLICY_INTERVAL
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OomPolicyConfig {
+    pub rss_limit: u64,
+    #[serde(default)]
+    pub action: OomAction,
+    #[serde(default = "default_oompolicy_balloon_step")]
+    pub balloon_step: u64,
+    // Milliseconds between two checks of the VMM RSS.
+    #[serde(default = "default_oompolicy_interval")]
+    pub interval: u64,
+}
+
+impl OomPolicyConfig {
+    pub const SYNTAX: &'static str = "OOM policy parameters \
+        \"rss_limit=<vmm_rss_soft_limit>,action=balloon|pause,\
+        balloon_step=<balloon_inflation_size>,interval=<check_interval_ms>\"";
+
+    pub fn parse(oom_policy: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("rss_limit")
+            .add("action")
+            .add("balloon_step")
+            .add("interval");
+        parser.parse(oom_policy).map_err(Error::ParseOomPolicy)?;
+
+        let rss_limit = parser
+            .convert::<ByteSized>("rss_limit")
+            .map_err(Error::ParseOomPolicy)?
+            .map(|v| v.0)
+            .ok_or(Error::ParseOomPolicyRssLimitMissing)?;
+        let action = parser
+            .convert("action")
+            .map_err(Error::ParseOomPolicy)?
+            .unwrap_or_default();
+        let balloon_step = parser
+            .convert::<ByteSized>("balloon_step")
+            .map_err(Error::ParseOomPolicy)?
+            .map_or(DEFAULT_OOM_POLICY_BALLOON_STEP, |v| v.0);
+        let interval = parser
+            .convert("interval")
+            .map_err(Error::ParseOomPolicy)?
+            .unwrap_or(DEFAULT_OOM_POLICY_INTERVAL);
+
+        Ok(OomPolicyConfig {
+            rss_limit,
+            action,
+            balloon_step,
+            interval,
+        })
+    }
+}
+
+/// What the VMM does when the guest triple faults.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum TripleFaultAction {
+    /// Reboot the guest, as a reset would.
+    Reboot,
+    /// Shut the VMM down, as a power off would.
+    Shutdown,
+    /// Pause the VM, leaving it in its faulting state for inspection.
+    Pause,
+}
+
+impl Default for TripleFaultAction {
+    fn default() -> Self {
+        TripleFaultAction::Reboot
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseTripleFaultActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for TripleFaultAction {
+    type Err = ParseTripleFaultActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reboot" => Ok(TripleFaultAction::Reboot),
+            "shutdown" => Ok(TripleFaultAction::Shutdown),
+            "pause" => Ok(TripleFaultAction::Pause),
+            _ => Err(ParseTripleFaultActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Step of the recovery run when the guest crashes.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum CrashAction {
+    /// Snapshot the VM to the given URL, pausing it first, so that its
+    /// memory and state can be inspected.
+    Snapshot(String),
+    /// Dump the guest memory to the given file, as an ELF core, pausing
+    /// the VM first.
+    Dump(PathBuf),
+    /// Report the crash to the event monitor.
+    Event,
+    /// Pause the VM, leaving it in its crashed state.
+    Pause,
+    /// Reboot the guest, as a reset would.
+    Reboot,
+    /// Shut the VMM down, as a power off would.
+    Shutdown,
+}
+
+impl From<TripleFaultAction> for CrashAction {
+    fn from(action: TripleFaultAction) -> Self {
+        match action {
+            TripleFaultAction::Reboot => CrashAction::Reboot,
+            TripleFaultAction::Shutdown => CrashAction::Shutdown,
+            TripleFaultAction::Pause => CrashAction::Pause,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseCrashActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for CrashAction {
+    type Err = ParseCrashActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(url) = s.strip_prefix("snapshot=") {
+            return Ok(CrashAction::Snapshot(url.to_owned()));
+        }
+        if let Some(file) = s.strip_prefix("dump=") {
+            return Ok(CrashAction::Dump(PathBuf::from(file)));
+        }
+
+        match s.to_lowercase().as_str() {
+            "event" => Ok(CrashAction::Event),
+            "pause" => Ok(CrashAction::Pause),
+            "reboot" => Ok(CrashAction::Reboot),
+            "shutdown" => Ok(CrashAction::Shutdown),
+            _ => Err(ParseCrashActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+impl CrashAction {
+    pub const SYNTAX: &'static str = "Actions run in order when the guest crashes, stopping at \
+        the first failing one, instead of the triple fault action \
+        \"<action>,<action>,...\" with the actions \"snapshot=<destination_url>|dump=<dump_file>|event|pause|reboot|shutdown\"";
+
+    pub fn parse_list(actions: &str) -> std::result::Result<Vec<Self>, ParseCrashActionError> {
+        actions.split(',').map(str::parse).collect()
+    }
+
+    /// Whether the VM is torn down, leaving nothing else to run.
+    pub fn is_final(&self) -> bool {
+        matches!(self, CrashAction::Reboot | CrashAction::Shutdown)
+    }
+
+    /// Runs the actions in order, stopping at the first failing one, and
+    /// returns how many completed.
+    pub fn run_chain<E: fmt::Debug>(
+        actions: &[Self],
+        mut run: impl FnMut(&Self) -> std::result::Result<(), E>,
+    ) -> usize {
+        for (completed, action) in actions.iter().enumerate() {
+            if let Err(e) = run(action) {
+                error!("Error running crash action {:?}: {:?}", action, e);
+                return completed;
+            }
+        }
+
+        actions.len()
+    }
+}
+
+pub const DEFAULT_NUM_PCI_SEGMENTS: u16 = 1;
+
+// Each segment gets its own ACPI PCI host bridge, named PCI0 to PCIF. As
+// the MSI device ID of a PCI device is its BDF, which doesn't carry the
+// segment, multiple segments are only supported on x86_64.
+#[cfg(target_arch = "x86_64")]
+pub const MAX_NUM_PCI_SEGMENTS: u16 = 16;
+#[cfg(target_arch = "aarch64")]
+pub const MAX_NUM_PCI_SEGMENTS: u16 = 1;
+
+fn default_platformconfig_num_pci_segments() -> u16 {
+    DEFAULT_NUM_PCI_SEGMENTS
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PlatformConfig {
+    #[serde(default = "default_platformconfig_num_pci_segments")]
+    pub num_pci_segments: u16,
+    /// Cap on the descriptor chains the virtio-block devices hold in flight
+    /// altogether, unlimited if not set.
+    #[serde(default)]
+    pub max_inflight_descriptors: Option<u64>,
+}
+
+impl Default for PlatformConfig {
+    fn default() -> Self {
+        PlatformConfig {
+            num_pci_segments: default_platformconfig_num_pci_segments(),
+            max_inflight_descriptors: None,
+        }
+    }
+}
+
+impl PlatformConfig {
+    pub const SYNTAX: &'static str = "Platform parameters \
+        \"num_pci_segments=<num_pci_segments>,\
+        max_inflight_descriptors=<max_descriptors_in_flight>\"";
+
+    pub fn parse(platform: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("num_pci_segments")
+            .add("max_inflight_descriptors");
+        parser.parse(platform).map_err(Error::ParsePlatform)?;
+
+        let num_pci_segments = parser
+            .convert("num_pci_segments")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or_else(default_platformconfig_num_pci_segments);
+        let max_inflight_descriptors = parser
+            .convert("max_inflight_descriptors")
+            .map_err(Error::ParsePlatform)?;
+
+        Ok(PlatformConfig {
+            num_pci_segments,
+            max_inflight_descriptors,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FsConfig {
+    pub tag: String,
+    pub socket: PathBuf,
+    #[serde(default = "default_fsconfig_num_queues")]
+    pub num_queues: usize,
+    #[serde(default = "default_fsconfig_queue_size")]
+    pub queue_size: u16,
+    #[serde(default = "default_fsconfig_dax")]
+    pub dax: bool,
+    #[serde(default = "default_fsconfig_cache_size")]
+    pub cache_size: u64,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub numa_node: Option<u32>,
+}
+
+fn default_fsconfig_num_queues() -> usize {
+    1
+}
+
+fn default_fsconfig_queue_size() -> u16 {
+    1024
+}
+
+fn default_fsconfig_dax() -> bool {
     true
 }
 
@@ -1040,6 +2997,8 @@ impl Default for FsConfig {
             dax: default_fsconfig_dax(),
             cache_size: default_fsconfig_cache_size(),
             id: None,
+            pci_segment: 0,
+            numa_node: None,
         }
     }
 }
@@ -1048,7 +3007,7 @@ impl FsConfig {
     pub const SYNTAX: &'static str = "virtio-fs parameters \
     \"tag=<tag_name>,socket=<socket_path>,num_queues=<number_of_queues>,\
     queue_size=<size_of_each_queue>,dax=on|off,cache_size=<DAX cache size: \
-    default 8Gib>,id=<device_id>\"";
+    default 8Gib>,id=<device_id>,pci_segment=<segment_id>,numa_node=<guest_numa_id>\"";
 
     pub fn parse(fs: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1059,7 +3018,9 @@ impl FsConfig {
             .add("queue_size")
             .add("num_queues")
             .add("socket")
-            .add("id");
+            .add("id")
+            .add("pci_segment")
+            .add("numa_node");
         parser.parse(fs).map_err(Error::ParseFileSystem)?;
 
         let tag = parser.get("tag").ok_or(Error::ParseFsTagMissing)?;
@@ -1091,6 +3052,13 @@ impl FsConfig {
             .0;
 
         let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseFileSystem)?
+            .unwrap_or_default();
+        let numa_node = parser
+            .convert("numa_node")
+            .map_err(Error::ParseFileSystem)?;
 
         Ok(FsConfig {
             tag,
@@ -1100,8 +3068,19 @@ impl FsConfig {
             dax,
             cache_size,
             id,
+            pci_segment,
+            numa_node,
         })
     }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        validate_queues(self.num_queues, self.queue_size)?;
+        if self.tag.is_empty() || self.tag.len() > virtio_devices::vhost_user::MAX_FS_TAG_LEN {
+            return Err(ValidationError::InvalidFsTag(self.tag.clone()));
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
@@ -1117,12 +3096,17 @@ pub struct PmemConfig {
     pub discard_writes: bool,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub numa_node: Option<u32>,
 }
 
 impl PmemConfig {
     pub const SYNTAX: &'static str = "Persistent memory parameters \
     \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off,\
-    mergeable=on|off,discard_writes=on|off,id=<device_id>\"";
+    mergeable=on|off,discard_writes=on|off,id=<device_id>,pci_segment=<segment_id>,\
+    numa_node=<guest_numa_id>\"";
     pub fn parse(pmem: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser
@@ -1131,7 +3115,9 @@ impl PmemConfig {
             .add("mergeable")
             .add("iommu")
             .add("discard_writes")
-            .add("id");
+            .add("id")
+            .add("pci_segment")
+            .add("numa_node");
         parser.parse(pmem).map_err(Error::ParsePersistentMemory)?;
 
         let file = PathBuf::from(parser.get("file").ok_or(Error::ParsePmemFileMissing)?);
@@ -1155,6 +3141,13 @@ impl PmemConfig {
             .unwrap_or(Toggle(false))
             .0;
         let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParsePersistentMemory)?
+            .unwrap_or_default();
+        let numa_node = parser
+            .convert("numa_node")
+            .map_err(Error::ParsePersistentMemory)?;
 
         Ok(PmemConfig {
             file,
@@ -1163,6 +3156,8 @@ impl PmemConfig {
             mergeable,
             discard_writes,
             id,
+            pci_segment,
+            numa_node,
         })
     }
 }
@@ -1174,11 +3169,15 @@ pub enum ConsoleOutputMode {
     Tty,
     File,
     Null,
+    Tcp,
 }
 
 impl ConsoleOutputMode {
     pub fn input_enabled(&self) -> bool {
-        matches!(self, ConsoleOutputMode::Tty | ConsoleOutputMode::Pty)
+        matches!(
+            self,
+            ConsoleOutputMode::Tty | ConsoleOutputMode::Pty | ConsoleOutputMode::Tcp
+        )
     }
 }
 
@@ -1189,6 +3188,9 @@ pub struct ConsoleConfig {
     pub mode: ConsoleOutputMode,
     #[serde(default)]
     pub iommu: bool,
+    /// Address the console is served on in tcp mode.
+    #[serde(default)]
+    pub socket: Option<SocketAddr>,
 }
 
 fn default_consoleconfig_file() -> Option<PathBuf> {
@@ -1204,10 +3206,12 @@ impl ConsoleConfig {
             .add_valueless("tty")
             .add_valueless("null")
             .add("file")
+            .add("tcp")
             .add("iommu");
         parser.parse(console).map_err(Error::ParseConsole)?;
 
         let mut file: Option<PathBuf> = default_consoleconfig_file();
+        let mut socket: Option<SocketAddr> = None;
         let mut mode: ConsoleOutputMode = ConsoleOutputMode::Off;
 
         if parser.is_set("off") {
@@ -1223,6 +3227,14 @@ impl ConsoleConfig {
                 Some(PathBuf::from(parser.get("file").ok_or(
                     Error::Validation(ValidationError::ConsoleFileMissing),
                 )?));
+        } else if parser.is_set("tcp") {
+            mode = ConsoleOutputMode::Tcp;
+            socket = Some(
+                parser
+                    .convert("tcp")
+                    .map_err(Error::ParseConsole)?
+                    .ok_or(Error::Validation(ValidationError::ConsoleSocketMissing))?,
+            );
         } else {
             return Err(Error::ParseConsoleInvalidModeGiven);
         }
@@ -1232,7 +3244,12 @@ impl ConsoleConfig {
             .unwrap_or(Toggle(false))
             .0;
 
-        Ok(Self { mode, file, iommu })
+        Ok(Self {
+            mode,
+            file,
+            iommu,
+            socket,
+        })
     }
 
     pub fn default_serial() -> Self {
@@ -1240,6 +3257,7 @@ impl ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Null,
             iommu: false,
+            socket: None,
         }
     }
 
@@ -1248,10 +3266,85 @@ impl ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Tty,
             iommu: false,
+            socket: None,
         }
     }
 }
 
+pub const DEFAULT_CRASH_DUMP_MAX_SIZE: u64 = 16 << 20;
+
+fn default_crashdumpconfig_max_size() -> u64 {
+    DEFAULT_CRASH_DUMP_MAX_SIZE
+}
+
+fn default_crashdumpconfig_rotate() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CrashDumpConfig {
+    pub file: PathBuf,
+    #[serde(default = "default_crashdumpconfig_max_size")]
+    pub max_size: u64,
+    // Keep the latest output rather than the earliest once full.
+    #[serde(default = "default_crashdumpconfig_rotate")]
+    pub rotate: bool,
+}
+
+impl CrashDumpConfig {
+    pub const SYNTAX: &'static str = "Crash dump parameters \
+        \"file=<crash_dump_file>,max_size=<crash_dump_file_size>,rotate=on|off\"";
+
+    pub fn parse(crash_dump: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("file").add("max_size").add("rotate");
+        parser.parse(crash_dump).map_err(Error::ParseCrashDump)?;
+
+        let file = parser
+            .get("file")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseCrashDumpFileMissing)?;
+        let max_size = parser
+            .convert::<ByteSized>("max_size")
+            .map_err(Error::ParseCrashDump)?
+            .map_or_else(default_crashdumpconfig_max_size, |v| v.0);
+        let rotate = parser
+            .convert::<Toggle>("rotate")
+            .map_err(Error::ParseCrashDump)?
+            .unwrap_or_else(|| Toggle(default_crashdumpconfig_rotate()))
+            .0;
+
+        Ok(CrashDumpConfig {
+            file,
+            max_size,
+            rotate,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TpmConfig {
+    /// Control socket of the swtpm emulator.
+    pub socket: PathBuf,
+}
+
+impl TpmConfig {
+    pub const SYNTAX: &'static str = "vTPM parameters \"socket=<swtpm_control_socket_path>\"";
+
+    pub fn parse(tpm: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("socket");
+        parser.parse(tpm).map_err(Error::ParseTpm)?;
+
+        let socket = parser
+            .get("socket")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseTpmSocketMissing)?;
+
+        Ok(TpmConfig { socket })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
 pub struct DeviceConfig {
     pub path: PathBuf,
@@ -1259,14 +3352,24 @@ pub struct DeviceConfig {
     pub iommu: bool,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub numa_node: Option<u32>,
 }
 
 impl DeviceConfig {
-    pub const SYNTAX: &'static str =
-        "Direct device assignment parameters \"path=<device_path>,iommu=on|off,id=<device_id>\"";
+    pub const SYNTAX: &'static str = "Direct device assignment parameters \
+        \"path=<device_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>,\
+        numa_node=<guest_numa_id>\"";
     pub fn parse(device: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("path").add("id").add("iommu");
+        parser
+            .add("path")
+            .add("id")
+            .add("iommu")
+            .add("pci_segment")
+            .add("numa_node");
         parser.parse(device).map_err(Error::ParseDevice)?;
 
         let path = parser
@@ -1279,62 +3382,339 @@ impl DeviceConfig {
             .unwrap_or(Toggle(false))
             .0;
         let id = parser.get("id");
-        Ok(DeviceConfig { path, iommu, id })
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseDevice)?
+            .unwrap_or_default();
+        let numa_node = parser.convert("numa_node").map_err(Error::ParseDevice)?;
+        Ok(DeviceConfig {
+            path,
+            iommu,
+            id,
+            pci_segment,
+            numa_node,
+        })
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
-pub struct VsockConfig {
-    pub cid: u64,
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct UserDeviceConfig {
     pub socket: PathBuf,
     #[serde(default)]
-    pub iommu: bool,
-    #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub numa_node: Option<u32>,
 }
 
-impl VsockConfig {
-    pub const SYNTAX: &'static str = "Virtio VSOCK parameters \
-        \"cid=<context_id>,socket=<socket_path>,iommu=on|off,id=<device_id>\"";
-    pub fn parse(vsock: &str) -> Result<Self> {
+impl UserDeviceConfig {
+    pub const SYNTAX: &'static str = "vfio-user device parameters \
+        \"socket=<socket_path>,id=<device_id>,pci_segment=<segment_id>,\
+        numa_node=<guest_numa_id>\"";
+    pub fn parse(user_device: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("socket").add("cid").add("iommu").add("id");
-        parser.parse(vsock).map_err(Error::ParseVsock)?;
+        parser
+            .add("socket")
+            .add("id")
+            .add("pci_segment")
+            .add("numa_node");
+        parser.parse(user_device).map_err(Error::ParseUserDevice)?;
 
         let socket = parser
             .get("socket")
             .map(PathBuf::from)
-            .ok_or(Error::ParseVsockSockMissing)?;
-        let iommu = parser
-            .convert::<Toggle>("iommu")
-            .map_err(Error::ParseVsock)?
-            .unwrap_or(Toggle(false))
-            .0;
-        let cid = parser
-            .convert("cid")
-            .map_err(Error::ParseVsock)?
-            .ok_or(Error::ParseVsockCidMissing)?;
+            .ok_or(Error::ParseUserDeviceSocketMissing)?;
         let id = parser.get("id");
-
-        Ok(VsockConfig {
-            cid,
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseUserDevice)?
+            .unwrap_or_default();
+        let numa_node = parser
+            .convert("numa_node")
+            .map_err(Error::ParseUserDevice)?;
+        Ok(UserDeviceConfig {
             socket,
-            iommu,
             id,
+            pci_segment,
+            numa_node,
         })
     }
 }
 
-#[cfg(target_arch = "x86_64")]
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
-pub struct SgxEpcConfig {
+pub struct VsockConfig {
+    pub cid: u64,
+    pub socket: PathBuf,
     #[serde(default)]
-    pub size: u64,
+    pub iommu: bool,
     #[serde(default)]
-    pub prefault: bool,
-}
-
-#[cfg(target_arch = "x86_64")]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub agent_port: Option<u32>,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub completion_port: Option<u32>,
+    #[serde(default)]
+    pub completion_action: CompletionAction,
+    #[serde(default)]
+    pub transport: VirtioTransportType,
+}
+
+/// What the VMM does when the guest closes a connection on the completion
+/// port of the vsock device.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum CompletionAction {
+    /// Shut the VMM down, as a power off would.
+    Shutdown,
+    /// Pause the VM, leaving it in its final state for inspection.
+    Pause,
+}
+
+impl Default for CompletionAction {
+    fn default() -> Self {
+        CompletionAction::Shutdown
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseCompletionActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for CompletionAction {
+    type Err = ParseCompletionActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "shutdown" => Ok(CompletionAction::Shutdown),
+            "pause" => Ok(CompletionAction::Pause),
+            _ => Err(ParseCompletionActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+impl VsockConfig {
+    pub const SYNTAX: &'static str = "Virtio VSOCK parameters \
+        \"cid=<context_id>,socket=<socket_path>,iommu=on|off,id=<device_id>,\
+        agent_port=<guest_agent_port>,pci_segment=<segment_id>,\
+        completion_port=<port>,completion_action=shutdown|pause,transport=pci|mmio\"";
+    pub fn parse(vsock: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("socket")
+            .add("cid")
+            .add("iommu")
+            .add("id")
+            .add("agent_port")
+            .add("pci_segment")
+            .add("completion_port")
+            .add("completion_action")
+            .add("transport");
+        parser.parse(vsock).map_err(Error::ParseVsock)?;
+
+        let socket = parser
+            .get("socket")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseVsockSockMissing)?;
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseVsock)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let cid = parser
+            .convert("cid")
+            .map_err(Error::ParseVsock)?
+            .ok_or(Error::ParseVsockCidMissing)?;
+        let id = parser.get("id");
+        let agent_port = parser.convert("agent_port").map_err(Error::ParseVsock)?;
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseVsock)?
+            .unwrap_or_default();
+        let completion_port = parser
+            .convert("completion_port")
+            .map_err(Error::ParseVsock)?;
+        let completion_action = parser
+            .convert("completion_action")
+            .map_err(Error::ParseVsock)?
+            .unwrap_or_default();
+        let transport = parser
+            .convert("transport")
+            .map_err(Error::ParseVsock)?
+            .unwrap_or_default();
+
+        Ok(VsockConfig {
+            cid,
+            socket,
+            iommu,
+            id,
+            agent_port,
+            pci_segment,
+            completion_port,
+            completion_action,
+            transport,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum InputKind {
+    Keyboard,
+    Mouse,
+    Tablet,
+    Evdev,
+}
+
+impl Default for InputKind {
+    fn default() -> Self {
+        InputKind::Keyboard
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseInputKindError {
+    InvalidValue(String),
+}
+
+impl FromStr for InputKind {
+    type Err = ParseInputKindError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keyboard" => Ok(InputKind::Keyboard),
+            "mouse" => Ok(InputKind::Mouse),
+            "tablet" => Ok(InputKind::Tablet),
+            "evdev" => Ok(InputKind::Evdev),
+            _ => Err(ParseInputKindError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct InputConfig {
+    #[serde(default)]
+    pub kind: InputKind,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+impl InputConfig {
+    pub const SYNTAX: &'static str = "Virtio input parameters \
+        \"kind=keyboard|mouse|tablet|evdev,path=<evdev_device_path>,id=<device_id>,\
+        pci_segment=<segment_id>\"";
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("kind").add("path").add("id").add("pci_segment");
+        parser.parse(input).map_err(Error::ParseInput)?;
+
+        let path = parser.get("path").map(PathBuf::from);
+        // A host device path on its own means the device is passed through.
+        let kind = parser
+            .convert("kind")
+            .map_err(Error::ParseInput)?
+            .unwrap_or_else(|| {
+                if path.is_some() {
+                    InputKind::Evdev
+                } else {
+                    InputKind::Keyboard
+                }
+            });
+        let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseInput)?
+            .unwrap_or_default();
+
+        Ok(InputConfig {
+            kind,
+            path,
+            id,
+            pci_segment,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        match (&self.kind, &self.path) {
+            (InputKind::Evdev, None) => Err(ValidationError::InputPathMissing),
+            (InputKind::Evdev, Some(_)) | (_, None) => Ok(()),
+            (_, Some(path)) => Err(ValidationError::InputPathUnexpected(path.clone())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct GpioConfig {
+    #[serde(default = "default_gpioconfig_num_lines")]
+    pub num_lines: u16,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+fn default_gpioconfig_num_lines() -> u16 {
+    DEFAULT_GPIO_LINES
+}
+
+impl Default for GpioConfig {
+    fn default() -> Self {
+        GpioConfig {
+            num_lines: default_gpioconfig_num_lines(),
+            id: None,
+            pci_segment: 0,
+        }
+    }
+}
+
+impl GpioConfig {
+    pub const SYNTAX: &'static str = "Virtio GPIO parameters \
+        \"lines=<number_of_lines>,id=<device_id>,pci_segment=<segment_id>\"";
+    pub fn parse(gpio: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("lines").add("id").add("pci_segment");
+        parser.parse(gpio).map_err(Error::ParseGpio)?;
+
+        let num_lines = parser
+            .convert("lines")
+            .map_err(Error::ParseGpio)?
+            .unwrap_or_else(default_gpioconfig_num_lines);
+        let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseGpio)?
+            .unwrap_or_default();
+
+        Ok(GpioConfig {
+            num_lines,
+            id,
+            pci_segment,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.num_lines == 0 || self.num_lines > virtio_devices::MAX_GPIO_LINES {
+            return Err(ValidationError::InvalidGpioLines(self.num_lines));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct SgxEpcConfig {
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub prefault: bool,
+}
+
+#[cfg(target_arch = "x86_64")]
 impl SgxEpcConfig {
     pub const SYNTAX: &'static str = "SGX EPC parameters \
         \"size=<epc_section_size>,prefault=on|off\"";
@@ -1358,6 +3738,68 @@ impl SgxEpcConfig {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct SevConfig {
+    /// Guest policy enforced by the SEV firmware, e.g. forbidding debugging.
+    #[serde(default)]
+    pub policy: u32,
+    /// Diffie-Hellman certificate of the guest owner, establishing the
+    /// launch session along with the session parameters.
+    #[serde(default)]
+    pub dh_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub session: Option<PathBuf>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SevConfig {
+    pub const SYNTAX: &'static str = "AMD SEV parameters \
+        \"policy=<guest_policy>,dh_cert=<guest_owner_dh_cert_file>,\
+        session=<launch_session_file>\"";
+    pub fn parse(sev: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("policy").add("dh_cert").add("session");
+        parser.parse(sev).map_err(Error::ParseSev)?;
+
+        let policy = parser
+            .convert::<HexU32>("policy")
+            .map_err(Error::ParseSev)?
+            .map_or(0, |v| v.0);
+        let dh_cert = parser.get("dh_cert").map(PathBuf::from);
+        let session = parser.get("session").map(PathBuf::from);
+
+        Ok(SevConfig {
+            policy,
+            dh_cert,
+            session,
+        })
+    }
+}
+
+/// Raw ACPI table provided by the user, exposed to the guest along with the
+/// ones the VMM generates.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct AcpiTableConfig {
+    pub path: PathBuf,
+}
+
+impl AcpiTableConfig {
+    pub const SYNTAX: &'static str = "ACPI table parameters \"path=<table_file>\"";
+    pub fn parse(acpi_table: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("path");
+        parser.parse(acpi_table).map_err(Error::ParseAcpiTable)?;
+
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseAcpiTablePathMissing)?;
+
+        Ok(AcpiTableConfig { path })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
 pub struct NumaDistance {
     #[serde(default)]
@@ -1474,98 +3916,683 @@ pub struct VmConfig {
     #[serde(default)]
     pub rng: RngConfig,
     pub balloon: Option<BalloonConfig>,
+    #[serde(default)]
+    pub cgroup: Option<CgroupConfig>,
+    #[serde(default)]
+    pub oom_policy: Option<OomPolicyConfig>,
+    #[serde(default)]
+    pub on_triple_fault: TripleFaultAction,
+    #[serde(default)]
+    pub on_crash: Option<Vec<CrashAction>>,
+    #[serde(default)]
+    pub platform: Option<PlatformConfig>,
     pub fs: Option<Vec<FsConfig>>,
     pub pmem: Option<Vec<PmemConfig>>,
     #[serde(default = "ConsoleConfig::default_serial")]
     pub serial: ConsoleConfig,
     #[serde(default = "ConsoleConfig::default_console")]
     pub console: ConsoleConfig,
+    #[serde(default)]
+    pub crash_dump: Option<CrashDumpConfig>,
     pub devices: Option<Vec<DeviceConfig>>,
+    pub user_devices: Option<Vec<UserDeviceConfig>>,
     pub vsock: Option<VsockConfig>,
+    pub input: Option<Vec<InputConfig>>,
+    pub gpio: Option<Vec<GpioConfig>>,
     #[serde(default)]
     pub iommu: bool,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<SgxEpcConfig>>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub sev: Option<SevConfig>,
     pub numa: Option<Vec<NumaConfig>>,
     #[serde(default)]
+    pub acpi_tables: Option<Vec<AcpiTableConfig>>,
+    #[serde(default)]
     pub watchdog: bool,
+    #[serde(default)]
+    pub battery: bool,
+    #[serde(default)]
+    pub tpm: Option<TpmConfig>,
+    /// Identifiers of the devices whose register accesses are logged.
+    #[serde(default)]
+    pub access_log: Option<Vec<String>>,
 }
 
 impl VmConfig {
+    /// Checks the configuration is consistent, before any resource is
+    /// allocated for the VM.
+    ///
+    /// All the devices are checked in a single pass, and every problem found
+    /// is reported: a single error is returned as is, several errors come
+    /// back as `ValidationError::Multiple`.
     pub fn validate(&self) -> ValidationResult<()> {
-        self.kernel.as_ref().ok_or(ValidationError::KernelMissing)?;
+        let mut errors = Vec::new();
+        let mut check = |result: ValidationResult<()>| {
+            if let Err(e) = result {
+                errors.push(e);
+            }
+        };
 
-        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
-        {
-            return Err(ValidationError::DoubleTtyMode);
+        check(self.validate_payload());
+        check(self.validate_consoles());
+
+        if self.cpus.max_vcpus < self.cpus.boot_vcpus {
+            check(Err(ValidationError::CpusMaxLowerThanBoot));
         }
 
-        if self.console.mode == ConsoleOutputMode::File && self.console.file.is_none() {
-            return Err(ValidationError::ConsoleFileMissing);
+        #[cfg(target_arch = "aarch64")]
+        if self.cpus.nested == Some(true) {
+            check(Err(ValidationError::NestedUnsupported));
         }
 
-        if self.serial.mode == ConsoleOutputMode::File && self.serial.file.is_none() {
-            return Err(ValidationError::ConsoleFileMissing);
+        #[cfg(target_arch = "aarch64")]
+        if self.cpus.tsc_deadline == Some(true) {
+            check(Err(ValidationError::TscDeadlineUnsupported));
         }
 
-        if self.cpus.max_vcpus < self.cpus.boot_vcpus {
-            return Err(ValidationError::CpusMaxLowerThanBoot);
+        #[cfg(target_arch = "aarch64")]
+        if !self.cpus.cpuid.is_empty() {
+            check(Err(ValidationError::CpuidUnsupported));
         }
 
-        if let Some(disks) = &self.disks {
-            for disk in disks {
-                if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
-                    return Err(ValidationError::DiskSocketAndPath);
-                }
-                if disk.vhost_user && !self.memory.shared {
-                    return Err(ValidationError::VhostUserRequiresSharedMemory);
-                }
-                if disk.vhost_user && disk.vhost_socket.is_none() {
-                    return Err(ValidationError::VhostUserMissingSocket);
-                }
+        #[cfg(target_arch = "aarch64")]
+        if self.cpus.caches.is_some() {
+            check(Err(ValidationError::CacheTopologyUnsupported));
+        }
+
+        #[cfg(not(feature = "acpi"))]
+        if self.battery {
+            check(Err(ValidationError::BatteryRequiresAcpi));
+        }
+
+        #[cfg(not(feature = "acpi"))]
+        if self.acpi_tables.is_some() {
+            check(Err(ValidationError::AcpiTablesRequireAcpi));
+        }
+
+        #[cfg(not(feature = "acpi"))]
+        if self.tpm.is_some() {
+            check(Err(ValidationError::TpmRequiresAcpi));
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        if self.tpm.is_some() {
+            check(Err(ValidationError::TpmUnsupported));
+        }
+
+        #[cfg(not(feature = "access_log"))]
+        if self.access_log.is_some() {
+            check(Err(ValidationError::AccessLogUnsupported));
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if self.sev.is_some() {
+            #[cfg(not(feature = "kvm"))]
+            check(Err(ValidationError::SevRequiresKvm));
+
+            // Only the memory present at boot is registered as encrypted.
+            if self.memory.hotplug_size.is_some()
+                || self
+                    .memory
+                    .zones
+                    .iter()
+                    .flatten()
+                    .any(|z| z.hotplug_size.is_some())
+            {
+                check(Err(ValidationError::SevMemoryHotplug));
             }
         }
 
-        if let Some(nets) = &self.net {
-            for net in nets {
-                if net.vhost_user && !self.memory.shared {
-                    return Err(ValidationError::VhostUserRequiresSharedMemory);
+        let mut boot_indices = BTreeSet::new();
+        for disk in self.disks.iter().flatten() {
+            if let Some(boot_index) = disk.boot_index {
+                if !boot_indices.insert(boot_index) {
+                    check(Err(ValidationError::DuplicateBootIndex(boot_index)));
                 }
             }
+            check(self.validate_disk(disk));
+        }
+
+        for net in self.net.iter().flatten() {
+            check(net.validate());
+            if net.vhost_user && !self.memory.is_shared() {
+                check(Err(ValidationError::VhostUserRequiresSharedMemory));
+            }
+            check(validate_queues(net.num_queues, net.queue_size));
+            check(validate_pci_identity(
+                net.pci_subsystem_vendor_id,
+                net.pci_serial.as_ref(),
+            ));
         }
 
         if let Some(fses) = &self.fs {
-            if !fses.is_empty() && !self.memory.shared {
-                return Err(ValidationError::VhostUserRequiresSharedMemory);
+            if !fses.is_empty() && !self.memory.is_shared() {
+                check(Err(ValidationError::VhostUserRequiresSharedMemory));
+            }
+            // Each share is a device of its own, which the guest mounts
+            // by its tag.
+            let mut tags = BTreeSet::new();
+            for fs in fses {
+                check(fs.validate());
+                if !tags.insert(&fs.tag) {
+                    check(Err(ValidationError::DuplicateFsTag(fs.tag.clone())));
+                }
             }
         }
 
-        if let Some(t) = &self.cpus.topology {
-            if t.threads_per_core == 0
-                || t.cores_per_die == 0
-                || t.dies_per_package == 0
-                || t.packages == 0
+        if let Some(user_devices) = &self.user_devices {
+            if !user_devices.is_empty() && !self.memory.is_shared() {
+                check(Err(ValidationError::UserDeviceRequiresSharedMemory));
+            }
+        }
+
+        for input in self.input.iter().flatten() {
+            check(input.validate());
+        }
+
+        for gpio in self.gpio.iter().flatten() {
+            check(gpio.validate());
+        }
+
+        check(validate_mmio_transport(
+            self.rng.transport,
+            &[("iommu", self.rng.iommu)],
+        ));
+        if let Some(vsock) = &self.vsock {
+            check(validate_mmio_transport(
+                vsock.transport,
+                &[
+                    ("iommu", vsock.iommu),
+                    ("pci_segment", vsock.pci_segment != 0),
+                ],
+            ));
+        }
+
+        check(self.validate_cpu_topology());
+        check(self.validate_cpuid());
+        check(self.validate_hugepages());
+
+        if self.memory.hotplug_slots == 0 || self.memory.hotplug_slots > MAX_MEMORY_HOTPLUG_SLOTS {
+            check(Err(ValidationError::InvalidHotplugSlots(
+                self.memory.hotplug_slots,
+            )));
+        }
+
+        if let Some(hotplug_base) = self.memory.hotplug_base {
+            if self.memory.hotplug_size.is_none()
+                && !self
+                    .memory
+                    .zones
+                    .iter()
+                    .flatten()
+                    .any(|z| z.hotplug_size.is_some())
             {
-                return Err(ValidationError::CpuTopologyZeroPart);
+                check(Err(ValidationError::HotplugBaseWithoutHotplugSize));
             }
+            if hotplug_base % MEMORY_HOTPLUG_ALIGNMENT != 0 {
+                check(Err(ValidationError::InvalidHotplugBase(hotplug_base)));
+            }
+        }
 
-            let total = t.threads_per_core * t.cores_per_die * t.dies_per_package * t.packages;
-            if total != self.cpus.max_vcpus {
-                return Err(ValidationError::CpuTopologyCount);
+        if let Some(crash_dump) = &self.crash_dump {
+            if self.console.mode == ConsoleOutputMode::Off {
+                check(Err(ValidationError::CrashDumpRequiresConsole));
+            }
+            if crash_dump.max_size == 0 {
+                check(Err(ValidationError::CrashDumpEmpty));
             }
         }
 
-        if let Some(hugepage_size) = &self.memory.hugepage_size {
-            if !self.memory.hugepages {
-                return Err(ValidationError::HugePageSizeWithoutHugePages);
+        if let Some(actions) = &self.on_crash {
+            if actions.is_empty() {
+                check(Err(ValidationError::CrashActionsEmpty));
             }
-            if !hugepage_size.is_power_of_two() {
-                return Err(ValidationError::InvalidHugePageSize(*hugepage_size));
+            if actions.iter().rev().skip(1).any(CrashAction::is_final) {
+                check(Err(ValidationError::CrashActionNotLast));
             }
         }
 
-        Ok(())
-    }
+        if let Some(oom_policy) = &self.oom_policy {
+            if oom_policy.action == OomAction::Balloon && self.balloon.is_none() {
+                check(Err(ValidationError::OomPolicyBalloonMissing));
+            }
+            if oom_policy.interval == 0 {
+                check(Err(ValidationError::OomPolicyZeroInterval));
+            }
+        }
+
+        check(self.validate_device_ids());
+        check(self.validate_host_devices());
+        check(self.validate_pci_segments());
+        check(self.validate_numa_nodes());
+        if self.max_inflight_descriptors() == Some(0) {
+            check(Err(ValidationError::InvalidMaxInflightDescriptors));
+        }
+
+        ValidationError::from_list(errors)
+    }
+
+    /// Checks the host provides what the configuration relies on: the
+    /// sockets of the vhost-user and vfio-user backends must be listening.
+    /// Boot memory larger than the host memory is only warned about, as the
+    /// guest may never touch all of it, or the host may rely on swap.
+    ///
+    /// This is kept apart from `validate()` as the backends can legitimately
+    /// be started between the creation of the VM and its boot.
+    pub fn validate_host(&self) -> ValidationResult<()> {
+        let mut errors = Vec::new();
+
+        let mut sockets = Vec::new();
+        for disk in self.disks.iter().flatten() {
+            if let (true, Some(socket)) = (disk.vhost_user, &disk.vhost_socket) {
+                sockets.push(PathBuf::from(socket));
+            }
+        }
+        for net in self.net.iter().flatten() {
+            if let (true, Some(socket)) = (net.vhost_user, &net.vhost_socket) {
+                sockets.push(PathBuf::from(socket));
+            }
+        }
+        sockets.extend(self.fs.iter().flatten().map(|fs| fs.socket.clone()));
+        sockets.extend(
+            self.user_devices
+                .iter()
+                .flatten()
+                .map(|user_device| user_device.socket.clone()),
+        );
+        for socket in sockets {
+            if !socket.exists() {
+                errors.push(ValidationError::BackendSocketMissing(socket));
+            }
+        }
+
+        // Safe since sysconf() doesn't touch any memory.
+        let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if pages > 0 && page_size > 0 {
+            let host_memory = pages as u64 * page_size as u64;
+            let boot_memory = self.memory.size
+                + self
+                    .memory
+                    .zones
+                    .iter()
+                    .flatten()
+                    .map(|zone| zone.size)
+                    .sum::<u64>();
+            if boot_memory > host_memory {
+                warn!(
+                    "Boot memory of {} bytes exceeds the {} bytes of host memory",
+                    boot_memory, host_memory
+                );
+            }
+        }
+
+        ValidationError::from_list(errors)
+    }
+
+    fn validate_payload(&self) -> ValidationResult<()> {
+        self.kernel.as_ref().ok_or(ValidationError::KernelMissing)?;
+
+        if let Some(initramfs) = &self.initramfs {
+            match (initramfs.path.as_os_str().is_empty(), &initramfs.data) {
+                (true, None) => return Err(ValidationError::InitramfsMissing),
+                (false, Some(_)) => return Err(ValidationError::InitramfsPathAndData),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_consoles(&self) -> ValidationResult<()> {
+        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
+        {
+            return Err(ValidationError::DoubleTtyMode);
+        }
+
+        if self.console.mode == ConsoleOutputMode::File && self.console.file.is_none() {
+            return Err(ValidationError::ConsoleFileMissing);
+        }
+
+        if self.serial.mode == ConsoleOutputMode::File && self.serial.file.is_none() {
+            return Err(ValidationError::ConsoleFileMissing);
+        }
+
+        if (self.console.mode == ConsoleOutputMode::Tcp && self.console.socket.is_none())
+            || (self.serial.mode == ConsoleOutputMode::Tcp && self.serial.socket.is_none())
+        {
+            return Err(ValidationError::ConsoleSocketMissing);
+        }
+
+        Ok(())
+    }
+
+    fn validate_disk(&self, disk: &DiskConfig) -> ValidationResult<()> {
+        if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
+            return Err(ValidationError::DiskSocketAndPath);
+        }
+        if disk.vhost_user && !self.memory.is_shared() {
+            return Err(ValidationError::VhostUserRequiresSharedMemory);
+        }
+        if disk.vhost_user && disk.vhost_socket.is_none() {
+            return Err(ValidationError::VhostUserMissingSocket);
+        }
+        if disk.num_queues < 1 {
+            return Err(ValidationError::VblkQueueLowerThan1);
+        }
+        // The guest driver maps queues to the vCPUs it can have, there's
+        // nothing to gain from more queues than that. The queues of a
+        // vhost-user backend are up to the backend.
+        if !disk.vhost_user && disk.num_queues > self.cpus.max_vcpus as usize {
+            warn!(
+                "Disk {:?} has {} queues, more than the {} vCPUs of the guest",
+                disk.path, disk.num_queues, self.cpus.max_vcpus
+            );
+        }
+        validate_queues(disk.num_queues, disk.queue_size)?;
+        validate_pci_identity(disk.pci_subsystem_vendor_id, disk.pci_serial.as_ref())?;
+        validate_activate_timeout(disk.activate_timeout, disk.vhost_user)?;
+        if disk.nvme {
+            validate_nvme_disk(disk)?;
+        }
+        // Like any hotplugged device, they can't be attached to the
+        // virtio-iommu once the guest is running.
+        if disk.cold && disk.iommu {
+            return Err(ValidationError::ColdDeviceIommu);
+        }
+        if disk.readahead_cache > 0 {
+            if disk.vhost_user {
+                return Err(ValidationError::VhostUserReadAhead);
+            }
+            if disk.readahead_window == 0 || disk.readahead_window > disk.readahead_cache {
+                return Err(ValidationError::InvalidReadAheadWindow(
+                    disk.readahead_window,
+                ));
+            }
+        }
+        if disk.overlay.is_some() {
+            if disk.vhost_user {
+                return Err(ValidationError::VhostUserOverlay);
+            }
+            if disk.readonly {
+                return Err(ValidationError::ReadOnlyOverlay);
+            }
+            if disk.direct {
+                return Err(ValidationError::DirectOverlay);
+            }
+        }
+        validate_notification_suppression(
+            disk.notify_threshold,
+            disk.notify_max_latency_us,
+            disk.queue_size,
+            disk.vhost_user,
+        )?;
+        if disk.vhost_user && (disk.num_workers.is_some() || disk.queue_weights.is_some()) {
+            return Err(ValidationError::VhostUserQueueScheduling);
+        }
+        if disk.vhost_user && disk.io_retries > 0 {
+            return Err(ValidationError::VhostUserIoRetries);
+        }
+        if disk.queue_depth == Some(0) {
+            return Err(ValidationError::InvalidDiskQueueDepth);
+        }
+        if disk.vhost_user && disk.queue_depth.is_some() {
+            return Err(ValidationError::VhostUserQueueDepth);
+        }
+        if let Some(io_priority) = disk.io_priority {
+            if disk.vhost_user {
+                return Err(ValidationError::VhostUserIoPriority);
+            }
+            if io_priority.level > IoPriority::MAX_LEVEL
+                || (io_priority.class == IoPriorityClass::Idle && io_priority.level != 0)
+            {
+                return Err(ValidationError::InvalidIoPriorityLevel(io_priority.level));
+            }
+        }
+        validate_mmio_transport(
+            disk.transport,
+            &[
+                ("nvme", disk.nvme),
+                ("vhost_user", disk.vhost_user),
+                ("iommu", disk.iommu),
+                ("pci_segment", disk.pci_segment != 0),
+                ("numa_node", disk.numa_node.is_some()),
+                (
+                    "pci_subsystem_vendor_id",
+                    disk.pci_subsystem_vendor_id.is_some(),
+                ),
+                ("pci_subsystem_id", disk.pci_subsystem_id.is_some()),
+                ("pci_serial", disk.pci_serial.is_some()),
+                ("cold", disk.cold),
+            ],
+        )?;
+        if let Some(num_workers) = disk.num_workers {
+            if num_workers == 0 || num_workers > disk.num_queues {
+                return Err(ValidationError::InvalidNumDiskWorkers(num_workers));
+            }
+        }
+        if let Some(queue_weights) = &disk.queue_weights {
+            if queue_weights.len() != disk.num_queues
+                || queue_weights
+                    .iter()
+                    .any(|w| !(1..=virtio_devices::MAX_QUEUE_WEIGHT).contains(w))
+            {
+                return Err(ValidationError::InvalidQueueWeights(queue_weights.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_cpu_topology(&self) -> ValidationResult<()> {
+        if let Some(t) = &self.cpus.topology {
+            if t.threads_per_core == 0
+                || t.cores_per_die == 0
+                || t.dies_per_package == 0
+                || t.packages == 0
+            {
+                return Err(ValidationError::CpuTopologyZeroPart);
+            }
+
+            let total = t.threads_per_core * t.cores_per_die * t.dies_per_package * t.packages;
+            if total != self.cpus.max_vcpus {
+                return Err(ValidationError::CpuTopologyCount);
+            }
+        }
+
+        if let Some(caches) = &self.cpus.caches {
+            if self.cpus.topology.is_none() {
+                return Err(ValidationError::CacheTopologyRequiresTopology);
+            }
+            if caches.l1 > caches.l2 || caches.l2 > caches.l3 {
+                return Err(ValidationError::CacheTopologyInconsistent);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_cpuid(&self) -> ValidationResult<()> {
+        let mut leaves = BTreeSet::new();
+        for leaf in self.cpus.cpuid.iter() {
+            if leaf.action == CpuidLeafAction::Zero
+                && MANDATORY_CPUID_LEAVES.contains(&leaf.function)
+            {
+                return Err(ValidationError::CpuidMandatoryLeafZeroed(leaf.function));
+            }
+
+            if !leaves.insert((leaf.function, leaf.index)) {
+                return Err(ValidationError::CpuidLeafDuplicate(
+                    leaf.function,
+                    leaf.index,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_hugepages(&self) -> ValidationResult<()> {
+        if let Some(hugepage_size) = &self.memory.hugepage_size {
+            if !self.memory.hugepages {
+                return Err(ValidationError::HugePageSizeWithoutHugePages);
+            }
+            if !hugepage_size.is_power_of_two() {
+                return Err(ValidationError::InvalidHugePageSize(*hugepage_size));
+            }
+        }
+
+        Ok(())
+    }
+
+    // The device identifiers name the nodes of the device tree, they must
+    // not collide.
+    fn validate_device_ids(&self) -> ValidationResult<()> {
+        let mut ids = BTreeSet::new();
+        let all_ids = self
+            .disks
+            .iter()
+            .flatten()
+            .map(|d| &d.id)
+            .chain(self.net.iter().flatten().map(|n| &n.id))
+            .chain(self.fs.iter().flatten().map(|f| &f.id))
+            .chain(self.pmem.iter().flatten().map(|p| &p.id))
+            .chain(self.devices.iter().flatten().map(|d| &d.id))
+            .chain(self.user_devices.iter().flatten().map(|u| &u.id))
+            .chain(self.vsock.iter().map(|v| &v.id))
+            .chain(self.input.iter().flatten().map(|i| &i.id))
+            .chain(self.gpio.iter().flatten().map(|g| &g.id))
+            .flatten();
+        for id in all_ids {
+            if !ids.insert(id) {
+                return Err(ValidationError::DuplicateDeviceId(id.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // A host PCI device can only be assigned once.
+    fn validate_host_devices(&self) -> ValidationResult<()> {
+        let mut paths = BTreeSet::new();
+        for device in self.devices.iter().flatten() {
+            let path = device
+                .path
+                .canonicalize()
+                .unwrap_or_else(|_| device.path.clone());
+            if !paths.insert(path) {
+                return Err(ValidationError::DuplicateHostDevice(device.path.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn num_pci_segments(&self) -> u16 {
+        self.platform
+            .as_ref()
+            .map_or(DEFAULT_NUM_PCI_SEGMENTS, |p| p.num_pci_segments)
+    }
+
+    pub fn max_inflight_descriptors(&self) -> Option<u64> {
+        self.platform
+            .as_ref()
+            .and_then(|p| p.max_inflight_descriptors)
+    }
+
+    /// Actions run when the guest triple faults, the crash actions taking
+    /// over the triple fault action when given.
+    pub fn triple_fault_actions(&self) -> Vec<CrashAction> {
+        self.on_crash
+            .clone()
+            .unwrap_or_else(|| vec![self.on_triple_fault.into()])
+    }
+
+    fn validate_pci_segments(&self) -> ValidationResult<()> {
+        let num_pci_segments = self.num_pci_segments();
+        if num_pci_segments == 0 || num_pci_segments > MAX_NUM_PCI_SEGMENTS {
+            return Err(ValidationError::InvalidNumPciSegments(num_pci_segments));
+        }
+
+        let validate = |pci_segment: u16, iommu: bool| {
+            if pci_segment >= num_pci_segments {
+                return Err(ValidationError::InvalidPciSegment(pci_segment));
+            }
+            // The virtio-iommu device only manages the first segment.
+            if iommu && pci_segment != 0 {
+                return Err(ValidationError::IommuNotSupportedOnSegment(pci_segment));
+            }
+            Ok(())
+        };
+
+        for disk in self.disks.iter().flatten() {
+            validate(disk.pci_segment, disk.iommu)?;
+        }
+        for net in self.net.iter().flatten() {
+            validate(net.pci_segment, net.iommu)?;
+        }
+        for fs in self.fs.iter().flatten() {
+            validate(fs.pci_segment, false)?;
+        }
+        for pmem in self.pmem.iter().flatten() {
+            validate(pmem.pci_segment, pmem.iommu)?;
+        }
+        for device in self.devices.iter().flatten() {
+            validate(device.pci_segment, device.iommu)?;
+        }
+        for user_device in self.user_devices.iter().flatten() {
+            validate(user_device.pci_segment, false)?;
+        }
+        if let Some(vsock) = &self.vsock {
+            validate(vsock.pci_segment, vsock.iommu)?;
+        }
+        for input in self.input.iter().flatten() {
+            validate(input.pci_segment, false)?;
+        }
+        for gpio in self.gpio.iter().flatten() {
+            validate(gpio.pci_segment, false)?;
+        }
+
+        Ok(())
+    }
+
+    fn validate_numa_nodes(&self) -> ValidationResult<()> {
+        let validate = |numa_node: Option<u32>| match numa_node {
+            Some(numa_node)
+                if !self
+                    .numa
+                    .iter()
+                    .flatten()
+                    .any(|n| n.guest_numa_id == numa_node) =>
+            {
+                Err(ValidationError::InvalidNumaNode(numa_node))
+            }
+            _ => Ok(()),
+        };
+
+        for disk in self.disks.iter().flatten() {
+            validate(disk.numa_node)?;
+        }
+        for net in self.net.iter().flatten() {
+            validate(net.numa_node)?;
+        }
+        for fs in self.fs.iter().flatten() {
+            validate(fs.numa_node)?;
+        }
+        for pmem in self.pmem.iter().flatten() {
+            validate(pmem.numa_node)?;
+        }
+        for device in self.devices.iter().flatten() {
+            validate(device.numa_node)?;
+        }
+        for user_device in self.user_devices.iter().flatten() {
+            validate(user_device.numa_node)?;
+        }
+
+        Ok(())
+    }
 
     pub fn parse(vm_params: VmParams) -> Result<Self> {
         let mut iommu = false;
@@ -1606,6 +4633,32 @@ impl VmConfig {
             balloon = Some(BalloonConfig::parse(balloon_params)?);
         }
 
+        let mut cgroup: Option<CgroupConfig> = None;
+        if let Some(cgroup_params) = &vm_params.cgroup {
+            cgroup = Some(CgroupConfig::parse(cgroup_params)?);
+        }
+
+        let mut oom_policy: Option<OomPolicyConfig> = None;
+        if let Some(oom_policy_params) = &vm_params.oom_policy {
+            oom_policy = Some(OomPolicyConfig::parse(oom_policy_params)?);
+        }
+
+        let on_triple_fault = vm_params
+            .on_triple_fault
+            .parse()
+            .map_err(Error::ParseOnTripleFault)?;
+
+        let on_crash = vm_params
+            .on_crash
+            .map(CrashAction::parse_list)
+            .transpose()
+            .map_err(Error::ParseOnCrash)?;
+
+        let mut platform: Option<PlatformConfig> = None;
+        if let Some(platform_params) = &vm_params.platform {
+            platform = Some(PlatformConfig::parse(platform_params)?);
+        }
+
         let mut fs: Option<Vec<FsConfig>> = None;
         if let Some(fs_list) = &vm_params.fs {
             let mut fs_config_list = Vec::new();
@@ -1634,6 +4687,16 @@ impl VmConfig {
         }
         let serial = ConsoleConfig::parse(vm_params.serial)?;
 
+        let mut crash_dump: Option<CrashDumpConfig> = None;
+        if let Some(crash_dump_params) = &vm_params.crash_dump {
+            crash_dump = Some(CrashDumpConfig::parse(crash_dump_params)?);
+        }
+
+        let mut tpm: Option<TpmConfig> = None;
+        if let Some(tpm_params) = &vm_params.tpm {
+            tpm = Some(TpmConfig::parse(tpm_params)?);
+        }
+
         let mut devices: Option<Vec<DeviceConfig>> = None;
         if let Some(device_list) = &vm_params.devices {
             let mut device_config_list = Vec::new();
@@ -1647,6 +4710,16 @@ impl VmConfig {
             devices = Some(device_config_list);
         }
 
+        let mut user_devices: Option<Vec<UserDeviceConfig>> = None;
+        if let Some(user_device_list) = &vm_params.user_devices {
+            let mut user_device_config_list = Vec::new();
+            for item in user_device_list.iter() {
+                let user_device_config = UserDeviceConfig::parse(item)?;
+                user_device_config_list.push(user_device_config);
+            }
+            user_devices = Some(user_device_config_list);
+        }
+
         let mut vsock: Option<VsockConfig> = None;
         if let Some(vs) = &vm_params.vsock {
             let vsock_config = VsockConfig::parse(vs)?;
@@ -1656,6 +4729,26 @@ impl VmConfig {
             vsock = Some(vsock_config);
         }
 
+        let mut input: Option<Vec<InputConfig>> = None;
+        if let Some(input_list) = &vm_params.input {
+            let mut input_config_list = Vec::new();
+            for item in input_list.iter() {
+                let input_config = InputConfig::parse(item)?;
+                input_config_list.push(input_config);
+            }
+            input = Some(input_config_list);
+        }
+
+        let mut gpio: Option<Vec<GpioConfig>> = None;
+        if let Some(gpio_list) = &vm_params.gpio {
+            let mut gpio_config_list = Vec::new();
+            for item in gpio_list.iter() {
+                let gpio_config = GpioConfig::parse(item)?;
+                gpio_config_list.push(gpio_config);
+            }
+            gpio = Some(gpio_config_list);
+        }
+
         #[cfg(target_arch = "x86_64")]
         let mut sgx_epc: Option<Vec<SgxEpcConfig>> = None;
         #[cfg(target_arch = "x86_64")]
@@ -1670,6 +4763,13 @@ impl VmConfig {
             }
         }
 
+        #[cfg(target_arch = "x86_64")]
+        let mut sev: Option<SevConfig> = None;
+        #[cfg(target_arch = "x86_64")]
+        if let Some(sev_params) = &vm_params.sev {
+            sev = Some(SevConfig::parse(sev_params)?);
+        }
+
         let mut numa: Option<Vec<NumaConfig>> = None;
         if let Some(numa_list) = &vm_params.numa {
             let mut numa_config_list = Vec::new();
@@ -1680,6 +4780,15 @@ impl VmConfig {
             numa = Some(numa_config_list);
         }
 
+        let mut acpi_tables: Option<Vec<AcpiTableConfig>> = None;
+        if let Some(acpi_table_list) = &vm_params.acpi_tables {
+            let mut acpi_table_config_list = Vec::new();
+            for item in acpi_table_list.iter() {
+                acpi_table_config_list.push(AcpiTableConfig::parse(item)?);
+            }
+            acpi_tables = Some(acpi_table_config_list);
+        }
+
         let mut kernel: Option<KernelConfig> = None;
         if let Some(k) = vm_params.kernel {
             kernel = Some(KernelConfig {
@@ -1691,6 +4800,7 @@ impl VmConfig {
         if let Some(k) = vm_params.initramfs {
             initramfs = Some(InitramfsConfig {
                 path: PathBuf::from(k),
+                ..Default::default()
             });
         }
 
@@ -1704,17 +4814,35 @@ impl VmConfig {
             net,
             rng,
             balloon,
+            cgroup,
+            oom_policy,
+            on_triple_fault,
+            on_crash,
+            platform,
             fs,
             pmem,
             serial,
             console,
+            crash_dump,
             devices,
+            user_devices,
             vsock,
+            input,
+            gpio,
             iommu,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
+            #[cfg(target_arch = "x86_64")]
+            sev,
             numa,
+            acpi_tables,
             watchdog: vm_params.watchdog,
+            battery: vm_params.battery,
+            tpm,
+            access_log: vm_params
+                .access_log
+                .as_ref()
+                .map(|ids| ids.iter().map(|id| (*id).to_owned()).collect()),
         };
         config.validate().map_err(Error::Validation)?;
         Ok(config)
@@ -1781,6 +4909,27 @@ mod tests {
 
         assert!(CpusConfig::parse("boot=8,topology=2:2:1").is_err());
         assert!(CpusConfig::parse("boot=8,topology=2:2:1:x").is_err());
+        assert_eq!(
+            CpusConfig::parse("boot=8,topology=2:2:2:1,caches=thread:core:die")?,
+            CpusConfig {
+                boot_vcpus: 8,
+                max_vcpus: 8,
+                topology: Some(CpuTopology {
+                    threads_per_core: 2,
+                    cores_per_die: 2,
+                    dies_per_package: 2,
+                    packages: 1
+                }),
+                caches: Some(CacheTopology {
+                    l1: CacheSharing::Thread,
+                    l2: CacheSharing::Core,
+                    l3: CacheSharing::Die,
+                }),
+                ..Default::default()
+            }
+        );
+        assert!(CpusConfig::parse("boot=8,caches=core:core").is_err());
+        assert!(CpusConfig::parse("boot=8,caches=core:core:socket").is_err());
         assert_eq!(
             CpusConfig::parse("boot=1,kvm_hyperv=on")?,
             CpusConfig {
@@ -1790,6 +4939,57 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            CpusConfig::parse("boot=1,nested=off")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                nested: Some(false),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=1,tsc_deadline=on")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                tsc_deadline: Some(true),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=1,cpuid=0x40000000@zero:0x7.1@host:0x6@0x4/0/0x1/0")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                cpuid: vec![
+                    CpuidLeafConfig {
+                        function: 0x4000_0000,
+                        index: 0,
+                        action: CpuidLeafAction::Zero,
+                    },
+                    CpuidLeafConfig {
+                        function: 0x7,
+                        index: 1,
+                        action: CpuidLeafAction::Host,
+                    },
+                    CpuidLeafConfig {
+                        function: 0x6,
+                        index: 0,
+                        action: CpuidLeafAction::Values {
+                            eax: 0x4,
+                            ebx: 0,
+                            ecx: 0x1,
+                            edx: 0,
+                        },
+                    },
+                ],
+                ..Default::default()
+            }
+        );
+        assert!(CpusConfig::parse("cpuid=0x6@0x4/0/0").is_err());
+        assert!(CpusConfig::parse("cpuid=0x6.0.1@host").is_err());
+        assert!(CpusConfig::parse("cpuid=0x6@all").is_err());
         Ok(())
     }
 
@@ -1837,6 +5037,22 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            MemoryConfig::parse("hotplug_size=4G,hotplug_base=16G", None)?,
+            MemoryConfig {
+                hotplug_size: Some(4 << 30),
+                hotplug_base: Some(16 << 30),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("hotplug_size=4G,hotplug_slots=16", None)?,
+            MemoryConfig {
+                hotplug_size: Some(4 << 30),
+                hotplug_slots: 16,
+                ..Default::default()
+            }
+        );
         assert_eq!(
             MemoryConfig::parse("hotplug_method=virtio-mem,hotplug_size=512M", None)?,
             MemoryConfig {
@@ -1854,6 +5070,22 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,prefault=on", None)?,
+            MemoryConfig {
+                size: 1 << 30,
+                prefault: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,locked=on", None)?,
+            MemoryConfig {
+                size: 1 << 30,
+                locked: true,
+                ..Default::default()
+            }
+        );
         Ok(())
     }
 
@@ -1875,9 +5107,17 @@ mod tests {
             }
         );
         assert_eq!(
-            DiskConfig::parse("vhost_user=true,socket=/tmp/sock")?,
+            DiskConfig::parse("path=/path/to_file,boot_index=1")?,
             DiskConfig {
-                vhost_socket: Some(String::from("/tmp/sock")),
+                path: Some(PathBuf::from("/path/to_file")),
+                boot_index: Some(1),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("vhost_user=true,socket=/tmp/sock")?,
+            DiskConfig {
+                vhost_socket: Some(String::from("/tmp/sock")),
                 vhost_user: true,
                 ..Default::default()
             }
@@ -1947,6 +5187,141 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,readahead_cache=16M")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                readahead_cache: 16 << 20,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,readahead_cache=16M,readahead_window=1M")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                readahead_cache: 16 << 20,
+                readahead_window: 1 << 20,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,overlay=/path/to_overlay")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                overlay: Some(PathBuf::from("/path/to_overlay")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,notify_threshold=8,notify_max_latency_us=500")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                notify_threshold: 8,
+                notify_max_latency_us: 500,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,io_retries=3,io_retry_backoff=50")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                io_retries: 3,
+                io_retry_backoff_ms: 50,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,queue_depth=64")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                queue_depth: Some(64),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,io_priority=realtime:1")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                io_priority: Some(IoPriority {
+                    class: IoPriorityClass::Realtime,
+                    level: 1,
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,io_priority=best-effort")?
+                .io_priority
+                .map(|p| p.ioprio()),
+            Some((2 << 13) | 4)
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,io_priority=idle")?
+                .io_priority
+                .map(|p| p.ioprio()),
+            Some(3 << 13)
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,io_priority=high").is_err());
+        assert!(DiskConfig::parse("path=/path/to_file,io_priority=idle:x").is_err());
+        // The API defaults the level as the command line does.
+        let io_priority = |json| serde_json::from_str::<IoPriority>(json).unwrap().ioprio();
+        assert_eq!(io_priority(r#"{"class":"BestEffort"}"#), (2 << 13) | 4);
+        assert_eq!(io_priority(r#"{"class":"Idle"}"#), 3 << 13);
+        assert_eq!(
+            io_priority(r#"{"class":"Realtime","level":1}"#),
+            (1 << 13) | 1
+        );
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,num_queues=4,num_workers=2,queue_weights=4:1:1:1"
+            )?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                num_queues: 4,
+                num_workers: Some(2),
+                queue_weights: Some(vec![4, 1, 1, 1]),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,pci_subsystem_vendor_id=0x1af4,pci_subsystem_id=2,\
+                 pci_serial=DISK-0001"
+            )?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                pci_subsystem_vendor_id: Some(0x1af4),
+                pci_subsystem_id: Some(2),
+                pci_serial: Some("DISK-0001".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,pci_subsystem_id=0x10000").is_err());
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,nvme=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                nvme: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,cold=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                cold: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,transport=mmio")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                transport: VirtioTransportType::Mmio,
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,transport=ccw").is_err());
 
         Ok(())
     }
@@ -2022,6 +5397,197 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,tap=tap0,vhost_kernel=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                tap: Some("tap0".to_owned()),
+                vhost_kernel: true,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,pci_subsystem_id=0xbeef,pci_serial=NIC 42")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                pci_subsystem_id: Some(0xbeef),
+                pci_serial: Some("NIC 42".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        // vhost-user requires a socket
+        assert!(NetConfig::parse("mac=de:ad:be:ef:12:34,vhost_user=true").is_err());
+        // The same goes for a hotplugged device, only validated on its own.
+        assert!(matches!(
+            NetConfig {
+                vhost_user: true,
+                ..Default::default()
+            }
+            .validate(),
+            Err(ValidationError::VhostUserMissingSocket)
+        ));
+        assert!(NetConfig {
+            vhost_user: true,
+            vhost_socket: Some("/tmp/sock".to_owned()),
+            ..Default::default()
+        }
+        .validate()
+        .is_ok());
+        // vhost-kernel is exclusive with vhost-user and the IOMMU
+        assert!(NetConfig::parse("vhost_user=true,socket=/tmp/sock,vhost_kernel=on").is_err());
+        assert!(NetConfig::parse("vhost_kernel=on,iommu=on").is_err());
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,vhost_user=true,socket=/tmp/sock,activate_timeout=2000"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                vhost_user: true,
+                vhost_socket: Some("/tmp/sock".to_owned()),
+                activate_timeout: Some(2000),
+                ..Default::default()
+            }
+        );
+        // the activation timeout only applies to vhost-user backends
+        assert!(matches!(
+            NetConfig::parse("tap=tap0,activate_timeout=2000"),
+            Err(Error::Validation(
+                ValidationError::ActivateTimeoutRequiresVhostUser
+            ))
+        ));
+        assert!(matches!(
+            NetConfig::parse("vhost_user=true,socket=/tmp/sock,activate_timeout=0"),
+            Err(Error::Validation(ValidationError::InvalidActivateTimeout))
+        ));
+
+        assert_eq!(
+            NetConfig::parse("vhost_user=true,socket=/tmp/sock,connect_timeout=5000")?,
+            NetConfig {
+                vhost_user: true,
+                vhost_socket: Some("/tmp/sock".to_owned()),
+                connect_timeout: Some(5000),
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            NetConfig::parse("tap=tap0,connect_timeout=5000"),
+            Err(Error::Validation(
+                ValidationError::ConnectTimeoutRequiresVhostUser
+            ))
+        ));
+        assert!(matches!(
+            NetConfig::parse("vhost_user=true,socket=/tmp/sock,connect_timeout=0"),
+            Err(Error::Validation(ValidationError::InvalidConnectTimeout))
+        ));
+        assert!(NetConfig::parse("vhost_user=true,socket=/tmp/sock,connect_timeout=10000").is_ok());
+        assert!(matches!(
+            NetConfig::parse("vhost_user=true,socket=/tmp/sock,connect_timeout=10001"),
+            Err(Error::Validation(ValidationError::InvalidConnectTimeout))
+        ));
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,cold=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                cold: true,
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            NetConfig::parse("tap=tap0,iommu=on,cold=on"),
+            Err(Error::Validation(ValidationError::ColdDeviceIommu))
+        ));
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,vhost_user=true,socket=/tmp/sock,host_tso=on,guest_ufo=off"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                vhost_user: true,
+                vhost_socket: Some("/tmp/sock".to_owned()),
+                guest_ufo: false,
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            NetConfig::parse("tap=tap0,host_csum=off"),
+            Err(Error::Validation(ValidationError::OffloadsRequireVhostUser))
+        ));
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,tap=tap0,anti_spoof=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                tap: Some("tap0".to_owned()),
+                anti_spoof: true,
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            NetConfig::parse("tap=tap0,vhost_kernel=on,anti_spoof=on"),
+            Err(Error::Validation(ValidationError::VhostKernelAntiSpoof))
+        ));
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,tap=tap0,busy_poll=50")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                tap: Some("tap0".to_owned()),
+                busy_poll: Some(50),
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            NetConfig::parse("tap=tap0,vhost_kernel=on,busy_poll=50"),
+            Err(Error::Validation(ValidationError::VhostKernelBusyPoll))
+        ));
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,tap=tap0,trusted_local=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                tap: Some("tap0".to_owned()),
+                trusted_local: true,
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            NetConfig::parse("vhost_user=true,socket=/tmp/sock,trusted_local=on"),
+            Err(Error::Validation(ValidationError::TrustedLocalUnsupported))
+        ));
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,tap=tap0,notify_threshold=16,notify_max_latency_us=200"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                tap: Some("tap0".to_owned()),
+                notify_threshold: 16,
+                notify_max_latency_us: 200,
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            NetConfig::parse("tap=tap0,notify_threshold=16"),
+            Err(Error::Validation(
+                ValidationError::InvalidNotificationSuppression(16, 0)
+            ))
+        ));
+        assert!(matches!(
+            NetConfig::parse(
+                "tap=tap0,vhost_kernel=on,notify_threshold=16,notify_max_latency_us=200"
+            ),
+            Err(Error::Validation(
+                ValidationError::NotificationSuppressionUnsupported
+            ))
+        ));
+
         Ok(())
     }
 
@@ -2040,6 +5606,10 @@ mod tests {
             RngConfig {
                 src: PathBuf::from("/dev/random"),
                 iommu: true,
+                transport: VirtioTransportType::Pci,
+                seed: None,
+                seed_mode: RngSeedMode::Replay,
+                quality: false,
             }
         );
         assert_eq!(
@@ -2049,6 +5619,29 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            RngConfig::parse("transport=mmio")?,
+            RngConfig {
+                transport: VirtioTransportType::Mmio,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RngConfig::parse("seed=/tmp/seed,seed_mode=consume")?,
+            RngConfig {
+                seed: Some(PathBuf::from("/tmp/seed")),
+                seed_mode: RngSeedMode::Consume,
+                ..Default::default()
+            }
+        );
+        assert!(RngConfig::parse("seed=/tmp/seed,seed_mode=record").is_err());
+        assert_eq!(
+            RngConfig::parse("quality=on")?,
+            RngConfig {
+                quality: true,
+                ..Default::default()
+            }
+        );
         Ok(())
     }
 
@@ -2143,6 +5736,15 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M,numa_node=1")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                size: Some(128 << 20),
+                numa_node: Some(1),
+                ..Default::default()
+            }
+        );
 
         Ok(())
     }
@@ -2157,6 +5759,7 @@ mod tests {
                 mode: ConsoleOutputMode::Off,
                 iommu: false,
                 file: None,
+                socket: None,
             }
         );
         assert_eq!(
@@ -2165,6 +5768,7 @@ mod tests {
                 mode: ConsoleOutputMode::Pty,
                 iommu: false,
                 file: None,
+                socket: None,
             }
         );
         assert_eq!(
@@ -2173,6 +5777,7 @@ mod tests {
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
                 file: None,
+                socket: None,
             }
         );
         assert_eq!(
@@ -2181,6 +5786,7 @@ mod tests {
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
                 file: None,
+                socket: None,
             }
         );
         assert_eq!(
@@ -2188,7 +5794,8 @@ mod tests {
             ConsoleConfig {
                 mode: ConsoleOutputMode::File,
                 iommu: false,
-                file: Some(PathBuf::from("/tmp/console"))
+                file: Some(PathBuf::from("/tmp/console")),
+                socket: None,
             }
         );
         assert_eq!(
@@ -2197,6 +5804,7 @@ mod tests {
                 mode: ConsoleOutputMode::Null,
                 iommu: true,
                 file: None,
+                socket: None,
             }
         );
         assert_eq!(
@@ -2204,9 +5812,20 @@ mod tests {
             ConsoleConfig {
                 mode: ConsoleOutputMode::File,
                 iommu: true,
-                file: Some(PathBuf::from("/tmp/console"))
+                file: Some(PathBuf::from("/tmp/console")),
+                socket: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("tcp=127.0.0.1:4444")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Tcp,
+                iommu: false,
+                file: None,
+                socket: Some("127.0.0.1:4444".parse().unwrap()),
             }
         );
+        assert!(ConsoleConfig::parse("tcp=127.0.0.1").is_err());
         Ok(())
     }
 
@@ -2219,7 +5838,9 @@ mod tests {
             DeviceConfig {
                 path: PathBuf::from("/path/to/device"),
                 id: None,
-                iommu: false
+                iommu: false,
+                pci_segment: 0,
+                numa_node: None,
             }
         );
 
@@ -2228,7 +5849,9 @@ mod tests {
             DeviceConfig {
                 path: PathBuf::from("/path/to/device"),
                 id: None,
-                iommu: true
+                iommu: true,
+                pci_segment: 0,
+                numa_node: None,
             }
         );
 
@@ -2237,7 +5860,58 @@ mod tests {
             DeviceConfig {
                 path: PathBuf::from("/path/to/device"),
                 id: Some("mydevice0".to_owned()),
-                iommu: true
+                iommu: true,
+                pci_segment: 0,
+                numa_node: None,
+            }
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,pci_segment=2")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: false,
+                pci_segment: 2,
+                numa_node: None,
+            }
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,numa_node=1")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: false,
+                pci_segment: 0,
+                numa_node: Some(1),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_device_parsing() -> Result<()> {
+        // User device must have a socket provided
+        assert!(UserDeviceConfig::parse("").is_err());
+        assert_eq!(
+            UserDeviceConfig::parse("socket=/path/to/socket")?,
+            UserDeviceConfig {
+                socket: PathBuf::from("/path/to/socket"),
+                id: None,
+                pci_segment: 0,
+                numa_node: None,
+            }
+        );
+
+        assert_eq!(
+            UserDeviceConfig::parse("socket=/path/to/socket,id=myuserdevice0")?,
+            UserDeviceConfig {
+                socket: PathBuf::from("/path/to/socket"),
+                id: Some("myuserdevice0".to_owned()),
+                pci_segment: 0,
+                numa_node: None,
             }
         );
 
@@ -2255,6 +5929,11 @@ mod tests {
                 socket: PathBuf::from("/tmp/sock"),
                 iommu: false,
                 id: None,
+                agent_port: None,
+                pci_segment: 0,
+                completion_port: None,
+                completion_action: CompletionAction::Shutdown,
+                transport: VirtioTransportType::Pci,
             }
         );
         assert_eq!(
@@ -2264,17 +5943,317 @@ mod tests {
                 socket: PathBuf::from("/tmp/sock"),
                 iommu: true,
                 id: None,
+                agent_port: None,
+                pci_segment: 0,
+                completion_port: None,
+                completion_action: CompletionAction::Shutdown,
+                transport: VirtioTransportType::Pci,
             }
         );
-        Ok(())
-    }
-
-    #[test]
-    fn test_config_validation() {
-        let valid_config = VmConfig {
-            cpus: CpusConfig {
-                boot_vcpus: 1,
-                max_vcpus: 1,
+        assert_eq!(
+            VsockConfig::parse("socket=/tmp/sock,cid=1,agent_port=1024")?,
+            VsockConfig {
+                cid: 1,
+                socket: PathBuf::from("/tmp/sock"),
+                iommu: false,
+                id: None,
+                agent_port: Some(1024),
+                pci_segment: 0,
+                completion_port: None,
+                completion_action: CompletionAction::Shutdown,
+                transport: VirtioTransportType::Pci,
+            }
+        );
+        assert_eq!(
+            VsockConfig::parse(
+                "socket=/tmp/sock,cid=1,completion_port=1025,completion_action=pause"
+            )?,
+            VsockConfig {
+                cid: 1,
+                socket: PathBuf::from("/tmp/sock"),
+                completion_port: Some(1025),
+                completion_action: CompletionAction::Pause,
+                ..Default::default()
+            }
+        );
+        assert!(VsockConfig::parse("socket=/tmp/sock,cid=1,completion_action=reboot").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_parsing() -> Result<()> {
+        assert_eq!(InputConfig::parse("")?, InputConfig::default());
+        assert_eq!(
+            InputConfig::parse("kind=tablet,id=tablet0")?,
+            InputConfig {
+                kind: InputKind::Tablet,
+                id: Some("tablet0".to_owned()),
+                ..Default::default()
+            }
+        );
+        // A path alone selects the evdev passthrough.
+        assert_eq!(
+            InputConfig::parse("path=/dev/input/event0")?,
+            InputConfig {
+                kind: InputKind::Evdev,
+                path: Some(PathBuf::from("/dev/input/event0")),
+                ..Default::default()
+            }
+        );
+        assert!(InputConfig::parse("kind=joystick").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_balloon_parsing() -> Result<()> {
+        assert_eq!(
+            BalloonConfig::parse("size=1G")?,
+            BalloonConfig {
+                size: 1 << 30,
+                free_page_hinting: false,
+            }
+        );
+        assert_eq!(
+            BalloonConfig::parse("size=0,free_page_hinting=on")?,
+            BalloonConfig {
+                size: 0,
+                free_page_hinting: true,
+            }
+        );
+        assert!(BalloonConfig::parse("free_page_hinting=maybe").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gpio_parsing() -> Result<()> {
+        assert_eq!(GpioConfig::parse("")?, GpioConfig::default());
+        assert_eq!(
+            GpioConfig::parse("lines=32,id=gpio0")?,
+            GpioConfig {
+                num_lines: 32,
+                id: Some("gpio0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert!(GpioConfig::parse("lines=on").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_cgroup() -> Result<()> {
+        assert!(CgroupConfig::parse("").is_err());
+        assert!(CgroupConfig::parse("cpu_quota=50000").is_err());
+        assert_eq!(
+            CgroupConfig::parse("path=/sys/fs/cgroup/ch")?,
+            CgroupConfig {
+                path: PathBuf::from("/sys/fs/cgroup/ch"),
+                cpu_quota: None,
+                cpu_period: DEFAULT_CGROUP_CPU_PERIOD,
+                memory_max: None,
+            }
+        );
+        assert_eq!(
+            CgroupConfig::parse(
+                "path=/sys/fs/cgroup/ch,cpu_quota=50000,cpu_period=200000,memory_max=1G"
+            )?,
+            CgroupConfig {
+                path: PathBuf::from("/sys/fs/cgroup/ch"),
+                cpu_quota: Some(50000),
+                cpu_period: 200_000,
+                memory_max: Some(1 << 30),
+            }
+        );
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_parse_sev() -> Result<()> {
+        assert_eq!(SevConfig::parse("")?, SevConfig::default());
+        assert_eq!(
+            SevConfig::parse("policy=0x5,dh_cert=/tmp/godh.cert,session=/tmp/launch.session")?,
+            SevConfig {
+                policy: 0x5,
+                dh_cert: Some(PathBuf::from("/tmp/godh.cert")),
+                session: Some(PathBuf::from("/tmp/launch.session")),
+            }
+        );
+        assert!(SevConfig::parse("policy=0xg").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_acpi_table() -> Result<()> {
+        assert!(AcpiTableConfig::parse("").is_err());
+        assert_eq!(
+            AcpiTableConfig::parse("path=/tmp/ssdt.aml")?,
+            AcpiTableConfig {
+                path: PathBuf::from("/tmp/ssdt.aml"),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_oom_policy() -> Result<()> {
+        assert!(OomPolicyConfig::parse("").is_err());
+        assert!(OomPolicyConfig::parse("action=pause").is_err());
+        assert!(OomPolicyConfig::parse("rss_limit=1G,action=kill").is_err());
+        assert_eq!(
+            OomPolicyConfig::parse("rss_limit=1G")?,
+            OomPolicyConfig {
+                rss_limit: 1 << 30,
+                action: OomAction::Balloon,
+                balloon_step: DEFAULT_OOM_POLICY_BALLOON_STEP,
+                interval: DEFAULT_OOM_POLICY_INTERVAL,
+            }
+        );
+        assert_eq!(
+            OomPolicyConfig::parse("rss_limit=2G,action=pause,balloon_step=64M,interval=500")?,
+            OomPolicyConfig {
+                rss_limit: 2 << 30,
+                action: OomAction::Pause,
+                balloon_step: 64 << 20,
+                interval: 500,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_triple_fault_action() {
+        assert_eq!(
+            "shutdown".parse::<TripleFaultAction>().unwrap(),
+            TripleFaultAction::Shutdown
+        );
+        assert_eq!(
+            "Pause".parse::<TripleFaultAction>().unwrap(),
+            TripleFaultAction::Pause
+        );
+        assert!("halt".parse::<TripleFaultAction>().is_err());
+        assert_eq!(TripleFaultAction::default(), TripleFaultAction::Reboot);
+    }
+
+    #[test]
+    fn test_parse_crash_actions() {
+        assert_eq!(
+            CrashAction::parse_list("snapshot=file:///tmp/crash,reboot").unwrap(),
+            vec![
+                CrashAction::Snapshot("file:///tmp/crash".to_owned()),
+                CrashAction::Reboot
+            ]
+        );
+        assert_eq!(
+            CrashAction::parse_list("Pause").unwrap(),
+            vec![CrashAction::Pause]
+        );
+        assert_eq!(
+            CrashAction::parse_list("dump=/tmp/crash.core,event,shutdown").unwrap(),
+            vec![
+                CrashAction::Dump(PathBuf::from("/tmp/crash.core")),
+                CrashAction::Event,
+                CrashAction::Shutdown
+            ]
+        );
+        assert!(CrashAction::parse_list("pause,halt").is_err());
+        assert!(CrashAction::parse_list("").is_err());
+    }
+
+    #[test]
+    fn test_run_crash_actions() {
+        let actions = vec![
+            CrashAction::Dump(PathBuf::from("/tmp/crash.core")),
+            CrashAction::Event,
+            CrashAction::Reboot,
+        ];
+
+        let mut run = Vec::new();
+        let completed = CrashAction::run_chain(&actions, |action| {
+            run.push(action.clone());
+            Ok::<(), ()>(())
+        });
+        assert_eq!(completed, 3);
+        assert_eq!(run, actions);
+
+        // A failing action skips the following ones.
+        let mut run = Vec::new();
+        let completed = CrashAction::run_chain(&actions, |action| {
+            run.push(action.clone());
+            match action {
+                CrashAction::Event => Err(()),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(completed, 1);
+        assert_eq!(run, actions[..2].to_vec());
+
+        assert_eq!(CrashAction::run_chain(&[], |_| Err(())), 0);
+    }
+
+    #[test]
+    fn test_parse_platform() -> Result<()> {
+        assert_eq!(PlatformConfig::parse("")?, PlatformConfig::default());
+        assert_eq!(
+            PlatformConfig::parse("num_pci_segments=4")?,
+            PlatformConfig {
+                num_pci_segments: 4,
+                max_inflight_descriptors: None,
+            }
+        );
+        assert_eq!(
+            PlatformConfig::parse("max_inflight_descriptors=4096")?,
+            PlatformConfig {
+                num_pci_segments: 1,
+                max_inflight_descriptors: Some(4096),
+            }
+        );
+        assert!(PlatformConfig::parse("num_pci_segments=foo").is_err());
+        assert!(PlatformConfig::parse("num_pci_segment=4").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_crash_dump() -> Result<()> {
+        assert!(CrashDumpConfig::parse("").is_err());
+        assert!(CrashDumpConfig::parse("max_size=1M").is_err());
+        assert_eq!(
+            CrashDumpConfig::parse("file=/tmp/crash")?,
+            CrashDumpConfig {
+                file: PathBuf::from("/tmp/crash"),
+                max_size: DEFAULT_CRASH_DUMP_MAX_SIZE,
+                rotate: true,
+            }
+        );
+        assert_eq!(
+            CrashDumpConfig::parse("file=/tmp/crash,max_size=1M,rotate=off")?,
+            CrashDumpConfig {
+                file: PathBuf::from("/tmp/crash"),
+                max_size: 1 << 20,
+                rotate: false,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_tpm() -> Result<()> {
+        assert!(TpmConfig::parse("").is_err());
+        assert!(TpmConfig::parse("path=/tmp/swtpm.sock").is_err());
+        assert_eq!(
+            TpmConfig::parse("socket=/tmp/swtpm.sock")?,
+            TpmConfig {
+                socket: PathBuf::from("/tmp/swtpm.sock"),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let valid_config = VmConfig {
+            cpus: CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
                 ..Default::default()
             },
             memory: MemoryConfig {
@@ -2282,10 +6261,14 @@ mod tests {
                 mergeable: false,
                 hotplug_method: HotplugMethod::Acpi,
                 hotplug_size: None,
+                hotplug_base: None,
                 hotplugged_size: None,
+                hotplug_slots: 8,
                 shared: false,
                 hugepages: false,
                 hugepage_size: None,
+                prefault: false,
+                locked: false,
                 zones: None,
             },
             kernel: Some(KernelConfig {
@@ -2300,27 +6283,48 @@ mod tests {
             rng: RngConfig {
                 src: PathBuf::from("/dev/urandom"),
                 iommu: false,
+                transport: VirtioTransportType::Pci,
+                seed: None,
+                seed_mode: RngSeedMode::Replay,
+                quality: false,
             },
             balloon: None,
+            cgroup: None,
+            oom_policy: None,
+            on_triple_fault: TripleFaultAction::Reboot,
+            on_crash: None,
+            platform: None,
             fs: None,
             pmem: None,
             serial: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
+                socket: None,
             },
             console: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
+                socket: None,
             },
+            crash_dump: None,
             devices: None,
+            user_devices: None,
             vsock: None,
+            input: None,
+            gpio: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
+            #[cfg(target_arch = "x86_64")]
+            sev: None,
             numa: None,
+            acpi_tables: None,
             watchdog: false,
+            battery: false,
+            tpm: None,
+            access_log: None,
         };
 
         assert!(valid_config.validate().is_ok());
@@ -2334,11 +6338,43 @@ mod tests {
         invalid_config.kernel = None;
         assert!(invalid_config.validate().is_err());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.initramfs = Some(InitramfsConfig::default());
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.initramfs = Some(InitramfsConfig {
+            path: PathBuf::from("/path/to/initramfs"),
+            data: Some(Arc::new(vec![0x1f, 0x8b])),
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.initramfs = Some(InitramfsConfig {
+            data: Some(Arc::new(vec![0x1f, 0x8b])),
+            ..Default::default()
+        });
+        assert!(still_valid_config.validate().is_ok());
+        // The content of the initramfs is never serialized.
+        let initramfs: InitramfsConfig = serde_json::from_str(
+            &serde_json::to_string(still_valid_config.initramfs.as_ref().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(initramfs, InitramfsConfig::default());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.serial.mode = ConsoleOutputMode::File;
         invalid_config.serial.file = None;
         assert!(invalid_config.validate().is_err());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::Tcp;
+        invalid_config.serial.socket = None;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::ConsoleSocketMissing)
+        ));
+
         let mut invalid_config = valid_config.clone();
         invalid_config.cpus.max_vcpus = 16;
         invalid_config.cpus.boot_vcpus = 32;
@@ -2355,6 +6391,71 @@ mod tests {
         });
         assert!(invalid_config.validate().is_err());
 
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.cpus.max_vcpus = 4;
+            still_valid_config.cpus.boot_vcpus = 4;
+            still_valid_config.cpus.topology = Some(CpuTopology {
+                threads_per_core: 2,
+                cores_per_die: 2,
+                dies_per_package: 1,
+                packages: 1,
+            });
+            still_valid_config.cpus.caches = Some(CacheTopology {
+                l1: CacheSharing::Thread,
+                ..Default::default()
+            });
+            assert!(still_valid_config.validate().is_ok());
+
+            // The L2 cache can't be per thread when the L1 is per core.
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.cpus.caches = Some(CacheTopology {
+                l2: CacheSharing::Thread,
+                ..Default::default()
+            });
+            assert!(matches!(
+                invalid_config.validate(),
+                Err(ValidationError::CacheTopologyInconsistent)
+            ));
+
+            let mut invalid_config = still_valid_config;
+            invalid_config.cpus.topology = None;
+            assert!(matches!(
+                invalid_config.validate(),
+                Err(ValidationError::CacheTopologyRequiresTopology)
+            ));
+
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.cpus.cpuid = vec![
+                CpuidLeafConfig {
+                    function: 0x1,
+                    index: 0,
+                    action: CpuidLeafAction::Host,
+                },
+                CpuidLeafConfig {
+                    function: 0x4000_0000,
+                    index: 0,
+                    action: CpuidLeafAction::Zero,
+                },
+            ];
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.cpus.cpuid[0].action = CpuidLeafAction::Zero;
+            assert!(matches!(
+                invalid_config.validate(),
+                Err(ValidationError::CpuidMandatoryLeafZeroed(0x1))
+            ));
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.cpus.cpuid[1].function = 0x1;
+            assert!(matches!(
+                invalid_config.validate(),
+                Err(ValidationError::CpuidLeafDuplicate(0x1, 0))
+            ));
+        }
+
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
             vhost_socket: Some("/path/to/sock".to_owned()),
@@ -2387,6 +6488,260 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            num_queues: 0,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        // More queues than vCPUs is only worth a warning, the guest sizing
+        // its queues by the vCPUs it can have once hotplugged.
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            num_queues: 4,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+        still_valid_config.cpus.boot_vcpus = 2;
+        still_valid_config.cpus.max_vcpus = 8;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to/image0")),
+                boot_index: Some(0),
+                ..Default::default()
+            },
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to/image1")),
+                boot_index: Some(0),
+                ..Default::default()
+            },
+        ]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.disks.as_mut().unwrap()[1].boot_index = Some(1);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            readahead_cache: 64 << 10,
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidReadAheadWindow(_))
+        ));
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.disks.as_mut().unwrap()[0].readahead_window = 64 << 10;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            overlay: Some(PathBuf::from("/path/to/overlay")),
+            readonly: true,
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::ReadOnlyOverlay)
+        ));
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.disks.as_mut().unwrap()[0].readonly = false;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            notify_threshold: 8,
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidNotificationSuppression(8, 0))
+        ));
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.disks.as_mut().unwrap()[0].notify_max_latency_us = 500;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.boot_vcpus = 2;
+        invalid_config.cpus.max_vcpus = 2;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            num_queues: 2,
+            num_workers: Some(3),
+            queue_weights: Some(vec![2, 0]),
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidNumDiskWorkers(3))
+        ));
+
+        invalid_config.disks.as_mut().unwrap()[0].num_workers = Some(1);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidQueueWeights(_))
+        ));
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.disks.as_mut().unwrap()[0].queue_weights = Some(vec![2, 1]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            queue_depth: Some(0),
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidDiskQueueDepth)
+        ));
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.disks.as_mut().unwrap()[0].queue_depth = Some(32);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            io_priority: Some(IoPriority {
+                class: IoPriorityClass::Idle,
+                level: 2,
+            }),
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidIoPriorityLevel(2))
+        ));
+
+        invalid_config.disks.as_mut().unwrap()[0].io_priority = Some(IoPriority {
+            class: IoPriorityClass::BestEffort,
+            level: 8,
+        });
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidIoPriorityLevel(8))
+        ));
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.disks.as_mut().unwrap()[0].io_priority = Some(IoPriority {
+            class: IoPriorityClass::BestEffort,
+            level: 7,
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            pci_subsystem_vendor_id: Some(0xffff),
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidPciSubsystemVendorId(0xffff))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            pci_serial: Some("\u{e9}t\u{e9}".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidPciSerial(_))
+        ));
+        invalid_config.net.as_mut().unwrap()[0].pci_serial = Some("x".repeat(256));
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidPciSerial(_))
+        ));
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.net.as_mut().unwrap()[0].pci_serial = Some("x".repeat(255));
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            nvme: true,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].iommu = true;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::NvmeUnsupportedOption("iommu"))
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].queue_size = 1;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidQueueSize(1))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            cold: true,
+            iommu: true,
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::ColdDeviceIommu)
+        ));
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            transport: VirtioTransportType::Mmio,
+            ..Default::default()
+        }]);
+        still_valid_config.net = Some(vec![NetConfig {
+            transport: VirtioTransportType::Mmio,
+            ..Default::default()
+        }]);
+        still_valid_config.rng.transport = VirtioTransportType::Mmio;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks.as_mut().unwrap()[0].nvme = true;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::MmioUnsupportedOption("nvme"))
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.net.as_mut().unwrap()[0].cold = true;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::MmioUnsupportedOption("cold"))
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.rng.iommu = true;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::MmioUnsupportedOption("iommu"))
+        ));
+
         let mut invalid_config = valid_config.clone();
         invalid_config.net = Some(vec![NetConfig {
             vhost_user: true,
@@ -2411,6 +6766,86 @@ mod tests {
 
         let mut still_valid_config = valid_config.clone();
         still_valid_config.memory.shared = true;
+        still_valid_config.fs = Some(vec![
+            FsConfig {
+                tag: "code".to_owned(),
+                socket: PathBuf::from("/tmp/code.sock"),
+                ..Default::default()
+            },
+            FsConfig {
+                tag: "data".to_owned(),
+                socket: PathBuf::from("/tmp/data.sock"),
+                ..Default::default()
+            },
+        ]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.fs.as_mut().unwrap()[1].tag = "code".to_owned();
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::DuplicateFsTag(tag)) if tag == "code"
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.fs.as_mut().unwrap()[1].tag = "x".repeat(37);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidFsTag(_))
+        ));
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.user_devices = Some(vec![UserDeviceConfig {
+            socket: PathBuf::from("/path/to/socket"),
+            id: None,
+            pci_segment: 0,
+            numa_node: None,
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..Default::default()
+        }]);
+        invalid_config.memory.size = 0;
+        invalid_config.memory.zones = Some(vec![
+            MemoryZoneConfig {
+                id: "mem0".to_owned(),
+                size: 1 << 30,
+                file: None,
+                shared: true,
+                hugepages: true,
+                hugepage_size: Some(2 << 20),
+                host_numa_node: None,
+                hotplug_size: None,
+                hotplugged_size: None,
+            },
+            MemoryZoneConfig {
+                id: "mem1".to_owned(),
+                size: 1 << 30,
+                file: None,
+                shared: false,
+                hugepages: true,
+                hugepage_size: Some(2 << 20),
+                host_numa_node: None,
+                hotplug_size: None,
+                hotplugged_size: None,
+            },
+        ]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.memory.zones.as_mut().unwrap()[1].shared = true;
         assert!(still_valid_config.validate().is_ok());
 
         let mut still_valid_config = valid_config.clone();
@@ -2427,9 +6862,286 @@ mod tests {
         invalid_config.memory.hugepage_size = Some(2 << 20);
         assert!(invalid_config.validate().is_err());
 
-        let mut invalid_config = valid_config;
+        let mut invalid_config = valid_config.clone();
         invalid_config.memory.hugepages = true;
         invalid_config.memory.hugepage_size = Some(3 << 20);
         assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.crash_dump = Some(CrashDumpConfig::parse("file=/tmp/crash").unwrap());
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.console.mode = ConsoleOutputMode::Off;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::CrashDumpRequiresConsole)
+        ));
+
+        let mut invalid_config = still_valid_config;
+        invalid_config.crash_dump.as_mut().unwrap().max_size = 0;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::CrashDumpEmpty)
+        ));
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.on_crash = Some(vec![
+            CrashAction::Snapshot("file:///tmp/crash".to_owned()),
+            CrashAction::Pause,
+            CrashAction::Shutdown,
+        ]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.on_crash = Some(vec![CrashAction::Reboot, CrashAction::Pause]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::CrashActionNotLast)
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.on_crash = Some(Vec::new());
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::CrashActionsEmpty)
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            num_pci_segments: 0,
+            ..Default::default()
+        });
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidNumPciSegments(0))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS + 1,
+            ..Default::default()
+        });
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidNumPciSegments(_))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            max_inflight_descriptors: Some(0),
+            ..Default::default()
+        });
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMaxInflightDescriptors)
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.input = Some(vec![InputConfig {
+            kind: InputKind::Evdev,
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InputPathMissing)
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.input = Some(vec![InputConfig {
+            kind: InputKind::Mouse,
+            path: Some(PathBuf::from("/dev/input/event0")),
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InputPathUnexpected(_))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.gpio = Some(vec![GpioConfig {
+            num_lines: 0,
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidGpioLines(0))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.hotplug_slots = MAX_MEMORY_HOTPLUG_SLOTS + 1;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidHotplugSlots(_))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.hotplug_base = Some(8 << 30);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::HotplugBaseWithoutHotplugSize)
+        ));
+        invalid_config.memory.hotplug_size = Some(1 << 30);
+        assert!(invalid_config.validate().is_ok());
+        invalid_config.memory.hotplug_base = Some((8 << 30) + (2 << 20));
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidHotplugBase(_))
+        ));
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.devices = Some(vec![DeviceConfig {
+            path: PathBuf::from("/path/to/device"),
+            pci_segment: 1,
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidPciSegment(1))
+        ));
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut still_valid_config = invalid_config.clone();
+            still_valid_config.platform = Some(PlatformConfig {
+                num_pci_segments: 2,
+                ..Default::default()
+            });
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config;
+            invalid_config.devices.as_mut().unwrap()[0].iommu = true;
+            assert!(matches!(
+                invalid_config.validate(),
+                Err(ValidationError::IommuNotSupportedOnSegment(1))
+            ));
+        }
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.fs = Some(vec![FsConfig {
+            tag: "myfs".to_owned(),
+            numa_node: Some(1),
+            ..Default::default()
+        }]);
+        invalid_config.memory.shared = true;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidNumaNode(1))
+        ));
+
+        let mut still_valid_config = invalid_config;
+        still_valid_config.numa = Some(vec![NumaConfig {
+            guest_numa_id: 1,
+            cpus: None,
+            distances: None,
+            memory_zones: None,
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config;
+        invalid_config.oom_policy = Some(OomPolicyConfig::parse("rss_limit=1G").unwrap());
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::OomPolicyBalloonMissing)
+        ));
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.balloon = Some(BalloonConfig {
+            size: 0,
+            free_page_hinting: false,
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = invalid_config;
+        still_valid_config.oom_policy =
+            Some(OomPolicyConfig::parse("rss_limit=1G,action=pause").unwrap());
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.oom_policy =
+            Some(OomPolicyConfig::parse("rss_limit=1G,action=pause,interval=0").unwrap());
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::OomPolicyZeroInterval)
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            num_queues: 3,
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::VnetQueueOdd)
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            queue_size: 100,
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidQueueSize(100))
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            id: Some("dev0".to_owned()),
+            ..Default::default()
+        }]);
+        invalid_config.net = Some(vec![NetConfig {
+            id: Some("dev0".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::DuplicateDeviceId(_))
+        ));
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.devices = Some(vec![
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                ..Default::default()
+            },
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                ..Default::default()
+            },
+        ]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::DuplicateHostDevice(_))
+        ));
+
+        #[cfg(all(target_arch = "x86_64", feature = "kvm"))]
+        {
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.sev = Some(SevConfig::default());
+            assert!(invalid_config.validate().is_ok());
+            invalid_config.memory.hotplug_size = Some(512 << 20);
+            assert!(matches!(
+                invalid_config.validate(),
+                Err(ValidationError::SevMemoryHotplug)
+            ));
+        }
+
+        // Every problem is reported, not only the first one.
+        let mut invalid_config = still_valid_config;
+        invalid_config.kernel = None;
+        invalid_config.serial.mode = ConsoleOutputMode::Tty;
+        invalid_config.memory.hugepage_size = Some(2 << 20);
+        let e = invalid_config.validate().unwrap_err();
+        assert!(matches!(e, ValidationError::Multiple(_)));
+        assert_eq!(e.errors().len(), 3);
+        assert!(matches!(e.errors()[0], ValidationError::KernelMissing));
+        assert!(matches!(e.errors()[1], ValidationError::DoubleTtyMode));
+        assert!(matches!(
+            e.errors()[2],
+            ValidationError::HugePageSizeWithoutHugePages
+        ));
     }
 }
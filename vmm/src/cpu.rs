@@ -14,6 +14,9 @@
 #[cfg(target_arch = "x86_64")]
 use crate::config::CpuTopology;
 use crate::config::CpusConfig;
+use crate::config::CrashAction;
+#[cfg(target_arch = "x86_64")]
+use crate::config::{CacheSharing, CacheTopology, CpuidLeafAction, CpuidLeafConfig};
 use crate::device_manager::DeviceManager;
 use crate::memory_manager::MemoryManager;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
@@ -25,20 +28,23 @@ use acpi_tables::{aml, aml::Aml, sdt::SDT};
 use anyhow::anyhow;
 #[cfg(target_arch = "x86_64")]
 use arch::x86_64::SgxEpcSection;
-#[cfg(target_arch = "x86_64")]
-use arch::CpuidPatch;
 use arch::EntryPoint;
+#[cfg(target_arch = "x86_64")]
+use arch::{CpuidPatch, CpuidReg};
 use devices::interrupt_controller::InterruptController;
 #[cfg(target_arch = "aarch64")]
 use hypervisor::kvm::kvm_bindings;
-use hypervisor::{vm::VmmOps, CpuState, HypervisorCpuError, VmExit};
+use hypervisor::{vm::VmmOps, CpuState, ExitStats, HypervisorCpuError, VmExit};
 #[cfg(target_arch = "x86_64")]
-use hypervisor::{CpuId, CpuIdEntry};
+use hypervisor::{CpuId, CpuIdEntry, CPUID_FLAG_VALID_INDEX};
 use libc::{c_void, siginfo_t};
 use seccomp::{SeccompAction, SeccompFilter};
+use std::collections::HashMap;
+use std::num::Wrapping;
 use std::os::unix::thread::JoinHandleExt;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
+use std::time::{Duration, Instant};
 use std::{cmp, io, result, thread};
 use vm_device::BusDevice;
 #[cfg(feature = "acpi")]
@@ -58,10 +64,28 @@ const TSC_DEADLINE_TIMER_ECX_BIT: u8 = 24; // tsc deadline timer ecx bit.
 const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
 #[cfg(target_arch = "x86_64")]
 const MTRR_EDX_BIT: u8 = 12; // Hypervisor ecx bit.
+#[cfg(target_arch = "x86_64")]
+const VMX_ECX_BIT: u8 = 5; // Intel virtualization, leaf 0x1 ecx bit.
+#[cfg(target_arch = "x86_64")]
+const SVM_ECX_BIT: u8 = 2; // AMD virtualization, leaf 0x8000_0001 ecx bit.
 
 #[cfg(feature = "acpi")]
 pub const CPU_MANAGER_ACPI_SIZE: usize = 0xc;
 
+// Time the vCPUs run between two throttling sleeps.
+const THROTTLE_TIMESLICE: Duration = Duration::from_millis(10);
+// The vCPUs always get some time to run.
+pub const MAX_THROTTLE_PERCENTAGE: u8 = 99;
+
+// Time a vCPU gets to exit the guest and take a pending NMI.
+#[cfg(target_arch = "x86_64")]
+pub const NMI_INJECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Difference between the TSC frequency of the guest and the host one KVM
+// absorbs without scaling the TSC, as per its default tsc_tolerance_ppm.
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+const TSC_TOLERANCE_PPM: u64 = 250;
+
 #[derive(Debug)]
 pub enum Error {
     /// Cannot create the vCPU.
@@ -73,6 +97,9 @@ pub enum Error {
     /// Cannot spawn a new vCPU thread.
     VcpuSpawn(io::Error),
 
+    /// Cannot spawn the thread throttling the vCPUs.
+    ThrottleSpawn(io::Error),
+
     /// Cannot patch the CPU ID
     PatchCpuId(anyhow::Error),
 
@@ -186,9 +213,248 @@ pub enum Error {
     /// Error populating CPUID with CPU identification
     #[cfg(target_arch = "x86_64")]
     CpuidIdentification(vmm_sys_util::fam::Error),
+
+    /// Error populating CPUID with the leaves from the configuration
+    #[cfg(target_arch = "x86_64")]
+    CpuidLeaf(vmm_sys_util::fam::Error),
+
+    /// Failed to get the CPUID of the vCPU.
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    VcpuGetCpuid(anyhow::Error),
+
+    /// Cannot get the TSC frequency of the vCPU.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    GetTscKhz(hypervisor::HypervisorCpuError),
+
+    /// Cannot set the TSC frequency of the vCPU.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    SetTscKhz(hypervisor::HypervisorCpuError),
+
+    /// The TSC frequency of the source (first, in kHz) differs from the one
+    /// of this host (second) and the host doesn't support TSC scaling.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    TscScalingUnsupported(u32, u32),
+
+    /// Nested virtualization was requested but the host doesn't expose it,
+    /// e.g. because the `nested` parameter of kvm_intel or kvm_amd is off.
+    #[cfg(target_arch = "x86_64")]
+    NestedVirtUnsupported,
+
+    /// The TSC-deadline timer was requested but the host doesn't emulate it.
+    #[cfg(target_arch = "x86_64")]
+    TscDeadlineUnsupported,
+
+    /// The vCPU doesn't exist.
+    #[cfg(target_arch = "x86_64")]
+    InvalidVcpuId(u8),
+
+    /// The vCPU didn't exit the guest in time to take the NMI.
+    #[cfg(target_arch = "x86_64")]
+    NmiTimeout(u8),
+
+    /// NMIs can't be injected on this architecture.
+    #[cfg(target_arch = "aarch64")]
+    NmiUnsupported,
 }
 pub type Result<T> = result::Result<T, Error>;
 
+/// Registers of a vCPU, as reported by the debug API.
+#[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct VcpuRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub cr8: u64,
+    pub efer: u64,
+    pub apic_base: u64,
+    /// CPUID exposed to the guest by the vCPU.
+    pub cpuid: Vec<arch::x86_64::CpuidLeaf>,
+}
+
+// Registers of a vCPU, along with its CPUID of up to cpuid_entries leaves.
+#[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+fn read_vcpu_registers(vcpu: &dyn hypervisor::Vcpu, cpuid_entries: usize) -> Result<VcpuRegisters> {
+    let regs = vcpu.get_regs().map_err(|e| Error::VcpuGetRegs(e.into()))?;
+    let sregs = vcpu
+        .get_sregs()
+        .map_err(|e| Error::VcpuGetSregs(e.into()))?;
+    let cpuid = vcpu
+        .get_cpuid2(cpuid_entries)
+        .map_err(|e| Error::VcpuGetCpuid(e.into()))?;
+
+    Ok(VcpuRegisters {
+        rax: regs.rax,
+        rbx: regs.rbx,
+        rcx: regs.rcx,
+        rdx: regs.rdx,
+        rsi: regs.rsi,
+        rdi: regs.rdi,
+        rsp: regs.rsp,
+        rbp: regs.rbp,
+        r8: regs.r8,
+        r9: regs.r9,
+        r10: regs.r10,
+        r11: regs.r11,
+        r12: regs.r12,
+        r13: regs.r13,
+        r14: regs.r14,
+        r15: regs.r15,
+        rip: regs.rip,
+        rflags: regs.rflags,
+        cr0: sregs.cr0,
+        cr2: sregs.cr2,
+        cr3: sregs.cr3,
+        cr4: sregs.cr4,
+        cr8: sregs.cr8,
+        efer: sregs.efer,
+        apic_base: sregs.apic_base,
+        cpuid: cpuid
+            .as_slice()
+            .iter()
+            .map(arch::x86_64::CpuidLeaf::from)
+            .collect(),
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+fn nested_virtualization(cpuid: &CpuId) -> bool {
+    CpuidPatch::is_feature_enabled(cpuid, 0x1, 0, CpuidReg::ECX, VMX_ECX_BIT as usize)
+        || CpuidPatch::is_feature_enabled(
+            cpuid,
+            0x8000_0001,
+            0,
+            CpuidReg::ECX,
+            SVM_ECX_BIT as usize,
+        )
+}
+
+// KVM lets a vCPU run its own guests as long as its CPUID exposes VMX or
+// SVM, which the supported CPUID only does when nested virtualization is
+// enabled on the host. Without a setting, the host's is kept.
+#[cfg(target_arch = "x86_64")]
+fn set_nested_virtualization(cpuid: &mut CpuId, nested: Option<bool>) -> Result<()> {
+    match nested {
+        Some(true) if !nested_virtualization(cpuid) => Err(Error::NestedVirtUnsupported),
+        Some(false) => {
+            for entry in cpuid.as_mut_slice().iter_mut() {
+                match entry.function {
+                    0x1 => entry.ecx &= !(1 << VMX_ECX_BIT),
+                    0x8000_0001 => entry.ecx &= !(1 << SVM_ECX_BIT),
+                    _ => {}
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// KVM emulates the TSC-deadline timer without reporting it in the supported
+// CPUID, its capability tells whether the host does.
+#[cfg(target_arch = "x86_64")]
+fn host_tsc_deadline_timer(hypervisor: &Arc<dyn hypervisor::Hypervisor>) -> bool {
+    #[cfg(feature = "kvm")]
+    {
+        hypervisor.check_capability(hypervisor::kvm::Cap::TscDeadlineTimer)
+    }
+    // Without a way to check it, it isn't assumed to be emulated.
+    #[cfg(not(feature = "kvm"))]
+    {
+        let _ = hypervisor;
+        false
+    }
+}
+
+// Resolves the TSC-deadline timer setting against the host support, the
+// host's is used when there isn't one.
+#[cfg(target_arch = "x86_64")]
+fn tsc_deadline_enabled(tsc_deadline: Option<bool>, host: bool) -> Result<bool> {
+    match tsc_deadline {
+        Some(true) if !host => Err(Error::TscDeadlineUnsupported),
+        Some(tsc_deadline) => Ok(tsc_deadline),
+        None => Ok(host),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn apply_cpuid_leaves(cpuid: &mut CpuId, cpuid_leaves: &[CpuidLeafConfig]) -> Result<()> {
+    for leaf in cpuid_leaves {
+        let (eax, ebx, ecx, edx) = match leaf.action {
+            CpuidLeafAction::Host => {
+                let host = unsafe { std::arch::x86_64::__cpuid_count(leaf.function, leaf.index) };
+                (host.eax, host.ebx, host.ecx, host.edx)
+            }
+            CpuidLeafAction::Zero => (0, 0, 0, 0),
+            CpuidLeafAction::Values { eax, ebx, ecx, edx } => (eax, ebx, ecx, edx),
+        };
+
+        // KVM only looks at the index of the leaves flagged as indexed.
+        let indexed = leaf.index != 0
+            || cpuid
+                .as_slice()
+                .iter()
+                .any(|c| c.function == leaf.function && c.flags & CPUID_FLAG_VALID_INDEX != 0);
+        cpuid.retain(|c| !(c.function == leaf.function && c.index == leaf.index));
+        cpuid
+            .push(CpuIdEntry {
+                function: leaf.function,
+                index: leaf.index,
+                flags: if indexed { CPUID_FLAG_VALID_INDEX } else { 0 },
+                eax,
+                ebx,
+                ecx,
+                edx,
+                ..Default::default()
+            })
+            .map_err(Error::CpuidLeaf)?;
+    }
+
+    Ok(())
+}
+
+// The CPUID of a vCPU, the common one reporting its x2APIC ID through the
+// topology leaves 0xb and 0x1f. The leaves from the configuration are applied
+// again, for them to take precedence over the x2APIC ID as well.
+#[cfg(target_arch = "x86_64")]
+fn vcpu_cpuid(cpuid: &CpuId, id: u8, cpuid_leaves: &[CpuidLeafConfig]) -> Result<CpuId> {
+    let mut cpuid = cpuid.clone();
+    arch::x86_64::set_cpuid_x2apic_id(&mut cpuid, id);
+    apply_cpuid_leaves(&mut cpuid, cpuid_leaves)?;
+
+    Ok(cpuid)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn tsc_deadline_timer(cpuid: &CpuId) -> bool {
+    CpuidPatch::is_feature_enabled(
+        cpuid,
+        0x1,
+        0,
+        CpuidReg::ECX,
+        TSC_DEADLINE_TIMER_ECX_BIT as usize,
+    )
+}
+
 #[cfg(feature = "acpi")]
 #[repr(packed)]
 struct LocalAPIC {
@@ -264,6 +530,8 @@ impl Vcpu {
     /// * `kernel_entry_point` - Kernel entry point address in guest memory and boot protocol used.
     /// * `vm_memory` - Guest memory.
     /// * `cpuid` - (x86_64) CpuId, wrapper over the `kvm_cpuid2` structure.
+    /// * `encryption_mask` - (x86_64) Bit set in the boot page tables when the
+    ///   guest memory is encrypted.
     pub fn configure(
         &mut self,
         #[cfg(target_arch = "aarch64")] vm: &Arc<dyn hypervisor::Vm>,
@@ -271,6 +539,7 @@ impl Vcpu {
         vm_memory: &GuestMemoryAtomic<GuestMemoryMmap>,
         #[cfg(target_arch = "x86_64")] cpuid: CpuId,
         #[cfg(target_arch = "x86_64")] kvm_hyperv: bool,
+        #[cfg(target_arch = "x86_64")] encryption_mask: u64,
     ) -> Result<()> {
         #[cfg(target_arch = "aarch64")]
         {
@@ -282,11 +551,11 @@ impl Vcpu {
         #[cfg(target_arch = "x86_64")]
         arch::configure_vcpu(
             &self.vcpu,
-            self.id,
             kernel_entry_point,
             vm_memory,
             cpuid,
             kvm_hyperv,
+            encryption_mask,
         )
         .map_err(Error::VcpuConfiguration)?;
 
@@ -365,6 +634,7 @@ impl Snapshottable for Vcpu {
         vcpu_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", VCPU_SNAPSHOT_ID),
             snapshot,
+            ..Default::default()
         });
 
         Ok(vcpu_snapshot)
@@ -411,13 +681,28 @@ pub struct CpuManager {
     exit_evt: EventFd,
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     reset_evt: EventFd,
+    // Written by the vCPUs triple faulting, for the VMM to run the
+    // configured actions.
+    triple_fault_evt: EventFd,
+    triple_fault_actions: Vec<CrashAction>,
+    // Encryption bit of the guest page table entries, when the guest memory
+    // is encrypted.
+    #[cfg(target_arch = "x86_64")]
+    memory_encryption_mask: u64,
     vcpu_states: Vec<VcpuState>,
     selected_cpu: u8,
     vcpus: Vec<Arc<Mutex<Vcpu>>>,
+    // The vCPU mutex is held while the vCPU runs, hence the hypervisor vCPUs
+    // are kept aside for reading their exit statistics.
+    hypervisor_vcpus: Vec<Arc<dyn hypervisor::Vcpu>>,
     seccomp_action: SeccompAction,
     vmmops: Arc<Box<dyn VmmOps>>,
     #[cfg(feature = "acpi")]
     acpi_address: GuestAddress,
+    // TSC frequency of the vCPUs restored from a snapshot, which the vCPUs
+    // hotplugged afterwards must run at as well.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    tsc_khz: Option<u32>,
 }
 
 const CPU_ENABLE_FLAG: usize = 0;
@@ -499,6 +784,11 @@ struct VcpuState {
     handle: Option<thread::JoinHandle<()>>,
     kill: Arc<AtomicBool>,
     vcpu_run_interrupted: Arc<AtomicBool>,
+    // Nanoseconds the vCPU must sleep the next time it exits to the VMM.
+    throttle_sleep: Arc<AtomicU64>,
+    // NMI to be injected the next time the vCPU exits to the VMM.
+    #[cfg(target_arch = "x86_64")]
+    nmi_pending: Arc<AtomicBool>,
 }
 
 impl VcpuState {
@@ -507,6 +797,12 @@ impl VcpuState {
     }
 
     fn signal_thread(&self) {
+        self.signal_thread_until(None);
+    }
+
+    // Signals the thread until the vCPU is out of KVM_RUN, done handling its
+    // last exit. Returns false if the deadline expired first.
+    fn signal_thread_until(&self, deadline: Option<Instant>) -> bool {
         if let Some(handle) = self.handle.as_ref() {
             loop {
                 unsafe {
@@ -514,6 +810,8 @@ impl VcpuState {
                 }
                 if self.vcpu_run_interrupted.load(Ordering::SeqCst) {
                     break;
+                } else if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                    return false;
                 } else {
                     // This is more effective than thread::yield_now() at
                     // avoiding a priority inversion with the vCPU thread
@@ -521,6 +819,18 @@ impl VcpuState {
                 }
             }
         }
+
+        true
+    }
+
+    // Unlike signal_thread(), doesn't wait for the vCPU to acknowledge the
+    // signal: missing it only leaves a throttling sleep out.
+    fn kick_thread(&self) {
+        if let Some(handle) = self.handle.as_ref() {
+            unsafe {
+                libc::pthread_kill(handle.as_pthread_t() as _, SIGRTMIN());
+            }
+        }
     }
 
     fn join_thread(&mut self) -> Result<()> {
@@ -548,6 +858,8 @@ impl CpuManager {
         vm: Arc<dyn hypervisor::Vm>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        triple_fault_evt: EventFd,
+        triple_fault_actions: Vec<CrashAction>,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         seccomp_action: SeccompAction,
         vmmops: Arc<Box<dyn VmmOps>>,
@@ -569,9 +881,13 @@ impl CpuManager {
             CpuManager::generate_common_cpuid(
                 hypervisor,
                 &config.topology,
+                config.caches,
                 sgx_epc_sections,
                 phys_bits,
                 config.kvm_hyperv,
+                config.nested,
+                config.tsc_deadline,
+                &config.cpuid,
             )?
         };
 
@@ -595,12 +911,19 @@ impl CpuManager {
             vcpu_states,
             exit_evt,
             reset_evt,
+            triple_fault_evt,
+            triple_fault_actions,
+            #[cfg(target_arch = "x86_64")]
+            memory_encryption_mask: 0,
             selected_cpu: 0,
             vcpus: Vec::with_capacity(usize::from(config.max_vcpus)),
+            hypervisor_vcpus: Vec::with_capacity(usize::from(config.max_vcpus)),
             seccomp_action,
             vmmops,
             #[cfg(feature = "acpi")]
             acpi_address,
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            tsc_khz: None,
         }));
 
         #[cfg(feature = "acpi")]
@@ -617,25 +940,35 @@ impl CpuManager {
     }
 
     #[cfg(target_arch = "x86_64")]
+    #[allow(clippy::too_many_arguments)]
     fn generate_common_cpuid(
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         topology: &Option<CpuTopology>,
+        caches: Option<CacheTopology>,
         sgx_epc_sections: Option<Vec<SgxEpcSection>>,
         phys_bits: u8,
         kvm_hyperv: bool,
+        nested: Option<bool>,
+        tsc_deadline: Option<bool>,
+        cpuid_leaves: &[CpuidLeafConfig],
     ) -> Result<CpuId> {
         let mut cpuid_patches = Vec::new();
 
+        let tsc_deadline =
+            tsc_deadline_enabled(tsc_deadline, host_tsc_deadline_timer(&hypervisor))?;
+
         // Patch tsc deadline timer bit
-        cpuid_patches.push(CpuidPatch {
-            function: 1,
-            index: 0,
-            flags_bit: None,
-            eax_bit: None,
-            ebx_bit: None,
-            ecx_bit: Some(TSC_DEADLINE_TIMER_ECX_BIT),
-            edx_bit: None,
-        });
+        if tsc_deadline {
+            cpuid_patches.push(CpuidPatch {
+                function: 1,
+                index: 0,
+                flags_bit: None,
+                eax_bit: None,
+                ebx_bit: None,
+                ecx_bit: Some(TSC_DEADLINE_TIMER_ECX_BIT),
+                edx_bit: None,
+            });
+        }
 
         // Patch hypervisor bit
         cpuid_patches.push(CpuidPatch {
@@ -666,6 +999,16 @@ impl CpuManager {
 
         CpuidPatch::patch_cpuid(&mut cpuid, cpuid_patches);
 
+        if !tsc_deadline {
+            for entry in cpuid.as_mut_slice().iter_mut() {
+                if entry.function == 0x1 {
+                    entry.ecx &= !(1 << TSC_DEADLINE_TIMER_ECX_BIT);
+                }
+            }
+        }
+
+        set_nested_virtualization(&mut cpuid, nested)?;
+
         if let Some(t) = topology {
             arch::x86_64::update_cpuid_topology(
                 &mut cpuid,
@@ -673,6 +1016,25 @@ impl CpuManager {
                 t.cores_per_die,
                 t.dies_per_package,
             );
+
+            // The cache leaves of the host describe its own topology.
+            let caches = caches.unwrap_or_default();
+            let sharing: Vec<arch::x86_64::CacheSharing> = [caches.l1, caches.l2, caches.l3]
+                .iter()
+                .map(|sharing| match sharing {
+                    CacheSharing::Thread => arch::x86_64::CacheSharing::Thread,
+                    CacheSharing::Core => arch::x86_64::CacheSharing::Core,
+                    CacheSharing::Die => arch::x86_64::CacheSharing::Die,
+                    CacheSharing::Package => arch::x86_64::CacheSharing::Package,
+                })
+                .collect();
+            arch::x86_64::update_cpuid_cache_topology(
+                &mut cpuid,
+                t.threads_per_core,
+                t.cores_per_die,
+                t.dies_per_package,
+                &sharing,
+            );
         }
 
         if let Some(sgx_epc_sections) = sgx_epc_sections {
@@ -754,6 +1116,10 @@ impl CpuManager {
             }
         }
 
+        // The leaves from the configuration come last, for them to take
+        // precedence over whatever was set up above.
+        apply_cpuid_leaves(&mut cpuid, cpuid_leaves)?;
+
         Ok(cpuid)
     }
 
@@ -776,17 +1142,34 @@ impl CpuManager {
                 .unwrap()
                 .restore(snapshot)
                 .expect("Failed to restore vCPU");
+
+            // The frequency must be set before the state, for the TSC value
+            // to be taken at the same scale.
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            {
+                let vcpu = vcpu.lock().unwrap();
+                if let Some(tsc_khz) = vcpu.saved_state.as_ref().and_then(|s| s.tsc_khz) {
+                    self.scale_tsc(&vcpu.vcpu, tsc_khz)?;
+                    self.tsc_khz = Some(tsc_khz);
+                }
+            }
         } else {
             let vm_memory = self.vm_memory.clone();
 
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            if let Some(tsc_khz) = self.tsc_khz {
+                self.scale_tsc(&vcpu.lock().unwrap().vcpu, tsc_khz)?;
+            }
+
             #[cfg(target_arch = "x86_64")]
             vcpu.lock()
                 .unwrap()
                 .configure(
                     entry_point,
                     &vm_memory,
-                    self.cpuid.clone(),
+                    vcpu_cpuid(&self.cpuid, cpu_id, &self.config.cpuid)?,
                     self.config.kvm_hyperv,
+                    self.memory_encryption_mask,
                 )
                 .expect("Failed to configure vCPU");
 
@@ -798,11 +1181,35 @@ impl CpuManager {
         }
 
         // Adding vCPU to the CpuManager's vCPU list.
+        self.hypervisor_vcpus
+            .push(vcpu.lock().unwrap().vcpu.clone());
         self.vcpus.push(Arc::clone(&vcpu));
 
         Ok(vcpu)
     }
 
+    /// Makes the TSC of the vCPU run at `tsc_khz`, which requires TSC scaling
+    /// when the frequency differs from the host one, e.g. after a migration.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    fn scale_tsc(&self, vcpu: &Arc<dyn hypervisor::Vcpu>, tsc_khz: u32) -> Result<()> {
+        let host_tsc_khz = vcpu.tsc_khz().map_err(Error::GetTscKhz)?;
+        if host_tsc_khz == tsc_khz {
+            return Ok(());
+        }
+        let tsc_scaling = self.vm.check_extension(hypervisor::kvm::Cap::TscControl);
+        if !tsc_frequency_supported(tsc_khz, host_tsc_khz, tsc_scaling) {
+            return Err(Error::TscScalingUnsupported(tsc_khz, host_tsc_khz));
+        }
+
+        info!(
+            "Scaling the TSC from {} kHz to {} kHz (ratio {:.6})",
+            host_tsc_khz,
+            tsc_khz,
+            f64::from(tsc_khz) / f64::from(host_tsc_khz)
+        );
+        vcpu.set_tsc_khz(tsc_khz).map_err(Error::SetTscKhz)
+    }
+
     /// Only create new vCPUs if there aren't any inactive ones to reuse
     fn create_vcpus(&mut self, desired_vcpus: u8, entry_point: Option<EntryPoint>) -> Result<()> {
         info!(
@@ -834,6 +1241,8 @@ impl CpuManager {
         let cpu_id = vcpu.lock().unwrap().id;
         let reset_evt = self.reset_evt.try_clone().unwrap();
         let exit_evt = self.exit_evt.try_clone().unwrap();
+        let triple_fault_evt = self.triple_fault_evt.try_clone().unwrap();
+        let triple_fault_actions = self.triple_fault_actions.clone();
         let vcpu_kill_signalled = self.vcpus_kill_signalled.clone();
         let vcpu_pause_signalled = self.vcpus_pause_signalled.clone();
 
@@ -841,6 +1250,9 @@ impl CpuManager {
         let vcpu_run_interrupted = self.vcpu_states[usize::from(cpu_id)]
             .vcpu_run_interrupted
             .clone();
+        let vcpu_throttle_sleep = self.vcpu_states[usize::from(cpu_id)].throttle_sleep.clone();
+        #[cfg(target_arch = "x86_64")]
+        let vcpu_nmi_pending = self.vcpu_states[usize::from(cpu_id)].nmi_pending.clone();
 
         info!("Starting vCPU: cpu_id = {}", cpu_id);
 
@@ -905,7 +1317,16 @@ impl CpuManager {
                             break;
                         }
 
-                        // vcpu.run() returns false on a triple-fault so trigger a reset
+                        // The NMI is injected from the vCPU thread as the
+                        // vCPU can't be accessed while it runs.
+                        #[cfg(target_arch = "x86_64")]
+                        if vcpu_nmi_pending.swap(false, Ordering::SeqCst) {
+                            if let Err(e) = vcpu.lock().unwrap().vcpu.nmi() {
+                                error!("Failed to inject NMI into vCPU {}: {:?}", cpu_id, e);
+                            }
+                        }
+
+                        let mut triple_faulted = false;
                         match vcpu.lock().unwrap().run() {
                             Ok(run) => match run {
                                 #[cfg(target_arch = "x86_64")]
@@ -932,6 +1353,18 @@ impl CpuManager {
                                     exit_evt.write(1).unwrap();
                                     break;
                                 }
+                                VmExit::TripleFault => {
+                                    warn!(
+                                        "vCPU {} triple faulted, actions: {:?}",
+                                        cpu_id, triple_fault_actions
+                                    );
+                                    triple_fault_evt.write(1).unwrap();
+                                    if triple_fault_stops_vcpu(&triple_fault_actions) {
+                                        vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                                        break;
+                                    }
+                                    triple_faulted = true;
+                                }
                                 _ => {
                                     error!("VCPU generated error: {:?}", Error::UnexpectedVmExit);
                                     break;
@@ -944,6 +1377,24 @@ impl CpuManager {
                             }
                         }
 
+                        // Rather than running into the fault again, wait for
+                        // the VMM to pause the VM, the vCPU mutex being
+                        // released as pausing the vCPU takes it. The thread
+                        // then parks until the VM is resumed.
+                        if triple_faulted {
+                            vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                            wait_for_pause(&vcpu_pause_signalled, &vcpu_kill_signalled, &vcpu_kill);
+                            continue;
+                        }
+
+                        // Sleep if the vCPUs are being throttled. Parking
+                        // rather than sleeping lets the signal sent when
+                        // pausing or killing the vCPU cut the sleep short.
+                        let throttle_sleep = vcpu_throttle_sleep.swap(0, Ordering::SeqCst);
+                        if throttle_sleep > 0 {
+                            thread::park_timeout(Duration::from_nanos(throttle_sleep));
+                        }
+
                         // We've been told to terminate
                         if vcpu_kill_signalled.load(Ordering::SeqCst)
                             || vcpu_kill.load(Ordering::SeqCst)
@@ -1004,6 +1455,8 @@ impl CpuManager {
         let mut state = &mut self.vcpu_states[usize::from(cpu_id)];
         state.kill.store(true, Ordering::SeqCst);
         state.signal_thread();
+        // A vCPU which triple faulted is parked rather than in the guest.
+        state.unpark_thread();
         state.join_thread()?;
         state.handle = None;
 
@@ -1013,6 +1466,13 @@ impl CpuManager {
         Ok(())
     }
 
+    // Sets the encryption bit of the page tables the boot vCPUs are
+    // configured with, which must be done before they are created.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_memory_encryption_mask(&mut self, mask: u64) {
+        self.memory_encryption_mask = mask;
+    }
+
     pub fn create_boot_vcpus(&mut self, entry_point: EntryPoint) -> Result<()> {
         self.create_vcpus(self.boot_vcpus(), Some(entry_point))
     }
@@ -1056,6 +1516,17 @@ impl CpuManager {
         }
     }
 
+    // Makes the running vCPUs sleep for the given time once kicked out of
+    // the guest.
+    fn throttle_vcpus(&self, sleep: Duration) {
+        for state in self.vcpu_states.iter().filter(|state| state.active()) {
+            state
+                .throttle_sleep
+                .store(sleep.as_nanos() as u64, Ordering::SeqCst);
+            state.kick_thread();
+        }
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         // Tell the vCPUs to stop themselves next time they go through the loop
         self.vcpus_kill_signalled.store(true, Ordering::SeqCst);
@@ -1091,6 +1562,90 @@ impl CpuManager {
         self.config.max_vcpus
     }
 
+    /// Whether the vCPUs can run their own guests.
+    #[cfg(target_arch = "x86_64")]
+    pub fn nested_virtualization(&self) -> bool {
+        nested_virtualization(&self.cpuid)
+    }
+
+    /// Whether the vCPUs can use the TSC-deadline mode of their local APIC
+    /// timer.
+    #[cfg(target_arch = "x86_64")]
+    pub fn tsc_deadline_timer(&self) -> bool {
+        tsc_deadline_timer(&self.cpuid)
+    }
+
+    /// Exit statistics aggregated across all the vCPUs.
+    pub fn exit_stats(&self) -> ExitStats {
+        self.hypervisor_vcpus
+            .iter()
+            .fold(ExitStats::default(), |mut acc, vcpu| {
+                acc += vcpu.exit_stats();
+                acc
+            })
+    }
+
+    pub fn counters(&self) -> HashMap<&'static str, Wrapping<u64>> {
+        let stats = self.exit_stats();
+        let mut counters = HashMap::new();
+
+        counters.insert("exits", Wrapping(stats.total()));
+        counters.insert("io_exits", Wrapping(stats.io));
+        counters.insert("mmio_exits", Wrapping(stats.mmio));
+        counters.insert("hlt_exits", Wrapping(stats.hlt));
+        counters.insert("shutdown_exits", Wrapping(stats.shutdown));
+        counters.insert("ioapic_eoi_exits", Wrapping(stats.ioapic_eoi));
+        counters.insert("hyperv_exits", Wrapping(stats.hyperv));
+        counters.insert("interrupted_exits", Wrapping(stats.interrupted));
+        counters.insert("other_exits", Wrapping(stats.other));
+
+        counters
+    }
+
+    /// Makes an NMI pending for a running vCPU, or for all of them if no
+    /// vCPU is given. The vCPUs take it once kicked out of the guest, which
+    /// wait_for_nmis() waits for.
+    #[cfg(target_arch = "x86_64")]
+    pub fn queue_nmi(&self, id: Option<u8>) -> Result<Vec<PendingNmi>> {
+        queue_nmi(&self.vcpu_states, id)
+    }
+
+    /// Signals a vCPU out of the guest, without waiting for it.
+    #[cfg(target_arch = "x86_64")]
+    pub fn kick_vcpu(&self, id: u8) {
+        if let Some(state) = self.vcpu_states.get(usize::from(id)) {
+            state.kick_thread();
+        }
+    }
+
+    /// Registers of a vCPU, which must not be running for them to be
+    /// consistent.
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    pub fn vcpu_registers(&self, id: u8) -> Result<VcpuRegisters> {
+        if !self
+            .vcpu_states
+            .get(usize::from(id))
+            .map_or(false, |state| state.active())
+        {
+            return Err(Error::InvalidVcpuId(id));
+        }
+        // The CPUID of a vCPU only differs from the common one by the
+        // topology leaves 0xb and 0x1f, which may be added to it.
+        read_vcpu_registers(
+            self.vcpus[usize::from(id)].lock().unwrap().vcpu.as_ref(),
+            self.cpuid.as_slice().len() + 2,
+        )
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn cpuid_leaves(&self) -> Vec<arch::x86_64::CpuidLeaf> {
+        self.cpuid
+            .as_slice()
+            .iter()
+            .map(arch::x86_64::CpuidLeaf::from)
+            .collect()
+    }
+
     fn present_vcpus(&self) -> u8 {
         self.vcpu_states
             .iter()
@@ -1458,16 +2013,187 @@ impl Aml for CpuManager {
     }
 }
 
-impl Pausable for CpuManager {
-    fn pause(&mut self) -> std::result::Result<(), MigratableError> {
+// Whether a vCPU which triple faulted exits right away. Unless the VM is
+// torn down by the first action, the VMM pauses it first.
+fn triple_fault_stops_vcpu(actions: &[CrashAction]) -> bool {
+    actions.first().map_or(true, CrashAction::is_final)
+}
+
+/// An NMI a vCPU hasn't taken yet.
+#[cfg(target_arch = "x86_64")]
+pub struct PendingNmi {
+    id: u8,
+    pending: Arc<AtomicBool>,
+}
+
+#[cfg(target_arch = "x86_64")]
+fn queue_nmi(vcpu_states: &[VcpuState], id: Option<u8>) -> Result<Vec<PendingNmi>> {
+    let targets: Vec<(u8, &VcpuState)> = match id {
+        Some(id) => match vcpu_states.get(usize::from(id)) {
+            Some(state) if state.active() => vec![(id, state)],
+            _ => return Err(Error::InvalidVcpuId(id)),
+        },
+        None => vcpu_states
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| state.active())
+            .map(|(id, state)| (id as u8, state))
+            .collect(),
+    };
+
+    Ok(targets
+        .into_iter()
+        .map(|(id, state)| {
+            state.nmi_pending.store(true, Ordering::SeqCst);
+            PendingNmi {
+                id,
+                pending: state.nmi_pending.clone(),
+            }
+        })
+        .collect())
+}
+
+/// Waits for the vCPUs to take their pending NMI, kicking them until then
+/// as the signal is missed by a vCPU about to enter the guest. The kicks
+/// are left to the caller, which must not hold the CpuManager lock while
+/// waiting.
+#[cfg(target_arch = "x86_64")]
+pub fn wait_for_nmis<F>(nmis: &[PendingNmi], deadline: Instant, mut kick: F) -> Result<()>
+where
+    F: FnMut(u8),
+{
+    for nmi in nmis {
+        while nmi.pending.load(Ordering::SeqCst) {
+            if Instant::now() >= deadline {
+                // The vCPU may only just have taken the NMI, which then
+                // mustn't be reported as lost.
+                if nmi
+                    .pending
+                    .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    return Err(Error::NmiTimeout(nmi.id));
+                }
+                break;
+            }
+            kick(nmi.id);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    Ok(())
+}
+
+// Parks a vCPU which triple faulted until the VM is paused, or the vCPU
+// killed, both of which unpark it.
+fn wait_for_pause(pause: &AtomicBool, kill_signalled: &AtomicBool, kill: &AtomicBool) {
+    while !pause.load(Ordering::SeqCst)
+        && !kill_signalled.load(Ordering::SeqCst)
+        && !kill.load(Ordering::SeqCst)
+    {
+        thread::park();
+    }
+}
+
+// Time the vCPUs sleep after each timeslice to be kept from running for
+// the given percentage of the time.
+fn throttle_sleep(percentage: u8) -> Duration {
+    let percentage = u32::from(percentage.min(MAX_THROTTLE_PERCENTAGE));
+    THROTTLE_TIMESLICE * percentage / (100 - percentage)
+}
+
+/// Keeps the vCPUs from running for a percentage of the time, for the guest
+/// to dirty its memory slower while it is being migrated.
+///
+/// Every time the vCPUs have run for `THROTTLE_TIMESLICE`, they are kicked out
+/// of the guest and sleep long enough for the percentage to be honoured. The
+/// throttling stops when dropped.
+pub struct CpuThrottle {
+    percentage: Arc<AtomicU8>,
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl CpuThrottle {
+    pub fn new(
+        cpu_manager: Arc<Mutex<CpuManager>>,
+        percentage: u8,
+        seccomp_action: &SeccompAction,
+    ) -> Result<Self> {
+        let throttle_seccomp_filter = get_seccomp_filter(seccomp_action, Thread::CpuThrottle)
+            .map_err(Error::CreateSeccompFilter)?;
+        let percentage = Arc::new(AtomicU8::new(percentage.min(MAX_THROTTLE_PERCENTAGE)));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_percentage = percentage.clone();
+        let thread_stop = stop.clone();
+        let handle = thread::Builder::new()
+            .name("cpu_throttle".to_string())
+            .spawn(move || {
+                if let Err(e) = SeccompFilter::apply(throttle_seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                    return;
+                }
+
+                while !thread_stop.load(Ordering::SeqCst) {
+                    let sleep = throttle_sleep(thread_percentage.load(Ordering::SeqCst));
+                    // Woken up early when stopped.
+                    thread::park_timeout(THROTTLE_TIMESLICE + sleep);
+                    if !thread_stop.load(Ordering::SeqCst) {
+                        cpu_manager.lock().unwrap().throttle_vcpus(sleep);
+                    }
+                }
+            })
+            .map_err(Error::ThrottleSpawn)?;
+
+        Ok(CpuThrottle {
+            percentage,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn set_percentage(&self, percentage: u8) {
+        self.percentage
+            .store(percentage.min(MAX_THROTTLE_PERCENTAGE), Ordering::SeqCst);
+    }
+}
+
+impl Drop for CpuThrottle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            if handle.join().is_err() {
+                error!("Failed joining the vCPU throttling thread");
+            }
+        }
+    }
+}
+
+impl CpuManager {
+    fn pause_vcpus(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> std::result::Result<(), MigratableError> {
         // Tell the vCPUs to pause themselves next time they exit
         self.vcpus_pause_signalled.store(true, Ordering::SeqCst);
 
+        // The vCPUs which triple faulted are parked until then.
+        for state in self.vcpu_states.iter() {
+            state.unpark_thread();
+        }
+
         // Signal to the spawned threads (vCPUs and console signal handler). For the vCPU threads
         // this will interrupt the KVM_RUN ioctl() allowing the loop to check the boolean set
         // above.
-        for state in self.vcpu_states.iter() {
-            state.signal_thread();
+        for (cpu_id, state) in self.vcpu_states.iter().enumerate() {
+            if !state.signal_thread_until(deadline) {
+                return Err(MigratableError::Quiesce(anyhow!(
+                    "Timed out waiting for vCPU {} to exit",
+                    cpu_id
+                )));
+            }
         }
 
         for vcpu in self.vcpus.iter() {
@@ -1486,6 +2212,16 @@ impl Pausable for CpuManager {
 
         Ok(())
     }
+}
+
+impl Pausable for CpuManager {
+    fn pause(&mut self) -> std::result::Result<(), MigratableError> {
+        self.pause_vcpus(None)
+    }
+
+    fn quiesce(&mut self, deadline: Instant) -> std::result::Result<(), MigratableError> {
+        self.pause_vcpus(Some(deadline))
+    }
 
     fn resume(&mut self) -> std::result::Result<(), MigratableError> {
         for vcpu in self.vcpus.iter() {
@@ -1537,6 +2273,15 @@ impl Snapshottable for CpuManager {
 impl Transportable for CpuManager {}
 impl Migratable for CpuManager {}
 
+// Whether the vCPUs can run at the TSC frequency of the source, in kHz, on
+// a host whose TSC runs at host_tsc_khz. Past the tolerance of KVM, this
+// requires TSC scaling.
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+fn tsc_frequency_supported(tsc_khz: u32, host_tsc_khz: u32, tsc_scaling: bool) -> bool {
+    let difference = (i64::from(tsc_khz) - i64::from(host_tsc_khz)).abs() as u64;
+    tsc_scaling || difference * 1_000_000 <= u64::from(host_tsc_khz) * TSC_TOLERANCE_PPM
+}
+
 #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
 #[cfg(test)]
 mod tests {
@@ -1662,12 +2407,362 @@ mod tests {
         let mut expected_sregs: SpecialRegisters = vcpu.get_sregs().unwrap();
         let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         configure_segments_and_sregs(&gm, &mut expected_sregs, BootProtocol::LinuxBoot).unwrap();
-        setup_page_tables(&gm, &mut expected_sregs).unwrap();
+        setup_page_tables(&gm, &mut expected_sregs, 0).unwrap();
 
-        setup_sregs(&gm, &vcpu, BootProtocol::LinuxBoot).unwrap();
+        setup_sregs(&gm, &vcpu, BootProtocol::LinuxBoot, 0).unwrap();
         let actual_sregs: SpecialRegisters = vcpu.get_sregs().unwrap();
         assert_eq!(expected_sregs, actual_sregs);
     }
+
+    #[test]
+    fn test_throttle_sleep() {
+        assert_eq!(throttle_sleep(0), Duration::from_millis(0));
+        assert_eq!(throttle_sleep(50), THROTTLE_TIMESLICE);
+        assert_eq!(throttle_sleep(80), THROTTLE_TIMESLICE * 4);
+        // the vCPUs always get to run
+        assert_eq!(throttle_sleep(100), throttle_sleep(MAX_THROTTLE_PERCENTAGE));
+        assert_eq!(throttle_sleep(99), THROTTLE_TIMESLICE * 99);
+    }
+
+    #[test]
+    fn test_tsc_frequency_supported() {
+        assert!(tsc_frequency_supported(2_000_000, 2_000_000, false));
+        // Hosts of the same model only calibrate their TSC within a few kHz.
+        assert!(tsc_frequency_supported(2_000_500, 2_000_000, false));
+        assert!(tsc_frequency_supported(1_999_500, 2_000_000, false));
+        assert!(!tsc_frequency_supported(2_000_501, 2_000_000, false));
+        assert!(!tsc_frequency_supported(2_400_000, 2_000_000, false));
+        assert!(tsc_frequency_supported(2_400_000, 2_000_000, true));
+    }
+
+    #[test]
+    fn test_set_tsc_khz() {
+        let hv = hypervisor::new().unwrap();
+        let vm = hv.create_vm().expect("new VM fd creation failed");
+        let vcpu = vm.create_vcpu(0, None).unwrap();
+        let host_tsc_khz = vcpu.tsc_khz().unwrap();
+
+        // A frequency within the tolerance doesn't need TSC scaling.
+        let tsc_khz = host_tsc_khz + host_tsc_khz / 10_000;
+        assert!(tsc_frequency_supported(tsc_khz, host_tsc_khz, false));
+        vcpu.set_tsc_khz(tsc_khz).unwrap();
+
+        let tsc_khz = host_tsc_khz / 2;
+        if vm.check_extension(hypervisor::kvm::Cap::TscControl) {
+            vcpu.set_tsc_khz(tsc_khz).unwrap();
+            assert_eq!(vcpu.tsc_khz().unwrap(), tsc_khz);
+        } else {
+            assert!(!tsc_frequency_supported(tsc_khz, host_tsc_khz, false));
+        }
+    }
+
+    #[test]
+    fn test_set_nested_virtualization() {
+        let leaves = |vmx: bool, svm: bool| {
+            CpuId::from_entries(&[
+                CpuIdEntry {
+                    function: 0x1,
+                    ecx: if vmx { 1 << VMX_ECX_BIT } else { 0 } | 1 << HYPERVISOR_ECX_BIT,
+                    ..Default::default()
+                },
+                CpuIdEntry {
+                    function: 0x8000_0001,
+                    ecx: if svm { 1 << SVM_ECX_BIT } else { 0 },
+                    ..Default::default()
+                },
+            ])
+        };
+
+        // Without a setting, the host's is kept.
+        let mut cpuid = leaves(true, false);
+        set_nested_virtualization(&mut cpuid, None).unwrap();
+        assert!(nested_virtualization(&cpuid));
+        let mut cpuid = leaves(false, false);
+        set_nested_virtualization(&mut cpuid, None).unwrap();
+        assert!(!nested_virtualization(&cpuid));
+
+        // Enabling it requires the host to expose VMX or SVM.
+        for (vmx, svm) in [(true, false), (false, true)].iter() {
+            let mut cpuid = leaves(*vmx, *svm);
+            set_nested_virtualization(&mut cpuid, Some(true)).unwrap();
+            assert!(nested_virtualization(&cpuid));
+        }
+        let mut cpuid = leaves(false, false);
+        assert!(matches!(
+            set_nested_virtualization(&mut cpuid, Some(true)),
+            Err(Error::NestedVirtUnsupported)
+        ));
+
+        // Disabling it hides both, leaving the other bits alone.
+        let mut cpuid = leaves(true, true);
+        set_nested_virtualization(&mut cpuid, Some(false)).unwrap();
+        assert!(!nested_virtualization(&cpuid));
+        assert_eq!(cpuid.as_slice()[0].ecx, 1 << HYPERVISOR_ECX_BIT);
+        assert_eq!(cpuid.as_slice()[1].ecx, 0);
+    }
+
+    #[test]
+    fn test_tsc_deadline_enabled() {
+        // Without a setting, the host's is used.
+        assert!(tsc_deadline_enabled(None, true).unwrap());
+        assert!(!tsc_deadline_enabled(None, false).unwrap());
+
+        // Enabling it requires the host to support it.
+        assert!(tsc_deadline_enabled(Some(true), true).unwrap());
+        assert!(matches!(
+            tsc_deadline_enabled(Some(true), false),
+            Err(Error::TscDeadlineUnsupported)
+        ));
+
+        // It can always be disabled.
+        assert!(!tsc_deadline_enabled(Some(false), true).unwrap());
+        assert!(!tsc_deadline_enabled(Some(false), false).unwrap());
+    }
+
+    #[test]
+    fn test_generate_common_cpuid_tsc_deadline() {
+        let hv = hypervisor::new().unwrap();
+        let host = host_tsc_deadline_timer(&hv);
+        let generate = |tsc_deadline| {
+            CpuManager::generate_common_cpuid(
+                hv.clone(),
+                &None,
+                None,
+                None,
+                46,
+                false,
+                None,
+                tsc_deadline,
+                &[],
+            )
+        };
+
+        assert_eq!(tsc_deadline_timer(&generate(None).unwrap()), host);
+        assert!(!tsc_deadline_timer(&generate(Some(false)).unwrap()));
+        if host {
+            assert!(tsc_deadline_timer(&generate(Some(true)).unwrap()));
+        } else {
+            assert!(matches!(
+                generate(Some(true)),
+                Err(Error::TscDeadlineUnsupported)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_generate_common_cpuid_leaves() {
+        let hv = hypervisor::new().unwrap();
+        let cpuid_leaves = vec![
+            CpuidLeafConfig {
+                function: 0x4000_0000,
+                index: 0,
+                action: CpuidLeafAction::Zero,
+            },
+            CpuidLeafConfig {
+                function: 0xb,
+                index: 0,
+                action: CpuidLeafAction::Values {
+                    eax: 1,
+                    ebx: 2,
+                    ecx: 3,
+                    edx: 4,
+                },
+            },
+        ];
+        let cpuid = CpuManager::generate_common_cpuid(
+            hv,
+            &None,
+            None,
+            None,
+            46,
+            false,
+            None,
+            None,
+            &cpuid_leaves,
+        )
+        .unwrap();
+        let leaf = |cpuid: &CpuId, function, index| {
+            cpuid
+                .as_slice()
+                .iter()
+                .filter(|c| c.function == function && c.index == index)
+                .map(|c| (c.eax, c.ebx, c.ecx, c.edx))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(leaf(&cpuid, 0x4000_0000, 0), vec![(0, 0, 0, 0)]);
+        assert_eq!(leaf(&cpuid, 0xb, 0), vec![(1, 2, 3, 4)]);
+
+        // The leaves from the configuration also take precedence over the
+        // x2APIC ID of the vCPU, unlike the other topology leaves.
+        let vcpu_cpuid = vcpu_cpuid(&cpuid, 3, &cpuid_leaves).unwrap();
+        assert_eq!(leaf(&vcpu_cpuid, 0xb, 0), vec![(1, 2, 3, 4)]);
+        for (_, _, _, edx) in leaf(&vcpu_cpuid, 0xb, 1) {
+            assert_eq!(edx, 3);
+        }
+        assert_eq!(vcpu_cpuid.as_slice().len(), cpuid.as_slice().len());
+    }
+
+    #[cfg(feature = "guest_debug")]
+    #[test]
+    fn test_read_vcpu_registers() {
+        let hv = hypervisor::new().unwrap();
+        let vm = hv.create_vm().expect("new VM fd creation failed");
+        let vcpu = vm.create_vcpu(0, None).unwrap();
+
+        let regs = StandardRegisters {
+            rax: 0x1234,
+            r15: 0x5678,
+            rip: 0x10_0000,
+            rsp: 0x8000,
+            rflags: 0x2,
+            ..Default::default()
+        };
+        vcpu.set_regs(&regs).unwrap();
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cr3 = 0x9000;
+        vcpu.set_sregs(&sregs).unwrap();
+        vcpu.set_cpuid2(&CpuId::from_entries(&[
+            CpuIdEntry {
+                function: 0x0,
+                eax: 0xd,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 0x1,
+                ecx: 1 << HYPERVISOR_ECX_BIT,
+                ..Default::default()
+            },
+        ]))
+        .unwrap();
+
+        let registers = read_vcpu_registers(vcpu.as_ref(), 4).unwrap();
+        assert_eq!(registers.rax, 0x1234);
+        assert_eq!(registers.r15, 0x5678);
+        assert_eq!(registers.rip, 0x10_0000);
+        assert_eq!(registers.rsp, 0x8000);
+        assert_eq!(registers.rflags, 0x2);
+        assert_eq!(registers.cr0, sregs.cr0);
+        assert_eq!(registers.cr3, 0x9000);
+        assert_eq!(registers.efer, sregs.efer);
+        assert_eq!(registers.apic_base, sregs.apic_base);
+        assert_eq!(registers.cpuid.len(), 2);
+        assert_eq!(registers.cpuid[0].function, 0x0);
+        assert_eq!(registers.cpuid[0].eax, 0xd);
+        assert_eq!(registers.cpuid[1].function, 0x1);
+        assert_eq!(registers.cpuid[1].ecx, 1 << HYPERVISOR_ECX_BIT);
+    }
+
+    #[test]
+    fn test_triple_fault_stops_vcpu() {
+        assert!(triple_fault_stops_vcpu(&[]));
+        assert!(triple_fault_stops_vcpu(&[CrashAction::Reboot]));
+        assert!(triple_fault_stops_vcpu(&[CrashAction::Shutdown]));
+        // The VM gets paused before being snapshotted or dumped.
+        assert!(!triple_fault_stops_vcpu(&[CrashAction::Pause]));
+        assert!(!triple_fault_stops_vcpu(&[
+            CrashAction::Dump(std::path::PathBuf::from("/tmp/crash.core")),
+            CrashAction::Event,
+            CrashAction::Reboot
+        ]));
+        assert!(!triple_fault_stops_vcpu(&[
+            CrashAction::Snapshot("file:///tmp/crash".to_owned()),
+            CrashAction::Shutdown
+        ]));
+    }
+
+    #[test]
+    fn test_wait_for_pause() {
+        let wait = |set: fn(&[Arc<AtomicBool>; 3])| {
+            let flags = [
+                Arc::new(AtomicBool::new(false)),
+                Arc::new(AtomicBool::new(false)),
+                Arc::new(AtomicBool::new(false)),
+            ];
+            let done = Arc::new(AtomicBool::new(false));
+            let waiter_flags = flags.clone();
+            let waiter_done = done.clone();
+            let waiter = thread::spawn(move || {
+                wait_for_pause(&waiter_flags[0], &waiter_flags[1], &waiter_flags[2]);
+                waiter_done.store(true, Ordering::SeqCst);
+            });
+
+            // The thread stays parked until a flag is set and it is unparked.
+            thread::sleep(Duration::from_millis(50));
+            waiter.thread().unpark();
+            thread::sleep(Duration::from_millis(50));
+            assert!(!done.load(Ordering::SeqCst));
+            set(&flags);
+            waiter.thread().unpark();
+            waiter.join().unwrap();
+        };
+
+        wait(|flags| flags[0].store(true, Ordering::SeqCst));
+        wait(|flags| flags[1].store(true, Ordering::SeqCst));
+        wait(|flags| flags[2].store(true, Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_queue_nmi() {
+        // Only vCPU 1 is running.
+        let mut vcpu_states: Vec<VcpuState> = (0..3).map(|_| VcpuState::default()).collect();
+        vcpu_states[1].handle = Some(thread::spawn(|| {}));
+
+        assert!(matches!(
+            queue_nmi(&vcpu_states, Some(0)),
+            Err(Error::InvalidVcpuId(0))
+        ));
+        assert!(matches!(
+            queue_nmi(&vcpu_states, Some(3)),
+            Err(Error::InvalidVcpuId(3))
+        ));
+        assert!(!vcpu_states[0].nmi_pending.load(Ordering::SeqCst));
+
+        let nmis = queue_nmi(&vcpu_states, Some(1)).unwrap();
+        assert_eq!(nmis.len(), 1);
+        assert_eq!(nmis[0].id, 1);
+        assert!(vcpu_states[1].nmi_pending.load(Ordering::SeqCst));
+
+        let nmis = queue_nmi(&vcpu_states, None).unwrap();
+        assert_eq!(nmis.len(), 1);
+        assert_eq!(nmis[0].id, 1);
+        assert!(!vcpu_states[0].nmi_pending.load(Ordering::SeqCst));
+        assert!(!vcpu_states[2].nmi_pending.load(Ordering::SeqCst));
+
+        vcpu_states[1].join_thread().unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_nmis() {
+        let nmi = |id| PendingNmi {
+            id,
+            pending: Arc::new(AtomicBool::new(true)),
+        };
+
+        // The vCPUs are kicked until they take the NMI.
+        let nmis = [nmi(0), nmi(1)];
+        let mut kicks = Vec::new();
+        wait_for_nmis(&nmis, Instant::now() + NMI_INJECTION_TIMEOUT, |id| {
+            kicks.push(id);
+            if kicks.len() % 2 == 0 {
+                nmis[usize::from(id)].pending.store(false, Ordering::SeqCst);
+            }
+        })
+        .unwrap();
+        assert_eq!(kicks, vec![0, 0, 1, 1]);
+
+        // An NMI not taken in time is no longer pending.
+        let nmis = [nmi(2)];
+        assert!(matches!(
+            wait_for_nmis(&nmis, Instant::now() + Duration::from_millis(10), |_| {}),
+            Err(Error::NmiTimeout(2))
+        ));
+        assert!(!nmis[0].pending.load(Ordering::SeqCst));
+
+        // An NMI taken by the deadline isn't reported as lost.
+        let nmis = [nmi(3)];
+        nmis[0].pending.store(false, Ordering::SeqCst);
+        wait_for_nmis(&nmis, Instant::now(), |_| panic!("vCPU kicked")).unwrap();
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
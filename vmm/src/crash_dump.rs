@@ -0,0 +1,269 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::CrashDumpConfig;
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+// Size of the chunks the content of the file is copied by on rotation.
+const ROTATE_CHUNK_SIZE: usize = 64 << 10;
+
+// Sizes of the ELF64 file and program headers.
+const ELF_HEADER_SIZE: u16 = 64;
+const ELF_PROGRAM_HEADER_SIZE: u16 = 56;
+const ET_CORE: u16 = 4;
+#[cfg(target_arch = "x86_64")]
+const EM_MACHINE: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const EM_MACHINE: u16 = 183;
+const PT_LOAD: u32 = 1;
+// Readable, writable and executable.
+const PF_RWX: u32 = 7;
+
+/// Host side of the virtio-console crash port, writing what the guest sends
+/// into a file of bounded size. The file is appended to, keeping the dumps
+/// from before a reboot of the VM. Once the file is full, it is either
+/// rotated, its content being copied to a file with a ".1" suffix, or left
+/// as is, the following data being dropped.
+///
+/// Both files are opened upfront, so that rotating them only needs the
+/// syscalls the seccomp filters of the console thread allow.
+pub struct CrashDumpFile {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    rotated: Option<File>,
+    size: u64,
+}
+
+impl CrashDumpFile {
+    pub fn new(config: &CrashDumpConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&config.file)?;
+        let size = file.metadata()?.len();
+        let rotated = if config.rotate {
+            Some(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .open(Self::rotated_path(&config.file))?,
+            )
+        } else {
+            None
+        };
+
+        Ok(CrashDumpFile {
+            path: config.file.clone(),
+            max_size: config.max_size,
+            file,
+            rotated,
+            size,
+        })
+    }
+
+    fn rotated_path(path: &Path) -> PathBuf {
+        let mut path = path.to_path_buf().into_os_string();
+        path.push(".1");
+        PathBuf::from(path)
+    }
+
+    fn rotate(file: &File, rotated: &File) -> io::Result<()> {
+        rotated.set_len(0)?;
+        let mut buf = vec![0u8; ROTATE_CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let len = file.read_at(&mut buf, offset)?;
+            if len == 0 {
+                break;
+            }
+            rotated.write_all_at(&buf[..len], offset)?;
+            offset += len as u64;
+        }
+        file.set_len(0)
+    }
+}
+
+impl Write for CrashDumpFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size >= self.max_size {
+            let rotated = match &self.rotated {
+                Some(rotated) => rotated,
+                // The data is dropped rather than failing the write, which
+                // would only get the guest to retry.
+                None => return Ok(buf.len()),
+            };
+
+            info!("Rotating crash dump file {:?}", self.path);
+            Self::rotate(&self.file, rotated)?;
+            self.size = 0;
+        }
+
+        let len = cmp::min(buf.len() as u64, self.max_size - self.size) as usize;
+        let written = self.file.write(&buf[..len])?;
+        self.size += written as u64;
+        if self.size >= self.max_size && self.rotated.is_none() {
+            warn!(
+                "Crash dump file {:?} is full, dropping any further output",
+                self.path
+            );
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes the guest memory as an ELF core, each region being loaded at its
+/// guest physical address, for the crash dump of a paused VM to be inspected
+/// with the usual tools.
+pub fn write_memory_dump<W: Write>(memory: &GuestMemoryMmap, writer: &mut W) -> io::Result<()> {
+    let regions: Vec<(u64, u64)> = memory.iter().map(|r| (r.start_addr().0, r.len())).collect();
+
+    let mut header = Vec::with_capacity(ELF_HEADER_SIZE as usize);
+    header.extend_from_slice(b"\x7fELF");
+    // 64-bit, little endian, current version, System V ABI.
+    header.extend_from_slice(&[2, 1, 1, 0]);
+    header.resize(16, 0);
+    header.extend_from_slice(&ET_CORE.to_le_bytes());
+    header.extend_from_slice(&EM_MACHINE.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    // No entry point, the program headers follow the file header, no
+    // section headers nor flags.
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&u64::from(ELF_HEADER_SIZE).to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&ELF_HEADER_SIZE.to_le_bytes());
+    header.extend_from_slice(&ELF_PROGRAM_HEADER_SIZE.to_le_bytes());
+    header.extend_from_slice(&(regions.len() as u16).to_le_bytes());
+    header.extend_from_slice(&[0; 6]);
+
+    let mut offset =
+        u64::from(ELF_HEADER_SIZE) + u64::from(ELF_PROGRAM_HEADER_SIZE) * regions.len() as u64;
+    for (addr, len) in regions.iter() {
+        header.extend_from_slice(&PT_LOAD.to_le_bytes());
+        header.extend_from_slice(&PF_RWX.to_le_bytes());
+        header.extend_from_slice(&offset.to_le_bytes());
+        // No virtual address, the guest paging being unknown.
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&addr.to_le_bytes());
+        header.extend_from_slice(&len.to_le_bytes());
+        header.extend_from_slice(&len.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        offset += len;
+    }
+    writer.write_all(&header)?;
+
+    for (addr, len) in regions {
+        memory
+            .write_all_to(GuestAddress(addr), writer, len as usize)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::fs;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_memory_dump() {
+        let memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x10000), 0x2000),
+        ])
+        .unwrap();
+        memory
+            .write_obj(0x1234_5678u32, GuestAddress(0x10))
+            .unwrap();
+        memory
+            .write_obj(0x9abc_def0u32, GuestAddress(0x11ff8))
+            .unwrap();
+
+        let mut dump = Vec::new();
+        write_memory_dump(&memory, &mut dump).unwrap();
+
+        let u16_at = |o: usize| u16::from_le_bytes(dump[o..o + 2].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_le_bytes(dump[o..o + 8].try_into().unwrap());
+        assert_eq!(&dump[..4], b"\x7fELF");
+        assert_eq!(u16_at(16), ET_CORE);
+        assert_eq!(u16_at(56), 2);
+
+        // Each region follows the program headers, in order.
+        let data = 64 + 2 * 56;
+        assert_eq!(dump.len(), data + 0x3000);
+        for (i, (offset, addr, len)) in [(data, 0, 0x1000), (data + 0x1000, 0x10000, 0x2000)]
+            .iter()
+            .enumerate()
+        {
+            let phdr = 64 + i * 56;
+            assert_eq!(u64_at(phdr + 8), *offset as u64);
+            assert_eq!(u64_at(phdr + 24), *addr);
+            assert_eq!(u64_at(phdr + 32), *len);
+            assert_eq!(u64_at(phdr + 40), *len);
+        }
+        assert_eq!(
+            &dump[data + 0x10..data + 0x14],
+            &0x1234_5678u32.to_le_bytes()
+        );
+        let end = data + 0x1000 + 0x1ff8;
+        assert_eq!(&dump[end..end + 4], &0x9abc_def0u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_crash_dump_file() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let mut config = CrashDumpConfig {
+            file: dir.as_path().join("crash"),
+            max_size: 8,
+            rotate: true,
+        };
+        let rotated = dir.as_path().join("crash.1");
+
+        // the file is rotated once full
+        let mut f = CrashDumpFile::new(&config).unwrap();
+        f.write_all(b"0123456789").unwrap();
+        assert_eq!(fs::read(&rotated).unwrap(), b"01234567");
+        assert_eq!(fs::read(&config.file).unwrap(), b"89");
+        f.write_all(b"abcdefgh").unwrap();
+        assert_eq!(fs::read(&rotated).unwrap(), b"89abcdef");
+        assert_eq!(fs::read(&config.file).unwrap(), b"gh");
+
+        // the content is kept when the file is opened again, on reboot
+        drop(f);
+        let mut f = CrashDumpFile::new(&config).unwrap();
+        f.write_all(b"ijklmn").unwrap();
+        assert_eq!(fs::read(&rotated).unwrap(), b"89abcdef");
+        assert_eq!(fs::read(&config.file).unwrap(), b"ghijklmn");
+        f.write_all(b"o").unwrap();
+        assert_eq!(fs::read(&rotated).unwrap(), b"ghijklmn");
+        assert_eq!(fs::read(&config.file).unwrap(), b"o");
+
+        // or the following data is dropped
+        config.rotate = false;
+        fs::remove_file(&config.file).unwrap();
+        fs::remove_file(&rotated).unwrap();
+        let mut f = CrashDumpFile::new(&config).unwrap();
+        f.write_all(b"0123456789").unwrap();
+        f.write_all(b"abcdefgh").unwrap();
+        assert_eq!(fs::read(&config.file).unwrap(), b"01234567");
+        assert!(!rotated.exists());
+    }
+}
@@ -11,8 +11,13 @@
 
 use crate::config::ConsoleOutputMode;
 use crate::config::DeviceConfig;
-use crate::config::{DiskConfig, FsConfig, NetConfig, PmemConfig, VmConfig, VsockConfig};
-use crate::device_tree::{DeviceNode, DeviceTree};
+use crate::config::{
+    DiskConfig, FsConfig, GpioConfig, InputConfig, InputKind, IoPriority, NetConfig, PmemConfig,
+    RngSeedMode, UserDeviceConfig, VirtioTransportType, VmConfig, VsockConfig,
+    DEFAULT_DISK_QUEUE_DEPTH,
+};
+use crate::crash_dump::CrashDumpFile;
+use crate::device_tree::{DependencyError, DeviceNode, DeviceTree};
 #[cfg(feature = "kvm")]
 use crate::interrupt::kvm::KvmMsiInterruptManager as MsiInterruptManager;
 #[cfg(feature = "mshv")]
@@ -21,6 +26,8 @@ use crate::interrupt::LegacyUserspaceInterruptManager;
 #[cfg(feature = "acpi")]
 use crate::memory_manager::MEMORY_MANAGER_ACPI_SIZE;
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
+use crate::pci_segment::{pci_bdf, pci_bdf_device, pci_bdf_segment, PciSegment};
+use crate::tcp_console::TcpConsole;
 #[cfg(feature = "acpi")]
 use crate::vm::NumaNodes;
 use crate::PciDeviceInfo;
@@ -32,7 +39,6 @@ use anyhow::anyhow;
 use arch::aarch64::gic::GICDevice;
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::DeviceInfoForFDT;
-#[cfg(feature = "acpi")]
 use arch::layout;
 #[cfg(target_arch = "x86_64")]
 use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
@@ -40,16 +46,17 @@ use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
 use arch::DeviceType;
 use block_util::{
     async_io::DiskFile, block_io_uring_is_supported, detect_image_type,
-    fixed_vhd_async::FixedVhdDiskAsync, fixed_vhd_sync::FixedVhdDiskSync, qcow_sync::QcowDiskSync,
-    raw_async::RawFileDisk, raw_sync::RawFileDiskSync, ImageType,
+    fixed_vhd_async::FixedVhdDiskAsync, fixed_vhd_sync::FixedVhdDiskSync, overlay::OverlayDiskFile,
+    qcow_sync::QcowDiskSync, raw_async::RawFileDisk, raw_sync::RawFileDiskSync,
+    read_ahead::ReadAheadDiskFile, ImageType,
 };
 #[cfg(target_arch = "aarch64")]
 use devices::gic;
 #[cfg(target_arch = "x86_64")]
 use devices::ioapic;
 use devices::{
-    interrupt_controller, interrupt_controller::InterruptController, legacy::Serial,
-    AcpiNotificationFlags,
+    interrupt_controller, interrupt_controller::InterruptController, legacy::RealTimeClock,
+    legacy::Serial, AcpiNotificationFlags, VirtualClock,
 };
 #[cfg(feature = "kvm")]
 use hypervisor::kvm_ioctls::*;
@@ -61,52 +68,64 @@ use libc::{
     isatty, tcgetattr, tcsetattr, termios, ECHO, ICANON, ISIG, MAP_NORESERVE, MAP_PRIVATE,
     MAP_SHARED, O_TMPFILE, PROT_READ, PROT_WRITE, TCSANOW, TIOCGWINSZ,
 };
-use pci::{
-    DeviceRelocation, PciBarRegionType, PciBus, PciConfigIo, PciConfigMmio, PciDevice, PciRoot,
-    VfioPciDevice,
-};
+use nvme::NvmePciDevice;
+use pci::{DeviceRelocation, PciBarRegionType, PciDevice, VfioPciDevice, VfioUserPciDevice};
 use seccomp::SeccompAction;
 use std::any::Any;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs::{read_link, File, OpenOptions};
 use std::io::{self, sink, stdout, Seek, SeekFrom};
 use std::mem::zeroed;
 use std::num::Wrapping;
-use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::{Arc, Barrier, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 #[cfg(feature = "kvm")]
 use vfio_ioctls::{VfioContainer, VfioDevice, VfioDmaMapping};
-use virtio_devices::transport::VirtioPciDevice;
 use virtio_devices::transport::VirtioTransport;
-use virtio_devices::vhost_user::VhostUserConfig;
+use virtio_devices::transport::{
+    VirtioMmioDevice, VirtioPciDevice, VirtioPciIdentity, VIRTIO_MMIO_SIZE,
+};
+use virtio_devices::vhost_user::{NetOffloads, VhostUserConfig};
 use virtio_devices::{DmaRemapping, IommuMapping};
 use virtio_devices::{VirtioSharedMemory, VirtioSharedMemoryList};
-use vm_allocator::SystemAllocator;
+use vm_allocator::{AddressAllocator, SystemAllocator};
 use vm_device::interrupt::{
-    InterruptIndex, InterruptManager, LegacyIrqGroupConfig, MsiIrqGroupConfig,
+    InterruptIndex, InterruptManager, InterruptSourceGroup, LegacyIrqGroupConfig, MsiIrqGroupConfig,
 };
 use vm_device::{Bus, BusDevice, Resource};
 use vm_memory::guest_memory::FileOffset;
 use vm_memory::{
-    Address, GuestAddress, GuestAddressSpace, GuestRegionMmap, GuestUsize, MmapRegion,
+    Address, GuestAddress, GuestAddressSpace, GuestMemory, GuestRegionMmap, GuestUsize, MmapRegion,
 };
+use vm_migration::protocol::MemoryRangeTable;
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
 };
-use vm_virtio::{VirtioDeviceType, VirtioIommuRemapping};
+use vm_virtio::{DescriptorInspector, VirtioDeviceType, VirtioIommuRemapping};
 use vmm_sys_util::eventfd::EventFd;
 
 #[cfg(target_arch = "aarch64")]
 const MMIO_LEN: u64 = 0x1000;
 
+// Window of the 32 bits MMIO hole the registers of the virtio-mmio devices
+// are allocated from, room for 256 of them.
+const VIRTIO_MMIO_WINDOW_SIZE: u64 = 0x10_0000;
+
+// I/O port of the pvpanic device, as QEMU places it.
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+const PVPANIC_PORT: u16 = 0x505;
+
 #[cfg(feature = "kvm")]
 const VFIO_DEVICE_NAME_PREFIX: &str = "_vfio";
 
+const VFIO_USER_DEVICE_NAME_PREFIX: &str = "_vfio_user";
+
 #[cfg(target_arch = "x86_64")]
 const IOAPIC_DEVICE_NAME: &str = "_ioapic";
 
@@ -115,6 +134,8 @@ const SERIAL_DEVICE_NAME_PREFIX: &str = "_serial";
 const CONSOLE_DEVICE_NAME: &str = "_console";
 const DISK_DEVICE_NAME_PREFIX: &str = "_disk";
 const FS_DEVICE_NAME_PREFIX: &str = "_fs";
+const INPUT_DEVICE_NAME_PREFIX: &str = "_input";
+const GPIO_DEVICE_NAME_PREFIX: &str = "_gpio";
 const MEM_DEVICE_NAME_PREFIX: &str = "_mem";
 const BALLOON_DEVICE_NAME: &str = "_balloon";
 const NET_DEVICE_NAME_PREFIX: &str = "_net";
@@ -122,10 +143,26 @@ const PMEM_DEVICE_NAME_PREFIX: &str = "_pmem";
 const RNG_DEVICE_NAME: &str = "_rng";
 const VSOCK_DEVICE_NAME_PREFIX: &str = "_vsock";
 const WATCHDOG_DEVICE_NAME: &str = "_watchdog";
+#[cfg(feature = "acpi")]
+const BATTERY_DEVICE_NAME: &str = "_battery";
 
 const IOMMU_DEVICE_NAME: &str = "_iommu";
 
 const VIRTIO_PCI_DEVICE_NAME_PREFIX: &str = "_virtio-pci";
+const VIRTIO_MMIO_DEVICE_NAME_PREFIX: &str = "_virtio-mmio";
+
+/// Stage of the life of a device, telling where a device failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceStage {
+    /// Creating the device from its configuration.
+    Create,
+    /// Placing the device on the PCI bus.
+    Plug,
+    /// Activating the device once the guest driver is ready.
+    Activate,
+    /// Removing the device from the VM.
+    Remove,
+}
 
 /// Errors associated with device manager
 #[derive(Debug)]
@@ -139,6 +176,9 @@ pub enum DeviceManagerError {
     /// Cannot create vhost-user-net device
     CreateVhostUserNet(virtio_devices::vhost_user::Error),
 
+    /// Cannot create vhost-net device
+    CreateVhostKernelNet(virtio_devices::vhost_kernel::Error),
+
     /// Cannot create virtio-blk device
     CreateVirtioBlock(io::Error),
 
@@ -181,9 +221,27 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-watchdog device
     CreateVirtioWatchdog(io::Error),
 
+    /// Cannot create virtio-input device
+    CreateVirtioInput(io::Error),
+
+    /// Cannot create virtio-gpio device
+    CreateVirtioGpio(io::Error),
+
     /// Failed parsing disk image format
     DetectImageType(io::Error),
 
+    /// Cannot find the type of the disk path
+    DiskFileType(io::Error),
+
+    /// Disk path is neither a regular file nor a block device
+    UnsupportedDiskFileType(PathBuf),
+
+    /// Only RAW images can be used as the base of an overlay
+    UnsupportedOverlayImageType(PathBuf),
+
+    /// Cannot open or create the disk overlay
+    OverlayDisk(io::Error),
+
     /// Cannot open qcow disk path
     QcowDeviceCreate(qcow::Error),
 
@@ -193,6 +251,9 @@ pub enum DeviceManagerError {
     /// Cannot allocate IRQ.
     AllocateIrq,
 
+    /// Legacy interrupt manager not created yet.
+    NoLegacyInterruptManager,
+
     /// Cannot configure the IRQ.
     Irq(vmm_sys_util::errno::Error),
 
@@ -232,6 +293,9 @@ pub enum DeviceManagerError {
     /// Error creating console output file
     ConsoleOutputFileOpen(io::Error),
 
+    /// Cannot open the crash dump file
+    CrashDumpFileOpen(io::Error),
+
     /// Error creating serial pty
     SerialPtyOpen(io::Error),
 
@@ -241,6 +305,12 @@ pub enum DeviceManagerError {
     /// Error setting pty raw mode
     SetPtyRaw(vmm_sys_util::errno::Error),
 
+    /// Error serving the serial port over TCP
+    SerialTcpListen(io::Error),
+
+    /// Error serving the console over TCP
+    ConsoleTcpListen(io::Error),
+
     /// Error getting pty peer
     GetPtyPeer(vmm_sys_util::errno::Error),
 
@@ -253,6 +323,24 @@ pub enum DeviceManagerError {
     /// Failed to map VFIO MMIO region.
     VfioMapRegion(pci::VfioPciError),
 
+    /// Cannot connect to the vfio-user server
+    VfioUserCreateClient(vfio_user::Error),
+
+    /// Cannot create a vfio-user PCI device
+    VfioUserCreate(pci::VfioUserPciDeviceError),
+
+    /// Failed to DMA map guest memory for a vfio-user device.
+    VfioUserDmaMap(pci::VfioUserPciDeviceError),
+
+    /// Cannot create an NVMe controller
+    CreateNvme(nvme::Error),
+
+    /// NVMe disks can't be hotplugged
+    NvmeHotplugNotSupported,
+
+    /// Devices on the virtio-mmio transport can't be hotplugged
+    MmioHotplugNotSupported,
+
     /// Failed to create the passthrough device.
     CreatePassthroughDevice(anyhow::Error),
 
@@ -301,6 +389,12 @@ pub enum DeviceManagerError {
     /// Missing PCI bus.
     NoPciBus,
 
+    /// Unknown PCI segment.
+    InvalidPciSegment(u16),
+
+    /// Failed to allocate the memory windows of the PCI segments.
+    AllocatePciSegmentWindows,
+
     /// Could not find an available device name.
     NoAvailableDeviceName,
 
@@ -325,6 +419,25 @@ pub enum DeviceManagerError {
     /// Failed to find device corresponding to the given identifier.
     UnknownDeviceId(String),
 
+    /// Not allowed to reset this device, only virtio PCI devices can be reset.
+    ResetNotAllowed(String),
+
+    /// Failed resetting a virtio device.
+    ResetVirtioDevice(virtio_devices::Error),
+
+    /// Not allowed to pause or resume this device, only virtio PCI devices
+    /// can be.
+    PauseNotAllowed(String),
+
+    /// Failed pausing a device.
+    PauseDevice(MigratableError),
+
+    /// Failed resuming a device.
+    ResumeDevice(MigratableError),
+
+    /// Not allowed to remove a paused device.
+    RemovalOfPausedDevice(String),
+
     /// Failed to find an available PCI device ID.
     NextPciDeviceId(pci::PciRootError),
 
@@ -355,6 +468,9 @@ pub enum DeviceManagerError {
     /// Failed updating guest memory for VFIO PCI device.
     UpdateMemoryForVfioPciDevice(pci::VfioPciError),
 
+    /// Failed updating guest memory for vfio-user PCI device.
+    UpdateMemoryForVfioUserPciDevice(pci::VfioUserPciDeviceError),
+
     /// Trying to use a directory for pmem but no size specified
     PmemWithDirectorySizeMissing,
 
@@ -385,9 +501,55 @@ pub enum DeviceManagerError {
     /// Missing virtio-balloon, can't proceed as expected.
     MissingVirtioBalloon,
 
+    /// Missing RTC device, can't read or set the guest time.
+    MissingRtc,
+
+    /// No virtio-input device with this identifier.
+    MissingVirtioInput(String),
+
+    /// Failed injecting events into a virtio-input device.
+    InjectInputEvents(io::Error),
+
+    /// No virtio-gpio device with this identifier.
+    MissingVirtioGpio(String),
+
+    /// Failed driving a line of a virtio-gpio device.
+    SetGpioLine(io::Error),
+
+    /// No virtio-net device with this identifier.
+    MissingVirtioNet(String),
+
+    /// Failed to start capturing the frames of a virtio-net device.
+    StartNetCapture(io::Error),
+
+    /// Failed to program the flow rules of a virtio-net device.
+    SetFlowRules(net_util::FlowSteeringError),
+
+    /// No vhost-user-net device with this identifier.
+    MissingVhostUserNet(String),
+
+    /// Failed to change the number of active queues of a vhost-user-net
+    /// device.
+    SetNetQueues(virtio_devices::vhost_user::Error),
+
+    /// Failed to spawn the thread dispatching the device events.
+    DeviceEventDispatcher(io::Error),
+
     /// Failed to do power button notification
     PowerButtonNotification(io::Error),
 
+    /// The VM has no battery.
+    MissingBattery,
+
+    /// The battery charge is not a percentage.
+    InvalidBatteryCharge(u8),
+
+    /// Failed to notify the guest of the new battery state.
+    BatteryNotification(io::Error),
+
+    /// Failed to connect to the vTPM emulator.
+    CreateTpm(devices::tpm::Error),
+
     /// Failed to set O_DIRECT flag to file descriptor
     SetDirectIo,
 
@@ -396,11 +558,93 @@ pub enum DeviceManagerError {
 
     /// Failed to create FixedVhdDiskSync
     CreateFixedVhdDiskSync(io::Error),
+
+    /// Failed ordering the devices following their dependencies.
+    DeviceDependencies(DependencyError),
+
+    /// Failed activating a virtio device.
+    ActivateVirtioDevice(virtio_devices::ActivateError),
+
+    /// A device failed at some stage, the underlying error telling why.
+    Device {
+        id: Option<String>,
+        device_type: String,
+        stage: DeviceStage,
+        error: Box<DeviceManagerError>,
+    },
 }
 pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
+impl DeviceManagerError {
+    /// Tells which device failed, and at which stage, for an error coming
+    /// from the device. Errors already identifying their device are kept
+    /// as they are.
+    fn device<T: ToString>(
+        id: Option<String>,
+        device_type: T,
+        stage: DeviceStage,
+    ) -> impl FnOnce(DeviceManagerError) -> DeviceManagerError {
+        move |error| match error {
+            error @ DeviceManagerError::Device { .. } => error,
+            error => DeviceManagerError::Device {
+                id,
+                device_type: device_type.to_string(),
+                stage,
+                error: Box::new(error),
+            },
+        }
+    }
+}
+
 type VirtioDeviceArc = Arc<Mutex<dyn virtio_devices::VirtioDevice>>;
 
+/// Connections to the vhost-user-net backends, by device identifier.
+pub type VhostUserBackends = HashMap<String, virtio_devices::vhost_user::NetBackend>;
+
+// A virtio device along with the information needed to plug it on the PCI
+// topology, or on the virtio-mmio transport.
+#[derive(Clone)]
+struct MetaVirtioDevice {
+    virtio_device: VirtioDeviceArc,
+    iommu: bool,
+    id: String,
+    pci_segment: u16,
+    // Guest NUMA node the device is local to.
+    numa_node: Option<u32>,
+    pci_identity: VirtioPciIdentity,
+    transport: VirtioTransportType,
+}
+
+// A device on the virtio-mmio transport, along with the resources described
+// to the guest.
+#[cfg_attr(not(feature = "acpi"), allow(dead_code))]
+struct VirtioMmioDeviceHandle {
+    device: Arc<Mutex<VirtioMmioDevice>>,
+    id: String,
+    addr: u64,
+    irq: u32,
+}
+
+impl From<&DiskConfig> for VirtioPciIdentity {
+    fn from(disk_cfg: &DiskConfig) -> Self {
+        VirtioPciIdentity {
+            subsystem_vendor_id: disk_cfg.pci_subsystem_vendor_id,
+            subsystem_id: disk_cfg.pci_subsystem_id,
+            serial: disk_cfg.pci_serial.clone(),
+        }
+    }
+}
+
+impl From<&NetConfig> for VirtioPciIdentity {
+    fn from(net_cfg: &NetConfig) -> Self {
+        VirtioPciIdentity {
+            subsystem_vendor_id: net_cfg.pci_subsystem_vendor_id,
+            subsystem_id: net_cfg.pci_subsystem_id,
+            serial: net_cfg.pci_serial.clone(),
+        }
+    }
+}
+
 #[cfg(feature = "acpi")]
 const DEVICE_MANAGER_ACPI_SIZE: usize = 0x10;
 
@@ -535,13 +779,37 @@ impl Console {
     }
 }
 
-struct AddressManager {
+pub(crate) struct AddressManager {
     allocator: Arc<Mutex<SystemAllocator>>,
     #[cfg(target_arch = "x86_64")]
-    io_bus: Arc<Bus>,
-    mmio_bus: Arc<Bus>,
+    pub(crate) io_bus: Arc<Bus>,
+    pub(crate) mmio_bus: Arc<Bus>,
     vm: Arc<dyn hypervisor::Vm>,
     device_tree: Arc<Mutex<DeviceTree>>,
+    // Memory windows of the PCI segments, the PCI BARs are allocated from.
+    pci_mmio_allocators: Vec<Arc<Mutex<AddressAllocator>>>,
+    pci_mmio_hole_allocators: Vec<Arc<Mutex<AddressAllocator>>>,
+}
+
+impl AddressManager {
+    // Find the window of the PCI segment containing the given address.
+    fn pci_allocator(
+        allocators: &[Arc<Mutex<AddressAllocator>>],
+        addr: u64,
+    ) -> std::result::Result<&Arc<Mutex<AddressAllocator>>, std::io::Error> {
+        allocators
+            .iter()
+            .find(|allocator| {
+                let allocator = allocator.lock().unwrap();
+                addr >= allocator.base().0 && addr <= allocator.end().0
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("no PCI segment window containing 0x{:x}", addr),
+                )
+            })
+    }
 }
 
 impl DeviceRelocation for AddressManager {
@@ -584,21 +852,17 @@ impl DeviceRelocation for AddressManager {
                 error!("I/O region is not supported");
             }
             PciBarRegionType::Memory32BitRegion | PciBarRegionType::Memory64BitRegion => {
-                // Update system allocator
+                // Update the allocator of the PCI segment the BAR belongs to
                 if region_type == PciBarRegionType::Memory32BitRegion {
-                    self.allocator
-                        .lock()
-                        .unwrap()
-                        .free_mmio_hole_addresses(GuestAddress(old_base), len as GuestUsize);
+                    let mut allocator =
+                        Self::pci_allocator(&self.pci_mmio_hole_allocators, old_base)?
+                            .lock()
+                            .unwrap();
 
-                    self.allocator
-                        .lock()
-                        .unwrap()
-                        .allocate_mmio_hole_addresses(
-                            Some(GuestAddress(new_base)),
-                            len as GuestUsize,
-                            None,
-                        )
+                    allocator.free(GuestAddress(old_base), len as GuestUsize);
+
+                    allocator
+                        .allocate(Some(GuestAddress(new_base)), len as GuestUsize, None)
                         .ok_or_else(|| {
                             io::Error::new(
                                 io::ErrorKind::Other,
@@ -606,19 +870,14 @@ impl DeviceRelocation for AddressManager {
                             )
                         })?;
                 } else {
-                    self.allocator
+                    let mut allocator = Self::pci_allocator(&self.pci_mmio_allocators, old_base)?
                         .lock()
-                        .unwrap()
-                        .free_mmio_addresses(GuestAddress(old_base), len as GuestUsize);
+                        .unwrap();
 
-                    self.allocator
-                        .lock()
-                        .unwrap()
-                        .allocate_mmio_addresses(
-                            Some(GuestAddress(new_base)),
-                            len as GuestUsize,
-                            None,
-                        )
+                    allocator.free(GuestAddress(old_base), len as GuestUsize);
+
+                    allocator
+                        .allocate(Some(GuestAddress(new_base)), len as GuestUsize, None)
                         .ok_or_else(|| {
                             io::Error::new(
                                 io::ErrorKind::Other,
@@ -752,10 +1011,48 @@ impl DeviceRelocation for AddressManager {
     }
 }
 
+// Logs the changes the guest makes to the lines of a virtio-gpio device,
+// such as a status LED being turned on.
+struct GpioLogger {
+    id: String,
+}
+
+impl virtio_devices::GpioObserver for GpioLogger {
+    fn line_changed(&self, line: u16, state: &virtio_devices::GpioLineState) {
+        info!(
+            "{}: GPIO line {} is now {:?} with value {}",
+            self.id, line, state.direction, state.value as u8
+        );
+    }
+}
+
+/// I/O priority of the workers of a disk, as reported with the VM info.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct DiskIoPriorityInfo {
+    pub id: String,
+    pub io_priority: IoPriority,
+    /// Whether all the workers of the device run with the I/O priority.
+    pub applied: bool,
+    /// Active I/O scheduler of the host block device holding the disk
+    /// image, if it sits on one.
+    pub host_scheduler: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct DeviceManagerState {
     device_tree: DeviceTree,
     device_id_cnt: Wrapping<usize>,
+    // Offset in seconds of the guest RTC time from the host time, so that
+    // a time set by the guest or through the API survives a restore.
+    #[serde(default)]
+    rtc_offset: Option<i64>,
+}
+
+fn host_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
 }
 
 /// Private structure for storing information about the MMIO device registered at some address on the bus.
@@ -793,6 +1090,12 @@ pub struct DeviceManager {
     // serial PTY
     serial_pty: Option<Arc<Mutex<(File, File)>>>,
 
+    // console served over TCP
+    console_tcp: Option<Arc<TcpConsole>>,
+
+    // serial served over TCP
+    serial_tcp: Option<Arc<TcpConsole>>,
+
     // Interrupt controller
     #[cfg(target_arch = "x86_64")]
     interrupt_controller: Option<Arc<Mutex<ioapic::Ioapic>>>,
@@ -809,6 +1112,14 @@ pub struct DeviceManager {
     #[cfg(feature = "acpi")]
     ged_notification_device: Option<Arc<Mutex<devices::AcpiGEDDevice>>>,
 
+    // ACPI battery and AC adapter
+    #[cfg(feature = "acpi")]
+    battery_device: Option<Arc<Mutex<devices::AcpiBatteryDevice>>>,
+
+    // vTPM, through its CRB interface
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    tpm_device: Option<Arc<Mutex<devices::tpm::Tpm>>>,
+
     // VM configuration
     config: Arc<Mutex<VmConfig>>,
 
@@ -816,7 +1127,13 @@ pub struct DeviceManager {
     memory_manager: Arc<Mutex<MemoryManager>>,
 
     // The virtio devices on the system
-    virtio_devices: Vec<(VirtioDeviceArc, bool, String)>,
+    virtio_devices: Vec<MetaVirtioDevice>,
+
+    // The devices on the virtio-mmio transport
+    virtio_mmio_devices: Vec<VirtioMmioDeviceHandle>,
+
+    // Allocator of the registers of the virtio-mmio devices
+    virtio_mmio_allocator: AddressAllocator,
 
     // List of bus devices
     // Let the DeviceManager keep strong references to the BusDevice devices.
@@ -827,8 +1144,21 @@ pub struct DeviceManager {
     // Counter to keep track of the consumed device IDs.
     device_id_cnt: Wrapping<usize>,
 
-    // Keep a reference to the PCI bus
-    pci_bus: Option<Arc<Mutex<PciBus>>>,
+    // PCI segments, each of them holding its own PCI bus
+    pci_segments: Vec<PciSegment>,
+
+    // PCI segment the hotplug registers currently refer to
+    selected_segment: usize,
+
+    // Clock the emulated time sources read from
+    #[cfg_attr(
+        all(target_arch = "x86_64", not(any(feature = "acpi", feature = "cmos"))),
+        allow(dead_code)
+    )]
+    clock: VirtualClock,
+
+    // RTC device the host can read and set the guest time through
+    rtc_device: Option<Arc<Mutex<dyn RealTimeClock>>>,
 
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     // MSI Interrupt Manager
@@ -843,25 +1173,23 @@ pub struct DeviceManager {
     // Paravirtualized IOMMU
     iommu_device: Option<Arc<Mutex<virtio_devices::Iommu>>>,
 
-    // Bitmap of PCI devices to hotplug.
-    pci_devices_up: u32,
-
-    // Bitmap of PCI devices to hotunplug.
-    pci_devices_down: u32,
-
     // Hashmap of device's name to their corresponding PCI b/d/f.
     pci_id_list: HashMap<String, u32>,
 
+    // Hashmap of the name of the devices left out until activated through
+    // the API to the PCI b/d/f reserved for them.
+    cold_devices: HashMap<String, u32>,
+
     // Hashmap of PCI b/d/f to their corresponding Arc<Mutex<dyn PciDevice>>.
     pci_devices: HashMap<u32, Arc<dyn Any + Send + Sync>>,
 
-    // BTreeMap of PCI b/d/f to their allocated IRQ.
-    pci_device_irqs: BTreeMap<u32, u32>,
-
     // Tree of devices, representing the dependencies between devices.
     // Useful for introspection, snapshot and restore.
     device_tree: Arc<Mutex<DeviceTree>>,
 
+    // Devices paused on their own, which stay paused when the VM resumes.
+    paused_devices: PausedDevices,
+
     // Exit event
     #[cfg(feature = "acpi")]
     exit_evt: EventFd,
@@ -881,10 +1209,49 @@ pub struct DeviceManager {
     // Possible handle to the virtio-balloon device
     balloon: Option<Arc<Mutex<virtio_devices::Balloon>>>,
 
+    // Handles to the virtio-mem devices
+    virtio_mem_devices: Vec<Arc<Mutex<virtio_devices::Mem>>>,
+
+    // Handles to the virtio-input devices, by identifier
+    input_devices: HashMap<String, Arc<Mutex<virtio_devices::Input>>>,
+
+    // Handles to the virtio-gpio devices, by identifier
+    gpio_devices: HashMap<String, Arc<Mutex<virtio_devices::Gpio>>>,
+
+    // Captures of the frames of the virtio-net devices, by identifier
+    net_captures: HashMap<String, net_util::PacketCapture>,
+
+    // Flow steering of the virtio-net devices, by identifier
+    net_flow_steerings: HashMap<String, net_util::FlowSteering>,
+
+    // Handles to the vhost-user-net devices, by identifier
+    vhost_user_net_devices: HashMap<String, Arc<Mutex<virtio_devices::vhost_user::Net>>>,
+
+    // Handles to the virtio-block devices given an I/O priority, along with
+    // it, by identifier
+    io_priority_block_devices: HashMap<String, (Arc<Mutex<virtio_devices::Block>>, IoPriority)>,
+
+    // Connections to the vhost-user-net backends of the devices of the
+    // rebooted VM, to be taken over by the devices with the same identifier
+    vhost_user_backends: VhostUserBackends,
+
+    // Cap on the descriptor chains the virtio devices hold in flight
+    inflight_limit: virtio_devices::InflightLimit,
+
+    // Dispatcher of the activation, pause and reset events of the devices
+    device_events: virtio_devices::DeviceEventDispatcher,
+
     // Virtio Device activation EventFd to allow the VMM thread to trigger device
     // activation and thus start the threads from the VMM thread
     activate_evt: EventFd,
 
+    // Signaled when the guest closes a connection on the vsock completion port
+    vsock_completion_evt: EventFd,
+
+    // Signaled when the guest reports a panic through the pvpanic device
+    #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
+    guest_panic_evt: EventFd,
+
     #[cfg(feature = "acpi")]
     acpi_address: GuestAddress,
 }
@@ -900,18 +1267,98 @@ impl DeviceManager {
         seccomp_action: SeccompAction,
         #[cfg(feature = "acpi")] numa_nodes: NumaNodes,
         activate_evt: &EventFd,
+        vsock_completion_evt: &EventFd,
+        guest_panic_evt: &EventFd,
     ) -> DeviceManagerResult<Arc<Mutex<Self>>> {
         let device_tree = Arc::new(Mutex::new(DeviceTree::new()));
 
+        let allocator = memory_manager.lock().unwrap().allocator();
+        let num_pci_segments = config.lock().unwrap().num_pci_segments();
+
+        // Split half of the device area, along with the 32 bits MMIO hole
+        // but for the virtio-mmio window, between the PCI segments. The rest
+        // of the device area is left to the memory hotplug and the other
+        // devices.
+        let start_of_device_area = memory_manager.lock().unwrap().start_of_device_area().0;
+        let end_of_device_area = memory_manager.lock().unwrap().end_of_device_area().0;
+        let pci_segment_size =
+            ((end_of_device_area - start_of_device_area + 1) / 2 / num_pci_segments as u64)
+                & !((1 << 30) - 1);
+        let pci_segment_hole_size = ((layout::MEM_32BIT_DEVICES_SIZE - VIRTIO_MMIO_WINDOW_SIZE)
+            / num_pci_segments as u64)
+            & !((1 << 20) - 1);
+        if pci_segment_size == 0 || pci_segment_hole_size == 0 {
+            return Err(DeviceManagerError::AllocatePciSegmentWindows);
+        }
+
+        let mut pci_mmio_allocators = Vec::new();
+        let mut pci_mmio_hole_allocators = Vec::new();
+        for _ in 0..num_pci_segments {
+            let base = allocator
+                .lock()
+                .unwrap()
+                .allocate_mmio_addresses(None, pci_segment_size, Some(1 << 30))
+                .ok_or(DeviceManagerError::AllocatePciSegmentWindows)?;
+            pci_mmio_allocators.push(Arc::new(Mutex::new(
+                AddressAllocator::new(base, pci_segment_size)
+                    .ok_or(DeviceManagerError::AllocatePciSegmentWindows)?,
+            )));
+
+            let base = allocator
+                .lock()
+                .unwrap()
+                .allocate_mmio_hole_addresses(None, pci_segment_hole_size, Some(1 << 20))
+                .ok_or(DeviceManagerError::AllocatePciSegmentWindows)?;
+            pci_mmio_hole_allocators.push(Arc::new(Mutex::new(
+                AddressAllocator::new(base, pci_segment_hole_size)
+                    .ok_or(DeviceManagerError::AllocatePciSegmentWindows)?,
+            )));
+        }
+
+        // The registers of the virtio-mmio devices must be below 4GiB for
+        // the ACPI tables to describe them.
+        let base = allocator
+            .lock()
+            .unwrap()
+            .allocate_mmio_hole_addresses(None, VIRTIO_MMIO_WINDOW_SIZE, Some(VIRTIO_MMIO_SIZE))
+            .ok_or(DeviceManagerError::AllocateMMIOAddress)?;
+        let virtio_mmio_allocator = AddressAllocator::new(base, VIRTIO_MMIO_WINDOW_SIZE)
+            .ok_or(DeviceManagerError::AllocateMMIOAddress)?;
+
         let address_manager = Arc::new(AddressManager {
-            allocator: memory_manager.lock().unwrap().allocator(),
+            allocator,
             #[cfg(target_arch = "x86_64")]
             io_bus: Arc::new(Bus::new()),
             mmio_bus: Arc::new(Bus::new()),
             vm: vm.clone(),
             device_tree: Arc::clone(&device_tree),
+            pci_mmio_allocators: pci_mmio_allocators.clone(),
+            pci_mmio_hole_allocators: pci_mmio_hole_allocators.clone(),
         });
 
+        let mut pci_segments = Vec::new();
+        for (id, (mmio_allocator, mmio_hole_allocator)) in pci_mmio_allocators
+            .into_iter()
+            .zip(pci_mmio_hole_allocators.into_iter())
+            .enumerate()
+        {
+            pci_segments.push(PciSegment::new(
+                id as u16,
+                &address_manager,
+                mmio_allocator,
+                mmio_hole_allocator,
+            )?);
+        }
+
+        let mut bus_devices: Vec<Arc<Mutex<dyn BusDevice>>> = Vec::new();
+        for pci_segment in pci_segments.iter() {
+            bus_devices.push(Arc::clone(&pci_segment.pci_config_mmio) as Arc<Mutex<dyn BusDevice>>);
+            #[cfg(target_arch = "x86_64")]
+            if let Some(pci_config_io) = &pci_segment.pci_config_io {
+                bus_devices.push(Arc::clone(pci_config_io) as Arc<Mutex<dyn BusDevice>>);
+            }
+        }
+
         // First we create the MSI interrupt manager, the legacy one is created
         // later, after the IOAPIC device creation.
         // The reason we create the MSI one first is because the IOAPIC needs it,
@@ -931,6 +1378,9 @@ impl DeviceManager {
             .unwrap()
             .allocate_mmio_addresses(None, DEVICE_MANAGER_ACPI_SIZE as u64, None)
             .ok_or(DeviceManagerError::AllocateIOPort)?;
+        let inflight_limit =
+            virtio_devices::InflightLimit::new(config.lock().unwrap().max_inflight_descriptors());
+
         let device_manager = DeviceManager {
             address_manager: Arc::clone(&address_manager),
             console: Arc::new(Console::default()),
@@ -940,22 +1390,30 @@ impl DeviceManager {
             cmdline_additions: Vec::new(),
             #[cfg(feature = "acpi")]
             ged_notification_device: None,
+            #[cfg(feature = "acpi")]
+            battery_device: None,
+            #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+            tpm_device: None,
             config,
             memory_manager,
             virtio_devices: Vec::new(),
-            bus_devices: Vec::new(),
+            virtio_mmio_devices: Vec::new(),
+            virtio_mmio_allocator,
+            bus_devices,
             device_id_cnt: Wrapping(0),
-            pci_bus: None,
+            pci_segments,
+            selected_segment: 0,
+            clock: VirtualClock::real(),
+            rtc_device: None,
             msi_interrupt_manager,
             legacy_interrupt_manager: None,
             passthrough_device: None,
             iommu_device: None,
-            pci_devices_up: 0,
-            pci_devices_down: 0,
             pci_id_list: HashMap::new(),
+            cold_devices: HashMap::new(),
             pci_devices: HashMap::new(),
-            pci_device_irqs: BTreeMap::new(),
             device_tree,
+            paused_devices: PausedDevices::default(),
             #[cfg(feature = "acpi")]
             exit_evt: _exit_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
             reset_evt: reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
@@ -965,13 +1423,32 @@ impl DeviceManager {
             #[cfg(feature = "acpi")]
             numa_nodes,
             balloon: None,
+            virtio_mem_devices: Vec::new(),
+            input_devices: HashMap::new(),
+            gpio_devices: HashMap::new(),
+            net_captures: HashMap::new(),
+            net_flow_steerings: HashMap::new(),
+            vhost_user_net_devices: HashMap::new(),
+            io_priority_block_devices: HashMap::new(),
+            vhost_user_backends: HashMap::new(),
+            inflight_limit,
+            device_events: virtio_devices::DeviceEventDispatcher::new()
+                .map_err(DeviceManagerError::DeviceEventDispatcher)?,
             activate_evt: activate_evt
                 .try_clone()
                 .map_err(DeviceManagerError::EventFd)?,
+            vsock_completion_evt: vsock_completion_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
+            guest_panic_evt: guest_panic_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
             #[cfg(feature = "acpi")]
             acpi_address,
             serial_pty: None,
             console_pty: None,
+            serial_tcp: None,
+            console_tcp: None,
         };
 
         let device_manager = Arc::new(Mutex::new(device_manager));
@@ -1001,8 +1478,16 @@ impl DeviceManager {
             .map(|pty| pty.lock().unwrap().0.try_clone().unwrap())
     }
 
+    pub fn serial_tcp(&self) -> Option<Arc<TcpConsole>> {
+        self.serial_tcp.clone()
+    }
+
+    pub fn console_tcp(&self) -> Option<Arc<TcpConsole>> {
+        self.console_tcp.clone()
+    }
+
     pub fn create_devices(&mut self) -> DeviceManagerResult<()> {
-        let mut virtio_devices: Vec<(VirtioDeviceArc, bool, String)> = Vec::new();
+        let mut virtio_devices: Vec<MetaVirtioDevice> = Vec::new();
 
         let interrupt_controller = self.add_interrupt_controller()?;
 
@@ -1048,6 +1533,12 @@ impl DeviceManager {
                     .try_clone()
                     .map_err(DeviceManagerError::EventFd)?,
             )?;
+            self.battery_device = self.add_acpi_battery_device()?;
+        }
+
+        #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+        {
+            self.tpm_device = self.add_tpm_device()?;
         }
 
         self.console = self.add_console_device(&legacy_interrupt_manager, &mut virtio_devices)?;
@@ -1081,10 +1572,12 @@ impl DeviceManager {
             );
         }
 
-        // There are 32 devices on the PCI bus, let's assign them an IRQ.
-        for i in 0..32 {
-            self.pci_device_irqs
-                .insert(i << 3, irqs[(i % num_irqs) as usize]);
+        // There are 32 devices on the bus of each PCI segment, let's assign
+        // them an IRQ.
+        for pci_segment in self.pci_segments.iter_mut() {
+            for (i, irq) in pci_segment.pci_irq_slots.iter_mut().enumerate() {
+                *irq = irqs[i % num_irqs];
+            }
         }
 
         Ok(())
@@ -1094,6 +1587,10 @@ impl DeviceManager {
         DeviceManagerState {
             device_tree: self.device_tree.lock().unwrap().clone(),
             device_id_cnt: self.device_id_cnt,
+            rtc_offset: self
+                .rtc_device
+                .as_ref()
+                .map(|rtc| rtc.lock().unwrap().time() as i64 - host_time()),
         }
     }
 
@@ -1111,14 +1608,8 @@ impl DeviceManager {
     #[allow(unused_variables)]
     fn add_pci_devices(
         &mut self,
-        virtio_devices: Vec<(VirtioDeviceArc, bool, String)>,
+        virtio_devices: Vec<MetaVirtioDevice>,
     ) -> DeviceManagerResult<()> {
-        let pci_root = PciRoot::new(None);
-        let mut pci_bus = PciBus::new(
-            pci_root,
-            Arc::clone(&self.address_manager) as Arc<dyn DeviceRelocation>,
-        );
-
         let iommu_id = String::from(IOMMU_DEVICE_NAME);
 
         let (iommu_device, iommu_mapping) = if self.config.lock().unwrap().iommu {
@@ -1141,26 +1632,58 @@ impl DeviceManager {
             (None, None)
         };
 
-        let mut iommu_attached_devices = Vec::new();
+        // The cold devices which already got a slot in a previous boot
+        // keep it, whether they were activated or not.
+        self.reserve_cold_devices(true)?;
 
-        for (device, iommu_attached, id) in virtio_devices {
-            let mapping: &Option<Arc<IommuMapping>> = if iommu_attached {
-                &iommu_mapping
-            } else {
-                &None
-            };
+        let mut iommu_attached_devices = Vec::new();
 
-            let dev_id = self.add_virtio_pci_device(device, &mut pci_bus, mapping, id)?;
+        for handle in virtio_devices {
+            let mapping: &Option<Arc<IommuMapping>> =
+                if handle.iommu { &iommu_mapping } else { &None };
+
+            let device_type =
+                VirtioDeviceType::from(handle.virtio_device.lock().unwrap().device_type());
+            if handle.transport == VirtioTransportType::Mmio {
+                self.add_virtio_mmio_device(handle.virtio_device, handle.id.clone())
+                    .map_err(DeviceManagerError::device(
+                        Some(handle.id),
+                        device_type,
+                        DeviceStage::Plug,
+                    ))?;
+                continue;
+            }
 
-            if iommu_attached {
+            let dev_id = self
+                .add_virtio_pci_device(
+                    handle.virtio_device,
+                    mapping,
+                    handle.id.clone(),
+                    handle.pci_segment,
+                    handle.pci_identity,
+                    self.cold_devices.remove(&handle.id),
+                )
+                .map_err(DeviceManagerError::device(
+                    Some(handle.id),
+                    device_type,
+                    DeviceStage::Plug,
+                ))?;
+            self.pci_segment_mut(handle.pci_segment)?
+                .set_device_numa_node(dev_id, handle.numa_node);
+
+            if handle.iommu {
                 iommu_attached_devices.push(dev_id);
             }
         }
 
-        let mut vfio_iommu_device_ids = self.add_vfio_devices(&mut pci_bus)?;
+        let mut vfio_iommu_device_ids = self.add_vfio_devices()?;
 
         iommu_attached_devices.append(&mut vfio_iommu_device_ids);
 
+        self.add_vfio_user_devices()?;
+
+        self.add_nvme_devices()?;
+
         if let Some(iommu_device) = iommu_device {
             iommu_device
                 .lock()
@@ -1170,35 +1693,113 @@ impl DeviceManager {
             // Because we determined the virtio-iommu b/d/f, we have to
             // add the device to the PCI topology now. Otherwise, the
             // b/d/f won't match the virtio-iommu device as expected.
-            self.add_virtio_pci_device(iommu_device, &mut pci_bus, &None, iommu_id)?;
+            // The virtio-iommu only manages the devices of the first PCI
+            // segment, which is where it is placed as well.
+            self.add_virtio_pci_device(
+                iommu_device,
+                &None,
+                iommu_id.clone(),
+                0,
+                VirtioPciIdentity::default(),
+                None,
+            )
+            .map_err(DeviceManagerError::device(
+                Some(iommu_id),
+                VirtioDeviceType::TYPE_IOMMU,
+                DeviceStage::Plug,
+            ))?;
         }
 
-        let pci_bus = Arc::new(Mutex::new(pci_bus));
-        let pci_config_io = Arc::new(Mutex::new(PciConfigIo::new(Arc::clone(&pci_bus))));
-        self.bus_devices
-            .push(Arc::clone(&pci_config_io) as Arc<Mutex<dyn BusDevice>>);
-        #[cfg(target_arch = "x86_64")]
-        self.address_manager
-            .io_bus
-            .insert(pci_config_io, 0xcf8, 0x8)
-            .map_err(DeviceManagerError::BusError)?;
-        let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(Arc::clone(&pci_bus))));
-        self.bus_devices
-            .push(Arc::clone(&pci_config_mmio) as Arc<Mutex<dyn BusDevice>>);
-        self.address_manager
-            .mmio_bus
-            .insert(
-                pci_config_mmio,
-                arch::layout::PCI_MMCONFIG_START.0,
-                arch::layout::PCI_MMCONFIG_SIZE,
-            )
-            .map_err(DeviceManagerError::BusError)?;
+        // The slots of the new cold devices are only reserved once the
+        // devices being restored got theirs back.
+        self.reserve_cold_devices(false)?;
+
+        Ok(())
+    }
+
+    // Reserves the slots of the cold devices, either the ones already
+    // given a slot, including the activated devices, or the new ones.
+    fn reserve_cold_devices(&mut self, reserved: bool) -> DeviceManagerResult<()> {
+        let mut block_devices = self.config.lock().unwrap().disks.clone();
+        if let Some(disk_list_cfg) = &mut block_devices {
+            for disk_cfg in disk_list_cfg.iter_mut().filter(|disk_cfg| {
+                disk_cfg.cold_pci_bdf.is_some() == reserved && (reserved || disk_cfg.cold)
+            }) {
+                self.reserve_cold_device(
+                    &mut disk_cfg.id,
+                    &mut disk_cfg.cold_pci_bdf,
+                    DISK_DEVICE_NAME_PREFIX,
+                    disk_cfg.pci_segment,
+                    disk_cfg.numa_node,
+                )?;
+            }
+        }
+        self.config.lock().unwrap().disks = block_devices;
 
-        self.pci_bus = Some(pci_bus);
+        let mut net_devices = self.config.lock().unwrap().net.clone();
+        if let Some(net_list_cfg) = &mut net_devices {
+            for net_cfg in net_list_cfg.iter_mut().filter(|net_cfg| {
+                net_cfg.cold_pci_bdf.is_some() == reserved && (reserved || net_cfg.cold)
+            }) {
+                self.reserve_cold_device(
+                    &mut net_cfg.id,
+                    &mut net_cfg.cold_pci_bdf,
+                    NET_DEVICE_NAME_PREFIX,
+                    net_cfg.pci_segment,
+                    net_cfg.numa_node,
+                )?;
+            }
+        }
+        self.config.lock().unwrap().net = net_devices;
 
         Ok(())
     }
 
+    // Reserves a PCI slot for a device left out until activated through the
+    // API, naming the device if it has no id yet. The slot it got in a
+    // previous boot is reserved again if any.
+    fn reserve_cold_device(
+        &mut self,
+        id: &mut Option<String>,
+        pci_bdf: &mut Option<u32>,
+        prefix: &str,
+        pci_segment_id: u16,
+        numa_node: Option<u32>,
+    ) -> DeviceManagerResult<PciDeviceInfo> {
+        let device_id = if let Some(device_id) = &*id {
+            if self.pci_id_list.contains_key(device_id) || self.cold_devices.contains_key(device_id)
+            {
+                return Err(DeviceManagerError::DeviceIdAlreadyInUse);
+            }
+            device_id.clone()
+        } else {
+            let device_id = self.next_device_name(prefix)?;
+            *id = Some(device_id.clone());
+            device_id
+        };
+
+        let pci_device_bdf = if let Some(pci_device_bdf) = *pci_bdf {
+            self.pci_segment(pci_bdf_segment(pci_device_bdf))?
+                .pci_bus
+                .lock()
+                .unwrap()
+                .get_device_id(pci_bdf_device(pci_device_bdf) as usize)
+                .map_err(DeviceManagerError::GetPciDeviceId)?;
+            pci_device_bdf
+        } else {
+            self.pci_segment(pci_segment_id)?.next_device_bdf()?
+        };
+        *pci_bdf = Some(pci_device_bdf);
+        self.pci_segment_mut(pci_bdf_segment(pci_device_bdf))?
+            .set_device_numa_node(pci_device_bdf, numa_node);
+        self.cold_devices.insert(device_id.clone(), pci_device_bdf);
+
+        Ok(PciDeviceInfo {
+            id: device_id,
+            bdf: pci_device_bdf,
+        })
+    }
+
     #[cfg(target_arch = "aarch64")]
     fn add_interrupt_controller(
         &mut self,
@@ -1363,6 +1964,7 @@ impl DeviceManager {
             interrupt_group,
             ged_irq,
             ged_address,
+            self.config.lock().unwrap().battery,
         )));
         self.address_manager
             .mmio_bus
@@ -1375,7 +1977,9 @@ impl DeviceManager {
         self.bus_devices
             .push(Arc::clone(&ged_device) as Arc<Mutex<dyn BusDevice>>);
 
-        let pm_timer_device = Arc::new(Mutex::new(devices::AcpiPMTimerDevice::new()));
+        let pm_timer_device = Arc::new(Mutex::new(devices::AcpiPMTimerDevice::new(
+            self.clock.clone(),
+        )));
 
         self.bus_devices
             .push(Arc::clone(&pm_timer_device) as Arc<Mutex<dyn BusDevice>>);
@@ -1398,22 +2002,87 @@ impl DeviceManager {
         Ok(Some(ged_device))
     }
 
-    #[cfg(target_arch = "x86_64")]
-    fn add_legacy_devices(&mut self, reset_evt: EventFd) -> DeviceManagerResult<()> {
-        // Add a shutdown device (i8042)
-        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(reset_evt)));
-
-        self.bus_devices
-            .push(Arc::clone(&i8042) as Arc<Mutex<dyn BusDevice>>);
+    #[cfg(feature = "acpi")]
+    fn add_acpi_battery_device(
+        &mut self,
+    ) -> DeviceManagerResult<Option<Arc<Mutex<devices::AcpiBatteryDevice>>>> {
+        if !self.config.lock().unwrap().battery {
+            return Ok(None);
+        }
 
+        let battery_address = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_mmio_addresses(None, devices::acpi::BATTERY_DEVICE_ACPI_SIZE as u64, None)
+            .ok_or(DeviceManagerError::AllocateMMIOAddress)?;
+        let id = String::from(BATTERY_DEVICE_NAME);
+        let battery_device = Arc::new(Mutex::new(devices::AcpiBatteryDevice::new(
+            id.clone(),
+            battery_address,
+        )));
         self.address_manager
-            .io_bus
-            .insert(i8042, 0x61, 0x4)
+            .mmio_bus
+            .insert(
+                battery_device.clone(),
+                battery_address.0,
+                devices::acpi::BATTERY_DEVICE_ACPI_SIZE as u64,
+            )
+            .map_err(DeviceManagerError::BusError)?;
+        self.bus_devices
+            .push(Arc::clone(&battery_device) as Arc<Mutex<dyn BusDevice>>);
+
+        // Fill the device tree with a new node, for the state of the battery
+        // to be snapshotted. In case of restore, the existing entry is
+        // simply overridden.
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, battery_device));
+
+        Ok(Some(battery_device))
+    }
+
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    fn add_tpm_device(&mut self) -> DeviceManagerResult<Option<Arc<Mutex<devices::tpm::Tpm>>>> {
+        let tpm_config = match self.config.lock().unwrap().tpm.clone() {
+            Some(tpm_config) => tpm_config,
+            None => return Ok(None),
+        };
+
+        let emulator =
+            devices::tpm::Emulator::new(&tpm_config.socket, devices::tpm::TPM_CRB_BUFFER_SIZE)
+                .map_err(DeviceManagerError::CreateTpm)?;
+        let tpm_device = Arc::new(Mutex::new(devices::tpm::Tpm::new(
+            Box::new(emulator),
+            layout::TPM_START,
+        )));
+        self.address_manager
+            .mmio_bus
+            .insert(tpm_device.clone(), layout::TPM_START.0, layout::TPM_SIZE)
+            .map_err(DeviceManagerError::BusError)?;
+        self.bus_devices
+            .push(Arc::clone(&tpm_device) as Arc<Mutex<dyn BusDevice>>);
+
+        Ok(Some(tpm_device))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn add_legacy_devices(&mut self, reset_evt: EventFd) -> DeviceManagerResult<()> {
+        // Add a shutdown device (i8042)
+        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(reset_evt)));
+
+        self.bus_devices
+            .push(Arc::clone(&i8042) as Arc<Mutex<dyn BusDevice>>);
+
+        self.address_manager
+            .io_bus
+            .insert(i8042, 0x61, 0x4)
             .map_err(DeviceManagerError::BusError)?;
         #[cfg(feature = "cmos")]
         {
             // Add a CMOS emulated device
-            use vm_memory::GuestMemory;
             let mem_size = self
                 .memory_manager
                 .lock()
@@ -1429,10 +2098,12 @@ impl DeviceManager {
             let cmos = Arc::new(Mutex::new(devices::legacy::Cmos::new(
                 mem_below_4g,
                 mem_above_4g,
+                self.clock.clone(),
             )));
 
             self.bus_devices
                 .push(Arc::clone(&cmos) as Arc<Mutex<dyn BusDevice>>);
+            self.rtc_device = Some(Arc::clone(&cmos) as Arc<Mutex<dyn RealTimeClock>>);
 
             self.address_manager
                 .io_bus
@@ -1451,6 +2122,24 @@ impl DeviceManager {
                 .insert(fwdebug, 0x402, 0x1)
                 .map_err(DeviceManagerError::BusError)?;
         }
+        // The guest only reports its panics for the crash actions to run,
+        // finding the device through ACPI.
+        #[cfg(feature = "acpi")]
+        if self.config.lock().unwrap().on_crash.is_some() {
+            let pvpanic = Arc::new(Mutex::new(devices::legacy::PvPanicDevice::new(
+                self.guest_panic_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )));
+
+            self.bus_devices
+                .push(Arc::clone(&pvpanic) as Arc<Mutex<dyn BusDevice>>);
+
+            self.address_manager
+                .io_bus
+                .insert(pvpanic, PVPANIC_PORT.into(), 0x1)
+                .map_err(DeviceManagerError::BusError)?;
+        }
 
         Ok(())
     }
@@ -1475,10 +2164,14 @@ impl DeviceManager {
             })
             .map_err(DeviceManagerError::CreateInterruptGroup)?;
 
-        let rtc_device = Arc::new(Mutex::new(devices::legacy::RTC::new(interrupt_group)));
+        let rtc_device = Arc::new(Mutex::new(devices::legacy::RTC::new(
+            interrupt_group,
+            self.clock.clone(),
+        )));
 
         self.bus_devices
             .push(Arc::clone(&rtc_device) as Arc<Mutex<dyn BusDevice>>);
+        self.rtc_device = Some(Arc::clone(&rtc_device) as Arc<Mutex<dyn RealTimeClock>>);
 
         let addr = GuestAddress(arch::layout::LEGACY_RTC_MAPPED_IO_START);
 
@@ -1643,7 +2336,7 @@ impl DeviceManager {
     fn add_console_device(
         &mut self,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
-        virtio_devices: &mut Vec<(VirtioDeviceArc, bool, String)>,
+        virtio_devices: &mut Vec<MetaVirtioDevice>,
     ) -> DeviceManagerResult<Arc<Console>> {
         let serial_config = self.config.lock().unwrap().serial.clone();
         let serial_writer: Option<Box<dyn io::Write + Send>> = match serial_config.mode {
@@ -1663,6 +2356,14 @@ impl DeviceManager {
                 self.config.lock().unwrap().serial.file = Some(path);
                 Some(Box::new(main.try_clone().unwrap()))
             }
+            ConsoleOutputMode::Tcp => {
+                let tcp = TcpConsole::new(serial_config.socket.unwrap())
+                    .map_err(DeviceManagerError::SerialTcpListen)?;
+                self.config.lock().unwrap().serial.socket = tcp.local_addr().ok();
+                let writer = tcp.writer();
+                self.serial_tcp = Some(Arc::new(tcp));
+                Some(Box::new(writer))
+            }
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
             ConsoleOutputMode::Off | ConsoleOutputMode::Null => None,
         };
@@ -1691,6 +2392,14 @@ impl DeviceManager {
                 self.config.lock().unwrap().console.file = Some(path);
                 Some(Box::new(main.try_clone().unwrap()))
             }
+            ConsoleOutputMode::Tcp => {
+                let tcp = TcpConsole::new(console_config.socket.unwrap())
+                    .map_err(DeviceManagerError::ConsoleTcpListen)?;
+                self.config.lock().unwrap().console.socket = tcp.local_addr().ok();
+                let writer = tcp.writer();
+                self.console_tcp = Some(Arc::new(tcp));
+                Some(Box::new(writer))
+            }
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
             ConsoleOutputMode::Null => Some(Box::new(sink())),
             ConsoleOutputMode::Off => None,
@@ -1699,9 +2408,19 @@ impl DeviceManager {
         let virtio_console_input = if let Some(writer) = console_writer {
             let id = String::from(CONSOLE_DEVICE_NAME);
 
+            let crash_dump = self.config.lock().unwrap().crash_dump.clone();
+            let crash_writer: Option<Box<dyn io::Write + Send + Sync>> = match crash_dump {
+                Some(crash_dump) => Some(Box::new(
+                    CrashDumpFile::new(&crash_dump)
+                        .map_err(DeviceManagerError::CrashDumpFileOpen)?,
+                )),
+                None => None,
+            };
+
             let (virtio_console_device, virtio_console_input) = virtio_devices::Console::new(
                 id.clone(),
                 writer,
+                crash_writer,
                 col,
                 row,
                 console_config.iommu,
@@ -1709,11 +2428,15 @@ impl DeviceManager {
             )
             .map_err(DeviceManagerError::CreateVirtioConsole)?;
             let virtio_console_device = Arc::new(Mutex::new(virtio_console_device));
-            virtio_devices.push((
-                Arc::clone(&virtio_console_device) as VirtioDeviceArc,
-                console_config.iommu,
-                id.clone(),
-            ));
+            virtio_devices.push(MetaVirtioDevice {
+                virtio_device: Arc::clone(&virtio_console_device) as VirtioDeviceArc,
+                iommu: console_config.iommu,
+                id: id.clone(),
+                pci_segment: 0,
+                numa_node: None,
+                pci_identity: VirtioPciIdentity::default(),
+                transport: VirtioTransportType::Pci,
+            });
 
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
@@ -1743,8 +2466,8 @@ impl DeviceManager {
         }))
     }
 
-    fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
-        let mut devices: Vec<(VirtioDeviceArc, bool, String)> = Vec::new();
+    fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
+        let mut devices: Vec<MetaVirtioDevice> = Vec::new();
 
         // Create "standard" virtio devices (net/block/rng)
         devices.append(&mut self.make_virtio_block_devices()?);
@@ -1768,13 +2491,144 @@ impl DeviceManager {
         // Add virtio-watchdog device
         devices.append(&mut self.make_virtio_watchdog_devices()?);
 
+        // Add virtio-input if required
+        devices.append(&mut self.make_virtio_input_devices()?);
+
+        // Add virtio-gpio if required
+        devices.append(&mut self.make_virtio_gpio_devices()?);
+
         Ok(devices)
     }
 
+    // Opens the image backing a disk, picking the implementation matching
+    // its format.
+    fn open_disk_image(&self, disk_cfg: &DiskConfig) -> DeviceManagerResult<Box<dyn DiskFile>> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        // The base image of an overlay is shared with other VMs, all the
+        // writes go to the overlay.
+        options.write(!disk_cfg.readonly && disk_cfg.overlay.is_none());
+        if disk_cfg.direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        // Open block device path
+        let mut file: File = options
+            .open(
+                disk_cfg
+                    .path
+                    .as_ref()
+                    .ok_or(DeviceManagerError::NoDiskPath)?
+                    .clone(),
+            )
+            .map_err(DeviceManagerError::Disk)?;
+
+        let file_type = file
+            .metadata()
+            .map_err(DeviceManagerError::DiskFileType)?
+            .file_type();
+        // A block device holds an image just like a regular file does, for
+        // instance a QCOW image stored in a LVM volume.
+        if !file_type.is_block_device() && !file_type.is_file() {
+            return Err(DeviceManagerError::UnsupportedDiskFileType(
+                disk_cfg
+                    .path
+                    .as_ref()
+                    .ok_or(DeviceManagerError::NoDiskPath)?
+                    .clone(),
+            ));
+        }
+        let image_type =
+            detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
+
+        if disk_cfg.overlay.is_some() && !matches!(image_type, ImageType::Raw) {
+            return Err(DeviceManagerError::UnsupportedOverlayImageType(
+                disk_cfg
+                    .path
+                    .as_ref()
+                    .ok_or(DeviceManagerError::NoDiskPath)?
+                    .clone(),
+            ));
+        }
+
+        let image = match image_type {
+            ImageType::FixedVhd => {
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                if block_io_uring_is_supported() && !disk_cfg.disable_io_uring {
+                    info!("Using asynchronous fixed VHD disk file (io_uring)");
+                    Box::new(
+                        FixedVhdDiskAsync::new(file)
+                            .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
+                    ) as Box<dyn DiskFile>
+                } else {
+                    info!("Using synchronous fixed VHD disk file");
+                    Box::new(
+                        FixedVhdDiskSync::new(file)
+                            .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
+                    ) as Box<dyn DiskFile>
+                }
+            }
+            ImageType::Raw if disk_cfg.overlay.is_some() => {
+                let overlay_path = disk_cfg.overlay.as_ref().unwrap();
+                info!("Using RAW disk file with overlay {:?}", overlay_path);
+                let overlay = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .open(overlay_path)
+                    .map_err(DeviceManagerError::OverlayDisk)?;
+                Box::new(
+                    OverlayDiskFile::new(file, overlay).map_err(DeviceManagerError::OverlayDisk)?,
+                ) as Box<dyn DiskFile>
+            }
+            ImageType::Raw => {
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                if block_io_uring_is_supported() && !disk_cfg.disable_io_uring {
+                    info!("Using asynchronous RAW disk file (io_uring)");
+                    Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
+                } else {
+                    info!("Using synchronous RAW disk file");
+                    Box::new(RawFileDiskSync::new(file)) as Box<dyn DiskFile>
+                }
+            }
+            ImageType::Qcow2 => {
+                info!("Using synchronous QCOW disk file");
+                if disk_cfg.num_queues > 1 {
+                    // The QCOW backend serializes all the accesses to
+                    // the image, there's no parallelism to expect from
+                    // having more than one queue.
+                    warn!(
+                        "QCOW disk file {:?} doesn't support concurrent accesses, \
+                         {} queues won't scale",
+                        disk_cfg.path, disk_cfg.num_queues
+                    );
+                }
+                Box::new(QcowDiskSync::new(file, disk_cfg.direct)) as Box<dyn DiskFile>
+            }
+        };
+
+        let image = if disk_cfg.readahead_cache > 0 {
+            info!(
+                "Using a read-ahead cache of {} bytes, reading {} bytes ahead",
+                disk_cfg.readahead_cache, disk_cfg.readahead_window
+            );
+            Box::new(ReadAheadDiskFile::new(
+                image,
+                disk_cfg.readahead_cache,
+                disk_cfg.readahead_window,
+            )) as Box<dyn DiskFile>
+        } else {
+            image
+        };
+
+        Ok(image)
+    }
+
     fn make_virtio_block_device(
         &mut self,
         disk_cfg: &mut DiskConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
         let id = if let Some(id) = &disk_cfg.id {
             id.clone()
         } else {
@@ -1789,6 +2643,8 @@ impl DeviceManager {
                 socket,
                 num_queues: disk_cfg.num_queues,
                 queue_size: disk_cfg.queue_size,
+                activate_timeout: disk_cfg.activate_timeout.map(Duration::from_millis),
+                ..Default::default()
             };
             let vhost_user_block_device = Arc::new(Mutex::new(
                 match virtio_devices::vhost_user::Blk::new(
@@ -1811,66 +2667,17 @@ impl DeviceManager {
                 .unwrap()
                 .insert(id.clone(), device_node!(id, vhost_user_block_device));
 
-            Ok((
-                Arc::clone(&vhost_user_block_device) as VirtioDeviceArc,
-                false,
+            Ok(MetaVirtioDevice {
+                virtio_device: Arc::clone(&vhost_user_block_device) as VirtioDeviceArc,
+                iommu: false,
                 id,
-            ))
+                pci_segment: disk_cfg.pci_segment,
+                numa_node: disk_cfg.numa_node,
+                pci_identity: VirtioPciIdentity::from(&*disk_cfg),
+                transport: disk_cfg.transport,
+            })
         } else {
-            let mut options = OpenOptions::new();
-            options.read(true);
-            options.write(!disk_cfg.readonly);
-            if disk_cfg.direct {
-                options.custom_flags(libc::O_DIRECT);
-            }
-            // Open block device path
-            let mut file: File = options
-                .open(
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
-                )
-                .map_err(DeviceManagerError::Disk)?;
-
-            let image_type =
-                detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
-
-            let image = match image_type {
-                ImageType::FixedVhd => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if block_io_uring_is_supported() && !disk_cfg.disable_io_uring {
-                        info!("Using asynchronous fixed VHD disk file (io_uring)");
-                        Box::new(
-                            FixedVhdDiskAsync::new(file)
-                                .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
-                        ) as Box<dyn DiskFile>
-                    } else {
-                        info!("Using synchronous fixed VHD disk file");
-                        Box::new(
-                            FixedVhdDiskSync::new(file)
-                                .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
-                        ) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Raw => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if block_io_uring_is_supported() && !disk_cfg.disable_io_uring {
-                        info!("Using asynchronous RAW disk file (io_uring)");
-                        Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
-                    } else {
-                        info!("Using synchronous RAW disk file");
-                        Box::new(RawFileDiskSync::new(file)) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Qcow2 => {
-                    info!("Using synchronous QCOW disk file");
-                    Box::new(QcowDiskSync::new(file, disk_cfg.direct)) as Box<dyn DiskFile>
-                }
-            };
+            let image = self.open_disk_image(disk_cfg)?;
 
             let dev = Arc::new(Mutex::new(
                 virtio_devices::Block::new(
@@ -1885,10 +2692,34 @@ impl DeviceManager {
                     disk_cfg.iommu,
                     disk_cfg.num_queues,
                     disk_cfg.queue_size,
+                    virtio_devices::NotificationSuppression {
+                        threshold: disk_cfg.notify_threshold,
+                        max_latency_us: disk_cfg.notify_max_latency_us,
+                    },
+                    virtio_devices::QueueScheduling {
+                        num_workers: disk_cfg.num_workers.unwrap_or_default(),
+                        weights: disk_cfg.queue_weights.clone().unwrap_or_default(),
+                    },
+                    virtio_devices::IoRetry {
+                        max_retries: disk_cfg.io_retries,
+                        backoff_ms: disk_cfg.io_retry_backoff_ms,
+                    },
                     self.seccomp_action.clone(),
                 )
                 .map_err(DeviceManagerError::CreateVirtioBlock)?,
             ));
+            {
+                let mut dev = dev.lock().unwrap();
+                dev.set_inflight_limit(self.inflight_limit.clone());
+                dev.set_queue_depth(disk_cfg.queue_depth.unwrap_or(DEFAULT_DISK_QUEUE_DEPTH));
+                if let Some(io_priority) = disk_cfg.io_priority {
+                    dev.set_io_priority(io_priority.ioprio());
+                }
+            }
+            if let Some(io_priority) = disk_cfg.io_priority {
+                self.io_priority_block_devices
+                    .insert(id.clone(), (dev.clone(), io_priority));
+            }
 
             let virtio_device = Arc::clone(&dev) as VirtioDeviceArc;
             let migratable_device = dev as Arc<Mutex<dyn Migratable>>;
@@ -1901,30 +2732,53 @@ impl DeviceManager {
                 .unwrap()
                 .insert(id.clone(), device_node!(id, migratable_device));
 
-            Ok((virtio_device, disk_cfg.iommu, id))
+            Ok(MetaVirtioDevice {
+                virtio_device,
+                iommu: disk_cfg.iommu,
+                id,
+                pci_segment: disk_cfg.pci_segment,
+                numa_node: disk_cfg.numa_node,
+                pci_identity: VirtioPciIdentity::from(&*disk_cfg),
+                transport: disk_cfg.transport,
+            })
         }
     }
 
-    fn make_virtio_block_devices(
-        &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    fn make_virtio_block_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
         let mut block_devices = self.config.lock().unwrap().disks.clone();
         if let Some(disk_list_cfg) = &mut block_devices {
-            for disk_cfg in disk_list_cfg.iter_mut() {
-                devices.push(self.make_virtio_block_device(disk_cfg)?);
+            // The NVMe disks are added along with the other PCI devices, and
+            // the cold ones only once activated.
+            for disk_cfg in disk_list_cfg
+                .iter_mut()
+                .filter(|disk_cfg| !disk_cfg.nvme && !disk_cfg.cold)
+            {
+                let device =
+                    self.make_virtio_block_device(disk_cfg)
+                        .map_err(DeviceManagerError::device(
+                            disk_cfg.id.clone(),
+                            VirtioDeviceType::TYPE_BLOCK,
+                            DeviceStage::Create,
+                        ))?;
+                devices.push((disk_cfg.boot_index, device));
             }
         }
         self.config.lock().unwrap().disks = block_devices;
 
-        Ok(devices)
+        // Firmwares usually try to boot from the disks in the order they
+        // are found on the PCI bus. Disks are placed on the bus following
+        // their boot index, the ones without an index coming last.
+        devices.sort_by_key(|(boot_index, _)| boot_index.map_or(u32::MAX, u32::from));
+
+        Ok(devices.into_iter().map(|(_, device)| device).collect())
     }
 
     fn make_virtio_net_device(
         &mut self,
         net_cfg: &mut NetConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
         let id = if let Some(id) = &net_cfg.id {
             id.clone()
         } else {
@@ -1939,20 +2793,53 @@ impl DeviceManager {
                 socket,
                 num_queues: net_cfg.num_queues,
                 queue_size: net_cfg.queue_size,
+                connect_timeout: net_cfg.connect_timeout.map(Duration::from_millis),
+                activate_timeout: net_cfg.activate_timeout.map(Duration::from_millis),
+                offloads: NetOffloads {
+                    host_csum: net_cfg.host_csum,
+                    host_tso: net_cfg.host_tso,
+                    host_ufo: net_cfg.host_ufo,
+                    guest_csum: net_cfg.guest_csum,
+                    guest_tso: net_cfg.guest_tso,
+                    guest_ufo: net_cfg.guest_ufo,
+                },
+                anti_spoof: net_cfg.anti_spoof,
             };
-            let vhost_user_net_device = Arc::new(Mutex::new(
-                match virtio_devices::vhost_user::Net::new(
+            // On a guest reboot, the connection to the backend of the
+            // previous device is taken over when possible.
+            let vun_device = self.vhost_user_backends.remove(&id).and_then(|backend| {
+                virtio_devices::vhost_user::Net::from_backend(
+                    id.clone(),
+                    net_cfg.mac,
+                    vu_cfg.clone(),
+                    backend,
+                    self.seccomp_action.clone(),
+                )
+                .map_err(|e| {
+                    warn!(
+                        "{}: Cannot take the vhost-user backend over, connecting again: {:?}",
+                        id, e
+                    )
+                })
+                .ok()
+            });
+            let vun_device = match vun_device {
+                Some(vun_device) => vun_device,
+                None => virtio_devices::vhost_user::Net::new(
                     id.clone(),
                     net_cfg.mac,
                     vu_cfg,
                     self.seccomp_action.clone(),
-                ) {
-                    Ok(vun_device) => vun_device,
-                    Err(e) => {
-                        return Err(DeviceManagerError::CreateVhostUserNet(e));
-                    }
-                },
-            ));
+                )
+                .map_err(DeviceManagerError::CreateVhostUserNet)?,
+            };
+            let vhost_user_net_device = Arc::new(Mutex::new(vun_device));
+            vhost_user_net_device
+                .lock()
+                .unwrap()
+                .set_busy_poll(net_cfg.busy_poll.map(Duration::from_micros));
+            self.vhost_user_net_devices
+                .insert(id.clone(), vhost_user_net_device.clone());
 
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
@@ -1962,11 +2849,67 @@ impl DeviceManager {
                 .unwrap()
                 .insert(id.clone(), device_node!(id, vhost_user_net_device));
 
-            Ok((
-                Arc::clone(&vhost_user_net_device) as VirtioDeviceArc,
-                net_cfg.iommu,
+            Ok(MetaVirtioDevice {
+                virtio_device: Arc::clone(&vhost_user_net_device) as VirtioDeviceArc,
+                iommu: net_cfg.iommu,
                 id,
-            ))
+                pci_segment: net_cfg.pci_segment,
+                numa_node: net_cfg.numa_node,
+                pci_identity: VirtioPciIdentity::from(&*net_cfg),
+                transport: net_cfg.transport,
+            })
+        } else if net_cfg.vhost_kernel {
+            let vhost_net_device = if let Some(fds) = &net_cfg.fds {
+                Arc::new(Mutex::new(
+                    virtio_devices::vhost_kernel::Net::from_tap_fds(
+                        id.clone(),
+                        fds,
+                        net_cfg.mac,
+                        net_cfg.queue_size,
+                        self.seccomp_action.clone(),
+                    )
+                    .map_err(DeviceManagerError::CreateVhostKernelNet)?,
+                ))
+            } else {
+                let (ip, mask) = if net_cfg.tap.is_some() {
+                    (None, None)
+                } else {
+                    (Some(net_cfg.ip), Some(net_cfg.mask))
+                };
+
+                Arc::new(Mutex::new(
+                    virtio_devices::vhost_kernel::Net::new(
+                        id.clone(),
+                        net_cfg.tap.as_deref(),
+                        ip,
+                        mask,
+                        net_cfg.mac,
+                        &mut net_cfg.host_mac,
+                        net_cfg.num_queues,
+                        net_cfg.queue_size,
+                        self.seccomp_action.clone(),
+                    )
+                    .map_err(DeviceManagerError::CreateVhostKernelNet)?,
+                ))
+            };
+
+            // Fill the device tree with a new node. In case of restore, we
+            // know there is nothing to do, so we can simply override the
+            // existing entry.
+            self.device_tree
+                .lock()
+                .unwrap()
+                .insert(id.clone(), device_node!(id, vhost_net_device));
+
+            Ok(MetaVirtioDevice {
+                virtio_device: Arc::clone(&vhost_net_device) as VirtioDeviceArc,
+                iommu: net_cfg.iommu,
+                id,
+                pci_segment: net_cfg.pci_segment,
+                numa_node: net_cfg.numa_node,
+                pci_identity: VirtioPciIdentity::from(&*net_cfg),
+                transport: net_cfg.transport,
+            })
         } else {
             let virtio_net_device = if let Some(ref tap_if_name) = net_cfg.tap {
                 Arc::new(Mutex::new(
@@ -2013,6 +2956,38 @@ impl DeviceManager {
                     .map_err(DeviceManagerError::CreateVirtioNet)?,
                 ))
             };
+            virtio_net_device
+                .lock()
+                .unwrap()
+                .set_anti_spoof(net_cfg.anti_spoof);
+            virtio_net_device
+                .lock()
+                .unwrap()
+                .set_trusted_local(net_cfg.trusted_local);
+            virtio_net_device
+                .lock()
+                .unwrap()
+                .set_notification_suppression(virtio_devices::NotificationSuppression {
+                    threshold: net_cfg.notify_threshold,
+                    max_latency_us: net_cfg.notify_max_latency_us,
+                });
+            virtio_net_device
+                .lock()
+                .unwrap()
+                .set_busy_poll(net_cfg.busy_poll.map(Duration::from_micros));
+            virtio_net_device
+                .lock()
+                .unwrap()
+                .set_event_notifier(self.device_events.notifier(id.clone()));
+
+            self.net_captures.insert(
+                id.clone(),
+                virtio_net_device.lock().unwrap().packet_capture(),
+            );
+            self.net_flow_steerings.insert(
+                id.clone(),
+                virtio_net_device.lock().unwrap().flow_steering(),
+            );
 
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
@@ -2022,23 +2997,31 @@ impl DeviceManager {
                 .unwrap()
                 .insert(id.clone(), device_node!(id, virtio_net_device));
 
-            Ok((
-                Arc::clone(&virtio_net_device) as VirtioDeviceArc,
-                net_cfg.iommu,
+            Ok(MetaVirtioDevice {
+                virtio_device: Arc::clone(&virtio_net_device) as VirtioDeviceArc,
+                iommu: net_cfg.iommu,
                 id,
-            ))
+                pci_segment: net_cfg.pci_segment,
+                numa_node: net_cfg.numa_node,
+                pci_identity: VirtioPciIdentity::from(&*net_cfg),
+                transport: net_cfg.transport,
+            })
         }
     }
 
     /// Add virto-net and vhost-user-net devices
-    fn make_virtio_net_devices(
-        &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    fn make_virtio_net_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
         let mut net_devices = self.config.lock().unwrap().net.clone();
         if let Some(net_list_cfg) = &mut net_devices {
-            for net_cfg in net_list_cfg.iter_mut() {
-                devices.push(self.make_virtio_net_device(net_cfg)?);
+            for net_cfg in net_list_cfg.iter_mut().filter(|net_cfg| !net_cfg.cold) {
+                devices.push(self.make_virtio_net_device(net_cfg).map_err(
+                    DeviceManagerError::device(
+                        net_cfg.id.clone(),
+                        VirtioDeviceType::TYPE_NET,
+                        DeviceStage::Create,
+                    ),
+                )?);
             }
         }
         self.config.lock().unwrap().net = net_devices;
@@ -2046,30 +3029,41 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_rng_devices(
-        &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    fn make_virtio_rng_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
         // Add virtio-rng if required
         let rng_config = self.config.lock().unwrap().rng.clone();
         if let Some(rng_path) = rng_config.src.to_str() {
             let id = String::from(RNG_DEVICE_NAME);
+            let seed = rng_config.seed.as_ref().map(|path| {
+                let mode = match rng_config.seed_mode {
+                    RngSeedMode::Consume => virtio_devices::RngSeedMode::Consume,
+                    RngSeedMode::Replay => virtio_devices::RngSeedMode::Replay,
+                };
+                (path.to_string_lossy().into_owned(), mode)
+            });
 
             let virtio_rng_device = Arc::new(Mutex::new(
                 virtio_devices::Rng::new(
                     id.clone(),
                     rng_path,
                     rng_config.iommu,
+                    seed.as_ref().map(|(path, mode)| (path.as_str(), *mode)),
+                    rng_config.quality,
                     self.seccomp_action.clone(),
                 )
                 .map_err(DeviceManagerError::CreateVirtioRng)?,
             ));
-            devices.push((
-                Arc::clone(&virtio_rng_device) as VirtioDeviceArc,
-                rng_config.iommu,
-                id.clone(),
-            ));
+            devices.push(MetaVirtioDevice {
+                virtio_device: Arc::clone(&virtio_rng_device) as VirtioDeviceArc,
+                iommu: rng_config.iommu,
+                id: id.clone(),
+                pci_segment: 0,
+                numa_node: None,
+                pci_identity: VirtioPciIdentity::default(),
+                transport: rng_config.transport,
+            });
 
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
@@ -2086,7 +3080,7 @@ impl DeviceManager {
     fn make_virtio_fs_device(
         &mut self,
         fs_cfg: &mut FsConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
         let id = if let Some(id) = &fs_cfg.id {
             id.clone()
         } else {
@@ -2132,11 +3126,11 @@ impl DeviceManager {
                 let (cache_base, cache_size) = if let Some((base, size)) = cache_range {
                     // The memory needs to be 2MiB aligned in order to support
                     // hugepages.
-                    self.address_manager
-                        .allocator
+                    self.pci_segment(fs_cfg.pci_segment)?
+                        .mmio_allocator
                         .lock()
                         .unwrap()
-                        .allocate_mmio_addresses(
+                        .allocate(
                             Some(GuestAddress(base)),
                             size as GuestUsize,
                             Some(0x0020_0000),
@@ -2149,11 +3143,11 @@ impl DeviceManager {
                     // The memory needs to be 2MiB aligned in order to support
                     // hugepages.
                     let base = self
-                        .address_manager
-                        .allocator
+                        .pci_segment(fs_cfg.pci_segment)?
+                        .mmio_allocator
                         .lock()
                         .unwrap()
-                        .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
+                        .allocate(None, size as GuestUsize, Some(0x0020_0000))
                         .ok_or(DeviceManagerError::FsRangeAllocation)?;
 
                     (base.raw_value(), size)
@@ -2220,21 +3214,33 @@ impl DeviceManager {
             node.migratable = Some(Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn Migratable>>);
             self.device_tree.lock().unwrap().insert(id.clone(), node);
 
-            Ok((Arc::clone(&virtio_fs_device) as VirtioDeviceArc, false, id))
+            Ok(MetaVirtioDevice {
+                virtio_device: Arc::clone(&virtio_fs_device) as VirtioDeviceArc,
+                iommu: false,
+                id,
+                pci_segment: fs_cfg.pci_segment,
+                numa_node: fs_cfg.numa_node,
+                pci_identity: VirtioPciIdentity::default(),
+                transport: VirtioTransportType::Pci,
+            })
         } else {
             Err(DeviceManagerError::NoVirtioFsSock)
         }
     }
 
-    fn make_virtio_fs_devices(
-        &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    fn make_virtio_fs_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
         let mut fs_devices = self.config.lock().unwrap().fs.clone();
         if let Some(fs_list_cfg) = &mut fs_devices {
             for fs_cfg in fs_list_cfg.iter_mut() {
-                devices.push(self.make_virtio_fs_device(fs_cfg)?);
+                devices.push(self.make_virtio_fs_device(fs_cfg).map_err(
+                    DeviceManagerError::device(
+                        fs_cfg.id.clone(),
+                        VirtioDeviceType::TYPE_FS,
+                        DeviceStage::Create,
+                    ),
+                )?);
             }
         }
         self.config.lock().unwrap().fs = fs_devices;
@@ -2245,7 +3251,7 @@ impl DeviceManager {
     fn make_virtio_pmem_device(
         &mut self,
         pmem_cfg: &mut PmemConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
         let id = if let Some(id) = &pmem_cfg.id {
             id.clone()
         } else {
@@ -2320,11 +3326,11 @@ impl DeviceManager {
         let (region_base, region_size) = if let Some((base, size)) = region_range {
             // The memory needs to be 2MiB aligned in order to support
             // hugepages.
-            self.address_manager
-                .allocator
+            self.pci_segment(pmem_cfg.pci_segment)?
+                .mmio_allocator
                 .lock()
                 .unwrap()
-                .allocate_mmio_addresses(
+                .allocate(
                     Some(GuestAddress(base)),
                     size as GuestUsize,
                     Some(0x0020_0000),
@@ -2336,11 +3342,11 @@ impl DeviceManager {
             // The memory needs to be 2MiB aligned in order to support
             // hugepages.
             let base = self
-                .address_manager
-                .allocator
+                .pci_segment(pmem_cfg.pci_segment)?
+                .mmio_allocator
                 .lock()
                 .unwrap()
-                .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
+                .allocate(None, size as GuestUsize, Some(0x0020_0000))
                 .ok_or(DeviceManagerError::PmemRangeAllocation)?;
 
             (base.raw_value(), size)
@@ -2405,22 +3411,30 @@ impl DeviceManager {
         node.migratable = Some(Arc::clone(&virtio_pmem_device) as Arc<Mutex<dyn Migratable>>);
         self.device_tree.lock().unwrap().insert(id.clone(), node);
 
-        Ok((
-            Arc::clone(&virtio_pmem_device) as VirtioDeviceArc,
-            pmem_cfg.iommu,
+        Ok(MetaVirtioDevice {
+            virtio_device: Arc::clone(&virtio_pmem_device) as VirtioDeviceArc,
+            iommu: pmem_cfg.iommu,
             id,
-        ))
+            pci_segment: pmem_cfg.pci_segment,
+            numa_node: pmem_cfg.numa_node,
+            pci_identity: VirtioPciIdentity::default(),
+            transport: VirtioTransportType::Pci,
+        })
     }
 
-    fn make_virtio_pmem_devices(
-        &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    fn make_virtio_pmem_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
         // Add virtio-pmem if required
         let mut pmem_devices = self.config.lock().unwrap().pmem.clone();
         if let Some(pmem_list_cfg) = &mut pmem_devices {
             for pmem_cfg in pmem_list_cfg.iter_mut() {
-                devices.push(self.make_virtio_pmem_device(pmem_cfg)?);
+                devices.push(self.make_virtio_pmem_device(pmem_cfg).map_err(
+                    DeviceManagerError::device(
+                        pmem_cfg.id.clone(),
+                        VirtioDeviceType::TYPE_PMEM,
+                        DeviceStage::Create,
+                    ),
+                )?);
             }
         }
         self.config.lock().unwrap().pmem = pmem_devices;
@@ -2431,7 +3445,7 @@ impl DeviceManager {
     fn make_virtio_vsock_device(
         &mut self,
         vsock_cfg: &mut VsockConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
         let id = if let Some(id) = &vsock_cfg.id {
             id.clone()
         } else {
@@ -2444,9 +3458,21 @@ impl DeviceManager {
             .socket
             .to_str()
             .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
-        let backend =
-            virtio_devices::vsock::VsockUnixBackend::new(vsock_cfg.cid, socket_path.to_string())
-                .map_err(DeviceManagerError::CreateVsockBackend)?;
+        let mut backend = virtio_devices::vsock::VsockUnixBackend::new(
+            vsock_cfg.cid,
+            socket_path.to_string(),
+            vsock_cfg.agent_port,
+        )
+        .map_err(DeviceManagerError::CreateVsockBackend)?;
+
+        if let Some(port) = vsock_cfg.completion_port {
+            backend.set_completion(
+                port,
+                self.vsock_completion_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            );
+        }
 
         let vsock_device = Arc::new(Mutex::new(
             virtio_devices::Vsock::new(
@@ -2468,30 +3494,36 @@ impl DeviceManager {
             .unwrap()
             .insert(id.clone(), device_node!(id, vsock_device));
 
-        Ok((
-            Arc::clone(&vsock_device) as VirtioDeviceArc,
-            vsock_cfg.iommu,
+        Ok(MetaVirtioDevice {
+            virtio_device: Arc::clone(&vsock_device) as VirtioDeviceArc,
+            iommu: vsock_cfg.iommu,
             id,
-        ))
+            pci_segment: vsock_cfg.pci_segment,
+            numa_node: None,
+            pci_identity: VirtioPciIdentity::default(),
+            transport: vsock_cfg.transport,
+        })
     }
 
-    fn make_virtio_vsock_devices(
-        &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    fn make_virtio_vsock_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
         let mut vsock = self.config.lock().unwrap().vsock.clone();
         if let Some(ref mut vsock_cfg) = &mut vsock {
-            devices.push(self.make_virtio_vsock_device(vsock_cfg)?);
+            devices.push(self.make_virtio_vsock_device(vsock_cfg).map_err(
+                DeviceManagerError::device(
+                    vsock_cfg.id.clone(),
+                    VirtioDeviceType::TYPE_VSOCK,
+                    DeviceStage::Create,
+                ),
+            )?);
         }
         self.config.lock().unwrap().vsock = vsock;
 
         Ok(devices)
     }
 
-    fn make_virtio_mem_devices(
-        &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    fn make_virtio_mem_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
         let mm = self.memory_manager.clone();
@@ -2522,11 +3554,17 @@ impl DeviceManager {
                     .map_err(DeviceManagerError::CreateVirtioMem)?,
                 ));
 
-                devices.push((
-                    Arc::clone(&virtio_mem_device) as VirtioDeviceArc,
-                    false,
-                    id.clone(),
-                ));
+                self.virtio_mem_devices.push(virtio_mem_device.clone());
+
+                devices.push(MetaVirtioDevice {
+                    virtio_device: Arc::clone(&virtio_mem_device) as VirtioDeviceArc,
+                    iommu: false,
+                    id: id.clone(),
+                    pci_segment: 0,
+                    numa_node: None,
+                    pci_identity: VirtioPciIdentity::default(),
+                    transport: VirtioTransportType::Pci,
+                });
 
                 // Fill the device tree with a new node. In case of restore, we
                 // know there is nothing to do, so we can simply override the
@@ -2541,9 +3579,7 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_balloon_devices(
-        &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    fn make_virtio_balloon_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
         if let Some(balloon_config) = &self.config.lock().unwrap().balloon {
@@ -2553,6 +3589,7 @@ impl DeviceManager {
                 virtio_devices::Balloon::new(
                     id.clone(),
                     balloon_config.size,
+                    balloon_config.free_page_hinting,
                     self.seccomp_action.clone(),
                 )
                 .map_err(DeviceManagerError::CreateVirtioBalloon)?,
@@ -2560,11 +3597,15 @@ impl DeviceManager {
 
             self.balloon = Some(virtio_balloon_device.clone());
 
-            devices.push((
-                Arc::clone(&virtio_balloon_device) as VirtioDeviceArc,
-                false,
-                id.clone(),
-            ));
+            devices.push(MetaVirtioDevice {
+                virtio_device: Arc::clone(&virtio_balloon_device) as VirtioDeviceArc,
+                iommu: false,
+                id: id.clone(),
+                pci_segment: 0,
+                numa_node: None,
+                pci_identity: VirtioPciIdentity::default(),
+                transport: VirtioTransportType::Pci,
+            });
 
             self.device_tree
                 .lock()
@@ -2575,9 +3616,7 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_watchdog_devices(
-        &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    fn make_virtio_watchdog_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
         if !self.config.lock().unwrap().watchdog {
@@ -2594,11 +3633,15 @@ impl DeviceManager {
             )
             .map_err(DeviceManagerError::CreateVirtioWatchdog)?,
         ));
-        devices.push((
-            Arc::clone(&virtio_watchdog_device) as VirtioDeviceArc,
-            false,
-            id.clone(),
-        ));
+        devices.push(MetaVirtioDevice {
+            virtio_device: Arc::clone(&virtio_watchdog_device) as VirtioDeviceArc,
+            iommu: false,
+            id: id.clone(),
+            pci_segment: 0,
+            numa_node: None,
+            pci_identity: VirtioPciIdentity::default(),
+            transport: VirtioTransportType::Pci,
+        });
 
         self.device_tree
             .lock()
@@ -2608,60 +3651,180 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn next_device_name(&mut self, prefix: &str) -> DeviceManagerResult<String> {
-        let start_id = self.device_id_cnt;
-        loop {
-            // Generate the temporary name.
-            let name = format!("{}{}", prefix, self.device_id_cnt);
-            // Increment the counter.
-            self.device_id_cnt += Wrapping(1);
-            // Check if the name is already in use.
-            if !self.pci_id_list.contains_key(&name) {
-                return Ok(name);
-            }
+    fn make_virtio_input_device(
+        &mut self,
+        input_cfg: &mut InputConfig,
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
+        let id = if let Some(id) = &input_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(INPUT_DEVICE_NAME_PREFIX)?;
+            input_cfg.id = Some(id.clone());
+            id
+        };
 
-            if self.device_id_cnt == start_id {
-                // We went through a full loop and there's nothing else we can
-                // do.
-                break;
-            }
-        }
-        Err(DeviceManagerError::NoAvailableDeviceName)
-    }
+        let source = match input_cfg.kind {
+            InputKind::Keyboard => virtio_devices::InputSource::Keyboard,
+            InputKind::Mouse => virtio_devices::InputSource::Mouse,
+            InputKind::Tablet => virtio_devices::InputSource::Tablet,
+            // The configuration validation guarantees the path is set.
+            InputKind::Evdev => virtio_devices::InputSource::Evdev(input_cfg.path.clone().unwrap()),
+        };
 
-    #[cfg_attr(not(feature = "kvm"), allow(unused_variables))]
-    fn add_passthrough_device(
-        &mut self,
-        pci: &mut PciBus,
-        device_cfg: &mut DeviceConfig,
-    ) -> DeviceManagerResult<(u32, String)> {
-        #[cfg(feature = "kvm")]
-        return self.add_vfio_device(pci, device_cfg);
+        let virtio_input_device = Arc::new(Mutex::new(
+            virtio_devices::Input::new(id.clone(), source, self.seccomp_action.clone())
+                .map_err(DeviceManagerError::CreateVirtioInput)?,
+        ));
 
-        #[cfg(not(feature = "kvm"))]
-        Err(DeviceManagerError::NoDevicePassthroughSupport)
-    }
+        self.input_devices
+            .insert(id.clone(), virtio_input_device.clone());
 
-    #[cfg(feature = "kvm")]
-    fn add_vfio_device(
-        &mut self,
-        pci: &mut PciBus,
-        device_cfg: &mut DeviceConfig,
+        // Fill the device tree with a new node. In case of restore, we
+        // know there is nothing to do, so we can simply override the
+        // existing entry.
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, virtio_input_device));
+
+        Ok(MetaVirtioDevice {
+            virtio_device: Arc::clone(&virtio_input_device) as VirtioDeviceArc,
+            iommu: false,
+            id,
+            pci_segment: input_cfg.pci_segment,
+            numa_node: None,
+            pci_identity: VirtioPciIdentity::default(),
+            transport: VirtioTransportType::Pci,
+        })
+    }
+
+    fn make_virtio_input_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
+        let mut devices = Vec::new();
+
+        let mut input_devices = self.config.lock().unwrap().input.clone();
+        if let Some(input_list_cfg) = &mut input_devices {
+            for input_cfg in input_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_input_device(input_cfg).map_err(
+                    DeviceManagerError::device(
+                        input_cfg.id.clone(),
+                        VirtioDeviceType::TYPE_INPUT,
+                        DeviceStage::Create,
+                    ),
+                )?);
+            }
+        }
+        self.config.lock().unwrap().input = input_devices;
+
+        Ok(devices)
+    }
+
+    fn make_virtio_gpio_device(
+        &mut self,
+        gpio_cfg: &mut GpioConfig,
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
+        let id = if let Some(id) = &gpio_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(GPIO_DEVICE_NAME_PREFIX)?;
+            gpio_cfg.id = Some(id.clone());
+            id
+        };
+
+        let mut gpio =
+            virtio_devices::Gpio::new(id.clone(), gpio_cfg.num_lines, self.seccomp_action.clone())
+                .map_err(DeviceManagerError::CreateVirtioGpio)?;
+        gpio.set_observer(Arc::new(GpioLogger { id: id.clone() }));
+        let virtio_gpio_device = Arc::new(Mutex::new(gpio));
+
+        self.gpio_devices
+            .insert(id.clone(), virtio_gpio_device.clone());
+
+        // Fill the device tree with a new node. In case of restore, we
+        // know there is nothing to do, so we can simply override the
+        // existing entry.
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, virtio_gpio_device));
+
+        Ok(MetaVirtioDevice {
+            virtio_device: Arc::clone(&virtio_gpio_device) as VirtioDeviceArc,
+            iommu: false,
+            id,
+            pci_segment: gpio_cfg.pci_segment,
+            numa_node: None,
+            pci_identity: VirtioPciIdentity::default(),
+            transport: VirtioTransportType::Pci,
+        })
+    }
+
+    fn make_virtio_gpio_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
+        let mut devices = Vec::new();
+
+        let mut gpio_devices = self.config.lock().unwrap().gpio.clone();
+        if let Some(gpio_list_cfg) = &mut gpio_devices {
+            for gpio_cfg in gpio_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_gpio_device(gpio_cfg).map_err(
+                    DeviceManagerError::device(
+                        gpio_cfg.id.clone(),
+                        VirtioDeviceType::TYPE_GPIO,
+                        DeviceStage::Create,
+                    ),
+                )?);
+            }
+        }
+        self.config.lock().unwrap().gpio = gpio_devices;
+
+        Ok(devices)
+    }
+
+    fn next_device_name(&mut self, prefix: &str) -> DeviceManagerResult<String> {
+        let start_id = self.device_id_cnt;
+        loop {
+            // Generate the temporary name.
+            let name = format!("{}{}", prefix, self.device_id_cnt);
+            // Increment the counter.
+            self.device_id_cnt += Wrapping(1);
+            // Check if the name is already in use.
+            if !self.pci_id_list.contains_key(&name) && !self.cold_devices.contains_key(&name) {
+                return Ok(name);
+            }
+
+            if self.device_id_cnt == start_id {
+                // We went through a full loop and there's nothing else we can
+                // do.
+                break;
+            }
+        }
+        Err(DeviceManagerError::NoAvailableDeviceName)
+    }
+
+    #[cfg_attr(not(feature = "kvm"), allow(unused_variables))]
+    fn add_passthrough_device(
+        &mut self,
+        device_cfg: &mut DeviceConfig,
+    ) -> DeviceManagerResult<(u32, String)> {
+        #[cfg(feature = "kvm")]
+        return self.add_vfio_device(device_cfg);
+
+        #[cfg(not(feature = "kvm"))]
+        Err(DeviceManagerError::NoDevicePassthroughSupport)
+    }
+
+    #[cfg(feature = "kvm")]
+    fn add_vfio_device(
+        &mut self,
+        device_cfg: &mut DeviceConfig,
     ) -> DeviceManagerResult<(u32, String)> {
         let passthrough_device = self
             .passthrough_device
             .as_ref()
             .ok_or(DeviceManagerError::NoDevicePassthroughSupport)?;
 
-        // We need to shift the device id since the 3 first bits
-        // are dedicated to the PCI function, and we know we don't
-        // do multifunction. Also, because we only support one PCI
-        // bus, the bus 0, we don't need to add anything to the
-        // global device ID.
-        let pci_device_bdf = pci
-            .next_device_id()
-            .map_err(DeviceManagerError::NextPciDeviceId)?
-            << 3;
+        let pci_segment_id = device_cfg.pci_segment;
+        let pci_device_bdf = self.pci_segment(pci_segment_id)?.next_device_bdf()?;
+        self.pci_segment_mut(pci_segment_id)?
+            .set_device_numa_node(pci_device_bdf, device_cfg.numa_node);
 
         let memory = self.memory_manager.lock().unwrap().guest_memory();
 
@@ -2710,20 +3873,7 @@ impl DeviceManager {
             }
         }
 
-        let legacy_interrupt_group = if let (Some(irq), Some(legacy_interrupt_manager)) = (
-            self.pci_device_irqs.get(&pci_device_bdf),
-            &self.legacy_interrupt_manager,
-        ) {
-            Some(
-                legacy_interrupt_manager
-                    .create_group(LegacyIrqGroupConfig {
-                        irq: *irq as InterruptIndex,
-                    })
-                    .map_err(DeviceManagerError::CreateInterruptGroup)?,
-            )
-        } else {
-            None
-        };
+        let legacy_interrupt_group = self.legacy_interrupt_group(pci_device_bdf)?;
 
         let memory = self.memory_manager.lock().unwrap().guest_memory();
         let mut vfio_pci_device = VfioPciDevice::new(
@@ -2754,6 +3904,9 @@ impl DeviceManager {
             .map_err(DeviceManagerError::VfioMapRegion)?;
 
         let mut node = device_node!(vfio_name);
+        if device_cfg.iommu && self.iommu_device.is_some() {
+            node.dependencies.push(iommu_pci_device_id());
+        }
 
         for region in vfio_pci_device.mmio_regions() {
             node.resources.push(Resource::MmioAddressRange {
@@ -2765,7 +3918,6 @@ impl DeviceManager {
         let vfio_pci_device = Arc::new(Mutex::new(vfio_pci_device));
 
         self.add_pci_device(
-            pci,
             vfio_pci_device.clone(),
             vfio_pci_device.clone(),
             vfio_pci_device,
@@ -2780,46 +3932,138 @@ impl DeviceManager {
         Ok((pci_device_bdf, vfio_name))
     }
 
+    fn pci_segment(&self, pci_segment_id: u16) -> DeviceManagerResult<&PciSegment> {
+        self.pci_segments
+            .get(pci_segment_id as usize)
+            .ok_or(DeviceManagerError::InvalidPciSegment(pci_segment_id))
+    }
+
+    fn pci_segment_mut(&mut self, pci_segment_id: u16) -> DeviceManagerResult<&mut PciSegment> {
+        self.pci_segments
+            .get_mut(pci_segment_id as usize)
+            .ok_or(DeviceManagerError::InvalidPciSegment(pci_segment_id))
+    }
+
+    // Create the interrupt group for the legacy IRQ assigned to the slot of
+    // the device identified by the b/d/f.
+    fn legacy_interrupt_group(
+        &self,
+        pci_device_bdf: u32,
+    ) -> DeviceManagerResult<Option<Arc<Box<dyn InterruptSourceGroup>>>> {
+        let irq = self
+            .pci_segment(pci_bdf_segment(pci_device_bdf))?
+            .pci_irq_slots[pci_bdf_device(pci_device_bdf) as usize];
+
+        if let Some(legacy_interrupt_manager) = &self.legacy_interrupt_manager {
+            Ok(Some(
+                legacy_interrupt_manager
+                    .create_group(LegacyIrqGroupConfig {
+                        irq: irq as InterruptIndex,
+                    })
+                    .map_err(DeviceManagerError::CreateInterruptGroup)?,
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn add_pci_device(
         &mut self,
-        pci_bus: &mut PciBus,
         bus_device: Arc<Mutex<dyn BusDevice>>,
         pci_device: Arc<Mutex<dyn PciDevice>>,
         any_device: Arc<dyn Any + Send + Sync>,
         bdf: u32,
         device_id: String,
     ) -> DeviceManagerResult<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>> {
+        if self.pci_id_list.contains_key(&device_id) {
+            return Err(DeviceManagerError::DeviceIdAlreadyInUse);
+        }
+
+        let pci_segment = self.pci_segment(pci_bdf_segment(bdf))?;
+        let pci_bus = Arc::clone(&pci_segment.pci_bus);
+
         let bars = pci_device
             .lock()
             .unwrap()
-            .allocate_bars(&mut self.address_manager.allocator.lock().unwrap())
+            .allocate_bars(
+                &mut self.address_manager.allocator.lock().unwrap(),
+                &mut pci_segment.mmio_allocator.lock().unwrap(),
+                &mut pci_segment.mmio_hole_allocator.lock().unwrap(),
+            )
             .map_err(DeviceManagerError::AllocateBars)?;
 
-        pci_bus
-            .add_device(bdf, pci_device)
-            .map_err(DeviceManagerError::AddPciDevice)?;
+        let result = {
+            let mut pci_bus = pci_bus.lock().unwrap();
+            pci_bus
+                .add_device(bdf, Arc::clone(&pci_device))
+                .and_then(|_| {
+                    pci_bus.register_mapping(
+                        Arc::clone(&bus_device),
+                        #[cfg(target_arch = "x86_64")]
+                        self.address_manager.io_bus.as_ref(),
+                        self.address_manager.mmio_bus.as_ref(),
+                        bars.clone(),
+                    )
+                })
+        };
+        if let Err(e) = result {
+            self.unplug_pci_device(bdf, &pci_device, &bus_device);
+            return Err(DeviceManagerError::AddPciDevice(e));
+        }
 
         self.pci_devices.insert(bdf, any_device);
-        self.bus_devices.push(Arc::clone(&bus_device));
+        self.bus_devices.push(bus_device);
+        self.pci_id_list.insert(device_id, bdf);
+        Ok(bars)
+    }
 
-        pci_bus
-            .register_mapping(
-                bus_device,
-                #[cfg(target_arch = "x86_64")]
-                self.address_manager.io_bus.as_ref(),
-                self.address_manager.mmio_bus.as_ref(),
-                bars.clone(),
-            )
-            .map_err(DeviceManagerError::AddPciDevice)?;
+    /// Undo add_pci_device(), for a device which failed to be plugged, so
+    /// that its BARs and bus ranges are free for the next device. As this
+    /// runs on an error path, the failures are only logged.
+    fn unplug_pci_device(
+        &mut self,
+        bdf: u32,
+        pci_device: &Arc<Mutex<dyn PciDevice>>,
+        bus_device: &Arc<Mutex<dyn BusDevice>>,
+    ) {
+        self.pci_id_list
+            .retain(|_, pci_device_bdf| *pci_device_bdf != bdf);
+        self.pci_devices.remove(&bdf);
+        self.bus_devices.retain(|dev| !Arc::ptr_eq(dev, bus_device));
 
-        if self.pci_id_list.contains_key(&device_id) {
-            return Err(DeviceManagerError::DeviceIdAlreadyInUse);
+        #[cfg(target_arch = "x86_64")]
+        if let Err(e) = self.io_bus().remove_by_device(bus_device) {
+            error!("Failed to remove the device from the IO bus: {:?}", e);
+        }
+        if let Err(e) = self.mmio_bus().remove_by_device(bus_device) {
+            error!("Failed to remove the device from the MMIO bus: {:?}", e);
+        }
+
+        let pci_segment = match self.pci_segment(pci_bdf_segment(bdf)) {
+            Ok(pci_segment) => pci_segment,
+            Err(e) => {
+                error!("Failed to unplug the PCI device: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = pci_segment
+            .pci_bus
+            .lock()
+            .unwrap()
+            .remove_by_device(pci_device)
+        {
+            error!("Failed to remove the device from the PCI bus: {:?}", e);
+        }
+        if let Err(e) = pci_device.lock().unwrap().free_bars(
+            &mut self.address_manager.allocator.lock().unwrap(),
+            &mut pci_segment.mmio_allocator.lock().unwrap(),
+            &mut pci_segment.mmio_hole_allocator.lock().unwrap(),
+        ) {
+            error!("Failed to free the PCI BARs: {:?}", e);
         }
-        self.pci_id_list.insert(device_id, bdf);
-        Ok(bars)
     }
 
-    fn add_vfio_devices(&mut self, pci: &mut PciBus) -> DeviceManagerResult<Vec<u32>> {
+    fn add_vfio_devices(&mut self) -> DeviceManagerResult<Vec<u32>> {
         let mut iommu_attached_device_ids = Vec::new();
         let mut devices = self.config.lock().unwrap().devices.clone();
 
@@ -2835,7 +4079,13 @@ impl DeviceManager {
             }
 
             for device_cfg in device_list_cfg.iter_mut() {
-                let (device_id, _) = self.add_passthrough_device(pci, device_cfg)?;
+                let (device_id, _) =
+                    self.add_passthrough_device(device_cfg)
+                        .map_err(DeviceManagerError::device(
+                            device_cfg.id.clone(),
+                            "vfio",
+                            DeviceStage::Plug,
+                        ))?;
                 if device_cfg.iommu && self.iommu_device.is_some() {
                     iommu_attached_device_ids.push(device_id);
                 }
@@ -2848,18 +4098,177 @@ impl DeviceManager {
         Ok(iommu_attached_device_ids)
     }
 
+    fn add_vfio_user_device(
+        &mut self,
+        device_cfg: &mut UserDeviceConfig,
+    ) -> DeviceManagerResult<(u32, String)> {
+        // Check the name before the guest memory gets shared with the server.
+        let vfio_user_name = if let Some(id) = &device_cfg.id {
+            if self.pci_id_list.contains_key(id) {
+                return Err(DeviceManagerError::DeviceIdAlreadyInUse);
+            }
+
+            id.clone()
+        } else {
+            let id = self.next_device_name(VFIO_USER_DEVICE_NAME_PREFIX)?;
+            device_cfg.id = Some(id.clone());
+            id
+        };
+
+        let pci_segment_id = device_cfg.pci_segment;
+        let pci_device_bdf = self.pci_segment(pci_segment_id)?.next_device_bdf()?;
+        self.pci_segment_mut(pci_segment_id)?
+            .set_device_numa_node(pci_device_bdf, device_cfg.numa_node);
+
+        let legacy_interrupt_group = self.legacy_interrupt_group(pci_device_bdf)?;
+
+        let client = Arc::new(Mutex::new(
+            vfio_user::Client::new(&device_cfg.socket)
+                .map_err(DeviceManagerError::VfioUserCreateClient)?,
+        ));
+
+        let mut vfio_user_pci_device =
+            VfioUserPciDevice::new(client, &self.msi_interrupt_manager, legacy_interrupt_group)
+                .map_err(DeviceManagerError::VfioUserCreate)?;
+
+        // Share the whole guest memory with the vfio-user server so that
+        // the emulated device can perform DMA.
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        memory.memory().with_regions_mut(|_, region| {
+            vfio_user_pci_device
+                .dma_map(region)
+                .map_err(DeviceManagerError::VfioUserDmaMap)
+        })?;
+
+        let vfio_user_pci_device = Arc::new(Mutex::new(vfio_user_pci_device));
+
+        self.add_pci_device(
+            vfio_user_pci_device.clone(),
+            vfio_user_pci_device.clone(),
+            vfio_user_pci_device,
+            pci_device_bdf,
+            vfio_user_name.clone(),
+        )?;
+
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(vfio_user_name.clone(), device_node!(vfio_user_name));
+
+        Ok((pci_device_bdf, vfio_user_name))
+    }
+
+    fn add_vfio_user_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut user_devices = self.config.lock().unwrap().user_devices.clone();
+
+        if let Some(device_list_cfg) = &mut user_devices {
+            for device_cfg in device_list_cfg.iter_mut() {
+                self.add_vfio_user_device(device_cfg)
+                    .map_err(DeviceManagerError::device(
+                        device_cfg.id.clone(),
+                        "vfio-user",
+                        DeviceStage::Plug,
+                    ))?;
+            }
+        }
+
+        // Update the list of devices
+        self.config.lock().unwrap().user_devices = user_devices;
+
+        Ok(())
+    }
+
+    fn add_nvme_device(&mut self, disk_cfg: &mut DiskConfig) -> DeviceManagerResult<(u32, String)> {
+        let id = if let Some(id) = &disk_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(DISK_DEVICE_NAME_PREFIX)?;
+            disk_cfg.id = Some(id.clone());
+            id
+        };
+
+        let pci_device_bdf = self.pci_segment(disk_cfg.pci_segment)?.next_device_bdf()?;
+        self.pci_segment_mut(disk_cfg.pci_segment)?
+            .set_device_numa_node(pci_device_bdf, disk_cfg.numa_node);
+        let image = self.open_disk_image(disk_cfg)?;
+
+        let nvme_device = Arc::new(Mutex::new(
+            NvmePciDevice::new(
+                id.clone(),
+                self.memory_manager.lock().unwrap().guest_memory(),
+                image,
+                disk_cfg.readonly,
+                disk_cfg.num_queues,
+                disk_cfg.queue_size,
+                &self.msi_interrupt_manager,
+                pci_device_bdf,
+                self.seccomp_action.clone(),
+            )
+            .map_err(DeviceManagerError::CreateNvme)?,
+        ));
+
+        self.add_pci_device(
+            nvme_device.clone(),
+            nvme_device.clone(),
+            nvme_device.clone(),
+            pci_device_bdf,
+            id.clone(),
+        )?;
+
+        let migratable_device = nvme_device as Arc<Mutex<dyn Migratable>>;
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, migratable_device));
+
+        Ok((pci_device_bdf, id))
+    }
+
+    fn add_nvme_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut block_devices = self.config.lock().unwrap().disks.clone();
+
+        if let Some(disk_list_cfg) = &mut block_devices {
+            for disk_cfg in disk_list_cfg.iter_mut().filter(|disk_cfg| disk_cfg.nvme) {
+                self.add_nvme_device(disk_cfg)
+                    .map_err(DeviceManagerError::device(
+                        disk_cfg.id.clone(),
+                        "nvme",
+                        DeviceStage::Plug,
+                    ))?;
+            }
+        }
+
+        // Update the list of devices
+        self.config.lock().unwrap().disks = block_devices;
+
+        Ok(())
+    }
+
     fn add_virtio_pci_device(
         &mut self,
         virtio_device: VirtioDeviceArc,
-        pci: &mut PciBus,
         iommu_mapping: &Option<Arc<IommuMapping>>,
         virtio_device_id: String,
+        pci_segment_id: u16,
+        pci_identity: VirtioPciIdentity,
+        reserved_pci_device_bdf: Option<u32>,
     ) -> DeviceManagerResult<u32> {
         let id = format!("{}-{}", VIRTIO_PCI_DEVICE_NAME_PREFIX, virtio_device_id);
 
         // Add the new virtio-pci node to the device tree.
         let mut node = device_node!(id);
         node.children = vec![virtio_device_id.clone()];
+        // The device can't be activated before the IOMMU it is attached to.
+        if iommu_mapping.is_some() {
+            node.dependencies.push(iommu_pci_device_id());
+        }
+
+        // Update the existing virtio node by setting the parent.
+        if let Some(node) = self.device_tree.lock().unwrap().get_mut(&virtio_device_id) {
+            node.parent = Some(id.clone());
+        } else {
+            return Err(DeviceManagerError::MissingNode);
+        }
 
         // Look for the id in the device tree. If it can be found, that means
         // the device is being restored, otherwise it's created from scratch.
@@ -2870,7 +4279,11 @@ impl DeviceManager {
                     .pci_bdf
                     .ok_or(DeviceManagerError::MissingDeviceNodePciBdf)?;
 
-                pci.get_device_id((pci_device_bdf >> 3) as usize)
+                self.pci_segment(pci_bdf_segment(pci_device_bdf))?
+                    .pci_bus
+                    .lock()
+                    .unwrap()
+                    .get_device_id(pci_bdf_device(pci_device_bdf) as usize)
                     .map_err(DeviceManagerError::GetPciDeviceId)?;
 
                 if node.resources.is_empty() {
@@ -2888,25 +4301,52 @@ impl DeviceManager {
                 };
 
                 (pci_device_bdf, config_bar_addr)
+            } else if let Some(pci_device_bdf) = reserved_pci_device_bdf {
+                (pci_device_bdf, None)
             } else {
-                // We need to shift the device id since the 3 first bits are dedicated
-                // to the PCI function, and we know we don't do multifunction.
-                // Also, because we only support one PCI bus, the bus 0, we don't need
-                // to add anything to the global device ID.
-                let pci_device_bdf = pci
-                    .next_device_id()
-                    .map_err(DeviceManagerError::NextPciDeviceId)?
-                    << 3;
+                let pci_device_bdf = self.pci_segment(pci_segment_id)?.next_device_bdf()?;
 
                 (pci_device_bdf, None)
             };
 
-        // Update the existing virtio node by setting the parent.
-        if let Some(node) = self.device_tree.lock().unwrap().get_mut(&virtio_device_id) {
-            node.parent = Some(id.clone());
-        } else {
-            return Err(DeviceManagerError::MissingNode);
+        // Give the slot back if the device can't be plugged, unless the
+        // caller reserved it, in which case it keeps it.
+        match self.plug_virtio_pci_device(
+            node,
+            virtio_device,
+            iommu_mapping,
+            virtio_device_id,
+            pci_identity,
+            pci_device_bdf,
+            config_bar_addr,
+        ) {
+            Ok(()) => Ok(pci_device_bdf),
+            Err(e) => {
+                if reserved_pci_device_bdf.is_none() {
+                    if let Err(e) = self
+                        .pci_segment_mut(pci_bdf_segment(pci_device_bdf))
+                        .and_then(|pci_segment| pci_segment.put_device_bdf(pci_device_bdf))
+                    {
+                        error!("Failed to give the PCI slot back: {:?}", e);
+                    }
+                }
+                Err(e)
+            }
         }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn plug_virtio_pci_device(
+        &mut self,
+        mut node: DeviceNode,
+        virtio_device: VirtioDeviceArc,
+        iommu_mapping: &Option<Arc<IommuMapping>>,
+        virtio_device_id: String,
+        pci_identity: VirtioPciIdentity,
+        pci_device_bdf: u32,
+        config_bar_addr: Option<u64>,
+    ) -> DeviceManagerResult<()> {
+        let id = node.id.clone();
 
         // Allows support for one MSI-X vector per queue. It also adds 1
         // as we need to take into account the dedicated vector to notify
@@ -2934,6 +4374,15 @@ impl DeviceManager {
                 None
             };
 
+        // The INTx line of the slot is used when the guest doesn't enable
+        // MSI-X.
+        let legacy_irq = self
+            .pci_segment(pci_bdf_segment(pci_device_bdf))?
+            .pci_irq_slots[pci_bdf_device(pci_device_bdf) as usize];
+        let legacy_irq = self
+            .legacy_interrupt_group(pci_device_bdf)?
+            .map(|legacy_interrupt_group| (legacy_irq, legacy_interrupt_group));
+
         let memory = self.memory_manager.lock().unwrap().guest_memory();
         let mut virtio_pci_device = VirtioPciDevice::new(
             id.clone(),
@@ -2942,50 +4391,223 @@ impl DeviceManager {
             msix_num,
             iommu_mapping_cb,
             &self.msi_interrupt_manager,
+            legacy_irq,
             pci_device_bdf,
             self.activate_evt
                 .try_clone()
                 .map_err(DeviceManagerError::EventFd)?,
+            pci_identity,
         )
         .map_err(DeviceManagerError::VirtioDevice)?;
 
-        // This is important as this will set the BAR address if it exists,
-        // which is mandatory on the restore path.
-        if let Some(addr) = config_bar_addr {
-            virtio_pci_device.set_config_bar_addr(addr);
-        }
+        // The guest can't share its encrypted memory with the devices, and
+        // must bounce their buffers through unencrypted memory.
+        #[cfg(target_arch = "x86_64")]
+        if self.config.lock().unwrap().sev.is_some() {
+            virtio_pci_device.set_access_platform(true);
+        }
+
+        #[cfg(feature = "access_log")]
+        virtio_pci_device.set_access_log(self.access_log_enabled(&virtio_device_id));
+
+        // This is important as this will set the BAR address if it exists,
+        // which is mandatory on the restore path.
+        if let Some(addr) = config_bar_addr {
+            virtio_pci_device.set_config_bar_addr(addr);
+        }
+
+        let virtio_pci_device = Arc::new(Mutex::new(virtio_pci_device));
+        let bars = self.add_pci_device(
+            virtio_pci_device.clone(),
+            virtio_pci_device.clone(),
+            virtio_pci_device.clone(),
+            pci_device_bdf,
+            virtio_device_id,
+        )?;
+
+        if let Err(e) = self.register_virtio_pci_ioevents(&virtio_pci_device) {
+            self.unplug_pci_device(
+                pci_device_bdf,
+                &(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn PciDevice>>),
+                &(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn BusDevice>>),
+            );
+            return Err(e);
+        }
+
+        // Update the device tree with correct resource information.
+        for pci_bar in bars.iter() {
+            node.resources.push(Resource::MmioAddressRange {
+                base: pci_bar.0.raw_value(),
+                size: pci_bar.1 as u64,
+            });
+        }
+        node.migratable = Some(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn Migratable>>);
+        node.pci_bdf = Some(pci_device_bdf);
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(())
+    }
+
+    /// Register the ioeventfds of the queues of the virtio-pci device, either
+    /// all of them or none.
+    fn register_virtio_pci_ioevents(
+        &self,
+        virtio_pci_device: &Arc<Mutex<VirtioPciDevice>>,
+    ) -> DeviceManagerResult<()> {
+        let virtio_pci_device = virtio_pci_device.lock().unwrap();
+        let ioeventfds = virtio_pci_device.ioeventfds(virtio_pci_device.config_bar_addr());
+        for (i, (event, addr)) in ioeventfds.iter().enumerate() {
+            if let Err(e) =
+                self.address_manager
+                    .vm
+                    .register_ioevent(event, &IoEventAddress::Mmio(*addr), None)
+            {
+                for (event, addr) in ioeventfds[..i].iter() {
+                    if let Err(e) = self
+                        .address_manager
+                        .vm
+                        .unregister_ioevent(event, &IoEventAddress::Mmio(*addr))
+                    {
+                        error!("Failed to unregister ioevent: {:?}", e);
+                    }
+                }
+                return Err(DeviceManagerError::RegisterIoevent(e.into()));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "access_log")]
+    fn access_log_enabled(&self, id: &str) -> bool {
+        self.config
+            .lock()
+            .unwrap()
+            .access_log
+            .as_ref()
+            .map_or(false, |ids| ids.iter().any(|i| i == id))
+    }
+
+    fn add_virtio_mmio_device(
+        &mut self,
+        virtio_device: VirtioDeviceArc,
+        virtio_device_id: String,
+    ) -> DeviceManagerResult<()> {
+        let id = format!("{}-{}", VIRTIO_MMIO_DEVICE_NAME_PREFIX, virtio_device_id);
+
+        // Add the new virtio-mmio node to the device tree.
+        let mut node = device_node!(id);
+        node.children = vec![virtio_device_id.clone()];
+
+        // Update the existing virtio node by setting the parent.
+        if let Some(node) = self.device_tree.lock().unwrap().get_mut(&virtio_device_id) {
+            node.parent = Some(id.clone());
+        } else {
+            return Err(DeviceManagerError::MissingNode);
+        }
+
+        // The devices are plugged in the same order on restore, getting the
+        // same resources again.
+        let addr = self
+            .virtio_mmio_allocator
+            .allocate(None, VIRTIO_MMIO_SIZE, Some(VIRTIO_MMIO_SIZE))
+            .ok_or(DeviceManagerError::AllocateMMIOAddress)?;
+        let irq = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_irq()
+            .ok_or(DeviceManagerError::AllocateIrq)?;
+
+        let interrupt_group = self
+            .legacy_interrupt_manager
+            .as_ref()
+            .ok_or(DeviceManagerError::NoLegacyInterruptManager)?
+            .create_group(LegacyIrqGroupConfig {
+                irq: irq as InterruptIndex,
+            })
+            .map_err(DeviceManagerError::CreateInterruptGroup)?;
+
+        let device_type = virtio_device.lock().unwrap().device_type();
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        let virtio_mmio_device = Arc::new(Mutex::new(
+            VirtioMmioDevice::new(
+                id.clone(),
+                memory,
+                virtio_device,
+                interrupt_group,
+                self.activate_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )
+            .map_err(DeviceManagerError::VirtioDevice)?,
+        ));
 
-        let virtio_pci_device = Arc::new(Mutex::new(virtio_pci_device));
-        let bars = self.add_pci_device(
-            pci,
-            virtio_pci_device.clone(),
-            virtio_pci_device.clone(),
-            virtio_pci_device.clone(),
-            pci_device_bdf,
-            virtio_device_id,
-        )?;
+        #[cfg(feature = "access_log")]
+        virtio_mmio_device
+            .lock()
+            .unwrap()
+            .set_access_log(self.access_log_enabled(&virtio_device_id));
+
+        self.bus_devices
+            .push(Arc::clone(&virtio_mmio_device) as Arc<Mutex<dyn BusDevice>>);
+        self.address_manager
+            .mmio_bus
+            .insert(virtio_mmio_device.clone(), addr.0, VIRTIO_MMIO_SIZE)
+            .map_err(DeviceManagerError::BusError)?;
 
-        let bar_addr = virtio_pci_device.lock().unwrap().config_bar_addr();
-        for (event, addr) in virtio_pci_device.lock().unwrap().ioeventfds(bar_addr) {
-            let io_addr = IoEventAddress::Mmio(addr);
+        for (event, addr, queue_index) in virtio_mmio_device.lock().unwrap().ioeventfds(addr.0) {
             self.address_manager
                 .vm
-                .register_ioevent(event, &io_addr, None)
+                .register_ioevent(
+                    event,
+                    &IoEventAddress::Mmio(addr),
+                    Some(hypervisor::DataMatch::DataMatch32(queue_index)),
+                )
                 .map_err(|e| DeviceManagerError::RegisterIoevent(e.into()))?;
         }
 
-        // Update the device tree with correct resource information.
-        for pci_bar in bars.iter() {
-            node.resources.push(Resource::MmioAddressRange {
-                base: pci_bar.0.raw_value(),
-                size: pci_bar.1 as u64,
-            });
-        }
-        node.migratable = Some(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn Migratable>>);
-        node.pci_bdf = Some(pci_device_bdf);
+        #[cfg(target_arch = "aarch64")]
+        self.id_to_dev_info.insert(
+            (DeviceType::Virtio(device_type), virtio_device_id.clone()),
+            MMIODeviceInfo {
+                addr: addr.0,
+                len: VIRTIO_MMIO_SIZE,
+                irq,
+            },
+        );
+
+        // Without the ACPI tables nor a device tree, the guest finds the
+        // devices through its command line.
+        #[cfg(all(target_arch = "x86_64", not(feature = "acpi")))]
+        self.cmdline_additions.push(format!(
+            "virtio_mmio.device={}K@0x{:08x}:{}",
+            VIRTIO_MMIO_SIZE >> 10,
+            addr.0,
+            irq
+        ));
+
+        node.resources.push(Resource::MmioAddressRange {
+            base: addr.0,
+            size: VIRTIO_MMIO_SIZE,
+        });
+        node.resources.push(Resource::LegacyIrq(irq));
+        node.migratable = Some(Arc::clone(&virtio_mmio_device) as Arc<Mutex<dyn Migratable>>);
         self.device_tree.lock().unwrap().insert(id, node);
 
-        Ok(pci_device_bdf)
+        info!(
+            "Plugged virtio device {} (type {}) on virtio-mmio at 0x{:x}, IRQ {}",
+            virtio_device_id, device_type, addr.0, irq
+        );
+        self.virtio_mmio_devices.push(VirtioMmioDeviceHandle {
+            device: virtio_mmio_device,
+            id: virtio_device_id,
+            addr: addr.0,
+            irq,
+        });
+
+        Ok(())
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -3001,6 +4623,10 @@ impl DeviceManager {
         &self.address_manager.allocator
     }
 
+    pub(crate) fn pci_segments(&self) -> &[PciSegment] {
+        &self.pci_segments
+    }
+
     pub fn interrupt_controller(&self) -> Option<Arc<Mutex<dyn InterruptController>>> {
         if let Some(interrupt_controller) = &self.interrupt_controller {
             Some(interrupt_controller.clone() as Arc<Mutex<dyn InterruptController>>)
@@ -3017,10 +4643,75 @@ impl DeviceManager {
         self.cmdline_additions.as_slice()
     }
 
-    pub fn update_memory(&self, _new_region: &Arc<GuestRegionMmap>) -> DeviceManagerResult<()> {
+    /// Gets the devices writing to the guest memory behind the back of the
+    /// VMM to log what they write to, for the memory to be migrated.
+    pub fn start_dirty_log(&self) -> std::result::Result<(), MigratableError> {
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        for handle in self.virtio_devices.iter() {
+            handle
+                .virtio_device
+                .lock()
+                .unwrap()
+                .start_dirty_log(&memory.memory())
+                .map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!(
+                        "Error starting the dirty log of {}: {:?}",
+                        handle.id,
+                        e
+                    ))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn stop_dirty_log(&self) -> std::result::Result<(), MigratableError> {
         let memory = self.memory_manager.lock().unwrap().guest_memory();
-        for (virtio_device, _, _) in self.virtio_devices.iter() {
-            virtio_device
+        for handle in self.virtio_devices.iter() {
+            handle
+                .virtio_device
+                .lock()
+                .unwrap()
+                .stop_dirty_log(&memory.memory())
+                .map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!(
+                        "Error stopping the dirty log of {}: {:?}",
+                        handle.id,
+                        e
+                    ))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// The guest memory the devices wrote to since the previous call.
+    pub fn dirty_log(&self) -> std::result::Result<MemoryRangeTable, MigratableError> {
+        let mut table = MemoryRangeTable::default();
+        for handle in self.virtio_devices.iter() {
+            let device_table = handle
+                .virtio_device
+                .lock()
+                .unwrap()
+                .dirty_log()
+                .map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!(
+                        "Error getting the dirty log of {}: {:?}",
+                        handle.id,
+                        e
+                    ))
+                })?;
+            table.extend(device_table);
+        }
+
+        Ok(table)
+    }
+
+    pub fn update_memory(&self, new_region: &Arc<GuestRegionMmap>) -> DeviceManagerResult<()> {
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        for handle in self.virtio_devices.iter() {
+            handle
+                .virtio_device
                 .lock()
                 .unwrap()
                 .update_memory(&memory.memory())
@@ -3033,24 +4724,98 @@ impl DeviceManager {
                 vfio_pci_device
                     .lock()
                     .unwrap()
-                    .update_memory(_new_region)
+                    .update_memory(new_region)
                     .map_err(DeviceManagerError::UpdateMemoryForVfioPciDevice)?;
             }
         }
 
+        // Take care of updating the memory for vfio-user PCI devices.
+        for (_, any_device) in self.pci_devices.iter() {
+            if let Ok(vfio_user_pci_device) =
+                Arc::clone(any_device).downcast::<Mutex<VfioUserPciDevice>>()
+            {
+                vfio_user_pci_device
+                    .lock()
+                    .unwrap()
+                    .dma_map(new_region)
+                    .map_err(DeviceManagerError::UpdateMemoryForVfioUserPciDevice)?;
+            }
+        }
+
         Ok(())
     }
 
-    pub fn activate_virtio_devices(&self) -> DeviceManagerResult<()> {
+    pub fn activate_virtio_devices(&mut self) -> DeviceManagerResult<()> {
+        // Activate the devices in a deterministic order, each one after the
+        // devices it depends on.
+        let pci_device_bdfs: Vec<u32> = self
+            .device_tree
+            .lock()
+            .unwrap()
+            .dependency_order()
+            .map_err(DeviceManagerError::DeviceDependencies)?
+            .iter()
+            .filter_map(|node| node.pci_bdf)
+            .collect();
+
+        // The devices pending after one failing their activation are still
+        // activated, the vCPUs which made them ready waiting for it. The
+        // first failure is returned.
+        let mut result = Ok(());
+
         // Find virtio pci devices and activate any pending ones
-        for (_, any_device) in self.pci_devices.iter() {
-            if let Ok(virtio_pci_device) =
-                Arc::clone(any_device).downcast::<Mutex<VirtioPciDevice>>()
-            {
-                virtio_pci_device.lock().unwrap().maybe_activate();
+        for pci_device_bdf in pci_device_bdfs {
+            if let Some(any_device) = self.pci_devices.get(&pci_device_bdf) {
+                if let Ok(virtio_pci_device) =
+                    Arc::clone(any_device).downcast::<Mutex<VirtioPciDevice>>()
+                {
+                    let mut virtio_pci_device = virtio_pci_device.lock().unwrap();
+                    // The threads of a device the driver reset were resumed
+                    // and killed, the new ones are not paused.
+                    if virtio_pci_device.needs_activation() {
+                        if let Some(id) = self.pci_device_name(pci_device_bdf) {
+                            self.paused_devices.reset(&id);
+                        }
+                    }
+                    let virtio_device = virtio_pci_device.virtio_device();
+                    let device_type =
+                        VirtioDeviceType::from(virtio_device.lock().unwrap().device_type());
+                    let activation = virtio_pci_device
+                        .maybe_activate()
+                        .map_err(DeviceManagerError::ActivateVirtioDevice)
+                        .map_err(DeviceManagerError::device(
+                            self.pci_device_name(pci_device_bdf),
+                            device_type,
+                            DeviceStage::Activate,
+                        ));
+                    result = result.and(activation);
+                }
             }
         }
-        Ok(())
+
+        for handle in self.virtio_mmio_devices.iter() {
+            let mut virtio_mmio_device = handle.device.lock().unwrap();
+            let virtio_device = virtio_mmio_device.virtio_device();
+            let device_type = VirtioDeviceType::from(virtio_device.lock().unwrap().device_type());
+            let activation = virtio_mmio_device
+                .maybe_activate()
+                .map_err(DeviceManagerError::ActivateVirtioDevice)
+                .map_err(DeviceManagerError::device(
+                    Some(handle.id.clone()),
+                    device_type,
+                    DeviceStage::Activate,
+                ));
+            result = result.and(activation);
+        }
+
+        result
+    }
+
+    fn pci_device_name(&self, pci_device_bdf: u32) -> Option<String> {
+        self.pci_id_list
+            .iter()
+            .find(|(_, bdf)| **bdf == pci_device_bdf)
+            .map(|(id, _)| id.clone())
     }
 
     pub fn notify_hotplug(
@@ -3074,11 +4839,9 @@ impl DeviceManager {
         &mut self,
         device_cfg: &mut DeviceConfig,
     ) -> DeviceManagerResult<PciDeviceInfo> {
-        let pci = if let Some(pci_bus) = &self.pci_bus {
-            Arc::clone(&pci_bus)
-        } else {
-            return Err(DeviceManagerError::NoPciBus);
-        };
+        if device_cfg.numa_node.is_some() {
+            warn!("Attaching device to a NUMA node is not available for hotplugged devices");
+        }
 
         if self.passthrough_device.is_none() {
             // If the passthrough device has not been created yet, it is created
@@ -3092,10 +4855,16 @@ impl DeviceManager {
         }
 
         let (device_id, device_name) =
-            self.add_passthrough_device(&mut pci.lock().unwrap(), device_cfg)?;
+            self.add_passthrough_device(device_cfg)
+                .map_err(DeviceManagerError::device(
+                    device_cfg.id.clone(),
+                    "vfio",
+                    DeviceStage::Plug,
+                ))?;
 
         // Update the PCIU bitmap
-        self.pci_devices_up |= 1 << (device_id >> 3);
+        self.pci_segment_mut(pci_bdf_segment(device_id))?
+            .pci_devices_up |= 1 << pci_bdf_device(device_id);
 
         Ok(PciDeviceInfo {
             id: device_name,
@@ -3104,7 +4873,20 @@ impl DeviceManager {
     }
 
     pub fn remove_device(&mut self, id: String) -> DeviceManagerResult<()> {
-        if let Some(pci_device_bdf) = self.pci_id_list.get(&id) {
+        // The threads of a paused device would never handle their kill event.
+        if self.paused_devices.contains(&id) {
+            return Err(DeviceManagerError::RemovalOfPausedDevice(id));
+        }
+
+        // A cold device only holds its PCI slot, there is nothing for the
+        // guest to eject.
+        if let Some(pci_device_bdf) = self.cold_devices.remove(&id) {
+            return self
+                .pci_segment_mut(pci_bdf_segment(pci_device_bdf))?
+                .put_device_bdf(pci_device_bdf);
+        }
+
+        if let Some(&pci_device_bdf) = self.pci_id_list.get(&id) {
             if let Some(any_device) = self.pci_devices.get(&pci_device_bdf) {
                 if let Ok(virtio_pci_device) =
                     Arc::clone(any_device).downcast::<Mutex<VirtioPciDevice>>()
@@ -3128,11 +4910,12 @@ impl DeviceManager {
                     }
                 }
             } else {
-                return Err(DeviceManagerError::UnknownPciBdf(*pci_device_bdf));
+                return Err(DeviceManagerError::UnknownPciBdf(pci_device_bdf));
             }
 
             // Update the PCID bitmap
-            self.pci_devices_down |= 1 << (*pci_device_bdf >> 3);
+            self.pci_segment_mut(pci_bdf_segment(pci_device_bdf))?
+                .pci_devices_down |= 1 << pci_bdf_device(pci_device_bdf);
 
             // Remove the device from the device tree along with its parent.
             let mut device_tree = self.device_tree.lock().unwrap();
@@ -3148,26 +4931,146 @@ impl DeviceManager {
         }
     }
 
-    pub fn eject_device(&mut self, device_id: u8) -> DeviceManagerResult<()> {
-        // Retrieve the PCI bus.
-        let pci = if let Some(pci_bus) = &self.pci_bus {
-            Arc::clone(&pci_bus)
-        } else {
-            return Err(DeviceManagerError::NoPciBus);
+    /// Registers a hook inspecting the descriptor chains of a virtio device,
+    /// which takes effect on its next activation.
+    pub fn set_descriptor_inspector(
+        &self,
+        id: &str,
+        inspector: Arc<dyn DescriptorInspector>,
+    ) -> DeviceManagerResult<()> {
+        let device = self
+            .virtio_devices
+            .iter()
+            .find(|d| d.id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+        device
+            .virtio_device
+            .lock()
+            .unwrap()
+            .set_descriptor_inspector(inspector);
+
+        Ok(())
+    }
+
+    pub fn reset_device(&mut self, id: String) -> DeviceManagerResult<()> {
+        let pci_device_bdf = *self
+            .pci_id_list
+            .get(&id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.clone()))?;
+        let any_device = self
+            .pci_devices
+            .get(&pci_device_bdf)
+            .ok_or(DeviceManagerError::UnknownPciBdf(pci_device_bdf))?;
+
+        let virtio_pci_device = Arc::clone(any_device)
+            .downcast::<Mutex<VirtioPciDevice>>()
+            .map_err(|_| DeviceManagerError::ResetNotAllowed(id))?;
+
+        virtio_pci_device
+            .lock()
+            .unwrap()
+            .reset_device()
+            .map_err(DeviceManagerError::ResetVirtioDevice)?;
+        // The device runs again on new threads.
+        self.paused_devices.reset(&id);
+
+        Ok(())
+    }
+
+    // Looks up a virtio PCI device, returning the virtio device as it can be
+    // paused through the device tree.
+    fn pausable_device(&self, id: &str) -> DeviceManagerResult<Arc<Mutex<dyn Migratable>>> {
+        let pci_device_bdf = *self
+            .pci_id_list
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+        let any_device = self
+            .pci_devices
+            .get(&pci_device_bdf)
+            .ok_or(DeviceManagerError::UnknownPciBdf(pci_device_bdf))?;
+        if !any_device.is::<Mutex<VirtioPciDevice>>() {
+            return Err(DeviceManagerError::PauseNotAllowed(id.to_owned()));
+        }
+
+        self.device_tree
+            .lock()
+            .unwrap()
+            .get(id)
+            .and_then(|node| node.migratable.clone())
+            .ok_or_else(|| DeviceManagerError::PauseNotAllowed(id.to_owned()))
+    }
+
+    /// Pauses a single device, without affecting the vCPUs or the other
+    /// devices. When the VM is paused, the device is already paused and only
+    /// kept paused once the VM resumes.
+    pub fn pause_device(&mut self, id: String, vm_paused: bool) -> DeviceManagerResult<()> {
+        let device = self.pausable_device(&id)?;
+        self.paused_devices
+            .pause(id, &device, vm_paused)
+            .map_err(DeviceManagerError::PauseDevice)
+    }
+
+    /// Resumes a device paused through pause_device(). When the VM is paused,
+    /// the device resumes along with it.
+    pub fn resume_device(&mut self, id: String, vm_paused: bool) -> DeviceManagerResult<()> {
+        let device = self.pausable_device(&id)?;
+        self.paused_devices
+            .resume(id, &device, vm_paused)
+            .map_err(DeviceManagerError::ResumeDevice)
+    }
+
+    pub fn eject_device(&mut self, pci_segment_id: u16, device_id: u8) -> DeviceManagerResult<()> {
+        let pci_device_bdf = pci_bdf(pci_segment_id, device_id);
+        let id = self.pci_device_name(pci_device_bdf);
+        let device_type = match self.pci_devices.get(&pci_device_bdf) {
+            Some(any_device) if any_device.is::<Mutex<VfioPciDevice>>() => "vfio".to_owned(),
+            Some(any_device) if any_device.is::<Mutex<VfioUserPciDevice>>() => {
+                "vfio-user".to_owned()
+            }
+            Some(any_device) if any_device.is::<Mutex<NvmePciDevice>>() => "nvme".to_owned(),
+            Some(any_device) => Arc::clone(any_device)
+                .downcast::<Mutex<VirtioPciDevice>>()
+                .map(|virtio_pci_device| {
+                    let virtio_device = virtio_pci_device.lock().unwrap().virtio_device();
+                    let device_type = virtio_device.lock().unwrap().device_type();
+                    VirtioDeviceType::from(device_type).to_string()
+                })
+                .unwrap_or_default(),
+            None => String::new(),
         };
 
+        self.eject_pci_device(pci_segment_id, device_id)
+            .map_err(DeviceManagerError::device(
+                id,
+                device_type,
+                DeviceStage::Remove,
+            ))
+    }
+
+    fn eject_pci_device(&mut self, pci_segment_id: u16, device_id: u8) -> DeviceManagerResult<()> {
+        // Retrieve the PCI bus of the segment.
+        let pci = Arc::clone(&self.pci_segment(pci_segment_id)?.pci_bus);
+
         // Convert the device ID into the corresponding b/d/f.
-        let pci_device_bdf = (device_id as u32) << 3;
+        let pci_device_bdf = pci_bdf(pci_segment_id, device_id);
+
+        // Close the capture of a virtio-net device going away.
+        if let Some(id) = self.pci_device_name(pci_device_bdf) {
+            if let Some(capture) = self.net_captures.remove(&id) {
+                capture.stop();
+            }
+            self.net_flow_steerings.remove(&id);
+            self.vhost_user_net_devices.remove(&id);
+            self.io_priority_block_devices.remove(&id);
+        }
 
         // Find the device name corresponding to the PCI b/d/f while removing
         // the device entry.
         self.pci_id_list.retain(|_, bdf| *bdf != pci_device_bdf);
 
         // Give the PCI device ID back to the PCI bus.
-        pci.lock()
-            .unwrap()
-            .put_device_id(device_id as usize)
-            .map_err(DeviceManagerError::PutPciDeviceId)?;
+        self.pci_segment_mut(pci_segment_id)?
+            .put_device_bdf(pci_device_bdf)?;
 
         if let Some(any_device) = self.pci_devices.remove(&pci_device_bdf) {
             let (pci_device, bus_device, virtio_device) = if let Ok(vfio_pci_device) =
@@ -3178,6 +5081,20 @@ impl DeviceManager {
                     Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn BusDevice>>,
                     None as Option<VirtioDeviceArc>,
                 )
+            } else if let Ok(vfio_user_pci_device) =
+                any_device.clone().downcast::<Mutex<VfioUserPciDevice>>()
+            {
+                (
+                    Arc::clone(&vfio_user_pci_device) as Arc<Mutex<dyn PciDevice>>,
+                    Arc::clone(&vfio_user_pci_device) as Arc<Mutex<dyn BusDevice>>,
+                    None as Option<VirtioDeviceArc>,
+                )
+            } else if let Ok(nvme_device) = any_device.clone().downcast::<Mutex<NvmePciDevice>>() {
+                (
+                    Arc::clone(&nvme_device) as Arc<Mutex<dyn PciDevice>>,
+                    Arc::clone(&nvme_device) as Arc<Mutex<dyn BusDevice>>,
+                    None as Option<VirtioDeviceArc>,
+                )
             } else if let Ok(virtio_pci_device) = any_device.downcast::<Mutex<VirtioPciDevice>>() {
                 let bar_addr = virtio_pci_device.lock().unwrap().config_bar_addr();
                 for (event, addr) in virtio_pci_device.lock().unwrap().ioeventfds(bar_addr) {
@@ -3198,10 +5115,15 @@ impl DeviceManager {
             };
 
             // Free the allocated BARs
+            let pci_segment = self.pci_segment(pci_segment_id)?;
             pci_device
                 .lock()
                 .unwrap()
-                .free_bars(&mut self.address_manager.allocator.lock().unwrap())
+                .free_bars(
+                    &mut self.address_manager.allocator.lock().unwrap(),
+                    &mut pci_segment.mmio_allocator.lock().unwrap(),
+                    &mut pci_segment.mmio_hole_allocator.lock().unwrap(),
+                )
                 .map_err(DeviceManagerError::FreePciBars)?;
 
             // Remove the device from the PCI bus
@@ -3245,7 +5167,7 @@ impl DeviceManager {
                 virtio_device.lock().unwrap().shutdown();
 
                 self.virtio_devices
-                    .retain(|(d, _, _)| !Arc::ptr_eq(d, &virtio_device));
+                    .retain(|handle| !Arc::ptr_eq(&handle.virtio_device, &virtio_device));
             }
 
             // At this point, the device has been removed from all the list and
@@ -3261,70 +5183,265 @@ impl DeviceManager {
 
     fn hotplug_virtio_pci_device(
         &mut self,
-        device: VirtioDeviceArc,
-        iommu_attached: bool,
-        id: String,
+        handle: MetaVirtioDevice,
+        reserved_pci_device_bdf: Option<u32>,
     ) -> DeviceManagerResult<PciDeviceInfo> {
-        if iommu_attached {
+        if handle.iommu {
             warn!("Placing device behind vIOMMU is not available for hotplugged devices");
         }
+        // The slots were described to the guest at boot, except for the cold
+        // devices which got theirs back then.
+        if handle.numa_node.is_some() && reserved_pci_device_bdf.is_none() {
+            warn!("Attaching device to a NUMA node is not available for hotplugged devices");
+        }
 
-        let pci = if let Some(pci_bus) = &self.pci_bus {
-            Arc::clone(&pci_bus)
-        } else {
-            return Err(DeviceManagerError::NoPciBus);
-        };
+        let device = handle.virtio_device.clone();
+        let id = handle.id.clone();
+        let pci_segment_id = handle.pci_segment;
+        let pci_identity = handle.pci_identity.clone();
 
         // Add the virtio device to the device manager list. This is important
         // as the list is used to notify virtio devices about memory updates
         // for instance.
-        self.virtio_devices
-            .push((device.clone(), iommu_attached, id.clone()));
+        self.virtio_devices.push(handle);
 
-        let device_id =
-            self.add_virtio_pci_device(device, &mut pci.lock().unwrap(), &None, id.clone())?;
+        let device_type = VirtioDeviceType::from(device.lock().unwrap().device_type());
+        let device_id = match self
+            .add_virtio_pci_device(
+                device.clone(),
+                &None,
+                id.clone(),
+                pci_segment_id,
+                pci_identity,
+                reserved_pci_device_bdf,
+            )
+            .map_err(DeviceManagerError::device(
+                Some(id.clone()),
+                device_type,
+                DeviceStage::Plug,
+            )) {
+            Ok(device_id) => device_id,
+            Err(e) => {
+                // Don't leave the device half added, as it could never be
+                // removed through the API since it didn't get a PCI slot.
+                self.virtio_devices
+                    .retain(|handle| !Arc::ptr_eq(&handle.virtio_device, &device));
+                self.device_tree.lock().unwrap().remove(&id);
+                device.lock().unwrap().shutdown();
+                return Err(e);
+            }
+        };
 
         // Update the PCIU bitmap
-        self.pci_devices_up |= 1 << (device_id >> 3);
+        self.pci_segment_mut(pci_segment_id)?.pci_devices_up |= 1 << pci_bdf_device(device_id);
 
         Ok(PciDeviceInfo { id, bdf: device_id })
     }
 
     pub fn add_disk(&mut self, disk_cfg: &mut DiskConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_block_device(disk_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        if disk_cfg.nvme {
+            return Err(DeviceManagerError::NvmeHotplugNotSupported);
+        }
+        if disk_cfg.transport == VirtioTransportType::Mmio {
+            return Err(DeviceManagerError::MmioHotplugNotSupported);
+        }
+
+        if disk_cfg.cold {
+            return self.reserve_cold_device(
+                &mut disk_cfg.id,
+                &mut disk_cfg.cold_pci_bdf,
+                DISK_DEVICE_NAME_PREFIX,
+                disk_cfg.pci_segment,
+                disk_cfg.numa_node,
+            );
+        }
+
+        let device =
+            self.make_virtio_block_device(disk_cfg)
+                .map_err(DeviceManagerError::device(
+                    disk_cfg.id.clone(),
+                    VirtioDeviceType::TYPE_BLOCK,
+                    DeviceStage::Create,
+                ))?;
+        self.hotplug_virtio_pci_device(device, None)
     }
 
     pub fn add_fs(&mut self, fs_cfg: &mut FsConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_fs_device(fs_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        let device = self
+            .make_virtio_fs_device(fs_cfg)
+            .map_err(DeviceManagerError::device(
+                fs_cfg.id.clone(),
+                VirtioDeviceType::TYPE_FS,
+                DeviceStage::Create,
+            ))?;
+        self.hotplug_virtio_pci_device(device, None)
     }
 
     pub fn add_pmem(&mut self, pmem_cfg: &mut PmemConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_pmem_device(pmem_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        let device = self
+            .make_virtio_pmem_device(pmem_cfg)
+            .map_err(DeviceManagerError::device(
+                pmem_cfg.id.clone(),
+                VirtioDeviceType::TYPE_PMEM,
+                DeviceStage::Create,
+            ))?;
+        self.hotplug_virtio_pci_device(device, None)
     }
 
     pub fn add_net(&mut self, net_cfg: &mut NetConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_net_device(net_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        if net_cfg.transport == VirtioTransportType::Mmio {
+            return Err(DeviceManagerError::MmioHotplugNotSupported);
+        }
+
+        if let Some(id) = &net_cfg.id {
+            if self.pci_id_list.contains_key(id) || self.cold_devices.contains_key(id) {
+                return Err(DeviceManagerError::DeviceIdAlreadyInUse);
+            }
+        }
+
+        if net_cfg.cold {
+            return self.reserve_cold_device(
+                &mut net_cfg.id,
+                &mut net_cfg.cold_pci_bdf,
+                NET_DEVICE_NAME_PREFIX,
+                net_cfg.pci_segment,
+                net_cfg.numa_node,
+            );
+        }
+
+        let device = self
+            .make_virtio_net_device(net_cfg)
+            .map_err(DeviceManagerError::device(
+                net_cfg.id.clone(),
+                VirtioDeviceType::TYPE_NET,
+                DeviceStage::Create,
+            ))?;
+        self.hotplug_virtio_pci_device(device, None)
     }
 
     pub fn add_vsock(&mut self, vsock_cfg: &mut VsockConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_vsock_device(vsock_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        if vsock_cfg.transport == VirtioTransportType::Mmio {
+            return Err(DeviceManagerError::MmioHotplugNotSupported);
+        }
+
+        let device =
+            self.make_virtio_vsock_device(vsock_cfg)
+                .map_err(DeviceManagerError::device(
+                    vsock_cfg.id.clone(),
+                    VirtioDeviceType::TYPE_VSOCK,
+                    DeviceStage::Create,
+                ))?;
+        self.hotplug_virtio_pci_device(device, None)
+    }
+
+    pub fn activate_device(&mut self, id: &str) -> DeviceManagerResult<PciDeviceInfo> {
+        let pci_device_bdf = *self
+            .cold_devices
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        let (disk_cfg, net_cfg) = {
+            let config = self.config.lock().unwrap();
+            (
+                config
+                    .disks
+                    .iter()
+                    .flatten()
+                    .find(|disk_cfg| disk_cfg.id.as_deref() == Some(id))
+                    .cloned(),
+                config
+                    .net
+                    .iter()
+                    .flatten()
+                    .find(|net_cfg| net_cfg.id.as_deref() == Some(id))
+                    .cloned(),
+            )
+        };
+
+        // The device is only created now, a vhost-user backend not being
+        // connected to before.
+        let device = if let Some(mut disk_cfg) = disk_cfg {
+            self.make_virtio_block_device(&mut disk_cfg)
+                .map_err(DeviceManagerError::device(
+                    disk_cfg.id.clone(),
+                    VirtioDeviceType::TYPE_BLOCK,
+                    DeviceStage::Create,
+                ))?
+        } else if let Some(mut net_cfg) = net_cfg {
+            self.make_virtio_net_device(&mut net_cfg)
+                .map_err(DeviceManagerError::device(
+                    net_cfg.id.clone(),
+                    VirtioDeviceType::TYPE_NET,
+                    DeviceStage::Create,
+                ))?
+        } else {
+            return Err(DeviceManagerError::UnknownDeviceId(id.to_owned()));
+        };
+
+        // The slot stays reserved if the device can't be plugged, for the
+        // activation to be retried.
+        let pci_device_info = self.hotplug_virtio_pci_device(device, Some(pci_device_bdf))?;
+        self.cold_devices.remove(id);
+
+        // Update VmConfig for the device to be created along with the
+        // others in case of a reboot.
+        let mut config = self.config.lock().unwrap();
+        for disk_cfg in config.disks.iter_mut().flatten() {
+            if disk_cfg.id.as_deref() == Some(id) {
+                disk_cfg.cold = false;
+            }
+        }
+        for net_cfg in config.net.iter_mut().flatten() {
+            if net_cfg.id.as_deref() == Some(id) {
+                net_cfg.cold = false;
+            }
+        }
+
+        Ok(pci_device_info)
     }
 
     pub fn counters(&self) -> HashMap<String, HashMap<&'static str, Wrapping<u64>>> {
         let mut counters = HashMap::new();
 
-        for (virtio_device, _, id) in &self.virtio_devices {
-            let virtio_device = virtio_device.lock().unwrap();
+        for handle in &self.virtio_devices {
+            let virtio_device = handle.virtio_device.lock().unwrap();
             if let Some(device_counters) = virtio_device.counters() {
-                counters.insert(id.clone(), device_counters.clone());
+                counters.insert(handle.id.clone(), device_counters.clone());
+            }
+            for (i, queue_counters) in virtio_device
+                .queue_counters()
+                .into_iter()
+                .flatten()
+                .enumerate()
+            {
+                counters.insert(format!("{}_q{}", handle.id, i), queue_counters);
+            }
+        }
+
+        // Report the interrupts raised on each MSI-X vector of the virtio-pci
+        // devices, one entry per (device, vector) pair.
+        for (id, pci_device_bdf) in &self.pci_id_list {
+            let virtio_pci_device = match self
+                .pci_devices
+                .get(pci_device_bdf)
+                .and_then(|d| Arc::clone(d).downcast::<Mutex<VirtioPciDevice>>().ok())
+            {
+                Some(virtio_pci_device) => virtio_pci_device,
+                None => continue,
+            };
+
+            for stats in virtio_pci_device.lock().unwrap().msix_vector_stats() {
+                let mut vector_counters = HashMap::new();
+                vector_counters.insert("interrupts", Wrapping(stats.count));
+                vector_counters.insert("last_interrupt_us", Wrapping(stats.last_trigger_us));
+                counters.insert(format!("{}_msix{}", id, stats.vector), vector_counters);
             }
         }
 
+        let mut inflight_counters = HashMap::new();
+        self.inflight_limit.report(&mut inflight_counters);
+        counters.insert("inflight".to_string(), inflight_counters);
+
         counters
     }
 
@@ -3341,6 +5458,125 @@ impl DeviceManager {
         Err(DeviceManagerError::MissingVirtioBalloon)
     }
 
+    pub fn balloon(&self) -> Option<Arc<Mutex<virtio_devices::Balloon>>> {
+        self.balloon.clone()
+    }
+
+    pub fn rtc_time(&self) -> DeviceManagerResult<u64> {
+        self.rtc_device
+            .as_ref()
+            .map(|rtc| rtc.lock().unwrap().time())
+            .ok_or(DeviceManagerError::MissingRtc)
+    }
+
+    pub fn set_rtc_time(&mut self, time: u64) -> DeviceManagerResult<()> {
+        self.rtc_device
+            .as_ref()
+            .map(|rtc| rtc.lock().unwrap().set_time(time))
+            .ok_or(DeviceManagerError::MissingRtc)
+    }
+
+    pub fn inject_input_events(
+        &self,
+        id: &str,
+        events: &[virtio_devices::InputEvent],
+    ) -> DeviceManagerResult<()> {
+        self.input_devices
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::MissingVirtioInput(id.to_owned()))?
+            .lock()
+            .unwrap()
+            .inject_events(events)
+            .map_err(DeviceManagerError::InjectInputEvents)
+    }
+
+    fn gpio_device(&self, id: &str) -> DeviceManagerResult<&Arc<Mutex<virtio_devices::Gpio>>> {
+        self.gpio_devices
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::MissingVirtioGpio(id.to_owned()))
+    }
+
+    pub fn set_gpio_line(&self, id: &str, line: u16, value: bool) -> DeviceManagerResult<()> {
+        self.gpio_device(id)?
+            .lock()
+            .unwrap()
+            .set_input(line, value)
+            .map_err(DeviceManagerError::SetGpioLine)
+    }
+
+    pub fn gpio_lines(&self, id: &str) -> DeviceManagerResult<Vec<virtio_devices::GpioLineState>> {
+        Ok(self.gpio_device(id)?.lock().unwrap().lines())
+    }
+
+    /// Starts capturing the frames of a virtio-net device into a pcap file,
+    /// or stops the capture if no path is given.
+    pub fn net_capture(&self, id: &str, path: Option<&Path>) -> DeviceManagerResult<()> {
+        let capture = self
+            .net_captures
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::MissingVirtioNet(id.to_owned()))?;
+
+        if let Some(path) = path {
+            capture
+                .start(path)
+                .map_err(DeviceManagerError::StartNetCapture)?;
+        } else if let Some(dropped) = capture.stop() {
+            info!("Capture of {} stopped, {} frames dropped", id, dropped);
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the flow rules of a virtio-net device, removing them all if
+    /// none are given.
+    pub fn set_flow_rules(
+        &self,
+        id: &str,
+        rules: &[net_util::FlowRule],
+    ) -> DeviceManagerResult<()> {
+        self.net_flow_steerings
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::MissingVirtioNet(id.to_owned()))?
+            .set_rules(rules)
+            .map_err(DeviceManagerError::SetFlowRules)
+    }
+
+    /// Sets the number of queues the backend of a vhost-user-net device
+    /// processes, whatever the number the guest enabled.
+    pub fn set_net_queues(&self, id: &str, num_queues: usize) -> DeviceManagerResult<()> {
+        self.vhost_user_net_devices
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::MissingVhostUserNet(id.to_owned()))?
+            .lock()
+            .unwrap()
+            .set_active_queues(num_queues)
+            .map_err(DeviceManagerError::SetNetQueues)
+    }
+
+    /// Takes the connections to the backends of the vhost-user-net devices,
+    /// for the devices of the VM created on a guest reboot to reuse them.
+    pub fn take_vhost_user_backends(&mut self) -> VhostUserBackends {
+        self.vhost_user_net_devices
+            .iter()
+            .filter_map(|(id, device)| {
+                let backend = device.lock().unwrap().take_backend()?;
+                Some((id.clone(), backend))
+            })
+            .collect()
+    }
+
+    /// Connections to be taken over by the devices about to be created.
+    pub fn set_vhost_user_backends(&mut self, backends: VhostUserBackends) {
+        self.vhost_user_backends = backends;
+    }
+
+    /// Registers a callback invoked, from a thread of its own, each time a
+    /// device is activated, paused or reset, e.g. for an external resource
+    /// manager to follow the state of the network interfaces.
+    pub fn register_device_event_callback(&self, callback: virtio_devices::DeviceEventCallback) {
+        self.device_events.register(callback);
+    }
+
     pub fn balloon_size(&self) -> u64 {
         if let Some(balloon) = &self.balloon {
             return balloon.lock().unwrap().get_actual();
@@ -3349,6 +5585,31 @@ impl DeviceManager {
         0
     }
 
+    pub fn disk_io_priorities(&self) -> Vec<DiskIoPriorityInfo> {
+        let mut io_priorities: Vec<DiskIoPriorityInfo> = self
+            .io_priority_block_devices
+            .iter()
+            .map(|(id, (dev, io_priority))| {
+                let dev = dev.lock().unwrap();
+                DiskIoPriorityInfo {
+                    id: id.clone(),
+                    io_priority: *io_priority,
+                    applied: dev.io_priority_applied(),
+                    host_scheduler: dev.host_io_scheduler().map(String::from),
+                }
+            })
+            .collect();
+        io_priorities.sort_by(|a, b| a.id.cmp(&b.id));
+        io_priorities
+    }
+
+    pub fn virtio_mem_plugged_size(&self) -> u64 {
+        self.virtio_mem_devices
+            .iter()
+            .map(|mem| mem.lock().unwrap().plugged_size())
+            .sum()
+    }
+
     pub fn device_tree(&self) -> Arc<Mutex<DeviceTree>> {
         self.device_tree.clone()
     }
@@ -3363,6 +5624,39 @@ impl DeviceManager {
             .notify(AcpiNotificationFlags::POWER_BUTTON_CHANGED)
             .map_err(DeviceManagerError::PowerButtonNotification)
     }
+
+    #[cfg(feature = "acpi")]
+    pub fn set_battery(&self, ac_online: bool, charge: u8) -> DeviceManagerResult<()> {
+        if charge > 100 {
+            return Err(DeviceManagerError::InvalidBatteryCharge(charge));
+        }
+
+        self.battery_device
+            .as_ref()
+            .ok_or(DeviceManagerError::MissingBattery)?
+            .lock()
+            .unwrap()
+            .set_state(ac_online, charge);
+
+        self.ged_notification_device
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .notify(AcpiNotificationFlags::BATTERY_CHANGED)
+            .map_err(DeviceManagerError::BatteryNotification)
+    }
+
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn tpm(&self) -> Option<&Arc<Mutex<devices::tpm::Tpm>>> {
+        self.tpm_device.as_ref()
+    }
+}
+
+// Identifier of the virtio-pci node of the IOMMU, which the devices attached
+// to the IOMMU depend on.
+fn iommu_pci_device_id() -> String {
+    format!("{}-{}", VIRTIO_PCI_DEVICE_NAME_PREFIX, IOMMU_DEVICE_NAME)
 }
 
 #[cfg(feature = "acpi")]
@@ -3380,102 +5674,26 @@ fn numa_node_id_from_memory_zone_id(numa_nodes: &NumaNodes, memory_zone_id: &str
 }
 
 #[cfg(feature = "acpi")]
-struct PciDevSlot {
-    device_id: u8,
-}
-
-#[cfg(feature = "acpi")]
-impl Aml for PciDevSlot {
-    fn to_aml_bytes(&self) -> Vec<u8> {
-        let sun = self.device_id;
-        let adr: u32 = (self.device_id as u32) << 16;
-        aml::Device::new(
-            format!("S{:03}", self.device_id).as_str().into(),
-            vec![
-                &aml::Name::new("_SUN".into(), &sun),
-                &aml::Name::new("_ADR".into(), &adr),
-                &aml::Method::new(
-                    "_EJ0".into(),
-                    1,
-                    true,
-                    vec![&aml::MethodCall::new(
-                        "\\_SB_.PHPR.PCEJ".into(),
-                        vec![&aml::Path::new("_SUN")],
-                    )],
-                ),
-            ],
-        )
-        .to_aml_bytes()
-    }
-}
-
-#[cfg(feature = "acpi")]
-struct PciDevSlotNotify {
-    device_id: u8,
-}
-
-#[cfg(feature = "acpi")]
-impl Aml for PciDevSlotNotify {
+impl Aml for DeviceManager {
     fn to_aml_bytes(&self) -> Vec<u8> {
-        let device_id_mask: u32 = 1 << self.device_id;
-        let object = aml::Path::new(&format!("S{:03}", self.device_id));
-        let mut bytes = aml::And::new(&aml::Local(0), &aml::Arg(0), &device_id_mask).to_aml_bytes();
-        bytes.extend_from_slice(
-            &aml::If::new(
-                &aml::Equal::new(&aml::Local(0), &device_id_mask),
-                vec![&aml::Notify::new(&object, &aml::Arg(1))],
-            )
-            .to_aml_bytes(),
-        );
-        bytes
-    }
-}
-
-#[cfg(feature = "acpi")]
-struct PciDevSlotMethods {}
+        let mut bytes = Vec::new();
 
-#[cfg(feature = "acpi")]
-impl Aml for PciDevSlotMethods {
-    fn to_aml_bytes(&self) -> Vec<u8> {
-        let mut device_notifies = Vec::new();
-        for device_id in 0..32 {
-            device_notifies.push(PciDevSlotNotify { device_id });
+        // Scan the hotplug state of every PCI segment.
+        let mut pci_scan_calls = Vec::new();
+        for pci_segment in self.pci_segments.iter() {
+            pci_scan_calls.push(aml::MethodCall::new(
+                format!("\\_SB_.PCI{:X}.PCNT", pci_segment.id)
+                    .as_str()
+                    .into(),
+                vec![],
+            ));
         }
-
-        let mut device_notifies_refs: Vec<&dyn aml::Aml> = Vec::new();
-        for device_notify in device_notifies.iter() {
-            device_notifies_refs.push(device_notify);
+        let mut pci_scan_inner: Vec<&dyn Aml> = Vec::new();
+        for pci_scan_call in pci_scan_calls.iter() {
+            pci_scan_inner.push(pci_scan_call);
         }
+        let pci_scan_method = aml::Method::new("PSCN".into(), 0, true, pci_scan_inner);
 
-        let mut bytes =
-            aml::Method::new("DVNT".into(), 2, true, device_notifies_refs).to_aml_bytes();
-
-        bytes.extend_from_slice(
-            &aml::Method::new(
-                "PCNT".into(),
-                0,
-                true,
-                vec![
-                    &aml::MethodCall::new(
-                        "DVNT".into(),
-                        vec![&aml::Path::new("\\_SB_.PHPR.PCIU"), &aml::ONE],
-                    ),
-                    &aml::MethodCall::new(
-                        "DVNT".into(),
-                        vec![&aml::Path::new("\\_SB_.PHPR.PCID"), &3usize],
-                    ),
-                ],
-            )
-            .to_aml_bytes(),
-        );
-        bytes
-    }
-}
-
-#[cfg(feature = "acpi")]
-impl Aml for DeviceManager {
-    fn to_aml_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
         // PCI hotplug controller
         bytes.extend_from_slice(
             &aml::Device::new(
@@ -3509,15 +5727,18 @@ impl Aml for DeviceManager {
                             aml::FieldEntry::Named(*b"PCIU", 32),
                             aml::FieldEntry::Named(*b"PCID", 32),
                             aml::FieldEntry::Named(*b"B0EJ", 32),
+                            aml::FieldEntry::Named(*b"PSEG", 32),
                         ],
                     ),
                     &aml::Method::new(
                         "PCEJ".into(),
-                        1,
+                        2,
                         true,
                         vec![
                             // Take lock defined above
                             &aml::Acquire::new("BLCK".into(), 0xffff),
+                            // Select the PCI segment (in second argument)
+                            &aml::Store::new(&aml::Path::new("PSEG"), &aml::Arg(1)),
                             // Write PCI bus number (in first argument) to I/O port via field
                             &aml::ShiftLeft::new(&aml::Path::new("B0EJ"), &aml::ONE, &aml::Arg(0)),
                             // Release lock
@@ -3526,79 +5747,16 @@ impl Aml for DeviceManager {
                             &aml::Return::new(&aml::ZERO),
                         ],
                     ),
+                    &pci_scan_method,
                 ],
             )
             .to_aml_bytes(),
         );
 
-        let start_of_device_area = self.memory_manager.lock().unwrap().start_of_device_area().0;
-        let end_of_device_area = self.memory_manager.lock().unwrap().end_of_device_area().0;
-
-        let mut pci_dsdt_inner_data: Vec<&dyn aml::Aml> = Vec::new();
-        let hid = aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0A08"));
-        pci_dsdt_inner_data.push(&hid);
-        let cid = aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A03"));
-        pci_dsdt_inner_data.push(&cid);
-        let adr = aml::Name::new("_ADR".into(), &aml::ZERO);
-        pci_dsdt_inner_data.push(&adr);
-        let seg = aml::Name::new("_SEG".into(), &aml::ZERO);
-        pci_dsdt_inner_data.push(&seg);
-        let uid = aml::Name::new("_UID".into(), &aml::ZERO);
-        pci_dsdt_inner_data.push(&uid);
-        let supp = aml::Name::new("SUPP".into(), &aml::ZERO);
-        pci_dsdt_inner_data.push(&supp);
-        let crs = aml::Name::new(
-            "_CRS".into(),
-            &aml::ResourceTemplate::new(vec![
-                &aml::AddressSpace::new_bus_number(0x0u16, 0xffu16),
-                &aml::IO::new(0xcf8, 0xcf8, 1, 0x8),
-                &aml::AddressSpace::new_memory(
-                    aml::AddressSpaceCachable::NotCacheable,
-                    true,
-                    layout::MEM_32BIT_DEVICES_START.0 as u32,
-                    (layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE - 1) as u32,
-                ),
-                &aml::AddressSpace::new_memory(
-                    aml::AddressSpaceCachable::NotCacheable,
-                    true,
-                    start_of_device_area,
-                    end_of_device_area,
-                ),
-            ]),
-        );
-        pci_dsdt_inner_data.push(&crs);
-
-        let mut pci_devices = Vec::new();
-        for device_id in 0..32 {
-            let pci_device = PciDevSlot { device_id };
-            pci_devices.push(pci_device);
+        let mut pci_dsdt_data = Vec::new();
+        for pci_segment in self.pci_segments.iter() {
+            pci_dsdt_data.extend_from_slice(&pci_segment.to_aml_bytes());
         }
-        for pci_device in pci_devices.iter() {
-            pci_dsdt_inner_data.push(pci_device);
-        }
-
-        let pci_device_methods = PciDevSlotMethods {};
-        pci_dsdt_inner_data.push(&pci_device_methods);
-
-        // Build PCI routing table, listing IRQs assigned to PCI devices.
-        let prt_package_list: Vec<(u32, u32)> = self
-            .pci_device_irqs
-            .iter()
-            .map(|(bdf, irq)| (((((*bdf >> 3) & 0x1fu32) << 16) | 0xffffu32), *irq))
-            .collect();
-        let prt_package_list: Vec<aml::Package> = prt_package_list
-            .iter()
-            .map(|(bdf, irq)| aml::Package::new(vec![bdf, &0u8, &0u8, irq]))
-            .collect();
-        let prt_package_list: Vec<&dyn Aml> = prt_package_list
-            .iter()
-            .map(|item| item as &dyn Aml)
-            .collect();
-        let prt = aml::Name::new("_PRT".into(), &aml::Package::new(prt_package_list));
-        pci_dsdt_inner_data.push(&prt);
-
-        let pci_dsdt_data =
-            aml::Device::new("_SB_.PCI0".into(), pci_dsdt_inner_data).to_aml_bytes();
 
         let mbrd_dsdt_data = aml::Device::new(
             "_SB_.MBRD".into(),
@@ -3653,6 +5811,60 @@ impl Aml for DeviceManager {
             .unwrap()
             .to_aml_bytes();
 
+        let battery_data = self
+            .battery_device
+            .as_ref()
+            .map(|battery| battery.lock().unwrap().to_aml_bytes());
+
+        #[cfg(target_arch = "x86_64")]
+        let pvpanic_data = aml::Device::new(
+            "_SB_.PEVT".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"QEMU0001"),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![&aml::IO::new(
+                        PVPANIC_PORT,
+                        PVPANIC_PORT,
+                        0,
+                        1,
+                    )]),
+                ),
+            ],
+        )
+        .to_aml_bytes();
+
+        #[cfg(target_arch = "x86_64")]
+        let tpm_data = self
+            .tpm_device
+            .as_ref()
+            .map(|tpm| tpm.lock().unwrap().to_aml_bytes());
+
+        let mut virtio_mmio_dsdt_data = Vec::new();
+        for (i, handle) in self.virtio_mmio_devices.iter().enumerate() {
+            virtio_mmio_dsdt_data.extend_from_slice(
+                &aml::Device::new(
+                    format!("_SB_.VR{:02X}", i).as_str().into(),
+                    vec![
+                        &aml::Name::new("_HID".into(), &"LNRO0005"),
+                        &aml::Name::new("_UID".into(), &i),
+                        &aml::Name::new(
+                            "_CRS".into(),
+                            &aml::ResourceTemplate::new(vec![
+                                &aml::Memory32Fixed::new(
+                                    true,
+                                    handle.addr as u32,
+                                    VIRTIO_MMIO_SIZE as u32,
+                                ),
+                                &aml::Interrupt::new(true, true, false, false, handle.irq),
+                            ]),
+                        ),
+                    ],
+                )
+                .to_aml_bytes(),
+            );
+        }
+
         bytes.extend_from_slice(pci_dsdt_data.as_slice());
         bytes.extend_from_slice(mbrd_dsdt_data.as_slice());
         if self.config.lock().unwrap().serial.mode != ConsoleOutputMode::Off {
@@ -3661,23 +5873,107 @@ impl Aml for DeviceManager {
         bytes.extend_from_slice(s5_sleep_data.as_slice());
         bytes.extend_from_slice(power_button_dsdt_data.as_slice());
         bytes.extend_from_slice(ged_data.as_slice());
+        if let Some(battery_data) = battery_data {
+            bytes.extend_from_slice(battery_data.as_slice());
+        }
+        #[cfg(target_arch = "x86_64")]
+        if self.config.lock().unwrap().on_crash.is_some() {
+            bytes.extend_from_slice(pvpanic_data.as_slice());
+        }
+        #[cfg(target_arch = "x86_64")]
+        if let Some(tpm_data) = tpm_data {
+            bytes.extend_from_slice(tpm_data.as_slice());
+        }
+        bytes.extend_from_slice(virtio_mmio_dsdt_data.as_slice());
         bytes
     }
 }
 
-impl Pausable for DeviceManager {
-    fn pause(&mut self) -> result::Result<(), MigratableError> {
-        for (_, device_node) in self.device_tree.lock().unwrap().iter() {
+// Devices paused on their own through pause_device(). Pausing and resuming
+// the VM leaves them alone, so that they are neither paused twice nor resumed
+// behind the back of whoever paused them.
+#[derive(Default)]
+struct PausedDevices(HashSet<String>);
+
+impl PausedDevices {
+    fn contains(&self, id: &str) -> bool {
+        self.0.contains(id)
+    }
+
+    fn pause(
+        &mut self,
+        id: String,
+        device: &Arc<Mutex<dyn Migratable>>,
+        vm_paused: bool,
+    ) -> result::Result<(), MigratableError> {
+        if self.0.contains(&id) {
+            return Ok(());
+        }
+
+        // A device paused along with the VM only has to be kept paused.
+        if !vm_paused {
+            device.lock().unwrap().pause()?;
+        }
+        self.0.insert(id);
+
+        Ok(())
+    }
+
+    fn resume(
+        &mut self,
+        id: String,
+        device: &Arc<Mutex<dyn Migratable>>,
+        vm_paused: bool,
+    ) -> result::Result<(), MigratableError> {
+        if !self.0.remove(&id) {
+            return Ok(());
+        }
+
+        // The device resumes along with the VM.
+        if !vm_paused {
+            if let Err(e) = device.lock().unwrap().resume() {
+                self.0.insert(id);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Forgets about a device after a reset, its threads having been resumed
+    // and replaced.
+    fn reset(&mut self, id: &str) {
+        if self.0.remove(id) {
+            info!("Device {} runs again after its reset", id);
+        }
+    }
+
+    fn pause_all(
+        &self,
+        device_tree: &DeviceTree,
+        deadline: Option<Instant>,
+    ) -> result::Result<(), MigratableError> {
+        for (id, device_node) in device_tree.iter() {
+            if self.contains(id) {
+                continue;
+            }
             if let Some(migratable) = &device_node.migratable {
-                migratable.lock().unwrap().pause()?;
+                let mut migratable = migratable.lock().unwrap();
+                match deadline {
+                    Some(deadline) => migratable.quiesce(deadline)?,
+                    None => migratable.pause()?,
+                }
             }
         }
 
         Ok(())
     }
 
-    fn resume(&mut self) -> result::Result<(), MigratableError> {
-        for (_, device_node) in self.device_tree.lock().unwrap().iter() {
+    fn resume_all(&self, device_tree: &DeviceTree) -> result::Result<(), MigratableError> {
+        for (id, device_node) in device_tree.iter() {
+            if self.contains(id) {
+                continue;
+            }
             if let Some(migratable) = &device_node.migratable {
                 migratable.lock().unwrap().resume()?;
             }
@@ -3687,6 +5983,23 @@ impl Pausable for DeviceManager {
     }
 }
 
+impl Pausable for DeviceManager {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.paused_devices
+            .pause_all(&self.device_tree.lock().unwrap(), None)
+    }
+
+    fn quiesce(&mut self, deadline: Instant) -> result::Result<(), MigratableError> {
+        self.paused_devices
+            .pause_all(&self.device_tree.lock().unwrap(), Some(deadline))
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.paused_devices
+            .resume_all(&self.device_tree.lock().unwrap())
+    }
+}
+
 impl Snapshottable for DeviceManager {
     fn id(&self) -> String {
         DEVICE_MANAGER_SNAPSHOT_ID.to_string()
@@ -3708,6 +6021,7 @@ impl Snapshottable for DeviceManager {
             id: format!("{}-section", DEVICE_MANAGER_SNAPSHOT_ID),
             snapshot: serde_json::to_vec(&self.state())
                 .map_err(|e| MigratableError::Snapshot(e.into()))?,
+            ..Default::default()
         });
 
         Ok(snapshot)
@@ -3715,27 +6029,33 @@ impl Snapshottable for DeviceManager {
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
         // Let's first restore the DeviceManager.
-        if let Some(device_manager_section) = snapshot
-            .snapshot_data
-            .get(&format!("{}-section", DEVICE_MANAGER_SNAPSHOT_ID))
+        let device_manager_state: DeviceManagerState = if let Some(device_manager_section) =
+            snapshot
+                .snapshot_data
+                .get(&format!("{}-section", DEVICE_MANAGER_SNAPSHOT_ID))
         {
-            let device_manager_state = serde_json::from_slice(&device_manager_section.snapshot)
-                .map_err(|e| {
-                    MigratableError::Restore(anyhow!("Could not deserialize DeviceManager {}", e))
-                })?;
-
-            self.set_state(&device_manager_state);
+            serde_json::from_slice(&device_manager_section.snapshot).map_err(|e| {
+                MigratableError::Restore(anyhow!("Could not deserialize DeviceManager {}", e))
+            })?
         } else {
             return Err(MigratableError::Restore(anyhow!(
                 "Could not find DeviceManager snapshot section"
             )));
-        }
+        };
+        self.set_state(&device_manager_state);
 
         // Now that DeviceManager is updated with the right states, it's time
         // to create the devices based on the configuration.
         self.create_devices()
             .map_err(|e| MigratableError::Restore(anyhow!("Could not create devices {:?}", e)))?;
 
+        // The RTC starts from the host time, the offset the guest had is
+        // applied again on top of it.
+        if let (Some(rtc), Some(rtc_offset)) = (&self.rtc_device, device_manager_state.rtc_offset) {
+            let time = host_time().saturating_add(rtc_offset);
+            rtc.lock().unwrap().set_time(time.max(0) as u64);
+        }
+
         // Finally, restore all devices associated with the DeviceManager.
         // It's important to restore devices in the right order, that's why
         // the device tree is the right way to ensure we restore a child before
@@ -3772,25 +6092,33 @@ impl Migratable for DeviceManager {}
 const PCIU_FIELD_OFFSET: u64 = 0;
 const PCID_FIELD_OFFSET: u64 = 4;
 const B0EJ_FIELD_OFFSET: u64 = 8;
+const PSEG_FIELD_OFFSET: u64 = 12;
 
 const PCIU_FIELD_SIZE: usize = 4;
 const PCID_FIELD_SIZE: usize = 4;
 const B0EJ_FIELD_SIZE: usize = 4;
+const PSEG_FIELD_SIZE: usize = 4;
 
 impl BusDevice for DeviceManager {
     fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
         match offset {
             PCIU_FIELD_OFFSET => {
                 assert!(data.len() == PCIU_FIELD_SIZE);
-                data.copy_from_slice(&self.pci_devices_up.to_le_bytes());
+                let pci_segment = &mut self.pci_segments[self.selected_segment];
+                data.copy_from_slice(&pci_segment.pci_devices_up.to_le_bytes());
                 // Clear the PCIU bitmap
-                self.pci_devices_up = 0;
+                pci_segment.pci_devices_up = 0;
             }
             PCID_FIELD_OFFSET => {
                 assert!(data.len() == PCID_FIELD_SIZE);
-                data.copy_from_slice(&self.pci_devices_down.to_le_bytes());
+                let pci_segment = &mut self.pci_segments[self.selected_segment];
+                data.copy_from_slice(&pci_segment.pci_devices_down.to_le_bytes());
                 // Clear the PCID bitmap
-                self.pci_devices_down = 0;
+                pci_segment.pci_devices_down = 0;
+            }
+            PSEG_FIELD_OFFSET => {
+                assert!(data.len() == PSEG_FIELD_SIZE);
+                data.copy_from_slice(&(self.selected_segment as u32).to_le_bytes());
             }
             _ => error!(
                 "Accessing unknown location at base 0x{:x}, offset 0x{:x}",
@@ -3815,12 +6143,27 @@ impl BusDevice for DeviceManager {
                 for device_id in 0..32 {
                     let mask = 1u32 << device_id;
                     if (device_bitmap & mask) == mask {
-                        if let Err(e) = self.eject_device(device_id as u8) {
+                        if let Err(e) = self.eject_device(self.selected_segment as u16, device_id) {
                             error!("Failed ejecting device {}: {:?}", device_id, e);
                         }
                     }
                 }
             }
+            PSEG_FIELD_OFFSET => {
+                assert!(data.len() == PSEG_FIELD_SIZE);
+                let mut data_array: [u8; 4] = [0, 0, 0, 0];
+                data_array.copy_from_slice(&data[..]);
+                let selected_segment = u32::from_le_bytes(data_array) as usize;
+                if selected_segment >= self.pci_segments.len() {
+                    error!(
+                        "Segment selection out of range: {} >= {}",
+                        selected_segment,
+                        self.pci_segments.len()
+                    );
+                    return None;
+                }
+                self.selected_segment = selected_segment;
+            }
             _ => error!(
                 "Accessing unknown location at base 0x{:x}, offset 0x{:x}",
                 base, offset
@@ -3838,8 +6181,388 @@ impl BusDevice for DeviceManager {
 
 impl Drop for DeviceManager {
     fn drop(&mut self) {
-        for (device, _, _) in self.virtio_devices.drain(..) {
-            device.lock().unwrap().shutdown();
+        for handle in self.virtio_devices.drain(..) {
+            handle.virtio_device.lock().unwrap().shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_migration::Transportable;
+
+    #[derive(Default)]
+    struct FakeDevice {
+        paused: bool,
+        pauses: u32,
+        resumes: u32,
+    }
+
+    impl Pausable for FakeDevice {
+        fn pause(&mut self) -> result::Result<(), MigratableError> {
+            assert!(!self.paused);
+            self.paused = true;
+            self.pauses += 1;
+            Ok(())
+        }
+
+        fn resume(&mut self) -> result::Result<(), MigratableError> {
+            assert!(self.paused);
+            self.paused = false;
+            self.resumes += 1;
+            Ok(())
+        }
+    }
+
+    impl Snapshottable for FakeDevice {}
+    impl Transportable for FakeDevice {}
+    impl Migratable for FakeDevice {}
+
+    fn device_tree(ids: &[&str]) -> (DeviceTree, Vec<Arc<Mutex<FakeDevice>>>) {
+        let mut device_tree = DeviceTree::new();
+        let mut devices = Vec::new();
+        for id in ids {
+            let id = id.to_string();
+            let device = Arc::new(Mutex::new(FakeDevice::default()));
+            device_tree.insert(id.clone(), device_node!(id, device));
+            devices.push(device);
+        }
+        (device_tree, devices)
+    }
+
+    fn migratable(device: &Arc<Mutex<FakeDevice>>) -> Arc<Mutex<dyn Migratable>> {
+        Arc::clone(device) as Arc<Mutex<dyn Migratable>>
+    }
+
+    #[test]
+    fn test_paused_devices_vm_pause() {
+        let (device_tree, devices) = device_tree(&["disk0", "net0"]);
+        let mut paused_devices = PausedDevices::default();
+
+        // A device paused on its own is left alone by the VM pause and
+        // resume.
+        paused_devices
+            .pause("disk0".to_string(), &migratable(&devices[0]), false)
+            .unwrap();
+        paused_devices.pause_all(&device_tree, None).unwrap();
+        paused_devices.resume_all(&device_tree).unwrap();
+        assert!(devices[0].lock().unwrap().paused);
+        assert_eq!(devices[0].lock().unwrap().pauses, 1);
+        assert_eq!(devices[1].lock().unwrap().pauses, 1);
+        assert_eq!(devices[1].lock().unwrap().resumes, 1);
+
+        // Pausing it again does nothing.
+        paused_devices
+            .pause("disk0".to_string(), &migratable(&devices[0]), false)
+            .unwrap();
+        assert_eq!(devices[0].lock().unwrap().pauses, 1);
+
+        paused_devices
+            .resume("disk0".to_string(), &migratable(&devices[0]), false)
+            .unwrap();
+        assert!(!devices[0].lock().unwrap().paused);
+        assert!(!paused_devices.contains("disk0"));
+    }
+
+    #[test]
+    fn test_paused_devices_vm_paused() {
+        let (device_tree, devices) = device_tree(&["disk0"]);
+        let mut paused_devices = PausedDevices::default();
+
+        // Pausing a device while the VM is paused keeps it paused once the
+        // VM resumes.
+        paused_devices.pause_all(&device_tree, None).unwrap();
+        paused_devices
+            .pause("disk0".to_string(), &migratable(&devices[0]), true)
+            .unwrap();
+        paused_devices.resume_all(&device_tree).unwrap();
+        assert!(devices[0].lock().unwrap().paused);
+        assert_eq!(devices[0].lock().unwrap().pauses, 1);
+
+        // Resuming it while the VM is paused defers to the VM resume.
+        paused_devices.pause_all(&device_tree, None).unwrap();
+        paused_devices
+            .resume("disk0".to_string(), &migratable(&devices[0]), true)
+            .unwrap();
+        assert!(devices[0].lock().unwrap().paused);
+        assert_eq!(devices[0].lock().unwrap().resumes, 0);
+        paused_devices.resume_all(&device_tree).unwrap();
+        assert!(!devices[0].lock().unwrap().paused);
+        assert_eq!(devices[0].lock().unwrap().resumes, 1);
+    }
+
+    #[test]
+    fn test_paused_devices_reset() {
+        let (device_tree, devices) = device_tree(&["disk0"]);
+        let mut paused_devices = PausedDevices::default();
+
+        paused_devices
+            .pause("disk0".to_string(), &migratable(&devices[0]), false)
+            .unwrap();
+
+        // The reset resumes the device threads before replacing them.
+        devices[0].lock().unwrap().resume().unwrap();
+        paused_devices.reset("disk0");
+        assert!(!paused_devices.contains("disk0"));
+
+        // The VM pause no longer skips the running device.
+        paused_devices.pause_all(&device_tree, None).unwrap();
+        assert!(devices[0].lock().unwrap().paused);
+        paused_devices.resume_all(&device_tree).unwrap();
+        assert!(!devices[0].lock().unwrap().paused);
+    }
+
+    #[test]
+    fn test_device_error() {
+        let error = DeviceManagerError::device(
+            Some("_disk0".to_owned()),
+            VirtioDeviceType::TYPE_BLOCK,
+            DeviceStage::Create,
+        )(DeviceManagerError::NoDiskPath);
+        let is_disk0_create = |error: &DeviceManagerError| {
+            matches!(
+                error,
+                DeviceManagerError::Device {
+                    id: Some(id),
+                    device_type,
+                    stage: DeviceStage::Create,
+                    error,
+                } if id == "_disk0"
+                    && device_type == "block"
+                    && matches!(**error, DeviceManagerError::NoDiskPath)
+            )
+        };
+        assert!(is_disk0_create(&error));
+
+        // An error which already tells which device failed is left as it is
+        // by the callers it goes through.
+        let error = DeviceManagerError::device(None, "vfio", DeviceStage::Plug)(error);
+        assert!(is_disk0_create(&error));
+    }
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    fn device_manager(config: &Arc<Mutex<VmConfig>>) -> Arc<Mutex<DeviceManager>> {
+        let hv = hypervisor::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let memory_config = config.lock().unwrap().memory.clone();
+        let memory_manager = MemoryManager::new(vm.clone(), &memory_config, false, 46).unwrap();
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        DeviceManager::new(
+            vm,
+            config.clone(),
+            memory_manager,
+            &evt,
+            &evt,
+            SeccompAction::Allow,
+            #[cfg(feature = "acpi")]
+            NumaNodes::new(),
+            &evt,
+            &evt,
+            &evt,
+        )
+        .unwrap()
+    }
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    #[test]
+    fn test_cold_device_slot() {
+        let mut config: VmConfig = serde_json::from_str("{}").unwrap();
+        config.disks = Some(vec![DiskConfig {
+            id: Some("data".to_owned()),
+            cold: true,
+            ..Default::default()
+        }]);
+        let config = Arc::new(Mutex::new(config));
+
+        let device_manager = device_manager(&config);
+        let mut dm = device_manager.lock().unwrap();
+        dm.reserve_cold_devices(true).unwrap();
+        assert!(dm.cold_devices.is_empty());
+        dm.reserve_cold_devices(false).unwrap();
+        let bdf = dm.cold_devices["data"];
+        assert_eq!(
+            config.lock().unwrap().disks.as_ref().unwrap()[0].cold_pci_bdf,
+            Some(bdf)
+        );
+
+        // Removing the device gives its slot back.
+        dm.remove_device("data".to_owned()).unwrap();
+        assert_eq!(dm.pci_segment(0).unwrap().next_device_bdf().unwrap(), bdf);
+        drop(dm);
+
+        // After a reboot, the device gets its slot back before the others
+        // are placed, whether activated or not.
+        config.lock().unwrap().disks.as_mut().unwrap()[0].cold = false;
+        let device_manager = self::device_manager(&config);
+        let mut dm = device_manager.lock().unwrap();
+        dm.reserve_cold_devices(true).unwrap();
+        assert_eq!(dm.cold_devices["data"], bdf);
+        assert_ne!(dm.pci_segment(0).unwrap().next_device_bdf().unwrap(), bdf);
+        dm.reserve_cold_devices(false).unwrap();
+        assert_eq!(dm.cold_devices.len(), 1);
+    }
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    #[test]
+    fn test_add_disk_error_stage() {
+        let config: VmConfig = serde_json::from_str("{}").unwrap();
+        let device_manager = device_manager(&Arc::new(Mutex::new(config)));
+        let mut dm = device_manager.lock().unwrap();
+
+        // A missing image fails the creation of the device.
+        let mut disk_cfg = DiskConfig::parse("path=/does/not/exist,id=data").unwrap();
+        assert!(matches!(
+            dm.add_disk(&mut disk_cfg),
+            Err(DeviceManagerError::Device {
+                id: Some(id),
+                device_type,
+                stage: DeviceStage::Create,
+                error,
+            }) if id == "data"
+                && device_type == "block"
+                && matches!(*error, DeviceManagerError::Disk(_))
+        ));
+
+        // A missing PCI segment fails placing it on the PCI bus.
+        let disk = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        disk.as_file().set_len(0x10_0000).unwrap();
+        let mut disk_cfg = DiskConfig::parse(&format!(
+            "path={},id=data,pci_segment=1",
+            disk.as_path().display()
+        ))
+        .unwrap();
+        assert!(matches!(
+            dm.add_disk(&mut disk_cfg),
+            Err(DeviceManagerError::Device {
+                id: Some(id),
+                device_type,
+                stage: DeviceStage::Plug,
+                error,
+            }) if id == "data"
+                && device_type == "block"
+                && matches!(*error, DeviceManagerError::InvalidPciSegment(1))
+        ));
+    }
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    #[test]
+    fn test_hotplug_virtio_pci_device_failure() {
+        let disk = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        disk.as_file().set_len(0x10_0000).unwrap();
+        let disk_cfg =
+            DiskConfig::parse(&format!("path={},num_queues=2", disk.as_path().display())).unwrap();
+        let config: VmConfig = serde_json::from_str("{}").unwrap();
+        let device_manager = device_manager(&Arc::new(Mutex::new(config)));
+        let mut dm = device_manager.lock().unwrap();
+
+        // Plug and remove a disk to find out where the next one goes.
+        let info = dm.add_disk(&mut disk_cfg.clone()).unwrap();
+        let addr = Arc::clone(&dm.pci_devices[&info.bdf])
+            .downcast::<Mutex<VirtioPciDevice>>()
+            .map(|virtio_pci_device| {
+                let virtio_pci_device = virtio_pci_device.lock().unwrap();
+                let ioeventfds = virtio_pci_device.ioeventfds(virtio_pci_device.config_bar_addr());
+                ioeventfds.last().unwrap().1
+            })
+            .unwrap();
+        dm.eject_device(0, pci_bdf_device(info.bdf)).unwrap();
+
+        // Taking the address of its last queue notification makes the next
+        // disk fail once it is on the PCI bus, with its other ioeventfds
+        // registered.
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let io_addr = IoEventAddress::Mmio(addr);
+        dm.address_manager
+            .vm
+            .register_ioevent(&evt, &io_addr, None)
+            .unwrap();
+        assert!(matches!(
+            dm.add_disk(&mut disk_cfg.clone()),
+            Err(DeviceManagerError::Device {
+                stage: DeviceStage::Plug,
+                ..
+            })
+        ));
+        assert!(!dm.pci_devices.contains_key(&info.bdf));
+
+        // Nothing is left behind, the slot, the BARs and the other
+        // ioeventfds being available again.
+        dm.address_manager
+            .vm
+            .unregister_ioevent(&evt, &io_addr)
+            .unwrap();
+        let retry = dm.add_disk(&mut disk_cfg.clone()).unwrap();
+        assert_eq!(retry.bdf, info.bdf);
+    }
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    #[test]
+    fn test_set_descriptor_inspector_unknown_device() {
+        struct Noop;
+        impl DescriptorInspector for Noop {
+            fn inspect(&self, _chain: &vm_virtio::DescriptorChainView) -> vm_virtio::Verdict {
+                vm_virtio::Verdict::Process
+            }
+        }
+
+        let config: VmConfig = serde_json::from_str("{}").unwrap();
+        let device_manager = device_manager(&Arc::new(Mutex::new(config)));
+        assert!(matches!(
+            device_manager
+                .lock()
+                .unwrap()
+                .set_descriptor_inspector("_net0", Arc::new(Noop)),
+            Err(DeviceManagerError::UnknownDeviceId(id)) if id == "_net0"
+        ));
+    }
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64", feature = "access_log"))]
+    #[test]
+    fn test_access_log_enabled() {
+        let mut config: VmConfig = serde_json::from_str("{}").unwrap();
+        let device_manager = device_manager(&Arc::new(Mutex::new(config.clone())));
+        assert!(!device_manager.lock().unwrap().access_log_enabled("_net0"));
+
+        // Only the devices whose identifiers are given exactly are logged.
+        config.access_log = Some(vec!["_net0".to_owned(), "_disk1".to_owned()]);
+        let device_manager = self::device_manager(&Arc::new(Mutex::new(config)));
+        let dm = device_manager.lock().unwrap();
+        assert!(dm.access_log_enabled("_net0"));
+        assert!(dm.access_log_enabled("_disk1"));
+        assert!(!dm.access_log_enabled("_net"));
+        assert!(!dm.access_log_enabled("_net01"));
+        assert!(!dm.access_log_enabled("_disk0"));
+    }
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    #[test]
+    fn test_virtio_mmio_device_plug() {
+        let disk = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        disk.as_file().set_len(0x10_0000).unwrap();
+        let mut config: VmConfig = serde_json::from_str("{}").unwrap();
+        config.console = crate::config::ConsoleConfig::parse("off").unwrap();
+        config.disks = Some(vec![DiskConfig::parse(&format!(
+            "path={},transport=mmio",
+            disk.as_path().display()
+        ))
+        .unwrap()]);
+
+        // The registers of the device are plugged in the virtio-mmio window,
+        // which the holes of the PCI segments don't overlap.
+        let device_manager = device_manager(&Arc::new(Mutex::new(config)));
+        let mut dm = device_manager.lock().unwrap();
+        dm.create_devices().unwrap();
+        assert_eq!(dm.virtio_mmio_devices.len(), 1);
+        let addr = dm.virtio_mmio_devices[0].addr;
+        assert!(addr >= layout::MEM_32BIT_DEVICES_START.0);
+        assert!(
+            addr + VIRTIO_MMIO_SIZE
+                <= layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE
+        );
+        for allocator in dm.address_manager.pci_mmio_hole_allocators.iter() {
+            let allocator = allocator.lock().unwrap();
+            assert!(addr + VIRTIO_MMIO_SIZE <= allocator.base().0 || addr > allocator.end().0);
         }
     }
 }
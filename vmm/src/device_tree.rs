@@ -2,7 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::{Arc, Mutex};
 use vm_device::Resource;
 use vm_migration::Migratable;
@@ -16,6 +16,9 @@ pub struct DeviceNode {
     #[serde(skip)]
     pub migratable: Option<Arc<Mutex<dyn Migratable>>>,
     pub pci_bdf: Option<u32>,
+    // Devices which must be activated before this one.
+    #[serde(default)]
+    pub dependencies: Vec<String>,
 }
 
 impl DeviceNode {
@@ -27,6 +30,7 @@ impl DeviceNode {
             children: Vec::new(),
             migratable,
             pci_bdf: None,
+            dependencies: Vec::new(),
         }
     }
 }
@@ -44,6 +48,14 @@ macro_rules! device_node {
     };
 }
 
+#[derive(Debug, PartialEq)]
+pub enum DependencyError {
+    /// A device depends on a device which doesn't exist.
+    MissingDependency(String, String),
+    /// Devices which can't be ordered, as they depend on a dependency cycle.
+    Cycle(Vec<String>),
+}
+
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct DeviceTree(HashMap<String, DeviceNode>);
 
@@ -69,6 +81,58 @@ impl DeviceTree {
     pub fn breadth_first_traversal(&self) -> BftIter {
         BftIter::new(&self.0)
     }
+    // Nodes ordered so that each node comes after the nodes it depends on.
+    // Nodes not depending on each other are sorted by identifier, making the
+    // order deterministic.
+    pub fn dependency_order(&self) -> Result<Vec<&DeviceNode>, DependencyError> {
+        // Number of dependencies not ordered yet for each node.
+        let mut pending: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (id, node) in self.0.iter() {
+            for dependency in node.dependencies.iter() {
+                if !self.0.contains_key(dependency) {
+                    return Err(DependencyError::MissingDependency(
+                        id.clone(),
+                        dependency.clone(),
+                    ));
+                }
+                dependents
+                    .entry(dependency.as_str())
+                    .or_default()
+                    .push(id.as_str());
+            }
+            pending.insert(id.as_str(), node.dependencies.len());
+        }
+
+        let mut ready: BTreeSet<&str> = pending
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut nodes = Vec::with_capacity(self.0.len());
+        while let Some(id) = ready.iter().next().copied() {
+            ready.remove(id);
+            pending.remove(id);
+            nodes.push(&self.0[id]);
+
+            for dependent in dependents.get(id).into_iter().flatten() {
+                if let Some(count) = pending.get_mut(dependent) {
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.insert(dependent);
+                    }
+                }
+            }
+        }
+
+        if !pending.is_empty() {
+            return Err(DependencyError::Cycle(
+                pending.keys().map(|id| id.to_string()).collect(),
+            ));
+        }
+
+        Ok(nodes)
+    }
 }
 
 // Breadth first traversal iterator.
@@ -132,7 +196,7 @@ impl<'a> DoubleEndedIterator for BftIter<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::{DeviceNode, DeviceTree};
+    use super::{DependencyError, DeviceNode, DeviceTree};
 
     #[test]
     fn test_device_tree() {
@@ -250,6 +314,63 @@ mod tests {
             aver_eq!(tb, iter_vec[1].id, child_2_id);
             aver_eq!(tb, iter_vec[0].id, child_3_id);
 
+            Ok(())
+        })
+    }
+    #[test]
+    fn test_dependency_order() {
+        test_block!(tb, "", {
+            let mut device_tree = DeviceTree::new();
+            let iommu_id = String::from("iommu");
+            let disk_id = String::from("disk0");
+            let net_id = String::from("net0");
+            let vfio_id = String::from("vfio0");
+            let mut disk_node = device_node!(disk_id);
+            let mut vfio_node = device_node!(vfio_id);
+            disk_node.dependencies = vec![iommu_id.clone()];
+            vfio_node.dependencies = vec![iommu_id.clone(), disk_id.clone()];
+            device_tree.insert(vfio_id.clone(), vfio_node);
+            device_tree.insert(disk_id.clone(), disk_node);
+            device_tree.insert(net_id.clone(), device_node!(net_id));
+            device_tree.insert(iommu_id.clone(), device_node!(iommu_id));
+
+            let ids = device_tree
+                .dependency_order()
+                .unwrap()
+                .iter()
+                .map(|n| n.id.clone())
+                .collect::<Vec<String>>();
+            aver_eq!(
+                tb,
+                ids,
+                vec![iommu_id.clone(), disk_id.clone(), net_id, vfio_id.clone()]
+            );
+
+            // A dependency on an unknown device.
+            device_tree
+                .get_mut(&disk_id)
+                .unwrap()
+                .dependencies
+                .push(String::from("rng0"));
+            aver_eq!(
+                tb,
+                device_tree.dependency_order().err(),
+                Some(DependencyError::MissingDependency(
+                    disk_id.clone(),
+                    String::from("rng0")
+                ))
+            );
+
+            // The IOMMU depending on the disk closes a cycle, which the VFIO
+            // device depends on.
+            device_tree.get_mut(&disk_id).unwrap().dependencies = vec![iommu_id.clone()];
+            device_tree.get_mut(&iommu_id).unwrap().dependencies = vec![disk_id.clone()];
+            aver_eq!(
+                tb,
+                device_tree.dependency_order().err(),
+                Some(DependencyError::Cycle(vec![disk_id, iommu_id, vfio_id]))
+            );
+
             Ok(())
         })
     }
@@ -0,0 +1,85 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref EVENT_MONITOR: Mutex<Option<File>> = Mutex::new(None);
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    timestamp: Duration,
+    source: &'a str,
+    event: &'a str,
+    properties: BTreeMap<&'a str, String>,
+}
+
+/// Sets the file the lifecycle events of the VMM are written to, as JSON
+/// objects, one per line.
+pub fn set_event_monitor(file: File) {
+    *EVENT_MONITOR.lock().unwrap() = Some(file);
+}
+
+/// Reports a lifecycle event, such as the OOM policy being triggered, if
+/// an event monitor was set.
+pub fn event(source: &str, event: &str, properties: &[(&str, String)]) {
+    let mut monitor = EVENT_MONITOR.lock().unwrap();
+    let file = match monitor.as_mut() {
+        Some(file) => file,
+        None => return,
+    };
+
+    let event = Event {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+        source,
+        event,
+        properties: properties.iter().cloned().collect(),
+    };
+    let mut line = match serde_json::to_vec(&event) {
+        Ok(line) => line,
+        Err(e) => {
+            warn!("Failed serializing event: {}", e);
+            return;
+        }
+    };
+    line.push(b'\n');
+    if let Err(e) = file.write_all(&line) {
+        warn!("Failed writing event: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_event() {
+        // Nothing is reported without a monitor.
+        event("vm", "ignored", &[]);
+
+        let monitor = TempFile::new().unwrap();
+        set_event_monitor(monitor.as_file().try_clone().unwrap());
+        event("vm", "oom-policy", &[("rss", 4096.to_string())]);
+        *EVENT_MONITOR.lock().unwrap() = None;
+
+        let content = fs::read_to_string(monitor.as_path()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let event: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(event["source"], "vm");
+        assert_eq!(event["event"], "oom-policy");
+        assert_eq!(event["properties"]["rss"], "4096");
+        assert!(event["timestamp"]["secs"].as_u64().unwrap() > 0);
+    }
+}
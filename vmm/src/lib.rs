@@ -23,42 +23,68 @@ extern crate vmm_sys_util;
 extern crate credibility;
 
 use crate::api::{
-    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo, VmReceiveMigrationData,
-    VmSendMigrationData, VmmPingResponse,
+    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, FlowRuleData, MigrationThrottleConfig,
+    VmInfo, VmReceiveMigrationData, VmRtcData, VmSendMigrationData, VmmPingResponse,
 };
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
+    CompletionAction, CrashAction, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig,
+    RestoreConfig, VmConfig, VsockConfig,
 };
-use crate::migration::{get_vm_snapshot, recv_vm_snapshot};
+use crate::device_manager::VhostUserBackends;
+use crate::migration::{get_vm_snapshot, recv_vm_snapshot, send_devices_only_snapshot};
+use crate::replication::Replication;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::vm::{Error as VmError, Vm, VmState};
+use crate::vm::{AgentConnection, CrashInfo, Error as VmError, Vm, VmState};
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
 use std::io::{Read, Write};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixListener;
 use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{result, thread};
 use thiserror::Error;
+use virtio_devices::vsock::agent::AgentRequest;
 use vm_migration::protocol::*;
 use vm_migration::{MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::DescriptorInspector;
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+pub use virtio_devices::set_guest_log_limit;
+
+// How often the messages suppressed by the guest log rate limit are
+// reported, for the floods which stopped without another message logged.
+const GUEST_LOG_REPORT_INTERVAL: Duration = Duration::from_secs(10);
 
 pub mod api;
+pub mod cgroup;
+pub mod cmdline;
 pub mod config;
 pub mod cpu;
+pub mod crash_dump;
 pub mod device_manager;
 pub mod device_tree;
+pub mod event_monitor;
 pub mod interrupt;
 pub mod memory_manager;
 pub mod migration;
+pub mod oom_policy;
+pub mod pci_segment;
+pub mod replication;
 pub mod seccomp_filters;
+pub mod selftest;
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+pub mod sev;
+pub mod tcp_console;
 pub mod vm;
 
 #[cfg(feature = "acpi")]
@@ -108,6 +134,14 @@ pub enum Error {
     #[error("Error handling VM pty: {0:?}")]
     Pty(VmError),
 
+    /// Cannot handle the VM TCP console
+    #[error("Error handling VM TCP console: {0:?}")]
+    TcpConsole(VmError),
+
+    /// Cannot take the action configured for the vsock completion
+    #[error("Error handling the vsock completion: {0:?}")]
+    VsockCompletion(VmError),
+
     /// Cannot reboot the VM
     #[error("Error rebooting VM: {0:?}")]
     VmReboot(VmError),
@@ -136,9 +170,9 @@ pub enum Error {
     #[error("Error applying seccomp filter: {0}")]
     ApplySeccompFilter(seccomp::Error),
 
-    /// Error activating virtio devices
-    #[error("Error activating virtio devices: {0:?}")]
-    ActivateVirtioDevices(VmError),
+    /// Cannot set the timer reporting the suppressed guest messages up
+    #[error("Error setting the guest log timer up: {0}")]
+    GuestLogTimer(#[source] io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -150,6 +184,13 @@ pub enum EpollDispatch {
     Api,
     ActivateVirtioDevices,
     Pty,
+    TcpConsole,
+    OomPolicy,
+    TripleFault,
+    GuestPanic,
+    VsockCompletion,
+    Replication,
+    GuestLogReport,
 }
 
 pub struct EpollContext {
@@ -281,6 +322,17 @@ pub fn start_vmm_thread(
     Ok(thread)
 }
 
+// Reports the activation, pause and reset of the devices to the event
+// monitor.
+fn report_device_event(id: &str, event: virtio_devices::DeviceEvent) {
+    let event = match event {
+        virtio_devices::DeviceEvent::Activated => "activated",
+        virtio_devices::DeviceEvent::Deactivated => "deactivated",
+        virtio_devices::DeviceEvent::Reset => "reset",
+    };
+    event_monitor::event("device", event, &[("id", id.to_owned())]);
+}
+
 pub struct Vmm {
     epoll: EpollContext,
     exit_evt: EventFd,
@@ -292,6 +344,16 @@ pub struct Vmm {
     seccomp_action: SeccompAction,
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     activate_evt: EventFd,
+    // Kept here rather than in the VM, which is recreated on reboot.
+    triple_fault: Option<CrashInfo>,
+    guest_panic: Option<CrashInfo>,
+    // Replication of the guest memory to a standby, until failover.
+    replication: Option<Replication>,
+    guest_log_timer: TimerFd,
+    // Registered with every VM created, for them to outlive reboots.
+    device_event_callbacks: Vec<virtio_devices::DeviceEventCallback>,
+    // Registered with every VM created as well, by device id.
+    descriptor_inspectors: HashMap<String, Arc<dyn DescriptorInspector>>,
 }
 
 impl Vmm {
@@ -326,6 +388,14 @@ impl Vmm {
             .add_event(&api_evt, EpollDispatch::Api)
             .map_err(Error::Epoll)?;
 
+        let mut guest_log_timer = TimerFd::new().map_err(Error::GuestLogTimer)?;
+        guest_log_timer
+            .reset(GUEST_LOG_REPORT_INTERVAL, Some(GUEST_LOG_REPORT_INTERVAL))
+            .map_err(Error::GuestLogTimer)?;
+        epoll
+            .add_event(&guest_log_timer, EpollDispatch::GuestLogReport)
+            .map_err(Error::Epoll)?;
+
         Ok(Vmm {
             epoll,
             exit_evt,
@@ -337,9 +407,33 @@ impl Vmm {
             seccomp_action,
             hypervisor,
             activate_evt,
+            triple_fault: None,
+            guest_panic: None,
+            replication: None,
+            guest_log_timer,
+            device_event_callbacks: vec![
+                Arc::new(report_device_event) as virtio_devices::DeviceEventCallback
+            ],
+            descriptor_inspectors: HashMap::new(),
         })
     }
 
+    fn register_device_event_callbacks(&self, vm: &Vm) {
+        for callback in self.device_event_callbacks.iter() {
+            vm.register_device_event_callback(callback.clone());
+        }
+        // The configuration of the VM may have changed since the inspector
+        // was registered, without the device.
+        for (id, inspector) in self.descriptor_inspectors.iter() {
+            if let Err(e) = vm.set_descriptor_inspector(id, inspector.clone()) {
+                warn!(
+                    "Error registering the descriptor inspector of {}: {:?}",
+                    id, e
+                );
+            }
+        }
+    }
+
     fn vm_boot(&mut self) -> result::Result<(), VmError> {
         // Create a new VM is we don't have one yet.
         if self.vm.is_none() {
@@ -358,6 +452,7 @@ impl Vmm {
                     &self.seccomp_action,
                     self.hypervisor.clone(),
                     activate_evt,
+                    VhostUserBackends::new(),
                 )?;
                 if let Some(ref serial_pty) = vm.serial_pty() {
                     self.epoll
@@ -369,6 +464,8 @@ impl Vmm {
                         .add_event(console_pty, EpollDispatch::Pty)
                         .map_err(VmError::EventfdError)?;
                 };
+                self.register_vm_events(&vm)
+                    .map_err(VmError::EventfdError)?;
                 self.vm = Some(vm);
             }
         }
@@ -381,6 +478,153 @@ impl Vmm {
         }
     }
 
+    // The consoles served over TCP are recreated along with the devices of
+    // the VM, which keeps them reachable across reboots, restores and
+    // migrations.
+    fn add_tcp_console_events(epoll: &mut EpollContext, vm: &Vm) -> result::Result<(), io::Error> {
+        if let Some(serial_tcp) = vm.serial_tcp() {
+            epoll.add_event(serial_tcp.as_ref(), EpollDispatch::TcpConsole)?;
+        }
+        if let Some(console_tcp) = vm.console_tcp() {
+            epoll.add_event(console_tcp.as_ref(), EpollDispatch::TcpConsole)?;
+        }
+
+        Ok(())
+    }
+
+    // The OOM policy checks are paced by a timer handled from the epoll loop.
+    fn add_oom_policy_event(&mut self, vm: &Vm) -> result::Result<(), io::Error> {
+        if let Some(timer) = vm.oom_policy_timer() {
+            self.epoll.add_event(timer, EpollDispatch::OomPolicy)?;
+        }
+
+        Ok(())
+    }
+
+    // Registers the events of a newly created VM, once its devices exist.
+    fn register_vm_events(&mut self, vm: &Vm) -> result::Result<(), io::Error> {
+        Self::add_tcp_console_events(&mut self.epoll, vm)?;
+        self.add_oom_policy_event(vm)?;
+        self.register_device_event_callbacks(vm);
+        self.epoll
+            .add_event(vm.triple_fault_evt(), EpollDispatch::TripleFault)?;
+        self.epoll
+            .add_event(vm.guest_panic_evt(), EpollDispatch::GuestPanic)?;
+        self.epoll
+            .add_event(vm.vsock_completion_evt(), EpollDispatch::VsockCompletion)?;
+
+        Ok(())
+    }
+
+    // The vCPU which triple faulted stopped running, or waits for the VM to
+    // be paused.
+    fn vm_triple_fault(&mut self, actions: Vec<CrashAction>) {
+        let count = self.triple_fault.as_ref().map_or(0, |i| i.count) + 1;
+        warn!(
+            "VM event: the guest triple faulted ({} so far), actions: {:?}",
+            count, actions
+        );
+
+        let completed = self.vm_crash("triple-fault", count, &actions);
+        self.triple_fault = Some(CrashInfo {
+            count,
+            actions,
+            completed,
+        });
+    }
+
+    // The guest panicked, and keeps running its panic handler.
+    fn vm_guest_panic(&mut self, actions: Vec<CrashAction>) {
+        let count = self.guest_panic.as_ref().map_or(0, |i| i.count) + 1;
+        warn!(
+            "VM event: the guest panicked ({} so far), actions: {:?}",
+            count, actions
+        );
+
+        let completed = self.vm_crash("panic", count, &actions);
+        self.guest_panic = Some(CrashInfo {
+            count,
+            actions,
+            completed,
+        });
+    }
+
+    // Runs the crash actions in order, the first failing one stopping the
+    // others, and returns how many completed.
+    fn vm_crash(&mut self, reason: &str, count: u64, actions: &[CrashAction]) -> usize {
+        CrashAction::run_chain(actions, |action| {
+            self.vm_crash_action(action, reason, count)
+        })
+    }
+
+    fn vm_crash_action(
+        &mut self,
+        action: &CrashAction,
+        reason: &str,
+        count: u64,
+    ) -> result::Result<(), VmError> {
+        // Several vCPUs faulting leave the VM paused by the first one.
+        let paused = matches!(
+            self.vm.as_ref().map(|vm| vm.get_state()),
+            Some(Ok(VmState::Paused))
+        );
+
+        match action {
+            CrashAction::Snapshot(destination_url) => {
+                if !paused {
+                    self.vm_quiesce()?;
+                }
+                self.vm_snapshot(destination_url, false)
+            }
+            CrashAction::Dump(file) => {
+                if !paused {
+                    self.vm_quiesce()?;
+                }
+                match self.vm {
+                    Some(ref vm) => vm.dump_memory(file),
+                    None => Err(VmError::VmNotRunning),
+                }
+            }
+            CrashAction::Event => {
+                event_monitor::event(
+                    "vm",
+                    "crash",
+                    &[("reason", reason.to_owned()), ("count", count.to_string())],
+                );
+                Ok(())
+            }
+            CrashAction::Pause if paused => Ok(()),
+            CrashAction::Pause => self.vm_pause(),
+            CrashAction::Reboot => self.vm_reboot(),
+            CrashAction::Shutdown => {
+                self.exit_evt.write(1).unwrap();
+                Ok(())
+            }
+        }
+    }
+
+    // The guest closed a connection on the vsock completion port, signaling
+    // the task it runs is done.
+    fn vm_vsock_completion(&mut self, action: CompletionAction) -> result::Result<(), VmError> {
+        info!(
+            "VM event: the guest signaled its completion, action: {:?}",
+            action
+        );
+
+        match action {
+            CompletionAction::Shutdown => {
+                self.exit_evt.write(1).unwrap();
+                Ok(())
+            }
+            // Several connections being closed leave the VM paused by the
+            // first one.
+            CompletionAction::Pause => match self.vm.as_ref().map(|vm| vm.get_state()) {
+                Some(Ok(VmState::Paused)) => Ok(()),
+                _ => self.vm_pause(),
+            },
+        }
+    }
+
     fn vm_pause(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             vm.pause().map_err(VmError::Pause)
@@ -389,6 +633,15 @@ impl Vmm {
         }
     }
 
+    fn vm_quiesce(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.quiesce(Instant::now() + QUIESCE_TIMEOUT)
+                .map_err(VmError::Pause)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_resume(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             vm.resume().map_err(VmError::Resume)
@@ -397,8 +650,22 @@ impl Vmm {
         }
     }
 
-    fn vm_snapshot(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+    fn vm_snapshot(
+        &mut self,
+        destination_url: &str,
+        devices_only: bool,
+    ) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
+            if devices_only {
+                return vm
+                    .snapshot_devices_only()
+                    .map_err(VmError::Snapshot)
+                    .and_then(|snapshot| {
+                        send_devices_only_snapshot(&snapshot, destination_url)
+                            .map_err(VmError::SnapshotSend)
+                    });
+            }
+
             vm.snapshot()
                 .map_err(VmError::Snapshot)
                 .and_then(|snapshot| {
@@ -434,7 +701,7 @@ impl Vmm {
             .try_clone()
             .map_err(VmError::EventFdClone)?;
 
-        let vm = Vm::new_from_snapshot(
+        let mut vm = Vm::new_from_snapshot(
             &snapshot,
             exit_evt,
             reset_evt,
@@ -444,17 +711,18 @@ impl Vmm {
             self.hypervisor.clone(),
             activate_evt,
         )?;
+
+        // Now we can restore the rest of the VM, which creates the devices.
+        vm.restore(snapshot).map_err(VmError::Restore)?;
+        self.register_vm_events(&vm)
+            .map_err(VmError::EventfdError)?;
         self.vm = Some(vm);
 
-        // Now we can restore the rest of the VM.
-        if let Some(ref mut vm) = self.vm {
-            vm.restore(snapshot).map_err(VmError::Restore)
-        } else {
-            Err(VmError::VmNotCreated)
-        }
+        Ok(())
     }
 
     fn vm_shutdown(&mut self) -> result::Result<(), VmError> {
+        self.stop_replication();
         if let Some(ref mut vm) = self.vm.take() {
             vm.shutdown()
         } else {
@@ -473,10 +741,17 @@ impl Vmm {
             }
         }
 
+        // The memory of the new VM isn't the one the standby holds a copy of.
+        self.stop_replication();
+
         // First we stop the current VM and create a new one.
-        if let Some(ref mut vm) = self.vm {
+        if let Some(mut vm) = self.vm.take() {
             let config = vm.get_config();
-            self.vm_shutdown()?;
+            vm.shutdown()?;
+            // The connections to the vhost-user backends survive the reboot,
+            // only the state of the devices visible to the guest is reset.
+            let vhost_user_backends = vm.take_vhost_user_backends();
+            drop(vm);
 
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
@@ -491,14 +766,18 @@ impl Vmm {
             if self.reset_evt.read().is_ok() {
                 warn!("Spurious second reset event received. Ignoring.");
             }
-            self.vm = Some(Vm::new(
+            let vm = Vm::new(
                 config,
                 exit_evt,
                 reset_evt,
                 &self.seccomp_action,
                 self.hypervisor.clone(),
                 activate_evt,
-            )?);
+                vhost_user_backends,
+            )?;
+            self.register_vm_events(&vm)
+                .map_err(VmError::EventfdError)?;
+            self.vm = Some(vm);
         }
 
         // Then we start the new VM.
@@ -524,13 +803,45 @@ impl Vmm {
                     memory_actual_size -= vm.balloon_size();
                 }
 
+                let memory_locked_size = self.vm.as_ref().and_then(|vm| vm.memory_locked_size());
+                let memory_hotplug_region =
+                    self.vm.as_ref().and_then(|vm| vm.memory_hotplug_region());
                 let device_tree = self.vm.as_ref().map(|vm| vm.device_tree());
+                let cgroup = self.vm.as_ref().and_then(|vm| vm.cgroup_path());
+                let memory_target = self.vm.as_ref().and_then(|vm| vm.memory_target());
+                let oom_policy = self.vm.as_ref().and_then(|vm| vm.oom_policy_info());
+                let disk_io_priorities = self
+                    .vm
+                    .as_ref()
+                    .map(|vm| vm.disk_io_priorities())
+                    .filter(|io_priorities| !io_priorities.is_empty());
+                let triple_fault = self.triple_fault.clone();
+                let guest_panic = self.guest_panic.clone();
+                #[cfg(target_arch = "x86_64")]
+                let nested = self.vm.as_ref().map(|vm| vm.nested_virtualization());
+                #[cfg(target_arch = "aarch64")]
+                let nested = None;
+                #[cfg(target_arch = "x86_64")]
+                let tsc_deadline = self.vm.as_ref().map(|vm| vm.tsc_deadline_timer());
+                #[cfg(target_arch = "aarch64")]
+                let tsc_deadline = None;
 
                 Ok(VmInfo {
                     config,
                     state,
                     memory_actual_size,
+                    memory_locked_size,
+                    memory_hotplug_region,
                     device_tree,
+                    cgroup,
+                    memory_target,
+                    oom_policy,
+                    triple_fault,
+                    guest_panic,
+                    nested,
+                    tsc_deadline,
+                    replication: self.replication.as_ref().map(|r| r.info()),
+                    disk_io_priorities,
                 })
             }
             None => Err(VmError::VmNotCreated),
@@ -554,6 +865,8 @@ impl Vmm {
         }
 
         self.vm_config = None;
+        self.triple_fault = None;
+        self.guest_panic = None;
 
         Ok(())
     }
@@ -580,6 +893,19 @@ impl Vmm {
         }
     }
 
+    fn vm_add_dimm(&mut self, size: u64) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.add_dimm(size) {
+                error!("Error when adding a DIMM: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_resize_zone(&mut self, id: String, desired_ram: u64) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             if let Err(e) = vm.resize_zone(id, desired_ram) {
@@ -593,6 +919,19 @@ impl Vmm {
         }
     }
 
+    fn vm_set_memory_target(&mut self, size: u64) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.set_memory_target(size) {
+                error!("Error when setting the VM memory target: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_add_device(&mut self, device_cfg: DeviceConfig) -> result::Result<Vec<u8>, VmError> {
         if let Some(ref mut vm) = self.vm {
             let info = vm.add_device(device_cfg).map_err(|e| {
@@ -618,6 +957,51 @@ impl Vmm {
         }
     }
 
+    fn vm_reset_device(&mut self, id: String) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.reset_device(id).map_err(|e| {
+                error!("Error when resetting device: {:?}", e);
+                e
+            })
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_pause_device(&mut self, id: String) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.pause_device(id).map_err(|e| {
+                error!("Error when pausing device: {:?}", e);
+                e
+            })
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_resume_device(&mut self, id: String) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.resume_device(id).map_err(|e| {
+                error!("Error when resuming device: {:?}", e);
+                e
+            })
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_activate_device(&mut self, id: String) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let info = vm.activate_device(id).map_err(|e| {
+                error!("Error when activating device: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_add_disk(&mut self, disk_cfg: DiskConfig) -> result::Result<Vec<u8>, VmError> {
         if let Some(ref mut vm) = self.vm {
             let info = vm.add_disk(disk_cfg).map_err(|e| {
@@ -678,6 +1062,50 @@ impl Vmm {
         }
     }
 
+    fn vm_agent_connection(&mut self) -> result::Result<AgentConnection, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.agent_connection()
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    // The agent may take a while to answer, the request runs in its own
+    // thread which sends the response back, not to block the VMM thread.
+    fn vm_agent_request(&mut self, request: AgentRequest, sender: Sender<ApiResponse>) {
+        let connection = match self.vm_agent_connection() {
+            Ok(connection) => connection,
+            Err(e) => {
+                sender.send(Err(ApiError::VmAgentRequest(e))).ok();
+                return;
+            }
+        };
+
+        let request_sender = sender.clone();
+        let result = thread::Builder::new()
+            .name("agent_request".to_string())
+            .spawn(move || {
+                let response = connection
+                    .request(request)
+                    .map_err(|e| {
+                        error!("Error when sending request to the guest agent: {:?}", e);
+                        e
+                    })
+                    .and_then(|response| {
+                        serde_json::to_vec(&response).map_err(VmError::SerializeJson)
+                    })
+                    .map_err(ApiError::VmAgentRequest)
+                    .map(ApiResponsePayload::VmAction);
+                request_sender.send(response).ok();
+            });
+        if let Err(e) = result {
+            error!("Failed to spawn the guest agent request thread: {:?}", e);
+            sender
+                .send(Err(ApiError::VmAgentRequest(VmError::AgentConnect(e))))
+                .ok();
+        }
+    }
+
     fn vm_counters(&mut self) -> result::Result<Vec<u8>, VmError> {
         if let Some(ref mut vm) = self.vm {
             let info = vm.counters().map_err(|e| {
@@ -690,6 +1118,179 @@ impl Vmm {
         }
     }
 
+    fn vm_get_rtc(&mut self) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let rtc = VmRtcData {
+                time: vm.rtc_time()?,
+            };
+            serde_json::to_vec(&rtc).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_launch_measurement(&self) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let measurement = vm.launch_measurement()?;
+            serde_json::to_vec(&measurement).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_descriptor_inspector(
+        &mut self,
+        id: String,
+        inspector: Arc<dyn DescriptorInspector>,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.set_descriptor_inspector(&id, inspector.clone())?;
+        }
+        self.descriptor_inspectors.insert(id, inspector);
+
+        Ok(())
+    }
+
+    fn vm_set_rtc(&mut self, time: u64) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.set_rtc_time(time) {
+                error!("Error when setting the guest RTC time: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_input_event(
+        &mut self,
+        id: &str,
+        events: &[virtio_devices::InputEvent],
+    ) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Err(e) = vm.inject_input_events(id, events) {
+                error!("Error when injecting input events: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_gpio(&self, id: &str, line: u16, value: bool) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Err(e) = vm.set_gpio_line(id, line, value) {
+                error!("Error when setting the GPIO line: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_gpio_lines(&self, id: &str) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let lines = vm.gpio_lines(id)?;
+            serde_json::to_vec(&lines).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_net_capture(&self, id: &str, path: Option<&Path>) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Err(e) = vm.net_capture(id, path) {
+                error!(
+                    "Error when starting or stopping the network capture: {:?}",
+                    e
+                );
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    #[cfg(feature = "guest_debug")]
+    fn vm_read_guest_mem(&self, gpa: u64, len: usize) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let data = vm.read_guest_memory(gpa, len)?;
+            serde_json::to_vec(&data).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    #[cfg(feature = "guest_debug")]
+    fn vm_write_guest_mem(&self, gpa: u64, data: &[u8]) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.write_guest_memory(gpa, data)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    fn vm_vcpu_registers(&self, id: u8) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let registers = vm.vcpu_registers(id)?;
+            serde_json::to_vec(&registers).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_inject_nmi(&self, id: Option<u8>) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.inject_nmi(id)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_battery(&self, ac_online: bool, charge: u8) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.set_battery(ac_online, charge)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_flow_rules(&self, id: &str, rules: &[FlowRuleData]) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            let rules: Vec<net_util::FlowRule> = rules.iter().map(|r| r.into()).collect();
+            if let Err(e) = vm.set_flow_rules(id, &rules) {
+                error!("Error when programming the flow rules: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_net_queues(&self, id: &str, num_queues: usize) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Err(e) = vm.set_net_queues(id, num_queues) {
+                error!("Error when setting the active queues: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_power_button(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             vm.power_button()
@@ -746,11 +1347,49 @@ impl Vmm {
         Ok(vm)
     }
 
-    fn vm_receive_state<T>(
+    #[cfg(target_arch = "x86_64")]
+    fn vm_receive_cpuid<T>(
         &mut self,
         req: &Request,
         socket: &mut T,
-        mut vm: Vm,
+        vm: &Vm,
+    ) -> std::result::Result<(), MigratableError>
+    where
+        T: Read + Write,
+    {
+        // Read in CPUID data
+        let mut data = vec![0u8; req.length() as usize];
+        socket
+            .read_exact(&mut data)
+            .map_err(MigratableError::MigrateSocket)?;
+        let source_cpuid: Vec<arch::x86_64::CpuidLeaf> =
+            serde_json::from_slice(&data).map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error deserialising CPUID: {}", e))
+            })?;
+
+        let missing = arch::x86_64::missing_cpuid_features(&source_cpuid, &vm.cpuid());
+        if !missing.is_empty() {
+            let missing = missing.join(", ");
+            Response::new(Status::Error, missing.len() as u64).write_to(socket)?;
+            socket
+                .write_all(missing.as_bytes())
+                .map_err(MigratableError::MigrateSocket)?;
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "Missing CPU features: {}",
+                missing
+            )));
+        }
+
+        Response::ok().write_to(socket)?;
+
+        Ok(())
+    }
+
+    fn vm_receive_state<T>(
+        &mut self,
+        req: &Request,
+        socket: &mut T,
+        mut vm: Vm,
     ) -> std::result::Result<(), MigratableError>
     where
         T: Read + Write,
@@ -772,6 +1411,10 @@ impl Vmm {
             Response::error().write_to(socket).ok();
             e
         })?;
+        self.register_vm_events(&vm).map_err(|e| {
+            Response::error().write_to(socket).ok();
+            MigratableError::MigrateReceive(anyhow!("Error registering VM events: {}", e))
+        })?;
         self.vm = Some(vm);
 
         Response::ok().write_to(socket)?;
@@ -800,6 +1443,34 @@ impl Vmm {
         Ok(())
     }
 
+    fn vm_receive_disk<T>(
+        &mut self,
+        req: &Request,
+        socket: &mut T,
+        vm: &mut Vm,
+    ) -> std::result::Result<(), MigratableError>
+    where
+        T: Read + Write,
+    {
+        let table_length = req
+            .length()
+            .checked_sub(DiskHeader::length())
+            .ok_or_else(|| MigratableError::MigrateReceive(anyhow!("Invalid disk header")))?;
+
+        // Read header and table
+        let header = DiskHeader::read_from(socket)?;
+        let table = MemoryRangeTable::read_from(socket, table_length)?;
+
+        // And then read the disk content itself
+        vm.receive_disk_ranges(&header, &table, socket)
+            .map_err(|e| {
+                Response::error().write_to(socket).ok();
+                e
+            })?;
+        Response::ok().write_to(socket)?;
+        Ok(())
+    }
+
     fn vm_receive_migration(
         &mut self,
         receive_data_migration: VmReceiveMigrationData,
@@ -891,6 +1562,44 @@ impl Vmm {
                         Response::error().write_to(&mut socket)?;
                     }
                 }
+                Command::Disk => {
+                    info!("Disk Command Received");
+
+                    if !started {
+                        warn!("Migration not started yet");
+                        Response::error().write_to(&mut socket)?;
+                        continue;
+                    }
+                    if let Some(ref mut vm) = vm.as_mut() {
+                        self.vm_receive_disk(&req, &mut socket, vm)?;
+                    } else {
+                        warn!("Configuration not sent yet");
+                        Response::error().write_to(&mut socket)?;
+                    }
+                }
+                Command::Cpuid => {
+                    info!("Cpuid Command Received");
+
+                    if !started {
+                        warn!("Migration not started yet");
+                        Response::error().write_to(&mut socket)?;
+                        continue;
+                    }
+                    #[cfg(target_arch = "x86_64")]
+                    {
+                        if let Some(ref vm) = vm.as_ref() {
+                            self.vm_receive_cpuid(&req, &mut socket, vm)?;
+                        } else {
+                            warn!("Configuration not sent yet");
+                            Response::error().write_to(&mut socket)?;
+                        }
+                    }
+                    #[cfg(target_arch = "aarch64")]
+                    {
+                        warn!("CPUID is not supported on AArch64");
+                        Response::error().write_to(&mut socket)?;
+                    }
+                }
                 Command::Complete => {
                     info!("Complete Command Received");
                     if let Some(ref mut vm) = self.vm.as_mut() {
@@ -915,11 +1624,11 @@ impl Vmm {
         Ok(())
     }
 
-    // Returns true if there were dirty pages to send
+    // Returns the number of dirty bytes sent
     fn vm_maybe_send_dirty_pages<T>(
         vm: &mut Vm,
         socket: &mut T,
-    ) -> result::Result<bool, MigratableError>
+    ) -> result::Result<u64, MigratableError>
     where
         T: Read + Write,
     {
@@ -928,10 +1637,10 @@ impl Vmm {
 
         // But if there are no regions go straight to pause
         if table.regions().is_empty() {
-            return Ok(false);
+            return Ok(0);
         }
 
-        Request::memory(table.length()).write_to(socket).unwrap();
+        Request::memory(table.length()).write_to(socket)?;
         table.write_to(socket)?;
         // And then the memory itself
         vm.send_memory_regions(&table, socket)?;
@@ -945,7 +1654,106 @@ impl Vmm {
             )));
         }
 
-        Ok(true)
+        Ok(table.memory_size())
+    }
+
+    // Sends the memory dirtied since the previous pass until none is left or
+    // the passes are exhausted, then pauses the VM. With auto_converge, the
+    // vCPUs are throttled harder after each pass dirtying more than half the
+    // memory sent by the previous one, meaning the guest dirties its memory
+    // faster than half the migration bandwidth.
+    fn vm_send_dirty_memory<T>(
+        vm: &mut Vm,
+        socket: &mut T,
+        mut previous_size: u64,
+        auto_converge: Option<MigrationThrottleConfig>,
+    ) -> result::Result<(), MigratableError>
+    where
+        T: Read + Write,
+    {
+        // Try at most 5 passes of dirty memory sending
+        const MAX_DIRTY_MIGRATIONS: usize = 5;
+        let mut throttle = 0;
+        for i in 0..MAX_DIRTY_MIGRATIONS {
+            info!("Dirty memory migration {} of {}", i, MAX_DIRTY_MIGRATIONS);
+            let start = Instant::now();
+            let size = Self::vm_maybe_send_dirty_pages(vm, socket)?;
+            if size == 0 {
+                break;
+            }
+            let elapsed = start.elapsed();
+
+            if let Some(config) = auto_converge {
+                let percentage = config.next_percentage(throttle, size, previous_size);
+                if percentage != throttle {
+                    throttle = percentage;
+                    vm.throttle_vcpus(throttle)?;
+                }
+            }
+
+            info!(
+                "Migration progress: pass {} sent {} dirty bytes in {:?} \
+                 ({} bytes dirty before), vCPUs throttled by {}%",
+                i, size, elapsed, previous_size, throttle
+            );
+            previous_size = size;
+        }
+
+        // Now pause VM, none of its threads being left in the middle of
+        // an operation
+        vm.quiesce(Instant::now() + QUIESCE_TIMEOUT)
+    }
+
+    fn vm_send_memory<T>(
+        vm: &mut Vm,
+        socket: &mut T,
+        table: &MemoryRangeTable,
+    ) -> result::Result<(), MigratableError>
+    where
+        T: Read + Write,
+    {
+        // Send memory table
+        Request::memory(table.length()).write_to(socket)?;
+        table.write_to(socket)?;
+        // And then the memory itself
+        vm.send_memory_regions(table, socket)?;
+        let res = Response::read_from(socket)?;
+        if res.status() != Status::Ok {
+            warn!("Error during memory migration");
+            Request::abandon().write_to(socket)?;
+            Response::read_from(socket).ok();
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error during memory migration"
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn vm_send_disks<T>(vm: &mut Vm, socket: &mut T) -> result::Result<(), MigratableError>
+    where
+        T: Read + Write,
+    {
+        for index in vm.migratable_disks()? {
+            // Send the header and the table of the extents holding data
+            let (header, table) = vm.disk_range_table(index)?;
+            Request::disk(DiskHeader::length() + table.length()).write_to(socket)?;
+            header.write_to(socket)?;
+            table.write_to(socket)?;
+            // And then the data itself, the holes are not sent
+            vm.send_disk_ranges(index, &table, socket)?;
+            let res = Response::read_from(socket)?;
+            if res.status() != Status::Ok {
+                warn!("Error during disk migration");
+                Request::abandon().write_to(socket)?;
+                Response::read_from(socket).ok();
+                return Err(MigratableError::MigrateSend(anyhow!(
+                    "Error during disk migration"
+                )));
+            }
+        }
+
+        Ok(())
     }
 
     fn vm_send_migration(
@@ -956,9 +1764,42 @@ impl Vmm {
             "Sending migration: destination_url = {}",
             send_data_migration.destination_url
         );
+        if self.replication.is_some() {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Replication already started"
+            )));
+        }
+        if send_data_migration.replication_interval == Some(0) {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Invalid replication interval"
+            )));
+        }
         if let Some(ref mut vm) = self.vm {
             let url = url::Url::parse(&send_data_migration.destination_url)
                 .map_err(|e| MigratableError::MigrateSend(anyhow!("Error parsing URL: {}", e)))?;
+            if let Some(config) = send_data_migration.auto_converge {
+                if !config.is_valid() {
+                    return Err(MigratableError::MigrateSend(anyhow!(
+                        "Invalid vCPU throttling: initial {}%, increment {}%, max {}%",
+                        config.initial,
+                        config.increment,
+                        config.max
+                    )));
+                }
+            }
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            if vm.get_config().lock().unwrap().sev.is_some() {
+                return Err(MigratableError::MigrateSend(anyhow!(
+                    "Migrating a VM with encrypted memory isn't supported"
+                )));
+            }
+            // Refused before any guest memory is sent, as the snapshot
+            // ending the migration would be.
+            if vm.get_config().lock().unwrap().tpm.is_some() {
+                return Err(MigratableError::MigrateSend(anyhow!(
+                    "Migrating a VM with a vTPM isn't supported"
+                )));
+            }
             let mut socket = match url.scheme() {
                 "unix" => UnixStream::connect(url.to_file_path().map_err(|_| {
                     MigratableError::MigrateSend(anyhow!("Error extracting path from URL"))
@@ -1001,77 +1842,216 @@ impl Vmm {
                 )));
             }
 
+            // Let the destination check it can expose the same CPU features
+            #[cfg(target_arch = "x86_64")]
+            if send_data_migration.check_cpuid {
+                let cpuid_data = serde_json::to_vec(&vm.cpuid()).map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!("Error serialising CPUID: {}", e))
+                })?;
+                Request::cpuid(cpuid_data.len() as u64).write_to(&mut socket)?;
+                socket
+                    .write_all(&cpuid_data)
+                    .map_err(MigratableError::MigrateSocket)?;
+                let res = Response::read_from(&mut socket)?;
+                if res.status() != Status::Ok {
+                    let mut missing = vec![0u8; res.length() as usize];
+                    socket.read_exact(&mut missing).ok();
+                    warn!("Error during CPUID migration");
+                    Request::abandon().write_to(&mut socket).ok();
+                    Response::read_from(&mut socket).ok();
+                    return Err(MigratableError::MigrateSend(anyhow!(
+                        "Destination CPU is not compatible: {}",
+                        String::from_utf8_lossy(&missing)
+                    )));
+                }
+            }
+
             // Start logging dirty pages
             vm.start_memory_dirty_log()?;
 
-            // Send memory table
-            let table = vm.memory_range_table()?;
-            Request::memory(table.length())
-                .write_to(&mut socket)
-                .unwrap();
-            table.write_to(&mut socket)?;
-            // And then the memory itself
-            vm.send_memory_regions(&table, &mut socket)?;
-            let res = Response::read_from(&mut socket)?;
-            if res.status() != Status::Ok {
-                warn!("Error during memory migration");
-                Request::abandon().write_to(&mut socket)?;
-                Response::read_from(&mut socket).ok();
-                return Err(MigratableError::MigrateSend(anyhow!(
-                    "Error during memory migration"
-                )));
+            // Let the guest report its free pages, which don't need to be
+            // sent. It doesn't touch them until they're released.
+            let mut table = vm.memory_range_table()?;
+            let free_page_hints = vm.request_free_page_hints()?;
+            if free_page_hints {
+                if let Some(used_table) =
+                    vm.exclude_free_pages(&table, Instant::now() + FREE_PAGE_HINT_TIMEOUT)
+                {
+                    table = used_table;
+                }
             }
 
-            // Try at most 5 passes of dirty memory sending
-            const MAX_DIRTY_MIGRATIONS: usize = 5;
-            for i in 0..MAX_DIRTY_MIGRATIONS {
-                info!("Dirty memory migration {} of {}", i, MAX_DIRTY_MIGRATIONS);
-                if !Self::vm_maybe_send_dirty_pages(vm, &mut socket)? {
-                    break;
-                }
+            let result = Self::vm_send_memory(vm, &mut socket, &table);
+            // The free pages the guest reuses from now on are dirtied, and
+            // sent by the following passes.
+            if free_page_hints {
+                vm.release_free_page_hints()?;
+            }
+            result?;
+
+            // The standby keeps receiving the dirty memory until failover
+            if let Some(interval) = send_data_migration.replication_interval {
+                let replication = Replication::new(
+                    socket,
+                    interval,
+                    send_data_migration.copy_disks,
+                    send_data_migration.auto_converge,
+                    table.memory_size(),
+                )
+                .map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!("Error starting replication: {:?}", e))
+                })?;
+                self.epoll
+                    .add_event(replication.timer(), EpollDispatch::Replication)
+                    .map_err(|e| {
+                        MigratableError::MigrateSend(anyhow!(
+                            "Error registering replication timer: {}",
+                            e
+                        ))
+                    })?;
+                self.replication = Some(replication);
+                info!("Replication started, every {}ms", interval);
+                return Ok(());
             }
 
-            // Now pause VM
-            vm.pause()?;
+            Self::vm_complete_migration(
+                vm,
+                &mut socket,
+                table.memory_size(),
+                send_data_migration.auto_converge,
+                send_data_migration.copy_disks,
+            )
+        } else {
+            Err(MigratableError::MigrateSend(anyhow!("VM is not running")))
+        }
+    }
 
-            // Send last batch of dirty pages
-            Self::vm_maybe_send_dirty_pages(vm, &mut socket)?;
+    // Sends the last passes of dirty memory, then everything the destination
+    // needs to resume the VM once it's paused.
+    fn vm_complete_migration<T>(
+        vm: &mut Vm,
+        socket: &mut T,
+        previous_size: u64,
+        auto_converge: Option<MigrationThrottleConfig>,
+        copy_disks: bool,
+    ) -> result::Result<(), MigratableError>
+    where
+        T: Read + Write,
+    {
+        let result = Self::vm_send_dirty_memory(vm, socket, previous_size, auto_converge);
+        // The vCPUs don't need to be throttled anymore, either because
+        // the VM is paused or the migration failed.
+        vm.throttle_vcpus(0)?;
+        result?;
+
+        // Send last batch of dirty pages
+        Self::vm_maybe_send_dirty_pages(vm, socket)?;
+
+        // Now that the VM is paused, the disks are not modified anymore
+        if copy_disks {
+            Self::vm_send_disks(vm, socket)?;
+        }
 
-            // Capture snapshot and send it
-            let vm_snapshot = vm.snapshot()?;
-            let snapshot_data = serde_json::to_vec(&vm_snapshot).unwrap();
-            Request::state(snapshot_data.len() as u64).write_to(&mut socket)?;
-            socket
-                .write_all(&snapshot_data)
-                .map_err(MigratableError::MigrateSocket)?;
-            let res = Response::read_from(&mut socket)?;
-            if res.status() != Status::Ok {
-                warn!("Error during state migration");
-                Request::abandon().write_to(&mut socket)?;
-                Response::read_from(&mut socket).ok();
-                return Err(MigratableError::MigrateSend(anyhow!(
-                    "Error during state migration"
-                )));
+        // Capture snapshot and send it
+        let vm_snapshot = vm.snapshot()?;
+        let snapshot_data = serde_json::to_vec(&vm_snapshot).unwrap();
+        Request::state(snapshot_data.len() as u64).write_to(socket)?;
+        socket
+            .write_all(&snapshot_data)
+            .map_err(MigratableError::MigrateSocket)?;
+        let res = Response::read_from(socket)?;
+        if res.status() != Status::Ok {
+            warn!("Error during state migration");
+            Request::abandon().write_to(socket)?;
+            Response::read_from(socket).ok();
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error during state migration"
+            )));
+        }
+
+        // Complete the migration
+        Request::complete().write_to(socket)?;
+        let res = Response::read_from(socket)?;
+        if res.status() != Status::Ok {
+            warn!("Error completing migration");
+            Request::abandon().write_to(socket)?;
+            Response::read_from(socket).ok();
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error completing migration"
+            )));
+        }
+        info!("Migration complete");
+        Ok(())
+    }
+
+    // Sends the memory dirtied since the previous pass to the standby. The
+    // replication stops if the standby is gone, leaving the VM running.
+    fn vm_replicate(&mut self) {
+        let (vm, replication) = match (self.vm.as_mut(), self.replication.as_mut()) {
+            (Some(vm), Some(replication)) => (vm, replication),
+            _ => return,
+        };
+
+        let result = replication
+            .wait()
+            .map_err(|e| {
+                MigratableError::MigrateSend(anyhow!("Error reading replication timer: {:?}", e))
+            })
+            .and_then(|_| Self::vm_maybe_send_dirty_pages(vm, replication.socket()));
+        match result {
+            Ok(size) => {
+                replication.pass_sent(size);
+                debug!("Replication pass sent {} dirty bytes", size);
             }
+            Err(e) => {
+                error!("Error replicating the memory, stopping: {:?}", e);
+                self.replication = None;
+                self.vm_stop_dirty_log();
+            }
+        }
+    }
 
-            // Complete the migration
-            Request::complete().write_to(&mut socket)?;
-            let res = Response::read_from(&mut socket)?;
-            if res.status() != Status::Ok {
-                warn!("Error completing migration");
-                Request::abandon().write_to(&mut socket)?;
-                Response::read_from(&mut socket).ok();
-                return Err(MigratableError::MigrateSend(anyhow!(
-                    "Error completing migration"
-                )));
+    // The VM keeps running on this host after a failed migration, without
+    // the devices having to log the memory they write to anymore.
+    fn vm_stop_dirty_log(&self) {
+        if let Some(ref vm) = self.vm {
+            if let Err(e) = vm.stop_memory_dirty_log() {
+                warn!("Error stopping the dirty log: {:?}", e);
             }
-            info!("Migration complete");
-            Ok(())
+        }
+    }
+
+    // Completes the migration to the standby, which resumes the VM.
+    fn vm_failover(&mut self) -> result::Result<(), MigratableError> {
+        let mut replication = self
+            .replication
+            .take()
+            .ok_or_else(|| MigratableError::MigrateSend(anyhow!("Replication not started")))?;
+
+        if let Some(ref mut vm) = self.vm {
+            info!("Failing over to the standby");
+            let info = replication.info();
+            let auto_converge = replication.auto_converge();
+            let copy_disks = replication.copy_disks();
+            let socket = replication.failover_socket().map_err(|e| {
+                MigratableError::MigrateSend(anyhow!("Error preparing the failover: {:?}", e))
+            })?;
+            Self::vm_complete_migration(vm, socket, info.last_pass_size, auto_converge, copy_disks)
         } else {
             Err(MigratableError::MigrateSend(anyhow!("VM is not running")))
         }
     }
 
+    // Lets the standby know it won't receive the VM.
+    fn stop_replication(&mut self) {
+        if let Some(mut replication) = self.replication.take() {
+            info!("Abandoning the replication");
+            Request::abandon().write_to(replication.socket()).ok();
+            Response::read_from(replication.socket()).ok();
+            self.vm_stop_dirty_log();
+        }
+    }
+
     fn control_loop(&mut self, api_receiver: Arc<Receiver<ApiRequest>>) -> Result<()> {
         const EPOLL_EVENTS_LEN: usize = 100;
 
@@ -1125,8 +2105,11 @@ impl Vmm {
                                     "Trying to activate pending virtio devices: count = {}",
                                     count
                                 );
-                                vm.activate_virtio_devices()
-                                    .map_err(Error::ActivateVirtioDevices)?;
+                                // A device failing its activation stays
+                                // inactive, without stopping the VMM.
+                                if let Err(e) = vm.activate_virtio_devices() {
+                                    error!("Error activating virtio devices: {:?}", e);
+                                }
                             }
                         }
                         EpollDispatch::Pty => {
@@ -1134,6 +2117,67 @@ impl Vmm {
                                 vm.handle_pty().map_err(Error::Pty)?;
                             }
                         }
+                        EpollDispatch::TcpConsole => {
+                            if let Some(ref vm) = self.vm {
+                                vm.handle_tcp_console().map_err(Error::TcpConsole)?;
+                            }
+                        }
+                        EpollDispatch::OomPolicy => {
+                            // Failing to check the memory usage once isn't a
+                            // reason to stop the VMM, the next check may work.
+                            if let Some(ref mut vm) = self.vm {
+                                if let Err(e) = vm.handle_oom_policy() {
+                                    error!("Error applying the OOM policy: {:?}", e);
+                                }
+                            }
+                        }
+                        EpollDispatch::TripleFault => {
+                            if let Some(ref vm) = self.vm {
+                                // Consume the event.
+                                vm.triple_fault_evt().read().map_err(Error::EventFdRead)?;
+                                let actions =
+                                    vm.get_config().lock().unwrap().triple_fault_actions();
+                                self.vm_triple_fault(actions);
+                            }
+                        }
+                        EpollDispatch::GuestPanic => {
+                            if let Some(ref vm) = self.vm {
+                                // Consume the event.
+                                vm.guest_panic_evt().read().map_err(Error::EventFdRead)?;
+                                let actions = vm
+                                    .get_config()
+                                    .lock()
+                                    .unwrap()
+                                    .on_crash
+                                    .clone()
+                                    .unwrap_or_default();
+                                self.vm_guest_panic(actions);
+                            }
+                        }
+                        EpollDispatch::Replication => self.vm_replicate(),
+                        EpollDispatch::GuestLogReport => {
+                            // Consume the expirations.
+                            self.guest_log_timer.wait().map_err(Error::GuestLogTimer)?;
+                            virtio_devices::report_suppressed_guest_logs();
+                        }
+                        EpollDispatch::VsockCompletion => {
+                            if let Some(ref vm) = self.vm {
+                                // Consume the event.
+                                vm.vsock_completion_evt()
+                                    .read()
+                                    .map_err(Error::EventFdRead)?;
+                                let action = vm
+                                    .get_config()
+                                    .lock()
+                                    .unwrap()
+                                    .vsock
+                                    .as_ref()
+                                    .map(|v| v.completion_action)
+                                    .unwrap_or_default();
+                                self.vm_vsock_completion(action)
+                                    .map_err(Error::VsockCompletion)?;
+                            }
+                        }
                         EpollDispatch::Api => {
                             // Consume the event.
                             self.api_evt.read().map_err(Error::EventFdRead)?;
@@ -1143,13 +2187,16 @@ impl Vmm {
 
                             match api_request {
                                 ApiRequest::VmCreate(config, sender) => {
-                                    // We only store the passed VM config.
-                                    // The VM will be created when being asked to boot it.
-                                    let response = if self.vm_config.is_none() {
+                                    // We only store the passed VM config, once
+                                    // validated. The VM will be created when
+                                    // being asked to boot it.
+                                    let response = if self.vm_config.is_some() {
+                                        Err(ApiError::VmAlreadyCreated)
+                                    } else if let Err(e) = config.lock().unwrap().validate() {
+                                        Err(ApiError::VmCreate(VmError::ConfigValidation(e)))
+                                    } else {
                                         self.vm_config = Some(config);
                                         Ok(ApiResponsePayload::Empty)
-                                    } else {
-                                        Err(ApiError::VmAlreadyCreated)
                                     };
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
@@ -1225,7 +2272,10 @@ impl Vmm {
                                 }
                                 ApiRequest::VmSnapshot(snapshot_data, sender) => {
                                     let response = self
-                                        .vm_snapshot(&snapshot_data.destination_url)
+                                        .vm_snapshot(
+                                            &snapshot_data.destination_url,
+                                            snapshot_data.devices_only,
+                                        )
                                         .map_err(ApiError::VmSnapshot)
                                         .map(|_| ApiResponsePayload::Empty);
 
@@ -1260,6 +2310,13 @@ impl Vmm {
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmAddDimm(add_dimm_data, sender) => {
+                                    let response = self
+                                        .vm_add_dimm(add_dimm_data.size)
+                                        .map_err(ApiError::VmAddDimm)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmResizeZone(resize_zone_data, sender) => {
                                     let response = self
                                         .vm_resize_zone(
@@ -1270,6 +2327,13 @@ impl Vmm {
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmSetMemoryTarget(memory_target_data, sender) => {
+                                    let response = self
+                                        .vm_set_memory_target(memory_target_data.size)
+                                        .map_err(ApiError::VmSetMemoryTarget)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmAddDevice(add_device_data, sender) => {
                                     let response = self
                                         .vm_add_device(add_device_data.as_ref().clone())
@@ -1284,6 +2348,34 @@ impl Vmm {
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmResetDevice(reset_device_data, sender) => {
+                                    let response = self
+                                        .vm_reset_device(reset_device_data.id.clone())
+                                        .map_err(ApiError::VmResetDevice)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmPauseDevice(pause_device_data, sender) => {
+                                    let response = self
+                                        .vm_pause_device(pause_device_data.id.clone())
+                                        .map_err(ApiError::VmPauseDevice)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmResumeDevice(resume_device_data, sender) => {
+                                    let response = self
+                                        .vm_resume_device(resume_device_data.id.clone())
+                                        .map_err(ApiError::VmResumeDevice)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmActivateDevice(activate_device_data, sender) => {
+                                    let response = self
+                                        .vm_activate_device(activate_device_data.id.clone())
+                                        .map_err(ApiError::VmActivateDevice)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmAddDisk(add_disk_data, sender) => {
                                     let response = self
                                         .vm_add_disk(add_disk_data.as_ref().clone())
@@ -1319,6 +2411,12 @@ impl Vmm {
                                         .map(ApiResponsePayload::VmAction);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmAgentRequest(agent_request_data, sender) => {
+                                    self.vm_agent_request(
+                                        agent_request_data.as_ref().clone(),
+                                        sender,
+                                    );
+                                }
                                 ApiRequest::VmCounters(sender) => {
                                     let response = self
                                         .vm_counters()
@@ -1327,6 +2425,141 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmGetRtc(sender) => {
+                                    let response = self
+                                        .vm_get_rtc()
+                                        .map_err(ApiError::VmGetRtc)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetRtc(rtc_data, sender) => {
+                                    let response = self
+                                        .vm_set_rtc(rtc_data.time)
+                                        .map_err(ApiError::VmSetRtc)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetDescriptorInspector(id, inspector, sender) => {
+                                    let response = self
+                                        .vm_set_descriptor_inspector(id, inspector)
+                                        .map_err(ApiError::VmSetDescriptorInspector)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmLaunchMeasurement(sender) => {
+                                    let response = self
+                                        .vm_launch_measurement()
+                                        .map_err(ApiError::VmLaunchMeasurement)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmInputEvent(input_event_data, sender) => {
+                                    let response = self
+                                        .vm_input_event(
+                                            &input_event_data.id,
+                                            &input_event_data.events,
+                                        )
+                                        .map_err(ApiError::VmInputEvent)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetGpio(gpio_data, sender) => {
+                                    let response = self
+                                        .vm_set_gpio(&gpio_data.id, gpio_data.line, gpio_data.value)
+                                        .map_err(ApiError::VmSetGpio)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmGpioLines(gpio_lines_data, sender) => {
+                                    let response = self
+                                        .vm_gpio_lines(&gpio_lines_data.id)
+                                        .map_err(ApiError::VmGpioLines)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmNetCapture(net_capture_data, sender) => {
+                                    let response = self
+                                        .vm_net_capture(
+                                            &net_capture_data.id,
+                                            net_capture_data.path.as_deref(),
+                                        )
+                                        .map_err(ApiError::VmNetCapture)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetBattery(battery_data, sender) => {
+                                    let response = self
+                                        .vm_set_battery(battery_data.ac_online, battery_data.charge)
+                                        .map_err(ApiError::VmSetBattery)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetFlowRules(flow_rules_data, sender) => {
+                                    let response = self
+                                        .vm_set_flow_rules(
+                                            &flow_rules_data.id,
+                                            &flow_rules_data.rules,
+                                        )
+                                        .map_err(ApiError::VmSetFlowRules)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetNetQueues(net_queues_data, sender) => {
+                                    let response = self
+                                        .vm_set_net_queues(
+                                            &net_queues_data.id,
+                                            net_queues_data.num_queues,
+                                        )
+                                        .map_err(ApiError::VmSetNetQueues)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmInjectNmi(inject_nmi_data, sender) => {
+                                    let response = self
+                                        .vm_inject_nmi(inject_nmi_data.id)
+                                        .map_err(ApiError::VmInjectNmi)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                #[cfg(feature = "guest_debug")]
+                                ApiRequest::VmReadGuestMem(read_data, sender) => {
+                                    let response = self
+                                        .vm_read_guest_mem(read_data.gpa, read_data.len)
+                                        .map_err(ApiError::VmReadGuestMem)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                #[cfg(feature = "guest_debug")]
+                                ApiRequest::VmWriteGuestMem(write_data, sender) => {
+                                    let response = self
+                                        .vm_write_guest_mem(write_data.gpa, &write_data.data)
+                                        .map_err(ApiError::VmWriteGuestMem)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+                                ApiRequest::VmVcpuRegisters(registers_data, sender) => {
+                                    let response = self
+                                        .vm_vcpu_registers(registers_data.id)
+                                        .map_err(ApiError::VmVcpuRegisters)
+                                        .map(ApiResponsePayload::VmAction);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmReceiveMigration(receive_migration_data, sender) => {
                                     let response = self
                                         .vm_receive_migration(
@@ -1339,7 +2572,17 @@ impl Vmm {
                                 ApiRequest::VmSendMigration(send_migration_data, sender) => {
                                     let response = self
                                         .vm_send_migration(send_migration_data.as_ref().clone())
-                                        .map_err(ApiError::VmSendMigration)
+                                        .map_err(|e| {
+                                            self.vm_stop_dirty_log();
+                                            ApiError::VmSendMigration(e)
+                                        })
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmFailover(sender) => {
+                                    let response = self
+                                        .vm_failover()
+                                        .map_err(ApiError::VmFailover)
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
@@ -1362,6 +2605,14 @@ impl Vmm {
     }
 }
 
+// Time the vCPUs and the device threads are given to get parked before the
+// VM is snapshotted or migrated.
+const QUIESCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Time the guest is given to report its free pages before the memory is
+// migrated, the pages reported later being sent.
+const FREE_PAGE_HINT_TIMEOUT: Duration = Duration::from_secs(1);
+
 const CPU_MANAGER_SNAPSHOT_ID: &str = "cpu-manager";
 const MEMORY_MANAGER_SNAPSHOT_ID: &str = "memory-manager";
 const DEVICE_MANAGER_SNAPSHOT_ID: &str = "device-manager";
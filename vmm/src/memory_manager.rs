@@ -27,6 +27,7 @@ use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::result;
 use std::sync::{Arc, Barrier, Mutex};
+use std::time::Instant;
 use url::Url;
 #[cfg(target_arch = "x86_64")]
 use vm_allocator::GsiApic;
@@ -52,8 +53,6 @@ const DEFAULT_MEMORY_ZONE: &str = "mem0";
 #[cfg(target_arch = "x86_64")]
 const X86_64_IRQ_BASE: u32 = 5;
 
-const HOTPLUG_COUNT: usize = 8;
-
 // Memory policy constants
 const MPOL_BIND: u32 = 2;
 const MPOL_MF_STRICT: u32 = 1;
@@ -107,6 +106,14 @@ impl MemoryZone {
 
 pub type MemoryZones = HashMap<String, MemoryZone>;
 
+/// Guest address range reserved for the hotpluggable memory, only backed by
+/// host memory once plugged.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct MemoryHotplugRegion {
+    pub base: u64,
+    pub size: u64,
+}
+
 struct GuestRamMapping {
     slot: u32,
     gpa: u64,
@@ -119,6 +126,7 @@ pub struct MemoryManager {
     next_memory_slot: u32,
     start_of_device_area: GuestAddress,
     end_of_device_area: GuestAddress,
+    hotplug_region: Option<MemoryHotplugRegion>,
     pub vm: Arc<dyn hypervisor::Vm>,
     hotplug_slots: Vec<HotPlugState>,
     selected_slot: usize,
@@ -132,6 +140,9 @@ pub struct MemoryManager {
     shared: bool,
     hugepages: bool,
     hugepage_size: Option<u64>,
+    locked: bool,
+    // Guest RAM locked into host memory.
+    locked_size: u64,
     #[cfg(target_arch = "x86_64")]
     sgx_epc_region: Option<SgxEpcRegion>,
     user_provided_zones: bool,
@@ -267,6 +278,22 @@ pub enum Error {
 
     /// Failed to allocate MMIO address
     AllocateMMIOAddress,
+
+    /// DIMMs can only be added with the ACPI hotplug method.
+    DimmRequiresAcpiHotplug,
+
+    /// Failed to lock the guest memory.
+    LockMemory(io::Error),
+
+    /// The RLIMIT_MEMLOCK of the VMM, in bytes, is too low to lock the guest
+    /// memory.
+    MemoryLockLimit(u64),
+
+    /// The hotplug region starts before the end of the boot RAM.
+    HotplugRegionOverlapsRam(GuestAddress),
+
+    /// The hotplug region ends past the address space of the devices.
+    HotplugRegionOverlapsDeviceArea(GuestAddress),
 }
 
 const ENABLE_FLAG: usize = 0;
@@ -633,8 +660,30 @@ impl MemoryManager {
             .map(|r| (r.0, r.1))
             .collect();
 
+        // Faulting the memory in beforehand reserves the huge pages, which
+        // aren't locked.
+        let prefault = prefault || config.locked;
+
+        let start = Instant::now();
         let (mem_regions, mut memory_zones) =
             Self::create_memory_regions_from_zones(&ram_regions, &zones, prefault)?;
+        if prefault {
+            info!(
+                "Prefaulted {} MiB of guest memory in {:?}",
+                ram_size >> 20,
+                start.elapsed()
+            );
+        }
+
+        let mut locked_size = 0;
+        if config.locked {
+            for zone in zones.iter() {
+                for region in memory_zones[&zone.id].regions() {
+                    locked_size += Self::lock_ram_region(region, zone.hugepages)?;
+                }
+            }
+            info!("Locked {} MiB of guest memory", locked_size >> 20);
+        }
 
         let guest_memory =
             GuestMemoryMmap::from_arc_regions(mem_regions).map_err(Error::GuestMemory)?;
@@ -650,6 +699,19 @@ impl MemoryManager {
 
         let mut start_of_device_area =
             MemoryManager::start_addr(guest_memory.last_addr(), allow_mem_hotplug)?;
+        // The hotplug region can be moved further away from the boot RAM,
+        // the address space in between being left unused.
+        if let Some(hotplug_base) = config.hotplug_base {
+            if hotplug_base < start_of_device_area.0 {
+                error!(
+                    "The memory hotplug region can't start at 0x{:x}, before 0x{:x}",
+                    hotplug_base, start_of_device_area.0
+                );
+                return Err(Error::HotplugRegionOverlapsRam(GuestAddress(hotplug_base)));
+            }
+            start_of_device_area = GuestAddress(hotplug_base);
+        }
+        let hotplug_start = start_of_device_area;
         let mut virtio_mem_regions: Vec<Arc<GuestRegionMmap>> = Vec::new();
 
         // Update list of memory zones for resize.
@@ -705,10 +767,27 @@ impl MemoryManager {
             }
         }
 
+        if start_of_device_area > end_of_device_area {
+            error!(
+                "The memory hotplug region ends at 0x{:x}, past the end of the \
+                address space at 0x{:x}",
+                start_of_device_area.0, end_of_device_area.0
+            );
+            return Err(Error::HotplugRegionOverlapsDeviceArea(start_of_device_area));
+        }
+        let hotplug_region = if start_of_device_area > hotplug_start {
+            Some(MemoryHotplugRegion {
+                base: hotplug_start.0,
+                size: start_of_device_area.0 - hotplug_start.0,
+            })
+        } else {
+            None
+        };
+
         let guest_memory = GuestMemoryAtomic::new(guest_memory);
 
-        let mut hotplug_slots = Vec::with_capacity(HOTPLUG_COUNT);
-        hotplug_slots.resize_with(HOTPLUG_COUNT, HotPlugState::default);
+        let mut hotplug_slots = Vec::with_capacity(config.hotplug_slots);
+        hotplug_slots.resize_with(config.hotplug_slots, HotPlugState::default);
 
         // Both MMIO and PIO address spaces start at address 0.
         let allocator = Arc::new(Mutex::new(
@@ -747,6 +826,7 @@ impl MemoryManager {
             next_memory_slot: 0,
             start_of_device_area,
             end_of_device_area,
+            hotplug_region,
             vm,
             hotplug_slots,
             selected_slot: 0,
@@ -760,6 +840,8 @@ impl MemoryManager {
             shared: config.shared,
             hugepages: config.hugepages,
             hugepage_size: config.hugepage_size,
+            locked: config.locked,
+            locked_size,
             #[cfg(target_arch = "x86_64")]
             sgx_epc_region: None,
             user_provided_zones,
@@ -886,6 +968,39 @@ impl MemoryManager {
         }
     }
 
+    // Locks the region into host memory, faulting it in. The huge pages are
+    // left as is, as they can't be swapped anyway.
+    fn lock_ram_region(region: &GuestRegionMmap, hugepages: bool) -> Result<u64, Error> {
+        if hugepages {
+            return Ok(0);
+        }
+
+        // Safe because the region is mapped for its whole length.
+        let ret = unsafe {
+            libc::mlock(
+                region.as_ptr() as *const libc::c_void,
+                region.len() as usize,
+            )
+        };
+        if ret != 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOMEM) | Some(libc::EPERM) => {
+                    let mut limit = libc::rlimit {
+                        rlim_cur: 0,
+                        rlim_max: 0,
+                    };
+                    // Safe because the limit is only written to.
+                    unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) };
+                    Err(Error::MemoryLockLimit(limit.rlim_cur))
+                }
+                _ => Err(Error::LockMemory(e)),
+            };
+        }
+
+        Ok(region.len())
+    }
+
     fn memfd_create(name: &ffi::CStr, flags: u32) -> Result<RawFd, io::Error> {
         let res = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags) };
 
@@ -997,15 +1112,20 @@ impl MemoryManager {
             }
         };
 
-        let mut mmap_flags = libc::MAP_NORESERVE
-            | if shared {
-                libc::MAP_SHARED
-            } else {
-                libc::MAP_PRIVATE
-            };
+        let mut mmap_flags = if shared {
+            libc::MAP_SHARED
+        } else {
+            libc::MAP_PRIVATE
+        };
         if prefault {
             mmap_flags |= libc::MAP_POPULATE;
         }
+        // MAP_POPULATE silently stops when running out of huge pages, leaving
+        // the guest to crash on a SIGBUS later on. Reserving the huge pages
+        // makes the mapping itself fail instead.
+        if !(prefault && hugepages) {
+            mmap_flags |= libc::MAP_NORESERVE;
+        }
 
         let region = GuestRegionMmap::new(
             MmapRegion::build(
@@ -1111,6 +1231,10 @@ impl MemoryManager {
             None,
         )?;
 
+        if self.locked {
+            self.locked_size += Self::lock_ram_region(&region, self.hugepages)?;
+        }
+
         // Map it into the guest
         let slot = self.create_userspace_mapping(
             region.start_addr().0,
@@ -1135,30 +1259,52 @@ impl MemoryManager {
         info!("Hotplugging new RAM: {}", size);
 
         // Check that there is a free slot
-        if self.next_hotplug_slot >= HOTPLUG_COUNT {
+        if self.next_hotplug_slot >= self.hotplug_slots.len() {
             return Err(Error::NoSlotAvailable);
         }
 
         // "Inserted" DIMM must have a size that is a multiple of 128MiB
-        if size % (128 << 20) != 0 {
+        if size == 0 || size % (128 << 20) != 0 {
             return Err(Error::InvalidSize);
         }
 
-        let start_addr = MemoryManager::start_addr(self.guest_memory.memory().last_addr(), true)?;
+        // The DIMMs are laid out back to back, the first one at the start of
+        // the hotplug region, so that it isn't fragmented.
+        let hotplug_region = self.hotplug_region.ok_or(Error::InsufficientHotplugRAM)?;
+        let start_addr = match self.next_hotplug_slot.checked_sub(1) {
+            Some(last_slot) => {
+                let slot = &self.hotplug_slots[last_slot];
+                GuestAddress(slot.base + slot.length)
+            }
+            None => GuestAddress(hotplug_region.base),
+        };
 
-        if start_addr.checked_add(size.try_into().unwrap()).unwrap() > self.start_of_device_area() {
+        let end_addr = start_addr
+            .checked_add(size as GuestUsize)
+            .ok_or(Error::InsufficientHotplugRAM)?;
+        if end_addr > GuestAddress(hotplug_region.base + hotplug_region.size) {
             return Err(Error::InsufficientHotplugRAM);
         }
 
-        let region = self.add_ram_region(start_addr, size)?;
-
-        // Tell the allocator
+        // Tell the allocator first, so that nothing is left to undo if the
+        // range is already taken.
         self.allocator
             .lock()
             .unwrap()
             .allocate_mmio_addresses(Some(start_addr), size as GuestUsize, None)
             .ok_or(Error::MemoryRangeAllocation)?;
 
+        let region = match self.add_ram_region(start_addr, size) {
+            Ok(region) => region,
+            Err(e) => {
+                self.allocator
+                    .lock()
+                    .unwrap()
+                    .free_mmio_addresses(start_addr, size as GuestUsize);
+                return Err(e);
+            }
+        };
+
         // Update the slot so that it can be queried via the I/O port
         let mut slot = &mut self.hotplug_slots[self.next_hotplug_slot];
         slot.active = true;
@@ -1350,15 +1496,29 @@ impl MemoryManager {
             }
             HotplugMethod::Acpi => {
                 if desired_ram > self.current_ram {
-                    region =
-                        Some(self.hotplug_ram_region((desired_ram - self.current_ram) as usize)?);
-                    self.current_ram = desired_ram;
+                    region = Some(self.add_dimm(desired_ram - self.current_ram)?);
                 }
             }
         }
         Ok(region)
     }
 
+    /// Adds a DIMM of `size` bytes after the last one, in the next free
+    /// slot. The new region is returned for the caller to notify the guest.
+    pub fn add_dimm(&mut self, size: u64) -> Result<Arc<GuestRegionMmap>, Error> {
+        if self.hotplug_method != HotplugMethod::Acpi {
+            return Err(Error::DimmRequiresAcpiHotplug);
+        }
+        if self.user_provided_zones {
+            return Err(Error::InvalidResizeWithMemoryZones);
+        }
+
+        let region = self.hotplug_ram_region(size as usize)?;
+        self.current_ram += size;
+
+        Ok(region)
+    }
+
     pub fn resize_zone(&mut self, id: &str, virtio_mem_size: u64) -> Result<(), Error> {
         if !self.user_provided_zones {
             error!(
@@ -1474,6 +1634,20 @@ impl MemoryManager {
         &self.memory_zones
     }
 
+    /// Guest address range reserved for the hotpluggable memory, if any.
+    pub fn hotplug_region(&self) -> Option<MemoryHotplugRegion> {
+        self.hotplug_region
+    }
+
+    /// Size of the guest RAM locked into host memory, if it should be.
+    pub fn locked_size(&self) -> Option<u64> {
+        if self.locked {
+            Some(self.locked_size)
+        } else {
+            None
+        }
+    }
+
     // Generate a table for the pages that are dirty. The algorithm is currently
     // very simple. If any page in a "block" of 64 pages is dirty then that whole
     // "block" is added to the table. These "blocks" are also collapsed together if
@@ -1550,6 +1724,14 @@ impl MemoryManager {
         }
         Ok(())
     }
+
+    // Files written by send() into the snapshot directory, relative to it.
+    pub fn snapshot_files(&self) -> Vec<PathBuf> {
+        self.snapshot_memory_regions
+            .iter()
+            .filter_map(|region| region.content.clone())
+            .collect()
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -2007,6 +2189,7 @@ impl Snapshottable for MemoryManager {
         memory_manager_snapshot.add_data_section(SnapshotDataSection {
             id: format!("{}-section", MEMORY_MANAGER_SNAPSHOT_ID),
             snapshot: snapshot_data_section,
+            ..Default::default()
         });
 
         let mut memory_snapshot = self.snapshot.lock().unwrap();
@@ -2080,3 +2263,138 @@ impl Transportable for MemoryManager {
     }
 }
 impl Migratable for MemoryManager {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(size: usize) -> GuestRegionMmap {
+        GuestRegionMmap::new(MmapRegion::new(size).unwrap(), GuestAddress(0)).unwrap()
+    }
+
+    #[test]
+    fn test_lock_ram_region() {
+        // The huge pages are left alone.
+        assert_eq!(
+            MemoryManager::lock_ram_region(&region(0x1000), true).unwrap(),
+            0
+        );
+
+        assert_eq!(
+            MemoryManager::lock_ram_region(&region(0x1000), false).unwrap(),
+            0x1000
+        );
+
+        // Past the limit, which only applies without CAP_IPC_LOCK, the error
+        // carries the limit for it to be raised.
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) },
+            0
+        );
+        if unsafe { libc::geteuid() } != 0 && limit.rlim_cur < 1 << 30 {
+            let size = limit.rlim_cur as usize + 0x1000;
+            assert!(matches!(
+                MemoryManager::lock_ram_region(&region(size), false),
+                Err(Error::MemoryLockLimit(l)) if l == limit.rlim_cur
+            ));
+        }
+    }
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    #[test]
+    fn test_hotplug_base() {
+        let memory_manager = |hotplug_base| {
+            let hv = hypervisor::new().unwrap();
+            let config = MemoryConfig {
+                size: 512 << 20,
+                hotplug_size: Some(512 << 20),
+                hotplug_base: Some(hotplug_base),
+                ..Default::default()
+            };
+            MemoryManager::new(hv.create_vm().unwrap(), &config, false, 46)
+        };
+
+        // The DIMMs are plugged from the start of the region, back to back.
+        let mm = memory_manager(8 << 30).unwrap();
+        let mut mm = mm.lock().unwrap();
+        assert_eq!(
+            mm.hotplug_region(),
+            Some(MemoryHotplugRegion {
+                base: 8 << 30,
+                size: 512 << 20,
+            })
+        );
+        let dimm = mm.hotplug_ram_region(128 << 20).unwrap();
+        assert_eq!(dimm.start_addr(), GuestAddress(8 << 30));
+        let dimm = mm.hotplug_ram_region(384 << 20).unwrap();
+        assert_eq!(dimm.start_addr(), GuestAddress((8 << 30) + (128 << 20)));
+        assert!(matches!(
+            mm.hotplug_ram_region(128 << 20),
+            Err(Error::InsufficientHotplugRAM)
+        ));
+
+        // The boot RAM ends below the 32-bit device area, the region then
+        // starting at 4 GiB at the earliest.
+        assert!(matches!(
+            memory_manager(1 << 30),
+            Err(Error::HotplugRegionOverlapsRam(GuestAddress(a))) if a == 1 << 30
+        ));
+        let base = mmio_address_space_size(46) - (256 << 20);
+        assert!(matches!(
+            memory_manager(base),
+            Err(Error::HotplugRegionOverlapsDeviceArea(_))
+        ));
+    }
+
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    #[test]
+    fn test_hotplug_ram_region() {
+        let memory_manager = |hotplug_slots| {
+            let hv = hypervisor::new().unwrap();
+            let config = MemoryConfig {
+                size: 512 << 20,
+                hotplug_size: Some(1 << 30),
+                hotplug_base: Some(8 << 30),
+                hotplug_slots,
+                ..Default::default()
+            };
+            MemoryManager::new(hv.create_vm().unwrap(), &config, false, 46).unwrap()
+        };
+
+        // Consecutive DIMMs are placed back to back, until there is no slot
+        // left for another one.
+        let mm = memory_manager(2);
+        let mut mm = mm.lock().unwrap();
+        let dimm = mm.hotplug_ram_region(256 << 20).unwrap();
+        assert_eq!(dimm.start_addr(), GuestAddress(8 << 30));
+        let dimm = mm.hotplug_ram_region(128 << 20).unwrap();
+        assert_eq!(dimm.start_addr(), GuestAddress((8 << 30) + (256 << 20)));
+        assert!(matches!(
+            mm.hotplug_ram_region(128 << 20),
+            Err(Error::NoSlotAvailable)
+        ));
+
+        // A size past the end of the region, or of the address space, is
+        // rejected without taking a slot.
+        let mm = memory_manager(2);
+        let mut mm = mm.lock().unwrap();
+        assert!(matches!(
+            mm.hotplug_ram_region(2 << 30),
+            Err(Error::InsufficientHotplugRAM)
+        ));
+        assert!(matches!(
+            mm.hotplug_ram_region(usize::MAX & !((128 << 20) - 1)),
+            Err(Error::InsufficientHotplugRAM)
+        ));
+        assert!(matches!(
+            mm.hotplug_ram_region(100 << 20),
+            Err(Error::InvalidSize)
+        ));
+        let dimm = mm.hotplug_ram_region(1 << 30).unwrap();
+        assert_eq!(dimm.start_addr(), GuestAddress(8 << 30));
+    }
+}
@@ -4,13 +4,228 @@
 
 use crate::vm::{VmSnapshot, VM_SNAPSHOT_ID};
 use anyhow::anyhow;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
 use url::Url;
 use vm_migration::{MigratableError, Snapshot};
 
 pub const VM_SNAPSHOT_FILE: &str = "vm.json";
+pub const SNAPSHOT_MANIFEST_FILE: &str = "manifest.json";
+
+// Version of the layout of a snapshot, to be bumped whenever a snapshot can't
+// be restored by a VMM understanding the previous version.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    // Path relative to the snapshot directory.
+    pub path: PathBuf,
+    pub size: u64,
+    // CRC-32 of the content of the file, missing from the manifests of the
+    // first snapshots.
+    #[serde(default)]
+    pub checksum: Option<u32>,
+}
+
+// Table driven CRC-32, with the polynomial of Ethernet and zlib.
+fn crc32_file(path: &Path) -> io::Result<u32> {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+        *entry = crc;
+    }
+
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; 1 << 16];
+    let mut crc = !0u32;
+    loop {
+        let count = file.read(&mut buf)?;
+        if count == 0 {
+            break;
+        }
+        for byte in buf[..count].iter() {
+            crc = table[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8);
+        }
+    }
+
+    Ok(!crc)
+}
+
+// Describes the content of a snapshot directory: the VM state and config in
+// VM_SNAPSHOT_FILE, along with the files holding the guest memory.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub format_version: u32,
+    pub vmm_version: String,
+    pub files: Vec<SnapshotFile>,
+    // Set for the snapshots only holding the VM config and the state of the
+    // devices, which can't be restored.
+    #[serde(default)]
+    pub devices_only: bool,
+}
+
+// Write the snapshot itself into VM_SNAPSHOT_FILE, in `dir`.
+pub fn write_vm_snapshot(
+    dir: &Path,
+    snapshot: &Snapshot,
+) -> std::result::Result<(), MigratableError> {
+    let vm_snapshot =
+        serde_json::to_vec(snapshot).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(dir.join(VM_SNAPSHOT_FILE))
+        .and_then(|mut f| f.write_all(&vm_snapshot))
+        .map_err(|e| MigratableError::MigrateSend(e.into()))
+}
+
+// Write the manifest of the snapshot stored in `dir`, made of `files`. This
+// must be done last, once VM_SNAPSHOT_FILE is written, as a snapshot with
+// VM_SNAPSHOT_FILE but without manifest is restored without being checked.
+pub fn write_snapshot_manifest(
+    dir: &Path,
+    files: Vec<PathBuf>,
+    devices_only: bool,
+) -> std::result::Result<(), MigratableError> {
+    let mut manifest = SnapshotManifest {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        vmm_version: env!("CARGO_PKG_VERSION").to_string(),
+        files: Vec::new(),
+        devices_only,
+    };
+    for path in files {
+        let size = dir
+            .join(&path)
+            .metadata()
+            .map_err(|e| MigratableError::MigrateSend(anyhow!("Could not stat {:?}: {}", path, e)))?
+            .len();
+        let checksum = crc32_file(&dir.join(&path)).map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Could not read {:?}: {}", path, e))
+        })?;
+        manifest.files.push(SnapshotFile {
+            path,
+            size,
+            checksum: Some(checksum),
+        });
+    }
+
+    let data = serde_json::to_vec(&manifest).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dir.join(SNAPSHOT_MANIFEST_FILE))
+        .and_then(|mut f| f.write_all(&data))
+        .map_err(|e| MigratableError::MigrateSend(e.into()))
+}
+
+// Check the snapshot stored in `dir` can be restored, by reading its manifest
+// and making sure all the files it lists are present and intact. The
+// snapshots taken before manifests were introduced don't have any, and are
+// restored without being checked.
+pub fn check_snapshot_manifest(
+    dir: &Path,
+) -> std::result::Result<Option<SnapshotManifest>, MigratableError> {
+    let manifest_path = dir.join(SNAPSHOT_MANIFEST_FILE);
+    if !manifest_path.exists() && dir.join(VM_SNAPSHOT_FILE).exists() {
+        warn!(
+            "Snapshot {:?} doesn't have a manifest, restoring it without checking it",
+            dir
+        );
+        return Ok(None);
+    }
+    let manifest_file = File::open(&manifest_path).map_err(|e| {
+        MigratableError::MigrateReceive(anyhow!(
+            "Could not open snapshot manifest, {:?} is not a snapshot: {}",
+            dir,
+            e
+        ))
+    })?;
+    let manifest: SnapshotManifest = serde_json::from_reader(BufReader::new(manifest_file))
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+
+    if manifest.format_version != SNAPSHOT_FORMAT_VERSION {
+        return Err(MigratableError::MigrateReceive(anyhow!(
+            "Snapshot format version {} from VMM version {} is not supported, \
+             expected format version {}",
+            manifest.format_version,
+            manifest.vmm_version,
+            SNAPSHOT_FORMAT_VERSION
+        )));
+    }
+
+    for file in manifest.files.iter() {
+        let size = dir
+            .join(&file.path)
+            .metadata()
+            .map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!(
+                    "Snapshot file {:?} is missing: {}",
+                    file.path,
+                    e
+                ))
+            })?
+            .len();
+        if size != file.size {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "Snapshot file {:?} is {} bytes instead of {}",
+                file.path,
+                size,
+                file.size
+            )));
+        }
+
+        if let Some(checksum) = file.checksum {
+            let actual = crc32_file(&dir.join(&file.path)).map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!(
+                    "Could not read snapshot file {:?}: {}",
+                    file.path,
+                    e
+                ))
+            })?;
+            if actual != checksum {
+                return Err(MigratableError::MigrateReceive(anyhow!(
+                    "Snapshot file {:?} is corrupted, its CRC-32 is {:#x} instead of {:#x}",
+                    file.path,
+                    actual,
+                    checksum
+                )));
+            }
+        }
+    }
+
+    Ok(Some(manifest))
+}
+
+/// Writes a snapshot taken by `Vm::snapshot_devices_only()`, which unlike a
+/// full snapshot doesn't come with any memory file.
+pub fn send_devices_only_snapshot(
+    snapshot: &Snapshot,
+    destination_url: &str,
+) -> std::result::Result<(), MigratableError> {
+    let url = Url::parse(destination_url).map_err(|e| {
+        MigratableError::MigrateSend(anyhow!("Could not parse destination URL: {}", e))
+    })?;
+    if url.scheme() != "file" {
+        return Err(MigratableError::MigrateSend(anyhow!(
+            "Unsupported VM transport URL scheme: {}",
+            url.scheme()
+        )));
+    }
+
+    let snapshot_dir = url_to_path(&url)?;
+    write_vm_snapshot(&snapshot_dir, snapshot)?;
+    write_snapshot_manifest(&snapshot_dir, vec![PathBuf::from(VM_SNAPSHOT_FILE)], true)
+}
 
 pub fn url_to_path(url: &Url) -> std::result::Result<PathBuf, MigratableError> {
     match url.scheme() {
@@ -43,6 +258,13 @@ pub fn recv_vm_snapshot(source_url: &str) -> std::result::Result<Snapshot, Migra
     match url.scheme() {
         "file" => {
             let mut vm_snapshot_path = url_to_path(&url)?;
+            if check_snapshot_manifest(&vm_snapshot_path)?.map_or(false, |m| m.devices_only) {
+                return Err(MigratableError::MigrateReceive(anyhow!(
+                    "Snapshot {:?} only holds the state of the devices, without the guest \
+                     memory, and can't be restored",
+                    vm_snapshot_path
+                )));
+            }
             vm_snapshot_path.push(VM_SNAPSHOT_FILE);
 
             // Try opening the snapshot file
@@ -75,3 +297,146 @@ pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, M
         "Could not find VM config snapshot section"
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use vm_migration::SnapshotDataSection;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_snapshot_manifest() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let dir = dir.as_path();
+        fs::write(dir.join(VM_SNAPSHOT_FILE), b"{}").unwrap();
+        fs::write(dir.join("memory-region-0"), vec![0u8; 4096]).unwrap();
+
+        write_snapshot_manifest(
+            dir,
+            vec![
+                PathBuf::from(VM_SNAPSHOT_FILE),
+                PathBuf::from("memory-region-0"),
+            ],
+            false,
+        )
+        .unwrap();
+        let manifest = check_snapshot_manifest(dir).unwrap().unwrap();
+        assert_eq!(manifest.format_version, SNAPSHOT_FORMAT_VERSION);
+        assert!(!manifest.devices_only);
+        assert_eq!(
+            manifest.files,
+            vec![
+                SnapshotFile {
+                    path: PathBuf::from(VM_SNAPSHOT_FILE),
+                    size: 2,
+                    checksum: Some(0xa3a6_bf43),
+                },
+                SnapshotFile {
+                    path: PathBuf::from("memory-region-0"),
+                    size: 4096,
+                    checksum: Some(0xc71c_0011),
+                }
+            ]
+        );
+
+        // An incomplete memory file.
+        fs::write(dir.join("memory-region-0"), vec![0u8; 1024]).unwrap();
+        assert!(check_snapshot_manifest(dir).is_err());
+        // A corrupted memory file, with the right size.
+        fs::write(dir.join("memory-region-0"), vec![1u8; 4096]).unwrap();
+        assert!(check_snapshot_manifest(dir).is_err());
+        fs::write(dir.join("memory-region-0"), vec![0u8; 4096]).unwrap();
+        assert!(check_snapshot_manifest(dir).is_ok());
+
+        // A snapshot from an unknown format version.
+        let manifest = SnapshotManifest {
+            format_version: SNAPSHOT_FORMAT_VERSION + 1,
+            ..manifest
+        };
+        fs::write(
+            dir.join(SNAPSHOT_MANIFEST_FILE),
+            serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+        assert!(check_snapshot_manifest(dir).is_err());
+
+        // A snapshot taken before manifests were introduced.
+        fs::remove_file(dir.join(SNAPSHOT_MANIFEST_FILE)).unwrap();
+        assert!(check_snapshot_manifest(dir).unwrap().is_none());
+
+        // An interrupted snapshot, or a directory which isn't a snapshot.
+        fs::remove_file(dir.join(VM_SNAPSHOT_FILE)).unwrap();
+        assert!(check_snapshot_manifest(dir).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let dir = dir.as_path();
+        let url = format!("file://{}", dir.display());
+
+        let mut snapshot = Snapshot::new(VM_SNAPSHOT_ID);
+        snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", VM_SNAPSHOT_ID),
+            snapshot: vec![1, 2, 3],
+            ..Default::default()
+        });
+        snapshot.add_snapshot(Snapshot::new("memory-manager"));
+        write_vm_snapshot(dir, &snapshot).unwrap();
+        fs::write(dir.join("memory-region-0"), vec![0u8; 4096]).unwrap();
+        write_snapshot_manifest(
+            dir,
+            vec![
+                PathBuf::from(VM_SNAPSHOT_FILE),
+                PathBuf::from("memory-region-0"),
+            ],
+            false,
+        )
+        .unwrap();
+
+        let restored = recv_vm_snapshot(&url).unwrap();
+        assert_eq!(
+            serde_json::to_vec(&restored).unwrap(),
+            serde_json::to_vec(&snapshot).unwrap()
+        );
+
+        // Restoring without manifest, as for a legacy snapshot.
+        fs::remove_file(dir.join(SNAPSHOT_MANIFEST_FILE)).unwrap();
+        let restored = recv_vm_snapshot(&url).unwrap();
+        assert_eq!(restored.id, VM_SNAPSHOT_ID);
+
+        // A devices only snapshot is refused.
+        write_snapshot_manifest(dir, vec![PathBuf::from(VM_SNAPSHOT_FILE)], true).unwrap();
+        assert!(recv_vm_snapshot(&url).is_err());
+    }
+
+    #[test]
+    fn test_devices_only_snapshot() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let url = format!("file://{}", dir.as_path().display());
+
+        let mut snapshot = Snapshot::new(VM_SNAPSHOT_ID);
+        snapshot.add_snapshot(Snapshot::new("device-manager"));
+        send_devices_only_snapshot(&snapshot, &url).unwrap();
+
+        // Only the snapshot itself is written, and recorded as such.
+        let manifest = check_snapshot_manifest(dir.as_path()).unwrap().unwrap();
+        assert!(manifest.devices_only);
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.files[0].path, PathBuf::from(VM_SNAPSHOT_FILE));
+
+        // Restoring from it is refused, even though it's complete.
+        match recv_vm_snapshot(&url) {
+            Err(MigratableError::MigrateReceive(e)) => {
+                assert!(e
+                    .to_string()
+                    .contains("only holds the state of the devices"))
+            }
+            _ => panic!("Devices only snapshot restored"),
+        }
+
+        // Only file URLs are supported.
+        assert!(send_devices_only_snapshot(&snapshot, "tcp://127.0.0.1:1234").is_err());
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::{OomAction, OomPolicyConfig};
+use std::fs;
+use std::io;
+use std::time::Duration;
+use vmm_sys_util::errno;
+use vmm_sys_util::timerfd::TimerFd;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the timer pacing the checks.
+    CreateTimer(errno::Error),
+    /// Cannot arm the timer.
+    ArmTimer(errno::Error),
+    /// Cannot consume the timer expirations.
+    ReadTimer(errno::Error),
+    /// Cannot read the memory usage of the VMM.
+    ReadRss(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// What happened the last time the policy was triggered.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct OomPolicyInfo {
+    /// Number of times the policy was triggered.
+    pub triggers: u64,
+    /// Resident set size of the VMM when last triggered.
+    pub rss: u64,
+    /// Action taken when last triggered.
+    pub action: OomAction,
+}
+
+/// Periodically compares the resident set size of the VMM against a soft
+/// limit, so that the guest memory can be reclaimed or the guest paused
+/// before the host runs out of memory and the OOM killer picks the VMM.
+pub struct OomPolicy {
+    config: OomPolicyConfig,
+    timer: TimerFd,
+    info: Option<OomPolicyInfo>,
+}
+
+impl OomPolicy {
+    pub fn new(config: &OomPolicyConfig) -> Result<Self> {
+        let mut timer = TimerFd::new().map_err(Error::CreateTimer)?;
+        let interval = Duration::from_millis(config.interval);
+        timer
+            .reset(interval, Some(interval))
+            .map_err(Error::ArmTimer)?;
+
+        Ok(OomPolicy {
+            config: config.clone(),
+            timer,
+            info: None,
+        })
+    }
+
+    /// The timer firing every time the memory usage should be checked.
+    pub fn timer(&self) -> &TimerFd {
+        &self.timer
+    }
+
+    pub fn config(&self) -> &OomPolicyConfig {
+        &self.config
+    }
+
+    pub fn info(&self) -> Option<OomPolicyInfo> {
+        self.info
+    }
+
+    /// Consume the timer and check the memory usage, returning the action
+    /// to take if the VMM is above the soft limit.
+    pub fn check(&mut self) -> Result<Option<(u64, OomAction)>> {
+        self.timer.wait().map_err(Error::ReadTimer)?;
+
+        let rss = vmm_rss().map_err(Error::ReadRss)?;
+        if rss <= self.config.rss_limit {
+            return Ok(None);
+        }
+
+        Ok(Some((rss, self.config.action)))
+    }
+
+    /// Keep track of the policy being triggered, as reported by info().
+    pub fn triggered(&mut self, rss: u64, action: OomAction) {
+        let triggers = self.info.map_or(0, |i| i.triggers) + 1;
+        self.info = Some(OomPolicyInfo {
+            triggers,
+            rss,
+            action,
+        });
+    }
+}
+
+// Resident set size of the VMM, in bytes.
+fn vmm_rss() -> io::Result<u64> {
+    parse_rss(&fs::read_to_string("/proc/self/status")?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no VmRSS entry"))
+}
+
+// Find the resident set size in the content of /proc/self/status, described
+// by an entry such as "VmRSS:	  123456 kB".
+fn parse_rss(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))
+        .and_then(|v| v.trim().strip_suffix("kB"))
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(|v| v << 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rss() {
+        let status = "Name:\tcloud-hypervisor\n\
+                      VmHWM:\t  204800 kB\n\
+                      VmRSS:\t  102400 kB\n\
+                      RssAnon:\t   81920 kB\n";
+        assert_eq!(parse_rss(status), Some(100 << 20));
+        assert_eq!(parse_rss("Name:\tcloud-hypervisor\n"), None);
+        assert_eq!(parse_rss("VmRSS:\tgarbage kB\n"), None);
+
+        assert!(vmm_rss().unwrap() > 0);
+    }
+}
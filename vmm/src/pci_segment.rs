@@ -0,0 +1,439 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::device_manager::{AddressManager, DeviceManagerError, DeviceManagerResult};
+#[cfg(feature = "acpi")]
+use acpi_tables::{aml, aml::Aml};
+use arch::layout;
+#[cfg(target_arch = "x86_64")]
+use pci::PciConfigIo;
+use pci::{DeviceRelocation, PciBus, PciConfigMmio, PciRoot};
+use std::sync::{Arc, Mutex};
+use vm_allocator::AddressAllocator;
+use vm_device::BusDevice;
+
+// Number of device slots on the bus of a PCI segment.
+pub(crate) const NUM_PCI_DEVICE_SLOTS: usize = 32;
+
+/// A PCI segment, with its own root bus, PCI MMCONFIG region and memory
+/// windows, exposed to the guest as a separate PCI host bridge.
+pub(crate) struct PciSegment {
+    pub(crate) id: u16,
+    pub(crate) pci_bus: Arc<Mutex<PciBus>>,
+    pub(crate) pci_config_mmio: Arc<Mutex<PciConfigMmio>>,
+    #[cfg_attr(not(feature = "acpi"), allow(dead_code))]
+    pub(crate) mmio_config_address: u64,
+
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) pci_config_io: Option<Arc<Mutex<PciConfigIo>>>,
+
+    // Bitmaps of the device slots hotplugged and unplugged since the guest
+    // last read them.
+    pub(crate) pci_devices_up: u32,
+    pub(crate) pci_devices_down: u32,
+
+    // Legacy interrupt assigned to each device slot.
+    pub(crate) pci_irq_slots: [u32; NUM_PCI_DEVICE_SLOTS],
+
+    // Guest NUMA node the device of each slot is local to, if any.
+    #[cfg_attr(not(feature = "acpi"), allow(dead_code))]
+    pci_numa_nodes: [Option<u32>; NUM_PCI_DEVICE_SLOTS],
+
+    // Windows the 64 bits and 32 bits BARs of the segment devices are
+    // allocated from.
+    pub(crate) mmio_allocator: Arc<Mutex<AddressAllocator>>,
+    pub(crate) mmio_hole_allocator: Arc<Mutex<AddressAllocator>>,
+}
+
+impl PciSegment {
+    pub(crate) fn new(
+        id: u16,
+        address_manager: &Arc<AddressManager>,
+        mmio_allocator: Arc<Mutex<AddressAllocator>>,
+        mmio_hole_allocator: Arc<Mutex<AddressAllocator>>,
+    ) -> DeviceManagerResult<PciSegment> {
+        let pci_root = PciRoot::new(None);
+        let pci_bus = Arc::new(Mutex::new(PciBus::new(
+            pci_root,
+            Arc::clone(address_manager) as Arc<dyn DeviceRelocation>,
+        )));
+
+        let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(Arc::clone(&pci_bus))));
+        let mmio_config_address =
+            layout::PCI_MMCONFIG_START.0 + layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT * id as u64;
+        address_manager
+            .mmio_bus
+            .insert(
+                Arc::clone(&pci_config_mmio) as Arc<Mutex<dyn BusDevice>>,
+                mmio_config_address,
+                layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
+            )
+            .map_err(DeviceManagerError::BusError)?;
+
+        // Only the first segment can be reached through the legacy PCI
+        // configuration I/O ports.
+        #[cfg(target_arch = "x86_64")]
+        let pci_config_io = if id == 0 {
+            let pci_config_io = Arc::new(Mutex::new(PciConfigIo::new(Arc::clone(&pci_bus))));
+            address_manager
+                .io_bus
+                .insert(
+                    Arc::clone(&pci_config_io) as Arc<Mutex<dyn BusDevice>>,
+                    0xcf8,
+                    0x8,
+                )
+                .map_err(DeviceManagerError::BusError)?;
+            Some(pci_config_io)
+        } else {
+            None
+        };
+
+        info!(
+            "Adding PCI segment: id={}, PCI MMIO config address: 0x{:x}, 64-bit window: 0x{:x}-0x{:x}, 32-bit window: 0x{:x}-0x{:x}",
+            id,
+            mmio_config_address,
+            mmio_allocator.lock().unwrap().base().0,
+            mmio_allocator.lock().unwrap().end().0,
+            mmio_hole_allocator.lock().unwrap().base().0,
+            mmio_hole_allocator.lock().unwrap().end().0,
+        );
+
+        Ok(PciSegment {
+            id,
+            pci_bus,
+            pci_config_mmio,
+            mmio_config_address,
+            #[cfg(target_arch = "x86_64")]
+            pci_config_io,
+            pci_devices_up: 0,
+            pci_devices_down: 0,
+            pci_irq_slots: [0; NUM_PCI_DEVICE_SLOTS],
+            pci_numa_nodes: [None; NUM_PCI_DEVICE_SLOTS],
+            mmio_allocator,
+            mmio_hole_allocator,
+        })
+    }
+
+    /// Reserve the next free device slot of the segment, returning the
+    /// matching BDF. The segment is carried by the upper 16 bits.
+    pub(crate) fn next_device_bdf(&self) -> DeviceManagerResult<u32> {
+        // We need to shift the device id since the 3 first bits are
+        // dedicated to the PCI function, and we know we don't do
+        // multifunction. Also, because we only support one PCI bus per
+        // segment, the bus 0, we don't need to add anything else.
+        let device_id = self
+            .pci_bus
+            .lock()
+            .unwrap()
+            .next_device_id()
+            .map_err(DeviceManagerError::NextPciDeviceId)?;
+
+        Ok(pci_bdf(self.id, device_id as u8))
+    }
+
+    /// Report the device identified by the BDF as local to the guest NUMA
+    /// node, through the _PXM of its slot. As the slots are only described
+    /// when the ACPI tables are created, this must happen beforehand.
+    pub(crate) fn set_device_numa_node(&mut self, bdf: u32, numa_node: Option<u32>) {
+        self.pci_numa_nodes[pci_bdf_device(bdf) as usize] = numa_node;
+    }
+
+    /// Give the device slot of the BDF back to the bus, along with the guest
+    /// NUMA node of the device it held, for the next device to be given the
+    /// slot not to be reported local to it.
+    pub(crate) fn put_device_bdf(&mut self, bdf: u32) -> DeviceManagerResult<()> {
+        self.pci_numa_nodes[pci_bdf_device(bdf) as usize] = None;
+        self.pci_bus
+            .lock()
+            .unwrap()
+            .put_device_id(pci_bdf_device(bdf) as usize)
+            .map_err(DeviceManagerError::PutPciDeviceId)
+    }
+}
+
+/// Build the BDF of the device in the given slot, on the bus 0 of the
+/// segment.
+pub(crate) fn pci_bdf(segment_id: u16, device_id: u8) -> u32 {
+    (segment_id as u32) << 16 | (device_id as u32) << 3
+}
+
+/// Segment holding the device identified by the BDF.
+pub(crate) fn pci_bdf_segment(bdf: u32) -> u16 {
+    (bdf >> 16) as u16
+}
+
+/// Device slot of the device identified by the BDF.
+pub(crate) fn pci_bdf_device(bdf: u32) -> u8 {
+    ((bdf >> 3) & 0x1f) as u8
+}
+
+#[cfg(feature = "acpi")]
+struct PciDevSlot {
+    device_id: u8,
+    numa_node: Option<u32>,
+}
+
+#[cfg(feature = "acpi")]
+impl Aml for PciDevSlot {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let sun = self.device_id;
+        let adr: u32 = (self.device_id as u32) << 16;
+        let sun_name = aml::Name::new("_SUN".into(), &sun);
+        let adr_name = aml::Name::new("_ADR".into(), &adr);
+        let sun_path = aml::Path::new("_SUN");
+        let seg_path = aml::Path::new("_SEG");
+        let pcej = aml::MethodCall::new("\\_SB_.PHPR.PCEJ".into(), vec![&sun_path, &seg_path]);
+        let ej0 = aml::Method::new("_EJ0".into(), 1, true, vec![&pcej]);
+        let pxm = self
+            .numa_node
+            .map(|numa_node| aml::Name::new("_PXM".into(), &numa_node));
+
+        let mut slot_data: Vec<&dyn Aml> = vec![&sun_name, &adr_name, &ej0];
+        if let Some(pxm) = &pxm {
+            slot_data.push(pxm);
+        }
+
+        aml::Device::new(format!("S{:03}", self.device_id).as_str().into(), slot_data)
+            .to_aml_bytes()
+    }
+}
+
+#[cfg(feature = "acpi")]
+struct PciDevSlotNotify {
+    device_id: u8,
+}
+
+#[cfg(feature = "acpi")]
+impl Aml for PciDevSlotNotify {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let device_id_mask: u32 = 1 << self.device_id;
+        let object = aml::Path::new(&format!("S{:03}", self.device_id));
+        let mut bytes = aml::And::new(&aml::Local(0), &aml::Arg(0), &device_id_mask).to_aml_bytes();
+        bytes.extend_from_slice(
+            &aml::If::new(
+                &aml::Equal::new(&aml::Local(0), &device_id_mask),
+                vec![&aml::Notify::new(&object, &aml::Arg(1))],
+            )
+            .to_aml_bytes(),
+        );
+        bytes
+    }
+}
+
+#[cfg(feature = "acpi")]
+struct PciDevSlotMethods {
+    segment_id: u16,
+}
+
+#[cfg(feature = "acpi")]
+impl Aml for PciDevSlotMethods {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut device_notifies = Vec::new();
+        for device_id in 0..NUM_PCI_DEVICE_SLOTS as u8 {
+            device_notifies.push(PciDevSlotNotify { device_id });
+        }
+
+        let mut device_notifies_refs: Vec<&dyn aml::Aml> = Vec::new();
+        for device_notify in device_notifies.iter() {
+            device_notifies_refs.push(device_notify);
+        }
+
+        let mut bytes =
+            aml::Method::new("DVNT".into(), 2, true, device_notifies_refs).to_aml_bytes();
+
+        // The hotplug bitmaps are the ones of the segment selected through
+        // PSEG, hence the lock.
+        bytes.extend_from_slice(
+            &aml::Method::new(
+                "PCNT".into(),
+                0,
+                true,
+                vec![
+                    &aml::Acquire::new("\\_SB_.PHPR.BLCK".into(), 0xffff),
+                    &aml::Store::new(&aml::Path::new("\\_SB_.PHPR.PSEG"), &self.segment_id),
+                    &aml::MethodCall::new(
+                        "DVNT".into(),
+                        vec![&aml::Path::new("\\_SB_.PHPR.PCIU"), &aml::ONE],
+                    ),
+                    &aml::MethodCall::new(
+                        "DVNT".into(),
+                        vec![&aml::Path::new("\\_SB_.PHPR.PCID"), &3usize],
+                    ),
+                    &aml::Release::new("\\_SB_.PHPR.BLCK".into()),
+                ],
+            )
+            .to_aml_bytes(),
+        );
+        bytes
+    }
+}
+
+#[cfg(feature = "acpi")]
+impl Aml for PciSegment {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut pci_dsdt_inner_data: Vec<&dyn aml::Aml> = Vec::new();
+        let hid = aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0A08"));
+        pci_dsdt_inner_data.push(&hid);
+        let cid = aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A03"));
+        pci_dsdt_inner_data.push(&cid);
+        let adr = aml::Name::new("_ADR".into(), &aml::ZERO);
+        pci_dsdt_inner_data.push(&adr);
+        let seg = aml::Name::new("_SEG".into(), &self.id);
+        pci_dsdt_inner_data.push(&seg);
+        let uid = aml::Name::new("_UID".into(), &self.id);
+        pci_dsdt_inner_data.push(&uid);
+        let supp = aml::Name::new("SUPP".into(), &aml::ZERO);
+        pci_dsdt_inner_data.push(&supp);
+
+        let (mmio_hole_start, mmio_hole_end) = {
+            let allocator = self.mmio_hole_allocator.lock().unwrap();
+            (allocator.base().0, allocator.end().0)
+        };
+        let (mmio_start, mmio_end) = {
+            let allocator = self.mmio_allocator.lock().unwrap();
+            (allocator.base().0, allocator.end().0)
+        };
+
+        let bus_number = aml::AddressSpace::new_bus_number(0x0u16, 0xffu16);
+        let config_io = aml::IO::new(0xcf8, 0xcf8, 1, 0x8);
+        let mmio_hole = aml::AddressSpace::new_memory(
+            aml::AddressSpaceCachable::NotCacheable,
+            true,
+            mmio_hole_start as u32,
+            mmio_hole_end as u32,
+        );
+        let mmio = aml::AddressSpace::new_memory(
+            aml::AddressSpaceCachable::NotCacheable,
+            true,
+            mmio_start,
+            mmio_end,
+        );
+        let mut crs_data: Vec<&dyn aml::Aml> = vec![&bus_number];
+        // Only the first segment decodes the legacy PCI configuration ports.
+        if self.id == 0 {
+            crs_data.push(&config_io);
+        }
+        crs_data.push(&mmio_hole);
+        crs_data.push(&mmio);
+        let crs = aml::Name::new("_CRS".into(), &aml::ResourceTemplate::new(crs_data));
+        pci_dsdt_inner_data.push(&crs);
+
+        let mut pci_devices = Vec::new();
+        for device_id in 0..NUM_PCI_DEVICE_SLOTS as u8 {
+            let pci_device = PciDevSlot {
+                device_id,
+                numa_node: self.pci_numa_nodes[device_id as usize],
+            };
+            pci_devices.push(pci_device);
+        }
+        for pci_device in pci_devices.iter() {
+            pci_dsdt_inner_data.push(pci_device);
+        }
+
+        let pci_device_methods = PciDevSlotMethods {
+            segment_id: self.id,
+        };
+        pci_dsdt_inner_data.push(&pci_device_methods);
+
+        // Build PCI routing table, listing IRQs assigned to PCI devices.
+        let prt_package_list: Vec<(u32, u32)> = self
+            .pci_irq_slots
+            .iter()
+            .enumerate()
+            .map(|(device_id, irq)| (((device_id as u32) << 16) | 0xffffu32, *irq))
+            .collect();
+        let prt_package_list: Vec<aml::Package> = prt_package_list
+            .iter()
+            .map(|(bdf, irq)| aml::Package::new(vec![bdf, &0u8, &0u8, irq]))
+            .collect();
+        let prt_package_list: Vec<&dyn Aml> = prt_package_list
+            .iter()
+            .map(|item| item as &dyn Aml)
+            .collect();
+        let prt = aml::Name::new("_PRT".into(), &aml::Package::new(prt_package_list));
+        pci_dsdt_inner_data.push(&prt);
+
+        aml::Device::new(
+            format!("_SB_.PCI{:X}", self.id).as_str().into(),
+            pci_dsdt_inner_data,
+        )
+        .to_aml_bytes()
+    }
+}
+
+#[cfg(all(test, feature = "acpi"))]
+mod tests {
+    use super::*;
+    use pci::{PciBarRegionType, PciDevice};
+    use std::io;
+    use vm_memory::GuestAddress;
+
+    struct NoopRelocation {}
+
+    impl DeviceRelocation for NoopRelocation {
+        fn move_bar(
+            &self,
+            _old_base: u64,
+            _new_base: u64,
+            _len: u64,
+            _pci_dev: &mut dyn PciDevice,
+            _region_type: PciBarRegionType,
+        ) -> std::result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    fn pci_segment() -> PciSegment {
+        let pci_bus = Arc::new(Mutex::new(PciBus::new(
+            PciRoot::new(None),
+            Arc::new(NoopRelocation {}),
+        )));
+        let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(Arc::clone(&pci_bus))));
+
+        PciSegment {
+            id: 0,
+            pci_bus,
+            pci_config_mmio,
+            mmio_config_address: layout::PCI_MMCONFIG_START.0,
+            #[cfg(target_arch = "x86_64")]
+            pci_config_io: None,
+            pci_devices_up: 0,
+            pci_devices_down: 0,
+            pci_irq_slots: [0; NUM_PCI_DEVICE_SLOTS],
+            pci_numa_nodes: [None; NUM_PCI_DEVICE_SLOTS],
+            mmio_allocator: Arc::new(Mutex::new(
+                AddressAllocator::new(GuestAddress(0x1_0000_0000), 0x1000_0000).unwrap(),
+            )),
+            mmio_hole_allocator: Arc::new(Mutex::new(
+                AddressAllocator::new(GuestAddress(0xc000_0000), 0x1000_0000).unwrap(),
+            )),
+        }
+    }
+
+    fn pxm_count(aml: &[u8]) -> usize {
+        aml.windows(4).filter(|name| *name == b"_PXM").count()
+    }
+
+    #[test]
+    fn test_device_numa_node() {
+        let mut pci_segment = pci_segment();
+        assert_eq!(pxm_count(&pci_segment.to_aml_bytes()), 0);
+
+        // Only the slot of the device is reported local to the node.
+        let bdf = pci_segment.next_device_bdf().unwrap();
+        pci_segment.set_device_numa_node(bdf, Some(1));
+        assert_eq!(pxm_count(&pci_segment.to_aml_bytes()), 1);
+        let slot = PciDevSlot {
+            device_id: pci_bdf_device(bdf),
+            numa_node: Some(1),
+        };
+        assert_eq!(pxm_count(&slot.to_aml_bytes()), 1);
+
+        // The next device given the slot isn't.
+        pci_segment.put_device_bdf(bdf).unwrap();
+        assert_eq!(pci_segment.next_device_bdf().unwrap(), bdf);
+        assert_eq!(pxm_count(&pci_segment.to_aml_bytes()), 0);
+    }
+}
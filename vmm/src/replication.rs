@@ -0,0 +1,188 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::api::MigrationThrottleConfig;
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+use vmm_sys_util::errno;
+use vmm_sys_util::timerfd::TimerFd;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the timer pacing the passes.
+    CreateTimer(errno::Error),
+    /// Cannot arm the timer.
+    ArmTimer(errno::Error),
+    /// Cannot consume the timer expirations.
+    ReadTimer(errno::Error),
+    /// Cannot set the timeout of the socket to the standby.
+    SetSocketTimeout(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// The passes run on the VMM control loop, which a standby not reading nor
+// replying anymore mustn't block for longer than this.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Progress of the replication to the standby.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ReplicationInfo {
+    /// Number of passes sending the dirty memory since the replication
+    /// started.
+    pub passes: u64,
+    /// Dirty memory sent by the last pass, in bytes.
+    pub last_pass_size: u64,
+    /// Memory sent since the replication started, the initial copy
+    /// included, in bytes.
+    pub total_size: u64,
+}
+
+/// Connection to a standby VMM holding a warm copy of the guest memory, to
+/// which the memory dirtied by the guest is periodically sent until the
+/// failover completes the migration.
+pub struct Replication {
+    socket: UnixStream,
+    timer: TimerFd,
+    copy_disks: bool,
+    auto_converge: Option<MigrationThrottleConfig>,
+    info: ReplicationInfo,
+}
+
+impl Replication {
+    pub fn new(
+        socket: UnixStream,
+        interval: u64,
+        copy_disks: bool,
+        auto_converge: Option<MigrationThrottleConfig>,
+        initial_size: u64,
+    ) -> Result<Self> {
+        set_socket_timeout(&socket, Some(SOCKET_TIMEOUT))?;
+
+        let mut timer = TimerFd::new().map_err(Error::CreateTimer)?;
+        let interval = Duration::from_millis(interval);
+        timer
+            .reset(interval, Some(interval))
+            .map_err(Error::ArmTimer)?;
+
+        Ok(Replication {
+            socket,
+            timer,
+            copy_disks,
+            auto_converge,
+            info: ReplicationInfo {
+                total_size: initial_size,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// The timer firing every time the dirty memory should be sent.
+    pub fn timer(&self) -> &TimerFd {
+        &self.timer
+    }
+
+    /// Consume the timer expirations.
+    pub fn wait(&mut self) -> Result<()> {
+        self.timer.wait().map_err(Error::ReadTimer)?;
+        Ok(())
+    }
+
+    pub fn socket(&mut self) -> &mut UnixStream {
+        &mut self.socket
+    }
+
+    /// The socket to complete the migration with, without a timeout as the
+    /// standby needs to restore the VM before replying.
+    pub fn failover_socket(&mut self) -> Result<&mut UnixStream> {
+        set_socket_timeout(&self.socket, None)?;
+        Ok(&mut self.socket)
+    }
+
+    pub fn copy_disks(&self) -> bool {
+        self.copy_disks
+    }
+
+    pub fn auto_converge(&self) -> Option<MigrationThrottleConfig> {
+        self.auto_converge
+    }
+
+    pub fn info(&self) -> ReplicationInfo {
+        self.info
+    }
+
+    /// Keep track of a pass sending `size` bytes of dirty memory, as
+    /// reported by info().
+    pub fn pass_sent(&mut self, size: u64) {
+        self.info.passes += 1;
+        self.info.last_pass_size = size;
+        self.info.total_size += size;
+    }
+}
+
+fn set_socket_timeout(socket: &UnixStream, timeout: Option<Duration>) -> Result<()> {
+    socket
+        .set_read_timeout(timeout)
+        .map_err(Error::SetSocketTimeout)?;
+    socket
+        .set_write_timeout(timeout)
+        .map_err(Error::SetSocketTimeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use vm_migration::protocol::{Request, Response};
+
+    #[test]
+    fn test_replication_info() {
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        let mut replication = Replication::new(socket, 100, false, None, 4096).unwrap();
+        replication.pass_sent(1024);
+        replication.pass_sent(512);
+
+        assert_eq!(
+            replication.info(),
+            ReplicationInfo {
+                passes: 2,
+                last_pass_size: 512,
+                total_size: 5632,
+            }
+        );
+    }
+
+    #[test]
+    fn test_standby_gone() {
+        let (socket, peer) = UnixStream::pair().unwrap();
+        let mut replication = Replication::new(socket, 100, false, None, 0).unwrap();
+        drop(peer);
+
+        assert!(Request::memory(4096)
+            .write_to(replication.socket())
+            .is_err());
+        assert!(Response::read_from(replication.socket()).is_err());
+    }
+
+    #[test]
+    fn test_standby_unresponsive() {
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        let mut replication = Replication::new(socket, 100, false, None, 0).unwrap();
+
+        // A standby which doesn't reply fails the pass after the timeout.
+        Request::memory(4096)
+            .write_to(replication.socket())
+            .unwrap();
+        let start = Instant::now();
+        assert!(Response::read_from(replication.socket()).is_err());
+        assert!(start.elapsed() >= SOCKET_TIMEOUT);
+
+        // Which doesn't apply to the failover.
+        let socket = replication.failover_socket().unwrap();
+        assert_eq!(socket.read_timeout().unwrap(), None);
+        assert_eq!(socket.write_timeout().unwrap(), None);
+    }
+}
@@ -13,6 +13,7 @@ use std::convert::TryInto;
 
 pub enum Thread {
     Api,
+    CpuThrottle,
     SignalHandler,
     Vcpu,
     Vmm,
@@ -52,13 +53,36 @@ const TIOCGTPEER: u64 = 0x5441;
 const FIOCLEX: u64 = 0x5451;
 const FIONBIO: u64 = 0x5421;
 
+// See include/uapi/linux/fs.h in the kernel code.
+const BLKSSZGET: u64 = 0x1268;
+const BLKGETSIZE64: u64 = 0x8008_1272;
+
+// See include/uapi/linux/vhost.h in the kernel code.
+const VHOST_GET_FEATURES: u64 = 0x8008_af00;
+const VHOST_SET_FEATURES: u64 = 0x4008_af00;
+const VHOST_SET_OWNER: u64 = 0xaf01;
+const VHOST_RESET_OWNER: u64 = 0xaf02;
+const VHOST_SET_MEM_TABLE: u64 = 0x4008_af03;
+const VHOST_SET_LOG_BASE: u64 = 0x4008_af04;
+const VHOST_SET_VRING_NUM: u64 = 0x4008_af10;
+const VHOST_SET_VRING_ADDR: u64 = 0x4028_af11;
+const VHOST_SET_VRING_BASE: u64 = 0x4008_af12;
+const VHOST_GET_VRING_BASE: u64 = 0xc008_af12;
+const VHOST_SET_VRING_KICK: u64 = 0x4008_af20;
+const VHOST_SET_VRING_CALL: u64 = 0x4008_af21;
+const VHOST_NET_SET_BACKEND: u64 = 0x4008_af30;
+
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNGETIFF: u64 = 0x8004_54d2;
 const TUNSETIFF: u64 = 0x4004_54ca;
 const TUNSETOFFLOAD: u64 = 0x4004_54d0;
 const TUNSETVNETHDRSZ: u64 = 0x4004_54d8;
+const TUNSETSTEERINGEBPF: u64 = 0x8004_54e0;
 const TUNGETFEATURES: u64 = 0x8004_54cf;
 
+// See include/uapi/linux/bpf.h in the kernel code.
+const BPF_PROG_LOAD: u64 = 5;
+
 // See include/uapi/linux/sockios.h in the kernel code.
 const SIOCGIFFLAGS: u64 = 0x8913;
 const SIOCGIFHWADDR: u64 = 0x8927;
@@ -119,7 +143,9 @@ pub const SYS_FTRUNCATE: libc::c_long = 46;
 pub const SYS_FTRUNCATE: libc::c_long = 77;
 
 fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
-    Ok(or![
+    let mut rules = or![
+        and![Cond::new(1, ArgLen::DWORD, Eq, BLKSSZGET)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, BLKGETSIZE64)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, FIOCLEX)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, FIONBIO)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_CHECK_EXTENSION,)?],
@@ -164,6 +190,20 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETIFF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETOFFLOAD)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETVNETHDRSZ)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETSTEERINGEBPF)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_GET_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_OWNER)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_RESET_OWNER)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_MEM_TABLE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_LOG_BASE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_NUM)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_ADDR)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_BASE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_GET_VRING_BASE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_KICK)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_CALL)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_NET_SET_BACKEND)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_GET_API_VERSION)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_CHECK_EXTENSION)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_SET_IOMMU)?],
@@ -184,7 +224,15 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_IOMMU_MAP_DMA)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_IOMMU_UNMAP_DMA)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_DEVICE_IOEVENTFD)?],
-    ])
+    ];
+
+    // The evdev devices passed through virtio-input are queried for their
+    // capabilities when the device is created.
+    for request in virtio_devices::evdev_ioctls() {
+        rules.push(and![Cond::new(1, ArgLen::DWORD, Eq, request)?]);
+    }
+
+    Ok(rules)
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -200,6 +248,8 @@ fn create_vmm_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
     const KVM_GET_XCRS: u64 = 0x8188_aea6;
     const KVM_GET_XSAVE: u64 = 0x9000_aea4;
     const KVM_KVMCLOCK_CTRL: u64 = 0xaead;
+    const KVM_MEMORY_ENCRYPT_OP: u64 = 0xc008_aeba;
+    const KVM_MEMORY_ENCRYPT_REG_REGION: u64 = 0x8010_aebb;
     const KVM_SET_CLOCK: u64 = 0x4030_ae7b;
     const KVM_SET_CPUID2: u64 = 0x4008_ae90;
     const KVM_SET_FPU: u64 = 0x41a0_ae8d;
@@ -223,6 +273,13 @@ fn create_vmm_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_XCRS,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_XSAVE,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_KVMCLOCK_CTRL)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_MEMORY_ENCRYPT_OP)?],
+        and![Cond::new(
+            1,
+            ArgLen::DWORD,
+            Eq,
+            KVM_MEMORY_ENCRYPT_REG_REGION
+        )?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_CLOCK)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_CPUID2)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_FPU)?],
@@ -283,6 +340,23 @@ fn signal_handler_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
     ])
 }
 
+fn cpu_throttle_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_clock_gettime),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_getpid),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_tgkill),
+        allow_syscall(libc::SYS_tkill),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
 // The filter containing the white listed syscall rules required by the VMM to
 // function.
 fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
@@ -293,6 +367,10 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         #[cfg(target_arch = "x86_64")]
         allow_syscall(libc::SYS_arch_prctl),
         allow_syscall(libc::SYS_bind),
+        allow_syscall_if(
+            libc::SYS_bpf,
+            or![and![Cond::new(0, ArgLen::DWORD, Eq, BPF_PROG_LOAD)?]],
+        ),
         allow_syscall(libc::SYS_brk),
         allow_syscall(libc::SYS_clock_gettime),
         allow_syscall(libc::SYS_clock_nanosleep),
@@ -324,6 +402,7 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_futex),
         allow_syscall(libc::SYS_getpid),
         allow_syscall(libc::SYS_getrandom),
+        allow_syscall(libc::SYS_getsockname),
         allow_syscall(libc::SYS_gettid),
         allow_syscall(libc::SYS_gettimeofday),
         allow_syscall(libc::SYS_getuid),
@@ -337,6 +416,10 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_madvise),
         allow_syscall(libc::SYS_mbind),
         allow_syscall(libc::SYS_memfd_create),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_mkdir),
+        #[cfg(target_arch = "aarch64")]
+        allow_syscall(libc::SYS_mkdirat),
         allow_syscall(libc::SYS_mmap),
         allow_syscall(libc::SYS_mprotect),
         allow_syscall(libc::SYS_mremap),
@@ -358,6 +441,8 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_recvfrom),
         allow_syscall(libc::SYS_recvmsg),
         allow_syscall(libc::SYS_restart_syscall),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_rmdir),
         allow_syscall(libc::SYS_rt_sigaction),
         allow_syscall(libc::SYS_rt_sigprocmask),
         allow_syscall(libc::SYS_rt_sigreturn),
@@ -366,12 +451,14 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_sendto),
         allow_syscall(libc::SYS_set_robust_list),
         allow_syscall(libc::SYS_set_tid_address),
+        allow_syscall(libc::SYS_setsockopt),
         allow_syscall(libc::SYS_sigaltstack),
         allow_syscall_if(
             libc::SYS_socket,
             or![
                 and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?],
                 and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET as u64)?],
+                and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET6 as u64)?],
             ],
         ),
         allow_syscall(libc::SYS_socketpair),
@@ -405,6 +492,9 @@ fn create_vcpu_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_USER_MEMORY_REGION,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_RUN,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_DEVICE_SET_IRQS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_GET_VRING_BASE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_NET_SET_BACKEND)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_RESET_OWNER)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_GROUP_UNSET_CONTAINER)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_IOMMU_UNMAP_DMA)?],
     ])
@@ -433,11 +523,15 @@ fn vcpu_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_pread64),
         allow_syscall(libc::SYS_pwrite64),
         allow_syscall(libc::SYS_read),
+        // Responses of the vTPM emulator
+        allow_syscall(libc::SYS_recvfrom),
         allow_syscall(libc::SYS_recvmsg),
         allow_syscall(libc::SYS_rt_sigaction),
         allow_syscall(libc::SYS_rt_sigprocmask),
         allow_syscall(libc::SYS_rt_sigreturn),
         allow_syscall(libc::SYS_sendmsg),
+        // Serial port served over TCP
+        allow_syscall(libc::SYS_sendto),
         allow_syscall(libc::SYS_sigaltstack),
         allow_syscall(libc::SYS_tgkill),
         allow_syscall(libc::SYS_tkill),
@@ -481,6 +575,7 @@ fn api_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
 fn get_seccomp_filter_trap(thread_type: Thread) -> Result<SeccompFilter, Error> {
     let rules = match thread_type {
         Thread::Api => api_thread_rules()?,
+        Thread::CpuThrottle => cpu_throttle_thread_rules()?,
         Thread::SignalHandler => signal_handler_thread_rules()?,
         Thread::Vcpu => vcpu_thread_rules()?,
         Thread::Vmm => vmm_thread_rules()?,
@@ -495,6 +590,7 @@ fn get_seccomp_filter_trap(thread_type: Thread) -> Result<SeccompFilter, Error>
 fn get_seccomp_filter_log(thread_type: Thread) -> Result<SeccompFilter, Error> {
     let rules = match thread_type {
         Thread::Api => api_thread_rules()?,
+        Thread::CpuThrottle => cpu_throttle_thread_rules()?,
         Thread::SignalHandler => signal_handler_thread_rules()?,
         Thread::Vcpu => vcpu_thread_rules()?,
         Thread::Vmm => vmm_thread_rules()?,
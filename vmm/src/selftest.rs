@@ -0,0 +1,328 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Validation of the devices of a VM booted with a self-test payload.
+//!
+//! The payload runs the guest agent on the vsock device, and provides the
+//! `ch-selftest` helper exercising each kind of device from the guest:
+//!
+//! - `ch-selftest net <mac> <host_ip>` pings the host through the interface
+//!   with the given MAC address.
+//! - `ch-selftest disk <serial> ro|rw` reads the disk with the given serial,
+//!   and writes back what it read unless the disk is read-only.
+//! - `ch-selftest fs <tag>` mounts the virtio-fs filesystem and lists it.
+//! - `ch-selftest rng` reads from the hardware random number generator.
+//!
+//! A check passes when the helper exits successfully.
+
+use crate::api::{vm_agent_request, ApiError, ApiRequest};
+use crate::config::VmConfig;
+use std::fmt;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use virtio_devices::vsock::agent::{AgentRequest, AgentResponse};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Vsock port of the agent, when the vsock device is added for the
+/// self-test.
+pub const SELFTEST_AGENT_PORT: u32 = 1024;
+
+const SELFTEST_HELPER: &str = "ch-selftest";
+
+// Name the device manager gives the rng device.
+const RNG_DEVICE_NAME: &str = "_rng";
+
+// Time the payload has to boot and start the agent.
+const AGENT_WAIT_TIMEOUT: Duration = Duration::from_secs(120);
+const AGENT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The agent didn't answer before the timeout.
+    AgentUnavailable(ApiError),
+    /// Error sending a check to the agent.
+    AgentRequest(ApiError),
+    /// The agent response can't be parsed.
+    InvalidResponse(serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A device exercised from the guest, with the helper arguments doing so.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+    pub device: String,
+    args: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// The device can't be exercised, for the given reason.
+    Skip(&'static str),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "PASS"),
+            Outcome::Fail(reason) => write!(f, "FAIL ({})", reason),
+            Outcome::Skip(reason) => write!(f, "SKIP ({})", reason),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckResult {
+    pub device: String,
+    pub outcome: Outcome,
+}
+
+fn args(args: &[&str]) -> Option<Vec<String>> {
+    Some(args.iter().map(|a| a.to_string()).collect())
+}
+
+/// Lists the checks of the devices in the configuration, in the order they
+/// are configured.
+pub fn checks(config: &VmConfig) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    // The devices are given their identifiers when the VM is booted, the
+    // fallbacks only being used before.
+    for (i, net) in config.net.iter().flatten().enumerate() {
+        checks.push(Check {
+            device: net.id.clone().unwrap_or_else(|| format!("_net{}", i)),
+            args: args(&["net", &net.mac.to_string(), &net.ip.to_string()]),
+        });
+    }
+
+    for (i, disk) in config.disks.iter().flatten().enumerate() {
+        let device = disk.id.clone().unwrap_or_else(|| format!("_disk{}", i));
+        // The serial of a vhost-user disk comes from its backend.
+        let args = match &disk.path {
+            Some(path) if !disk.vhost_user => {
+                let serial = block_util::build_disk_image_id(path);
+                let len = serial.iter().position(|b| *b == 0).unwrap_or(serial.len());
+                let mode = if disk.readonly { "ro" } else { "rw" };
+                args(&["disk", &String::from_utf8_lossy(&serial[..len]), mode])
+            }
+            _ => None,
+        };
+        checks.push(Check { device, args });
+    }
+
+    for (i, fs) in config.fs.iter().flatten().enumerate() {
+        checks.push(Check {
+            device: fs.id.clone().unwrap_or_else(|| format!("_fs{}", i)),
+            args: args(&["fs", &fs.tag]),
+        });
+    }
+
+    checks.push(Check {
+        device: RNG_DEVICE_NAME.to_string(),
+        args: args(&["rng"]),
+    });
+
+    checks
+}
+
+fn agent_request(
+    api_evt: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+    request: AgentRequest,
+) -> std::result::Result<Result<AgentResponse>, ApiError> {
+    let body = vm_agent_request(
+        api_evt.try_clone().unwrap(),
+        api_sender.clone(),
+        Arc::new(request),
+    )?;
+    let body = body.as_ref().map(|b| b.raw()).unwrap_or_default();
+
+    Ok(serde_json::from_slice(body).map_err(Error::InvalidResponse))
+}
+
+fn wait_for_agent(api_evt: &EventFd, api_sender: &Sender<ApiRequest>) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        match agent_request(api_evt, api_sender, AgentRequest::Ping) {
+            Ok(response) => return response.map(|_| ()),
+            Err(e) if start.elapsed() >= AGENT_WAIT_TIMEOUT => {
+                return Err(Error::AgentUnavailable(e))
+            }
+            Err(e) => debug!("Guest agent not ready yet: {:?}", e),
+        }
+        thread::sleep(AGENT_RETRY_INTERVAL);
+    }
+}
+
+/// Waits for the agent of the booted payload, then runs the checks one
+/// after the other.
+pub fn run(
+    api_evt: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+    checks: Vec<Check>,
+) -> Result<Vec<CheckResult>> {
+    wait_for_agent(api_evt, api_sender)?;
+
+    let mut results = Vec::with_capacity(checks.len());
+    for check in checks {
+        let outcome = match check.args {
+            Some(args) => {
+                info!(
+                    "Self-test of {}: {} {:?}",
+                    check.device, SELFTEST_HELPER, args
+                );
+                let request = AgentRequest::Exec {
+                    command: SELFTEST_HELPER.to_string(),
+                    args,
+                };
+                let response =
+                    agent_request(api_evt, api_sender, request).map_err(Error::AgentRequest)??;
+                match response {
+                    AgentResponse::Exec { status: 0, .. } => Outcome::Pass,
+                    AgentResponse::Exec { status, stderr, .. } => {
+                        let stderr = String::from_utf8_lossy(&stderr).trim().to_string();
+                        if stderr.is_empty() {
+                            Outcome::Fail(format!("exit status {}", status))
+                        } else {
+                            Outcome::Fail(stderr)
+                        }
+                    }
+                    AgentResponse::Error { message } => Outcome::Fail(message),
+                    response => Outcome::Fail(format!("unexpected response {:?}", response)),
+                }
+            }
+            None => Outcome::Skip("unknown guest serial"),
+        };
+        results.push(CheckResult {
+            device: check.device,
+            outcome,
+        });
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ApiResponsePayload;
+    use crate::config::{DiskConfig, NetConfig};
+    use std::sync::mpsc::{channel, Receiver};
+
+    #[test]
+    fn test_selftest_checks() {
+        let mut config: VmConfig = serde_json::from_str(r#"{"kernel": {"path": "/dev/null"}}"#)
+            .expect("Invalid VM configuration");
+        config.net = Some(vec![NetConfig {
+            id: Some("net_test".to_string()),
+            ..NetConfig::parse("mac=12:34:56:78:90:ab,ip=192.168.1.1").unwrap()
+        }]);
+        config.disks = Some(vec![
+            DiskConfig::parse("path=/dev/null,readonly=on").unwrap(),
+            DiskConfig::parse("vhost_user=true,socket=/tmp/sock").unwrap(),
+        ]);
+
+        let checks = checks(&config);
+        let devices: Vec<&str> = checks.iter().map(|c| c.device.as_str()).collect();
+        assert_eq!(devices, vec!["net_test", "_disk0", "_disk1", "_rng"]);
+        assert_eq!(
+            checks[0].args,
+            args(&["net", "12:34:56:78:90:ab", "192.168.1.1"])
+        );
+        assert_eq!(checks[1].args.as_ref().unwrap()[2], "ro");
+        assert_eq!(checks[2].args, None);
+        assert_eq!(checks[3].args, args(&["rng"]));
+    }
+
+    // Plays the VMM thread, answering the agent requests as the agent of
+    // the payload would, until the sender is dropped.
+    fn fake_vmm(api_receiver: Receiver<ApiRequest>) -> thread::JoinHandle<Vec<AgentRequest>> {
+        thread::spawn(move || {
+            let mut requests = Vec::new();
+            while let Ok(request) = api_receiver.recv() {
+                let (request, sender) = match request {
+                    ApiRequest::VmAgentRequest(request, sender) => (request, sender),
+                    _ => panic!("Unexpected API request"),
+                };
+                let response = match request.as_ref() {
+                    AgentRequest::Ping => AgentResponse::Pong,
+                    AgentRequest::Exec { args, .. } if args[0] == "rng" => AgentResponse::Exec {
+                        status: 0,
+                        stdout: Vec::new(),
+                        stderr: Vec::new(),
+                    },
+                    AgentRequest::Exec { .. } => AgentResponse::Exec {
+                        status: 1,
+                        stdout: Vec::new(),
+                        stderr: b"No disk with the serial\n".to_vec(),
+                    },
+                    _ => AgentResponse::Error {
+                        message: "Unsupported".to_string(),
+                    },
+                };
+                requests.push(request.as_ref().clone());
+                sender
+                    .send(Ok(ApiResponsePayload::VmAction(
+                        serde_json::to_vec(&response).unwrap(),
+                    )))
+                    .unwrap();
+            }
+            requests
+        })
+    }
+
+    #[test]
+    fn test_selftest_run() {
+        let (api_sender, api_receiver) = channel();
+        let api_evt = EventFd::new(0).unwrap();
+        let vmm = fake_vmm(api_receiver);
+
+        let mut config: VmConfig = serde_json::from_str(r#"{"kernel": {"path": "/dev/null"}}"#)
+            .expect("Invalid VM configuration");
+        config.disks = Some(vec![
+            DiskConfig {
+                id: Some("_disk0".to_string()),
+                ..DiskConfig::parse("path=/dev/null").unwrap()
+            },
+            DiskConfig {
+                id: Some("_disk1".to_string()),
+                ..DiskConfig::parse("vhost_user=true,socket=/tmp/sock").unwrap()
+            },
+        ]);
+
+        let results = run(&api_evt, &api_sender, checks(&config)).unwrap();
+        drop(api_sender);
+        assert_eq!(
+            results,
+            vec![
+                CheckResult {
+                    device: "_disk0".to_string(),
+                    outcome: Outcome::Fail("No disk with the serial".to_string()),
+                },
+                CheckResult {
+                    device: "_disk1".to_string(),
+                    outcome: Outcome::Skip("unknown guest serial"),
+                },
+                CheckResult {
+                    device: "_rng".to_string(),
+                    outcome: Outcome::Pass,
+                },
+            ]
+        );
+
+        // The agent is pinged before the checks, the skipped ones not being
+        // sent.
+        let requests = vmm.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0], AgentRequest::Ping);
+        assert!(
+            matches!(&requests[1], AgentRequest::Exec { command, .. } if command == SELFTEST_HELPER)
+        );
+    }
+}
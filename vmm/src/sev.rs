@@ -0,0 +1,238 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Launch of a guest whose memory is encrypted with AMD SEV.
+//!
+//! The VM is initialized as a SEV guest before any vCPU is created, its
+//! memory is registered as encrypted, and once the boot payload is loaded,
+//! the firmware encrypts it in place and measures it before the guest runs.
+
+use crate::config::SevConfig;
+use crate::vm::LaunchMeasurement;
+use hypervisor::HypervisorVmError;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+const SEV_DEVICE: &str = "/dev/sev";
+const KVM_AMD_SEV_PARAMETER: &str = "/sys/module/kvm_amd/parameters/sev";
+
+// CPUID leaf describing the AMD memory encryption capabilities.
+const CPUID_AMD_MEMORY_ENCRYPTION: u32 = 0x8000_001f;
+const CPUID_SEV_SUPPORTED: u32 = 1 << 1;
+const CPUID_CBIT_POSITION_MASK: u32 = 0x3f;
+
+// Length of the launch measurement, followed by its nonce.
+const MEASUREMENT_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The host CPU doesn't support SEV.
+    CpuUnsupported,
+    /// SEV isn't enabled in the KVM module.
+    KvmDisabled,
+    /// Cannot open the SEV firmware device.
+    OpenDevice(io::Error),
+    /// Cannot read a guest owner's file.
+    ReadFile(PathBuf, io::Error),
+    /// Cannot initialize the VM as a SEV guest.
+    Init(HypervisorVmError),
+    /// Cannot start the launch.
+    LaunchStart(HypervisorVmError),
+    /// Cannot register the guest memory as encrypted.
+    RegisterMemory(HypervisorVmError),
+    /// The range to encrypt isn't in guest memory.
+    InvalidRange(GuestAddress, usize),
+    /// Cannot encrypt the boot payload.
+    LaunchUpdateData(HypervisorVmError),
+    /// Cannot measure the boot payload.
+    LaunchMeasure(HypervisorVmError),
+    /// Cannot complete the launch.
+    LaunchFinish(HypervisorVmError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Checks the host can run SEV guests, returning the position of the
+// encryption bit in the guest page table entries.
+fn host_cbit_position() -> Result<u32> {
+    // Safe because the CPUID leaves are only read.
+    let max_leaf = unsafe { std::arch::x86_64::__cpuid(0x8000_0000) }.eax;
+    if max_leaf < CPUID_AMD_MEMORY_ENCRYPTION {
+        return Err(Error::CpuUnsupported);
+    }
+    let leaf = unsafe { std::arch::x86_64::__cpuid(CPUID_AMD_MEMORY_ENCRYPTION) };
+    if leaf.eax & CPUID_SEV_SUPPORTED == 0 {
+        return Err(Error::CpuUnsupported);
+    }
+
+    match fs::read_to_string(KVM_AMD_SEV_PARAMETER)
+        .as_deref()
+        .map(str::trim)
+    {
+        Ok("Y") | Ok("1") => Ok(leaf.ebx & CPUID_CBIT_POSITION_MASK),
+        _ => Err(Error::KvmDisabled),
+    }
+}
+
+// The address the guest memory range is mapped at in the VMM, the range
+// having to be within a single region.
+fn host_address(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) -> Result<u64> {
+    let last = (len as u64)
+        .checked_sub(1)
+        .and_then(|offset| addr.checked_add(offset))
+        .ok_or(Error::InvalidRange(addr, len))?;
+    let region = mem
+        .find_region(addr)
+        .filter(|r| r.to_region_addr(last).is_some())
+        .ok_or(Error::InvalidRange(addr, len))?;
+
+    Ok(region.as_ptr() as u64 + addr.unchecked_offset_from(region.start_addr()))
+}
+
+fn read_file(path: &Option<PathBuf>) -> Result<Vec<u8>> {
+    match path {
+        Some(path) => fs::read(path).map_err(|e| Error::ReadFile(path.clone(), e)),
+        None => Ok(Vec::new()),
+    }
+}
+
+pub struct SevGuest {
+    vm: Arc<dyn hypervisor::Vm>,
+    sev: File,
+    cbit_position: u32,
+    measurement: Option<LaunchMeasurement>,
+}
+
+impl SevGuest {
+    /// Initializes the VM as a SEV guest and starts its launch, which must
+    /// be done before the vCPUs are created.
+    pub fn new(vm: Arc<dyn hypervisor::Vm>, config: &SevConfig) -> Result<Self> {
+        let cbit_position = host_cbit_position()?;
+        let dh_cert = read_file(&config.dh_cert)?;
+        let session = read_file(&config.session)?;
+
+        let sev = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(SEV_DEVICE)
+            .map_err(Error::OpenDevice)?;
+
+        vm.sev_init(sev.as_raw_fd()).map_err(Error::Init)?;
+        vm.sev_launch_start(sev.as_raw_fd(), config.policy, &dh_cert, &session)
+            .map_err(Error::LaunchStart)?;
+
+        Ok(SevGuest {
+            vm,
+            sev,
+            cbit_position,
+            measurement: None,
+        })
+    }
+
+    /// The bit set in the guest page table entries for the memory they map
+    /// to be accessed encrypted.
+    pub fn encryption_mask(&self) -> u64 {
+        1 << self.cbit_position
+    }
+
+    /// Registers the guest memory as encrypted.
+    pub fn register_memory(&self, mem: &GuestMemoryMmap) -> Result<()> {
+        for region in mem.iter() {
+            self.vm
+                .sev_register_region(region.as_ptr() as u64, region.len() as u64)
+                .map_err(Error::RegisterMemory)?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts the boot payload in place, measures it and completes the
+    /// launch.
+    pub fn launch(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        ranges: &[(GuestAddress, usize)],
+    ) -> Result<()> {
+        for (addr, len) in ranges {
+            let host_addr = host_address(mem, *addr, *len)?;
+            self.vm
+                .sev_launch_update_data(self.sev.as_raw_fd(), host_addr, *len as u64)
+                .map_err(Error::LaunchUpdateData)?;
+        }
+
+        let measurement = self
+            .vm
+            .sev_launch_measure(self.sev.as_raw_fd())
+            .map_err(Error::LaunchMeasure)?;
+        let (measurement, mnonce) = measurement.split_at(MEASUREMENT_LEN);
+        self.measurement = Some(LaunchMeasurement {
+            measurement: hex(measurement),
+            mnonce: hex(mnonce),
+        });
+
+        self.vm
+            .sev_launch_finish(self.sev.as_raw_fd())
+            .map_err(Error::LaunchFinish)
+    }
+
+    /// The launch measurement, once the guest is launched.
+    pub fn measurement(&self) -> Option<&LaunchMeasurement> {
+        self.measurement.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex(&[]), "");
+        assert_eq!(hex(&[0x00, 0x0f, 0xa5, 0xff]), "000fa5ff");
+    }
+
+    #[test]
+    fn test_host_address() {
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x20000), 0x10000),
+        ])
+        .unwrap();
+        let base = mem.find_region(GuestAddress(0x20000)).unwrap().as_ptr() as u64;
+
+        assert_eq!(
+            host_address(&mem, GuestAddress(0x21000), 0x1000).unwrap(),
+            base + 0x1000
+        );
+        assert_eq!(
+            host_address(&mem, GuestAddress(0x20000), 0x10000).unwrap(),
+            base
+        );
+
+        // Ranges must be non-empty, within guest memory and a single region.
+        for (addr, len) in [
+            (0x1000, 0),
+            (0x10000, 0x1000),
+            (0x8000, 0x10000),
+            (0x2f000, 0x2000),
+            (u64::MAX, 2),
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                host_address(&mem, GuestAddress(*addr), *len),
+                Err(Error::InvalidRange(a, l)) if a == GuestAddress(*addr) && l == *len
+            ));
+        }
+    }
+}
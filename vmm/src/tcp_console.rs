@@ -0,0 +1,330 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+const LISTENER_EVENT: u64 = 0;
+const CLIENT_EVENT: u64 = 1;
+
+/// A console served over TCP, to which one client at a time can connect to
+/// interact with the guest. The output of the guest is dropped while no
+/// client is connected.
+///
+/// The listener and the client are polled through an epoll file of their
+/// own, so that the VMM only has to wait for a single file descriptor no
+/// matter the clients coming and going.
+///
+/// There is no authentication, anyone able to reach the address gets to
+/// interact with the guest.
+pub struct TcpConsole {
+    listener: TcpListener,
+    client: Arc<Mutex<Option<TcpStream>>>,
+    epoll_file: File,
+}
+
+impl TcpConsole {
+    pub fn new(addr: SocketAddr) -> io::Result<Self> {
+        let listener = bind(addr)?;
+        warn!(
+            "Console served on {} without authentication, anyone reaching it can interact with the guest",
+            listener.local_addr()?
+        );
+
+        let epoll_fd = epoll::create(true)?;
+        // Use 'File' to enforce closing on 'epoll_fd'
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        epoll::ctl(
+            epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            listener.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, LISTENER_EVENT),
+        )?;
+
+        Ok(TcpConsole {
+            listener,
+            client: Arc::new(Mutex::new(None)),
+            epoll_file,
+        })
+    }
+
+    /// The address the console is served on, telling the port chosen by the
+    /// system when the configured one is 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// A writer sending the output of the guest to the connected client.
+    pub fn writer(&self) -> TcpConsoleWriter {
+        TcpConsoleWriter {
+            client: self.client.clone(),
+        }
+    }
+
+    /// Handles the connection and disconnection of the clients, returning the
+    /// number of input bytes read into `out`.
+    pub fn process(&self, out: &mut [u8]) -> io::Result<usize> {
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 2];
+        let num_events = epoll::wait(self.epoll_file.as_raw_fd(), 0, &mut events[..])?;
+
+        let mut count = 0;
+        for event in events.iter().take(num_events) {
+            match event.data {
+                CLIENT_EVENT => count = self.read(out)?,
+                LISTENER_EVENT => self.accept()?,
+                _ => {}
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn accept(&self) -> io::Result<()> {
+        let (stream, addr) = match self.listener.accept() {
+            Ok(connection) => connection,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut client = self.client.lock().unwrap();
+        if client.is_some() {
+            warn!(
+                "Refusing console connection from {}, a client is connected already",
+                addr
+            );
+            return Ok(());
+        }
+
+        // A client not keeping up with the output must not stall the guest.
+        stream.set_nonblocking(true)?;
+        epoll::ctl(
+            self.epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            stream.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, CLIENT_EVENT),
+        )?;
+        info!("Console client connected from {}", addr);
+        *client = Some(stream);
+
+        Ok(())
+    }
+
+    fn read(&self, out: &mut [u8]) -> io::Result<usize> {
+        let mut client = self.client.lock().unwrap();
+        let stream = match client.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(0),
+        };
+
+        match stream.read(out) {
+            Ok(count) if count > 0 => return Ok(count),
+            Ok(_) => info!("Console client disconnected"),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(0),
+            Err(e) => warn!("Console client connection failed: {}", e),
+        }
+
+        epoll::ctl(
+            self.epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_DEL,
+            stream.as_raw_fd(),
+            epoll::Event::new(epoll::Events::empty(), 0),
+        )?;
+        *client = None;
+
+        Ok(0)
+    }
+}
+
+// Binds the listener with SO_REUSEPORT, for the VM migrated to the same host
+// to serve its console on the same address while the source still does.
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // Safe because the return value is checked.
+    let fd = unsafe {
+        libc::socket(
+            domain,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the socket was just created, the listener closing it on
+    // failure as well.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    let enable: libc::c_int = 1;
+    for option in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        // Safe because the option value outlives the call.
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                *option,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // Safe because the addresses outlive the calls, which are given their
+    // actual size.
+    let ret = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because the return value is checked.
+    if unsafe { libc::listen(fd, libc::SOMAXCONN) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+impl AsRawFd for TcpConsole {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll_file.as_raw_fd()
+    }
+}
+
+pub struct TcpConsoleWriter {
+    client: Arc<Mutex<Option<TcpStream>>>,
+}
+
+impl Write for TcpConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(stream) = self.client.lock().unwrap().as_mut() {
+            // Whatever the client can't take right away is dropped, and the
+            // failure of the connection is left for the reading side to
+            // handle.
+            if let Err(e) = stream.write_all(buf) {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    debug!("Failed writing to the console client: {}", e);
+                }
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(console: &TcpConsole, out: &mut [u8]) -> usize {
+        // Wait for the loopback connection to report its events.
+        let mut pollfd = libc::pollfd {
+            fd: console.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because pollfd outlives the call.
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 5000) }, 1);
+        console.process(out).unwrap()
+    }
+
+    #[test]
+    fn test_tcp_console() {
+        let console = TcpConsole::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = console.local_addr().unwrap();
+        let mut writer = console.writer();
+        let mut out = [0u8; 64];
+
+        // output is dropped while no client is connected
+        assert_eq!(writer.write(b"lost").unwrap(), 4);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert_eq!(process(&console, &mut out), 0);
+        // a second client is refused while the first one is connected
+        let mut refused = TcpStream::connect(addr).unwrap();
+        assert_eq!(process(&console, &mut out), 0);
+        assert_eq!(refused.read(&mut out).unwrap(), 0);
+
+        writer.write_all(b"login: ").unwrap();
+        let mut output = [0u8; 7];
+        client.read_exact(&mut output).unwrap();
+        assert_eq!(&output, b"login: ");
+
+        client.write_all(b"root\r").unwrap();
+        assert_eq!(process(&console, &mut out), 5);
+        assert_eq!(&out[..5], b"root\r");
+
+        // a new client can connect once the previous one is gone
+        drop(client);
+        assert_eq!(process(&console, &mut out), 0);
+        let _client = TcpStream::connect(addr).unwrap();
+        assert_eq!(process(&console, &mut out), 0);
+        assert!(console.client.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_tcp_console_same_address() {
+        // The VM migrated to the same host serves its console while the
+        // source one still does.
+        let source = TcpConsole::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = source.local_addr().unwrap();
+        let destination = TcpConsole::new(addr).unwrap();
+        drop(source);
+
+        let mut out = [0u8; 64];
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert_eq!(process(&destination, &mut out), 0);
+        client.write_all(b"root\r").unwrap();
+        assert_eq!(process(&destination, &mut out), 5);
+    }
+}
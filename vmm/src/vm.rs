@@ -22,21 +22,36 @@ extern crate signal_hook;
 extern crate vm_allocator;
 extern crate vm_memory;
 
+use crate::cgroup::{self, Cgroup};
+use crate::cmdline::{self, BootEnvironment, CmdlineBuilder};
 #[cfg(feature = "acpi")]
 use crate::config::NumaConfig;
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig, ValidationError,
-    VmConfig, VsockConfig,
+    CrashAction, DeviceConfig, DiskConfig, FsConfig, HotplugMethod, MemoryConfig, NetConfig,
+    OomAction, PmemConfig, ValidationError, VmConfig, VsockConfig,
 };
 use crate::cpu;
-use crate::device_manager::{self, get_win_size, Console, DeviceManager, DeviceManagerError};
+use crate::crash_dump;
+use crate::device_manager::{
+    self, get_win_size, Console, DeviceManager, DeviceManagerError, DiskIoPriorityInfo,
+    VhostUserBackends,
+};
 use crate::device_tree::DeviceTree;
-use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
-use crate::migration::{get_vm_snapshot, url_to_path, VM_SNAPSHOT_FILE};
+use crate::event_monitor;
+use crate::memory_manager::{Error as MemoryManagerError, MemoryHotplugRegion, MemoryManager};
+use crate::migration::{
+    get_vm_snapshot, url_to_path, write_snapshot_manifest, write_vm_snapshot, VM_SNAPSHOT_FILE,
+};
+use crate::oom_policy::{self, OomPolicy, OomPolicyInfo};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+use crate::sev::{self, SevGuest};
+use crate::tcp_console::TcpConsole;
 use crate::{
     PciDeviceInfo, CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID, MEMORY_MANAGER_SNAPSHOT_ID,
 };
+#[cfg(feature = "acpi")]
+use acpi_tables::sdt::SDT;
 use anyhow::anyhow;
 use arch::get_host_cpu_phys_bits;
 #[cfg(target_arch = "x86_64")]
@@ -44,7 +59,6 @@ use arch::BootProtocol;
 use arch::EntryPoint;
 use devices::AcpiNotificationFlags;
 use hypervisor::vm::{HypervisorVmError, VmmOps};
-use linux_loader::cmdline::Cmdline;
 #[cfg(target_arch = "x86_64")]
 use linux_loader::loader::elf::Error::InvalidElfMagicNumber;
 #[cfg(target_arch = "x86_64")]
@@ -63,25 +77,32 @@ use std::ffi::CString;
 #[cfg(target_arch = "x86_64")]
 use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, Read, Write};
 use std::io::{Seek, SeekFrom};
 use std::num::Wrapping;
 use std::ops::Deref;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use std::{result, str, thread};
 use url::Url;
+use virtio_devices::vsock::agent::{self, AgentRequest, AgentResponse};
 use vm_device::Bus;
 use vm_memory::{
     Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
     GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
 };
 use vm_migration::{
-    protocol::{MemoryRange, MemoryRangeTable},
+    protocol::{DiskHeader, MemoryRange, MemoryRangeTable},
     Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshottable,
     Transportable,
 };
-use vmm_sys_util::eventfd::EventFd;
+use vm_virtio::DescriptorInspector;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 use vmm_sys_util::terminal::Terminal;
+use vmm_sys_util::timerfd::TimerFd;
 
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::gic::gicv3::kvm::{KvmGICv3, GIC_V3_SNAPSHOT_ID};
@@ -107,11 +128,15 @@ pub enum Error {
     /// Cannot load the initramfs in memory
     InitramfsLoad,
 
+    /// The initramfs content provided in memory didn't survive a snapshot
+    /// or a migration
+    InitramfsUnavailable,
+
     /// Cannot load the command line in memory
     LoadCmdLine(linux_loader::loader::Error),
 
-    /// Cannot modify the command line
-    CmdLineInsertStr(linux_loader::cmdline::Error),
+    /// Cannot compose the command line
+    Cmdline(cmdline::Error),
 
     /// Cannot convert command line into CString
     CmdLineCString(std::ffi::NulError),
@@ -133,6 +158,9 @@ pub enum Error {
     /// Write to the pty console failed.
     PtyConsole(io::Error),
 
+    /// Serving the console over TCP failed.
+    TcpConsole(io::Error),
+
     /// Cannot setup terminal in raw mode.
     SetTerminalRaw(vmm_sys_util::errno::Error),
 
@@ -163,6 +191,9 @@ pub enum Error {
     /// VM is not running
     VmNotRunning,
 
+    /// Cannot create EventFd.
+    EventFdCreate(io::Error),
+
     /// Cannot clone EventFd.
     EventFdClone(io::Error),
 
@@ -208,6 +239,9 @@ pub enum Error {
     /// Cannot send VM snapshot
     SnapshotSend(MigratableError),
 
+    /// Cannot dump the guest memory
+    MemoryDump(io::Error),
+
     /// Cannot convert source URL from Path into &str
     RestoreSourceUrlPathToStr,
 
@@ -241,8 +275,69 @@ pub enum Error {
     /// Power button not supported
     PowerButtonNotSupported,
 
+    /// Cannot launch the guest with its memory encrypted
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    Sev(sev::Error),
+
+    /// No launch measurement, the guest memory not being encrypted
+    LaunchMeasurementUnavailable,
+
     /// Error triggering power button
     PowerButton(device_manager::DeviceManagerError),
+
+    /// Battery not supported
+    BatteryNotSupported,
+
+    /// Error setting the battery state
+    SetBattery(device_manager::DeviceManagerError),
+
+    /// No guest agent port configured on the vsock device
+    NoAgentPort,
+
+    /// Cannot connect to the guest agent
+    AgentConnect(io::Error),
+
+    /// Error exchanging messages with the guest agent
+    AgentRequest(virtio_devices::vsock::agent::Error),
+
+    /// Cannot set up the cgroup limiting the VM resources
+    Cgroup(cgroup::Error),
+
+    /// The memory target can't be reached with the memory configuration
+    InvalidMemoryTarget(u64),
+
+    /// Cannot find out how much memory is available on the host
+    HostMemoryInfo(io::Error),
+
+    /// Reaching the memory target needs more memory than available on the host
+    MemoryTargetOvercommit(u64),
+
+    /// The RAM was resized for the memory target but not the balloon, and
+    /// the RAM couldn't be resized back either
+    MemoryTargetHalfApplied(Box<Error>),
+
+    /// Cannot monitor the VMM memory usage for the OOM policy
+    OomPolicy(oom_policy::Error),
+
+    /// Guest memory access larger than allowed in a single request
+    #[cfg(feature = "guest_debug")]
+    GuestMemoryAccessTooLarge(usize),
+
+    /// The guest memory range isn't entirely mapped
+    #[cfg(feature = "guest_debug")]
+    UnmappedGuestMemory(u64, usize),
+
+    /// Cannot access the guest memory
+    #[cfg(feature = "guest_debug")]
+    GuestMemoryAccess(vm_memory::GuestMemoryError),
+
+    /// The vCPU registers can only be read while the VM is paused
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    VmNotPaused,
+
+    /// Cannot load the ACPI tables provided by the user
+    #[cfg(feature = "acpi")]
+    AcpiTable(crate::acpi::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -279,6 +374,119 @@ impl NumaNode {
 
 pub type NumaNodes = BTreeMap<u32, NumaNode>;
 
+// Smallest memory target accepted, as reclaiming more memory through the
+// balloon would most likely get the guest to run out of memory.
+const MIN_MEMORY_TARGET: u64 = 128 << 20;
+
+/// What happened the last time the guest crashed, either triple faulting
+/// or panicking.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct CrashInfo {
+    /// Number of such crashes since the VM was created, across reboots.
+    pub count: u64,
+    /// Actions run on the last one.
+    pub actions: Vec<CrashAction>,
+    /// Number of these actions which completed, the others being skipped
+    /// once one failed.
+    pub completed: usize,
+}
+
+/// Launch measurement of a guest whose memory is encrypted, for the guest
+/// owner to attest it was launched with the expected payload and policy.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct LaunchMeasurement {
+    /// Measurement computed by the firmware, hex encoded.
+    pub measurement: String,
+    /// Nonce the measurement was computed with, hex encoded.
+    pub mnonce: String,
+}
+
+/// Progress of the guest towards the memory target set through the API.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct MemoryTargetInfo {
+    /// Memory the guest should end up with.
+    pub target: u64,
+    /// Memory currently available to the guest.
+    pub actual: u64,
+    /// Hotpluggable memory currently plugged by the guest.
+    pub plugged_size: u64,
+    /// Memory currently reclaimed by the balloon.
+    pub balloon_size: u64,
+}
+
+// RAM size and balloon size reaching a memory target.
+#[derive(Debug, PartialEq)]
+struct MemoryTargetPlan {
+    target: u64,
+    ram: u64,
+    balloon: u64,
+}
+
+/// Maximum number of bytes of guest memory read or written by a single
+/// request of the debug API.
+#[cfg(feature = "guest_debug")]
+pub const MAX_GUEST_MEMORY_ACCESS: usize = 64 << 10;
+
+// Memory is preferably plugged and unplugged through virtio-mem, which gives
+// the memory back to the host by large blocks, leaving the balloon to only
+// cover the remainder, or the whole difference when there is no virtio-mem.
+fn plan_memory_target(
+    target: u64,
+    memory: &MemoryConfig,
+    balloon: bool,
+) -> Result<MemoryTargetPlan> {
+    if memory.zones.is_some() || target < MIN_MEMORY_TARGET {
+        return Err(Error::InvalidMemoryTarget(target));
+    }
+
+    // The balloon works with pages.
+    let target = target >> virtio_devices::VIRTIO_BALLOON_PFN_SHIFT
+        << virtio_devices::VIRTIO_BALLOON_PFN_SHIFT;
+
+    let ram = match (&memory.hotplug_method, memory.hotplug_size) {
+        (HotplugMethod::VirtioMem, Some(hotplug_size)) => {
+            let block_size = virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE;
+            let plugged =
+                (target.saturating_sub(memory.size) + block_size - 1) / block_size * block_size;
+
+            memory.size + cmp::min(plugged, hotplug_size / block_size * block_size)
+        }
+        // Memory hotplugged through ACPI can't be unplugged, which is why it
+        // is left alone rather than growing the VM for good.
+        _ => memory.size + memory.hotplugged_size.unwrap_or(0),
+    };
+
+    if target > ram || (target < ram && !balloon) {
+        return Err(Error::InvalidMemoryTarget(target));
+    }
+
+    Ok(MemoryTargetPlan {
+        target,
+        ram,
+        balloon: ram - target,
+    })
+}
+
+// RAM the guest currently has, counted the way plan_memory_target() does,
+// the balloon being left out.
+fn memory_target_ram(memory: &MemoryConfig, virtio_mem_plugged_size: u64) -> u64 {
+    match (&memory.hotplug_method, memory.hotplug_size) {
+        (HotplugMethod::VirtioMem, Some(_)) => memory.size + virtio_mem_plugged_size,
+        _ => memory.size + memory.hotplugged_size.unwrap_or(0),
+    }
+}
+
+// Memory the host can provide without swapping, according to /proc/meminfo.
+fn host_available_memory() -> io::Result<u64> {
+    std::fs::read_to_string("/proc/meminfo")?
+        .lines()
+        .find_map(|l| l.strip_prefix("MemAvailable:"))
+        .and_then(|v| v.trim().strip_suffix("kB"))
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(|v| v << 10)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no MemAvailable entry"))
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
 pub enum VmState {
     Created,
@@ -463,9 +671,50 @@ pub fn physical_bits(max_phys_bits: Option<u8>) -> u8 {
     cmp::min(host_phys_bits, max_phys_bits.unwrap_or(host_phys_bits))
 }
 
+/// Where to reach the guest agent, along with the identifier of the request
+/// about to be sent.
+pub struct AgentConnection {
+    socket: PathBuf,
+    port: u32,
+    id: u64,
+}
+
+impl AgentConnection {
+    /// Send the request to the agent and wait for its response. This can
+    /// block for a while, so it must not run on the VMM thread.
+    pub fn request(self, request: AgentRequest) -> Result<AgentResponse> {
+        // Don't wait forever if the agent doesn't answer.
+        const AGENT_TIMEOUT: Duration = Duration::from_secs(10);
+        // Longest acknowledgement of the connection, "OK <host_port>\n".
+        const ACK_MAX_LEN: u64 = 32;
+
+        let mut stream = UnixStream::connect(&self.socket).map_err(Error::AgentConnect)?;
+        stream
+            .set_read_timeout(Some(AGENT_TIMEOUT))
+            .and_then(|_| stream.write_all(format!("CONNECT {}\n", self.port).as_bytes()))
+            .map_err(Error::AgentConnect)?;
+
+        // The stream is closed if the guest refused the connection. Nothing
+        // follows the acknowledgement until the request is sent, so nothing
+        // is lost along with the buffer.
+        let mut ack = Vec::new();
+        io::BufReader::new((&stream).take(ACK_MAX_LEN))
+            .read_until(b'\n', &mut ack)
+            .map_err(Error::AgentConnect)?;
+        if !ack.starts_with(b"OK ") || !ack.ends_with(b"\n") {
+            return Err(Error::AgentConnect(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "unexpected vsock connection acknowledgement",
+            )));
+        }
+
+        agent::send_request(&mut stream, self.id, request).map_err(Error::AgentRequest)
+    }
+}
+
 pub struct Vm {
     kernel: File,
-    initramfs: Option<File>,
+    initramfs: Option<Initramfs>,
     threads: Vec<thread::JoinHandle<()>>,
     device_manager: Arc<Mutex<DeviceManager>>,
     config: Arc<Mutex<VmConfig>>,
@@ -481,11 +730,62 @@ pub struct Vm {
     saved_clock: Option<hypervisor::ClockData>,
     #[cfg(feature = "acpi")]
     numa_nodes: NumaNodes,
+    #[cfg(feature = "acpi")]
+    acpi_tables: Vec<SDT>,
     seccomp_action: SeccompAction,
     exit_evt: EventFd,
+    agent_request_id: u64,
+    cgroup: Option<Cgroup>,
+    memory_target: Option<u64>,
+    oom_policy: Option<OomPolicy>,
+    cpu_throttle: Option<cpu::CpuThrottle>,
+    // Written by the vCPUs when the guest triple faults.
+    triple_fault_evt: EventFd,
+    // Written by the vsock device when the guest closes a connection on the
+    // completion port.
+    vsock_completion_evt: EventFd,
+    // Written by the pvpanic device when the guest panics.
+    guest_panic_evt: EventFd,
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    sev: Option<SevGuest>,
+    // Guest memory holding the boot payload, encrypted before the guest
+    // runs when its memory is encrypted.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    sev_launch_ranges: Vec<(GuestAddress, usize)>,
+}
+
+// Where the initramfs is loaded from, either a file or a buffer provided
+// through the config, for instance built on the fly by an orchestrator. The
+// buffer is not available anymore once the config went through a snapshot
+// or a migration.
+enum Initramfs {
+    File(File),
+    Memory(Arc<Vec<u8>>),
+    Unavailable,
+}
+
+// The VM runs without resource limits when the VMM isn't allowed to manage
+// the cgroup.
+fn cgroup_unless_denied(cgroup: Option<cgroup::Result<Cgroup>>) -> Result<Option<Cgroup>> {
+    match cgroup {
+        Some(Err(e)) if e.is_permission_denied() => {
+            warn!(
+                "Not allowed to set up the cgroup, running without resource limits: {:?}",
+                e
+            );
+            Ok(None)
+        }
+        cgroup => cgroup.transpose().map_err(Error::Cgroup),
+    }
 }
 
 impl Vm {
+    // The VMM joins the cgroup before the guest RAM is allocated, for the
+    // RAM to be charged to it.
+    fn new_cgroup(config: &Arc<Mutex<VmConfig>>) -> Result<Option<Cgroup>> {
+        cgroup_unless_denied(config.lock().unwrap().cgroup.as_ref().map(Cgroup::new))
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn new_from_memory_manager(
         config: Arc<Mutex<VmConfig>>,
@@ -497,18 +797,34 @@ impl Vm {
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         #[cfg(feature = "kvm")] _saved_clock: Option<hypervisor::ClockData>,
         activate_evt: EventFd,
+        cgroup: Option<Cgroup>,
     ) -> Result<Self> {
-        config
+        let oom_policy = config
             .lock()
             .unwrap()
-            .validate()
-            .map_err(Error::ConfigValidation)?;
+            .oom_policy
+            .as_ref()
+            .map(OomPolicy::new)
+            .transpose()
+            .map_err(Error::OomPolicy)?;
 
         // Create NUMA nodes based on NumaConfig.
         #[cfg(feature = "acpi")]
         let numa_nodes =
             Self::create_numa_nodes(config.lock().unwrap().numa.clone(), &memory_manager)?;
 
+        #[cfg(feature = "acpi")]
+        let acpi_tables = {
+            let config = config.lock().unwrap();
+            crate::acpi::load_user_tables(
+                config.acpi_tables.as_deref().unwrap_or(&[]),
+                config.tpm.is_some(),
+            )
+            .map_err(Error::AcpiTable)?
+        };
+
+        let vsock_completion_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let guest_panic_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let device_manager = DeviceManager::new(
             vm.clone(),
             config.clone(),
@@ -519,6 +835,8 @@ impl Vm {
             #[cfg(feature = "acpi")]
             numa_nodes.clone(),
             &activate_evt,
+            &vsock_completion_evt,
+            &guest_panic_evt,
         )
         .map_err(Error::DeviceManager)?;
 
@@ -538,6 +856,7 @@ impl Vm {
         }));
 
         let exit_evt_clone = exit_evt.try_clone().map_err(Error::EventFdClone)?;
+        let triple_fault_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let cpu_manager = cpu::CpuManager::new(
             &config.lock().unwrap().cpus.clone(),
             &device_manager,
@@ -545,6 +864,8 @@ impl Vm {
             vm.clone(),
             exit_evt_clone,
             reset_evt,
+            triple_fault_evt.try_clone().map_err(Error::EventFdClone)?,
+            config.lock().unwrap().triple_fault_actions(),
             hypervisor,
             seccomp_action.clone(),
             vm_ops,
@@ -560,7 +881,11 @@ impl Vm {
             .unwrap()
             .initramfs
             .as_ref()
-            .map(|i| File::open(&i.path))
+            .map(|i| match &i.data {
+                Some(data) => Ok(Initramfs::Memory(data.clone())),
+                None if i.path.as_os_str().is_empty() => Ok(Initramfs::Unavailable),
+                None => File::open(&i.path).map(Initramfs::File),
+            })
             .transpose()
             .map_err(Error::InitramfsFile)?;
 
@@ -580,8 +905,22 @@ impl Vm {
             saved_clock: _saved_clock,
             #[cfg(feature = "acpi")]
             numa_nodes,
+            #[cfg(feature = "acpi")]
+            acpi_tables,
             seccomp_action: seccomp_action.clone(),
             exit_evt,
+            agent_request_id: 0,
+            cgroup,
+            memory_target: None,
+            oom_policy,
+            cpu_throttle: None,
+            triple_fault_evt,
+            vsock_completion_evt,
+            guest_panic_evt,
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            sev: None,
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            sev_launch_ranges: Vec::new(),
         })
     }
 
@@ -650,6 +989,13 @@ impl Vm {
         Ok(numa_nodes)
     }
 
+    // Reject a bad configuration before any resource is allocated for the VM.
+    fn validate_config(config: &Arc<Mutex<VmConfig>>) -> Result<()> {
+        let config = config.lock().unwrap();
+        config.validate().map_err(Error::ConfigValidation)?;
+        config.validate_host().map_err(Error::ConfigValidation)
+    }
+
     pub fn new(
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
@@ -657,21 +1003,45 @@ impl Vm {
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
+        vhost_user_backends: VhostUserBackends,
     ) -> Result<Self> {
+        Vm::validate_config(&config)?;
+
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         hypervisor.check_required_extensions().unwrap();
         let vm = hypervisor.create_vm().unwrap();
         #[cfg(target_arch = "x86_64")]
         vm.enable_split_irq().unwrap();
+
+        // The VM must be a SEV guest before its memory and vCPUs are set up.
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        let sev = config
+            .lock()
+            .unwrap()
+            .sev
+            .as_ref()
+            .map(|sev_config| SevGuest::new(vm.clone(), sev_config))
+            .transpose()
+            .map_err(Error::Sev)?;
+
+        let cgroup = Vm::new_cgroup(&config)?;
         let phys_bits = physical_bits(config.lock().unwrap().cpus.max_phys_bits);
+        let memory_config = config.lock().unwrap().memory.clone();
         let memory_manager = MemoryManager::new(
             vm.clone(),
-            &config.lock().unwrap().memory.clone(),
-            false,
+            &memory_config,
+            memory_config.prefault,
             phys_bits,
         )
         .map_err(Error::MemoryManager)?;
 
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        if let Some(sev) = &sev {
+            let guest_memory = memory_manager.lock().unwrap().guest_memory();
+            sev.register_memory(&guest_memory.memory())
+                .map_err(Error::Sev)?;
+        }
+
         #[cfg(target_arch = "x86_64")]
         {
             if let Some(sgx_epc_config) = config.lock().unwrap().sgx_epc.clone() {
@@ -683,7 +1053,8 @@ impl Vm {
             }
         }
 
-        let new_vm = Vm::new_from_memory_manager(
+        #[allow(unused_mut)]
+        let mut new_vm = Vm::new_from_memory_manager(
             config,
             memory_manager,
             vm,
@@ -694,16 +1065,27 @@ impl Vm {
             #[cfg(feature = "kvm")]
             None,
             activate_evt,
+            cgroup,
         )?;
 
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        if let Some(sev) = sev {
+            new_vm
+                .cpu_manager
+                .lock()
+                .unwrap()
+                .set_memory_encryption_mask(sev.encryption_mask());
+            new_vm.sev = Some(sev);
+        }
+
         // The device manager must create the devices from here as it is part
         // of the regular code path creating everything from scratch.
-        new_vm
-            .device_manager
-            .lock()
-            .unwrap()
+        let mut device_manager = new_vm.device_manager.lock().unwrap();
+        device_manager.set_vhost_user_backends(vhost_user_backends);
+        device_manager
             .create_devices()
             .map_err(Error::DeviceManager)?;
+        drop(device_manager);
         Ok(new_vm)
     }
 
@@ -725,21 +1107,24 @@ impl Vm {
         vm.enable_split_irq().unwrap();
         let vm_snapshot = get_vm_snapshot(snapshot).map_err(Error::Restore)?;
         let config = vm_snapshot.config;
+        Vm::validate_config(&config)?;
         if let Some(state) = vm_snapshot.state {
             vm.set_state(state)
                 .map_err(|e| Error::Restore(MigratableError::Restore(e.into())))?;
         }
 
+        let cgroup = Vm::new_cgroup(&config)?;
         let memory_manager = if let Some(memory_manager_snapshot) =
             snapshot.snapshots.get(MEMORY_MANAGER_SNAPSHOT_ID)
         {
             let phys_bits = physical_bits(config.lock().unwrap().cpus.max_phys_bits);
+            let memory_config = config.lock().unwrap().memory.clone();
             MemoryManager::new_from_snapshot(
                 memory_manager_snapshot,
                 vm.clone(),
-                &config.lock().unwrap().memory.clone(),
+                &memory_config,
                 source_url,
-                prefault,
+                prefault || memory_config.prefault,
                 phys_bits,
             )
             .map_err(Error::MemoryManager)?
@@ -760,6 +1145,7 @@ impl Vm {
             #[cfg(feature = "kvm")]
             None,
             activate_evt,
+            cgroup,
         )
     }
 
@@ -771,6 +1157,8 @@ impl Vm {
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
     ) -> Result<Self> {
+        Vm::validate_config(&config)?;
+
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         hypervisor.check_required_extensions().unwrap();
         let vm = hypervisor.create_vm().unwrap();
@@ -778,10 +1166,12 @@ impl Vm {
         vm.enable_split_irq().unwrap();
         let phys_bits = physical_bits(config.lock().unwrap().cpus.max_phys_bits);
 
+        let cgroup = Vm::new_cgroup(&config)?;
+        let memory_config = config.lock().unwrap().memory.clone();
         let memory_manager = MemoryManager::new(
             vm.clone(),
-            &config.lock().unwrap().memory.clone(),
-            false,
+            &memory_config,
+            memory_config.prefault,
             phys_bits,
         )
         .map_err(Error::MemoryManager)?;
@@ -797,39 +1187,69 @@ impl Vm {
             #[cfg(feature = "kvm")]
             None,
             activate_evt,
+            cgroup,
         )
     }
 
     fn load_initramfs(&mut self, guest_mem: &GuestMemoryMmap) -> Result<arch::InitramfsConfig> {
-        let mut initramfs = self.initramfs.as_ref().unwrap();
-        let size: usize = initramfs
-            .seek(SeekFrom::End(0))
-            .map_err(|_| Error::InitramfsLoad)?
-            .try_into()
-            .unwrap();
-        initramfs
-            .seek(SeekFrom::Start(0))
-            .map_err(|_| Error::InitramfsLoad)?;
+        let size: usize = match self.initramfs.as_mut().unwrap() {
+            Initramfs::File(initramfs) => {
+                let size = initramfs
+                    .seek(SeekFrom::End(0))
+                    .map_err(|_| Error::InitramfsLoad)?
+                    .try_into()
+                    .unwrap();
+                initramfs
+                    .seek(SeekFrom::Start(0))
+                    .map_err(|_| Error::InitramfsLoad)?;
+                size
+            }
+            Initramfs::Memory(data) => data.len(),
+            Initramfs::Unavailable => return Err(Error::InitramfsUnavailable),
+        };
 
         let address =
             arch::initramfs_load_addr(guest_mem, size).map_err(|_| Error::InitramfsLoad)?;
         let address = GuestAddress(address);
 
-        guest_mem
-            .read_from(address, &mut initramfs, size)
-            .map_err(|_| Error::InitramfsLoad)?;
+        match self.initramfs.as_mut().unwrap() {
+            Initramfs::File(initramfs) => guest_mem
+                .read_from(address, initramfs, size)
+                .map(|_| ())
+                .map_err(|_| Error::InitramfsLoad)?,
+            Initramfs::Memory(data) => guest_mem
+                .write_slice(data, address)
+                .map_err(|_| Error::InitramfsLoad)?,
+            Initramfs::Unavailable => return Err(Error::InitramfsUnavailable),
+        }
+
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        self.sev_launch_ranges.push((address, size));
 
         Ok(arch::InitramfsConfig { address, size })
     }
 
     fn get_cmdline(&mut self) -> Result<CString> {
-        let mut cmdline = Cmdline::new(arch::CMDLINE_MAX_SIZE);
-        cmdline
-            .insert_str(self.config.lock().unwrap().cmdline.args.clone())
-            .map_err(Error::CmdLineInsertStr)?;
+        let config = self.config.lock().unwrap();
+        let mut builder = CmdlineBuilder::new(BootEnvironment {
+            serial: config.serial.mode.clone(),
+            console: config.console.mode.clone(),
+            initramfs: config.initramfs.is_some(),
+        });
+        builder.raw(&config.cmdline.args).map_err(Error::Cmdline)?;
+        drop(config);
         for entry in self.device_manager.lock().unwrap().cmdline_additions() {
-            cmdline.insert_str(entry).map_err(Error::CmdLineInsertStr)?;
+            builder.raw(entry).map_err(Error::Cmdline)?;
+        }
+        builder.default_consoles();
+
+        for warning in builder.warnings() {
+            warn!("Kernel command line: {}", warning);
         }
+
+        let cmdline = builder
+            .build(arch::CMDLINE_MAX_SIZE)
+            .map_err(Error::Cmdline)?;
         Ok(CString::new(cmdline).map_err(Error::CmdLineCString)?)
     }
 
@@ -889,6 +1309,12 @@ impl Vm {
         )
         .map_err(Error::LoadCmdLine)?;
 
+        // The low memory the boot structures are written to, followed by the
+        // kernel.
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        self.sev_launch_ranges
+            .push((GuestAddress(0), entry_addr.kernel_end as usize));
+
         if entry_addr.setup_header.is_some() {
             let load_addr = entry_addr
                 .kernel_load
@@ -946,6 +1372,7 @@ impl Vm {
                 &self.cpu_manager,
                 &self.memory_manager,
                 &self.numa_nodes,
+                &self.acpi_tables,
             ));
         }
 
@@ -1007,19 +1434,16 @@ impl Vm {
             .get_device_info()
             .clone();
 
-        let pci_space_start: GuestAddress = self
-            .memory_manager
-            .lock()
-            .as_ref()
-            .unwrap()
-            .start_of_device_area();
-
-        let pci_space_end: GuestAddress = self
-            .memory_manager
-            .lock()
-            .as_ref()
-            .unwrap()
-            .end_of_device_area();
+        // Only a single PCI segment is supported on AArch64, the 64 bits
+        // memory window of which is exposed to the guest.
+        let (pci_space_start, pci_space_end) = {
+            let device_manager = self.device_manager.lock().unwrap();
+            let mmio_allocator = device_manager.pci_segments()[0]
+                .mmio_allocator
+                .lock()
+                .unwrap();
+            (mmio_allocator.base(), mmio_allocator.end())
+        };
 
         let pci_space_size = pci_space_end
             .checked_offset_from(pci_space_start)
@@ -1036,6 +1460,7 @@ impl Vm {
                 &self.cpu_manager,
                 &self.memory_manager,
                 &self.numa_nodes,
+                &self.acpi_tables,
             );
         }
 
@@ -1076,6 +1501,14 @@ impl Vm {
         self.device_manager.lock().unwrap().console_pty()
     }
 
+    pub fn serial_tcp(&self) -> Option<Arc<TcpConsole>> {
+        self.device_manager.lock().unwrap().serial_tcp()
+    }
+
+    pub fn console_tcp(&self) -> Option<Arc<TcpConsole>> {
+        self.device_manager.lock().unwrap().console_tcp()
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
         let new_state = VmState::Shutdown;
@@ -1124,6 +1557,11 @@ impl Vm {
         desired_memory: Option<u64>,
         desired_balloon: Option<u64>,
     ) -> Result<()> {
+        // Resizing the memory explicitly overrides any memory target.
+        if desired_memory.is_some() || desired_balloon.is_some() {
+            self.memory_target = None;
+        }
+
         if let Some(desired_vcpus) = desired_vcpus {
             if self
                 .cpu_manager
@@ -1202,6 +1640,106 @@ impl Vm {
         Ok(())
     }
 
+    /// Plug or unplug memory and resize the balloon so that the guest ends up
+    /// with `target` bytes of memory. The guest reaches the target on its own
+    /// pace, which can be followed through memory_target().
+    pub fn set_memory_target(&mut self, target: u64) -> Result<()> {
+        let (plan, current_ram, current_balloon) = {
+            let config = self.config.lock().unwrap();
+            (
+                plan_memory_target(target, &config.memory, config.balloon.is_some())?,
+                config.memory.size + config.memory.hotplugged_size.unwrap_or(0),
+                config.balloon.as_ref().map(|b| b.size),
+            )
+        };
+
+        let needed = plan
+            .target
+            .saturating_sub(self.memory_target_info(plan.target).actual);
+        if needed > host_available_memory().map_err(Error::HostMemoryInfo)? {
+            return Err(Error::MemoryTargetOvercommit(needed));
+        }
+
+        let desired_ram = if plan.ram != current_ram {
+            Some(plan.ram)
+        } else {
+            None
+        };
+        let desired_balloon = match current_balloon {
+            Some(size) if size != plan.balloon => Some(plan.balloon),
+            _ => None,
+        };
+        self.resize(None, desired_ram, None)?;
+        if let Err(e) = self.resize(None, None, desired_balloon) {
+            // Don't leave the guest half-way to the target.
+            if desired_ram.is_some() {
+                if let Err(rollback_error) = self.resize(None, Some(current_ram), None) {
+                    error!(
+                        "Failed to resize the RAM back after failing to resize the balloon: {:?}",
+                        rollback_error
+                    );
+                    return Err(Error::MemoryTargetHalfApplied(Box::new(e)));
+                }
+            }
+            return Err(e);
+        }
+
+        self.memory_target = Some(plan.target);
+
+        Ok(())
+    }
+
+    pub fn memory_target(&self) -> Option<MemoryTargetInfo> {
+        self.memory_target
+            .map(|target| self.memory_target_info(target))
+    }
+
+    fn memory_target_info(&self, target: u64) -> MemoryTargetInfo {
+        let (plugged_size, balloon_size) = {
+            let device_manager = self.device_manager.lock().unwrap();
+            (
+                device_manager.virtio_mem_plugged_size(),
+                device_manager.balloon_size(),
+            )
+        };
+        let ram = memory_target_ram(&self.config.lock().unwrap().memory, plugged_size);
+
+        MemoryTargetInfo {
+            target,
+            actual: ram.saturating_sub(balloon_size),
+            plugged_size,
+            balloon_size,
+        }
+    }
+
+    pub fn add_dimm(&mut self, size: u64) -> Result<()> {
+        // Like a resize, adding memory overrides any memory target.
+        self.memory_target = None;
+
+        let new_region = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .add_dimm(size)
+            .map_err(Error::MemoryManager)?;
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .update_memory(&new_region)
+            .map_err(Error::DeviceManager)?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(AcpiNotificationFlags::MEMORY_DEVICES_CHANGED)
+            .map_err(Error::DeviceManager)?;
+
+        // The VM reboots with the memory added so far.
+        self.config.lock().unwrap().memory.size += size;
+
+        Ok(())
+    }
+
     pub fn resize_zone(&mut self, id: String, desired_memory: u64) -> Result<()> {
         let memory_config = &mut self.config.lock().unwrap().memory;
 
@@ -1266,6 +1804,71 @@ impl Vm {
         Ok(pci_device_info)
     }
 
+    pub fn set_descriptor_inspector(
+        &self,
+        id: &str,
+        inspector: Arc<dyn DescriptorInspector>,
+    ) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_descriptor_inspector(id, inspector)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn reset_device(&mut self, id: String) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .reset_device(id)
+            .map_err(Error::DeviceManager)
+    }
+
+    // Whether the devices are paused along with the VM, only a running or
+    // paused VM having activated devices.
+    fn devices_paused(&self) -> Result<bool> {
+        match *self.state.read().unwrap() {
+            VmState::Running => Ok(false),
+            VmState::Paused => Ok(true),
+            _ => Err(Error::VmNotRunning),
+        }
+    }
+
+    pub fn pause_device(&mut self, id: String) -> Result<()> {
+        let vm_paused = self.devices_paused()?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .pause_device(id, vm_paused)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn resume_device(&mut self, id: String) -> Result<()> {
+        let vm_paused = self.devices_paused()?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .resume_device(id, vm_paused)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn activate_device(&mut self, id: String) -> Result<PciDeviceInfo> {
+        let pci_device_info = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .activate_device(&id)
+            .map_err(Error::DeviceManager)?;
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
+            .map_err(Error::DeviceManager)?;
+
+        Ok(pci_device_info)
+    }
+
     pub fn remove_device(&mut self, _id: String) -> Result<()> {
         self.device_manager
             .lock()
@@ -1341,6 +1944,22 @@ impl Vm {
     }
 
     pub fn add_fs(&mut self, mut _fs_cfg: FsConfig) -> Result<PciDeviceInfo> {
+        _fs_cfg.validate().map_err(Error::ConfigValidation)?;
+        {
+            let config = self.config.lock().unwrap();
+            if !config.memory.is_shared() {
+                return Err(Error::ConfigValidation(
+                    ValidationError::VhostUserRequiresSharedMemory,
+                ));
+            }
+            // The guest mounts each share by its tag.
+            if config.fs.iter().flatten().any(|fs| fs.tag == _fs_cfg.tag) {
+                return Err(Error::ConfigValidation(ValidationError::DuplicateFsTag(
+                    _fs_cfg.tag,
+                )));
+            }
+        }
+
         let pci_device_info = self
             .device_manager
             .lock()
@@ -1396,12 +2015,19 @@ impl Vm {
         Ok(pci_device_info)
     }
 
-    pub fn add_net(&mut self, mut _net_cfg: NetConfig) -> Result<PciDeviceInfo> {
+    pub fn add_net(&mut self, mut net_cfg: NetConfig) -> Result<PciDeviceInfo> {
+        net_cfg.validate().map_err(Error::ConfigValidation)?;
+        if net_cfg.vhost_user && !self.config.lock().unwrap().memory.is_shared() {
+            return Err(Error::ConfigValidation(
+                ValidationError::VhostUserRequiresSharedMemory,
+            ));
+        }
+
         let pci_device_info = self
             .device_manager
             .lock()
             .unwrap()
-            .add_net(&mut _net_cfg)
+            .add_net(&mut net_cfg)
             .map_err(Error::DeviceManager)?;
 
         // Update VmConfig by adding the new device. This is important to
@@ -1409,9 +2035,9 @@ impl Vm {
         {
             let mut config = self.config.lock().unwrap();
             if let Some(net) = config.net.as_mut() {
-                net.push(_net_cfg);
+                net.push(net_cfg);
             } else {
-                config.net = Some(vec![_net_cfg]);
+                config.net = Some(vec![net_cfg]);
             }
         }
 
@@ -1453,35 +2079,185 @@ impl Vm {
     }
 
     pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
-        Ok(self.device_manager.lock().unwrap().counters())
+        let mut counters = self.device_manager.lock().unwrap().counters();
+        counters.insert(
+            "vcpus".to_string(),
+            self.cpu_manager.lock().unwrap().counters(),
+        );
+
+        Ok(counters)
     }
 
-    fn os_signal_handler(
-        mut signals: Signals,
-        console_input_clone: Arc<Console>,
-        on_tty: bool,
-        exit_evt: EventFd,
-    ) {
-        for signal in signals.forever() {
-            match signal {
-                SIGWINCH => {
-                    let (col, row) = get_win_size();
-                    console_input_clone.update_console_size(col, row);
-                }
-                SIGTERM | SIGINT => {
-                    if on_tty {
-                        io::stdin()
-                            .lock()
-                            .set_canon_mode()
-                            .expect("failed to restore terminal mode");
-                    }
-                    if exit_evt.write(1).is_err() {
-                        std::process::exit(1);
-                    }
-                }
-                _ => (),
-            }
-        }
+    pub fn rtc_time(&self) -> Result<u64> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .rtc_time()
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn set_rtc_time(&mut self, time: u64) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_rtc_time(time)
+            .map_err(Error::DeviceManager)?;
+        info!("Guest RTC time set to {}", time);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "guest_debug")]
+    pub fn read_guest_memory(&self, gpa: u64, len: usize) -> Result<Vec<u8>> {
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        let memory = memory.memory();
+        read_debug_memory(&memory, gpa, len)
+    }
+
+    #[cfg(feature = "guest_debug")]
+    pub fn write_guest_memory(&self, gpa: u64, data: &[u8]) -> Result<()> {
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        let memory = memory.memory();
+        write_debug_memory(&memory, gpa, data)?;
+        info!("Guest memory written at 0x{:x} ({} bytes)", gpa, data.len());
+
+        Ok(())
+    }
+
+    // Returns once the vCPUs took the NMI. The CpuManager lock is only
+    // taken to kick them, not while waiting.
+    #[cfg(target_arch = "x86_64")]
+    pub fn inject_nmi(&self, id: Option<u8>) -> Result<()> {
+        check_nmi_state(self.get_state()?)?;
+
+        let nmis = self
+            .cpu_manager
+            .lock()
+            .unwrap()
+            .queue_nmi(id)
+            .map_err(Error::CpuManager)?;
+        cpu::wait_for_nmis(&nmis, Instant::now() + cpu::NMI_INJECTION_TIMEOUT, |id| {
+            self.cpu_manager.lock().unwrap().kick_vcpu(id)
+        })
+        .map_err(Error::CpuManager)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn inject_nmi(&self, _id: Option<u8>) -> Result<()> {
+        check_nmi_state(self.get_state()?)?;
+
+        Err(Error::CpuManager(cpu::Error::NmiUnsupported))
+    }
+
+    // The vCPUs are only stopped while the VM is paused, otherwise the
+    // registers would change while being read.
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    pub fn vcpu_registers(&self, id: u8) -> Result<cpu::VcpuRegisters> {
+        if self.get_state()? != VmState::Paused {
+            return Err(Error::VmNotPaused);
+        }
+
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .vcpu_registers(id)
+            .map_err(Error::CpuManager)
+    }
+
+    pub fn inject_input_events(
+        &self,
+        id: &str,
+        events: &[virtio_devices::InputEvent],
+    ) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .inject_input_events(id, events)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn set_gpio_line(&self, id: &str, line: u16, value: bool) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_gpio_line(id, line, value)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn gpio_lines(&self, id: &str) -> Result<Vec<virtio_devices::GpioLineState>> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .gpio_lines(id)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn net_capture(&self, id: &str, path: Option<&Path>) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .net_capture(id, path)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn set_flow_rules(&self, id: &str, rules: &[net_util::FlowRule]) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_flow_rules(id, rules)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn set_net_queues(&self, id: &str, num_queues: usize) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_net_queues(id, num_queues)
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Takes the connections to the vhost-user-net backends, for the VM
+    /// created on a guest reboot to reuse them.
+    pub fn take_vhost_user_backends(&self) -> VhostUserBackends {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .take_vhost_user_backends()
+    }
+
+    pub fn register_device_event_callback(&self, callback: virtio_devices::DeviceEventCallback) {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .register_device_event_callback(callback);
+    }
+
+    fn os_signal_handler(
+        mut signals: Signals,
+        console_input_clone: Arc<Console>,
+        on_tty: bool,
+        exit_evt: EventFd,
+    ) {
+        for signal in signals.forever() {
+            match signal {
+                SIGWINCH => {
+                    let (col, row) = get_win_size();
+                    console_input_clone.update_console_size(col, row);
+                }
+                SIGTERM | SIGINT => {
+                    if on_tty {
+                        io::stdin()
+                            .lock()
+                            .set_canon_mode()
+                            .expect("failed to restore terminal mode");
+                    }
+                    if exit_evt.write(1).is_err() {
+                        std::process::exit(1);
+                    }
+                }
+                _ => (),
+            }
+        }
     }
 
     pub fn boot(&mut self) -> Result<()> {
@@ -1504,6 +2280,13 @@ impl Vm {
 
         self.configure_system(entry_point)?;
 
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        if let Some(sev) = self.sev.as_mut() {
+            let mem = self.memory_manager.lock().unwrap().boot_guest_memory();
+            sev.launch(&mem, &self.sev_launch_ranges)
+                .map_err(Error::Sev)?;
+        }
+
         self.cpu_manager
             .lock()
             .unwrap()
@@ -1587,6 +2370,28 @@ impl Vm {
         Ok(())
     }
 
+    pub fn handle_tcp_console(&self) -> Result<()> {
+        let dm = self.device_manager.lock().unwrap();
+        let console = dm.console();
+        let mut out = [0u8; 64];
+        if let Some(tcp) = dm.serial_tcp() {
+            let count = tcp.process(&mut out).map_err(Error::TcpConsole)?;
+            if count > 0 && console.input_enabled() {
+                console
+                    .queue_input_bytes_serial(&out[..count])
+                    .map_err(Error::Console)?;
+            }
+        }
+        if let Some(tcp) = dm.console_tcp() {
+            let count = tcp.process(&mut out).map_err(Error::TcpConsole)?;
+            if count > 0 && console.input_enabled() {
+                console.queue_input_bytes_console(&out[..count]);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn handle_stdin(&self) -> Result<()> {
         let mut out = [0u8; 64];
         let count = io::stdin()
@@ -1718,6 +2523,14 @@ impl Vm {
         Ok(())
     }
 
+    pub fn memory_locked_size(&self) -> Option<u64> {
+        self.memory_manager.lock().unwrap().locked_size()
+    }
+
+    pub fn memory_hotplug_region(&self) -> Option<MemoryHotplugRegion> {
+        self.memory_manager.lock().unwrap().hotplug_region()
+    }
+
     /// Gets the actual size of the balloon.
     pub fn balloon_size(&self) -> u64 {
         self.device_manager.lock().unwrap().balloon_size()
@@ -1746,6 +2559,22 @@ impl Vm {
         Ok(())
     }
 
+    /// Dumps the guest memory to the given file, as an ELF core. The VM
+    /// should be paused for the dump to be consistent. Only the owner can
+    /// read the file, holding whatever secrets the guest does.
+    pub fn dump_memory(&self, path: &Path) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(Error::MemoryDump)?;
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+
+        crash_dump::write_memory_dump(&guest_memory.memory(), &mut file).map_err(Error::MemoryDump)
+    }
+
     pub fn send_memory_regions<F>(
         &mut self,
         ranges: &MemoryRangeTable,
@@ -1786,22 +2615,481 @@ impl Vm {
     }
 
     pub fn start_memory_dirty_log(&self) -> std::result::Result<(), MigratableError> {
-        self.memory_manager.lock().unwrap().start_memory_dirty_log()
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .start_memory_dirty_log()?;
+        self.device_manager.lock().unwrap().start_dirty_log()
+    }
+
+    /// Stops the devices from logging the memory they write to, once the
+    /// migration is abandoned and the VM keeps running.
+    pub fn stop_memory_dirty_log(&self) -> std::result::Result<(), MigratableError> {
+        self.device_manager.lock().unwrap().stop_dirty_log()
     }
 
     pub fn dirty_memory_range_table(
         &self,
     ) -> std::result::Result<MemoryRangeTable, MigratableError> {
-        self.memory_manager
+        let mut table = self
+            .memory_manager
             .lock()
             .unwrap()
-            .dirty_memory_range_table()
+            .dirty_memory_range_table()?;
+        // Pages dirtied by both the vCPUs and the devices are sent twice,
+        // which is harmless.
+        table.extend(self.device_manager.lock().unwrap().dirty_log()?);
+
+        Ok(table)
+    }
+
+    /// Asks the guest to report its free pages through the balloon, for
+    /// the memory migration to skip them. Returns false if it can't.
+    pub fn request_free_page_hints(&self) -> std::result::Result<bool, MigratableError> {
+        let balloon = match self.device_manager.lock().unwrap().balloon() {
+            Some(balloon) => balloon,
+            None => return Ok(false),
+        };
+        let memory_size = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .guest_memory()
+            .memory()
+            .last_addr()
+            .raw_value()
+            + 1;
+
+        let mut balloon = balloon.lock().unwrap();
+        balloon.request_free_page_hints(memory_size).map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Error requesting free page hints: {:?}", e))
+        })
+    }
+
+    /// Removes the pages the guest reported as free from the table, once
+    /// it reported them all or the deadline expired.
+    pub fn exclude_free_pages(
+        &self,
+        table: &MemoryRangeTable,
+        deadline: Instant,
+    ) -> Option<MemoryRangeTable> {
+        let balloon = self.device_manager.lock().unwrap().balloon()?;
+        let balloon = balloon.lock().unwrap();
+        if !balloon.wait_free_page_hints(deadline) {
+            warn!("The guest didn't report all its free pages in time");
+        }
+        info!(
+            "Skipping {} free pages reported by the guest",
+            balloon.free_pages()
+        );
+
+        Some(balloon.exclude_free_pages(table))
+    }
+
+    /// Lets the guest reuse the pages it reported as free.
+    pub fn release_free_page_hints(&self) -> std::result::Result<(), MigratableError> {
+        if let Some(balloon) = self.device_manager.lock().unwrap().balloon() {
+            balloon
+                .lock()
+                .unwrap()
+                .release_free_page_hints()
+                .map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!(
+                        "Error releasing free page hints: {:?}",
+                        e
+                    ))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the vCPUs from running for the given percentage of the time,
+    /// zero letting them run freely again.
+    pub fn throttle_vcpus(&mut self, percentage: u8) -> std::result::Result<(), MigratableError> {
+        if percentage == 0 {
+            self.cpu_throttle = None;
+        } else if let Some(throttle) = &self.cpu_throttle {
+            throttle.set_percentage(percentage);
+        } else {
+            let throttle =
+                cpu::CpuThrottle::new(self.cpu_manager.clone(), percentage, &self.seccomp_action)
+                    .map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!("Error throttling vCPUs: {:?}", e))
+                })?;
+            self.cpu_throttle = Some(throttle);
+        }
+
+        Ok(())
+    }
+
+    /// Snapshots the VM config along with the state of the devices, leaving
+    /// the guest memory and the vCPUs out. The result describes the devices
+    /// of the VM, but can't be restored.
+    pub fn snapshot_devices_only(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        self.take_snapshot(true)
+    }
+
+    // Snapshots the VM, only along with its devices for `devices_only`.
+    fn take_snapshot(
+        &mut self,
+        devices_only: bool,
+    ) -> std::result::Result<Snapshot, MigratableError> {
+        if self.get_state().unwrap() != VmState::Paused {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Trying to snapshot while VM is running"
+            )));
+        }
+
+        let mut vm_snapshot = Snapshot::new(VM_SNAPSHOT_ID);
+        let vm_snapshot_data = if devices_only {
+            VmSnapshot {
+                config: self.get_config(),
+                #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+                clock: None,
+                state: None,
+            }
+        } else {
+            let vm_state = self
+                .vm
+                .state()
+                .map_err(|e| MigratableError::Snapshot(e.into()))?;
+            VmSnapshot {
+                config: self.get_config(),
+                #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+                clock: self.saved_clock,
+                state: Some(vm_state),
+            }
+        };
+        let vm_snapshot_data = serde_json::to_vec(&vm_snapshot_data)
+            .map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        if !devices_only {
+            vm_snapshot.add_snapshot(self.cpu_manager.lock().unwrap().snapshot()?);
+            vm_snapshot.add_snapshot(self.memory_manager.lock().unwrap().snapshot()?);
+
+            #[cfg(target_arch = "aarch64")]
+            self.add_vgic_snapshot_section(&mut vm_snapshot)
+                .map_err(|e| MigratableError::Snapshot(e.into()))?;
+        }
+
+        vm_snapshot.add_snapshot(self.device_manager.lock().unwrap().snapshot()?);
+        vm_snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", VM_SNAPSHOT_ID),
+            snapshot: vm_snapshot_data,
+            ..Default::default()
+        });
+
+        Ok(vm_snapshot)
+    }
+
+    /// CPUID exposed to the guest, which the destination of a migration
+    /// must be able to expose as well.
+    #[cfg(target_arch = "x86_64")]
+    pub fn cpuid(&self) -> Vec<arch::x86_64::CpuidLeaf> {
+        self.cpu_manager.lock().unwrap().cpuid_leaves()
+    }
+
+    /// Indexes in the config of the disks which content can be copied to
+    /// the destination of a migration. Only raw images backed by a file are
+    /// supported, as they are the only ones not caching any metadata which
+    /// could otherwise be stale once the content has been received.
+    pub fn migratable_disks(&self) -> std::result::Result<Vec<u64>, MigratableError> {
+        let mut indexes = Vec::new();
+
+        if let Some(disks) = &self.config.lock().unwrap().disks {
+            for (index, disk) in disks.iter().enumerate() {
+                let path = match &disk.path {
+                    Some(path) if !disk.vhost_user => path,
+                    _ => continue,
+                };
+                if disk.overlay.is_some() {
+                    warn!("Not copying disk {:?}: backed by an overlay", path);
+                    continue;
+                }
+
+                let mut file = File::open(path).map_err(|e| {
+                    MigratableError::MigrateSend(anyhow!("Error opening disk {:?}: {}", path, e))
+                })?;
+                match block_util::detect_image_type(&mut file) {
+                    Ok(block_util::ImageType::Raw) => indexes.push(index as u64),
+                    _ => warn!("Not copying disk {:?}: not a raw image", path),
+                }
+            }
+        }
+
+        Ok(indexes)
+    }
+
+    fn disk_path(&self, index: u64) -> std::result::Result<PathBuf, MigratableError> {
+        self.config
+            .lock()
+            .unwrap()
+            .disks
+            .as_ref()
+            .and_then(|disks| disks.get(index as usize))
+            .and_then(|disk| disk.path.clone())
+            .ok_or_else(|| MigratableError::MigrateReceive(anyhow!("Unknown disk {}", index)))
+    }
+
+    /// Describe the content of the disk, the extents holding data being the
+    /// only ones that need to be sent.
+    pub fn disk_range_table(
+        &self,
+        index: u64,
+    ) -> std::result::Result<(DiskHeader, MemoryRangeTable), MigratableError> {
+        let path = self.disk_path(index)?;
+        let file = File::open(&path).map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Error opening disk {:?}: {}", path, e))
+        })?;
+        let metadata = file.metadata().map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Error getting size of {:?}: {}", path, e))
+        })?;
+
+        let mut table = MemoryRangeTable::default();
+        for (offset, length) in block_util::data_extents(&file).map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Error finding extents of {:?}: {}", path, e))
+        })? {
+            table.push(MemoryRange {
+                gpa: offset,
+                length,
+            });
+        }
+
+        Ok((
+            DiskHeader {
+                index,
+                size: metadata.len(),
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+            },
+            table,
+        ))
+    }
+
+    pub fn send_disk_ranges<F>(
+        &self,
+        index: u64,
+        ranges: &MemoryRangeTable,
+        fd: &mut F,
+    ) -> std::result::Result<(), MigratableError>
+    where
+        F: Write,
+    {
+        let path = self.disk_path(index)?;
+        let mut file = File::open(&path).map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Error opening disk {:?}: {}", path, e))
+        })?;
+
+        for range in ranges.regions() {
+            file.seek(SeekFrom::Start(range.gpa)).map_err(|e| {
+                MigratableError::MigrateSend(anyhow!("Error seeking in {:?}: {}", path, e))
+            })?;
+            let count = io::copy(&mut (&mut file).take(range.length), fd)
+                .map_err(MigratableError::MigrateSocket)?;
+            // The extents have been found while the VM was paused, the file
+            // is not expected to shrink.
+            if count != range.length {
+                return Err(MigratableError::MigrateSend(anyhow!(
+                    "Disk {:?} truncated during migration",
+                    path
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Recreate the disk from the extents sent by the source, the holes
+    /// being punched so that the sparse layout is preserved.
+    pub fn receive_disk_ranges<F>(
+        &self,
+        header: &DiskHeader,
+        ranges: &MemoryRangeTable,
+        fd: &mut F,
+    ) -> std::result::Result<(), MigratableError>
+    where
+        F: Read,
+    {
+        let path = self.disk_path(header.index)?;
+        check_disk_not_shared(&path, header)?;
+        let mut file = OpenOptions::new().write(true).open(&path).map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error opening disk {:?}: {}", path, e))
+        })?;
+
+        file.set_len(header.size)
+            .and_then(|_| block_util::punch_hole(&file, 0, header.size))
+            .map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error resetting disk {:?}: {}", path, e))
+            })?;
+
+        for range in ranges.regions() {
+            file.seek(SeekFrom::Start(range.gpa)).map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error seeking in {:?}: {}", path, e))
+            })?;
+            let count = io::copy(&mut fd.by_ref().take(range.length), &mut file)
+                .map_err(MigratableError::MigrateSocket)?;
+            if count != range.length {
+                return Err(MigratableError::MigrateReceive(anyhow!(
+                    "Disk {:?} content truncated",
+                    path
+                )));
+            }
+        }
+
+        file.sync_all().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error syncing disk {:?}: {}", path, e))
+        })
+    }
+
+    /// Prepare a request to the agent running in the guest, through the
+    /// vsock device. The VMM is the only host process allowed to connect to
+    /// the agent port.
+    pub fn agent_connection(&mut self) -> Result<AgentConnection> {
+        let (socket, port) = match &self.config.lock().unwrap().vsock {
+            Some(VsockConfig {
+                socket,
+                agent_port: Some(port),
+                ..
+            }) => (socket.clone(), *port),
+            _ => return Err(Error::NoAgentPort),
+        };
+
+        self.agent_request_id = self.agent_request_id.wrapping_add(1);
+        Ok(AgentConnection {
+            socket,
+            port,
+            id: self.agent_request_id,
+        })
     }
 
     pub fn device_tree(&self) -> Arc<Mutex<DeviceTree>> {
         self.device_manager.lock().unwrap().device_tree()
     }
 
+    pub fn disk_io_priorities(&self) -> Vec<DiskIoPriorityInfo> {
+        self.device_manager.lock().unwrap().disk_io_priorities()
+    }
+
+    pub fn cgroup_path(&self) -> Option<PathBuf> {
+        self.cgroup.as_ref().map(|c| c.path().to_path_buf())
+    }
+
+    pub fn oom_policy_timer(&self) -> Option<&TimerFd> {
+        self.oom_policy.as_ref().map(|p| p.timer())
+    }
+
+    pub fn oom_policy_info(&self) -> Option<OomPolicyInfo> {
+        self.oom_policy.as_ref().and_then(|p| p.info())
+    }
+
+    pub fn triple_fault_evt(&self) -> &EventFd {
+        &self.triple_fault_evt
+    }
+
+    pub fn vsock_completion_evt(&self) -> &EventFd {
+        &self.vsock_completion_evt
+    }
+
+    pub fn guest_panic_evt(&self) -> &EventFd {
+        &self.guest_panic_evt
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn nested_virtualization(&self) -> bool {
+        self.cpu_manager.lock().unwrap().nested_virtualization()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn tsc_deadline_timer(&self) -> bool {
+        self.cpu_manager.lock().unwrap().tsc_deadline_timer()
+    }
+
+    /// Compare the memory usage of the VMM against the OOM policy soft limit,
+    /// reclaiming some guest memory through the balloon or pausing the guest
+    /// if above the limit.
+    pub fn handle_oom_policy(&mut self) -> Result<()> {
+        let (rss, action) = match self.oom_policy.as_mut() {
+            Some(oom_policy) => match oom_policy.check().map_err(Error::OomPolicy)? {
+                Some(trigger) => trigger,
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+
+        // A paused guest can't allocate more memory, and a guest which isn't
+        // running yet, or anymore, doesn't have any to give back.
+        if self.get_state()? != VmState::Running {
+            return Ok(());
+        }
+
+        let action = match action {
+            OomAction::Balloon => match self.oom_policy_balloon_size() {
+                Some(desired_balloon) => {
+                    warn!(
+                        "VMM RSS {} above the OOM policy limit, inflating the balloon to {}",
+                        rss, desired_balloon
+                    );
+                    if let Err(e) = self.resize(None, None, Some(desired_balloon)) {
+                        error!("Failed inflating the balloon: {:?}", e);
+                    }
+                    OomAction::Balloon
+                }
+                None => {
+                    warn!(
+                        "VMM RSS {} above the OOM policy limit, balloon fully inflated, pausing the VM",
+                        rss
+                    );
+                    self.oom_policy_pause();
+                    OomAction::Pause
+                }
+            },
+            OomAction::Pause => {
+                warn!("VMM RSS {} above the OOM policy limit, pausing the VM", rss);
+                self.oom_policy_pause();
+                OomAction::Pause
+            }
+        };
+
+        if let Some(oom_policy) = self.oom_policy.as_mut() {
+            oom_policy.triggered(rss, action);
+        }
+        event_monitor::event(
+            "vm",
+            "oom-policy",
+            &[
+                ("rss", rss.to_string()),
+                ("action", format!("{:?}", action).to_lowercase()),
+            ],
+        );
+
+        Ok(())
+    }
+
+    // Size the balloon should be inflated to, or None if it can't reclaim
+    // anything more without leaving the guest with less than the smallest
+    // memory target.
+    fn oom_policy_balloon_size(&self) -> Option<u64> {
+        let config = self.config.lock().unwrap();
+        let balloon_step = config.oom_policy.as_ref()?.balloon_step;
+        let balloon_size = config.balloon.as_ref()?.size;
+        let max_balloon_size = (config.memory.size + config.memory.hotplugged_size.unwrap_or(0))
+            .saturating_sub(MIN_MEMORY_TARGET);
+
+        let desired_balloon = cmp::min(balloon_size + balloon_step, max_balloon_size);
+        if desired_balloon > balloon_size {
+            Some(desired_balloon)
+        } else {
+            None
+        }
+    }
+
+    fn oom_policy_pause(&mut self) {
+        if let Err(e) = self.pause() {
+            error!("Failed pausing the VM: {:?}", e);
+        }
+    }
+
     pub fn activate_virtio_devices(&self) -> Result<()> {
         self.device_manager
             .lock()
@@ -1821,10 +3109,30 @@ impl Vm {
         #[cfg(not(feature = "acpi"))]
         Err(Error::PowerButtonNotSupported)
     }
+
+    pub fn launch_measurement(&self) -> Result<LaunchMeasurement> {
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        if let Some(measurement) = self.sev.as_ref().and_then(|sev| sev.measurement()) {
+            return Ok(measurement.clone());
+        }
+        Err(Error::LaunchMeasurementUnavailable)
+    }
+
+    pub fn set_battery(&self, _ac_online: bool, _charge: u8) -> Result<()> {
+        #[cfg(feature = "acpi")]
+        return self
+            .device_manager
+            .lock()
+            .unwrap()
+            .set_battery(_ac_online, _charge)
+            .map_err(Error::SetBattery);
+        #[cfg(not(feature = "acpi"))]
+        Err(Error::BatteryNotSupported)
+    }
 }
 
-impl Pausable for Vm {
-    fn pause(&mut self) -> std::result::Result<(), MigratableError> {
+impl Vm {
+    fn pause_vm(&mut self, deadline: Option<Instant>) -> std::result::Result<(), MigratableError> {
         let mut state = self
             .state
             .try_write()
@@ -1845,13 +3153,54 @@ impl Pausable for Vm {
             clock.flags = 0;
             self.saved_clock = Some(clock);
         }
-        self.cpu_manager.lock().unwrap().pause()?;
-        self.device_manager.lock().unwrap().pause()?;
+
+        match deadline {
+            Some(deadline) => {
+                // The vCPUs are parked first, so that no device is handed
+                // any more work once they are.
+                let quiesced = self
+                    .cpu_manager
+                    .lock()
+                    .unwrap()
+                    .quiesce(deadline)
+                    .and_then(|_| self.device_manager.lock().unwrap().quiesce(deadline));
+
+                // Rather than being left partially paused, the VM keeps
+                // running when any of its threads didn't get parked in time.
+                if let Err(e) = quiesced {
+                    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+                    {
+                        self.saved_clock = None;
+                    }
+                    self.device_manager.lock().unwrap().resume()?;
+                    self.cpu_manager.lock().unwrap().resume()?;
+                    return Err(e);
+                }
+            }
+            None => {
+                self.cpu_manager.lock().unwrap().pause()?;
+                self.device_manager.lock().unwrap().pause()?;
+            }
+        }
 
         *state = new_state;
 
         Ok(())
     }
+}
+
+impl Pausable for Vm {
+    fn pause(&mut self) -> std::result::Result<(), MigratableError> {
+        self.pause_vm(None)
+    }
+
+    /// Pauses the VM once all the vCPUs and the threads of its devices are
+    /// parked: no vCPU is in the middle of an exit and no device is
+    /// processing a descriptor chain. The VM keeps running if they aren't
+    /// all parked by the deadline.
+    fn quiesce(&mut self, deadline: Instant) -> std::result::Result<(), MigratableError> {
+        self.pause_vm(Some(deadline))
+    }
 
     fn resume(&mut self) -> std::result::Result<(), MigratableError> {
         let mut state = self
@@ -1897,40 +3246,22 @@ impl Snapshottable for Vm {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let current_state = self.get_state().unwrap();
-        if current_state != VmState::Paused {
+        // The encrypted guest memory can't be restored elsewhere.
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        if self.sev.is_some() {
             return Err(MigratableError::Snapshot(anyhow!(
-                "Trying to snapshot while VM is running"
+                "Snapshotting a VM with encrypted memory isn't supported"
             )));
         }
 
-        let mut vm_snapshot = Snapshot::new(VM_SNAPSHOT_ID);
-        let vm_state = self
-            .vm
-            .state()
-            .map_err(|e| MigratableError::Snapshot(e.into()))?;
-        let vm_snapshot_data = serde_json::to_vec(&VmSnapshot {
-            config: self.get_config(),
-            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
-            clock: self.saved_clock,
-            state: Some(vm_state),
-        })
-        .map_err(|e| MigratableError::Snapshot(e.into()))?;
-
-        vm_snapshot.add_snapshot(self.cpu_manager.lock().unwrap().snapshot()?);
-        vm_snapshot.add_snapshot(self.memory_manager.lock().unwrap().snapshot()?);
-
-        #[cfg(target_arch = "aarch64")]
-        self.add_vgic_snapshot_section(&mut vm_snapshot)
-            .map_err(|e| MigratableError::Snapshot(e.into()))?;
-
-        vm_snapshot.add_snapshot(self.device_manager.lock().unwrap().snapshot()?);
-        vm_snapshot.add_data_section(SnapshotDataSection {
-            id: format!("{}-section", VM_SNAPSHOT_ID),
-            snapshot: vm_snapshot_data,
-        });
+        // The state of the vTPM is held by its emulator.
+        if self.config.lock().unwrap().tpm.is_some() {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Snapshotting a VM with a vTPM isn't supported"
+            )));
+        }
 
-        Ok(vm_snapshot)
+        self.take_snapshot(false)
     }
 
     fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
@@ -2069,24 +3400,7 @@ impl Transportable for Vm {
 
         match url.scheme() {
             "file" => {
-                let mut vm_snapshot_path = url_to_path(&url)?;
-                vm_snapshot_path.push(VM_SNAPSHOT_FILE);
-
-                // Create the snapshot file
-                let mut vm_snapshot_file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create_new(true)
-                    .open(vm_snapshot_path)
-                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
-
-                // Serialize and write the snapshot
-                let vm_snapshot = serde_json::to_vec(snapshot)
-                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
-
-                vm_snapshot_file
-                    .write(&vm_snapshot)
-                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                let snapshot_dir = url_to_path(&url)?;
 
                 // Tell the memory manager to also send/write its own snapshot.
                 if let Some(memory_manager_snapshot) =
@@ -2101,6 +3415,14 @@ impl Transportable for Vm {
                         "Missing memory manager snapshot"
                     )));
                 }
+
+                // Written after the memory, so that an interrupted snapshot
+                // without manifest isn't mistaken for a legacy one.
+                write_vm_snapshot(&snapshot_dir, snapshot)?;
+
+                let mut files = vec![PathBuf::from(VM_SNAPSHOT_FILE)];
+                files.append(&mut self.memory_manager.lock().unwrap().snapshot_files());
+                write_snapshot_manifest(&snapshot_dir, files, false)?;
             }
             _ => {
                 return Err(MigratableError::MigrateSend(anyhow!(
@@ -2114,6 +3436,67 @@ impl Transportable for Vm {
 }
 impl Migratable for Vm {}
 
+// Refuse to receive a disk into the very file the source is using, as on a
+// migration to the same host, since it would be wiped while being sent.
+// The vCPUs of a paused VM wouldn't take the NMI until it's resumed.
+fn check_nmi_state(state: VmState) -> Result<()> {
+    if state != VmState::Running {
+        return Err(Error::VmNotRunning);
+    }
+
+    Ok(())
+}
+
+fn check_disk_not_shared(
+    path: &Path,
+    header: &DiskHeader,
+) -> std::result::Result<(), MigratableError> {
+    let metadata = std::fs::metadata(path).map_err(|e| {
+        MigratableError::MigrateReceive(anyhow!("Error opening disk {:?}: {}", path, e))
+    })?;
+    if metadata.dev() == header.dev && metadata.ino() == header.ino {
+        return Err(MigratableError::MigrateReceive(anyhow!(
+            "Disk {:?} is the image the source is using",
+            path
+        )));
+    }
+
+    Ok(())
+}
+
+// Checks a guest memory access of the debug API, before anything gets
+// allocated for it.
+#[cfg(feature = "guest_debug")]
+fn check_debug_memory_access(memory: &GuestMemoryMmap, gpa: u64, len: usize) -> Result<()> {
+    if len > MAX_GUEST_MEMORY_ACCESS {
+        return Err(Error::GuestMemoryAccessTooLarge(len));
+    }
+    if !memory.check_range(GuestAddress(gpa), len) {
+        return Err(Error::UnmappedGuestMemory(gpa, len));
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "guest_debug")]
+fn read_debug_memory(memory: &GuestMemoryMmap, gpa: u64, len: usize) -> Result<Vec<u8>> {
+    check_debug_memory_access(memory, gpa, len)?;
+    let mut data = vec![0u8; len];
+    memory
+        .read_slice(&mut data, GuestAddress(gpa))
+        .map_err(Error::GuestMemoryAccess)?;
+
+    Ok(data)
+}
+
+#[cfg(feature = "guest_debug")]
+fn write_debug_memory(memory: &GuestMemoryMmap, gpa: u64, data: &[u8]) -> Result<()> {
+    check_debug_memory_access(memory, gpa, data.len())?;
+    memory
+        .write_slice(data, GuestAddress(gpa))
+        .map_err(Error::GuestMemoryAccess)
+}
+
 #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
 #[cfg(test)]
 mod tests {
@@ -2171,6 +3554,168 @@ mod tests {
     fn test_vm_paused_transitions() {
         test_vm_state_transitions(VmState::Paused);
     }
+
+    #[test]
+    fn test_plan_memory_target() {
+        let mut memory = MemoryConfig {
+            size: 1 << 30,
+            ..Default::default()
+        };
+
+        // Without hotpluggable memory nor balloon, nothing can change.
+        assert!(plan_memory_target(1 << 30, &memory, false).is_ok());
+        assert!(matches!(
+            plan_memory_target(512 << 20, &memory, false),
+            Err(Error::InvalidMemoryTarget(_))
+        ));
+
+        // The balloon reclaims the difference, rounded to its page size.
+        assert_eq!(
+            plan_memory_target((512 << 20) + 100, &memory, true).unwrap(),
+            MemoryTargetPlan {
+                target: 512 << 20,
+                ram: 1 << 30,
+                balloon: 512 << 20,
+            }
+        );
+        assert!(matches!(
+            plan_memory_target(64 << 20, &memory, true),
+            Err(Error::InvalidMemoryTarget(_))
+        ));
+        assert!(matches!(
+            plan_memory_target(2 << 30, &memory, true),
+            Err(Error::InvalidMemoryTarget(_))
+        ));
+
+        // Memory is plugged by blocks, the balloon covering the remainder.
+        memory.hotplug_method = HotplugMethod::VirtioMem;
+        memory.hotplug_size = Some(1 << 30);
+        assert_eq!(
+            plan_memory_target((3 << 29) + (4 << 10), &memory, true).unwrap(),
+            MemoryTargetPlan {
+                target: (3 << 29) + (4 << 10),
+                ram: (3 << 29) + (2 << 20),
+                balloon: (2 << 20) - (4 << 10),
+            }
+        );
+        assert_eq!(
+            plan_memory_target(512 << 20, &memory, true).unwrap(),
+            MemoryTargetPlan {
+                target: 512 << 20,
+                ram: 1 << 30,
+                balloon: 512 << 20,
+            }
+        );
+        assert!(plan_memory_target(2 << 30, &memory, true).is_ok());
+        assert!(matches!(
+            plan_memory_target((2 << 30) + (4 << 10), &memory, true),
+            Err(Error::InvalidMemoryTarget(_))
+        ));
+    }
+
+    #[test]
+    fn test_cgroup_unless_denied() {
+        assert!(matches!(cgroup_unless_denied(None), Ok(None)));
+
+        // The VM runs without limits when the VMM can't manage the cgroup,
+        // but not when the cgroup can't be set up for another reason.
+        let denied = cgroup::Error::AddProcess(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(matches!(cgroup_unless_denied(Some(Err(denied))), Ok(None)));
+        let invalid = cgroup::Error::SetCpuMax(io::Error::from_raw_os_error(libc::EINVAL));
+        assert!(matches!(
+            cgroup_unless_denied(Some(Err(invalid))),
+            Err(Error::Cgroup(cgroup::Error::SetCpuMax(_)))
+        ));
+    }
+
+    #[test]
+    fn test_memory_target_ram() {
+        // The memory hotplugged through ACPI is part of the guest RAM, the
+        // virtio-mem device being unused.
+        let mut memory = MemoryConfig {
+            size: 1 << 30,
+            hotplug_size: Some(1 << 30),
+            hotplugged_size: Some(512 << 20),
+            ..Default::default()
+        };
+        assert_eq!(memory_target_ram(&memory, 0), 3 << 29);
+        let plan = plan_memory_target(1 << 30, &memory, true).unwrap();
+        assert_eq!(plan.ram, memory_target_ram(&memory, 0));
+
+        // With virtio-mem, only what the guest plugged counts.
+        memory.hotplug_method = HotplugMethod::VirtioMem;
+        memory.hotplugged_size = None;
+        assert_eq!(memory_target_ram(&memory, 256 << 20), 5 << 28);
+    }
+
+    #[test]
+    fn test_check_disk_not_shared() {
+        let source = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let destination = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let metadata = source.as_file().metadata().unwrap();
+        let header = DiskHeader {
+            index: 0,
+            size: 0,
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        };
+
+        assert!(check_disk_not_shared(destination.as_path(), &header).is_ok());
+        assert!(check_disk_not_shared(source.as_path(), &header).is_err());
+    }
+
+    #[test]
+    fn test_check_nmi_state() {
+        assert!(check_nmi_state(VmState::Running).is_ok());
+        for state in [VmState::Created, VmState::Paused, VmState::Shutdown].iter() {
+            assert!(matches!(check_nmi_state(*state), Err(Error::VmNotRunning)));
+        }
+    }
+
+    #[cfg(feature = "guest_debug")]
+    #[test]
+    fn test_debug_memory() {
+        // Two RAM regions with a hole in between.
+        let memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10_0000),
+            (GuestAddress(0x20_0000), 0x10_0000),
+        ])
+        .unwrap();
+
+        write_debug_memory(&memory, 0x1000, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(
+            read_debug_memory(&memory, 0x1000, 4).unwrap(),
+            vec![1, 2, 3, 4]
+        );
+
+        // A range overlapping the hole is refused, none of it being written.
+        assert!(matches!(
+            write_debug_memory(&memory, 0xf_fffe, &[5, 6, 7, 8]),
+            Err(Error::UnmappedGuestMemory(0xf_fffe, 4))
+        ));
+        assert_eq!(read_debug_memory(&memory, 0xf_fffe, 2).unwrap(), vec![0, 0]);
+        assert!(matches!(
+            read_debug_memory(&memory, 0x10_0000, 1),
+            Err(Error::UnmappedGuestMemory(0x10_0000, 1))
+        ));
+
+        // The size is checked before anything is allocated.
+        assert!(matches!(
+            read_debug_memory(&memory, 0, usize::MAX),
+            Err(Error::GuestMemoryAccessTooLarge(usize::MAX))
+        ));
+        let data = vec![0; MAX_GUEST_MEMORY_ACCESS + 1];
+        assert!(matches!(
+            write_debug_memory(&memory, 0, &data),
+            Err(Error::GuestMemoryAccessTooLarge(_))
+        ));
+        assert_eq!(
+            read_debug_memory(&memory, 0, MAX_GUEST_MEMORY_ACCESS)
+                .unwrap()
+                .len(),
+            MAX_GUEST_MEMORY_ACCESS
+        );
+    }
 }
 
 #[cfg(target_arch = "aarch64")]